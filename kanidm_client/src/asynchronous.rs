@@ -0,0 +1,531 @@
+// The async client, which the blocking client is a wrapper around. Each
+// method gives a future that must be run on a tokio runtime. The session is
+// held in the cookie store of the http client, so it's shared by every clone
+// of a client, and by the blocking client the async one was taken from.
+use futures::{future, Future, Stream};
+use reqwest::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE};
+use reqwest::r#async::{Client, RequestBuilder, Response as HttpResponse};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+use kanidm_proto::v1::{
+    AuthAllowed, AuthCredential, AuthDenyReason, AuthRequest, AuthResponse, AuthState, AuthStep,
+    CreateRequest, CreateResponse, DeleteRequest, DeleteResponse, Entry, Filter, LogoutRequest,
+    ModifyList, ModifyRequest, ModifyResponse, ReauthRequest, SearchRequest, SearchResponse,
+    UserAuthToken, WebauthnAssertion, WhoamiResponse,
+};
+
+use crate::{
+    error_from_response, is_cbor_refused, read_body, ClientError, Response, CONTENT_TYPE_CBOR,
+    CONTENT_TYPE_JSON,
+};
+
+pub type ClientFuture<T> = Box<dyn Future<Item = T, Error = ClientError> + Send>;
+
+fn fail<T: Send + 'static>(e: ClientError) -> ClientFuture<T> {
+    Box::new(future::err(e))
+}
+
+// Read the whole of a response, so that it can be decoded.
+pub(crate) fn read_response(response: HttpResponse) -> ClientFuture<Response> {
+    let status = response.status();
+    let headers = response.headers().clone();
+    Box::new(
+        response
+            .into_body()
+            .concat2()
+            .map_err(ClientError::Transport)
+            .map(move |body| Response {
+                status: status,
+                headers: headers,
+                body: body.to_vec(),
+            }),
+    )
+}
+
+// The body of a response the server accepted, or the error it gave.
+fn read_ok<T: DeserializeOwned>(response: Response) -> Result<T, ClientError> {
+    match response.status() {
+        reqwest::StatusCode::OK => read_body(&response),
+        unexpect => Err(error_from_response(&response, unexpect)),
+    }
+}
+
+// The auths that are denied at the first step for a reason the caller can
+// act on. Any other denial is found out at the next step.
+fn init_denied(state: &AuthState) -> Option<ClientError> {
+    match state {
+        AuthState::Denied(AuthDenyReason::Locked(until), _) => {
+            Some(ClientError::AccountLocked(*until))
+        }
+        AuthState::Denied(AuthDenyReason::NotYetValid(from), _) => {
+            Some(ClientError::AccountNotYetValid(*from))
+        }
+        AuthState::Denied(AuthDenyReason::Expired(at), _) => Some(ClientError::AccountExpired(*at)),
+        _ => None,
+    }
+}
+
+fn password_state(state: AuthState) -> Result<UserAuthToken, ClientError> {
+    match state {
+        AuthState::Success(uat) => {
+            debug!("==> Authed as uat; {:?}", uat);
+            Ok(uat)
+        }
+        AuthState::Continue(allowed) => {
+            if allowed.iter().any(|a| match a {
+                AuthAllowed::TOTP | AuthAllowed::Webauthn(_) | AuthAllowed::BackupCode => true,
+                _ => false,
+            }) {
+                Err(ClientError::MFARequired(allowed))
+            } else {
+                Err(ClientError::AuthenticationFailed)
+            }
+        }
+        AuthState::Denied(AuthDenyReason::Locked(until), _) => {
+            Err(ClientError::AccountLocked(until))
+        }
+        AuthState::Denied(AuthDenyReason::Expired(at), _) => Err(ClientError::AccountExpired(at)),
+        _ => Err(ClientError::AuthenticationFailed),
+    }
+}
+
+// The state after the last credential of an auth, which must be a success.
+fn final_state(state: AuthState) -> Result<UserAuthToken, ClientError> {
+    match state {
+        AuthState::Success(uat) => {
+            debug!("==> Authed as uat; {:?}", uat);
+            Ok(uat)
+        }
+        AuthState::Denied(AuthDenyReason::Locked(until), _) => {
+            Err(ClientError::AccountLocked(until))
+        }
+        _ => Err(ClientError::AuthenticationFailed),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct KanidmAsyncClient {
+    pub(crate) client: Client,
+    addr: String,
+    // Whether requests are sent as cbor. This is cleared for good if the
+    // server turns out not to understand it.
+    cbor: Arc<AtomicBool>,
+    // The signed token of our session, given as a bearer token with each
+    // request. The session cookie is used over it by the server, so this
+    // only matters for a session that was begun by another client.
+    token: Arc<RwLock<Option<String>>>,
+}
+
+impl KanidmAsyncClient {
+    pub(crate) fn new(client: Client, addr: &str, cbor: bool) -> Self {
+        KanidmAsyncClient {
+            client: client,
+            addr: addr.to_string(),
+            cbor: Arc::new(AtomicBool::new(cbor)),
+            token: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    // The token of the session the last auth began, which can be kept to
+    // carry on the session with set_token in a later client. It's no longer
+    // accepted once the session ends or expires.
+    pub fn get_token(&self) -> Option<String> {
+        self.token.read().expect("token lock poisoned").clone()
+    }
+
+    pub fn set_token(&self, token: &str) {
+        *self.token.write().expect("token lock poisoned") = Some(token.to_string());
+    }
+
+    pub fn clear_token(&self) {
+        *self.token.write().expect("token lock poisoned") = None;
+    }
+
+    fn with_token(&self, req: RequestBuilder) -> RequestBuilder {
+        match self.get_token() {
+            Some(token) => req.header(AUTHORIZATION, format!("Bearer {}", token)),
+            None => req,
+        }
+    }
+
+    // Only send and accept json, as clients did before cbor.
+    pub fn prefer_json(self) -> Self {
+        self.cbor.store(false, Ordering::Relaxed);
+        self
+    }
+
+    pub fn uses_cbor(&self) -> bool {
+        self.cbor.load(Ordering::Relaxed)
+    }
+
+    pub fn get_url(&self) -> &str {
+        self.addr.as_str()
+    }
+
+    fn accept(&self) -> &'static str {
+        if self.uses_cbor() {
+            CONTENT_TYPE_CBOR
+        } else {
+            CONTENT_TYPE_JSON
+        }
+    }
+
+    pub(crate) fn perform(&self, req: RequestBuilder) -> ClientFuture<Response> {
+        Box::new(
+            req.send()
+                .map_err(ClientError::Transport)
+                .and_then(read_response),
+        )
+    }
+
+    // The request is sent as cbor unless the server is known not to accept
+    // it. If it's refused, it's sent again as json, which is used from then
+    // on. Nothing was done by the refused request, as it was never decoded.
+    // The response is given before its body is read, so that it can be
+    // streamed.
+    pub(crate) fn send_post<R: Serialize>(
+        &self,
+        dest: &str,
+        request: &R,
+    ) -> ClientFuture<HttpResponse> {
+        let json = match serde_json::to_vec(request) {
+            Ok(b) => b,
+            Err(_) => return fail(ClientError::JsonParse),
+        };
+        let json_req = self
+            .with_token(self.client.post(dest))
+            .header(CONTENT_TYPE, CONTENT_TYPE_JSON)
+            .header(ACCEPT, CONTENT_TYPE_JSON)
+            .body(json);
+        if !self.uses_cbor() {
+            return Box::new(json_req.send().map_err(ClientError::Transport));
+        }
+
+        let cbor = match serde_cbor::to_vec(request) {
+            Ok(b) => b,
+            Err(_) => return fail(ClientError::JsonParse),
+        };
+        let flag = self.cbor.clone();
+        Box::new(
+            self.with_token(self.client.post(dest))
+                .header(CONTENT_TYPE, CONTENT_TYPE_CBOR)
+                .header(ACCEPT, CONTENT_TYPE_CBOR)
+                .body(cbor)
+                .send()
+                .map_err(ClientError::Transport)
+                .and_then(move |response| -> ClientFuture<HttpResponse> {
+                    if !is_cbor_refused(response.status(), response.headers()) {
+                        return Box::new(future::ok(response));
+                    }
+                    debug!("Server refused cbor, falling back to json");
+                    flag.store(false, Ordering::Relaxed);
+                    Box::new(json_req.send().map_err(ClientError::Transport))
+                }),
+        )
+    }
+
+    pub(crate) fn perform_post<R: Serialize>(
+        &self,
+        dest: &str,
+        request: &R,
+    ) -> ClientFuture<Response> {
+        Box::new(self.send_post(dest, request).and_then(read_response))
+    }
+
+    // For the requests named entirely by their path.
+    pub(crate) fn perform_post_empty(&self, dest: &str) -> ClientFuture<Response> {
+        self.perform(
+            self.with_token(self.client.post(dest))
+                .header(ACCEPT, self.accept()),
+        )
+    }
+
+    pub(crate) fn send_get(&self, dest: &str) -> ClientFuture<HttpResponse> {
+        Box::new(
+            self.with_token(self.client.get(dest))
+                .header(ACCEPT, self.accept())
+                .send()
+                .map_err(ClientError::Transport),
+        )
+    }
+
+    pub(crate) fn perform_get(&self, dest: &str) -> ClientFuture<Response> {
+        Box::new(self.send_get(dest).and_then(read_response))
+    }
+
+    fn auth_step(&self, step: AuthStep) -> ClientFuture<AuthState> {
+        let auth_dest = format!("{}/v1/auth", self.addr);
+        let client = self.clone();
+        Box::new(
+            self.perform_post(auth_dest.as_str(), &AuthRequest { step: step })
+                .and_then(read_ok::<AuthResponse>)
+                .map(move |r| {
+                    if let Some(token) = r.token {
+                        client.set_token(token.as_str());
+                    }
+                    r.state
+                }),
+        )
+    }
+
+    fn auth_step_init(&self, ident: &str, appid: Option<&str>) -> ClientFuture<AuthState> {
+        self.auth_step(AuthStep::Init(
+            ident.to_string(),
+            appid.map(|s| s.to_string()),
+        ))
+    }
+
+    // auth
+    pub fn auth_anonymous(&self) -> ClientFuture<UserAuthToken> {
+        let client = self.clone();
+        Box::new(
+            self.auth_step_init("anonymous", None)
+                .and_then(move |state| match state {
+                    AuthState::Denied(_, _) => fail(ClientError::AuthenticationFailed),
+                    _ => client.auth_step(AuthStep::Creds(vec![AuthCredential::Anonymous])),
+                })
+                .and_then(|state| match state {
+                    AuthState::Success(uat) => {
+                        debug!("==> Authed as uat; {:?}", uat);
+                        Ok(uat)
+                    }
+                    _ => Err(ClientError::AuthenticationFailed),
+                }),
+        )
+    }
+
+    pub fn auth_simple_password(&self, ident: &str, password: &str) -> ClientFuture<UserAuthToken> {
+        let client = self.clone();
+        let password = password.to_string();
+        Box::new(self.auth_step_init(ident, None).and_then(move |state| {
+            match init_denied(&state) {
+                Some(e) => fail(e),
+                None => client.auth_step_password(password.as_str()),
+            }
+        }))
+    }
+
+    // As auth_simple_password, but requesting claims for the session. The
+    // claims the account and credentials qualify for are in the token, and
+    // the rest are silently not granted. Like auth_simple_password, this may
+    // return MFARequired, and some claims are only granted with the second
+    // factor.
+    pub fn auth_password_with_claims(
+        &self,
+        ident: &str,
+        password: &str,
+        claims: Vec<&str>,
+    ) -> ClientFuture<UserAuthToken> {
+        let client = self.clone();
+        let password = password.to_string();
+        let claims: Vec<String> = claims.into_iter().map(|c| c.to_string()).collect();
+        Box::new(self.auth_step_init(ident, None).and_then(
+            move |state| -> ClientFuture<UserAuthToken> {
+                if let Some(e) = init_denied(&state) {
+                    return fail(e);
+                }
+                Box::new(
+                    client
+                        .auth_step(AuthStep::RequestClaims(claims))
+                        .and_then(move |state| match state {
+                            AuthState::Continue(_) => client.auth_step_password(password.as_str()),
+                            _ => fail(ClientError::AuthenticationFailed),
+                        }),
+                )
+            },
+        ))
+    }
+
+    // Authenticate as a service account with one of its api tokens. What
+    // the session may do depends on the token.
+    pub fn auth_api_token(&self, ident: &str, token: &str) -> ClientFuture<UserAuthToken> {
+        let client = self.clone();
+        let token = token.to_string();
+        Box::new(
+            self.auth_step_init(ident, None)
+                .and_then(move |state| {
+                    if let Some(e) = init_denied(&state) {
+                        return fail(e);
+                    }
+                    match state {
+                        AuthState::Denied(_, _) => fail(ClientError::AuthenticationFailed),
+                        _ => {
+                            client.auth_step(AuthStep::Creds(vec![AuthCredential::ApiToken(token)]))
+                        }
+                    }
+                })
+                .and_then(final_state),
+        )
+    }
+
+    // Authenticate our current session again, so that it may make changes
+    // that need a recent authentication. The session keeps its id and
+    // expiry. Like auth_simple_password, this may return MFARequired.
+    pub fn reauth_simple_password(&self, password: &str) -> ClientFuture<UserAuthToken> {
+        let dest = format!("{}/v1/auth/reauth", self.addr);
+        let client = self.clone();
+        let password = password.to_string();
+        Box::new(
+            self.perform_post(dest.as_str(), &ReauthRequest::new())
+                .and_then(|response| match response.status() {
+                    reqwest::StatusCode::UNAUTHORIZED => Err(ClientError::Unauthorized),
+                    _ => read_ok::<AuthResponse>(response),
+                })
+                .and_then(move |r| match r.state {
+                    AuthState::Denied(AuthDenyReason::Locked(until), _) => {
+                        fail(ClientError::AccountLocked(until))
+                    }
+                    AuthState::Denied(_, _) => fail(ClientError::AuthenticationFailed),
+                    _ => client.auth_step_password(password.as_str()),
+                }),
+        )
+    }
+
+    fn auth_step_password(&self, password: &str) -> ClientFuture<UserAuthToken> {
+        Box::new(
+            self.auth_step(AuthStep::Creds(vec![AuthCredential::Password(
+                password.to_string(),
+            )]))
+            .and_then(password_state),
+        )
+    }
+
+    // Give a second factor to an auth that returned MFARequired.
+    pub fn auth_step_totp(&self, totp: &str) -> ClientFuture<UserAuthToken> {
+        self.auth_step_mfa(AuthCredential::TOTP(totp.to_string()))
+    }
+
+    pub fn auth_step_webauthn(&self, asrt: WebauthnAssertion) -> ClientFuture<UserAuthToken> {
+        self.auth_step_mfa(AuthCredential::Webauthn(asrt))
+    }
+
+    // Each backup code can only be used once.
+    pub fn auth_step_backup_code(&self, code: &str) -> ClientFuture<UserAuthToken> {
+        self.auth_step_mfa(AuthCredential::BackupCode(code.to_string()))
+    }
+
+    fn auth_step_mfa(&self, cred: AuthCredential) -> ClientFuture<UserAuthToken> {
+        Box::new(
+            self.auth_step(AuthStep::Creds(vec![cred]))
+                .and_then(final_state),
+        )
+    }
+
+    pub fn auth_password_totp(
+        &self,
+        ident: &str,
+        password: &str,
+        totp: &str,
+    ) -> ClientFuture<UserAuthToken> {
+        let client = self.clone();
+        let totp = totp.to_string();
+        Box::new(
+            self.auth_simple_password(ident, password)
+                .then(move |r| match r {
+                    Err(ClientError::MFARequired(_)) => client.auth_step_totp(totp.as_str()),
+                    // The account doesn't need the totp, which is still a
+                    // failure as we were told it did.
+                    Ok(_) => fail(ClientError::AuthenticationFailed),
+                    Err(e) => fail(e),
+                }),
+        )
+    }
+
+    // whoami
+    pub fn whoami(&self) -> ClientFuture<Option<(Entry, UserAuthToken)>> {
+        let whoami_dest = format!("{}/v1/whoami", self.addr);
+        Box::new(self.perform_get(whoami_dest.as_str()).and_then(
+            |response| match response.status() {
+                reqwest::StatusCode::UNAUTHORIZED => Ok(None),
+                _ => read_ok::<WhoamiResponse>(response).map(|r| Some((r.youare, r.uat))),
+            },
+        ))
+    }
+
+    // End the current session. The token is no longer accepted by the
+    // server, and we must authenticate again.
+    pub fn logout(&self) -> ClientFuture<()> {
+        let dest = format!("{}/v1/logout", self.addr);
+        let client = self.clone();
+        Box::new(
+            self.perform_post(dest.as_str(), &LogoutRequest::new())
+                .and_then(move |response| match response.status() {
+                    reqwest::StatusCode::OK => {
+                        client.clear_token();
+                        Ok(())
+                    }
+                    reqwest::StatusCode::UNAUTHORIZED => Err(ClientError::Unauthorized),
+                    unexpect => Err(error_from_response(&response, unexpect)),
+                }),
+        )
+    }
+
+    // search
+    pub fn search(&self, filter: Filter) -> ClientFuture<Vec<Entry>> {
+        Box::new(
+            self.perform_search(SearchRequest::new(filter))
+                .map(|sr| sr.entries),
+        )
+    }
+
+    pub fn search_with_attrs(
+        &self,
+        filter: Filter,
+        attrs: Vec<String>,
+    ) -> ClientFuture<Vec<Entry>> {
+        Box::new(
+            self.perform_search(SearchRequest::new_with_attrs(filter, attrs))
+                .map(|sr| sr.entries),
+        )
+    }
+
+    pub(crate) fn perform_search(&self, sr: SearchRequest) -> ClientFuture<SearchResponse> {
+        let dest = format!("{}/v1/search", self.addr);
+        Box::new(self.perform_post(dest.as_str(), &sr).and_then(read_ok))
+    }
+
+    // create, returning the uuids of the new entries in the order given.
+    pub fn create(&self, entries: Vec<Entry>) -> ClientFuture<Vec<String>> {
+        let c = CreateRequest { entries: entries };
+        let dest = format!("{}/v1/create", self.addr);
+        Box::new(
+            self.perform_post(dest.as_str(), &c)
+                .and_then(read_ok::<CreateResponse>)
+                .map(|r| r.uuids),
+        )
+    }
+
+    // modify, returning the number of entries changed. Unless allow_empty is
+    // set, a filter that matches nothing is an error.
+    pub fn modify(
+        &self,
+        filter: Filter,
+        modlist: ModifyList,
+        allow_empty: bool,
+    ) -> ClientFuture<u64> {
+        let mut m = ModifyRequest::new(filter, modlist);
+        m.allow_empty = allow_empty;
+        let dest = format!("{}/v1/modify", self.addr);
+        Box::new(
+            self.perform_post(dest.as_str(), &m)
+                .and_then(read_ok::<ModifyResponse>)
+                .map(|r| r.modified),
+        )
+    }
+
+    // delete, returning the number of entries removed. Unless allow_empty is
+    // set, a filter that matches nothing is an error.
+    pub fn delete(&self, filter: Filter, allow_empty: bool) -> ClientFuture<u64> {
+        let mut d = DeleteRequest::new(filter);
+        d.allow_empty = allow_empty;
+        let dest = format!("{}/v1/delete", self.addr);
+        Box::new(
+            self.perform_post(dest.as_str(), &d)
+                .and_then(read_ok::<DeleteResponse>)
+                .map(|r| r.deleted),
+        )
+    }
+}
@@ -3,18 +3,53 @@
 
 #[macro_use]
 extern crate log;
+#[macro_use]
+extern crate serde_derive;
 
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_cbor;
 use serde_json;
 
+use futures::sync::oneshot;
+use futures::{Future, Stream};
 use reqwest;
+use reqwest::header::{HeaderMap, AUTHORIZATION, CONTENT_TYPE, LOCATION};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use std::time::Duration;
+use tokio::runtime::Runtime;
 
 use kanidm_proto::v1::{
-    AuthCredential, AuthRequest, AuthResponse, AuthState, AuthStep, CreateRequest, Entry, Filter,
-    OperationResponse, SearchRequest, SearchResponse, UserAuthToken, WhoamiResponse,
+    AccessCheckEntry, AccessCheckOperation, AccessCheckRequest, AccessCheckResponse,
+    AccessControlCreateRights, AccessControlModifyRights, AccessControlProfile,
+    ApiTokenGenerateRequest, ApiTokenGenerateResponse, ApiTokenInfo, ApiTokenListRequest,
+    ApiTokenListResponse, AuditListRequest, AuditListResponse, AuditRecord, AuthAllowed,
+    BackupCodesGenerateRequest, BackupCodesGenerateResponse, BackupRequest, BackupResponse,
+    ChangedEntry, ChangesResponse, CompareRequest, CompareResponse, CredentialChangeRequest,
+    CredentialPolicy, CredentialPolicyRequest, CredentialStatusResponse, EffectiveAccess,
+    EffectiveAccessRequest, EffectiveAccessResponse, Entry, ErrorResponse, ExportStreamItem,
+    Filter, FilterParseError, IndexStatus, IndexStatusRequest, IndexStatusResponse, JwkSet, Modify,
+    ModifyBatchRequest, ModifyBatchResponse, ModifyList, Oauth2AuthorizeRequest,
+    Oauth2ErrorResponse, Oauth2TokenRequest, Oauth2TokenResponse, Oauth2UserInfo, PasswordFeedback,
+    RadiusAuthToken, RadiusSecretGenerateRequest, RadiusSecretGenerateResponse, ReindexRequest,
+    ReindexResponse, ReviveRecycledResponse, SchemaAttribute, SchemaClass, SchemaRequest,
+    SchemaResponse, SearchCountRequest, SearchCountResponse, SearchPlan, SearchRecycledRequest,
+    SearchRecycledResponse, SearchRequest, SearchResponse, SearchStreamItem, SessionInfo,
+    SessionListRequest, SessionListResponse, SortOrder, TOTPGenerateRequest, TOTPGenerateResponse,
+    TOTPSecret, TOTPVerifyRequest, UnixAuthRequest, UnixGroupToken, UnixUserToken, UserAuthToken,
+    VacuumRequest, VacuumResponse, WebauthnAssertion, WebauthnCreationChallenge,
+    WebauthnGenerateRequest, WebauthnGenerateResponse, WebauthnListResponse,
+    WebauthnRegisterCredential, WebauthnRegisterRequest, WebauthnRemoveRequest, WebauthnTokenInfo,
+    SEARCH_STREAM_CBOR,
 };
 
+mod asynchronous;
+
+pub use crate::asynchronous::{ClientFuture, KanidmAsyncClient};
+
 #[derive(Debug)]
 pub enum ClientError {
     Unauthorized,
@@ -22,220 +57,2204 @@ pub enum ClientError {
     Transport(reqwest::Error),
     AuthenticationFailed,
     JsonParse,
+    FilterParse(FilterParseError),
+    // The index of the batch item that failed, and the server's error for it.
+    BatchItemFailed(u64, ErrorResponse),
+    // The uuid of each entry that could not be revived, and the server's
+    // error for it. Nothing was revived.
+    ReviveFailed(Vec<(String, ErrorResponse)>),
+    // The password was accepted, but the account also requires one of these
+    // second factors, which can be given with auth_step_totp,
+    // auth_step_webauthn or auth_step_backup_code.
+    MFARequired(Vec<AuthAllowed>),
+    // The totp code did not match the secret being enrolled.
+    InvalidTOTP,
+    // The current password given for a password change was wrong.
+    IncorrectPassword,
+    // The new password was too weak, and why.
+    PasswordQuality(Vec<PasswordFeedback>),
+    // There were too many failed authentications. Try again after this
+    // time, in seconds since the unix epoch.
+    AccountLocked(u64),
+    // The account may not authenticate until this time, in seconds since
+    // the unix epoch.
+    AccountNotYetValid(u64),
+    // The account expired at this time, in seconds since the unix epoch.
+    AccountExpired(u64),
+    // The session authenticated too long ago for this change. Authenticate
+    // again with reauth_simple_password and retry.
+    ReauthRequired,
+    // Another entry already has this value of an attribute that must be
+    // unique, such as a gidnumber. Holds the name of the attribute.
+    DuplicateValue(String),
+    // The session may not do this.
+    AccessDenied,
+    // Nothing matched the request.
+    NotFound,
+    // The account or group with this name, given to one of the idm_account
+    // or idm_group methods, does not exist.
+    NoSuchAccount(String),
+    NoSuchGroup(String),
+    // Too many requests were made. Retry after this many seconds.
+    RateLimited(u64),
+    // The search could give more entries than this limit. Narrow the
+    // filter, or use search_paged with pages no larger than the limit.
+    ResultLimit(u64),
+    // The changes since the change id that was given are no longer kept by
+    // the server, so everything must be read again.
+    ChangelogTrimmed,
+    // An oauth2 request was refused, with the error code the rfc gives,
+    // such as invalid_grant.
+    Oauth2(String),
+    // Any other error the server gave, with its status.
+    Operation(reqwest::StatusCode, ErrorResponse),
+    // A streamed search ended before the server said it was done, so the
+    // entries already given may not be all that matched.
+    StreamTruncated,
+    // The client couldn't be built as it was configured, and why. A file
+    // that is at fault is named.
+    Config(String),
+}
+
+impl ClientError {
+    // Whether the same request may succeed if it's made again later, as
+    // when the server's database was busy. Nothing was changed by a request
+    // that failed this way.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ClientError::RateLimited(_) => true,
+            ClientError::Operation(_, e) => e.is_retryable(),
+            ClientError::Http(status) => *status == reqwest::StatusCode::SERVICE_UNAVAILABLE,
+            _ => false,
+        }
+    }
+
+    // The id the server logged the failed operation under, for the errors
+    // that have no variant of their own.
+    pub fn eventid(&self) -> Option<&str> {
+        match self {
+            ClientError::Operation(_, e) | ClientError::BatchItemFailed(_, e) => {
+                e.eventid.as_ref().map(|s| s.as_str())
+            }
+            _ => None,
+        }
+    }
+}
+
+const CONTENT_TYPE_CBOR: &str = "application/cbor";
+const CONTENT_TYPE_JSON: &str = "application/json";
+
+// A response with the whole of its body read, as is every response but a
+// streamed one.
+#[derive(Debug)]
+struct Response {
+    status: reqwest::StatusCode,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn status(&self) -> reqwest::StatusCode {
+        self.status
+    }
+
+    fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+}
+
+fn is_cbor(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.starts_with(CONTENT_TYPE_CBOR))
+        .unwrap_or(false)
+}
+
+// A server that predates cbor reads any body as json, so refuses a cbor body
+// as a bad request with no error response. A newer server would answer in
+// cbor, as it was asked to.
+fn is_cbor_refused(status: reqwest::StatusCode, headers: &HeaderMap) -> bool {
+    match status {
+        reqwest::StatusCode::BAD_REQUEST | reqwest::StatusCode::UNSUPPORTED_MEDIA_TYPE => {
+            !is_cbor(headers)
+        }
+        _ => false,
+    }
+}
+
+// Decode a body by its content type rather than by what was asked for, as
+// older servers only ever answer in json.
+fn read_body<T: DeserializeOwned>(response: &Response) -> Result<T, ClientError> {
+    if is_cbor(response.headers()) {
+        serde_cbor::from_slice(&response.body).map_err(|_| ClientError::JsonParse)
+    } else {
+        serde_json::from_slice(&response.body).map_err(|_| ClientError::JsonParse)
+    }
+}
+
+// Turn the body of a failed response into an error. The errors the caller
+// can act on have their own variants, and the rest are given as the server
+// sent them. A body that isn't an error response, such as one from a proxy,
+// leaves only the status.
+fn error_from_response(response: &Response, unexpect: reqwest::StatusCode) -> ClientError {
+    match read_body(response) {
+        Ok(err) => client_error(err, unexpect),
+        Err(_) => ClientError::Http(unexpect),
+    }
+}
+
+fn client_error(err: ErrorResponse, unexpect: reqwest::StatusCode) -> ClientError {
+    match err.code.as_str() {
+        "NotAuthenticated" => ClientError::Unauthorized,
+        "AccessDenied" => ClientError::AccessDenied,
+        "NoMatchingEntries" => ClientError::NotFound,
+        "ReauthRequired" => ClientError::ReauthRequired,
+        "InvalidTOTP" => ClientError::InvalidTOTP,
+        "IncorrectPassword" => ClientError::IncorrectPassword,
+        "PasswordQuality" => ClientError::PasswordQuality(err.feedback),
+        "RateLimited" => ClientError::RateLimited(err.retry_after.unwrap_or(0)),
+        "ChangelogTrimmed" => ClientError::ChangelogTrimmed,
+        "ResultLimit" => match err.detail.as_ref().and_then(|d| d.parse().ok()) {
+            Some(limit) => ClientError::ResultLimit(limit),
+            None => ClientError::Operation(unexpect, err),
+        },
+        "DuplicateValue" => match err.detail {
+            Some(attr) => ClientError::DuplicateValue(attr),
+            None => ClientError::Operation(unexpect, err),
+        },
+        "BatchItemFailed" => match (err.index, err.inner.clone()) {
+            (Some(i), Some(mut inner)) => {
+                inner.eventid = err.eventid;
+                ClientError::BatchItemFailed(i, *inner)
+            }
+            _ => ClientError::Operation(unexpect, err),
+        },
+        "ReviveFailed" => ClientError::ReviveFailed(err.failed),
+        _ => ClientError::Operation(unexpect, err),
+    }
+}
+
+// The oauth2 endpoints give their errors as the rfc does, unless the request
+// was refused before it got that far, such as when it isn't authenticated.
+fn oauth2_error_from_response(response: &Response, unexpect: reqwest::StatusCode) -> ClientError {
+    if let Ok(err) = serde_json::from_slice::<Oauth2ErrorResponse>(&response.body) {
+        return ClientError::Oauth2(err.error);
+    }
+    match serde_json::from_slice::<ErrorResponse>(&response.body) {
+        Ok(err) => client_error(err, unexpect),
+        Err(_) => ClientError::Http(unexpect),
+    }
+}
+
+fn system_config_filter() -> Filter {
+    Filter::Eq("class".to_string(), "system_config".to_string())
+}
+
+fn domain_info_filter() -> Filter {
+    Filter::Eq("class".to_string(), "domain_info".to_string())
+}
+
+fn domain_info_from_entry(e: &Entry) -> Result<DomainInfo, ClientError> {
+    Ok(DomainInfo {
+        uuid: e
+            .get_ava_single("domain_uuid")
+            .ok_or(ClientError::JsonParse)?
+            .to_string(),
+        domain_name: e
+            .get_ava_single("domain_name")
+            .ok_or(ClientError::JsonParse)?
+            .to_string(),
+        domain_display_name: e
+            .get_ava_single("domain_display_name")
+            .map(|s| s.to_string()),
+    })
+}
+
+// The server compares passwords to the badlist without case, so entries are
+// lowercased, and any repeats dropped, before they are sent.
+fn normalise_badlist(words: &[&str]) -> Vec<String> {
+    let words: BTreeSet<String> = words
+        .iter()
+        .map(|w| w.trim().to_lowercase())
+        .filter(|w| !w.is_empty())
+        .collect();
+    words.into_iter().collect()
+}
+
+// The attributes of an access control profile that an update may change.
+// The uuid is fixed once created.
+static ACP_MANAGED_ATTRS: [&'static str; 12] = [
+    "class",
+    "name",
+    "description",
+    "acp_enable",
+    "acp_receiver",
+    "acp_targetscope",
+    "acp_search_attr",
+    "acp_modify_presentattr",
+    "acp_modify_removedattr",
+    "acp_modify_class",
+    "acp_create_attr",
+    "acp_create_class",
+];
+
+fn acp_to_entry(acp: &AccessControlProfile) -> Entry {
+    // The server adds the object class.
+    let mut classes = vec!["access_control_profile"];
+    let mut attrs = BTreeMap::new();
+    attrs.insert("name".to_string(), vec![acp.name.clone()]);
+    if let Some(u) = &acp.uuid {
+        attrs.insert("uuid".to_string(), vec![u.clone()]);
+    }
+    if let Some(d) = &acp.description {
+        attrs.insert("description".to_string(), vec![d.clone()]);
+    }
+    attrs.insert("acp_enable".to_string(), vec![acp.enabled.to_string()]);
+    attrs.insert(
+        "acp_receiver".to_string(),
+        vec![serde_json::to_string(&acp.receiver).unwrap()],
+    );
+    attrs.insert(
+        "acp_targetscope".to_string(),
+        vec![serde_json::to_string(&acp.targetscope).unwrap()],
+    );
+    if let Some(search) = &acp.search {
+        classes.push("access_control_search");
+        attrs.insert("acp_search_attr".to_string(), search.clone());
+    }
+    if let Some(m) = &acp.modify {
+        classes.push("access_control_modify");
+        attrs.insert(
+            "acp_modify_presentattr".to_string(),
+            m.present_attrs.clone(),
+        );
+        attrs.insert(
+            "acp_modify_removedattr".to_string(),
+            m.removed_attrs.clone(),
+        );
+        attrs.insert("acp_modify_class".to_string(), m.classes.clone());
+    }
+    if let Some(c) = &acp.create {
+        classes.push("access_control_create");
+        attrs.insert("acp_create_attr".to_string(), c.attrs.clone());
+        attrs.insert("acp_create_class".to_string(), c.classes.clone());
+    }
+    if acp.delete {
+        classes.push("access_control_delete");
+    }
+    attrs.insert(
+        "class".to_string(),
+        classes.into_iter().map(|c| c.to_string()).collect(),
+    );
+    // An empty attribute is not sent, as the server would reject it.
+    attrs.retain(|_, vs| !vs.is_empty());
+    Entry { attrs: attrs }
+}
+
+fn acp_from_entry(e: &Entry) -> Result<AccessControlProfile, ClientError> {
+    let filter = |attr: &str| -> Result<Filter, ClientError> {
+        e.get_ava_single(attr)
+            .ok_or(ClientError::JsonParse)
+            .and_then(|v| serde_json::from_str(v).map_err(|_| ClientError::JsonParse))
+    };
+    let values = |attr: &str| -> Vec<String> {
+        e.get_ava(attr)
+            .map(|vs| vs.to_vec())
+            .unwrap_or_else(Vec::new)
+    };
+    let has_class = |c: &str| e.attribute_value_pres("class", c);
+
+    Ok(AccessControlProfile {
+        name: e
+            .get_ava_single("name")
+            .ok_or(ClientError::JsonParse)?
+            .to_string(),
+        uuid: e.get_ava_single("uuid").map(|s| s.to_string()),
+        description: e.get_ava_single("description").map(|s| s.to_string()),
+        enabled: e
+            .get_ava_bool("acp_enable")
+            .map_err(|_| ClientError::JsonParse)?
+            .unwrap_or(false),
+        receiver: filter("acp_receiver")?,
+        targetscope: filter("acp_targetscope")?,
+        search: if has_class("access_control_search") {
+            Some(values("acp_search_attr"))
+        } else {
+            None
+        },
+        modify: if has_class("access_control_modify") {
+            Some(AccessControlModifyRights {
+                present_attrs: values("acp_modify_presentattr"),
+                removed_attrs: values("acp_modify_removedattr"),
+                classes: values("acp_modify_class"),
+            })
+        } else {
+            None
+        },
+        create: if has_class("access_control_create") {
+            Some(AccessControlCreateRights {
+                attrs: values("acp_create_attr"),
+                classes: values("acp_create_class"),
+            })
+        } else {
+            None
+        },
+        delete: has_class("access_control_delete"),
+    })
+}
+
+fn acp_filter(name: &str) -> Filter {
+    Filter::And(vec![
+        Filter::Eq("class".to_string(), "access_control_profile".to_string()),
+        Filter::Eq("name".to_string(), name.to_string()),
+    ])
+}
+
+fn account_filter(name: &str) -> Filter {
+    Filter::And(vec![
+        Filter::Eq("class".to_string(), "account".to_string()),
+        Filter::Eq("name".to_string(), name.to_string()),
+    ])
 }
 
+fn group_filter(name: &str) -> Filter {
+    Filter::And(vec![
+        Filter::Eq("class".to_string(), "group".to_string()),
+        Filter::Eq("name".to_string(), name.to_string()),
+    ])
+}
+
+// The error for an account or group that didn't match, rather than the
+// NotFound of the request that was made for it.
+fn no_such_account(name: &str) -> impl Fn(ClientError) -> ClientError + '_ {
+    move |e| match e {
+        ClientError::NotFound => ClientError::NoSuchAccount(name.to_string()),
+        e => e,
+    }
+}
+
+fn no_such_group(name: &str) -> impl Fn(ClientError) -> ClientError + '_ {
+    move |e| match e {
+        ClientError::NotFound => ClientError::NoSuchGroup(name.to_string()),
+        e => e,
+    }
+}
+
+// The options that may be given in a config file. Any that are missing are
+// left as they were.
+#[derive(Debug, Deserialize)]
+struct KanidmClientConfig {
+    uri: Option<String>,
+    ca_path: Option<String>,
+    danger_accept_invalid_certs: Option<bool>,
+    // In seconds.
+    connect_timeout: Option<u64>,
+    request_timeout: Option<u64>,
+    proxy: Option<String>,
+}
+
+pub const DEFAULT_SYSTEM_CONFIG: &str = "/etc/kanidm/config";
+// Under the home directory of the user.
+pub const DEFAULT_USER_CONFIG: &str = ".config/kanidm";
+
+// Builds a client, blocking or async, so that both are set up the same way.
+//
+// An option may be given to the builder, or read from a config file, which
+// is toml with the names of the options as keys. An option that is already
+// set is never replaced by a config file, so whatever is given explicitly
+// wins however the calls are ordered, followed by the first file that gives
+// it. read_default_config reads the config of the user and then that of the
+// system, so the precedence is explicit > user config > system config.
+#[derive(Debug, Clone, Default)]
+pub struct KanidmClientBuilder {
+    address: Option<String>,
+    ca: Option<String>,
+    danger_accept_invalid_certs: Option<bool>,
+    connect_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    proxy: Option<String>,
+    json: bool,
+}
+
+impl KanidmClientBuilder {
+    pub fn new() -> Self {
+        KanidmClientBuilder::default()
+    }
+
+    pub fn address(mut self, address: &str) -> Self {
+        self.address = Some(address.to_string());
+        self
+    }
+
+    // Trust the ca in this pem file, as well as the system's.
+    pub fn add_root_certificate(mut self, ca_path: &str) -> Self {
+        self.ca = Some(ca_path.to_string());
+        self
+    }
+
+    // Accept any certificate the server gives, which allows anyone between
+    // us and the server to read and change everything we send. Only for
+    // testing against a server that can't be given a proper certificate.
+    pub fn danger_accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.danger_accept_invalid_certs = Some(accept);
+        self
+    }
+
+    // How long to wait for a connection to the server to be made.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    // How long to wait for the whole of a response, from when the request
+    // is sent. A streamed response must also be read within this time.
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    // Send every request through this http or https proxy.
+    pub fn proxy(mut self, url: &str) -> Self {
+        self.proxy = Some(url.to_string());
+        self
+    }
+
+    // Only send and accept json, as clients did before cbor.
+    pub fn prefer_json(mut self) -> Self {
+        self.json = true;
+        self
+    }
+
+    // Read the options this builder doesn't have yet from a config file. A
+    // file that doesn't exist is skipped, but one that can't be read or
+    // parsed is an error.
+    pub fn read_options_from_optional_config<P: AsRef<Path>>(
+        self,
+        config_path: P,
+    ) -> Result<Self, ClientError> {
+        let path = config_path.as_ref();
+        let contents = match std::fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                debug!("No client config at {}", path.display());
+                return Ok(self);
+            }
+            Err(e) => {
+                return Err(ClientError::Config(format!(
+                    "Failed to read {}: {}",
+                    path.display(),
+                    e
+                )))
+            }
+        };
+        let config: KanidmClientConfig = toml::from_str(contents.as_str()).map_err(|e| {
+            ClientError::Config(format!("Failed to parse {}: {}", path.display(), e))
+        })?;
+        Ok(self.apply_config(config))
+    }
+
+    // Read the config of the user, then the config of the system.
+    pub fn read_default_config(self) -> Result<Self, ClientError> {
+        let builder = match std::env::var_os("HOME") {
+            Some(home) => {
+                self.read_options_from_optional_config(Path::new(&home).join(DEFAULT_USER_CONFIG))?
+            }
+            None => self,
+        };
+        builder.read_options_from_optional_config(DEFAULT_SYSTEM_CONFIG)
+    }
+
+    fn apply_config(self, config: KanidmClientConfig) -> Self {
+        KanidmClientBuilder {
+            address: self.address.or(config.uri),
+            ca: self.ca.or(config.ca_path),
+            danger_accept_invalid_certs: self
+                .danger_accept_invalid_certs
+                .or(config.danger_accept_invalid_certs),
+            connect_timeout: self
+                .connect_timeout
+                .or(config.connect_timeout.map(Duration::from_secs)),
+            request_timeout: self
+                .request_timeout
+                .or(config.request_timeout.map(Duration::from_secs)),
+            proxy: self.proxy.or(config.proxy),
+            json: self.json,
+        }
+    }
+
+    fn read_ca(ca_path: &str) -> Result<reqwest::Certificate, ClientError> {
+        let mut buf = Vec::new();
+        File::open(ca_path)
+            .and_then(|mut f| f.read_to_end(&mut buf))
+            .map_err(|e| ClientError::Config(format!("Failed to read ca {}: {}", ca_path, e)))?;
+        reqwest::Certificate::from_pem(&buf)
+            .map_err(|e| ClientError::Config(format!("Failed to parse ca {}: {}", ca_path, e)))
+    }
+
+    pub fn build_async(self) -> Result<KanidmAsyncClient, ClientError> {
+        let address = self.address.as_ref().ok_or_else(|| {
+            ClientError::Config("No server address was given or configured".to_string())
+        })?;
+
+        // Redirects aren't followed, so that the redirect from an oauth2
+        // authorization can be given to the caller.
+        let mut client_builder = reqwest::r#async::Client::builder()
+            .cookie_store(true)
+            .redirect(reqwest::RedirectPolicy::none());
+
+        if let Some(ca_path) = self.ca.as_ref() {
+            client_builder = client_builder.add_root_certificate(Self::read_ca(ca_path)?);
+        }
+        if self.danger_accept_invalid_certs == Some(true) {
+            warn!("Certificates will not be verified, so this connection is not secure");
+            client_builder = client_builder.danger_accept_invalid_certs(true);
+        }
+        if let Some(timeout) = self.connect_timeout {
+            client_builder = client_builder.connect_timeout(timeout);
+        }
+        if let Some(timeout) = self.request_timeout {
+            client_builder = client_builder.timeout(timeout);
+        }
+        if let Some(url) = self.proxy.as_ref() {
+            let proxy = reqwest::Proxy::all(url.as_str())
+                .map_err(|e| ClientError::Config(format!("Invalid proxy {}: {}", url, e)))?;
+            client_builder = client_builder.proxy(proxy);
+        }
+
+        let client = client_builder.build().map_err(ClientError::Transport)?;
+        Ok(KanidmAsyncClient::new(client, address, !self.json))
+    }
+
+    pub fn build(self) -> Result<KanidmClient, ClientError> {
+        // One thread is plenty, as only one request is waited for at a time.
+        let rt = tokio::runtime::Builder::new()
+            .core_threads(1)
+            .name_prefix("kanidm-client-")
+            .build()
+            .map_err(|e| ClientError::Config(format!("Failed to start the runtime: {}", e)))?;
+        Ok(KanidmClient {
+            asclient: self.build_async()?,
+            rt: rt,
+        })
+    }
+}
+
+// The blocking client. Each request is made by the async client it wraps,
+// and waited for.
 #[derive(Debug)]
 pub struct KanidmClient {
-    client: reqwest::Client,
-    addr: String,
+    asclient: KanidmAsyncClient,
+    rt: Runtime,
 }
 
 impl KanidmClient {
+    // A client of this server, trusting this ca as well as the system's.
+    // Panics if the ca can't be read, so use KanidmClientBuilder to handle
+    // that, or to read the config files.
     pub fn new(addr: &str, ca: Option<&str>) -> Self {
-        let ca = ca.map(|ca_path| {
-            //Okay we have a ca to add. Let's read it in and setup.
-            let mut buf = Vec::new();
-            // TODO: Better than expect?
-            let mut f = File::open(ca_path).expect("Failed to open ca");
-            f.read_to_end(&mut buf).expect("Failed to read ca");
-            reqwest::Certificate::from_pem(&buf).expect("Failed to parse ca")
-        });
-
-        let client_builder = reqwest::Client::builder().cookie_store(true);
-
-        let client_builder = match ca {
-            Some(cert) => client_builder.add_root_certificate(cert),
-            None => client_builder,
-        };
+        let builder = KanidmClientBuilder::new().address(addr);
+        match ca {
+            Some(ca_path) => builder.add_root_certificate(ca_path),
+            None => builder,
+        }
+        .build()
+        .expect("Failed to build client")
+    }
 
-        let client = client_builder
-            .build()
-            .expect("Unexpected reqwest builder failure!");
+    // Only send and accept json, as clients did before cbor.
+    pub fn prefer_json(self) -> Self {
         KanidmClient {
-            client: client,
-            addr: addr.to_string(),
+            asclient: self.asclient.prefer_json(),
+            rt: self.rt,
         }
     }
 
-    fn auth_step_init(&self, ident: &str, appid: Option<&str>) -> Result<AuthState, ClientError> {
-        // TODO: Way to avoid formatting so much?
-        let auth_dest = format!("{}/v1/auth", self.addr);
+    pub fn uses_cbor(&self) -> bool {
+        self.asclient.uses_cbor()
+    }
+
+    pub fn get_url(&self) -> &str {
+        self.asclient.get_url()
+    }
 
-        let auth_init = AuthRequest {
-            step: AuthStep::Init(ident.to_string(), appid.map(|s| s.to_string())),
-        };
+    // The token of the session the last auth began, which can be kept to
+    // carry on the session with set_token in a later client.
+    pub fn get_token(&self) -> Option<String> {
+        self.asclient.get_token()
+    }
+
+    pub fn set_token(&self, token: &str) {
+        self.asclient.set_token(token)
+    }
+
+    pub fn clear_token(&self) {
+        self.asclient.clear_token()
+    }
+
+    // The async client this wraps. It shares our session, so a clone of it
+    // may carry on from an auth made here, or the other way around.
+    pub fn async_client(&self) -> &KanidmAsyncClient {
+        &self.asclient
+    }
+
+    // The future is run on our runtime rather than this thread, as the
+    // connections it uses are driven there.
+    fn block_on<T: Send + 'static>(&self, f: ClientFuture<T>) -> Result<T, ClientError> {
+        oneshot::spawn(f, &self.rt.executor()).wait()
+    }
+
+    fn perform(&self, req: reqwest::r#async::RequestBuilder) -> Result<Response, ClientError> {
+        self.block_on(self.asclient.perform(req))
+    }
+
+    fn perform_post<R: Serialize>(&self, dest: &str, request: &R) -> Result<Response, ClientError> {
+        self.block_on(self.asclient.perform_post(dest, request))
+    }
 
-        // Handle this!
-        let mut response = self
-            .client
-            .post(auth_dest.as_str())
-            .body(serde_json::to_string(&auth_init).expect("Generated invalid initstep?!"))
-            .send()
-            .map_err(|e| ClientError::Transport(e))?;
+    fn perform_post_empty(&self, dest: &str) -> Result<Response, ClientError> {
+        self.block_on(self.asclient.perform_post_empty(dest))
+    }
+
+    fn perform_get(&self, dest: &str) -> Result<Response, ClientError> {
+        self.block_on(self.asclient.perform_get(dest))
+    }
 
+    // A streamed response the server accepted. The body of one it didn't is
+    // read in full for the error.
+    fn stream_ok(
+        &self,
+        response: reqwest::r#async::Response,
+    ) -> Result<reqwest::r#async::Response, ClientError> {
         match response.status() {
-            reqwest::StatusCode::OK => {}
-            unexpect => return Err(ClientError::Http(unexpect)),
+            reqwest::StatusCode::OK => Ok(response),
+            unexpect => {
+                let response = self.block_on(asynchronous::read_response(response))?;
+                Err(error_from_response(&response, unexpect))
+            }
         }
-        // Check that we got the next step
-        let r: AuthResponse = serde_json::from_str(response.text().unwrap().as_str()).unwrap();
-
-        Ok(r.state)
     }
 
     // auth
     pub fn auth_anonymous(&self) -> Result<UserAuthToken, ClientError> {
-        let _state = match self.auth_step_init("anonymous", None) {
-            Ok(s) => s,
-            Err(e) => return Err(e),
-        };
+        self.block_on(self.asclient.auth_anonymous())
+    }
+
+    pub fn auth_simple_password(
+        &self,
+        ident: &str,
+        password: &str,
+    ) -> Result<UserAuthToken, ClientError> {
+        self.block_on(self.asclient.auth_simple_password(ident, password))
+    }
 
-        // TODO: Avoid creating this so much?
-        let auth_dest = format!("{}/v1/auth", self.addr);
+    // As auth_simple_password, but requesting claims for the session. The
+    // claims the account and credentials qualify for are in the token, and
+    // the rest are silently not granted. Like auth_simple_password, this may
+    // return MFARequired, and some claims are only granted with the second
+    // factor.
+    pub fn auth_password_with_claims(
+        &self,
+        ident: &str,
+        password: &str,
+        claims: Vec<&str>,
+    ) -> Result<UserAuthToken, ClientError> {
+        self.block_on(
+            self.asclient
+                .auth_password_with_claims(ident, password, claims),
+        )
+    }
 
-        // Check state for auth continue contains anonymous.
+    // Authenticate as a service account with one of its api tokens. What
+    // the session may do depends on the token.
+    pub fn auth_api_token(&self, ident: &str, token: &str) -> Result<UserAuthToken, ClientError> {
+        self.block_on(self.asclient.auth_api_token(ident, token))
+    }
 
-        let auth_anon = AuthRequest {
-            step: AuthStep::Creds(vec![AuthCredential::Anonymous]),
-        };
+    // Authenticate our current session again, so that it may make changes
+    // that need a recent authentication. The session keeps its id and
+    // expiry. Like auth_simple_password, this may return MFARequired.
+    pub fn reauth_simple_password(&self, password: &str) -> Result<UserAuthToken, ClientError> {
+        self.block_on(self.asclient.reauth_simple_password(password))
+    }
+
+    // Give a second factor to an auth that returned MFARequired.
+    pub fn auth_step_totp(&self, totp: &str) -> Result<UserAuthToken, ClientError> {
+        self.block_on(self.asclient.auth_step_totp(totp))
+    }
+
+    pub fn auth_step_webauthn(
+        &self,
+        asrt: WebauthnAssertion,
+    ) -> Result<UserAuthToken, ClientError> {
+        self.block_on(self.asclient.auth_step_webauthn(asrt))
+    }
+
+    // Each backup code can only be used once.
+    pub fn auth_step_backup_code(&self, code: &str) -> Result<UserAuthToken, ClientError> {
+        self.block_on(self.asclient.auth_step_backup_code(code))
+    }
 
-        let mut response = self
-            .client
-            .post(auth_dest.as_str())
-            .body(serde_json::to_string(&auth_anon).unwrap())
-            .send()
-            .map_err(|e| ClientError::Transport(e))?;
+    pub fn auth_password_totp(
+        &self,
+        ident: &str,
+        password: &str,
+        totp: &str,
+    ) -> Result<UserAuthToken, ClientError> {
+        self.block_on(self.asclient.auth_password_totp(ident, password, totp))
+    }
+
+    // Generate a totp secret for our own account. It must be confirmed with
+    // totp_verify before it is required to authenticate.
+    pub fn totp_generate(&self) -> Result<(TOTPSecret, String), ClientError> {
+        let dest = format!("{}/v1/self/_credential/totp/_generate", self.get_url());
+
+        let response = self.perform_post(dest.as_str(), &TOTPGenerateRequest::new())?;
 
         match response.status() {
             reqwest::StatusCode::OK => {}
-            unexpect => return Err(ClientError::Http(unexpect)),
+            reqwest::StatusCode::UNAUTHORIZED => return Err(ClientError::Unauthorized),
+            unexpect => return Err(error_from_response(&response, unexpect)),
         }
-        // Check that we got the next step
-        let r: AuthResponse = serde_json::from_str(response.text().unwrap().as_str()).unwrap();
 
-        match r.state {
-            AuthState::Success(uat) => {
-                debug!("==> Authed as uat; {:?}", uat);
-                Ok(uat)
-            }
-            _ => Err(ClientError::AuthenticationFailed),
+        let r: TOTPGenerateResponse = read_body(&response)?;
+        Ok((r.secret, r.uri))
+    }
+
+    pub fn totp_verify(&self, totp: &str) -> Result<(), ClientError> {
+        let dest = format!("{}/v1/self/_credential/totp/_verify", self.get_url());
+
+        let response = self.perform_post(dest.as_str(), &TOTPVerifyRequest::new(totp))?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => Ok(()),
+            unexpect => Err(error_from_response(&response, unexpect)),
         }
     }
 
-    pub fn auth_simple_password(
+    // Begin registering a webauthn token to our own account. The challenge is
+    // given to the token, and its response to webauthn_register.
+    pub fn webauthn_generate(&self) -> Result<WebauthnCreationChallenge, ClientError> {
+        let dest = format!("{}/v1/self/_credential/webauthn/_generate", self.get_url());
+
+        let response = self.perform_post(dest.as_str(), &WebauthnGenerateRequest::new())?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => {}
+            reqwest::StatusCode::UNAUTHORIZED => return Err(ClientError::Unauthorized),
+            unexpect => return Err(error_from_response(&response, unexpect)),
+        }
+
+        let r: WebauthnGenerateResponse = read_body(&response)?;
+        Ok(r.challenge)
+    }
+
+    pub fn webauthn_register(
         &self,
-        ident: &str,
-        password: &str,
-    ) -> Result<UserAuthToken, ClientError> {
-        // TODO: Way to avoid formatting so much?
-        let auth_dest = format!("{}/v1/auth", self.addr);
+        name: &str,
+        credential: WebauthnRegisterCredential,
+    ) -> Result<(), ClientError> {
+        let dest = format!("{}/v1/self/_credential/webauthn/_register", self.get_url());
 
-        let _state = match self.auth_step_init(ident, None) {
-            Ok(s) => s,
-            Err(e) => return Err(e),
-        };
+        let response = self.perform_post(
+            dest.as_str(),
+            &WebauthnRegisterRequest::new(name, credential),
+        )?;
 
-        // Send the credentials required now
-        let auth_req = AuthRequest {
-            step: AuthStep::Creds(vec![AuthCredential::Password(password.to_string())]),
-        };
+        match response.status() {
+            reqwest::StatusCode::OK => Ok(()),
+            unexpect => Err(error_from_response(&response, unexpect)),
+        }
+    }
 
-        let mut response = self
-            .client
-            .post(auth_dest.as_str())
-            .body(serde_json::to_string(&auth_req).unwrap())
-            .send()
-            .map_err(|e| ClientError::Transport(e))?;
+    pub fn webauthn_list(&self) -> Result<Vec<WebauthnTokenInfo>, ClientError> {
+        let dest = format!("{}/v1/self/_credential/webauthn", self.get_url());
+        let response = self.perform_get(dest.as_str())?;
 
         match response.status() {
             reqwest::StatusCode::OK => {}
-            unexpect => return Err(ClientError::Http(unexpect)),
+            reqwest::StatusCode::UNAUTHORIZED => return Err(ClientError::Unauthorized),
+            unexpect => return Err(error_from_response(&response, unexpect)),
         }
 
-        let r: AuthResponse = serde_json::from_str(response.text().unwrap().as_str()).unwrap();
+        let r: WebauthnListResponse = read_body(&response)?;
+        Ok(r.tokens)
+    }
 
-        match r.state {
-            AuthState::Success(uat) => {
-                debug!("==> Authed as uat; {:?}", uat);
-                Ok(uat)
-            }
-            _ => Err(ClientError::AuthenticationFailed),
+    pub fn webauthn_remove(&self, name: &str) -> Result<(), ClientError> {
+        let dest = format!("{}/v1/self/_credential/webauthn/_remove", self.get_url());
+
+        let response = self.perform_post(dest.as_str(), &WebauthnRemoveRequest::new(name))?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => Ok(()),
+            unexpect => Err(error_from_response(&response, unexpect)),
         }
     }
 
-    // whoami
-    pub fn whoami(&self) -> Result<Option<(Entry, UserAuthToken)>, ClientError> {
-        let whoami_dest = format!("{}/v1/whoami", self.addr);
-        let mut response = self.client.get(whoami_dest.as_str()).send().unwrap();
-        // https://docs.rs/reqwest/0.9.15/reqwest/struct.Response.html
+    // Replace the backup codes of our own account. This is the only time
+    // the codes are given out, so they must be kept by the caller.
+    pub fn backup_codes_generate(&self) -> Result<Vec<String>, ClientError> {
+        let dest = format!(
+            "{}/v1/self/_credential/backup_codes/_generate",
+            self.get_url()
+        );
+
+        let response = self.perform_post(dest.as_str(), &BackupCodesGenerateRequest::new())?;
 
         match response.status() {
-            // Continue to process.
             reqwest::StatusCode::OK => {}
-            reqwest::StatusCode::UNAUTHORIZED => return Ok(None),
-            unexpect => return Err(ClientError::Http(unexpect)),
+            reqwest::StatusCode::UNAUTHORIZED => return Err(ClientError::Unauthorized),
+            unexpect => return Err(error_from_response(&response, unexpect)),
         }
 
-        let r: WhoamiResponse = serde_json::from_str(response.text().unwrap().as_str()).unwrap();
+        let r: BackupCodesGenerateResponse = read_body(&response)?;
+        Ok(r.codes)
+    }
+
+    // Replace the radius secret of our own account, returning the new
+    // secret. Unlike backup codes, it can be read again later.
+    pub fn radius_secret_generate(&self) -> Result<String, ClientError> {
+        let dest = format!("{}/v1/self/_credential/radius/_generate", self.get_url());
 
-        Ok(Some((r.youare, r.uat)))
+        let response = self.perform_post(dest.as_str(), &RadiusSecretGenerateRequest::new())?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => {}
+            reqwest::StatusCode::UNAUTHORIZED => return Err(ClientError::Unauthorized),
+            unexpect => return Err(error_from_response(&response, unexpect)),
+        }
+
+        let r: RadiusSecretGenerateResponse = read_body(&response)?;
+        Ok(r.secret)
     }
 
-    // search
-    pub fn search_str(&self, query: &str) -> Result<Vec<Entry>, ClientError> {
-        let filter: Filter = serde_json::from_str(query).map_err(|e| {
-            error!("JSON Parse Failure -> {:?}", e);
-            ClientError::JsonParse
-        })?;
-        self.search(filter)
+    // What a radius server needs to authenticate the account with this name
+    // or uuid. We may read our own, and radius servers may read any.
+    pub fn radius_auth_token_get(&self, account: &str) -> Result<RadiusAuthToken, ClientError> {
+        let dest = format!("{}/v1/account/{}/_radius/_token", self.get_url(), account);
+
+        let response = self.perform_get(dest.as_str())?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => {}
+            reqwest::StatusCode::UNAUTHORIZED => return Err(ClientError::Unauthorized),
+            unexpect => return Err(error_from_response(&response, unexpect)),
+        }
+
+        read_body(&response)
     }
 
-    pub fn search(&self, filter: Filter) -> Result<Vec<Entry>, ClientError> {
-        let sr = SearchRequest { filter: filter };
-        let dest = format!("{}/v1/search", self.addr);
+    // The ssh public keys of the account with this name, one per line as
+    // sshd reads them. This only needs an anonymous session.
+    pub fn idm_account_get_ssh_pubkeys(&self, account: &str) -> Result<Vec<String>, ClientError> {
+        let dest = format!("{}/v1/account/{}/_ssh_pubkeys", self.get_url(), account);
 
-        let mut response = self
-            .client
-            .post(dest.as_str())
-            .body(serde_json::to_string(&sr).unwrap())
-            .send()
-            .map_err(|e| ClientError::Transport(e))?;
+        let response = self.perform_get(dest.as_str())?;
 
         match response.status() {
             reqwest::StatusCode::OK => {}
-            unexpect => return Err(ClientError::Http(unexpect)),
+            reqwest::StatusCode::UNAUTHORIZED => return Err(ClientError::Unauthorized),
+            unexpect => return Err(error_from_response(&response, unexpect)),
         }
 
-        // TODO: What about errors
-        let sr: SearchResponse = serde_json::from_str(response.text().unwrap().as_str()).unwrap();
-        Ok(sr.entries)
+        read_body(&response)
+    }
+
+    // The ssh public keys of an account with their tags, as "tag: key".
+    pub fn idm_account_list_ssh_pubkeys(&self, account: &str) -> Result<Vec<String>, ClientError> {
+        let entries = self.search(Filter::Eq("name".to_string(), account.to_string()))?;
+        Ok(entries
+            .first()
+            .and_then(|e| e.attrs.get("ssh_publickey"))
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    // Add an ssh public key to an account, by name. The server refuses a key
+    // that sshd wouldn't accept.
+    pub fn idm_account_post_ssh_pubkey(
+        &self,
+        account: &str,
+        tag: &str,
+        pubkey: &str,
+    ) -> Result<(), ClientError> {
+        self.modify(
+            Filter::Eq("name".to_string(), account.to_string()),
+            ModifyList::new_list(vec![Modify::Present(
+                "ssh_publickey".to_string(),
+                format!("{}: {}", tag, pubkey),
+            )]),
+            false,
+        )
+        .map(|_| ())
+    }
+
+    pub fn idm_account_delete_ssh_pubkey(
+        &self,
+        account: &str,
+        tag: &str,
+    ) -> Result<(), ClientError> {
+        self.modify(
+            Filter::Eq("name".to_string(), account.to_string()),
+            ModifyList::new_list(vec![Modify::Removed(
+                "ssh_publickey".to_string(),
+                tag.to_string(),
+            )]),
+            false,
+        )
+        .map(|_| ())
+    }
+
+    // Make an account, by name, a posix account so it may log in to unix
+    // machines. Without a gidnumber, one is generated from its uuid. Giving
+    // one used by another account or group is a DuplicateValue error.
+    pub fn idm_account_unix_extend(
+        &self,
+        id: &str,
+        gidnumber: Option<u32>,
+        shell: Option<&str>,
+    ) -> Result<(), ClientError> {
+        let mut mods = vec![Modify::Present(
+            "class".to_string(),
+            "posixaccount".to_string(),
+        )];
+        if let Some(gid) = gidnumber {
+            mods.push(Modify::Purged("gidnumber".to_string()));
+            mods.push(Modify::Present("gidnumber".to_string(), gid.to_string()));
+        }
+        if let Some(shell) = shell {
+            mods.push(Modify::Purged("loginshell".to_string()));
+            mods.push(Modify::Present("loginshell".to_string(), shell.to_string()));
+        }
+        self.modify(
+            Filter::Eq("name".to_string(), id.to_string()),
+            ModifyList::new_list(mods),
+            false,
+        )
+        .map(|_| ())
     }
 
-    // create
-    pub fn create(&self, entries: Vec<Entry>) -> Result<(), ClientError> {
-        let c = CreateRequest { entries: entries };
+    // As idm_account_unix_extend, for a group.
+    pub fn idm_group_unix_extend(
+        &self,
+        id: &str,
+        gidnumber: Option<u32>,
+    ) -> Result<(), ClientError> {
+        let mut mods = vec![Modify::Present(
+            "class".to_string(),
+            "posixgroup".to_string(),
+        )];
+        if let Some(gid) = gidnumber {
+            mods.push(Modify::Purged("gidnumber".to_string()));
+            mods.push(Modify::Present("gidnumber".to_string(), gid.to_string()));
+        }
+        self.modify(
+            Filter::Eq("name".to_string(), id.to_string()),
+            ModifyList::new_list(mods),
+            false,
+        )
+        .map(|_| ())
+    }
 
-        // TODO: Avoid formatting this so much!
-        let dest = format!("{}/v1/create", self.addr);
+    // The posix account with this name or uuid, as a unix machine resolves
+    // it. This only needs an anonymous session.
+    pub fn idm_account_unix_token_get(&self, id: &str) -> Result<UnixUserToken, ClientError> {
+        let dest = format!("{}/v1/account/{}/_unix/_token", self.get_url(), id);
 
-        let mut response = self
-            .client
-            .post(dest.as_str())
-            .body(serde_json::to_string(&c).unwrap())
-            .send()
-            .map_err(|e| ClientError::Transport(e))?;
+        let response = self.perform_get(dest.as_str())?;
 
         match response.status() {
             reqwest::StatusCode::OK => {}
-            unexpect => return Err(ClientError::Http(unexpect)),
+            reqwest::StatusCode::UNAUTHORIZED => return Err(ClientError::Unauthorized),
+            unexpect => return Err(error_from_response(&response, unexpect)),
         }
 
-        // TODO: What about errors
-        let _r: OperationResponse =
-            serde_json::from_str(response.text().unwrap().as_str()).unwrap();
-        Ok(())
+        read_body(&response)
     }
 
-    // modify
-    //
+    pub fn idm_group_unix_token_get(&self, id: &str) -> Result<UnixGroupToken, ClientError> {
+        let dest = format!("{}/v1/group/{}/_unix/_token", self.get_url(), id);
+
+        let response = self.perform_get(dest.as_str())?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => {}
+            reqwest::StatusCode::UNAUTHORIZED => return Err(ClientError::Unauthorized),
+            unexpect => return Err(error_from_response(&response, unexpect)),
+        }
+
+        read_body(&response)
+    }
+
+    pub fn credential_status(&self) -> Result<CredentialStatusResponse, ClientError> {
+        let dest = format!("{}/v1/self/_credential/_status", self.get_url());
+        let response = self.perform_get(dest.as_str())?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => {}
+            reqwest::StatusCode::UNAUTHORIZED => return Err(ClientError::Unauthorized),
+            unexpect => return Err(error_from_response(&response, unexpect)),
+        }
+
+        read_body(&response)
+    }
+
+    // Set which factors our primary credential requires. None returns to the
+    // policy that follows from the factors it has.
+    pub fn credential_set_policy(
+        &self,
+        policy: Option<CredentialPolicy>,
+    ) -> Result<(), ClientError> {
+        let dest = format!("{}/v1/self/_credential/_policy", self.get_url());
+
+        let response = self.perform_post(dest.as_str(), &CredentialPolicyRequest::new(policy))?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => Ok(()),
+            reqwest::StatusCode::UNAUTHORIZED => Err(ClientError::Unauthorized),
+            unexpect => Err(error_from_response(&response, unexpect)),
+        }
+    }
+
+    fn credential_change(&self, req: &CredentialChangeRequest) -> Result<(), ClientError> {
+        let dest = format!("{}/v1/credential/_change", self.get_url());
+
+        let response = self.perform_post(dest.as_str(), req)?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => Ok(()),
+            unexpect => Err(error_from_response(&response, unexpect)),
+        }
+    }
+
+    // Change the password of our own account. The current password may only
+    // be left out if we authenticated within the last few minutes. Our other
+    // sessions are ended.
+    pub fn idm_account_self_set_password(
+        &self,
+        current: Option<&str>,
+        new: &str,
+    ) -> Result<(), ClientError> {
+        self.credential_change(&CredentialChangeRequest::new_self(current, new))
+    }
+
+    // Reset the password of another account, by name or uuid. With
+    // must_change, they must change it again before doing anything else.
+    pub fn idm_account_set_password(
+        &self,
+        target: &str,
+        new: &str,
+        must_change: bool,
+    ) -> Result<(), ClientError> {
+        self.credential_change(&CredentialChangeRequest::new_admin_reset(
+            target,
+            new,
+            must_change,
+        ))
+    }
+
+    // Set the unix password of a posix account, by name or uuid, which may
+    // be our own. This doesn't change its primary password, or end any
+    // sessions.
+    pub fn idm_account_unix_cred_put(&self, target: &str, new: &str) -> Result<(), ClientError> {
+        self.credential_change(&CredentialChangeRequest::new_unix_password(target, new))
+    }
+
+    // Check the unix password of an account, as a unix machine does for
+    // pam. The token is only given if the password is correct, and there is
+    // no difference between a wrong password and a missing account.
+    pub fn idm_account_unix_cred_verify(
+        &self,
+        account: &str,
+        cred: &str,
+    ) -> Result<Option<UnixUserToken>, ClientError> {
+        let dest = format!("{}/v1/unix/_auth", self.get_url());
+
+        let response = self.perform_post(dest.as_str(), &UnixAuthRequest::new(account, cred))?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => {}
+            reqwest::StatusCode::UNAUTHORIZED => return Err(ClientError::Unauthorized),
+            unexpect => return Err(error_from_response(&response, unexpect)),
+        }
+
+        let r: Option<UnixUserToken> = read_body(&response)?;
+        Ok(r)
+    }
+
+    // End the lock on an account, by name, from too many failed
+    // authentications.
+    pub fn idm_account_unlock(&self, target: &str) -> Result<(), ClientError> {
+        self.modify(
+            Filter::Eq("name".to_string(), target.to_string()),
+            ModifyList::new_list(vec![Modify::Purged("account_locked_until".to_string())]),
+            false,
+        )
+        .map(|_| ())
+    }
+
+    // The valid from and expiry times of an account, by name, as rfc3339
+    // datetimes. None is an open end of the window.
+    pub fn idm_account_get_validity(
+        &self,
+        target: &str,
+    ) -> Result<(Option<String>, Option<String>), ClientError> {
+        let entries = self.search(Filter::Eq("name".to_string(), target.to_string()))?;
+        let get = |attr: &str| {
+            entries
+                .first()
+                .and_then(|e| e.get_ava_single(attr))
+                .map(|s| s.to_string())
+        };
+        Ok((get("account_valid_from"), get("account_expire")))
+    }
+
+    // Set when an account, by name, may begin to authenticate, as an
+    // rfc3339 datetime. None allows it from any time.
+    pub fn idm_account_set_valid_from(
+        &self,
+        target: &str,
+        from: Option<&str>,
+    ) -> Result<(), ClientError> {
+        self.idm_account_set_datetime(target, "account_valid_from", from)
+    }
+
+    // Set when an account, by name, expires, as an rfc3339 datetime. None
+    // means it never does.
+    pub fn idm_account_set_expire(
+        &self,
+        target: &str,
+        expire: Option<&str>,
+    ) -> Result<(), ClientError> {
+        self.idm_account_set_datetime(target, "account_expire", expire)
+    }
+
+    fn idm_account_set_datetime(
+        &self,
+        target: &str,
+        attr: &str,
+        value: Option<&str>,
+    ) -> Result<(), ClientError> {
+        let mut mods = vec![Modify::Purged(attr.to_string())];
+        if let Some(v) = value {
+            mods.push(Modify::Present(attr.to_string(), v.to_string()));
+        }
+        self.modify(
+            Filter::Eq("name".to_string(), target.to_string()),
+            ModifyList::new_list(mods),
+            false,
+        )
+        .map(|_| ())
+    }
+
+    // Create a person account, returning its uuid. It has no credentials
+    // until a password is set for it.
+    pub fn idm_account_create(&self, name: &str, displayname: &str) -> Result<String, ClientError> {
+        let mut attrs = BTreeMap::new();
+        attrs.insert(
+            "class".to_string(),
+            vec!["account".to_string(), "person".to_string()],
+        );
+        attrs.insert("name".to_string(), vec![name.to_string()]);
+        attrs.insert("displayname".to_string(), vec![displayname.to_string()]);
+        self.create(vec![Entry { attrs: attrs }])?
+            .pop()
+            .ok_or(ClientError::JsonParse)
+    }
+
+    pub fn idm_account_get(&self, name: &str) -> Result<Option<Entry>, ClientError> {
+        self.search(account_filter(name)).map(|mut r| r.pop())
+    }
+
+    pub fn idm_account_delete(&self, name: &str) -> Result<(), ClientError> {
+        self.delete(account_filter(name), false)
+            .map(|_| ())
+            .map_err(no_such_account(name))
+    }
+
+    pub fn idm_account_set_displayname(
+        &self,
+        name: &str,
+        displayname: &str,
+    ) -> Result<(), ClientError> {
+        self.modify(
+            account_filter(name),
+            ModifyList::new_list(vec![
+                Modify::Purged("displayname".to_string()),
+                Modify::Present("displayname".to_string(), displayname.to_string()),
+            ]),
+            false,
+        )
+        .map(|_| ())
+        .map_err(no_such_account(name))
+    }
+
+    // Create a group, with no members, returning its uuid.
+    pub fn idm_group_create(&self, name: &str) -> Result<String, ClientError> {
+        let mut attrs = BTreeMap::new();
+        attrs.insert("class".to_string(), vec!["group".to_string()]);
+        attrs.insert("name".to_string(), vec![name.to_string()]);
+        self.create(vec![Entry { attrs: attrs }])?
+            .pop()
+            .ok_or(ClientError::JsonParse)
+    }
+
+    // Add members to a group, by name or uuid. Adding one that is already a
+    // member is not an error.
+    pub fn idm_group_add_members(&self, name: &str, members: &[&str]) -> Result<(), ClientError> {
+        let mods = members
+            .iter()
+            .map(|m| Modify::Present("member".to_string(), m.to_string()))
+            .collect();
+        self.modify(group_filter(name), ModifyList::new_list(mods), false)
+            .map(|_| ())
+            .map_err(no_such_group(name))
+    }
+
+    pub fn idm_group_remove_members(
+        &self,
+        name: &str,
+        members: &[&str],
+    ) -> Result<(), ClientError> {
+        let mods = members
+            .iter()
+            .map(|m| Modify::Removed("member".to_string(), m.to_string()))
+            .collect();
+        self.modify(group_filter(name), ModifyList::new_list(mods), false)
+            .map(|_| ())
+            .map_err(no_such_group(name))
+    }
+
+    // The direct members of a group, by name, sorted. A member we may not
+    // read the name of is given by its uuid.
+    pub fn idm_group_list_members(&self, name: &str) -> Result<Vec<String>, ClientError> {
+        let group = self
+            .search_with_attrs(group_filter(name), vec!["member".to_string()])?
+            .pop()
+            .ok_or_else(|| ClientError::NoSuchGroup(name.to_string()))?;
+        let uuids = match group.attrs.get("member") {
+            Some(uuids) if !uuids.is_empty() => uuids.clone(),
+            _ => return Ok(Vec::new()),
+        };
+
+        let names: BTreeMap<String, String> = self
+            .search_with_attrs(
+                Filter::Inclusion("uuid".to_string(), uuids.clone()),
+                vec!["uuid".to_string(), "name".to_string()],
+            )?
+            .into_iter()
+            .filter_map(|e| {
+                let uuid = e.get_ava_single("uuid")?.to_string();
+                let name = e.get_ava_single("name")?.to_string();
+                Some((uuid, name))
+            })
+            .collect();
+        let mut members: Vec<String> = uuids
+            .into_iter()
+            .map(|u| names.get(&u).cloned().unwrap_or(u))
+            .collect();
+        members.sort();
+        Ok(members)
+    }
+
+    pub fn idm_group_delete(&self, name: &str) -> Result<(), ClientError> {
+        self.delete(group_filter(name), false)
+            .map(|_| ())
+            .map_err(no_such_group(name))
+    }
+
+    // All the access control profiles we are allowed to read.
+    pub fn idm_acp_list(&self) -> Result<Vec<AccessControlProfile>, ClientError> {
+        self.search_sorted(
+            Filter::Eq("class".to_string(), "access_control_profile".to_string()),
+            "name",
+        )?
+        .iter()
+        .map(acp_from_entry)
+        .collect()
+    }
+
+    pub fn idm_acp_get(&self, name: &str) -> Result<Option<AccessControlProfile>, ClientError> {
+        self.search(acp_filter(name))?
+            .first()
+            .map(acp_from_entry)
+            .transpose()
+    }
+
+    // Create an access control profile, returning its uuid. The server
+    // rejects a profile whose filters don't validate, or that names an
+    // attribute or class that isn't in the schema.
+    pub fn idm_acp_create(&self, acp: &AccessControlProfile) -> Result<String, ClientError> {
+        self.create(vec![acp_to_entry(acp)])?
+            .pop()
+            .ok_or(ClientError::JsonParse)
+    }
+
+    // Change the access control profile of the same name to match acp.
+    pub fn idm_acp_update(&self, acp: &AccessControlProfile) -> Result<(), ClientError> {
+        let current = self
+            .search(acp_filter(acp.name.as_str()))?
+            .pop()
+            .ok_or(ClientError::NotFound)?;
+        let mut current_attrs = current.attrs;
+        current_attrs.retain(|k, _| ACP_MANAGED_ATTRS.contains(&k.as_str()));
+        let mut target = acp_to_entry(acp);
+        target.attrs.remove("uuid");
+        if let Some(classes) = target.attrs.get_mut("class") {
+            classes.push("object".to_string());
+        }
+
+        let modlist = Entry {
+            attrs: current_attrs,
+        }
+        .diff(&target);
+        if modlist.mods.is_empty() {
+            return Ok(());
+        }
+        self.modify(acp_filter(acp.name.as_str()), modlist, false)
+            .map(|_| ())
+    }
+
+    pub fn idm_acp_delete(&self, name: &str) -> Result<(), ClientError> {
+        self.delete(acp_filter(name), false).map(|_| ())
+    }
+
+    // Whether receiver, by name or uuid, would be allowed an operation on
+    // each entry matching filter, and which profiles applied. Nothing is
+    // changed. This is only for idm_admins and idm_acp_manager_priv.
+    pub fn access_check(
+        &self,
+        receiver: &str,
+        filter: Filter,
+        operation: AccessCheckOperation,
+    ) -> Result<Vec<AccessCheckEntry>, ClientError> {
+        let ac = AccessCheckRequest::new(receiver, filter, operation);
+        let dest = format!("{}/v1/access/_check", self.get_url());
+
+        let response = self.perform_post(dest.as_str(), &ac)?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => {}
+            unexpect => return Err(error_from_response(&response, unexpect)),
+        }
+
+        let r: AccessCheckResponse = read_body(&response)?;
+        Ok(r.entries)
+    }
+
+    // What the authenticated account may do to each entry matching target
+    // that it can see.
+    pub fn effective_access(&self, target: Filter) -> Result<Vec<EffectiveAccess>, ClientError> {
+        let ea = EffectiveAccessRequest::new(target);
+        let dest = format!("{}/v1/access/_effective", self.get_url());
+
+        let response = self.perform_post(dest.as_str(), &ea)?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => {}
+            unexpect => return Err(error_from_response(&response, unexpect)),
+        }
+
+        let r: EffectiveAccessResponse = read_body(&response)?;
+        Ok(r.entries)
+    }
+
+    // The writes recorded in the audit log, oldest first. The bounds are
+    // rfc3339 times, and the target is the uuid of an entry.
+    pub fn audit_list(
+        &self,
+        since: Option<&str>,
+        until: Option<&str>,
+        target: Option<&str>,
+    ) -> Result<Vec<AuditRecord>, ClientError> {
+        let al = AuditListRequest {
+            since: since.map(|s| s.to_string()),
+            until: until.map(|s| s.to_string()),
+            target: target.map(|s| s.to_string()),
+        };
+        let dest = format!("{}/v1/audit/_list", self.get_url());
+
+        let response = self.perform_post(dest.as_str(), &al)?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => {}
+            unexpect => return Err(error_from_response(&response, unexpect)),
+        }
+
+        let r: AuditListResponse = read_body(&response)?;
+        Ok(r.records)
+    }
+
+    // The entries changed after the change id since, or every change the
+    // server still keeps with none, and the id of the server's latest change
+    // to ask for the changes since next time.
+    pub fn changes_since(
+        &self,
+        since: Option<&str>,
+    ) -> Result<(Vec<ChangedEntry>, Option<String>), ClientError> {
+        // A change id is only digits, hex and hyphens, so needn't be escaped.
+        let dest = match since {
+            Some(s) => format!("{}/v1/changes?since={}", self.get_url(), s),
+            None => format!("{}/v1/changes", self.get_url()),
+        };
+
+        let response = self.perform_get(dest.as_str())?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => {}
+            unexpect => return Err(error_from_response(&response, unexpect)),
+        }
+
+        let r: ChangesResponse = read_body(&response)?;
+        Ok((r.entries, r.cid))
+    }
+
+    // Ask the server to take a backup now. This gives the path on the server
+    // that it was written to.
+    pub fn backup(&self) -> Result<String, ClientError> {
+        let br = BackupRequest::new();
+        let dest = format!("{}/v1/backup", self.get_url());
+        let response = self.perform_post(dest.as_str(), &br)?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => {}
+            unexpect => return Err(error_from_response(&response, unexpect)),
+        }
+
+        let r: BackupResponse = read_body(&response)?;
+        Ok(r.path)
+    }
+
+    // Ask the server to rebuild its indexes. This gives the number of keys
+    // in each index.
+    pub fn reindex(&self) -> Result<BTreeMap<String, usize>, ClientError> {
+        let rr = ReindexRequest::new();
+        let dest = format!("{}/v1/reindex", self.get_url());
+        let response = self.perform_post(dest.as_str(), &rr)?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => {}
+            unexpect => return Err(error_from_response(&response, unexpect)),
+        }
+
+        let r: ReindexResponse = read_body(&response)?;
+        Ok(r.indexes)
+    }
+
+    // Ask the server to vacuum and check its database file.
+    pub fn vacuum(&self) -> Result<(), ClientError> {
+        let vr = VacuumRequest::new();
+        let dest = format!("{}/v1/vacuum", self.get_url());
+        let response = self.perform_post(dest.as_str(), &vr)?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => {}
+            unexpect => return Err(error_from_response(&response, unexpect)),
+        }
+
+        let _: VacuumResponse = read_body(&response)?;
+        Ok(())
+    }
+
+    // Which indexes the schema declares, which exist, and how selective
+    // each one is.
+    pub fn index_status(&self) -> Result<Vec<IndexStatus>, ClientError> {
+        let ir = IndexStatusRequest::new();
+        let dest = format!("{}/v1/index/_status", self.get_url());
+        let response = self.perform_post(dest.as_str(), &ir)?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => {}
+            unexpect => return Err(error_from_response(&response, unexpect)),
+        }
+
+        let r: IndexStatusResponse = read_body(&response)?;
+        Ok(r.indexes)
+    }
+
+    // The known weak passwords that the server refuses to set.
+    pub fn system_password_badlist_get(&self) -> Result<Vec<String>, ClientError> {
+        let entries = self.search(system_config_filter())?;
+        Ok(entries
+            .first()
+            .and_then(|e| e.attrs.get("badlist_password"))
+            .cloned()
+            .unwrap_or_else(Vec::new))
+    }
+
+    pub fn system_password_badlist_append(&self, words: &[&str]) -> Result<(), ClientError> {
+        let mods = normalise_badlist(words)
+            .into_iter()
+            .map(|w| Modify::Present("badlist_password".to_string(), w))
+            .collect();
+        self.modify(system_config_filter(), ModifyList::new_list(mods), false)
+            .map(|_| ())
+    }
+
+    pub fn system_password_badlist_remove(&self, words: &[&str]) -> Result<(), ClientError> {
+        let mods = normalise_badlist(words)
+            .into_iter()
+            .map(|w| Modify::Removed("badlist_password".to_string(), w))
+            .collect();
+        self.modify(system_config_filter(), ModifyList::new_list(mods), false)
+            .map(|_| ())
+    }
+
+    pub fn system_anonymous_set_disabled(&self, disabled: bool) -> Result<(), ClientError> {
+        let mods = vec![
+            Modify::Purged("disable_anonymous".to_string()),
+            Modify::Present("disable_anonymous".to_string(), disabled.to_string()),
+        ];
+        self.modify(system_config_filter(), ModifyList::new_list(mods), false)
+            .map(|_| ())
+    }
+
+    pub fn idm_domain_get(&self) -> Result<DomainInfo, ClientError> {
+        self.search(domain_info_filter())?
+            .first()
+            .ok_or(ClientError::NotFound)
+            .and_then(domain_info_from_entry)
+    }
+
+    pub fn idm_domain_set_display_name(&self, name: &str) -> Result<(), ClientError> {
+        let mods = vec![
+            Modify::Purged("domain_display_name".to_string()),
+            Modify::Present("domain_display_name".to_string(), name.to_string()),
+        ];
+        self.modify(domain_info_filter(), ModifyList::new_list(mods), false)
+            .map(|_| ())
+    }
+
+    // Renaming the domain regenerates the spn of every account and group, so
+    // anything that refers to them by spn must be changed too.
+    pub fn idm_domain_set_name(&self, name: &str) -> Result<(), ClientError> {
+        let mods = vec![
+            Modify::Purged("domain_name".to_string()),
+            Modify::Present("domain_name".to_string(), name.to_string()),
+        ];
+        self.modify(domain_info_filter(), ModifyList::new_list(mods), false)
+            .map(|_| ())
+    }
+
+    // whoami
+    pub fn whoami(&self) -> Result<Option<(Entry, UserAuthToken)>, ClientError> {
+        self.block_on(self.asclient.whoami())
+    }
+
+    // End the current session. The token is no longer accepted by the
+    // server, and we must authenticate again.
+    pub fn logout(&self) -> Result<(), ClientError> {
+        self.block_on(self.asclient.logout())
+    }
+
+    // The active sessions of the account with this name or uuid.
+    pub fn session_list(&self, account: &str) -> Result<Vec<SessionInfo>, ClientError> {
+        let dest = format!("{}/v1/sessions", self.get_url());
+
+        let response = self.perform_post(dest.as_str(), &SessionListRequest::new(account))?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => {}
+            unexpect => return Err(error_from_response(&response, unexpect)),
+        }
+
+        let r: SessionListResponse = read_body(&response)?;
+        Ok(r.sessions)
+    }
+
+    pub fn session_revoke(&self, sessionid: &str) -> Result<(), ClientError> {
+        let dest = format!("{}/v1/sessions/{}/_revoke", self.get_url(), sessionid);
+
+        let response = self.perform_post_empty(dest.as_str())?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => Ok(()),
+            unexpect => Err(error_from_response(&response, unexpect)),
+        }
+    }
+
+    // Generate an api token for the service account with this name or uuid.
+    // The token is only given out here, so it must be kept by the caller.
+    // Without read_write, sessions of the token may only read.
+    pub fn service_account_api_token_generate(
+        &self,
+        account: &str,
+        label: &str,
+        expiry: Option<u64>,
+        read_write: bool,
+    ) -> Result<ApiTokenGenerateResponse, ClientError> {
+        let dest = format!("{}/v1/service_account/_api_token/_generate", self.get_url());
+        let req = ApiTokenGenerateRequest::new(account, label, expiry, read_write);
+
+        let response = self.perform_post(dest.as_str(), &req)?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => {}
+            reqwest::StatusCode::UNAUTHORIZED => return Err(ClientError::Unauthorized),
+            unexpect => return Err(error_from_response(&response, unexpect)),
+        }
+
+        read_body(&response)
+    }
+
+    pub fn service_account_api_token_list(
+        &self,
+        account: &str,
+    ) -> Result<Vec<ApiTokenInfo>, ClientError> {
+        let dest = format!("{}/v1/service_account/_api_token/_list", self.get_url());
+
+        let response = self.perform_post(dest.as_str(), &ApiTokenListRequest::new(account))?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => {}
+            reqwest::StatusCode::UNAUTHORIZED => return Err(ClientError::Unauthorized),
+            unexpect => return Err(error_from_response(&response, unexpect)),
+        }
+
+        let r: ApiTokenListResponse = read_body(&response)?;
+        Ok(r.tokens)
+    }
+
+    // Destroy the api token with this id. Sessions begun with it are ended.
+    pub fn service_account_api_token_destroy(
+        &self,
+        account: &str,
+        id: &str,
+    ) -> Result<(), ClientError> {
+        let dest = format!(
+            "{}/v1/service_account/{}/_api_token/{}/_destroy",
+            self.get_url(),
+            account,
+            id
+        );
+
+        let response = self.perform_post_empty(dest.as_str())?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => Ok(()),
+            reqwest::StatusCode::UNAUTHORIZED => Err(ClientError::Unauthorized),
+            unexpect => Err(error_from_response(&response, unexpect)),
+        }
+    }
+
+    // The public keys the server signs auth tokens with.
+    pub fn jwk(&self) -> Result<JwkSet, ClientError> {
+        let jwk_dest = format!("{}/v1/jwk", self.get_url());
+        let response = self.perform_get(jwk_dest.as_str())?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => {}
+            unexpect => return Err(error_from_response(&response, unexpect)),
+        }
+
+        let r: JwkSet = read_body(&response)?;
+
+        Ok(r)
+    }
+
+    // Authorize an oauth2 client as this session, as a browser sent by the
+    // client would. Gives the location the browser is redirected back to,
+    // which carries the code, or the error if the request was refused.
+    pub fn oauth2_authorize(&self, req: &Oauth2AuthorizeRequest) -> Result<String, ClientError> {
+        let dest = format!("{}/oauth2/authorize", self.get_url());
+        let response = self.perform(self.asclient.client.get(dest.as_str()).query(req))?;
+
+        match response.status() {
+            reqwest::StatusCode::FOUND => response
+                .headers()
+                .get(LOCATION)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string())
+                .ok_or(ClientError::Http(reqwest::StatusCode::FOUND)),
+            unexpect => Err(oauth2_error_from_response(&response, unexpect)),
+        }
+    }
+
+    // Exchange a code for tokens, as the client would. The request is form
+    // encoded, as the rfc requires.
+    pub fn oauth2_token_exchange(
+        &self,
+        req: &Oauth2TokenRequest,
+    ) -> Result<Oauth2TokenResponse, ClientError> {
+        let dest = format!("{}/oauth2/token", self.get_url());
+        let response = self.perform(self.asclient.client.post(dest.as_str()).form(req))?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => read_body(&response),
+            unexpect => Err(oauth2_error_from_response(&response, unexpect)),
+        }
+    }
+
+    // The claims an oauth2 access token grants its bearer.
+    pub fn oauth2_userinfo(&self, access_token: &str) -> Result<Oauth2UserInfo, ClientError> {
+        let dest = format!("{}/oauth2/userinfo", self.get_url());
+        let response = self.perform(
+            self.asclient
+                .client
+                .get(dest.as_str())
+                .header(AUTHORIZATION, format!("Bearer {}", access_token)),
+        )?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => read_body(&response),
+            unexpect => Err(oauth2_error_from_response(&response, unexpect)),
+        }
+    }
+
+    // search
+    pub fn search_str(&self, query: &str) -> Result<Vec<Entry>, ClientError> {
+        let filter: Filter = serde_json::from_str(query).map_err(|e| {
+            error!("JSON Parse Failure -> {:?}", e);
+            ClientError::JsonParse
+        })?;
+        self.search(filter)
+    }
+
+    pub fn search_ldap_str(&self, query: &str) -> Result<Vec<Entry>, ClientError> {
+        let filter = Filter::from_ldap_str(query).map_err(|e| {
+            error!("Filter Parse Failure -> {}", e);
+            ClientError::FilterParse(e)
+        })?;
+        self.search(filter)
+    }
+
+    pub fn search(&self, filter: Filter) -> Result<Vec<Entry>, ClientError> {
+        self.block_on(self.asclient.search(filter))
+    }
+
+    // Search, and ask the server to trace how it answered. The plan is only
+    // returned to admins.
+    pub fn search_traced(
+        &self,
+        filter: Filter,
+    ) -> Result<(Vec<Entry>, Option<SearchPlan>), ClientError> {
+        self.perform_search(SearchRequest::new_traced(filter))
+            .map(|sr| (sr.entries, sr.plan))
+    }
+
+    pub fn search_with_attrs(
+        &self,
+        filter: Filter,
+        attrs: Vec<String>,
+    ) -> Result<Vec<Entry>, ClientError> {
+        self.block_on(self.asclient.search_with_attrs(filter, attrs))
+    }
+
+    // The number of entries matching the filter that we are allowed to read.
+    pub fn search_count(&self, filter: Filter) -> Result<u64, ClientError> {
+        self.perform_search_count(SearchCountRequest::new(filter))
+    }
+
+    // Whether any entry we are allowed to read matches the filter.
+    pub fn exists(&self, filter: Filter) -> Result<bool, ClientError> {
+        self.perform_search_count(SearchCountRequest::new_exists(filter))
+            .map(|c| c > 0)
+    }
+
+    fn perform_search_count(&self, sr: SearchCountRequest) -> Result<u64, ClientError> {
+        let dest = format!("{}/v1/search/count", self.get_url());
+
+        let response = self.perform_post(dest.as_str(), &sr)?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => {}
+            unexpect => return Err(error_from_response(&response, unexpect)),
+        }
+
+        let sr: SearchCountResponse = read_body(&response)?;
+        Ok(sr.count)
+    }
+
+    // Whether the single entry matching filter has value in attr. This only
+    // needs compare access to attr, not read.
+    pub fn compare(&self, filter: Filter, attr: &str, value: &str) -> Result<bool, ClientError> {
+        let cr = CompareRequest::new(filter, attr.to_string(), value.to_string());
+        let dest = format!("{}/v1/compare", self.get_url());
+
+        let response = self.perform_post(dest.as_str(), &cr)?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => {}
+            unexpect => return Err(error_from_response(&response, unexpect)),
+        }
+
+        let r: CompareResponse = read_body(&response)?;
+        Ok(r.matched)
+    }
+
+    // The attribute definitions in the schema.
+    pub fn schema_attribute_list(&self) -> Result<Vec<SchemaAttribute>, ClientError> {
+        self.perform_schema().map(|sr| sr.attributes)
+    }
+
+    // The class definitions in the schema.
+    pub fn schema_class_list(&self) -> Result<Vec<SchemaClass>, ClientError> {
+        self.perform_schema().map(|sr| sr.classes)
+    }
+
+    fn perform_schema(&self) -> Result<SchemaResponse, ClientError> {
+        let dest = format!("{}/v1/schema", self.get_url());
+
+        let response = self.perform_post(dest.as_str(), &SchemaRequest::new())?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => {}
+            unexpect => return Err(error_from_response(&response, unexpect)),
+        }
+
+        let sr: SchemaResponse = read_body(&response)?;
+        Ok(sr)
+    }
+
+    // Search with the results ordered by attr, ascending.
+    pub fn search_sorted(&self, filter: Filter, attr: &str) -> Result<Vec<Entry>, ClientError> {
+        self.perform_search(SearchRequest::new_sorted(
+            filter,
+            attr,
+            SortOrder::Ascending,
+        ))
+        .map(|sr| sr.entries)
+    }
+
+    // Search in pages of page_size entries. The returned iterator requests
+    // each following page from the server as the previous one is consumed.
+    pub fn search_paged(&self, filter: Filter, page_size: u32) -> SearchPaged<'_> {
+        SearchPaged {
+            client: self,
+            filter: filter,
+            page_size: page_size,
+            next_cookie: None,
+            entries: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    // Search, reading each entry as the server sends it rather than waiting
+    // for them all. An error after the first entry is given by the iterator,
+    // and ends it.
+    pub fn search_stream(&self, filter: Filter) -> Result<SearchStream, ClientError> {
+        let sr = SearchRequest::new(filter);
+        let dest = format!("{}/v1/search/_stream", self.get_url());
+
+        let response = self.block_on(self.asclient.send_post(dest.as_str(), &sr))?;
+        let response = self.stream_ok(response)?;
+
+        let cbor = is_cbor_stream(response.headers());
+        Ok(SearchStream {
+            reader: BufReader::new(BodyReader::new(response)),
+            cbor: cbor,
+            done: false,
+        })
+    }
+
+    // Export the entries changed since the cursor, or every entry this
+    // account may read without one. The records are read as the server sends
+    // them, and once they are all read the stream holds the cursor to export
+    // since next time. If the changes since the cursor are no longer kept,
+    // this fails with ChangelogTrimmed, and only a full export is complete.
+    pub fn export(&self, since: Option<&str>) -> Result<ExportStream, ClientError> {
+        // As for changes_since, a cursor needn't be escaped.
+        let dest = match since {
+            Some(s) => format!("{}/v1/export?since={}", self.get_url(), s),
+            None => format!("{}/v1/export", self.get_url()),
+        };
+
+        let response = self.block_on(self.asclient.send_get(dest.as_str()))?;
+        let response = self.stream_ok(response)?;
+
+        let cbor = is_cbor_stream(response.headers());
+        Ok(ExportStream {
+            reader: BufReader::new(BodyReader::new(response)),
+            cbor: cbor,
+            done: false,
+            cursor: None,
+        })
+    }
+
+    fn perform_search(&self, sr: SearchRequest) -> Result<SearchResponse, ClientError> {
+        self.block_on(self.asclient.perform_search(sr))
+    }
+
+    // create, returning the uuids of the new entries in the order given.
+    pub fn create(&self, entries: Vec<Entry>) -> Result<Vec<String>, ClientError> {
+        self.block_on(self.asclient.create(entries))
+    }
+
+    // modify, returning the number of entries changed. Unless allow_empty is
+    // set, a filter that matches nothing is an error.
+    pub fn modify(
+        &self,
+        filter: Filter,
+        modlist: ModifyList,
+        allow_empty: bool,
+    ) -> Result<u64, ClientError> {
+        self.block_on(self.asclient.modify(filter, modlist, allow_empty))
+    }
+
+    // Apply a set of modifications atomically, returning the number of entries
+    // each one changed. If any change fails, none are applied, and the server
+    // reports which change failed.
+    pub fn modify_batch(
+        &self,
+        changes: Vec<(Filter, ModifyList)>,
+    ) -> Result<Vec<u64>, ClientError> {
+        let mb = ModifyBatchRequest::new(changes);
+        let dest = format!("{}/v1/modify/batch", self.get_url());
+
+        let response = self.perform_post(dest.as_str(), &mb)?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => {}
+            unexpect => return Err(error_from_response(&response, unexpect)),
+        }
+
+        let r: ModifyBatchResponse = read_body(&response)?;
+        Ok(r.modified)
+    }
+
+    // delete, returning the number of entries removed. Unless allow_empty is
+    // set, a filter that matches nothing is an error.
+    pub fn delete(&self, filter: Filter, allow_empty: bool) -> Result<u64, ClientError> {
+        self.block_on(self.asclient.delete(filter, allow_empty))
+    }
+
+    // Search the recycle bin. Each entry carries the time it was deleted.
+    pub fn recycle_bin_list(&self, filter: Filter) -> Result<Vec<Entry>, ClientError> {
+        let sr = SearchRecycledRequest::new(filter);
+        let dest = format!("{}/v1/recycle_bin", self.get_url());
+
+        let response = self.perform_post(dest.as_str(), &sr)?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => {}
+            unexpect => return Err(error_from_response(&response, unexpect)),
+        }
+
+        let r: SearchRecycledResponse = read_body(&response)?;
+        Ok(r.entries)
+    }
+
+    // Revive a recycled entry by uuid, returning the uuids that were revived.
+    pub fn recycle_bin_revive(&self, uuid: &str) -> Result<Vec<String>, ClientError> {
+        let dest = format!("{}/v1/recycle_bin/{}/_revive", self.get_url(), uuid);
+
+        let response = self.perform_post_empty(dest.as_str())?;
+
+        match response.status() {
+            reqwest::StatusCode::OK => {}
+            unexpect => return Err(error_from_response(&response, unexpect)),
+        }
+
+        let r: ReviveRecycledResponse = read_body(&response)?;
+        Ok(r.revived)
+    }
+}
+
+#[derive(Debug)]
+pub struct SearchPaged<'a> {
+    client: &'a KanidmClient,
+    filter: Filter,
+    page_size: u32,
+    next_cookie: Option<String>,
+    entries: VecDeque<Entry>,
+    done: bool,
+}
+
+impl<'a> Iterator for SearchPaged<'a> {
+    type Item = Result<Entry, ClientError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(e) = self.entries.pop_front() {
+                return Some(Ok(e));
+            }
+            if self.done {
+                return None;
+            }
+
+            let sr = SearchRequest::new_paged(
+                self.filter.clone(),
+                self.page_size,
+                self.next_cookie.take(),
+            );
+            match self.client.perform_search(sr) {
+                Ok(sr) => {
+                    self.done = sr.next_cookie.is_none();
+                    self.next_cookie = sr.next_cookie;
+                    self.entries.extend(sr.entries);
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+// The body of a streamed response, read as the server sends it. Each chunk
+// is waited for as it's needed, while the runtime of the client drives the
+// connection.
+struct BodyReader {
+    chunks: futures::stream::Wait<reqwest::r#async::Decoder>,
+    chunk: Option<reqwest::r#async::Chunk>,
+    pos: usize,
+}
+
+impl BodyReader {
+    fn new(response: reqwest::r#async::Response) -> Self {
+        BodyReader {
+            chunks: response.into_body().wait(),
+            chunk: None,
+            pos: 0,
+        }
+    }
+}
+
+impl std::fmt::Debug for BodyReader {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("BodyReader")
+            .field("pos", &self.pos)
+            .finish()
+    }
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(chunk) = self.chunk.as_ref() {
+                if self.pos < chunk.len() {
+                    let n = buf.len().min(chunk.len() - self.pos);
+                    buf[..n].copy_from_slice(&chunk[self.pos..self.pos + n]);
+                    self.pos += n;
+                    return Ok(n);
+                }
+            }
+            match self.chunks.next() {
+                Some(Ok(chunk)) => {
+                    self.chunk = Some(chunk);
+                    self.pos = 0;
+                }
+                Some(Err(e)) => return Err(io::Error::new(io::ErrorKind::Other, e)),
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SearchStream {
+    reader: BufReader<BodyReader>,
+    cbor: bool,
+    done: bool,
+}
+
+fn is_cbor_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.starts_with(SEARCH_STREAM_CBOR))
+        .unwrap_or(false)
+}
+
+// Read the next record of a stream, framed as the proto describes.
+fn read_stream_item<T: DeserializeOwned>(
+    reader: &mut BufReader<BodyReader>,
+    cbor: bool,
+) -> Result<T, ClientError> {
+    if cbor {
+        let mut len = [0; 4];
+        reader
+            .read_exact(&mut len)
+            .map_err(|_| ClientError::StreamTruncated)?;
+        let mut body = vec![0; u32::from_be_bytes(len) as usize];
+        reader
+            .read_exact(&mut body)
+            .map_err(|_| ClientError::StreamTruncated)?;
+        serde_cbor::from_slice(&body).map_err(|_| ClientError::JsonParse)
+    } else {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) | Err(_) => return Err(ClientError::StreamTruncated),
+            Ok(_) => {}
+        }
+        serde_json::from_str(&line).map_err(|_| ClientError::JsonParse)
+    }
+}
+
+impl Iterator for SearchStream {
+    type Item = Result<Entry, ClientError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match read_stream_item(&mut self.reader, self.cbor) {
+            Ok(SearchStreamItem::Entry(e)) => Some(Ok(e)),
+            Ok(SearchStreamItem::Done) => {
+                self.done = true;
+                None
+            }
+            Ok(SearchStreamItem::Error(err)) => {
+                self.done = true;
+                Some(Err(client_error(err, reqwest::StatusCode::OK)))
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+// One record of an export: an entry as it is now, or the uuid of an entry
+// that was deleted.
+#[derive(Debug)]
+pub enum ExportRecord {
+    Entry(Entry),
+    Deleted(String),
+}
+
+#[derive(Debug)]
+pub struct ExportStream {
+    reader: BufReader<BodyReader>,
+    cbor: bool,
+    done: bool,
+    cursor: Option<String>,
+}
+
+impl ExportStream {
+    // The cursor to export since next time. This is only known once every
+    // record has been read, and is None if the export failed.
+    pub fn cursor(&self) -> Option<&str> {
+        self.cursor.as_ref().map(|s| s.as_str())
+    }
+}
+
+impl Iterator for ExportStream {
+    type Item = Result<ExportRecord, ClientError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match read_stream_item(&mut self.reader, self.cbor) {
+            Ok(ExportStreamItem::Entry(e)) => Some(Ok(ExportRecord::Entry(e))),
+            Ok(ExportStreamItem::Deleted(u)) => Some(Ok(ExportRecord::Deleted(u))),
+            Ok(ExportStreamItem::Done(cursor)) => {
+                self.done = true;
+                self.cursor = cursor;
+                None
+            }
+            Ok(ExportStreamItem::Error(err)) => {
+                self.done = true;
+                Some(Err(client_error(err, reqwest::StatusCode::OK)))
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
 }
@@ -0,0 +1,172 @@
+#![deny(warnings)]
+
+extern crate actix;
+use actix::prelude::*;
+
+extern crate kanidm;
+extern crate kanidm_client;
+extern crate kanidm_proto;
+extern crate serde_json;
+extern crate tokio;
+
+use kanidm_client::{ClientError, KanidmAsyncClient, KanidmClient, KanidmClientBuilder};
+
+use kanidm::config::{Configuration, IntegrationTestConfig};
+use kanidm::core::create_server_core;
+use kanidm_proto::v1::{Entry, Filter, Modify, ModifyList};
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use tokio::runtime::Runtime;
+
+extern crate env_logger;
+
+static PORT_ALLOC: AtomicUsize = AtomicUsize::new(21080);
+static ADMIN_TEST_PASSWORD: &'static str = "integration test admin password";
+
+fn run_test(test_fn: fn(KanidmAsyncClient, Runtime) -> ()) {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let (tx, rx) = mpsc::channel();
+    let port = PORT_ALLOC.fetch_add(1, Ordering::SeqCst);
+
+    let int_config = Box::new(IntegrationTestConfig {
+        admin_password: ADMIN_TEST_PASSWORD.to_string(),
+    });
+
+    let mut config = Configuration::new();
+    config.address = format!("127.0.0.1:{}", port);
+    config.secure_cookies = false;
+    config.integration_test_config = Some(int_config);
+
+    thread::spawn(move || {
+        System::run(move || {
+            create_server_core(config);
+            let _ = tx.send(System::current());
+        });
+    });
+    let sys = rx.recv().unwrap();
+    System::set_current(sys.clone());
+
+    let addr = format!("http://127.0.0.1:{}", port);
+    let asclient = KanidmClientBuilder::new()
+        .address(addr.as_str())
+        .build_async()
+        .expect("Failed to build client");
+    let rt = Runtime::new().expect("Failed to start runtime");
+
+    test_fn(asclient, rt);
+
+    let _ = sys.stop();
+}
+
+#[test]
+fn test_async_auth_whoami() {
+    run_test(|asclient: KanidmAsyncClient, mut rt: Runtime| {
+        assert!(rt.block_on(asclient.whoami()).unwrap().is_none());
+
+        match rt.block_on(asclient.auth_simple_password("admin", "wrong password")) {
+            Err(ClientError::AuthenticationFailed) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        let uat = rt
+            .block_on(asclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD))
+            .expect("Failed to auth");
+        let (e, whoami_uat) = rt.block_on(asclient.whoami()).unwrap().expect("No session");
+        assert!(whoami_uat.uuid == uat.uuid);
+        assert!(e.attrs.get("name") == Some(&vec!["admin".to_string()]));
+
+        // A clone carries the same session.
+        let other = asclient.clone();
+        assert!(rt.block_on(other.logout()).is_ok());
+        assert!(rt.block_on(asclient.whoami()).unwrap().is_none());
+
+        let uat = rt
+            .block_on(asclient.auth_anonymous())
+            .expect("Failed to auth");
+        assert!(uat.name == "anonymous");
+    });
+}
+
+#[test]
+fn test_async_create_search_modify_delete() {
+    run_test(|asclient: KanidmAsyncClient, mut rt: Runtime| {
+        rt.block_on(asclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD))
+            .expect("Failed to auth");
+
+        let e: Entry = serde_json::from_str(
+            r#"{"attrs": {"class": ["group"], "name": ["async_group"], "description": ["before"]}}"#,
+        )
+        .unwrap();
+        let uuids = rt.block_on(asclient.create(vec![e])).unwrap();
+        assert!(uuids.len() == 1);
+
+        let filter = Filter::Eq("name".to_string(), "async_group".to_string());
+        let entries = rt.block_on(asclient.search(filter.clone())).unwrap();
+        assert!(entries.len() == 1);
+
+        let modified = rt
+            .block_on(asclient.modify(
+                filter.clone(),
+                ModifyList::new_list(vec![Modify::Set(
+                    "description".to_string(),
+                    vec!["after".to_string()],
+                )]),
+                false,
+            ))
+            .unwrap();
+        assert!(modified == 1);
+        let entries = rt
+            .block_on(asclient.search_with_attrs(filter.clone(), vec!["description".to_string()]))
+            .unwrap();
+        assert!(entries[0].attrs.get("description") == Some(&vec!["after".to_string()]));
+
+        assert!(rt.block_on(asclient.delete(filter.clone(), false)).unwrap() == 1);
+        assert!(rt
+            .block_on(asclient.search(filter.clone()))
+            .unwrap()
+            .is_empty());
+        match rt.block_on(asclient.delete(filter, false)) {
+            Err(ClientError::NotFound) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+    });
+}
+
+#[test]
+fn test_async_shares_blocking_session() {
+    run_test(|asclient: KanidmAsyncClient, mut rt: Runtime| {
+        let rsclient = KanidmClient::new(asclient.get_url(), None);
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+
+        // The async client of a blocking one carries on from its auth.
+        let shared = rsclient.async_client().clone();
+        let (_, uat) = rt.block_on(shared.whoami()).unwrap().expect("No session");
+        assert!(uat.name == "admin");
+
+        // And the other way around.
+        assert!(rt.block_on(shared.logout()).is_ok());
+        assert!(rsclient.whoami().unwrap().is_none());
+
+        // A separately built client has a session of its own.
+        assert!(rt.block_on(asclient.whoami()).unwrap().is_none());
+
+        // Json is used throughout when asked for.
+        let json_client = KanidmClientBuilder::new()
+            .address(asclient.get_url())
+            .prefer_json()
+            .build_async()
+            .expect("Failed to build client");
+        assert!(!json_client.uses_cbor());
+        rt.block_on(json_client.auth_simple_password("admin", ADMIN_TEST_PASSWORD))
+            .expect("Failed to auth");
+        assert!(!rt
+            .block_on(json_client.search(Filter::Eq("name".to_string(), "admin".to_string())))
+            .unwrap()
+            .is_empty());
+        assert!(!json_client.uses_cbor());
+    });
+}
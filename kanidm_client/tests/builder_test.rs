@@ -0,0 +1,437 @@
+#![deny(warnings)]
+
+extern crate actix;
+use actix::prelude::*;
+
+extern crate kanidm;
+extern crate kanidm_client;
+extern crate libc;
+extern crate openssl;
+
+use kanidm_client::{ClientError, KanidmClientBuilder};
+
+use kanidm::config::{Configuration, IntegrationTestConfig, TlsConfiguration};
+use kanidm::core::create_server_core;
+
+use openssl::asn1::Asn1Time;
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::ssl::{SslConnector, SslMethod, SslStream};
+use openssl::x509::extension::{
+    BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAlternativeName,
+};
+use openssl::x509::{X509Builder, X509Name, X509NameBuilder, X509};
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+extern crate env_logger;
+
+static PORT_ALLOC: AtomicUsize = AtomicUsize::new(22080);
+static ADMIN_TEST_PASSWORD: &'static str = "integration test admin password";
+
+// A directory of our own for the files of a test.
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("kanidm_client_{}", name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).expect("Failed to create test dir");
+    dir
+}
+
+fn write_file(dir: &Path, name: &str, contents: &[u8]) -> String {
+    let path = dir.join(name);
+    fs::write(&path, contents).expect("Failed to write test file");
+    path.to_str().unwrap().to_string()
+}
+
+fn generate_key() -> PKey<Private> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+}
+
+fn cn_name(cn: &str) -> X509Name {
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", cn).unwrap();
+    name.build()
+}
+
+fn cert_builder(serial: u32, subject: &X509Name, key: &PKey<Private>) -> X509Builder {
+    let mut builder = X509Builder::new().unwrap();
+    builder.set_version(2).unwrap();
+    builder
+        .set_serial_number(&BigNum::from_u32(serial).unwrap().to_asn1_integer().unwrap())
+        .unwrap();
+    builder.set_subject_name(subject).unwrap();
+    builder.set_pubkey(key).unwrap();
+    builder
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    builder
+        .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    builder
+}
+
+// A certificate for localhost signed by the ca, and its key.
+fn generate_leaf(ca: &X509, ca_key: &PKey<Private>, serial: u32) -> (X509, PKey<Private>) {
+    let key = generate_key();
+    let mut cert = cert_builder(serial, &cn_name("localhost"), &key);
+    cert.set_issuer_name(ca.subject_name()).unwrap();
+    cert.append_extension(BasicConstraints::new().build().unwrap())
+        .unwrap();
+    cert.append_extension(
+        KeyUsage::new()
+            .critical()
+            .digital_signature()
+            .key_encipherment()
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+    cert.append_extension(ExtendedKeyUsage::new().server_auth().build().unwrap())
+        .unwrap();
+    let san = SubjectAlternativeName::new()
+        .dns("localhost")
+        .build(&cert.x509v3_context(Some(&**ca), None))
+        .unwrap();
+    cert.append_extension(san).unwrap();
+    cert.sign(ca_key, MessageDigest::sha256()).unwrap();
+    (cert.build(), key)
+}
+
+// The files of a ca, and of a certificate for localhost signed by it, as the
+// insecure tls script would make. The ca and its key are kept, to sign a
+// renewed certificate.
+struct TestTls {
+    ca: X509,
+    ca_key: PKey<Private>,
+    ca_path: String,
+    cert_path: String,
+    key_path: String,
+}
+
+impl TestTls {
+    fn write_leaf(&self, cert: &X509, key: &PKey<Private>) {
+        fs::write(&self.cert_path, &cert.to_pem().unwrap()).expect("Failed to write cert");
+        fs::write(&self.key_path, &key.private_key_to_pem_pkcs8().unwrap())
+            .expect("Failed to write key");
+    }
+}
+
+fn generate_tls(dir: &Path) -> TestTls {
+    let ca_key = generate_key();
+    let ca_name = cn_name("insecure.ca.localhost");
+    let mut ca = cert_builder(1, &ca_name, &ca_key);
+    ca.set_issuer_name(&ca_name).unwrap();
+    ca.append_extension(BasicConstraints::new().critical().ca().build().unwrap())
+        .unwrap();
+    ca.append_extension(
+        KeyUsage::new()
+            .critical()
+            .key_cert_sign()
+            .crl_sign()
+            .build()
+            .unwrap(),
+    )
+    .unwrap();
+    ca.sign(&ca_key, MessageDigest::sha256()).unwrap();
+    let ca: X509 = ca.build();
+
+    let (cert, key) = generate_leaf(&ca, &ca_key, 2);
+    let tls = TestTls {
+        ca_path: write_file(dir, "ca.pem", &ca.to_pem().unwrap()),
+        cert_path: dir.join("cert.pem").to_str().unwrap().to_string(),
+        key_path: dir.join("key.pem").to_str().unwrap().to_string(),
+        ca: ca,
+        ca_key: ca_key,
+    };
+    tls.write_leaf(&cert, &key);
+    tls
+}
+
+// Run a server with tls, giving the test its url and its tls files.
+fn run_tls_test(test_fn: fn(&str, &TestTls) -> ()) {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let (tx, rx) = mpsc::channel();
+    let port = PORT_ALLOC.fetch_add(1, Ordering::SeqCst);
+
+    let dir = test_dir(format!("tls_{}", port).as_str());
+    let tls = generate_tls(&dir);
+
+    let int_config = Box::new(IntegrationTestConfig {
+        admin_password: ADMIN_TEST_PASSWORD.to_string(),
+    });
+
+    let mut config = Configuration::new();
+    config.address = format!("127.0.0.1:{}", port);
+    config.integration_test_config = Some(int_config);
+    config.tls_config = Some(TlsConfiguration {
+        ca: tls.ca_path.clone(),
+        cert: tls.cert_path.clone(),
+        key: tls.key_path.clone(),
+        min_version: None,
+    });
+
+    thread::spawn(move || {
+        System::run(move || {
+            create_server_core(config);
+            let _ = tx.send(System::current());
+        });
+    });
+    let sys = rx.recv().unwrap();
+    System::set_current(sys.clone());
+
+    // The certificate is for localhost, not the address.
+    let addr = format!("https://localhost:{}", port);
+    test_fn(addr.as_str(), &tls);
+
+    let _ = sys.stop();
+    let _ = fs::remove_dir_all(&dir);
+}
+
+// A connection to the server that trusts only the ca.
+fn connect_tls(addr: &str, ca: &str) -> SslStream<TcpStream> {
+    let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+    connector.set_ca_file(ca).unwrap();
+    let stream = TcpStream::connect(addr.trim_start_matches("https://"))
+        .expect("Failed to connect to server");
+    connector
+        .build()
+        .connect("localhost", stream)
+        .expect("Failed tls handshake")
+}
+
+fn presented_cert(addr: &str, ca: &str) -> Vec<u8> {
+    connect_tls(addr, ca)
+        .ssl()
+        .peer_certificate()
+        .expect("No certificate presented")
+        .to_der()
+        .unwrap()
+}
+
+#[test]
+fn test_builder_custom_ca() {
+    run_tls_test(|addr: &str, tls: &TestTls| {
+        let ca = tls.ca_path.as_str();
+        let rsclient = KanidmClientBuilder::new()
+            .address(addr)
+            .add_root_certificate(ca)
+            .build()
+            .expect("Failed to build client");
+        assert!(rsclient.auth_anonymous().is_ok());
+
+        // The ca isn't trusted by the system.
+        let rsclient = KanidmClientBuilder::new()
+            .address(addr)
+            .build()
+            .expect("Failed to build client");
+        match rsclient.auth_anonymous() {
+            Err(ClientError::Transport(_)) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        // Unless nothing is checked at all.
+        let rsclient = KanidmClientBuilder::new()
+            .address(addr)
+            .danger_accept_invalid_certs(true)
+            .build()
+            .expect("Failed to build client");
+        assert!(rsclient.auth_anonymous().is_ok());
+
+        // The same can be given by a config file.
+        let dir = test_dir("tls_config");
+        let config = write_file(
+            &dir,
+            "config",
+            format!("uri = \"{}\"\nca_path = \"{}\"\n", addr, ca).as_bytes(),
+        );
+        let rsclient = KanidmClientBuilder::new()
+            .read_options_from_optional_config(config.as_str())
+            .expect("Failed to read config")
+            .build()
+            .expect("Failed to build client");
+        assert!(rsclient.get_url() == addr);
+        assert!(rsclient.auth_anonymous().is_ok());
+        let _ = fs::remove_dir_all(&dir);
+    });
+}
+
+#[test]
+fn test_builder_invalid_ca() {
+    let dir = test_dir("invalid_ca");
+    let ca = write_file(&dir, "ca.pem", b"not a certificate");
+    match KanidmClientBuilder::new()
+        .address("https://localhost:8443")
+        .add_root_certificate(ca.as_str())
+        .build()
+    {
+        Err(ClientError::Config(msg)) => assert!(msg.contains(ca.as_str())),
+        r => panic!("Unexpected result {:?}", r),
+    }
+
+    let missing = dir.join("missing.pem");
+    let missing = missing.to_str().unwrap();
+    match KanidmClientBuilder::new()
+        .address("https://localhost:8443")
+        .add_root_certificate(missing)
+        .build_async()
+    {
+        Err(ClientError::Config(msg)) => assert!(msg.contains(missing)),
+        r => panic!("Unexpected result {:?}", r),
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_builder_config_precedence() {
+    let dir = test_dir("config_precedence");
+    let system = write_file(
+        &dir,
+        "system",
+        b"uri = \"https://system.example.com\"\nca_path = \"/nonexistent/system_ca.pem\"\n",
+    );
+    let user = write_file(
+        &dir,
+        "user",
+        b"uri = \"https://user.example.com\"\nrequest_timeout = 30\n",
+    );
+    let read = |builder: KanidmClientBuilder, paths: &[&str]| {
+        paths.iter().fold(builder, |b, p| {
+            b.read_options_from_optional_config(p)
+                .expect("Failed to read config")
+        })
+    };
+
+    // What the user config doesn't give is taken from the system config.
+    match read(
+        KanidmClientBuilder::new(),
+        &[user.as_str(), system.as_str()],
+    )
+    .build()
+    {
+        Err(ClientError::Config(msg)) => assert!(msg.contains("/nonexistent/system_ca.pem")),
+        r => panic!("Unexpected result {:?}", r),
+    }
+
+    let ca_free = write_file(
+        &dir,
+        "system_no_ca",
+        b"uri = \"https://system.example.com\"\n",
+    );
+    // The user config is used over the system config, as the default config
+    // is read.
+    let rsclient = read(
+        KanidmClientBuilder::new(),
+        &[user.as_str(), ca_free.as_str()],
+    )
+    .build()
+    .expect("Failed to build client");
+    assert!(rsclient.get_url() == "https://user.example.com");
+    let rsclient = read(KanidmClientBuilder::new(), &[ca_free.as_str()])
+        .build()
+        .expect("Failed to build client");
+    assert!(rsclient.get_url() == "https://system.example.com");
+
+    // An explicit option is used over both, whether it's given before or
+    // after they're read.
+    let rsclient = read(
+        KanidmClientBuilder::new().address("https://explicit.example.com"),
+        &[user.as_str(), ca_free.as_str()],
+    )
+    .build()
+    .expect("Failed to build client");
+    assert!(rsclient.get_url() == "https://explicit.example.com");
+    let rsclient = read(
+        KanidmClientBuilder::new(),
+        &[user.as_str(), ca_free.as_str()],
+    )
+    .address("https://explicit.example.com")
+    .build()
+    .expect("Failed to build client");
+    assert!(rsclient.get_url() == "https://explicit.example.com");
+
+    // A missing config is skipped, but a broken one is an error.
+    let missing = dir.join("missing");
+    let rsclient = read(
+        KanidmClientBuilder::new(),
+        &[missing.to_str().unwrap(), ca_free.as_str()],
+    )
+    .build()
+    .expect("Failed to build client");
+    assert!(rsclient.get_url() == "https://system.example.com");
+    let broken = write_file(&dir, "broken", b"uri = ");
+    match KanidmClientBuilder::new().read_options_from_optional_config(broken.as_str()) {
+        Err(ClientError::Config(msg)) => assert!(msg.contains(broken.as_str())),
+        r => panic!("Unexpected result {:?}", r),
+    }
+
+    // Without an address from anywhere, there is nothing to connect to.
+    match KanidmClientBuilder::new().build() {
+        Err(ClientError::Config(_)) => {}
+        r => panic!("Unexpected result {:?}", r),
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_tls_reload() {
+    run_tls_test(|addr: &str, tls: &TestTls| {
+        let ca = tls.ca_path.as_str();
+        let first = fs::read(&tls.cert_path).unwrap();
+        let first = X509::from_pem(&first).unwrap().to_der().unwrap();
+        assert!(presented_cert(addr, ca) == first);
+
+        // Renew the certificate, as an acme client would, while a
+        // connection is open.
+        let mut open = connect_tls(addr, ca);
+        let (cert, key) = generate_leaf(&tls.ca, &tls.ca_key, 3);
+        tls.write_leaf(&cert, &key);
+        let renewed = cert.to_der().unwrap();
+
+        // Nothing changes until the server is told to reload.
+        assert!(presented_cert(addr, ca) == first);
+        unsafe {
+            libc::kill(libc::getpid(), libc::SIGHUP);
+        }
+        // The reload happens on the server's thread.
+        let reloaded = (0..50).any(|_| {
+            if presented_cert(addr, ca) == renewed {
+                true
+            } else {
+                thread::sleep(std::time::Duration::from_millis(100));
+                false
+            }
+        });
+        assert!(reloaded);
+
+        // The connection from before the reload is still served.
+        open.write_all(b"GET /v1/jwk HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        let _ = open.read_to_string(&mut response);
+        assert!(response.starts_with("HTTP/1.1 200"));
+
+        // A renewal that can't be used is refused, and the certificate that
+        // was loaded before is kept.
+        let (_, other_key) = generate_leaf(&tls.ca, &tls.ca_key, 4);
+        fs::write(
+            &tls.key_path,
+            &other_key.private_key_to_pem_pkcs8().unwrap(),
+        )
+        .unwrap();
+        unsafe {
+            libc::kill(libc::getpid(), libc::SIGHUP);
+        }
+        thread::sleep(std::time::Duration::from_millis(500));
+        assert!(presented_cert(addr, ca) == renewed);
+    });
+}
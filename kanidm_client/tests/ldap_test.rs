@@ -0,0 +1,206 @@
+#![deny(warnings)]
+
+extern crate actix;
+use actix::prelude::*;
+
+extern crate kanidm;
+extern crate kanidm_client;
+extern crate kanidm_proto;
+extern crate serde_json;
+
+use kanidm_client::KanidmClient;
+
+use kanidm::config::{Configuration, IntegrationTestConfig};
+use kanidm::core::create_server_core;
+use kanidm_proto::v1::Entry;
+
+extern crate ldap3;
+use ldap3::{LdapConn, Scope, SearchEntry, SearchOptions};
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+extern crate env_logger;
+
+// Each test takes two ports, for the api and for ldap.
+static PORT_ALLOC: AtomicUsize = AtomicUsize::new(18080);
+static ADMIN_TEST_PASSWORD: &'static str = "integration test admin password";
+static LDAP_TEST_PASSWORD: &'static str = "a ldap test password";
+
+// The default domain is localhost, so this is the base of every dn.
+static BASEDN: &'static str = "dc=localhost";
+
+fn run_test(test_fn: fn(KanidmClient, String) -> ()) {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let (tx, rx) = mpsc::channel();
+    let port = PORT_ALLOC.fetch_add(2, Ordering::SeqCst);
+
+    let int_config = Box::new(IntegrationTestConfig {
+        admin_password: ADMIN_TEST_PASSWORD.to_string(),
+    });
+
+    let mut config = Configuration::new();
+    config.address = format!("127.0.0.1:{}", port);
+    // Without tls, which is only allowed as this is an integration test.
+    config.ldapaddress = Some(format!("127.0.0.1:{}", port + 1));
+    config.secure_cookies = false;
+    config.integration_test_config = Some(int_config);
+
+    thread::spawn(move || {
+        System::run(move || {
+            create_server_core(config);
+            let _ = tx.send(System::current());
+        });
+    });
+    let sys = rx.recv().unwrap();
+    System::set_current(sys.clone());
+
+    let addr = format!("http://127.0.0.1:{}", port);
+    let rsclient = KanidmClient::new(addr.as_str(), None);
+
+    test_fn(rsclient, format!("ldap://127.0.0.1:{}", port + 1));
+
+    let _ = sys.stop();
+}
+
+fn search(ldap: &LdapConn, base: &str, scope: Scope, filter: &str) -> (u32, Vec<SearchEntry>) {
+    let r = ldap
+        .search(base, scope, filter, vec!["*"])
+        .expect("Failed to search");
+    let entries = r.0.into_iter().map(SearchEntry::construct).collect();
+    (r.1.rc, entries)
+}
+
+fn setup_accounts(rsclient: &KanidmClient) {
+    rsclient
+        .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+        .expect("Failed to auth");
+    let entries: Vec<Entry> = vec![
+        r#"{"attrs": {"class": ["person", "account"], "name": ["ldapuser"], "displayname": ["Ldap User"]}}"#,
+        r#"{"attrs": {"class": ["person", "account"], "name": ["ldapreset"], "displayname": ["Ldap Reset"]}}"#,
+        r#"{"attrs": {"class": ["group"], "name": ["ldapgroup"], "member": ["ldapuser"]}}"#,
+    ]
+    .into_iter()
+    .map(|e| serde_json::from_str(e).unwrap())
+    .collect();
+    assert!(rsclient.create(entries).is_ok());
+    assert!(rsclient
+        .idm_account_set_password("ldapuser", LDAP_TEST_PASSWORD, false)
+        .is_ok());
+    assert!(rsclient
+        .idm_account_set_password("ldapreset", LDAP_TEST_PASSWORD, true)
+        .is_ok());
+}
+
+#[test]
+fn test_ldap_root_dse_and_schema() {
+    run_test(|_rsclient: KanidmClient, url: String| {
+        let ldap = LdapConn::new(url.as_str()).expect("Failed to connect");
+
+        let (rc, entries) = search(&ldap, "", Scope::Base, "(objectClass=*)");
+        assert!(rc == 0 && entries.len() == 1);
+        let dse = &entries[0];
+        assert!(dse.dn == "");
+        assert!(dse.attrs["namingContexts"] == vec![BASEDN.to_string()]);
+        assert!(dse.attrs["supportedLDAPVersion"] == vec!["3".to_string()]);
+        let schema_dn = dse.attrs["subschemaSubentry"][0].clone();
+
+        let (rc, entries) = search(&ldap, schema_dn.as_str(), Scope::Base, "(objectClass=*)");
+        assert!(rc == 0 && entries.len() == 1);
+        assert!(entries[0].attrs.contains_key("attributeTypes"));
+        assert!(entries[0].attrs.contains_key("objectClasses"));
+
+        let (rc, entries) = search(&ldap, BASEDN, Scope::Base, "(objectClass=*)");
+        assert!(rc == 0 && entries.len() == 1);
+        assert!(entries[0].attrs["dc"] == vec!["localhost".to_string()]);
+
+        // Anything not below the base doesn't exist.
+        let (rc, _) = search(
+            &ldap,
+            "dc=example,dc=com",
+            Scope::Subtree,
+            "(objectClass=*)",
+        );
+        assert!(rc == 32);
+        assert!(ldap.unbind().is_ok());
+    });
+}
+
+#[test]
+fn test_ldap_bind_and_search() {
+    run_test(|rsclient: KanidmClient, url: String| {
+        setup_accounts(&rsclient);
+        let ldap = LdapConn::new(url.as_str()).expect("Failed to connect");
+        let user_dn = format!("cn=ldapuser,{}", BASEDN);
+
+        // Until it binds, a connection searches as anonymous, who may only
+        // read what is public.
+        let (rc, entries) = search(&ldap, BASEDN, Scope::Subtree, "(cn=ldapuser)");
+        assert!(rc == 0 && entries.len() == 1);
+        assert!(entries[0].dn == user_dn);
+        assert!(!entries[0].attrs.contains_key("memberof"));
+
+        // Wrong passwords, binds without one, and accounts that must change
+        // their password are all refused.
+        let bind_rc = |dn: &str, pw: &str| ldap.simple_bind(dn, pw).expect("Failed to bind").rc;
+        assert!(bind_rc(user_dn.as_str(), "not the password") == 49);
+        assert!(bind_rc(user_dn.as_str(), "") == 53);
+        assert!(bind_rc("cn=ldapreset", LDAP_TEST_PASSWORD) == 49);
+        assert!(bind_rc("cn=nobody", LDAP_TEST_PASSWORD) == 49);
+        // The account may be named by spn as well.
+        assert!(bind_rc("ldapuser@localhost", LDAP_TEST_PASSWORD) == 0);
+        assert!(bind_rc(user_dn.as_str(), LDAP_TEST_PASSWORD) == 0);
+
+        let (rc, entries) = search(&ldap, BASEDN, Scope::Subtree, "(cn=ldapuser)");
+        assert!(rc == 0 && entries.len() == 1);
+        let e = &entries[0];
+        assert!(e.dn == user_dn);
+        assert!(e.attrs["cn"] == vec!["ldapuser".to_string()]);
+        assert!(e.attrs["displayname"] == vec!["Ldap User".to_string()]);
+        assert!(e.attrs["memberof"].contains(&format!("cn=ldapgroup,{}", BASEDN)));
+
+        // Groups are found by their members, as a dn.
+        let filter = format!("(&(objectClass=person)(memberOf=cn=ldapgroup,{}))", BASEDN);
+        let (rc, entries) = search(&ldap, BASEDN, Scope::Subtree, filter.as_str());
+        assert!(rc == 0);
+        assert!(
+            entries.iter().map(|e| e.dn.as_str()).collect::<Vec<_>>() == vec![user_dn.as_str()]
+        );
+
+        let (rc, entries) = search(&ldap, user_dn.as_str(), Scope::Base, "(objectClass=*)");
+        assert!(rc == 0 && entries.len() == 1);
+        let (rc, entries) = search(&ldap, user_dn.as_str(), Scope::OneLevel, "(objectClass=*)");
+        assert!(rc == 0 && entries.is_empty());
+
+        // Only what was asked for is given.
+        let r = ldap
+            .search(BASEDN, Scope::Subtree, "(cn=ldapgroup)", vec!["member"])
+            .expect("Failed to search");
+        let entries: Vec<_> = r.0.into_iter().map(SearchEntry::construct).collect();
+        assert!(r.1.rc == 0 && entries.len() == 1);
+        assert!(entries[0].attrs.len() == 1);
+        assert!(entries[0].attrs["member"] == vec![user_dn.clone()]);
+
+        let r = ldap
+            .with_search_options(SearchOptions::new().sizelimit(1))
+            .search(BASEDN, Scope::Subtree, "(objectClass=person)", vec!["cn"])
+            .expect("Failed to search");
+        assert!(r.1.rc == 4 && r.0.len() == 1);
+
+        // Filters we can't answer exactly are refused.
+        let (rc, _) = search(&ldap, BASEDN, Scope::Subtree, "(cn=ldap*)");
+        assert!(rc == 53);
+
+        // Nothing can be changed.
+        assert!(ldap.delete(user_dn.as_str()).expect("Failed to delete").rc == 53);
+        let mut cn = HashSet::new();
+        cn.insert("someone");
+        let r = ldap
+            .add(format!("cn=someone,{}", BASEDN).as_str(), vec![("cn", cn)])
+            .expect("Failed to add");
+        assert!(r.rc == 53);
+        assert!(ldap.unbind().is_ok());
+    });
+}
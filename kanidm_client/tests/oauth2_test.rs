@@ -0,0 +1,252 @@
+#![deny(warnings)]
+
+extern crate actix;
+use actix::prelude::*;
+
+extern crate kanidm;
+extern crate kanidm_client;
+extern crate kanidm_proto;
+extern crate serde_json;
+
+use kanidm_client::{ClientError, KanidmClient};
+
+use kanidm::config::{Configuration, IntegrationTestConfig};
+use kanidm::core::create_server_core;
+use kanidm_proto::v1::{Entry, Oauth2AuthorizeRequest, Oauth2TokenRequest};
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+extern crate env_logger;
+
+static PORT_ALLOC: AtomicUsize = AtomicUsize::new(19080);
+static ADMIN_TEST_PASSWORD: &'static str = "integration test admin password";
+static OAUTH2_TEST_PASSWORD: &'static str = "an oauth2 test password";
+
+static TEST_CLIENT: &'static str = "testapp";
+static TEST_REDIRECT: &'static str = "https://app.example.com/callback";
+// The example verifier and challenge of RFC 7636 appendix B.
+static TEST_VERIFIER: &'static str = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+static TEST_CHALLENGE: &'static str = "E9Melhoa2OwvFrEMTJguCHaoeK1t6URwbuz47vCqDmm";
+
+fn run_test(test_fn: fn(KanidmClient) -> ()) {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let (tx, rx) = mpsc::channel();
+    let port = PORT_ALLOC.fetch_add(1, Ordering::SeqCst);
+
+    let int_config = Box::new(IntegrationTestConfig {
+        admin_password: ADMIN_TEST_PASSWORD.to_string(),
+    });
+
+    let mut config = Configuration::new();
+    config.address = format!("127.0.0.1:{}", port);
+    config.secure_cookies = false;
+    config.integration_test_config = Some(int_config);
+
+    thread::spawn(move || {
+        System::run(move || {
+            create_server_core(config);
+            let _ = tx.send(System::current());
+        });
+    });
+    let sys = rx.recv().unwrap();
+    System::set_current(sys.clone());
+
+    let addr = format!("http://127.0.0.1:{}", port);
+    let rsclient = KanidmClient::new(addr.as_str(), None);
+
+    test_fn(rsclient);
+
+    let _ = sys.stop();
+}
+
+// Register the client, and an account that may use it, then give a client
+// authenticated as that account.
+fn setup_client(rsclient: &KanidmClient) -> KanidmClient {
+    rsclient
+        .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+        .expect("Failed to auth");
+    let entries: Vec<Entry> = vec![
+        r#"{"attrs": {"class": ["person", "account"], "name": ["oauth2user"], "displayname": ["Oauth2 User"]}}"#,
+        r#"{"attrs": {"class": ["group"], "name": ["oauth2group"], "member": ["oauth2user"]}}"#,
+        r#"{"attrs": {"class": ["oauth2_client"], "name": ["testapp"], "oauth2_redirect_uri": ["https://app.example.com/callback"], "oauth2_scope_map": ["openid oauth2group", "profile oauth2group", "groups oauth2group", "admin idm_admins"]}}"#,
+    ]
+    .into_iter()
+    .map(|e| serde_json::from_str(e).unwrap())
+    .collect();
+    assert!(rsclient.create(entries).is_ok());
+    assert!(rsclient
+        .idm_account_set_password("oauth2user", OAUTH2_TEST_PASSWORD, false)
+        .is_ok());
+
+    let user = KanidmClient::new(rsclient.get_url(), None);
+    user.auth_simple_password("oauth2user", OAUTH2_TEST_PASSWORD)
+        .expect("Failed to auth");
+    user
+}
+
+fn authorize_request(scope: &str) -> Oauth2AuthorizeRequest {
+    let mut req = Oauth2AuthorizeRequest::new(TEST_CLIENT, TEST_REDIRECT, scope, TEST_CHALLENGE);
+    req.state = Some("some state".to_string());
+    req
+}
+
+// The value of a parameter of the redirect back to the client.
+fn redirect_param(location: &str, name: &str) -> Option<String> {
+    assert!(location.starts_with(TEST_REDIRECT));
+    let query = location.splitn(2, '?').nth(1)?;
+    query
+        .split('&')
+        .filter_map(|p| {
+            let mut kv = p.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) if k == name => Some(v.to_string()),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+fn authorize_code(user: &KanidmClient, scope: &str) -> String {
+    let location = user
+        .oauth2_authorize(&authorize_request(scope))
+        .expect("Failed to authorize");
+    assert!(redirect_param(location.as_str(), "state") == Some("some%20state".to_string()));
+    redirect_param(location.as_str(), "code").expect("No code was given")
+}
+
+#[test]
+fn test_oauth2_code_exchange() {
+    run_test(|rsclient: KanidmClient| {
+        let user = setup_client(&rsclient);
+
+        // Only the scopes the account's groups are mapped to are granted.
+        let code = authorize_code(&user, "openid profile groups admin");
+        let tr = rsclient
+            .oauth2_token_exchange(&Oauth2TokenRequest::new(
+                code.as_str(),
+                TEST_REDIRECT,
+                TEST_CLIENT,
+                TEST_VERIFIER,
+            ))
+            .expect("Failed to exchange code");
+        assert!(tr.token_type == "Bearer");
+        assert!(tr.scope == "openid profile groups");
+        assert!(tr.id_token.is_some());
+
+        let ui = rsclient
+            .oauth2_userinfo(tr.access_token.as_str())
+            .expect("Failed to get userinfo");
+        assert!(ui.preferred_username == Some("oauth2user".to_string()));
+        assert!(ui.name == Some("Oauth2 User".to_string()));
+        assert!(ui
+            .groups
+            .expect("No groups were given")
+            .contains(&"oauth2group".to_string()));
+
+        // A code is only good once.
+        match rsclient.oauth2_token_exchange(&Oauth2TokenRequest::new(
+            code.as_str(),
+            TEST_REDIRECT,
+            TEST_CLIENT,
+            TEST_VERIFIER,
+        )) {
+            Err(ClientError::Oauth2(e)) => assert!(e == "invalid_grant"),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        // Without openid there is no id token.
+        let code = authorize_code(&user, "profile");
+        let tr = rsclient
+            .oauth2_token_exchange(&Oauth2TokenRequest::new(
+                code.as_str(),
+                TEST_REDIRECT,
+                TEST_CLIENT,
+                TEST_VERIFIER,
+            ))
+            .expect("Failed to exchange code");
+        assert!(tr.id_token.is_none());
+
+        // The access token ends with the session it came from.
+        assert!(user.logout().is_ok());
+        match rsclient.oauth2_userinfo(tr.access_token.as_str()) {
+            Err(ClientError::Oauth2(e)) => assert!(e == "invalid_token"),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    });
+}
+
+#[test]
+fn test_oauth2_refused() {
+    run_test(|rsclient: KanidmClient| {
+        // Authorization needs a session.
+        match rsclient.oauth2_authorize(&authorize_request("openid")) {
+            Err(ClientError::Unauthorized) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+        let user = setup_client(&rsclient);
+
+        // A redirect uri that isn't registered, or an unknown client, is never
+        // redirected to.
+        let mut req = authorize_request("openid");
+        req.redirect_uri = "https://app.example.com/callback/".to_string();
+        match user.oauth2_authorize(&req) {
+            Err(ClientError::Oauth2(e)) => assert!(e == "invalid_request"),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        let mut req = authorize_request("openid");
+        req.client_id = "otherapp".to_string();
+        match user.oauth2_authorize(&req) {
+            Err(ClientError::Oauth2(e)) => assert!(e == "invalid_client"),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        // Anything else is given to the client by redirect.
+        let location = user
+            .oauth2_authorize(&authorize_request("admin"))
+            .expect("Failed to authorize");
+        assert!(redirect_param(location.as_str(), "code").is_none());
+        assert!(redirect_param(location.as_str(), "error") == Some("access_denied".to_string()));
+        let mut req = authorize_request("openid");
+        req.code_challenge = None;
+        let location = user.oauth2_authorize(&req).expect("Failed to authorize");
+        assert!(redirect_param(location.as_str(), "error") == Some("invalid_request".to_string()));
+
+        // The verifier must match the challenge, and a failed exchange still
+        // spends the code.
+        let code = authorize_code(&user, "openid");
+        let mut tr = Oauth2TokenRequest::new(
+            code.as_str(),
+            TEST_REDIRECT,
+            TEST_CLIENT,
+            &TEST_VERIFIER.replace("d", "e"),
+        );
+        match rsclient.oauth2_token_exchange(&tr) {
+            Err(ClientError::Oauth2(e)) => assert!(e == "invalid_grant"),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        tr.code_verifier = TEST_VERIFIER.to_string();
+        match rsclient.oauth2_token_exchange(&tr) {
+            Err(ClientError::Oauth2(e)) => assert!(e == "invalid_grant"),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        // Nor may the code be exchanged with another redirect uri.
+        let code = authorize_code(&user, "openid");
+        match rsclient.oauth2_token_exchange(&Oauth2TokenRequest::new(
+            code.as_str(),
+            "https://app.example.com/other",
+            TEST_CLIENT,
+            TEST_VERIFIER,
+        )) {
+            Err(ClientError::Oauth2(e)) => assert!(e == "invalid_grant"),
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        match rsclient.oauth2_userinfo("not a token") {
+            Err(ClientError::Oauth2(e)) => assert!(e == "invalid_token"),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    });
+}
@@ -11,11 +11,18 @@ extern crate kanidm_client;
 extern crate kanidm_proto;
 extern crate serde_json;
 
-use kanidm_client::KanidmClient;
+use kanidm_client::{ClientError, ExportRecord, KanidmClient};
 
-use kanidm::config::{Configuration, IntegrationTestConfig};
+use kanidm::config::{Configuration, IntegrationTestConfig, OnlineBackup};
 use kanidm::core::create_server_core;
-use kanidm_proto::v1::Entry;
+use kanidm_proto::v1::{
+    AccessCheckOperation, AccessControlModifyRights, AccessControlProfile, AuthAllowed,
+    AuthCredential, AuthRequest, AuthResponse, AuthState, AuthStep, CreateRequest,
+    CredentialPolicy, DeleteRequest, Entry, ErrorResponse, Filter, HealthCheck, HealthResponse,
+    Modify, ModifyList, PasswordFeedback, PlanState, SearchRequest, SearchResponse,
+    WebauthnAssertion, WebauthnAssertionResponse, WebauthnAttestationResponse,
+    WebauthnCreationChallenge, WebauthnRegisterCredential, WebauthnRequestChallenge, KOPID,
+};
 
 extern crate reqwest;
 
@@ -26,16 +33,36 @@ extern crate futures;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc;
 use std::thread;
+use std::time::{Duration, SystemTime};
 
 extern crate env_logger;
 extern crate tokio;
 
+extern crate openssl;
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::sha::sha256;
+use openssl::sign::Signer;
+
+extern crate base64;
+extern crate serde_cbor;
+use serde_cbor::Value as CBORValue;
+use std::collections::BTreeMap;
+
 static PORT_ALLOC: AtomicUsize = AtomicUsize::new(8080);
 static ADMIN_TEST_PASSWORD: &'static str = "integration test admin password";
 
 // Test external behaviorus of the service.
 
 fn run_test(test_fn: fn(KanidmClient) -> ()) {
+    run_test_with(|_| {}, test_fn)
+}
+
+// As run_test, with the config changed by setup first.
+fn run_test_with(setup: fn(&mut Configuration), test_fn: fn(KanidmClient) -> ()) {
     // ::std::env::set_var("RUST_LOG", "actix_web=debug,kanidm=debug");
     let _ = env_logger::builder().is_test(true).try_init();
     let (tx, rx) = mpsc::channel();
@@ -49,7 +76,16 @@ fn run_test(test_fn: fn(KanidmClient) -> ()) {
     config.address = format!("127.0.0.1:{}", port);
     config.secure_cookies = false;
     config.integration_test_config = Some(int_config);
-    // Setup the config ...
+    config.online_backup = Some(OnlineBackup {
+        path: std::env::temp_dir()
+            .join(format!("kanidm_test_backup_{}", port))
+            .to_str()
+            .expect("Invalid temp path")
+            .to_string(),
+        interval: 86400,
+        versions: 2,
+    });
+    setup(&mut config);
 
     thread::spawn(move || {
         // Spawn a thread for the test runner, this should have a unique
@@ -102,7 +138,135 @@ fn test_server_create() {
         assert!(a_res.is_ok());
 
         let res = rsclient.create(vec![e]);
+        assert!(res.map(|uuids| uuids.len()).unwrap() == 1);
+
+        // The uuids are returned in the same order as the entries.
+        let names = vec!["testperson_c", "testperson_a", "testperson_b"];
+        let entries: Vec<Entry> = names
+            .iter()
+            .map(|n| {
+                let mut e: Entry = serde_json::from_str(
+                    r#"{
+                    "attrs": {
+                        "class": ["person", "account"]
+                    }
+                }"#,
+                )
+                .unwrap();
+                e.attrs.insert("name".to_string(), vec![n.to_string()]);
+                e.attrs
+                    .insert("displayname".to_string(), vec![n.to_string()]);
+                e
+            })
+            .collect();
+        let uuids = rsclient.create(entries).unwrap();
+        assert!(uuids.len() == names.len());
+        for (n, u) in names.iter().zip(uuids.iter()) {
+            let r = rsclient
+                .search(Filter::Eq("name".to_string(), n.to_string()))
+                .unwrap();
+            assert!(r.len() == 1);
+            assert!(r[0].get_ava_single("uuid") == Some(u.as_str()));
+        }
+    });
+}
+
+#[test]
+fn test_server_modify_delete_counts() {
+    run_test(|rsclient: KanidmClient| {
+        let a_res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+        assert!(a_res.is_ok());
+
+        let f_none = Filter::Eq("name".to_string(), "notaperson".to_string());
+        let modlist = ModifyList::builder()
+            .present("description", "changed")
+            .build()
+            .unwrap();
+
+        // Matching nothing is an error, unless the caller allows it.
+        let r = rsclient.modify(f_none.clone(), modlist.clone(), false);
+        assert!(r.is_err());
+        let r = rsclient.modify(f_none.clone(), modlist, true);
+        assert!(r.unwrap() == 0);
+
+        let r = rsclient.delete(f_none.clone(), false);
+        assert!(r.is_err());
+        let r = rsclient.delete(f_none, true);
+        assert!(r.unwrap() == 0);
+    });
+}
+
+#[test]
+fn test_server_modify_batch() {
+    run_test(|rsclient: KanidmClient| {
+        let a_res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+        assert!(a_res.is_ok());
+
+        let change = |attr: &str| {
+            (
+                Filter::Eq(attr.to_string(), "testperson".to_string()),
+                ModifyList::builder()
+                    .present("description", "changed")
+                    .build()
+                    .unwrap(),
+            )
+        };
+
+        // The failing item of the batch is reported by index.
+        let r = rsclient.modify_batch(vec![
+            change("name"),
+            change("name"),
+            change("nonexistentattr"),
+            change("name"),
+            change("name"),
+        ]);
+        match r {
+            Err(ClientError::BatchItemFailed(2, e)) => {
+                assert!(e.code == "InvalidAttributeName")
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+    });
+}
+
+#[test]
+fn test_server_compare() {
+    run_test(|rsclient: KanidmClient| {
+        let res = rsclient.auth_anonymous();
+        assert!(res.is_ok());
+
+        let f_admins = Filter::Eq("name".to_string(), "idm_admins".to_string());
+
+        // Anonymous has neither read nor compare on descriptions or membership.
+        assert!(rsclient
+            .compare(f_admins.clone(), "name", "idm_admins")
+            .unwrap());
+        assert!(rsclient
+            .compare(
+                f_admins.clone(),
+                "description",
+                "Builtin IDM Administrators Group."
+            )
+            .is_err());
+        assert!(rsclient
+            .compare(f_admins.clone(), "member", "admin")
+            .is_err());
+
+        let res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
         assert!(res.is_ok());
+
+        // References can be compared by name or uuid.
+        assert!(rsclient
+            .compare(f_admins.clone(), "member", "admin")
+            .unwrap());
+        assert!(rsclient
+            .compare(
+                f_admins.clone(),
+                "member",
+                "00000000-0000-0000-0000-000000000000"
+            )
+            .unwrap());
+        assert!(!rsclient.compare(f_admins, "member", "anonymous").unwrap());
     });
 }
 
@@ -125,51 +289,2553 @@ fn test_server_whoami_anonymous() {
         };
         debug!("{}", uat);
         assert!(uat.name == "anonymous");
+        assert!(uat.anonymous);
     });
 }
 
 #[test]
-fn test_server_whoami_admin_simple_password() {
+fn test_server_anonymous_restricted() {
     run_test(|rsclient: KanidmClient| {
-        // First show we are un-authenticated.
-        let pre_res = rsclient.whoami();
-        // This means it was okay whoami, but no uat attached.
-        assert!(pre_res.unwrap().is_none());
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+        let e: Entry = serde_json::from_str(
+            r#"{
+            "attrs": {
+                "class": ["person", "account"],
+                "name": ["testperson"],
+                "displayname": ["Test Person"],
+                "mail": ["testperson@example.com"]
+            }
+        }"#,
+        )
+        .unwrap();
+        assert!(rsclient.create(vec![e]).is_ok());
+        let f_person = Filter::Eq("name".to_string(), "testperson".to_string());
+
+        // Anonymous can see public attributes, but not mail.
+        assert!(rsclient.auth_anonymous().is_ok());
+        let entries = rsclient.search(f_person.clone()).unwrap();
+        assert!(entries.len() == 1);
+        assert!(entries[0].attrs.get("displayname") == Some(&vec!["Test Person".to_string()]));
+        assert!(entries[0].attrs.get("mail").is_none());
+
+        // Once disabled, anonymous can't authenticate at all.
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+        assert!(rsclient.system_anonymous_set_disabled(true).is_ok());
+        match rsclient.auth_anonymous() {
+            Err(ClientError::AuthenticationFailed) => {}
+            r => panic!("unexpected anonymous auth result {:?}", r),
+        }
+
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+        assert!(rsclient.system_anonymous_set_disabled(false).is_ok());
+        assert!(rsclient.auth_anonymous().is_ok());
+    });
+}
+
+#[test]
+fn test_server_jwk() {
+    run_test(|rsclient: KanidmClient| {
+        // The keys are public, so no auth is needed.
+        let jwks = rsclient.jwk().expect("Failed to get jwk");
+        assert!(jwks.keys.len() == 1);
+        let key = &jwks.keys[0];
+        assert!(key.kty == "EC");
+        assert!(key.crv == "P-256");
+        assert!(key.alg == "ES256");
+
+        // The token we are issued is signed by that key, and accepted back.
+        let res = rsclient.auth_anonymous();
+        assert!(res.is_ok());
+        assert!(rsclient.whoami().unwrap().is_some());
+    });
+}
 
+#[test]
+fn test_server_logout() {
+    run_test(|rsclient: KanidmClient| {
         let res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
         assert!(res.is_ok());
+        assert!(rsclient.whoami().unwrap().is_some());
 
-        // Now do a whoami.
-        let (_e, uat) = match rsclient.whoami().unwrap() {
-            Some((e, uat)) => (e, uat),
-            None => panic!(),
-        };
-        debug!("{}", uat);
+        // Once logged out, we are no longer authenticated.
+        assert!(rsclient.logout().is_ok());
+        assert!(rsclient.whoami().unwrap().is_none());
+        match rsclient.logout() {
+            Err(ClientError::Unauthorized) => {}
+            r => panic!("unexpected logout result {:?}", r),
+        }
+    });
+}
+
+#[test]
+fn test_server_bearer_token() {
+    run_test(|rsclient: KanidmClient| {
+        assert!(rsclient.get_token().is_none());
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+        let token = rsclient.get_token().expect("No token given by auth");
+
+        // A new client, as a later cli command would be, carries on the
+        // session from the token alone.
+        let other = KanidmClient::new(rsclient.get_url(), None);
+        assert!(other.whoami().unwrap().is_none());
+        other.set_token(token.as_str());
+        let (_, uat) = other.whoami().unwrap().expect("Token not accepted");
         assert!(uat.name == "admin");
+
+        // Once the session is ended, the token is no longer accepted.
+        assert!(rsclient.logout().is_ok());
+        assert!(other.whoami().unwrap().is_none());
+
+        other.set_token("not a token");
+        assert!(other.whoami().unwrap().is_none());
     });
 }
 
 #[test]
-fn test_server_search() {
+fn test_server_session_revoke() {
     run_test(|rsclient: KanidmClient| {
-        // First show we are un-authenticated.
-        let pre_res = rsclient.whoami();
-        // This means it was okay whoami, but no uat attached.
-        assert!(pre_res.unwrap().is_none());
+        let uat = rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
 
-        let res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
-        assert!(res.is_ok());
+        let sessions = rsclient.session_list("admin").expect("Failed to list");
+        let session = sessions
+            .iter()
+            .find(|s| s.sessionid == uat.sessionid)
+            .expect("Session not listed");
+        assert!(session.account == uat.uuid);
+        assert!(session.issued_at == uat.issued_at);
+        assert!(session.source.is_some());
 
-        let rset = rsclient
-            .search_str("{\"Eq\":[\"name\", \"admin\"]}")
+        // Revoking doesn't touch our cookie, so the token is presented
+        // again, but the server no longer accepts it.
+        let sessionid = uat.sessionid.to_string();
+        assert!(rsclient.session_revoke(sessionid.as_str()).is_ok());
+        assert!(rsclient.whoami().unwrap().is_none());
+        assert!(rsclient.session_list("admin").is_err());
+    });
+}
+
+// The code an authenticator would show for this step of the secret.
+fn totp_code(secret: &[u8], step: u64) -> String {
+    let key = PKey::hmac(secret).unwrap();
+    let mut signer = Signer::new(MessageDigest::sha1(), &key).unwrap();
+    signer.update(&step.to_be_bytes()).unwrap();
+    let hmac = signer.sign_to_vec().unwrap();
+    let offset = (hmac[hmac.len() - 1] & 0xf) as usize;
+    let code = ((hmac[offset] as u32 & 0x7f) << 24)
+        | ((hmac[offset + 1] as u32) << 16)
+        | ((hmac[offset + 2] as u32) << 8)
+        | (hmac[offset + 3] as u32);
+    format!("{:06}", code % 1_000_000)
+}
+
+#[test]
+fn test_server_totp_auth() {
+    run_test(|rsclient: KanidmClient| {
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+
+        let (secret, uri) = rsclient.totp_generate().expect("Failed to generate");
+        assert!(uri.starts_with("otpauth://totp/"));
+        assert!(uri.contains(secret.get_secret().as_str()));
+
+        let step = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            / secret.step;
+
+        // A wrong code is refused, and the right one enrolls the secret.
+        let bad = format!(
+            "{:06}",
+            (totp_code(&secret.secret, step).parse::<u32>().unwrap() + 1) % 1_000_000
+        );
+        match rsclient.totp_verify(bad.as_str()) {
+            Err(ClientError::InvalidTOTP) => {}
+            r => panic!("unexpected verify result {:?}", r),
+        }
+        assert!(rsclient
+            .totp_verify(totp_code(&secret.secret, step).as_str())
+            .is_ok());
+        assert!(rsclient.logout().is_ok());
+
+        // The password alone is no longer enough.
+        match rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD) {
+            Err(ClientError::MFARequired(_)) => {}
+            r => panic!("unexpected auth result {:?}", r),
+        }
+        // The code used to enroll can't be used again, but the next one is
+        // within the allowed skew.
+        assert!(rsclient
+            .auth_step_totp(totp_code(&secret.secret, step).as_str())
+            .is_err());
+        assert!(rsclient.whoami().unwrap().is_none());
+
+        let next = totp_code(&secret.secret, step + 1);
+        assert!(rsclient
+            .auth_password_totp("admin", ADMIN_TEST_PASSWORD, next.as_str())
+            .is_ok());
+        assert!(rsclient.whoami().unwrap().is_some());
+        assert!(rsclient.logout().is_ok());
+
+        // And then that code is spent too.
+        assert!(rsclient
+            .auth_password_totp("admin", ADMIN_TEST_PASSWORD, next.as_str())
+            .is_err());
+        assert!(rsclient.whoami().unwrap().is_none());
+    });
+}
+
+#[test]
+fn test_server_backup_code_auth() {
+    run_test(|rsclient: KanidmClient| {
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+
+        // Backup codes need a second factor to stand in for.
+        assert!(rsclient.backup_codes_generate().is_err());
+        let (secret, _) = rsclient.totp_generate().expect("Failed to generate");
+        let step = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            / secret.step;
+        assert!(rsclient
+            .totp_verify(totp_code(&secret.secret, step).as_str())
+            .is_ok());
+
+        let codes = rsclient
+            .backup_codes_generate()
+            .expect("Failed to generate backup codes");
+        let status = rsclient.credential_status().expect("Failed to get status");
+        assert!(status.password && status.totp);
+        assert!(status.backup_codes_remaining == codes.len() as u32);
+        assert!(rsclient.logout().is_ok());
+
+        // A code stands in for the totp, once.
+        match rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD) {
+            Err(ClientError::MFARequired(allowed)) => {
+                assert!(allowed.contains(&AuthAllowed::BackupCode))
+            }
+            r => panic!("unexpected auth result {:?}", r),
+        }
+        assert!(rsclient.auth_step_backup_code(codes[0].as_str()).is_ok());
+        let status = rsclient.credential_status().expect("Failed to get status");
+        assert!(status.backup_codes_remaining == codes.len() as u32 - 1);
+        assert!(rsclient.logout().is_ok());
+
+        assert!(rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .is_err());
+        assert!(rsclient.auth_step_backup_code(codes[0].as_str()).is_err());
+        assert!(rsclient.whoami().unwrap().is_none());
+    });
+}
+
+#[test]
+fn test_server_password_change() {
+    run_test(|rsclient: KanidmClient| {
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+        let e: Entry = serde_json::from_str(
+            r#"{
+            "attrs": {
+                "class": ["person", "account"],
+                "name": ["testperson"],
+                "displayname": ["testperson"]
+            }
+        }"#,
+        )
+        .unwrap();
+        assert!(rsclient.create(vec![e]).is_ok());
+
+        // Weak passwords are refused, and the reason given.
+        match rsclient.idm_account_set_password("testperson", "short", true) {
+            Err(ClientError::PasswordQuality(_)) => {}
+            r => panic!("unexpected reset result {:?}", r),
+        }
+        assert!(rsclient
+            .idm_account_set_password("testperson", "a temporary password", true)
+            .is_ok());
+        assert!(rsclient.logout().is_ok());
+
+        // After the reset, the token is only good for changing the password.
+        let uat = rsclient
+            .auth_simple_password("testperson", "a temporary password")
+            .expect("Failed to auth");
+        assert!(uat.must_change_password);
+        assert!(rsclient.whoami().unwrap().is_some());
+        match rsclient.credential_status() {
+            Err(ClientError::Unauthorized) => {}
+            r => panic!("unexpected status result {:?}", r),
+        }
+        match rsclient
+            .idm_account_self_set_password(Some("not the password"), "a brand new password")
+        {
+            Err(ClientError::IncorrectPassword) => {}
+            r => panic!("unexpected change result {:?}", r),
+        }
+        assert!(rsclient
+            .idm_account_self_set_password(Some("a temporary password"), "a brand new password")
+            .is_ok());
+        // That session ends with the change.
+        assert!(rsclient.whoami().unwrap().is_none());
+
+        assert!(rsclient
+            .auth_simple_password("testperson", "a temporary password")
+            .is_err());
+        let uat = rsclient
+            .auth_simple_password("testperson", "a brand new password")
+            .expect("Failed to auth");
+        assert!(!uat.must_change_password);
+        assert!(rsclient.credential_status().is_ok());
+    });
+}
+
+#[test]
+fn test_server_password_badlist() {
+    run_test(|rsclient: KanidmClient| {
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+        let e: Entry = serde_json::from_str(
+            r#"{
+            "attrs": {
+                "class": ["person", "account"],
+                "name": ["testperson"],
+                "displayname": ["testperson"]
+            }
+        }"#,
+        )
+        .unwrap();
+        assert!(rsclient.create(vec![e]).is_ok());
+
+        assert!(rsclient.system_password_badlist_get().unwrap().is_empty());
+        // Words are normalised, so these are the same entry.
+        assert!(rsclient
+            .system_password_badlist_append(&["Tr0ub4dor&3xyz", " tr0ub4dor&3xyz "])
+            .is_ok());
+        assert!(rsclient.system_password_badlist_get().unwrap() == vec!["tr0ub4dor&3xyz"]);
+
+        match rsclient.idm_account_set_password("testperson", "tR0ub4dor&3xyz", false) {
+            Err(ClientError::PasswordQuality(feedback)) => {
+                assert!(feedback == vec![PasswordFeedback::BadListed])
+            }
+            r => panic!("unexpected reset result {:?}", r),
+        }
+
+        assert!(rsclient
+            .system_password_badlist_remove(&["TR0UB4DOR&3XYZ"])
+            .is_ok());
+        assert!(rsclient.system_password_badlist_get().unwrap().is_empty());
+        assert!(rsclient
+            .idm_account_set_password("testperson", "tR0ub4dor&3xyz", false)
+            .is_ok());
+    });
+}
+
+#[test]
+fn test_server_account_lockout() {
+    run_test(|rsclient: KanidmClient| {
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+        let e: Entry = serde_json::from_str(
+            r#"{
+            "attrs": {
+                "class": ["person", "account"],
+                "name": ["testperson"],
+                "displayname": ["testperson"]
+            }
+        }"#,
+        )
+        .unwrap();
+        assert!(rsclient.create(vec![e]).is_ok());
+        assert!(rsclient
+            .idm_account_set_password("testperson", "a brand new password", false)
+            .is_ok());
+        assert!(rsclient.logout().is_ok());
+
+        let mut locked = None;
+        for _ in 0..10 {
+            match rsclient.auth_simple_password("testperson", "not the password") {
+                Err(ClientError::AuthenticationFailed) => {}
+                Err(ClientError::AccountLocked(until)) => {
+                    locked = Some(until);
+                    break;
+                }
+                r => panic!("unexpected auth result {:?}", r),
+            }
+        }
+        assert!(locked.is_some());
+        match rsclient.auth_simple_password("testperson", "a brand new password") {
+            Err(ClientError::AccountLocked(until)) => assert!(Some(until) == locked),
+            r => panic!("unexpected auth result {:?}", r),
+        }
+
+        // Only testperson is locked, and an admin can end the lock at once.
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+        assert!(rsclient.idm_account_unlock("testperson").is_ok());
+        assert!(rsclient.logout().is_ok());
+        assert!(rsclient
+            .auth_simple_password("testperson", "a brand new password")
+            .is_ok());
+    });
+}
+
+#[test]
+fn test_server_account_validity() {
+    run_test(|rsclient: KanidmClient| {
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+        let e: Entry = serde_json::from_str(
+            r#"{
+            "attrs": {
+                "class": ["person", "account"],
+                "name": ["testperson"],
+                "displayname": ["testperson"]
+            }
+        }"#,
+        )
+        .unwrap();
+        assert!(rsclient.create(vec![e]).is_ok());
+        assert!(rsclient
+            .idm_account_set_password("testperson", "a brand new password", false)
+            .is_ok());
+        assert!(rsclient.idm_account_get_validity("testperson").unwrap() == (None, None));
+
+        assert!(rsclient
+            .idm_account_set_expire("testperson", Some("2000-01-01T00:00:00Z"))
+            .is_ok());
+        assert!(rsclient.logout().is_ok());
+        match rsclient.auth_simple_password("testperson", "a brand new password") {
+            Err(ClientError::AccountExpired(at)) => assert!(at == 946684800),
+            r => panic!("unexpected auth result {:?}", r),
+        }
+
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+        assert!(rsclient.idm_account_set_expire("testperson", None).is_ok());
+        assert!(rsclient
+            .idm_account_set_valid_from("testperson", Some("2999-01-01T00:00:00Z"))
+            .is_ok());
+        assert!(
+            rsclient.idm_account_get_validity("testperson").unwrap()
+                == (Some("2999-01-01T00:00:00Z".to_string()), None)
+        );
+        assert!(rsclient.logout().is_ok());
+        match rsclient.auth_simple_password("testperson", "a brand new password") {
+            Err(ClientError::AccountNotYetValid(_)) => {}
+            r => panic!("unexpected auth result {:?}", r),
+        }
+
+        // Once the window is open again, so is the account.
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+        assert!(rsclient
+            .idm_account_set_valid_from("testperson", None)
+            .is_ok());
+        assert!(rsclient.logout().is_ok());
+        assert!(rsclient
+            .auth_simple_password("testperson", "a brand new password")
+            .is_ok());
+        assert!(rsclient.whoami().unwrap().is_some());
+    });
+}
+
+#[test]
+fn test_server_access_control_profiles() {
+    run_test(|rsclient: KanidmClient| {
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+        for name in ["helpdesk", "testperson"].iter() {
+            let e: Entry = serde_json::from_str(&format!(
+                r#"{{
+                "attrs": {{
+                    "class": ["person", "account"],
+                    "name": ["{0}"],
+                    "displayname": ["{0}"]
+                }}
+            }}"#,
+                name
+            ))
             .unwrap();
-        println!("{:?}", rset);
-        let e = rset.first().unwrap();
-        // Check it's admin.
-        println!("{:?}", e);
-        let name = e.attrs.get("name").unwrap();
-        assert!(name == &vec!["admin".to_string()]);
+            assert!(rsclient.create(vec![e]).is_ok());
+        }
+        assert!(rsclient
+            .idm_account_set_password("helpdesk", "a brand new password", false)
+            .is_ok());
+
+        let mut acp = AccessControlProfile::new(
+            "helpdesk_displayname",
+            Filter::Eq("name".to_string(), "helpdesk".to_string()),
+            Filter::Eq("class".to_string(), "person".to_string()),
+        );
+        acp.search = Some(vec!["name".to_string(), "displayname".to_string()]);
+        acp.modify = Some(AccessControlModifyRights {
+            present_attrs: vec!["displayname".to_string()],
+            removed_attrs: vec!["displayname".to_string()],
+            classes: Vec::new(),
+        });
+        assert!(rsclient.idm_acp_create(&acp).is_ok());
+
+        let got = rsclient
+            .idm_acp_get("helpdesk_displayname")
+            .unwrap()
+            .expect("acp not found");
+        assert!(got.receiver == acp.receiver);
+        assert!(got.targetscope == acp.targetscope);
+        assert!(got.search == acp.search);
+        assert!(got.modify == acp.modify);
+        assert!(got.create.is_none());
+        assert!(!got.delete);
+        assert!(rsclient
+            .idm_acp_list()
+            .unwrap()
+            .iter()
+            .any(|a| a.name == "helpdesk_displayname"));
+        // Updating to the same profile changes nothing.
+        assert!(rsclient.idm_acp_update(&acp).is_ok());
+
+        // A profile naming an attribute that doesn't exist is rejected.
+        let mut bad = AccessControlProfile::new(
+            "helpdesk_bad",
+            Filter::Eq("name".to_string(), "helpdesk".to_string()),
+            Filter::Eq("class".to_string(), "person".to_string()),
+        );
+        bad.search = Some(vec!["nonexistant".to_string()]);
+        assert!(rsclient.idm_acp_create(&bad).is_err());
+
+        // Check what helpdesk could do, without doing it.
+        let set_displayname =
+            AccessCheckOperation::Modify(ModifyList::new_list(vec![Modify::Present(
+                "displayname".to_string(),
+                "changed".to_string(),
+            )]));
+        let set_member = AccessCheckOperation::Modify(ModifyList::new_list(vec![Modify::Present(
+            "member".to_string(),
+            "helpdesk".to_string(),
+        )]));
+        let r = rsclient
+            .access_check(
+                "helpdesk",
+                Filter::Eq("name".to_string(), "testperson".to_string()),
+                set_displayname,
+            )
+            .unwrap();
+        assert!(r.len() == 1);
+        assert!(r[0].allowed);
+        assert!(r[0].profiles == vec!["helpdesk_displayname".to_string()]);
+
+        let r = rsclient
+            .access_check(
+                "helpdesk",
+                Filter::Eq("name".to_string(), "testperson".to_string()),
+                set_member.clone(),
+            )
+            .unwrap();
+        assert!(r.len() == 1);
+        assert!(!r[0].allowed);
+        assert!(r[0].profiles == vec!["helpdesk_displayname".to_string()]);
+
+        let r = rsclient
+            .access_check(
+                "helpdesk",
+                Filter::Eq("name".to_string(), "idm_people_write_priv".to_string()),
+                set_member,
+            )
+            .unwrap();
+        assert!(r.len() == 1);
+        assert!(!r[0].allowed);
+        assert!(r[0].profiles.is_empty());
+
+        // And helpdesk really can.
+        assert!(rsclient.logout().is_ok());
+        rsclient
+            .auth_simple_password("helpdesk", "a brand new password")
+            .expect("Failed to auth");
+        assert!(rsclient
+            .modify(
+                Filter::Eq("name".to_string(), "testperson".to_string()),
+                ModifyList::new_list(vec![Modify::Present(
+                    "displayname".to_string(),
+                    "changed".to_string()
+                )]),
+                false
+            )
+            .is_ok());
+        assert!(rsclient
+            .modify(
+                Filter::Eq("name".to_string(), "idm_people_write_priv".to_string()),
+                ModifyList::new_list(vec![Modify::Present(
+                    "member".to_string(),
+                    "helpdesk".to_string()
+                )]),
+                false
+            )
+            .is_err());
+        // Only admins may check access.
+        assert!(rsclient
+            .access_check(
+                "helpdesk",
+                Filter::Eq("name".to_string(), "testperson".to_string()),
+                AccessCheckOperation::Search,
+            )
+            .is_err());
     });
 }
 
-// Test hitting all auth-required endpoints and assert they give unauthorized.
+// The effective access report must match what the account can really do.
+#[test]
+fn test_server_effective_access() {
+    run_test(|rsclient: KanidmClient| {
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+        let e: Entry = serde_json::from_str(
+            r#"{
+            "attrs": {
+                "class": ["person", "account"],
+                "name": ["testperson"],
+                "displayname": ["testperson"]
+            }
+        }"#,
+        )
+        .unwrap();
+        assert!(rsclient.create(vec![e]).is_ok());
+        let testperson = || Filter::Eq("name".to_string(), "testperson".to_string());
+
+        let check = |rsclient: &KanidmClient| {
+            let r = rsclient.effective_access(testperson()).unwrap();
+            assert!(r.len() == 1);
+            let modify = rsclient.modify(
+                testperson(),
+                ModifyList::new_list(vec![Modify::Present(
+                    "displayname".to_string(),
+                    "changed".to_string(),
+                )]),
+                false,
+            );
+            assert!(modify.is_ok() == r[0].modify_present.contains(&"displayname".to_string()));
+            r.into_iter().next().unwrap()
+        };
+
+        let admin = check(&rsclient);
+        assert!(admin.name == Some("testperson".to_string()));
+        assert!(admin.search.contains(&"displayname".to_string()));
+        assert!(admin.modify_present.contains(&"displayname".to_string()));
+
+        assert!(rsclient.logout().is_ok());
+        assert!(rsclient.auth_anonymous().is_ok());
+        let anon = check(&rsclient);
+        assert!(anon.search.contains(&"name".to_string()));
+        assert!(anon.modify_present.is_empty());
+        assert!(!anon.delete);
+        assert!(rsclient.delete(testperson(), false).is_err());
+
+        // Entries that can't be seen aren't reported on.
+        assert!(rsclient
+            .effective_access(Filter::Eq("name".to_string(), "notaname".to_string()))
+            .unwrap()
+            .is_empty());
+    });
+}
+
+// A webauthn token in software, standing in for a browser and a hardware
+// token. It signs for the default origin of the test server.
+struct SoftToken {
+    key: EcKey<Private>,
+    cred_id: Vec<u8>,
+    counter: u32,
+}
+
+static WEBAUTHN_ORIGIN: &'static str = "https://localhost";
+
+fn b64(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+impl SoftToken {
+    fn new() -> Self {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        SoftToken {
+            key: EcKey::generate(&group).unwrap(),
+            cred_id: vec![1, 2, 3, 4, 5, 6, 7, 8],
+            counter: 0,
+        }
+    }
+
+    fn client_data(type_: &str, challenge: &str) -> Vec<u8> {
+        format!(
+            r#"{{"type":"{}","challenge":"{}","origin":"{}"}}"#,
+            type_, challenge, WEBAUTHN_ORIGIN
+        )
+        .into_bytes()
+    }
+
+    fn register(&self, chal: &WebauthnCreationChallenge) -> WebauthnRegisterCredential {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let mut ctx = BigNumContext::new().unwrap();
+        let mut x = BigNum::new().unwrap();
+        let mut y = BigNum::new().unwrap();
+        self.key
+            .public_key()
+            .affine_coordinates_gfp(&group, &mut x, &mut y, &mut ctx)
+            .unwrap();
+        let mut cose = BTreeMap::new();
+        cose.insert(CBORValue::Integer(1), CBORValue::Integer(2));
+        cose.insert(CBORValue::Integer(3), CBORValue::Integer(-7));
+        cose.insert(CBORValue::Integer(-1), CBORValue::Integer(1));
+        cose.insert(CBORValue::Integer(-2), CBORValue::Bytes(x.to_vec()));
+        cose.insert(CBORValue::Integer(-3), CBORValue::Bytes(y.to_vec()));
+
+        // rp id hash, flags of user present and attested data, counter,
+        // aaguid, credential id and the key.
+        let mut auth_data = sha256(chal.rp.id.as_bytes()).to_vec();
+        auth_data.push(0x41);
+        auth_data.extend_from_slice(&self.counter.to_be_bytes());
+        auth_data.extend_from_slice(&[0; 16]);
+        auth_data.extend_from_slice(&(self.cred_id.len() as u16).to_be_bytes());
+        auth_data.extend_from_slice(&self.cred_id);
+        auth_data.extend(serde_cbor::to_vec(&CBORValue::Map(cose)).unwrap());
+
+        let mut att_obj = BTreeMap::new();
+        att_obj.insert(
+            CBORValue::Text("fmt".to_string()),
+            CBORValue::Text("none".to_string()),
+        );
+        att_obj.insert(
+            CBORValue::Text("attStmt".to_string()),
+            CBORValue::Map(BTreeMap::new()),
+        );
+        att_obj.insert(
+            CBORValue::Text("authData".to_string()),
+            CBORValue::Bytes(auth_data),
+        );
+
+        WebauthnRegisterCredential {
+            id: b64(&self.cred_id),
+            raw_id: b64(&self.cred_id),
+            response: WebauthnAttestationResponse {
+                attestation_object: b64(&serde_cbor::to_vec(&CBORValue::Map(att_obj)).unwrap()),
+                client_data_json: b64(&Self::client_data(
+                    "webauthn.create",
+                    chal.challenge.as_str(),
+                )),
+            },
+            type_: "public-key".to_string(),
+        }
+    }
+
+    fn sign(&mut self, chal: &WebauthnRequestChallenge) -> WebauthnAssertion {
+        self.counter += 1;
+        let mut auth_data = sha256(chal.rp_id.as_bytes()).to_vec();
+        auth_data.push(0x01);
+        auth_data.extend_from_slice(&self.counter.to_be_bytes());
+        let cd = Self::client_data("webauthn.get", chal.challenge.as_str());
+
+        let pkey = PKey::from_ec_key(self.key.clone()).unwrap();
+        let mut signer = Signer::new(MessageDigest::sha256(), &pkey).unwrap();
+        signer.update(&auth_data).unwrap();
+        signer.update(&sha256(&cd)).unwrap();
+
+        WebauthnAssertion {
+            id: b64(&self.cred_id),
+            raw_id: b64(&self.cred_id),
+            response: WebauthnAssertionResponse {
+                authenticator_data: b64(&auth_data),
+                client_data_json: b64(&cd),
+                signature: b64(&signer.sign_to_vec().unwrap()),
+                user_handle: None,
+            },
+            type_: "public-key".to_string(),
+        }
+    }
+}
+
+#[test]
+fn test_server_webauthn_auth() {
+    run_test(|rsclient: KanidmClient| {
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+
+        let mut token = SoftToken::new();
+        let chal = rsclient.webauthn_generate().expect("Failed to generate");
+        assert!(chal.exclude_credentials.is_empty());
+        rsclient
+            .webauthn_register("softtoken", token.register(&chal))
+            .expect("Failed to register");
+
+        let tokens = rsclient.webauthn_list().expect("Failed to list");
+        assert!(tokens.len() == 1);
+        assert!(tokens[0].name == "softtoken");
+        assert!(rsclient.logout().is_ok());
+
+        // The password is followed by the challenge for the token.
+        let chal = match rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD) {
+            Err(ClientError::MFARequired(allowed)) => match allowed.as_slice() {
+                [AuthAllowed::Webauthn(chal)] => chal.clone(),
+                _ => panic!("unexpected mechs {:?}", allowed),
+            },
+            r => panic!("unexpected auth result {:?}", r),
+        };
+        assert!(rsclient.auth_step_webauthn(token.sign(&chal)).is_ok());
+        assert!(rsclient.whoami().unwrap().is_some());
+        assert!(rsclient.webauthn_list().unwrap()[0].counter == 1);
+
+        // Under a webauthn only policy, the token can't be removed.
+        assert!(rsclient
+            .credential_set_policy(Some(CredentialPolicy::WebauthnOnly))
+            .is_ok());
+        let status = rsclient.credential_status().expect("Failed to get status");
+        assert!(status.policy == Some(CredentialPolicy::WebauthnOnly));
+        assert!(rsclient.webauthn_remove("softtoken").is_err());
+        assert!(rsclient.credential_set_policy(None).is_ok());
+
+        // Once removed, the password alone is enough.
+        assert!(rsclient.webauthn_remove("missing").is_err());
+        assert!(rsclient.webauthn_remove("softtoken").is_ok());
+        assert!(rsclient.webauthn_list().unwrap().is_empty());
+        assert!(rsclient.logout().is_ok());
+        assert!(rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .is_ok());
+    });
+}
+
+#[test]
+fn test_server_schema_anonymous() {
+    run_test(|rsclient: KanidmClient| {
+        // Schema isn't secret, so anonymous can list it.
+        let res = rsclient.auth_anonymous();
+        assert!(res.is_ok());
+
+        let attrs = rsclient
+            .schema_attribute_list()
+            .expect("Failed to list attributes");
+        let member = attrs
+            .iter()
+            .find(|a| a.name == "member")
+            .expect("member not found");
+        assert!(member.multivalue);
+        assert!(member.syntax == "REFERENCE_UUID");
+
+        let classes = rsclient
+            .schema_class_list()
+            .expect("Failed to list classes");
+        let account = classes
+            .iter()
+            .find(|c| c.name == "account")
+            .expect("account not found");
+        assert!(account.must.contains(&"displayname".to_string()));
+        assert!(account.may.contains(&"account_expire".to_string()));
+    });
+}
+
+#[test]
+fn test_server_recycle_bin() {
+    run_test(|rsclient: KanidmClient| {
+        let a_res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+        assert!(a_res.is_ok());
+
+        let e: Entry = serde_json::from_str(
+            r#"{
+            "attrs": {
+                "class": ["account", "person"],
+                "name": ["testperson"],
+                "displayname": ["testperson"]
+            }
+        }"#,
+        )
+        .unwrap();
+        let uuids = rsclient.create(vec![e.clone()]).unwrap();
+        let f_name = Filter::Eq("name".to_string(), "testperson".to_string());
+
+        let r = rsclient.delete(f_name.clone(), false);
+        assert!(r.unwrap() == 1);
+        assert!(rsclient.search(f_name.clone()).unwrap().len() == 0);
+
+        // The deleted account is in the recycle bin, with when it was deleted.
+        let rset = rsclient.recycle_bin_list(f_name.clone()).unwrap();
+        assert!(rset.len() == 1);
+        assert!(rset[0].get_ava_single("uuid") == Some(uuids[0].as_str()));
+        assert!(rset[0].get_ava_single("deleted_at").is_some());
+
+        // Revive it by uuid, and it's live again.
+        let revived = rsclient.recycle_bin_revive(uuids[0].as_str()).unwrap();
+        assert!(revived == uuids);
+        assert!(rsclient.search(f_name.clone()).unwrap().len() == 1);
+        assert!(rsclient.recycle_bin_list(f_name.clone()).unwrap().len() == 0);
+
+        // If the name has been taken by a new account in the meantime, the
+        // revive fails and says why.
+        assert!(rsclient.delete(f_name.clone(), false).unwrap() == 1);
+        assert!(rsclient.create(vec![e]).is_ok());
+        match rsclient.recycle_bin_revive(uuids[0].as_str()) {
+            Err(ClientError::ReviveFailed(failed)) => {
+                assert!(failed.len() == 1);
+                assert!(failed[0].0 == uuids[0]);
+                assert!(failed[0].1.code == "DuplicateValue");
+            }
+            r => panic!("unexpected revive result {:?}", r),
+        }
+        assert!(rsclient.recycle_bin_list(f_name).unwrap().len() == 1);
+    });
+}
+
+#[test]
+fn test_server_whoami_admin_simple_password() {
+    run_test(|rsclient: KanidmClient| {
+        // First show we are un-authenticated.
+        let pre_res = rsclient.whoami();
+        // This means it was okay whoami, but no uat attached.
+        assert!(pre_res.unwrap().is_none());
+
+        let res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+        assert!(res.is_ok());
+
+        // Now do a whoami.
+        let (_e, uat) = match rsclient.whoami().unwrap() {
+            Some((e, uat)) => (e, uat),
+            None => panic!(),
+        };
+        debug!("{}", uat);
+        assert!(uat.name == "admin");
+
+        // The token carries when it was issued, and is good for a while yet.
+        let ct = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("Clock failure!");
+        assert!(uat.issued_at <= ct.as_secs());
+        assert!(uat.expiry > uat.issued_at);
+        assert!(!uat.is_expired(ct));
+    });
+}
+
+#[test]
+fn test_server_reauth() {
+    run_test(|rsclient: KanidmClient| {
+        // There is no session to reauth yet.
+        assert!(rsclient
+            .reauth_simple_password(ADMIN_TEST_PASSWORD)
+            .is_err());
+
+        let uat = rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+        assert!(rsclient.reauth_simple_password("wrong password").is_err());
+        let uat_re = rsclient
+            .reauth_simple_password(ADMIN_TEST_PASSWORD)
+            .expect("Failed to reauth");
+
+        // The same session continues, with the new token in use.
+        assert!(uat_re.sessionid == uat.sessionid);
+        assert!(uat_re.expiry == uat.expiry);
+        assert!(uat_re.auth_time >= uat.auth_time);
+        let (_e, uat_now) = rsclient.whoami().unwrap().unwrap();
+        assert!(uat_now.sessionid == uat.sessionid);
+        assert!(uat_now.auth_time == uat_re.auth_time);
+    });
+}
+
+#[test]
+fn test_server_service_account_api_token() {
+    run_test(|rsclient: KanidmClient| {
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+        let e: Entry = serde_json::from_str(
+            r#"{
+            "attrs": {
+                "class": ["object", "account", "service_account"],
+                "name": ["testservice"],
+                "displayname": ["Test Service"]
+            }
+        }"#,
+        )
+        .unwrap();
+        assert!(rsclient.create(vec![e]).is_ok());
+
+        let r = rsclient
+            .service_account_api_token_generate("testservice", "backup", None, false)
+            .expect("Failed to generate api token");
+        let tokens = rsclient
+            .service_account_api_token_list("testservice")
+            .expect("Failed to list api tokens");
+        assert!(tokens.len() == 1);
+        assert!(tokens[0].id == r.id);
+        assert!(tokens[0].last_used.is_none());
+
+        // The token stands in for a password, and its session may only read.
+        assert!(rsclient
+            .auth_api_token("testservice", "not a token")
+            .is_err());
+        let uat = rsclient
+            .auth_api_token("testservice", r.token.as_str())
+            .expect("Failed to auth with api token");
+        assert!(uat.api_token == Some(r.id));
+        assert!(uat.read_only);
+        assert!(rsclient
+            .search(Filter::Eq("name".to_string(), "testservice".to_string()))
+            .is_ok());
+        assert!(rsclient
+            .modify(
+                Filter::Eq("name".to_string(), "testservice".to_string()),
+                ModifyList::new_list(vec![Modify::Purged("displayname".to_string())]),
+                false
+            )
+            .is_err());
+
+        // Once destroyed, the token can't be used.
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+        let id = r.id.to_string();
+        rsclient
+            .service_account_api_token_destroy("testservice", id.as_str())
+            .expect("Failed to destroy api token");
+        assert!(rsclient
+            .service_account_api_token_list("testservice")
+            .expect("Failed to list api tokens")
+            .is_empty());
+        assert!(rsclient
+            .auth_api_token("testservice", r.token.as_str())
+            .is_err());
+    });
+}
+
+#[test]
+fn test_server_radius_secret() {
+    run_test(|rsclient: KanidmClient| {
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+        for name in &["testperson", "testradius", "testother"] {
+            let e: Entry = serde_json::from_str(
+                format!(
+                    r#"{{
+                    "attrs": {{
+                        "class": ["person", "account"],
+                        "name": ["{}"],
+                        "displayname": ["{}"]
+                    }}
+                }}"#,
+                    name, name
+                )
+                .as_str(),
+            )
+            .unwrap();
+            assert!(rsclient.create(vec![e]).is_ok());
+            assert!(rsclient
+                .idm_account_set_password(name, "a radius test password", false)
+                .is_ok());
+        }
+        assert!(rsclient
+            .modify(
+                Filter::Eq("name".to_string(), "idm_radius_servers".to_string()),
+                ModifyList::new_list(vec![Modify::Present(
+                    "member".to_string(),
+                    "testradius".to_string()
+                )]),
+                false
+            )
+            .is_ok());
+
+        // The owner generates their secret, and may read it back.
+        rsclient
+            .auth_simple_password("testperson", "a radius test password")
+            .expect("Failed to auth");
+        let secret = rsclient
+            .radius_secret_generate()
+            .expect("Failed to generate radius secret");
+        let rat = rsclient
+            .radius_auth_token_get("testperson")
+            .expect("Failed to get radius token");
+        assert!(rat.secret == secret);
+        assert!(rat.name == "testperson");
+
+        // A radius server may read it too.
+        rsclient
+            .auth_simple_password("testradius", "a radius test password")
+            .expect("Failed to auth");
+        let rat = rsclient
+            .radius_auth_token_get("testperson")
+            .expect("Failed to get radius token");
+        assert!(rat.secret == secret);
+
+        // But nobody else.
+        rsclient
+            .auth_simple_password("testother", "a radius test password")
+            .expect("Failed to auth");
+        assert!(rsclient.radius_auth_token_get("testperson").is_err());
+    });
+}
+
+#[test]
+fn test_server_ssh_publickeys() {
+    run_test(|rsclient: KanidmClient| {
+        let key = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIC6QCYmikCVyX0g7E6MRDYEVh2OmvffZOnDQtxeCRDdx user@laptop";
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+        let e: Entry = serde_json::from_str(
+            r#"{
+            "attrs": {
+                "class": ["person", "account"],
+                "name": ["testperson"],
+                "displayname": ["testperson"]
+            }
+        }"#,
+        )
+        .unwrap();
+        assert!(rsclient.create(vec![e]).is_ok());
+
+        assert!(rsclient
+            .idm_account_post_ssh_pubkey("testperson", "laptop", key)
+            .is_ok());
+        // A key that doesn't parse is refused when it's written.
+        assert!(rsclient
+            .idm_account_post_ssh_pubkey("testperson", "desktop", "ssh-ed25519 not-a-key")
+            .is_err());
+        let keys = rsclient
+            .idm_account_list_ssh_pubkeys("testperson")
+            .expect("Failed to list keys");
+        assert!(keys == vec![format!("laptop: {}", key)]);
+
+        // Anonymous may read the keys, as sshd would.
+        rsclient.auth_anonymous().expect("Failed to auth");
+        let keys = rsclient
+            .idm_account_get_ssh_pubkeys("testperson")
+            .expect("Failed to get keys");
+        assert!(keys == vec![key.to_string()]);
+        assert!(rsclient
+            .idm_account_get_ssh_pubkeys("nosuchaccount")
+            .is_err());
+
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+        assert!(rsclient
+            .idm_account_delete_ssh_pubkey("testperson", "laptop")
+            .is_ok());
+        let keys = rsclient
+            .idm_account_get_ssh_pubkeys("testperson")
+            .expect("Failed to get keys");
+        assert!(keys.is_empty());
+    });
+}
+
+#[test]
+fn test_server_unix_extend() {
+    run_test(|rsclient: KanidmClient| {
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+        let ea: Entry = serde_json::from_str(
+            r#"{
+            "attrs": {
+                "class": ["person", "account"],
+                "name": ["testperson"],
+                "displayname": ["testperson"]
+            }
+        }"#,
+        )
+        .unwrap();
+        let eb: Entry = serde_json::from_str(
+            r#"{
+            "attrs": {
+                "class": ["person", "account"],
+                "name": ["testperson2"],
+                "displayname": ["testperson2"]
+            }
+        }"#,
+        )
+        .unwrap();
+        assert!(rsclient.create(vec![ea, eb]).is_ok());
+
+        assert!(rsclient
+            .idm_account_unix_extend("testperson", Some(20001), Some("/bin/bash"))
+            .is_ok());
+        // Another account can't be given the same gidnumber, but may have
+        // one generated.
+        match rsclient.idm_account_unix_extend("testperson2", Some(20001), None) {
+            Err(ClientError::DuplicateValue(attr)) => assert!(attr == "gidnumber"),
+            _ => panic!(),
+        }
+        assert!(rsclient
+            .idm_account_unix_extend("testperson2", None, None)
+            .is_ok());
+        assert!(rsclient
+            .idm_account_unix_cred_put("testperson", "eicieY7ahchaoCh0eeTa")
+            .is_ok());
+
+        // Anonymous may resolve them, as a unix machine would. An account is
+        // its own primary group.
+        rsclient.auth_anonymous().expect("Failed to auth");
+        let ut = rsclient
+            .idm_account_unix_token_get("testperson")
+            .expect("Failed to get unix token");
+        assert!(ut.name == "testperson");
+        assert!(ut.gidnumber == 20001);
+        assert!(ut.shell == Some("/bin/bash".to_string()));
+        assert!(ut.groups.len() == 1);
+        assert!(ut.groups[0].name == "testperson");
+        assert!(ut.groups[0].gidnumber == 20001);
+
+        let ut = rsclient
+            .idm_account_unix_token_get("testperson2")
+            .expect("Failed to get unix token");
+        assert!(ut.gidnumber >= 65536);
+        assert!(ut.shell.is_none());
+
+        // Only posix groups have a group token.
+        assert!(rsclient.idm_group_unix_token_get("idm_admins").is_err());
+    });
+}
+
+#[test]
+fn test_server_unix_auth() {
+    run_test(|rsclient: KanidmClient| {
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+        let ea: Entry = serde_json::from_str(
+            r#"{
+            "attrs": {
+                "class": ["person", "account"],
+                "name": ["testperson"],
+                "displayname": ["testperson"]
+            }
+        }"#,
+        )
+        .unwrap();
+        let eb: Entry = serde_json::from_str(
+            r#"{
+            "attrs": {
+                "class": ["object", "account", "service_account"],
+                "name": ["testunixd"],
+                "displayname": ["Test Unixd"]
+            }
+        }"#,
+        )
+        .unwrap();
+        assert!(rsclient.create(vec![ea, eb]).is_ok());
+        assert!(rsclient
+            .idm_account_unix_extend("testperson", None, None)
+            .is_ok());
+        assert!(rsclient
+            .idm_account_unix_cred_put("testperson", "eicieY7ahchaoCh0eeTa")
+            .is_ok());
+        let r = rsclient
+            .service_account_api_token_generate("testunixd", "pam", None, false)
+            .expect("Failed to generate api token");
+
+        // Until it's in idm_unix_auth_servers, the service may not check.
+        assert!(rsclient
+            .idm_account_unix_cred_verify("testperson", "eicieY7ahchaoCh0eeTa")
+            .is_err());
+        rsclient
+            .auth_api_token("testunixd", r.token.as_str())
+            .expect("Failed to auth with api token");
+        assert!(rsclient
+            .idm_account_unix_cred_verify("testperson", "eicieY7ahchaoCh0eeTa")
+            .is_err());
+
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+        assert!(rsclient
+            .modify(
+                Filter::Eq("name".to_string(), "idm_unix_auth_servers".to_string()),
+                ModifyList::new_list(vec![Modify::Present(
+                    "member".to_string(),
+                    "testunixd".to_string()
+                )]),
+                false
+            )
+            .is_ok());
+
+        // Admin is still not a service, so may not check.
+        assert!(rsclient
+            .idm_account_unix_cred_verify("testperson", "eicieY7ahchaoCh0eeTa")
+            .is_err());
+
+        rsclient
+            .auth_api_token("testunixd", r.token.as_str())
+            .expect("Failed to auth with api token");
+        let ut = rsclient
+            .idm_account_unix_cred_verify("testperson", "eicieY7ahchaoCh0eeTa")
+            .expect("Failed to check unix password")
+            .expect("Unix password not accepted");
+        assert!(ut.name == "testperson");
+
+        // Every failure looks the same.
+        for (account, cred) in &[
+            ("testperson", "not the password"),
+            ("admin", ADMIN_TEST_PASSWORD),
+            ("nosuchaccount", "eicieY7ahchaoCh0eeTa"),
+        ] {
+            assert!(rsclient
+                .idm_account_unix_cred_verify(account, cred)
+                .expect("Failed to check unix password")
+                .is_none());
+        }
+    });
+}
+
+#[test]
+fn test_server_search() {
+    run_test(|rsclient: KanidmClient| {
+        // First show we are un-authenticated.
+        let pre_res = rsclient.whoami();
+        // This means it was okay whoami, but no uat attached.
+        assert!(pre_res.unwrap().is_none());
+
+        let res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+        assert!(res.is_ok());
+
+        let rset = rsclient
+            .search_str("{\"Eq\":[\"name\", \"admin\"]}")
+            .unwrap();
+        println!("{:?}", rset);
+        let e = rset.first().unwrap();
+        // Check it's admin.
+        println!("{:?}", e);
+        assert!(e.get_ava_single("name") == Some("admin"));
+
+        // The same search as an ldap filter gives the same result.
+        let rset_ldap = rsclient
+            .search_ldap_str("(&(class=account)(name=admin))")
+            .unwrap();
+        assert!(rset_ldap.len() == 1);
+        assert!(rset_ldap[0].get_ava_single("name") == Some("admin"));
+
+        // A malformed filter is rejected before it reaches the server.
+        match rsclient.search_ldap_str("(&(name=admin)") {
+            Err(ClientError::FilterParse(_)) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // Only the requested attributes are returned, along with the uuid.
+        let rset_attrs = rsclient
+            .search_with_attrs(
+                Filter::Eq("name".to_string(), "admin".to_string()),
+                vec!["name".to_string(), "notanattr".to_string()],
+            )
+            .unwrap();
+        assert!(rset_attrs.len() == 1);
+        let names: Vec<&str> = rset_attrs[0].attrs.keys().map(|k| k.as_str()).collect();
+        assert!(names == vec!["name", "uuid"]);
+
+        // Paging through a set larger than one page gives the same entries.
+        let mut all: Vec<_> = rsclient
+            .search(Filter::Pres("class".to_string()))
+            .unwrap()
+            .into_iter()
+            .map(|e| e.attrs)
+            .collect();
+        assert!(all.len() > 3);
+        let mut paged: Vec<_> = rsclient
+            .search_paged(Filter::Pres("class".to_string()), 3)
+            .map(|e| e.unwrap().attrs)
+            .collect();
+        all.sort();
+        paged.sort();
+        assert!(all == paged);
+
+        // Sorted results come back in name order.
+        let names: Vec<String> = rsclient
+            .search_sorted(Filter::Pres("name".to_string()), "name")
+            .unwrap()
+            .into_iter()
+            .map(|e| e.get_ava_single("name").unwrap().to_string())
+            .collect();
+        assert!(names.len() > 1);
+        assert!(names.windows(2).all(|w| w[0] <= w[1]));
+
+        // Counting gives the number of entries a search would return.
+        let count = rsclient
+            .search_count(Filter::Pres("name".to_string()))
+            .unwrap();
+        assert!(count == names.len() as u64);
+        assert!(rsclient
+            .exists(Filter::Eq("name".to_string(), "admin".to_string()))
+            .unwrap());
+        assert!(!rsclient
+            .exists(Filter::Eq("name".to_string(), "notaname".to_string()))
+            .unwrap());
+    });
+}
+
+#[test]
+fn test_server_domain_info() {
+    run_test(|rsclient: KanidmClient| {
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+
+        let domain = rsclient.idm_domain_get().expect("Failed to get domain");
+        assert!(domain.domain_name == "localhost");
+        assert!(domain.domain_display_name == Some("localhost".to_string()));
+
+        assert!(rsclient
+            .idm_domain_set_display_name("Test Deployment")
+            .is_ok());
+        let renamed = rsclient.idm_domain_get().expect("Failed to get domain");
+        assert!(renamed.domain_display_name == Some("Test Deployment".to_string()));
+        assert!(renamed.uuid == domain.uuid);
+
+        let spn_filter = |spn: &str| Filter::Eq("spn".to_string(), spn.to_string());
+        let admins = rsclient
+            .search(spn_filter("idm_admins@localhost"))
+            .expect("Failed to search");
+        assert!(admins.len() == 1);
+
+        // Every spn is regenerated with the new domain name, and the old
+        // spns no longer match.
+        assert!(rsclient.idm_domain_set_name("example.com").is_ok());
+        assert!(rsclient.idm_domain_get().unwrap().domain_name == "example.com");
+        assert!(rsclient
+            .search(spn_filter("idm_admins@localhost"))
+            .unwrap()
+            .is_empty());
+        assert!(rsclient
+            .search(spn_filter("admin@localhost"))
+            .unwrap()
+            .is_empty());
+        let admin = rsclient
+            .search(spn_filter("admin@example.com"))
+            .expect("Failed to search");
+        assert!(admin.len() == 1);
+        assert!(admin[0].get_ava_single("name") == Some("admin"));
+    });
+}
+
+#[test]
+fn test_server_spn() {
+    run_test(|rsclient: KanidmClient| {
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+
+        let (_e, uat) = rsclient.whoami().unwrap().expect("No uat");
+        assert!(uat.spn == "admin@localhost");
+
+        let e: Entry = serde_json::from_str(
+            r#"{
+            "attrs": {
+                "class": ["person", "account"],
+                "name": ["testperson"],
+                "displayname": ["testperson"]
+            }
+        }"#,
+        )
+        .unwrap();
+        assert!(rsclient.create(vec![e]).is_ok());
+
+        // The spn is returned, and may be used where a name is expected.
+        let r = rsclient
+            .search(Filter::Eq(
+                "name".to_string(),
+                "testperson@localhost".to_string(),
+            ))
+            .expect("Failed to search");
+        assert!(r.len() == 1);
+        assert!(r[0].get_ava_single("spn") == Some("testperson@localhost"));
+
+        // But it can't be changed.
+        let ml = ModifyList::new_list(vec![
+            Modify::Purged("spn".to_string()),
+            Modify::Present("spn".to_string(), "other@localhost".to_string()),
+        ]);
+        assert!(rsclient
+            .modify(
+                Filter::Eq("name".to_string(), "testperson".to_string()),
+                ml,
+                false
+            )
+            .is_err());
+    });
+}
+
+// Beginning to authenticate is limited by source. Past the limit it's
+// refused with 429 until the window allows another.
+#[test]
+fn test_server_rate_limit() {
+    run_test_with(
+        |config| {
+            config.rate_limits.auth = 3;
+            config.rate_limits.window = 2;
+        },
+        |rsclient: KanidmClient| {
+            let client = reqwest::Client::builder()
+                .build()
+                .expect("Failed to build client");
+            let url = format!("{}/v1/auth", rsclient.get_url());
+            let init = serde_json::to_string(&AuthRequest {
+                step: AuthStep::Init("anonymous".to_string(), None),
+            })
+            .unwrap();
+            let post = || {
+                client
+                    .post(url.as_str())
+                    .body(init.clone())
+                    .send()
+                    .expect("Failed to send")
+            };
+
+            for _ in 0..3 {
+                assert!(post().status().is_success());
+            }
+            let mut response = post();
+            assert!(response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS);
+            let retry_after = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok())
+                .expect("Retry-After should be given");
+            assert!(retry_after >= 1 && retry_after <= 2);
+            let err: ErrorResponse = serde_json::from_str(response.text().unwrap().as_str())
+                .expect("Failed to parse error response");
+            assert!(err.code == "RateLimited");
+            assert!(err.retry_after == Some(retry_after));
+            match rsclient.auth_anonymous() {
+                Err(ClientError::RateLimited(secs)) => assert!(secs >= 1),
+                r => panic!("unexpected auth result {:?}", r),
+            }
+
+            // Once the window has passed, the limit is whole again.
+            thread::sleep(Duration::from_secs(2));
+            for _ in 0..2 {
+                assert!(post().status().is_success());
+            }
+            assert!(rsclient.auth_anonymous().is_ok());
+        },
+    );
+}
+
+// Each kind of error is given its own status, with a body that names the
+// error. These are checked without the client, which hides the status.
+#[test]
+fn test_server_error_status() {
+    run_test(|rsclient: KanidmClient| {
+        let client = reqwest::Client::builder()
+            .cookie_store(true)
+            .build()
+            .expect("Failed to build client");
+        let url = rsclient.get_url().to_string();
+        let post = |path: &str, body: String| -> reqwest::Response {
+            client
+                .post(format!("{}{}", url, path).as_str())
+                .body(body)
+                .send()
+                .expect("Failed to send")
+        };
+        let post_err = |path: &str, body: String| -> (reqwest::StatusCode, ErrorResponse) {
+            let mut response = post(path, body);
+            let err = serde_json::from_str(response.text().unwrap().as_str())
+                .expect("Failed to parse error response");
+            (response.status(), err)
+        };
+        let name_eq = |name: &str| Filter::Eq("name".to_string(), name.to_string());
+
+        let search = serde_json::to_string(&SearchRequest::new(name_eq("admin"))).unwrap();
+        let (status, err) = post_err("/v1/search", search);
+        assert!(status == reqwest::StatusCode::UNAUTHORIZED);
+        assert!(err.code == "NotAuthenticated");
+
+        let init = AuthRequest {
+            step: AuthStep::Init("admin".to_string(), None),
+        };
+        let creds = AuthRequest {
+            step: AuthStep::Creds(vec![AuthCredential::Password(
+                ADMIN_TEST_PASSWORD.to_string(),
+            )]),
+        };
+        assert!(post("/v1/auth", serde_json::to_string(&init).unwrap())
+            .status()
+            .is_success());
+        assert!(post("/v1/auth", serde_json::to_string(&creds).unwrap())
+            .status()
+            .is_success());
+
+        let (status, err) = post_err("/v1/sessions/notauuid/_revoke", String::new());
+        assert!(status == reqwest::StatusCode::BAD_REQUEST);
+        assert!(err.code == "InvalidUuid");
+
+        let mut sr = SearchRequest::new(name_eq("admin"));
+        sr.page_size = Some(0);
+        let (status, err) = post_err("/v1/search", serde_json::to_string(&sr).unwrap());
+        assert!(status == reqwest::StatusCode::BAD_REQUEST);
+        assert!(err.code == "InvalidRequestState");
+
+        let dr = DeleteRequest::new(name_eq("nonexistant"));
+        let (status, err) = post_err("/v1/delete", serde_json::to_string(&dr).unwrap());
+        assert!(status == reqwest::StatusCode::NOT_FOUND);
+        assert!(err.code == "NoMatchingEntries");
+
+        let e: Entry = serde_json::from_str(
+            r#"{
+            "attrs": {
+                "class": ["group"],
+                "name": ["idm_admins"]
+            }
+        }"#,
+        )
+        .unwrap();
+        let cr = CreateRequest { entries: vec![e] };
+        let (status, err) = post_err("/v1/create", serde_json::to_string(&cr).unwrap());
+        assert!(status == reqwest::StatusCode::CONFLICT);
+        assert!(err.code == "DuplicateValue");
+        assert!(err.detail == Some("name".to_string()));
+
+        let dr = DeleteRequest::new(name_eq("anonymous"));
+        let (status, _) = post_err("/v1/delete", serde_json::to_string(&dr).unwrap());
+        assert!(status == reqwest::StatusCode::FORBIDDEN);
+
+        // The client turns these into its own errors.
+        assert!(match rsclient.delete(name_eq("nonexistant"), false) {
+            Err(ClientError::Unauthorized) => true,
+            _ => false,
+        });
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+        assert!(match rsclient.delete(name_eq("nonexistant"), false) {
+            Err(ClientError::NotFound) => true,
+            _ => false,
+        });
+    });
+}
+
+// Test hitting all auth-required endpoints and assert they give unauthorized.
+
+// Every response carries the id the server logged the operation under, and an
+// error body carries the same id so it can be matched with the logs.
+#[test]
+fn test_server_eventid() {
+    run_test(|rsclient: KanidmClient| {
+        let client = reqwest::Client::builder()
+            .cookie_store(true)
+            .build()
+            .expect("Failed to build client");
+        let url = rsclient.get_url().to_string();
+        let post = |path: &str, body: String| -> reqwest::Response {
+            client
+                .post(format!("{}{}", url, path).as_str())
+                .body(body)
+                .send()
+                .expect("Failed to send")
+        };
+        let opid = |response: &reqwest::Response| -> String {
+            response
+                .headers()
+                .get(KOPID)
+                .and_then(|hv| hv.to_str().ok())
+                .expect("No operation id in response")
+                .to_string()
+        };
+
+        let init = AuthRequest {
+            step: AuthStep::Init("admin".to_string(), None),
+        };
+        let creds = AuthRequest {
+            step: AuthStep::Creds(vec![AuthCredential::Password(
+                ADMIN_TEST_PASSWORD.to_string(),
+            )]),
+        };
+        let first = post("/v1/auth", serde_json::to_string(&init).unwrap());
+        assert!(first.status().is_success());
+        let response = post("/v1/auth", serde_json::to_string(&creds).unwrap());
+        assert!(response.status().is_success());
+        // Each operation has its own id.
+        assert!(opid(&first) != opid(&response));
+
+        let e: Entry = serde_json::from_str(
+            r#"{
+            "attrs": {
+                "class": ["nonexistantclass"],
+                "name": ["testeventid"]
+            }
+        }"#,
+        )
+        .unwrap();
+        let cr = CreateRequest { entries: vec![e] };
+        let mut response = post("/v1/create", serde_json::to_string(&cr).unwrap());
+        assert!(response.status() == reqwest::StatusCode::BAD_REQUEST);
+        let eventid = opid(&response);
+        let err: ErrorResponse = serde_json::from_str(response.text().unwrap().as_str())
+            .expect("Failed to parse error response");
+        assert!(err.code == "SchemaViolation");
+        assert!(err.eventid == Some(eventid));
+    });
+}
+
+// The metrics of a few requests are counted, and given in the prometheus
+// text format.
+#[test]
+fn test_server_metrics() {
+    run_test(|rsclient: KanidmClient| {
+        let url = format!("{}/metrics", rsclient.get_url());
+        let scrape = || -> String {
+            let mut response = reqwest::get(url.as_str()).expect("Failed to scrape");
+            assert!(response.status().is_success());
+            response.text().expect("Failed to read metrics")
+        };
+        let value = |metrics: &str, name: &str| -> f64 {
+            metrics
+                .lines()
+                .find(|l| l.starts_with(name) && l[name.len()..].starts_with(' '))
+                .and_then(|l| l[name.len() + 1..].parse().ok())
+                .expect("Metric not found")
+        };
+
+        let before = scrape();
+        assert!(value(before.as_str(), "kanidm_db_entries") > 0.0);
+
+        assert!(rsclient
+            .auth_simple_password("admin", "wrong password")
+            .is_err());
+        assert!(rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .is_ok());
+
+        let e: Entry = serde_json::from_str(
+            r#"{
+            "attrs": {
+                "class": ["group"],
+                "name": ["testmetrics"]
+            }
+        }"#,
+        )
+        .unwrap();
+        assert!(rsclient.create(vec![e]).is_ok());
+        assert!(rsclient
+            .search(Filter::Eq("name".to_string(), "testmetrics".to_string()))
+            .is_ok());
+
+        let after = scrape();
+        for name in &[
+            "kanidm_auth_total{result=\"success\"}",
+            "kanidm_auth_total{result=\"denied\"}",
+            "kanidm_http_requests_total{operation=\"create\",status=\"2xx\"}",
+            "kanidm_http_requests_total{operation=\"search\",status=\"2xx\"}",
+            "kanidm_http_request_duration_seconds_count{operation=\"auth\"}",
+            "kanidm_backend_transaction_duration_seconds_count{kind=\"write\"}",
+            "kanidm_db_entries",
+        ] {
+            assert!(value(after.as_str(), name) > value(before.as_str(), name));
+        }
+    });
+}
+
+// The probes answer without authentication, and the readiness probe reports
+// each of its checks.
+#[test]
+fn test_server_status() {
+    run_test(|rsclient: KanidmClient| {
+        let probe = |path: &str| -> HealthResponse {
+            let mut response = reqwest::get(format!("{}{}", rsclient.get_url(), path).as_str())
+                .expect("Failed to probe");
+            assert!(response.status() == reqwest::StatusCode::OK);
+            serde_json::from_str(response.text().unwrap().as_str())
+                .expect("Failed to parse health response")
+        };
+
+        let live = probe("/status");
+        assert!(live.healthy);
+        assert!(live.checks["runtime"].healthy);
+
+        let ready = probe("/status/ready");
+        assert!(ready.healthy);
+        assert!(ready.checks["database"] == HealthCheck::new_healthy());
+        assert!(ready.checks["consistency"] == HealthCheck::new_healthy());
+    });
+}
+
+#[test]
+fn test_server_backup() {
+    run_test(|rsclient: KanidmClient| {
+        match rsclient.backup() {
+            Err(ClientError::Unauthorized) => {}
+            r => panic!("unexpected backup result {:?}", r),
+        }
+
+        let res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+        assert!(res.is_ok());
+
+        // The backup is written on the server, which here is the same host.
+        let path = rsclient.backup().expect("Backup failed!");
+        let backup = std::fs::read_to_string(path.as_str()).expect("Failed to read backup");
+        let backup: serde_json::Value =
+            serde_json::from_str(backup.as_str()).expect("Failed to parse backup");
+        assert!(backup["version"] == 1);
+        assert!(backup["entries"].as_array().map(|e| e.len() > 0) == Some(true));
+    });
+}
+
+#[test]
+fn test_server_reindex_vacuum() {
+    run_test(|rsclient: KanidmClient| {
+        match rsclient.reindex() {
+            Err(ClientError::Unauthorized) => {}
+            r => panic!("unexpected reindex result {:?}", r),
+        }
+        match rsclient.vacuum() {
+            Err(ClientError::Unauthorized) => {}
+            r => panic!("unexpected vacuum result {:?}", r),
+        }
+
+        let res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+        assert!(res.is_ok());
+
+        let indexes = rsclient.reindex().expect("Reindex failed!");
+        assert!(indexes.get("name.eq").map(|c| *c > 0) == Some(true));
+        assert!(rsclient.vacuum().is_ok());
+
+        // Searches by an indexed attribute still find the entry.
+        let r = rsclient
+            .search(Filter::Eq("name".to_string(), "admin".to_string()))
+            .expect("Search failed!");
+        assert!(r.len() == 1);
+    });
+}
+
+#[test]
+fn test_server_search_plan() {
+    run_test(|rsclient: KanidmClient| {
+        // Anonymous may ask for a trace, but isn't given the plan.
+        let anon = rsclient.auth_anonymous();
+        assert!(anon.is_ok());
+        let (_, plan) = rsclient
+            .search_traced(Filter::Eq("name".to_string(), "anonymous".to_string()))
+            .expect("Search failed!");
+        assert!(plan.is_none());
+
+        let res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+        assert!(res.is_ok());
+
+        let (r, plan) = rsclient
+            .search_traced(Filter::Eq("name".to_string(), "admin".to_string()))
+            .expect("Search failed!");
+        assert!(r.len() == 1);
+        let plan = plan.expect("No plan");
+        assert!(plan.root.state == PlanState::Indexed);
+        assert!(
+            plan.root.find("eq name").map(|n| n.state.to_string()) == Some("indexed".to_string())
+        );
+        assert!(plan.allowed == 1);
+
+        let (_, plan) = rsclient
+            .search_traced(Filter::Sub(
+                "description".to_string(),
+                "builtin".to_string(),
+            ))
+            .expect("Search failed!");
+        let plan = plan.expect("No plan");
+        assert!(plan.root.state.to_string() == "full scan");
+        assert!(plan.root.find("sub description").map(|n| n.state) == Some(PlanState::FullScan));
+        assert!(plan.loaded >= plan.matched && plan.matched >= plan.allowed);
+    });
+}
+
+#[test]
+fn test_server_index_status() {
+    run_test(|rsclient: KanidmClient| {
+        match rsclient.index_status() {
+            Err(ClientError::Unauthorized) => {}
+            r => panic!("unexpected index status result {:?}", r),
+        }
+
+        let res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+        assert!(res.is_ok());
+
+        let status = rsclient.index_status().expect("Index status failed!");
+        let name_eq = status
+            .iter()
+            .find(|s| s.name == "name.eq")
+            .expect("No name.eq index");
+        assert!(name_eq.declared && name_eq.exists && name_eq.keys > 0);
+
+        // The schema reports the indexes it declares.
+        let attrs = rsclient
+            .schema_attribute_list()
+            .expect("Schema list failed!");
+        let name_attr = attrs
+            .iter()
+            .find(|a| a.name == "name")
+            .expect("No name attribute");
+        assert!(name_attr.index == vec!["EQUALITY".to_string()]);
+    });
+}
+
+#[test]
+fn test_server_changes() {
+    run_test(|rsclient: KanidmClient| {
+        match rsclient.changes_since(None) {
+            Err(ClientError::Unauthorized) => {}
+            r => panic!("unexpected changes result {:?}", r),
+        }
+
+        let res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+        assert!(res.is_ok());
+
+        let e: Entry = serde_json::from_str(
+            r#"{
+            "attrs": {
+                "class": ["person"],
+                "name": ["testperson"],
+                "displayname": ["testperson"]
+            }
+        }"#,
+        )
+        .unwrap();
+        assert!(rsclient.create(vec![e]).is_ok());
+
+        let (_, cid) = rsclient.changes_since(None).expect("Changes failed!");
+        let cid = cid.expect("No change id");
+
+        let ml = ModifyList::new_list(vec![
+            Modify::Purged("displayname".to_string()),
+            Modify::Present("displayname".to_string(), "Renamed".to_string()),
+        ]);
+        assert!(rsclient
+            .modify(
+                Filter::Eq("name".to_string(), "testperson".to_string()),
+                ml,
+                false
+            )
+            .is_ok());
+
+        let (entries, latest) = rsclient
+            .changes_since(Some(cid.as_str()))
+            .expect("Changes failed!");
+        assert!(entries.len() == 1);
+        assert!(entries[0].attrs == vec!["displayname".to_string()]);
+        assert!(latest.as_ref() == Some(&entries[0].cid));
+
+        match rsclient.changes_since(Some("not-a-cid")) {
+            Err(ClientError::Operation(reqwest::StatusCode::BAD_REQUEST, _)) => {}
+            r => panic!("unexpected changes result {:?}", r),
+        }
+    });
+}
+
+// Each export gives what changed since the cursor of the last, as the
+// exporting account may read it.
+fn export_names(
+    rsclient: &KanidmClient,
+    since: Option<&str>,
+) -> (Vec<String>, Vec<String>, String) {
+    let mut stream = rsclient.export(since).expect("Export failed!");
+    let mut names = Vec::new();
+    let mut deleted = Vec::new();
+    for r in stream.by_ref() {
+        match r.expect("Export failed!") {
+            ExportRecord::Entry(e) => {
+                names.push(e.get_ava_single("name").expect("No name").to_string())
+            }
+            ExportRecord::Deleted(u) => deleted.push(u),
+        }
+    }
+    names.sort();
+    let cursor = stream.cursor().expect("No cursor").to_string();
+    (names, deleted, cursor)
+}
+
+#[test]
+fn test_server_export() {
+    run_test(|rsclient: KanidmClient| {
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+        let mut uuids = Vec::new();
+        for name in ["exporter", "testperson1", "testperson2"].iter() {
+            let e: Entry = serde_json::from_str(&format!(
+                r#"{{
+                "attrs": {{
+                    "class": ["person", "account"],
+                    "name": ["{0}"],
+                    "displayname": ["{0}"]
+                }}
+            }}"#,
+                name
+            ))
+            .unwrap();
+            uuids.extend(rsclient.create(vec![e]).expect("Create failed!"));
+        }
+        assert!(rsclient
+            .idm_account_set_password("exporter", "a brand new password", false)
+            .is_ok());
+        let mut acp = AccessControlProfile::new(
+            "exporter_people",
+            Filter::Eq("name".to_string(), "exporter".to_string()),
+            Filter::Eq("class".to_string(), "person".to_string()),
+        );
+        acp.search = Some(vec![
+            "name".to_string(),
+            "uuid".to_string(),
+            "displayname".to_string(),
+        ]);
+        assert!(rsclient.idm_acp_create(&acp).is_ok());
+
+        let as_exporter = || {
+            rsclient
+                .auth_simple_password("exporter", "a brand new password")
+                .expect("Failed to auth");
+        };
+        let as_admin = || {
+            rsclient
+                .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+                .expect("Failed to auth");
+        };
+
+        // The full export only has what the exporter may read.
+        as_exporter();
+        let (names, deleted, cursor) = export_names(&rsclient, None);
+        assert!(names == vec!["exporter", "testperson1", "testperson2"]);
+        assert!(deleted.is_empty());
+
+        // The first cycle: a change, a delete, and a group the exporter
+        // can't read.
+        as_admin();
+        let ml = ModifyList::new_list(vec![
+            Modify::Purged("displayname".to_string()),
+            Modify::Present("displayname".to_string(), "Renamed".to_string()),
+        ]);
+        assert!(rsclient
+            .modify(
+                Filter::Eq("name".to_string(), "testperson1".to_string()),
+                ml,
+                false
+            )
+            .is_ok());
+        assert!(rsclient
+            .delete(
+                Filter::Eq("name".to_string(), "testperson2".to_string()),
+                false
+            )
+            .is_ok());
+        let g: Entry = serde_json::from_str(
+            r#"{
+            "attrs": {
+                "class": ["group"],
+                "name": ["testgroup"]
+            }
+        }"#,
+        )
+        .unwrap();
+        assert!(rsclient.create(vec![g]).is_ok());
+
+        as_exporter();
+        let (names, deleted, next) = export_names(&rsclient, Some(cursor.as_str()));
+        assert!(names == vec!["testperson1"]);
+        assert!(deleted == vec![uuids[2].clone()]);
+        assert!(next != cursor);
+        let cursor = next;
+
+        // The second cycle: only what was created since is given.
+        as_admin();
+        let e: Entry = serde_json::from_str(
+            r#"{
+            "attrs": {
+                "class": ["person", "account"],
+                "name": ["testperson3"],
+                "displayname": ["testperson3"]
+            }
+        }"#,
+        )
+        .unwrap();
+        assert!(rsclient.create(vec![e]).is_ok());
+
+        as_exporter();
+        let (names, deleted, next) = export_names(&rsclient, Some(cursor.as_str()));
+        assert!(names == vec!["testperson3"]);
+        assert!(deleted.is_empty());
+
+        // Nothing has changed since.
+        let (names, deleted, last) = export_names(&rsclient, Some(next.as_str()));
+        assert!(names.is_empty() && deleted.is_empty());
+        assert!(last == next);
+
+        match rsclient.export(Some("not-a-cursor")) {
+            Err(ClientError::Operation(reqwest::StatusCode::BAD_REQUEST, _)) => {}
+            r => panic!("unexpected export result {:?}", r.map(|_| ())),
+        }
+    });
+}
+
+// Bodies are read as cbor when the content type says so, and responses are
+// given as cbor when it's accepted, otherwise both are json.
+#[test]
+fn test_server_cbor() {
+    run_test(|rsclient: KanidmClient| {
+        let client = reqwest::Client::builder()
+            .cookie_store(true)
+            .build()
+            .expect("Failed to build client");
+        let url = rsclient.get_url().to_string();
+        let post = |path: &str, content: &str, accept: &str, body: Vec<u8>| -> reqwest::Response {
+            client
+                .post(format!("{}{}", url, path).as_str())
+                .header(reqwest::header::CONTENT_TYPE, content)
+                .header(reqwest::header::ACCEPT, accept)
+                .body(body)
+                .send()
+                .expect("Failed to send")
+        };
+        let is_cbor = |response: &reqwest::Response| -> bool {
+            response
+                .headers()
+                .get(reqwest::header::CONTENT_TYPE)
+                .and_then(|hv| hv.to_str().ok())
+                == Some("application/cbor")
+        };
+        let body = |response: &mut reqwest::Response| -> Vec<u8> {
+            let mut buf = Vec::new();
+            response.copy_to(&mut buf).expect("Failed to read body");
+            buf
+        };
+
+        let init = AuthRequest {
+            step: AuthStep::Init("admin".to_string(), None),
+        };
+        let creds = AuthRequest {
+            step: AuthStep::Creds(vec![AuthCredential::Password(
+                ADMIN_TEST_PASSWORD.to_string(),
+            )]),
+        };
+        let cbor = "application/cbor";
+        let json = "application/json";
+        let response = post("/v1/auth", cbor, cbor, serde_cbor::to_vec(&init).unwrap());
+        assert!(response.status().is_success());
+        assert!(is_cbor(&response));
+        let mut response = post("/v1/auth", cbor, cbor, serde_cbor::to_vec(&creds).unwrap());
+        assert!(response.status().is_success());
+        let ar: AuthResponse =
+            serde_cbor::from_slice(&body(&mut response)).expect("Failed to decode cbor");
+        assert!(match ar.state {
+            AuthState::Success(_) => true,
+            _ => false,
+        });
+
+        let sr = SearchRequest::new(Filter::Eq("name".to_string(), "admin".to_string()));
+        // A json request may be answered in cbor.
+        let mut response = post("/v1/search", json, cbor, serde_json::to_vec(&sr).unwrap());
+        assert!(is_cbor(&response));
+        let r: SearchResponse =
+            serde_cbor::from_slice(&body(&mut response)).expect("Failed to decode cbor");
+        assert!(r.entries.len() == 1);
+        // And a cbor request in json.
+        let mut response = post("/v1/search", cbor, json, serde_cbor::to_vec(&sr).unwrap());
+        assert!(!is_cbor(&response));
+        let r: SearchResponse =
+            serde_json::from_slice(&body(&mut response)).expect("Failed to decode json");
+        assert!(r.entries.len() == 1);
+
+        // Errors are given in cbor too.
+        let mut sr = SearchRequest::new(Filter::Pres("class".to_string()));
+        sr.page_size = Some(0);
+        let mut response = post("/v1/search", cbor, cbor, serde_cbor::to_vec(&sr).unwrap());
+        assert!(response.status() == reqwest::StatusCode::BAD_REQUEST);
+        let err: ErrorResponse =
+            serde_cbor::from_slice(&body(&mut response)).expect("Failed to decode cbor");
+        assert!(err.code == "InvalidRequestState");
+
+        // A json body claiming to be cbor can't be decoded.
+        let response = post("/v1/search", cbor, cbor, serde_json::to_vec(&sr).unwrap());
+        assert!(response.status() == reqwest::StatusCode::BAD_REQUEST);
+
+        // The client uses cbor throughout, as this server accepts it.
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+        assert!(rsclient.search(Filter::Pres("class".to_string())).is_ok());
+        assert!(rsclient.whoami().expect("Failed to whoami").is_some());
+        assert!(rsclient.uses_cbor());
+
+        // And a client may still choose json.
+        let json_client = KanidmClient::new(rsclient.get_url(), None).prefer_json();
+        json_client
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+        assert!(json_client
+            .search(Filter::Pres("class".to_string()))
+            .is_ok());
+        assert!(!json_client.uses_cbor());
+    });
+}
+
+#[test]
+fn test_server_search_stream() {
+    run_test(|rsclient: KanidmClient| {
+        let filter = Filter::Eq("class".to_string(), "group".to_string());
+        match rsclient.search_stream(filter.clone()) {
+            Err(ClientError::Unauthorized) => {}
+            r => panic!("unauthenticated stream was not refused: {:?}", r),
+        }
+
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+        let entries: Vec<Entry> = (0..300)
+            .map(|i| {
+                serde_json::from_str(&format!(
+                    r#"{{
+                    "attrs": {{
+                        "class": ["group"],
+                        "name": ["stream_group_{}"]
+                    }}
+                }}"#,
+                    i
+                ))
+                .unwrap()
+            })
+            .collect();
+        assert!(rsclient.create(entries).is_ok());
+
+        let names = |entries: Vec<Entry>| -> std::collections::BTreeSet<String> {
+            entries
+                .into_iter()
+                .filter_map(|e| e.attrs.get("name").and_then(|v| v.first().cloned()))
+                .collect()
+        };
+        let expect = names(rsclient.search(filter.clone()).expect("Failed to search"));
+        assert!(expect.len() > 300);
+
+        let streamed: Result<Vec<Entry>, _> = rsclient
+            .search_stream(filter.clone())
+            .expect("Failed to stream")
+            .collect();
+        assert!(rsclient.uses_cbor());
+        assert!(names(streamed.expect("Stream failed")) == expect);
+
+        // The same entries are given as json lines.
+        let json_client = KanidmClient::new(rsclient.get_url(), None).prefer_json();
+        json_client
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+        let streamed: Result<Vec<Entry>, _> = json_client
+            .search_stream(filter.clone())
+            .expect("Failed to stream")
+            .collect();
+        assert!(names(streamed.expect("Stream failed")) == expect);
+
+        // A stream can't be paged, and says so before anything is sent.
+        let mut sr = SearchRequest::new(filter);
+        sr.page_size = Some(10);
+        let client = reqwest::Client::builder()
+            .cookie_store(true)
+            .build()
+            .expect("Failed to build client");
+        let url = rsclient.get_url().to_string();
+        let creds = AuthRequest {
+            step: AuthStep::Init("admin".to_string(), None),
+        };
+        client
+            .post(format!("{}/v1/auth", url).as_str())
+            .json(&creds)
+            .send()
+            .expect("Failed to send");
+        let creds = AuthRequest {
+            step: AuthStep::Creds(vec![AuthCredential::Password(
+                ADMIN_TEST_PASSWORD.to_string(),
+            )]),
+        };
+        client
+            .post(format!("{}/v1/auth", url).as_str())
+            .json(&creds)
+            .send()
+            .expect("Failed to send");
+        let mut response = client
+            .post(format!("{}/v1/search/_stream", url).as_str())
+            .json(&sr)
+            .send()
+            .expect("Failed to send");
+        assert!(response.status() == reqwest::StatusCode::BAD_REQUEST);
+        let err: ErrorResponse = response.json().expect("Failed to decode json");
+        assert!(err.code == "InvalidRequestState");
+    });
+}
+
+#[test]
+fn test_server_idm_account_lifecycle() {
+    run_test(|rsclient: KanidmClient| {
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+
+        let uuid = rsclient
+            .idm_account_create("idm_person", "Idm Person")
+            .unwrap();
+        let e = rsclient
+            .idm_account_get("idm_person")
+            .unwrap()
+            .expect("Account not found");
+        assert!(e.get_ava_single("uuid") == Some(uuid.as_str()));
+        assert!(e.get_ava_single("displayname") == Some("Idm Person"));
+
+        rsclient
+            .idm_account_set_displayname("idm_person", "Renamed Person")
+            .unwrap();
+        let e = rsclient.idm_account_get("idm_person").unwrap().unwrap();
+        assert!(e.get_ava_single("displayname") == Some("Renamed Person"));
+
+        // A group isn't an account, even with the same name.
+        rsclient.idm_group_create("idm_not_account").unwrap();
+        assert!(rsclient
+            .idm_account_get("idm_not_account")
+            .unwrap()
+            .is_none());
+
+        rsclient.idm_account_delete("idm_person").unwrap();
+        assert!(rsclient.idm_account_get("idm_person").unwrap().is_none());
+        match rsclient.idm_account_delete("idm_person") {
+            Err(ClientError::NoSuchAccount(name)) => assert!(name == "idm_person"),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        match rsclient.idm_account_set_displayname("idm_not_account", "Nobody") {
+            Err(ClientError::NoSuchAccount(name)) => assert!(name == "idm_not_account"),
+            r => panic!("Unexpected result {:?}", r),
+        }
+    });
+}
+
+#[test]
+fn test_server_idm_group_members() {
+    run_test(|rsclient: KanidmClient| {
+        rsclient
+            .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+            .expect("Failed to auth");
+
+        rsclient
+            .idm_account_create("idm_member_a", "Member A")
+            .unwrap();
+        rsclient
+            .idm_account_create("idm_member_b", "Member B")
+            .unwrap();
+        rsclient.idm_group_create("idm_members").unwrap();
+        assert!(rsclient
+            .idm_group_list_members("idm_members")
+            .unwrap()
+            .is_empty());
+
+        // Members are given by name, and listed by name rather than uuid.
+        rsclient
+            .idm_group_add_members("idm_members", &["idm_member_b", "idm_member_a"])
+            .unwrap();
+        assert!(
+            rsclient.idm_group_list_members("idm_members").unwrap()
+                == vec!["idm_member_a".to_string(), "idm_member_b".to_string()]
+        );
+        // Adding a member again changes nothing.
+        rsclient
+            .idm_group_add_members("idm_members", &["idm_member_a"])
+            .unwrap();
+        assert!(
+            rsclient
+                .idm_group_list_members("idm_members")
+                .unwrap()
+                .len()
+                == 2
+        );
+
+        rsclient
+            .idm_group_remove_members("idm_members", &["idm_member_a"])
+            .unwrap();
+        assert!(
+            rsclient.idm_group_list_members("idm_members").unwrap()
+                == vec!["idm_member_b".to_string()]
+        );
+
+        match rsclient.idm_group_add_members("idm_no_group", &["idm_member_a"]) {
+            Err(ClientError::NoSuchGroup(name)) => assert!(name == "idm_no_group"),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        match rsclient.idm_group_list_members("idm_no_group") {
+            Err(ClientError::NoSuchGroup(_)) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+
+        rsclient.idm_group_delete("idm_members").unwrap();
+        match rsclient.idm_group_delete("idm_members") {
+            Err(ClientError::NoSuchGroup(name)) => assert!(name == "idm_members"),
+            r => panic!("Unexpected result {:?}", r),
+        }
+        // An account isn't a group.
+        match rsclient.idm_group_delete("idm_member_a") {
+            Err(ClientError::NoSuchGroup(_)) => {}
+            r => panic!("Unexpected result {:?}", r),
+        }
+    });
+}
+
+// What's too large is refused before it's decoded with 413, and what's decoded
+// but asks for too much is refused with an error that names the limit.
+#[test]
+fn test_server_request_limits() {
+    run_test_with(
+        |config| {
+            config.request_limits.maximum = 4096;
+            config.request_limits.max_create_entries = 2;
+            config.request_limits.max_modifications = 3;
+            config.request_limits.max_value_length = 64;
+        },
+        |rsclient: KanidmClient| {
+            let a_res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+            assert!(a_res.is_ok());
+
+            let person = |name: &str, desc: &str| -> Entry {
+                let mut e: Entry = serde_json::from_str(
+                    r#"{
+                    "attrs": {
+                        "class": ["person", "account"]
+                    }
+                }"#,
+                )
+                .unwrap();
+                e.attrs.insert("name".to_string(), vec![name.to_string()]);
+                e.attrs
+                    .insert("displayname".to_string(), vec![name.to_string()]);
+                e.attrs
+                    .insert("description".to_string(), vec![desc.to_string()]);
+                e
+            };
+
+            // Over the body limit, however few entries it has.
+            let client = reqwest::Client::builder()
+                .build()
+                .expect("Failed to build client");
+            let cr = CreateRequest::new(vec![person("limit_a", "a"), person("limit_b", "b")]);
+            let mut body = serde_json::to_string(&cr).unwrap();
+            body.extend(std::iter::repeat(' ').take(4096));
+            let mut response = client
+                .post(format!("{}/v1/create", rsclient.get_url()).as_str())
+                .body(body)
+                .send()
+                .expect("Failed to send");
+            assert!(response.status() == reqwest::StatusCode::PAYLOAD_TOO_LARGE);
+            let err: ErrorResponse = serde_json::from_str(response.text().unwrap().as_str())
+                .expect("Failed to parse error response");
+            assert!(err.code == "RequestTooLarge");
+            assert!(err.limit == Some(4096));
+
+            match rsclient.create(vec![
+                person("limit_a", "a"),
+                person("limit_b", "b"),
+                person("limit_c", "c"),
+            ]) {
+                Err(ClientError::Operation(status, err)) => {
+                    assert!(status == reqwest::StatusCode::BAD_REQUEST);
+                    assert!(err.code == "TooManyEntries");
+                    assert!(err.limit == Some(2));
+                }
+                r => panic!("Unexpected result {:?}", r),
+            }
+
+            let long = "x".repeat(65);
+            match rsclient.create(vec![person("limit_a", long.as_str())]) {
+                Err(ClientError::Operation(status, err)) => {
+                    assert!(status == reqwest::StatusCode::BAD_REQUEST);
+                    assert!(err.code == "ValueTooLong");
+                    assert!(err.detail == Some("description".to_string()));
+                    assert!(err.limit == Some(64));
+                }
+                r => panic!("Unexpected result {:?}", r),
+            }
+
+            // Nothing refused was created, and what's within the limits is.
+            assert!(rsclient
+                .create(vec![person("limit_a", "a"), person("limit_b", "b")])
+                .is_ok());
+
+            let f = Filter::Eq("name".to_string(), "limit_a".to_string());
+            let mods = (0..4)
+                .map(|i| Modify::Present("description".to_string(), i.to_string()))
+                .collect();
+            match rsclient.modify(f.clone(), ModifyList::new_list(mods), false) {
+                Err(ClientError::Operation(_, err)) => {
+                    assert!(err.code == "TooManyModifications");
+                    assert!(err.limit == Some(3));
+                }
+                r => panic!("Unexpected result {:?}", r),
+            }
+            let mods = vec![Modify::Present("description".to_string(), long.clone())];
+            match rsclient.modify(f, ModifyList::new_list(mods), false) {
+                Err(ClientError::Operation(_, err)) => assert!(err.code == "ValueTooLong"),
+                r => panic!("Unexpected result {:?}", r),
+            }
+        },
+    );
+}
+
+// The filter limits of the config are those searches are held to.
+#[test]
+fn test_server_filter_limits() {
+    run_test_with(
+        |config| {
+            config.filter_limits.max_elements = 3;
+            config.filter_limits_anonymous.max_depth = 2;
+            config.filter_limits_anonymous.max_inclusion = 2;
+        },
+        |rsclient: KanidmClient| {
+            let name_eq = |name: &str| Filter::Eq("name".to_string(), name.to_string());
+
+            assert!(rsclient.auth_anonymous().is_ok());
+            assert!(rsclient.search(Filter::And(vec![name_eq("admin")])).is_ok());
+            match rsclient.search(Filter::And(vec![Filter::And(vec![name_eq("admin")])])) {
+                Err(ClientError::Operation(status, err)) => {
+                    assert!(status == reqwest::StatusCode::BAD_REQUEST);
+                    assert!(err.code == "ResourceLimit");
+                }
+                r => panic!("Unexpected result {:?}", r),
+            }
+            let names = |n: &[&str]| {
+                Filter::Inclusion(
+                    "name".to_string(),
+                    n.iter().map(|s| s.to_string()).collect(),
+                )
+            };
+            assert!(rsclient.search(names(&["admin", "anonymous"])).is_ok());
+            match rsclient.search(names(&["admin", "anonymous", "idm_admins"])) {
+                Err(ClientError::Operation(_, err)) => assert!(err.code == "ResourceLimit"),
+                r => panic!("Unexpected result {:?}", r),
+            }
+
+            let a_res = rsclient.auth_simple_password("admin", ADMIN_TEST_PASSWORD);
+            assert!(a_res.is_ok());
+            // Deeper than anonymous may go, but within the elements allowed.
+            assert!(rsclient
+                .search(Filter::And(vec![Filter::And(vec![name_eq("admin")])]))
+                .is_ok());
+            match rsclient.search(Filter::Or(vec![
+                name_eq("admin"),
+                name_eq("anonymous"),
+                name_eq("idm_admins"),
+            ])) {
+                Err(ClientError::Operation(_, err)) => assert!(err.code == "ResourceLimit"),
+                r => panic!("Unexpected result {:?}", r),
+            }
+        },
+    );
+}
@@ -0,0 +1,332 @@
+#![deny(warnings)]
+
+extern crate actix;
+use actix::prelude::*;
+
+extern crate kanidm;
+extern crate kanidm_client;
+extern crate kanidm_proto;
+extern crate reqwest;
+extern crate serde_json;
+
+use kanidm_client::KanidmClient;
+
+use kanidm::config::{Configuration, IntegrationTestConfig};
+use kanidm::core::create_server_core;
+use kanidm_proto::v1::{Entry, Filter, Modify, ModifyList};
+
+use serde_json::Value;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+extern crate env_logger;
+
+static PORT_ALLOC: AtomicUsize = AtomicUsize::new(20080);
+static ADMIN_TEST_PASSWORD: &'static str = "integration test admin password";
+
+// The payloads below are as Azure AD and Okta send them, as it's their
+// quirks that a provisioning endpoint has to cope with.
+static AZURE_CREATE_USER: &'static str = r#"{"schemas":["urn:ietf:params:scim:schemas:core:2.0:User","urn:ietf:params:scim:schemas:extension:enterprise:2.0:User"],"externalId":"0a21f0f2-8d2a-4f8e-bf98-7363c4aed4ef","userName":"Test_User@testuser.com","active":true,"emails":[{"primary":true,"type":"work","value":"Test_User@testuser.com"}],"meta":{"resourceType":"User"},"name":{"formatted":"givenName familyName","familyName":"familyName","givenName":"givenName"},"roles":[]}"#;
+static AZURE_PATCH_USER: &'static str = r#"{"schemas":["urn:ietf:params:scim:api:messages:2.0:PatchOp"],"Operations":[{"op":"Replace","path":"emails[type eq \"work\"].value","value":"updatedEmail@testuser.com"},{"op":"Replace","path":"name.familyName","value":"updatedFamilyName"}]}"#;
+static AZURE_DISABLE_USER: &'static str = r#"{"schemas":["urn:ietf:params:scim:api:messages:2.0:PatchOp"],"Operations":[{"op":"Replace","path":"active","value":"False"}]}"#;
+static OKTA_ENABLE_USER: &'static str = r#"{"schemas":["urn:ietf:params:scim:api:messages:2.0:PatchOp"],"Operations":[{"op":"replace","value":{"active":true}}]}"#;
+static OKTA_CREATE_GROUP: &'static str = r#"{"schemas":["urn:ietf:params:scim:schemas:core:2.0:Group"],"displayName":"Test SCIMv2","members":[]}"#;
+
+fn run_test(test_fn: fn(KanidmClient) -> ()) {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let (tx, rx) = mpsc::channel();
+    let port = PORT_ALLOC.fetch_add(1, Ordering::SeqCst);
+
+    let int_config = Box::new(IntegrationTestConfig {
+        admin_password: ADMIN_TEST_PASSWORD.to_string(),
+    });
+
+    let mut config = Configuration::new();
+    config.address = format!("127.0.0.1:{}", port);
+    config.secure_cookies = false;
+    config.integration_test_config = Some(int_config);
+
+    thread::spawn(move || {
+        System::run(move || {
+            create_server_core(config);
+            let _ = tx.send(System::current());
+        });
+    });
+    let sys = rx.recv().unwrap();
+    System::set_current(sys.clone());
+
+    let addr = format!("http://127.0.0.1:{}", port);
+    let rsclient = KanidmClient::new(addr.as_str(), None);
+
+    test_fn(rsclient);
+
+    let _ = sys.stop();
+}
+
+struct ScimClient {
+    client: reqwest::Client,
+    url: String,
+    token: String,
+}
+
+struct ScimResponse {
+    status: reqwest::StatusCode,
+    location: Option<String>,
+    body: Value,
+}
+
+impl ScimClient {
+    fn send(&self, method: reqwest::Method, path: &str, body: Option<&str>) -> ScimResponse {
+        let mut req = self
+            .client
+            .request(method, format!("{}/scim/v2{}", self.url, path).as_str())
+            .header(
+                reqwest::header::AUTHORIZATION,
+                format!("Bearer {}", self.token),
+            )
+            .header(reqwest::header::CONTENT_TYPE, "application/scim+json");
+        if let Some(body) = body {
+            req = req.body(body.to_string());
+        }
+        let mut response = req.send().expect("Failed to send");
+        let text = response.text().expect("Failed to read body");
+        ScimResponse {
+            status: response.status(),
+            location: response
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string()),
+            body: if text.is_empty() {
+                Value::Null
+            } else {
+                serde_json::from_str(text.as_str()).expect("Invalid json")
+            },
+        }
+    }
+
+    fn get(&self, path: &str) -> ScimResponse {
+        self.send(reqwest::Method::GET, path, None)
+    }
+
+    fn patch(&self, path: &str, body: &str) -> ScimResponse {
+        self.send(reqwest::Method::PATCH, path, Some(body))
+    }
+}
+
+// A service account that may provision, and a client with its api token.
+fn setup_scim(rsclient: &KanidmClient) -> ScimClient {
+    rsclient
+        .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+        .expect("Failed to auth");
+    let e: Entry = serde_json::from_str(
+        r#"{"attrs": {"class": ["object", "account", "service_account"], "name": ["scimsync"], "displayname": ["Scim Sync"]}}"#,
+    )
+    .unwrap();
+    assert!(rsclient.create(vec![e]).is_ok());
+    assert!(rsclient
+        .modify(
+            Filter::Eq("name".to_string(), "idm_scim_provisioners".to_string()),
+            ModifyList::new_list(vec![Modify::Present(
+                "member".to_string(),
+                "scimsync".to_string()
+            )]),
+            false
+        )
+        .is_ok());
+    let r = rsclient
+        .service_account_api_token_generate("scimsync", "scim", None, true)
+        .expect("Failed to generate api token");
+
+    ScimClient {
+        client: reqwest::Client::new(),
+        url: rsclient.get_url().to_string(),
+        token: r.token,
+    }
+}
+
+fn create_user(scim: &ScimClient, name: &str) -> String {
+    let body = format!(
+        r#"{{"schemas":["urn:ietf:params:scim:schemas:core:2.0:User"],"userName":"{}","active":true}}"#,
+        name
+    );
+    let r = scim.send(reqwest::Method::POST, "/Users", Some(body.as_str()));
+    assert!(r.status == reqwest::StatusCode::CREATED);
+    r.body["id"].as_str().expect("No id was given").to_string()
+}
+
+fn member_ids(group: &Value) -> Vec<String> {
+    let mut ids: Vec<String> = group["members"]
+        .as_array()
+        .expect("No members were given")
+        .iter()
+        .map(|m| m["value"].as_str().unwrap().to_string())
+        .collect();
+    ids.sort();
+    ids
+}
+
+#[test]
+fn test_scim_auth() {
+    run_test(|rsclient: KanidmClient| {
+        let mut scim = setup_scim(&rsclient);
+        let r = scim.get("/Users");
+        assert!(r.status == reqwest::StatusCode::OK);
+
+        let token = scim.token.clone();
+        scim.token = format!("{}x", token);
+        let r = scim.get("/Users");
+        assert!(r.status == reqwest::StatusCode::UNAUTHORIZED);
+        assert!(r.body["status"] == "401");
+
+        // A token that may only read can't provision.
+        scim.token = rsclient
+            .service_account_api_token_generate("scimsync", "scim read", None, false)
+            .expect("Failed to generate api token")
+            .token;
+        assert!(scim.get("/Users").status == reqwest::StatusCode::OK);
+        let r = scim.send(reqwest::Method::POST, "/Users", Some(AZURE_CREATE_USER));
+        assert!(r.status == reqwest::StatusCode::FORBIDDEN);
+    });
+}
+
+#[test]
+fn test_scim_users() {
+    run_test(|rsclient: KanidmClient| {
+        let scim = setup_scim(&rsclient);
+
+        let r = scim.send(reqwest::Method::POST, "/Users", Some(AZURE_CREATE_USER));
+        assert!(r.status == reqwest::StatusCode::CREATED);
+        let id = r.body["id"].as_str().expect("No id was given").to_string();
+        let path = format!("/Users/{}", id);
+        assert!(r
+            .location
+            .expect("No location was given")
+            .ends_with(path.as_str()));
+        assert!(r.body["userName"]
+            .as_str()
+            .unwrap()
+            .eq_ignore_ascii_case("Test_User@testuser.com"));
+        assert!(r.body["externalId"] == "0a21f0f2-8d2a-4f8e-bf98-7363c4aed4ef");
+        assert!(r.body["active"] == true);
+
+        // Clients create a user again when they lose track of it, and expect
+        // to be told it exists.
+        let r = scim.send(reqwest::Method::POST, "/Users", Some(AZURE_CREATE_USER));
+        assert!(r.status == reqwest::StatusCode::CONFLICT);
+        assert!(r.body["scimType"] == "uniqueness");
+
+        let r = scim.get("/Users?filter=userName%20eq%20%22Test_User%40testuser.com%22");
+        assert!(r.status == reqwest::StatusCode::OK);
+        assert!(r.body["totalResults"] == 1);
+        assert!(r.body["Resources"][0]["id"] == id.as_str());
+        let r =
+            scim.get("/Users?filter=externalId%20eq%20%220a21f0f2-8d2a-4f8e-bf98-7363c4aed4ef%22");
+        assert!(r.body["totalResults"] == 1);
+        let r = scim.get("/Users?filter=title%20eq%20%22x%22");
+        assert!(r.status == reqwest::StatusCode::BAD_REQUEST);
+        assert!(r.body["scimType"] == "invalidFilter");
+
+        let r = scim.patch(path.as_str(), AZURE_PATCH_USER);
+        assert!(r.status == reqwest::StatusCode::OK);
+        assert!(r.body["emails"][0]["value"] == "updatedEmail@testuser.com");
+
+        let r = scim.patch(path.as_str(), AZURE_DISABLE_USER);
+        assert!(r.status == reqwest::StatusCode::OK);
+        assert!(r.body["active"] == false);
+        let r = scim.patch(path.as_str(), OKTA_ENABLE_USER);
+        assert!(r.body["active"] == true);
+
+        let r = scim.send(
+            reqwest::Method::PUT,
+            path.as_str(),
+            Some(r#"{"schemas":["urn:ietf:params:scim:schemas:core:2.0:User"],"userName":"test_user@testuser.com","displayName":"Test User","emails":[]}"#),
+        );
+        assert!(r.status == reqwest::StatusCode::OK);
+        assert!(r.body["displayName"] == "Test User");
+        assert!(r.body.get("emails").is_none());
+        assert!(r.body.get("externalId").is_none());
+
+        assert!(scim.get("/Users/notauuid").status == reqwest::StatusCode::NOT_FOUND);
+        let r = scim.send(reqwest::Method::DELETE, path.as_str(), None);
+        assert!(r.status == reqwest::StatusCode::NO_CONTENT);
+        assert!(scim.get(path.as_str()).status == reqwest::StatusCode::NOT_FOUND);
+    });
+}
+
+#[test]
+fn test_scim_groups() {
+    run_test(|rsclient: KanidmClient| {
+        let scim = setup_scim(&rsclient);
+        let alice = create_user(&scim, "alice");
+        let bob = create_user(&scim, "bob");
+
+        let r = scim.send(reqwest::Method::POST, "/Groups", Some(OKTA_CREATE_GROUP));
+        assert!(r.status == reqwest::StatusCode::CREATED);
+        let id = r.body["id"].as_str().expect("No id was given").to_string();
+        let path = format!("/Groups/{}", id);
+        assert!(member_ids(&r.body).is_empty());
+
+        // Azure AD adds and removes members one change at a time.
+        let r = scim.patch(
+            path.as_str(),
+            format!(
+                r#"{{"schemas":["urn:ietf:params:scim:api:messages:2.0:PatchOp"],"Operations":[{{"op":"Add","path":"members","value":[{{"$ref":null,"value":"{}"}}]}}]}}"#,
+                alice
+            )
+            .as_str(),
+        );
+        assert!(r.status == reqwest::StatusCode::OK);
+        assert!(member_ids(&r.body) == vec![alice.clone()]);
+
+        // Okta replaces the whole of the members on each push.
+        let r = scim.patch(
+            path.as_str(),
+            format!(
+                r#"{{"schemas":["urn:ietf:params:scim:api:messages:2.0:PatchOp"],"Operations":[{{"op":"replace","path":"members","value":[{{"value":"{}","display":"bob"}}]}}]}}"#,
+                bob
+            )
+            .as_str(),
+        );
+        assert!(r.status == reqwest::StatusCode::OK);
+        assert!(member_ids(&r.body) == vec![bob.clone()]);
+
+        let r = scim.patch(
+            path.as_str(),
+            format!(
+                r#"{{"schemas":["urn:ietf:params:scim:api:messages:2.0:PatchOp"],"Operations":[{{"op":"remove","path":"members[value eq \"{}\"]"}},{{"op":"add","path":"members","value":[{{"value":"{}","display":"alice"}}]}}]}}"#,
+                bob, alice
+            )
+            .as_str(),
+        );
+        assert!(r.status == reqwest::StatusCode::OK);
+        assert!(member_ids(&r.body) == vec![alice.clone()]);
+
+        // Okta renames a group by giving it again, with its id.
+        let r = scim.patch(
+            path.as_str(),
+            format!(
+                r#"{{"schemas":["urn:ietf:params:scim:api:messages:2.0:PatchOp"],"Operations":[{{"op":"replace","value":{{"id":"{}","displayName":"Renamed"}}}}]}}"#,
+                id
+            )
+            .as_str(),
+        );
+        assert!(r.status == reqwest::StatusCode::OK);
+        assert!(r.body["displayName"]
+            .as_str()
+            .unwrap()
+            .eq_ignore_ascii_case("Renamed"));
+        assert!(member_ids(&r.body) == vec![alice.clone()]);
+
+        let r = scim.get("/Groups?filter=displayName%20eq%20%22renamed%22");
+        assert!(r.body["totalResults"] == 1);
+        assert!(r.body["Resources"][0]["id"] == id.as_str());
+        // Users are never listed as groups.
+        let r = scim.get(format!("/Groups/{}", alice).as_str());
+        assert!(r.status == reqwest::StatusCode::NOT_FOUND);
+
+        let r = scim.send(reqwest::Method::DELETE, path.as_str(), None);
+        assert!(r.status == reqwest::StatusCode::NO_CONTENT);
+    });
+}
@@ -1,5 +1,8 @@
+use chrono::{SecondsFormat, TimeZone, Utc};
 use std::collections::BTreeMap;
 use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
 use uuid::Uuid;
 
 // These proto implementations are here because they have public definitions
@@ -17,6 +20,58 @@ pub enum SchemaError {
     Corrupted,
 }
 
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemaError::NotImplemented => write!(f, "not implemented by the schema"),
+            SchemaError::InvalidClass => write!(f, "a class is missing or not in the schema"),
+            SchemaError::MissingMustAttribute(a) => {
+                write!(f, "the required attribute {} is missing", a)
+            }
+            SchemaError::InvalidAttribute => write!(f, "an attribute is not in the schema"),
+            SchemaError::InvalidAttributeSyntax => {
+                write!(f, "a value does not match the syntax of its attribute")
+            }
+            SchemaError::EmptyFilter => write!(f, "the filter is empty"),
+            SchemaError::Corrupted => write!(f, "the schema is corrupted"),
+        }
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
+// What kind of failure the database had. The detail of it is only written
+// to the server's log.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum BackendErrorKind {
+    // The database was locked by another transaction. Retrying may succeed.
+    Busy,
+    Corrupt,
+    Io,
+    Constraint,
+    Full,
+    Other,
+}
+
+impl BackendErrorKind {
+    pub fn is_retryable(&self) -> bool {
+        *self == BackendErrorKind::Busy
+    }
+}
+
+impl fmt::Display for BackendErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendErrorKind::Busy => write!(f, "the database is busy"),
+            BackendErrorKind::Corrupt => write!(f, "the database is corrupt"),
+            BackendErrorKind::Io => write!(f, "the database could not be read or written"),
+            BackendErrorKind::Constraint => write!(f, "a database constraint was violated"),
+            BackendErrorKind::Full => write!(f, "the disk is full"),
+            BackendErrorKind::Other => write!(f, "an unexpected database error occured"),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq)]
 pub enum OperationError {
     EmptyRequest,
@@ -40,18 +95,466 @@ pub enum OperationError {
     InvalidSchemaState(&'static str),
     InvalidAccountState(&'static str),
     BackendEngine,
-    SQLiteError, //(RusqliteError)
+    // The database failed, and what kind of failure it was.
+    SQLiteError(BackendErrorKind),
     FsError,
     SerdeJsonError,
     SerdeCborError,
+    CryptographyError,
     AccessDenied,
     NotAuthenticated,
     InvalidAuthState(&'static str),
     InvalidSessionState,
     SystemProtectedObject,
+    // The entry is system protected, and this attribute of it may not be
+    // changed.
+    SystemProtectedAttribute(String),
+    ResourceLimit,
+    // The index of the batch item that failed, and why.
+    BatchItemFailed(u64, Box<OperationError>),
+    ModifyAssertionFailed,
+    // A value of this unique attribute is already held by another entry.
+    DuplicateValue(String),
+    // The entry has aged out of the recycle bin and can no longer be revived.
+    ReviveTombstone,
+    // The uuid of each entry that could not be revived, and why. A revive
+    // is all or nothing, so when this is returned no entry was revived.
+    ReviveFailed(Vec<(String, OperationError)>),
+    // The totp code given did not match the pending secret.
+    InvalidTOTP,
+    // The webauthn response did not verify, and why.
+    InvalidWebauthn(&'static str),
+    // The current password given for a password change was wrong.
+    IncorrectPassword,
+    // The new password was rejected by the password quality checks, and why.
+    PasswordQuality(Vec<PasswordFeedback>),
+    // The session authenticated too long ago for this operation. It can be
+    // continued once the credentials are given again with a reauth.
+    ReauthRequired,
+    // Too many requests were made. Another may be made after this many
+    // seconds.
+    RateLimited(u64),
+    // The file is not a backup this server can restore. The version is given
+    // when the file is a backup, but of another version.
+    IncompatibleBackup(Option<u32>),
+    // The part of the database named was written by a newer server. The
+    // version it's at, and the newest this server supports, are given.
+    IncompatibleDatabase(String, i64, i64),
+    // The search could give more entries than the limit of this many. The
+    // filter must be narrowed, or the results requested in pages no larger
+    // than the limit.
+    ResultLimit(u64),
+    // The body of the request was larger than this many bytes, so it was
+    // refused before it was read.
+    RequestTooLarge(u64),
+    // A create may give at most this many entries.
+    TooManyEntries(u64),
+    // A modify may make at most this many modifications, or a batch this
+    // many across all of its changes.
+    TooManyModifications(u64),
+    // A value of the attribute is longer than this many bytes.
+    ValueTooLong(String, u64),
+    // The changelog no longer holds the changes since the given change id,
+    // as they are older than it keeps. Everything must be read again.
+    ChangelogTrimmed,
+    // An oauth2 request was refused, with the error code of RFC 6749 that
+    // says why, such as invalid_grant.
+    Oauth2(&'static str),
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+// Why a password was rejected, and what could be done about it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum PasswordFeedback {
+    // The password is shorter than this many characters.
+    TooShort(u32),
+    // The password is in the badlist of known weak passwords.
+    BadListed,
+    // The password is too easily guessed. This is followed by the reasons
+    // below where they are known.
+    TooGuessable,
+    AvoidRepeats,
+    AvoidSequences,
+    AvoidCommonWords,
+    AvoidPersonalInfo,
+}
+
+impl fmt::Display for PasswordFeedback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PasswordFeedback::TooShort(min) => {
+                write!(f, "too short, use at least {} characters", min)
+            }
+            PasswordFeedback::BadListed => write!(f, "found in badlist of known weak passwords"),
+            PasswordFeedback::TooGuessable => write!(
+                f,
+                "too guessable, use a longer password or add more uncommon words"
+            ),
+            PasswordFeedback::AvoidRepeats => write!(f, "avoid repeated characters"),
+            PasswordFeedback::AvoidSequences => {
+                write!(f, "avoid sequences like abc or 1234, and rows of keys")
+            }
+            PasswordFeedback::AvoidCommonWords => write!(f, "avoid common words and passwords"),
+            PasswordFeedback::AvoidPersonalInfo => {
+                write!(f, "avoid your name and account name")
+            }
+        }
+    }
+}
+
+impl OperationError {
+    // The name of the error, as it is given in an ErrorResponse. These are
+    // never changed once given out, so clients may act on them.
+    pub fn code(&self) -> &'static str {
+        match self {
+            OperationError::EmptyRequest => "EmptyRequest",
+            OperationError::Backend => "Backend",
+            OperationError::NoMatchingEntries => "NoMatchingEntries",
+            OperationError::CorruptedEntry(_) => "CorruptedEntry",
+            OperationError::ConsistencyError(_) => "ConsistencyError",
+            OperationError::SchemaViolation(_) => "SchemaViolation",
+            OperationError::Plugin => "Plugin",
+            OperationError::FilterGeneration => "FilterGeneration",
+            OperationError::FilterUUIDResolution => "FilterUUIDResolution",
+            OperationError::InvalidAttributeName(_) => "InvalidAttributeName",
+            OperationError::InvalidAttribute(_) => "InvalidAttribute",
+            OperationError::InvalidDBState => "InvalidDBState",
+            OperationError::InvalidEntryID => "InvalidEntryID",
+            OperationError::InvalidRequestState => "InvalidRequestState",
+            OperationError::InvalidState => "InvalidState",
+            OperationError::InvalidEntryState => "InvalidEntryState",
+            OperationError::InvalidUuid => "InvalidUuid",
+            OperationError::InvalidACPState(_) => "InvalidACPState",
+            OperationError::InvalidSchemaState(_) => "InvalidSchemaState",
+            OperationError::InvalidAccountState(_) => "InvalidAccountState",
+            OperationError::BackendEngine => "BackendEngine",
+            OperationError::SQLiteError(_) => "SQLiteError",
+            OperationError::FsError => "FsError",
+            OperationError::SerdeJsonError => "SerdeJsonError",
+            OperationError::SerdeCborError => "SerdeCborError",
+            OperationError::CryptographyError => "CryptographyError",
+            OperationError::AccessDenied => "AccessDenied",
+            OperationError::NotAuthenticated => "NotAuthenticated",
+            OperationError::InvalidAuthState(_) => "InvalidAuthState",
+            OperationError::InvalidSessionState => "InvalidSessionState",
+            OperationError::SystemProtectedObject => "SystemProtectedObject",
+            OperationError::SystemProtectedAttribute(_) => "SystemProtectedAttribute",
+            OperationError::ResourceLimit => "ResourceLimit",
+            OperationError::BatchItemFailed(_, _) => "BatchItemFailed",
+            OperationError::ModifyAssertionFailed => "ModifyAssertionFailed",
+            OperationError::DuplicateValue(_) => "DuplicateValue",
+            OperationError::ReviveTombstone => "ReviveTombstone",
+            OperationError::ReviveFailed(_) => "ReviveFailed",
+            OperationError::InvalidTOTP => "InvalidTOTP",
+            OperationError::InvalidWebauthn(_) => "InvalidWebauthn",
+            OperationError::IncorrectPassword => "IncorrectPassword",
+            OperationError::PasswordQuality(_) => "PasswordQuality",
+            OperationError::ReauthRequired => "ReauthRequired",
+            OperationError::RateLimited(_) => "RateLimited",
+            OperationError::IncompatibleBackup(_) => "IncompatibleBackup",
+            OperationError::IncompatibleDatabase(_, _, _) => "IncompatibleDatabase",
+            OperationError::ResultLimit(_) => "ResultLimit",
+            OperationError::RequestTooLarge(_) => "RequestTooLarge",
+            OperationError::TooManyEntries(_) => "TooManyEntries",
+            OperationError::TooManyModifications(_) => "TooManyModifications",
+            OperationError::ValueTooLong(_, _) => "ValueTooLong",
+            OperationError::ChangelogTrimmed => "ChangelogTrimmed",
+            OperationError::Oauth2(_) => "Oauth2",
+        }
+    }
+}
+
+impl fmt::Display for OperationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OperationError::EmptyRequest => write!(f, "the request is empty"),
+            OperationError::Backend => write!(f, "the backend failed"),
+            OperationError::NoMatchingEntries => write!(f, "no entries matched"),
+            OperationError::CorruptedEntry(id) => write!(f, "entry {} is corrupted", id),
+            OperationError::ConsistencyError(errs) => {
+                write!(f, "the consistency check failed")?;
+                let mut sep = ": ";
+                for e in errs.iter().filter_map(|r| r.as_ref().err()) {
+                    write!(f, "{}{}", sep, e)?;
+                    sep = "; ";
+                }
+                Ok(())
+            }
+            OperationError::SchemaViolation(se) => write!(f, "schema violation: {}", se),
+            OperationError::Plugin => write!(f, "a plugin failed"),
+            OperationError::FilterGeneration => write!(f, "the filter could not be generated"),
+            OperationError::FilterUUIDResolution => {
+                write!(f, "a name in the filter could not be resolved to a uuid")
+            }
+            OperationError::InvalidAttributeName(a) => {
+                write!(f, "{} is not a valid attribute name", a)
+            }
+            OperationError::InvalidAttribute(a) => write!(f, "invalid attribute: {}", a),
+            OperationError::InvalidDBState => write!(f, "the database is in an invalid state"),
+            OperationError::InvalidEntryID => write!(f, "invalid entry id"),
+            OperationError::InvalidRequestState => write!(f, "the request is invalid"),
+            OperationError::InvalidState => write!(f, "the server is in an invalid state"),
+            OperationError::InvalidEntryState => write!(f, "an entry is in an invalid state"),
+            OperationError::InvalidUuid => write!(f, "invalid uuid"),
+            OperationError::InvalidACPState(s) => {
+                write!(f, "invalid access control profile: {}", s)
+            }
+            OperationError::InvalidSchemaState(s) => write!(f, "invalid schema: {}", s),
+            OperationError::InvalidAccountState(s) => write!(f, "invalid account: {}", s),
+            OperationError::BackendEngine => write!(f, "the backend engine failed"),
+            OperationError::SQLiteError(kind) => write!(f, "the database failed: {}", kind),
+            OperationError::FsError => write!(f, "a filesystem operation failed"),
+            OperationError::SerdeJsonError => write!(f, "json serialisation failed"),
+            OperationError::SerdeCborError => write!(f, "cbor serialisation failed"),
+            OperationError::CryptographyError => write!(f, "a cryptographic operation failed"),
+            OperationError::AccessDenied => write!(f, "access denied"),
+            OperationError::NotAuthenticated => write!(f, "not authenticated"),
+            OperationError::InvalidAuthState(s) => {
+                write!(f, "invalid authentication state: {}", s)
+            }
+            OperationError::InvalidSessionState => write!(f, "the session is invalid"),
+            OperationError::SystemProtectedObject => write!(f, "the entry is system protected"),
+            OperationError::SystemProtectedAttribute(a) => {
+                write!(
+                    f,
+                    "the attribute {} of a system protected entry may not change",
+                    a
+                )
+            }
+            OperationError::ResourceLimit => write!(f, "a resource limit was exceeded"),
+            OperationError::BatchItemFailed(i, e) => write!(f, "batch item {} failed: {}", i, e),
+            OperationError::ModifyAssertionFailed => write!(f, "a modify assertion failed"),
+            OperationError::DuplicateValue(a) => {
+                write!(f, "another entry already has this value of {}", a)
+            }
+            OperationError::ReviveTombstone => {
+                write!(f, "the entry is a tombstone and can not be revived")
+            }
+            OperationError::ReviveFailed(failed) => {
+                write!(f, "the revive failed")?;
+                let mut sep = ": ";
+                for (u, e) in failed.iter() {
+                    write!(f, "{}{} {}", sep, u, e)?;
+                    sep = "; ";
+                }
+                Ok(())
+            }
+            OperationError::InvalidTOTP => write!(f, "the totp code is incorrect"),
+            OperationError::InvalidWebauthn(s) => write!(f, "invalid webauthn response: {}", s),
+            OperationError::IncorrectPassword => write!(f, "the password is incorrect"),
+            OperationError::PasswordQuality(feedback) => {
+                write!(f, "the password was rejected")?;
+                let mut sep = ": ";
+                for fb in feedback.iter() {
+                    write!(f, "{}{}", sep, fb)?;
+                    sep = "; ";
+                }
+                Ok(())
+            }
+            OperationError::ReauthRequired => {
+                write!(f, "the credentials must be given again for this operation")
+            }
+            OperationError::RateLimited(secs) => {
+                write!(f, "too many requests, retry after {} seconds", secs)
+            }
+            OperationError::IncompatibleBackup(Some(v)) => {
+                write!(
+                    f,
+                    "the backup is of version {}, which can not be restored",
+                    v
+                )
+            }
+            OperationError::IncompatibleBackup(None) => {
+                write!(f, "the file is not a backup that can be restored")
+            }
+            OperationError::IncompatibleDatabase(part, v, supported) => write!(
+                f,
+                "the database's {} is at version {}, newer than the version {} this server supports",
+                part, v, supported
+            ),
+            OperationError::ResultLimit(limit) => write!(
+                f,
+                "the search could give more than {} entries, narrow the filter or search in pages",
+                limit
+            ),
+            OperationError::RequestTooLarge(limit) => {
+                write!(f, "the request is larger than the limit of {} bytes", limit)
+            }
+            OperationError::TooManyEntries(limit) => {
+                write!(f, "a create may give at most {} entries", limit)
+            }
+            OperationError::TooManyModifications(limit) => {
+                write!(f, "a modify may make at most {} modifications", limit)
+            }
+            OperationError::ValueTooLong(attr, limit) => write!(
+                f,
+                "a value of {} is longer than the limit of {} bytes",
+                attr, limit
+            ),
+            OperationError::ChangelogTrimmed => write!(
+                f,
+                "the changes since that change id have been trimmed from the changelog"
+            ),
+            OperationError::Oauth2(code) => write!(f, "the oauth2 request was refused: {}", code),
+        }
+    }
+}
+
+// The wrapped error is the source, and for a failed consistency check, the
+// first failure.
+impl std::error::Error for OperationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            OperationError::SchemaViolation(se) => Some(se),
+            OperationError::BatchItemFailed(_, e) => Some(&**e),
+            OperationError::ConsistencyError(errs) => errs
+                .iter()
+                .filter_map(|r| r.as_ref().err())
+                .next()
+                .map(|e| e as &(dyn std::error::Error + 'static)),
+            _ => None,
+        }
+    }
+}
+
+impl From<SchemaError> for OperationError {
+    fn from(se: SchemaError) -> Self {
+        OperationError::SchemaViolation(se)
+    }
+}
+
+impl From<ConsistencyError> for OperationError {
+    fn from(ce: ConsistencyError) -> Self {
+        OperationError::ConsistencyError(vec![Err(ce)])
+    }
+}
+
+// The header that every response for an operation carries its event id in.
+// The server writes the id with everything it logs for the operation.
+pub const KOPID: &str = "X-KANIDM-OPID";
+
+// The body of every failed response, which is the stable wire form of an
+// OperationError. The code names the error, and the other fields are only
+// present for the errors that have them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ErrorResponse {
+    pub code: String,
+    // What went wrong, for people. This may change between versions, so
+    // should not be acted on, except for DuplicateValue, ValueTooLong and
+    // SystemProtectedAttribute where it is the name of the attribute.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    // For PasswordQuality, why the password was rejected.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub feedback: Vec<PasswordFeedback>,
+    // For BatchItemFailed, the index of the item that failed, and why.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inner: Option<Box<ErrorResponse>>,
+    // For ReviveFailed, the uuid of each entry that could not be revived,
+    // and why.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failed: Vec<(String, ErrorResponse)>,
+    // For RateLimited, how many seconds until another request may be made.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+    // For an error of a limit that was exceeded, such as RequestTooLarge,
+    // the limit. For ValueTooLong, the detail is the name of the attribute.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u64>,
+    // For SQLiteError, what kind of failure it was.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend: Option<BackendErrorKind>,
+    // The id of the operation that failed, as in the KOPID header.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eventid: Option<String>,
+}
+
+impl ErrorResponse {
+    fn new(code: &str) -> Self {
+        ErrorResponse {
+            code: code.to_string(),
+            detail: None,
+            feedback: Vec::new(),
+            index: None,
+            inner: None,
+            failed: Vec::new(),
+            retry_after: None,
+            limit: None,
+            backend: None,
+            eventid: None,
+        }
+    }
+
+    // Whether the same request may succeed if it's made again later.
+    pub fn is_retryable(&self) -> bool {
+        self.code == "RateLimited" || self.backend.map(|k| k.is_retryable()).unwrap_or(false)
+    }
+}
+
+impl From<&OperationError> for ErrorResponse {
+    fn from(e: &OperationError) -> Self {
+        let mut er = ErrorResponse::new(e.code());
+        match e {
+            OperationError::CorruptedEntry(id) => er.detail = Some(id.to_string()),
+            OperationError::ConsistencyError(_) | OperationError::SchemaViolation(_) => {
+                er.detail = Some(e.to_string())
+            }
+            OperationError::InvalidAttributeName(s)
+            | OperationError::SystemProtectedAttribute(s)
+            | OperationError::DuplicateValue(s) => er.detail = Some(s.clone()),
+            OperationError::InvalidAttribute(s)
+            | OperationError::InvalidACPState(s)
+            | OperationError::InvalidSchemaState(s)
+            | OperationError::InvalidAccountState(s)
+            | OperationError::InvalidAuthState(s)
+            | OperationError::InvalidWebauthn(s)
+            | OperationError::Oauth2(s) => er.detail = Some(s.to_string()),
+            OperationError::BatchItemFailed(index, inner) => {
+                er.index = Some(*index);
+                er.inner = Some(Box::new(ErrorResponse::from(inner.as_ref())));
+            }
+            OperationError::ReviveFailed(failed) => {
+                er.failed = failed
+                    .iter()
+                    .map(|(u, e)| (u.clone(), ErrorResponse::from(e)))
+                    .collect();
+            }
+            OperationError::PasswordQuality(feedback) => er.feedback = feedback.clone(),
+            OperationError::RateLimited(secs) => er.retry_after = Some(*secs),
+            // The detail is kept for the clients that read the limit from it.
+            OperationError::ResultLimit(limit) => {
+                er.detail = Some(limit.to_string());
+                er.limit = Some(*limit);
+            }
+            OperationError::RequestTooLarge(limit)
+            | OperationError::TooManyEntries(limit)
+            | OperationError::TooManyModifications(limit) => er.limit = Some(*limit),
+            OperationError::ValueTooLong(attr, limit) => {
+                er.detail = Some(attr.clone());
+                er.limit = Some(*limit);
+            }
+            OperationError::SQLiteError(kind) => er.backend = Some(*kind),
+            _ => {}
+        }
+        er
+    }
+}
+
+impl fmt::Display for ErrorResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.code)?;
+        if let Some(detail) = &self.detail {
+            write!(f, ": {}", detail)?;
+        }
+        if let Some(inner) = &self.inner {
+            write!(f, ": {}", inner)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum ConsistencyError {
     Unknown,
     // Class, Attribute
@@ -62,10 +565,116 @@ pub enum ConsistencyError {
     UuidNotUnique(String),
     RefintNotUpheld(u64),
     MemberOfInvalid(u64),
-    InvalidAttributeType(&'static str),
+    SpnInvalid(u64),
+    InvalidAttributeType(String),
     DuplicateUniqueAttribute(String),
+    // A schema definition that is still used by entries.
+    SchemaAttributeInUse(String),
+    SchemaClassInUse(String),
+    SchemaUniqueAttributeNotIndexed(String),
+    // The lines that sqlite's integrity check gave.
+    SqliteIntegrityFailure(Vec<String>),
+    // An entry the schema would refuse.
+    EntrySchemaInvalid(u64),
+    // The index, and the entry whose keys in it are wrong.
+    IndexInvalid(String, u64),
+}
+
+impl fmt::Display for ConsistencyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConsistencyError::Unknown => write!(f, "unknown inconsistency"),
+            ConsistencyError::SchemaClassMissingAttribute(c, a) => write!(
+                f,
+                "class {} names the attribute {}, which is not in the schema",
+                c, a
+            ),
+            ConsistencyError::QueryServerSearchFailure => write!(f, "an internal search failed"),
+            ConsistencyError::EntryUuidCorrupt(id) => write!(f, "entry {} has a corrupt uuid", id),
+            ConsistencyError::UuidIndexCorrupt(u) => {
+                write!(f, "the uuid index is corrupt for {}", u)
+            }
+            ConsistencyError::UuidNotUnique(u) => {
+                write!(f, "the uuid {} is held by more than one entry", u)
+            }
+            ConsistencyError::RefintNotUpheld(id) => {
+                write!(f, "entry {} refers to an entry that does not exist", id)
+            }
+            ConsistencyError::MemberOfInvalid(id) => {
+                write!(f, "entry {} has an incorrect memberof", id)
+            }
+            ConsistencyError::SpnInvalid(id) => write!(f, "entry {} has an incorrect spn", id),
+            ConsistencyError::InvalidAttributeType(a) => {
+                write!(f, "invalid attribute type: {}", a)
+            }
+            ConsistencyError::DuplicateUniqueAttribute(a) => write!(
+                f,
+                "more than one entry has the same value of the unique attribute {}",
+                a
+            ),
+            ConsistencyError::SchemaAttributeInUse(a) => {
+                write!(f, "the schema attribute {} is still used by entries", a)
+            }
+            ConsistencyError::SchemaClassInUse(c) => {
+                write!(f, "the schema class {} is still used by entries", c)
+            }
+            ConsistencyError::SchemaUniqueAttributeNotIndexed(a) => {
+                write!(f, "the unique attribute {} is not indexed for equality", a)
+            }
+            ConsistencyError::SqliteIntegrityFailure(lines) => write!(
+                f,
+                "the database file failed its integrity check: {}",
+                lines.join("; ")
+            ),
+            ConsistencyError::EntrySchemaInvalid(id) => {
+                write!(f, "entry {} does not conform to the schema", id)
+            }
+            ConsistencyError::IndexInvalid(i, id) => {
+                write!(f, "the index {} has the wrong keys for entry {}", i, id)
+            }
+        }
+    }
+}
+
+impl ConsistencyError {
+    // Whether repair can fix this, as it's derived from other state that
+    // can be trusted.
+    pub fn repairable(&self) -> bool {
+        match self {
+            ConsistencyError::UuidIndexCorrupt(_)
+            | ConsistencyError::RefintNotUpheld(_)
+            | ConsistencyError::MemberOfInvalid(_)
+            | ConsistencyError::SpnInvalid(_)
+            | ConsistencyError::IndexInvalid(_, _) => true,
+            _ => false,
+        }
+    }
+
+    // What an admin can do about this.
+    pub fn guidance(&self) -> &'static str {
+        match self {
+            ConsistencyError::EntryUuidCorrupt(_) | ConsistencyError::UuidNotUnique(_) => {
+                "the uuids of entries can't be trusted - restore a backup from before this appeared"
+            }
+            ConsistencyError::SqliteIntegrityFailure(_) => {
+                "the database file is damaged - restore a backup"
+            }
+            ConsistencyError::SchemaClassMissingAttribute(_, _)
+            | ConsistencyError::SchemaAttributeInUse(_)
+            | ConsistencyError::SchemaClassInUse(_)
+            | ConsistencyError::SchemaUniqueAttributeNotIndexed(_)
+            | ConsistencyError::EntrySchemaInvalid(_)
+            | ConsistencyError::DuplicateUniqueAttribute(_) => {
+                "correct the schema or the entries by hand, or restore a backup"
+            }
+            _ if self.repairable() => "run kanidmd repair",
+            _ => "this can't be repaired - restore a backup",
+        }
+    }
 }
 
+impl std::error::Error for ConsistencyError {}
+
 /* ===== higher level types ===== */
 // These are all types that are conceptually layers ontop of entry and
 // friends. They allow us to process more complex requests and provide
@@ -82,9 +691,20 @@ pub struct Group {
 pub struct Claim {
     pub name: String,
     pub uuid: String,
-    // These can be ephemeral, or shortlived in a session.
-    // some may even need requesting.
-    // pub expiry: DateTime
+    // Claims must be requested as the session authenticates, and may expire
+    // before the session does, as seconds since the unix epoch. A claim
+    // without an expiry lasts as long as the session.
+    #[serde(default)]
+    pub expiry: Option<u64>,
+}
+
+impl Claim {
+    pub fn is_expired(&self, ct: Duration) -> bool {
+        match self.expiry {
+            Some(expiry) => ct.as_secs() >= expiry,
+            None => false,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -106,30 +726,92 @@ pub struct Application {
 // and to the Entry so that filters or access controls can be applied.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct UserAuthToken {
-    // When this data should be considered invalid. Interpretation
-    // may depend on the client application.
-    // pub expiry: DateTime,
+    // When this token was issued, and when it should be considered invalid,
+    // as seconds since the unix epoch. The server will not accept the
+    // token after expiry.
+    pub issued_at: u64,
+    pub expiry: u64,
+    // When credentials were last given for this session. This begins as
+    // issued_at, and a reauth moves it on without beginning a new session.
+    #[serde(default)]
+    pub auth_time: u64,
+    // The server side session this token belongs to. Once the session is
+    // ended by logout or revoked, the token is no longer accepted.
+    pub sessionid: Uuid,
     pub name: String,
+    // The name qualified with the domain, name@domain_name, which is unique
+    // beyond this deployment.
+    #[serde(default)]
+    pub spn: String,
     pub displayname: String,
     pub uuid: String,
     pub application: Option<Application>,
     pub groups: Vec<Group>,
     pub claims: Vec<Claim>,
+    // The password was reset by an administrator, and must be changed
+    // before the token is accepted for anything else.
+    #[serde(default)]
+    pub must_change_password: bool,
+    // The session is of the anonymous account, which can only read a
+    // little public information.
+    #[serde(default)]
+    pub anonymous: bool,
+    // The session was begun with this api token of a service account,
+    // rather than interactively. A read only token can't make any changes.
+    #[serde(default)]
+    pub api_token: Option<Uuid>,
+    #[serde(default)]
+    pub read_only: bool,
     // Should we allow supplemental ava's to be added on request?
 }
 
+impl UserAuthToken {
+    // Has this token expired at the current time ct, as a duration since
+    // the unix epoch? A client holding a token can use this to re-auth
+    // rather than having requests rejected.
+    pub fn is_expired(&self, ct: Duration) -> bool {
+        ct.as_secs() >= self.expiry
+    }
+}
+
+fn fmt_epoch(secs: u64) -> String {
+    match Utc.timestamp_opt(secs as i64, 0).single() {
+        Some(dt) => dt.to_rfc3339_opts(SecondsFormat::Secs, true),
+        None => secs.to_string(),
+    }
+}
+
 impl fmt::Display for UserAuthToken {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "issued at: {}", fmt_epoch(self.issued_at))?;
+        writeln!(f, "expiry: {}", fmt_epoch(self.expiry))?;
+        writeln!(f, "authenticated at: {}", fmt_epoch(self.auth_time))?;
+        writeln!(f, "session: {}", self.sessionid)?;
         writeln!(f, "name: {}", self.name)?;
+        writeln!(f, "spn: {}", self.spn)?;
         writeln!(f, "display: {}", self.displayname)?;
         writeln!(f, "uuid: {}", self.uuid)?;
         writeln!(f, "groups: {:?}", self.groups)?;
-        writeln!(f, "claims: {:?}", self.claims)
+        writeln!(f, "claims: {:?}", self.claims)?;
+        if self.must_change_password {
+            writeln!(f, "password must be changed")?;
+        }
+        if self.anonymous {
+            writeln!(f, "anonymous session, with limited access")?;
+        }
+        if let Some(id) = &self.api_token {
+            writeln!(f, "service session, with api token: {}", id)?;
+        }
+        if self.read_only {
+            writeln!(f, "read only session")?;
+        }
+        Ok(())
     }
 }
 
-// UAT will need a downcast to Entry, which adds in the claims to the entry
-// for the purpose of filtering.
+// The server downcasts the UAT to the entry of its account, which adds in the
+// unexpired claims as the "claim" attribute, so access controls can filter on
+// them.
 
 /* ===== low level proto types ===== */
 
@@ -138,29 +820,615 @@ impl fmt::Display for UserAuthToken {
 // the in memory server core entry type, without affecting the protoEntry type
 //
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Entry {
     pub attrs: BTreeMap<String, Vec<String>>,
 }
 
+// Errors from reading a typed value from a proto entry. The attribute has a
+// value, but it can't be parsed as the requested type.
+#[derive(Debug, PartialEq)]
+pub enum EntryValueError {
+    InvalidUuid(String),
+    InvalidInteger(String),
+    InvalidBool(String),
+}
+
+impl fmt::Display for EntryValueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EntryValueError::InvalidUuid(v) => write!(f, "'{}' is not a valid uuid", v),
+            EntryValueError::InvalidInteger(v) => write!(f, "'{}' is not a valid integer", v),
+            EntryValueError::InvalidBool(v) => write!(f, "'{}' is not a valid bool", v),
+        }
+    }
+}
+
+// Attribute names are matched without regard to case. As on the server, the
+// single value accessors give None unless there is exactly one value.
+impl Entry {
+    pub fn get_ava(&self, attr: &str) -> Option<&[String]> {
+        match self.attrs.get(attr) {
+            Some(vs) => Some(vs.as_slice()),
+            None => self
+                .attrs
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(attr))
+                .map(|(_, vs)| vs.as_slice()),
+        }
+    }
+
+    pub fn get_ava_single(&self, attr: &str) -> Option<&str> {
+        match self.get_ava(attr) {
+            Some([v]) => Some(v.as_str()),
+            _ => None,
+        }
+    }
+
+    pub fn get_ava_single_uuid(&self, attr: &str) -> Result<Option<Uuid>, EntryValueError> {
+        self.get_ava_single(attr)
+            .map(|v| Uuid::parse_str(v).map_err(|_| EntryValueError::InvalidUuid(v.to_string())))
+            .transpose()
+    }
+
+    pub fn get_ava_single_u64(&self, attr: &str) -> Result<Option<u64>, EntryValueError> {
+        self.get_ava_single(attr)
+            .map(|v| {
+                v.parse::<u64>()
+                    .map_err(|_| EntryValueError::InvalidInteger(v.to_string()))
+            })
+            .transpose()
+    }
+
+    pub fn get_ava_bool(&self, attr: &str) -> Result<Option<bool>, EntryValueError> {
+        self.get_ava_single(attr)
+            .map(|v| {
+                v.parse::<bool>()
+                    .map_err(|_| EntryValueError::InvalidBool(v.to_string()))
+            })
+            .transpose()
+    }
+
+    pub fn attribute_pres(&self, attr: &str) -> bool {
+        self.get_ava(attr).is_some()
+    }
+
+    pub fn attribute_value_pres(&self, attr: &str, value: &str) -> bool {
+        match self.get_ava(attr) {
+            Some(vs) => vs.iter().any(|v| v == value),
+            None => false,
+        }
+    }
+}
+
+impl Entry {
+    // Whether a value can be written as is in ldif, following the SAFE-STRING
+    // rules of rfc2849. Anything else is base64 encoded.
+    fn is_ldif_safe(v: &str) -> bool {
+        !v.starts_with(|c| c == ' ' || c == ':' || c == '<')
+            && !v.ends_with(' ')
+            && v.bytes()
+                .all(|b| b != 0 && b != b'\n' && b != b'\r' && b.is_ascii())
+    }
+
+    // An ldif-ish form of the entry, with one "attr: value" line for each value.
+    // Attributes are in sorted order, and values that can't be written safely
+    // are base64 encoded, as "attr:: value".
+    pub fn to_ldif(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (attr, vs) in self.attrs.iter() {
+            for v in vs.iter() {
+                if Entry::is_ldif_safe(v) {
+                    writeln!(f, "{}: {}", attr, v)?;
+                } else {
+                    writeln!(f, "{}:: {}", attr, base64::encode(v))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Diffing and applying modify lists locally. Attribute names are lowercased,
+// and values are compared with leading and trailing whitespace trimmed, so a
+// value that differs only in surrounding whitespace is not a change. Values
+// are kept in sorted order, as they are when returned by the server.
+impl Entry {
+    fn value_eq(a: &str, b: &str) -> bool {
+        a.trim() == b.trim()
+    }
+
+    fn normalised_attrs(&self) -> BTreeMap<String, Vec<&String>> {
+        let mut attrs: BTreeMap<String, Vec<&String>> = BTreeMap::new();
+        for (k, vs) in self.attrs.iter() {
+            attrs.entry(k.to_lowercase()).or_default().extend(vs.iter());
+        }
+        attrs
+    }
+
+    // The changes that turn this entry into other.
+    pub fn diff(&self, other: &Entry) -> ModifyList {
+        let ours = self.normalised_attrs();
+        let theirs = other.normalised_attrs();
+        let mut mods = Vec::new();
+
+        for (attr, vs) in ours.iter() {
+            match theirs.get(attr) {
+                None => mods.push(Modify::Purged(attr.clone())),
+                Some(other_vs) => mods.extend(
+                    vs.iter()
+                        .filter(|v| !other_vs.iter().any(|ov| Entry::value_eq(v, ov)))
+                        .map(|v| Modify::Removed(attr.clone(), (*v).clone())),
+                ),
+            }
+        }
+
+        for (attr, other_vs) in theirs.iter() {
+            let vs = ours.get(attr);
+            mods.extend(
+                other_vs
+                    .iter()
+                    .filter(|ov| match vs {
+                        Some(vs) => !vs.iter().any(|v| Entry::value_eq(v, ov)),
+                        None => true,
+                    })
+                    .map(|ov| Modify::Present(attr.clone(), (*ov).clone())),
+            );
+        }
+
+        ModifyList::new_list(mods)
+    }
+
+    fn present(&mut self, attr: &str, value: &str) {
+        let vs = self.attrs.entry(attr.to_string()).or_default();
+        if !vs.iter().any(|v| Entry::value_eq(v, value)) {
+            let idx = vs
+                .binary_search_by(|v| v.as_str().cmp(value))
+                .unwrap_or_else(|i| i);
+            vs.insert(idx, value.to_string());
+        }
+    }
+
+    // Apply a modify list to this entry as the server would, to preview the
+    // result. Assertions are not checked.
+    pub fn apply(&mut self, modlist: &ModifyList) {
+        // Bring any differently cased attribute names together first.
+        let attrs = std::mem::replace(&mut self.attrs, BTreeMap::new());
+        for (k, vs) in attrs.into_iter() {
+            for v in vs.iter() {
+                self.present(k.to_lowercase().as_str(), v);
+            }
+        }
+
+        for m in modlist.mods.iter() {
+            let attr = m.attr().to_lowercase();
+            match m {
+                Modify::Present(_, v) => self.present(attr.as_str(), v),
+                Modify::Removed(_, v) => {
+                    if let Some(vs) = self.attrs.get_mut(&attr) {
+                        vs.retain(|ev| !Entry::value_eq(ev, v));
+                        if vs.is_empty() {
+                            self.attrs.remove(&attr);
+                        }
+                    }
+                }
+                Modify::Purged(_) => {
+                    self.attrs.remove(&attr);
+                }
+                Modify::Set(_, vs) => {
+                    self.attrs.remove(&attr);
+                    vs.iter().for_each(|v| self.present(attr.as_str(), v));
+                }
+                Modify::Assert(_, _) | Modify::AssertMissing(_) => {}
+            }
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Ord, PartialOrd, Eq, PartialEq)]
 pub enum Filter {
     // This is attr - value
     Eq(String, String),
     Sub(String, String),
     Pres(String),
+    // Ordering is determined by the attributes syntax on the server.
+    Gte(String, String),
+    Lte(String, String),
+    // The attribute has a value equal to any of these.
+    Inclusion(String, Vec<String>),
     Or(Vec<Filter>),
     And(Vec<Filter>),
     AndNot(Box<Filter>),
     #[serde(rename = "Self")]
     SelfUUID,
+    // Match all or no entries. These are unit variants, so they serialise as
+    // the plain strings "True" and "False", which servers that predate them
+    // reject as an unknown variant rather than misinterpreting.
+    True,
+    False,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+// Errors from parsing an ldap style filter string. The position is the byte
+// offset into the filter where the problem was found.
+#[derive(Debug, PartialEq)]
+pub enum FilterParseError {
+    Empty,
+    UnexpectedEnd,
+    UnexpectedChar(char, usize),
+    UnbalancedParens(usize),
+    EmptyAttribute(usize),
+    EmptyValue(usize),
+    InvalidEscape(usize),
+    InvalidUtf8(usize),
+    UnsupportedMatch(String, usize),
+    UnsupportedSubstring(usize),
+}
+
+impl fmt::Display for FilterParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FilterParseError::Empty => write!(f, "the filter is empty"),
+            FilterParseError::UnexpectedEnd => write!(f, "the filter ended unexpectedly"),
+            FilterParseError::UnexpectedChar(c, p) => {
+                write!(f, "unexpected character '{}' at position {}", c, p)
+            }
+            FilterParseError::UnbalancedParens(p) => {
+                write!(f, "unbalanced parentheses at position {}", p)
+            }
+            FilterParseError::EmptyAttribute(p) => {
+                write!(f, "missing attribute name at position {}", p)
+            }
+            FilterParseError::EmptyValue(p) => write!(f, "missing value at position {}", p),
+            FilterParseError::InvalidEscape(p) => write!(
+                f,
+                "invalid escape at position {}, expected \\ and two hex digits",
+                p
+            ),
+            FilterParseError::InvalidUtf8(p) => {
+                write!(f, "escaped value at position {} is not valid utf8", p)
+            }
+            FilterParseError::UnsupportedMatch(m, p) => {
+                write!(f, "unsupported match type '{}' at position {}", m, p)
+            }
+            FilterParseError::UnsupportedSubstring(p) => write!(
+                f,
+                "unsupported substring at position {}, only *value* is supported",
+                p
+            ),
+        }
+    }
+}
+
+impl Filter {
+    // Parse an rfc4515 style filter such as (&(class=account)(name=fred)). As a
+    // convenience, a single term may be given without the enclosing parens.
+    pub fn from_ldap_str(s: &str) -> Result<Self, FilterParseError> {
+        let end = s.trim_end().len();
+        let mut parser = LdapFilterParser {
+            s: &s.as_bytes()[..end],
+            pos: end - s.trim().len(),
+        };
+        if parser.peek().is_none() {
+            return Err(FilterParseError::Empty);
+        }
+        let f = if parser.peek() == Some(b'(') {
+            parser.parse_filter()?
+        } else {
+            parser.parse_item()?
+        };
+        match parser.peek() {
+            None => Ok(f),
+            Some(b')') => Err(FilterParseError::UnbalancedParens(parser.pos)),
+            Some(_) => Err(parser.unexpected()),
+        }
+    }
+
+    // The ldap text form has no inclusion term, so this rewrites them to the
+    // equivalent or of eq terms. This is the filter you get back after a round
+    // trip through to_string and from_ldap_str.
+    pub fn normalise(&self) -> Self {
+        match self {
+            Filter::Inclusion(a, vs) => Filter::Or(
+                vs.iter()
+                    .map(|v| Filter::Eq(a.clone(), v.clone()))
+                    .collect(),
+            ),
+            Filter::Or(l) => Filter::Or(l.iter().map(|f| f.normalise()).collect()),
+            Filter::And(l) => Filter::And(l.iter().map(|f| f.normalise()).collect()),
+            Filter::AndNot(f) => Filter::AndNot(Box::new(f.normalise())),
+            f => f.clone(),
+        }
+    }
+}
+
+// Escape a value per rfc4515 so that it can be placed in a filter string.
+fn ldap_escape(v: &str) -> String {
+    let mut r = String::with_capacity(v.len());
+    for c in v.chars() {
+        match c {
+            '(' => r.push_str("\\28"),
+            ')' => r.push_str("\\29"),
+            '*' => r.push_str("\\2a"),
+            '\\' => r.push_str("\\5c"),
+            '\0' => r.push_str("\\00"),
+            c => r.push(c),
+        }
+    }
+    r
+}
+
+// This renders as an ldap filter, which from_ldap_str will parse back to the
+// normalised form of the filter. Empty values can't be expressed in this
+// form, and empty and/or terms parse back as True and False.
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Filter::Eq(a, v) => write!(f, "({}={})", a, ldap_escape(v)),
+            Filter::Sub(a, v) => write!(f, "({}=*{}*)", a, ldap_escape(v)),
+            Filter::Pres(a) => write!(f, "({}=*)", a),
+            Filter::Gte(a, v) => write!(f, "({}>={})", a, ldap_escape(v)),
+            Filter::Lte(a, v) => write!(f, "({}<={})", a, ldap_escape(v)),
+            Filter::Inclusion(a, vs) => {
+                write!(f, "(|")?;
+                for v in vs {
+                    write!(f, "({}={})", a, ldap_escape(v))?;
+                }
+                write!(f, ")")
+            }
+            Filter::Or(l) => {
+                write!(f, "(|")?;
+                for sf in l {
+                    write!(f, "{}", sf)?;
+                }
+                write!(f, ")")
+            }
+            Filter::And(l) => {
+                write!(f, "(&")?;
+                for sf in l {
+                    write!(f, "{}", sf)?;
+                }
+                write!(f, ")")
+            }
+            Filter::AndNot(sf) => write!(f, "(!{})", sf),
+            Filter::SelfUUID => write!(f, "(uuid=self)"),
+            Filter::True => write!(f, "(&)"),
+            Filter::False => write!(f, "(|)"),
+        }
+    }
+}
+
+struct LdapFilterParser<'a> {
+    s: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> LdapFilterParser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.s.get(self.pos).cloned()
+    }
+
+    fn unexpected(&self) -> FilterParseError {
+        // Report the whole character, not just the byte we stopped on.
+        match std::str::from_utf8(&self.s[self.pos..])
+            .ok()
+            .and_then(|r| r.chars().next())
+        {
+            Some(c) => FilterParseError::UnexpectedChar(c, self.pos),
+            None => FilterParseError::UnexpectedEnd,
+        }
+    }
+
+    fn expect_close(&mut self) -> Result<(), FilterParseError> {
+        match self.peek() {
+            Some(b')') => {
+                self.pos += 1;
+                Ok(())
+            }
+            None => Err(FilterParseError::UnbalancedParens(self.pos)),
+            Some(_) => Err(self.unexpected()),
+        }
+    }
+
+    // filter = "(" ( and / or / not / item ) ")"
+    fn parse_filter(&mut self) -> Result<Filter, FilterParseError> {
+        // Skip the open paren, our caller has already checked it.
+        self.pos += 1;
+        let f = match self.peek() {
+            // An empty and/or is the absolute true/false of rfc4526.
+            Some(b'&') => {
+                let l = self.parse_list()?;
+                if l.len() == 0 {
+                    Filter::True
+                } else {
+                    Filter::And(l)
+                }
+            }
+            Some(b'|') => {
+                let l = self.parse_list()?;
+                if l.len() == 0 {
+                    Filter::False
+                } else {
+                    Filter::Or(l)
+                }
+            }
+            Some(b'!') => {
+                self.pos += 1;
+                match self.peek() {
+                    Some(b'(') => Filter::AndNot(Box::new(self.parse_filter()?)),
+                    None => return Err(FilterParseError::UnbalancedParens(self.pos)),
+                    Some(_) => return Err(self.unexpected()),
+                }
+            }
+            Some(_) => self.parse_item()?,
+            None => return Err(FilterParseError::UnbalancedParens(self.pos)),
+        };
+        self.expect_close()?;
+        Ok(f)
+    }
+
+    fn parse_list(&mut self) -> Result<Vec<Filter>, FilterParseError> {
+        self.pos += 1;
+        let mut l = Vec::new();
+        while self.peek() == Some(b'(') {
+            l.push(self.parse_filter()?);
+        }
+        Ok(l)
+    }
+
+    // item = attr ( "=" / ">=" / "<=" ) value, where an "=" value may contain
+    // unescaped * to request presence or substring matching.
+    fn parse_item(&mut self) -> Result<Filter, FilterParseError> {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            match c {
+                b'=' | b'<' | b'>' | b'~' | b':' | b'(' | b')' | b'*' | b'\\' => break,
+                _ => self.pos += 1,
+            }
+        }
+        // This can't fail, as we only stopped on an ascii byte or the end.
+        let attr = std::str::from_utf8(&self.s[start..self.pos])
+            .map_err(|_| FilterParseError::InvalidUtf8(start))?
+            .to_string();
+        if attr.len() == 0 {
+            return match self.peek() {
+                None => Err(FilterParseError::UnexpectedEnd),
+                Some(b'(') | Some(b')') => Err(self.unexpected()),
+                Some(_) => Err(FilterParseError::EmptyAttribute(start)),
+            };
+        }
+
+        let op_start = self.pos;
+        let op = match (self.peek(), self.s.get(self.pos + 1)) {
+            (Some(b'='), _) => {
+                self.pos += 1;
+                b'='
+            }
+            (Some(c @ b'>'), Some(b'=')) | (Some(c @ b'<'), Some(b'=')) => {
+                self.pos += 2;
+                c
+            }
+            // Approximate and extensible matches have no equivalent.
+            (Some(b'~'), Some(b'=')) => {
+                return Err(FilterParseError::UnsupportedMatch(
+                    "~=".to_string(),
+                    op_start,
+                ))
+            }
+            (Some(b':'), _) => {
+                return Err(FilterParseError::UnsupportedMatch(
+                    ":=".to_string(),
+                    op_start,
+                ))
+            }
+            (None, _) => return Err(FilterParseError::UnexpectedEnd),
+            (Some(_), _) => return Err(self.unexpected()),
+        };
+
+        let value_start = self.pos;
+        let parts = self.parse_value()?;
+
+        match (op, parts.as_slice()) {
+            // A uuid can never be "self", so we use this to express SelfUUID.
+            (b'=', [v]) if attr.eq_ignore_ascii_case("uuid") && v == "self" => Ok(Filter::SelfUUID),
+            (b'=', [v]) => Ok(Filter::Eq(attr, v.clone())),
+            (b'=', [a, b]) if a.len() == 0 && b.len() == 0 => Ok(Filter::Pres(attr)),
+            (b'=', [a, v, b]) if a.len() == 0 && b.len() == 0 && v.len() != 0 => {
+                Ok(Filter::Sub(attr, v.clone()))
+            }
+            (b'>', [v]) => Ok(Filter::Gte(attr, v.clone())),
+            (b'<', [v]) => Ok(Filter::Lte(attr, v.clone())),
+            (b'=', _) => Err(FilterParseError::UnsupportedSubstring(value_start)),
+            (_, _) => Err(FilterParseError::UnsupportedSubstring(op_start)),
+        }
+    }
+
+    // Read a value up to the closing paren, unescaping as we go. The value
+    // is split on any unescaped *, so a plain value is a single part.
+    fn parse_value(&mut self) -> Result<Vec<String>, FilterParseError> {
+        let start = self.pos;
+        let mut parts = Vec::new();
+        let mut part_start = self.pos;
+        let mut buf: Vec<u8> = Vec::new();
+        loop {
+            match self.peek() {
+                None | Some(b')') => break,
+                Some(b'(') => return Err(self.unexpected()),
+                Some(b'*') => {
+                    parts.push(Self::to_utf8(buf, part_start)?);
+                    buf = Vec::new();
+                    self.pos += 1;
+                    part_start = self.pos;
+                }
+                Some(b'\\') => {
+                    let hex = self
+                        .s
+                        .get(self.pos + 1..self.pos + 3)
+                        .and_then(|h| std::str::from_utf8(h).ok())
+                        .and_then(|h| u8::from_str_radix(h, 16).ok());
+                    match hex {
+                        Some(b) => buf.push(b),
+                        None => return Err(FilterParseError::InvalidEscape(self.pos)),
+                    }
+                    self.pos += 3;
+                }
+                Some(c) => {
+                    buf.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+        parts.push(Self::to_utf8(buf, part_start)?);
+        if parts.len() == 1 && parts[0].len() == 0 {
+            return Err(FilterParseError::EmptyValue(start));
+        }
+        Ok(parts)
+    }
+
+    fn to_utf8(buf: Vec<u8>, pos: usize) -> Result<String, FilterParseError> {
+        String::from_utf8(buf).map_err(|_| FilterParseError::InvalidUtf8(pos))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum Modify {
     Present(String, String),
     Removed(String, String),
     Purged(String),
+    // These change nothing, but fail the whole modification with
+    // ModifyAssertionFailed unless the entry has the value, or lacks the
+    // attribute, before any changes are applied.
+    Assert(String, String),
+    AssertMissing(String),
+    // Replaces every value of the attribute with these, as a single change.
+    Set(String, Vec<String>),
+}
+
+impl Modify {
+    fn attr(&self) -> &str {
+        match self {
+            Modify::Present(a, _)
+            | Modify::Removed(a, _)
+            | Modify::Purged(a)
+            | Modify::Assert(a, _)
+            | Modify::AssertMissing(a)
+            | Modify::Set(a, _) => a.as_str(),
+        }
+    }
+
+    fn values(&self) -> Vec<&str> {
+        match self {
+            Modify::Present(_, v) | Modify::Removed(_, v) | Modify::Assert(_, v) => {
+                vec![v.as_str()]
+            }
+            Modify::Purged(_) | Modify::AssertMissing(_) => Vec::new(),
+            Modify::Set(_, vs) => vs.iter().map(|v| v.as_str()).collect(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -172,58 +1440,496 @@ impl ModifyList {
     pub fn new_list(mods: Vec<Modify>) -> Self {
         ModifyList { mods: mods }
     }
+
+    pub fn builder() -> ModifyListBuilder {
+        ModifyListBuilder { mods: Vec::new() }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct OperationResponse {}
+// Errors from building a modify list. The position is the index of the
+// offending modification in the order it was added to the builder.
+#[derive(Debug, PartialEq)]
+pub enum ModifyListBuildError {
+    EmptyAttribute(usize),
+    EmptyValue(usize),
+}
 
-impl OperationResponse {
-    pub fn new(_: ()) -> Self {
-        OperationResponse {}
+impl fmt::Display for ModifyListBuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModifyListBuildError::EmptyAttribute(p) => {
+                write!(f, "missing attribute name in modification {}", p)
+            }
+            ModifyListBuildError::EmptyValue(p) => {
+                write!(f, "empty value in modification {}", p)
+            }
+        }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct SearchRequest {
-    pub filter: Filter,
+// Builds a modify list one change at a time. Attribute names are lowercased,
+// and a change identical to the last change of the same attribute is dropped,
+// as repeating it could have no effect.
+#[derive(Debug)]
+pub struct ModifyListBuilder {
+    mods: Vec<Modify>,
 }
 
-impl SearchRequest {
-    pub fn new(filter: Filter) -> Self {
-        SearchRequest { filter: filter }
+impl ModifyListBuilder {
+    pub fn present(mut self, attr: &str, value: &str) -> Self {
+        self.mods
+            .push(Modify::Present(attr.to_lowercase(), value.to_string()));
+        self
+    }
+
+    pub fn removed(mut self, attr: &str, value: &str) -> Self {
+        self.mods
+            .push(Modify::Removed(attr.to_lowercase(), value.to_string()));
+        self
+    }
+
+    pub fn purge(mut self, attr: &str) -> Self {
+        self.mods.push(Modify::Purged(attr.to_lowercase()));
+        self
+    }
+
+    pub fn assert(mut self, attr: &str, value: &str) -> Self {
+        self.mods
+            .push(Modify::Assert(attr.to_lowercase(), value.to_string()));
+        self
+    }
+
+    pub fn assert_missing(mut self, attr: &str) -> Self {
+        self.mods.push(Modify::AssertMissing(attr.to_lowercase()));
+        self
+    }
+
+    pub fn set(mut self, attr: &str, values: Vec<&str>) -> Self {
+        self.mods.push(Modify::Set(
+            attr.to_lowercase(),
+            values.into_iter().map(|v| v.to_string()).collect(),
+        ));
+        self
+    }
+
+    pub fn build(self) -> Result<ModifyList, ModifyListBuildError> {
+        let mut mods: Vec<Modify> = Vec::with_capacity(self.mods.len());
+        for (p, m) in self.mods.into_iter().enumerate() {
+            if m.attr().trim().is_empty() {
+                return Err(ModifyListBuildError::EmptyAttribute(p));
+            }
+            if m.values().iter().any(|v| v.is_empty()) {
+                return Err(ModifyListBuildError::EmptyValue(p));
+            }
+            // Only the last change to this attribute is compared, as an
+            // identical change that is separated by another, such as present,
+            // removed, present, is not a repeat.
+            let repeat = mods
+                .iter()
+                .rev()
+                .find(|prev| prev.attr() == m.attr())
+                .map(|prev| prev == &m)
+                .unwrap_or(false);
+            if !repeat {
+                mods.push(m);
+            }
+        }
+        Ok(ModifyList { mods: mods })
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct SearchResponse {
-    pub entries: Vec<Entry>,
-}
+pub struct OperationResponse {}
 
-impl SearchResponse {
-    pub fn new(entries: Vec<Entry>) -> Self {
-        SearchResponse { entries: entries }
+impl OperationResponse {
+    pub fn new(_: ()) -> Self {
+        OperationResponse {}
     }
 }
 
+// The uuids of the created entries, in the same order as the entries of the
+// CreateRequest.
 #[derive(Debug, Serialize, Deserialize)]
-pub struct CreateRequest {
-    pub entries: Vec<Entry>,
+pub struct CreateResponse {
+    pub uuids: Vec<String>,
 }
 
-impl CreateRequest {
-    pub fn new(entries: Vec<Entry>) -> Self {
-        CreateRequest { entries: entries }
+impl CreateResponse {
+    pub fn new(uuids: Vec<String>) -> Self {
+        CreateResponse { uuids: uuids }
     }
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum SortOrder {
+    Ascending,
+    Descending,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
-pub struct DeleteRequest {
+pub struct SearchRequest {
     pub filter: Filter,
+    // The attributes to return on each entry. None returns everything
+    // you can read, and an empty list returns only the uuid.
+    pub attrs: Option<Vec<String>>,
+    // The maximum number of entries to return. If more remain, the response
+    // carries a cookie to request the next page with.
+    pub page_size: Option<u32>,
+    // The next_cookie from a previous response, to continue that search.
+    pub page_cookie: Option<String>,
+    // Order the results by the smallest value of this attribute. Entries
+    // without the attribute are returned last.
+    pub sort: Option<(String, SortOrder)>,
+    // Record how the search was answered and log it. Admins are given the
+    // plan in the response as well.
+    #[serde(default)]
+    pub trace: bool,
+}
+
+impl SearchRequest {
+    pub fn new(filter: Filter) -> Self {
+        SearchRequest {
+            filter: filter,
+            attrs: None,
+            page_size: None,
+            page_cookie: None,
+            sort: None,
+            trace: false,
+        }
+    }
+
+    pub fn new_with_attrs(filter: Filter, attrs: Vec<String>) -> Self {
+        SearchRequest {
+            filter: filter,
+            attrs: Some(attrs),
+            page_size: None,
+            page_cookie: None,
+            sort: None,
+            trace: false,
+        }
+    }
+
+    pub fn new_sorted(filter: Filter, attr: &str, order: SortOrder) -> Self {
+        SearchRequest {
+            filter: filter,
+            attrs: None,
+            page_size: None,
+            page_cookie: None,
+            sort: Some((attr.to_string(), order)),
+            trace: false,
+        }
+    }
+
+    pub fn new_traced(filter: Filter) -> Self {
+        SearchRequest {
+            filter: filter,
+            attrs: None,
+            page_size: None,
+            page_cookie: None,
+            sort: None,
+            trace: true,
+        }
+    }
+
+    pub fn new_paged(filter: Filter, page_size: u32, page_cookie: Option<String>) -> Self {
+        SearchRequest {
+            filter: filter,
+            attrs: None,
+            page_size: Some(page_size),
+            page_cookie: page_cookie,
+            sort: None,
+            trace: false,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SearchResponse {
+    pub entries: Vec<Entry>,
+    // Present when a paged search has more entries to return.
+    pub next_cookie: Option<String>,
+    // How the search was answered, when an admin asked for a trace.
+    #[serde(default)]
+    pub plan: Option<SearchPlan>,
+}
+
+impl SearchResponse {
+    pub fn new(entries: Vec<Entry>) -> Self {
+        SearchResponse {
+            entries: entries,
+            next_cookie: None,
+            plan: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum PlanState {
+    // The indexes gave the candidates for the term.
+    Indexed,
+    // The term couldn't narrow the candidates, so any entry could match.
+    FullScan,
+}
+
+impl fmt::Display for PlanState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PlanState::Indexed => write!(f, "indexed"),
+            PlanState::FullScan => write!(f, "full scan"),
+        }
+    }
+}
+
+// A term of the normalised filter, and the candidates the indexes gave for
+// it.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PlanNode {
+    // Such as "eq name" or "and".
+    pub term: String,
+    pub state: PlanState,
+    pub candidates: Option<usize>,
+    pub children: Vec<PlanNode>,
+}
+
+impl PlanNode {
+    // The first node for this term, searching depth first.
+    pub fn find(&self, term: &str) -> Option<&PlanNode> {
+        if self.term == term {
+            Some(self)
+        } else {
+            self.children.iter().filter_map(|c| c.find(term)).next()
+        }
+    }
+
+    fn fmt_depth(&self, f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
+        write!(
+            f,
+            "{:width$}{} ({}",
+            "",
+            self.term,
+            self.state,
+            width = depth * 2
+        )?;
+        if let Some(c) = self.candidates {
+            write!(f, ", {} candidates", c)?;
+        }
+        writeln!(f, ")")?;
+        for child in self.children.iter() {
+            child.fmt_depth(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+// How a search was answered. Entries are loaded from the candidates, tested
+// against the filter, and then reduced to those the access controls allow.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SearchPlan {
+    pub filter: String,
+    pub root: PlanNode,
+    pub loaded: usize,
+    pub matched: usize,
+    pub allowed: usize,
+}
+
+impl fmt::Display for SearchPlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "filter: {}", self.filter)?;
+        writeln!(
+            f,
+            "loaded: {} matched: {} allowed: {}",
+            self.loaded, self.matched, self.allowed
+        )?;
+        self.root.fmt_depth(f, 0)
+    }
+}
+
+// A streamed search, to /v1/search/_stream, is answered with a sequence of
+// these rather than one SearchResponse. As json, each is a line of its own.
+// As cbor, each is preceded by its length as a 4 byte big endian integer.
+pub const SEARCH_STREAM_JSON: &str = "application/x-ndjson";
+pub const SEARCH_STREAM_CBOR: &str = "application/x-kanidm-cbor-stream";
+
+// Entries are sent as they are read. The stream always ends with Done or
+// Error, so one that ends without either was cut short. An error found
+// before any entry was sent is given as an ordinary error response instead.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SearchStreamItem {
+    Entry(Entry),
+    Done,
+    Error(ErrorResponse),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SearchCountRequest {
+    pub filter: Filter,
+    // Only ask if any entry matches, so the count is at most 1.
+    pub exists: bool,
+}
+
+impl SearchCountRequest {
+    pub fn new(filter: Filter) -> Self {
+        SearchCountRequest {
+            filter: filter,
+            exists: false,
+        }
+    }
+
+    pub fn new_exists(filter: Filter) -> Self {
+        SearchCountRequest {
+            filter: filter,
+            exists: true,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SearchCountResponse {
+    pub count: u64,
+}
+
+impl SearchCountResponse {
+    pub fn new(count: u64) -> Self {
+        SearchCountResponse { count: count }
+    }
+}
+
+// Assert that the single entry matching filter has value in attr. This is
+// answered even if the caller can't read attr, provided they have been granted
+// compare on it. The value is normalised to the attribute's syntax first.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CompareRequest {
+    pub filter: Filter,
+    pub attr: String,
+    pub value: String,
+}
+
+impl CompareRequest {
+    pub fn new(filter: Filter, attr: String, value: String) -> Self {
+        CompareRequest {
+            filter: filter,
+            attr: attr,
+            value: value,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CompareResponse {
+    pub matched: bool,
+}
+
+impl CompareResponse {
+    pub fn new(matched: bool) -> Self {
+        CompareResponse { matched: matched }
+    }
+}
+
+// A stable, typed view of an attribute definition from the schema.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SchemaAttribute {
+    pub name: String,
+    pub description: String,
+    pub multivalue: bool,
+    pub unique: bool,
+    // The values are redacted in the audit log of changes.
+    #[serde(default)]
+    pub secret: bool,
+    pub syntax: String,
+    // The types of index kept for the attribute.
+    #[serde(default)]
+    pub index: Vec<String>,
+}
+
+impl fmt::Display for SchemaAttribute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "attributename: {}", self.name)?;
+        writeln!(f, "description: {}", self.description)?;
+        writeln!(f, "multivalue: {}", self.multivalue)?;
+        writeln!(f, "unique: {}", self.unique)?;
+        writeln!(f, "secret: {}", self.secret)?;
+        writeln!(f, "syntax: {}", self.syntax)?;
+        writeln!(f, "index: {}", self.index.join(", "))
+    }
+}
+
+// A stable, typed view of a class definition from the schema. System and
+// user defined must/may attributes are merged.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SchemaClass {
+    pub name: String,
+    pub must: Vec<String>,
+    pub may: Vec<String>,
+}
+
+impl fmt::Display for SchemaClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "classname: {}", self.name)?;
+        writeln!(f, "must: {}", self.must.join(", "))?;
+        writeln!(f, "may: {}", self.may.join(", "))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SchemaRequest {}
+
+impl SchemaRequest {
+    pub fn new() -> Self {
+        SchemaRequest {}
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SchemaResponse {
+    pub attributes: Vec<SchemaAttribute>,
+    pub classes: Vec<SchemaClass>,
+}
+
+impl SchemaResponse {
+    pub fn new(attributes: Vec<SchemaAttribute>, classes: Vec<SchemaClass>) -> Self {
+        SchemaResponse {
+            attributes: attributes,
+            classes: classes,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateRequest {
+    pub entries: Vec<Entry>,
+}
+
+impl CreateRequest {
+    pub fn new(entries: Vec<Entry>) -> Self {
+        CreateRequest { entries: entries }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteRequest {
+    pub filter: Filter,
+    // If true, a filter that matches nothing is not an error.
+    #[serde(default)]
+    pub allow_empty: bool,
 }
 
 impl DeleteRequest {
     pub fn new(filter: Filter) -> Self {
-        DeleteRequest { filter: filter }
+        DeleteRequest {
+            filter: filter,
+            allow_empty: false,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteResponse {
+    pub deleted: u64,
+}
+
+impl DeleteResponse {
+    pub fn new(deleted: u64) -> Self {
+        DeleteResponse { deleted: deleted }
     }
 }
 
@@ -232,6 +1938,9 @@ pub struct ModifyRequest {
     // Probably needs a modlist?
     pub filter: Filter,
     pub modlist: ModifyList,
+    // If true, a filter that matches nothing is not an error.
+    #[serde(default)]
+    pub allow_empty: bool,
 }
 
 impl ModifyRequest {
@@ -239,10 +1948,47 @@ impl ModifyRequest {
         ModifyRequest {
             filter: filter,
             modlist: modlist,
+            allow_empty: false,
         }
     }
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ModifyResponse {
+    pub modified: u64,
+}
+
+impl ModifyResponse {
+    pub fn new(modified: u64) -> Self {
+        ModifyResponse { modified: modified }
+    }
+}
+
+// A set of modifications, each with their own filter, that are applied
+// together. If any one of them fails, none are applied.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ModifyBatchRequest {
+    pub changes: Vec<(Filter, ModifyList)>,
+}
+
+impl ModifyBatchRequest {
+    pub fn new(changes: Vec<(Filter, ModifyList)>) -> Self {
+        ModifyBatchRequest { changes: changes }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ModifyBatchResponse {
+    // The number of entries modified by each change, in request order.
+    pub modified: Vec<u64>,
+}
+
+impl ModifyBatchResponse {
+    pub fn new(modified: Vec<u64>) -> Self {
+        ModifyBatchResponse { modified: modified }
+    }
+}
+
 // Login is a multi-step process potentially. First the client says who they
 // want to request
 //
@@ -255,11 +2001,33 @@ impl ModifyRequest {
 //
 // On loginSuccess, we send a cookie, and that allows the token to be
 // generated. The cookie can be shared between servers.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub enum AuthCredential {
     Anonymous,
     Password(String),
-    // TOTP(String),
+    TOTP(String),
+    Webauthn(WebauthnAssertion),
+    BackupCode(String),
+    // The whole token, as it was given when generated. Only service accounts
+    // authenticate this way.
+    ApiToken(String),
+}
+
+// Requests are logged as they're debug formatted, so a secret in one is
+// always written as this instead.
+pub const REDACTED: &str = "<redacted>";
+
+impl fmt::Debug for AuthCredential {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthCredential::Anonymous => write!(f, "Anonymous"),
+            AuthCredential::Password(_) => write!(f, "Password({})", REDACTED),
+            AuthCredential::TOTP(_) => write!(f, "TOTP({})", REDACTED),
+            AuthCredential::Webauthn(a) => f.debug_tuple("Webauthn").field(a).finish(),
+            AuthCredential::BackupCode(_) => write!(f, "BackupCode({})", REDACTED),
+            AuthCredential::ApiToken(_) => write!(f, "ApiToken({})", REDACTED),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -272,6 +2040,10 @@ pub enum AuthStep {
     ),
     */
     Creds(Vec<AuthCredential>),
+    // Request named claims for the session, after the init and before the
+    // credentials are given. The claims the account and its credentials
+    // qualify for are added to the token on success, and the rest are not.
+    RequestClaims(Vec<String>),
     // Should we have a "finalise" type to attempt to finish based on
     // what we have given?
 }
@@ -282,13 +2054,48 @@ pub struct AuthRequest {
     pub step: AuthStep,
 }
 
+// Give the credentials of the current session again, to refresh its
+// auth_time for operations that require a recent authentication. This
+// begins as an AuthStep::Init does, and the credentials are then given with
+// AuthStep::Creds. On success the session is the same, with a new token.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReauthRequest {
+    pub appid: Option<String>,
+}
+
+impl ReauthRequest {
+    pub fn new() -> Self {
+        ReauthRequest { appid: None }
+    }
+}
+
 // Respond with the list of auth types and nonce, etc.
 // It can also contain a denied, or success.
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub enum AuthAllowed {
     Anonymous,
     Password,
-    // Webauthn(String),
+    TOTP,
+    // The challenge to be signed by one of the registered tokens.
+    Webauthn(WebauthnRequestChallenge),
+    BackupCode,
+    ApiToken,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum AuthDenyReason {
+    // The credentials given were wrong.
+    Failed,
+    // The credentials can't be used to authenticate this account.
+    NotPermitted,
+    // There were too many failed authentications. Another may be tried after
+    // this time, in seconds since the unix epoch.
+    Locked(u64),
+    // The account may not authenticate until its valid from time, in
+    // seconds since the unix epoch.
+    NotYetValid(u64),
+    // The account expired at this time, in seconds since the unix epoch.
+    Expired(u64),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -296,77 +2103,2994 @@ pub enum AuthState {
     // Everything is good, your cookie has been issued, and a token is set here
     // for the client to view.
     Success(UserAuthToken),
-    // Something was bad, your session is terminated and no cookie.
-    Denied(String),
-    // Continue to auth, allowed mechanisms listed.
+    // Something was bad, your session is terminated and no cookie. The
+    // reason is for clients to act on, and the message for people.
+    Denied(AuthDenyReason, String),
+    // Continue to auth. Factors are given in the order the credential policy
+    // requires, and any one of the listed mechanisms satisfies the next one.
     Continue(Vec<AuthAllowed>),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct AuthResponse {
     pub sessionid: Uuid,
     pub state: AuthState,
+    // On success, the signed token that carries the session. A client that
+    // can't keep the session cookie, such as the cli between invocations,
+    // may keep this and give it as a bearer token instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
 }
 
-/* Recycle Requests area */
+impl fmt::Debug for AuthResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthResponse")
+            .field("sessionid", &self.sessionid)
+            .field("state", &self.state)
+            .field("token", &self.token.as_ref().map(|_| REDACTED))
+            .finish()
+    }
+}
 
-// Only two actions on recycled is possible. Search and Revive.
+/* Sessions */
 
-pub struct SearchRecycledRequest {
-    pub filter: Filter,
+// End the session that this request is made with.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogoutRequest {}
+
+impl LogoutRequest {
+    pub fn new() -> Self {
+        LogoutRequest {}
+    }
 }
 
-impl SearchRecycledRequest {
-    pub fn new(filter: Filter) -> Self {
-        SearchRecycledRequest { filter: filter }
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LogoutResponse {}
+
+impl LogoutResponse {
+    pub fn new() -> Self {
+        LogoutResponse {}
     }
 }
 
-// Need a search response here later.
+// An active session of an account. Times are seconds since the unix epoch.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SessionInfo {
+    pub sessionid: Uuid,
+    pub account: String,
+    // The address the session was authenticated from, if known.
+    pub source: Option<String>,
+    pub issued_at: u64,
+    pub expiry: u64,
+}
 
-pub struct ReviveRecycledRequest {
-    pub filter: Filter,
+impl fmt::Display for SessionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} account: {} source: {} issued at: {} expiry: {}",
+            self.sessionid,
+            self.account,
+            self.source
+                .as_ref()
+                .map(|s| s.as_str())
+                .unwrap_or("unknown"),
+            fmt_epoch(self.issued_at),
+            fmt_epoch(self.expiry)
+        )
+    }
 }
 
-impl ReviveRecycledRequest {
-    pub fn new(filter: Filter) -> Self {
-        ReviveRecycledRequest { filter: filter }
+// List the active sessions of the account with this name or uuid.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionListRequest {
+    pub account: String,
+}
+
+impl SessionListRequest {
+    pub fn new(account: &str) -> Self {
+        SessionListRequest {
+            account: account.to_string(),
+        }
     }
 }
 
-// This doesn't need seralise because it's only accessed via a "get".
-#[derive(Debug)]
-pub struct WhoamiRequest {}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionListResponse {
+    pub sessions: Vec<SessionInfo>,
+}
 
-impl WhoamiRequest {
-    pub fn new() -> Self {
-        WhoamiRequest {}
+impl SessionListResponse {
+    pub fn new(sessions: Vec<SessionInfo>) -> Self {
+        SessionListResponse { sessions: sessions }
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct WhoamiResponse {
-    // Should we just embed the entry? Or destructure it?
-    pub youare: Entry,
-    pub uat: UserAuthToken,
+pub struct SessionRevokeRequest {
+    pub sessionid: Uuid,
 }
 
-impl WhoamiResponse {
-    pub fn new(e: Entry, uat: UserAuthToken) -> Self {
-        WhoamiResponse {
-            youare: e,
-            uat: uat,
+impl SessionRevokeRequest {
+    pub fn new(sessionid: Uuid) -> Self {
+        SessionRevokeRequest {
+            sessionid: sessionid,
         }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::v1::Filter as ProtoFilter;
-    #[test]
-    fn test_protofilter_simple() {
-        let pf: ProtoFilter = ProtoFilter::Pres("class".to_string());
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionRevokeResponse {}
 
-        println!("{:?}", serde_json::to_string(&pf).expect("JSON failure"));
+impl SessionRevokeResponse {
+    pub fn new() -> Self {
+        SessionRevokeResponse {}
+    }
+}
+
+/* TOTP */
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum TOTPAlgo {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl fmt::Display for TOTPAlgo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TOTPAlgo::Sha1 => write!(f, "SHA1"),
+            TOTPAlgo::Sha256 => write!(f, "SHA256"),
+            TOTPAlgo::Sha512 => write!(f, "SHA512"),
+        }
+    }
+}
+
+// A newly generated totp secret, for the user to load into their
+// authenticator before confirming it with a code.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TOTPSecret {
+    pub accountname: String,
+    pub issuer: String,
+    pub secret: Vec<u8>,
+    pub algo: TOTPAlgo,
+    pub step: u64,
+}
+
+// RFC 4648 base32 without padding, as authenticators expect.
+fn base32_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let mut out = String::with_capacity((data.len() * 8 + 4) / 5);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for byte in data {
+        buffer = (buffer << 8) | (*byte as u32);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+fn uri_encode(data: &str) -> String {
+    data.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+impl TOTPSecret {
+    pub fn get_secret(&self) -> String {
+        base32_encode(&self.secret)
+    }
+
+    // The otpauth uri used in provisioning qr codes.
+    pub fn to_uri(&self) -> String {
+        let issuer = uri_encode(&self.issuer);
+        format!(
+            "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm={}&digits=6&period={}",
+            issuer,
+            uri_encode(&self.accountname),
+            self.get_secret(),
+            issuer,
+            self.algo,
+            self.step
+        )
+    }
+}
+
+// Generate a new totp secret for the authenticated account. It is held
+// pending until a code from it is verified.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TOTPGenerateRequest {}
+
+impl TOTPGenerateRequest {
+    pub fn new() -> Self {
+        TOTPGenerateRequest {}
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TOTPGenerateResponse {
+    pub secret: TOTPSecret,
+    pub uri: String,
+}
+
+impl TOTPGenerateResponse {
+    pub fn new(secret: TOTPSecret) -> Self {
+        let uri = secret.to_uri();
+        TOTPGenerateResponse {
+            secret: secret,
+            uri: uri,
+        }
+    }
+}
+
+// Confirm the pending totp secret with a code from it, which adds it to the
+// primary credential of the account.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TOTPVerifyRequest {
+    pub totp: String,
+}
+
+impl TOTPVerifyRequest {
+    pub fn new(totp: &str) -> Self {
+        TOTPVerifyRequest {
+            totp: totp.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TOTPVerifyResponse {}
+
+impl TOTPVerifyResponse {
+    pub fn new() -> Self {
+        TOTPVerifyResponse {}
+    }
+}
+
+/* Webauthn */
+
+// These follow the shape of the PublicKeyCredential dictionaries of the
+// webauthn spec, so that a browser can use them directly once the base64url
+// fields are decoded.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct WebauthnRelyingParty {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct WebauthnUser {
+    // The base64url of the account uuid.
+    pub id: String,
+    pub name: String,
+    pub display_name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct WebauthnPubKeyCredParams {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub alg: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct WebauthnCredentialDescriptor {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct WebauthnCreationChallenge {
+    pub challenge: String,
+    pub rp: WebauthnRelyingParty,
+    pub user: WebauthnUser,
+    pub pub_key_cred_params: Vec<WebauthnPubKeyCredParams>,
+    // Milliseconds.
+    pub timeout: u32,
+    // Tokens that are already registered, so they aren't registered twice.
+    pub exclude_credentials: Vec<WebauthnCredentialDescriptor>,
+    pub attestation: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct WebauthnRequestChallenge {
+    pub challenge: String,
+    pub timeout: u32,
+    pub rp_id: String,
+    pub allow_credentials: Vec<WebauthnCredentialDescriptor>,
+    pub user_verification: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct WebauthnAttestationResponse {
+    pub attestation_object: String,
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+}
+
+// The result of navigator.credentials.create().
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct WebauthnRegisterCredential {
+    pub id: String,
+    pub raw_id: String,
+    pub response: WebauthnAttestationResponse,
+    #[serde(rename = "type")]
+    pub type_: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct WebauthnAssertionResponse {
+    pub authenticator_data: String,
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub signature: String,
+    pub user_handle: Option<String>,
+}
+
+// The result of navigator.credentials.get().
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct WebauthnAssertion {
+    pub id: String,
+    pub raw_id: String,
+    pub response: WebauthnAssertionResponse,
+    #[serde(rename = "type")]
+    pub type_: String,
+}
+
+// A registered token of an account.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct WebauthnTokenInfo {
+    pub name: String,
+    // The base64url credential id.
+    pub id: String,
+    pub counter: u32,
+}
+
+impl fmt::Display for WebauthnTokenInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} id: {} counter: {}", self.name, self.id, self.counter)
+    }
+}
+
+// Begin registering a token to the authenticated account.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebauthnGenerateRequest {}
+
+impl WebauthnGenerateRequest {
+    pub fn new() -> Self {
+        WebauthnGenerateRequest {}
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebauthnGenerateResponse {
+    pub challenge: WebauthnCreationChallenge,
+}
+
+impl WebauthnGenerateResponse {
+    pub fn new(challenge: WebauthnCreationChallenge) -> Self {
+        WebauthnGenerateResponse {
+            challenge: challenge,
+        }
+    }
+}
+
+// Complete the registration with the response of the token to the challenge.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebauthnRegisterRequest {
+    pub name: String,
+    pub credential: WebauthnRegisterCredential,
+}
+
+impl WebauthnRegisterRequest {
+    pub fn new(name: &str, credential: WebauthnRegisterCredential) -> Self {
+        WebauthnRegisterRequest {
+            name: name.to_string(),
+            credential: credential,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebauthnRegisterResponse {}
+
+impl WebauthnRegisterResponse {
+    pub fn new() -> Self {
+        WebauthnRegisterResponse {}
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebauthnListResponse {
+    pub tokens: Vec<WebauthnTokenInfo>,
+}
+
+impl WebauthnListResponse {
+    pub fn new(tokens: Vec<WebauthnTokenInfo>) -> Self {
+        WebauthnListResponse { tokens: tokens }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebauthnRemoveRequest {
+    pub name: String,
+}
+
+impl WebauthnRemoveRequest {
+    pub fn new(name: &str) -> Self {
+        WebauthnRemoveRequest {
+            name: name.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebauthnRemoveResponse {}
+
+impl WebauthnRemoveResponse {
+    pub fn new() -> Self {
+        WebauthnRemoveResponse {}
+    }
+}
+
+/* Backup codes */
+
+// Replace the backup codes of the authenticated account with new ones. The
+// codes are only ever shown in this response.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackupCodesGenerateRequest {}
+
+impl BackupCodesGenerateRequest {
+    pub fn new() -> Self {
+        BackupCodesGenerateRequest {}
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackupCodesGenerateResponse {
+    pub codes: Vec<String>,
+}
+
+impl BackupCodesGenerateResponse {
+    pub fn new(codes: Vec<String>) -> Self {
+        BackupCodesGenerateResponse { codes: codes }
+    }
+}
+
+// Which combination of factors a credential requires to authenticate.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum CredentialPolicy {
+    PasswordOnly,
+    // The password, and then a totp, webauthn token or backup code.
+    PasswordMFA,
+    WebauthnOnly,
+}
+
+impl fmt::Display for CredentialPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CredentialPolicy::PasswordOnly => write!(f, "password"),
+            CredentialPolicy::PasswordMFA => write!(f, "password-mfa"),
+            CredentialPolicy::WebauthnOnly => write!(f, "webauthn"),
+        }
+    }
+}
+
+impl FromStr for CredentialPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "password" => Ok(CredentialPolicy::PasswordOnly),
+            "password-mfa" => Ok(CredentialPolicy::PasswordMFA),
+            "webauthn" => Ok(CredentialPolicy::WebauthnOnly),
+            _ => Err(format!("unknown credential policy {}", s)),
+        }
+    }
+}
+
+// Set the policy of our primary credential. None returns to the policy that
+// follows from the factors the credential has.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CredentialPolicyRequest {
+    pub policy: Option<CredentialPolicy>,
+}
+
+impl CredentialPolicyRequest {
+    pub fn new(policy: Option<CredentialPolicy>) -> Self {
+        CredentialPolicyRequest { policy: policy }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CredentialPolicyResponse {}
+
+impl CredentialPolicyResponse {
+    pub fn new() -> Self {
+        CredentialPolicyResponse {}
+    }
+}
+
+// Which factors the primary credential of the authenticated account has.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CredentialStatusResponse {
+    // The policy in force, if there is a credential.
+    pub policy: Option<CredentialPolicy>,
+    pub password: bool,
+    pub totp: bool,
+    // The names of the registered webauthn tokens.
+    pub webauthn: Vec<String>,
+    pub backup_codes_remaining: u32,
+}
+
+impl fmt::Display for CredentialStatusResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.policy {
+            Some(p) => writeln!(f, "policy: {}", p)?,
+            None => writeln!(f, "policy: none")?,
+        }
+        writeln!(f, "password: {}", self.password)?;
+        writeln!(f, "totp: {}", self.totp)?;
+        writeln!(f, "webauthn: {}", self.webauthn.join(", "))?;
+        write!(f, "backup codes remaining: {}", self.backup_codes_remaining)
+    }
+}
+
+/* Password change */
+
+#[derive(Serialize, Deserialize)]
+pub enum CredentialChangeRequest {
+    // Change the password of the authenticated account. The current password
+    // is only optional if the session authenticated recently.
+    SelfPassword {
+        current: Option<String>,
+        new: String,
+    },
+    // Reset the password of another account, by name or uuid. With
+    // must_change, the account must then change it before anything else.
+    AdminReset {
+        target: String,
+        new: String,
+        must_change: bool,
+    },
+    // Set the unix password of an account, by name or uuid, which may be
+    // our own. This is separate to the primary credential, and is what unix
+    // machines check when the account logs in to them.
+    UnixPassword {
+        target: String,
+        new: String,
+    },
+}
+
+impl fmt::Debug for CredentialChangeRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CredentialChangeRequest::SelfPassword { current, new: _ } => f
+                .debug_struct("SelfPassword")
+                .field("current", &current.as_ref().map(|_| REDACTED))
+                .field("new", &REDACTED)
+                .finish(),
+            CredentialChangeRequest::AdminReset {
+                target,
+                new: _,
+                must_change,
+            } => f
+                .debug_struct("AdminReset")
+                .field("target", target)
+                .field("new", &REDACTED)
+                .field("must_change", must_change)
+                .finish(),
+            CredentialChangeRequest::UnixPassword { target, new: _ } => f
+                .debug_struct("UnixPassword")
+                .field("target", target)
+                .field("new", &REDACTED)
+                .finish(),
+        }
+    }
+}
+
+impl CredentialChangeRequest {
+    pub fn new_self(current: Option<&str>, new: &str) -> Self {
+        CredentialChangeRequest::SelfPassword {
+            current: current.map(|s| s.to_string()),
+            new: new.to_string(),
+        }
+    }
+
+    pub fn new_admin_reset(target: &str, new: &str, must_change: bool) -> Self {
+        CredentialChangeRequest::AdminReset {
+            target: target.to_string(),
+            new: new.to_string(),
+            must_change: must_change,
+        }
+    }
+
+    pub fn new_unix_password(target: &str, new: &str) -> Self {
+        CredentialChangeRequest::UnixPassword {
+            target: target.to_string(),
+            new: new.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CredentialChangeResponse {}
+
+impl CredentialChangeResponse {
+    pub fn new() -> Self {
+        CredentialChangeResponse {}
+    }
+}
+
+/* Service account api tokens */
+
+// An api token of a service account. The secret is only shown when the
+// token is generated. Times are seconds since the unix epoch.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ApiTokenInfo {
+    pub id: Uuid,
+    pub label: String,
+    pub created: u64,
+    // The token can't be used from this time, if it is set.
+    pub expiry: Option<u64>,
+    pub last_used: Option<u64>,
+    pub read_write: bool,
+}
+
+impl fmt::Display for ApiTokenInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} label: {} created: {} expiry: {} last used: {} {}",
+            self.id,
+            self.label,
+            fmt_epoch(self.created),
+            self.expiry
+                .map(fmt_epoch)
+                .unwrap_or_else(|| "never".to_string()),
+            self.last_used
+                .map(fmt_epoch)
+                .unwrap_or_else(|| "never".to_string()),
+            if self.read_write {
+                "read write"
+            } else {
+                "read only"
+            }
+        )
+    }
+}
+
+// Generate an api token for the service account with this name or uuid.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiTokenGenerateRequest {
+    pub account: String,
+    pub label: String,
+    pub expiry: Option<u64>,
+    pub read_write: bool,
+}
+
+impl ApiTokenGenerateRequest {
+    pub fn new(account: &str, label: &str, expiry: Option<u64>, read_write: bool) -> Self {
+        ApiTokenGenerateRequest {
+            account: account.to_string(),
+            label: label.to_string(),
+            expiry: expiry,
+            read_write: read_write,
+        }
+    }
+}
+
+// The token to authenticate with, which can't be retrieved again.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiTokenGenerateResponse {
+    pub id: Uuid,
+    pub token: String,
+}
+
+impl ApiTokenGenerateResponse {
+    pub fn new(id: Uuid, token: String) -> Self {
+        ApiTokenGenerateResponse {
+            id: id,
+            token: token,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiTokenListRequest {
+    pub account: String,
+}
+
+impl ApiTokenListRequest {
+    pub fn new(account: &str) -> Self {
+        ApiTokenListRequest {
+            account: account.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiTokenListResponse {
+    pub tokens: Vec<ApiTokenInfo>,
+}
+
+impl ApiTokenListResponse {
+    pub fn new(tokens: Vec<ApiTokenInfo>) -> Self {
+        ApiTokenListResponse { tokens: tokens }
+    }
+}
+
+// Revoke a token, which also ends the sessions begun with it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiTokenDestroyRequest {
+    pub account: String,
+    pub id: Uuid,
+}
+
+impl ApiTokenDestroyRequest {
+    pub fn new(account: &str, id: Uuid) -> Self {
+        ApiTokenDestroyRequest {
+            account: account.to_string(),
+            id: id,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiTokenDestroyResponse {}
+
+impl ApiTokenDestroyResponse {
+    pub fn new() -> Self {
+        ApiTokenDestroyResponse {}
+    }
+}
+
+/* Radius */
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RadiusSecretGenerateRequest {}
+
+impl RadiusSecretGenerateRequest {
+    pub fn new() -> Self {
+        RadiusSecretGenerateRequest {}
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RadiusSecretGenerateResponse {
+    pub secret: String,
+}
+
+impl RadiusSecretGenerateResponse {
+    pub fn new(secret: String) -> Self {
+        RadiusSecretGenerateResponse { secret: secret }
+    }
+}
+
+// What a radius server needs to authenticate an account. The groups let it
+// decide what the account is given, such as which vlan.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RadiusAuthToken {
+    pub name: String,
+    pub displayname: String,
+    pub uuid: String,
+    pub secret: String,
+    pub groups: Vec<Group>,
+}
+
+impl fmt::Debug for RadiusAuthToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RadiusAuthToken")
+            .field("name", &self.name)
+            .field("displayname", &self.displayname)
+            .field("uuid", &self.uuid)
+            .field("secret", &REDACTED)
+            .field("groups", &self.groups)
+            .finish()
+    }
+}
+
+impl fmt::Display for RadiusAuthToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "name: {}", self.name)?;
+        writeln!(f, "displayname: {}", self.displayname)?;
+        writeln!(f, "uuid: {}", self.uuid)?;
+        writeln!(f, "secret: {}", self.secret)?;
+        for g in &self.groups {
+            writeln!(f, "group: {} ({})", g.name, g.uuid)?;
+        }
+        Ok(())
+    }
+}
+
+/* Unix */
+
+// A group as a unix machine sees it.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UnixGroupToken {
+    pub name: String,
+    pub uuid: String,
+    pub gidnumber: u32,
+}
+
+impl fmt::Display for UnixGroupToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "name: {}", self.name)?;
+        writeln!(f, "uuid: {}", self.uuid)?;
+        writeln!(f, "gidnumber: {}", self.gidnumber)
+    }
+}
+
+// What a unix machine needs to resolve an account for nss. An account is
+// its own primary group, which is the first of its groups, so its uid and
+// gid are both the gidnumber.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UnixUserToken {
+    pub name: String,
+    pub displayname: String,
+    pub uuid: String,
+    pub gidnumber: u32,
+    pub shell: Option<String>,
+    pub groups: Vec<UnixGroupToken>,
+}
+
+impl fmt::Display for UnixUserToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "name: {}", self.name)?;
+        writeln!(f, "displayname: {}", self.displayname)?;
+        writeln!(f, "uuid: {}", self.uuid)?;
+        writeln!(f, "gidnumber: {}", self.gidnumber)?;
+        match &self.shell {
+            Some(s) => writeln!(f, "shell: {}", s)?,
+            None => writeln!(f, "shell: none")?,
+        }
+        for g in &self.groups {
+            writeln!(f, "group: {} ({})", g.name, g.gidnumber)?;
+        }
+        Ok(())
+    }
+}
+
+// A unix machine asking if cred is the unix password of the account, by
+// name or uuid. The reply is the account's token if it is, and null for any
+// failure, including that there is no such account.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UnixAuthRequest {
+    pub account: String,
+    pub cred: String,
+}
+
+impl fmt::Debug for UnixAuthRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UnixAuthRequest")
+            .field("account", &self.account)
+            .field("cred", &REDACTED)
+            .finish()
+    }
+}
+
+impl UnixAuthRequest {
+    pub fn new(account: &str, cred: &str) -> Self {
+        UnixAuthRequest {
+            account: account.to_string(),
+            cred: cred.to_string(),
+        }
+    }
+}
+
+/* Token signing keys */
+
+// A public key that issued UserAuthTokens are signed with, in the form of
+// RFC 7517, so that other services can verify tokens themselves.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Jwk {
+    pub kty: String,
+    pub crv: String,
+    pub x: String,
+    pub y: String,
+    pub kid: String,
+    pub alg: String,
+    #[serde(rename = "use")]
+    pub use_: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JwkSet {
+    pub keys: Vec<Jwk>,
+}
+
+/* OAuth2 */
+
+// An authorization request of RFC 6749, sent as the query of the authorize
+// url by the browser of an authenticated account. Only the code flow is
+// supported, and it must use PKCE with S256, as RFC 7636 describes.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Oauth2AuthorizeRequest {
+    pub response_type: String,
+    pub client_id: String,
+    pub redirect_uri: String,
+    // The scopes asked for, separated by spaces.
+    #[serde(default)]
+    pub scope: String,
+    pub state: Option<String>,
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+    // Given back in the id token, so the client can tie it to this request.
+    pub nonce: Option<String>,
+}
+
+impl Oauth2AuthorizeRequest {
+    pub fn new(client_id: &str, redirect_uri: &str, scope: &str, code_challenge: &str) -> Self {
+        Oauth2AuthorizeRequest {
+            response_type: "code".to_string(),
+            client_id: client_id.to_string(),
+            redirect_uri: redirect_uri.to_string(),
+            scope: scope.to_string(),
+            state: None,
+            code_challenge: Some(code_challenge.to_string()),
+            code_challenge_method: Some("S256".to_string()),
+            nonce: None,
+        }
+    }
+}
+
+// The exchange of an authorization code for tokens, sent as a form. The
+// redirect uri and client must be those the code was issued to, and the
+// verifier must match the challenge it was issued with.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Oauth2TokenRequest {
+    pub grant_type: String,
+    pub code: String,
+    pub redirect_uri: String,
+    pub client_id: String,
+    #[serde(default)]
+    pub code_verifier: String,
+}
+
+impl Oauth2TokenRequest {
+    pub fn new(code: &str, redirect_uri: &str, client_id: &str, code_verifier: &str) -> Self {
+        Oauth2TokenRequest {
+            grant_type: "authorization_code".to_string(),
+            code: code.to_string(),
+            redirect_uri: redirect_uri.to_string(),
+            client_id: client_id.to_string(),
+            code_verifier: code_verifier.to_string(),
+        }
+    }
+}
+
+// The id token is only given when the openid scope was granted.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Oauth2TokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: u64,
+    pub scope: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+}
+
+// How an oauth2 request was refused, in the form of RFC 6749 rather than as
+// an ErrorResponse, as that is what oauth2 clients expect.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Oauth2ErrorResponse {
+    pub error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_description: Option<String>,
+}
+
+// The standard claims about the account a token was issued to. The subject
+// is always given, and the rest only when the profile or groups scopes were
+// granted.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Oauth2UserInfo {
+    pub sub: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preferred_username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub groups: Option<Vec<String>>,
+}
+
+// The claims of an id token, signed as a compact ES256 JWS with the same keys
+// as UserAuthTokens, so it can be checked with the keys of /v1/jwk.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OidcToken {
+    pub iss: String,
+    pub sub: String,
+    pub aud: String,
+    pub iat: u64,
+    pub exp: u64,
+    pub auth_time: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preferred_username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub groups: Option<Vec<String>>,
+}
+
+/* Domain */
+
+// The deployment this server is part of. The domain name is what spns are
+// made with, so renaming the domain changes every spn.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DomainInfo {
+    pub uuid: String,
+    pub domain_name: String,
+    pub domain_display_name: Option<String>,
+}
+
+impl fmt::Display for DomainInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "domain_uuid: {}", self.uuid)?;
+        writeln!(f, "domain_name: {}", self.domain_name)?;
+        if let Some(d) = &self.domain_display_name {
+            writeln!(f, "domain_display_name: {}", d)?;
+        }
+        Ok(())
+    }
+}
+
+/* Access controls */
+
+// A typed view of an access control profile. The receiver is who the
+// profile grants rights to, and the targetscope what entries the rights are
+// over. The rights each map to one of the access_control_* classes, and a
+// profile with none of them grants nothing.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AccessControlProfile {
+    pub name: String,
+    pub uuid: Option<String>,
+    pub description: Option<String>,
+    pub enabled: bool,
+    pub receiver: Filter,
+    pub targetscope: Filter,
+    // The attributes of the targets that may be searched on and read.
+    pub search: Option<Vec<String>>,
+    pub modify: Option<AccessControlModifyRights>,
+    pub create: Option<AccessControlCreateRights>,
+    pub delete: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AccessControlModifyRights {
+    // Attributes that may have values added or removed.
+    pub present_attrs: Vec<String>,
+    pub removed_attrs: Vec<String>,
+    // The classes that may be added to or removed from a target.
+    pub classes: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AccessControlCreateRights {
+    // The only attributes and classes a created entry may have.
+    pub attrs: Vec<String>,
+    pub classes: Vec<String>,
+}
+
+impl AccessControlProfile {
+    pub fn new(name: &str, receiver: Filter, targetscope: Filter) -> Self {
+        AccessControlProfile {
+            name: name.to_string(),
+            uuid: None,
+            description: None,
+            enabled: true,
+            receiver: receiver,
+            targetscope: targetscope,
+            search: None,
+            modify: None,
+            create: None,
+            delete: false,
+        }
+    }
+}
+
+impl fmt::Display for AccessControlProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "name: {}", self.name)?;
+        if let Some(u) = &self.uuid {
+            writeln!(f, "uuid: {}", u)?;
+        }
+        if let Some(d) = &self.description {
+            writeln!(f, "description: {}", d)?;
+        }
+        writeln!(f, "enabled: {}", self.enabled)?;
+        writeln!(f, "receiver: {}", self.receiver)?;
+        writeln!(f, "targetscope: {}", self.targetscope)?;
+        if let Some(attrs) = &self.search {
+            writeln!(f, "search: {}", attrs.join(", "))?;
+        }
+        if let Some(m) = &self.modify {
+            writeln!(f, "modify present: {}", m.present_attrs.join(", "))?;
+            writeln!(f, "modify removed: {}", m.removed_attrs.join(", "))?;
+            writeln!(f, "modify classes: {}", m.classes.join(", "))?;
+        }
+        if let Some(c) = &self.create {
+            writeln!(f, "create attrs: {}", c.attrs.join(", "))?;
+            writeln!(f, "create classes: {}", c.classes.join(", "))?;
+        }
+        writeln!(f, "delete: {}", self.delete)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum AccessCheckOperation {
+    Search,
+    Modify(ModifyList),
+    Delete,
+}
+
+// Ask whether receiver, by name or uuid, would be allowed an operation on
+// each entry matching filter, without performing it. Only idm_admins and
+// idm_acp_manager_priv may ask this.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AccessCheckRequest {
+    pub receiver: String,
+    pub filter: Filter,
+    pub operation: AccessCheckOperation,
+}
+
+impl AccessCheckRequest {
+    pub fn new(receiver: &str, filter: Filter, operation: AccessCheckOperation) -> Self {
+        AccessCheckRequest {
+            receiver: receiver.to_string(),
+            filter: filter,
+            operation: operation,
+        }
+    }
+}
+
+// The names of the profiles of the operation's kind that apply to the
+// receiver over this entry. A profile can apply and still not grant the
+// attributes that were asked for, so profiles may be listed for an entry
+// that is not allowed. No profiles means none applied.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AccessCheckEntry {
+    pub uuid: String,
+    pub allowed: bool,
+    pub profiles: Vec<String>,
+}
+
+impl fmt::Display for AccessCheckEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let decision = if self.allowed { "allowed" } else { "denied" };
+        if self.profiles.is_empty() {
+            write!(f, "{}: {} (no profile applies)", self.uuid, decision)
+        } else {
+            write!(
+                f,
+                "{}: {} (profiles: {})",
+                self.uuid,
+                decision,
+                self.profiles.join(", ")
+            )
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AccessCheckResponse {
+    pub entries: Vec<AccessCheckEntry>,
+}
+
+impl AccessCheckResponse {
+    pub fn new(entries: Vec<AccessCheckEntry>) -> Self {
+        AccessCheckResponse { entries: entries }
+    }
+}
+
+// The rights the requesting identity has over each entry matching target
+// that it can see. These are worked out the same way the server enforces
+// them, so an operation within them is allowed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EffectiveAccessRequest {
+    pub target: Filter,
+}
+
+impl EffectiveAccessRequest {
+    pub fn new(target: Filter) -> Self {
+        EffectiveAccessRequest { target: target }
+    }
+}
+
+// The name is only given when it may be read.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct EffectiveAccess {
+    pub uuid: String,
+    pub name: Option<String>,
+    pub search: Vec<String>,
+    pub modify_present: Vec<String>,
+    pub modify_removed: Vec<String>,
+    pub modify_classes: Vec<String>,
+    pub delete: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EffectiveAccessResponse {
+    pub entries: Vec<EffectiveAccess>,
+}
+
+impl EffectiveAccessResponse {
+    pub fn new(entries: Vec<EffectiveAccess>) -> Self {
+        EffectiveAccessResponse { entries: entries }
+    }
+}
+
+/* Recycle Requests area */
+
+// Only two actions on recycled is possible. Search and Revive.
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SearchRecycledRequest {
+    pub filter: Filter,
+}
+
+impl SearchRecycledRequest {
+    pub fn new(filter: Filter) -> Self {
+        SearchRecycledRequest { filter: filter }
+    }
+}
+
+// Each entry carries deleted_at, the time it was moved to the recycle bin.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SearchRecycledResponse {
+    pub entries: Vec<Entry>,
+}
+
+impl SearchRecycledResponse {
+    pub fn new(entries: Vec<Entry>) -> Self {
+        SearchRecycledResponse { entries: entries }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReviveRecycledRequest {
+    pub filter: Filter,
+}
+
+impl ReviveRecycledRequest {
+    pub fn new(filter: Filter) -> Self {
+        ReviveRecycledRequest { filter: filter }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReviveRecycledResponse {
+    // The uuids of the entries that were brought back.
+    pub revived: Vec<String>,
+}
+
+impl ReviveRecycledResponse {
+    pub fn new(revived: Vec<String>) -> Self {
+        ReviveRecycledResponse { revived: revived }
+    }
+}
+
+/* Audit log area */
+
+// Written in place of the values of attributes the schema marks as secret.
+pub const AUDIT_REDACTED: &str = "[redacted]";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum AuditOperation {
+    Create,
+    Modify,
+    Delete,
+    Revive,
+}
+
+impl fmt::Display for AuditOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuditOperation::Create => write!(f, "create"),
+            AuditOperation::Modify => write!(f, "modify"),
+            AuditOperation::Delete => write!(f, "delete"),
+            AuditOperation::Revive => write!(f, "revive"),
+        }
+    }
+}
+
+// A write made by an account, as it was recorded. The changes of a create
+// are the attributes of the new entry, and of a modify or revive they are the
+// modifications. A delete records no changes.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AuditRecord {
+    // rfc3339, to the second.
+    pub time: String,
+    pub eventid: String,
+    // The uuid of the account that made the change.
+    pub identity: String,
+    pub operation: AuditOperation,
+    pub targets: Vec<String>,
+    pub changes: Vec<Modify>,
+}
+
+impl fmt::Display for AuditRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} {} by {} (operation id: {})",
+            self.time, self.operation, self.identity, self.eventid
+        )?;
+        for t in self.targets.iter() {
+            writeln!(f, "  target: {}", t)?;
+        }
+        for c in self.changes.iter() {
+            match c {
+                Modify::Present(a, v) => writeln!(f, "  + {}: {}", a, v)?,
+                Modify::Removed(a, v) => writeln!(f, "  - {}: {}", a, v)?,
+                Modify::Purged(a) => writeln!(f, "  - {}", a)?,
+                Modify::Assert(a, v) => writeln!(f, "  = {}: {}", a, v)?,
+                Modify::AssertMissing(a) => writeln!(f, "  ! {}", a)?,
+                Modify::Set(a, vs) => writeln!(f, "  := {}: {}", a, vs.join(", "))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// List the audit log, oldest first. The times are rfc3339 and both bounds
+// are inclusive. With a target uuid, only the records of changes to that
+// entry are given. Only idm_admins may read the audit log.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct AuditListRequest {
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub target: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuditListResponse {
+    pub records: Vec<AuditRecord>,
+}
+
+impl AuditListResponse {
+    pub fn new(records: Vec<AuditRecord>) -> Self {
+        AuditListResponse { records: records }
+    }
+}
+
+/* Changelog area */
+
+// Every write is given a change id, which is the server's time in
+// nanoseconds, a sequence for writes made within the same nanosecond, and the
+// uuid of the server, as "<time>-<sequence>-<uuid>". Ids of one server order
+// as the writes were made.
+//
+// List the entries changed after the change id since, or every change the
+// changelog still holds when there is none. Only idm_admins may read the
+// changelog.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct ChangesRequest {
+    pub since: Option<String>,
+}
+
+// An entry that changed, with the names of the attributes that changed and
+// the id of its latest change. Values are never given, so they must be read
+// from the entry. An entry that is no longer found has been purged.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ChangedEntry {
+    pub uuid: String,
+    pub attrs: Vec<String>,
+    pub cid: String,
+}
+
+// The changed entries, in the order of their latest change, and the id of
+// the latest change the server has made. That id is the since of the next
+// request, even if nothing changed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChangesResponse {
+    pub entries: Vec<ChangedEntry>,
+    pub cid: Option<String>,
+}
+
+impl ChangesResponse {
+    pub fn new(entries: Vec<ChangedEntry>, cid: Option<String>) -> Self {
+        ChangesResponse {
+            entries: entries,
+            cid: cid,
+        }
+    }
+}
+
+// Export the entries changed after the change id since, as the requester may
+// read them, or every entry they may read when there is none. Unlike the
+// changelog itself, anyone may export. The answer is streamed, as a search
+// to /v1/search/_stream is.
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct ExportRequest {
+    pub since: Option<String>,
+}
+
+// Each entry that changed is given as it is now, if the requester may read
+// its uuid. An entry that was deleted, recycled or purged since is given by
+// its uuid. An entry that the requester can no longer read is left out, as
+// it isn't known whether they could read it before. The stream ends with Done and the id to export since next time,
+// or with Error. If the changes since were trimmed from the changelog, the
+// error is ChangelogTrimmed, and only a full export is complete.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ExportStreamItem {
+    Entry(Entry),
+    Deleted(String),
+    Done(Option<String>),
+    Error(ErrorResponse),
+}
+
+/* Backup area */
+
+// Take a backup of the running server, into the directory it was configured
+// with.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackupRequest {}
+
+impl BackupRequest {
+    pub fn new() -> Self {
+        BackupRequest {}
+    }
+}
+
+// Where the server wrote the backup.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackupResponse {
+    pub path: String,
+}
+
+impl BackupResponse {
+    pub fn new(path: String) -> Self {
+        BackupResponse { path: path }
+    }
+}
+
+/* Maintenance area */
+
+// Build every index again from the entries, as the schema now says.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReindexRequest {}
+
+impl ReindexRequest {
+    pub fn new() -> Self {
+        ReindexRequest {}
+    }
+}
+
+// The number of keys in each index, by attribute.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReindexResponse {
+    pub indexes: BTreeMap<String, usize>,
+}
+
+impl ReindexResponse {
+    pub fn new(indexes: BTreeMap<String, usize>) -> Self {
+        ReindexResponse { indexes: indexes }
+    }
+}
+
+// Reclaim the free space in the database file, and check its integrity.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VacuumRequest {}
+
+impl VacuumRequest {
+    pub fn new() -> Self {
+        VacuumRequest {}
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VacuumResponse {}
+
+impl VacuumResponse {
+    pub fn new() -> Self {
+        VacuumResponse {}
+    }
+}
+
+// An index that the schema asks for, or that exists, or both. An index that
+// is declared but doesn't exist yet is built by a reindex.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct IndexStatus {
+    // Such as "name.eq".
+    pub name: String,
+    pub attr: String,
+    pub index: String,
+    pub declared: bool,
+    pub exists: bool,
+    // The keys of every entry, and how many of them differ.
+    pub keys: usize,
+    pub distinct: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IndexStatusRequest {}
+
+impl IndexStatusRequest {
+    pub fn new() -> Self {
+        IndexStatusRequest {}
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IndexStatusResponse {
+    pub indexes: Vec<IndexStatus>,
+}
+
+impl IndexStatusResponse {
+    pub fn new(indexes: Vec<IndexStatus>) -> Self {
+        IndexStatusResponse { indexes: indexes }
+    }
+}
+
+/* Health area */
+
+// One of the checks made for a health probe. The detail only says why it
+// failed, and never carries anything of the entries.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct HealthCheck {
+    pub healthy: bool,
+    pub detail: Option<String>,
+}
+
+impl HealthCheck {
+    pub fn new_healthy() -> Self {
+        HealthCheck {
+            healthy: true,
+            detail: None,
+        }
+    }
+
+    pub fn new_failed(detail: String) -> Self {
+        HealthCheck {
+            healthy: false,
+            detail: Some(detail),
+        }
+    }
+}
+
+// The server is healthy only when every check is.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HealthResponse {
+    pub healthy: bool,
+    pub checks: BTreeMap<String, HealthCheck>,
+}
+
+impl HealthResponse {
+    pub fn new(checks: BTreeMap<String, HealthCheck>) -> Self {
+        HealthResponse {
+            healthy: checks.values().all(|c| c.healthy),
+            checks: checks,
+        }
+    }
+}
+
+/* Verify area */
+
+// The checks of consistency that can be run alone, each for a part of what
+// the server derives or holds.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum VerifyCheck {
+    Schema,
+    Index,
+    Refint,
+    MemberOf,
+}
+
+impl FromStr for VerifyCheck {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "schema" => Ok(VerifyCheck::Schema),
+            "index" => Ok(VerifyCheck::Index),
+            "refint" => Ok(VerifyCheck::Refint),
+            "memberof" => Ok(VerifyCheck::MemberOf),
+            _ => Err(format!(
+                "unknown check {} - must be one of schema, index, refint or memberof",
+                s
+            )),
+        }
+    }
+}
+
+// With no checks given, every check is run.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct VerifyRequest {
+    #[serde(default)]
+    pub checks: Vec<VerifyCheck>,
+}
+
+impl VerifyRequest {
+    pub fn new(checks: Vec<VerifyCheck>) -> Self {
+        VerifyRequest { checks: checks }
+    }
+}
+
+// The server is consistent when there are no results.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct VerifyResponse {
+    pub results: Vec<ConsistencyError>,
+}
+
+impl VerifyResponse {
+    pub fn new(results: Vec<ConsistencyError>) -> Self {
+        VerifyResponse { results: results }
+    }
+}
+
+/* Repair area */
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct RepairRequest {
+    // Find what would be changed, without changing it.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+impl RepairRequest {
+    pub fn new(dry_run: bool) -> Self {
+        RepairRequest { dry_run: dry_run }
+    }
+}
+
+// A change made by a repair. The target is the entry's uuid, or for an
+// index, the entry's id, as there may be no such entry.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct RepairChange {
+    pub target: String,
+    pub attr: String,
+    pub before: Vec<String>,
+    pub after: Vec<String>,
+}
+
+impl RepairChange {
+    pub fn new(target: String, attr: String, before: Vec<String>, after: Vec<String>) -> Self {
+        RepairChange {
+            target: target,
+            attr: attr,
+            before: before,
+            after: after,
+        }
+    }
+}
+
+impl fmt::Display for RepairChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}: [{}] -> [{}]",
+            self.target,
+            self.attr,
+            self.before.join(", "),
+            self.after.join(", ")
+        )
+    }
+}
+
+// What was found that repair can't fix is left as it was, and is given back
+// with what remains after the repair.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct RepairResponse {
+    pub dry_run: bool,
+    pub changes: Vec<RepairChange>,
+    pub unrepaired: Vec<ConsistencyError>,
+}
+
+impl RepairResponse {
+    pub fn new(
+        dry_run: bool,
+        changes: Vec<RepairChange>,
+        unrepaired: Vec<ConsistencyError>,
+    ) -> Self {
+        RepairResponse {
+            dry_run: dry_run,
+            changes: changes,
+            unrepaired: unrepaired,
+        }
+    }
+}
+
+// This doesn't need seralise because it's only accessed via a "get".
+#[derive(Debug)]
+pub struct WhoamiRequest {}
+
+impl WhoamiRequest {
+    pub fn new() -> Self {
+        WhoamiRequest {}
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WhoamiResponse {
+    // Should we just embed the entry? Or destructure it?
+    pub youare: Entry,
+    pub uat: UserAuthToken,
+}
+
+impl WhoamiResponse {
+    pub fn new(e: Entry, uat: UserAuthToken) -> Self {
+        WhoamiResponse {
+            youare: e,
+            uat: uat,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::v1::Filter as ProtoFilter;
+    use crate::v1::FilterParseError;
+    use crate::v1::UserAuthToken;
+    use crate::v1::{ConsistencyError, OperationError, PasswordFeedback, SchemaError};
+    use crate::v1::{Entry, EntryValueError, Modify, ModifyList, ModifyListBuildError};
+    use crate::v1::{TOTPAlgo, TOTPSecret};
+    use std::collections::BTreeMap;
+    use std::error::Error;
+    use std::time::Duration;
+    use uuid::Uuid;
+    #[test]
+    fn test_totp_secret_uri() {
+        let secret = TOTPSecret {
+            accountname: "admin@example.com".to_string(),
+            issuer: "Kani IDM".to_string(),
+            secret: b"12345678901234567890".to_vec(),
+            algo: TOTPAlgo::Sha1,
+            step: 30,
+        };
+        assert_eq!(secret.get_secret(), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+        assert_eq!(
+            secret.to_uri(),
+            "otpauth://totp/Kani%20IDM:admin%40example.com?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&issuer=Kani%20IDM&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+
+    #[test]
+    fn test_protofilter_simple() {
+        let pf: ProtoFilter = ProtoFilter::Pres("class".to_string());
+
+        println!("{:?}", serde_json::to_string(&pf).expect("JSON failure"));
+    }
+
+    #[test]
+    fn test_protofilter_bool_json() {
+        let pf = ProtoFilter::And(vec![ProtoFilter::True, ProtoFilter::False]);
+        let s = serde_json::to_string(&pf).expect("JSON failure");
+        assert_eq!(s, r#"{"And":["True","False"]}"#);
+        let r: ProtoFilter = serde_json::from_str(s.as_str()).expect("JSON failure");
+        assert_eq!(r, pf);
+    }
+
+    #[test]
+    fn test_protofilter_from_ldap_str() {
+        let eq = |a: &str, v: &str| ProtoFilter::Eq(a.to_string(), v.to_string());
+
+        assert_eq!(
+            ProtoFilter::from_ldap_str("(&(class=account)(name=fred))"),
+            Ok(ProtoFilter::And(vec![
+                eq("class", "account"),
+                eq("name", "fred")
+            ]))
+        );
+        assert_eq!(
+            ProtoFilter::from_ldap_str("(|(name=fred)(!(name=alice)))"),
+            Ok(ProtoFilter::Or(vec![
+                eq("name", "fred"),
+                ProtoFilter::AndNot(Box::new(eq("name", "alice"))),
+            ]))
+        );
+        assert_eq!(
+            ProtoFilter::from_ldap_str("(class=*)"),
+            Ok(ProtoFilter::Pres("class".to_string()))
+        );
+        assert_eq!(
+            ProtoFilter::from_ldap_str("(name=*re*)"),
+            Ok(ProtoFilter::Sub("name".to_string(), "re".to_string()))
+        );
+        assert_eq!(
+            ProtoFilter::from_ldap_str("(gidnumber>=1000)"),
+            Ok(ProtoFilter::Gte(
+                "gidnumber".to_string(),
+                "1000".to_string()
+            ))
+        );
+        assert_eq!(
+            ProtoFilter::from_ldap_str("(gidnumber<=1000)"),
+            Ok(ProtoFilter::Lte(
+                "gidnumber".to_string(),
+                "1000".to_string()
+            ))
+        );
+        // The enclosing parens and surrounding whitespace are optional
+        assert_eq!(
+            ProtoFilter::from_ldap_str("  name=fred smith "),
+            Ok(eq("name", "fred smith"))
+        );
+        // Escapes
+        assert_eq!(
+            ProtoFilter::from_ldap_str(r"(description=\28a\29 \2a\5c)"),
+            Ok(eq("description", r"(a) *\"))
+        );
+        assert_eq!(
+            ProtoFilter::from_ldap_str(r"(description=*\2A*)"),
+            Ok(ProtoFilter::Sub("description".to_string(), "*".to_string()))
+        );
+        assert_eq!(
+            ProtoFilter::from_ldap_str(r"(name=\c3\a9)"),
+            Ok(eq("name", "é"))
+        );
+        assert_eq!(
+            ProtoFilter::from_ldap_str("(uuid=self)"),
+            Ok(ProtoFilter::SelfUUID)
+        );
+        assert_eq!(ProtoFilter::from_ldap_str("(&)"), Ok(ProtoFilter::True));
+        assert_eq!(
+            ProtoFilter::from_ldap_str("(|(name=fred)(|))"),
+            Ok(ProtoFilter::Or(vec![
+                eq("name", "fred"),
+                ProtoFilter::False
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_protofilter_from_ldap_str_invalid() {
+        assert_eq!(ProtoFilter::from_ldap_str(""), Err(FilterParseError::Empty));
+        assert_eq!(
+            ProtoFilter::from_ldap_str("   "),
+            Err(FilterParseError::Empty)
+        );
+        assert_eq!(
+            ProtoFilter::from_ldap_str("(&(name=a)"),
+            Err(FilterParseError::UnbalancedParens(10))
+        );
+        assert_eq!(
+            ProtoFilter::from_ldap_str("(name=a))"),
+            Err(FilterParseError::UnbalancedParens(8))
+        );
+        assert_eq!(
+            ProtoFilter::from_ldap_str("(!)"),
+            Err(FilterParseError::UnexpectedChar(')', 2))
+        );
+        assert_eq!(
+            ProtoFilter::from_ldap_str("(=a)"),
+            Err(FilterParseError::EmptyAttribute(1))
+        );
+        assert_eq!(
+            ProtoFilter::from_ldap_str("(name=)"),
+            Err(FilterParseError::EmptyValue(6))
+        );
+        assert_eq!(
+            ProtoFilter::from_ldap_str("(name)"),
+            Err(FilterParseError::UnexpectedChar(')', 5))
+        );
+        assert_eq!(
+            ProtoFilter::from_ldap_str("(name=a(b)"),
+            Err(FilterParseError::UnexpectedChar('(', 7))
+        );
+        assert_eq!(
+            ProtoFilter::from_ldap_str("(!name=a)"),
+            Err(FilterParseError::UnexpectedChar('n', 2))
+        );
+        assert_eq!(
+            ProtoFilter::from_ldap_str(r"(name=\2)"),
+            Err(FilterParseError::InvalidEscape(6))
+        );
+        assert_eq!(
+            ProtoFilter::from_ldap_str(r"(name=\zz)"),
+            Err(FilterParseError::InvalidEscape(6))
+        );
+        assert_eq!(
+            ProtoFilter::from_ldap_str(r"(name=\ff)"),
+            Err(FilterParseError::InvalidUtf8(6))
+        );
+        assert_eq!(
+            ProtoFilter::from_ldap_str("(name~=fred)"),
+            Err(FilterParseError::UnsupportedMatch("~=".to_string(), 5))
+        );
+        assert_eq!(
+            ProtoFilter::from_ldap_str("(name=fr*ed)"),
+            Err(FilterParseError::UnsupportedSubstring(6))
+        );
+        assert_eq!(
+            ProtoFilter::from_ldap_str("(name=fred*)"),
+            Err(FilterParseError::UnsupportedSubstring(6))
+        );
+        assert_eq!(
+            ProtoFilter::from_ldap_str("(name>=*)"),
+            Err(FilterParseError::UnsupportedSubstring(5))
+        );
+    }
+
+    #[test]
+    fn test_protofilter_display() {
+        let f = ProtoFilter::And(vec![
+            ProtoFilter::Eq("class".to_string(), "group".to_string()),
+            ProtoFilter::Eq("name".to_string(), "admins".to_string()),
+        ]);
+        assert_eq!(f.to_string(), "(&(class=group)(name=admins))");
+
+        let f = ProtoFilter::Or(vec![
+            ProtoFilter::Sub("description".to_string(), "(a*b)\\".to_string()),
+            ProtoFilter::AndNot(Box::new(ProtoFilter::SelfUUID)),
+        ]);
+        assert_eq!(
+            f.to_string(),
+            r"(|(description=*\28a\2ab\29\5c*)(!(uuid=self)))"
+        );
+    }
+
+    fn filter_corpus() -> Vec<ProtoFilter> {
+        let s = |v: &str| v.to_string();
+        let leaves = vec![
+            ProtoFilter::Eq(s("name"), s("fred")),
+            ProtoFilter::Eq(
+                s("description"),
+                s("a (complex) value\\with *all* the specials\0"),
+            ),
+            ProtoFilter::Eq(s("displayname"), s(" leading and trailing spaces ")),
+            ProtoFilter::Eq(s("displayname"), s("ünïcödé 名前")),
+            ProtoFilter::Sub(s("name"), s("re")),
+            ProtoFilter::Sub(s("name"), s("*")),
+            ProtoFilter::Pres(s("class")),
+            ProtoFilter::Gte(s("gidnumber"), s("1000")),
+            ProtoFilter::Lte(s("gidnumber"), s("2000")),
+            ProtoFilter::Inclusion(s("name"), vec![s("alice"), s("(bob)")]),
+            ProtoFilter::SelfUUID,
+            ProtoFilter::True,
+            ProtoFilter::False,
+        ];
+        // Nest every leaf in each kind of compound term, and combine them all.
+        let mut corpus = leaves.clone();
+        for l in leaves.iter() {
+            corpus.push(ProtoFilter::AndNot(Box::new(l.clone())));
+            corpus.push(ProtoFilter::And(vec![l.clone()]));
+            corpus.push(ProtoFilter::Or(vec![
+                l.clone(),
+                ProtoFilter::AndNot(Box::new(ProtoFilter::And(vec![l.clone(), l.clone()]))),
+            ]));
+        }
+        corpus.push(ProtoFilter::And(leaves.clone()));
+        corpus.push(ProtoFilter::Or(leaves));
+        corpus
+    }
+
+    #[test]
+    fn test_protofilter_ldap_str_roundtrip() {
+        for f in filter_corpus() {
+            let s = f.to_string();
+            assert_eq!(ProtoFilter::from_ldap_str(s.as_str()), Ok(f.normalise()));
+        }
+    }
+
+    #[test]
+    fn test_modifylist_set_json() {
+        let ml = ModifyList::builder()
+            .set("member", vec!["a", "b"])
+            .build()
+            .expect("Build failure");
+        let s = serde_json::to_string(&ml).expect("JSON failure");
+        assert_eq!(s, r#"{"mods":[{"Set":["member",["a","b"]]}]}"#);
+    }
+
+    #[test]
+    fn test_modifylist_builder_normalise() {
+        let ml = ModifyList::builder()
+            .present("Member", "a")
+            .purge("LEGACY_attr")
+            .removed("mail", "B@Example.com")
+            .build()
+            .expect("Build failure");
+        assert_eq!(
+            ml.mods,
+            vec![
+                Modify::Present("member".to_string(), "a".to_string()),
+                Modify::Purged("legacy_attr".to_string()),
+                // Only the attribute name is changed, never the value.
+                Modify::Removed("mail".to_string(), "B@Example.com".to_string()),
+            ]
+        );
+
+        assert_eq!(
+            ModifyList::builder()
+                .purge("name")
+                .present("", "a")
+                .build()
+                .err(),
+            Some(ModifyListBuildError::EmptyAttribute(1))
+        );
+        assert_eq!(
+            ModifyList::builder().purge(" ").build().err(),
+            Some(ModifyListBuildError::EmptyAttribute(0))
+        );
+        assert_eq!(
+            ModifyList::builder().removed("mail", "").build().err(),
+            Some(ModifyListBuildError::EmptyValue(0))
+        );
+        assert_eq!(
+            ModifyList::builder()
+                .purge("mail")
+                .set("member", vec!["a", ""])
+                .build()
+                .err(),
+            Some(ModifyListBuildError::EmptyValue(1))
+        );
+    }
+
+    #[test]
+    fn test_modifylist_builder_dedup() {
+        let ml = ModifyList::builder()
+            .present("member", "a")
+            .present("Member", "a")
+            .present("mail", "a")
+            .present("member", "a")
+            .present("member", "b")
+            .build()
+            .expect("Build failure");
+        assert_eq!(
+            ml.mods,
+            vec![
+                Modify::Present("member".to_string(), "a".to_string()),
+                Modify::Present("mail".to_string(), "a".to_string()),
+                Modify::Present("member".to_string(), "b".to_string()),
+            ]
+        );
+
+        // A repeat after a different change to the attribute is kept, as
+        // dropping it would change the result.
+        let ml = ModifyList::builder()
+            .present("member", "a")
+            .removed("member", "a")
+            .present("member", "a")
+            .build()
+            .expect("Build failure");
+        assert_eq!(ml.mods.len(), 3);
+    }
+
+    #[test]
+    fn test_entry_ldif_display() {
+        let mut e = Entry {
+            attrs: BTreeMap::new(),
+        };
+        let mut set = |a: &str, vs: Vec<&str>| {
+            e.attrs.insert(
+                a.to_string(),
+                vs.into_iter().map(|v| v.to_string()).collect(),
+            )
+        };
+        set("name", vec!["testperson"]);
+        set("class", vec!["object", "person"]);
+        set("description", vec![" leading space", "a:b <c>"]);
+        set("displayname", vec!["Zoë"]);
+        set("legalname", vec!["two\nlines"]);
+        set("mail", vec![":colon", "<angle", "trailing "]);
+
+        let expect = r#"class: object
+class: person
+description:: IGxlYWRpbmcgc3BhY2U=
+description: a:b <c>
+displayname:: Wm/Dqw==
+legalname:: dHdvCmxpbmVz
+mail:: OmNvbG9u
+mail:: PGFuZ2xl
+mail:: dHJhaWxpbmcg
+name: testperson
+"#;
+        assert_eq!(e.to_string(), expect);
+        assert_eq!(e.to_ldif(), expect);
+    }
+
+    // A small deterministic generator, so the round trip can be checked over
+    // many entries without a dependency.
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self, bound: u64) -> u64 {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (self.0 >> 33) % bound
+        }
+
+        fn entry(&mut self) -> Entry {
+            let names = ["class", "name", "member", "mail", "description"];
+            let values = ["a", "b", "c", "person", "group", "x y"];
+            let mut attrs: BTreeMap<String, Vec<String>> = BTreeMap::new();
+            for n in names.iter() {
+                let mut vs: Vec<String> = values
+                    .iter()
+                    .filter(|_| self.next(3) == 0)
+                    .map(|v| v.to_string())
+                    .collect();
+                vs.sort();
+                if !vs.is_empty() {
+                    attrs.insert(n.to_string(), vs);
+                }
+            }
+            Entry { attrs: attrs }
+        }
+    }
+
+    #[test]
+    fn test_entry_diff_apply_roundtrip() {
+        let mut rng = Lcg(12345);
+        for _ in 0..500 {
+            let a = rng.entry();
+            let b = rng.entry();
+            let ml = a.diff(&b);
+            let mut r = a.clone();
+            r.apply(&ml);
+            assert_eq!(r, b);
+            // No changes are needed once they are the same.
+            assert!(r.diff(&b).mods.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_entry_diff() {
+        let entry = |attrs: Vec<(&str, Vec<&str>)>| Entry {
+            attrs: attrs
+                .into_iter()
+                .map(|(k, vs)| {
+                    (
+                        k.to_string(),
+                        vs.into_iter().map(|v| v.to_string()).collect(),
+                    )
+                })
+                .collect(),
+        };
+        let a = entry(vec![
+            ("Name", vec!["testperson"]),
+            ("member", vec!["a", "b"]),
+            ("legacy_attr", vec!["old"]),
+            ("description", vec!["text"]),
+        ]);
+        let b = entry(vec![
+            ("name", vec!["testperson"]),
+            ("member", vec!["b", "c"]),
+            // Only surrounding whitespace differs, so this is unchanged.
+            ("description", vec![" text "]),
+        ]);
+        let s = |v: &str| v.to_string();
+        assert_eq!(
+            a.diff(&b).mods,
+            vec![
+                Modify::Purged(s("legacy_attr")),
+                Modify::Removed(s("member"), s("a")),
+                Modify::Present(s("member"), s("c")),
+            ]
+        );
+
+        let mut r = a.clone();
+        r.apply(&a.diff(&b));
+        assert_eq!(
+            r,
+            entry(vec![
+                ("name", vec!["testperson"]),
+                ("member", vec!["b", "c"]),
+                ("description", vec!["text"]),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_entry_typed_accessors() {
+        let mut e = Entry {
+            attrs: BTreeMap::new(),
+        };
+        let mut set = |a: &str, vs: Vec<&str>| {
+            e.attrs.insert(
+                a.to_string(),
+                vs.into_iter().map(|v| v.to_string()).collect(),
+            )
+        };
+        set("name", vec!["testperson"]);
+        set("uuid", vec!["cc8e95b4-c24f-4d68-ba54-8bed76f63930"]);
+        set("gidnumber", vec!["2000"]);
+        set("locked", vec!["true"]);
+        set("badint", vec!["two thousand"]);
+        set("member", vec!["a", "b"]);
+        set("MixedCase", vec!["value"]);
+
+        // Attribute names are not case sensitive.
+        assert_eq!(e.get_ava_single("NAME"), Some("testperson"));
+        assert_eq!(e.get_ava_single("mixedcase"), Some("value"));
+        assert!(e.attribute_pres("Member"));
+        assert!(e.attribute_value_pres("member", "b"));
+        assert!(!e.attribute_value_pres("member", "c"));
+        assert_eq!(
+            e.get_ava("member"),
+            Some(&["a".to_string(), "b".to_string()][..])
+        );
+        // Many values are not a single value.
+        assert_eq!(e.get_ava_single("member"), None);
+
+        assert_eq!(
+            e.get_ava_single_uuid("uuid"),
+            Ok(Uuid::parse_str("cc8e95b4-c24f-4d68-ba54-8bed76f63930").ok())
+        );
+        assert_eq!(e.get_ava_single_u64("gidnumber"), Ok(Some(2000)));
+        assert_eq!(e.get_ava_bool("locked"), Ok(Some(true)));
+
+        // A missing attribute is not an error ...
+        assert!(!e.attribute_pres("missing"));
+        assert_eq!(e.get_ava("missing"), None);
+        assert_eq!(e.get_ava_single_uuid("missing"), Ok(None));
+        assert_eq!(e.get_ava_single_u64("missing"), Ok(None));
+        assert_eq!(e.get_ava_bool("missing"), Ok(None));
+
+        // ... but a value of the wrong type is.
+        assert_eq!(
+            e.get_ava_single_u64("badint"),
+            Err(EntryValueError::InvalidInteger("two thousand".to_string()))
+        );
+        assert_eq!(
+            e.get_ava_single_uuid("name"),
+            Err(EntryValueError::InvalidUuid("testperson".to_string()))
+        );
+        assert_eq!(
+            e.get_ava_bool("gidnumber"),
+            Err(EntryValueError::InvalidBool("2000".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_uat_expiry() {
+        let uat = UserAuthToken {
+            issued_at: 1577836800,
+            expiry: 1577840400,
+            auth_time: 1577836800,
+            sessionid: Uuid::new_v4(),
+            name: "admin".to_string(),
+            spn: "admin@localhost".to_string(),
+            displayname: "admin".to_string(),
+            uuid: "00000000-0000-0000-0000-000000000000".to_string(),
+            application: None,
+            groups: Vec::new(),
+            claims: Vec::new(),
+            must_change_password: false,
+            anonymous: false,
+            api_token: None,
+            read_only: false,
+        };
+
+        assert!(!uat.is_expired(Duration::from_secs(1577836800)));
+        assert!(!uat.is_expired(Duration::from_secs(1577840399)));
+        assert!(uat.is_expired(Duration::from_secs(1577840400)));
+
+        let s = uat.to_string();
+        assert!(s.contains("issued at: 2020-01-01T00:00:00Z"));
+        assert!(s.contains("expiry: 2020-01-01T01:00:00Z"));
+        assert!(s.contains("authenticated at: 2020-01-01T00:00:00Z"));
+    }
+
+    // Clients may match on these, so they must not change by accident.
+    #[test]
+    fn test_error_display() {
+        assert_eq!(
+            SchemaError::MissingMustAttribute("name".to_string()).to_string(),
+            "the required attribute name is missing"
+        );
+        assert_eq!(
+            ConsistencyError::RefintNotUpheld(4).to_string(),
+            "entry 4 refers to an entry that does not exist"
+        );
+        assert_eq!(OperationError::AccessDenied.to_string(), "access denied");
+        assert_eq!(
+            OperationError::InvalidACPState("missing targetscope").to_string(),
+            "invalid access control profile: missing targetscope"
+        );
+        assert_eq!(
+            OperationError::SchemaViolation(SchemaError::InvalidClass).to_string(),
+            "schema violation: a class is missing or not in the schema"
+        );
+        assert_eq!(
+            OperationError::ConsistencyError(vec![
+                Ok(()),
+                Err(ConsistencyError::UuidNotUnique("u1".to_string())),
+                Err(ConsistencyError::MemberOfInvalid(2)),
+            ])
+            .to_string(),
+            "the consistency check failed: the uuid u1 is held by more than one entry; entry 2 has an incorrect memberof"
+        );
+        assert_eq!(
+            OperationError::BatchItemFailed(
+                1,
+                Box::new(OperationError::DuplicateValue("name".to_string()))
+            )
+            .to_string(),
+            "batch item 1 failed: another entry already has this value of name"
+        );
+        assert_eq!(
+            OperationError::ReviveFailed(vec![("u1".to_string(), OperationError::ReviveTombstone)])
+                .to_string(),
+            "the revive failed: u1 the entry is a tombstone and can not be revived"
+        );
+        assert_eq!(
+            OperationError::PasswordQuality(vec![PasswordFeedback::TooShort(10)]).to_string(),
+            "the password was rejected: too short, use at least 10 characters"
+        );
+    }
+
+    #[test]
+    fn test_error_source() {
+        let e: OperationError = SchemaError::InvalidAttribute.into();
+        assert_eq!(
+            e,
+            OperationError::SchemaViolation(SchemaError::InvalidAttribute)
+        );
+        assert_eq!(
+            e.source().map(|s| s.to_string()),
+            Some(SchemaError::InvalidAttribute.to_string())
+        );
+
+        let e = OperationError::BatchItemFailed(0, Box::new(e));
+        let inner = e.source().expect("no source");
+        assert_eq!(
+            inner.source().map(|s| s.to_string()),
+            Some(SchemaError::InvalidAttribute.to_string())
+        );
+
+        let e: OperationError = ConsistencyError::Unknown.into();
+        assert_eq!(
+            e.source().map(|s| s.to_string()),
+            Some(ConsistencyError::Unknown.to_string())
+        );
+        assert!(OperationError::AccessDenied.source().is_none());
+    }
+}
+
+// Every type that is sent to or from the server must decode to what was
+// encoded, as either json or cbor.
+#[cfg(test)]
+mod roundtrip_tests {
+    use crate::v1::*;
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+    use std::time::Instant;
+
+    // The json value of each pass is compared, so the types needn't be
+    // PartialEq.
+    fn assert_roundtrip<T: Serialize + DeserializeOwned>(v: &T) {
+        let expect = serde_json::to_value(v).expect("json encode failed");
+
+        let json = serde_json::to_vec(v).expect("json encode failed");
+        let from_json: T = serde_json::from_slice(&json).expect("json decode failed");
+        assert_eq!(serde_json::to_value(&from_json).unwrap(), expect);
+
+        let cbor = serde_cbor::to_vec(v).expect("cbor encode failed");
+        let from_cbor: T = serde_cbor::from_slice(&cbor).expect("cbor decode failed");
+        assert_eq!(serde_json::to_value(&from_cbor).unwrap(), expect);
+    }
+
+    fn entry(name: &str) -> Entry {
+        let mut attrs = BTreeMap::new();
+        attrs.insert("class".to_string(), vec!["account".to_string()]);
+        attrs.insert("name".to_string(), vec![name.to_string()]);
+        Entry { attrs: attrs }
+    }
+
+    // Every variant that changes the encoding, including the renamed Self.
+    fn filter() -> Filter {
+        Filter::And(vec![
+            Filter::Eq("name".to_string(), "admin".to_string()),
+            Filter::Sub("name".to_string(), "adm".to_string()),
+            Filter::Pres("class".to_string()),
+            Filter::Gte("gidnumber".to_string(), "1000".to_string()),
+            Filter::Lte("gidnumber".to_string(), "2000".to_string()),
+            Filter::Inclusion("name".to_string(), vec!["a".to_string(), "b".to_string()]),
+            Filter::Or(vec![Filter::SelfUUID, Filter::True]),
+            Filter::AndNot(Box::new(Filter::False)),
+        ])
+    }
+
+    fn modlist() -> ModifyList {
+        ModifyList::new_list(vec![
+            Modify::Present("a".to_string(), "1".to_string()),
+            Modify::Removed("a".to_string(), "2".to_string()),
+            Modify::Purged("b".to_string()),
+            Modify::Assert("c".to_string(), "3".to_string()),
+            Modify::AssertMissing("d".to_string()),
+            Modify::Set("e".to_string(), vec!["4".to_string()]),
+        ])
+    }
+
+    fn uat() -> UserAuthToken {
+        UserAuthToken {
+            issued_at: 1,
+            expiry: 2,
+            auth_time: 1,
+            sessionid: Uuid::new_v4(),
+            name: "admin".to_string(),
+            spn: "admin@example.com".to_string(),
+            displayname: "Admin".to_string(),
+            uuid: "00000000-0000-0000-0000-000000000000".to_string(),
+            application: Some(Application {
+                name: "app".to_string(),
+                uuid: "00000000-0000-0000-0000-000000000001".to_string(),
+            }),
+            groups: vec![Group {
+                name: "idm_admins".to_string(),
+                uuid: "00000000-0000-0000-0000-000000000002".to_string(),
+            }],
+            claims: vec![Claim {
+                name: "claim".to_string(),
+                uuid: "00000000-0000-0000-0000-000000000003".to_string(),
+                expiry: Some(3),
+            }],
+            must_change_password: true,
+            anonymous: false,
+            api_token: Some(Uuid::new_v4()),
+            read_only: true,
+        }
+    }
+
+    fn credential_descriptor() -> WebauthnCredentialDescriptor {
+        WebauthnCredentialDescriptor {
+            type_: "public-key".to_string(),
+            id: "id".to_string(),
+        }
+    }
+
+    fn request_challenge() -> WebauthnRequestChallenge {
+        WebauthnRequestChallenge {
+            challenge: "challenge".to_string(),
+            timeout: 60000,
+            rp_id: "example.com".to_string(),
+            allow_credentials: vec![credential_descriptor()],
+            user_verification: "preferred".to_string(),
+        }
+    }
+
+    fn creation_challenge() -> WebauthnCreationChallenge {
+        WebauthnCreationChallenge {
+            challenge: "challenge".to_string(),
+            rp: WebauthnRelyingParty {
+                id: "example.com".to_string(),
+                name: "Example".to_string(),
+            },
+            user: WebauthnUser {
+                id: "user".to_string(),
+                name: "admin".to_string(),
+                display_name: "Admin".to_string(),
+            },
+            pub_key_cred_params: vec![WebauthnPubKeyCredParams {
+                type_: "public-key".to_string(),
+                alg: -7,
+            }],
+            timeout: 60000,
+            exclude_credentials: vec![credential_descriptor()],
+            attestation: "none".to_string(),
+        }
+    }
+
+    fn assertion() -> WebauthnAssertion {
+        WebauthnAssertion {
+            id: "id".to_string(),
+            raw_id: "raw".to_string(),
+            response: WebauthnAssertionResponse {
+                authenticator_data: "data".to_string(),
+                client_data_json: "{}".to_string(),
+                signature: "sig".to_string(),
+                user_handle: None,
+            },
+            type_: "public-key".to_string(),
+        }
+    }
+
+    fn search_plan() -> SearchPlan {
+        SearchPlan {
+            filter: "(name=admin)".to_string(),
+            root: PlanNode {
+                term: "and".to_string(),
+                state: PlanState::Indexed,
+                candidates: Some(1),
+                children: vec![PlanNode {
+                    term: "pres class".to_string(),
+                    state: PlanState::FullScan,
+                    candidates: None,
+                    children: Vec::new(),
+                }],
+            },
+            loaded: 1,
+            matched: 1,
+            allowed: 1,
+        }
+    }
+
+    fn error_response() -> ErrorResponse {
+        let inner = OperationError::PasswordQuality(vec![
+            PasswordFeedback::TooShort(10),
+            PasswordFeedback::BadListed,
+        ]);
+        let mut er = ErrorResponse::from(&OperationError::BatchItemFailed(3, Box::new(inner)));
+        er.eventid = Some(Uuid::new_v4().to_hyphenated_ref().to_string());
+        er
+    }
+
+    #[test]
+    fn test_proto_roundtrip_errors() {
+        assert_roundtrip(&error_response());
+        assert_roundtrip(&ErrorResponse::from(&OperationError::ReviveFailed(vec![(
+            "uuid".to_string(),
+            OperationError::ReviveTombstone,
+        )])));
+        assert_roundtrip(&ErrorResponse::from(&OperationError::RateLimited(30)));
+        assert_roundtrip(&ErrorResponse::from(&OperationError::ValueTooLong(
+            "description".to_string(),
+            65536,
+        )));
+        assert_roundtrip(&ErrorResponse::from(&OperationError::SQLiteError(
+            BackendErrorKind::Busy,
+        )));
+        assert_roundtrip(&SchemaError::MissingMustAttribute("name".to_string()));
+        assert_roundtrip(&BackendErrorKind::Corrupt);
+    }
+
+    #[test]
+    fn test_proto_roundtrip_operations() {
+        assert_roundtrip(&filter());
+        assert_roundtrip(&modlist());
+        assert_roundtrip(&CreateRequest::new(vec![entry("a"), entry("b")]));
+        assert_roundtrip(&CreateResponse::new(vec!["uuid".to_string()]));
+        assert_roundtrip(&OperationResponse::new(()));
+        assert_roundtrip(&SearchRequest::new_sorted(
+            filter(),
+            "name",
+            SortOrder::Descending,
+        ));
+        assert_roundtrip(&SearchRequest::new_paged(
+            filter(),
+            10,
+            Some("cookie".to_string()),
+        ));
+        assert_roundtrip(&SearchRequest::new_traced(filter()));
+        let mut sr = SearchResponse::new(vec![entry("a")]);
+        sr.next_cookie = Some("cookie".to_string());
+        sr.plan = Some(search_plan());
+        assert_roundtrip(&sr);
+        assert_roundtrip(&SearchStreamItem::Entry(entry("a")));
+        assert_roundtrip(&SearchStreamItem::Done);
+        assert_roundtrip(&SearchStreamItem::Error(error_response()));
+        assert_roundtrip(&SearchCountRequest::new_exists(filter()));
+        assert_roundtrip(&SearchCountResponse::new(3));
+        assert_roundtrip(&CompareRequest::new(
+            filter(),
+            "name".to_string(),
+            "admin".to_string(),
+        ));
+        assert_roundtrip(&CompareResponse::new(true));
+        assert_roundtrip(&DeleteRequest::new(filter()));
+        assert_roundtrip(&DeleteResponse::new(2));
+        assert_roundtrip(&ModifyRequest::new(filter(), modlist()));
+        assert_roundtrip(&ModifyResponse::new(2));
+        assert_roundtrip(&ModifyBatchRequest::new(vec![(filter(), modlist())]));
+        assert_roundtrip(&ModifyBatchResponse::new(vec![1, 2]));
+        assert_roundtrip(&SchemaRequest::new());
+        assert_roundtrip(&SchemaResponse::new(
+            vec![SchemaAttribute {
+                name: "name".to_string(),
+                description: "The name".to_string(),
+                multivalue: false,
+                unique: true,
+                secret: false,
+                syntax: "UTF8STRING_INSENSITIVE".to_string(),
+                index: vec!["EQUALITY".to_string()],
+            }],
+            vec![SchemaClass {
+                name: "account".to_string(),
+                must: vec!["name".to_string()],
+                may: vec!["displayname".to_string()],
+            }],
+        ));
+        assert_roundtrip(&SearchRecycledRequest::new(filter()));
+        assert_roundtrip(&SearchRecycledResponse::new(vec![entry("a")]));
+        assert_roundtrip(&ReviveRecycledRequest::new(filter()));
+        assert_roundtrip(&ReviveRecycledResponse::new(vec!["uuid".to_string()]));
+    }
+
+    #[test]
+    fn test_proto_roundtrip_auth() {
+        for step in vec![
+            AuthStep::Init("admin".to_string(), Some("app".to_string())),
+            AuthStep::Creds(vec![
+                AuthCredential::Anonymous,
+                AuthCredential::Password("password".to_string()),
+                AuthCredential::TOTP("123456".to_string()),
+                AuthCredential::Webauthn(assertion()),
+                AuthCredential::BackupCode("code".to_string()),
+                AuthCredential::ApiToken("token".to_string()),
+            ]),
+            AuthStep::RequestClaims(vec!["claim".to_string()]),
+        ] {
+            assert_roundtrip(&AuthRequest { step: step });
+        }
+        assert_roundtrip(&ReauthRequest::new());
+        for state in vec![
+            AuthState::Success(uat()),
+            AuthState::Denied(AuthDenyReason::Failed, "failed".to_string()),
+            AuthState::Denied(AuthDenyReason::NotPermitted, "denied".to_string()),
+            AuthState::Denied(AuthDenyReason::Locked(1), "locked".to_string()),
+            AuthState::Denied(AuthDenyReason::NotYetValid(1), "not yet".to_string()),
+            AuthState::Denied(AuthDenyReason::Expired(1), "expired".to_string()),
+            AuthState::Continue(vec![
+                AuthAllowed::Anonymous,
+                AuthAllowed::Password,
+                AuthAllowed::TOTP,
+                AuthAllowed::Webauthn(request_challenge()),
+                AuthAllowed::BackupCode,
+                AuthAllowed::ApiToken,
+            ]),
+        ] {
+            assert_roundtrip(&AuthResponse {
+                sessionid: Uuid::new_v4(),
+                state: state,
+                token: None,
+            });
+        }
+        assert_roundtrip(&LogoutRequest::new());
+        assert_roundtrip(&LogoutResponse::new());
+        assert_roundtrip(&WhoamiResponse::new(entry("admin"), uat()));
+        assert_roundtrip(&SessionListRequest::new("admin"));
+        assert_roundtrip(&SessionListResponse::new(vec![SessionInfo {
+            sessionid: Uuid::new_v4(),
+            account: "admin".to_string(),
+            source: Some("127.0.0.1".to_string()),
+            issued_at: 1,
+            expiry: 2,
+        }]));
+        assert_roundtrip(&SessionRevokeRequest::new(Uuid::new_v4()));
+        assert_roundtrip(&SessionRevokeResponse::new());
+        assert_roundtrip(&JwkSet {
+            keys: vec![Jwk {
+                kty: "EC".to_string(),
+                crv: "P-256".to_string(),
+                x: "x".to_string(),
+                y: "y".to_string(),
+                kid: "kid".to_string(),
+                alg: "ES256".to_string(),
+                use_: "sig".to_string(),
+            }],
+        });
+    }
+
+    // Each secret is still sent, but never shown when debug formatted.
+    #[test]
+    fn test_proto_debug_redacts_secrets() {
+        let secret = "hunter2 correct horse";
+        let shown = vec![
+            format!(
+                "{:?}",
+                AuthRequest {
+                    step: AuthStep::Creds(vec![
+                        AuthCredential::Password(secret.to_string()),
+                        AuthCredential::TOTP(secret.to_string()),
+                        AuthCredential::BackupCode(secret.to_string()),
+                        AuthCredential::ApiToken(secret.to_string()),
+                    ]),
+                }
+            ),
+            format!(
+                "{:?}",
+                AuthResponse {
+                    sessionid: Uuid::new_v4(),
+                    state: AuthState::Success(uat()),
+                    token: Some(secret.to_string()),
+                }
+            ),
+            format!(
+                "{:?}",
+                CredentialChangeRequest::new_self(Some(secret), secret)
+            ),
+            format!(
+                "{:?}",
+                CredentialChangeRequest::new_admin_reset("admin", secret, true)
+            ),
+            format!(
+                "{:?}",
+                CredentialChangeRequest::new_unix_password("admin", secret)
+            ),
+            format!("{:?}", UnixAuthRequest::new("admin", secret)),
+            format!(
+                "{:?}",
+                RadiusAuthToken {
+                    name: "admin".to_string(),
+                    displayname: "Admin".to_string(),
+                    uuid: "uuid".to_string(),
+                    secret: secret.to_string(),
+                    groups: Vec::new(),
+                }
+            ),
+        ];
+        for s in shown {
+            assert!(!s.contains(secret), "{}", s);
+            assert!(s.contains(REDACTED), "{}", s);
+        }
+        assert_roundtrip(&UnixAuthRequest::new("admin", secret));
+    }
+
+    #[test]
+    fn test_proto_roundtrip_oauth2() {
+        let mut ar = Oauth2AuthorizeRequest::new("app", "https://app/cb", "openid", "challenge");
+        ar.state = Some("state".to_string());
+        assert_roundtrip(&ar);
+        assert_roundtrip(&Oauth2TokenRequest::new(
+            "code",
+            "https://app/cb",
+            "app",
+            "verifier",
+        ));
+        assert_roundtrip(&Oauth2TokenResponse {
+            access_token: "at".to_string(),
+            token_type: "Bearer".to_string(),
+            expires_in: 3600,
+            scope: "openid".to_string(),
+            id_token: None,
+        });
+        assert_roundtrip(&Oauth2ErrorResponse {
+            error: "invalid_grant".to_string(),
+            error_description: None,
+        });
+        assert_roundtrip(&Oauth2UserInfo {
+            sub: "00000000-0000-0000-0000-000000000000".to_string(),
+            name: Some("Admin".to_string()),
+            preferred_username: None,
+            groups: Some(vec!["idm_admins".to_string()]),
+        });
+    }
+
+    #[test]
+    fn test_proto_roundtrip_credentials() {
+        assert_roundtrip(&TOTPGenerateRequest::new());
+        assert_roundtrip(&TOTPGenerateResponse::new(TOTPSecret {
+            accountname: "admin".to_string(),
+            issuer: "Kani IDM".to_string(),
+            secret: vec![0, 1, 2, 255],
+            algo: TOTPAlgo::Sha256,
+            step: 30,
+        }));
+        assert_roundtrip(&TOTPVerifyRequest::new("123456"));
+        assert_roundtrip(&TOTPVerifyResponse::new());
+        assert_roundtrip(&WebauthnGenerateRequest::new());
+        assert_roundtrip(&WebauthnGenerateResponse::new(creation_challenge()));
+        assert_roundtrip(&WebauthnRegisterRequest::new(
+            "key",
+            WebauthnRegisterCredential {
+                id: "id".to_string(),
+                raw_id: "raw".to_string(),
+                response: WebauthnAttestationResponse {
+                    attestation_object: "object".to_string(),
+                    client_data_json: "{}".to_string(),
+                },
+                type_: "public-key".to_string(),
+            },
+        ));
+        assert_roundtrip(&WebauthnRegisterResponse::new());
+        assert_roundtrip(&WebauthnListResponse::new(vec![WebauthnTokenInfo {
+            name: "key".to_string(),
+            id: "id".to_string(),
+            counter: 4,
+        }]));
+        assert_roundtrip(&WebauthnRemoveRequest::new("key"));
+        assert_roundtrip(&WebauthnRemoveResponse::new());
+        assert_roundtrip(&BackupCodesGenerateRequest::new());
+        assert_roundtrip(&BackupCodesGenerateResponse::new(vec!["code".to_string()]));
+        assert_roundtrip(&CredentialPolicyRequest::new(Some(
+            CredentialPolicy::PasswordMFA,
+        )));
+        assert_roundtrip(&CredentialPolicyResponse::new());
+        assert_roundtrip(&CredentialStatusResponse {
+            policy: Some(CredentialPolicy::WebauthnOnly),
+            password: true,
+            totp: false,
+            webauthn: vec!["key".to_string()],
+            backup_codes_remaining: 8,
+        });
+        assert_roundtrip(&CredentialChangeRequest::new_self(Some("old"), "new"));
+        assert_roundtrip(&CredentialChangeRequest::new_admin_reset(
+            "admin", "new", true,
+        ));
+        assert_roundtrip(&CredentialChangeRequest::new_unix_password("admin", "new"));
+        assert_roundtrip(&CredentialChangeResponse::new());
+        assert_roundtrip(&ApiTokenGenerateRequest::new("svc", "label", Some(1), true));
+        assert_roundtrip(&ApiTokenGenerateResponse::new(
+            Uuid::new_v4(),
+            "token".to_string(),
+        ));
+        assert_roundtrip(&ApiTokenListRequest::new("svc"));
+        assert_roundtrip(&ApiTokenListResponse::new(vec![ApiTokenInfo {
+            id: Uuid::new_v4(),
+            label: "label".to_string(),
+            created: 1,
+            expiry: None,
+            last_used: Some(2),
+            read_write: false,
+        }]));
+        assert_roundtrip(&ApiTokenDestroyRequest::new("svc", Uuid::new_v4()));
+        assert_roundtrip(&ApiTokenDestroyResponse::new());
+        assert_roundtrip(&RadiusSecretGenerateRequest::new());
+        assert_roundtrip(&RadiusSecretGenerateResponse::new("secret".to_string()));
+        assert_roundtrip(&RadiusAuthToken {
+            name: "admin".to_string(),
+            displayname: "Admin".to_string(),
+            uuid: "uuid".to_string(),
+            secret: "secret".to_string(),
+            groups: uat().groups,
+        });
+        let group = UnixGroupToken {
+            name: "group".to_string(),
+            uuid: "uuid".to_string(),
+            gidnumber: 1000,
+        };
+        assert_roundtrip(&group);
+        assert_roundtrip(&UnixUserToken {
+            name: "admin".to_string(),
+            displayname: "Admin".to_string(),
+            uuid: "uuid".to_string(),
+            gidnumber: 1000,
+            shell: Some("/bin/sh".to_string()),
+            groups: vec![group],
+        });
+        assert_roundtrip(&UnixAuthRequest::new("admin", "password"));
+    }
+
+    #[test]
+    fn test_proto_roundtrip_admin() {
+        let mut acp = AccessControlProfile::new("acp", filter(), Filter::SelfUUID);
+        acp.search = Some(vec!["name".to_string()]);
+        acp.modify = Some(AccessControlModifyRights {
+            present_attrs: vec!["name".to_string()],
+            removed_attrs: vec!["name".to_string()],
+            classes: vec!["account".to_string()],
+        });
+        acp.create = Some(AccessControlCreateRights {
+            attrs: vec!["name".to_string()],
+            classes: vec!["account".to_string()],
+        });
+        assert_roundtrip(&acp);
+        assert_roundtrip(&DomainInfo {
+            uuid: "uuid".to_string(),
+            domain_name: "example.com".to_string(),
+            domain_display_name: None,
+        });
+        for op in vec![
+            AccessCheckOperation::Search,
+            AccessCheckOperation::Modify(modlist()),
+            AccessCheckOperation::Delete,
+        ] {
+            assert_roundtrip(&AccessCheckRequest::new("admin", filter(), op));
+        }
+        assert_roundtrip(&AccessCheckResponse::new(vec![AccessCheckEntry {
+            uuid: "uuid".to_string(),
+            allowed: true,
+            profiles: vec!["acp".to_string()],
+        }]));
+        assert_roundtrip(&EffectiveAccessRequest::new(filter()));
+        assert_roundtrip(&EffectiveAccessResponse::new(vec![EffectiveAccess {
+            uuid: "uuid".to_string(),
+            name: Some("admin".to_string()),
+            search: vec!["name".to_string()],
+            modify_present: Vec::new(),
+            modify_removed: Vec::new(),
+            modify_classes: Vec::new(),
+            delete: false,
+        }]));
+        assert_roundtrip(&AuditListRequest::default());
+        assert_roundtrip(&AuditListResponse::new(vec![AuditRecord {
+            time: "2019-01-01T00:00:00+00:00".to_string(),
+            eventid: "eventid".to_string(),
+            identity: "identity".to_string(),
+            operation: AuditOperation::Revive,
+            targets: vec!["uuid".to_string()],
+            changes: modlist().mods,
+        }]));
+        assert_roundtrip(&ChangesRequest::default());
+        assert_roundtrip(&ChangesResponse::new(
+            vec![ChangedEntry {
+                uuid: "uuid".to_string(),
+                attrs: vec!["name".to_string()],
+                cid: "1-0-uuid".to_string(),
+            }],
+            Some("1-0-uuid".to_string()),
+        ));
+        assert_roundtrip(&ExportRequest::default());
+        assert_roundtrip(&ExportStreamItem::Entry(entry("a")));
+        assert_roundtrip(&ExportStreamItem::Deleted("uuid".to_string()));
+        assert_roundtrip(&ExportStreamItem::Done(Some("1-0-uuid".to_string())));
+        assert_roundtrip(&ExportStreamItem::Error(error_response()));
+        assert_roundtrip(&BackupRequest::new());
+        assert_roundtrip(&BackupResponse::new("/tmp/backup".to_string()));
+        let mut indexes = BTreeMap::new();
+        indexes.insert("name.eq".to_string(), 4);
+        assert_roundtrip(&ReindexRequest::new());
+        assert_roundtrip(&ReindexResponse::new(indexes));
+        assert_roundtrip(&VacuumRequest::new());
+        assert_roundtrip(&VacuumResponse::new());
+        assert_roundtrip(&IndexStatusRequest::new());
+        assert_roundtrip(&IndexStatusResponse::new(vec![IndexStatus {
+            name: "name.eq".to_string(),
+            attr: "name".to_string(),
+            index: "eq".to_string(),
+            declared: true,
+            exists: true,
+            keys: 4,
+            distinct: 4,
+        }]));
+        let mut checks = BTreeMap::new();
+        checks.insert("runtime".to_string(), HealthCheck::new_healthy());
+        assert_roundtrip(&HealthResponse::new(checks));
+    }
+
+    // Not a test as such, but a comparison of the formats for a large
+    // search. Run it with cargo test -- --ignored --nocapture.
+    #[test]
+    #[ignore]
+    fn bench_search_response_formats() {
+        let entries: Vec<Entry> = (0..10_000)
+            .map(|i| {
+                let mut e = entry(format!("user{}", i).as_str());
+                e.attrs.insert(
+                    "uuid".to_string(),
+                    vec![Uuid::new_v4().to_hyphenated_ref().to_string()],
+                );
+                e.attrs.insert(
+                    "memberof".to_string(),
+                    vec!["idm_people".to_string(), "idm_all_accounts".to_string()],
+                );
+                e
+            })
+            .collect();
+        let sr = SearchResponse::new(entries);
+
+        let start = Instant::now();
+        let json = serde_json::to_vec(&sr).unwrap();
+        let json_enc = start.elapsed();
+        let start = Instant::now();
+        let from_json: SearchResponse = serde_json::from_slice(&json).unwrap();
+        let json_dec = start.elapsed();
+
+        let start = Instant::now();
+        let cbor = serde_cbor::to_vec(&sr).unwrap();
+        let cbor_enc = start.elapsed();
+        let start = Instant::now();
+        let from_cbor: SearchResponse = serde_cbor::from_slice(&cbor).unwrap();
+        let cbor_dec = start.elapsed();
+
+        assert_eq!(from_json.entries, sr.entries);
+        assert_eq!(from_cbor.entries, sr.entries);
+        println!(
+            "json: {} bytes, encode {:?}, decode {:?}",
+            json.len(),
+            json_enc,
+            json_dec
+        );
+        println!(
+            "cbor: {} bytes, encode {:?}, decode {:?}",
+            cbor.len(),
+            cbor_enc,
+            cbor_dec
+        );
     }
 }
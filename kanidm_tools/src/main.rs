@@ -1,65 +1,1188 @@
 extern crate structopt;
-use kanidm_client::KanidmClient;
+use kanidm_client::{ClientError, ExportRecord, KanidmClient, KanidmClientBuilder};
+use kanidm_proto::v1::{
+    AccessCheckOperation, AuthAllowed, CredentialPolicy, EffectiveAccess, ExportStreamItem, Filter,
+    Modify, ModifyList, PasswordFeedback, UserAuthToken,
+};
+use serde::Serialize;
+use std::fmt;
+use std::io::{self, Write};
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
 use structopt::StructOpt;
 extern crate env_logger;
 #[macro_use]
 extern crate log;
+#[macro_use]
+extern crate serde_derive;
+
+mod raw;
+mod token_cache;
+use token_cache::{CachedToken, TokenCache};
+
+// How a failed command exits, so that a script can tell an authentication
+// that was refused from a server that couldn't be reached.
+const EXIT_FAILED: i32 = 1;
+const EXIT_AUTH_DENIED: i32 = 2;
+const EXIT_CONNECTION: i32 = 3;
+// An export since a cursor whose changes are no longer kept.
+const EXIT_CHANGELOG_TRIMMED: i32 = 4;
+
+// Set by --json, after which results and errors are printed as json.
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, StructOpt)]
+struct OutputOpt {
+    #[structopt(short = "d", long = "debug")]
+    debug: bool,
+    #[structopt(long = "json")]
+    json: bool,
+}
+
+#[derive(Debug, StructOpt)]
+struct CommonOpt {
+    #[structopt(flatten)]
+    output: OutputOpt,
+    // Without these, the uri and ca of the config files are used.
+    #[structopt(short = "H", long = "url")]
+    addr: Option<String>,
+    #[structopt(short = "D", long = "name")]
+    username: String,
+    #[structopt(parse(from_os_str), short = "C", long = "ca")]
+    ca_path: Option<PathBuf>,
+    // Read the password from the first line of this file rather than
+    // prompting for it.
+    #[structopt(parse(from_os_str), long = "password-file")]
+    password_file: Option<PathBuf>,
+    // Read the password from the first line of stdin. A totp or backup code
+    // that is needed is read from the line after.
+    #[structopt(long = "password-stdin")]
+    password_stdin: bool,
+}
+
+impl CommonOpt {
+    fn build_client(&self) -> KanidmClient {
+        build_client(self.addr.as_ref(), self.ca_path.as_ref()).unwrap_or_else(|e| fail(&e))
+    }
+
+    fn to_client(&self) -> KanidmClient {
+        let client = self.build_client();
+
+        // A session begun by an earlier command is carried on, as long as the
+        // server still accepts it.
+        if let Some(path) = TokenCache::default_path() {
+            let cached = TokenCache::load(&path)
+                .get(client.get_url(), self.username.as_str(), now_secs())
+                .cloned();
+            if let Some(cached) = cached {
+                client.set_token(cached.token.as_str());
+                match client.whoami() {
+                    Ok(Some(_)) => return client,
+                    _ => {
+                        eprintln!("Your session has ended, please authenticate again");
+                        client.clear_token();
+                        forget_token(&path, client.get_url(), self.username.as_str());
+                    }
+                }
+            }
+        }
+
+        self.authenticate(&client);
+        client
+    }
+
+    // Begin a new session, and cache it for the commands that follow.
+    fn authenticate(&self, client: &KanidmClient) -> UserAuthToken {
+        let r = if self.username == "anonymous" {
+            client.auth_anonymous()
+        } else {
+            let password = self.read_password();
+            match client.auth_simple_password(self.username.as_str(), password.as_str()) {
+                Err(ClientError::MFARequired(allowed)) => {
+                    let backup = allowed.contains(&AuthAllowed::BackupCode);
+                    if allowed.contains(&AuthAllowed::TOTP) || backup {
+                        let code = if backup {
+                            prompt("Enter TOTP or backup code: ")
+                        } else {
+                            prompt("Enter TOTP: ")
+                        };
+                        // A totp is always six digits, which a backup code
+                        // never is.
+                        if code.len() == 6 && code.chars().all(|c| c.is_ascii_digit()) {
+                            client.auth_step_totp(code.as_str())
+                        } else {
+                            client.auth_step_backup_code(code.as_str())
+                        }
+                    } else {
+                        // Webauthn needs a browser to talk to the token.
+                        exit_with(
+                            EXIT_AUTH_DENIED,
+                            "This account requires a webauthn token, which can't be used here",
+                        )
+                    }
+                }
+                r => r,
+            }
+        };
+
+        let uat = match r {
+            Ok(uat) => uat,
+            Err(ClientError::AccountLocked(until)) => exit_with(
+                EXIT_AUTH_DENIED,
+                format!(
+                    "Too many failed authentications, try again in {}s",
+                    until.saturating_sub(now_secs())
+                )
+                .as_str(),
+            ),
+            Err(ClientError::AccountNotYetValid(_)) => {
+                exit_with(EXIT_AUTH_DENIED, "This account is not yet valid")
+            }
+            Err(ClientError::AccountExpired(_)) => {
+                exit_with(EXIT_AUTH_DENIED, "This account has expired")
+            }
+            Err(e) => fail(&e),
+        };
+
+        if uat.must_change_password {
+            eprintln!("Your password was reset, and must be changed before anything else.");
+            eprintln!("Use \"account credential set-password\" to change it.");
+        }
+        if let (Some(path), Some(token)) = (TokenCache::default_path(), client.get_token()) {
+            let cached = CachedToken {
+                token: token,
+                expiry: uat.expiry,
+            };
+            let url = client.get_url();
+            let name = self.username.as_str();
+            if let Err(e) = TokenCache::update(&path, |c| c.insert(url, name, cached)) {
+                warn!("Failed to save session to {}: {}", path.display(), e);
+            }
+        }
+        uat
+    }
+
+    fn read_password(&self) -> String {
+        if let Some(p) = &self.password_file {
+            match std::fs::read_to_string(p) {
+                Ok(contents) => contents.lines().next().unwrap_or("").to_string(),
+                Err(e) => exit_with(
+                    EXIT_FAILED,
+                    format!("Error reading {}: {}", p.display(), e).as_str(),
+                ),
+            }
+        } else if self.password_stdin {
+            let mut line = String::new();
+            if let Err(e) = io::stdin().read_line(&mut line) {
+                exit_with(EXIT_FAILED, format!("Error reading stdin: {}", e).as_str());
+            }
+            line.trim_end_matches(|c| c == '\n' || c == '\r')
+                .to_string()
+        } else {
+            rpassword::prompt_password_stderr("Enter password: ").unwrap()
+        }
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn forget_token(path: &PathBuf, url: &str, name: &str) {
+    if let Err(e) = TokenCache::update(path, |c| {
+        c.remove(url, name);
+    }) {
+        warn!("Failed to remove session from {}: {}", path.display(), e);
+    }
+}
+
+// What is given on the command line is used over the config files.
+fn build_client(
+    addr: Option<&String>,
+    ca_path: Option<&PathBuf>,
+) -> Result<KanidmClient, ClientError> {
+    let mut builder = KanidmClientBuilder::new();
+    if let Some(addr) = addr {
+        builder = builder.address(addr.as_str());
+    }
+    if let Some(ca_path) = ca_path {
+        builder = builder.add_root_certificate(ca_path.to_str().unwrap());
+    }
+    builder.read_default_config()?.build()
+}
+
+// Ask for a new password twice, so a typo doesn't lock anyone out.
+fn prompt_new_password() -> String {
+    let password = rpassword::prompt_password_stderr("Enter new password: ").unwrap();
+    let confirm = rpassword::prompt_password_stderr("Confirm new password: ").unwrap();
+    if password != confirm {
+        exit_with(EXIT_FAILED, "Passwords do not match");
+    }
+    password
+}
+
+fn json_output() -> bool {
+    JSON_OUTPUT.load(Ordering::Relaxed)
+}
+
+fn print_json<T: Serialize + ?Sized>(v: &T) {
+    println!("{}", serde_json::to_string(v).unwrap());
+}
+
+// A result, as it displays or as json.
+fn output<T: fmt::Display + Serialize>(v: &T) {
+    if json_output() {
+        print_json(v);
+    } else {
+        let s = v.to_string();
+        if s.ends_with('\n') {
+            print!("{}", s);
+        } else {
+            println!("{}", s);
+        }
+    }
+}
+
+// Each result on a line of its own, or as one json list.
+fn output_list<T: fmt::Display + Serialize>(vs: &[T]) {
+    if json_output() {
+        print_json(vs);
+    } else {
+        output_list(&vs);
+    }
+}
+
+#[derive(Serialize)]
+struct JsonDone<'a> {
+    status: &'static str,
+    message: &'a str,
+}
+
+#[derive(Serialize)]
+struct JsonError<'a> {
+    status: &'static str,
+    message: &'a str,
+    // The client error as debug formatted, if it was one.
+    error: Option<String>,
+    operation_id: Option<&'a str>,
+    exit_code: i32,
+}
+
+#[derive(Serialize)]
+struct CachedSession<'a> {
+    url: &'a str,
+    name: &'a str,
+    expiry: u64,
+}
+
+#[derive(Serialize)]
+struct TotpEnrollment {
+    secret: String,
+    uri: String,
+}
+
+#[derive(Serialize)]
+struct AccountValidity {
+    valid_from: Option<String>,
+    expire: Option<String>,
+}
+
+// What a command that has no result did.
+fn done(message: String) {
+    if json_output() {
+        print_json(&JsonDone {
+            status: "ok",
+            message: message.as_str(),
+        });
+    } else {
+        println!("{}", message);
+    }
+}
+
+fn exit_code(e: &ClientError) -> i32 {
+    match e {
+        ClientError::AuthenticationFailed
+        | ClientError::Unauthorized
+        | ClientError::MFARequired(_)
+        | ClientError::AccountLocked(_)
+        | ClientError::AccountNotYetValid(_)
+        | ClientError::AccountExpired(_) => EXIT_AUTH_DENIED,
+        ClientError::Transport(_) => EXIT_CONNECTION,
+        _ => EXIT_FAILED,
+    }
+}
+
+fn print_failure(message: &str, e: Option<&ClientError>, code: i32) {
+    // The operation id is what the server logged the failure under, so
+    // it's given when there is one, to be quoted when asking about it.
+    let eventid = e.and_then(|e| e.eventid());
+    if json_output() {
+        print_json(&JsonError {
+            status: "error",
+            message: message,
+            error: e.map(|e| format!("{:?}", e)),
+            operation_id: eventid,
+            exit_code: code,
+        });
+    } else {
+        println!("Error: {}", message);
+        if let Some(eventid) = eventid {
+            println!("operation id: {}", eventid);
+        }
+    }
+}
+
+fn print_error(e: &ClientError) {
+    let message = match e {
+        ClientError::NoSuchAccount(name) => format!("account {} does not exist", name),
+        ClientError::NoSuchGroup(name) => format!("group {} does not exist", name),
+        e => format!("{:?}", e),
+    };
+    print_failure(message.as_str(), Some(e), exit_code(e));
+}
+
+fn fail(e: &ClientError) -> ! {
+    print_error(e);
+    std::process::exit(exit_code(e))
+}
+
+fn exit_with(code: i32, message: &str) -> ! {
+    print_failure(message, None, code);
+    std::process::exit(code)
+}
+
+fn print_password_feedback(feedback: &[PasswordFeedback]) {
+    if json_output() {
+        let reasons: Vec<String> = feedback.iter().map(|f| f.to_string()).collect();
+        let message = format!("Password rejected: {}", reasons.join(", "));
+        print_failure(message.as_str(), None, EXIT_FAILED);
+        return;
+    }
+    println!("Password rejected:");
+    for f in feedback {
+        println!("  - {}", f);
+    }
+}
+
+// One row per entry, named where the name can be read, with each column
+// padded to its widest value.
+fn print_effective_access(entries: &[EffectiveAccess]) {
+    let header = vec![
+        "entry".to_string(),
+        "search".to_string(),
+        "modify present".to_string(),
+        "modify removed".to_string(),
+        "modify classes".to_string(),
+        "delete".to_string(),
+    ];
+    let rows: Vec<Vec<String>> = entries
+        .iter()
+        .map(|e| {
+            vec![
+                e.name.clone().unwrap_or_else(|| e.uuid.clone()),
+                e.search.join(","),
+                e.modify_present.join(","),
+                e.modify_removed.join(","),
+                e.modify_classes.join(","),
+                if e.delete { "yes" } else { "no" }.to_string(),
+            ]
+        })
+        .collect();
+
+    let widths: Vec<usize> = (0..header.len())
+        .map(|i| {
+            rows.iter()
+                .map(|r| r[i].len())
+                .chain(std::iter::once(header[i].len()))
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    for row in std::iter::once(&header).chain(rows.iter()) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths.iter())
+            .map(|(v, w)| format!("{:width$}", v, width = *w))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
+
+// Entries are named by their uuid, which never changes, and which the server
+// only exports entries with. A deleted entry is given as an ldif change
+// record.
+fn print_export_ldif(r: &ExportRecord) {
+    match r {
+        ExportRecord::Entry(e) => {
+            let uuid = e.get_ava_single("uuid").unwrap_or("");
+            println!("dn: uuid={}", uuid);
+            print!("{}", e.to_ldif());
+        }
+        ExportRecord::Deleted(u) => {
+            println!("dn: uuid={}", u);
+            println!("changetype: delete");
+        }
+    }
+    println!();
+}
+
+fn prompt(msg: &str) -> String {
+    eprint!("{}", msg);
+    io::stderr().flush().unwrap();
+    let mut input = String::new();
+    io::stdin().read_line(&mut input).unwrap();
+    input.trim().to_string()
+}
+
+#[derive(Debug, StructOpt)]
+struct SearchOpt {
+    #[structopt()]
+    filter: String,
+    // Treat the filter as an ldap filter string, rather than json.
+    #[structopt(short = "l", long = "ldap")]
+    ldap: bool,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+enum SchemaOpt {
+    #[structopt(name = "list")]
+    List(CommonOpt),
+}
+
+#[derive(Debug, StructOpt)]
+struct ReviveOpt {
+    #[structopt()]
+    uuid: String,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+enum RecycleOpt {
+    #[structopt(name = "list")]
+    List(CommonOpt),
+    #[structopt(name = "revive")]
+    Revive(ReviveOpt),
+}
+
+#[derive(Debug, StructOpt)]
+enum TOTPOpt {
+    #[structopt(name = "enroll")]
+    Enroll(CommonOpt),
+}
+
+#[derive(Debug, StructOpt)]
+struct WebauthnRemoveOpt {
+    #[structopt()]
+    name: String,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+enum WebauthnOpt {
+    #[structopt(name = "list")]
+    List(CommonOpt),
+    #[structopt(name = "remove")]
+    Remove(WebauthnRemoveOpt),
+}
+
+#[derive(Debug, StructOpt)]
+struct PolicyOpt {
+    // One of password, password-mfa or webauthn, or auto to follow the
+    // factors the credential has.
+    #[structopt()]
+    policy: String,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct ResetPasswordOpt {
+    // The account to reset, by name or uuid.
+    #[structopt()]
+    account: String,
+    // Require the account to change the password before anything else.
+    #[structopt(long = "must-change")]
+    must_change: bool,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct UnlockOpt {
+    // The account to unlock, by name.
+    #[structopt()]
+    account: String,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct ValidityShowOpt {
+    // The account to show, by name.
+    #[structopt()]
+    account: String,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct ValiditySetOpt {
+    #[structopt()]
+    account: String,
+    // An rfc3339 datetime, such as 2020-01-01T00:00:00Z. "any" or "clear"
+    // remove the start of the window, and "never" or "clear" its end.
+    #[structopt()]
+    datetime: String,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+enum ValidityOpt {
+    #[structopt(name = "show")]
+    Show(ValidityShowOpt),
+    #[structopt(name = "begin-from")]
+    BeginFrom(ValiditySetOpt),
+    #[structopt(name = "expire-at")]
+    ExpireAt(ValiditySetOpt),
+}
+
+#[derive(Debug, StructOpt)]
+enum CredentialOpt {
+    #[structopt(name = "status")]
+    Status(CommonOpt),
+    #[structopt(name = "generate-backup-codes")]
+    GenerateBackupCodes(CommonOpt),
+    #[structopt(name = "set-policy")]
+    SetPolicy(PolicyOpt),
+    #[structopt(name = "set-password")]
+    SetPassword(CommonOpt),
+    #[structopt(name = "reset-password")]
+    ResetPassword(ResetPasswordOpt),
+}
+
+#[derive(Debug, StructOpt)]
+struct BadlistWordsOpt {
+    #[structopt()]
+    words: Vec<String>,
+    // A file of further words, one per line.
+    #[structopt(parse(from_os_str), short = "f", long = "file")]
+    file: Option<PathBuf>,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+impl BadlistWordsOpt {
+    fn read_words(&self) -> Vec<String> {
+        let mut words = self.words.clone();
+        if let Some(p) = &self.file {
+            let contents = std::fs::read_to_string(p).unwrap_or_else(|e| {
+                exit_with(
+                    EXIT_FAILED,
+                    format!("Error reading {}: {}", p.display(), e).as_str(),
+                )
+            });
+            words.extend(contents.lines().map(|l| l.to_string()));
+        }
+        words
+    }
+}
+
+#[derive(Debug, StructOpt)]
+enum BadlistOpt {
+    #[structopt(name = "list")]
+    List(CommonOpt),
+    #[structopt(name = "add")]
+    Add(BadlistWordsOpt),
+    #[structopt(name = "remove")]
+    Remove(BadlistWordsOpt),
+}
+
+#[derive(Debug, StructOpt)]
+struct ApiTokenGenerateOpt {
+    // The service account, by name or uuid.
+    #[structopt()]
+    account: String,
+    // What the token is for, to tell it apart when listed.
+    #[structopt()]
+    label: String,
+    // How many seconds the token is valid for. Without this, it never
+    // expires.
+    #[structopt(long = "valid-for")]
+    valid_for: Option<u64>,
+    // Allow sessions of the token to make changes, not only read.
+    #[structopt(long = "read-write")]
+    read_write: bool,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct ApiTokenListOpt {
+    #[structopt()]
+    account: String,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct ApiTokenDestroyOpt {
+    #[structopt()]
+    account: String,
+    // The id of the token, as listed.
+    #[structopt()]
+    id: String,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+enum ApiTokenOpt {
+    #[structopt(name = "generate")]
+    Generate(ApiTokenGenerateOpt),
+    #[structopt(name = "list")]
+    List(ApiTokenListOpt),
+    #[structopt(name = "destroy")]
+    Destroy(ApiTokenDestroyOpt),
+}
+
+#[derive(Debug, StructOpt)]
+enum ServiceAccountOpt {
+    #[structopt(name = "api-token")]
+    ApiToken(ApiTokenOpt),
+}
+
+#[derive(Debug, StructOpt)]
+struct RadiusShowOpt {
+    // The account to show, by name or uuid.
+    #[structopt()]
+    account: String,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+enum RadiusOpt {
+    #[structopt(name = "generate")]
+    Generate(CommonOpt),
+    #[structopt(name = "show")]
+    Show(RadiusShowOpt),
+}
+
+#[derive(Debug, StructOpt)]
+struct SshAddOpt {
+    // The account to add the key to, by name.
+    #[structopt()]
+    account: String,
+    // What the key is for, to remove it by later.
+    #[structopt()]
+    tag: String,
+    // The public key, as it is in the .pub file.
+    #[structopt()]
+    pubkey: String,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct SshDeleteOpt {
+    #[structopt()]
+    account: String,
+    #[structopt()]
+    tag: String,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct SshListOpt {
+    #[structopt()]
+    account: String,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+enum SshOpt {
+    #[structopt(name = "list-publickeys")]
+    List(SshListOpt),
+    #[structopt(name = "add-publickey")]
+    Add(SshAddOpt),
+    #[structopt(name = "delete-publickey")]
+    Delete(SshDeleteOpt),
+}
+
+#[derive(Debug, StructOpt)]
+struct PosixShowOpt {
+    // The account or group to show, by name or uuid.
+    #[structopt()]
+    id: String,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct AccountPosixSetOpt {
+    #[structopt()]
+    account: String,
+    // Generated from the uuid when not given.
+    #[structopt(long = "gidnumber")]
+    gidnumber: Option<u32>,
+    #[structopt(long = "shell")]
+    shell: Option<String>,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct AccountPosixPasswordOpt {
+    #[structopt()]
+    account: String,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+enum AccountPosixOpt {
+    #[structopt(name = "show")]
+    Show(PosixShowOpt),
+    #[structopt(name = "set")]
+    Set(AccountPosixSetOpt),
+    #[structopt(name = "set-password")]
+    SetPassword(AccountPosixPasswordOpt),
+}
+
+#[derive(Debug, StructOpt)]
+struct AccountCreateOpt {
+    #[structopt()]
+    account: String,
+    // The name of the person, as it's shown.
+    #[structopt()]
+    displayname: String,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct AccountNamedOpt {
+    #[structopt()]
+    account: String,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+enum AccountOpt {
+    #[structopt(name = "create")]
+    Create(AccountCreateOpt),
+    #[structopt(name = "get")]
+    Get(AccountNamedOpt),
+    #[structopt(name = "delete")]
+    Delete(AccountNamedOpt),
+    #[structopt(name = "set-displayname")]
+    SetDisplayname(AccountCreateOpt),
+    #[structopt(name = "credential")]
+    Credential(CredentialOpt),
+    #[structopt(name = "unlock")]
+    Unlock(UnlockOpt),
+    #[structopt(name = "validity")]
+    Validity(ValidityOpt),
+    #[structopt(name = "radius")]
+    Radius(RadiusOpt),
+    #[structopt(name = "ssh")]
+    Ssh(SshOpt),
+    #[structopt(name = "posix")]
+    Posix(AccountPosixOpt),
+}
+
+#[derive(Debug, StructOpt)]
+struct GroupPosixSetOpt {
+    #[structopt()]
+    group: String,
+    // Generated from the uuid when not given.
+    #[structopt(long = "gidnumber")]
+    gidnumber: Option<u32>,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+enum GroupPosixOpt {
+    #[structopt(name = "show")]
+    Show(PosixShowOpt),
+    #[structopt(name = "set")]
+    Set(GroupPosixSetOpt),
+}
+
+#[derive(Debug, StructOpt)]
+struct GroupNamedOpt {
+    #[structopt()]
+    group: String,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct GroupMembersOpt {
+    #[structopt()]
+    group: String,
+    // The accounts or groups, by name or uuid.
+    #[structopt()]
+    members: Vec<String>,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+enum GroupOpt {
+    #[structopt(name = "create")]
+    Create(GroupNamedOpt),
+    #[structopt(name = "delete")]
+    Delete(GroupNamedOpt),
+    #[structopt(name = "list-members")]
+    ListMembers(GroupNamedOpt),
+    #[structopt(name = "add-members")]
+    AddMembers(GroupMembersOpt),
+    #[structopt(name = "remove-members")]
+    RemoveMembers(GroupMembersOpt),
+    #[structopt(name = "posix")]
+    Posix(GroupPosixOpt),
+}
+
+#[derive(Debug, StructOpt)]
+struct AcpGetOpt {
+    #[structopt()]
+    name: String,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct AcpCheckOpt {
+    // The account to check the access of, by name or uuid.
+    #[structopt()]
+    receiver: String,
+    // An ldap filter of the entries to check.
+    #[structopt()]
+    filter: String,
+    // Check a modify of these changes, given as attr=value to add a value or
+    // attr to remove all values. Without these or --delete, check a search.
+    #[structopt(short = "m", long = "modify")]
+    modify: Vec<String>,
+    #[structopt(long = "delete")]
+    delete: bool,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+impl AcpCheckOpt {
+    fn operation(&self) -> AccessCheckOperation {
+        if self.delete {
+            AccessCheckOperation::Delete
+        } else if self.modify.is_empty() {
+            AccessCheckOperation::Search
+        } else {
+            let mods = self
+                .modify
+                .iter()
+                .map(|m| {
+                    let mut parts = m.splitn(2, '=');
+                    let attr = parts.next().unwrap_or("").to_string();
+                    match parts.next() {
+                        Some(v) => Modify::Present(attr, v.to_string()),
+                        None => Modify::Purged(attr),
+                    }
+                })
+                .collect();
+            AccessCheckOperation::Modify(ModifyList::new_list(mods))
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+enum AcpOpt {
+    #[structopt(name = "list")]
+    List(CommonOpt),
+    #[structopt(name = "get")]
+    Get(AcpGetOpt),
+    #[structopt(name = "check")]
+    Check(AcpCheckOpt),
+}
+
+#[derive(Debug, StructOpt)]
+struct DomainSetDisplayNameOpt {
+    #[structopt()]
+    name: String,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct DomainSetNameOpt {
+    #[structopt()]
+    name: String,
+    // Renaming the domain changes every spn, so it must be confirmed.
+    #[structopt(long = "yes")]
+    yes: bool,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+enum DomainOpt {
+    #[structopt(name = "show")]
+    Show(CommonOpt),
+    #[structopt(name = "set-display-name")]
+    SetDisplayName(DomainSetDisplayNameOpt),
+    #[structopt(name = "set-domain-name")]
+    SetDomainName(DomainSetNameOpt),
+}
+
+#[derive(Debug, StructOpt)]
+struct AuditListOpt {
+    // List the changes made from this time, given as rfc3339.
+    #[structopt(long = "since")]
+    since: Option<String>,
+    #[structopt(long = "until")]
+    until: Option<String>,
+    // Only list the changes to this entry, by uuid.
+    #[structopt(long = "target")]
+    target: Option<String>,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
 
 #[derive(Debug, StructOpt)]
-struct CommonOpt {
-    #[structopt(short = "d", long = "debug")]
-    debug: bool,
-    #[structopt(short = "H", long = "url")]
-    addr: String,
-    #[structopt(short = "D", long = "name")]
-    username: String,
-    #[structopt(parse(from_os_str), short = "C", long = "ca")]
-    ca_path: Option<PathBuf>,
+enum AuditOpt {
+    #[structopt(name = "list")]
+    List(AuditListOpt),
 }
 
-impl CommonOpt {
-    fn to_client(&self) -> KanidmClient {
-        let ca_path: Option<&str> = self.ca_path.as_ref().map(|p| p.to_str().unwrap());
-        let client = KanidmClient::new(self.addr.as_str(), ca_path);
+#[derive(Debug, StructOpt)]
+struct ExportOpt {
+    // The cursor printed at the end of the last export. Without this, every
+    // entry is exported.
+    #[structopt(long = "since")]
+    since: Option<String>,
+    // Write ldif rather than a line of json for each record.
+    #[structopt(long = "ldif")]
+    ldif: bool,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
 
-        let r = if self.username == "anonymous" {
-            client.auth_anonymous()
-        } else {
-            let password = rpassword::prompt_password_stderr("Enter password: ").unwrap();
-            client.auth_simple_password(self.username.as_str(), password.as_str())
-        };
+#[derive(Debug, StructOpt)]
+struct EffectiveAccessOpt {
+    // An ldap filter of the entries to report on.
+    #[structopt()]
+    filter: String,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
 
-        if r.is_err() {
-            println!("Error during authentication phase: {:?}", r);
-            std::process::exit(1);
-        }
+// A filter may be json, or an ldap filter.
+#[derive(Debug, StructOpt)]
+struct RawSearchOpt {
+    #[structopt(long = "filter")]
+    filter: String,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
 
-        client
-    }
+#[derive(Debug, StructOpt)]
+struct RawCreateOpt {
+    // A json list of entries, or a single entry.
+    #[structopt(parse(from_os_str), long = "file")]
+    file: PathBuf,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
 }
 
 #[derive(Debug, StructOpt)]
-struct SearchOpt {
-    #[structopt()]
+struct RawModifyOpt {
+    #[structopt(long = "filter")]
+    filter: String,
+    // A json modify list.
+    #[structopt(parse(from_os_str), long = "file")]
+    file: PathBuf,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct RawDeleteOpt {
+    #[structopt(long = "filter")]
     filter: String,
+    // Don't ask before deleting everything with an attribute.
+    #[structopt(long = "yes")]
+    yes: bool,
     #[structopt(flatten)]
     commonopts: CommonOpt,
 }
 
+#[derive(Debug, StructOpt)]
+enum RawOpt {
+    #[structopt(name = "effective-access")]
+    EffectiveAccess(EffectiveAccessOpt),
+    #[structopt(name = "search")]
+    Search(RawSearchOpt),
+    #[structopt(name = "create")]
+    Create(RawCreateOpt),
+    #[structopt(name = "modify")]
+    Modify(RawModifyOpt),
+    #[structopt(name = "delete")]
+    Delete(RawDeleteOpt),
+}
+
+#[derive(Debug, StructOpt)]
+struct SessionListOpt {
+    #[structopt(flatten)]
+    output: OutputOpt,
+}
+
+#[derive(Debug, StructOpt)]
+enum SessionOpt {
+    // The sessions cached by earlier commands.
+    #[structopt(name = "list")]
+    List(SessionListOpt),
+}
+
 #[derive(Debug, StructOpt)]
 enum ClientOpt {
     #[structopt(name = "search")]
     Search(SearchOpt),
+    #[structopt(name = "login")]
+    Login(CommonOpt),
     #[structopt(name = "whoami")]
     Whoami(CommonOpt),
+    #[structopt(name = "logout")]
+    Logout(CommonOpt),
+    #[structopt(name = "session")]
+    Session(SessionOpt),
+    #[structopt(name = "schema")]
+    Schema(SchemaOpt),
+    #[structopt(name = "recycle-bin")]
+    RecycleBin(RecycleOpt),
+    #[structopt(name = "totp")]
+    TOTP(TOTPOpt),
+    #[structopt(name = "webauthn")]
+    Webauthn(WebauthnOpt),
+    #[structopt(name = "account")]
+    Account(AccountOpt),
+    #[structopt(name = "badlist")]
+    Badlist(BadlistOpt),
+    #[structopt(name = "service-account")]
+    ServiceAccount(ServiceAccountOpt),
+    #[structopt(name = "group")]
+    Group(GroupOpt),
+    #[structopt(name = "acp")]
+    Acp(AcpOpt),
+    #[structopt(name = "domain")]
+    Domain(DomainOpt),
+    #[structopt(name = "audit")]
+    Audit(AuditOpt),
+    #[structopt(name = "export")]
+    Export(ExportOpt),
+    #[structopt(name = "raw")]
+    Raw(RawOpt),
 }
 
 impl ClientOpt {
-    fn debug(&self) -> bool {
+    fn output(&self) -> &OutputOpt {
         match self {
-            ClientOpt::Whoami(copt) => copt.debug,
-            ClientOpt::Search(sopt) => sopt.commonopts.debug,
+            ClientOpt::Login(copt) | ClientOpt::Whoami(copt) | ClientOpt::Logout(copt) => {
+                &copt.output
+            }
+            ClientOpt::Session(SessionOpt::List(lopt)) => &lopt.output,
+            ClientOpt::Search(sopt) => &sopt.commonopts.output,
+            ClientOpt::Schema(SchemaOpt::List(copt)) => &copt.output,
+            ClientOpt::RecycleBin(RecycleOpt::List(copt)) => &copt.output,
+            ClientOpt::RecycleBin(RecycleOpt::Revive(ropt)) => &ropt.commonopts.output,
+            ClientOpt::TOTP(TOTPOpt::Enroll(copt)) => &copt.output,
+            ClientOpt::Webauthn(WebauthnOpt::List(copt)) => &copt.output,
+            ClientOpt::Webauthn(WebauthnOpt::Remove(wopt)) => &wopt.commonopts.output,
+            ClientOpt::Account(AccountOpt::Credential(CredentialOpt::Status(copt))) => &copt.output,
+            ClientOpt::Account(AccountOpt::Credential(CredentialOpt::GenerateBackupCodes(
+                copt,
+            ))) => &copt.output,
+            ClientOpt::Account(AccountOpt::Credential(CredentialOpt::SetPolicy(popt))) => {
+                &popt.commonopts.output
+            }
+            ClientOpt::Account(AccountOpt::Credential(CredentialOpt::SetPassword(copt))) => {
+                &copt.output
+            }
+            ClientOpt::Account(AccountOpt::Credential(CredentialOpt::ResetPassword(ropt))) => {
+                &ropt.commonopts.output
+            }
+            ClientOpt::Account(AccountOpt::Create(copt))
+            | ClientOpt::Account(AccountOpt::SetDisplayname(copt)) => &copt.commonopts.output,
+            ClientOpt::Account(AccountOpt::Get(nopt))
+            | ClientOpt::Account(AccountOpt::Delete(nopt)) => &nopt.commonopts.output,
+            ClientOpt::Account(AccountOpt::Unlock(uopt)) => &uopt.commonopts.output,
+            ClientOpt::Account(AccountOpt::Validity(ValidityOpt::Show(sopt))) => {
+                &sopt.commonopts.output
+            }
+            ClientOpt::Account(AccountOpt::Validity(ValidityOpt::BeginFrom(vopt)))
+            | ClientOpt::Account(AccountOpt::Validity(ValidityOpt::ExpireAt(vopt))) => {
+                &vopt.commonopts.output
+            }
+            ClientOpt::Account(AccountOpt::Radius(RadiusOpt::Generate(copt))) => &copt.output,
+            ClientOpt::Account(AccountOpt::Radius(RadiusOpt::Show(sopt))) => {
+                &sopt.commonopts.output
+            }
+            ClientOpt::Account(AccountOpt::Ssh(SshOpt::List(lopt))) => &lopt.commonopts.output,
+            ClientOpt::Account(AccountOpt::Ssh(SshOpt::Add(aopt))) => &aopt.commonopts.output,
+            ClientOpt::Account(AccountOpt::Ssh(SshOpt::Delete(dopt))) => &dopt.commonopts.output,
+            ClientOpt::Account(AccountOpt::Posix(AccountPosixOpt::Show(sopt))) => {
+                &sopt.commonopts.output
+            }
+            ClientOpt::Account(AccountOpt::Posix(AccountPosixOpt::Set(sopt))) => {
+                &sopt.commonopts.output
+            }
+            ClientOpt::Account(AccountOpt::Posix(AccountPosixOpt::SetPassword(popt))) => {
+                &popt.commonopts.output
+            }
+            ClientOpt::Badlist(BadlistOpt::List(copt)) => &copt.output,
+            ClientOpt::Badlist(BadlistOpt::Add(bopt))
+            | ClientOpt::Badlist(BadlistOpt::Remove(bopt)) => &bopt.commonopts.output,
+            ClientOpt::ServiceAccount(ServiceAccountOpt::ApiToken(ApiTokenOpt::Generate(gopt))) => {
+                &gopt.commonopts.output
+            }
+            ClientOpt::ServiceAccount(ServiceAccountOpt::ApiToken(ApiTokenOpt::List(lopt))) => {
+                &lopt.commonopts.output
+            }
+            ClientOpt::ServiceAccount(ServiceAccountOpt::ApiToken(ApiTokenOpt::Destroy(dopt))) => {
+                &dopt.commonopts.output
+            }
+            ClientOpt::Group(GroupOpt::Create(nopt))
+            | ClientOpt::Group(GroupOpt::Delete(nopt))
+            | ClientOpt::Group(GroupOpt::ListMembers(nopt)) => &nopt.commonopts.output,
+            ClientOpt::Group(GroupOpt::AddMembers(mopt))
+            | ClientOpt::Group(GroupOpt::RemoveMembers(mopt)) => &mopt.commonopts.output,
+            ClientOpt::Group(GroupOpt::Posix(GroupPosixOpt::Show(sopt))) => &sopt.commonopts.output,
+            ClientOpt::Group(GroupOpt::Posix(GroupPosixOpt::Set(sopt))) => &sopt.commonopts.output,
+            ClientOpt::Acp(AcpOpt::List(copt)) => &copt.output,
+            ClientOpt::Acp(AcpOpt::Get(gopt)) => &gopt.commonopts.output,
+            ClientOpt::Acp(AcpOpt::Check(copt)) => &copt.commonopts.output,
+            ClientOpt::Domain(DomainOpt::Show(copt)) => &copt.output,
+            ClientOpt::Domain(DomainOpt::SetDisplayName(dopt)) => &dopt.commonopts.output,
+            ClientOpt::Domain(DomainOpt::SetDomainName(dopt)) => &dopt.commonopts.output,
+            ClientOpt::Audit(AuditOpt::List(aopt)) => &aopt.commonopts.output,
+            ClientOpt::Export(eopt) => &eopt.commonopts.output,
+            ClientOpt::Raw(RawOpt::EffectiveAccess(eopt)) => &eopt.commonopts.output,
+            ClientOpt::Raw(RawOpt::Search(sopt)) => &sopt.commonopts.output,
+            ClientOpt::Raw(RawOpt::Create(copt)) => &copt.commonopts.output,
+            ClientOpt::Raw(RawOpt::Modify(mopt)) => &mopt.commonopts.output,
+            ClientOpt::Raw(RawOpt::Delete(dopt)) => &dopt.commonopts.output,
         }
     }
 }
@@ -67,7 +1190,8 @@ impl ClientOpt {
 fn main() {
     let opt = ClientOpt::from_args();
 
-    if opt.debug() {
+    JSON_OUTPUT.store(opt.output().json, Ordering::Relaxed);
+    if opt.output().debug {
         ::std::env::set_var("RUST_LOG", "kanidm=debug,kanidm_client=debug");
     } else {
         ::std::env::set_var("RUST_LOG", "kanidm=info,kanidm_client=info");
@@ -75,6 +1199,16 @@ fn main() {
     env_logger::init();
 
     match opt {
+        ClientOpt::Login(copt) => {
+            // A new session is begun, even if one is cached.
+            let client = copt.build_client();
+            let uat = copt.authenticate(&client);
+            if json_output() {
+                print_json(&uat);
+            } else {
+                println!("Logged in as {}", uat.name);
+            }
+        }
         ClientOpt::Whoami(copt) => {
             let client = copt.to_client();
 
@@ -82,20 +1216,741 @@ fn main() {
                 Ok(o_ent) => match o_ent {
                     Some((ent, uat)) => {
                         debug!("{:?}", ent);
-                        println!("{}", uat);
+                        output(&ent);
+                        output(&uat);
                     }
-                    None => println!("Unauthenticated"),
+                    None => exit_with(EXIT_AUTH_DENIED, "Unauthenticated"),
                 },
-                Err(e) => println!("Error: {:?}", e),
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Logout(copt) => {
+            // Only the cached session is ended, so there's no need to
+            // authenticate to log out.
+            let client = copt.build_client();
+            let path = match TokenCache::default_path() {
+                Some(p) => p,
+                None => exit_with(
+                    EXIT_FAILED,
+                    format!("No session cached for {}", copt.username).as_str(),
+                ),
+            };
+            let url = client.get_url().to_string();
+            let cached = TokenCache::load(&path)
+                .get(url.as_str(), copt.username.as_str(), now_secs())
+                .cloned();
+            match cached {
+                Some(cached) => {
+                    client.set_token(cached.token.as_str());
+                    match client.logout() {
+                        // The server had already ended the session.
+                        Ok(_) | Err(ClientError::Unauthorized) => {}
+                        Err(e) => fail(&e),
+                    }
+                    forget_token(&path, url.as_str(), copt.username.as_str());
+                    done("Logged out".to_string());
+                }
+                None => {
+                    // An expired entry is removed all the same.
+                    forget_token(&path, url.as_str(), copt.username.as_str());
+                    done(format!("No session cached for {}", copt.username));
+                }
+            }
+        }
+        ClientOpt::Session(SessionOpt::List(_)) => {
+            let cache = TokenCache::default_path()
+                .map(|p| TokenCache::load(&p))
+                .unwrap_or_default();
+            let now = now_secs();
+            if json_output() {
+                let sessions: Vec<CachedSession> = cache
+                    .entries()
+                    .into_iter()
+                    .map(|(url, name, cached)| CachedSession {
+                        url: url,
+                        name: name,
+                        expiry: cached.expiry,
+                    })
+                    .collect();
+                print_json(&sessions);
+            } else {
+                for (url, name, cached) in cache.entries() {
+                    if cached.expiry > now {
+                        println!("{} {}: expires in {}s", url, name, cached.expiry - now);
+                    } else {
+                        println!("{} {}: expired", url, name);
+                    }
+                }
             }
         }
         ClientOpt::Search(sopt) => {
             let client = sopt.commonopts.to_client();
 
-            let rset = client.search_str(sopt.filter.as_str()).unwrap();
+            let rset = if sopt.ldap {
+                client.search_ldap_str(sopt.filter.as_str())
+            } else {
+                client.search_str(sopt.filter.as_str())
+            };
+
+            let rset = match rset {
+                Ok(rset) => rset,
+                Err(ClientError::FilterParse(e)) => {
+                    exit_with(EXIT_FAILED, format!("Invalid filter: {}", e).as_str());
+                }
+                Err(e) => fail(&e),
+            };
+
+            output_list(&rset);
+        }
+        ClientOpt::Schema(SchemaOpt::List(copt)) => {
+            let client = copt.to_client();
+
+            let attrs = client.schema_attribute_list().unwrap_or_else(|e| fail(&e));
+            output_list(&attrs);
+
+            let classes = client.schema_class_list().unwrap_or_else(|e| fail(&e));
+            output_list(&classes);
+        }
+        ClientOpt::RecycleBin(RecycleOpt::List(copt)) => {
+            let client = copt.to_client();
+
+            let rset = client
+                .recycle_bin_list(Filter::Pres("class".to_string()))
+                .unwrap_or_else(|e| fail(&e));
+            output_list(&rset);
+        }
+        ClientOpt::RecycleBin(RecycleOpt::Revive(ropt)) => {
+            let client = ropt.commonopts.to_client();
+
+            let revived = client
+                .recycle_bin_revive(ropt.uuid.as_str())
+                .unwrap_or_else(|e| match e {
+                    ClientError::ReviveFailed(failed) => {
+                        let failed: Vec<String> = failed
+                            .iter()
+                            .map(|(u, e)| format!("failed: {} -> {}", u, e))
+                            .collect();
+                        exit_with(EXIT_FAILED, failed.join("\n").as_str())
+                    }
+                    e => fail(&e),
+                });
+            if json_output() {
+                print_json(&revived);
+            } else {
+                for u in revived {
+                    println!("revived: {}", u);
+                }
+            }
+        }
+        ClientOpt::TOTP(TOTPOpt::Enroll(copt)) => {
+            let client = copt.to_client();
+
+            let (secret, uri) = client.totp_generate().unwrap_or_else(|e| fail(&e));
+            if json_output() {
+                print_json(&TotpEnrollment {
+                    secret: secret.get_secret(),
+                    uri: uri,
+                });
+            } else {
+                println!("Add this secret to your authenticator:");
+                println!("secret: {}", secret.get_secret());
+                println!("uri: {}", uri);
+            }
+
+            // Until a code is verified, the secret isn't required to login.
+            loop {
+                let totp = prompt("Enter TOTP: ");
+                match client.totp_verify(totp.as_str()) {
+                    Ok(_) => {
+                        done("TOTP enrolled".to_string());
+                        break;
+                    }
+                    Err(ClientError::InvalidTOTP) => eprintln!("Incorrect code, try again"),
+                    Err(e) => fail(&e),
+                }
+            }
+        }
+        ClientOpt::Webauthn(WebauthnOpt::List(copt)) => {
+            let client = copt.to_client();
+
+            let tokens = client.webauthn_list().unwrap_or_else(|e| fail(&e));
+            output_list(&tokens);
+        }
+        ClientOpt::Webauthn(WebauthnOpt::Remove(wopt)) => {
+            let client = wopt.commonopts.to_client();
+
+            match client.webauthn_remove(wopt.name.as_str()) {
+                Ok(_) => done(format!("Removed {}", wopt.name)),
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Account(AccountOpt::Credential(CredentialOpt::Status(copt))) => {
+            let client = copt.to_client();
+
+            match client.credential_status() {
+                Ok(status) => output(&status),
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Account(AccountOpt::Credential(CredentialOpt::GenerateBackupCodes(copt))) => {
+            let client = copt.to_client();
+
+            let codes = client.backup_codes_generate().unwrap_or_else(|e| fail(&e));
+            // These can't be shown again, and replace any previous codes.
+            if !json_output() {
+                println!("Store these backup codes somewhere safe. Each can be used once:");
+            }
+            output_list(&codes);
+        }
+        ClientOpt::Account(AccountOpt::Credential(CredentialOpt::SetPolicy(popt))) => {
+            let policy = if popt.policy == "auto" {
+                None
+            } else {
+                Some(
+                    CredentialPolicy::from_str(popt.policy.as_str()).unwrap_or_else(|e| {
+                        exit_with(EXIT_FAILED, e.to_string().as_str());
+                    }),
+                )
+            };
+            let client = popt.commonopts.to_client();
+
+            match client.credential_set_policy(policy) {
+                Ok(_) => done(format!("Credential policy set to {}", popt.policy)),
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Account(AccountOpt::Credential(CredentialOpt::SetPassword(copt))) => {
+            // We have only just authenticated with the current password, so
+            // it needn't be given again.
+            let client = copt.to_client();
+            let password = prompt_new_password();
+
+            match client.idm_account_self_set_password(None, password.as_str()) {
+                Ok(_) => done("Password changed, and your other sessions ended".to_string()),
+                Err(ClientError::PasswordQuality(feedback)) => {
+                    print_password_feedback(&feedback);
+                    std::process::exit(EXIT_FAILED);
+                }
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Account(AccountOpt::Credential(CredentialOpt::ResetPassword(ropt))) => {
+            let client = ropt.commonopts.to_client();
+            let password = prompt_new_password();
+
+            match client.idm_account_set_password(
+                ropt.account.as_str(),
+                password.as_str(),
+                ropt.must_change,
+            ) {
+                Ok(_) => done(format!("Password of {} reset", ropt.account)),
+                Err(ClientError::PasswordQuality(feedback)) => {
+                    print_password_feedback(&feedback);
+                    std::process::exit(EXIT_FAILED);
+                }
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Account(AccountOpt::Create(copt)) => {
+            let client = copt.commonopts.to_client();
+
+            match client.idm_account_create(copt.account.as_str(), copt.displayname.as_str()) {
+                Ok(uuid) => done(format!("Created {} ({})", copt.account, uuid)),
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Account(AccountOpt::Get(nopt)) => {
+            let client = nopt.commonopts.to_client();
+
+            match client.idm_account_get(nopt.account.as_str()) {
+                Ok(Some(e)) => output(&e),
+                Ok(None) => fail(&ClientError::NoSuchAccount(nopt.account.clone())),
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Account(AccountOpt::Delete(nopt)) => {
+            let client = nopt.commonopts.to_client();
+
+            match client.idm_account_delete(nopt.account.as_str()) {
+                Ok(_) => done(format!("Deleted {}", nopt.account)),
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Account(AccountOpt::SetDisplayname(copt)) => {
+            let client = copt.commonopts.to_client();
+
+            match client
+                .idm_account_set_displayname(copt.account.as_str(), copt.displayname.as_str())
+            {
+                Ok(_) => done(format!("Updated the displayname of {}", copt.account)),
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Account(AccountOpt::Unlock(uopt)) => {
+            let client = uopt.commonopts.to_client();
+
+            match client.idm_account_unlock(uopt.account.as_str()) {
+                Ok(_) => done(format!("{} unlocked", uopt.account)),
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Account(AccountOpt::Validity(ValidityOpt::Show(sopt))) => {
+            let client = sopt.commonopts.to_client();
+
+            match client.idm_account_get_validity(sopt.account.as_str()) {
+                Ok((valid_from, expire)) => {
+                    if json_output() {
+                        print_json(&AccountValidity {
+                            valid_from: valid_from,
+                            expire: expire,
+                        });
+                    } else {
+                        println!(
+                            "valid after: {}",
+                            valid_from.unwrap_or_else(|| "any time".to_string())
+                        );
+                        println!("expire: {}", expire.unwrap_or_else(|| "never".to_string()));
+                    }
+                }
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Account(AccountOpt::Validity(ValidityOpt::BeginFrom(vopt))) => {
+            let client = vopt.commonopts.to_client();
+            let from = match vopt.datetime.as_str() {
+                "any" | "clear" => None,
+                dt => Some(dt),
+            };
+
+            match client.idm_account_set_valid_from(vopt.account.as_str(), from) {
+                Ok(_) => match from {
+                    Some(dt) => done(format!("{} is valid from {}", vopt.account, dt)),
+                    None => done(format!("{} is valid from any time", vopt.account)),
+                },
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Account(AccountOpt::Validity(ValidityOpt::ExpireAt(vopt))) => {
+            let client = vopt.commonopts.to_client();
+            let expire = match vopt.datetime.as_str() {
+                "never" | "clear" => None,
+                dt => Some(dt),
+            };
+
+            match client.idm_account_set_expire(vopt.account.as_str(), expire) {
+                Ok(_) => match expire {
+                    Some(dt) => done(format!("{} expires at {}", vopt.account, dt)),
+                    None => done(format!("{} never expires", vopt.account)),
+                },
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Account(AccountOpt::Radius(RadiusOpt::Generate(copt))) => {
+            let client = copt.to_client();
+
+            match client.radius_secret_generate() {
+                Ok(ref secret) if json_output() => print_json(secret),
+                Ok(secret) => println!("Radius secret: {}", secret),
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Account(AccountOpt::Radius(RadiusOpt::Show(sopt))) => {
+            let client = sopt.commonopts.to_client();
+
+            match client.radius_auth_token_get(sopt.account.as_str()) {
+                Ok(rat) => output(&rat),
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Account(AccountOpt::Ssh(SshOpt::List(lopt))) => {
+            let client = lopt.commonopts.to_client();
+
+            let keys = client
+                .idm_account_list_ssh_pubkeys(lopt.account.as_str())
+                .unwrap_or_else(|e| fail(&e));
+            output_list(&keys);
+        }
+        ClientOpt::Account(AccountOpt::Ssh(SshOpt::Add(aopt))) => {
+            let client = aopt.commonopts.to_client();
+
+            match client.idm_account_post_ssh_pubkey(
+                aopt.account.as_str(),
+                aopt.tag.as_str(),
+                aopt.pubkey.as_str(),
+            ) {
+                Ok(_) => done(format!("Added {} to {}", aopt.tag, aopt.account)),
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Account(AccountOpt::Ssh(SshOpt::Delete(dopt))) => {
+            let client = dopt.commonopts.to_client();
+
+            match client.idm_account_delete_ssh_pubkey(dopt.account.as_str(), dopt.tag.as_str()) {
+                Ok(_) => done(format!("Removed {} from {}", dopt.tag, dopt.account)),
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Account(AccountOpt::Posix(AccountPosixOpt::Show(sopt))) => {
+            let client = sopt.commonopts.to_client();
+
+            match client.idm_account_unix_token_get(sopt.id.as_str()) {
+                Ok(ut) => output(&ut),
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Account(AccountOpt::Posix(AccountPosixOpt::Set(sopt))) => {
+            let client = sopt.commonopts.to_client();
+
+            match client.idm_account_unix_extend(
+                sopt.account.as_str(),
+                sopt.gidnumber,
+                sopt.shell.as_deref(),
+            ) {
+                Ok(_) => done(format!("{} is now a posix account", sopt.account)),
+                Err(ClientError::DuplicateValue(attr)) => {
+                    exit_with(EXIT_FAILED, format!("{} is already in use", attr).as_str())
+                }
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Account(AccountOpt::Posix(AccountPosixOpt::SetPassword(popt))) => {
+            let client = popt.commonopts.to_client();
+            let password = prompt_new_password();
+
+            match client.idm_account_unix_cred_put(popt.account.as_str(), password.as_str()) {
+                Ok(_) => done(format!("Unix password of {} set", popt.account)),
+                Err(ClientError::PasswordQuality(feedback)) => {
+                    print_password_feedback(&feedback);
+                    std::process::exit(EXIT_FAILED);
+                }
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Badlist(BadlistOpt::List(copt)) => {
+            let client = copt.to_client();
+
+            let words = client
+                .system_password_badlist_get()
+                .unwrap_or_else(|e| fail(&e));
+            output_list(&words);
+        }
+        ClientOpt::Badlist(BadlistOpt::Add(bopt)) => {
+            let words = bopt.read_words();
+            let client = bopt.commonopts.to_client();
+
+            let words: Vec<&str> = words.iter().map(|w| w.as_str()).collect();
+            match client.system_password_badlist_append(&words) {
+                Ok(_) => done("Badlist updated".to_string()),
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Badlist(BadlistOpt::Remove(bopt)) => {
+            let words = bopt.read_words();
+            let client = bopt.commonopts.to_client();
+
+            let words: Vec<&str> = words.iter().map(|w| w.as_str()).collect();
+            match client.system_password_badlist_remove(&words) {
+                Ok(_) => done("Badlist updated".to_string()),
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::ServiceAccount(ServiceAccountOpt::ApiToken(ApiTokenOpt::Generate(gopt))) => {
+            let expiry = gopt.valid_for.map(|v| {
+                SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .expect("Clock failure!")
+                    .as_secs()
+                    + v
+            });
+            let client = gopt.commonopts.to_client();
+
+            let r = client
+                .service_account_api_token_generate(
+                    gopt.account.as_str(),
+                    gopt.label.as_str(),
+                    expiry,
+                    gopt.read_write,
+                )
+                .unwrap_or_else(|e| fail(&e));
+            // Only a hash is kept, so this can't be shown again.
+            if json_output() {
+                print_json(&r);
+            } else {
+                println!("Generated api token {}. Store it somewhere safe:", r.id);
+                println!("{}", r.token);
+            }
+        }
+        ClientOpt::ServiceAccount(ServiceAccountOpt::ApiToken(ApiTokenOpt::List(lopt))) => {
+            let client = lopt.commonopts.to_client();
+
+            let tokens = client
+                .service_account_api_token_list(lopt.account.as_str())
+                .unwrap_or_else(|e| fail(&e));
+            output_list(&tokens);
+        }
+        ClientOpt::ServiceAccount(ServiceAccountOpt::ApiToken(ApiTokenOpt::Destroy(dopt))) => {
+            let client = dopt.commonopts.to_client();
+
+            match client.service_account_api_token_destroy(dopt.account.as_str(), dopt.id.as_str())
+            {
+                Ok(_) => done(format!("Destroyed api token {}", dopt.id)),
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Group(GroupOpt::Create(nopt)) => {
+            let client = nopt.commonopts.to_client();
+
+            match client.idm_group_create(nopt.group.as_str()) {
+                Ok(uuid) => done(format!("Created {} ({})", nopt.group, uuid)),
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Group(GroupOpt::Delete(nopt)) => {
+            let client = nopt.commonopts.to_client();
+
+            match client.idm_group_delete(nopt.group.as_str()) {
+                Ok(_) => done(format!("Deleted {}", nopt.group)),
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Group(GroupOpt::ListMembers(nopt)) => {
+            let client = nopt.commonopts.to_client();
+
+            let members = client
+                .idm_group_list_members(nopt.group.as_str())
+                .unwrap_or_else(|e| fail(&e));
+            output_list(&members);
+        }
+        ClientOpt::Group(GroupOpt::AddMembers(mopt)) => {
+            let client = mopt.commonopts.to_client();
+            let members: Vec<&str> = mopt.members.iter().map(|m| m.as_str()).collect();
+
+            match client.idm_group_add_members(mopt.group.as_str(), &members) {
+                Ok(_) => done(format!("Added {} to {}", members.join(", "), mopt.group)),
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Group(GroupOpt::RemoveMembers(mopt)) => {
+            let client = mopt.commonopts.to_client();
+            let members: Vec<&str> = mopt.members.iter().map(|m| m.as_str()).collect();
+
+            match client.idm_group_remove_members(mopt.group.as_str(), &members) {
+                Ok(_) => done(format!(
+                    "Removed {} from {}",
+                    members.join(", "),
+                    mopt.group
+                )),
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Group(GroupOpt::Posix(GroupPosixOpt::Show(sopt))) => {
+            let client = sopt.commonopts.to_client();
+
+            match client.idm_group_unix_token_get(sopt.id.as_str()) {
+                Ok(gt) => output(&gt),
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Group(GroupOpt::Posix(GroupPosixOpt::Set(sopt))) => {
+            let client = sopt.commonopts.to_client();
+
+            match client.idm_group_unix_extend(sopt.group.as_str(), sopt.gidnumber) {
+                Ok(_) => done(format!("{} is now a posix group", sopt.group)),
+                Err(ClientError::DuplicateValue(attr)) => {
+                    exit_with(EXIT_FAILED, format!("{} is already in use", attr).as_str())
+                }
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Acp(AcpOpt::List(copt)) => {
+            let client = copt.to_client();
+
+            let acps = client.idm_acp_list().unwrap_or_else(|e| fail(&e));
+            output_list(&acps);
+        }
+        ClientOpt::Acp(AcpOpt::Get(gopt)) => {
+            let client = gopt.commonopts.to_client();
+
+            match client.idm_acp_get(gopt.name.as_str()) {
+                Ok(Some(a)) => output(&a),
+                Ok(None) => {
+                    exit_with(
+                        EXIT_FAILED,
+                        format!("No access control profile named {}", gopt.name).as_str(),
+                    );
+                }
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Acp(AcpOpt::Check(copt)) => {
+            let filter = Filter::from_ldap_str(copt.filter.as_str()).unwrap_or_else(|e| {
+                exit_with(EXIT_FAILED, format!("Invalid filter: {}", e).as_str());
+            });
+            let client = copt.commonopts.to_client();
+
+            let entries = client
+                .access_check(copt.receiver.as_str(), filter, copt.operation())
+                .unwrap_or_else(|e| fail(&e));
+            if entries.is_empty() && !json_output() {
+                println!("No entries match the filter");
+            }
+            output_list(&entries);
+        }
+        ClientOpt::Audit(AuditOpt::List(aopt)) => {
+            let client = aopt.commonopts.to_client();
+
+            let records = client
+                .audit_list(
+                    aopt.since.as_ref().map(|s| s.as_str()),
+                    aopt.until.as_ref().map(|s| s.as_str()),
+                    aopt.target.as_ref().map(|s| s.as_str()),
+                )
+                .unwrap_or_else(|e| fail(&e));
+            if records.is_empty() && !json_output() {
+                println!("No changes were recorded");
+            }
+            output_list(&records);
+        }
+        ClientOpt::Export(eopt) => {
+            let client = eopt.commonopts.to_client();
+
+            let mut records = match client.export(eopt.since.as_ref().map(|s| s.as_str())) {
+                Ok(records) => records,
+                Err(ClientError::ChangelogTrimmed) => {
+                    eprintln!("The changes since this cursor are no longer kept.");
+                    eprintln!("Run again without --since for a full export.");
+                    std::process::exit(EXIT_CHANGELOG_TRIMMED);
+                }
+                Err(e) => fail(&e),
+            };
+
+            // The records are written as they arrive, so a failure part way
+            // leaves what was written without a cursor, and the export must
+            // be run again from the last one.
+            for r in records.by_ref() {
+                let r = r.unwrap_or_else(|e| fail(&e));
+                if eopt.ldif {
+                    print_export_ldif(&r);
+                } else {
+                    let item = match r {
+                        ExportRecord::Entry(e) => ExportStreamItem::Entry(e),
+                        ExportRecord::Deleted(u) => ExportStreamItem::Deleted(u),
+                    };
+                    println!("{}", serde_json::to_string(&item).unwrap());
+                }
+            }
+
+            let cursor = records.cursor().map(|c| c.to_string());
+            if eopt.ldif {
+                println!("# cursor: {}", cursor.unwrap_or_default());
+            } else {
+                let done = ExportStreamItem::Done(cursor);
+                println!("{}", serde_json::to_string(&done).unwrap());
+            }
+        }
+        ClientOpt::Raw(RawOpt::EffectiveAccess(eopt)) => {
+            let filter = Filter::from_ldap_str(eopt.filter.as_str()).unwrap_or_else(|e| {
+                exit_with(EXIT_FAILED, format!("Invalid filter: {}", e).as_str());
+            });
+            let client = eopt.commonopts.to_client();
+
+            let entries = client.effective_access(filter).unwrap_or_else(|e| fail(&e));
+            if json_output() {
+                print_json(&entries);
+            } else if entries.is_empty() {
+                println!("No entries match the filter");
+            } else {
+                print_effective_access(&entries);
+            }
+        }
+        ClientOpt::Raw(RawOpt::Search(sopt)) => {
+            let filter = raw::parse_filter(sopt.filter.as_str())
+                .unwrap_or_else(|e| exit_with(EXIT_FAILED, e.as_str()));
+            let client = sopt.commonopts.to_client();
+
+            let entries = client.search(filter).unwrap_or_else(|e| fail(&e));
+            output_list(&entries);
+        }
+        ClientOpt::Raw(RawOpt::Create(copt)) => {
+            let entries = raw::read_entries(&copt.file)
+                .unwrap_or_else(|e| exit_with(EXIT_FAILED, e.as_str()));
+            let client = copt.commonopts.to_client();
+
+            let uuids = client.create(entries).unwrap_or_else(|e| fail(&e));
+            if json_output() {
+                print_json(&uuids);
+            } else {
+                for u in uuids {
+                    println!("created: {}", u);
+                }
+            }
+        }
+        ClientOpt::Raw(RawOpt::Modify(mopt)) => {
+            let filter = raw::parse_filter(mopt.filter.as_str())
+                .unwrap_or_else(|e| exit_with(EXIT_FAILED, e.as_str()));
+            let modlist = raw::read_modlist(&mopt.file)
+                .unwrap_or_else(|e| exit_with(EXIT_FAILED, e.as_str()));
+            let client = mopt.commonopts.to_client();
+
+            match client.modify(filter, modlist, false) {
+                Ok(n) => done(format!("Modified {} entries", n)),
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Raw(RawOpt::Delete(dopt)) => {
+            let filter = raw::parse_filter(dopt.filter.as_str())
+                .unwrap_or_else(|e| exit_with(EXIT_FAILED, e.as_str()));
+            if raw::is_bare_pres(&filter) && !dopt.yes {
+                let answer = prompt(
+                    format!(
+                        "{} matches nearly every entry. Delete them all? [y/N] ",
+                        dopt.filter
+                    )
+                    .as_str(),
+                );
+                if answer != "y" && answer != "yes" {
+                    exit_with(EXIT_FAILED, "Nothing was deleted");
+                }
+            }
+            let client = dopt.commonopts.to_client();
+
+            match client.delete(filter, false) {
+                Ok(n) => done(format!("Deleted {} entries", n)),
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Domain(DomainOpt::Show(copt)) => {
+            let client = copt.to_client();
+
+            match client.idm_domain_get() {
+                Ok(d) => output(&d),
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Domain(DomainOpt::SetDisplayName(dopt)) => {
+            let client = dopt.commonopts.to_client();
+
+            match client.idm_domain_set_display_name(dopt.name.as_str()) {
+                Ok(_) => done("Domain display name set".to_string()),
+                Err(e) => fail(&e),
+            }
+        }
+        ClientOpt::Domain(DomainOpt::SetDomainName(dopt)) => {
+            if !dopt.yes {
+                eprintln!(
+                    "WARNING: renaming the domain changes the spn of every account and group."
+                );
+                eprintln!("Anything that refers to them by spn will stop matching them.");
+                exit_with(
+                    EXIT_FAILED,
+                    format!("Run again with --yes to rename the domain to {}", dopt.name).as_str(),
+                );
+            }
+            let client = dopt.commonopts.to_client();
 
-            for e in rset {
-                println!("{:?}", e);
+            match client.idm_domain_set_name(dopt.name.as_str()) {
+                Ok(_) => done(format!("Domain renamed to {}", dopt.name)),
+                Err(e) => fail(&e),
             }
         }
     }
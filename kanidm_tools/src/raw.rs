@@ -0,0 +1,175 @@
+// Reading the files and filters given to the raw commands, which are sent to
+// the server as they are. A file that doesn't deserialise is reported with
+// where in it the problem is, as these are written by hand.
+use kanidm_proto::v1::{Entry, Filter, ModifyList};
+use serde::de::DeserializeOwned;
+use std::fs;
+use std::path::Path;
+
+// The value at path in the file that failed to deserialise, and why.
+fn from_json<T: DeserializeOwned>(source: &str, data: &str) -> Result<T, String> {
+    let de = &mut serde_json::Deserializer::from_str(data);
+    serde_path_to_error::deserialize(de).map_err(|e| {
+        let path = e.path().to_string();
+        let inner = e.into_inner();
+        // The inner error ends with its own position, which is given first
+        // instead, in the form editors understand.
+        let msg = inner.to_string();
+        let msg = match msg.rfind(" at line ") {
+            Some(i) => msg[..i].to_string(),
+            None => msg,
+        };
+        if path == "." {
+            format!("{}:{}:{}: {}", source, inner.line(), inner.column(), msg)
+        } else {
+            format!(
+                "{}:{}:{}: at {}: {}",
+                source,
+                inner.line(),
+                inner.column(),
+                path,
+                msg
+            )
+        }
+    })
+}
+
+fn read_file(path: &Path) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| format!("Error reading {}: {}", path.display(), e))
+}
+
+// Either a list of entries, or a single entry.
+pub fn parse_entries(source: &str, data: &str) -> Result<Vec<Entry>, String> {
+    if data.trim_start().starts_with('[') {
+        from_json(source, data)
+    } else {
+        from_json(source, data).map(|e| vec![e])
+    }
+}
+
+pub fn read_entries(path: &Path) -> Result<Vec<Entry>, String> {
+    parse_entries(
+        path.display().to_string().as_str(),
+        read_file(path)?.as_str(),
+    )
+}
+
+pub fn parse_modlist(source: &str, data: &str) -> Result<ModifyList, String> {
+    from_json(source, data)
+}
+
+pub fn read_modlist(path: &Path) -> Result<ModifyList, String> {
+    parse_modlist(
+        path.display().to_string().as_str(),
+        read_file(path)?.as_str(),
+    )
+}
+
+// A filter is json when it's an object or a string, as the unit variants
+// such as "Self" are, and an ldap filter otherwise.
+pub fn parse_filter(s: &str) -> Result<Filter, String> {
+    let t = s.trim_start();
+    if t.starts_with('{') || t.starts_with('"') {
+        from_json("filter", s)
+    } else {
+        Filter::from_ldap_str(s).map_err(|e| format!("Invalid filter: {}", e))
+    }
+}
+
+// Whether the filter matches every entry with an attribute, such as
+// (class=*), which is almost always everything.
+pub fn is_bare_pres(f: &Filter) -> bool {
+    match f {
+        Filter::Pres(_) | Filter::True => true,
+        Filter::And(fs) | Filter::Or(fs) if fs.len() == 1 => is_bare_pres(&fs[0]),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_bare_pres, parse_entries, parse_filter, parse_modlist};
+    use kanidm_proto::v1::{Filter, Modify};
+
+    #[test]
+    fn test_raw_parse_entries() {
+        let entries = parse_entries(
+            "entries.json",
+            r#"[
+                {"attrs": {"class": ["group"], "name": ["raw_a"]}},
+                {"attrs": {"class": ["group"], "name": ["raw_b"]}}
+            ]"#,
+        )
+        .unwrap();
+        assert!(entries.len() == 2);
+        assert!(entries[1].get_ava_single("name") == Some("raw_b"));
+
+        let entries = parse_entries(
+            "entry.json",
+            r#"{"attrs": {"class": ["group"], "name": ["raw_a"]}}"#,
+        )
+        .unwrap();
+        assert!(entries.len() == 1);
+    }
+
+    #[test]
+    fn test_raw_parse_errors() {
+        // A value of the wrong type is pointed at by where it is in the
+        // file, and by its path.
+        let e = parse_entries(
+            "entries.json",
+            "[\n  {\"attrs\": {\"class\": [\"group\"]}},\n  {\"attrs\": {\"name\": \"raw_b\"}}\n]",
+        )
+        .unwrap_err();
+        assert!(e.starts_with("entries.json:3:"));
+        assert!(e.contains("at [1].attrs.name: invalid type: string"));
+
+        let e = parse_entries("entries.json", "[{\"attr\": {}}]").unwrap_err();
+        assert!(e.starts_with("entries.json:1:"));
+        assert!(e.contains("unknown field `attr`") || e.contains("missing field `attrs`"));
+
+        let e = parse_modlist(
+            "modlist.json",
+            "{\"mods\": [\n  {\"Present\": [\"name\", \"x\"]},\n  {\"Replace\": [\"name\", \"y\"]}\n]}",
+        )
+        .unwrap_err();
+        assert!(e.starts_with("modlist.json:3:"));
+        assert!(e.contains("at mods[1]"));
+        assert!(e.contains("unknown variant `Replace`"));
+
+        // Something that isn't json at all is pointed at too.
+        let e = parse_modlist("modlist.json", "{\"mods\": [}").unwrap_err();
+        assert!(e.starts_with("modlist.json:1:11: "));
+    }
+
+    #[test]
+    fn test_raw_parse_modlist_and_filter() {
+        let ml = parse_modlist(
+            "modlist.json",
+            r#"{"mods": [{"Purged": "description"}, {"Present": ["description", "x"]}]}"#,
+        )
+        .unwrap();
+        assert!(ml.mods.len() == 2);
+        match &ml.mods[1] {
+            Modify::Present(a, v) => assert!(a == "description" && v == "x"),
+            m => panic!("Unexpected modify {:?}", m),
+        }
+
+        let json = parse_filter(r#"{"Eq": ["name", "admin"]}"#).unwrap();
+        let ldap = parse_filter("(name=admin)").unwrap();
+        assert!(json == ldap);
+        assert!(parse_filter("(name=admin").is_err());
+        assert!(parse_filter(r#"{"Eq": ["name"]}"#)
+            .unwrap_err()
+            .starts_with("filter:1:"));
+
+        assert!(is_bare_pres(&parse_filter("(class=*)").unwrap()));
+        assert!(is_bare_pres(&Filter::And(vec![Filter::Pres(
+            "name".to_string()
+        )])));
+        assert!(!is_bare_pres(
+            &parse_filter("(&(class=*)(name=admin))").unwrap()
+        ));
+        assert!(!is_bare_pres(&parse_filter("(name=admin)").unwrap()));
+    }
+}
@@ -0,0 +1,93 @@
+// For sshd's AuthorizedKeysCommand, such as:
+//
+//   AuthorizedKeysCommand /usr/bin/kanidm_ssh_authorizedkeys -H https://idm.example.com %u
+//   AuthorizedKeysCommandUser nobody
+//
+// The keys of the account are printed one per line. sshd only uses them if
+// we exit with 0, so on any error nothing is printed and we exit with 1, and
+// sshd falls back to its other sources of keys.
+extern crate structopt;
+use kanidm_client::KanidmClientBuilder;
+use std::path::PathBuf;
+use structopt::StructOpt;
+extern crate env_logger;
+#[macro_use]
+extern crate log;
+
+#[derive(Debug, StructOpt)]
+struct SshAuthorizedOpt {
+    #[structopt(short = "d", long = "debug")]
+    debug: bool,
+    // Without these, the uri and ca of the config files are used.
+    #[structopt(short = "H", long = "url")]
+    addr: Option<String>,
+    #[structopt(parse(from_os_str), short = "C", long = "ca")]
+    ca_path: Option<PathBuf>,
+    // A service account to read the keys as, rather than anonymous. Its api
+    // token is read from the file given, so it isn't in the process list.
+    #[structopt(short = "D", long = "name")]
+    username: Option<String>,
+    #[structopt(parse(from_os_str), short = "T", long = "token-file")]
+    token_path: Option<PathBuf>,
+    // The account that is logging in.
+    #[structopt()]
+    account: String,
+}
+
+fn main() {
+    let opt = SshAuthorizedOpt::from_args();
+
+    // Logs go to stderr, so that sshd only reads keys from stdout.
+    if opt.debug {
+        ::std::env::set_var("RUST_LOG", "kanidm=debug,kanidm_client=debug");
+    } else {
+        ::std::env::set_var("RUST_LOG", "kanidm=warn,kanidm_client=warn");
+    }
+    env_logger::init();
+
+    let mut builder = KanidmClientBuilder::new();
+    if let Some(addr) = opt.addr.as_ref() {
+        builder = builder.address(addr.as_str());
+    }
+    if let Some(ca_path) = opt.ca_path.as_ref() {
+        builder = builder.add_root_certificate(ca_path.to_str().unwrap());
+    }
+    let client = match builder.read_default_config().and_then(|b| b.build()) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Error building client: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let r = match (&opt.username, &opt.token_path) {
+        (Some(name), Some(p)) => match std::fs::read_to_string(p) {
+            Ok(token) => client.auth_api_token(name.as_str(), token.trim()),
+            Err(e) => {
+                error!("Error reading {:?}: {:?}", p, e);
+                std::process::exit(1);
+            }
+        },
+        (None, None) => client.auth_anonymous(),
+        _ => {
+            error!("A service account name and token file must be given together");
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = r {
+        error!("Error during authentication phase: {:?}", e);
+        std::process::exit(1);
+    }
+
+    match client.idm_account_get_ssh_pubkeys(opt.account.as_str()) {
+        Ok(keys) => {
+            for k in keys {
+                println!("{}", k);
+            }
+        }
+        Err(e) => {
+            error!("Error reading ssh keys of {}: {:?}", opt.account, e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,238 @@
+// The tokens of the sessions the cli has begun, so that each command needn't
+// authenticate again. They're kept by server and account, in a file only the
+// user may read, as anyone holding a token has its session until it expires.
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions, Permissions};
+use std::io::{self, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+// Under the home directory of the user.
+pub const DEFAULT_TOKEN_CACHE: &str = ".cache/kanidm_tokens";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedToken {
+    pub token: String,
+    // When the server stops accepting the token, in seconds since the unix
+    // epoch.
+    pub expiry: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TokenCache {
+    // By server url, then by account name.
+    servers: BTreeMap<String, BTreeMap<String, CachedToken>>,
+}
+
+impl TokenCache {
+    pub fn default_path() -> Option<PathBuf> {
+        std::env::var_os("HOME").map(|home| Path::new(&home).join(DEFAULT_TOKEN_CACHE))
+    }
+
+    // A cache that is missing or can't be read is treated as empty, so the
+    // worst that happens is that we authenticate again.
+    pub fn load(path: &Path) -> Self {
+        match fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                warn!("Ignoring unreadable token cache {}: {}", path.display(), e);
+                TokenCache::default()
+            }),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => TokenCache::default(),
+            Err(e) => {
+                warn!("Ignoring unreadable token cache {}: {}", path.display(), e);
+                TokenCache::default()
+            }
+        }
+    }
+
+    // The cached token of the account at this server, unless it has expired
+    // by ct, in seconds since the unix epoch.
+    pub fn get(&self, url: &str, name: &str, ct: u64) -> Option<&CachedToken> {
+        self.servers
+            .get(url)
+            .and_then(|accounts| accounts.get(name))
+            .filter(|t| t.expiry > ct)
+    }
+
+    pub fn insert(&mut self, url: &str, name: &str, token: CachedToken) {
+        self.servers
+            .entry(url.to_string())
+            .or_insert_with(BTreeMap::new)
+            .insert(name.to_string(), token);
+    }
+
+    pub fn remove(&mut self, url: &str, name: &str) -> Option<CachedToken> {
+        let removed = self
+            .servers
+            .get_mut(url)
+            .and_then(|accounts| accounts.remove(name));
+        if self.servers.get(url).map(|a| a.is_empty()).unwrap_or(false) {
+            self.servers.remove(url);
+        }
+        removed
+    }
+
+    // Each cached token, as (url, name, token).
+    pub fn entries(&self) -> Vec<(&str, &str, &CachedToken)> {
+        self.servers
+            .iter()
+            .flat_map(|(url, accounts)| {
+                accounts
+                    .iter()
+                    .map(move |(name, t)| (url.as_str(), name.as_str(), t))
+            })
+            .collect()
+    }
+
+    // The cache is written to a file of its own and renamed over the old
+    // one, so that another command reading or saving it at the same time
+    // never sees half of it. The file is only readable by the user from the
+    // moment it's made.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file_name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "kanidm_tokens".to_string());
+        let tmp = path.with_file_name(format!(".{}.{}.tmp", file_name, std::process::id()));
+
+        let data = serde_json::to_vec(self).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let written = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp)
+            .and_then(|mut f| {
+                // The mode is only given to a file that is created, so a
+                // leftover one is set too.
+                f.set_permissions(Permissions::from_mode(0o600))?;
+                f.write_all(&data)?;
+                f.sync_all()
+            })
+            .and_then(|_| fs::rename(&tmp, path));
+        if written.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        written
+    }
+
+    // Load, change and save the cache in one go, so that as little as
+    // possible of what another command saved in the meantime is lost.
+    pub fn update<F: FnOnce(&mut TokenCache)>(path: &Path, f: F) -> io::Result<()> {
+        let mut cache = TokenCache::load(path);
+        f(&mut cache);
+        cache.save(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CachedToken, TokenCache};
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+
+    static URL: &'static str = "https://idm.example.com";
+
+    fn test_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kanidm_tools_{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("cache").join("kanidm_tokens")
+    }
+
+    fn token(t: &str, expiry: u64) -> CachedToken {
+        CachedToken {
+            token: t.to_string(),
+            expiry: expiry,
+        }
+    }
+
+    #[test]
+    fn test_token_cache_reuse() {
+        let path = test_path("token_cache_reuse");
+        assert!(TokenCache::load(&path).entries().is_empty());
+
+        TokenCache::update(&path, |c| {
+            c.insert(URL, "admin", token("admin token", 200));
+            c.insert(URL, "anonymous", token("anon token", 200));
+            c.insert(
+                "https://other.example.com",
+                "admin",
+                token("other token", 200),
+            );
+        })
+        .expect("Failed to save cache");
+
+        // Each token is kept for its own server and account.
+        let cache = TokenCache::load(&path);
+        assert!(cache.get(URL, "admin", 100) == Some(&token("admin token", 200)));
+        assert!(cache.get(URL, "anonymous", 100) == Some(&token("anon token", 200)));
+        assert!(
+            cache.get("https://other.example.com", "admin", 100)
+                == Some(&token("other token", 200))
+        );
+        assert!(cache.get(URL, "someone", 100).is_none());
+        assert!(cache.entries().len() == 3);
+
+        // A new auth replaces the token.
+        TokenCache::update(&path, |c| c.insert(URL, "admin", token("new token", 300)))
+            .expect("Failed to save cache");
+        let cache = TokenCache::load(&path);
+        assert!(cache.get(URL, "admin", 100) == Some(&token("new token", 300)));
+
+        TokenCache::update(&path, |c| {
+            assert!(c.remove(URL, "admin").is_some());
+            assert!(c.remove(URL, "admin").is_none());
+        })
+        .expect("Failed to save cache");
+        let cache = TokenCache::load(&path);
+        assert!(cache.get(URL, "admin", 100).is_none());
+        assert!(cache.entries().len() == 2);
+
+        // No temporary file is left behind.
+        let dir = path.parent().unwrap();
+        assert!(fs::read_dir(dir).unwrap().count() == 1);
+        let _ = fs::remove_dir_all(dir.parent().unwrap());
+    }
+
+    #[test]
+    fn test_token_cache_expiry() {
+        let path = test_path("token_cache_expiry");
+        TokenCache::update(&path, |c| c.insert(URL, "admin", token("admin token", 200)))
+            .expect("Failed to save cache");
+
+        // Once expired the token isn't given, so we authenticate again.
+        let cache = TokenCache::load(&path);
+        assert!(cache.get(URL, "admin", 199).is_some());
+        assert!(cache.get(URL, "admin", 200).is_none());
+        assert!(cache.entries().len() == 1);
+
+        // A cache that can't be read is as good as empty.
+        fs::write(&path, b"not a cache").unwrap();
+        assert!(TokenCache::load(&path).entries().is_empty());
+        let _ = fs::remove_dir_all(path.parent().unwrap().parent().unwrap());
+    }
+
+    #[test]
+    fn test_token_cache_permissions() {
+        let path = test_path("token_cache_permissions");
+        TokenCache::update(&path, |c| c.insert(URL, "admin", token("admin token", 200)))
+            .expect("Failed to save cache");
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert!(mode & 0o777 == 0o600);
+
+        // A cache that was made readable by others is locked again when it's
+        // saved.
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        TokenCache::update(&path, |c| {
+            c.insert(URL, "anonymous", token("anon token", 200))
+        })
+        .expect("Failed to save cache");
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert!(mode & 0o777 == 0o600);
+        let _ = fs::remove_dir_all(path.parent().unwrap().parent().unwrap());
+    }
+}
@@ -0,0 +1,304 @@
+#![deny(warnings)]
+
+extern crate actix;
+use actix::prelude::*;
+
+extern crate assert_cmd;
+extern crate kanidm;
+extern crate serde_json;
+
+use assert_cmd::prelude::*;
+use kanidm::config::{Configuration, IntegrationTestConfig};
+use kanidm::core::create_server_core;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+extern crate env_logger;
+
+static PORT_ALLOC: AtomicUsize = AtomicUsize::new(23080);
+static ADMIN_TEST_PASSWORD: &'static str = "integration test admin password";
+
+// Run a server, giving the test its url and a home directory of its own, so
+// the sessions the cli caches don't leak between tests.
+fn run_test(test_fn: fn(&str, &Path) -> ()) {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let (tx, rx) = mpsc::channel();
+    let port = PORT_ALLOC.fetch_add(1, Ordering::SeqCst);
+
+    let int_config = Box::new(IntegrationTestConfig {
+        admin_password: ADMIN_TEST_PASSWORD.to_string(),
+    });
+
+    let mut config = Configuration::new();
+    config.address = format!("127.0.0.1:{}", port);
+    config.secure_cookies = false;
+    config.integration_test_config = Some(int_config);
+
+    thread::spawn(move || {
+        System::run(move || {
+            create_server_core(config);
+            let _ = tx.send(System::current());
+        });
+    });
+    let sys = rx.recv().unwrap();
+    System::set_current(sys.clone());
+
+    let home = test_home(port);
+    let addr = format!("http://127.0.0.1:{}", port);
+    test_fn(addr.as_str(), &home);
+
+    let _ = sys.stop();
+    let _ = fs::remove_dir_all(&home);
+}
+
+fn test_home(port: usize) -> PathBuf {
+    let home = std::env::temp_dir().join(format!("kanidm_tools_cli_{}", port));
+    let _ = fs::remove_dir_all(&home);
+    fs::create_dir_all(&home).expect("Failed to create test home");
+    home
+}
+
+fn kanidm(home: &Path, args: &[&str]) -> Command {
+    let mut cmd = Command::cargo_bin("kanidm").expect("Failed to find kanidm binary");
+    cmd.env("HOME", home).args(args);
+    cmd
+}
+
+fn stdout_json(output: &std::process::Output) -> serde_json::Value {
+    serde_json::from_slice(&output.stdout).expect("Output was not json")
+}
+
+#[test]
+fn test_cli_login_password_stdin() {
+    run_test(|addr: &str, home: &Path| {
+        let output = kanidm(
+            home,
+            &[
+                "login",
+                "-H",
+                addr,
+                "-D",
+                "admin",
+                "--password-stdin",
+                "--json",
+            ],
+        )
+        .with_stdin()
+        .buffer(format!("{}\n", ADMIN_TEST_PASSWORD))
+        .assert()
+        .success()
+        .get_output()
+        .clone();
+        let uat = stdout_json(&output);
+        assert!(uat["name"] == "admin");
+
+        // The session is cached, so no password is needed after login.
+        let output = kanidm(home, &["whoami", "-H", addr, "-D", "admin", "--json"])
+            .assert()
+            .success()
+            .get_output()
+            .clone();
+        let mut lines = output.stdout.split(|b| *b == b'\n');
+        let entry: serde_json::Value = serde_json::from_slice(lines.next().unwrap()).unwrap();
+        assert!(entry["attrs"]["name"][0] == "admin");
+
+        // Once logged out, a password is needed again, and there is none.
+        kanidm(home, &["logout", "-H", addr, "-D", "admin"])
+            .assert()
+            .success();
+        kanidm(
+            home,
+            &["whoami", "-H", addr, "-D", "admin", "--password-stdin"],
+        )
+        .with_stdin()
+        .buffer("")
+        .assert()
+        .code(2);
+    });
+}
+
+#[test]
+fn test_cli_login_password_file() {
+    run_test(|addr: &str, home: &Path| {
+        let password_file = home.join("password");
+        fs::write(&password_file, format!("{}\n", ADMIN_TEST_PASSWORD)).unwrap();
+        let password_file = password_file.to_str().unwrap();
+
+        kanidm(
+            home,
+            &[
+                "login",
+                "-H",
+                addr,
+                "-D",
+                "admin",
+                "--password-file",
+                password_file,
+            ],
+        )
+        .assert()
+        .success();
+
+        // Anonymous needs no password at all.
+        kanidm(home, &["login", "-H", addr, "-D", "anonymous"])
+            .assert()
+            .success();
+
+        let output = kanidm(home, &["session", "list", "--json"])
+            .assert()
+            .success()
+            .get_output()
+            .clone();
+        let sessions = stdout_json(&output);
+        assert!(sessions.as_array().map(|s| s.len()) == Some(2));
+    });
+}
+
+#[test]
+fn test_cli_exit_codes() {
+    run_test(|addr: &str, home: &Path| {
+        // A wrong password is told apart from a server that isn't there.
+        let output = kanidm(
+            home,
+            &[
+                "login",
+                "-H",
+                addr,
+                "-D",
+                "admin",
+                "--password-stdin",
+                "--json",
+            ],
+        )
+        .with_stdin()
+        .buffer("wrong password\n")
+        .assert()
+        .code(2)
+        .get_output()
+        .clone();
+        let err = stdout_json(&output);
+        assert!(err["status"] == "error");
+        assert!(err["exit_code"] == 2);
+
+        kanidm(
+            home,
+            &["login", "-H", "http://127.0.0.1:1", "-D", "anonymous"],
+        )
+        .assert()
+        .code(3);
+
+        // Any other failure is an error of its own, given as json when asked.
+        let output = kanidm(
+            home,
+            &[
+                "account",
+                "get",
+                "no_such_account",
+                "-H",
+                addr,
+                "-D",
+                "admin",
+                "--password-stdin",
+                "--json",
+            ],
+        )
+        .with_stdin()
+        .buffer(format!("{}\n", ADMIN_TEST_PASSWORD))
+        .assert()
+        .code(1)
+        .get_output()
+        .clone();
+        let err = stdout_json(&output);
+        assert!(err["message"] == "account no_such_account does not exist");
+    });
+}
+
+#[test]
+fn test_cli_raw_round_trip() {
+    run_test(|addr: &str, home: &Path| {
+        let login = |args: &[&str]| {
+            let mut all = args.to_vec();
+            all.extend_from_slice(&["-H", addr, "-D", "admin", "--password-stdin"]);
+            kanidm(home, &all)
+                .with_stdin()
+                .buffer(format!("{}\n", ADMIN_TEST_PASSWORD))
+                .assert()
+                .get_output()
+                .clone()
+        };
+
+        let entries = home.join("entries.json");
+        fs::write(
+            &entries,
+            r#"[
+                {"attrs": {"class": ["group"], "name": ["raw_group"], "description": ["before"]}}
+            ]"#,
+        )
+        .unwrap();
+        let output = login(&["raw", "create", "--file", entries.to_str().unwrap()]);
+        assert!(output.status.success());
+
+        let output = login(&["raw", "search", "--filter", "(name=raw_group)", "--json"]);
+        assert!(output.status.success());
+        let found = stdout_json(&output);
+        assert!(found[0]["attrs"]["description"][0] == "before");
+
+        let modlist = home.join("modlist.json");
+        fs::write(
+            &modlist,
+            r#"{"mods": [{"Purged": "description"}, {"Present": ["description", "after"]}]}"#,
+        )
+        .unwrap();
+        let output = login(&[
+            "raw",
+            "modify",
+            "--filter",
+            r#"{"Eq": ["name", "raw_group"]}"#,
+            "--file",
+            modlist.to_str().unwrap(),
+        ]);
+        assert!(output.status.success());
+        let output = login(&["raw", "search", "--filter", "(name=raw_group)"]);
+        assert!(String::from_utf8_lossy(&output.stdout).contains("description: after"));
+
+        // A bare presence filter isn't deleted without being confirmed.
+        let output = kanidm(
+            home,
+            &[
+                "raw",
+                "delete",
+                "--filter",
+                "(class=*)",
+                "-H",
+                addr,
+                "-D",
+                "admin",
+            ],
+        )
+        .with_stdin()
+        .buffer("n\n")
+        .assert()
+        .code(1)
+        .get_output()
+        .clone();
+        assert!(String::from_utf8_lossy(&output.stdout).contains("Nothing was deleted"));
+
+        let output = login(&["raw", "delete", "--filter", "(name=raw_group)"]);
+        assert!(output.status.success());
+        let output = login(&["raw", "search", "--filter", "(name=raw_group)", "--json"]);
+        assert!(stdout_json(&output).as_array().map(|a| a.is_empty()) == Some(true));
+
+        // A malformed file is pointed at, and nothing is sent.
+        fs::write(&entries, "[\n  {\"attrs\": {\"name\": \"raw_broken\"}}\n]").unwrap();
+        let output = login(&["raw", "create", "--file", entries.to_str().unwrap()]);
+        assert!(output.status.code() == Some(1));
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.contains("entries.json:2:"));
+        assert!(stdout.contains("at [0].attrs.name"));
+    });
+}
@@ -0,0 +1,382 @@
+use crate::unix_proto::UnixId;
+use kanidm_proto::v1::{UnixGroupToken, UnixUserToken};
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkcs5::pbkdf2_hmac;
+use openssl::rand::rand_bytes;
+use rusqlite::types::ToSql;
+use rusqlite::Connection;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+// Offline passwords are only checked when a login is attempted, so don't
+// need a memory hard kdf.
+const PBKDF2_COST: usize = 10000;
+const PBKDF2_SALT_LEN: usize = 24;
+const PBKDF2_KEY_LEN: usize = 64;
+
+#[derive(Debug)]
+pub enum CacheError {
+    Sqlite(rusqlite::Error),
+    // A cached token or password that can't be read back.
+    Corrupt,
+    Crypto,
+}
+
+impl From<rusqlite::Error> for CacheError {
+    fn from(e: rusqlite::Error) -> Self {
+        CacheError::Sqlite(e)
+    }
+}
+
+// The unix password of an account, as it was at its last successful online
+// authentication, so that it can still log in when the server can't be
+// reached.
+#[derive(Serialize, Deserialize)]
+struct OfflinePassword {
+    cost: usize,
+    salt: Vec<u8>,
+    key: Vec<u8>,
+}
+
+impl OfflinePassword {
+    fn new(cleartext: &str) -> Result<Self, CacheError> {
+        let mut salt: Vec<u8> = (0..PBKDF2_SALT_LEN).map(|_| 0).collect();
+        rand_bytes(salt.as_mut_slice()).map_err(|_| CacheError::Crypto)?;
+        let key = Self::derive(cleartext, salt.as_slice(), PBKDF2_COST)?;
+        Ok(OfflinePassword {
+            cost: PBKDF2_COST,
+            salt: salt,
+            key: key,
+        })
+    }
+
+    fn derive(cleartext: &str, salt: &[u8], cost: usize) -> Result<Vec<u8>, CacheError> {
+        let mut key: Vec<u8> = (0..PBKDF2_KEY_LEN).map(|_| 0).collect();
+        pbkdf2_hmac(
+            cleartext.as_bytes(),
+            salt,
+            cost,
+            MessageDigest::sha256(),
+            key.as_mut_slice(),
+        )
+        .map_err(|_| CacheError::Crypto)?;
+        Ok(key)
+    }
+
+    fn verify(&self, cleartext: &str) -> Result<bool, CacheError> {
+        let key = Self::derive(cleartext, self.salt.as_slice(), self.cost)?;
+        Ok(key.len() == self.key.len() && memcmp::eq(key.as_slice(), self.key.as_slice()))
+    }
+}
+
+// The accounts and groups the resolver has seen, with when each should next
+// be asked for from the server, in seconds since the epoch.
+pub struct Db {
+    conn: Connection,
+}
+
+impl Db {
+    pub fn new(path: &str) -> Result<Self, CacheError> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS account_t (
+                uuid TEXT PRIMARY KEY,
+                name TEXT NOT NULL UNIQUE,
+                gidnumber INTEGER NOT NULL UNIQUE,
+                token TEXT NOT NULL,
+                password TEXT,
+                expiry INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS group_t (
+                uuid TEXT PRIMARY KEY,
+                name TEXT NOT NULL UNIQUE,
+                gidnumber INTEGER NOT NULL UNIQUE,
+                token TEXT NOT NULL,
+                expiry INTEGER NOT NULL
+            );",
+        )?;
+        Ok(Db { conn: conn })
+    }
+
+    fn get_token<T: DeserializeOwned>(
+        &self,
+        table: &str,
+        id: &UnixId,
+    ) -> Result<Option<(T, u64)>, CacheError> {
+        let (column, value): (&str, &dyn ToSql) = match id {
+            UnixId::Name(n) => ("name", n),
+            UnixId::Gid(g) => ("gidnumber", g),
+        };
+        let r = self.conn.query_row_named(
+            format!("SELECT token, expiry FROM {} WHERE {} = :id", table, column).as_str(),
+            &[(":id", value)],
+            |row| -> (String, i64) { (row.get(0), row.get(1)) },
+        );
+        match r {
+            Ok((data, expiry)) => {
+                let token = serde_json::from_str(data.as_str()).map_err(|_| CacheError::Corrupt)?;
+                Ok(Some((token, expiry.max(0) as u64)))
+            }
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(CacheError::Sqlite(e)),
+        }
+    }
+
+    fn update_token<T: Serialize>(
+        &self,
+        table: &str,
+        uuid: &str,
+        name: &str,
+        gidnumber: u32,
+        token: &T,
+        expiry: u64,
+    ) -> Result<(), CacheError> {
+        let data = serde_json::to_string(token).map_err(|_| CacheError::Corrupt)?;
+        let expiry = expiry as i64;
+        // A rename, or a gidnumber given to another entry, can leave an older
+        // row in the way.
+        self.conn.execute_named(
+            format!(
+                "DELETE FROM {} WHERE (name = :name OR gidnumber = :gidnumber) AND uuid != :uuid",
+                table
+            )
+            .as_str(),
+            &[
+                (":name", &name),
+                (":gidnumber", &gidnumber),
+                (":uuid", &uuid),
+            ],
+        )?;
+        let params: &[(&str, &dyn ToSql)] = &[
+            (":uuid", &uuid),
+            (":name", &name),
+            (":gidnumber", &gidnumber),
+            (":token", &data),
+            (":expiry", &expiry),
+        ];
+        // Updating in place keeps the offline password of an account.
+        let updated = self.conn.execute_named(
+            format!(
+                "UPDATE {} SET name = :name, gidnumber = :gidnumber, token = :token, expiry = :expiry WHERE uuid = :uuid",
+                table
+            )
+            .as_str(),
+            params,
+        )?;
+        if updated == 0 {
+            self.conn.execute_named(
+                format!(
+                    "INSERT INTO {} (uuid, name, gidnumber, token, expiry) VALUES (:uuid, :name, :gidnumber, :token, :expiry)",
+                    table
+                )
+                .as_str(),
+                params,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn delete_token(&self, table: &str, uuid: &str) -> Result<(), CacheError> {
+        self.conn.execute_named(
+            format!("DELETE FROM {} WHERE uuid = :uuid", table).as_str(),
+            &[(":uuid", &uuid)],
+        )?;
+        Ok(())
+    }
+
+    // The cached account, and when it expires.
+    pub fn get_account(&self, id: &UnixId) -> Result<Option<(UnixUserToken, u64)>, CacheError> {
+        self.get_token("account_t", id)
+    }
+
+    pub fn update_account(&self, token: &UnixUserToken, expiry: u64) -> Result<(), CacheError> {
+        self.update_token(
+            "account_t",
+            token.uuid.as_str(),
+            token.name.as_str(),
+            token.gidnumber,
+            token,
+            expiry,
+        )
+    }
+
+    pub fn delete_account(&self, uuid: &str) -> Result<(), CacheError> {
+        self.delete_token("account_t", uuid)
+    }
+
+    pub fn list_accounts(&self) -> Result<Vec<UnixUserToken>, CacheError> {
+        let mut stmt = self.conn.prepare("SELECT token FROM account_t")?;
+        let data: Vec<String> = stmt
+            .query_map(rusqlite::NO_PARAMS, |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+        data.iter()
+            .map(|d| serde_json::from_str(d.as_str()).map_err(|_| CacheError::Corrupt))
+            .collect()
+    }
+
+    // This must only be given a password the server has just accepted.
+    pub fn update_account_password(&self, uuid: &str, cred: &str) -> Result<(), CacheError> {
+        let pw = OfflinePassword::new(cred)?;
+        let data = serde_json::to_string(&pw).map_err(|_| CacheError::Corrupt)?;
+        self.conn.execute_named(
+            "UPDATE account_t SET password = :password WHERE uuid = :uuid",
+            &[(":password", &data), (":uuid", &uuid)],
+        )?;
+        Ok(())
+    }
+
+    // An account that has never authenticated online has no offline
+    // password, so can't be authenticated as.
+    pub fn check_account_password(&self, uuid: &str, cred: &str) -> Result<bool, CacheError> {
+        let r = self.conn.query_row_named(
+            "SELECT password FROM account_t WHERE uuid = :uuid",
+            &[(":uuid", &uuid)],
+            |row| -> Option<String> { row.get(0) },
+        );
+        match r {
+            Ok(Some(data)) => {
+                let pw: OfflinePassword =
+                    serde_json::from_str(data.as_str()).map_err(|_| CacheError::Corrupt)?;
+                pw.verify(cred)
+            }
+            Ok(None) | Err(rusqlite::Error::QueryReturnedNoRows) => Ok(false),
+            Err(e) => Err(CacheError::Sqlite(e)),
+        }
+    }
+
+    // The cached group, and when it expires.
+    pub fn get_group(&self, id: &UnixId) -> Result<Option<(UnixGroupToken, u64)>, CacheError> {
+        self.get_token("group_t", id)
+    }
+
+    pub fn update_group(&self, token: &UnixGroupToken, expiry: u64) -> Result<(), CacheError> {
+        self.update_token(
+            "group_t",
+            token.uuid.as_str(),
+            token.name.as_str(),
+            token.gidnumber,
+            token,
+            expiry,
+        )
+    }
+
+    pub fn delete_group(&self, uuid: &str) -> Result<(), CacheError> {
+        self.delete_token("group_t", uuid)
+    }
+
+    pub fn invalidate(&self) -> Result<(), CacheError> {
+        self.conn
+            .execute_batch("UPDATE account_t SET expiry = 0; UPDATE group_t SET expiry = 0;")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Db;
+    use crate::unix_proto::UnixId;
+    use kanidm_proto::v1::{UnixGroupToken, UnixUserToken};
+
+    fn testperson(name: &str, gidnumber: u32) -> UnixUserToken {
+        UnixUserToken {
+            name: name.to_string(),
+            displayname: "Test Person".to_string(),
+            uuid: "0c5d6bb8-fb05-4b1c-a2a3-a25ad4bd4cd6".to_string(),
+            gidnumber: gidnumber,
+            shell: Some("/bin/sh".to_string()),
+            groups: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_cache_account() {
+        let db = Db::new(":memory:").expect("Failed to open cache");
+        assert!(db
+            .get_account(&UnixId::Name("testperson".to_string()))
+            .expect("Failed to read cache")
+            .is_none());
+
+        db.update_account(&testperson("testperson", 20001), 100)
+            .expect("Failed to update cache");
+        for id in &[UnixId::Name("testperson".to_string()), UnixId::Gid(20001)] {
+            let (token, expiry) = db
+                .get_account(id)
+                .expect("Failed to read cache")
+                .expect("Missing account");
+            assert!(token.name == "testperson");
+            assert!(expiry == 100);
+        }
+
+        // A rename replaces the old name.
+        db.update_account(&testperson("renamed", 20001), 200)
+            .expect("Failed to update cache");
+        assert!(db
+            .get_account(&UnixId::Name("testperson".to_string()))
+            .expect("Failed to read cache")
+            .is_none());
+        assert!(db.list_accounts().expect("Failed to list").len() == 1);
+
+        // Invalidating expires everything, but keeps it.
+        db.invalidate().expect("Failed to invalidate");
+        let (_, expiry) = db
+            .get_account(&UnixId::Gid(20001))
+            .expect("Failed to read cache")
+            .expect("Missing account");
+        assert!(expiry == 0);
+
+        db.delete_account("0c5d6bb8-fb05-4b1c-a2a3-a25ad4bd4cd6")
+            .expect("Failed to delete");
+        assert!(db.list_accounts().expect("Failed to list").is_empty());
+    }
+
+    #[test]
+    fn test_cache_account_password() {
+        let db = Db::new(":memory:").expect("Failed to open cache");
+        let token = testperson("testperson", 20001);
+        db.update_account(&token, 100)
+            .expect("Failed to update cache");
+        assert!(!db
+            .check_account_password(token.uuid.as_str(), "password")
+            .expect("Failed to check"));
+
+        db.update_account_password(token.uuid.as_str(), "password")
+            .expect("Failed to set password");
+        assert!(db
+            .check_account_password(token.uuid.as_str(), "password")
+            .expect("Failed to check"));
+        assert!(!db
+            .check_account_password(token.uuid.as_str(), "wrong")
+            .expect("Failed to check"));
+
+        // Refreshing the account keeps its password.
+        db.update_account(&token, 200)
+            .expect("Failed to update cache");
+        assert!(db
+            .check_account_password(token.uuid.as_str(), "password")
+            .expect("Failed to check"));
+    }
+
+    #[test]
+    fn test_cache_group() {
+        let db = Db::new(":memory:").expect("Failed to open cache");
+        let token = UnixGroupToken {
+            name: "testgroup".to_string(),
+            uuid: "2b6ea6f2-4a3e-4d53-a2cd-1d70e9a8b7a1".to_string(),
+            gidnumber: 30001,
+        };
+        db.update_group(&token, 100)
+            .expect("Failed to update cache");
+        let (group, _) = db
+            .get_group(&UnixId::Gid(30001))
+            .expect("Failed to read cache")
+            .expect("Missing group");
+        assert!(group.name == "testgroup");
+        db.delete_group(token.uuid.as_str())
+            .expect("Failed to delete");
+        assert!(db
+            .get_group(&UnixId::Name("testgroup".to_string()))
+            .expect("Failed to read cache")
+            .is_none());
+    }
+}
@@ -0,0 +1,20 @@
+use crate::unix_proto::{ClientRequest, ClientResponse};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+
+fn invalid_data(e: serde_json::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+// Send a request to kanidm_unixd on the socket at path, and wait for its
+// response.
+pub fn call_daemon(path: &str, req: &ClientRequest) -> Result<ClientResponse, io::Error> {
+    let mut stream = UnixStream::connect(path)?;
+    let mut data = serde_json::to_string(req).map_err(invalid_data)?;
+    data.push('\n');
+    stream.write_all(data.as_bytes())?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    serde_json::from_str(line.as_str()).map_err(invalid_data)
+}
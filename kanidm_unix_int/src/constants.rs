@@ -0,0 +1,5 @@
+pub static DEFAULT_SOCK_PATH: &'static str = "/var/run/kanidm-unixd/sock";
+pub static DEFAULT_DB_PATH: &'static str = "/var/cache/kanidm-unixd/kanidm.cache.db";
+// How long, in seconds, a cached account or group is used before the server
+// is asked for it again.
+pub static DEFAULT_CACHE_TIMEOUT: u64 = 300;
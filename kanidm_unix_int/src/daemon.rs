@@ -0,0 +1,107 @@
+// Resolves posix accounts and groups for nss and pam on this machine, from
+// a cache that is kept so that logins still work when the server can't be
+// reached. Clients talk to it over a unix socket, with the requests in
+// kanidm_unix_common::unix_proto.
+extern crate structopt;
+use kanidm_client::KanidmClient;
+use kanidm_unix_common::cache::Db;
+use kanidm_unix_common::constants::{DEFAULT_CACHE_TIMEOUT, DEFAULT_DB_PATH, DEFAULT_SOCK_PATH};
+use kanidm_unix_common::resolver::{serve, Resolver};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use structopt::StructOpt;
+extern crate env_logger;
+#[macro_use]
+extern crate log;
+
+#[derive(Debug, StructOpt)]
+struct UnixdOpt {
+    #[structopt(short = "d", long = "debug")]
+    debug: bool,
+    #[structopt(short = "H", long = "url")]
+    addr: String,
+    #[structopt(parse(from_os_str), short = "C", long = "ca")]
+    ca_path: Option<PathBuf>,
+    // The service account to resolve as, which must be in
+    // idm_unix_auth_servers to check passwords. Its api token is read from
+    // the file given, so it isn't in the process list.
+    #[structopt(short = "D", long = "name")]
+    username: Option<String>,
+    #[structopt(parse(from_os_str), short = "T", long = "token-file")]
+    token_path: Option<PathBuf>,
+    #[structopt(long = "db")]
+    db_path: Option<String>,
+    #[structopt(long = "socket")]
+    sock_path: Option<String>,
+    // How long, in seconds, a cached account or group is used for.
+    #[structopt(long = "cache-timeout")]
+    cache_timeout: Option<u64>,
+}
+
+fn main() {
+    let opt = UnixdOpt::from_args();
+
+    if opt.debug {
+        ::std::env::set_var("RUST_LOG", "kanidm_unix_common=debug,kanidm_client=debug");
+    } else {
+        ::std::env::set_var("RUST_LOG", "kanidm_unix_common=info,kanidm_client=info");
+    }
+    env_logger::init();
+
+    let auth = match (&opt.username, &opt.token_path) {
+        (Some(name), Some(p)) => match fs::read_to_string(p) {
+            Ok(token) => Some((name.clone(), token.trim().to_string())),
+            Err(e) => {
+                error!("Error reading {:?}: {:?}", p, e);
+                std::process::exit(1);
+            }
+        },
+        (None, None) => None,
+        _ => {
+            error!("A service account name and token file must be given together");
+            std::process::exit(1);
+        }
+    };
+
+    let ca_path: Option<&str> = opt.ca_path.as_ref().map(|p| p.to_str().unwrap());
+    let client = KanidmClient::new(opt.addr.as_str(), ca_path);
+
+    let db_path = opt
+        .db_path
+        .as_ref()
+        .map(|s| s.as_str())
+        .unwrap_or(DEFAULT_DB_PATH);
+    let db = Db::new(db_path).unwrap_or_else(|e| {
+        error!("Error opening cache {}: {:?}", db_path, e);
+        std::process::exit(1);
+    });
+    let resolver = Resolver::new(
+        client,
+        auth,
+        db,
+        opt.cache_timeout.unwrap_or(DEFAULT_CACHE_TIMEOUT),
+    );
+
+    // A socket left by an earlier run would stop us binding.
+    let sock_path = opt
+        .sock_path
+        .as_ref()
+        .map(|s| s.as_str())
+        .unwrap_or(DEFAULT_SOCK_PATH);
+    let _ = fs::remove_file(sock_path);
+    let listener = UnixListener::bind(sock_path).unwrap_or_else(|e| {
+        error!("Error binding {}: {:?}", sock_path, e);
+        std::process::exit(1);
+    });
+    // nss asks from within every process, whoever it runs as.
+    if let Err(e) = fs::set_permissions(sock_path, fs::Permissions::from_mode(0o777)) {
+        error!("Error setting permissions of {}: {:?}", sock_path, e);
+        std::process::exit(1);
+    }
+
+    info!("Listening on {}", sock_path);
+    serve(listener, Arc::new(Mutex::new(resolver)));
+}
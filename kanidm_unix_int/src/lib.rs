@@ -0,0 +1,13 @@
+#![deny(warnings)]
+#![warn(unused_extern_crates)]
+
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate log;
+
+pub mod cache;
+pub mod client;
+pub mod constants;
+pub mod resolver;
+pub mod unix_proto;
@@ -0,0 +1,289 @@
+use crate::cache::{CacheError, Db};
+use crate::unix_proto::{ClientRequest, ClientResponse, ResolverStatus, UnixId};
+use kanidm_client::{ClientError, KanidmClient};
+use kanidm_proto::v1::{Filter, UnixGroupToken, UnixUserToken};
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::SystemTime;
+
+fn current_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Clock failure!")
+        .as_secs()
+}
+
+// The uuid of the posix entry of class with this gidnumber. Only its uuid is
+// read, and the token is then asked for as with a name.
+fn uuid_of_gid(
+    client: &KanidmClient,
+    class: &str,
+    gid: u32,
+) -> Result<Option<String>, ClientError> {
+    let entries = client.search(Filter::And(vec![
+        Filter::Eq("class".to_string(), class.to_string()),
+        Filter::Eq("gidnumber".to_string(), gid.to_string()),
+    ]))?;
+    Ok(entries
+        .first()
+        .and_then(|e| e.get_ava_single("uuid"))
+        .map(|u| u.to_string()))
+}
+
+// The server answers with an error for an entry that doesn't exist, or
+// that isn't posix, and we don't need to tell these apart.
+fn not_found<T>(r: Result<T, ClientError>) -> Result<Option<T>, ClientError> {
+    match r {
+        Ok(t) => Ok(Some(t)),
+        Err(ClientError::NotFound)
+        | Err(ClientError::AccessDenied)
+        | Err(ClientError::Operation(_, _))
+        | Err(ClientError::Http(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn get_account_online(
+    client: &KanidmClient,
+    id: &UnixId,
+) -> Result<Option<UnixUserToken>, ClientError> {
+    match id {
+        UnixId::Name(n) => not_found(client.idm_account_unix_token_get(n.as_str())),
+        UnixId::Gid(g) => match uuid_of_gid(client, "posixaccount", *g)? {
+            Some(u) => not_found(client.idm_account_unix_token_get(u.as_str())),
+            None => Ok(None),
+        },
+    }
+}
+
+fn get_group_online(
+    client: &KanidmClient,
+    id: &UnixId,
+) -> Result<Option<UnixGroupToken>, ClientError> {
+    match id {
+        UnixId::Name(n) => not_found(client.idm_group_unix_token_get(n.as_str())),
+        UnixId::Gid(g) => match uuid_of_gid(client, "posixgroup", *g)? {
+            Some(u) => not_found(client.idm_group_unix_token_get(u.as_str())),
+            None => Ok(None),
+        },
+    }
+}
+
+// Answers lookups for nss and pam from the cache, asking the server for
+// what isn't cached or has expired.
+pub struct Resolver {
+    client: KanidmClient,
+    // The service account to authenticate as, and its api token. Without one
+    // the resolver reads as anonymous, and can't check passwords online.
+    auth: Option<(String, String)>,
+    authenticated: bool,
+    db: Db,
+    // How long, in seconds, a cached entry is used for.
+    timeout: u64,
+    status: ResolverStatus,
+}
+
+impl Resolver {
+    pub fn new(client: KanidmClient, auth: Option<(String, String)>, db: Db, timeout: u64) -> Self {
+        Resolver {
+            client: client,
+            auth: auth,
+            authenticated: false,
+            db: db,
+            timeout: timeout,
+            status: ResolverStatus::Online,
+        }
+    }
+
+    // As of the last time the server was asked for anything.
+    pub fn status(&self) -> ResolverStatus {
+        self.status
+    }
+
+    fn ensure_auth(&mut self) -> Result<(), ClientError> {
+        if self.authenticated {
+            return Ok(());
+        }
+        match &self.auth {
+            Some((name, token)) => self
+                .client
+                .auth_api_token(name.as_str(), token.as_str())
+                .map(|_| ()),
+            None => self.client.auth_anonymous().map(|_| ()),
+        }?;
+        self.authenticated = true;
+        Ok(())
+    }
+
+    // Ask the server, authenticating first if we haven't, and again if our
+    // session has ended. None means the server couldn't be reached, and the
+    // resolver is degraded until it can be.
+    fn ask<T, F>(&mut self, f: F) -> Option<Result<T, ClientError>>
+    where
+        F: Fn(&KanidmClient) -> Result<T, ClientError>,
+    {
+        let r = match self.ensure_auth().and_then(|_| f(&self.client)) {
+            Err(ClientError::Unauthorized) => {
+                self.authenticated = false;
+                self.ensure_auth().and_then(|_| f(&self.client))
+            }
+            r => r,
+        };
+        match r {
+            Err(ClientError::Transport(e)) => {
+                warn!("kanidm server can't be reached, using cache -> {:?}", e);
+                self.authenticated = false;
+                self.status = ResolverStatus::Degraded;
+                None
+            }
+            r => {
+                self.status = ResolverStatus::Online;
+                Some(r)
+            }
+        }
+    }
+
+    pub fn get_account(&mut self, id: &UnixId) -> Result<Option<UnixUserToken>, CacheError> {
+        let ct = current_time();
+        let cached = self.db.get_account(id)?;
+        if let Some((token, expiry)) = &cached {
+            if ct < *expiry {
+                return Ok(Some(token.clone()));
+            }
+        }
+
+        match self.ask(|c| get_account_online(c, id)) {
+            Some(Ok(Some(token))) => {
+                self.db.update_account(&token, ct + self.timeout)?;
+                Ok(Some(token))
+            }
+            Some(Ok(None)) => {
+                if let Some((token, _)) = cached {
+                    debug!("{} is no longer a posix account", token.name);
+                    self.db.delete_account(token.uuid.as_str())?;
+                }
+                Ok(None)
+            }
+            Some(Err(e)) => {
+                error!("Error reading account {:?} -> {:?}", id, e);
+                Ok(cached.map(|(token, _)| token))
+            }
+            None => Ok(cached.map(|(token, _)| token)),
+        }
+    }
+
+    pub fn get_group(&mut self, id: &UnixId) -> Result<Option<UnixGroupToken>, CacheError> {
+        let ct = current_time();
+        let cached = self.db.get_group(id)?;
+        if let Some((token, expiry)) = &cached {
+            if ct < *expiry {
+                return Ok(Some(token.clone()));
+            }
+        }
+
+        match self.ask(|c| get_group_online(c, id)) {
+            Some(Ok(Some(token))) => {
+                self.db.update_group(&token, ct + self.timeout)?;
+                Ok(Some(token))
+            }
+            Some(Ok(None)) => {
+                if let Some((token, _)) = cached {
+                    debug!("{} is no longer a posix group", token.name);
+                    self.db.delete_group(token.uuid.as_str())?;
+                }
+                Ok(None)
+            }
+            Some(Err(e)) => {
+                error!("Error reading group {:?} -> {:?}", id, e);
+                Ok(cached.map(|(token, _)| token))
+            }
+            None => Ok(cached.map(|(token, _)| token)),
+        }
+    }
+
+    pub fn list_accounts(&self) -> Result<Vec<UnixUserToken>, CacheError> {
+        self.db.list_accounts()
+    }
+
+    // Online, the server checks the password, and if it's right it is kept
+    // for offline use. Offline, only a password kept this way is accepted.
+    pub fn authenticate(&mut self, account: &str, cred: &str) -> Result<bool, CacheError> {
+        let ct = current_time();
+        match self.ask(|c| c.idm_account_unix_cred_verify(account, cred)) {
+            Some(Ok(Some(token))) => {
+                self.db.update_account(&token, ct + self.timeout)?;
+                self.db.update_account_password(token.uuid.as_str(), cred)?;
+                Ok(true)
+            }
+            Some(Ok(None)) => Ok(false),
+            Some(Err(e)) => {
+                error!("Error authenticating {} -> {:?}", account, e);
+                Ok(false)
+            }
+            None => match self.db.get_account(&UnixId::Name(account.to_string()))? {
+                Some((token, _)) => self.db.check_account_password(token.uuid.as_str(), cred),
+                None => Ok(false),
+            },
+        }
+    }
+
+    pub fn invalidate(&self) -> Result<(), CacheError> {
+        self.db.invalidate()
+    }
+
+    pub fn handle(&mut self, req: ClientRequest) -> ClientResponse {
+        let r = match req {
+            ClientRequest::GetAccount(id) => self.get_account(&id).map(ClientResponse::Account),
+            ClientRequest::GetGroup(id) => self.get_group(&id).map(ClientResponse::Group),
+            ClientRequest::ListAccounts => self.list_accounts().map(ClientResponse::Accounts),
+            ClientRequest::Authenticate { account, cred } => self
+                .authenticate(account.as_str(), cred.as_str())
+                .map(ClientResponse::Authenticated),
+            ClientRequest::InvalidateCache => self.invalidate().map(|_| ClientResponse::Ok),
+            ClientRequest::Status => Ok(ClientResponse::Status(self.status)),
+        };
+        r.unwrap_or_else(|e| {
+            error!("Cache error -> {:?}", e);
+            ClientResponse::Error
+        })
+    }
+}
+
+fn handle_client(stream: UnixStream, resolver: &Mutex<Resolver>) -> Result<(), std::io::Error> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    // The request isn't logged, as it may hold a password.
+    let resp = match serde_json::from_str::<ClientRequest>(line.as_str()) {
+        Ok(req) => resolver.lock().expect("Resolver poisoned").handle(req),
+        Err(e) => {
+            error!("Invalid request -> {:?}", e);
+            ClientResponse::Error
+        }
+    };
+    let mut data = serde_json::to_string(&resp)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    data.push('\n');
+    (&stream).write_all(data.as_bytes())
+}
+
+// Answer clients on the socket, one thread each. Requests are answered one
+// at a time, as they share the cache and the session with the server.
+pub fn serve(listener: UnixListener, resolver: Arc<Mutex<Resolver>>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let resolver = resolver.clone();
+                thread::spawn(move || {
+                    if let Err(e) = handle_client(stream, &resolver) {
+                        error!("Error answering client -> {:?}", e);
+                    }
+                });
+            }
+            Err(e) => error!("Error accepting client -> {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,45 @@
+// Ask kanidm_unixd about its state, or have it refresh its cache.
+extern crate structopt;
+use kanidm_unix_common::client::call_daemon;
+use kanidm_unix_common::constants::DEFAULT_SOCK_PATH;
+use kanidm_unix_common::unix_proto::{ClientRequest, ClientResponse};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+struct SocketOpt {
+    #[structopt(long = "socket")]
+    sock_path: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+enum UnixOpt {
+    #[structopt(name = "status")]
+    Status(SocketOpt),
+    #[structopt(name = "invalidate")]
+    Invalidate(SocketOpt),
+}
+
+fn main() {
+    let (sopt, req) = match UnixOpt::from_args() {
+        UnixOpt::Status(sopt) => (sopt, ClientRequest::Status),
+        UnixOpt::Invalidate(sopt) => (sopt, ClientRequest::InvalidateCache),
+    };
+    let sock_path = sopt
+        .sock_path
+        .as_ref()
+        .map(|s| s.as_str())
+        .unwrap_or(DEFAULT_SOCK_PATH);
+
+    match call_daemon(sock_path, &req) {
+        Ok(ClientResponse::Status(status)) => println!("{:?}", status),
+        Ok(ClientResponse::Ok) => println!("Cache invalidated"),
+        Ok(r) => {
+            println!("Error: unexpected response {:?}", r);
+            std::process::exit(1);
+        }
+        Err(e) => {
+            println!("Error: {:?}", e);
+            std::process::exit(1);
+        }
+    }
+}
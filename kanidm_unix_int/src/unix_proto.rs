@@ -0,0 +1,47 @@
+use kanidm_proto::v1::{UnixGroupToken, UnixUserToken};
+
+// How nss names an account or group - by name, or by gidnumber as for
+// getpwuid and getgrgid. An account is its own primary group, so its uid is
+// its gidnumber.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum UnixId {
+    Name(String),
+    Gid(u32),
+}
+
+// A request to kanidm_unixd over its socket. Each connection carries one
+// request and its response, as a line of json each.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ClientRequest {
+    GetAccount(UnixId),
+    GetGroup(UnixId),
+    // The accounts in the cache, for nss to enumerate. The server isn't
+    // asked, as it may hold far more than this machine has seen.
+    ListAccounts,
+    // Check the unix password of an account, by name, for pam.
+    Authenticate { account: String, cred: String },
+    // Expire every cached account and group, so that the server is asked
+    // for each when it is next looked up. Offline passwords are kept.
+    InvalidateCache,
+    Status,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ClientResponse {
+    Account(Option<UnixUserToken>),
+    Group(Option<UnixGroupToken>),
+    Accounts(Vec<UnixUserToken>),
+    Authenticated(bool),
+    Status(ResolverStatus),
+    Ok,
+    Error,
+}
+
+// The resolver is online while the server answers it. When the server
+// can't be reached it is degraded, and answers from the cache alone, even
+// with entries past their ttl.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum ResolverStatus {
+    Online,
+    Degraded,
+}
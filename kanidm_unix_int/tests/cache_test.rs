@@ -0,0 +1,239 @@
+#![deny(warnings)]
+
+extern crate actix;
+use actix::prelude::*;
+
+use kanidm::config::{Configuration, IntegrationTestConfig};
+use kanidm::core::create_server_core;
+use kanidm_client::KanidmClient;
+use kanidm_proto::v1::{Entry, Filter, Modify, ModifyList};
+use kanidm_unix_common::cache::Db;
+use kanidm_unix_common::client::call_daemon;
+use kanidm_unix_common::resolver::{serve, Resolver};
+use kanidm_unix_common::unix_proto::{ClientRequest, ClientResponse, ResolverStatus, UnixId};
+
+use std::os::unix::net::UnixListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+static PORT_ALLOC: AtomicUsize = AtomicUsize::new(18080);
+static ADMIN_TEST_PASSWORD: &'static str = "integration test admin password";
+static TESTPERSON_PASSWORD: &'static str = "eicieY7ahchaoCh0eeTa";
+// Nothing listens here, so the server is unreachable.
+static DEAD_ADDR: &'static str = "http://127.0.0.1:1";
+
+// As in the client tests, but the test is also given where the server is,
+// and the path of a cache of its own.
+fn run_test(test_fn: fn(KanidmClient, &str, &str) -> ()) {
+    let _ = env_logger::builder().is_test(true).try_init();
+    let (tx, rx) = mpsc::channel();
+    let port = PORT_ALLOC.fetch_add(1, Ordering::SeqCst);
+
+    let int_config = Box::new(IntegrationTestConfig {
+        admin_password: ADMIN_TEST_PASSWORD.to_string(),
+    });
+
+    let mut config = Configuration::new();
+    config.address = format!("127.0.0.1:{}", port);
+    config.secure_cookies = false;
+    config.integration_test_config = Some(int_config);
+
+    thread::spawn(move || {
+        System::run(move || {
+            create_server_core(config);
+            let _ = tx.send(System::current());
+        });
+    });
+    let sys = rx.recv().unwrap();
+    System::set_current(sys.clone());
+
+    let addr = format!("http://127.0.0.1:{}", port);
+    let rsclient = KanidmClient::new(addr.as_str(), None);
+
+    let db_path = std::env::temp_dir().join(format!("kanidm_unixd_test_{}.db", port));
+    let _ = std::fs::remove_file(&db_path);
+    test_fn(rsclient, addr.as_str(), db_path.to_str().unwrap());
+    let _ = std::fs::remove_file(&db_path);
+
+    let _ = sys.stop();
+}
+
+// testperson and testnoauth are posix accounts, and only testperson has a
+// unix password. testunixd is a service account that may check passwords,
+// and its api token is returned.
+fn setup_posix(rsclient: &KanidmClient) -> String {
+    rsclient
+        .auth_simple_password("admin", ADMIN_TEST_PASSWORD)
+        .expect("Failed to auth");
+    let es: Vec<Entry> = vec![
+        r#"{"attrs": {"class": ["person", "account"], "name": ["testperson"], "displayname": ["testperson"]}}"#,
+        r#"{"attrs": {"class": ["person", "account"], "name": ["testnoauth"], "displayname": ["testnoauth"]}}"#,
+        r#"{"attrs": {"class": ["object", "account", "service_account"], "name": ["testunixd"], "displayname": ["Test Unixd"]}}"#,
+    ]
+    .into_iter()
+    .map(|s| serde_json::from_str(s).unwrap())
+    .collect();
+    assert!(rsclient.create(es).is_ok());
+    assert!(rsclient
+        .idm_account_unix_extend("testperson", Some(20001), None)
+        .is_ok());
+    assert!(rsclient
+        .idm_account_unix_extend("testnoauth", Some(20002), None)
+        .is_ok());
+    assert!(rsclient
+        .idm_account_unix_cred_put("testperson", TESTPERSON_PASSWORD)
+        .is_ok());
+    assert!(rsclient
+        .modify(
+            Filter::Eq("name".to_string(), "idm_unix_auth_servers".to_string()),
+            ModifyList::new_list(vec![Modify::Present(
+                "member".to_string(),
+                "testunixd".to_string()
+            )]),
+            false
+        )
+        .is_ok());
+    rsclient
+        .service_account_api_token_generate("testunixd", "unixd", None, false)
+        .expect("Failed to generate api token")
+        .token
+}
+
+fn resolver(addr: &str, token: &str, db_path: &str, timeout: u64) -> Resolver {
+    let db = Db::new(db_path).expect("Failed to open cache");
+    Resolver::new(
+        KanidmClient::new(addr, None),
+        Some(("testunixd".to_string(), token.to_string())),
+        db,
+        timeout,
+    )
+}
+
+#[test]
+fn test_cache_online() {
+    run_test(|rsclient: KanidmClient, addr: &str, db_path: &str| {
+        let token = setup_posix(&rsclient);
+        let mut r = resolver(addr, token.as_str(), db_path, 300);
+
+        for id in &[UnixId::Name("testperson".to_string()), UnixId::Gid(20001)] {
+            let ut = r
+                .get_account(id)
+                .expect("Failed to resolve")
+                .expect("Missing account");
+            assert!(ut.name == "testperson");
+            assert!(ut.gidnumber == 20001);
+        }
+        assert!(r
+            .get_account(&UnixId::Name("admin".to_string()))
+            .expect("Failed to resolve")
+            .is_none());
+        assert!(r
+            .get_group(&UnixId::Name("idm_admins".to_string()))
+            .expect("Failed to resolve")
+            .is_none());
+        assert!(r.status() == ResolverStatus::Online);
+        assert!(r.list_accounts().expect("Failed to list").len() == 1);
+
+        assert!(r
+            .authenticate("testperson", TESTPERSON_PASSWORD)
+            .expect("Failed to authenticate"));
+        assert!(!r
+            .authenticate("testperson", "not the password")
+            .expect("Failed to authenticate"));
+        assert!(!r
+            .authenticate("testnoauth", TESTPERSON_PASSWORD)
+            .expect("Failed to authenticate"));
+    });
+}
+
+// When the server can't be reached, expired entries are still given and
+// passwords from earlier online auths still work, but the resolver is
+// degraded.
+#[test]
+fn test_cache_offline() {
+    run_test(|rsclient: KanidmClient, addr: &str, db_path: &str| {
+        let token = setup_posix(&rsclient);
+        {
+            let mut r = resolver(addr, token.as_str(), db_path, 0);
+            assert!(r
+                .authenticate("testperson", TESTPERSON_PASSWORD)
+                .expect("Failed to authenticate"));
+            assert!(r
+                .get_account(&UnixId::Name("testnoauth".to_string()))
+                .expect("Failed to resolve")
+                .is_some());
+        }
+
+        let mut r = resolver(DEAD_ADDR, token.as_str(), db_path, 0);
+        let ut = r
+            .get_account(&UnixId::Gid(20001))
+            .expect("Failed to resolve")
+            .expect("Missing account");
+        assert!(ut.name == "testperson");
+        assert!(r.status() == ResolverStatus::Degraded);
+        assert!(r.list_accounts().expect("Failed to list").len() == 2);
+        assert!(r
+            .get_account(&UnixId::Name("neverseen".to_string()))
+            .expect("Failed to resolve")
+            .is_none());
+
+        assert!(r
+            .authenticate("testperson", TESTPERSON_PASSWORD)
+            .expect("Failed to authenticate"));
+        assert!(!r
+            .authenticate("testperson", "not the password")
+            .expect("Failed to authenticate"));
+        // Only an online auth keeps a password for offline use.
+        assert!(!r
+            .authenticate("testnoauth", TESTPERSON_PASSWORD)
+            .expect("Failed to authenticate"));
+
+        // Once the server answers again, so is the resolver.
+        let mut r = resolver(addr, token.as_str(), db_path, 0);
+        assert!(r
+            .get_account(&UnixId::Name("testperson".to_string()))
+            .expect("Failed to resolve")
+            .is_some());
+        assert!(r.status() == ResolverStatus::Online);
+    });
+}
+
+#[test]
+fn test_cache_socket() {
+    run_test(|rsclient: KanidmClient, addr: &str, db_path: &str| {
+        let token = setup_posix(&rsclient);
+        let sock_path = format!("{}.sock", db_path);
+        let _ = std::fs::remove_file(sock_path.as_str());
+        let listener = UnixListener::bind(sock_path.as_str()).expect("Failed to bind");
+        let r = Arc::new(Mutex::new(resolver(addr, token.as_str(), db_path, 300)));
+        thread::spawn(move || serve(listener, r));
+
+        match call_daemon(
+            sock_path.as_str(),
+            &ClientRequest::GetAccount(UnixId::Name("testperson".to_string())),
+        ) {
+            Ok(ClientResponse::Account(Some(ut))) => assert!(ut.gidnumber == 20001),
+            _ => panic!(),
+        }
+        match call_daemon(
+            sock_path.as_str(),
+            &ClientRequest::Authenticate {
+                account: "testperson".to_string(),
+                cred: TESTPERSON_PASSWORD.to_string(),
+            },
+        ) {
+            Ok(ClientResponse::Authenticated(true)) => {}
+            _ => panic!(),
+        }
+        match call_daemon(sock_path.as_str(), &ClientRequest::InvalidateCache) {
+            Ok(ClientResponse::Ok) => {}
+            _ => panic!(),
+        }
+        match call_daemon(sock_path.as_str(), &ClientRequest::Status) {
+            Ok(ClientResponse::Status(ResolverStatus::Online)) => {}
+            _ => panic!(),
+        }
+        let _ = std::fs::remove_file(sock_path.as_str());
+    });
+}
@@ -16,6 +16,8 @@
 //
 
 use concread::cowcell::{CowCell, CowCellReadTxn, CowCellWriteTxn};
+use kanidm_proto::v1::AccessCheckOperation;
+use kanidm_proto::v1::EffectiveAccess;
 use kanidm_proto::v1::Filter as ProtoFilter;
 use kanidm_proto::v1::OperationError;
 use std::collections::{BTreeMap, BTreeSet};
@@ -28,7 +30,9 @@ use crate::modify::Modify;
 use crate::server::{QueryServerTransaction, QueryServerWriteTransaction};
 use crate::value::PartialValue;
 
-use crate::event::{CreateEvent, DeleteEvent, EventOrigin, ModifyEvent, SearchEvent};
+use crate::event::{
+    CompareEvent, CreateEvent, DeleteEvent, Event, EventOrigin, ModifyEvent, SearchEvent,
+};
 
 lazy_static! {
     static ref CLASS_ACS: PartialValue = PartialValue::new_class("access_control_search");
@@ -36,6 +40,7 @@ lazy_static! {
     static ref CLASS_ACD: PartialValue = PartialValue::new_class("access_control_delete");
     static ref CLASS_ACM: PartialValue = PartialValue::new_class("access_control_modify");
     static ref CLASS_ACP: PartialValue = PartialValue::new_class("access_control_profile");
+    static ref CLASS_ACCMP: PartialValue = PartialValue::new_class("access_control_compare");
 }
 
 // =========================================================================
@@ -263,6 +268,58 @@ impl AccessControlModify {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct AccessControlCompare {
+    acp: AccessControlProfile,
+    attrs: Vec<String>,
+}
+
+impl AccessControlCompare {
+    pub fn try_from(
+        audit: &mut AuditScope,
+        qs: &QueryServerWriteTransaction,
+        value: &Entry<EntryValid, EntryCommitted>,
+    ) -> Result<Self, OperationError> {
+        if !value.attribute_value_pres("class", &CLASS_ACCMP) {
+            audit_log!(audit, "class access_control_compare not present.");
+            return Err(OperationError::InvalidACPState(
+                "Missing access_control_compare",
+            ));
+        }
+
+        let attrs = try_audit!(
+            audit,
+            value
+                .get_ava_string("acp_compare_attr")
+                .ok_or(OperationError::InvalidACPState("Missing acp_compare_attr"))
+        );
+
+        Ok(AccessControlCompare {
+            acp: AccessControlProfile::try_from(audit, qs, value)?,
+            attrs: attrs,
+        })
+    }
+
+    #[cfg(test)]
+    unsafe fn from_raw(
+        name: &str,
+        uuid: &str,
+        receiver: Filter<FilterValid>,
+        targetscope: Filter<FilterValid>,
+        attrs: &str,
+    ) -> Self {
+        AccessControlCompare {
+            acp: AccessControlProfile {
+                name: name.to_string(),
+                uuid: Uuid::parse_str(uuid).unwrap(),
+                receiver: receiver,
+                targetscope: targetscope,
+            },
+            attrs: attrs.split_whitespace().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 struct AccessControlProfile {
     name: String,
@@ -310,7 +367,10 @@ impl AccessControlProfile {
                 .ok_or(OperationError::InvalidACPState("Missing acp_targetscope"))
         );
 
-        let receiver_i = try_audit!(audit, Filter::from_rw(audit, &receiver_f, qs));
+        // These are stored in the database, so they are internal and not subject to
+        // the filter limits of client requests.
+        let ev = Event::from_internal();
+        let receiver_i = try_audit!(audit, Filter::from_rw(audit, &ev, &receiver_f, qs));
         let receiver = try_audit!(
             audit,
             receiver_i
@@ -318,7 +378,7 @@ impl AccessControlProfile {
                 .map_err(|e| OperationError::SchemaViolation(e))
         );
 
-        let targetscope_i = try_audit!(audit, Filter::from_rw(audit, &targetscope_f, qs));
+        let targetscope_i = try_audit!(audit, Filter::from_rw(audit, &ev, &targetscope_f, qs));
         let targetscope = try_audit!(
             audit,
             targetscope_i
@@ -346,6 +406,7 @@ pub struct AccessControlsInner {
     acps_create: BTreeMap<Uuid, AccessControlCreate>,
     acps_modify: BTreeMap<Uuid, AccessControlModify>,
     acps_delete: BTreeMap<Uuid, AccessControlDelete>,
+    acps_compare: BTreeMap<Uuid, AccessControlCompare>,
 }
 
 impl AccessControlsInner {
@@ -355,6 +416,7 @@ impl AccessControlsInner {
             acps_create: BTreeMap::new(),
             acps_modify: BTreeMap::new(),
             acps_delete: BTreeMap::new(),
+            acps_compare: BTreeMap::new(),
         }
     }
 }
@@ -367,29 +429,14 @@ pub trait AccessControlsTransaction {
     fn get_inner(&self) -> &AccessControlsInner;
 
     // Contains all the way to eval acps to entries
-    fn search_filter_entries(
-        &self,
+    // The search acps whose receiver matches the identity of the event.
+    fn search_related_acp<'a>(
+        &'a self,
         audit: &mut AuditScope,
-        se: &SearchEvent,
-        entries: Vec<Entry<EntryValid, EntryCommitted>>,
-    ) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError> {
-        audit_log!(audit, "Access check for event: {:?}", se);
-
-        // If this is an internal search, return our working set.
-        let rec_entry: &Entry<EntryValid, EntryCommitted> = match &se.event.origin {
-            EventOrigin::Internal => {
-                audit_log!(audit, "Internal operation, bypassing access check");
-                // No need to check ACS
-                return Ok(entries);
-            }
-            EventOrigin::User(e) => &e,
-        };
-
-        // Some useful references we'll use for the remainder of the operation
-        let state = self.get_inner();
-
-        // First get the set of acps that apply to this receiver
-        let related_acp: Vec<&AccessControlSearch> = state
+        ev: &Event,
+        rec_entry: &Entry<EntryValid, EntryCommitted>,
+    ) -> Vec<&'a AccessControlSearch> {
+        self.get_inner()
             .acps_search
             .iter()
             .filter_map(|(_, acs)| {
@@ -409,7 +456,7 @@ pub trait AccessControlsTransaction {
                 // such that it takes an entry, rather than an event, but that
                 // would create issues in search.
                 let f_val = acs.acp.receiver.clone();
-                match f_val.resolve(&se.event) {
+                match f_val.resolve(ev) {
                     Ok(f_res) => {
                         if rec_entry.entry_match_no_index(&f_res) {
                             Some(acs)
@@ -427,7 +474,74 @@ pub trait AccessControlsTransaction {
                     }
                 }
             })
-            .collect();
+            .collect()
+    }
+
+    // The attributes of e that the related search acps allow to be read.
+    fn search_allowed_attrs<'a>(
+        &self,
+        audit: &mut AuditScope,
+        ev: &Event,
+        related_acp: &[&'a AccessControlSearch],
+        e: &Entry<EntryValid, EntryCommitted>,
+    ) -> BTreeSet<&'a str> {
+        related_acp
+            .iter()
+            .filter_map(|acs| {
+                let f_val = acs.acp.targetscope.clone();
+                match f_val.resolve(ev) {
+                    Ok(f_res) => {
+                        // if it applies
+                        if e.entry_match_no_index(&f_res) {
+                            audit_log!(audit, "entry {:?} matches acs {:?}", e.get_uuid(), acs);
+                            // add search_attrs to allowed.
+                            let r: Vec<&str> = acs.attrs.iter().map(|s| s.as_str()).collect();
+                            Some(r)
+                        } else {
+                            audit_log!(
+                                audit,
+                                "entry {:?} DOES NOT match acs {:?}",
+                                e.get_uuid(),
+                                acs
+                            );
+                            None
+                        }
+                    }
+                    Err(e) => {
+                        audit_log!(
+                            audit,
+                            "A internal filter was passed for resolution!?!? {:?}",
+                            e
+                        );
+                        None
+                    }
+                }
+            })
+            .flatten()
+            .collect()
+    }
+
+    // Contains all the way to eval acps to entries
+    fn search_filter_entries(
+        &self,
+        audit: &mut AuditScope,
+        se: &SearchEvent,
+        entries: Vec<Entry<EntryValid, EntryCommitted>>,
+    ) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError> {
+        audit_log!(audit, "Access check for event: {:?}", se);
+
+        // If this is an internal search, return our working set.
+        let rec_entry: &Entry<EntryValid, EntryCommitted> = match &se.event.origin {
+            EventOrigin::Internal => {
+                audit_log!(audit, "Internal operation, bypassing access check");
+                // No need to check ACS
+                return Ok(entries);
+            }
+            EventOrigin::User(e) => &e,
+        };
+
+        // First get the set of acps that apply to this receiver
+        let related_acp = self.search_related_acp(audit, &se.event, rec_entry);
 
         audit_log!(audit, "Related acs -> {:?}", related_acp);
 
@@ -440,46 +554,7 @@ pub trait AccessControlsTransaction {
             .into_iter()
             .filter(|e| {
                 // For each acp
-                let allowed_attrs: BTreeSet<&str> = related_acp
-                    .iter()
-                    .filter_map(|acs| {
-                        let f_val = acs.acp.targetscope.clone();
-                        match f_val.resolve(&se.event) {
-                            Ok(f_res) => {
-                                // if it applies
-                                if e.entry_match_no_index(&f_res) {
-                                    audit_log!(
-                                        audit,
-                                        "entry {:?} matches acs {:?}",
-                                        e.get_uuid(),
-                                        acs
-                                    );
-                                    // add search_attrs to allowed.
-                                    let r: Vec<&str> =
-                                        acs.attrs.iter().map(|s| s.as_str()).collect();
-                                    Some(r)
-                                } else {
-                                    audit_log!(
-                                        audit,
-                                        "entry {:?} DOES NOT match acs {:?}",
-                                        e.get_uuid(),
-                                        acs
-                                    );
-                                    None
-                                }
-                            }
-                            Err(e) => {
-                                audit_log!(
-                                    audit,
-                                    "A internal filter was passed for resolution!?!? {:?}",
-                                    e
-                                );
-                                None
-                            }
-                        }
-                    })
-                    .flatten()
-                    .collect();
+                let allowed_attrs = self.search_allowed_attrs(audit, &se.event, &related_acp, e);
 
                 audit_log!(audit, "-- for entry         --> {:?}", e.get_uuid());
                 audit_log!(audit, "allowed attributes   --> {:?}", allowed_attrs);
@@ -504,6 +579,27 @@ pub trait AccessControlsTransaction {
         se: &SearchEvent,
         entries: Vec<Entry<EntryValid, EntryCommitted>>,
     ) -> Result<Vec<Entry<EntryReduced, EntryCommitted>>, OperationError> {
+        let mut allowed_entries = Vec::with_capacity(entries.len());
+        self.search_filter_entry_attributes_each(audit, se, entries, &mut |_, e| {
+            allowed_entries.push(e);
+            Ok(())
+        })?;
+        Ok(allowed_entries)
+    }
+
+    // As search_filter_entry_attributes, but each entry is given to f as
+    // soon as it's reduced, so the reduced set is never held at once. If f
+    // fails, no more entries are reduced.
+    fn search_filter_entry_attributes_each(
+        &self,
+        audit: &mut AuditScope,
+        se: &SearchEvent,
+        entries: Vec<Entry<EntryValid, EntryCommitted>>,
+        f: &mut dyn FnMut(
+            &mut AuditScope,
+            Entry<EntryReduced, EntryCommitted>,
+        ) -> Result<(), OperationError>,
+    ) -> Result<(), OperationError> {
         /*
          * Super similar to above (could even re-use some parts). Given a set of entries,
          * reduce the attribute sets on them to "what is visible". This is ONLY called on
@@ -519,21 +615,49 @@ pub trait AccessControlsTransaction {
             EventOrigin::Internal => {
                 audit_log!(audit, "IMPOSSIBLE STATE: Internal search in external interface?! Returning empty for safety.");
                 // No need to check ACS
-                return Ok(Vec::new());
+                return Ok(());
             }
             EventOrigin::User(e) => &e,
         };
 
-        // Some useful references we'll use for the remainder of the operation
-        let state = self.get_inner();
-
         // Get the relevant acps for this receiver.
-        let related_acp: Vec<&AccessControlSearch> = state
-            .acps_search
+        let related_acp = self.search_related_acp(audit, &se.event, rec_entry);
+
+        audit_log!(audit, "Related acs -> {:?}", related_acp);
+
+        // Get the set of attributes requested by the caller
+        // TODO #69: This currently
+        // is ALL ATTRIBUTES, so we actually work here to just remove things we
+        // CAN'T see instead.
+
+        //  For each entry
+        for e in entries.into_iter() {
+            // Get the set of attributes you can see
+            let allowed_attrs = self.search_allowed_attrs(audit, &se.event, &related_acp, &e);
+            // Remove all others that are present on the entry.
+            audit_log!(audit, "-- for entry         --> {:?}", e.get_uuid());
+            audit_log!(audit, "allowed attributes   --> {:?}", allowed_attrs);
+
+            // Now purge the attrs that are NOT in this.
+            let reduced = e.reduce_attributes(allowed_attrs);
+            f(audit, reduced)?;
+        }
+        Ok(())
+    }
+
+    // The modify acps whose receiver matches the identity of the event.
+    fn modify_related_acp<'a>(
+        &'a self,
+        audit: &mut AuditScope,
+        ev: &Event,
+        rec_entry: &Entry<EntryValid, EntryCommitted>,
+    ) -> Vec<&'a AccessControlModify> {
+        self.get_inner()
+            .acps_modify
             .iter()
             .filter_map(|(_, acs)| {
                 let f_val = acs.acp.receiver.clone();
-                match f_val.resolve(&se.event) {
+                match f_val.resolve(ev) {
                     Ok(f_res) => {
                         if rec_entry.entry_match_no_index(&f_res) {
                             Some(acs)
@@ -551,69 +675,66 @@ pub trait AccessControlsTransaction {
                     }
                 }
             })
-            .collect();
-
-        audit_log!(audit, "Related acs -> {:?}", related_acp);
-
-        // Get the set of attributes requested by the caller
-        // TODO #69: This currently
-        // is ALL ATTRIBUTES, so we actually work here to just remove things we
-        // CAN'T see instead.
+            .collect()
+    }
 
-        //  For each entry
-        let allowed_entries: Vec<Entry<EntryReduced, EntryCommitted>> = entries
-            .into_iter()
-            .map(|e| {
-                // Get the set of attributes you can see
-                let allowed_attrs: BTreeSet<&str> = related_acp
-                    .iter()
-                    .filter_map(|acs| {
-                        let f_val = acs.acp.targetscope.clone();
-                        match f_val.resolve(&se.event) {
-                            Ok(f_res) => {
-                                // if it applies
-                                if e.entry_match_no_index(&f_res) {
-                                    audit_log!(
-                                        audit,
-                                        "entry {:?} matches acs {:?}",
-                                        e.get_uuid(),
-                                        acs
-                                    );
-                                    // add search_attrs to allowed.
-                                    let r: Vec<&str> =
-                                        acs.attrs.iter().map(|s| s.as_str()).collect();
-                                    Some(r)
-                                } else {
-                                    audit_log!(
-                                        audit,
-                                        "entry {:?} DOES NOT match acs {:?}",
-                                        e.get_uuid(),
-                                        acs
-                                    );
-                                    None
-                                }
-                            }
-                            Err(e) => {
-                                audit_log!(
-                                    audit,
-                                    "A internal filter was passed for resolution!?!? {:?}",
-                                    e
-                                );
-                                None
-                            }
+    // The attributes that may be made present, the attributes that may be
+    // removed, and the classes that may be changed on e, by the related
+    // modify acps.
+    fn modify_allowed_sets<'a>(
+        &self,
+        audit: &mut AuditScope,
+        ev: &Event,
+        related_acp: &[&'a AccessControlModify],
+        e: &Entry<EntryValid, EntryCommitted>,
+    ) -> (BTreeSet<&'a str>, BTreeSet<&'a str>, BTreeSet<&'a str>) {
+        // For this entry, find the acp's that apply to it from the
+        // set that apply to the entry that is performing the operation
+        let scoped_acp: Vec<&AccessControlModify> = related_acp
+            .iter()
+            .filter_map(|acm: &&AccessControlModify| {
+                // We are continually compiling and using these
+                // in a tight loop, so this is a possible oppurtunity
+                // to cache or handle these filters better - filter compiler
+                // cache maybe?
+                let f_val = acm.acp.targetscope.clone();
+                match f_val.resolve(ev) {
+                    Ok(f_res) => {
+                        if e.entry_match_no_index(&f_res) {
+                            Some(*acm)
+                        } else {
+                            None
                         }
-                    })
-                    .flatten()
-                    .collect();
-                // Remove all others that are present on the entry.
-                audit_log!(audit, "-- for entry         --> {:?}", e.get_uuid());
-                audit_log!(audit, "allowed attributes   --> {:?}", allowed_attrs);
-
-                // Now purge the attrs that are NOT in this.
-                e.reduce_attributes(allowed_attrs)
+                    }
+                    Err(e) => {
+                        audit_log!(
+                            audit,
+                            "A internal filter was passed for resolution!?!? {:?}",
+                            e
+                        );
+                        None
+                    }
+                }
             })
             .collect();
-        Ok(allowed_entries)
+        // Build the sets of classes, pres and rem we are allowed to modify, extend
+        // or use based on the set of matched acps.
+        let allowed_pres: BTreeSet<&str> = scoped_acp
+            .iter()
+            .flat_map(|acp| acp.presattrs.iter().map(|v| v.as_str()))
+            .collect();
+
+        let allowed_rem: BTreeSet<&str> = scoped_acp
+            .iter()
+            .flat_map(|acp| acp.remattrs.iter().map(|v| v.as_str()))
+            .collect();
+
+        let allowed_classes: BTreeSet<&str> = scoped_acp
+            .iter()
+            .flat_map(|acp| acp.classes.iter().map(|v| v.as_str()))
+            .collect();
+
+        (allowed_pres, allowed_rem, allowed_classes)
     }
 
     fn modify_allow_operation(
@@ -632,16 +753,14 @@ pub trait AccessControlsTransaction {
             EventOrigin::User(e) => &e,
         };
 
-        // Some useful references we'll use for the remainder of the operation
-        let state = self.get_inner();
-
         // Pre-check if the no-no purge class is present
         if me.modlist.iter().fold(false, |acc, m| {
             if acc {
                 return acc;
             } else {
                 match m {
-                    Modify::Purged(a) => {
+                    // Setting class removes any classes not listed, like a purge.
+                    Modify::Purged(a) | Modify::Set(a, _) => {
                         if a == "class" {
                             true
                         } else {
@@ -657,30 +776,7 @@ pub trait AccessControlsTransaction {
         }
 
         // Find the acps that relate to the caller.
-        let related_acp: Vec<&AccessControlModify> = state
-            .acps_modify
-            .iter()
-            .filter_map(|(_, acs)| {
-                let f_val = acs.acp.receiver.clone();
-                match f_val.resolve(&me.event) {
-                    Ok(f_res) => {
-                        if rec_entry.entry_match_no_index(&f_res) {
-                            Some(acs)
-                        } else {
-                            None
-                        }
-                    }
-                    Err(e) => {
-                        audit_log!(
-                            audit,
-                            "A internal filter was passed for resolution!?!? {:?}",
-                            e
-                        );
-                        None
-                    }
-                }
-            })
-            .collect();
+        let related_acp = self.modify_related_acp(audit, &me.event, rec_entry);
 
         audit_log!(audit, "Related acs -> {:?}", related_acp);
 
@@ -690,6 +786,7 @@ pub trait AccessControlsTransaction {
             .iter()
             .filter_map(|m| match m {
                 Modify::Present(a, _) => Some(a.as_str()),
+                Modify::Set(a, _) => Some(a.as_str()),
                 _ => None,
             })
             .collect();
@@ -700,6 +797,20 @@ pub trait AccessControlsTransaction {
             .filter_map(|m| match m {
                 Modify::Removed(a, _) => Some(a.as_str()),
                 Modify::Purged(a) => Some(a.as_str()),
+                Modify::Set(a, _) => Some(a.as_str()),
+                _ => None,
+            })
+            .collect();
+
+        // Asserting on an attribute requires being able to change it in some way,
+        // so that assertions can't be used to probe values that are otherwise
+        // hidden.
+        let requested_assert: BTreeSet<&str> = me
+            .modlist
+            .iter()
+            .filter_map(|m| match m {
+                Modify::Assert(a, _) => Some(a.as_str()),
+                Modify::AssertMissing(a) => Some(a.as_str()),
                 _ => None,
             })
             .collect();
@@ -737,57 +848,15 @@ pub trait AccessControlsTransaction {
 
         audit_log!(audit, "Requested present set: {:?}", requested_pres);
         audit_log!(audit, "Requested remove set: {:?}", requested_rem);
+        audit_log!(audit, "Requested assert set: {:?}", requested_assert);
         audit_log!(audit, "Requested class set: {:?}", requested_classes);
 
         let r = entries.iter().fold(true, |acc, e| {
             if acc == false {
                 false
             } else {
-                // For this entry, find the acp's that apply to it from the
-                // set that apply to the entry that is performing the operation
-                let scoped_acp: Vec<&AccessControlModify> = related_acp
-                    .iter()
-                    .filter_map(|acm: &&AccessControlModify| {
-                        // We are continually compiling and using these
-                        // in a tight loop, so this is a possible oppurtunity
-                        // to cache or handle these filters better - filter compiler
-                        // cache maybe?
-                        let f_val = acm.acp.targetscope.clone();
-                        match f_val.resolve(&me.event) {
-                            Ok(f_res) => {
-                                if e.entry_match_no_index(&f_res) {
-                                    Some(*acm)
-                                } else {
-                                    None
-                                }
-                            }
-                            Err(e) => {
-                                audit_log!(
-                                    audit,
-                                    "A internal filter was passed for resolution!?!? {:?}",
-                                    e
-                                );
-                                None
-                            }
-                        }
-                    })
-                    .collect();
-                // Build the sets of classes, pres and rem we are allowed to modify, extend
-                // or use based on the set of matched acps.
-                let allowed_pres: BTreeSet<&str> = scoped_acp
-                    .iter()
-                    .flat_map(|acp| acp.presattrs.iter().map(|v| v.as_str()))
-                    .collect();
-
-                let allowed_rem: BTreeSet<&str> = scoped_acp
-                    .iter()
-                    .flat_map(|acp| acp.remattrs.iter().map(|v| v.as_str()))
-                    .collect();
-
-                let allowed_classes: BTreeSet<&str> = scoped_acp
-                    .iter()
-                    .flat_map(|acp| acp.classes.iter().map(|v| v.as_str()))
-                    .collect();
+                let (allowed_pres, allowed_rem, allowed_classes) =
+                    self.modify_allowed_sets(audit, &me.event, &related_acp, e);
 
                 // Now check all the subsets are true. Remember, purge class
                 // is already checked above.
@@ -807,6 +876,13 @@ pub trait AccessControlsTransaction {
                     audit_log!(audit, "{:?} !⊆ {:?}", requested_classes, allowed_classes);
                     return false;
                 }
+                let allowed_assert: BTreeSet<&str> =
+                    allowed_pres.union(&allowed_rem).map(|a| *a).collect();
+                if !requested_assert.is_subset(&allowed_assert) {
+                    audit_log!(audit, "requested_assert is not a subset of allowed");
+                    audit_log!(audit, "{:?} !⊆ {:?}", requested_assert, allowed_assert);
+                    return false;
+                }
                 true
             } // if acc == false
         });
@@ -973,32 +1049,19 @@ pub trait AccessControlsTransaction {
         Ok(r)
     }
 
-    fn delete_allow_operation(
-        &self,
+    // The delete acps whose receiver matches the identity of the event.
+    fn delete_related_acp<'a>(
+        &'a self,
         audit: &mut AuditScope,
-        de: &DeleteEvent,
-        entries: &Vec<Entry<EntryValid, EntryCommitted>>,
-    ) -> Result<bool, OperationError> {
-        audit_log!(audit, "Access check for event: {:?}", de);
-
-        let rec_entry: &Entry<EntryValid, EntryCommitted> = match &de.event.origin {
-            EventOrigin::Internal => {
-                // No need to check ACS
-                return Ok(true);
-            }
-            EventOrigin::User(e) => &e,
-        };
-
-        // Some useful references we'll use for the remainder of the operation
-        let state = self.get_inner();
-
-        // Find the acps that relate to the caller.
-        let related_acp: Vec<&AccessControlDelete> = state
+        ev: &Event,
+        rec_entry: &Entry<EntryValid, EntryCommitted>,
+    ) -> Vec<&'a AccessControlDelete> {
+        self.get_inner()
             .acps_delete
             .iter()
             .filter_map(|(_, acs)| {
                 let f_val = acs.acp.receiver.clone();
-                match f_val.resolve(&de.event) {
+                match f_val.resolve(ev) {
                     Ok(f_res) => {
                         if rec_entry.entry_match_no_index(&f_res) {
                             Some(acs)
@@ -1016,59 +1079,247 @@ pub trait AccessControlsTransaction {
                     }
                 }
             })
+            .collect()
+    }
+
+    // Whether any of the related delete acps allow e to be deleted.
+    fn delete_allowed(
+        &self,
+        audit: &mut AuditScope,
+        ev: &Event,
+        related_acp: &[&AccessControlDelete],
+        e: &Entry<EntryValid, EntryCommitted>,
+    ) -> bool {
+        related_acp.iter().fold(false, |r_acc, acd| {
+            if r_acc == true {
+                // If something allowed us to delete, skip doing silly work.
+                r_acc
+            } else {
+                let f_val = acd.acp.targetscope.clone();
+                match f_val.resolve(ev) {
+                    Ok(f_res) => {
+                        if e.entry_match_no_index(&f_res) {
+                            audit_log!(audit, "entry {:?} matches acs {:?}", e.get_uuid(), acd);
+                            // It matches, so we can delete this!
+                            true
+                        } else {
+                            audit_log!(
+                                audit,
+                                "entry {:?} DOES NOT match acs {:?}",
+                                e.get_uuid(),
+                                acd
+                            );
+                            // Does not match, fail.
+                            false
+                        }
+                    }
+                    Err(e) => {
+                        audit_log!(
+                            audit,
+                            "A internal filter was passed for resolution!?!? {:?}",
+                            e
+                        );
+                        // Default to failing here.
+                        false
+                    }
+                } // match
+            } // else
+        }) // fold related_acp
+    }
+
+    fn delete_allow_operation(
+        &self,
+        audit: &mut AuditScope,
+        de: &DeleteEvent,
+        entries: &Vec<Entry<EntryValid, EntryCommitted>>,
+    ) -> Result<bool, OperationError> {
+        audit_log!(audit, "Access check for event: {:?}", de);
+
+        let rec_entry: &Entry<EntryValid, EntryCommitted> = match &de.event.origin {
+            EventOrigin::Internal => {
+                // No need to check ACS
+                return Ok(true);
+            }
+            EventOrigin::User(e) => &e,
+        };
+
+        // Find the acps that relate to the caller.
+        let related_acp = self.delete_related_acp(audit, &de.event, rec_entry);
+
+        audit_log!(audit, "Related acs -> {:?}", related_acp);
+
+        // For each entry
+        let r = entries.iter().fold(true, |acc, e| {
+            if acc == false {
+                // Any false, denies the whole operation.
+                false
+            } else {
+                self.delete_allowed(audit, &de.event, &related_acp, e)
+            } // if/else
+        });
+        Ok(r)
+    }
+
+    fn compare_allow_operation(
+        &self,
+        audit: &mut AuditScope,
+        ce: &CompareEvent,
+        entry: &Entry<EntryValid, EntryCommitted>,
+    ) -> Result<bool, OperationError> {
+        audit_log!(audit, "Access check for event: {:?}", ce);
+
+        let rec_entry: &Entry<EntryValid, EntryCommitted> = match &ce.event.origin {
+            EventOrigin::Internal => {
+                // No need to check ACS
+                return Ok(true);
+            }
+            EventOrigin::User(e) => &e,
+        };
+
+        // Some useful references we'll use for the remainder of the operation
+        let state = self.get_inner();
+
+        let receiver_matches = |acp: &AccessControlProfile| -> bool {
+            match acp.receiver.clone().resolve(&ce.event) {
+                Ok(f_res) => rec_entry.entry_match_no_index(&f_res),
+                Err(_) => false,
+            }
+        };
+        let target_matches = |acp: &AccessControlProfile| -> bool {
+            match acp.targetscope.clone().resolve(&ce.event) {
+                Ok(f_res) => entry.entry_match_no_index(&f_res),
+                Err(_) => false,
+            }
+        };
+
+        // Being able to read the attribute implies being able to compare it,
+        // so both the search and compare acps that apply to this receiver and
+        // entry contribute to the allowed set.
+        let allowed_attrs: BTreeSet<&str> = state
+            .acps_search
+            .values()
+            .filter(|acs| receiver_matches(&acs.acp) && target_matches(&acs.acp))
+            .flat_map(|acs| acs.attrs.iter().map(|s| s.as_str()))
+            .chain(
+                state
+                    .acps_compare
+                    .values()
+                    .filter(|acc| receiver_matches(&acc.acp) && target_matches(&acc.acp))
+                    .flat_map(|acc| acc.attrs.iter().map(|s| s.as_str())),
+            )
+            .collect();
+
+        audit_log!(audit, "-- for entry         --> {:?}", entry.get_uuid());
+        audit_log!(audit, "allowed attributes   --> {:?}", allowed_attrs);
+        audit_log!(audit, "requested attribute  --> {:?}", ce.attr);
+
+        let decision = allowed_attrs.contains(ce.attr.as_str());
+        audit_log!(audit, "compare attr decision --> {:?}", decision);
+        Ok(decision)
+    }
+
+    // The names of the profiles of the operation's kind that apply to the
+    // receiver of the event over entry. This explains a decision rather than
+    // making one, as a profile that applies may still not grant the
+    // attributes the operation needs.
+    fn applicable_acp_names(
+        &self,
+        audit: &mut AuditScope,
+        op: &AccessCheckOperation,
+        ev: &Event,
+        entry: &Entry<EntryValid, EntryCommitted>,
+    ) -> Vec<String> {
+        let rec_entry: &Entry<EntryValid, EntryCommitted> = match &ev.origin {
+            EventOrigin::Internal => return Vec::new(),
+            EventOrigin::User(e) => &e,
+        };
+
+        let state = self.get_inner();
+
+        let acps: Vec<&AccessControlProfile> = match op {
+            AccessCheckOperation::Search => state.acps_search.values().map(|a| &a.acp).collect(),
+            AccessCheckOperation::Modify(_) => state.acps_modify.values().map(|a| &a.acp).collect(),
+            AccessCheckOperation::Delete => state.acps_delete.values().map(|a| &a.acp).collect(),
+        };
+
+        let names: Vec<String> = acps
+            .into_iter()
+            .filter(|acp| {
+                let receiver_matches = match acp.receiver.clone().resolve(ev) {
+                    Ok(f_res) => rec_entry.entry_match_no_index(&f_res),
+                    Err(_) => false,
+                };
+                let target_matches = match acp.targetscope.clone().resolve(ev) {
+                    Ok(f_res) => entry.entry_match_no_index(&f_res),
+                    Err(_) => false,
+                };
+                receiver_matches && target_matches
+            })
+            .map(|acp| acp.name.clone())
             .collect();
 
-        audit_log!(audit, "Related acs -> {:?}", related_acp);
+        audit_log!(
+            audit,
+            "applicable acps for {:?} --> {:?}",
+            entry.get_uuid(),
+            names
+        );
+        names
+    }
+
+    // The rights the identity of the event has over each entry, from the
+    // same acps and sets that search, modify and delete are decided by. An
+    // internal event is never access checked, so there is nothing to report.
+    fn effective_access(
+        &self,
+        audit: &mut AuditScope,
+        ev: &Event,
+        entries: &Vec<Entry<EntryValid, EntryCommitted>>,
+    ) -> Vec<EffectiveAccess> {
+        let rec_entry: &Entry<EntryValid, EntryCommitted> = match &ev.origin {
+            EventOrigin::Internal => return Vec::new(),
+            EventOrigin::User(e) => &e,
+        };
 
-        // For each entry
-        let r = entries.iter().fold(true, |acc, e| {
-            if acc == false {
-                // Any false, denies the whole operation.
-                false
-            } else {
-                related_acp.iter().fold(false, |r_acc, acd| {
-                    if r_acc == true {
-                        // If something allowed us to delete, skip doing silly work.
-                        r_acc
-                    } else {
-                        let f_val = acd.acp.targetscope.clone();
-                        match f_val.resolve(&de.event) {
-                            Ok(f_res) => {
-                                if e.entry_match_no_index(&f_res) {
-                                    audit_log!(
-                                        audit,
-                                        "entry {:?} matches acs {:?}",
-                                        e.get_uuid(),
-                                        acd
-                                    );
-                                    // It matches, so we can delete this!
-                                    true
-                                } else {
-                                    audit_log!(
-                                        audit,
-                                        "entry {:?} DOES NOT match acs {:?}",
-                                        e.get_uuid(),
-                                        acd
-                                    );
-                                    // Does not match, fail.
-                                    false
-                                }
-                            }
-                            Err(e) => {
-                                audit_log!(
-                                    audit,
-                                    "A internal filter was passed for resolution!?!? {:?}",
-                                    e
-                                );
-                                // Default to failing here.
-                                false
-                            }
-                        } // match
-                    } // else
-                }) // fold related_acp
-            } // if/else
-        });
-        Ok(r)
+        let search_acp = self.search_related_acp(audit, ev, rec_entry);
+        let modify_acp = self.modify_related_acp(audit, ev, rec_entry);
+        let delete_acp = self.delete_related_acp(audit, ev, rec_entry);
+
+        entries
+            .iter()
+            .map(|e| {
+                let search = self.search_allowed_attrs(audit, ev, &search_acp, e);
+                let (pres, rem, classes) = self.modify_allowed_sets(audit, ev, &modify_acp, e);
+                let delete = self.delete_allowed(audit, ev, &delete_acp, e);
+                audit_log!(
+                    audit,
+                    "effective access for {:?} --> search {:?} pres {:?} rem {:?} classes {:?} delete {:?}",
+                    e.get_uuid(),
+                    search,
+                    pres,
+                    rem,
+                    classes,
+                    delete
+                );
+
+                let name = if search.contains("name") {
+                    e.get_ava_single_string("name")
+                } else {
+                    None
+                };
+                let to_strings =
+                    |set: BTreeSet<&str>| -> Vec<String> { set.into_iter().map(|s| s.to_string()).collect() };
+                EffectiveAccess {
+                    uuid: e.get_uuid().to_hyphenated_ref().to_string(),
+                    name: name,
+                    search: to_strings(search),
+                    modify_present: to_strings(pres),
+                    modify_removed: to_strings(rem),
+                    modify_classes: to_strings(classes),
+                    delete: delete,
+                }
+            })
+            .collect()
     }
 }
 
@@ -1127,6 +1378,19 @@ impl<'a> AccessControlsWriteTransaction<'a> {
         Ok(())
     }
 
+    pub fn update_compare(
+        &mut self,
+        acps: Vec<AccessControlCompare>,
+    ) -> Result<(), OperationError> {
+        let inner = self.get_inner_mut();
+        inner.acps_compare.clear();
+        for acp in acps {
+            let uuid = acp.acp.uuid.clone();
+            inner.acps_compare.insert(uuid, acp);
+        }
+        Ok(())
+    }
+
     pub fn commit(self) -> Result<(), OperationError> {
         self.inner.commit();
         Ok(())
@@ -1180,14 +1444,15 @@ impl AccessControls {
 #[cfg(test)]
 mod tests {
     use crate::access::{
-        AccessControlCreate, AccessControlDelete, AccessControlModify, AccessControlProfile,
-        AccessControlSearch, AccessControls, AccessControlsTransaction,
+        AccessControlCompare, AccessControlCreate, AccessControlDelete, AccessControlModify,
+        AccessControlProfile, AccessControlSearch, AccessControls, AccessControlsTransaction,
+        AccessControlsWriteTransaction,
     };
     use crate::audit::AuditScope;
-    use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntryReduced};
+    use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntryReduced, EntryValid};
     // use crate::server::QueryServerWriteTransaction;
 
-    use crate::event::{CreateEvent, DeleteEvent, ModifyEvent, SearchEvent};
+    use crate::event::{CompareEvent, CreateEvent, DeleteEvent, ModifyEvent, SearchEvent};
     // use crate::filter::Filter;
     // use crate::proto_v1::Filter as ProtoFilter;
     use crate::constants::{JSON_ADMIN_V1, JSON_ANONYMOUS_V1, JSON_TESTPERSON1, JSON_TESTPERSON2};
@@ -1456,6 +1721,105 @@ mod tests {
         })
     }
 
+    #[test]
+    fn test_access_acp_compare_parser() {
+        run_test!(|qs: &QueryServer, audit: &mut AuditScope| {
+            // Test that parsing compare access controls works.
+            let qs_write = qs.write();
+
+            // Missing class acp
+            acp_from_entry_err!(
+                audit,
+                &qs_write,
+                r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "access_control_compare"],
+                        "name": ["acp_invalid"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                        "acp_receiver": [
+                            "{\"Eq\":[\"name\",\"a\"]}"
+                        ],
+                        "acp_targetscope": [
+                            "{\"Eq\":[\"name\",\"a\"]}"
+                        ],
+                        "acp_compare_attr": ["member"]
+                    }
+                }"#,
+                AccessControlCompare
+            );
+
+            // Missing class acc
+            acp_from_entry_err!(
+                audit,
+                &qs_write,
+                r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "access_control_profile"],
+                        "name": ["acp_invalid"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                        "acp_receiver": [
+                            "{\"Eq\":[\"name\",\"a\"]}"
+                        ],
+                        "acp_targetscope": [
+                            "{\"Eq\":[\"name\",\"a\"]}"
+                        ],
+                        "acp_compare_attr": ["member"]
+                    }
+                }"#,
+                AccessControlCompare
+            );
+
+            // Missing attr acp_compare_attr
+            acp_from_entry_err!(
+                audit,
+                &qs_write,
+                r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "access_control_profile", "access_control_compare"],
+                        "name": ["acp_invalid"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                        "acp_receiver": [
+                            "{\"Eq\":[\"name\",\"a\"]}"
+                        ],
+                        "acp_targetscope": [
+                            "{\"Eq\":[\"name\",\"a\"]}"
+                        ]
+                    }
+                }"#,
+                AccessControlCompare
+            );
+
+            // All good!
+            acp_from_entry_ok!(
+                audit,
+                &qs_write,
+                r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "access_control_profile", "access_control_compare"],
+                        "name": ["acp_valid"],
+                        "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                        "acp_receiver": [
+                            "{\"Eq\":[\"name\",\"a\"]}"
+                        ],
+                        "acp_targetscope": [
+                            "{\"Eq\":[\"name\",\"a\"]}"
+                        ],
+                        "acp_compare_attr": ["member"]
+                    }
+                }"#,
+                AccessControlCompare
+            );
+        })
+    }
+
     #[test]
     fn test_access_acp_modify_parser() {
         run_test!(|qs: &QueryServer, audit: &mut AuditScope| {
@@ -1890,6 +2254,15 @@ mod tests {
             )
         };
 
+        // Name assert
+        let me_assert = unsafe {
+            ModifyEvent::new_impersonate_entry_ser(
+                JSON_ADMIN_V1,
+                filter_all!(f_eq("name", PartialValue::new_iutf8s("testperson1"))),
+                modlist!([m_assert("name", &PartialValue::new_iutf8s("testperson1"))]),
+            )
+        };
+
         // Class account pres
         let me_pres_class = unsafe {
             ModifyEvent::new_impersonate_entry_ser(
@@ -1981,6 +2354,11 @@ mod tests {
         // Test rejected purge
         test_acp_modify!(&me_purge, vec![acp_deny.clone()], &r_set, false);
 
+        // test allowed assert, as name may be changed
+        test_acp_modify!(&me_assert, vec![acp_allow.clone()], &r_set, true);
+        // Test rejected assert, as name may not be changed
+        test_acp_modify!(&me_assert, vec![acp_deny.clone()], &r_set, false);
+
         // test allowed pres class
         test_acp_modify!(&me_pres_class, vec![acp_allow.clone()], &r_set, true);
         // test allowed rem class
@@ -2183,4 +2561,239 @@ mod tests {
         // Test reject delete
         test_acp_delete!(&de_anon, vec![acp], &r_set, false);
     }
+
+    macro_rules! test_acp_compare {
+        (
+            $ce:expr,
+            $search:expr,
+            $controls:expr,
+            $entry:expr,
+            $expect:expr
+        ) => {{
+            let ac = AccessControls::new();
+            let mut acw = ac.write();
+            acw.update_search($search).expect("Failed to update");
+            acw.update_compare($controls).expect("Failed to update");
+            let acw = acw;
+
+            let mut audit = AuditScope::new("test_acp_compare");
+            let res = acw
+                .compare_allow_operation(&mut audit, $ce, $entry)
+                .expect("op failed");
+            println!("result --> {:?}", res);
+            println!("expect --> {:?}", $expect);
+            // should be ok, and same as expect.
+            assert!(res == $expect);
+        }};
+    }
+
+    #[test]
+    fn test_access_enforce_compare() {
+        let e1: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_TESTPERSON1);
+        let ev1 = unsafe { e1.to_valid_committed() };
+
+        let ce = |attr: &str| unsafe {
+            CompareEvent::new_impersonate_entry_ser(
+                JSON_ANONYMOUS_V1,
+                filter_all!(f_eq("name", PartialValue::new_iutf8s("testperson1"))),
+                attr,
+                PartialValue::new_iutf8s("testperson1"),
+            )
+        };
+
+        let acs = unsafe {
+            AccessControlSearch::from_raw(
+                "test_search",
+                "cc8e95b4-c24f-4d68-ba54-8bed76f63930",
+                // Apply to anonymous
+                filter_valid!(f_eq("name", PartialValue::new_iutf8s("anonymous"))),
+                // To read testperson
+                filter_valid!(f_eq("name", PartialValue::new_iutf8s("testperson1"))),
+                "name",
+            )
+        };
+
+        let acc = unsafe {
+            AccessControlCompare::from_raw(
+                "test_compare",
+                "87bfe9b8-7600-431e-a492-1dde64bbc453",
+                // Apply to anonymous
+                filter_valid!(f_eq("name", PartialValue::new_iutf8s("anonymous"))),
+                // To compare on testperson
+                filter_valid!(f_eq("name", PartialValue::new_iutf8s("testperson1"))),
+                "legalname",
+            )
+        };
+
+        // Compare is granted without read.
+        test_acp_compare!(&ce("legalname"), vec![], vec![acc.clone()], &ev1, true);
+        // Read implies compare.
+        test_acp_compare!(&ce("name"), vec![acs.clone()], vec![], &ev1, true);
+        // Neither read nor compare on the attribute.
+        test_acp_compare!(&ce("description"), vec![acs], vec![acc], &ev1, false);
+    }
+
+    // The report must agree with what each operation is actually allowed.
+    fn check_effective_access(
+        acw: &AccessControlsWriteTransaction,
+        receiver: &str,
+        entry: &Entry<EntryValid, EntryCommitted>,
+    ) {
+        let mut audit = AuditScope::new("test_effective_access");
+        let filter = || filter_all!(f_eq("name", PartialValue::new_iutf8s("testperson1")));
+        let ev = unsafe { DeleteEvent::new_impersonate_entry_ser(receiver, filter()) };
+        let mut report = acw.effective_access(&mut audit, &ev.event, &vec![entry.clone()]);
+        assert!(report.len() == 1);
+        let report = report.remove(0);
+        println!("report --> {:?}", report);
+
+        for attr in ["name", "displayname", "legalname", "member"].iter() {
+            let se = unsafe {
+                SearchEvent::new_impersonate_entry_ser(receiver, filter_all!(f_pres(attr)))
+            };
+            let found = acw
+                .search_filter_entries(&mut audit, &se, vec![entry.clone()])
+                .expect("op failed")
+                .len()
+                == 1;
+            assert!(found == report.search.contains(&attr.to_string()));
+
+            let me_pres = unsafe {
+                ModifyEvent::new_impersonate_entry_ser(
+                    receiver,
+                    filter(),
+                    modlist!([m_pres(attr, &Value::new_iutf8s("value"))]),
+                )
+            };
+            let allowed = acw
+                .modify_allow_operation(&mut audit, &me_pres, &vec![entry.clone()])
+                .expect("op failed");
+            assert!(allowed == report.modify_present.contains(&attr.to_string()));
+
+            let me_purge = unsafe {
+                ModifyEvent::new_impersonate_entry_ser(
+                    receiver,
+                    filter(),
+                    modlist!([m_purge(attr)]),
+                )
+            };
+            let allowed = acw
+                .modify_allow_operation(&mut audit, &me_purge, &vec![entry.clone()])
+                .expect("op failed");
+            assert!(allowed == report.modify_removed.contains(&attr.to_string()));
+        }
+
+        let me_class = unsafe {
+            ModifyEvent::new_impersonate_entry_ser(
+                receiver,
+                filter(),
+                modlist!([m_pres("class", &Value::new_class("account"))]),
+            )
+        };
+        let allowed = acw
+            .modify_allow_operation(&mut audit, &me_class, &vec![entry.clone()])
+            .expect("op failed");
+        assert!(
+            allowed
+                == (report.modify_present.contains(&"class".to_string())
+                    && report.modify_classes.contains(&"account".to_string()))
+        );
+
+        let allowed = acw
+            .delete_allow_operation(&mut audit, &ev, &vec![entry.clone()])
+            .expect("op failed");
+        assert!(allowed == report.delete);
+    }
+
+    #[test]
+    fn test_access_effective_access() {
+        let e1: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_TESTPERSON1);
+        let ev1 = unsafe { e1.to_valid_committed() };
+
+        let acs = unsafe {
+            AccessControlSearch::from_raw(
+                "test_search",
+                "cc8e95b4-c24f-4d68-ba54-8bed76f63930",
+                // Apply to admin
+                filter_valid!(f_eq("name", PartialValue::new_iutf8s("admin"))),
+                // To read testperson
+                filter_valid!(f_eq("name", PartialValue::new_iutf8s("testperson1"))),
+                "name displayname",
+            )
+        };
+        let acm = unsafe {
+            AccessControlModify::from_raw(
+                "test_modify",
+                "87bfe9b8-7600-431e-a492-1dde64bbc455",
+                // Apply to admin
+                filter_valid!(f_eq("name", PartialValue::new_iutf8s("admin"))),
+                // To modify testperson
+                filter_valid!(f_eq("name", PartialValue::new_iutf8s("testperson1"))),
+                "displayname class",
+                "name",
+                "account",
+            )
+        };
+        // Only over another entry, so never part of the report.
+        let acm_other = unsafe {
+            AccessControlModify::from_raw(
+                "test_modify_other",
+                "87bfe9b8-7600-431e-a492-1dde64bbc456",
+                // Apply to admin
+                filter_valid!(f_eq("name", PartialValue::new_iutf8s("admin"))),
+                // To modify testperson2
+                filter_valid!(f_eq("name", PartialValue::new_iutf8s("testperson2"))),
+                "member legalname",
+                "member legalname",
+                "group",
+            )
+        };
+        let acd = unsafe {
+            AccessControlDelete::from_raw(
+                "test_delete",
+                "87bfe9b8-7600-431e-a492-1dde64bbc453",
+                // Apply to admin
+                filter_valid!(f_eq("name", PartialValue::new_iutf8s("admin"))),
+                // To delete testperson
+                filter_valid!(f_eq("name", PartialValue::new_iutf8s("testperson1"))),
+            )
+        };
+
+        let ac = AccessControls::new();
+        let mut acw = ac.write();
+        acw.update_search(vec![acs]).expect("Failed to update");
+        acw.update_modify(vec![acm, acm_other])
+            .expect("Failed to update");
+        acw.update_delete(vec![acd]).expect("Failed to update");
+
+        let mut audit = AuditScope::new("test_effective_access");
+        let ev = unsafe {
+            DeleteEvent::new_impersonate_entry_ser(
+                JSON_ADMIN_V1,
+                filter_all!(f_eq("name", PartialValue::new_iutf8s("testperson1"))),
+            )
+        };
+        let report = acw.effective_access(&mut audit, &ev.event, &vec![ev1.clone()]);
+        assert!(report.len() == 1);
+        assert!(report[0].name == Some("testperson1".to_string()));
+        assert!(report[0].search == vec!["displayname".to_string(), "name".to_string()]);
+        assert!(report[0].modify_present == vec!["class".to_string(), "displayname".to_string()]);
+        assert!(report[0].modify_removed == vec!["name".to_string()]);
+        assert!(report[0].modify_classes == vec!["account".to_string()]);
+        assert!(report[0].delete);
+
+        check_effective_access(&acw, JSON_ADMIN_V1, &ev1);
+        // Anonymous has nothing, and the name can't be read.
+        let ev = unsafe {
+            DeleteEvent::new_impersonate_entry_ser(
+                JSON_ANONYMOUS_V1,
+                filter_all!(f_eq("name", PartialValue::new_iutf8s("testperson1"))),
+            )
+        };
+        let report = acw.effective_access(&mut audit, &ev.event, &vec![ev1.clone()]);
+        assert!(report[0].name.is_none());
+        assert!(report[0].search.is_empty());
+        assert!(!report[0].delete);
+        check_effective_access(&acw, JSON_ANONYMOUS_V1, &ev1);
+    }
 }
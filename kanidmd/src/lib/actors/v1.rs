@@ -1,23 +1,53 @@
 use std::sync::Arc;
 
+use crate::admin::{read_domain_info, DomainInfo};
 use crate::audit::AuditScope;
 
 use crate::async_log::EventLog;
+use crate::be::BackendTransaction;
+use crate::config::OnlineBackup;
+use crate::constants::_UUID_IDM_ADMINS;
 use crate::event::{
-    AuthEvent, CreateEvent, DeleteEvent, ModifyEvent, PurgeRecycledEvent, PurgeTombstoneEvent,
-    SearchEvent, SearchResult, WhoamiResult,
+    AccessCheckEvent, AuditListEvent, AuthEvent, BackupEvent, ChangesEvent, CompareEvent,
+    CreateEvent, DelayedActionEvent, DeleteEvent, EffectiveAccessEvent, ExportEvent, ExportRecord,
+    IndexStatusEvent, ModifyBatchEvent, ModifyEvent, OnlineBackupEvent, PurgeChangelogEvent,
+    PurgeRecycledEvent, PurgeTombstoneEvent, ReindexEvent, ReviveRecycledEvent, SchemaResult,
+    SearchEvent, SearchResult, ShutdownEvent, VacuumEvent, VerifyEvent, WhoamiResult,
 };
 use kanidm_proto::v1::OperationError;
 
+use crate::credential::Policy;
+use crate::idm::account::Account;
+use crate::idm::oauth2::Oauth2CodeGrant;
+use crate::idm::reauth::ProtectedOperation;
 use crate::idm::server::IdmServer;
 use crate::server::{QueryServer, QueryServerTransaction};
 
 use kanidm_proto::v1::{
-    AuthRequest, AuthResponse, CreateRequest, DeleteRequest, ModifyRequest, OperationResponse,
-    SearchRequest, SearchResponse, UserAuthToken, WhoamiResponse,
+    AccessCheckRequest, AccessCheckResponse, ApiTokenDestroyRequest, ApiTokenDestroyResponse,
+    ApiTokenGenerateRequest, ApiTokenGenerateResponse, ApiTokenListRequest, ApiTokenListResponse,
+    AuditListRequest, AuditListResponse, AuthRequest, AuthResponse, BackupCodesGenerateResponse,
+    BackupRequest, BackupResponse, ChangesRequest, ChangesResponse, CompareRequest,
+    CompareResponse, CreateRequest, CreateResponse, CredentialChangeRequest,
+    CredentialChangeResponse, CredentialPolicyRequest, CredentialPolicyResponse,
+    CredentialStatusResponse, DeleteRequest, DeleteResponse, EffectiveAccessRequest,
+    EffectiveAccessResponse, ExportRequest, ExportStreamItem, HealthResponse, IndexStatusRequest,
+    IndexStatusResponse, LogoutResponse, ModifyBatchRequest, ModifyBatchResponse, ModifyRequest,
+    ModifyResponse, Oauth2AuthorizeRequest, Oauth2TokenRequest, RadiusAuthToken,
+    RadiusSecretGenerateResponse, ReauthRequest, ReindexRequest, ReindexResponse, RepairRequest,
+    RepairResponse, ReviveRecycledRequest, ReviveRecycledResponse, SchemaRequest, SchemaResponse,
+    SearchCountRequest, SearchCountResponse, SearchRecycledRequest, SearchRecycledResponse,
+    SearchRequest, SearchResponse, SearchStreamItem, SessionListRequest, SessionListResponse,
+    SessionRevokeRequest, SessionRevokeResponse, TOTPGenerateResponse, TOTPVerifyRequest,
+    TOTPVerifyResponse, UnixAuthRequest, UnixGroupToken, UnixUserToken, UserAuthToken,
+    VacuumRequest, VacuumResponse, VerifyRequest, VerifyResponse, WebauthnGenerateResponse,
+    WebauthnListResponse, WebauthnRegisterRequest, WebauthnRegisterResponse, WebauthnRemoveRequest,
+    WebauthnRemoveResponse, WhoamiResponse,
 };
 
 use actix::prelude::*;
+use futures::sync::mpsc;
+use futures::Sink;
 use std::time::SystemTime;
 use uuid::Uuid;
 
@@ -29,12 +59,16 @@ use uuid::Uuid;
 // part of the protocol.
 
 pub struct WhoamiMessage {
+    pub eventid: Uuid,
     pub uat: Option<UserAuthToken>,
 }
 
 impl WhoamiMessage {
-    pub fn new(uat: Option<UserAuthToken>) -> Self {
-        WhoamiMessage { uat: uat }
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>) -> Self {
+        WhoamiMessage {
+            eventid: eventid,
+            uat: uat,
+        }
     }
 }
 
@@ -44,15 +78,24 @@ impl Message for WhoamiMessage {
 
 #[derive(Debug)]
 pub struct AuthMessage {
+    pub eventid: Uuid,
     pub sessionid: Option<Uuid>,
     pub req: AuthRequest,
+    pub source: Option<String>,
 }
 
 impl AuthMessage {
-    pub fn new(req: AuthRequest, sessionid: Option<Uuid>) -> Self {
+    pub fn new(
+        eventid: Uuid,
+        req: AuthRequest,
+        sessionid: Option<Uuid>,
+        source: Option<String>,
+    ) -> Self {
         AuthMessage {
+            eventid: eventid,
             sessionid: sessionid,
             req: req,
+            source: source,
         }
     }
 }
@@ -61,59 +104,127 @@ impl Message for AuthMessage {
     type Result = Result<AuthResponse, OperationError>;
 }
 
+#[derive(Debug)]
+pub struct ReauthMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub req: ReauthRequest,
+    pub source: Option<String>,
+}
+
+impl ReauthMessage {
+    pub fn new(
+        eventid: Uuid,
+        uat: Option<UserAuthToken>,
+        req: ReauthRequest,
+        source: Option<String>,
+    ) -> Self {
+        ReauthMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+            source: source,
+        }
+    }
+}
+
+impl Message for ReauthMessage {
+    type Result = Result<AuthResponse, OperationError>;
+}
+
 pub struct CreateMessage {
+    pub eventid: Uuid,
     pub uat: Option<UserAuthToken>,
     pub req: CreateRequest,
 }
 
 impl CreateMessage {
-    pub fn new(uat: Option<UserAuthToken>, req: CreateRequest) -> Self {
-        CreateMessage { uat: uat, req: req }
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, req: CreateRequest) -> Self {
+        CreateMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+        }
     }
 }
 
 impl Message for CreateMessage {
-    type Result = Result<OperationResponse, OperationError>;
+    type Result = Result<CreateResponse, OperationError>;
 }
 
 pub struct DeleteMessage {
+    pub eventid: Uuid,
     pub uat: Option<UserAuthToken>,
     pub req: DeleteRequest,
 }
 
 impl DeleteMessage {
-    pub fn new(uat: Option<UserAuthToken>, req: DeleteRequest) -> Self {
-        DeleteMessage { uat: uat, req: req }
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, req: DeleteRequest) -> Self {
+        DeleteMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+        }
     }
 }
 
 impl Message for DeleteMessage {
-    type Result = Result<OperationResponse, OperationError>;
+    type Result = Result<DeleteResponse, OperationError>;
 }
 
 pub struct ModifyMessage {
+    pub eventid: Uuid,
     pub uat: Option<UserAuthToken>,
     pub req: ModifyRequest,
 }
 
 impl ModifyMessage {
-    pub fn new(uat: Option<UserAuthToken>, req: ModifyRequest) -> Self {
-        ModifyMessage { uat: uat, req: req }
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, req: ModifyRequest) -> Self {
+        ModifyMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+        }
     }
 }
 
 impl Message for ModifyMessage {
-    type Result = Result<OperationResponse, OperationError>;
+    type Result = Result<ModifyResponse, OperationError>;
+}
+
+pub struct ModifyBatchMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub req: ModifyBatchRequest,
+}
+
+impl ModifyBatchMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, req: ModifyBatchRequest) -> Self {
+        ModifyBatchMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+        }
+    }
+}
+
+impl Message for ModifyBatchMessage {
+    type Result = Result<ModifyBatchResponse, OperationError>;
 }
 
 pub struct SearchMessage {
+    pub eventid: Uuid,
     pub uat: Option<UserAuthToken>,
     pub req: SearchRequest,
 }
 
 impl SearchMessage {
-    pub fn new(uat: Option<UserAuthToken>, req: SearchRequest) -> Self {
-        SearchMessage { uat: uat, req: req }
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, req: SearchRequest) -> Self {
+        SearchMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+        }
     }
 }
 
@@ -121,222 +232,1875 @@ impl Message for SearchMessage {
     type Result = Result<SearchResponse, OperationError>;
 }
 
-pub struct QueryServerV1 {
-    log: actix::Addr<EventLog>,
-    qs: QueryServer,
-    idms: Arc<IdmServer>,
+// The entries of a streamed search are sent down tx as they are reduced,
+// rather than being collected into one response. The stream ends with Done,
+// or with the error that stopped it.
+pub struct SearchStreamMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub req: SearchRequest,
+    pub tx: mpsc::Sender<Result<SearchStreamItem, OperationError>>,
 }
 
-impl Actor for QueryServerV1 {
-    type Context = SyncContext<Self>;
-
-    fn started(&mut self, _ctx: &mut Self::Context) {
-        // ctx.set_mailbox_capacity(1 << 31);
+impl SearchStreamMessage {
+    pub fn new(
+        eventid: Uuid,
+        uat: Option<UserAuthToken>,
+        req: SearchRequest,
+        tx: mpsc::Sender<Result<SearchStreamItem, OperationError>>,
+    ) -> Self {
+        SearchStreamMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+            tx: tx,
+        }
     }
 }
 
-impl QueryServerV1 {
-    pub fn new(log: actix::Addr<EventLog>, qs: QueryServer, idms: Arc<IdmServer>) -> Self {
-        log_event!(log, "Starting query server v1 worker ...");
-        QueryServerV1 {
-            log: log,
-            qs: qs,
-            idms: idms,
+impl Message for SearchStreamMessage {
+    type Result = ();
+}
+
+pub struct SearchCountMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub req: SearchCountRequest,
+}
+
+impl SearchCountMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, req: SearchCountRequest) -> Self {
+        SearchCountMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
         }
     }
+}
 
-    pub fn start(
-        log: actix::Addr<EventLog>,
-        query_server: QueryServer,
-        idms: IdmServer,
-        threads: usize,
-    ) -> actix::Addr<QueryServerV1> {
-        let idms_arc = Arc::new(idms);
-        SyncArbiter::start(threads, move || {
-            QueryServerV1::new(log.clone(), query_server.clone(), idms_arc.clone())
-        })
+impl Message for SearchCountMessage {
+    type Result = Result<SearchCountResponse, OperationError>;
+}
+
+pub struct CompareMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub req: CompareRequest,
+}
+
+impl CompareMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, req: CompareRequest) -> Self {
+        CompareMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+        }
     }
 }
 
-// The server only recieves "Message" structures, which
-// are whole self contained DB operations with all parsing
-// required complete. We still need to do certain validation steps, but
-// at this point our just is just to route to do_<action>
+impl Message for CompareMessage {
+    type Result = Result<CompareResponse, OperationError>;
+}
 
-impl Handler<SearchMessage> for QueryServerV1 {
-    type Result = Result<SearchResponse, OperationError>;
+pub struct SchemaMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub req: SchemaRequest,
+}
 
-    fn handle(&mut self, msg: SearchMessage, _: &mut Self::Context) -> Self::Result {
-        let mut audit = AuditScope::new("search");
-        let res = audit_segment!(&mut audit, || {
-            // Begin a read
-            let qs_read = self.qs.read();
+impl SchemaMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, req: SchemaRequest) -> Self {
+        SchemaMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+        }
+    }
+}
 
-            // Make an event from the request
-            let srch = match SearchEvent::from_message(&mut audit, msg, &qs_read) {
-                Ok(s) => s,
-                Err(e) => {
-                    audit_log!(audit, "Failed to begin search: {:?}", e);
-                    return Err(e);
-                }
-            };
+impl Message for SchemaMessage {
+    type Result = Result<SchemaResponse, OperationError>;
+}
 
-            audit_log!(audit, "Begin event {:?}", srch);
+pub struct SearchRecycledMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub req: SearchRecycledRequest,
+}
 
-            match qs_read.search_ext(&mut audit, &srch) {
-                Ok(entries) => {
-                    SearchResult::new(&mut audit, &qs_read, entries).map(|ok_sr| ok_sr.response())
-                }
-                Err(e) => Err(e),
-            }
-        });
-        // At the end of the event we send it for logging.
-        self.log.do_send(audit);
-        res
+impl SearchRecycledMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, req: SearchRecycledRequest) -> Self {
+        SearchRecycledMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+        }
     }
 }
 
-impl Handler<CreateMessage> for QueryServerV1 {
-    type Result = Result<OperationResponse, OperationError>;
+impl Message for SearchRecycledMessage {
+    type Result = Result<SearchRecycledResponse, OperationError>;
+}
 
-    fn handle(&mut self, msg: CreateMessage, _: &mut Self::Context) -> Self::Result {
-        let mut audit = AuditScope::new("create");
-        let res = audit_segment!(&mut audit, || {
-            let mut qs_write = self.qs.write();
+pub struct ReviveRecycledMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub req: ReviveRecycledRequest,
+}
 
-            let crt = match CreateEvent::from_message(&mut audit, msg, &qs_write) {
-                Ok(c) => c,
-                Err(e) => {
-                    audit_log!(audit, "Failed to begin create: {:?}", e);
-                    return Err(e);
-                }
-            };
+impl ReviveRecycledMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, req: ReviveRecycledRequest) -> Self {
+        ReviveRecycledMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+        }
+    }
+}
 
-            audit_log!(audit, "Begin create event {:?}", crt);
+impl Message for ReviveRecycledMessage {
+    type Result = Result<ReviveRecycledResponse, OperationError>;
+}
 
-            qs_write
-                .create(&mut audit, &crt)
-                .and_then(|_| qs_write.commit(&mut audit).map(|_| OperationResponse {}))
-        });
-        // At the end of the event we send it for logging.
-        self.log.do_send(audit);
-        res
-    }
+pub struct LogoutMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
 }
 
-impl Handler<ModifyMessage> for QueryServerV1 {
-    type Result = Result<OperationResponse, OperationError>;
+impl LogoutMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>) -> Self {
+        LogoutMessage {
+            eventid: eventid,
+            uat: uat,
+        }
+    }
+}
 
-    fn handle(&mut self, msg: ModifyMessage, _: &mut Self::Context) -> Self::Result {
-        let mut audit = AuditScope::new("modify");
-        let res = audit_segment!(&mut audit, || {
-            let mut qs_write = self.qs.write();
-            let mdf = match ModifyEvent::from_message(&mut audit, msg, &qs_write) {
-                Ok(m) => m,
-                Err(e) => {
-                    audit_log!(audit, "Failed to begin modify: {:?}", e);
-                    return Err(e);
-                }
-            };
+impl Message for LogoutMessage {
+    type Result = Result<LogoutResponse, OperationError>;
+}
 
-            audit_log!(audit, "Begin modify event {:?}", mdf);
+pub struct SessionListMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub req: SessionListRequest,
+}
 
-            qs_write
-                .modify(&mut audit, &mdf)
-                .and_then(|_| qs_write.commit(&mut audit).map(|_| OperationResponse {}))
-        });
-        self.log.do_send(audit);
-        res
+impl SessionListMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, req: SessionListRequest) -> Self {
+        SessionListMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+        }
     }
 }
 
-impl Handler<DeleteMessage> for QueryServerV1 {
-    type Result = Result<OperationResponse, OperationError>;
+impl Message for SessionListMessage {
+    type Result = Result<SessionListResponse, OperationError>;
+}
 
-    fn handle(&mut self, msg: DeleteMessage, _: &mut Self::Context) -> Self::Result {
-        let mut audit = AuditScope::new("delete");
-        let res = audit_segment!(&mut audit, || {
-            let mut qs_write = self.qs.write();
+pub struct SessionRevokeMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub req: SessionRevokeRequest,
+}
 
-            let del = match DeleteEvent::from_message(&mut audit, msg, &qs_write) {
-                Ok(d) => d,
-                Err(e) => {
-                    audit_log!(audit, "Failed to begin delete: {:?}", e);
-                    return Err(e);
-                }
-            };
+impl SessionRevokeMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, req: SessionRevokeRequest) -> Self {
+        SessionRevokeMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+        }
+    }
+}
 
-            audit_log!(audit, "Begin delete event {:?}", del);
+impl Message for SessionRevokeMessage {
+    type Result = Result<SessionRevokeResponse, OperationError>;
+}
 
-            qs_write
-                .delete(&mut audit, &del)
-                .and_then(|_| qs_write.commit(&mut audit).map(|_| OperationResponse {}))
-        });
-        self.log.do_send(audit);
-        res
-    }
+pub struct TOTPGenerateMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
 }
 
-// Need an auth session storage. LRU?
-// requires a lock ...
-// needs session id, entry, etc.
+impl TOTPGenerateMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>) -> Self {
+        TOTPGenerateMessage {
+            eventid: eventid,
+            uat: uat,
+        }
+    }
+}
 
-impl Handler<AuthMessage> for QueryServerV1 {
-    type Result = Result<AuthResponse, OperationError>;
+impl Message for TOTPGenerateMessage {
+    type Result = Result<TOTPGenerateResponse, OperationError>;
+}
 
-    fn handle(&mut self, msg: AuthMessage, _: &mut Self::Context) -> Self::Result {
-        // This is probably the first function that really implements logic
-        // "on top" of the db server concept. In this case we check if
-        // the credentials provided is sufficient to say if someone is
-        // "authenticated" or not.
-        let mut audit = AuditScope::new("auth");
-        let res = audit_segment!(&mut audit, || {
-            audit_log!(audit, "Begin auth event {:?}", msg);
+pub struct TOTPVerifyMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub req: TOTPVerifyRequest,
+}
 
-            // Destructure it.
-            // Convert the AuthRequest to an AuthEvent that the idm server
-            // can use.
+impl TOTPVerifyMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, req: TOTPVerifyRequest) -> Self {
+        TOTPVerifyMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+        }
+    }
+}
 
-            let mut idm_write = self.idms.write();
+impl Message for TOTPVerifyMessage {
+    type Result = Result<TOTPVerifyResponse, OperationError>;
+}
 
-            let ae = try_audit!(audit, AuthEvent::from_message(msg));
+pub struct WebauthnGenerateMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+}
 
-            let ct = SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .expect("Clock failure!");
+impl WebauthnGenerateMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>) -> Self {
+        WebauthnGenerateMessage {
+            eventid: eventid,
+            uat: uat,
+        }
+    }
+}
 
-            // Trigger a session clean *before* we take any auth steps.
-            // It's important to do this before to ensure that timeouts on
-            // the session are enforced.
-            idm_write.expire_auth_sessions(ct);
+impl Message for WebauthnGenerateMessage {
+    type Result = Result<WebauthnGenerateResponse, OperationError>;
+}
 
-            // Generally things like auth denied are in Ok() msgs
-            // so true errors should always trigger a rollback.
-            let r = idm_write
-                .auth(&mut audit, &ae, ct)
-                .and_then(|r| idm_write.commit().map(|_| r));
+pub struct WebauthnRegisterMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub req: WebauthnRegisterRequest,
+}
 
-            audit_log!(audit, "Sending result -> {:?}", r);
-            // Build the result.
-            r.map(|r| r.response())
-        });
-        // At the end of the event we send it for logging.
-        self.log.do_send(audit);
-        res
+impl WebauthnRegisterMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, req: WebauthnRegisterRequest) -> Self {
+        WebauthnRegisterMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+        }
     }
 }
 
-impl Handler<WhoamiMessage> for QueryServerV1 {
-    type Result = Result<WhoamiResponse, OperationError>;
+impl Message for WebauthnRegisterMessage {
+    type Result = Result<WebauthnRegisterResponse, OperationError>;
+}
 
-    fn handle(&mut self, msg: WhoamiMessage, _: &mut Self::Context) -> Self::Result {
-        let mut audit = AuditScope::new("whoami");
-        let res = audit_segment!(&mut audit, || {
-            // TODO #62: Move this to IdmServer!!!
-            // Begin a read
-            let qs_read = self.qs.read();
+pub struct WebauthnListMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+}
 
-            // Make an event from the whoami request. This will process the event and
+impl WebauthnListMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>) -> Self {
+        WebauthnListMessage {
+            eventid: eventid,
+            uat: uat,
+        }
+    }
+}
+
+impl Message for WebauthnListMessage {
+    type Result = Result<WebauthnListResponse, OperationError>;
+}
+
+pub struct WebauthnRemoveMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub req: WebauthnRemoveRequest,
+}
+
+impl WebauthnRemoveMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, req: WebauthnRemoveRequest) -> Self {
+        WebauthnRemoveMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+        }
+    }
+}
+
+impl Message for WebauthnRemoveMessage {
+    type Result = Result<WebauthnRemoveResponse, OperationError>;
+}
+
+pub struct BackupCodesGenerateMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+}
+
+impl BackupCodesGenerateMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>) -> Self {
+        BackupCodesGenerateMessage {
+            eventid: eventid,
+            uat: uat,
+        }
+    }
+}
+
+impl Message for BackupCodesGenerateMessage {
+    type Result = Result<BackupCodesGenerateResponse, OperationError>;
+}
+
+pub struct CredentialStatusMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+}
+
+impl CredentialStatusMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>) -> Self {
+        CredentialStatusMessage {
+            eventid: eventid,
+            uat: uat,
+        }
+    }
+}
+
+impl Message for CredentialStatusMessage {
+    type Result = Result<CredentialStatusResponse, OperationError>;
+}
+
+pub struct CredentialPolicyMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub req: CredentialPolicyRequest,
+}
+
+impl CredentialPolicyMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, req: CredentialPolicyRequest) -> Self {
+        CredentialPolicyMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+        }
+    }
+}
+
+impl Message for CredentialPolicyMessage {
+    type Result = Result<CredentialPolicyResponse, OperationError>;
+}
+
+pub struct CredentialChangeMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub req: CredentialChangeRequest,
+}
+
+impl CredentialChangeMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, req: CredentialChangeRequest) -> Self {
+        CredentialChangeMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+        }
+    }
+}
+
+impl Message for CredentialChangeMessage {
+    type Result = Result<CredentialChangeResponse, OperationError>;
+}
+
+pub struct ApiTokenGenerateMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub req: ApiTokenGenerateRequest,
+}
+
+impl ApiTokenGenerateMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, req: ApiTokenGenerateRequest) -> Self {
+        ApiTokenGenerateMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+        }
+    }
+}
+
+impl Message for ApiTokenGenerateMessage {
+    type Result = Result<ApiTokenGenerateResponse, OperationError>;
+}
+
+pub struct ApiTokenListMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub req: ApiTokenListRequest,
+}
+
+impl ApiTokenListMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, req: ApiTokenListRequest) -> Self {
+        ApiTokenListMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+        }
+    }
+}
+
+impl Message for ApiTokenListMessage {
+    type Result = Result<ApiTokenListResponse, OperationError>;
+}
+
+pub struct ApiTokenDestroyMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub req: ApiTokenDestroyRequest,
+}
+
+impl ApiTokenDestroyMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, req: ApiTokenDestroyRequest) -> Self {
+        ApiTokenDestroyMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+        }
+    }
+}
+
+impl Message for ApiTokenDestroyMessage {
+    type Result = Result<ApiTokenDestroyResponse, OperationError>;
+}
+
+pub struct RadiusSecretGenerateMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+}
+
+impl RadiusSecretGenerateMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>) -> Self {
+        RadiusSecretGenerateMessage {
+            eventid: eventid,
+            uat: uat,
+        }
+    }
+}
+
+impl Message for RadiusSecretGenerateMessage {
+    type Result = Result<RadiusSecretGenerateResponse, OperationError>;
+}
+
+pub struct RadiusAuthTokenMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub account: String,
+}
+
+impl RadiusAuthTokenMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, account: String) -> Self {
+        RadiusAuthTokenMessage {
+            eventid: eventid,
+            uat: uat,
+            account: account,
+        }
+    }
+}
+
+impl Message for RadiusAuthTokenMessage {
+    type Result = Result<RadiusAuthToken, OperationError>;
+}
+
+pub struct SshPublicKeysMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub account: String,
+}
+
+impl SshPublicKeysMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, account: String) -> Self {
+        SshPublicKeysMessage {
+            eventid: eventid,
+            uat: uat,
+            account: account,
+        }
+    }
+}
+
+impl Message for SshPublicKeysMessage {
+    type Result = Result<Vec<String>, OperationError>;
+}
+
+pub struct UnixUserTokenMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub id: String,
+}
+
+impl UnixUserTokenMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, id: String) -> Self {
+        UnixUserTokenMessage {
+            eventid: eventid,
+            uat: uat,
+            id: id,
+        }
+    }
+}
+
+impl Message for UnixUserTokenMessage {
+    type Result = Result<UnixUserToken, OperationError>;
+}
+
+pub struct UnixGroupTokenMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub id: String,
+}
+
+impl UnixGroupTokenMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, id: String) -> Self {
+        UnixGroupTokenMessage {
+            eventid: eventid,
+            uat: uat,
+            id: id,
+        }
+    }
+}
+
+impl Message for UnixGroupTokenMessage {
+    type Result = Result<UnixGroupToken, OperationError>;
+}
+
+pub struct UnixAuthMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub req: UnixAuthRequest,
+}
+
+impl UnixAuthMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, req: UnixAuthRequest) -> Self {
+        UnixAuthMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+        }
+    }
+}
+
+impl Message for UnixAuthMessage {
+    type Result = Result<Option<UnixUserToken>, OperationError>;
+}
+
+pub struct Oauth2AuthorizeMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub req: Oauth2AuthorizeRequest,
+}
+
+impl Oauth2AuthorizeMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, req: Oauth2AuthorizeRequest) -> Self {
+        Oauth2AuthorizeMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+        }
+    }
+}
+
+impl Message for Oauth2AuthorizeMessage {
+    type Result = Result<String, OperationError>;
+}
+
+pub struct Oauth2TokenMessage {
+    pub eventid: Uuid,
+    pub req: Oauth2TokenRequest,
+}
+
+impl Oauth2TokenMessage {
+    pub fn new(eventid: Uuid, req: Oauth2TokenRequest) -> Self {
+        Oauth2TokenMessage {
+            eventid: eventid,
+            req: req,
+        }
+    }
+}
+
+impl Message for Oauth2TokenMessage {
+    type Result = Result<Oauth2CodeGrant, OperationError>;
+}
+
+// A client authenticating each request with a service account's api token,
+// as scim clients do, rather than beginning a session.
+pub struct ApiTokenAuthMessage {
+    pub eventid: Uuid,
+    pub token: String,
+}
+
+impl ApiTokenAuthMessage {
+    pub fn new(eventid: Uuid, token: String) -> Self {
+        ApiTokenAuthMessage {
+            eventid: eventid,
+            token: token,
+        }
+    }
+}
+
+impl Message for ApiTokenAuthMessage {
+    type Result = Result<UserAuthToken, OperationError>;
+}
+
+pub struct AccessCheckMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub req: AccessCheckRequest,
+}
+
+impl AccessCheckMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, req: AccessCheckRequest) -> Self {
+        AccessCheckMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+        }
+    }
+}
+
+impl Message for AccessCheckMessage {
+    type Result = Result<AccessCheckResponse, OperationError>;
+}
+
+pub struct AuditListMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub req: AuditListRequest,
+}
+
+impl AuditListMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, req: AuditListRequest) -> Self {
+        AuditListMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+        }
+    }
+}
+
+impl Message for AuditListMessage {
+    type Result = Result<AuditListResponse, OperationError>;
+}
+
+pub struct ChangesMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub req: ChangesRequest,
+}
+
+impl ChangesMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, req: ChangesRequest) -> Self {
+        ChangesMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+        }
+    }
+}
+
+impl Message for ChangesMessage {
+    type Result = Result<ChangesResponse, OperationError>;
+}
+
+// As a streamed search, the records of an export are sent down tx as they
+// are read, and it ends with Done or the error that stopped it.
+pub struct ExportMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub req: ExportRequest,
+    pub tx: mpsc::Sender<Result<ExportStreamItem, OperationError>>,
+}
+
+impl ExportMessage {
+    pub fn new(
+        eventid: Uuid,
+        uat: Option<UserAuthToken>,
+        req: ExportRequest,
+        tx: mpsc::Sender<Result<ExportStreamItem, OperationError>>,
+    ) -> Self {
+        ExportMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+            tx: tx,
+        }
+    }
+}
+
+impl Message for ExportMessage {
+    type Result = ();
+}
+
+pub struct BackupMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub req: BackupRequest,
+}
+
+impl BackupMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, req: BackupRequest) -> Self {
+        BackupMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+        }
+    }
+}
+
+impl Message for BackupMessage {
+    type Result = Result<BackupResponse, OperationError>;
+}
+
+pub struct ReindexMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub req: ReindexRequest,
+}
+
+impl ReindexMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, req: ReindexRequest) -> Self {
+        ReindexMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+        }
+    }
+}
+
+impl Message for ReindexMessage {
+    type Result = Result<ReindexResponse, OperationError>;
+}
+
+pub struct VacuumMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub req: VacuumRequest,
+}
+
+impl VacuumMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, req: VacuumRequest) -> Self {
+        VacuumMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+        }
+    }
+}
+
+impl Message for VacuumMessage {
+    type Result = Result<VacuumResponse, OperationError>;
+}
+
+pub struct IndexStatusMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub req: IndexStatusRequest,
+}
+
+impl IndexStatusMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, req: IndexStatusRequest) -> Self {
+        IndexStatusMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+        }
+    }
+}
+
+impl Message for IndexStatusMessage {
+    type Result = Result<IndexStatusResponse, OperationError>;
+}
+
+// How many entries are stored, for a metrics scrape.
+pub struct EntryCountMessage {
+    pub eventid: Uuid,
+}
+
+impl EntryCountMessage {
+    pub fn new(eventid: Uuid) -> Self {
+        EntryCountMessage { eventid: eventid }
+    }
+}
+
+impl Message for EntryCountMessage {
+    type Result = Result<usize, OperationError>;
+}
+
+pub struct ReadinessMessage {
+    pub eventid: Uuid,
+}
+
+impl ReadinessMessage {
+    pub fn new(eventid: Uuid) -> Self {
+        ReadinessMessage { eventid: eventid }
+    }
+}
+
+impl Message for ReadinessMessage {
+    type Result = Result<HealthResponse, OperationError>;
+}
+
+// The requests of the admin socket. Nothing authenticates to it, so these
+// are made internally, as whoever can reach the socket is trusted.
+pub struct AdminRecoverAccountMessage {
+    pub eventid: Uuid,
+    pub name: String,
+}
+
+impl AdminRecoverAccountMessage {
+    pub fn new(eventid: Uuid, name: String) -> Self {
+        AdminRecoverAccountMessage {
+            eventid: eventid,
+            name: name,
+        }
+    }
+}
+
+impl Message for AdminRecoverAccountMessage {
+    type Result = Result<String, OperationError>;
+}
+
+pub struct AdminDomainInfoMessage {
+    pub eventid: Uuid,
+}
+
+impl AdminDomainInfoMessage {
+    pub fn new(eventid: Uuid) -> Self {
+        AdminDomainInfoMessage { eventid: eventid }
+    }
+}
+
+impl Message for AdminDomainInfoMessage {
+    type Result = Result<DomainInfo, OperationError>;
+}
+
+pub struct AdminBackupMessage {
+    pub eventid: Uuid,
+    pub path: String,
+}
+
+impl AdminBackupMessage {
+    pub fn new(eventid: Uuid, path: String) -> Self {
+        AdminBackupMessage {
+            eventid: eventid,
+            path: path,
+        }
+    }
+}
+
+impl Message for AdminBackupMessage {
+    type Result = Result<(), OperationError>;
+}
+
+pub struct AdminVerifyMessage {
+    pub eventid: Uuid,
+    pub req: VerifyRequest,
+}
+
+impl AdminVerifyMessage {
+    pub fn new(eventid: Uuid, req: VerifyRequest) -> Self {
+        AdminVerifyMessage {
+            eventid: eventid,
+            req: req,
+        }
+    }
+}
+
+impl Message for AdminVerifyMessage {
+    type Result = Result<VerifyResponse, OperationError>;
+}
+
+pub struct AdminRepairMessage {
+    pub eventid: Uuid,
+    pub req: RepairRequest,
+}
+
+impl AdminRepairMessage {
+    pub fn new(eventid: Uuid, req: RepairRequest) -> Self {
+        AdminRepairMessage {
+            eventid: eventid,
+            req: req,
+        }
+    }
+}
+
+impl Message for AdminRepairMessage {
+    type Result = Result<RepairResponse, OperationError>;
+}
+
+pub struct EffectiveAccessMessage {
+    pub eventid: Uuid,
+    pub uat: Option<UserAuthToken>,
+    pub req: EffectiveAccessRequest,
+}
+
+impl EffectiveAccessMessage {
+    pub fn new(eventid: Uuid, uat: Option<UserAuthToken>, req: EffectiveAccessRequest) -> Self {
+        EffectiveAccessMessage {
+            eventid: eventid,
+            uat: uat,
+            req: req,
+        }
+    }
+}
+
+impl Message for EffectiveAccessMessage {
+    type Result = Result<EffectiveAccessResponse, OperationError>;
+}
+
+pub struct QueryServerV1 {
+    log: actix::Addr<EventLog>,
+    qs: QueryServer,
+    idms: Arc<IdmServer>,
+    backup: Option<OnlineBackup>,
+}
+
+impl Actor for QueryServerV1 {
+    type Context = SyncContext<Self>;
+
+    fn started(&mut self, _ctx: &mut Self::Context) {
+        // ctx.set_mailbox_capacity(1 << 31);
+    }
+}
+
+impl QueryServerV1 {
+    pub fn new(
+        log: actix::Addr<EventLog>,
+        qs: QueryServer,
+        idms: Arc<IdmServer>,
+        backup: Option<OnlineBackup>,
+    ) -> Self {
+        log_event!(log, "Starting query server v1 worker ...");
+        QueryServerV1 {
+            log: log,
+            qs: qs,
+            idms: idms,
+            backup: backup,
+        }
+    }
+
+    pub fn start(
+        log: actix::Addr<EventLog>,
+        query_server: QueryServer,
+        idms: Arc<IdmServer>,
+        backup: Option<OnlineBackup>,
+        threads: usize,
+    ) -> actix::Addr<QueryServerV1> {
+        SyncArbiter::start(threads, move || {
+            QueryServerV1::new(
+                log.clone(),
+                query_server.clone(),
+                idms.clone(),
+                backup.clone(),
+            )
+        })
+    }
+
+    // Backups are taken from a single read, so they are consistent while
+    // writes carry on around them.
+    fn online_backup(&self, audit: &mut AuditScope) -> Result<String, OperationError> {
+        let ob = match &self.backup {
+            Some(ob) => ob,
+            None => {
+                audit_log!(audit, "Online backups are not configured");
+                return Err(OperationError::InvalidState);
+            }
+        };
+        let ct = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("Clock failure!");
+        let qs_read = self.qs.read();
+        qs_read
+            .get_be_txn()
+            .backup_rotate(audit, ob.path.as_str(), ob.versions, ct)
+    }
+}
+
+// The server only recieves "Message" structures, which
+// are whole self contained DB operations with all parsing
+// required complete. We still need to do certain validation steps, but
+// at this point our just is just to route to do_<action>
+
+impl Handler<SearchMessage> for QueryServerV1 {
+    type Result = Result<SearchResponse, OperationError>;
+
+    fn handle(&mut self, msg: SearchMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("search", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            audit_log!(audit, "search: filter -> {}", msg.req.filter);
+            // Only admins are given the plan, as it shows how many entries
+            // matched before access controls were applied.
+            let eventid = msg.eventid;
+            let trace = msg.req.trace;
+            let plan_to_caller = trace
+                && msg
+                    .uat
+                    .as_ref()
+                    .map(|uat| uat.groups.iter().any(|g| g.uuid == _UUID_IDM_ADMINS))
+                    .unwrap_or(false);
+            // Begin a read
+            let qs_read = self.qs.read();
+
+            // Make an event from the request
+            let srch = match SearchEvent::from_message(&mut audit, msg, &qs_read) {
+                Ok(s) => s,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin search: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            audit_log!(audit, "Begin event {:?}", srch);
+
+            match qs_read.search_ext_plan(&mut audit, &srch) {
+                Ok((entries, next_cookie, plan)) => {
+                    if trace {
+                        audit_log!(audit, "search plan -> {}", plan);
+                        info!("Search plan for {} -> {}", eventid, plan);
+                    } else {
+                        debug!("Search plan for {} -> {}", eventid, plan);
+                    }
+                    SearchResult::new(&mut audit, &qs_read, entries, next_cookie).map(|ok_sr| {
+                        let mut response = ok_sr.response();
+                        if plan_to_caller {
+                            response.plan = Some(plan);
+                        }
+                        response
+                    })
+                }
+                Err(e) => Err(e),
+            }
+        });
+        // At the end of the event we send it for logging.
+        self.log.do_send(audit);
+        res
+    }
+}
+
+// The channel is bounded, so while the client is slow to read, this waits
+// rather than reading further ahead. That keeps the read transaction open
+// for as long as the client takes.
+impl Handler<SearchStreamMessage> for QueryServerV1 {
+    type Result = ();
+
+    fn handle(&mut self, msg: SearchStreamMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("search_stream", msg.eventid);
+        let SearchStreamMessage {
+            eventid,
+            uat,
+            req,
+            tx,
+        } = msg;
+        let mut tx = tx.wait();
+        let res = audit_segment!(&mut audit, || {
+            audit_log!(audit, "search_stream: filter -> {}", req.filter);
+            let trace = req.trace;
+            // Begin a read
+            let qs_read = self.qs.read();
+
+            let msg = SearchMessage::new(eventid, uat, req);
+            let srch = match SearchEvent::from_message(&mut audit, msg, &qs_read) {
+                Ok(s) => s,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin search: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            audit_log!(audit, "Begin event {:?}", srch);
+
+            let plan = qs_read.search_ext_each(&mut audit, &srch, &mut |audit, e| {
+                let pe = e.into_pe(audit, &qs_read)?;
+                // This only fails once the client has gone, so there is no
+                // one left to read the rest.
+                tx.send(Ok(SearchStreamItem::Entry(pe))).map_err(|_| {
+                    audit_log!(audit, "search_stream: client went away");
+                    OperationError::InvalidState
+                })
+            })?;
+            if trace {
+                audit_log!(audit, "search plan -> {}", plan);
+                info!("Search plan for {} -> {}", eventid, plan);
+            } else {
+                debug!("Search plan for {} -> {}", eventid, plan);
+            }
+            Ok(())
+        });
+        // As above, if this fails there is no one to tell.
+        let _ = tx
+            .send(res.map(|_| SearchStreamItem::Done))
+            .and_then(|_| tx.flush());
+        // At the end of the event we send it for logging.
+        self.log.do_send(audit);
+    }
+}
+
+impl Handler<SearchCountMessage> for QueryServerV1 {
+    type Result = Result<SearchCountResponse, OperationError>;
+
+    fn handle(&mut self, msg: SearchCountMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("search_count", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            audit_log!(audit, "search_count: filter -> {}", msg.req.filter);
+            // Begin a read
+            let qs_read = self.qs.read();
+
+            let exists = msg.req.exists;
+            let srch = match SearchEvent::from_count_message(&mut audit, msg, &qs_read) {
+                Ok(s) => s,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin search count: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            audit_log!(audit, "Begin event {:?}", srch);
+
+            let res = if exists {
+                qs_read
+                    .search_exists(&mut audit, &srch)
+                    .map(|b| if b { 1 } else { 0 })
+            } else {
+                qs_read.search_count(&mut audit, &srch)
+            };
+            res.map(|count| SearchCountResponse::new(count))
+        });
+        // At the end of the event we send it for logging.
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<CompareMessage> for QueryServerV1 {
+    type Result = Result<CompareResponse, OperationError>;
+
+    fn handle(&mut self, msg: CompareMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("compare", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            audit_log!(audit, "compare: filter -> {}", msg.req.filter);
+            let qs_read = self.qs.read();
+
+            let cmp = match CompareEvent::from_message(&mut audit, msg, &qs_read) {
+                Ok(c) => c,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin compare: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            audit_log!(audit, "Begin event {:?}", cmp);
+
+            qs_read
+                .compare(&mut audit, &cmp)
+                .map(|matched| CompareResponse::new(matched))
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<SchemaMessage> for QueryServerV1 {
+    type Result = Result<SchemaResponse, OperationError>;
+
+    fn handle(&mut self, msg: SchemaMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("schema", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            audit_log!(audit, "schema: request -> {:?}", msg.req);
+            let qs_read = self.qs.read();
+
+            let srch = match SearchEvent::from_schema_message(&mut audit, msg, &qs_read) {
+                Ok(s) => s,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin schema search: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            audit_log!(audit, "Begin event {:?}", srch);
+
+            qs_read
+                .search_ext(&mut audit, &srch)
+                .map(|entries| SchemaResult::new(entries).response())
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<SearchRecycledMessage> for QueryServerV1 {
+    type Result = Result<SearchRecycledResponse, OperationError>;
+
+    fn handle(&mut self, msg: SearchRecycledMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("search_recycled", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            audit_log!(audit, "search_recycled: filter -> {}", msg.req.filter);
+            let qs_read = self.qs.read();
+
+            let srch = match SearchEvent::from_rec_message(&mut audit, msg, &qs_read) {
+                Ok(s) => s,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin recycled search: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            audit_log!(audit, "Begin event {:?}", srch);
+
+            match qs_read.search_ext(&mut audit, &srch) {
+                Ok(entries) => SearchResult::new(&mut audit, &qs_read, entries, None)
+                    .map(|ok_sr| SearchRecycledResponse::new(ok_sr.response().entries)),
+                Err(e) => Err(e),
+            }
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<ReviveRecycledMessage> for QueryServerV1 {
+    type Result = Result<ReviveRecycledResponse, OperationError>;
+
+    fn handle(&mut self, msg: ReviveRecycledMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("revive_recycled", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            audit_log!(audit, "revive_recycled: filter -> {}", msg.req.filter);
+            let mut qs_write = self.qs.write();
+
+            let rev = match ReviveRecycledEvent::from_message(&mut audit, msg, &qs_write) {
+                Ok(r) => r,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin revive: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            audit_log!(audit, "Begin revive event {:?}", rev);
+
+            qs_write
+                .revive_recycled(&mut audit, &rev)
+                .and_then(|revived| {
+                    qs_write.commit(&mut audit).map(|_| {
+                        ReviveRecycledResponse::new(
+                            revived
+                                .iter()
+                                .map(|u| u.to_hyphenated_ref().to_string())
+                                .collect(),
+                        )
+                    })
+                })
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<AccessCheckMessage> for QueryServerV1 {
+    type Result = Result<AccessCheckResponse, OperationError>;
+
+    fn handle(&mut self, msg: AccessCheckMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("access_check", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            audit_log!(audit, "access_check: filter -> {}", msg.req.filter);
+            // Building the modlist needs a write transaction, but it is never
+            // committed.
+            let qs_write = self.qs.write();
+
+            let ace = match AccessCheckEvent::from_message(&mut audit, msg, &qs_write) {
+                Ok(a) => a,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin access check: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            audit_log!(audit, "Begin access check event {:?}", ace);
+
+            qs_write
+                .access_check(&mut audit, &ace)
+                .map(|entries| AccessCheckResponse::new(entries))
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<EffectiveAccessMessage> for QueryServerV1 {
+    type Result = Result<EffectiveAccessResponse, OperationError>;
+
+    fn handle(&mut self, msg: EffectiveAccessMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("effective_access", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            audit_log!(audit, "effective_access: target -> {}", msg.req.target);
+            // Begin a read
+            let qs_read = self.qs.read();
+
+            let eae = match EffectiveAccessEvent::from_message(&mut audit, msg, &qs_read) {
+                Ok(e) => e,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin effective access: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            audit_log!(audit, "Begin event {:?}", eae);
+
+            qs_read
+                .effective_access(&mut audit, &eae)
+                .map(|entries| EffectiveAccessResponse::new(entries))
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<AuditListMessage> for QueryServerV1 {
+    type Result = Result<AuditListResponse, OperationError>;
+
+    fn handle(&mut self, msg: AuditListMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("audit_list", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            // Begin a read
+            let qs_read = self.qs.read();
+
+            let ale = match AuditListEvent::from_message(&mut audit, msg, &qs_read) {
+                Ok(a) => a,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin audit list: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            audit_log!(audit, "Begin event {:?}", ale);
+
+            qs_read
+                .audit_list(&mut audit, &ale)
+                .map(|records| AuditListResponse::new(records))
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<ChangesMessage> for QueryServerV1 {
+    type Result = Result<ChangesResponse, OperationError>;
+
+    fn handle(&mut self, msg: ChangesMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("changes", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let qs_read = self.qs.read();
+
+            let che = match ChangesEvent::from_message(&mut audit, msg) {
+                Ok(c) => c,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin changes: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            audit_log!(audit, "Begin event {:?}", che);
+
+            qs_read.changes_since(&mut audit, &che)
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+// The whole export is read from one read transaction, so the cursor given at
+// the end is exactly as up to date as the records before it.
+impl Handler<ExportMessage> for QueryServerV1 {
+    type Result = ();
+
+    fn handle(&mut self, msg: ExportMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("export", msg.eventid);
+        let ExportMessage { uat, req, tx, .. } = msg;
+        let mut tx = tx.wait();
+        let res = audit_segment!(&mut audit, || {
+            let qs_read = self.qs.read();
+
+            let ee = match ExportEvent::from_request(&mut audit, uat, &req, &qs_read) {
+                Ok(e) => e,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin export: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            audit_log!(audit, "Begin event {:?}", ee);
+
+            qs_read.export_each(&mut audit, &ee, &mut |audit, r| {
+                let item = match r {
+                    ExportRecord::Entry(e) => ExportStreamItem::Entry(e.into_pe(audit, &qs_read)?),
+                    ExportRecord::Deleted(u) => {
+                        ExportStreamItem::Deleted(u.to_hyphenated_ref().to_string())
+                    }
+                };
+                tx.send(Ok(item)).map_err(|_| {
+                    audit_log!(audit, "export: client went away");
+                    OperationError::InvalidState
+                })
+            })
+        });
+        let _ = tx
+            .send(res.map(|cid| ExportStreamItem::Done(cid.map(|c| c.to_string()))))
+            .and_then(|_| tx.flush());
+        self.log.do_send(audit);
+    }
+}
+
+impl Handler<EntryCountMessage> for QueryServerV1 {
+    type Result = Result<usize, OperationError>;
+
+    fn handle(&mut self, msg: EntryCountMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("entry_count", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let qs_read = self.qs.read();
+            qs_read.get_be_txn().count_entries(&mut audit)
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<ReadinessMessage> for QueryServerV1 {
+    type Result = Result<HealthResponse, OperationError>;
+
+    fn handle(&mut self, msg: ReadinessMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("readiness", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let hr = self.qs.health(&mut audit);
+            audit_log!(audit, "Readiness result: {:?}", hr);
+            Ok(hr)
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<BackupMessage> for QueryServerV1 {
+    type Result = Result<BackupResponse, OperationError>;
+
+    fn handle(&mut self, msg: BackupMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("backup", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let be = {
+                let qs_read = self.qs.read();
+                match BackupEvent::from_message(&mut audit, msg, &qs_read) {
+                    Ok(b) => b,
+                    Err(e) => {
+                        audit_log!(audit, "Failed to begin backup: {:?}", e);
+                        return Err(e);
+                    }
+                }
+            };
+
+            audit_log!(audit, "Begin event {:?}", be);
+
+            self.online_backup(&mut audit).map(|path| {
+                info!("Online backup written to {}", path);
+                BackupResponse::new(path)
+            })
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<AdminRecoverAccountMessage> for QueryServerV1 {
+    type Result = Result<String, OperationError>;
+
+    fn handle(&mut self, msg: AdminRecoverAccountMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("admin_recover_account", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            audit_log!(audit, "Begin admin socket recovery of {}", msg.name);
+            let mut idms_prox_write = self.idms.proxy_write();
+            let pw = idms_prox_write.recover_account_local(&mut audit, msg.name.clone())?;
+            idms_prox_write.commit(&mut audit).map(|_| {
+                info!("Recovered {} through the admin socket", msg.name);
+                pw
+            })
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<AdminDomainInfoMessage> for QueryServerV1 {
+    type Result = Result<DomainInfo, OperationError>;
+
+    fn handle(&mut self, msg: AdminDomainInfoMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("admin_domain_info", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let qs_read = self.qs.read();
+            read_domain_info(&mut audit, &qs_read)
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<AdminBackupMessage> for QueryServerV1 {
+    type Result = Result<(), OperationError>;
+
+    fn handle(&mut self, msg: AdminBackupMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("admin_backup", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let qs_read = self.qs.read();
+            qs_read
+                .get_be_txn()
+                .backup(&mut audit, msg.path.as_str())
+                .map(|_| info!("Backup written to {} through the admin socket", msg.path))
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<AdminVerifyMessage> for QueryServerV1 {
+    type Result = Result<VerifyResponse, OperationError>;
+
+    fn handle(&mut self, msg: AdminVerifyMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("admin_verify", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let results = self.qs.verify_checks(&mut audit, msg.req.checks.as_slice());
+            if results.len() != 0 {
+                error!(
+                    "Verification through the admin socket failed -> {:?}",
+                    results
+                );
+            }
+            Ok(VerifyResponse::new(results))
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<AdminRepairMessage> for QueryServerV1 {
+    type Result = Result<RepairResponse, OperationError>;
+
+    fn handle(&mut self, msg: AdminRepairMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("admin_repair", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            self.qs.repair_consistency(&mut audit, msg.req.dry_run)
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<ReindexMessage> for QueryServerV1 {
+    type Result = Result<ReindexResponse, OperationError>;
+
+    fn handle(&mut self, msg: ReindexMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("reindex", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let re = {
+                let qs_read = self.qs.read();
+                match ReindexEvent::from_message(&mut audit, msg, &qs_read) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        audit_log!(audit, "Failed to begin reindex: {:?}", e);
+                        return Err(e);
+                    }
+                }
+            };
+
+            audit_log!(audit, "Begin event {:?}", re);
+
+            let mut qs_write = self.qs.write();
+            qs_write
+                .reindex(&mut audit)
+                .and_then(|counts| qs_write.commit(&mut audit).map(|_| counts))
+                .map(|counts| {
+                    info!("Reindexed {} indexes", counts.len());
+                    ReindexResponse::new(counts)
+                })
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<VacuumMessage> for QueryServerV1 {
+    type Result = Result<VacuumResponse, OperationError>;
+
+    fn handle(&mut self, msg: VacuumMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("vacuum", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            // The read is ended before the vacuum, as it can't proceed while
+            // there are any.
+            let ve = {
+                let qs_read = self.qs.read();
+                match VacuumEvent::from_message(&mut audit, msg, &qs_read) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        audit_log!(audit, "Failed to begin vacuum: {:?}", e);
+                        return Err(e);
+                    }
+                }
+            };
+
+            audit_log!(audit, "Begin event {:?}", ve);
+
+            self.qs.vacuum(&mut audit).map(|_| {
+                info!("Database vacuumed");
+                VacuumResponse::new()
+            })
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<IndexStatusMessage> for QueryServerV1 {
+    type Result = Result<IndexStatusResponse, OperationError>;
+
+    fn handle(&mut self, msg: IndexStatusMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("index_status", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let qs_read = self.qs.read();
+            let ie = match IndexStatusEvent::from_message(&mut audit, msg, &qs_read) {
+                Ok(i) => i,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin index status: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            audit_log!(audit, "Begin event {:?}", ie);
+
+            qs_read
+                .index_status(&mut audit)
+                .map(|indexes| IndexStatusResponse::new(indexes))
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<CreateMessage> for QueryServerV1 {
+    type Result = Result<CreateResponse, OperationError>;
+
+    fn handle(&mut self, msg: CreateMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("create", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let mut qs_write = self.qs.write();
+
+            let crt = match CreateEvent::from_message(&mut audit, msg, &qs_write) {
+                Ok(c) => c,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin create: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            audit_log!(audit, "Begin create event {:?}", crt);
+
+            qs_write.create_uuids(&mut audit, &crt).and_then(|uuids| {
+                qs_write.commit(&mut audit).map(|_| {
+                    CreateResponse::new(
+                        uuids
+                            .iter()
+                            .map(|u| u.to_hyphenated_ref().to_string())
+                            .collect(),
+                    )
+                })
+            })
+        });
+        // At the end of the event we send it for logging.
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<ModifyMessage> for QueryServerV1 {
+    type Result = Result<ModifyResponse, OperationError>;
+
+    fn handle(&mut self, msg: ModifyMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("modify", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            audit_log!(audit, "modify: filter -> {}", msg.req.filter);
+            let mut qs_write = self.qs.write();
+            let mdf = match ModifyEvent::from_message(&mut audit, msg, &qs_write) {
+                Ok(m) => m,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin modify: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            audit_log!(audit, "Begin modify event {:?}", mdf);
+
+            qs_write
+                .modify_count(&mut audit, &mdf)
+                .and_then(|modified| {
+                    qs_write
+                        .commit(&mut audit)
+                        .map(|_| ModifyResponse::new(modified))
+                })
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<ModifyBatchMessage> for QueryServerV1 {
+    type Result = Result<ModifyBatchResponse, OperationError>;
+
+    fn handle(&mut self, msg: ModifyBatchMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("modify_batch", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            audit_log!(audit, "modify_batch: {} changes", msg.req.changes.len());
+            let mut qs_write = self.qs.write();
+            let mbe = match ModifyBatchEvent::from_message(&mut audit, msg, &qs_write) {
+                Ok(m) => m,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin modify_batch: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            audit_log!(audit, "Begin modify_batch event {:?}", mbe);
+
+            qs_write
+                .modify_batch(&mut audit, &mbe)
+                .and_then(|modified| {
+                    qs_write
+                        .commit(&mut audit)
+                        .map(|_| ModifyBatchResponse::new(modified))
+                })
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<DeleteMessage> for QueryServerV1 {
+    type Result = Result<DeleteResponse, OperationError>;
+
+    fn handle(&mut self, msg: DeleteMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("delete", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            audit_log!(audit, "delete: filter -> {}", msg.req.filter);
+            let mut qs_write = self.qs.write();
+
+            let del = match DeleteEvent::from_message(&mut audit, msg, &qs_write) {
+                Ok(d) => d,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin delete: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            audit_log!(audit, "Begin delete event {:?}", del);
+
+            qs_write.delete_count(&mut audit, &del).and_then(|deleted| {
+                qs_write
+                    .commit(&mut audit)
+                    .map(|_| DeleteResponse::new(deleted))
+            })
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+// Need an auth session storage. LRU?
+// requires a lock ...
+// needs session id, entry, etc.
+
+impl Handler<AuthMessage> for QueryServerV1 {
+    type Result = Result<AuthResponse, OperationError>;
+
+    fn handle(&mut self, msg: AuthMessage, _: &mut Self::Context) -> Self::Result {
+        // This is probably the first function that really implements logic
+        // "on top" of the db server concept. In this case we check if
+        // the credentials provided is sufficient to say if someone is
+        // "authenticated" or not.
+        let mut audit = AuditScope::new_with_eventid("auth", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            audit_log!(audit, "Begin auth event {:?}", msg);
+
+            // Destructure it.
+            // Convert the AuthRequest to an AuthEvent that the idm server
+            // can use.
+
+            let mut idm_write = self.idms.write();
+
+            let ae = try_audit!(audit, AuthEvent::from_message(msg));
+
+            let ct = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("Clock failure!");
+
+            // Trigger a session clean *before* we take any auth steps.
+            // It's important to do this before to ensure that timeouts on
+            // the session are enforced.
+            idm_write.expire_auth_sessions(ct);
+
+            // Generally things like auth denied are in Ok() msgs
+            // so true errors should always trigger a rollback.
+            let r = idm_write
+                .auth(&mut audit, &ae, ct)
+                .and_then(|r| idm_write.commit().map(|_| r));
+
+            audit_log!(audit, "Sending result -> {:?}", r);
+            // Build the result.
+            r.map(|r| r.response())
+        });
+        // At the end of the event we send it for logging.
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<ReauthMessage> for QueryServerV1 {
+    type Result = Result<AuthResponse, OperationError>;
+
+    fn handle(&mut self, msg: ReauthMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("reauth", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            audit_log!(audit, "Begin reauth event {:?}", msg);
+
+            let mut idm_write = self.idms.write();
+
+            let ae = try_audit!(audit, AuthEvent::from_reauth_message(msg));
+
+            let ct = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("Clock failure!");
+
+            idm_write.expire_auth_sessions(ct);
+
+            // The credentials are then given as for any other auth.
+            let r = idm_write
+                .auth(&mut audit, &ae, ct)
+                .and_then(|r| idm_write.commit().map(|_| r));
+
+            audit_log!(audit, "Sending result -> {:?}", r);
+            r.map(|r| r.response())
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<WhoamiMessage> for QueryServerV1 {
+    type Result = Result<WhoamiResponse, OperationError>;
+
+    fn handle(&mut self, msg: WhoamiMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("whoami", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            // TODO #62: Move this to IdmServer!!!
+            // Begin a read
+            let qs_read = self.qs.read();
+
+            // Make an event from the whoami request. This will process the event and
             // generate a selfuuid search.
             //
             // This current handles the unauthenticated check, and will
@@ -345,37 +2109,710 @@ impl Handler<WhoamiMessage> for QueryServerV1 {
             // this far.
             let uat = msg.uat.clone().ok_or(OperationError::NotAuthenticated)?;
 
-            let srch = match SearchEvent::from_whoami_request(&mut audit, msg.uat, &qs_read) {
-                Ok(s) => s,
-                Err(e) => {
-                    audit_log!(audit, "Failed to begin whoami: {:?}", e);
-                    return Err(e);
+            // The token may have been issued before the account was given an
+            // expiry that has now passed.
+            let ct = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("Clock failure!");
+            let target =
+                Uuid::parse_str(uat.uuid.as_str()).map_err(|_| OperationError::InvalidUuid)?;
+            let entry = qs_read.internal_search_uuid(&mut audit, &target)?;
+            let account = Account::try_from_entry(&mut audit, entry, &qs_read)?;
+            if let Some(reason) = account.validity_denied(ct) {
+                audit_log!(audit, "whoami denied -> {:?}", reason);
+                return Err(OperationError::NotAuthenticated);
+            }
+
+            let srch = match SearchEvent::from_whoami_request(&mut audit, msg.uat, &qs_read) {
+                Ok(s) => s,
+                Err(e) => {
+                    audit_log!(audit, "Failed to begin whoami: {:?}", e);
+                    return Err(e);
+                }
+            };
+
+            audit_log!(audit, "Begin event {:?}", srch);
+
+            match qs_read.search_ext(&mut audit, &srch) {
+                Ok(mut entries) => {
+                    // assert there is only one ...
+                    match entries.len() {
+                        0 => Err(OperationError::NoMatchingEntries),
+                        1 => {
+                            let e = entries.pop().expect("Entry length mismatch!!!");
+                            // Now convert to a response, and return
+                            WhoamiResult::new(&mut audit, &qs_read, e, uat)
+                                .map(|ok_wr| ok_wr.response())
+                        }
+                        // Somehow we matched multiple, which should be impossible.
+                        _ => Err(OperationError::InvalidState),
+                    }
+                }
+                // Something else went wrong ...
+                Err(e) => Err(e),
+            }
+        });
+        // Should we log the final result?
+        // At the end of the event we send it for logging.
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<LogoutMessage> for QueryServerV1 {
+    type Result = Result<LogoutResponse, OperationError>;
+
+    fn handle(&mut self, msg: LogoutMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("logout", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let uat = msg.uat.ok_or(OperationError::NotAuthenticated)?;
+
+            let mut idm_write = self.idms.write();
+            idm_write
+                .logout(&mut audit, &uat)
+                .and_then(|_| idm_write.commit())
+                .map(|_| LogoutResponse::new())
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<SessionListMessage> for QueryServerV1 {
+    type Result = Result<SessionListResponse, OperationError>;
+
+    fn handle(&mut self, msg: SessionListMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("session_list", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let uat = msg.uat.ok_or(OperationError::NotAuthenticated)?;
+
+            // The account may be given by name or uuid.
+            let account = match Uuid::parse_str(msg.req.account.as_str()) {
+                Ok(u) => u,
+                Err(_) => {
+                    let qs_read = self.qs.read();
+                    try_audit!(
+                        audit,
+                        qs_read.name_to_uuid(&mut audit, msg.req.account.as_str())
+                    )
+                }
+            };
+
+            let ct = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("Clock failure!");
+
+            self.idms
+                .list_sessions(&mut audit, &uat, &account, ct)
+                .map(|sessions| SessionListResponse::new(sessions))
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<SessionRevokeMessage> for QueryServerV1 {
+    type Result = Result<SessionRevokeResponse, OperationError>;
+
+    fn handle(&mut self, msg: SessionRevokeMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("session_revoke", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let uat = msg.uat.ok_or(OperationError::NotAuthenticated)?;
+
+            let mut idm_write = self.idms.write();
+            idm_write
+                .revoke_session(&mut audit, &uat, &msg.req.sessionid)
+                .and_then(|_| idm_write.commit())
+                .map(|_| SessionRevokeResponse::new())
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<TOTPGenerateMessage> for QueryServerV1 {
+    type Result = Result<TOTPGenerateResponse, OperationError>;
+
+    fn handle(&mut self, msg: TOTPGenerateMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("totp_generate", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let uat = msg.uat.ok_or(OperationError::NotAuthenticated)?;
+
+            let ct = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("Clock failure!");
+            self.idms
+                .check_reauth(&mut audit, &uat, ProtectedOperation::CredentialChange, ct)?;
+
+            let mut idm_write = self.idms.write();
+            let secret = idm_write.generate_account_totp(&mut audit, &uat)?;
+            idm_write
+                .commit()
+                .map(|_| TOTPGenerateResponse::new(secret))
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<TOTPVerifyMessage> for QueryServerV1 {
+    type Result = Result<TOTPVerifyResponse, OperationError>;
+
+    fn handle(&mut self, msg: TOTPVerifyMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("totp_verify", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let uat = msg.uat.ok_or(OperationError::NotAuthenticated)?;
+            let target =
+                Uuid::parse_str(uat.uuid.as_str()).map_err(|_| OperationError::InvalidUuid)?;
+            let chal = msg
+                .req
+                .totp
+                .trim()
+                .parse::<u32>()
+                .map_err(|_| OperationError::InvalidTOTP)?;
+
+            let ct = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("Clock failure!");
+            self.idms
+                .check_reauth(&mut audit, &uat, ProtectedOperation::CredentialChange, ct)?;
+
+            let mut idm_write = self.idms.write();
+            let (totp, step) = idm_write.verify_account_totp(&mut audit, &uat, chal, ct)?;
+
+            // The credential must be written before the pending secret is
+            // discarded, else a failure here would lose it.
+            let mut idms_prox_write = self.idms.proxy_write();
+            let cred_uuid = idms_prox_write.set_account_totp(&mut audit, &target, totp)?;
+            idms_prox_write.commit(&mut audit)?;
+
+            idm_write.set_totp_last_step(cred_uuid, step);
+            idm_write.commit().map(|_| TOTPVerifyResponse::new())
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<WebauthnGenerateMessage> for QueryServerV1 {
+    type Result = Result<WebauthnGenerateResponse, OperationError>;
+
+    fn handle(&mut self, msg: WebauthnGenerateMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("webauthn_generate", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let uat = msg.uat.ok_or(OperationError::NotAuthenticated)?;
+
+            let ct = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("Clock failure!");
+            self.idms
+                .check_reauth(&mut audit, &uat, ProtectedOperation::CredentialChange, ct)?;
+
+            let mut idm_write = self.idms.write();
+            let challenge = idm_write.generate_account_webauthn(&mut audit, &uat)?;
+            idm_write
+                .commit()
+                .map(|_| WebauthnGenerateResponse::new(challenge))
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<WebauthnRegisterMessage> for QueryServerV1 {
+    type Result = Result<WebauthnRegisterResponse, OperationError>;
+
+    fn handle(&mut self, msg: WebauthnRegisterMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("webauthn_register", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let uat = msg.uat.ok_or(OperationError::NotAuthenticated)?;
+
+            let ct = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("Clock failure!");
+            self.idms
+                .check_reauth(&mut audit, &uat, ProtectedOperation::CredentialChange, ct)?;
+            let target =
+                Uuid::parse_str(uat.uuid.as_str()).map_err(|_| OperationError::InvalidUuid)?;
+
+            let mut idm_write = self.idms.write();
+            let token = idm_write.verify_account_webauthn(
+                &mut audit,
+                &uat,
+                msg.req.name.as_str(),
+                &msg.req.credential,
+            );
+            // The challenge is spent whether or not the token verified.
+            idm_write.commit()?;
+
+            let mut idms_prox_write = self.idms.proxy_write();
+            idms_prox_write.add_account_webauthn(&mut audit, &target, token?)?;
+            idms_prox_write
+                .commit(&mut audit)
+                .map(|_| WebauthnRegisterResponse::new())
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<WebauthnListMessage> for QueryServerV1 {
+    type Result = Result<WebauthnListResponse, OperationError>;
+
+    fn handle(&mut self, msg: WebauthnListMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("webauthn_list", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let uat = msg.uat.ok_or(OperationError::NotAuthenticated)?;
+
+            self.idms
+                .list_account_webauthn(&mut audit, &uat)
+                .map(|tokens| WebauthnListResponse::new(tokens))
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<WebauthnRemoveMessage> for QueryServerV1 {
+    type Result = Result<WebauthnRemoveResponse, OperationError>;
+
+    fn handle(&mut self, msg: WebauthnRemoveMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("webauthn_remove", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let uat = msg.uat.ok_or(OperationError::NotAuthenticated)?;
+
+            let ct = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("Clock failure!");
+            self.idms
+                .check_reauth(&mut audit, &uat, ProtectedOperation::CredentialChange, ct)?;
+            let target =
+                Uuid::parse_str(uat.uuid.as_str()).map_err(|_| OperationError::InvalidUuid)?;
+
+            let mut idms_prox_write = self.idms.proxy_write();
+            idms_prox_write.remove_account_webauthn(&mut audit, &target, msg.req.name.as_str())?;
+            idms_prox_write
+                .commit(&mut audit)
+                .map(|_| WebauthnRemoveResponse::new())
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<BackupCodesGenerateMessage> for QueryServerV1 {
+    type Result = Result<BackupCodesGenerateResponse, OperationError>;
+
+    fn handle(&mut self, msg: BackupCodesGenerateMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("backup_codes_generate", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let uat = msg.uat.ok_or(OperationError::NotAuthenticated)?;
+
+            let ct = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("Clock failure!");
+            self.idms
+                .check_reauth(&mut audit, &uat, ProtectedOperation::CredentialChange, ct)?;
+            let target =
+                Uuid::parse_str(uat.uuid.as_str()).map_err(|_| OperationError::InvalidUuid)?;
+
+            let mut idms_prox_write = self.idms.proxy_write();
+            let codes = idms_prox_write.generate_backup_codes(&mut audit, &target)?;
+            idms_prox_write
+                .commit(&mut audit)
+                .map(|_| BackupCodesGenerateResponse::new(codes))
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<CredentialStatusMessage> for QueryServerV1 {
+    type Result = Result<CredentialStatusResponse, OperationError>;
+
+    fn handle(&mut self, msg: CredentialStatusMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("credential_status", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let uat = msg.uat.ok_or(OperationError::NotAuthenticated)?;
+
+            self.idms.credential_status(&mut audit, &uat)
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<CredentialPolicyMessage> for QueryServerV1 {
+    type Result = Result<CredentialPolicyResponse, OperationError>;
+
+    fn handle(&mut self, msg: CredentialPolicyMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("credential_policy", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let uat = msg.uat.ok_or(OperationError::NotAuthenticated)?;
+
+            let ct = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("Clock failure!");
+            self.idms
+                .check_reauth(&mut audit, &uat, ProtectedOperation::CredentialChange, ct)?;
+            let target =
+                Uuid::parse_str(uat.uuid.as_str()).map_err(|_| OperationError::InvalidUuid)?;
+
+            let mut idms_prox_write = self.idms.proxy_write();
+            idms_prox_write.set_account_credential_policy(
+                &mut audit,
+                &target,
+                msg.req.policy.map(Policy::from),
+            )?;
+            idms_prox_write
+                .commit(&mut audit)
+                .map(|_| CredentialPolicyResponse::new())
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<CredentialChangeMessage> for QueryServerV1 {
+    type Result = Result<CredentialChangeResponse, OperationError>;
+
+    fn handle(&mut self, msg: CredentialChangeMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("credential_change", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let uat = msg.uat.ok_or(OperationError::NotAuthenticated)?;
+
+            let ct = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("Clock failure!");
+
+            let mut idm_write = self.idms.write();
+            let mut idms_prox_write = self.idms.proxy_write();
+            let (target, keep) = match &msg.req {
+                CredentialChangeRequest::SelfPassword { current, new } => {
+                    let target = idms_prox_write.self_set_account_password(
+                        &mut audit,
+                        &uat,
+                        current.as_ref().map(|s| s.as_str()),
+                        new.as_str(),
+                        ct,
+                    )?;
+                    // The session making the change is kept, unless it only
+                    // existed to change a password that had been reset.
+                    let keep = if uat.must_change_password {
+                        None
+                    } else {
+                        Some(uat.sessionid)
+                    };
+                    (target, keep)
+                }
+                CredentialChangeRequest::AdminReset {
+                    target,
+                    new,
+                    must_change,
+                } => {
+                    self.idms.check_reauth(
+                        &mut audit,
+                        &uat,
+                        ProtectedOperation::CredentialChange,
+                        ct,
+                    )?;
+                    let target = idms_prox_write.admin_set_account_password(
+                        &mut audit,
+                        &uat,
+                        target.as_str(),
+                        new.as_str(),
+                        *must_change,
+                    )?;
+                    (target, None)
+                }
+                CredentialChangeRequest::UnixPassword { target, new } => {
+                    self.idms.check_reauth(
+                        &mut audit,
+                        &uat,
+                        ProtectedOperation::CredentialChange,
+                        ct,
+                    )?;
+                    idms_prox_write.set_unix_account_password(
+                        &mut audit,
+                        &uat,
+                        target.as_str(),
+                        new.as_str(),
+                    )?;
+                    // Sessions don't use the unix password, so are kept.
+                    return idms_prox_write
+                        .commit(&mut audit)
+                        .map(|_| CredentialChangeResponse::new());
                 }
             };
+            idms_prox_write.commit(&mut audit)?;
 
-            audit_log!(audit, "Begin event {:?}", srch);
+            // Anyone holding the old password is now locked out.
+            idm_write.end_account_sessions(&mut audit, &target, keep.as_ref());
+            idm_write.commit().map(|_| CredentialChangeResponse::new())
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
 
-            match qs_read.search_ext(&mut audit, &srch) {
-                Ok(mut entries) => {
-                    // assert there is only one ...
-                    match entries.len() {
-                        0 => Err(OperationError::NoMatchingEntries),
-                        1 => {
-                            let e = entries.pop().expect("Entry length mismatch!!!");
-                            // Now convert to a response, and return
-                            WhoamiResult::new(&mut audit, &qs_read, e, uat)
-                                .map(|ok_wr| ok_wr.response())
-                        }
-                        // Somehow we matched multiple, which should be impossible.
-                        _ => Err(OperationError::InvalidState),
-                    }
-                }
-                // Something else went wrong ...
-                Err(e) => Err(e),
-            }
+impl Handler<ApiTokenGenerateMessage> for QueryServerV1 {
+    type Result = Result<ApiTokenGenerateResponse, OperationError>;
+
+    fn handle(&mut self, msg: ApiTokenGenerateMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("api_token_generate", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let uat = msg.uat.ok_or(OperationError::NotAuthenticated)?;
+
+            let ct = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("Clock failure!");
+            self.idms
+                .check_reauth(&mut audit, &uat, ProtectedOperation::CredentialChange, ct)?;
+
+            let mut idms_prox_write = self.idms.proxy_write();
+            let (id, token) = idms_prox_write.generate_api_token(
+                &mut audit,
+                &uat,
+                msg.req.account.as_str(),
+                msg.req.label.as_str(),
+                msg.req.expiry,
+                msg.req.read_write,
+                ct,
+            )?;
+            idms_prox_write
+                .commit(&mut audit)
+                .map(|_| ApiTokenGenerateResponse::new(id, token))
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<ApiTokenListMessage> for QueryServerV1 {
+    type Result = Result<ApiTokenListResponse, OperationError>;
+
+    fn handle(&mut self, msg: ApiTokenListMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("api_token_list", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let uat = msg.uat.ok_or(OperationError::NotAuthenticated)?;
+
+            let mut idms_prox_write = self.idms.proxy_write();
+            idms_prox_write
+                .list_api_tokens(&mut audit, &uat, msg.req.account.as_str())
+                .map(ApiTokenListResponse::new)
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<ApiTokenDestroyMessage> for QueryServerV1 {
+    type Result = Result<ApiTokenDestroyResponse, OperationError>;
+
+    fn handle(&mut self, msg: ApiTokenDestroyMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("api_token_destroy", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let uat = msg.uat.ok_or(OperationError::NotAuthenticated)?;
+
+            let ct = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("Clock failure!");
+            self.idms
+                .check_reauth(&mut audit, &uat, ProtectedOperation::CredentialChange, ct)?;
+
+            let mut idm_write = self.idms.write();
+            let mut idms_prox_write = self.idms.proxy_write();
+            idms_prox_write.destroy_api_token(
+                &mut audit,
+                &uat,
+                msg.req.account.as_str(),
+                &msg.req.id,
+            )?;
+            idms_prox_write.commit(&mut audit)?;
+
+            // Sessions begun with the token end with it.
+            idm_write.end_api_token_sessions(&mut audit, &msg.req.id);
+            idm_write.commit().map(|_| ApiTokenDestroyResponse::new())
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<RadiusSecretGenerateMessage> for QueryServerV1 {
+    type Result = Result<RadiusSecretGenerateResponse, OperationError>;
+
+    fn handle(&mut self, msg: RadiusSecretGenerateMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("radius_secret_generate", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let uat = msg.uat.ok_or(OperationError::NotAuthenticated)?;
+
+            let ct = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("Clock failure!");
+            self.idms
+                .check_reauth(&mut audit, &uat, ProtectedOperation::CredentialChange, ct)?;
+            let target =
+                Uuid::parse_str(uat.uuid.as_str()).map_err(|_| OperationError::InvalidUuid)?;
+
+            let mut idms_prox_write = self.idms.proxy_write();
+            let secret = idms_prox_write.generate_radius_secret(&mut audit, &target)?;
+            idms_prox_write
+                .commit(&mut audit)
+                .map(|_| RadiusSecretGenerateResponse::new(secret))
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<RadiusAuthTokenMessage> for QueryServerV1 {
+    type Result = Result<RadiusAuthToken, OperationError>;
+
+    fn handle(&mut self, msg: RadiusAuthTokenMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("radius_auth_token", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let uat = msg.uat.ok_or(OperationError::NotAuthenticated)?;
+
+            self.idms
+                .get_radius_auth_token(&mut audit, &uat, msg.account.as_str())
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<SshPublicKeysMessage> for QueryServerV1 {
+    type Result = Result<Vec<String>, OperationError>;
+
+    fn handle(&mut self, msg: SshPublicKeysMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("ssh_publickeys", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let uat = msg.uat.ok_or(OperationError::NotAuthenticated)?;
+
+            self.idms
+                .get_ssh_publickeys(&mut audit, &uat, msg.account.as_str())
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<UnixUserTokenMessage> for QueryServerV1 {
+    type Result = Result<UnixUserToken, OperationError>;
+
+    fn handle(&mut self, msg: UnixUserTokenMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("unix_user_token", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let uat = msg.uat.ok_or(OperationError::NotAuthenticated)?;
+
+            self.idms
+                .get_unix_user_token(&mut audit, &uat, msg.id.as_str())
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<UnixGroupTokenMessage> for QueryServerV1 {
+    type Result = Result<UnixGroupToken, OperationError>;
+
+    fn handle(&mut self, msg: UnixGroupTokenMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("unix_group_token", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let uat = msg.uat.ok_or(OperationError::NotAuthenticated)?;
+
+            self.idms
+                .get_unix_group_token(&mut audit, &uat, msg.id.as_str())
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<UnixAuthMessage> for QueryServerV1 {
+    type Result = Result<Option<UnixUserToken>, OperationError>;
+
+    fn handle(&mut self, msg: UnixAuthMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("unix_auth", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let uat = msg.uat.ok_or(OperationError::NotAuthenticated)?;
+
+            let mut idm_write = self.idms.write();
+
+            let ct = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("Clock failure!");
+
+            idm_write
+                .auth_unix(
+                    &mut audit,
+                    &uat,
+                    msg.req.account.as_str(),
+                    msg.req.cred.as_str(),
+                    ct,
+                )
+                .and_then(|r| idm_write.commit().map(|_| r))
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<Oauth2AuthorizeMessage> for QueryServerV1 {
+    type Result = Result<String, OperationError>;
+
+    fn handle(&mut self, msg: Oauth2AuthorizeMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("oauth2_authorize", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let uat = msg.uat.ok_or(OperationError::NotAuthenticated)?;
+
+            let ct = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("Clock failure!");
+
+            let mut idm_write = self.idms.write();
+            idm_write
+                .oauth2_authorize(&mut audit, &uat, &msg.req, ct)
+                .and_then(|r| idm_write.commit().map(|_| r))
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<Oauth2TokenMessage> for QueryServerV1 {
+    type Result = Result<Oauth2CodeGrant, OperationError>;
+
+    fn handle(&mut self, msg: Oauth2TokenMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("oauth2_token", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let ct = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("Clock failure!");
+
+            let mut idm_write = self.idms.write();
+            let r = idm_write.oauth2_token_exchange(&mut audit, &msg.req, ct);
+            // The code is spent even if the exchange failed.
+            idm_write.commit()?;
+            r
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<ApiTokenAuthMessage> for QueryServerV1 {
+    type Result = Result<UserAuthToken, OperationError>;
+
+    fn handle(&mut self, msg: ApiTokenAuthMessage, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new_with_eventid("api_token_auth", msg.eventid);
+        let res = audit_segment!(&mut audit, || {
+            let ct = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("Clock failure!");
+            self.idms.auth_api_token(&mut audit, msg.token.as_str(), ct)
         });
-        // Should we log the final result?
-        // At the end of the event we send it for logging.
         self.log.do_send(audit);
         res
     }
@@ -392,8 +2829,12 @@ impl Handler<PurgeTombstoneEvent> for QueryServerV1 {
             audit_log!(audit, "Begin purge tombstone event {:?}", msg);
             let qs_write = self.qs.write();
 
+            let ct = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("Clock failure!");
+
             let res = qs_write
-                .purge_tombstones(&mut audit)
+                .purge_tombstones(&mut audit, ct, msg.max_age)
                 .and_then(|_| qs_write.commit(&mut audit));
             audit_log!(audit, "Purge tombstones result: {:?}", res);
             res.expect("Invalid Server State");
@@ -413,8 +2854,12 @@ impl Handler<PurgeRecycledEvent> for QueryServerV1 {
             audit_log!(audit, "Begin purge recycled event {:?}", msg);
             let qs_write = self.qs.write();
 
+            let ct = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("Clock failure!");
+
             let res = qs_write
-                .purge_recycled(&mut audit)
+                .purge_recycled(&mut audit, ct, msg.max_age)
                 .and_then(|_| qs_write.commit(&mut audit));
             audit_log!(audit, "Purge recycled result: {:?}", res);
             res.expect("Invalid Server State");
@@ -424,3 +2869,113 @@ impl Handler<PurgeRecycledEvent> for QueryServerV1 {
         res
     }
 }
+
+impl Handler<PurgeChangelogEvent> for QueryServerV1 {
+    type Result = ();
+
+    fn handle(&mut self, msg: PurgeChangelogEvent, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new("purge changelog");
+        let res = audit_segment!(&mut audit, || {
+            audit_log!(audit, "Begin purge changelog event {:?}", msg);
+            let qs_write = self.qs.write();
+
+            let ct = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("Clock failure!");
+
+            let res = qs_write
+                .purge_changelog(&mut audit, ct, msg.max_age)
+                .and_then(|_| qs_write.commit(&mut audit));
+            audit_log!(audit, "Purge changelog result: {:?}", res);
+            res.expect("Invalid Server State");
+        });
+        // At the end of the event we send it for logging.
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<VerifyEvent> for QueryServerV1 {
+    type Result = ();
+
+    fn handle(&mut self, msg: VerifyEvent, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new("verify");
+        audit_segment!(&mut audit, || {
+            audit_log!(audit, "Begin verify event {:?}", msg);
+            let res = self.qs.verify(&mut audit);
+            if res.len() != 0 {
+                error!("Database verification failed -> {:?}", res);
+            }
+            audit_log!(audit, "Verify result: {:?}", res);
+        });
+        self.log.do_send(audit);
+    }
+}
+
+// Only sent to the write worker, so the derived work is applied in turn with
+// the other writes.
+impl Handler<DelayedActionEvent> for QueryServerV1 {
+    type Result = ();
+
+    fn handle(&mut self, msg: DelayedActionEvent, _: &mut Self::Context) -> Self::Result {
+        if self.qs.delayed_actions() == 0 {
+            return;
+        }
+        let mut audit = AuditScope::new("delayed_actions");
+        audit_segment!(&mut audit, || {
+            audit_log!(audit, "Begin delayed action event {:?}", msg);
+            match self.qs.process_delayed_actions(&mut audit) {
+                Ok(n) => audit_log!(audit, "Applied {} delayed actions", n),
+                Err(e) => {
+                    error!("Delayed action failed -> {:?}, repairing", e);
+                    if let Err(e) = self.qs.repair(&mut audit) {
+                        error!("Repair failed -> {:?}", e);
+                    }
+                }
+            }
+        });
+        self.log.do_send(audit);
+    }
+}
+
+impl Handler<ShutdownEvent> for QueryServerV1 {
+    type Result = Result<(), OperationError>;
+
+    fn handle(&mut self, msg: ShutdownEvent, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new("shutdown");
+        let res = audit_segment!(&mut audit, || {
+            audit_log!(audit, "Begin shutdown event {:?}", msg);
+            // What's left of the delayed actions is applied now, rather
+            // than left for the consistency pass after we start again.
+            match self.qs.process_delayed_actions(&mut audit) {
+                Ok(n) => audit_log!(audit, "Applied {} delayed actions", n),
+                Err(e) => error!("Delayed action failed during shutdown -> {:?}", e),
+            }
+            self.qs.checkpoint(&mut audit).and_then(|complete| {
+                if complete {
+                    Ok(())
+                } else {
+                    Err(OperationError::Backend)
+                }
+            })
+        });
+        self.log.do_send(audit);
+        res
+    }
+}
+
+impl Handler<OnlineBackupEvent> for QueryServerV1 {
+    type Result = ();
+
+    fn handle(&mut self, msg: OnlineBackupEvent, _: &mut Self::Context) -> Self::Result {
+        let mut audit = AuditScope::new("online_backup");
+        audit_segment!(&mut audit, || {
+            audit_log!(audit, "Begin online backup event {:?}", msg);
+            match self.online_backup(&mut audit) {
+                Ok(path) => info!("Online backup written to {}", path),
+                Err(e) => error!("Online backup failed -> {:?}", e),
+            }
+        });
+        self.log.do_send(audit);
+    }
+}
@@ -0,0 +1,356 @@
+// The admin socket, a unix socket for an operator on the server's own host,
+// to recover the server when it can't be reached otherwise, such as when its
+// certificate has expired. Nothing authenticates to it, so it's only as safe
+// as its permissions, which allow the user the server runs as and its group.
+// Each request is a line of json, and is answered with one.
+use crate::actors::v1::{
+    AdminBackupMessage, AdminDomainInfoMessage, AdminRecoverAccountMessage, AdminRepairMessage,
+    AdminVerifyMessage, QueryServerV1,
+};
+use crate::audit::AuditScope;
+use crate::be::BackendTransaction;
+use crate::constants::UUID_DOMAIN_INFO;
+use crate::logging::{log_level, set_log_level, set_target_log_level, target_log_level};
+use crate::server::QueryServerTransaction;
+
+use actix::dev::ToEnvelope;
+use actix::{Actor, Addr, Arbiter, Handler, Message};
+use futures::{future, Future, Sink, Stream};
+use kanidm_proto::v1::{
+    OperationError, RepairRequest, RepairResponse, VerifyRequest, VerifyResponse,
+};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::sync::Arc;
+use tokio::codec::{Framed, LinesCodec};
+use tokio::net::{UnixListener, UnixStream};
+use uuid::Uuid;
+
+const ADMIN_SOCKET_MODE: u32 = 0o660;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum AdminRequest {
+    // Set a generated password on the account, which is given back.
+    RecoverAccount(String),
+    DomainInfo,
+    // Write a backup to the path, which the server must be able to write.
+    Backup(String),
+    GetLogLevel,
+    SetLogLevel(String),
+    // The level of one target, such as be or http, given as target then
+    // level. A level of default logs it at the level of the rest again.
+    GetTargetLogLevel(String),
+    SetTargetLogLevel(String, String),
+    // Check the consistency of the server, in a read so writes continue.
+    Verify(VerifyRequest),
+    // Fix what verify finds that can be derived again.
+    Repair(RepairRequest),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum AdminResponse {
+    Password(String),
+    DomainInfo(DomainInfo),
+    LogLevel(String),
+    Verify(VerifyResponse),
+    Repair(RepairResponse),
+    Success,
+    Error(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DomainInfo {
+    pub name: String,
+    pub display_name: String,
+    pub uuid: String,
+    pub entries: usize,
+}
+
+pub(crate) fn read_domain_info<T: QueryServerTransaction>(
+    au: &mut AuditScope,
+    qs: &T,
+) -> Result<DomainInfo, OperationError> {
+    let e = qs.internal_search_uuid(au, &UUID_DOMAIN_INFO)?;
+    let entries = qs.get_be_txn().count_entries(au)?;
+    Ok(DomainInfo {
+        name: e
+            .get_ava_single_str("domain_name")
+            .ok_or(OperationError::InvalidEntryState)?
+            .to_string(),
+        display_name: e
+            .get_ava_single_str("domain_display_name")
+            .unwrap_or("")
+            .to_string(),
+        uuid: e
+            .get_ava_single("domain_uuid")
+            .and_then(|v| v.to_uuid())
+            .ok_or(OperationError::InvalidEntryState)?
+            .to_hyphenated_ref()
+            .to_string(),
+        entries: entries,
+    })
+}
+
+struct AdminServer {
+    qe_r: Addr<QueryServerV1>,
+    qe_w: Addr<QueryServerV1>,
+}
+
+type AdminFuture = Box<dyn Future<Item = AdminResponse, Error = ()>>;
+
+fn send<M, T>(qe: &Addr<QueryServerV1>, m: M, f: fn(T) -> AdminResponse) -> AdminFuture
+where
+    M: Message<Result = Result<T, OperationError>> + Send + 'static,
+    T: Send + 'static,
+    QueryServerV1: Handler<M>,
+    <QueryServerV1 as Actor>::Context: ToEnvelope<QueryServerV1, M>,
+{
+    Box::new(qe.send(m).then(move |r| -> Result<AdminResponse, ()> {
+        Ok(match r {
+            Ok(Ok(t)) => f(t),
+            Ok(Err(e)) => AdminResponse::Error(format!("{:?}", e)),
+            Err(e) => AdminResponse::Error(format!("the query server failed - {:?}", e)),
+        })
+    }))
+}
+
+fn handle(admin: &Arc<AdminServer>, line: String) -> AdminFuture {
+    let eventid = Uuid::new_v4();
+    let req: AdminRequest = match serde_json::from_str(line.as_str()) {
+        Ok(req) => req,
+        Err(e) => {
+            let msg = format!("invalid request - {}", e);
+            return Box::new(future::ok(AdminResponse::Error(msg)));
+        }
+    };
+    // Everything asked of the socket is logged, as who asked isn't known.
+    info!("Admin socket request {} -> {:?}", eventid, req);
+    match req {
+        AdminRequest::RecoverAccount(name) => send(
+            &admin.qe_w,
+            AdminRecoverAccountMessage::new(eventid, name),
+            AdminResponse::Password,
+        ),
+        AdminRequest::DomainInfo => send(
+            &admin.qe_r,
+            AdminDomainInfoMessage::new(eventid),
+            AdminResponse::DomainInfo,
+        ),
+        AdminRequest::Backup(path) => {
+            send(&admin.qe_r, AdminBackupMessage::new(eventid, path), |_| {
+                AdminResponse::Success
+            })
+        }
+        AdminRequest::Verify(req) => send(
+            &admin.qe_r,
+            AdminVerifyMessage::new(eventid, req),
+            AdminResponse::Verify,
+        ),
+        AdminRequest::Repair(req) => send(
+            &admin.qe_w,
+            AdminRepairMessage::new(eventid, req),
+            AdminResponse::Repair,
+        ),
+        AdminRequest::GetLogLevel => Box::new(future::ok(AdminResponse::LogLevel(log_level()))),
+        AdminRequest::SetLogLevel(level) => {
+            let resp = match set_log_level(level.as_str()) {
+                Ok(_) => {
+                    info!("Log level set to {} through the admin socket", log_level());
+                    AdminResponse::LogLevel(log_level())
+                }
+                Err(e) => AdminResponse::Error(e),
+            };
+            Box::new(future::ok(resp))
+        }
+        AdminRequest::GetTargetLogLevel(target) => {
+            Box::new(future::ok(match target_log_level(target.as_str()) {
+                Ok(level) => AdminResponse::LogLevel(level),
+                Err(e) => AdminResponse::Error(e),
+            }))
+        }
+        AdminRequest::SetTargetLogLevel(target, level) => {
+            let resp = match set_target_log_level(target.as_str(), level.as_str())
+                .and_then(|_| target_log_level(target.as_str()))
+            {
+                Ok(level) => {
+                    info!(
+                        "Log level of {} set to {} through the admin socket",
+                        target, level
+                    );
+                    AdminResponse::LogLevel(level)
+                }
+                Err(e) => AdminResponse::Error(e),
+            };
+            Box::new(future::ok(resp))
+        }
+    }
+}
+
+fn serve(stream: UnixStream, admin: Arc<AdminServer>) -> impl Future<Item = (), Error = ()> {
+    let (sink, requests) = Framed::new(stream, LinesCodec::new()).split();
+    requests
+        .map_err(|e| debug!("Admin socket connection closed -> {:?}", e))
+        .fold(sink, move |sink, line| {
+            handle(&admin, line)
+                .and_then(|resp| {
+                    serde_json::to_string(&resp)
+                        .map_err(|e| error!("Failed to serialise admin response -> {:?}", e))
+                })
+                .and_then(|line| {
+                    sink.send(line)
+                        .map_err(|e| debug!("Admin socket connection closed -> {:?}", e))
+                })
+        })
+        .map(|_| ())
+}
+
+// Serve the admin socket at path. A socket left there by a server that
+// didn't stop cleanly is replaced.
+pub(crate) fn start_admin_socket(
+    path: &str,
+    qe_r: Addr<QueryServerV1>,
+    qe_w: Addr<QueryServerV1>,
+) -> Result<(), ()> {
+    let stale = fs::symlink_metadata(path)
+        .map(|m| m.file_type().is_socket())
+        .unwrap_or(false);
+    if stale {
+        let _ = fs::remove_file(path);
+    }
+    let listener = UnixListener::bind(path)
+        .map_err(|e| error!("Failed to bind admin socket {} -> {:?}", path, e))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(ADMIN_SOCKET_MODE))
+        .map_err(|e| error!("Failed to set permissions of {} -> {:?}", path, e))?;
+    info!("Serving the admin socket at {}", path);
+
+    let admin = Arc::new(AdminServer {
+        qe_r: qe_r,
+        qe_w: qe_w,
+    });
+    let server = listener
+        .incoming()
+        .map_err(|e| error!("Admin socket listener failed -> {:?}", e))
+        .for_each(move |stream| {
+            Arbiter::spawn(serve(stream, admin.clone()));
+            Ok(())
+        });
+    Arbiter::spawn(server);
+    Ok(())
+}
+
+// The admin socket of a running server. This fails if no server is listening
+// at path, when the commands work on the database instead.
+pub struct AdminClient {
+    stream: BufReader<StdUnixStream>,
+}
+
+impl AdminClient {
+    pub fn connect(path: &str) -> io::Result<Self> {
+        StdUnixStream::connect(path).map(|s| AdminClient {
+            stream: BufReader::new(s),
+        })
+    }
+
+    pub fn request(&mut self, req: &AdminRequest) -> Result<AdminResponse, String> {
+        let mut line = serde_json::to_string(req).map_err(|e| format!("{:?}", e))?;
+        line.push('\n');
+        self.stream
+            .get_mut()
+            .write_all(line.as_bytes())
+            .map_err(|e| format!("failed to send to the admin socket - {}", e))?;
+        let mut resp = String::new();
+        match self.stream.read_line(&mut resp) {
+            Ok(0) => Err("the server closed the admin socket".to_string()),
+            Ok(_) => serde_json::from_str(resp.as_str())
+                .map_err(|e| format!("invalid response from the admin socket - {}", e)),
+            Err(e) => Err(format!("failed to read from the admin socket - {}", e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AdminRequest, AdminResponse};
+    use crate::logging::{log_level, set_log_level, set_target_log_level, target_log_level};
+    use kanidm_proto::v1::{
+        ConsistencyError, RepairChange, RepairRequest, RepairResponse, VerifyRequest,
+        VerifyResponse,
+    };
+
+    #[test]
+    fn test_admin_request_lines() {
+        // Each is sent as a single line.
+        let req = serde_json::to_string(&AdminRequest::RecoverAccount("admin".to_string()))
+            .expect("Failed to serialise");
+        assert!(!req.contains('\n'));
+        assert!(
+            serde_json::from_str::<AdminRequest>(req.as_str()).ok()
+                == Some(AdminRequest::RecoverAccount("admin".to_string()))
+        );
+        assert!(
+            serde_json::from_str::<AdminRequest>("\"DomainInfo\"").ok()
+                == Some(AdminRequest::DomainInfo)
+        );
+        assert!(
+            serde_json::from_str::<AdminResponse>("{\"Error\":\"x\"}").ok()
+                == Some(AdminResponse::Error("x".to_string()))
+        );
+        // The checks may be left out, to run them all.
+        assert!(
+            serde_json::from_str::<AdminRequest>("{\"Verify\":{}}").ok()
+                == Some(AdminRequest::Verify(VerifyRequest::new(Vec::new())))
+        );
+        let resp =
+            AdminResponse::Verify(VerifyResponse::new(vec![ConsistencyError::IndexInvalid(
+                "name.eq".to_string(),
+                7,
+            )]));
+        let line = serde_json::to_string(&resp).expect("Failed to serialise");
+        assert!(serde_json::from_str::<AdminResponse>(line.as_str()).ok() == Some(resp));
+        assert!(
+            serde_json::from_str::<AdminRequest>("{\"Repair\":{}}").ok()
+                == Some(AdminRequest::Repair(RepairRequest::new(false)))
+        );
+        let resp = AdminResponse::Repair(RepairResponse::new(
+            true,
+            vec![RepairChange::new(
+                "id 7".to_string(),
+                "index name.eq".to_string(),
+                vec!["ghost".to_string()],
+                Vec::new(),
+            )],
+            vec![ConsistencyError::UuidNotUnique("x".to_string())],
+        ));
+        let line = serde_json::to_string(&resp).expect("Failed to serialise");
+        assert!(serde_json::from_str::<AdminResponse>(line.as_str()).ok() == Some(resp));
+    }
+
+    #[test]
+    fn test_admin_log_level() {
+        let before = log_level();
+        assert!(set_log_level("Debug").is_ok());
+        assert!(log_level() == "debug");
+        assert!(set_log_level("loud").is_err());
+        assert!(log_level() == "debug");
+
+        assert!(set_target_log_level("be", "trace").is_ok());
+        assert!(target_log_level("be") == Ok("trace".to_string()));
+        assert!(target_log_level("qs") == Ok("debug".to_string()));
+        assert!(set_target_log_level("be", "loud").is_err());
+        assert!(set_target_log_level("beee", "info").is_err());
+        assert!(target_log_level("beee").is_err());
+        assert!(set_target_log_level("be", "default").is_ok());
+        assert!(target_log_level("be") == Ok("debug".to_string()));
+        assert!(set_log_level(before.as_str()).is_ok());
+
+        assert!(
+            serde_json::from_str::<AdminRequest>("{\"SetTargetLogLevel\":[\"be\",\"trace\"]}").ok()
+                == Some(AdminRequest::SetTargetLogLevel(
+                    "be".to_string(),
+                    "trace".to_string()
+                ))
+        );
+    }
+}
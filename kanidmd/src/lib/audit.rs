@@ -6,6 +6,7 @@ use std::time::SystemTime;
 use chrono::offset::Utc;
 use chrono::DateTime;
 use serde_json;
+use uuid::Uuid;
 
 #[macro_export]
 macro_rules! audit_log {
@@ -16,7 +17,7 @@ macro_rules! audit_log {
             // debug!($($arg)*)
             // debug!("DEBUG AUDIT ({}:{} {})-> ", file!(), line!(), $audit.id());
             // debug!("line: {}", line!());
-            debug!($($arg)*)
+            debug!("[{}] {}", $audit.eventid(), format_args!($($arg)*))
         }
         $audit.log_event(
             fmt::format(
@@ -108,6 +109,10 @@ pub struct AuditScope {
     // to automatically annotate line numbers of code?
     time: String,
     name: String,
+    // The id of the operation this is part of. It's given to the client and
+    // written with everything logged for the operation, so they can be
+    // matched up.
+    eventid: Uuid,
     duration: Option<Duration>,
     events: Vec<AuditEvent>,
 }
@@ -128,21 +133,37 @@ impl fmt::Display for AuditScope {
 
 impl AuditScope {
     pub fn new(name: &str) -> Self {
+        AuditScope::new_with_eventid(name, Uuid::new_v4())
+    }
+
+    // A scope for an operation requested by a client, with the event id
+    // given to the request as it was received.
+    pub fn new_with_eventid(name: &str, eventid: Uuid) -> Self {
         let t_now = SystemTime::now();
         let datetime: DateTime<Utc> = t_now.into();
 
         AuditScope {
             time: datetime.to_rfc3339(),
             name: String::from(name),
+            eventid: eventid,
             duration: None,
             events: Vec::new(),
         }
     }
 
+    // A scope for part of this operation, to be appended to it.
+    pub fn child(&self, name: &str) -> Self {
+        AuditScope::new_with_eventid(name, self.eventid)
+    }
+
     pub fn id(&self) -> &str {
         self.name.as_str()
     }
 
+    pub fn eventid(&self) -> &Uuid {
+        &self.eventid
+    }
+
     pub fn set_duration(&mut self, diff: Duration) {
         self.duration = Some(diff);
     }
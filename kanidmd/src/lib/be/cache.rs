@@ -0,0 +1,155 @@
+use crate::entry::{Entry, EntryCommitted, EntryValid};
+use crate::metrics::Metrics;
+use crate::value::IndexType;
+use lru::LruCache;
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+
+// An index lookup, by the attribute, the type of index and the key.
+pub type IdlKey = (String, IndexType, String);
+
+struct CacheInner {
+    generation: u64,
+    // None when the cache is configured with no space.
+    entries: Option<LruCache<u64, Entry<EntryValid, EntryCommitted>>>,
+    idls: Option<LruCache<IdlKey, BTreeSet<i64>>>,
+}
+
+// Entries, and the results of index lookups, kept between transactions so a
+// search needn't read and deserialise them from sqlite each time. This is
+// shared by every clone of the backend.
+//
+// A write that commits changes removes what it changed, then moves the cache
+// to a new generation. A transaction only uses the cache while it's in the
+// generation the transaction began in, as after that it may hold changes
+// sqlite won't show the transaction.
+pub struct BackendCache {
+    inner: Mutex<CacheInner>,
+}
+
+// What a write transaction has changed, to be removed from the cache when it
+// commits.
+#[derive(Debug, Default)]
+pub struct CacheDirty {
+    pub ids: BTreeSet<u64>,
+    pub keys: BTreeSet<IdlKey>,
+    // Set when too much changed to track, as in a reindex or a restore.
+    pub all_entries: bool,
+    pub all_idls: bool,
+}
+
+impl CacheDirty {
+    fn is_empty(&self) -> bool {
+        self.ids.is_empty() && self.keys.is_empty() && !self.all_entries && !self.all_idls
+    }
+}
+
+impl BackendCache {
+    pub fn new(entries: usize, idls: usize) -> Self {
+        BackendCache {
+            inner: Mutex::new(CacheInner {
+                generation: 0,
+                entries: if entries > 0 {
+                    Some(LruCache::new(entries))
+                } else {
+                    None
+                },
+                idls: if idls > 0 {
+                    Some(LruCache::new(idls))
+                } else {
+                    None
+                },
+            }),
+        }
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.inner.lock().expect("cache lock poisoned").generation
+    }
+
+    // A lookup is only counted when the transaction may use the cache.
+    pub fn get_entry(
+        &self,
+        metrics: &Metrics,
+        generation: u64,
+        id: u64,
+    ) -> Option<Entry<EntryValid, EntryCommitted>> {
+        let mut inner = self.inner.lock().expect("cache lock poisoned");
+        if inner.generation != generation {
+            return None;
+        }
+        let entries = inner.entries.as_mut()?;
+        let e = entries.get(&id).cloned();
+        metrics.record_entry_cache(e.is_some());
+        e
+    }
+
+    pub fn insert_entry(&self, generation: u64, e: &Entry<EntryValid, EntryCommitted>) {
+        let mut inner = self.inner.lock().expect("cache lock poisoned");
+        if inner.generation != generation {
+            return;
+        }
+        if let Some(entries) = inner.entries.as_mut() {
+            entries.put(e.get_id(), e.clone());
+        }
+    }
+
+    pub fn get_idl(
+        &self,
+        metrics: &Metrics,
+        generation: u64,
+        key: &IdlKey,
+    ) -> Option<BTreeSet<i64>> {
+        let mut inner = self.inner.lock().expect("cache lock poisoned");
+        if inner.generation != generation {
+            return None;
+        }
+        let idls = inner.idls.as_mut()?;
+        let idl = idls.get(key).cloned();
+        metrics.record_idl_cache(idl.is_some());
+        idl
+    }
+
+    pub fn insert_idl(&self, generation: u64, key: IdlKey, idl: &BTreeSet<i64>) {
+        let mut inner = self.inner.lock().expect("cache lock poisoned");
+        if inner.generation != generation {
+            return;
+        }
+        if let Some(idls) = inner.idls.as_mut() {
+            idls.put(key, idl.clone());
+        }
+    }
+
+    // The cache is locked while the write commits, so no transaction can
+    // begin after the commit and still see what it changed in the cache.
+    pub fn commit<E, F>(&self, dirty: CacheDirty, commit: F) -> Result<(), E>
+    where
+        F: FnOnce() -> Result<(), E>,
+    {
+        let mut inner = self.inner.lock().expect("cache lock poisoned");
+        commit()?;
+        if dirty.is_empty() {
+            return Ok(());
+        }
+        if let Some(entries) = inner.entries.as_mut() {
+            if dirty.all_entries {
+                entries.clear();
+            } else {
+                for id in dirty.ids.iter() {
+                    entries.pop(id);
+                }
+            }
+        }
+        if let Some(idls) = inner.idls.as_mut() {
+            if dirty.all_idls {
+                idls.clear();
+            } else {
+                for key in dirty.keys.iter() {
+                    idls.pop(key);
+                }
+            }
+        }
+        inner.generation += 1;
+        Ok(())
+    }
+}
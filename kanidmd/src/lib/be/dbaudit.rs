@@ -0,0 +1,88 @@
+use chrono::{TimeZone, Utc};
+use kanidm_proto::v1::{AuditOperation, AuditRecord, Modify as ProtoModify};
+use uuid::Uuid;
+
+// The values of secret attributes are redacted before a record is made, so
+// they are never written here.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum DbAuditChangeV1 {
+    Present(String, String),
+    Removed(String, String),
+    Purged(String),
+    Assert(String, String),
+    AssertMissing(String),
+    Set(String, Vec<String>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum DbAuditOperationV1 {
+    Create,
+    Modify,
+    Delete,
+    Revive,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DbAuditRecordV1 {
+    // Seconds since the epoch.
+    pub time: i64,
+    pub eventid: Uuid,
+    pub identity: Uuid,
+    pub operation: DbAuditOperationV1,
+    pub targets: Vec<Uuid>,
+    pub changes: Vec<DbAuditChangeV1>,
+}
+
+// This is what we store into the auditlog table. It's kept apart from the
+// entries, so the record of a change outlives the entry it was made to.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum DbAuditRecord {
+    V1(DbAuditRecordV1),
+}
+
+impl DbAuditRecord {
+    pub fn time(&self) -> i64 {
+        match self {
+            DbAuditRecord::V1(r) => r.time,
+        }
+    }
+
+    pub fn has_target(&self, u: &Uuid) -> bool {
+        match self {
+            DbAuditRecord::V1(r) => r.targets.contains(u),
+        }
+    }
+
+    pub fn into_proto(self) -> AuditRecord {
+        match self {
+            DbAuditRecord::V1(r) => AuditRecord {
+                time: Utc.timestamp(r.time, 0).to_rfc3339(),
+                eventid: r.eventid.to_hyphenated_ref().to_string(),
+                identity: r.identity.to_hyphenated_ref().to_string(),
+                operation: match r.operation {
+                    DbAuditOperationV1::Create => AuditOperation::Create,
+                    DbAuditOperationV1::Modify => AuditOperation::Modify,
+                    DbAuditOperationV1::Delete => AuditOperation::Delete,
+                    DbAuditOperationV1::Revive => AuditOperation::Revive,
+                },
+                targets: r
+                    .targets
+                    .iter()
+                    .map(|u| u.to_hyphenated_ref().to_string())
+                    .collect(),
+                changes: r
+                    .changes
+                    .into_iter()
+                    .map(|c| match c {
+                        DbAuditChangeV1::Present(a, v) => ProtoModify::Present(a, v),
+                        DbAuditChangeV1::Removed(a, v) => ProtoModify::Removed(a, v),
+                        DbAuditChangeV1::Purged(a) => ProtoModify::Purged(a),
+                        DbAuditChangeV1::Assert(a, v) => ProtoModify::Assert(a, v),
+                        DbAuditChangeV1::AssertMissing(a) => ProtoModify::AssertMissing(a),
+                        DbAuditChangeV1::Set(a, vs) => ProtoModify::Set(a, vs),
+                    })
+                    .collect(),
+            },
+        }
+    }
+}
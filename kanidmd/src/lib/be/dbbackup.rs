@@ -0,0 +1,136 @@
+use crate::audit::AuditScope;
+use crate::be::dbaudit::DbAuditRecord;
+use crate::be::dbentry::DbEntry;
+use crate::entry::{Entry, EntryCommitted, EntryValid};
+use kanidm_proto::v1::OperationError;
+use serde_json;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+
+// The version of the backups that are written. A restore refuses any other
+// that it doesn't know how to migrate, so this MUST change whenever the
+// format does.
+pub const DBBACKUP_VERSION: u32 = 1;
+// Backups from before the format was versioned are a list of the entries
+// alone, with no header. They are restored as this version.
+pub const DBBACKUP_VERSION_LEGACY: u32 = 0;
+
+// Only the version is read at first, so a backup that can't be restored is
+// found before anything is removed to restore it.
+#[derive(Deserialize, Debug)]
+pub struct DbBackupHeader {
+    pub version: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DbBackup {
+    pub version: u32,
+    // Every entry, including recycled entries and tombstones, the schema,
+    // and the domain info with its keys.
+    pub entries: Vec<DbEntry>,
+    pub auditlog: Vec<DbAuditRecord>,
+}
+
+// The content of a backup once it's been read and checked, and is known to
+// be one that can be restored.
+pub struct DbBackupContent {
+    pub version: u32,
+    pub entries: Vec<Entry<EntryValid, EntryCommitted>>,
+    pub auditlog: Vec<DbAuditRecord>,
+}
+
+impl DbBackupContent {
+    // How many entries there are of each class.
+    pub fn class_counts(&self) -> BTreeMap<String, usize> {
+        let mut counts = BTreeMap::new();
+        for e in self.entries.iter() {
+            if let Some(classes) = e.get_ava("class") {
+                for c in classes.iter().filter_map(|v| v.to_str()) {
+                    *counts.entry(c.to_string()).or_insert(0) += 1;
+                }
+            }
+        }
+        counts
+    }
+}
+
+// Read the whole backup and check every entry in it. Nothing is changed
+// here, so a backup that is truncated or corrupt fails before a restore
+// removes anything.
+pub fn read_backup(
+    audit: &mut AuditScope,
+    src_path: &str,
+) -> Result<DbBackupContent, OperationError> {
+    // load all entries into RAM, may need to change this later
+    // if the size of the database compared to RAM is an issue
+    let serialized_string = try_audit!(
+        audit,
+        fs::read_to_string(src_path),
+        "fs::read_to_string {:?}",
+        OperationError::FsError
+    );
+
+    let version = if serialized_string.trim_start().starts_with('[') {
+        DBBACKUP_VERSION_LEGACY
+    } else {
+        match serde_json::from_str::<DbBackupHeader>(&serialized_string) {
+            Ok(h) => h.version,
+            Err(e) => {
+                audit_log!(audit, "not a backup {:?}", e);
+                return Err(OperationError::IncompatibleBackup(None));
+            }
+        }
+    };
+
+    let (dbentries, auditlog) = match version {
+        DBBACKUP_VERSION_LEGACY => {
+            audit_log!(audit, "migrating unversioned backup");
+            let dbentries: Vec<DbEntry> = try_audit!(
+                audit,
+                serde_json::from_str(&serialized_string),
+                "serde_json error {:?}",
+                OperationError::SerdeJsonError
+            );
+            (dbentries, Vec::new())
+        }
+        DBBACKUP_VERSION => {
+            let backup: DbBackup = try_audit!(
+                audit,
+                serde_json::from_str(&serialized_string),
+                "serde_json error {:?}",
+                OperationError::SerdeJsonError
+            );
+            (backup.entries, backup.auditlog)
+        }
+        v => {
+            audit_log!(audit, "unsupported backup version {}", v);
+            return Err(OperationError::IncompatibleBackup(Some(v)));
+        }
+    };
+
+    // The ids are only the order of the entries in the backup, as they are
+    // given new ones when they are restored.
+    let mut uuids = BTreeSet::new();
+    let entries: Result<Vec<_>, _> = dbentries
+        .into_iter()
+        .enumerate()
+        .map(|(i, db_e)| {
+            let id = i as u64 + 1;
+            let e = Entry::from_dbentry(db_e, id).map_err(|_| {
+                audit_log!(audit, "backup entry {} is corrupt", id);
+                OperationError::CorruptedEntry(id)
+            })?;
+            if !uuids.insert(e.get_uuid().clone()) {
+                audit_log!(audit, "backup entry {} duplicates {:?}", id, e.get_uuid());
+                return Err(OperationError::CorruptedEntry(id));
+            }
+            Ok(e)
+        })
+        .collect();
+
+    Ok(DbBackupContent {
+        version: version,
+        entries: entries?,
+        auditlog: auditlog,
+    })
+}
@@ -0,0 +1,52 @@
+use crate::cid::Cid;
+use std::collections::{BTreeMap, BTreeSet};
+use uuid::Uuid;
+
+// Where the change ids of this database are up to. There is only ever one
+// row of this.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DbChangelogMetaV1 {
+    // The uuid this server gives its change ids. It's made when the database
+    // is, and never changes.
+    pub s_uuid: Uuid,
+    // The id of the last write that changed anything.
+    pub last: Option<Cid>,
+    // The id of the latest change that has been trimmed from the changelog,
+    // so changes since any earlier id can't be given.
+    pub trimmed: Option<Cid>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum DbChangelogMeta {
+    V1(DbChangelogMetaV1),
+}
+
+// The id of the last change to each attribute of an entry, which is kept
+// for an attribute that has since been removed. This is what we store into
+// the changestate table, by the uuid of the entry.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum DbChangeState {
+    V1(BTreeMap<String, Cid>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DbChangeRecordV1 {
+    pub cid: Cid,
+    pub uuid: Uuid,
+    pub attrs: BTreeSet<String>,
+}
+
+// One entry that a write changed. This is what we store into the changelog
+// table, and it's trimmed by age.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum DbChangeRecord {
+    V1(DbChangeRecordV1),
+}
+
+impl DbChangeRecord {
+    pub fn cid(&self) -> &Cid {
+        match self {
+            DbChangeRecord::V1(r) => &r.cid,
+        }
+    }
+}
@@ -3,21 +3,80 @@ use uuid::Uuid;
 #[derive(Serialize, Deserialize, Debug)]
 pub enum DbPasswordV1 {
     PBKDF2(usize, Vec<u8>, Vec<u8>),
+    SCRYPT(u64, u32, u32, Vec<u8>, Vec<u8>),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum DbTotpAlgoV1 {
+    S1,
+    S256,
+    S512,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DbTotpV1 {
+    pub k: Vec<u8>,
+    pub s: u64,
+    pub a: DbTotpAlgoV1,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DbWebauthnV1 {
+    pub n: String,
+    pub i: Vec<u8>,
+    pub x: Vec<u8>,
+    pub y: Vec<u8>,
+    pub c: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum DbCredPolicyV1 {
+    PasswordOnly,
+    PasswordMFA,
+    WebauthnOnly,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DbCredV1 {
     pub password: Option<DbPasswordV1>,
+    // Credentials stored before totp was added have no such field.
+    #[serde(default)]
+    pub totp: Option<DbTotpV1>,
+    #[serde(default)]
+    pub webauthn: Vec<DbWebauthnV1>,
+    #[serde(default)]
+    pub backup_codes: Vec<DbPasswordV1>,
+    #[serde(default)]
+    pub policy: Option<DbCredPolicyV1>,
+    #[serde(default)]
+    pub must_change: bool,
     pub claims: Vec<String>,
     pub uuid: Uuid,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DbApiTokenV1 {
+    pub u: Uuid,
+    pub l: String,
+    pub s: DbPasswordV1,
+    pub c: u64,
+    pub e: Option<u64>,
+    pub lu: Option<u64>,
+    pub rw: bool,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DbValueCredV1 {
     pub t: String,
     pub d: DbCredV1,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DbValueTaggedStringV1 {
+    pub t: String,
+    pub d: String,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum DbValueV1 {
     U8(String),
@@ -29,4 +88,11 @@ pub enum DbValueV1 {
     RF(Uuid),
     JF(String),
     CR(DbValueCredV1),
+    N32(u32),
+    BI(Vec<u8>),
+    // Always rfc3339 in utc.
+    DT(String),
+    AT(DbApiTokenV1),
+    RU(String),
+    SK(DbValueTaggedStringV1),
 }
@@ -0,0 +1,95 @@
+// Backend failures. The error from sqlite is kept here so it can be logged,
+// but only its kind is given to the client, which is enough to tell a busy
+// database that can be retried from one that has failed.
+use rusqlite::ErrorCode;
+use std::fmt;
+
+use crate::audit::AuditScope;
+use kanidm_proto::v1::{BackendErrorKind, OperationError};
+
+#[derive(Debug)]
+pub struct BackendError {
+    kind: BackendErrorKind,
+    message: String,
+}
+
+impl BackendError {
+    pub fn kind(&self) -> BackendErrorKind {
+        self.kind
+    }
+
+    // Log the failure with the event id of the audit scope, so the detail
+    // can be found with the rest of the operation, and give the error the
+    // client will see.
+    pub fn into_operation_error(self, au: &mut AuditScope) -> OperationError {
+        error!("backend failure in event {}: {}", au.eventid(), self);
+        audit_log!(au, "backend failure: {}", self);
+        OperationError::SQLiteError(self.kind)
+    }
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.kind, self.message)
+    }
+}
+
+impl From<rusqlite::Error> for BackendError {
+    fn from(e: rusqlite::Error) -> Self {
+        let kind = match &e {
+            rusqlite::Error::SqliteFailure(fe, _) => match fe.code {
+                ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked => BackendErrorKind::Busy,
+                ErrorCode::DatabaseCorrupt | ErrorCode::NotADatabase => BackendErrorKind::Corrupt,
+                ErrorCode::SystemIOFailure
+                | ErrorCode::CannotOpen
+                | ErrorCode::PermissionDenied
+                | ErrorCode::ReadOnly
+                | ErrorCode::FileLockingProtocolFailed => BackendErrorKind::Io,
+                ErrorCode::ConstraintViolation => BackendErrorKind::Constraint,
+                ErrorCode::DiskFull => BackendErrorKind::Full,
+                _ => BackendErrorKind::Other,
+            },
+            _ => BackendErrorKind::Other,
+        };
+        BackendError {
+            kind: kind,
+            message: e.to_string(),
+        }
+    }
+}
+
+// The error the client sees for a failed sqlite call.
+pub fn sqlite_error(au: &mut AuditScope, e: rusqlite::Error) -> OperationError {
+    BackendError::from(e).into_operation_error(au)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BackendError;
+    use kanidm_proto::v1::BackendErrorKind;
+    use rusqlite::ffi;
+
+    fn kind_of(code: i32) -> BackendErrorKind {
+        BackendError::from(rusqlite::Error::SqliteFailure(ffi::Error::new(code), None)).kind()
+    }
+
+    #[test]
+    fn test_be_error_kind() {
+        assert_eq!(kind_of(ffi::SQLITE_BUSY), BackendErrorKind::Busy);
+        assert_eq!(kind_of(ffi::SQLITE_LOCKED), BackendErrorKind::Busy);
+        assert_eq!(kind_of(ffi::SQLITE_CORRUPT), BackendErrorKind::Corrupt);
+        assert_eq!(kind_of(ffi::SQLITE_IOERR), BackendErrorKind::Io);
+        assert_eq!(
+            kind_of(ffi::SQLITE_CONSTRAINT),
+            BackendErrorKind::Constraint
+        );
+        assert_eq!(kind_of(ffi::SQLITE_FULL), BackendErrorKind::Full);
+        assert_eq!(kind_of(ffi::SQLITE_ERROR), BackendErrorKind::Other);
+        assert_eq!(
+            BackendError::from(rusqlite::Error::QueryReturnedNoRows).kind(),
+            BackendErrorKind::Other
+        );
+        assert!(kind_of(ffi::SQLITE_BUSY).is_retryable());
+        assert!(!kind_of(ffi::SQLITE_CORRUPT).is_retryable());
+    }
+}
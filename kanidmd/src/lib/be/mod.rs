@@ -1,24 +1,48 @@
 //! Db executor actor
 
+use chrono::{TimeZone, Utc};
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
 use rand::prelude::*;
 use rusqlite::types::ToSql;
-use rusqlite::NO_PARAMS;
+use rusqlite::{Connection, DatabaseName, OpenFlags, NO_PARAMS};
 use serde_cbor;
 use serde_json;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
 use std::convert::TryFrom;
 use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use uuid::Uuid;
 
 use crate::audit::AuditScope;
+use crate::be::cache::{BackendCache, CacheDirty, IdlKey};
+use crate::be::dbaudit::DbAuditRecord;
+use crate::be::dbbackup::{read_backup, DbBackup, DBBACKUP_VERSION};
+use crate::be::dbchange::{
+    DbChangeRecord, DbChangeRecordV1, DbChangeState, DbChangelogMeta, DbChangelogMetaV1,
+};
 use crate::be::dbentry::DbEntry;
+use crate::be::error::{sqlite_error, BackendError};
+use crate::cid::Cid;
+use crate::constants::{ENTRY_CACHE_SIZE, IDL_CACHE_SIZE};
 use crate::entry::{Entry, EntryCommitted, EntryNew, EntryValid};
-use crate::filter::{Filter, FilterValidResolved};
+use crate::filter::{Filter, FilterResolved, FilterValidResolved};
+use crate::metrics::Metrics;
 use crate::utils::SID;
-use kanidm_proto::v1::{ConsistencyError, OperationError};
+use crate::value::{IndexType, Value};
+use kanidm_proto::v1::{ConsistencyError, OperationError, PlanNode, PlanState, SearchPlan};
 
+mod cache;
+pub mod dbaudit;
+pub mod dbbackup;
+pub mod dbchange;
 pub mod dbentry;
 pub mod dbvalue;
+mod error;
 mod idl;
 mod mem_be;
 mod sqlite_be;
@@ -30,36 +54,360 @@ struct IdEntry {
     data: Vec<u8>,
 }
 
+// The keys an index holds for an entry, and the keys it should hold.
+pub struct IndexDiff {
+    pub name: String,
+    pub id: i64,
+    pub held: BTreeSet<String>,
+    pub expect: BTreeSet<String>,
+}
+
 pub struct Backend {
     pool: Pool<SqliteConnectionManager>,
+    metrics: Arc<Metrics>,
+    cache: Arc<BackendCache>,
+    // False from when a verification of the database finds it inconsistent
+    // until one finds it consistent again. This is shared by every clone.
+    consistent: Arc<AtomicBool>,
 }
 
 pub struct BackendReadTransaction {
     committed: bool,
     conn: r2d2::PooledConnection<SqliteConnectionManager>,
+    started: Instant,
+    metrics: Arc<Metrics>,
+    cache: Arc<BackendCache>,
+    // The generation of the cache this began in, or None once the cache is
+    // bypassed.
+    generation: Option<u64>,
 }
 
 pub struct BackendWriteTransaction {
     committed: bool,
     conn: r2d2::PooledConnection<SqliteConnectionManager>,
+    started: Instant,
+    metrics: Arc<Metrics>,
+    cache: Arc<BackendCache>,
+    generation: u64,
+    dirty: RefCell<CacheDirty>,
+    // The change id of this write, given when it first changes an entry.
+    cid: RefCell<Option<Cid>>,
 }
 
 pub trait BackendTransaction {
     fn get_conn(&self) -> &r2d2::PooledConnection<r2d2_sqlite::SqliteConnectionManager>;
 
+    fn get_metrics(&self) -> &Metrics;
+
+    // The cached entry or index lookup, if there is one that this
+    // transaction may use.
+    fn cache_get_entry(&self, id: u64) -> Option<Entry<EntryValid, EntryCommitted>>;
+
+    fn cache_insert_entry(&self, e: &Entry<EntryValid, EntryCommitted>);
+
+    fn cache_get_idl(&self, key: &IdlKey) -> Option<BTreeSet<i64>>;
+
+    fn cache_insert_idl(&self, key: IdlKey, idl: &BTreeSet<i64>);
+
+    // The indexes that exist. These are read from the tables, so they are
+    // what the last reindex made.
+    fn get_idx_set(
+        &self,
+        au: &mut AuditScope,
+    ) -> Result<BTreeSet<(String, IndexType)>, OperationError> {
+        let mut stmt = self
+            .get_conn()
+            .prepare(
+                "SELECT name FROM sqlite_master WHERE type = 'table' AND name LIKE 'idx\\_%' ESCAPE '\\'",
+            )
+            .map_err(|e| sqlite_error(au, e))?;
+        let name_iter = stmt
+            .query_map(NO_PARAMS, |row| row.get::<_, String>(0))
+            .map_err(|e| sqlite_error(au, e))?;
+
+        let mut idx = BTreeSet::new();
+        for row in name_iter {
+            let name = row.map_err(|e| sqlite_error(au, e))?;
+            if let Some(i) = idx_from_table(name.as_str()) {
+                idx.insert(i);
+            }
+        }
+        Ok(idx)
+    }
+
+    // The ids of the entries that have this key in an index.
+    fn idx_lookup(
+        &self,
+        au: &mut AuditScope,
+        attr: &str,
+        itype: &IndexType,
+        key: &str,
+    ) -> Result<BTreeSet<i64>, OperationError> {
+        let cache_key = (attr.to_string(), itype.clone(), key.to_string());
+        if let Some(idl) = self.cache_get_idl(&cache_key) {
+            return Ok(idl);
+        }
+
+        let mut stmt = self
+            .get_conn()
+            .prepare(format!("SELECT id FROM {} WHERE key = :key", idx_table(attr, itype)).as_str())
+            .map_err(|e| sqlite_error(au, e))?;
+        let id_iter = stmt
+            .query_map_named(&[(":key", &key as &dyn ToSql)], |row| row.get::<_, i64>(0))
+            .map_err(|e| sqlite_error(au, e))?;
+
+        let mut idl = BTreeSet::new();
+        for row in id_iter {
+            idl.insert(row.map_err(|e| sqlite_error(au, e))?);
+        }
+        self.cache_insert_idl(cache_key, &idl);
+        Ok(idl)
+    }
+
+    // The number of keys in an index, and how many of them differ.
+    fn idx_cardinality(
+        &self,
+        au: &mut AuditScope,
+        attr: &str,
+        itype: &IndexType,
+    ) -> Result<(usize, usize), OperationError> {
+        self.get_conn()
+            .query_row(
+                format!(
+                    "SELECT COUNT(key), COUNT(DISTINCT key) FROM {}",
+                    idx_table(attr, itype)
+                )
+                .as_str(),
+                NO_PARAMS,
+                |row| (row.get::<_, i64>(0), row.get::<_, i64>(1)),
+            )
+            .map(|(keys, distinct)| (keys as usize, distinct as usize))
+            .map_err(|e| sqlite_error(au, e))
+    }
+
+    // Work out from the indexes which entries could match the filter. None
+    // means the indexes can't say, and every entry is a candidate. The
+    // candidates are still tested against the filter, so this only has to
+    // never leave out an entry that matches. This must agree with
+    // FilterResolved::is_indexed. The plan of each term is returned with
+    // it, for tracing.
+    fn filter2idl(
+        &self,
+        au: &mut AuditScope,
+        f: &FilterResolved,
+        idx: &BTreeSet<(String, IndexType)>,
+    ) -> Result<(Option<BTreeSet<i64>>, PlanNode), OperationError> {
+        let mut children = Vec::new();
+        let idl = match f {
+            FilterResolved::Eq(attr, value)
+                if idx.contains(&(attr.clone(), IndexType::EQUALITY)) =>
+            {
+                Some(self.idx_lookup(au, attr, &IndexType::EQUALITY, &value.get_idx_eq_key())?)
+            }
+            FilterResolved::Inclusion(attr, values)
+                if idx.contains(&(attr.clone(), IndexType::EQUALITY)) =>
+            {
+                let mut result = BTreeSet::new();
+                for v in values.iter() {
+                    result.extend(self.idx_lookup(
+                        au,
+                        attr,
+                        &IndexType::EQUALITY,
+                        &v.get_idx_eq_key(),
+                    )?);
+                }
+                Some(result)
+            }
+            FilterResolved::Pres(attr) if idx.contains(&(attr.clone(), IndexType::PRESENCE)) => {
+                Some(self.idx_lookup(au, attr, &IndexType::PRESENCE, IDX_PRES_KEY)?)
+            }
+            FilterResolved::Sub(attr, value)
+                if idx.contains(&(attr.clone(), IndexType::SUBSTRING)) =>
+            {
+                // Every key of the substring must be in a value that has it.
+                let mut result: Option<BTreeSet<i64>> = None;
+                for key in value.get_idx_sub_keys().iter() {
+                    let idl = self.idx_lookup(au, attr, &IndexType::SUBSTRING, key)?;
+                    result = Some(match result {
+                        Some(r) => r.intersection(&idl).cloned().collect(),
+                        None => idl,
+                    });
+                }
+                result
+            }
+            FilterResolved::And(l) => {
+                // Any term that is indexed narrows the candidates.
+                let mut result: Option<BTreeSet<i64>> = None;
+                for f in l.iter() {
+                    let (idl, plan) = self.filter2idl(au, f, idx)?;
+                    children.push(plan);
+                    if let Some(idl) = idl {
+                        result = Some(match result {
+                            Some(r) => r.intersection(&idl).cloned().collect(),
+                            None => idl,
+                        });
+                    }
+                }
+                result
+            }
+            FilterResolved::Or(l) => {
+                // Every term must be indexed, else any entry could match.
+                let mut result = Some(BTreeSet::new());
+                for f in l.iter() {
+                    let (idl, plan) = self.filter2idl(au, f, idx)?;
+                    children.push(plan);
+                    match (idl, result.as_mut()) {
+                        (Some(idl), Some(r)) => r.extend(idl),
+                        (None, Some(_)) => result = None,
+                        (_, None) => {}
+                    }
+                }
+                result
+            }
+            FilterResolved::False => Some(BTreeSet::new()),
+            _ => None,
+        };
+        let plan = PlanNode {
+            term: plan_term(f),
+            state: if idl.is_some() {
+                PlanState::Indexed
+            } else {
+                PlanState::FullScan
+            },
+            candidates: idl.as_ref().map(|idl| idl.len()),
+            children: children,
+        };
+        Ok((idl, plan))
+    }
+
+    // Load the entries with these ids, or every entry if there are none.
+    fn get_identries(
+        &self,
+        au: &mut AuditScope,
+        idl: Option<&BTreeSet<i64>>,
+    ) -> Result<Vec<IdEntry>, OperationError> {
+        let mut raw_entries: Vec<IdEntry> = Vec::new();
+        match idl {
+            Some(idl) => {
+                let mut stmt = self
+                    .get_conn()
+                    .prepare("SELECT id, data FROM id2entry WHERE id = :id")
+                    .map_err(|e| sqlite_error(au, e))?;
+                for id in idl.iter() {
+                    let id2entry_iter = stmt
+                        .query_map_named(&[(":id", id as &dyn ToSql)], |row| IdEntry {
+                            id: row.get(0),
+                            data: row.get(1),
+                        })
+                        .map_err(|e| sqlite_error(au, e))?;
+                    for row in id2entry_iter {
+                        raw_entries.push(row.map_err(|e| sqlite_error(au, e))?);
+                    }
+                }
+            }
+            None => {
+                // read them all
+                let mut stmt = self
+                    .get_conn()
+                    .prepare("SELECT id, data FROM id2entry")
+                    .map_err(|e| sqlite_error(au, e))?;
+                let id2entry_iter = stmt
+                    .query_map(NO_PARAMS, |row| IdEntry {
+                        id: row.get(0),
+                        data: row.get(1),
+                    })
+                    .map_err(|e| sqlite_error(au, e))?;
+
+                for row in id2entry_iter {
+                    // audit_log!(au, "raw entry: {:?}", row);
+                    raw_entries.push(row.map_err(|e| sqlite_error(au, e))?);
+                }
+            }
+        }
+        Ok(raw_entries)
+    }
+
+    // Load the entries with these ids, or every entry if there are none,
+    // taking those the cache has from it. They are given in the order of
+    // their ids.
+    fn get_entries(
+        &self,
+        au: &mut AuditScope,
+        idl: Option<&BTreeSet<i64>>,
+    ) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError> {
+        let mut entries = BTreeMap::new();
+        let raw_entries = match idl {
+            Some(idl) => {
+                let mut missed = BTreeSet::new();
+                for id in idl.iter() {
+                    match u64::try_from(*id)
+                        .ok()
+                        .and_then(|id| self.cache_get_entry(id))
+                    {
+                        Some(e) => {
+                            entries.insert(*id, e);
+                        }
+                        None => {
+                            missed.insert(*id);
+                        }
+                    }
+                }
+                if missed.is_empty() {
+                    Vec::new()
+                } else {
+                    self.get_identries(au, Some(&missed))?
+                }
+            }
+            None => {
+                // Every entry is read, but those that are cached needn't be
+                // deserialised.
+                let mut raw_entries = Vec::new();
+                for id_ent in self.get_identries(au, None)? {
+                    match u64::try_from(id_ent.id)
+                        .ok()
+                        .and_then(|id| self.cache_get_entry(id))
+                    {
+                        Some(e) => {
+                            entries.insert(id_ent.id, e);
+                        }
+                        None => raw_entries.push(id_ent),
+                    }
+                }
+                raw_entries
+            }
+        };
+
+        for id_ent in raw_entries.iter() {
+            let e = identry_to_entry(id_ent)?;
+            self.cache_insert_entry(&e);
+            entries.insert(id_ent.id, e);
+        }
+        Ok(entries.into_iter().map(|(_, e)| e).collect())
+    }
+
     // Take filter, and AuditScope ref?
     fn search(
         &self,
         au: &mut AuditScope,
         filt: &Filter<FilterValidResolved>,
     ) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError> {
+        self.search_plan(au, filt, None).map(|(entries, _)| entries)
+    }
+
+    // As search, but also return how the indexes answered it, and how many
+    // entries were loaded to find those that match. The caller completes
+    // the plan with the entries that access controls allow. With a limit,
+    // the search fails before any entry is loaded if there are more
+    // candidates than it.
+    fn search_plan(
+        &self,
+        au: &mut AuditScope,
+        filt: &Filter<FilterValidResolved>,
+        limit: Option<usize>,
+    ) -> Result<(Vec<Entry<EntryValid, EntryCommitted>>, SearchPlan), OperationError> {
         // Do things
         // Alloc a vec for the entries.
         // TODO #8: Make this actually a good size for the result set ...
-        // TODO #8: Actually compute indexes here.
-        // So to make this use indexes, we can use the filter type and
-        // destructure it to work out what we need to actually search (if
-        // possible) to create the candidate set.
         // Unlike DS, even if we don't get the index back, we can just pass
         // to the in-memory filter test and be done.
         audit_segment!(au, || {
@@ -67,70 +415,47 @@ pub trait BackendTransaction {
             let filt = filt.optimise();
             audit_log!(au, "filter optimised to --> {:?}", filt);
 
-            let mut raw_entries: Vec<IdEntry> = Vec::new();
-            {
-                // Actually do a search now!
-                // read them all
-                let mut stmt = try_audit!(
-                    au,
-                    self.get_conn().prepare("SELECT id, data FROM id2entry"),
-                    "SQLite Error {:?}",
-                    OperationError::SQLiteError
-                );
-                let id2entry_iter = try_audit!(
-                    au,
-                    stmt.query_map(NO_PARAMS, |row| IdEntry {
-                        id: row.get(0),
-                        data: row.get(1),
-                    }),
-                    "SQLite Error {:?}",
-                    OperationError::SQLiteError
-                );
+            let idx = self.get_idx_set(au)?;
+            let (idl, root) = self.filter2idl(au, filt.to_inner(), &idx)?;
+            match &idl {
+                Some(idl) => audit_log!(au, "indexed search, {} candidates", idl.len()),
+                None => {
+                    audit_log!(au, "unindexed search, all entries are candidates");
+                    self.get_metrics().record_full_scan();
+                }
+            };
 
-                for row in id2entry_iter {
-                    // audit_log!(au, "raw entry: {:?}", row);
-                    raw_entries.push(try_audit!(
+            if let Some(limit) = limit {
+                let candidates = match &idl {
+                    Some(idl) => idl.len(),
+                    None => self.count_entries(au)?,
+                };
+                if candidates > limit {
+                    audit_log!(
                         au,
-                        row,
-                        "SQLite Error {:?}",
-                        OperationError::SQLiteError
-                    ));
+                        "{} candidates exceed the limit of {}",
+                        candidates,
+                        limit
+                    );
+                    return Err(OperationError::ResultLimit(limit as u64));
                 }
             }
-            // Do other things
-            // Now, de-serialise the raw_entries back to entries, and populate their ID's
 
-            let entries: Result<Vec<Entry<EntryValid, EntryCommitted>>, _> = raw_entries
-                .iter()
-                .filter_map(|id_ent| {
-                    // We need the matches here to satisfy the filter map
-                    let db_e = match serde_cbor::from_slice(id_ent.data.as_slice())
-                        .map_err(|_| OperationError::SerdeCborError)
-                    {
-                        Ok(v) => v,
-                        Err(e) => return Some(Err(e)),
-                    };
-                    let id = match u64::try_from(id_ent.id)
-                        .map_err(|_| OperationError::InvalidEntryID)
-                    {
-                        Ok(v) => v,
-                        Err(e) => return Some(Err(e)),
-                    };
-                    let e = match Entry::from_dbentry(db_e, id)
-                        .map_err(|_| OperationError::CorruptedEntry(id))
-                    {
-                        Ok(v) => v,
-                        Err(e) => return Some(Err(e)),
-                    };
-                    if e.entry_match_no_index(&filt) {
-                        Some(Ok(e))
-                    } else {
-                        None
-                    }
-                })
+            let candidates = self.get_entries(au, idl.as_ref())?;
+            let loaded = candidates.len();
+            let entries: Vec<_> = candidates
+                .into_iter()
+                .filter(|e| e.entry_match_no_index(&filt))
                 .collect();
 
-            entries
+            let plan = SearchPlan {
+                filter: format!("{:?}", filt.to_inner()),
+                root: root,
+                loaded: loaded,
+                matched: entries.len(),
+                allowed: entries.len(),
+            };
+            Ok((entries, plan))
         })
     }
 
@@ -167,37 +492,207 @@ pub trait BackendTransaction {
         }
     }
 
+    // The records of the audit log made between since and until, inclusive,
+    // in the order they were written.
+    fn search_audit(
+        &self,
+        au: &mut AuditScope,
+        since: i64,
+        until: i64,
+    ) -> Result<Vec<DbAuditRecord>, OperationError> {
+        let mut raw_records: Vec<Vec<u8>> = Vec::new();
+        {
+            let mut stmt = self
+                .get_conn()
+                .prepare(
+                    "SELECT data FROM auditlog WHERE time >= :since AND time <= :until ORDER BY id ASC",
+                )
+                .map_err(|e| sqlite_error(au, e))?;
+            let auditlog_iter = stmt
+                .query_map_named(&[(":since", &since), (":until", &until)], |row| row.get(0))
+                .map_err(|e| sqlite_error(au, e))?;
+
+            for row in auditlog_iter {
+                raw_records.push(row.map_err(|e| sqlite_error(au, e))?);
+            }
+        }
+
+        raw_records
+            .iter()
+            .map(|data| {
+                serde_cbor::from_slice(data.as_slice()).map_err(|_| OperationError::SerdeCborError)
+            })
+            .collect()
+    }
+
+    fn get_changelog_meta(&self, au: &mut AuditScope) -> Result<DbChangelogMetaV1, OperationError> {
+        let data: Vec<u8> = self
+            .get_conn()
+            .query_row(
+                "SELECT data FROM changelog_meta WHERE id = 1",
+                NO_PARAMS,
+                |row| row.get(0),
+            )
+            .map_err(|e| sqlite_error(au, e))?;
+        match serde_cbor::from_slice(data.as_slice()) {
+            Ok(DbChangelogMeta::V1(meta)) => Ok(meta),
+            Err(_) => Err(OperationError::SerdeCborError),
+        }
+    }
+
+    // The id of the last change to each attribute of the entry. This is empty
+    // for an entry that hasn't changed since the changelog was added, or
+    // since a restore.
+    fn get_changestate(
+        &self,
+        au: &mut AuditScope,
+        uuid: &Uuid,
+    ) -> Result<BTreeMap<String, Cid>, OperationError> {
+        let u = uuid.to_hyphenated_ref().to_string();
+        let data: Option<Vec<u8>> = match self.get_conn().query_row_named(
+            "SELECT data FROM changestate WHERE uuid = :uuid",
+            &[(":uuid", &u)],
+            |row| row.get(0),
+        ) {
+            Ok(d) => Some(d),
+            Err(rusqlite::Error::QueryReturnedNoRows) => None,
+            Err(e) => return Err(sqlite_error(au, e)),
+        };
+        match data {
+            Some(d) => match serde_cbor::from_slice(d.as_slice()) {
+                Ok(DbChangeState::V1(cs)) => Ok(cs),
+                Err(_) => Err(OperationError::SerdeCborError),
+            },
+            None => Ok(BTreeMap::new()),
+        }
+    }
+
+    // The records of the changelog with a change id after since, or all of
+    // them, in the order they were written.
+    fn search_changes(
+        &self,
+        au: &mut AuditScope,
+        since: Option<&Cid>,
+    ) -> Result<Vec<DbChangeRecord>, OperationError> {
+        // A change in the same nanosecond may still be after since, so the
+        // rest are removed once they are read.
+        let ts = since.map(|c| c.ts as i64).unwrap_or(std::i64::MIN);
+        let mut raw_records: Vec<Vec<u8>> = Vec::new();
+        {
+            let mut stmt = self
+                .get_conn()
+                .prepare("SELECT data FROM changelog WHERE ts >= :ts ORDER BY id ASC")
+                .map_err(|e| sqlite_error(au, e))?;
+            let changelog_iter = stmt
+                .query_map_named(&[(":ts", &ts)], |row| row.get(0))
+                .map_err(|e| sqlite_error(au, e))?;
+
+            for row in changelog_iter {
+                raw_records.push(row.map_err(|e| sqlite_error(au, e))?);
+            }
+        }
+
+        let records: Result<Vec<DbChangeRecord>, _> = raw_records
+            .iter()
+            .map(|data| {
+                serde_cbor::from_slice(data.as_slice()).map_err(|_| OperationError::SerdeCborError)
+            })
+            .collect();
+        Ok(records?
+            .into_iter()
+            .filter(|r| match since {
+                Some(c) => r.cid() > c,
+                None => true,
+            })
+            .collect())
+    }
+
+    // Every entry that is stored, including recycled entries and tombstones.
+    fn count_entries(&self, au: &mut AuditScope) -> Result<usize, OperationError> {
+        self.get_conn()
+            .query_row("SELECT COUNT(id) FROM id2entry", NO_PARAMS, |row| {
+                row.get::<_, i64>(0)
+            })
+            .map(|c| c as usize)
+            .map_err(|e| sqlite_error(au, e))
+    }
+
     fn verify(&self) -> Vec<Result<(), ConsistencyError>> {
         Vec::new()
     }
 
-    fn backup(&self, audit: &mut AuditScope, dst_path: &str) -> Result<(), OperationError> {
-        // load all entries into RAM, may need to change this later
-        // if the size of the database compared to RAM is an issue
-        let mut raw_entries: Vec<IdEntry> = Vec::new();
+    // Each index must hold exactly the keys its entries generate. This gives
+    // each entry whose keys differ, by a key missing or one left behind, once
+    // for each index it's wrong in.
+    fn index_diffs(&self, au: &mut AuditScope) -> Result<Vec<IndexDiff>, OperationError> {
+        let idx = self.get_idx_set(au)?;
+        let raw_entries = self.get_identries(au, None)?;
+        let entries: Vec<_> = raw_entries
+            .iter()
+            .map(|id_ent| identry_to_entry(id_ent).map(|e| (id_ent.id, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut diffs = Vec::new();
+        for (attr, itype) in idx.iter() {
+            let mut expect: BTreeMap<i64, BTreeSet<String>> = BTreeMap::new();
+            for (id, e) in entries.iter() {
+                if let Some(vs) = e.get_ava(attr) {
+                    expect.insert(*id, idx_keys(vs.as_slice(), itype));
+                }
+            }
 
-        {
-            let mut stmt = try_audit!(
-                audit,
-                self.get_conn().prepare("SELECT id, data FROM id2entry"),
-                "sqlite error {:?}",
-                OperationError::SQLiteError
-            );
+            let mut held: BTreeMap<i64, BTreeSet<String>> = BTreeMap::new();
+            let mut stmt = self
+                .get_conn()
+                .prepare(format!("SELECT key, id FROM {}", idx_table(attr, itype)).as_str())
+                .map_err(|e| sqlite_error(au, e))?;
+            let pair_iter = stmt
+                .query_map(NO_PARAMS, |row| {
+                    (row.get::<_, String>(0), row.get::<_, i64>(1))
+                })
+                .map_err(|e| sqlite_error(au, e))?;
+            for row in pair_iter {
+                let (key, id) = row.map_err(|e| sqlite_error(au, e))?;
+                held.entry(id).or_insert_with(BTreeSet::new).insert(key);
+            }
 
-            let id2entry_iter = try_audit!(
-                audit,
-                stmt.query_map(NO_PARAMS, |row| IdEntry {
-                    id: row.get(0),
-                    data: row.get(1),
-                }),
-                "sqlite error {:?}",
-                OperationError::SQLiteError
-            );
+            let ids: BTreeSet<i64> = expect.keys().chain(held.keys()).cloned().collect();
+            for id in ids.into_iter() {
+                let h = held.remove(&id).unwrap_or_else(BTreeSet::new);
+                let x = expect.remove(&id).unwrap_or_else(BTreeSet::new);
+                if h != x {
+                    diffs.push(IndexDiff {
+                        name: idx_name(attr, itype),
+                        id: id,
+                        held: h,
+                        expect: x,
+                    });
+                }
+            }
+        }
+        Ok(diffs)
+    }
 
-            for row in id2entry_iter {
-                raw_entries.push(row.map_err(|_| OperationError::SQLiteError)?);
+    fn verify_indexes(&self, au: &mut AuditScope) -> Vec<Result<(), ConsistencyError>> {
+        match self.index_diffs(au) {
+            Ok(diffs) => diffs
+                .into_iter()
+                .map(|d| {
+                    audit_log!(au, "index {} is wrong for entry {}", d.name, d.id);
+                    Err(ConsistencyError::IndexInvalid(d.name, d.id as u64))
+                })
+                .collect(),
+            Err(e) => {
+                audit_log!(au, "failed to read the indexes -> {:?}", e);
+                vec![Err(ConsistencyError::QueryServerSearchFailure)]
             }
         }
+    }
+
+    fn backup(&self, audit: &mut AuditScope, dst_path: &str) -> Result<(), OperationError> {
+        // load all entries into RAM, may need to change this later
+        // if the size of the database compared to RAM is an issue
+        let raw_entries = self.get_identries(audit, None)?;
 
         let entries: Result<Vec<DbEntry>, _> = raw_entries
             .iter()
@@ -209,7 +704,17 @@ pub trait BackendTransaction {
 
         let entries = entries?;
 
-        let serialized_entries = serde_json::to_string_pretty(&entries);
+        // This is in the same transaction as the entries, so the backup is
+        // of a single point in time.
+        let auditlog = self.search_audit(audit, std::i64::MIN, std::i64::MAX)?;
+
+        let backup = DbBackup {
+            version: DBBACKUP_VERSION,
+            entries: entries,
+            auditlog: auditlog,
+        };
+
+        let serialized_entries = serde_json::to_string_pretty(&backup);
 
         let serialized_entries_str = try_audit!(
             audit,
@@ -218,7 +723,11 @@ pub trait BackendTransaction {
             OperationError::SerdeJsonError
         );
 
-        let result = fs::write(dst_path, serialized_entries_str);
+        // Write aside and rename over the destination, so that it's never
+        // left with part of a backup.
+        let tmp_path = format!("{}.tmp", dst_path);
+        let result = fs::write(tmp_path.as_str(), serialized_entries_str)
+            .and_then(|_| fs::rename(tmp_path.as_str(), dst_path));
 
         try_audit!(
             audit,
@@ -229,6 +738,172 @@ pub trait BackendTransaction {
 
         Ok(())
     }
+
+    // Take a backup into dir, named for the time it's taken, and remove the
+    // oldest backups there so that only the newest versions are kept. This
+    // gives the path of the new backup.
+    fn backup_rotate(
+        &self,
+        audit: &mut AuditScope,
+        dir: &str,
+        versions: usize,
+        ct: Duration,
+    ) -> Result<String, OperationError> {
+        try_audit!(
+            audit,
+            fs::create_dir_all(dir),
+            "fs::create_dir_all error {:?}",
+            OperationError::FsError
+        );
+
+        // These sort in the order they were taken.
+        let name = format!(
+            "{}{}{}",
+            BACKUP_FILE_PREFIX,
+            Utc.timestamp(ct.as_secs() as i64, ct.subsec_nanos())
+                .format("%Y%m%dT%H%M%S%.3fZ"),
+            BACKUP_FILE_SUFFIX
+        );
+        let dst_path = Path::new(dir).join(name);
+        let dst_path = dst_path.to_str().ok_or(OperationError::FsError)?;
+        self.backup(audit, dst_path)?;
+
+        let dir_entries = try_audit!(
+            audit,
+            fs::read_dir(dir),
+            "fs::read_dir error {:?}",
+            OperationError::FsError
+        );
+        let mut backups: Vec<_> = dir_entries
+            .filter_map(|de| de.ok().map(|de| de.path()))
+            .filter(|p| {
+                p.file_name()
+                    .and_then(|n| n.to_str())
+                    .map(|n| n.starts_with(BACKUP_FILE_PREFIX) && n.ends_with(BACKUP_FILE_SUFFIX))
+                    .unwrap_or(false)
+            })
+            .collect();
+        backups.sort();
+        let remove = backups.len().saturating_sub(versions);
+        for old in backups.iter().take(remove) {
+            audit_log!(audit, "removing old backup {:?}", old);
+            try_audit!(
+                audit,
+                fs::remove_file(old),
+                "fs::remove_file error {:?}",
+                OperationError::FsError
+            );
+        }
+
+        Ok(dst_path.to_string())
+    }
+}
+
+static BACKUP_FILE_PREFIX: &'static str = "kanidm-backup-";
+static BACKUP_FILE_SUFFIX: &'static str = ".json";
+
+// Each index is a table of the keys of an attribute's values, and the ids
+// of the entries that have them. The tables are named for the type of index
+// and the attribute.
+static IDX_PREFIX: &'static str = "idx_";
+static IDX_TYPES: [IndexType; 3] = [
+    IndexType::EQUALITY,
+    IndexType::PRESENCE,
+    IndexType::SUBSTRING,
+];
+// A presence index has this one key for each entry with the attribute.
+static IDX_PRES_KEY: &'static str = "";
+
+fn idx_type_name(itype: &IndexType) -> &'static str {
+    match itype {
+        IndexType::EQUALITY => "eq",
+        IndexType::PRESENCE => "pres",
+        IndexType::SUBSTRING => "sub",
+    }
+}
+
+fn idx_table(attr: &str, itype: &IndexType) -> String {
+    format!("{}{}_{}", IDX_PREFIX, idx_type_name(itype), attr)
+}
+
+fn idx_from_table(name: &str) -> Option<(String, IndexType)> {
+    IDX_TYPES.iter().find_map(|itype| {
+        let prefix = format!("{}{}_", IDX_PREFIX, idx_type_name(itype));
+        if name.starts_with(prefix.as_str()) {
+            Some((name[prefix.len()..].to_string(), itype.clone()))
+        } else {
+            None
+        }
+    })
+}
+
+// How an index is named to admins, such as "name.eq".
+pub fn idx_name(attr: &str, itype: &IndexType) -> String {
+    format!("{}.{}", attr, idx_type_name(itype))
+}
+
+// The attribute name is part of the table's name, so only one that is safe
+// there can be indexed.
+// The term of the filter a plan node is for. Values are left out, so that a
+// trace never logs them.
+fn plan_term(f: &FilterResolved) -> String {
+    match f {
+        FilterResolved::Eq(a, _) => format!("eq {}", a),
+        FilterResolved::Sub(a, _) => format!("sub {}", a),
+        FilterResolved::Pres(a) => format!("pres {}", a),
+        FilterResolved::Gte(a, _) => format!("gte {}", a),
+        FilterResolved::Lte(a, _) => format!("lte {}", a),
+        FilterResolved::Inclusion(a, _) => format!("inclusion {}", a),
+        FilterResolved::Or(_) => "or".to_string(),
+        FilterResolved::And(_) => "and".to_string(),
+        FilterResolved::AndNot(_) => "andnot".to_string(),
+        FilterResolved::True => "true".to_string(),
+        FilterResolved::False => "false".to_string(),
+    }
+}
+
+// The attributes whose values differ between the entry as it was and as it
+// is, including those that were added or removed.
+fn changed_attrs(
+    pre: &Entry<EntryValid, EntryCommitted>,
+    post: &Entry<EntryValid, EntryCommitted>,
+) -> BTreeSet<String> {
+    let pre: BTreeMap<_, _> = pre.avas().collect();
+    let post: BTreeMap<_, _> = post.avas().collect();
+    pre.keys()
+        .chain(post.keys())
+        .filter(|a| pre.get(*a) != post.get(*a))
+        .map(|a| (*a).clone())
+        .collect()
+}
+
+fn identry_to_entry(id_ent: &IdEntry) -> Result<Entry<EntryValid, EntryCommitted>, OperationError> {
+    let db_e = serde_cbor::from_slice(id_ent.data.as_slice())
+        .map_err(|_| OperationError::SerdeCborError)?;
+    let id = u64::try_from(id_ent.id).map_err(|_| OperationError::InvalidEntryID)?;
+    Entry::from_dbentry(db_e, id).map_err(|_| OperationError::CorruptedEntry(id))
+}
+
+fn idx_attr_is_valid(attr: &str) -> bool {
+    !attr.is_empty() && attr.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn idx_keys(vs: &[&Value], itype: &IndexType) -> BTreeSet<String> {
+    match itype {
+        IndexType::EQUALITY => vs
+            .iter()
+            .map(|v| v.to_partialvalue().get_idx_eq_key())
+            .collect(),
+        IndexType::PRESENCE => {
+            let mut keys = BTreeSet::new();
+            keys.insert(IDX_PRES_KEY.to_string());
+            keys
+        }
+        IndexType::SUBSTRING => vs
+            .iter()
+            .flat_map(|v| v.to_partialvalue().get_idx_sub_keys())
+            .collect(),
+    }
 }
 
 impl Drop for BackendReadTransaction {
@@ -243,11 +918,16 @@ impl Drop for BackendReadTransaction {
                 // it becomes and issue :(
                 .expect("Unable to rollback transaction! Can not proceed!!!");
         }
+        self.metrics.record_be_txn(false, self.started.elapsed());
     }
 }
 
 impl BackendReadTransaction {
-    pub fn new(conn: r2d2::PooledConnection<SqliteConnectionManager>) -> Self {
+    pub fn new(
+        conn: r2d2::PooledConnection<SqliteConnectionManager>,
+        metrics: Arc<Metrics>,
+        cache: Arc<BackendCache>,
+    ) -> Self {
         // Start the transaction
         debug!("Starting BE RO txn ...");
         // I'm happy for this to be an expect, because this is a huge failure
@@ -257,20 +937,104 @@ impl BackendReadTransaction {
         // There is no way to flag this is an RO operation.
         conn.execute("BEGIN TRANSACTION", NO_PARAMS)
             .expect("Unable to begin transaction!");
+        // sqlite only takes the snapshot a transaction reads from at its
+        // first read, so that is made now. Whatever commits after this, the
+        // transaction reads the database as it was when it began.
+        conn.query_row("SELECT COUNT(*) FROM sqlite_master", NO_PARAMS, |_| ())
+            .expect("Unable to begin transaction!");
+        let generation = cache.generation();
         BackendReadTransaction {
             committed: false,
             conn: conn,
+            started: Instant::now(),
+            metrics: metrics,
+            cache: cache,
+            generation: Some(generation),
         }
     }
+
+    // Read everything from the database for the rest of the transaction, as
+    // a verification must check what is stored rather than the cache.
+    pub fn bypass_cache(&mut self) {
+        self.generation = None;
+    }
 }
 
 impl BackendTransaction for BackendReadTransaction {
     fn get_conn(&self) -> &r2d2::PooledConnection<r2d2_sqlite::SqliteConnectionManager> {
         &self.conn
     }
+
+    fn get_metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    fn cache_get_entry(&self, id: u64) -> Option<Entry<EntryValid, EntryCommitted>> {
+        self.generation
+            .and_then(|g| self.cache.get_entry(&self.metrics, g, id))
+    }
+
+    fn cache_insert_entry(&self, e: &Entry<EntryValid, EntryCommitted>) {
+        if let Some(g) = self.generation {
+            self.cache.insert_entry(g, e);
+        }
+    }
+
+    fn cache_get_idl(&self, key: &IdlKey) -> Option<BTreeSet<i64>> {
+        self.generation
+            .and_then(|g| self.cache.get_idl(&self.metrics, g, key))
+    }
+
+    fn cache_insert_idl(&self, key: IdlKey, idl: &BTreeSet<i64>) {
+        if let Some(g) = self.generation {
+            self.cache.insert_idl(g, key, idl);
+        }
+    }
 }
 
 static DBV_ID2ENTRY: &'static str = "id2entry";
+static DBV_AUDITLOG: &'static str = "auditlog";
+static DBV_CHANGELOG: &'static str = "changelog";
+static DBV_INDEX: &'static str = "index";
+static DBV_MIGRATION_LEVEL: &'static str = "migration_level";
+
+// The version of each part of the database that this server writes. When the
+// database is opened, a part at a lower version is upgraded, but a part at a
+// higher version was written by a newer server, and can't be read.
+pub static DB_VERSIONS: [(&'static str, i64); 4] = [
+    (DBV_ID2ENTRY, 2),
+    (DBV_AUDITLOG, 1),
+    (DBV_CHANGELOG, 1),
+    (DBV_INDEX, 1),
+];
+
+fn db_version_key(conn: &Connection, key: &str) -> i64 {
+    match conn.query_row_named(
+        "SELECT version FROM db_version WHERE id = :id",
+        &[(":id", &key)],
+        |row| row.get(0),
+    ) {
+        Ok(e) => e,
+        Err(_) => {
+            // The value is missing, default to 0.
+            0
+        }
+    }
+}
+
+// The version each part of the database at path is at, and the version this
+// server supports. The database is only read, so nothing is upgraded.
+pub fn read_db_versions(
+    audit: &mut AuditScope,
+    path: &str,
+) -> Result<Vec<(&'static str, i64, i64)>, OperationError> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|e| sqlite_error(audit, e))?;
+    Ok(DB_VERSIONS
+        .iter()
+        .map(|(key, supported)| (*key, db_version_key(&conn, key), *supported))
+        .collect())
+}
 
 impl Drop for BackendWriteTransaction {
     // Abort
@@ -281,43 +1045,79 @@ impl Drop for BackendWriteTransaction {
                 .execute("ROLLBACK TRANSACTION", NO_PARAMS)
                 .expect("Unable to rollback transaction! Can not proceed!!!");
         }
+        self.metrics.record_be_txn(true, self.started.elapsed());
     }
 }
 
+// A write only takes from the cache what it hasn't changed, and adds
+// nothing to it, as what it reads may not be committed.
 impl BackendTransaction for BackendWriteTransaction {
     fn get_conn(&self) -> &r2d2::PooledConnection<r2d2_sqlite::SqliteConnectionManager> {
         &self.conn
     }
+
+    fn get_metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    fn cache_get_entry(&self, id: u64) -> Option<Entry<EntryValid, EntryCommitted>> {
+        let dirty = self.dirty.borrow();
+        if dirty.all_entries || dirty.ids.contains(&id) {
+            return None;
+        }
+        self.cache.get_entry(&self.metrics, self.generation, id)
+    }
+
+    fn cache_insert_entry(&self, _e: &Entry<EntryValid, EntryCommitted>) {}
+
+    fn cache_get_idl(&self, key: &IdlKey) -> Option<BTreeSet<i64>> {
+        let dirty = self.dirty.borrow();
+        if dirty.all_idls || dirty.keys.contains(key) {
+            return None;
+        }
+        self.cache.get_idl(&self.metrics, self.generation, key)
+    }
+
+    fn cache_insert_idl(&self, _key: IdlKey, _idl: &BTreeSet<i64>) {}
 }
 
 impl BackendWriteTransaction {
-    pub fn new(conn: r2d2::PooledConnection<SqliteConnectionManager>) -> Self {
+    pub fn new(
+        conn: r2d2::PooledConnection<SqliteConnectionManager>,
+        metrics: Arc<Metrics>,
+        cache: Arc<BackendCache>,
+    ) -> Self {
         // Start the transaction
         debug!("Starting BE WR txn ...");
         conn.execute("BEGIN TRANSACTION", NO_PARAMS)
             .expect("Unable to begin transaction!");
+        let generation = cache.generation();
         BackendWriteTransaction {
             committed: false,
             conn: conn,
+            started: Instant::now(),
+            metrics: metrics,
+            cache: cache,
+            generation: generation,
+            dirty: RefCell::new(CacheDirty::default()),
+            cid: RefCell::new(None),
         }
     }
 
-    fn get_id2entry_max_id(&self) -> Result<i64, OperationError> {
+    fn get_id2entry_max_id(&self, au: &mut AuditScope) -> Result<i64, OperationError> {
         let mut stmt = self
             .conn
             .prepare("SELECT MAX(id) as id_max FROM id2entry")
-            .map_err(|_| OperationError::SQLiteError)?;
+            .map_err(|e| sqlite_error(au, e))?;
         // This exists checks for if any rows WERE returned
         // that way we know to shortcut or not.
-        let v = stmt
-            .exists(NO_PARAMS)
-            .map_err(|_| OperationError::SQLiteError)?;
+        let v = stmt.exists(NO_PARAMS).map_err(|e| sqlite_error(au, e))?;
 
         Ok(if v {
             // We have some rows, let get max!
             let i: Option<i64> = stmt
                 .query_row(NO_PARAMS, |row| row.get(0))
-                .map_err(|_| OperationError::SQLiteError)?;
+                .map_err(|e| sqlite_error(au, e))?;
             i.unwrap_or(0)
         } else {
             // No rows are present, return a 0.
@@ -329,9 +1129,9 @@ impl BackendWriteTransaction {
         &self,
         au: &mut AuditScope,
         dbentries: &Vec<DbEntry>,
-    ) -> Result<(), OperationError> {
+    ) -> Result<Vec<i64>, OperationError> {
         // Get the max id from the db. We store this ourselves to avoid max() calls.
-        let mut id_max = self.get_id2entry_max_id()?;
+        let mut id_max = self.get_id2entry_max_id(au)?;
 
         let ser_entries: Result<Vec<IdEntry>, _> = dbentries
             .iter()
@@ -349,28 +1149,97 @@ impl BackendWriteTransaction {
 
         let ser_entries = ser_entries?;
         {
-            let mut stmt = try_audit!(
-                au,
-                self.conn
-                    .prepare("INSERT INTO id2entry (id, data) VALUES (:id, :data)"),
-                "rusqlite error {:?}",
-                OperationError::SQLiteError
-            );
+            let mut stmt = self
+                .conn
+                .prepare("INSERT INTO id2entry (id, data) VALUES (:id, :data)")
+                .map_err(|e| sqlite_error(au, e))?;
 
             // write them all
-            for ser_entry in ser_entries {
-                try_audit!(
-                    au,
-                    stmt.execute_named(&[
-                        (":id", &ser_entry.id as &dyn ToSql),
-                        (":data", &ser_entry.data as &dyn ToSql)
-                    ]),
-                    "rusqlite error {:?}",
-                    OperationError::SQLiteError
-                );
+            for ser_entry in ser_entries.iter() {
+                stmt.execute_named(&[
+                    (":id", &ser_entry.id as &dyn ToSql),
+                    (":data", &ser_entry.data as &dyn ToSql),
+                ])
+                .map_err(|e| sqlite_error(au, e))?;
             }
         }
 
+        // An id may have been used by an entry that was deleted.
+        self.dirty
+            .borrow_mut()
+            .ids
+            .extend(ser_entries.iter().map(|ser_entry| ser_entry.id as u64));
+        Ok(ser_entries.iter().map(|ser_entry| ser_entry.id).collect())
+    }
+
+    // Add the keys of an entry's values to each index.
+    fn idx_add<STATE>(
+        &self,
+        au: &mut AuditScope,
+        idx: &BTreeSet<(String, IndexType)>,
+        id: i64,
+        e: &Entry<EntryValid, STATE>,
+    ) -> Result<(), OperationError> {
+        for (attr, itype) in idx.iter() {
+            let vs = match e.get_ava(attr) {
+                Some(vs) => vs,
+                None => continue,
+            };
+            let mut stmt = self
+                .conn
+                .prepare(
+                    format!(
+                        "INSERT INTO {} (key, id) VALUES (:key, :id)",
+                        idx_table(attr, itype)
+                    )
+                    .as_str(),
+                )
+                .map_err(|e| sqlite_error(au, e))?;
+            let mut dirty = self.dirty.borrow_mut();
+            for key in idx_keys(vs.as_slice(), itype).into_iter() {
+                stmt.execute_named(&[(":key", &key as &dyn ToSql), (":id", &id as &dyn ToSql)])
+                    .map_err(|e| sqlite_error(au, e))?;
+                // After a reindex every lookup is removed anyway.
+                if !dirty.all_idls {
+                    dirty.keys.insert((attr.clone(), itype.clone(), key));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Remove every key of an entry from each index.
+    fn idx_remove(
+        &self,
+        au: &mut AuditScope,
+        idx: &BTreeSet<(String, IndexType)>,
+        id: i64,
+    ) -> Result<(), OperationError> {
+        for (attr, itype) in idx.iter() {
+            // The keys are read first, so the lookups of them that are
+            // cached can be removed.
+            let table = idx_table(attr, itype);
+            {
+                let mut stmt = self
+                    .conn
+                    .prepare(format!("SELECT key FROM {} WHERE id = :id", table).as_str())
+                    .map_err(|e| sqlite_error(au, e))?;
+                let key_iter = stmt
+                    .query_map_named(&[(":id", &id as &dyn ToSql)], |row| row.get::<_, String>(0))
+                    .map_err(|e| sqlite_error(au, e))?;
+                let mut dirty = self.dirty.borrow_mut();
+                for row in key_iter {
+                    let key = row.map_err(|e| sqlite_error(au, e))?;
+                    dirty.keys.insert((attr.clone(), itype.clone(), key));
+                }
+            }
+            self.conn
+                .execute_named(
+                    format!("DELETE FROM {} WHERE id = :id", table).as_str(),
+                    &[(":id", &id as &dyn ToSql)],
+                )
+                .map_err(|e| sqlite_error(au, e))?;
+        }
         Ok(())
     }
 
@@ -396,9 +1265,18 @@ impl BackendWriteTransaction {
 
             let dbentries: Vec<_> = entries.iter().map(|e| e.into_dbentry()).collect();
 
-            self.internal_create(au, &dbentries)
+            let ids = self.internal_create(au, &dbentries)?;
+
+            let idx = self.get_idx_set(au)?;
+            for (id, e) in ids.iter().zip(entries.iter()) {
+                self.idx_add(au, &idx, *id, e)?;
+            }
 
-            // TODO #8: update indexes (as needed)
+            for e in entries.iter() {
+                let attrs = e.avas().map(|(a, _)| a.clone()).collect();
+                self.record_change(au, e.get_uuid(), attrs)?;
+            }
+            Ok(())
         })
     }
 
@@ -457,23 +1335,46 @@ impl BackendWriteTransaction {
             return Err(OperationError::InvalidEntryState);
         }
 
+        // The entries as they were, to find what changed.
+        let ids: BTreeSet<i64> = ser_entries.iter().map(|ser_ent| ser_ent.id).collect();
+        let pre_entries: BTreeMap<Uuid, Entry<EntryValid, EntryCommitted>> = self
+            .get_entries(au, Some(&ids))?
+            .into_iter()
+            .map(|e| (e.get_uuid().clone(), e))
+            .collect();
+
         // Now, given the list of id's, update them
         {
-            let mut stmt = try_audit!(
-                au,
-                self.conn
-                    .prepare("UPDATE id2entry SET data = :data WHERE id = :id"),
-                "RusqliteError: {:?}",
-                OperationError::SQLiteError
-            );
+            let mut stmt = self
+                .conn
+                .prepare("UPDATE id2entry SET data = :data WHERE id = :id")
+                .map_err(|e| sqlite_error(au, e))?;
 
             for ser_ent in ser_entries.iter() {
-                try_audit!(
-                    au,
-                    stmt.execute_named(&[(":id", &ser_ent.id), (":data", &ser_ent.data)]),
-                    "RusqliteError: {:?}",
-                    OperationError::SQLiteError
-                );
+                stmt.execute_named(&[(":id", &ser_ent.id), (":data", &ser_ent.data)])
+                    .map_err(|e| sqlite_error(au, e))?;
+            }
+        }
+        self.dirty
+            .borrow_mut()
+            .ids
+            .extend(ser_entries.iter().map(|ser_ent| ser_ent.id as u64));
+
+        // Any value could have changed, so the entry is indexed again.
+        let idx = self.get_idx_set(au)?;
+        for (ser_ent, e) in ser_entries.iter().zip(entries.iter()) {
+            self.idx_remove(au, &idx, ser_ent.id)?;
+            self.idx_add(au, &idx, ser_ent.id, e)?;
+        }
+
+        // An entry that was written again as it was hasn't changed.
+        for e in entries.iter() {
+            let attrs = match pre_entries.get(e.get_uuid()) {
+                Some(pre) => changed_attrs(pre, e),
+                None => e.avas().map(|(a, _)| a.clone()).collect(),
+            };
+            if !attrs.is_empty() {
+                self.record_change(au, e.get_uuid(), attrs)?;
             }
         }
 
@@ -523,16 +1424,36 @@ impl BackendWriteTransaction {
                 // SQL doesn't say if the thing "does or does not exist anymore". As a result,
                 // two deletes is a safe and valid operation. Given how we allocate ID's we are
                 // probably okay with this.
-                let mut stmt = try_audit!(
-                    au,
-                    self.conn.prepare("DELETE FROM id2entry WHERE id = :id"),
-                    "SQLite Error {:?}",
-                    OperationError::SQLiteError
-                );
+                let mut stmt = self
+                    .conn
+                    .prepare("DELETE FROM id2entry WHERE id = :id")
+                    .map_err(|e| sqlite_error(au, e))?;
 
                 for id in id_list.iter() {
-                    stmt.execute(&[id])
-                        .map_err(|_| OperationError::SQLiteError)?;
+                    stmt.execute(&[id]).map_err(|e| sqlite_error(au, e))?;
+                }
+            }
+            self.dirty
+                .borrow_mut()
+                .ids
+                .extend(id_list.iter().map(|id| *id as u64));
+
+            let idx = self.get_idx_set(au)?;
+            for id in id_list.iter() {
+                self.idx_remove(au, &idx, *id)?;
+            }
+
+            // The changes that made the entries tombstones are kept in the
+            // changelog, but there is nothing left to hold a change state.
+            {
+                let mut stmt = self
+                    .conn
+                    .prepare("DELETE FROM changestate WHERE uuid = :uuid")
+                    .map_err(|e| sqlite_error(au, e))?;
+                for e in entries.iter() {
+                    let u = e.get_uuid().to_hyphenated_ref().to_string();
+                    stmt.execute_named(&[(":uuid", &u)])
+                        .map_err(|e| sqlite_error(au, e))?;
                 }
             }
 
@@ -540,43 +1461,185 @@ impl BackendWriteTransaction {
         })
     }
 
+    pub fn append_audit(
+        &self,
+        au: &mut AuditScope,
+        record: &DbAuditRecord,
+    ) -> Result<(), OperationError> {
+        let data = serde_cbor::to_vec(record).map_err(|_| OperationError::SerdeCborError)?;
+        self.conn
+            .execute_named(
+                "INSERT INTO auditlog (time, data) VALUES (:time, :data)",
+                &[
+                    (":time", &record.time() as &dyn ToSql),
+                    (":data", &data as &dyn ToSql),
+                ],
+            )
+            .map_err(|e| sqlite_error(au, e))?;
+        Ok(())
+    }
+
+    fn set_changelog_meta(
+        &self,
+        au: &mut AuditScope,
+        meta: DbChangelogMetaV1,
+    ) -> Result<(), OperationError> {
+        let data = serde_cbor::to_vec(&DbChangelogMeta::V1(meta))
+            .map_err(|_| OperationError::SerdeCborError)?;
+        self.conn
+            .execute_named(
+                "INSERT OR REPLACE INTO changelog_meta (id, data) VALUES (1, :data)",
+                &[(":data", &data)],
+            )
+            .map_err(|e| sqlite_error(au, e))?;
+        Ok(())
+    }
+
+    // The change id of this write. It's given when first asked for, so a
+    // write that changes nothing doesn't use one.
+    fn get_cid(&self, au: &mut AuditScope) -> Result<Cid, OperationError> {
+        if let Some(cid) = self.cid.borrow().as_ref() {
+            return Ok(cid.clone());
+        }
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("Clock failure!");
+        let now = now.as_secs() * 1_000_000_000 + now.subsec_nanos() as u64;
+
+        let mut meta = self.get_changelog_meta(au)?;
+        let cid = Cid::next(meta.last.as_ref(), &meta.s_uuid, now);
+        meta.last = Some(cid.clone());
+        self.set_changelog_meta(au, meta)?;
+        audit_log!(au, "change id {}", cid);
+        *self.cid.borrow_mut() = Some(cid.clone());
+        Ok(cid)
+    }
+
+    // Give these attributes of the entry the change id of this write, and
+    // add the change to the changelog.
+    fn record_change(
+        &self,
+        au: &mut AuditScope,
+        uuid: &Uuid,
+        attrs: BTreeSet<String>,
+    ) -> Result<(), OperationError> {
+        let cid = self.get_cid(au)?;
+
+        let mut changestate = self.get_changestate(au, uuid)?;
+        for a in attrs.iter() {
+            changestate.insert(a.clone(), cid.clone());
+        }
+        let u = uuid.to_hyphenated_ref().to_string();
+        let data = serde_cbor::to_vec(&DbChangeState::V1(changestate))
+            .map_err(|_| OperationError::SerdeCborError)?;
+        self.conn
+            .execute_named(
+                "INSERT OR REPLACE INTO changestate (uuid, data) VALUES (:uuid, :data)",
+                &[(":uuid", &u as &dyn ToSql), (":data", &data as &dyn ToSql)],
+            )
+            .map_err(|e| sqlite_error(au, e))?;
+
+        let record = DbChangeRecord::V1(DbChangeRecordV1 {
+            cid: cid.clone(),
+            uuid: uuid.clone(),
+            attrs: attrs,
+        });
+        let data = serde_cbor::to_vec(&record).map_err(|_| OperationError::SerdeCborError)?;
+        self.conn
+            .execute_named(
+                "INSERT INTO changelog (ts, data) VALUES (:ts, :data)",
+                &[
+                    (":ts", &(cid.ts as i64) as &dyn ToSql),
+                    (":data", &data as &dyn ToSql),
+                ],
+            )
+            .map_err(|e| sqlite_error(au, e))?;
+        Ok(())
+    }
+
+    // Remove the records of the changelog made before this time, in
+    // nanoseconds since the epoch. This gives how many were removed.
+    pub fn trim_changelog(
+        &self,
+        au: &mut AuditScope,
+        before: u64,
+    ) -> Result<usize, OperationError> {
+        let before = before as i64;
+        let latest: Option<Vec<u8>> = match self.conn.query_row_named(
+            "SELECT data FROM changelog WHERE ts < :ts ORDER BY id DESC LIMIT 1",
+            &[(":ts", &before)],
+            |row| row.get(0),
+        ) {
+            Ok(d) => Some(d),
+            Err(rusqlite::Error::QueryReturnedNoRows) => None,
+            Err(e) => return Err(sqlite_error(au, e)),
+        };
+        let latest: DbChangeRecord = match latest {
+            Some(d) => {
+                serde_cbor::from_slice(d.as_slice()).map_err(|_| OperationError::SerdeCborError)?
+            }
+            None => return Ok(0),
+        };
+
+        let trimmed = self
+            .conn
+            .execute_named("DELETE FROM changelog WHERE ts < :ts", &[(":ts", &before)])
+            .map_err(|e| sqlite_error(au, e))?;
+
+        let mut meta = self.get_changelog_meta(au)?;
+        meta.trimmed = Some(latest.cid().clone());
+        self.set_changelog_meta(au, meta)?;
+        Ok(trimmed)
+    }
+
     pub unsafe fn purge(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
         // remove all entries from database
-        try_audit!(
-            audit,
-            self.conn.execute("DELETE FROM id2entry", NO_PARAMS),
-            "rustqlite error {:?}",
-            OperationError::SQLiteError
-        );
+        self.conn
+            .execute("DELETE FROM id2entry", NO_PARAMS)
+            .map_err(|e| sqlite_error(audit, e))?;
+        self.dirty.borrow_mut().all_entries = true;
 
         Ok(())
     }
 
     pub fn restore(&self, audit: &mut AuditScope, src_path: &str) -> Result<(), OperationError> {
-        // load all entries into RAM, may need to change this later
-        // if the size of the database compared to RAM is an issue
-        let serialized_string_option = fs::read_to_string(src_path);
-
-        let serialized_string = try_audit!(
+        // Nothing is removed until the whole backup is known to be one that
+        // can be restored.
+        let backup = read_backup(audit, src_path)?;
+        audit_log!(
             audit,
-            serialized_string_option,
-            "fs::read_to_string {:?}",
-            OperationError::FsError
+            "restoring {} entries from a version {} backup",
+            backup.entries.len(),
+            backup.version
         );
 
         try_audit!(audit, unsafe { self.purge(audit) });
+        self.conn
+            .execute("DELETE FROM auditlog", NO_PARAMS)
+            .map_err(|e| sqlite_error(audit, e))?;
+        self.conn
+            .execute("DELETE FROM changestate", NO_PARAMS)
+            .map_err(|e| sqlite_error(audit, e))?;
+        self.conn
+            .execute("DELETE FROM changelog", NO_PARAMS)
+            .map_err(|e| sqlite_error(audit, e))?;
+        // Anything may have changed, so no change made before the restore
+        // can be followed by the changes since it.
+        let cid = self.get_cid(audit)?;
+        let mut meta = self.get_changelog_meta(audit)?;
+        meta.trimmed = Some(cid);
+        self.set_changelog_meta(audit, meta)?;
+
+        let dbentries: Vec<DbEntry> = backup.entries.iter().map(|e| e.into_dbentry()).collect();
+        self.internal_create(audit, &dbentries)?;
+        for record in backup.auditlog.iter() {
+            self.append_audit(audit, record)?;
+        }
 
-        let entries_option: Result<Vec<DbEntry>, serde_json::Error> =
-            serde_json::from_str(&serialized_string);
-
-        let entries = try_audit!(
-            audit,
-            entries_option,
-            "serde_json error {:?}",
-            OperationError::SerdeJsonError
-        );
-
-        self.internal_create(audit, &entries)?;
+        // The indexes still hold the keys of the entries that were purged,
+        // so they are rebuilt from the restored ones.
+        let idx = self.get_idx_set(audit)?;
+        self.reindex(audit, &idx)?;
 
         let vr = self.verify();
         if vr.len() == 0 {
@@ -584,36 +1647,180 @@ impl BackendWriteTransaction {
         } else {
             Err(OperationError::ConsistencyError(vr))
         }
-        // TODO #8: run re-index after db is restored
+    }
+
+    // Drop every index, and build each of these from the entries. This is in
+    // the transaction, so if it fails the indexes are left as they were.
+    // This gives the number of keys in each index.
+    pub fn reindex(
+        &self,
+        au: &mut AuditScope,
+        idx: &BTreeSet<(String, IndexType)>,
+    ) -> Result<BTreeMap<String, usize>, OperationError> {
+        audit_segment!(au, || {
+            self.dirty.borrow_mut().all_idls = true;
+            for (attr, itype) in self.get_idx_set(au)?.iter() {
+                self.conn
+                    .execute(
+                        format!("DROP TABLE {}", idx_table(attr, itype)).as_str(),
+                        NO_PARAMS,
+                    )
+                    .map_err(|e| sqlite_error(au, e))?;
+            }
+
+            let mut valid_idx = BTreeSet::new();
+            for (attr, itype) in idx.iter() {
+                if !idx_attr_is_valid(attr) {
+                    audit_log!(au, "attribute {} can't be indexed, skipping", attr);
+                    continue;
+                }
+                let table = idx_table(attr, itype);
+                self.conn
+                    .execute(
+                        format!(
+                            "CREATE TABLE {} (key TEXT NOT NULL, id INTEGER NOT NULL)",
+                            table
+                        )
+                        .as_str(),
+                        NO_PARAMS,
+                    )
+                    .map_err(|e| sqlite_error(au, e))?;
+                self.conn
+                    .execute(
+                        format!("CREATE INDEX {}_key ON {} (key)", table, table).as_str(),
+                        NO_PARAMS,
+                    )
+                    .map_err(|e| sqlite_error(au, e))?;
+                self.conn
+                    .execute(
+                        format!("CREATE INDEX {}_id ON {} (id)", table, table).as_str(),
+                        NO_PARAMS,
+                    )
+                    .map_err(|e| sqlite_error(au, e))?;
+                valid_idx.insert((attr.clone(), itype.clone()));
+            }
+
+            let raw_entries = self.get_identries(au, None)?;
+            let total = raw_entries.len();
+            audit_log!(au, "reindexing {} entries", total);
+            for (i, id_ent) in raw_entries.iter().enumerate() {
+                let e = identry_to_entry(id_ent)?;
+                self.idx_add(au, &valid_idx, id_ent.id, &e)?;
+                if (i + 1) % 1000 == 0 {
+                    info!("reindexed {} of {} entries", i + 1, total);
+                }
+            }
+
+            let mut counts = BTreeMap::new();
+            for (attr, itype) in valid_idx.iter() {
+                let (keys, _) = self.idx_cardinality(au, attr, itype)?;
+                let name = idx_name(attr, itype);
+                audit_log!(au, "index {} has {} keys", name, keys);
+                counts.insert(name, keys);
+            }
+            Ok(counts)
+        })
+    }
+
+    // Rebuild the indexes only if these differ from those that exist.
+    pub fn update_indexes(
+        &self,
+        au: &mut AuditScope,
+        idx: &BTreeSet<(String, IndexType)>,
+    ) -> Result<(), OperationError> {
+        let idx: BTreeSet<_> = idx
+            .iter()
+            .filter(|(attr, _)| idx_attr_is_valid(attr))
+            .cloned()
+            .collect();
+        if self.get_idx_set(au)? != idx {
+            audit_log!(au, "indexes have changed, rebuilding them");
+            self.reindex(au, &idx)?;
+        }
+        Ok(())
+    }
+
+    // The level of the builtin migrations that have been applied, which is
+    // kept with the versions of the tables, as 0 until the first start.
+    pub fn get_migration_level(&self) -> i64 {
+        self.get_db_version_key(DBV_MIGRATION_LEVEL)
+    }
+
+    pub fn set_migration_level(
+        &self,
+        audit: &mut AuditScope,
+        level: i64,
+    ) -> Result<(), OperationError> {
+        self.conn
+            .execute_named(
+                "INSERT OR REPLACE INTO db_version (id, version) VALUES(:id, :level)",
+                &[(":id", &DBV_MIGRATION_LEVEL), (":level", &level)],
+            )
+            .map(|_| ())
+            .map_err(|e| sqlite_error(audit, e))
     }
 
     pub fn commit(mut self) -> Result<(), OperationError> {
         debug!("Commiting BE txn");
         assert!(!self.committed);
         self.committed = true;
-        self.conn
-            .execute("COMMIT TRANSACTION", NO_PARAMS)
-            .map(|_| ())
-            .map_err(|e| {
-                println!("{:?}", e);
-                OperationError::BackendEngine
-            })
+        let dirty = self.dirty.replace(CacheDirty::default());
+        let conn = &self.conn;
+        self.cache.commit(dirty, || {
+            conn.execute("COMMIT TRANSACTION", NO_PARAMS)
+                .map(|_| ())
+                .map_err(|e| {
+                    // There is no audit scope here, so the failure is only
+                    // logged.
+                    let be_err = BackendError::from(e);
+                    error!("backend commit failure: {}", be_err);
+                    OperationError::SQLiteError(be_err.kind())
+                })
+        })
     }
 
     // ===== inner helpers =====
     // Some of these are not self due to use in new()
     fn get_db_version_key(&self, key: &str) -> i64 {
-        match self.conn.query_row_named(
-            "SELECT version FROM db_version WHERE id = :id",
-            &[(":id", &key)],
-            |row| row.get(0),
-        ) {
-            Ok(e) => e,
-            Err(_) => {
-                // The value is missing, default to 0.
-                0
-            }
+        db_version_key(&self.conn, key)
+    }
+
+    // Entries are read with any field they lack given its default, such as
+    // the totp of a credential stored before there was totp, so writing each
+    // one again stores them all in full.
+    fn reserialize_entries(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        let raw_entries = self.get_identries(audit, None)?;
+        for id_ent in raw_entries.iter() {
+            let db_e: DbEntry = serde_cbor::from_slice(id_ent.data.as_slice())
+                .map_err(|_| OperationError::SerdeCborError)?;
+            let data = serde_cbor::to_vec(&db_e).map_err(|_| OperationError::SerdeCborError)?;
+            self.conn
+                .execute_named(
+                    "UPDATE id2entry SET data = :data WHERE id = :id",
+                    &[(":id", &id_ent.id), (":data", &data)],
+                )
+                .map_err(|e| sqlite_error(audit, e))?;
         }
+        audit_log!(audit, "reserialized {} entries", raw_entries.len());
+        Ok(())
+    }
+
+    // Copy the whole database aside before it's upgraded, as it was.
+    fn backup_before_upgrade(
+        &self,
+        audit: &mut AuditScope,
+        path: &str,
+    ) -> Result<(), OperationError> {
+        let ct = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or(Duration::from_secs(0));
+        let dst_path = format!("{}.pre-upgrade-{}", path, ct.as_secs());
+        self.conn
+            .backup(DatabaseName::Main, dst_path.as_str(), None)
+            .map_err(|e| sqlite_error(audit, e))?;
+        audit_log!(audit, "backed up the database to {}", dst_path);
+        info!("Backed up the database to {} before upgrading it", dst_path);
+        Ok(())
     }
 
     fn get_db_sid(&self) -> SID {
@@ -656,24 +1863,21 @@ impl BackendWriteTransaction {
         nsid
     }
 
-    pub fn setup(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+    // Create the database, or upgrade it to the versions this server writes.
+    // It's backed up next to path before an upgrade, unless it's in memory.
+    pub fn setup(&self, audit: &mut AuditScope, path: &str) -> Result<(), OperationError> {
         {
             // Enable WAL mode, which is just faster and better.
-            //
-            // We have to use stmt + prepare because execute can't handle
-            // the "wal" row on result when this works!
-            let mut wal_stmt = try_audit!(
-                audit,
-                self.conn.prepare("PRAGMA journal_mode=WAL;"),
-                "sqlite error {:?}",
-                OperationError::SQLiteError
-            );
-            try_audit!(
-                audit,
-                wal_stmt.query(NO_PARAMS),
-                "sqlite error {:?}",
-                OperationError::SQLiteError
-            );
+            //
+            // We have to use stmt + prepare because execute can't handle
+            // the "wal" row on result when this works!
+            let mut wal_stmt = self
+                .conn
+                .prepare("PRAGMA journal_mode=WAL;")
+                .map_err(|e| sqlite_error(audit, e))?;
+            wal_stmt
+                .query(NO_PARAMS)
+                .map_err(|e| sqlite_error(audit, e))?;
 
             // This stores versions of components. For example:
             // ----------------------
@@ -687,72 +1891,197 @@ impl BackendWriteTransaction {
             // rolled back individually, by upgraded in isolation, and more
             //
             // NEVER CHANGE THIS DEFINITION.
-            try_audit!(
-                audit,
-                self.conn.execute(
+            self.conn
+                .execute(
                     "CREATE TABLE IF NOT EXISTS db_version (
                         id TEXT PRIMARY KEY,
                         version INTEGER
                     )
                     ",
                     NO_PARAMS,
-                ),
-                "sqlite error {:?}",
-                OperationError::SQLiteError
-            );
+                )
+                .map_err(|e| sqlite_error(audit, e))?;
 
             // If the table is empty, populate the versions as 0.
+            let versions: Vec<_> = DB_VERSIONS
+                .iter()
+                .map(|(key, supported)| (*key, self.get_db_version_key(key), *supported))
+                .collect();
+            if let Some((key, v, supported)) = versions.iter().find(|(_, v, s)| v > s) {
+                audit_log!(
+                    audit,
+                    "{} is at version {}, newer than {}",
+                    key,
+                    v,
+                    supported
+                );
+                error!(
+                    "The database's {} is at version {}, but this server supports up to version {}. It was written by a newer server.",
+                    key, v, supported
+                );
+                return Err(OperationError::IncompatibleDatabase(
+                    key.to_string(),
+                    *v,
+                    *supported,
+                ));
+            }
+
+            // A new database has nothing to upgrade.
             let mut dbv_id2entry = self.get_db_version_key(DBV_ID2ENTRY);
             audit_log!(audit, "dbv_id2entry initial == {}", dbv_id2entry);
+            let exists = dbv_id2entry > 0;
+            if exists && path != "" && versions.iter().any(|(_, v, s)| v < s) {
+                self.backup_before_upgrade(audit, path)?;
+            }
 
             // Check db_version here.
             //   * if 0 -> create v1.
             if dbv_id2entry == 0 {
-                try_audit!(
-                    audit,
-                    self.conn.execute(
+                self.conn
+                    .execute(
                         "CREATE TABLE IF NOT EXISTS id2entry (
                             id INTEGER PRIMARY KEY ASC,
                             data BLOB NOT NULL
                         )
                         ",
                         NO_PARAMS,
-                    ),
-                    "sqlite error {:?}",
-                    OperationError::SQLiteError
-                );
-                try_audit!(
-                    audit,
-                    self.conn.execute(
+                    )
+                    .map_err(|e| sqlite_error(audit, e))?;
+                self.conn
+                    .execute(
                         "CREATE TABLE IF NOT EXISTS db_sid (
                             id INTEGER PRIMARY KEY ASC,
                             data BLOB NOT NULL
                         )
                         ",
                         NO_PARAMS,
-                    ),
-                    "sqlite error {:?}",
-                    OperationError::SQLiteError
-                );
+                    )
+                    .map_err(|e| sqlite_error(audit, e))?;
                 dbv_id2entry = 1;
                 audit_log!(audit, "dbv_id2entry migrated -> {}", dbv_id2entry);
             }
-            //   * if v1 -> complete.
+            //   * if v1 -> store every entry in full.
+            if dbv_id2entry == 1 {
+                self.reserialize_entries(audit)?;
+                dbv_id2entry = 2;
+                audit_log!(audit, "dbv_id2entry migrated -> {}", dbv_id2entry);
+            }
+            //   * if v2 -> complete.
 
-            try_audit!(
-                audit,
-                self.conn.execute_named(
+            self.conn
+                .execute_named(
                     "INSERT OR REPLACE INTO db_version (id, version) VALUES(:id, :dbv_id2entry)",
                     &[(":id", &DBV_ID2ENTRY), (":dbv_id2entry", &dbv_id2entry)],
-                ),
-                "sqlite error {:?}",
-                OperationError::SQLiteError
-            );
+                )
+                .map_err(|e| sqlite_error(audit, e))?;
+
+            // The audit log isn't part of the entries, so it's versioned on
+            // its own, and a restore of the entries leaves it alone.
+            let mut dbv_auditlog = self.get_db_version_key(DBV_AUDITLOG);
+            audit_log!(audit, "dbv_auditlog initial == {}", dbv_auditlog);
+
+            if dbv_auditlog == 0 {
+                self.conn
+                    .execute(
+                        "CREATE TABLE IF NOT EXISTS auditlog (
+                            id INTEGER PRIMARY KEY ASC,
+                            time INTEGER NOT NULL,
+                            data BLOB NOT NULL
+                        )
+                        ",
+                        NO_PARAMS,
+                    )
+                    .map_err(|e| sqlite_error(audit, e))?;
+                dbv_auditlog = 1;
+                audit_log!(audit, "dbv_auditlog migrated -> {}", dbv_auditlog);
+            }
+
+            self.conn
+                .execute_named(
+                    "INSERT OR REPLACE INTO db_version (id, version) VALUES(:id, :dbv_auditlog)",
+                    &[(":id", &DBV_AUDITLOG), (":dbv_auditlog", &dbv_auditlog)],
+                )
+                .map_err(|e| sqlite_error(audit, e))?;
+
+            // The changelog is versioned on its own too, so a database from
+            // before it existed gains it here.
+            let mut dbv_changelog = self.get_db_version_key(DBV_CHANGELOG);
+            audit_log!(audit, "dbv_changelog initial == {}", dbv_changelog);
+
+            if dbv_changelog == 0 {
+                self.conn
+                    .execute(
+                        "CREATE TABLE IF NOT EXISTS changelog_meta (
+                            id INTEGER PRIMARY KEY ASC,
+                            data BLOB NOT NULL
+                        )
+                        ",
+                        NO_PARAMS,
+                    )
+                    .map_err(|e| sqlite_error(audit, e))?;
+                self.conn
+                    .execute(
+                        "CREATE TABLE IF NOT EXISTS changestate (
+                            uuid TEXT PRIMARY KEY,
+                            data BLOB NOT NULL
+                        )
+                        ",
+                        NO_PARAMS,
+                    )
+                    .map_err(|e| sqlite_error(audit, e))?;
+                self.conn
+                    .execute(
+                        "CREATE TABLE IF NOT EXISTS changelog (
+                            id INTEGER PRIMARY KEY ASC,
+                            ts INTEGER NOT NULL,
+                            data BLOB NOT NULL
+                        )
+                        ",
+                        NO_PARAMS,
+                    )
+                    .map_err(|e| sqlite_error(audit, e))?;
+                self.set_changelog_meta(
+                    audit,
+                    DbChangelogMetaV1 {
+                        s_uuid: Uuid::new_v4(),
+                        last: None,
+                        trimmed: None,
+                    },
+                )?;
+                dbv_changelog = 1;
+                audit_log!(audit, "dbv_changelog migrated -> {}", dbv_changelog);
+            }
+
+            self.conn
+                .execute_named(
+                    "INSERT OR REPLACE INTO db_version (id, version) VALUES(:id, :dbv_changelog)",
+                    &[(":id", &DBV_CHANGELOG), (":dbv_changelog", &dbv_changelog)],
+                )
+                .map_err(|e| sqlite_error(audit, e))?;
 
             // NOTE: Indexing is configured in a different step!
             // Indexing uses a db version flag to represent the version
             // of the indexes representation on disk in case we change
-            // it.
+            // it. Indexes from before it was recorded can't be known to be
+            // in this one, so they are built again.
+            let mut dbv_index = self.get_db_version_key(DBV_INDEX);
+            audit_log!(audit, "dbv_index initial == {}", dbv_index);
+
+            if dbv_index == 0 {
+                if exists {
+                    let idx = self.get_idx_set(audit)?;
+                    self.reindex(audit, &idx)?;
+                }
+                dbv_index = 1;
+                audit_log!(audit, "dbv_index migrated -> {}", dbv_index);
+            }
+
+            self.conn
+                .execute_named(
+                    "INSERT OR REPLACE INTO db_version (id, version) VALUES(:id, :dbv_index)",
+                    &[(":id", &DBV_INDEX), (":dbv_index", &dbv_index)],
+                )
+                .map_err(|e| sqlite_error(audit, e))?;
             Ok(())
         }
     }
@@ -774,12 +2103,17 @@ impl Backend {
             };
             // Look at max_size and thread_pool here for perf later
             let pool = builder2.build(manager).expect("Failed to create pool");
-            let be = Backend { pool: pool };
+            let be = Backend {
+                pool: pool,
+                metrics: Arc::new(Metrics::new()),
+                cache: Arc::new(BackendCache::new(ENTRY_CACHE_SIZE, IDL_CACHE_SIZE)),
+                consistent: Arc::new(AtomicBool::new(true)),
+            };
 
             // Now complete our setup with a txn
             let r = {
                 let be_txn = be.write();
-                be_txn.setup(audit).and_then(|_| be_txn.commit())
+                be_txn.setup(audit, path).and_then(|_| be_txn.commit())
             };
 
             audit_log!(audit, "be new setup: {:?}", r);
@@ -796,7 +2130,7 @@ impl Backend {
             .pool
             .get()
             .expect("Unable to get connection from pool!!!");
-        BackendReadTransaction::new(conn)
+        BackendReadTransaction::new(conn, self.metrics.clone(), self.cache.clone())
     }
 
     pub fn write(&self) -> BackendWriteTransaction {
@@ -804,7 +2138,32 @@ impl Backend {
             .pool
             .get()
             .expect("Unable to get connection from pool!!!");
-        BackendWriteTransaction::new(conn)
+        BackendWriteTransaction::new(conn, self.metrics.clone(), self.cache.clone())
+    }
+
+    // The server's metrics are given after the setup, so the transactions
+    // made by it aren't counted.
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = metrics;
+    }
+
+    pub fn get_metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    // How many entries and index lookups are cached, where 0 disables that
+    // cache. This empties the cache, so it's set before the backend is
+    // cloned.
+    pub fn set_cache_size(&mut self, entries: usize, idls: usize) {
+        self.cache = Arc::new(BackendCache::new(entries, idls));
+    }
+
+    pub fn set_consistent(&self, consistent: bool) {
+        self.consistent.store(consistent, Ordering::Relaxed);
+    }
+
+    pub fn is_consistent(&self) -> bool {
+        self.consistent.load(Ordering::Relaxed)
     }
 
     pub fn get_db_sid(&self) -> SID {
@@ -814,6 +2173,63 @@ impl Backend {
         s
     }
 
+    // Rebuild the database file to reclaim the space of what has been
+    // removed, then check the file is intact. This can't be done in a
+    // transaction, so the caller must make sure nothing else is writing.
+    pub fn vacuum(&self, au: &mut AuditScope) -> Result<(), OperationError> {
+        audit_segment!(au, || {
+            let conn = self
+                .pool
+                .get()
+                .expect("Unable to get connection from pool!!!");
+            conn.execute("VACUUM", NO_PARAMS)
+                .map_err(|e| sqlite_error(au, e))?;
+
+            let mut stmt = conn
+                .prepare("PRAGMA integrity_check")
+                .map_err(|e| sqlite_error(au, e))?;
+            let check_iter = stmt
+                .query_map(NO_PARAMS, |row| row.get::<_, String>(0))
+                .map_err(|e| sqlite_error(au, e))?;
+            let mut lines = Vec::new();
+            for row in check_iter {
+                lines.push(row.map_err(|e| sqlite_error(au, e))?);
+            }
+
+            if lines.len() == 1 && lines[0] == "ok" {
+                Ok(())
+            } else {
+                audit_log!(au, "integrity check failed: {:?}", lines);
+                Err(OperationError::ConsistencyError(vec![Err(
+                    ConsistencyError::SqliteIntegrityFailure(lines),
+                )]))
+            }
+        })
+    }
+
+    // Copy what has been committed to the write ahead log into the database
+    // file, and empty the log, so the file alone is complete once we stop.
+    // Gives false if a transaction that's still open kept the log from being
+    // copied in full.
+    pub fn checkpoint(&self, au: &mut AuditScope) -> Result<bool, OperationError> {
+        audit_segment!(au, || {
+            let conn = self
+                .pool
+                .get()
+                .expect("Unable to get connection from pool!!!");
+            // The first column is 1 if the checkpoint was blocked.
+            let busy = conn
+                .query_row("PRAGMA wal_checkpoint(TRUNCATE)", NO_PARAMS, |row| {
+                    row.get::<_, i64>(0)
+                })
+                .map_err(|e| sqlite_error(au, e))?;
+            if busy != 0 {
+                audit_log!(au, "checkpoint was blocked by an open transaction");
+            }
+            Ok(busy == 0)
+        })
+    }
+
     pub fn reset_db_sid(&self) -> SID {
         let bwt = self.write();
         let s = bwt.generate_db_sid();
@@ -827,6 +2243,9 @@ impl Clone for Backend {
         // Make another Be and close the pool.
         Backend {
             pool: self.pool.clone(),
+            metrics: self.metrics.clone(),
+            cache: self.cache.clone(),
+            consistent: self.consistent.clone(),
         }
     }
 }
@@ -837,11 +2256,19 @@ impl Clone for Backend {
 mod tests {
 
     use std::fs;
+    use std::time::Duration;
 
     use super::super::audit::AuditScope;
-    use super::super::entry::{Entry, EntryInvalid, EntryNew};
-    use super::{Backend, BackendTransaction, BackendWriteTransaction, OperationError};
-    use crate::value::{PartialValue, Value};
+    use super::super::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntryValid};
+    use super::dbaudit::{DbAuditOperationV1, DbAuditRecord, DbAuditRecordV1};
+    use super::{
+        read_db_versions, Backend, BackendTransaction, BackendWriteTransaction, OperationError,
+    };
+    use crate::value::{IndexType, PartialValue, Value};
+    use kanidm_proto::v1::{BackendErrorKind, PlanState};
+    use rusqlite::NO_PARAMS;
+    use std::collections::BTreeSet;
+    use uuid::Uuid;
 
     macro_rules! run_test {
         ($test_fn:expr) => {{
@@ -859,6 +2286,12 @@ mod tests {
         }};
     }
 
+    fn idx_set(idx: &[(&str, IndexType)]) -> BTreeSet<(String, IndexType)> {
+        idx.iter()
+            .map(|(attr, itype)| (attr.to_string(), itype.clone()))
+            .collect()
+    }
+
     macro_rules! entry_exists {
         ($audit:expr, $be:expr, $ent:expr) => {{
             let ei = unsafe { $ent.clone().to_valid_committed() };
@@ -1113,13 +2546,203 @@ mod tests {
                 _ => (),
             }
 
+            let record = DbAuditRecord::V1(DbAuditRecordV1 {
+                time: 10,
+                eventid: Uuid::new_v4(),
+                identity: Uuid::new_v4(),
+                operation: DbAuditOperationV1::Create,
+                targets: Vec::new(),
+                changes: Vec::new(),
+            });
+            assert!(be.append_audit(audit, &record).is_ok());
+
             be.backup(audit, DB_BACKUP_FILE_NAME)
                 .expect("Backup failed!");
+
+            // Wipe everything the backup holds.
+            assert!(unsafe { be.purge(audit) }.is_ok());
+            be.get_conn()
+                .execute("DELETE FROM auditlog", NO_PARAMS)
+                .expect("Failed to wipe auditlog");
+            assert!(!entry_exists!(audit, be, e1));
+
             be.restore(audit, DB_BACKUP_FILE_NAME)
                 .expect("Restore failed!");
+            assert!(entry_exists!(audit, be, e1));
+            assert!(entry_exists!(audit, be, e2));
+            assert!(entry_exists!(audit, be, e3));
+            let r = be
+                .search_audit(audit, 0, 20)
+                .expect("Failed to search audit log");
+            assert!(r.len() == 1);
+            assert!(r[0].time() == 10);
+        });
+    }
+
+    // A file that isn't a backup of this version is refused before anything
+    // is removed.
+    #[test]
+    fn test_restore_incompatible() {
+        run_test!(|audit: &mut AuditScope, be: &BackendWriteTransaction| {
+            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e1.add_ava("userid", &Value::from("william"));
+            e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+            let ve1 = unsafe { e1.clone().to_valid_new() };
+            assert!(be.create(audit, &vec![ve1]).is_ok());
+
+            let path = std::env::temp_dir().join("kanidm_test_restore_incompatible.json");
+            let path = path.to_str().expect("Invalid temp path");
+
+            fs::write(path, r#"{"version": 999, "entries": [], "auditlog": []}"#)
+                .expect("Failed to write backup");
+            assert!(be.restore(audit, path) == Err(OperationError::IncompatibleBackup(Some(999))));
+
+            fs::write(path, "not a backup").expect("Failed to write backup");
+            assert!(be.restore(audit, path) == Err(OperationError::IncompatibleBackup(None)));
+
+            // A truncated backup fails before anything is removed.
+            fs::write(
+                path,
+                r#"{"version": 1, "entries": [{"ent": {"V1": {"attrs": {}}}"#,
+            )
+            .expect("Failed to write backup");
+            assert!(be.restore(audit, path) == Err(OperationError::SerdeJsonError));
+
+            // As does one with an entry that can't be read.
+            fs::write(
+                path,
+                r#"{"version": 1, "entries": [{"ent": {"V1": {"attrs": {}}}}], "auditlog": []}"#,
+            )
+            .expect("Failed to write backup");
+            assert!(be.restore(audit, path) == Err(OperationError::CorruptedEntry(1)));
+
+            assert!(entry_exists!(audit, be, e1));
+            let _ = fs::remove_file(path);
+        });
+    }
+
+    // Only the newest versions are kept in the backup directory.
+    #[test]
+    fn test_backup_rotate() {
+        run_test!(|audit: &mut AuditScope, be: &BackendWriteTransaction| {
+            let dir = std::env::temp_dir().join(format!("kanidm_test_backup_{}", Uuid::new_v4()));
+            let dir = dir.to_str().expect("Invalid temp path");
+
+            let paths: Vec<String> = (1..4)
+                .map(|i| {
+                    be.backup_rotate(audit, dir, 2, Duration::from_secs(i * 60))
+                        .expect("Backup failed!")
+                })
+                .collect();
+
+            assert!(fs::metadata(paths[0].as_str()).is_err());
+            assert!(fs::metadata(paths[1].as_str()).is_ok());
+            assert!(fs::metadata(paths[2].as_str()).is_ok());
+            assert!(fs::read_dir(dir).expect("Failed to read dir").count() == 2);
+            assert!(be.restore(audit, paths[2].as_str()).is_ok());
+
+            let _ = fs::remove_dir_all(dir);
         });
     }
 
+    // A copy of the fixture, a database from before the auditlog, changelog
+    // and indexes were versioned, with id2entry at version 1.
+    fn fixture_db(name: &str) -> (String, String) {
+        let dir = std::env::temp_dir().join(format!("kanidm_test_{}_{}", name, Uuid::new_v4()));
+        fs::create_dir_all(&dir).expect("Failed to create test dir");
+        let path = dir.join("kanidm.db");
+        fs::copy(
+            concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/tests/fixtures/db_id2entry_v1.db"
+            ),
+            &path,
+        )
+        .expect("Failed to copy fixture");
+        (
+            dir.to_str().expect("Invalid temp path").to_string(),
+            path.to_str().expect("Invalid temp path").to_string(),
+        )
+    }
+
+    fn pre_upgrade_backups(dir: &str) -> Vec<String> {
+        fs::read_dir(dir)
+            .expect("Failed to read dir")
+            .filter_map(|e| e.ok())
+            .map(|e| e.path().to_str().expect("Invalid path").to_string())
+            .filter(|p| p.contains(".pre-upgrade-"))
+            .collect()
+    }
+
+    #[test]
+    fn test_db_upgrade_fixture() {
+        let mut audit = AuditScope::new("test_db_upgrade_fixture");
+        let (dir, path) = fixture_db("upgrade");
+
+        let versions = read_db_versions(&mut audit, path.as_str()).expect("Failed to read");
+        assert!(versions.contains(&("id2entry", 1, 2)));
+        assert!(versions.contains(&("index", 0, 1)));
+
+        {
+            let be = Backend::new(&mut audit, path.as_str(), 1).expect("Failed to upgrade");
+            let be_txn = be.write();
+            let raw_entries = be_txn
+                .get_identries(&mut audit, None)
+                .expect("Failed to read entries");
+            assert!(raw_entries.len() == 1);
+            // The credential is stored with the fields it lacked.
+            let data = raw_entries[0].data.as_slice();
+            assert!(data.windows(4).any(|w| w == b"totp"));
+            assert!(data.windows(11).any(|w| w == b"must_change"));
+        }
+
+        let versions = read_db_versions(&mut audit, path.as_str()).expect("Failed to read");
+        assert!(versions.iter().all(|(_, v, s)| v == s));
+
+        // What was there before is kept, as it was.
+        let backups = pre_upgrade_backups(dir.as_str());
+        assert!(backups.len() == 1);
+        let versions = read_db_versions(&mut audit, backups[0].as_str()).expect("Failed to read");
+        assert!(versions.contains(&("id2entry", 1, 2)));
+
+        // Once current, it's opened again without another backup.
+        assert!(Backend::new(&mut audit, path.as_str(), 1).is_ok());
+        assert!(pre_upgrade_backups(dir.as_str()).len() == 1);
+
+        println!("{}", audit);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_db_newer_refused() {
+        let mut audit = AuditScope::new("test_db_newer_refused");
+        let (dir, path) = fixture_db("newer");
+
+        rusqlite::Connection::open(path.as_str())
+            .and_then(|conn| {
+                conn.execute(
+                    "UPDATE db_version SET version = 99 WHERE id = 'id2entry'",
+                    NO_PARAMS,
+                )
+            })
+            .expect("Failed to set version");
+
+        match Backend::new(&mut audit, path.as_str(), 1) {
+            Err(e) => {
+                assert!(e == OperationError::IncompatibleDatabase("id2entry".to_string(), 99, 2))
+            }
+            Ok(_) => panic!("A newer database was opened"),
+        }
+        // Nothing was upgraded, or backed up.
+        let versions = read_db_versions(&mut audit, path.as_str()).expect("Failed to read");
+        assert!(versions.contains(&("id2entry", 99, 2)));
+        assert!(versions.contains(&("index", 0, 1)));
+        assert!(pre_upgrade_backups(dir.as_str()).is_empty());
+
+        println!("{}", audit);
+        let _ = fs::remove_dir_all(dir);
+    }
+
     #[test]
     fn test_sid_generation_and_reset() {
         run_test!(|_audit: &mut AuditScope, be: &BackendWriteTransaction| {
@@ -1132,4 +2755,374 @@ mod tests {
             assert!(sid3 == sid4);
         });
     }
+
+    // A failure in sqlite is given with its kind, not its detail.
+    #[test]
+    fn test_sqlite_error_kind() {
+        run_test!(|audit: &mut AuditScope, be: &BackendWriteTransaction| {
+            be.get_conn()
+                .execute("DROP TABLE id2entry", NO_PARAMS)
+                .expect("Failed to drop id2entry");
+            let r = be.search(audit, unsafe { &filter_resolved!(f_pres("userid")) });
+            match r {
+                Err(OperationError::SQLiteError(BackendErrorKind::Other)) => {}
+                _ => panic!("unexpected search result"),
+            }
+        });
+    }
+
+    // The audit log is kept apart from the entries, so it's left as it was
+    // when they are all removed.
+    #[test]
+    fn test_audit_append_search() {
+        run_test!(|audit: &mut AuditScope, be: &BackendWriteTransaction| {
+            let target = Uuid::new_v4();
+            let record = |time: i64| {
+                DbAuditRecord::V1(DbAuditRecordV1 {
+                    time: time,
+                    eventid: Uuid::new_v4(),
+                    identity: Uuid::new_v4(),
+                    operation: DbAuditOperationV1::Delete,
+                    targets: vec![target.clone()],
+                    changes: Vec::new(),
+                })
+            };
+            assert!(be.append_audit(audit, &record(10)).is_ok());
+            assert!(be.append_audit(audit, &record(20)).is_ok());
+            assert!(unsafe { be.purge(audit) }.is_ok());
+
+            let r = be
+                .search_audit(audit, 0, 15)
+                .expect("Failed to search audit log");
+            assert!(r.len() == 1);
+            assert!(r[0].time() == 10);
+            assert!(r[0].has_target(&target));
+            let r = be
+                .search_audit(audit, 10, 20)
+                .expect("Failed to search audit log");
+            assert!(r.len() == 2);
+            assert!(r[1].time() == 20);
+        });
+    }
+
+    // The indexes follow every change to the entries, and a reindex puts
+    // right one that has been damaged.
+    #[test]
+    fn test_reindex_search() {
+        run_test!(|audit: &mut AuditScope, be: &BackendWriteTransaction| {
+            let counts = be
+                .reindex(
+                    audit,
+                    &idx_set(&[
+                        ("userid", IndexType::EQUALITY),
+                        ("not-valid", IndexType::EQUALITY),
+                    ]),
+                )
+                .expect("Failed to reindex");
+            assert!(counts.len() == 1);
+            assert!(counts.get("userid.eq") == Some(&0));
+
+            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e1.add_ava("userid", &Value::from("william"));
+            e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+
+            let mut e2: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e2.add_ava("userid", &Value::from("alice"));
+            e2.add_ava("uuid", &Value::from("4b6228ab-1dbe-42a4-a9f5-f6368222438e"));
+
+            let ve1 = unsafe { e1.clone().to_valid_new() };
+            let ve2 = unsafe { e2.clone().to_valid_new() };
+            assert!(be.create(audit, &vec![ve1, ve2]).is_ok());
+
+            let search_userid = |audit: &mut AuditScope, userid: &str| {
+                let filt =
+                    unsafe { filter_resolved!(f_eq("userid", PartialValue::new_utf8s(userid))) };
+                be.search(audit, &filt).expect("Search failed!")
+            };
+            assert!(search_userid(audit, "william").len() == 1);
+            assert!(search_userid(audit, "alice").len() == 1);
+
+            // Change william's userid, so the old key must be gone.
+            let r1 = search_userid(audit, "william").remove(0);
+            let mut r1 = r1.invalidate();
+            r1.purge_ava("userid");
+            r1.add_ava("userid", &Value::from("bill"));
+            let vr1 = unsafe { r1.to_valid_committed() };
+            assert!(be.modify(audit, &vec![vr1.clone()]).is_ok());
+            assert!(search_userid(audit, "william").len() == 0);
+            assert!(search_userid(audit, "bill").len() == 1);
+
+            // A damaged index gives the wrong result until it's rebuilt.
+            be.get_conn()
+                .execute("DELETE FROM idx_eq_userid", NO_PARAMS)
+                .expect("Failed to damage the index");
+            assert!(search_userid(audit, "bill").len() == 0);
+            let counts = be
+                .reindex(audit, &idx_set(&[("userid", IndexType::EQUALITY)]))
+                .expect("Failed to reindex");
+            assert!(counts.get("userid.eq") == Some(&2));
+            assert!(search_userid(audit, "bill").len() == 1);
+
+            assert!(be.delete(audit, &vec![vr1]).is_ok());
+            assert!(search_userid(audit, "bill").len() == 0);
+            let keys: i64 = be
+                .get_conn()
+                .query_row("SELECT COUNT(id) FROM idx_eq_userid", NO_PARAMS, |row| {
+                    row.get(0)
+                })
+                .expect("Failed to count the index");
+            assert!(keys == 1);
+
+            // Without the index, the search looks at every entry.
+            be.get_conn()
+                .execute("DROP TABLE idx_eq_userid", NO_PARAMS)
+                .expect("Failed to drop the index");
+            assert!(search_userid(audit, "alice").len() == 1);
+        });
+    }
+
+    #[test]
+    fn test_vacuum() {
+        let mut audit = AuditScope::new("run_test");
+        let be = Backend::new(&mut audit, "", 1).expect("Failed to setup backend");
+        assert!(be.vacuum(&mut audit).is_ok());
+    }
+
+    // Only a search that no index can narrow tests every entry.
+    #[test]
+    fn test_index_full_scans() {
+        run_test!(|audit: &mut AuditScope, be: &BackendWriteTransaction| {
+            be.reindex(
+                audit,
+                &idx_set(&[
+                    ("userid", IndexType::EQUALITY),
+                    ("userid", IndexType::PRESENCE),
+                    ("userid", IndexType::SUBSTRING),
+                ]),
+            )
+            .expect("Failed to reindex");
+
+            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e1.add_ava("userid", &Value::from("william"));
+            e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+            let mut e2: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e2.add_ava("userid", &Value::from("alice"));
+            e2.add_ava("uuid", &Value::from("4b6228ab-1dbe-42a4-a9f5-f6368222438e"));
+            let ve1 = unsafe { e1.to_valid_new() };
+            let ve2 = unsafe { e2.to_valid_new() };
+            assert!(be.create(audit, &vec![ve1, ve2]).is_ok());
+
+            let scans = be.get_metrics().full_scans();
+            let r = be
+                .search(audit, unsafe {
+                    &filter_resolved!(f_eq("userid", PartialValue::new_utf8s("alice")))
+                })
+                .expect("Search failed!");
+            assert!(r.len() == 1);
+            let r = be
+                .search(audit, unsafe { &filter_resolved!(f_pres("userid")) })
+                .expect("Search failed!");
+            assert!(r.len() == 2);
+            let r = be
+                .search(audit, unsafe {
+                    &filter_resolved!(f_sub("userid", PartialValue::new_utf8s("illi")))
+                })
+                .expect("Search failed!");
+            assert!(r.len() == 1);
+            assert!(be.get_metrics().full_scans() == scans);
+
+            // Too short to have a key, so every entry is tested.
+            let r = be
+                .search(audit, unsafe {
+                    &filter_resolved!(f_sub("userid", PartialValue::new_utf8s("li")))
+                })
+                .expect("Search failed!");
+            assert!(r.len() == 2);
+            assert!(be.get_metrics().full_scans() == scans + 1);
+
+            let r = be
+                .search(audit, unsafe { &filter_resolved!(f_pres("uuid")) })
+                .expect("Search failed!");
+            assert!(r.len() == 2);
+            assert!(be.get_metrics().full_scans() == scans + 2);
+        });
+    }
+
+    #[test]
+    fn test_search_plan() {
+        run_test!(|audit: &mut AuditScope, be: &BackendWriteTransaction| {
+            be.reindex(audit, &idx_set(&[("userid", IndexType::EQUALITY)]))
+                .expect("Failed to reindex");
+
+            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e1.add_ava("userid", &Value::from("william"));
+            e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+            let mut e2: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e2.add_ava("userid", &Value::from("alice"));
+            e2.add_ava("uuid", &Value::from("4b6228ab-1dbe-42a4-a9f5-f6368222438e"));
+            let ve1 = unsafe { e1.to_valid_new() };
+            let ve2 = unsafe { e2.to_valid_new() };
+            assert!(be.create(audit, &vec![ve1, ve2]).is_ok());
+
+            let (r, plan) = be
+                .search_plan(
+                    audit,
+                    unsafe { &filter_resolved!(f_eq("userid", PartialValue::new_utf8s("alice"))) },
+                    None,
+                )
+                .expect("Search failed!");
+            assert!(r.len() == 1);
+            assert!(plan.root.term == "eq userid");
+            assert!(plan.root.state == PlanState::Indexed);
+            assert!(plan.root.candidates == Some(1));
+            assert!(plan.loaded == 1 && plan.matched == 1);
+
+            // Every entry is loaded to find the one that matches.
+            let (r, plan) = be
+                .search_plan(
+                    audit,
+                    unsafe { &filter_resolved!(f_sub("userid", PartialValue::new_utf8s("illi"))) },
+                    None,
+                )
+                .expect("Search failed!");
+            assert!(r.len() == 1);
+            assert!(plan.root.term == "sub userid");
+            assert!(plan.root.state == PlanState::FullScan);
+            assert!(plan.root.candidates.is_none());
+            assert!(plan.loaded == 2 && plan.matched == 1);
+
+            // The indexed term narrows the and, and the other is reported.
+            let (r, plan) = be
+                .search_plan(
+                    audit,
+                    unsafe {
+                        &filter_resolved!(f_and!([
+                            f_eq("userid", PartialValue::new_utf8s("alice")),
+                            f_sub("userid", PartialValue::new_utf8s("lic"))
+                        ]))
+                    },
+                    None,
+                )
+                .expect("Search failed!");
+            assert!(r.len() == 1);
+            assert!(plan.root.state == PlanState::Indexed);
+            assert!(plan.root.candidates == Some(1));
+            assert!(plan.root.find("sub userid").map(|n| n.state) == Some(PlanState::FullScan));
+            assert!(plan.loaded == 1);
+        });
+    }
+
+    #[test]
+    fn test_search_limit() {
+        run_test!(|audit: &mut AuditScope, be: &BackendWriteTransaction| {
+            be.reindex(audit, &idx_set(&[("userid", IndexType::EQUALITY)]))
+                .expect("Failed to reindex");
+
+            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e1.add_ava("userid", &Value::from("william"));
+            e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+            let mut e2: Entry<EntryInvalid, EntryNew> = Entry::new();
+            e2.add_ava("userid", &Value::from("alice"));
+            e2.add_ava("uuid", &Value::from("4b6228ab-1dbe-42a4-a9f5-f6368222438e"));
+            let ve1 = unsafe { e1.to_valid_new() };
+            let ve2 = unsafe { e2.to_valid_new() };
+            assert!(be.create(audit, &vec![ve1, ve2]).is_ok());
+
+            // The index gives one candidate, which is within the limit.
+            let f_eq =
+                unsafe { filter_resolved!(f_eq("userid", PartialValue::new_utf8s("alice"))) };
+            let (r, _) = be
+                .search_plan(audit, &f_eq, Some(1))
+                .expect("Search failed!");
+            assert!(r.len() == 1);
+
+            // Every entry is a candidate of a full scan, even though only
+            // one would match.
+            let f_sub =
+                unsafe { filter_resolved!(f_sub("userid", PartialValue::new_utf8s("lic"))) };
+            match be.search_plan(audit, &f_sub, Some(1)) {
+                Err(OperationError::ResultLimit(1)) => {}
+                r => panic!("unexpected search result {:?}", r.map(|(e, _)| e.len())),
+            }
+            let (r, _) = be
+                .search_plan(audit, &f_sub, Some(2))
+                .expect("Search failed!");
+            assert!(r.len() == 1);
+        });
+    }
+
+    // A write removes only what it changed from the cache, and a reader that
+    // bypasses the cache doesn't touch it.
+    #[test]
+    fn test_cache_invalidation() {
+        fn search_userid<T: BackendTransaction>(
+            audit: &mut AuditScope,
+            be_txn: &T,
+            userid: &str,
+        ) -> Vec<Entry<EntryValid, EntryCommitted>> {
+            let filt = unsafe { filter_resolved!(f_eq("userid", PartialValue::new_utf8s(userid))) };
+            be_txn.search(audit, &filt).expect("Search failed!")
+        }
+
+        let mut audit = AuditScope::new("run_test");
+        let audit = &mut audit;
+        let be = Backend::new(audit, "", 1).expect("Failed to setup backend");
+
+        let be_txn = be.write();
+        be_txn
+            .reindex(audit, &idx_set(&[("userid", IndexType::EQUALITY)]))
+            .expect("Failed to reindex");
+        let mut e1: Entry<EntryInvalid, EntryNew> = Entry::new();
+        e1.add_ava("userid", &Value::from("william"));
+        e1.add_ava("uuid", &Value::from("db237e8a-0079-4b8c-8a56-593b22aa44d1"));
+        let mut e2: Entry<EntryInvalid, EntryNew> = Entry::new();
+        e2.add_ava("userid", &Value::from("alice"));
+        e2.add_ava("uuid", &Value::from("4b6228ab-1dbe-42a4-a9f5-f6368222438e"));
+        let ve1 = unsafe { e1.to_valid_new() };
+        let ve2 = unsafe { e2.to_valid_new() };
+        assert!(be_txn.create(audit, &vec![ve1, ve2]).is_ok());
+        assert!(be_txn.commit().is_ok());
+
+        // Both are loaded, and then cached.
+        let be_txn = be.read();
+        let metrics = be_txn.get_metrics();
+        assert!(search_userid(audit, &be_txn, "william").len() == 1);
+        assert!(search_userid(audit, &be_txn, "alice").len() == 1);
+        assert!(metrics.entry_cache() == (0, 2));
+        assert!(metrics.idl_cache() == (0, 2));
+        assert!(search_userid(audit, &be_txn, "alice").len() == 1);
+        assert!(metrics.entry_cache() == (1, 2));
+        assert!(metrics.idl_cache() == (1, 2));
+        drop(be_txn);
+
+        let be_txn = be.write();
+        let r1 = search_userid(audit, &be_txn, "william").remove(0);
+        let mut r1 = r1.invalidate();
+        r1.purge_ava("userid");
+        r1.add_ava("userid", &Value::from("bill"));
+        let vr1 = unsafe { r1.to_valid_committed() };
+        assert!(be_txn.modify(audit, &vec![vr1]).is_ok());
+        assert!(be_txn.commit().is_ok());
+
+        // The modified entry and its keys are loaded again, and the other
+        // entry is still cached.
+        let be_txn = be.read();
+        let metrics = be_txn.get_metrics();
+        let (hits, misses) = metrics.entry_cache();
+        let r = search_userid(audit, &be_txn, "bill");
+        assert!(r.len() == 1);
+        assert!(r[0].get_ava_single_str("userid") == Some("bill"));
+        assert!(search_userid(audit, &be_txn, "william").len() == 0);
+        assert!(metrics.entry_cache() == (hits, misses + 1));
+        assert!(search_userid(audit, &be_txn, "alice").len() == 1);
+        assert!(metrics.entry_cache() == (hits + 1, misses + 1));
+        drop(be_txn);
+
+        let mut be_txn = be.read();
+        be_txn.bypass_cache();
+        let metrics = be_txn.get_metrics();
+        let before = (metrics.entry_cache(), metrics.idl_cache());
+        assert!(search_userid(audit, &be_txn, "alice").len() == 1);
+        assert!((metrics.entry_cache(), metrics.idl_cache()) == before);
+    }
 }
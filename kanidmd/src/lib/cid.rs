@@ -0,0 +1,94 @@
+// The id of a change. Every write that changes an entry is given the next
+// of these, and it's recorded against each attribute the write changed.
+//
+// Ids are ordered by time, then by the sequence, so the ids one server gives
+// always increase, even if its clock goes back. The uuid of the server that
+// made the change only orders changes made by different servers at the same
+// time, which isn't possible until there is replication.
+use std::fmt;
+use std::str::FromStr;
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Cid {
+    // Nanoseconds since the epoch.
+    pub ts: u64,
+    pub seq: u64,
+    pub s_uuid: Uuid,
+}
+
+impl Cid {
+    // The id after last for a change made at now. The time is kept from last
+    // if the clock is behind it, and only the sequence increases.
+    pub fn next(last: Option<&Cid>, s_uuid: &Uuid, now: u64) -> Self {
+        match last {
+            Some(l) if l.ts >= now => Cid {
+                ts: l.ts,
+                seq: l.seq + 1,
+                s_uuid: s_uuid.clone(),
+            },
+            _ => Cid {
+                ts: now,
+                seq: 0,
+                s_uuid: s_uuid.clone(),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Cid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}-{}",
+            self.ts,
+            self.seq,
+            self.s_uuid.to_hyphenated_ref()
+        )
+    }
+}
+
+impl FromStr for Cid {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        // The uuid has hyphens of its own, so it's all that's left after the
+        // second.
+        let mut parts = s.splitn(3, '-');
+        let ts = parts.next().and_then(|p| p.parse().ok()).ok_or(())?;
+        let seq = parts.next().and_then(|p| p.parse().ok()).ok_or(())?;
+        let s_uuid = parts
+            .next()
+            .and_then(|p| Uuid::parse_str(p).ok())
+            .ok_or(())?;
+        Ok(Cid {
+            ts: ts,
+            seq: seq,
+            s_uuid: s_uuid,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cid::Cid;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_cid_order_and_parse() {
+        let s_uuid = Uuid::new_v4();
+        let a = Cid::next(None, &s_uuid, 100);
+        // The clock went back, so only the sequence moves on.
+        let b = Cid::next(Some(&a), &s_uuid, 50);
+        let c = Cid::next(Some(&b), &s_uuid, 200);
+        assert!(a < b && b < c);
+        assert!(b.ts == 100 && b.seq == 1);
+        assert!(c.ts == 200 && c.seq == 0);
+
+        for cid in [a, b, c].iter() {
+            assert!(cid.to_string().parse::<Cid>() == Ok(cid.clone()));
+        }
+        assert!("100-0".parse::<Cid>().is_err());
+        assert!("100-x-uuid".parse::<Cid>().is_err());
+    }
+}
@@ -1,43 +1,261 @@
+use crate::constants::{
+    AUTH_LOCKOUT_THRESHOLD, AUTH_LOCKOUT_WINDOW, AUTH_TOKEN_LIFETIME, CHANGELOG_MAX_AGE,
+    ENTRY_CACHE_SIZE, IDL_CACHE_SIZE, ONLINE_BACKUP_INTERVAL, ONLINE_BACKUP_VERSIONS,
+    REAUTH_WINDOW, RECYCLEBIN_MAX_AGE, SHUTDOWN_GRACE_PERIOD, TOMBSTONE_MAX_AGE,
+};
+use crate::filter::FilterLimits;
+use crate::logging::{parse_log_format, LogFormat, TARGETS as LOG_TARGETS};
+use crate::ratelimit::{parse_addr_range, AddrRange, RateLimits};
+use crate::requestlimit::RequestLimits;
+use crate::tls::check_tls_files;
 use num_cpus;
 use rand::prelude::*;
+use std::collections::BTreeMap;
 use std::fmt;
-use std::path::PathBuf;
+use std::fs;
+use std::net::ToSocketAddrs;
+use std::path::Path;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct IntegrationTestConfig {
     pub admin_password: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+pub fn parse_tls_version(v: &str) -> Option<TlsVersion> {
+    match v {
+        "1.2" => Some(TlsVersion::Tls12),
+        "1.3" => Some(TlsVersion::Tls13),
+        _ => None,
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TlsConfiguration {
     pub ca: String,
+    // The certificate, and any intermediates after it.
     pub cert: String,
     pub key: String,
+    // The oldest version of tls a client may connect with, or the oldest
+    // that openssl allows if not set.
+    pub min_version: Option<TlsVersion>,
+}
+
+// Backups taken while the server runs, on a schedule or when an admin asks.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OnlineBackup {
+    // The directory backups are written into.
+    pub path: String,
+    // Seconds between scheduled backups. They are taken at multiples of this
+    // since the epoch, so a daily backup is taken at midnight UTC.
+    pub interval: u64,
+    // How many backups are kept. The oldest are removed after each backup.
+    pub versions: usize,
+}
+
+// The interval of a backup schedule. This is @hourly, @daily or @weekly as
+// in cron, though a week is counted from the epoch, which was a Thursday,
+// or a number of seconds.
+pub fn parse_backup_schedule(schedule: &str) -> Option<u64> {
+    match schedule {
+        "@hourly" => Some(3600),
+        "@daily" => Some(86400),
+        "@weekly" => Some(604800),
+        s => s.parse::<u64>().ok().filter(|i| *i > 0),
+    }
+}
+
+// An entry of the ldap attribute map, given as ldapname=ourname.
+pub fn parse_ldap_attr_map(m: &str) -> Option<(String, String)> {
+    let mut parts = m.splitn(2, '=');
+    let l = parts.next()?.trim();
+    let k = parts.next()?.trim();
+    if l.is_empty() || k.is_empty() {
+        None
+    } else {
+        Some((l.to_lowercase(), k.to_lowercase()))
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Configuration {
     pub address: String,
+    // Where /metrics is served, without authentication. When this isn't set
+    // it's served with everything else at address.
+    pub metrics_address: Option<String>,
+    // Where the read only ldap gateway listens, if it's enabled. It needs
+    // tls, as binds send the password in the clear.
+    pub ldapaddress: Option<String>,
+    // Ldap attribute names to give as our attribute names, in addition to,
+    // or in place of, the default map.
+    pub ldap_attr_map: BTreeMap<String, String>,
     pub domain: String,
+    // The origin browsers report for webauthn, https://<domain> if not set.
+    // It's also the issuer of the oauth2 tokens we sign.
+    pub origin: Option<String>,
     pub threads: usize,
     // db type later
     pub db_path: String,
-    pub maximum_request: usize,
+    // Where to serve the admin socket, and where the commands that recover
+    // a running server look for it.
+    pub admin_socket: Option<String>,
+    // How many entries and index lookups are cached, 0 for none.
+    pub cache_entries: usize,
+    pub cache_idls: usize,
+    // The level kanidm logs at, and the targets, such as be, that are
+    // logged at another.
+    pub log_level: String,
+    pub log_targets: BTreeMap<String, String>,
+    pub log_format: LogFormat,
     pub secure_cookies: bool,
+    // How long, in seconds, an authenticated session is valid for.
+    pub session_lifetime: u64,
+    // How many failed authentications in a row lock an account, 0 for never,
+    // and how long the first lock lasts, in seconds.
+    pub auth_lockout_threshold: u32,
+    pub auth_lockout_window: u64,
+    // How recently, in seconds, a session must have authenticated to change
+    // credentials, access controls or the system configuration. 0 for any
+    // valid session.
+    pub reauth_within: u64,
     pub tls_config: Option<TlsConfiguration>,
     pub cookie_key: [u8; 32],
+    pub filter_limits: FilterLimits,
+    pub filter_limits_anonymous: FilterLimits,
+    pub rate_limits: RateLimits,
+    pub request_limits: RequestLimits,
+    // Retention, in seconds, of recycled entries and of tombstones.
+    pub recycle_bin_max_age: u64,
+    pub tombstone_max_age: u64,
+    // How long, in seconds, a change is kept in the changelog.
+    pub changelog_max_age: u64,
+    pub online_backup: Option<OnlineBackup>,
+    // How long, in seconds, to wait for what's in progress once told to
+    // stop.
+    pub shutdown_grace_period: u64,
     pub integration_test_config: Option<Box<IntegrationTestConfig>>,
 }
 
 impl fmt::Display for Configuration {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "address: {}, ", self.address)
+            .and_then(|_| match &self.metrics_address {
+                Some(ma) => write!(f, "metrics address: {}, ", ma),
+                None => write!(f, "metrics address: {}, ", self.address),
+            })
+            .and_then(|_| match &self.ldapaddress {
+                Some(la) => write!(f, "ldap address: {}, ", la),
+                None => write!(f, "ldap address: disabled, "),
+            })
             .and_then(|_| write!(f, "domain: {}, ", self.domain))
+            .and_then(|_| write!(f, "origin: {}, ", self.webauthn_origin()))
             .and_then(|_| write!(f, "thread count: {}, ", self.threads))
             .and_then(|_| write!(f, "dbpath: {}, ", self.db_path))
-            .and_then(|_| write!(f, "max request size: {}b, ", self.maximum_request))
+            .and_then(|_| match &self.admin_socket {
+                Some(p) => write!(f, "admin socket: {}, ", p),
+                None => write!(f, "admin socket: disabled, "),
+            })
+            .and_then(|_| {
+                write!(
+                    f,
+                    "cache: {} entries {} idls, ",
+                    self.cache_entries, self.cache_idls
+                )
+            })
+            .and_then(|_| {
+                let rl = &self.request_limits;
+                write!(
+                    f,
+                    "max request size: {}b auth {}b search {}b, ",
+                    rl.maximum, rl.maximum_auth, rl.maximum_search
+                )
+                .and_then(|_| {
+                    write!(
+                        f,
+                        "max create entries: {}, max modifications: {}, max value length: {}b, ",
+                        rl.max_create_entries, rl.max_modifications, rl.max_value_length
+                    )
+                })
+            })
+            .and_then(|_| write!(f, "log level: {}, ", self.log_level))
+            .and_then(|_| {
+                self.log_targets
+                    .iter()
+                    .try_for_each(|(t, l)| write!(f, "log level of {}: {}, ", t, l))
+            })
+            .and_then(|_| write!(f, "log format: {}, ", self.log_format))
             .and_then(|_| write!(f, "secure cookies: {}, ", self.secure_cookies))
+            .and_then(|_| write!(f, "session lifetime: {}s, ", self.session_lifetime))
+            .and_then(|_| {
+                write!(
+                    f,
+                    "auth lockout: {} failures for {}s, ",
+                    self.auth_lockout_threshold, self.auth_lockout_window
+                )
+            })
+            .and_then(|_| write!(f, "reauth within: {}s, ", self.reauth_within))
             .and_then(|_| write!(f, "with TLS: {}, ", self.tls_config.is_some()))
+            .and_then(|_| {
+                write!(
+                    f,
+                    "filter limits: depth {} elements {} inclusion {} unindexed {} results {}, ",
+                    self.filter_limits.max_depth,
+                    self.filter_limits.max_elements,
+                    self.filter_limits.max_inclusion,
+                    self.filter_limits.allow_unindexed,
+                    self.filter_limits.max_results
+                )
+            })
+            .and_then(|_| {
+                write!(
+                    f,
+                    "anonymous filter limits: depth {} elements {} inclusion {} unindexed {} results {}, ",
+                    self.filter_limits_anonymous.max_depth,
+                    self.filter_limits_anonymous.max_elements,
+                    self.filter_limits_anonymous.max_inclusion,
+                    self.filter_limits_anonymous.allow_unindexed,
+                    self.filter_limits_anonymous.max_results
+                )
+            })
+            .and_then(|_| {
+                write!(
+                    f,
+                    "rate limits: auth {} global {} anonymous search {} global {} per {}s, ",
+                    self.rate_limits.auth,
+                    self.rate_limits.auth_global,
+                    self.rate_limits.anonymous_search,
+                    self.rate_limits.anonymous_search_global,
+                    self.rate_limits.window
+                )
+            })
+            .and_then(|_| {
+                write!(
+                    f,
+                    "recycle bin max age: {}s, tombstone max age: {}s, ",
+                    self.recycle_bin_max_age, self.tombstone_max_age
+                )
+            })
+            .and_then(|_| write!(f, "changelog max age: {}s, ", self.changelog_max_age))
+            .and_then(|_| match &self.online_backup {
+                Some(ob) => write!(
+                    f,
+                    "online backup: every {}s to {} keeping {}, ",
+                    ob.interval, ob.path, ob.versions
+                ),
+                None => write!(f, "online backup: disabled, "),
+            })
+            .and_then(|_| {
+                write!(
+                    f,
+                    "shutdown grace period: {}s, ",
+                    self.shutdown_grace_period
+                )
+            })
             .and_then(|_| {
                 write!(
                     f,
@@ -52,16 +270,37 @@ impl Configuration {
     pub fn new() -> Self {
         let mut c = Configuration {
             address: String::from("127.0.0.1:8080"),
+            metrics_address: None,
+            ldapaddress: None,
+            ldap_attr_map: BTreeMap::new(),
             domain: String::from("localhost"),
+            origin: None,
             threads: num_cpus::get(),
             db_path: String::from(""),
-            maximum_request: 262144, // 256k
-            // log type
+            admin_socket: None,
+            cache_entries: ENTRY_CACHE_SIZE,
+            cache_idls: IDL_CACHE_SIZE,
+            log_level: String::from("info"),
+            log_targets: BTreeMap::new(),
+            log_format: LogFormat::Text,
             // log path
             // TODO #63: default true in prd
             secure_cookies: if cfg!(test) { false } else { true },
+            session_lifetime: AUTH_TOKEN_LIFETIME,
+            auth_lockout_threshold: AUTH_LOCKOUT_THRESHOLD,
+            auth_lockout_window: AUTH_LOCKOUT_WINDOW,
+            reauth_within: REAUTH_WINDOW,
             tls_config: None,
             cookie_key: [0; 32],
+            filter_limits: FilterLimits::new(),
+            filter_limits_anonymous: FilterLimits::new_anonymous(),
+            rate_limits: RateLimits::new(),
+            request_limits: RequestLimits::new(),
+            recycle_bin_max_age: RECYCLEBIN_MAX_AGE,
+            tombstone_max_age: TOMBSTONE_MAX_AGE,
+            changelog_max_age: CHANGELOG_MAX_AGE,
+            online_backup: None,
+            shutdown_grace_period: SHUTDOWN_GRACE_PERIOD,
             integration_test_config: None,
         };
         let mut rng = StdRng::from_entropy();
@@ -69,63 +308,1275 @@ impl Configuration {
         c
     }
 
-    pub fn update_db_path(&mut self, p: &PathBuf) {
-        match p.to_str() {
-            Some(p) => self.db_path = p.to_string(),
-            None => {
-                error!("Invalid DB path supplied");
-                std::process::exit(1);
+    pub fn webauthn_origin(&self) -> String {
+        self.origin
+            .clone()
+            .unwrap_or_else(|| format!("https://{}", self.domain))
+    }
+}
+
+// A length of time in the server config, as a number of seconds, or as a
+// string of a number and a unit of s, m, h, d or w, such as "12h".
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum ConfigDuration {
+    Seconds(u64),
+    Text(String),
+}
+
+pub fn parse_duration(d: &str) -> Option<u64> {
+    let d = d.trim();
+    let split = d.find(|c: char| !c.is_ascii_digit()).unwrap_or(d.len());
+    let (n, unit) = d.split_at(split);
+    let n = n.parse::<u64>().ok()?;
+    let scale = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        "w" => 604800,
+        _ => return None,
+    };
+    n.checked_mul(scale)
+}
+
+impl ConfigDuration {
+    pub fn as_secs(&self) -> Option<u64> {
+        match self {
+            ConfigDuration::Seconds(s) => Some(*s),
+            ConfigDuration::Text(t) => parse_duration(t.as_str()),
+        }
+    }
+}
+
+impl fmt::Display for ConfigDuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigDuration::Seconds(s) => write!(f, "{}", s),
+            ConfigDuration::Text(t) => write!(f, "{}", t),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfigTls {
+    pub ca: Option<String>,
+    // The certificate of the server, followed by any intermediates.
+    pub chain: Option<String>,
+    pub key: Option<String>,
+    // 1.2 or 1.3.
+    pub min_version: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfigLimits {
+    pub max_results: Option<usize>,
+    pub max_results_anonymous: Option<usize>,
+    pub allow_unindexed_anonymous: Option<bool>,
+    // How deep, and how many terms, a filter a client sends may be.
+    pub max_filter_depth: Option<usize>,
+    pub max_filter_depth_anonymous: Option<usize>,
+    pub max_filter_elements: Option<usize>,
+    pub max_filter_elements_anonymous: Option<usize>,
+    // How many values an inclusion in a filter may have.
+    pub max_filter_inclusion: Option<usize>,
+    pub max_filter_inclusion_anonymous: Option<usize>,
+    pub cache_entries: Option<usize>,
+    pub cache_idls: Option<usize>,
+    // In bytes. maximum_request is of the body of any request that isn't an
+    // authentication or a search.
+    pub maximum_request: Option<usize>,
+    pub maximum_request_auth: Option<usize>,
+    pub maximum_request_search: Option<usize>,
+    pub max_create_entries: Option<usize>,
+    pub max_modifications: Option<usize>,
+    pub max_value_length: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfigBackup {
+    pub path: Option<String>,
+    pub schedule: Option<String>,
+    pub versions: Option<usize>,
+}
+
+// Addresses are given as 192.0.2.1, or as networks such as 10.0.0.0/8.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfigRateLimit {
+    pub auth: Option<u32>,
+    pub auth_global: Option<u32>,
+    pub anonymous_search: Option<u32>,
+    pub anonymous_search_global: Option<u32>,
+    pub window: Option<ConfigDuration>,
+    pub max_sources: Option<usize>,
+    pub trusted_proxies: Option<Vec<String>>,
+    pub exempt: Option<Vec<String>>,
+}
+
+// The server config file, which is toml, such as
+//
+//     bindaddress = "[::]:8443"
+//     db_path = "/var/lib/kanidm/kanidm.db"
+//     domain = "idm.example.com"
+//     session_lifetime = "1h"
+//
+//     [tls]
+//     ca = "/etc/kanidm/ca.pem"
+//     chain = "/etc/kanidm/chain.pem"
+//     key = "/etc/kanidm/key.pem"
+//
+// Every option may be left out, as it may be given as a flag instead, and
+// the flags are made into one of these too. Nothing is checked until
+// validate, so that each problem with the file and flags together can be
+// reported at once.
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bindaddress: Option<String>,
+    pub ldapbindaddress: Option<String>,
+    pub metrics_bindaddress: Option<String>,
+    pub db_path: Option<String>,
+    pub admin_socket: Option<String>,
+    pub domain: Option<String>,
+    pub origin: Option<String>,
+    // error, warn, info, debug or trace.
+    pub log_level: Option<String>,
+    // text, or json for a json object on each line.
+    pub log_format: Option<String>,
+    pub session_lifetime: Option<ConfigDuration>,
+    pub reauth_within: Option<ConfigDuration>,
+    pub auth_lockout_threshold: Option<u32>,
+    pub auth_lockout_window: Option<ConfigDuration>,
+    pub recycle_bin_max_age: Option<ConfigDuration>,
+    pub tombstone_max_age: Option<ConfigDuration>,
+    pub changelog_max_age: Option<ConfigDuration>,
+    pub shutdown_grace_period: Option<ConfigDuration>,
+    // ldapname = "ourname"
+    #[serde(default)]
+    pub ldap_attr_map: BTreeMap<String, String>,
+    // be = "debug"
+    #[serde(default)]
+    pub log_targets: BTreeMap<String, String>,
+    #[serde(default)]
+    pub tls: ServerConfigTls,
+    #[serde(default)]
+    pub limits: ServerConfigLimits,
+    #[serde(default)]
+    pub backup: ServerConfigBackup,
+    #[serde(default)]
+    pub rate_limit: ServerConfigRateLimit,
+}
+
+// A problem with one option of the server config, named as it is in the
+// file, such as tls.key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    pub option: &'static str,
+    pub msg: String,
+}
+
+impl ConfigError {
+    fn new(option: &'static str, msg: String) -> Self {
+        ConfigError {
+            option: option,
+            msg: msg,
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.option, self.msg)
+    }
+}
+
+const LOG_LEVELS: [&str; 5] = ["error", "warn", "info", "debug", "trace"];
+
+fn check_log_level(option: &'static str, l: &str, errs: &mut Vec<ConfigError>) -> Option<String> {
+    let l = l.to_lowercase();
+    if LOG_LEVELS.contains(&l.as_str()) {
+        Some(l)
+    } else {
+        errs.push(ConfigError::new(
+            option,
+            format!(
+                "invalid level {} - must be one of {}",
+                l,
+                LOG_LEVELS.join(", ")
+            ),
+        ));
+        None
+    }
+}
+
+fn check_address(option: &'static str, addr: &str, errs: &mut Vec<ConfigError>) {
+    match addr.to_socket_addrs() {
+        Ok(mut addrs) => {
+            if addrs.next().is_none() {
+                errs.push(ConfigError::new(
+                    option,
+                    format!("{} resolves to no addresses", addr),
+                ))
             }
         }
+        Err(e) => errs.push(ConfigError::new(
+            option,
+            format!("invalid address {} - {}", addr, e),
+        )),
     }
+}
 
-    pub fn update_bind(&mut self, b: &Option<String>) {
-        self.address = b
-            .as_ref()
-            .map(|s| s.clone())
-            .unwrap_or_else(|| String::from("127.0.0.1:8080"));
-    }
-
-    pub fn update_tls(
-        &mut self,
-        ca: &Option<PathBuf>,
-        cert: &Option<PathBuf>,
-        key: &Option<PathBuf>,
-    ) {
-        match (ca, cert, key) {
-            (None, None, None) => {}
-            (Some(cap), Some(certp), Some(keyp)) => {
-                let cas = match cap.to_str() {
-                    Some(cav) => cav.to_string(),
-                    None => {
-                        error!("Invalid CA path");
-                        std::process::exit(1);
-                    }
-                };
-                let certs = match certp.to_str() {
-                    Some(certv) => certv.to_string(),
-                    None => {
-                        error!("Invalid Cert path");
-                        std::process::exit(1);
-                    }
-                };
-                let keys = match keyp.to_str() {
-                    Some(keyv) => keyv.to_string(),
-                    None => {
-                        error!("Invalid Key path");
-                        std::process::exit(1);
-                    }
+// The db and the admin socket are created if they're missing, but not the
+// directory they're in.
+fn check_parent_dir(option: &'static str, path: &str, errs: &mut Vec<ConfigError>) -> bool {
+    match Path::new(path).parent() {
+        Some(d) if !d.as_os_str().is_empty() && !d.is_dir() => {
+            errs.push(ConfigError::new(
+                option,
+                format!("{} is not a directory", d.display()),
+            ));
+            false
+        }
+        _ => true,
+    }
+}
+
+fn check_duration(
+    option: &'static str,
+    d: &Option<ConfigDuration>,
+    errs: &mut Vec<ConfigError>,
+) -> Option<u64> {
+    let d = d.as_ref()?;
+    let secs = d.as_secs();
+    if secs.is_none() {
+        errs.push(ConfigError::new(
+            option,
+            format!(
+                "invalid duration {} - must be a number of seconds, or a number followed by s, m, h, d or w",
+                d
+            ),
+        ));
+    }
+    secs
+}
+
+fn check_addr_ranges(
+    option: &'static str,
+    ranges: &Option<Vec<String>>,
+    errs: &mut Vec<ConfigError>,
+) -> Option<Vec<AddrRange>> {
+    let ranges = ranges.as_ref()?;
+    let mut parsed = Vec::with_capacity(ranges.len());
+    for r in ranges.iter() {
+        match parse_addr_range(r.as_str()) {
+            Some(r) => parsed.push(r),
+            None => errs.push(ConfigError::new(
+                option,
+                format!(
+                    "invalid address {} - must be an address, or a network as address/prefix",
+                    r
+                ),
+            )),
+        }
+    }
+    Some(parsed)
+}
+
+fn check_nonzero(
+    option: &'static str,
+    n: &Option<usize>,
+    errs: &mut Vec<ConfigError>,
+) -> Option<usize> {
+    match n {
+        Some(0) => {
+            errs.push(ConfigError::new(option, "must be more than 0".to_string()));
+            None
+        }
+        n => *n,
+    }
+}
+
+impl ServerConfig {
+    pub fn read(path: &Path) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(|e| {
+            ConfigError::new("config", format!("can't read {} - {}", path.display(), e))
+        })?;
+        toml::from_str(contents.as_str()).map_err(|e| {
+            ConfigError::new("config", format!("can't parse {} - {}", path.display(), e))
+        })
+    }
+
+    // The options of self, with those it doesn't have taken from other, so
+    // that flags.or(file) gives the flags precedence. The ldap attribute
+    // maps, and the levels of log targets, are merged.
+    pub fn or(self, other: ServerConfig) -> Self {
+        let mut ldap_attr_map = other.ldap_attr_map;
+        ldap_attr_map.extend(self.ldap_attr_map);
+        let mut log_targets = other.log_targets;
+        log_targets.extend(self.log_targets);
+        ServerConfig {
+            bindaddress: self.bindaddress.or(other.bindaddress),
+            ldapbindaddress: self.ldapbindaddress.or(other.ldapbindaddress),
+            metrics_bindaddress: self.metrics_bindaddress.or(other.metrics_bindaddress),
+            db_path: self.db_path.or(other.db_path),
+            admin_socket: self.admin_socket.or(other.admin_socket),
+            domain: self.domain.or(other.domain),
+            origin: self.origin.or(other.origin),
+            log_level: self.log_level.or(other.log_level),
+            log_format: self.log_format.or(other.log_format),
+            session_lifetime: self.session_lifetime.or(other.session_lifetime),
+            reauth_within: self.reauth_within.or(other.reauth_within),
+            auth_lockout_threshold: self.auth_lockout_threshold.or(other.auth_lockout_threshold),
+            auth_lockout_window: self.auth_lockout_window.or(other.auth_lockout_window),
+            recycle_bin_max_age: self.recycle_bin_max_age.or(other.recycle_bin_max_age),
+            tombstone_max_age: self.tombstone_max_age.or(other.tombstone_max_age),
+            changelog_max_age: self.changelog_max_age.or(other.changelog_max_age),
+            shutdown_grace_period: self.shutdown_grace_period.or(other.shutdown_grace_period),
+            ldap_attr_map: ldap_attr_map,
+            log_targets: log_targets,
+            tls: ServerConfigTls {
+                ca: self.tls.ca.or(other.tls.ca),
+                chain: self.tls.chain.or(other.tls.chain),
+                key: self.tls.key.or(other.tls.key),
+                min_version: self.tls.min_version.or(other.tls.min_version),
+            },
+            limits: ServerConfigLimits {
+                max_results: self.limits.max_results.or(other.limits.max_results),
+                max_results_anonymous: self
+                    .limits
+                    .max_results_anonymous
+                    .or(other.limits.max_results_anonymous),
+                allow_unindexed_anonymous: self
+                    .limits
+                    .allow_unindexed_anonymous
+                    .or(other.limits.allow_unindexed_anonymous),
+                max_filter_depth: self
+                    .limits
+                    .max_filter_depth
+                    .or(other.limits.max_filter_depth),
+                max_filter_depth_anonymous: self
+                    .limits
+                    .max_filter_depth_anonymous
+                    .or(other.limits.max_filter_depth_anonymous),
+                max_filter_elements: self
+                    .limits
+                    .max_filter_elements
+                    .or(other.limits.max_filter_elements),
+                max_filter_elements_anonymous: self
+                    .limits
+                    .max_filter_elements_anonymous
+                    .or(other.limits.max_filter_elements_anonymous),
+                max_filter_inclusion: self
+                    .limits
+                    .max_filter_inclusion
+                    .or(other.limits.max_filter_inclusion),
+                max_filter_inclusion_anonymous: self
+                    .limits
+                    .max_filter_inclusion_anonymous
+                    .or(other.limits.max_filter_inclusion_anonymous),
+                cache_entries: self.limits.cache_entries.or(other.limits.cache_entries),
+                cache_idls: self.limits.cache_idls.or(other.limits.cache_idls),
+                maximum_request: self.limits.maximum_request.or(other.limits.maximum_request),
+                maximum_request_auth: self
+                    .limits
+                    .maximum_request_auth
+                    .or(other.limits.maximum_request_auth),
+                maximum_request_search: self
+                    .limits
+                    .maximum_request_search
+                    .or(other.limits.maximum_request_search),
+                max_create_entries: self
+                    .limits
+                    .max_create_entries
+                    .or(other.limits.max_create_entries),
+                max_modifications: self
+                    .limits
+                    .max_modifications
+                    .or(other.limits.max_modifications),
+                max_value_length: self
+                    .limits
+                    .max_value_length
+                    .or(other.limits.max_value_length),
+            },
+            backup: ServerConfigBackup {
+                path: self.backup.path.or(other.backup.path),
+                schedule: self.backup.schedule.or(other.backup.schedule),
+                versions: self.backup.versions.or(other.backup.versions),
+            },
+            rate_limit: ServerConfigRateLimit {
+                auth: self.rate_limit.auth.or(other.rate_limit.auth),
+                auth_global: self.rate_limit.auth_global.or(other.rate_limit.auth_global),
+                anonymous_search: self
+                    .rate_limit
+                    .anonymous_search
+                    .or(other.rate_limit.anonymous_search),
+                anonymous_search_global: self
+                    .rate_limit
+                    .anonymous_search_global
+                    .or(other.rate_limit.anonymous_search_global),
+                window: self.rate_limit.window.or(other.rate_limit.window),
+                max_sources: self.rate_limit.max_sources.or(other.rate_limit.max_sources),
+                trusted_proxies: self
+                    .rate_limit
+                    .trusted_proxies
+                    .or(other.rate_limit.trusted_proxies),
+                exempt: self.rate_limit.exempt.or(other.rate_limit.exempt),
+            },
+        }
+    }
+
+    // What every command needs, the db, the admin socket of the server
+    // running on it, and the logging.
+    fn validate_common(&self, config: &mut Configuration, errs: &mut Vec<ConfigError>) {
+        match &self.db_path {
+            Some(p) => {
+                if check_parent_dir("db_path", p, errs) {
+                    config.db_path = p.clone();
+                }
+            }
+            None => errs.push(ConfigError::new("db_path", "must be given".to_string())),
+        }
+        if let Some(p) = &self.admin_socket {
+            if check_parent_dir("admin_socket", p, errs) {
+                config.admin_socket = Some(p.clone());
+            }
+        }
+
+        if let Some(l) = &self.log_level {
+            if let Some(l) = check_log_level("log_level", l, errs) {
+                config.log_level = l;
+            }
+        }
+        for (t, l) in self.log_targets.iter() {
+            if !LOG_TARGETS.contains(&t.as_str()) {
+                errs.push(ConfigError::new(
+                    "log_targets",
+                    format!(
+                        "invalid target {} - must be one of {}",
+                        t,
+                        LOG_TARGETS.join(", ")
+                    ),
+                ))
+            } else if let Some(l) = check_log_level("log_targets", l, errs) {
+                config.log_targets.insert(t.clone(), l);
+            }
+        }
+        if let Some(f) = &self.log_format {
+            match parse_log_format(f.to_lowercase().as_str()) {
+                Some(f) => config.log_format = f,
+                None => errs.push(ConfigError::new(
+                    "log_format",
+                    format!("invalid format {} - must be text or json", f),
+                )),
+            }
+        }
+    }
+
+    // Only what the commands that work on the db without running the server
+    // need.
+    pub fn validate_offline(&self) -> Result<Configuration, Vec<ConfigError>> {
+        let mut errs = Vec::new();
+        let mut config = Configuration::new();
+        self.validate_common(&mut config, &mut errs);
+        if errs.is_empty() {
+            Ok(config)
+        } else {
+            Err(errs)
+        }
+    }
+
+    // The configuration of the server, or every problem found with it.
+    pub fn validate(&self) -> Result<Configuration, Vec<ConfigError>> {
+        let mut errs = Vec::new();
+        let mut config = Configuration::new();
+        self.validate_common(&mut config, &mut errs);
+
+        if let Some(a) = &self.bindaddress {
+            check_address("bindaddress", a.as_str(), &mut errs);
+            config.address = a.clone();
+        }
+        if let Some(a) = &self.ldapbindaddress {
+            check_address("ldapbindaddress", a.as_str(), &mut errs);
+        }
+        config.ldapaddress = self.ldapbindaddress.clone();
+        if let Some(a) = &self.metrics_bindaddress {
+            check_address("metrics_bindaddress", a.as_str(), &mut errs);
+        }
+        config.metrics_address = self.metrics_bindaddress.clone();
+
+        match &self.domain {
+            Some(d) => config.domain = d.clone(),
+            None => errs.push(ConfigError::new("domain", "must be given".to_string())),
+        }
+        if let Some(o) = &self.origin {
+            if !o.starts_with("https://") && !o.starts_with("http://") {
+                errs.push(ConfigError::new(
+                    "origin",
+                    format!("invalid origin {} - must be an http or https url", o),
+                ))
+            }
+        }
+        config.origin = self.origin.clone();
+
+        for (l, k) in self.ldap_attr_map.iter() {
+            let m = format!("{}={}", l, k);
+            match parse_ldap_attr_map(m.as_str()) {
+                Some((l, k)) => {
+                    config.ldap_attr_map.insert(l, k);
+                }
+                None => errs.push(ConfigError::new(
+                    "ldap_attr_map",
+                    format!("invalid map {} - must be ldapname=name", m),
+                )),
+            }
+        }
+
+        if let Some(s) = check_duration("session_lifetime", &self.session_lifetime, &mut errs) {
+            if s == 0 {
+                errs.push(ConfigError::new(
+                    "session_lifetime",
+                    "must be more than 0".to_string(),
+                ))
+            }
+            config.session_lifetime = s;
+        }
+        if let Some(s) = check_duration("reauth_within", &self.reauth_within, &mut errs) {
+            config.reauth_within = s;
+        }
+        if let Some(t) = self.auth_lockout_threshold {
+            config.auth_lockout_threshold = t;
+        }
+        if let Some(s) = check_duration("auth_lockout_window", &self.auth_lockout_window, &mut errs)
+        {
+            config.auth_lockout_window = s;
+        }
+        if let Some(s) = check_duration("recycle_bin_max_age", &self.recycle_bin_max_age, &mut errs)
+        {
+            config.recycle_bin_max_age = s;
+        }
+        if let Some(s) = check_duration("tombstone_max_age", &self.tombstone_max_age, &mut errs) {
+            config.tombstone_max_age = s;
+        }
+        if let Some(s) = check_duration("changelog_max_age", &self.changelog_max_age, &mut errs) {
+            config.changelog_max_age = s;
+        }
+        if let Some(s) = check_duration(
+            "shutdown_grace_period",
+            &self.shutdown_grace_period,
+            &mut errs,
+        ) {
+            config.shutdown_grace_period = s;
+        }
+
+        self.validate_tls(&mut config, &mut errs);
+
+        let limits = &self.limits;
+        if let Some(m) = check_nonzero("limits.max_results", &limits.max_results, &mut errs) {
+            config.filter_limits.max_results = m;
+        }
+        if let Some(m) = check_nonzero(
+            "limits.max_results_anonymous",
+            &limits.max_results_anonymous,
+            &mut errs,
+        ) {
+            config.filter_limits_anonymous.max_results = m;
+        }
+        if let Some(a) = limits.allow_unindexed_anonymous {
+            config.filter_limits_anonymous.allow_unindexed = a;
+        }
+        if let Some(m) = check_nonzero(
+            "limits.max_filter_depth",
+            &limits.max_filter_depth,
+            &mut errs,
+        ) {
+            config.filter_limits.max_depth = m;
+        }
+        if let Some(m) = check_nonzero(
+            "limits.max_filter_depth_anonymous",
+            &limits.max_filter_depth_anonymous,
+            &mut errs,
+        ) {
+            config.filter_limits_anonymous.max_depth = m;
+        }
+        if let Some(m) = check_nonzero(
+            "limits.max_filter_elements",
+            &limits.max_filter_elements,
+            &mut errs,
+        ) {
+            config.filter_limits.max_elements = m;
+        }
+        if let Some(m) = check_nonzero(
+            "limits.max_filter_elements_anonymous",
+            &limits.max_filter_elements_anonymous,
+            &mut errs,
+        ) {
+            config.filter_limits_anonymous.max_elements = m;
+        }
+        if let Some(m) = check_nonzero(
+            "limits.max_filter_inclusion",
+            &limits.max_filter_inclusion,
+            &mut errs,
+        ) {
+            config.filter_limits.max_inclusion = m;
+        }
+        if let Some(m) = check_nonzero(
+            "limits.max_filter_inclusion_anonymous",
+            &limits.max_filter_inclusion_anonymous,
+            &mut errs,
+        ) {
+            config.filter_limits_anonymous.max_inclusion = m;
+        }
+        if let Some(c) = limits.cache_entries {
+            config.cache_entries = c;
+        }
+        if let Some(c) = limits.cache_idls {
+            config.cache_idls = c;
+        }
+        if let Some(m) = check_nonzero("limits.maximum_request", &limits.maximum_request, &mut errs)
+        {
+            config.request_limits.maximum = m;
+        }
+        if let Some(m) = check_nonzero(
+            "limits.maximum_request_auth",
+            &limits.maximum_request_auth,
+            &mut errs,
+        ) {
+            config.request_limits.maximum_auth = m;
+        }
+        if let Some(m) = check_nonzero(
+            "limits.maximum_request_search",
+            &limits.maximum_request_search,
+            &mut errs,
+        ) {
+            config.request_limits.maximum_search = m;
+        }
+        if let Some(m) = check_nonzero(
+            "limits.max_create_entries",
+            &limits.max_create_entries,
+            &mut errs,
+        ) {
+            config.request_limits.max_create_entries = m;
+        }
+        if let Some(m) = check_nonzero(
+            "limits.max_modifications",
+            &limits.max_modifications,
+            &mut errs,
+        ) {
+            config.request_limits.max_modifications = m;
+        }
+        if let Some(m) = check_nonzero(
+            "limits.max_value_length",
+            &limits.max_value_length,
+            &mut errs,
+        ) {
+            config.request_limits.max_value_length = m;
+        }
+
+        self.validate_backup(&mut config, &mut errs);
+        self.validate_rate_limit(&mut config, &mut errs);
+
+        if errs.is_empty() {
+            Ok(config)
+        } else {
+            Err(errs)
+        }
+    }
+
+    fn validate_tls(&self, config: &mut Configuration, errs: &mut Vec<ConfigError>) {
+        let tls = &self.tls;
+        let min_version = match &tls.min_version {
+            Some(v) => match parse_tls_version(v.as_str()) {
+                Some(v) => Some(v),
+                None => {
+                    errs.push(ConfigError::new(
+                        "tls.min_version",
+                        format!("invalid version {} - must be 1.2 or 1.3", v),
+                    ));
+                    None
+                }
+            },
+            None => None,
+        };
+        match (&tls.ca, &tls.chain, &tls.key) {
+            (None, None, None) => {
+                if tls.min_version.is_some() {
+                    errs.push(ConfigError::new(
+                        "tls.min_version",
+                        "must be given with a ca, chain and key".to_string(),
+                    ))
+                }
+            }
+            (Some(ca), Some(chain), Some(key)) => {
+                let tls_config = TlsConfiguration {
+                    ca: ca.clone(),
+                    cert: chain.clone(),
+                    key: key.clone(),
+                    min_version: min_version,
                 };
-                self.tls_config = Some(TlsConfiguration {
-                    ca: cas,
-                    cert: certs,
-                    key: keys,
+                for (option, msg) in check_tls_files(&tls_config) {
+                    errs.push(ConfigError::new(option, msg))
+                }
+                config.tls_config = Some(tls_config)
+            }
+            _ => errs.push(ConfigError::new(
+                "tls",
+                "ca, chain and key must all be given, or none of them".to_string(),
+            )),
+        }
+    }
+
+    fn validate_rate_limit(&self, config: &mut Configuration, errs: &mut Vec<ConfigError>) {
+        let rl = &self.rate_limit;
+        let limits = &mut config.rate_limits;
+        if let Some(n) = rl.auth {
+            limits.auth = n;
+        }
+        if let Some(n) = rl.auth_global {
+            limits.auth_global = n;
+        }
+        if let Some(n) = rl.anonymous_search {
+            limits.anonymous_search = n;
+        }
+        if let Some(n) = rl.anonymous_search_global {
+            limits.anonymous_search_global = n;
+        }
+        if let Some(s) = check_duration("rate_limit.window", &rl.window, errs) {
+            if s == 0 {
+                errs.push(ConfigError::new(
+                    "rate_limit.window",
+                    "must be more than 0".to_string(),
+                ))
+            }
+            limits.window = s;
+        }
+        if let Some(m) = check_nonzero("rate_limit.max_sources", &rl.max_sources, errs) {
+            limits.max_sources = m;
+        }
+        if let Some(r) = check_addr_ranges("rate_limit.trusted_proxies", &rl.trusted_proxies, errs)
+        {
+            limits.trusted_proxies = r;
+        }
+        if let Some(r) = check_addr_ranges("rate_limit.exempt", &rl.exempt, errs) {
+            limits.exempt = r;
+        }
+    }
+
+    fn validate_backup(&self, config: &mut Configuration, errs: &mut Vec<ConfigError>) {
+        let backup = &self.backup;
+        let interval = match &backup.schedule {
+            Some(s) => match parse_backup_schedule(s.as_str()) {
+                Some(i) => i,
+                None => {
+                    errs.push(ConfigError::new(
+                        "backup.schedule",
+                        format!(
+                            "invalid schedule {} - must be @hourly, @daily, @weekly or a number of seconds",
+                            s
+                        ),
+                    ));
+                    ONLINE_BACKUP_INTERVAL
+                }
+            },
+            None => ONLINE_BACKUP_INTERVAL,
+        };
+        let versions = check_nonzero("backup.versions", &backup.versions, errs)
+            .unwrap_or(ONLINE_BACKUP_VERSIONS);
+        match &backup.path {
+            Some(p) => {
+                if !Path::new(p).is_dir() {
+                    errs.push(ConfigError::new(
+                        "backup.path",
+                        format!("{} is not a directory", p),
+                    ))
+                }
+                config.online_backup = Some(OnlineBackup {
+                    path: p.clone(),
+                    interval: interval,
+                    versions: versions,
                 })
             }
-            _ => {
-                error!("Invalid TLS configuration - must provide ca, cert and key!");
-                std::process::exit(1);
+            None => {
+                if backup.schedule.is_some() || backup.versions.is_some() {
+                    errs.push(ConfigError::new(
+                        "backup.path",
+                        "must be given with a backup schedule or versions".to_string(),
+                    ))
+                }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        parse_backup_schedule, parse_duration, parse_ldap_attr_map, ConfigDuration, ServerConfig,
+        TlsVersion,
+    };
+    use crate::constants::{AUTH_TOKEN_LIFETIME, ONLINE_BACKUP_VERSIONS};
+    use crate::filter::FilterLimits;
+    use crate::logging::LogFormat;
+    use crate::ratelimit::parse_addr_range;
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::x509::{X509Builder, X509NameBuilder};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kanidm_config_{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("Failed to create test dir");
+        dir
+    }
+
+    fn test_path(dir: &Path, name: &str) -> String {
+        dir.join(name).to_str().unwrap().to_string()
+    }
+
+    fn generate_key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    // A self signed certificate, which does for the ca and the chain, and
+    // its key.
+    fn generate_tls(dir: &Path, name: &str, not_after: Asn1Time) -> (String, String) {
+        let key = generate_key();
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_text("CN", "localhost").unwrap();
+        let subject = subject.build();
+        let mut cert = X509Builder::new().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&subject).unwrap();
+        cert.set_issuer_name(&subject).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::from_unix(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&not_after).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        let cert = cert.build();
+
+        let cert_path = test_path(dir, format!("{}_cert.pem", name).as_str());
+        let key_path = test_path(dir, format!("{}_key.pem", name).as_str());
+        fs::write(&cert_path, cert.to_pem().unwrap()).unwrap();
+        fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        (cert_path, key_path)
+    }
+
+    // Only what must be given.
+    fn minimal_config(dir: &Path) -> ServerConfig {
+        let mut sconfig = ServerConfig::default();
+        sconfig.db_path = Some(test_path(dir, "kanidm.db"));
+        sconfig.domain = Some("idm.example.com".to_string());
+        sconfig
+    }
+
+    fn error_options(sconfig: &ServerConfig) -> Vec<&'static str> {
+        let mut options: Vec<_> = sconfig
+            .validate()
+            .expect_err("Config should be invalid")
+            .into_iter()
+            .map(|e| e.option)
+            .collect();
+        options.sort();
+        options
+    }
+
+    #[test]
+    fn test_parse_backup_schedule() {
+        assert!(parse_backup_schedule("@hourly") == Some(3600));
+        assert!(parse_backup_schedule("@daily") == Some(86400));
+        assert!(parse_backup_schedule("@weekly") == Some(604800));
+        assert!(parse_backup_schedule("900") == Some(900));
+        assert!(parse_backup_schedule("0") == None);
+        assert!(parse_backup_schedule("@monthly") == None);
+        assert!(parse_backup_schedule("0 2 * * *") == None);
+    }
+
+    #[test]
+    fn test_parse_ldap_attr_map() {
+        assert!(
+            parse_ldap_attr_map("Mail=email") == Some(("mail".to_string(), "email".to_string()))
+        );
+        assert!(
+            parse_ldap_attr_map(" uid = name ") == Some(("uid".to_string(), "name".to_string()))
+        );
+        assert!(parse_ldap_attr_map("mail") == None);
+        assert!(parse_ldap_attr_map("=email") == None);
+        assert!(parse_ldap_attr_map("mail=") == None);
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert!(parse_duration("90") == Some(90));
+        assert!(parse_duration("90s") == Some(90));
+        assert!(parse_duration("15m") == Some(900));
+        assert!(parse_duration("12h") == Some(43200));
+        assert!(parse_duration("7d") == Some(604800));
+        assert!(parse_duration("2w") == Some(1209600));
+        assert!(parse_duration(" 3 h ") == Some(10800));
+        assert!(parse_duration("h") == None);
+        assert!(parse_duration("3y") == None);
+        assert!(parse_duration("1.5h") == None);
+        assert!(parse_duration("-1") == None);
+        assert!(ConfigDuration::Seconds(30).as_secs() == Some(30));
+        assert!(ConfigDuration::Text("1m".to_string()).as_secs() == Some(60));
+    }
+
+    #[test]
+    fn test_server_config_file() {
+        let dir = test_dir("file");
+        fs::create_dir_all(dir.join("backups")).unwrap();
+        let (cert, key) = generate_tls(&dir, "tls", Asn1Time::days_from_now(1).unwrap());
+        let contents = format!(
+            r#"
+            bindaddress = "127.0.0.1:8443"
+            ldapbindaddress = "127.0.0.1:3636"
+            metrics_bindaddress = "127.0.0.1:9090"
+            db_path = "{db}"
+            admin_socket = "{socket}"
+            domain = "idm.example.com"
+            origin = "https://idm.example.com:8443"
+            log_level = "Debug"
+            log_format = "json"
+            session_lifetime = "2h"
+            reauth_within = 300
+            auth_lockout_threshold = 5
+            auth_lockout_window = "10m"
+            changelog_max_age = "7d"
+            shutdown_grace_period = "1m"
+
+            [ldap_attr_map]
+            Mail = "email"
+
+            [log_targets]
+            be = "warn"
+            http = "Trace"
+
+            [tls]
+            ca = "{cert}"
+            chain = "{cert}"
+            key = "{key}"
+            min_version = "1.3"
+
+            [limits]
+            max_results = 1000
+            allow_unindexed_anonymous = false
+            max_filter_depth = 16
+            max_filter_elements_anonymous = 32
+            max_filter_inclusion = 1024
+            max_filter_inclusion_anonymous = 8
+            cache_entries = 0
+
+            [backup]
+            path = "{backups}"
+            schedule = "@hourly"
+
+            [rate_limit]
+            auth = 10
+            anonymous_search = 0
+            window = "5m"
+            trusted_proxies = ["10.0.0.0/8", "fd00::1"]
+            exempt = ["192.0.2.10"]
+            "#,
+            db = test_path(&dir, "kanidm.db"),
+            socket = test_path(&dir, "kanidm.sock"),
+            cert = cert,
+            key = key,
+            backups = test_path(&dir, "backups"),
+        );
+        let path = dir.join("server.toml");
+        fs::write(&path, contents).unwrap();
+
+        let config = ServerConfig::read(&path)
+            .expect("Failed to read config")
+            .validate()
+            .expect("Config should be valid");
+        assert!(config.address == "127.0.0.1:8443");
+        assert!(config.ldapaddress == Some("127.0.0.1:3636".to_string()));
+        assert!(config.metrics_address == Some("127.0.0.1:9090".to_string()));
+        assert!(config.db_path == test_path(&dir, "kanidm.db"));
+        assert!(config.admin_socket == Some(test_path(&dir, "kanidm.sock")));
+        assert!(config.webauthn_origin() == "https://idm.example.com:8443");
+        assert!(config.log_level == "debug");
+        assert!(config.log_targets.get("be") == Some(&"warn".to_string()));
+        assert!(config.log_targets.get("http") == Some(&"trace".to_string()));
+        assert!(config.log_format == LogFormat::Json);
+        assert!(config.session_lifetime == 7200);
+        assert!(config.reauth_within == 300);
+        assert!(config.auth_lockout_threshold == 5);
+        assert!(config.auth_lockout_window == 600);
+        assert!(config.changelog_max_age == 604800);
+        assert!(config.shutdown_grace_period == 60);
+        assert!(config.ldap_attr_map.get("mail") == Some(&"email".to_string()));
+        let tls_config = config
+            .tls_config
+            .as_ref()
+            .expect("TLS should be configured");
+        assert!(tls_config.cert == cert);
+        assert!(tls_config.min_version == Some(TlsVersion::Tls13));
+        assert!(config.filter_limits.max_results == 1000);
+        assert!(!config.filter_limits_anonymous.allow_unindexed);
+        assert!(config.filter_limits.max_depth == 16);
+        assert!(config.filter_limits_anonymous.max_elements == 32);
+        assert!(config.filter_limits.max_inclusion == 1024);
+        assert!(config.filter_limits_anonymous.max_inclusion == 8);
+        // What isn't given keeps its default.
+        assert!(config.filter_limits.max_elements == FilterLimits::new().max_elements);
+        assert!(
+            config.filter_limits_anonymous.max_depth == FilterLimits::new_anonymous().max_depth
+        );
+        assert!(config.cache_entries == 0);
+        let backup = config.online_backup.expect("Backup should be enabled");
+        assert!(backup.interval == 3600);
+        assert!(backup.versions == ONLINE_BACKUP_VERSIONS);
+        let rl = &config.rate_limits;
+        assert!(rl.auth == 10);
+        assert!(rl.anonymous_search == 0);
+        assert!(rl.window == 300);
+        assert!(rl.trusted_proxies.len() == 2);
+        assert!(rl.exempt == vec![parse_addr_range("192.0.2.10").unwrap()]);
+
+        // Only what must be given is needed, and the rest is defaulted.
+        let config = minimal_config(&dir)
+            .validate()
+            .expect("Config should be valid");
+        assert!(config.address == "127.0.0.1:8080");
+        assert!(config.log_level == "info");
+        assert!(config.log_targets.is_empty());
+        assert!(config.log_format == LogFormat::Text);
+        assert!(config.session_lifetime == AUTH_TOKEN_LIFETIME);
+        assert!(config.tls_config.is_none());
+        assert!(config.online_backup.is_none());
+
+        // An option we don't know is a mistake, not something to ignore.
+        fs::write(
+            &path,
+            "domain = \"idm.example.com\"\nbind_address = \"[::]:443\"\n",
+        )
+        .unwrap();
+        let e = ServerConfig::read(&path).expect_err("Unknown option should be refused");
+        assert!(e.option == "config");
+        assert!(e.msg.contains("bind_address"));
+        fs::write(&path, "session_lifetime = [1]\n").unwrap();
+        assert!(ServerConfig::read(&path).is_err());
+        assert!(ServerConfig::read(&dir.join("missing.toml")).is_err());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_server_config_validate_all_errors() {
+        let dir = test_dir("validate_all_errors");
+        // Every problem is reported, not only the first.
+        let mut sconfig = ServerConfig::default();
+        sconfig.bindaddress = Some("not an address".to_string());
+        sconfig.metrics_bindaddress = Some("127.0.0.1".to_string());
+        sconfig.origin = Some("idm.example.com".to_string());
+        sconfig.log_level = Some("loud".to_string());
+        sconfig.log_format = Some("xml".to_string());
+        sconfig
+            .log_targets
+            .insert("database".to_string(), "info".to_string());
+        sconfig.session_lifetime = Some(ConfigDuration::Text("1 fortnight".to_string()));
+        sconfig.reauth_within = Some(ConfigDuration::Text("5min".to_string()));
+        sconfig.tombstone_max_age = Some(ConfigDuration::Text("".to_string()));
+        sconfig
+            .ldap_attr_map
+            .insert("mail".to_string(), "".to_string());
+        sconfig.tls.chain = Some(test_path(&dir, "cert.pem"));
+        sconfig.limits.max_results = Some(0);
+        sconfig.backup.schedule = Some("0 2 * * *".to_string());
+        assert!(
+            error_options(&sconfig)
+                == vec![
+                    "backup.path",
+                    "backup.schedule",
+                    "bindaddress",
+                    "db_path",
+                    "domain",
+                    "ldap_attr_map",
+                    "limits.max_results",
+                    "log_format",
+                    "log_level",
+                    "log_targets",
+                    "metrics_bindaddress",
+                    "origin",
+                    "reauth_within",
+                    "session_lifetime",
+                    "tls",
+                    "tombstone_max_age",
+                ]
+        );
+
+        let mut sconfig = minimal_config(&dir);
+        sconfig.db_path = Some(test_path(&dir.join("missing"), "kanidm.db"));
+        sconfig.admin_socket = Some(test_path(&dir.join("missing"), "kanidm.sock"));
+        sconfig.session_lifetime = Some(ConfigDuration::Seconds(0));
+        sconfig.limits.maximum_request = Some(0);
+        sconfig.limits.max_filter_depth_anonymous = Some(0);
+        sconfig.limits.max_filter_inclusion = Some(0);
+        sconfig.limits.max_value_length = Some(0);
+        sconfig.backup.path = Some(test_path(&dir, "missing"));
+        sconfig.backup.versions = Some(0);
+        sconfig.rate_limit.window = Some(ConfigDuration::Seconds(0));
+        sconfig.rate_limit.max_sources = Some(0);
+        sconfig.rate_limit.exempt = Some(vec!["127.0.0.1".to_string(), "10.0.0.0/40".to_string()]);
+        assert!(
+            error_options(&sconfig)
+                == vec![
+                    "admin_socket",
+                    "backup.path",
+                    "backup.versions",
+                    "db_path",
+                    "limits.max_filter_depth_anonymous",
+                    "limits.max_filter_inclusion",
+                    "limits.max_value_length",
+                    "limits.maximum_request",
+                    "rate_limit.exempt",
+                    "rate_limit.max_sources",
+                    "rate_limit.window",
+                    "session_lifetime",
+                ]
+        );
+
+        // The commands that don't run the server only need the db.
+        let mut sconfig = ServerConfig::default();
+        sconfig.db_path = Some(test_path(&dir, "kanidm.db"));
+        assert!(sconfig.validate_offline().is_ok());
+        sconfig.log_level = Some("loud".to_string());
+        assert!(sconfig.validate_offline().is_err());
+        assert!(ServerConfig::default().validate_offline().is_err());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_server_config_validate_tls() {
+        let dir = test_dir("validate_tls");
+        let (cert, key) = generate_tls(&dir, "tls", Asn1Time::days_from_now(1).unwrap());
+
+        let mut sconfig = minimal_config(&dir);
+        sconfig.tls.ca = Some(cert.clone());
+        sconfig.tls.chain = Some(cert.clone());
+        sconfig.tls.key = Some(key.clone());
+        sconfig.tls.min_version = Some("1.2".to_string());
+        assert!(sconfig.validate().is_ok());
+
+        // A certificate that has expired is named, with when it expired.
+        let (expired_cert, expired_key) =
+            generate_tls(&dir, "expired", Asn1Time::from_unix(86400).unwrap());
+        let mut expired = sconfig.clone();
+        expired.tls.chain = Some(expired_cert.clone());
+        expired.tls.key = Some(expired_key);
+        let errs = expired
+            .validate()
+            .expect_err("Certificate should be expired");
+        assert!(errs.len() == 1);
+        assert!(errs[0].option == "tls.chain");
+        assert!(errs[0].msg.contains(expired_cert.as_str()));
+        assert!(errs[0].msg.contains("expired at Jan  2 00:00:00 1970"));
+
+        let mut bad_version = sconfig.clone();
+        bad_version.tls.min_version = Some("1.1".to_string());
+        assert!(error_options(&bad_version) == vec!["tls.min_version"]);
+
+        // A key that isn't the certificate's.
+        let other_key = test_path(&dir, "other_key.pem");
+        fs::write(
+            &other_key,
+            generate_key().private_key_to_pem_pkcs8().unwrap(),
+        )
+        .unwrap();
+        let mut mismatched = sconfig.clone();
+        mismatched.tls.key = Some(other_key);
+        let errs = mismatched.validate().expect_err("Key should not match");
+        assert!(errs.len() == 1);
+        assert!(errs[0].option == "tls.key");
+        assert!(errs[0].msg.contains("is not the key of the certificate"));
+
+        // Files that are missing, or aren't what they should be, are each
+        // reported.
+        let mut broken = sconfig.clone();
+        broken.tls.ca = Some(test_path(&dir, "missing.pem"));
+        broken.tls.chain = Some(key.clone());
+        broken.tls.key = Some(cert.clone());
+        assert!(error_options(&broken) == vec!["tls.ca", "tls.chain", "tls.key"]);
+
+        // All three are needed.
+        let mut partial = sconfig.clone();
+        partial.tls.ca = None;
+        assert!(error_options(&partial) == vec!["tls"]);
+        let mut version_only = minimal_config(&dir);
+        version_only.tls.min_version = Some("1.3".to_string());
+        assert!(error_options(&version_only) == vec!["tls.min_version"]);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_server_config_precedence() {
+        let dir = test_dir("precedence");
+        let file: ServerConfig = toml::from_str(
+            r#"
+            bindaddress = "127.0.0.1:8443"
+            domain = "idm.example.com"
+            session_lifetime = "2h"
+            log_level = "warn"
+
+            [ldap_attr_map]
+            mail = "email"
+            uid = "name"
+
+            [limits]
+            max_results = 1000
+            cache_idls = 16
+            "#,
+        )
+        .expect("Failed to parse config");
+
+        let mut flags = minimal_config(&dir);
+        flags.domain = None;
+        flags.bindaddress = Some("127.0.0.1:9443".to_string());
+        flags.session_lifetime = Some(ConfigDuration::Seconds(600));
+        flags
+            .ldap_attr_map
+            .insert("uid".to_string(), "uuid".to_string());
+        flags.limits.max_results = Some(50);
+
+        // What the flags give wins, and the file gives the rest.
+        let config = flags
+            .or(file.clone())
+            .validate()
+            .expect("Config should be valid");
+        assert!(config.address == "127.0.0.1:9443");
+        assert!(config.domain == "idm.example.com");
+        assert!(config.session_lifetime == 600);
+        assert!(config.log_level == "warn");
+        assert!(config.ldap_attr_map.get("mail") == Some(&"email".to_string()));
+        assert!(config.ldap_attr_map.get("uid") == Some(&"uuid".to_string()));
+        assert!(config.filter_limits.max_results == 50);
+        assert!(config.cache_idls == 16);
+
+        // Without flags, the file is used as it is.
+        let config = ServerConfig::default()
+            .or(file)
+            .or(minimal_config(&dir))
+            .validate()
+            .expect("Config should be valid");
+        assert!(config.address == "127.0.0.1:8443");
+        assert!(config.session_lifetime == 7200);
+        assert!(config.ldap_attr_map.get("uid") == Some(&"name".to_string()));
+        assert!(config.filter_limits.max_results == 1000);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
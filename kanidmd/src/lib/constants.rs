@@ -6,16 +6,93 @@ pub static PURGE_TIMEOUT: u64 = 60;
 // For production, 1 hour.
 #[cfg(not(test))]
 pub static PURGE_TIMEOUT: u64 = 3600;
+// Unless configured otherwise, online backups are taken daily, and the last
+// week of them is kept.
+pub static ONLINE_BACKUP_INTERVAL: u64 = 86400;
+pub static ONLINE_BACKUP_VERSIONS: usize = 7;
+// How often the database is verified while the server runs, 1 hour. The
+// readiness check fails from when a verification does until one passes.
+pub static VERIFY_TIMEOUT: u64 = 3600;
+// Derived work that would change more entries than this is queued to be
+// applied after the write that caused it, rather than in it. The queue is
+// checked every second.
+pub static DELAYED_ACTION_THRESHOLD: usize = 64;
+pub static DELAYED_ACTION_TIMEOUT: u64 = 1;
+// How long the server waits, once told to stop, for requests in progress and
+// queued writes to finish, 30 seconds. What kanidmd then exits with says
+// whether they did, or were cut off.
+pub static SHUTDOWN_GRACE_PERIOD: u64 = 30;
+pub static EXIT_SHUTDOWN_CLEAN: i32 = 0;
+pub static EXIT_SHUTDOWN_FORCED: i32 = 2;
+// The level of the builtin entries. It's raised when a builtin is added or
+// its definition changes, and a database below it has every builtin brought
+// up to its definition, undoing what admins have changed on them. At the
+// level, the builtins are only repaired.
+pub static SYSTEM_MIGRATION_LEVEL: i64 = 1;
+// How long an entry stays in the recycle bin before it becomes a tombstone,
+// and how long a tombstone is kept before it is removed. Both 7 days.
+pub static RECYCLEBIN_MAX_AGE: u64 = 604800;
+pub static TOMBSTONE_MAX_AGE: u64 = 604800;
+// How long a change is kept in the changelog, also 7 days. The changes since
+// an older change id can't be given.
+pub static CHANGELOG_MAX_AGE: u64 = 604800;
+// How many entries, and how many index lookups, the backend keeps in memory
+// unless configured otherwise.
+pub static ENTRY_CACHE_SIZE: usize = 4096;
+pub static IDL_CACHE_SIZE: usize = 8192;
+// The ldap attribute names the ldap gateway gives as the names of our own
+// attributes, unless configured otherwise. Any other attribute keeps its name.
+pub static LDAP_ATTR_MAP: &'static [(&'static str, &'static str)] = &[
+    ("cn", "name"),
+    ("objectclass", "class"),
+    ("memberof", "memberof"),
+];
 // 5 minute auth session window.
 pub static AUTH_SESSION_TIMEOUT: u64 = 300;
+// How long an issued UserAuthToken is valid for, 1 hour.
+pub static AUTH_TOKEN_LIFETIME: u64 = 3600;
+// An oauth2 authorization code must be exchanged within a minute of being
+// issued. The access token it gives lasts an hour, or until the session it
+// came from ends if that's sooner.
+pub static OAUTH2_CODE_LIFETIME: u64 = 60;
+pub static OAUTH2_ACCESS_TOKEN_LIFETIME: u64 = 3600;
+// Changing credentials, access controls or the system configuration needs a
+// session that authenticated within the last 5 minutes, unless configured
+// otherwise. An older session must reauth first.
+pub static REAUTH_WINDOW: u64 = 300;
+// The shortest password that will be accepted.
+pub static PW_MIN_LENGTH: usize = 10;
+// The lowest strength score, from 0 to 4, a password may have unless the
+// system config sets another.
+pub static PW_MIN_SCORE: u8 = 3;
+// After this many consecutive failed authentications an account is locked,
+// for 60 seconds at first. Each further failure doubles the lock, up to a day.
+pub static AUTH_LOCKOUT_THRESHOLD: u32 = 5;
+pub static AUTH_LOCKOUT_WINDOW: u64 = 60;
+pub static AUTH_LOCKOUT_MAX: u64 = 86400;
+// Many users may share an address, so it is allowed this many times the
+// failures of an account before it is locked.
+pub static AUTH_LOCKOUT_SOURCE_FACTOR: u32 = 4;
+// Posix accounts and groups not given a gidnumber have one generated from
+// their uuid in this range. It starts above the ids distributions give local
+// users and groups, and ends before 2^31 as some tools treat ids as signed.
+pub static GID_GENERATED_MIN: u32 = 65536;
+pub static GID_GENERATED_MAX: u32 = 0x7fff_ffff;
 
 pub static STR_UUID_ADMIN: &'static str = "00000000-0000-0000-0000-000000000000";
 pub static STR_UUID_ANONYMOUS: &'static str = "00000000-0000-0000-0000-ffffffffffff";
 pub static STR_UUID_DOES_NOT_EXIST: &'static str = "00000000-0000-0000-0000-fffffffffffe";
+// What changes made through the admin socket are recorded as being made by.
+// No entry has it, as nothing authenticates to the socket.
+pub static STR_UUID_LOCAL_ADMIN: &'static str = "00000000-0000-0000-0000-fffffffffffd";
 lazy_static! {
     pub static ref UUID_ADMIN: Uuid = Uuid::parse_str(STR_UUID_ADMIN).unwrap();
     pub static ref UUID_DOES_NOT_EXIST: Uuid = Uuid::parse_str(STR_UUID_DOES_NOT_EXIST).unwrap();
     pub static ref UUID_ANONYMOUS: Uuid = Uuid::parse_str(STR_UUID_ANONYMOUS).unwrap();
+    pub static ref UUID_LOCAL_ADMIN: Uuid = Uuid::parse_str(STR_UUID_LOCAL_ADMIN).unwrap();
+    pub static ref UUID_SYSTEM_INFO: Uuid = Uuid::parse_str(_UUID_SYSTEM_INFO).unwrap();
+    pub static ref UUID_SYSTEM_CONFIG: Uuid = Uuid::parse_str(_UUID_SYSTEM_CONFIG).unwrap();
+    pub static ref UUID_DOMAIN_INFO: Uuid = Uuid::parse_str(_UUID_DOMAIN_INFO).unwrap();
 }
 
 pub static JSON_ADMIN_V1: &'static str = r#"{
@@ -24,7 +101,7 @@ pub static JSON_ADMIN_V1: &'static str = r#"{
     },
     "state": null,
     "attrs": {
-        "class": ["account", "memberof", "object"],
+        "class": ["account", "memberof", "object", "system_restricted"],
         "name": ["admin"],
         "uuid": ["00000000-0000-0000-0000-000000000000"],
         "description": ["Builtin Admin account."],
@@ -39,7 +116,7 @@ pub static JSON_IDM_ADMINS_V1: &'static str = r#"{
     },
     "state": null,
     "attrs": {
-        "class": ["group", "object"],
+        "class": ["group", "object", "system_restricted"],
         "name": ["idm_admins"],
         "uuid": ["00000000-0000-0000-0000-000000000001"],
         "description": ["Builtin IDM Administrators Group."],
@@ -52,7 +129,7 @@ pub static JSON_IDM_ADMINS_V1: &'static str = r#"{
 pub static _UUID_IDM_PEOPLE_READ_PRIV: &'static str = "00000000-0000-0000-0000-000000000002";
 pub static JSON_IDM_PEOPLE_READ_PRIV_V1: &'static str = r#"{
     "attrs": {
-        "class": ["group", "object"],
+        "class": ["group", "object", "system_restricted"],
         "name": ["idm_people_read_priv"],
         "uuid": ["00000000-0000-0000-0000-000000000002"],
         "description": ["Builtin IDM Group for granting elevated people (personal data) read permissions."],
@@ -63,7 +140,7 @@ pub static JSON_IDM_PEOPLE_READ_PRIV_V1: &'static str = r#"{
 pub static _UUID_IDM_PEOPLE_WRITE_PRIV: &'static str = "00000000-0000-0000-0000-000000000003";
 pub static JSON_IDM_PEOPLE_WRITE_PRIV_V1: &'static str = r#"{
     "attrs": {
-        "class": ["group", "object"],
+        "class": ["group", "object", "system_restricted"],
         "name": ["idm_people_write_priv"],
         "uuid": ["00000000-0000-0000-0000-000000000003"],
         "description": ["Builtin IDM Group for granting elevated people (personal data) write permissions."]
@@ -73,7 +150,7 @@ pub static JSON_IDM_PEOPLE_WRITE_PRIV_V1: &'static str = r#"{
 pub static _UUID_IDM_GROUP_WRITE_PRIV: &'static str = "00000000-0000-0000-0000-000000000004";
 pub static JSON_IDM_GROUP_WRITE_PRIV_V1: &'static str = r#"{
     "attrs": {
-        "class": ["group", "object"],
+        "class": ["group", "object", "system_restricted"],
         "name": ["idm_group_write_priv"],
         "uuid": ["00000000-0000-0000-0000-000000000004"],
         "description": ["Builtin IDM Group for granting elevated group write permissions."],
@@ -84,7 +161,7 @@ pub static JSON_IDM_GROUP_WRITE_PRIV_V1: &'static str = r#"{
 pub static _UUID_IDM_ACCOUNT_READ_PRIV: &'static str = "00000000-0000-0000-0000-000000000005";
 pub static JSON_IDM_ACCOUNT_READ_PRIV_V1: &'static str = r#"{
     "attrs": {
-        "class": ["group", "object"],
+        "class": ["group", "object", "system_restricted"],
         "name": ["idm_account_read_priv"],
         "uuid": ["00000000-0000-0000-0000-000000000005"],
         "description": ["Builtin IDM Group for granting elevated account read permissions."],
@@ -98,17 +175,18 @@ pub static JSON_IDM_ACCOUNT_READ_PRIV_V1: &'static str = r#"{
 pub static _UUID_IDM_ACCOUNT_WRITE_PRIV: &'static str = "00000000-0000-0000-0000-000000000006";
 pub static JSON_IDM_ACCOUNT_WRITE_PRIV_V1: &'static str = r#"{
     "attrs": {
-        "class": ["group", "object"],
+        "class": ["group", "object", "system_restricted"],
         "name": ["idm_account_write_priv"],
         "uuid": ["00000000-0000-0000-0000-000000000006"],
-        "description": ["Builtin IDM Group for granting elevated account write permissions."]
+        "description": ["Builtin IDM Group for granting elevated account write permissions."],
+        "member": ["00000000-0000-0000-0000-000000000001"]
     }
 }"#;
 // * RADIUS servers
 pub static _UUID_IDM_RADIUS_SERVERS: &'static str = "00000000-0000-0000-0000-000000000007";
 pub static JSON_IDM_RADIUS_SERVERS_V1: &'static str = r#"{
     "attrs": {
-        "class": ["group", "object"],
+        "class": ["group", "object", "system_restricted"],
         "name": ["idm_radius_servers"],
         "uuid": ["00000000-0000-0000-0000-000000000007"],
         "description": ["Builtin IDM Group for RADIUS server access delegation."]
@@ -118,7 +196,7 @@ pub static JSON_IDM_RADIUS_SERVERS_V1: &'static str = r#"{
 pub static _UUID_IDM_HP_ACCOUNT_READ_PRIV: &'static str = "00000000-0000-0000-0000-000000000008";
 pub static JSON_IDM_HP_ACCOUNT_READ_PRIV_V1: &'static str = r#"{
     "attrs": {
-        "class": ["group", "object"],
+        "class": ["group", "object", "system_restricted"],
         "name": ["idm_hp_account_read_priv"],
         "uuid": ["00000000-0000-0000-0000-000000000008"],
         "description": ["Builtin IDM Group for granting elevated account read permissions over high privilege accounts."],
@@ -132,7 +210,7 @@ pub static JSON_IDM_HP_ACCOUNT_READ_PRIV_V1: &'static str = r#"{
 pub static _UUID_IDM_HP_ACCOUNT_WRITE_PRIV: &'static str = "00000000-0000-0000-0000-000000000009";
 pub static JSON_IDM_HP_ACCOUNT_WRITE_PRIV_V1: &'static str = r#"{
     "attrs": {
-        "class": ["group", "object"],
+        "class": ["group", "object", "system_restricted"],
         "name": ["idm_hp_account_write_priv"],
         "uuid": ["00000000-0000-0000-0000-000000000009"],
         "description": ["Builtin IDM Group for granting elevated account write permissions over high privilege accounts."],
@@ -145,7 +223,7 @@ pub static JSON_IDM_HP_ACCOUNT_WRITE_PRIV_V1: &'static str = r#"{
 pub static _UUID_IDM_SCHEMA_WRITE_PRIV: &'static str = "00000000-0000-0000-0000-000000000010";
 pub static JSON_IDM_SCHEMA_WRITE_PRIV_V1: &'static str = r#"{
     "attrs": {
-        "class": ["group", "object"],
+        "class": ["group", "object", "system_restricted"],
         "name": ["idm_schema_write_priv"],
         "uuid": ["00000000-0000-0000-0000-000000000010"],
         "description": ["Builtin IDM Group for granting elevated schema write permissions."],
@@ -158,7 +236,7 @@ pub static JSON_IDM_SCHEMA_WRITE_PRIV_V1: &'static str = r#"{
 pub static _UUID_IDM_ACP_MANAGER_PRIV: &'static str = "00000000-0000-0000-0000-000000000011";
 pub static JSON_IDM_ACP_MANAGER_PRIV_V1: &'static str = r#"{
     "attrs": {
-        "class": ["group", "object"],
+        "class": ["group", "object", "system_restricted"],
         "name": ["idm_acp_manager_priv"],
         "uuid": ["00000000-0000-0000-0000-000000000011"],
         "description": ["Builtin IDM Group for granting control over all access control profile modifications."],
@@ -169,7 +247,7 @@ pub static JSON_IDM_ACP_MANAGER_PRIV_V1: &'static str = r#"{
 pub static _UUID_IDM_HP_GROUP_WRITE_PRIV: &'static str = "00000000-0000-0000-0000-000000000009";
 pub static JSON_IDM_HP_GROUP_WRITE_PRIV_V1: &'static str = r#"{
     "attrs": {
-        "class": ["group", "object"],
+        "class": ["group", "object", "system_restricted"],
         "name": ["idm_hp_group_write_priv"],
         "uuid": ["00000000-0000-0000-0000-000000000012"],
         "description": ["Builtin IDM Group for granting elevated group write privileges for high privilege groups."],
@@ -181,7 +259,7 @@ pub static _UUID_IDM_SERVICE_ACCOUNT_CREATE_PRIV: &'static str =
     "00000000-0000-0000-0000-000000000013";
 pub static JSON_IDM_SERVICE_ACCOUNT_CREATE_PRIV_V1: &'static str = r#"{
     "attrs": {
-        "class": ["group", "object"],
+        "class": ["group", "object", "system_restricted"],
         "name": ["idm_service_account_create_priv"],
         "uuid": ["00000000-0000-0000-0000-000000000013"],
         "description": ["Builtin IDM Group for granting service account creation rights"],
@@ -193,7 +271,7 @@ pub static _UUID_IDM_PERSON_ACCOUNT_CREATE_PRIV: &'static str =
     "00000000-0000-0000-0000-000000000014";
 pub static JSON_IDM_PERSON_ACCOUNT_CREATE_PRIV_V1: &'static str = r#"{
     "attrs": {
-        "class": ["group", "object"],
+        "class": ["group", "object", "system_restricted"],
         "name": ["idm_person_account_create_priv"],
         "uuid": ["00000000-0000-0000-0000-000000000014"],
         "description": ["Builtin IDM Group for granting person/account creation rights"],
@@ -201,11 +279,68 @@ pub static JSON_IDM_PERSON_ACCOUNT_CREATE_PRIV_V1: &'static str = r#"{
     }
 }"#;
 
+pub static _UUID_IDM_ACCOUNT_UNIX_EXTEND_PRIV: &'static str =
+    "00000000-0000-0000-0000-000000000015";
+pub static JSON_IDM_ACCOUNT_UNIX_EXTEND_PRIV_V1: &'static str = r#"{
+    "attrs": {
+        "class": ["group", "object", "system_restricted"],
+        "name": ["idm_account_unix_extend_priv"],
+        "uuid": ["00000000-0000-0000-0000-000000000015"],
+        "description": ["Builtin IDM Group for granting unix account extension rights."],
+        "member": ["00000000-0000-0000-0000-000000000001"]
+    }
+}"#;
+
+pub static _UUID_IDM_GROUP_UNIX_EXTEND_PRIV: &'static str = "00000000-0000-0000-0000-000000000016";
+pub static JSON_IDM_GROUP_UNIX_EXTEND_PRIV_V1: &'static str = r#"{
+    "attrs": {
+        "class": ["group", "object", "system_restricted"],
+        "name": ["idm_group_unix_extend_priv"],
+        "uuid": ["00000000-0000-0000-0000-000000000016"],
+        "description": ["Builtin IDM Group for granting unix group extension rights."],
+        "member": ["00000000-0000-0000-0000-000000000001"]
+    }
+}"#;
+
+// * Unix machines that check unix passwords for pam
+pub static _UUID_IDM_UNIX_AUTH_SERVERS: &'static str = "00000000-0000-0000-0000-000000000017";
+pub static JSON_IDM_UNIX_AUTH_SERVERS_V1: &'static str = r#"{
+    "attrs": {
+        "class": ["group", "object", "system_restricted"],
+        "name": ["idm_unix_auth_servers"],
+        "uuid": ["00000000-0000-0000-0000-000000000017"],
+        "description": ["Builtin IDM Group for unix machines that may check unix passwords."]
+    }
+}"#;
+
+// * Accounts whose searches aren't limited in the entries they may load
+pub static _UUID_IDM_UNLIMITED_SEARCH_PRIV: &'static str = "00000000-0000-0000-0000-000000000018";
+pub static JSON_IDM_UNLIMITED_SEARCH_PRIV_V1: &'static str = r#"{
+    "attrs": {
+        "class": ["group", "object", "system_restricted"],
+        "name": ["idm_unlimited_search_priv"],
+        "uuid": ["00000000-0000-0000-0000-000000000018"],
+        "description": ["Builtin IDM Group for accounts whose searches are not limited in size."],
+        "member": ["00000000-0000-0000-0000-000000000001"]
+    }
+}"#;
+
+// * Service accounts that provision people and groups with scim
+pub static _UUID_IDM_SCIM_PROVISIONERS: &'static str = "00000000-0000-0000-0000-000000000019";
+pub static JSON_IDM_SCIM_PROVISIONERS_V1: &'static str = r#"{
+    "attrs": {
+        "class": ["group", "object", "system_restricted"],
+        "name": ["idm_scim_provisioners"],
+        "uuid": ["00000000-0000-0000-0000-000000000019"],
+        "description": ["Builtin IDM Group for service accounts that provision people and groups with scim."]
+    }
+}"#;
+
 // This must be the last group to init to include the UUID of the other high priv groups.
 pub static _UUID_IDM_HIGH_PRIVILEGE: &'static str = "00000000-0000-0000-0000-000000001000";
 pub static JSON_IDM_HIGH_PRIVILEGE_V1: &'static str = r#"{
     "attrs": {
-        "class": ["group", "object"],
+        "class": ["group", "object", "system_restricted"],
         "name": ["idm_high_privilege"],
         "uuid": ["00000000-0000-0000-0000-000000001000"],
         "description": ["Builtin IDM provided groups with high levels of access that should be audited and limited in modification."],
@@ -224,6 +359,10 @@ pub static JSON_IDM_HIGH_PRIVILEGE_V1: &'static str = r#"{
             "00000000-0000-0000-0000-000000000012",
             "00000000-0000-0000-0000-000000000013",
             "00000000-0000-0000-0000-000000000014",
+            "00000000-0000-0000-0000-000000000015",
+            "00000000-0000-0000-0000-000000000016",
+            "00000000-0000-0000-0000-000000000017",
+            "00000000-0000-0000-0000-000000000019",
             "00000000-0000-0000-0000-000000001000"
         ]
     }
@@ -247,6 +386,7 @@ pub static JSON_IDM_ACP_XX_V1: &'static str = r#"{
     "attrs": {
         "class": [
             "object",
+            "system",
             "access_control_profile",
             "access_control_search",
             "access_control_modify",
@@ -289,7 +429,7 @@ pub static _UUID_IDM_ADMINS_ACP_RECYCLE_SEARCH_V1: &'static str =
     "00000000-0000-0000-0000-ffffff000002";
 pub static JSON_IDM_ADMINS_ACP_RECYCLE_SEARCH_V1: &'static str = r#"{
     "attrs": {
-        "class": ["object", "access_control_profile", "access_control_search"],
+        "class": ["object", "access_control_profile", "access_control_search", "system"],
         "name": ["idm_admins_acp_recycle_search"],
         "uuid": ["00000000-0000-0000-0000-ffffff000002"],
         "description": ["Builtin IDM admin recycle bin search permission."],
@@ -300,14 +440,14 @@ pub static JSON_IDM_ADMINS_ACP_RECYCLE_SEARCH_V1: &'static str = r#"{
         "acp_targetscope": [
             "{\"Eq\": [\"class\", \"recycled\"]}"
         ],
-        "acp_search_attr": ["name", "class", "uuid"]
+        "acp_search_attr": ["name", "class", "uuid", "recycled_references", "deleted_at"]
     }
 }"#;
 
 pub static _UUID_IDM_ADMINS_ACP_REVIVE_V1: &'static str = "00000000-0000-0000-0000-ffffff000003";
 pub static JSON_IDM_ADMINS_ACP_REVIVE_V1: &'static str = r#"{
     "attrs": {
-        "class": ["object", "access_control_profile", "access_control_modify"],
+        "class": ["object", "access_control_profile", "access_control_modify", "system"],
         "name": ["idm_admins_acp_revive"],
         "uuid": ["00000000-0000-0000-0000-ffffff000003"],
         "description": ["Builtin IDM Administrators Access Controls."],
@@ -318,7 +458,7 @@ pub static JSON_IDM_ADMINS_ACP_REVIVE_V1: &'static str = r#"{
         "acp_targetscope": [
             "{\"Eq\":[\"class\",\"recycled\"]}"
         ],
-        "acp_modify_removedattr": ["class"],
+        "acp_modify_removedattr": ["class", "recycled_references", "deleted_at"],
         "acp_modify_class": ["recycled"]
     }
 }"#;
@@ -326,7 +466,7 @@ pub static JSON_IDM_ADMINS_ACP_REVIVE_V1: &'static str = r#"{
 pub static _UUID_IDM_SELF_ACP_READ_V1: &'static str = "00000000-0000-0000-0000-ffffff000004";
 pub static JSON_IDM_SELF_ACP_READ_V1: &'static str = r#"{
     "attrs": {
-        "class": ["object", "access_control_profile", "access_control_search"],
+        "class": ["object", "access_control_profile", "access_control_search", "system"],
         "name": ["idm_self_acp_read"],
         "uuid": ["00000000-0000-0000-0000-ffffff000004"],
         "description": ["Builtin IDM Control for self read - required for whoami and many other functions."],
@@ -339,12 +479,14 @@ pub static JSON_IDM_SELF_ACP_READ_V1: &'static str = r#"{
         ],
         "acp_search_attr": [
             "name",
+            "spn",
             "displayname",
             "legalname",
             "class",
             "memberof",
             "member",
-            "uuid"
+            "uuid",
+            "radius_secret"
         ]
     }
 }"#;
@@ -355,6 +497,7 @@ pub static JSON_IDM_ADMINS_ACP_MANAGE_V1: &'static str = r#"{
     "attrs": {
         "class": [
             "object",
+            "system",
             "access_control_profile",
             "access_control_modify",
             "access_control_create",
@@ -385,23 +528,25 @@ pub static _UUID_IDM_ALL_ACP_READ_V1: &'static str = "00000000-0000-0000-0000-ff
 pub static JSON_IDM_ALL_ACP_READ_V1: &'static str = r#"{
     "state": null,
     "attrs": {
-        "class": ["object", "access_control_profile", "access_control_search"],
+        "class": ["object", "access_control_profile", "access_control_search", "system"],
         "name": ["idm_all_acp_read"],
         "uuid": ["00000000-0000-0000-0000-ffffff000006"],
-        "description": ["Builtin IDM Control for all read - IE anonymous and all authenticated accounts."],
+        "description": ["Builtin IDM Control for all read - IE all authenticated accounts. Anonymous has its own."],
         "acp_enable": ["true"],
         "acp_receiver": [
-            "{\"Pres\":\"class\"}"
+            "{\"And\": [{\"Pres\": \"class\"}, {\"AndNot\": {\"Eq\": [\"uuid\", \"00000000-0000-0000-0000-ffffffffffff\"]}}]}"
         ],
         "acp_targetscope": [
             "{\"And\": [{\"Pres\": \"class\"}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}]}}]}"
         ],
         "acp_search_attr": [
             "name",
+            "spn",
             "displayname",
             "class",
             "memberof",
-            "member"
+            "member",
+            "ssh_publickey"
         ]
     }
 }"#;
@@ -412,6 +557,7 @@ pub static JSON_IDM_ACP_PEOPLE_READ_PRIV_V1: &'static str = r#"{
     "attrs": {
         "class": [
             "object",
+            "system",
             "access_control_profile",
             "access_control_search"
         ],
@@ -437,6 +583,7 @@ pub static JSON_IDM_ACP_PEOPLE_WRITE_PRIV_V1: &'static str = r#"{
     "attrs": {
         "class": [
             "object",
+            "system",
             "access_control_profile",
             "access_control_modify"
         ],
@@ -464,6 +611,7 @@ pub static JSON_IDM_ACP_GROUP_WRITE_PRIV_V1: &'static str = r#"{
     "attrs": {
         "class": [
             "object",
+            "system",
             "access_control_profile",
             "access_control_search",
             "access_control_modify",
@@ -497,6 +645,7 @@ pub static JSON_IDM_ACP_ACCOUNT_READ_PRIV_V1: &'static str = r#"{
     "attrs": {
         "class": [
             "object",
+            "system",
             "access_control_profile",
             "access_control_search"
         ],
@@ -511,7 +660,8 @@ pub static JSON_IDM_ACP_ACCOUNT_READ_PRIV_V1: &'static str = r#"{
             "{\"And\": [{\"Eq\": [\"class\",\"account\"]}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"memberof\",\"00000000-0000-0000-0000-000000001000\"]}, {\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}]}}]}"
         ],
         "acp_search_attr": [
-            "class", "name", "uuid", "displayname", "ssh_publickey", "primary_credential", "memberof", "mail"
+            "class", "name", "spn", "uuid", "displayname", "ssh_publickey", "primary_credential", "memberof", "mail",
+            "auth_failures", "account_locked_until", "account_valid_from", "account_expire"
         ]
     }
 }"#;
@@ -522,8 +672,10 @@ pub static JSON_IDM_ACP_ACCOUNT_WRITE_PRIV_V1: &'static str = r#"{
     "attrs": {
         "class": [
             "object",
+            "system",
             "access_control_profile",
-            "access_control_modify"
+            "access_control_modify",
+            "access_control_delete"
         ],
         "name": ["idm_acp_account_write_priv"],
         "uuid": ["00000000-0000-0000-0000-ffffff000011"],
@@ -536,10 +688,12 @@ pub static JSON_IDM_ACP_ACCOUNT_WRITE_PRIV_V1: &'static str = r#"{
             "{\"And\": [{\"Eq\": [\"class\",\"account\"]}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"memberof\",\"00000000-0000-0000-0000-000000001000\"]}, {\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}]}}]}"
         ],
         "acp_modify_removedattr": [
-            "name", "displayname", "ssh_publickey", "primary_credential", "mail"
+            "name", "displayname", "ssh_publickey", "primary_credential", "mail",
+            "account_locked_until", "account_valid_from", "account_expire"
         ],
         "acp_modify_presentattr": [
-            "name", "displayname", "ssh_publickey", "primary_credential", "mail"
+            "name", "displayname", "ssh_publickey", "primary_credential", "mail",
+            "account_valid_from", "account_expire"
         ]
     }
 }"#;
@@ -550,6 +704,7 @@ pub static JSON_IDM_ACP_SERVICE_ACCOUNT_CREATE_V1: &'static str = r#"{
     "attrs": {
         "class": [
             "object",
+            "system",
             "access_control_profile",
             "access_control_create"
         ],
@@ -571,7 +726,7 @@ pub static JSON_IDM_ACP_SERVICE_ACCOUNT_CREATE_V1: &'static str = r#"{
             "ssh_publickey"
         ],
         "acp_create_class": [
-            "object", "account"
+            "object", "account", "service_account"
         ]
     }
 }"#;
@@ -582,6 +737,7 @@ pub static JSON_IDM_ACP_PERSON_ACCOUNT_CREATE_V1: &'static str = r#"{
     "attrs": {
         "class": [
             "object",
+            "system",
             "access_control_profile",
             "access_control_create"
         ],
@@ -613,12 +769,12 @@ pub static JSON_IDM_ACP_PERSON_ACCOUNT_CREATE_V1: &'static str = r#"{
 // 14 radius read acp JSON_IDM_RADIUS_SERVERS_V1
 pub static _UUID_IDM_ACP_RADIUS_SERVERS_V1: &'static str = "00000000-0000-0000-0000-ffffff000014";
 // The targetscope of this could change later to a "radius access" group or similar so we can add/remove
-//  users from having radius access easier.
-// TODO #17: Add the radius credential type that we need to read here.
+//  users from having radius access easier. Memberof lets the radius server map accounts to vlans.
 pub static JSON_IDM_ACP_RADIUS_SERVERS_V1: &'static str = r#"{
     "attrs": {
         "class": [
             "object",
+            "system",
             "access_control_profile",
             "access_control_search"
         ],
@@ -630,10 +786,10 @@ pub static JSON_IDM_ACP_RADIUS_SERVERS_V1: &'static str = r#"{
             "{\"Eq\":[\"memberof\",\"00000000-0000-0000-0000-000000000007\"]}"
         ],
         "acp_targetscope": [
-            "{\"And\": [{\"Pres\": \"class\"}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}]}}]}"
+            "{\"And\": [{\"Eq\": [\"class\",\"account\"]}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}]}}]}"
         ],
         "acp_search_attr": [
-            "name", "uuid"
+            "name", "uuid", "displayname", "memberof", "radius_secret"
         ]
     }
 }"#;
@@ -644,6 +800,7 @@ pub static JSON_IDM_ACP_HP_ACCOUNT_READ_PRIV_V1: &'static str = r#"{
     "attrs": {
         "class": [
             "object",
+            "system",
             "access_control_profile",
             "access_control_search"
         ],
@@ -658,7 +815,8 @@ pub static JSON_IDM_ACP_HP_ACCOUNT_READ_PRIV_V1: &'static str = r#"{
             "{\"And\": [{\"Eq\": [\"class\",\"account\"]}, {\"Eq\": [\"memberof\",\"00000000-0000-0000-0000-000000001000\"]}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}]}}]}"
         ],
         "acp_search_attr": [
-            "class", "name", "uuid", "displayname", "ssh_publickey", "primary_credential", "memberof"
+            "class", "name", "uuid", "displayname", "ssh_publickey", "primary_credential", "memberof",
+            "auth_failures", "account_locked_until", "account_valid_from", "account_expire"
         ]
     }
 }"#;
@@ -669,6 +827,7 @@ pub static JSON_IDM_ACP_HP_ACCOUNT_WRITE_PRIV_V1: &'static str = r#"{
     "attrs": {
         "class": [
             "object",
+            "system",
             "access_control_profile",
             "access_control_modify",
             "access_control_delete"
@@ -684,10 +843,12 @@ pub static JSON_IDM_ACP_HP_ACCOUNT_WRITE_PRIV_V1: &'static str = r#"{
             "{\"And\": [{\"Eq\": [\"class\",\"account\"]}, {\"Eq\": [\"memberof\",\"00000000-0000-0000-0000-000000001000\"]}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}]}}]}"
         ],
         "acp_modify_removedattr": [
-            "name", "displayname", "ssh_publickey", "primary_credential"
+            "name", "displayname", "ssh_publickey", "primary_credential",
+            "account_locked_until", "account_valid_from", "account_expire"
         ],
         "acp_modify_presentattr": [
-            "name", "displayname", "ssh_publickey", "primary_credential"
+            "name", "displayname", "ssh_publickey", "primary_credential",
+            "account_valid_from", "account_expire"
         ]
     }
 }"#;
@@ -699,6 +860,7 @@ pub static JSON_IDM_ACP_HP_GROUP_WRITE_PRIV_V1: &'static str = r#"{
     "attrs": {
         "class": [
             "object",
+            "system",
             "access_control_profile",
             "access_control_search",
             "access_control_modify",
@@ -733,10 +895,12 @@ pub static JSON_IDM_ACP_SCHEMA_WRITE_ATTRS_PRIV_V1: &'static str = r#"{
     "attrs": {
         "class": [
             "object",
+            "system",
             "access_control_profile",
             "access_control_search",
             "access_control_modify",
-            "access_control_create"
+            "access_control_create",
+            "access_control_delete"
         ],
         "name": ["idm_acp_schema_write_attrs_priv"],
         "uuid": ["00000000-0000-0000-0000-ffffff000018"],
@@ -795,6 +959,7 @@ pub static JSON_IDM_ACP_ACP_MANAGER_PRIV_V1: &'static str = r#"{
     "attrs": {
         "class": [
             "object",
+            "system",
             "access_control_profile",
             "access_control_search",
             "access_control_modify",
@@ -890,10 +1055,12 @@ pub static JSON_IDM_ACP_SCHEMA_WRITE_CLASSES_PRIV_V1: &'static str = r#"{
     "attrs": {
         "class": [
             "object",
+            "system",
             "access_control_profile",
             "access_control_search",
             "access_control_modify",
-            "access_control_create"
+            "access_control_create",
+            "access_control_delete"
         ],
         "name": ["idm_acp_schema_write_classes_priv"],
         "uuid": ["00000000-0000-0000-0000-ffffff000020"],
@@ -943,190 +1110,1039 @@ pub static JSON_IDM_ACP_SCHEMA_WRITE_CLASSES_PRIV_V1: &'static str = r#"{
 }"#;
 
 // 21 - anonymous / everyone schema read.
-
-// Anonymous should be the last opbject in the range here.
-pub static JSON_ANONYMOUS_V1: &'static str = r#"{
+pub static _UUID_IDM_ALL_ACP_SCHEMA_READ_V1: &'static str = "00000000-0000-0000-0000-ffffff000021";
+pub static JSON_IDM_ALL_ACP_SCHEMA_READ_V1: &'static str = r#"{
     "attrs": {
-        "class": ["account", "object"],
-        "name": ["anonymous"],
-        "uuid": ["00000000-0000-0000-0000-ffffffffffff"],
-        "description": ["Anonymous access account."],
-        "displayname": ["Anonymous"]
+        "class": ["object", "access_control_profile", "access_control_search", "system"],
+        "name": ["idm_all_acp_schema_read"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000021"],
+        "description": ["Builtin IDM Control for schema read - IE anonymous and all authenticated accounts."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Pres\":\"class\"}"
+        ],
+        "acp_targetscope": [
+            "{\"And\": [{\"Or\": [{\"Eq\": [\"class\",\"attributetype\"]}, {\"Eq\": [\"class\",\"classtype\"]}]}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}]}}]}"
+        ],
+        "acp_search_attr": [
+            "class",
+            "description",
+            "attributename",
+            "classname",
+            "multivalue",
+            "unique",
+            "syntax",
+            "systemmay",
+            "may",
+            "systemmust",
+            "must",
+            "uuid"
+        ]
     }
 }"#;
 
-// Core
-// Schema uuids start at 00000000-0000-0000-0000-ffff00000000
-pub static UUID_SCHEMA_ATTR_CLASS: &'static str = "00000000-0000-0000-0000-ffff00000000";
-pub static UUID_SCHEMA_ATTR_UUID: &'static str = "00000000-0000-0000-0000-ffff00000001";
-pub static UUID_SCHEMA_ATTR_NAME: &'static str = "00000000-0000-0000-0000-ffff00000002";
-pub static UUID_SCHEMA_ATTR_ATTRIBUTENAME: &'static str = "00000000-0000-0000-0000-ffff00000048";
-pub static UUID_SCHEMA_ATTR_CLASSNAME: &'static str = "00000000-0000-0000-0000-ffff00000049";
-pub static UUID_SCHEMA_ATTR_PRINCIPAL_NAME: &'static str = "00000000-0000-0000-0000-ffff00000003";
-pub static UUID_SCHEMA_ATTR_DESCRIPTION: &'static str = "00000000-0000-0000-0000-ffff00000004";
-pub static UUID_SCHEMA_ATTR_MULTIVALUE: &'static str = "00000000-0000-0000-0000-ffff00000005";
-pub static UUID_SCHEMA_ATTR_UNIQUE: &'static str = "00000000-0000-0000-0000-ffff00000047";
-pub static UUID_SCHEMA_ATTR_INDEX: &'static str = "00000000-0000-0000-0000-ffff00000006";
-pub static UUID_SCHEMA_ATTR_SYNTAX: &'static str = "00000000-0000-0000-0000-ffff00000007";
-pub static UUID_SCHEMA_ATTR_SYSTEMMAY: &'static str = "00000000-0000-0000-0000-ffff00000008";
-pub static UUID_SCHEMA_ATTR_MAY: &'static str = "00000000-0000-0000-0000-ffff00000009";
-pub static UUID_SCHEMA_ATTR_SYSTEMMUST: &'static str = "00000000-0000-0000-0000-ffff00000010";
-pub static UUID_SCHEMA_ATTR_MUST: &'static str = "00000000-0000-0000-0000-ffff00000011";
-pub static UUID_SCHEMA_ATTR_MEMBEROF: &'static str = "00000000-0000-0000-0000-ffff00000012";
-pub static UUID_SCHEMA_ATTR_MEMBER: &'static str = "00000000-0000-0000-0000-ffff00000013";
-pub static UUID_SCHEMA_ATTR_DIRECTMEMBEROF: &'static str = "00000000-0000-0000-0000-ffff00000014";
-pub static UUID_SCHEMA_ATTR_VERSION: &'static str = "00000000-0000-0000-0000-ffff00000015";
-pub static UUID_SCHEMA_ATTR_DOMAIN: &'static str = "00000000-0000-0000-0000-ffff00000016";
-pub static UUID_SCHEMA_ATTR_ACP_ENABLE: &'static str = "00000000-0000-0000-0000-ffff00000017";
-pub static UUID_SCHEMA_ATTR_ACP_RECEIVER: &'static str = "00000000-0000-0000-0000-ffff00000018";
-pub static UUID_SCHEMA_ATTR_ACP_TARGETSCOPE: &'static str = "00000000-0000-0000-0000-ffff00000019";
-pub static UUID_SCHEMA_ATTR_ACP_SEARCH_ATTR: &'static str = "00000000-0000-0000-0000-ffff00000020";
-pub static UUID_SCHEMA_ATTR_ACP_CREATE_CLASS: &'static str = "00000000-0000-0000-0000-ffff00000021";
-pub static UUID_SCHEMA_ATTR_ACP_CREATE_ATTR: &'static str = "00000000-0000-0000-0000-ffff00000022";
-pub static UUID_SCHEMA_ATTR_ACP_MODIFY_REMOVEDATTR: &'static str =
-    "00000000-0000-0000-0000-ffff00000023";
-pub static UUID_SCHEMA_ATTR_ACP_MODIFY_PRESENTATTR: &'static str =
-    "00000000-0000-0000-0000-ffff00000024";
-pub static UUID_SCHEMA_ATTR_ACP_MODIFY_CLASS: &'static str = "00000000-0000-0000-0000-ffff00000025";
-
-pub static UUID_SCHEMA_CLASS_ATTRIBUTETYPE: &'static str = "00000000-0000-0000-0000-ffff00000026";
-pub static UUID_SCHEMA_CLASS_CLASSTYPE: &'static str = "00000000-0000-0000-0000-ffff00000027";
-pub static UUID_SCHEMA_CLASS_OBJECT: &'static str = "00000000-0000-0000-0000-ffff00000028";
-pub static UUID_SCHEMA_CLASS_EXTENSIBLEOBJECT: &'static str =
-    "00000000-0000-0000-0000-ffff00000029";
-pub static UUID_SCHEMA_CLASS_MEMBEROF: &'static str = "00000000-0000-0000-0000-ffff00000030";
+// 22 - system config, that may be changed while the server runs.
+pub static _UUID_SYSTEM_CONFIG: &'static str = "00000000-0000-0000-0000-ffffff000022";
+pub static JSON_SYSTEM_CONFIG_V1: &'static str = r#"{
+    "attrs": {
+        "class": ["object", "system_config", "system_undeletable"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000022"],
+        "description": ["System configuration that may be changed at runtime."]
+    }
+}"#;
 
-pub static UUID_SCHEMA_CLASS_RECYCLED: &'static str = "00000000-0000-0000-0000-ffff00000031";
-pub static UUID_SCHEMA_CLASS_TOMBSTONE: &'static str = "00000000-0000-0000-0000-ffff00000032";
-pub static UUID_SCHEMA_CLASS_SYSTEM_INFO: &'static str = "00000000-0000-0000-0000-ffff00000033";
-pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_PROFILE: &'static str =
-    "00000000-0000-0000-0000-ffff00000034";
-pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_SEARCH: &'static str =
-    "00000000-0000-0000-0000-ffff00000035";
-pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_DELETE: &'static str =
-    "00000000-0000-0000-0000-ffff00000036";
-pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_MODIFY: &'static str =
-    "00000000-0000-0000-0000-ffff00000037";
-pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_CREATE: &'static str =
-    "00000000-0000-0000-0000-ffff00000038";
-pub static UUID_SCHEMA_CLASS_SYSTEM: &'static str = "00000000-0000-0000-0000-ffff00000039";
+// 23 - idm_admins may manage the system config.
+pub static _UUID_IDM_ACP_SYSTEM_CONFIG_PRIV_V1: &'static str =
+    "00000000-0000-0000-0000-ffffff000023";
+pub static JSON_IDM_ACP_SYSTEM_CONFIG_PRIV_V1: &'static str = r#"{
+    "attrs": {
+        "class": [
+            "object",
+            "system",
+            "access_control_profile",
+            "access_control_search",
+            "access_control_modify"
+        ],
+        "name": ["idm_acp_system_config_priv"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000023"],
+        "description": ["Builtin IDM Control for managing the system config."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"memberof\",\"00000000-0000-0000-0000-000000000001\"]}"
+        ],
+        "acp_targetscope": [
+            "{\"Eq\":[\"class\",\"system_config\"]}"
+        ],
+        "acp_search_attr": [
+            "class", "uuid", "description", "badlist_password", "password_min_score",
+            "disable_anonymous"
+        ],
+        "acp_modify_removedattr": ["badlist_password", "password_min_score", "disable_anonymous"],
+        "acp_modify_presentattr": ["badlist_password", "password_min_score", "disable_anonymous"]
+    }
+}"#;
 
-// system supplementary
-pub static UUID_SCHEMA_ATTR_DISPLAYNAME: &'static str = "00000000-0000-0000-0000-ffff00000040";
-pub static JSON_SCHEMA_ATTR_DISPLAYNAME: &'static str = r#"{
-    "valid": {
-      "uuid": "00000000-0000-0000-0000-ffff00000040"
-    },
-    "state": null,
+// 24 - anonymous may only read the names of things.
+pub static _UUID_IDM_ACP_ANONYMOUS_READ_V1: &'static str = "00000000-0000-0000-0000-ffffff000024";
+pub static JSON_IDM_ACP_ANONYMOUS_READ_V1: &'static str = r#"{
     "attrs": {
-      "class": [
-        "object",
-        "system",
-        "attributetype"
-      ],
-      "description": [
-        "The publicly visible display name of this person"
-      ],
-      "index": [
-        "EQUALITY"
-      ],
-      "unique": [
-        "false"
-      ],
-      "multivalue": [
-        "false"
-      ],
-      "attributename": [
-        "displayname"
-      ],
-      "syntax": [
-        "UTF8STRING"
-      ],
-      "uuid": [
-        "00000000-0000-0000-0000-ffff00000040"
-      ]
+        "class": ["object", "access_control_profile", "access_control_search", "system"],
+        "name": ["idm_acp_anonymous_read"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000024"],
+        "description": ["Builtin IDM Control for anonymous read of public information."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"uuid\",\"00000000-0000-0000-0000-ffffffffffff\"]}"
+        ],
+        "acp_targetscope": [
+            "{\"And\": [{\"Pres\": \"class\"}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}]}}]}"
+        ],
+        "acp_search_attr": [
+            "name",
+            "spn",
+            "displayname",
+            "class",
+            "ssh_publickey"
+        ]
     }
 }"#;
-pub static UUID_SCHEMA_ATTR_MAIL: &'static str = "00000000-0000-0000-0000-ffff00000041";
-pub static JSON_SCHEMA_ATTR_MAIL: &'static str = r#"
-  {
-    "valid": {
-      "uuid": "00000000-0000-0000-0000-ffff00000041"
-    },
-    "state": null,
+
+// 25 - service account api tokens are managed by those who may create them.
+pub static _UUID_IDM_ACP_SERVICE_ACCOUNT_API_TOKEN_MANAGE_V1: &'static str =
+    "00000000-0000-0000-0000-ffffff000025";
+pub static JSON_IDM_ACP_SERVICE_ACCOUNT_API_TOKEN_MANAGE_V1: &'static str = r#"{
     "attrs": {
-      "class": [
-        "object",
-        "system",
-        "attributetype"
-      ],
-      "description": [
-        "mail addresses of the object"
-      ],
-      "index": [
-        "EQUALITY"
-      ],
-      "unique": [
-        "true"
-      ],
-      "multivalue": [
-        "true"
-      ],
-      "attributename": [
-        "mail"
-      ],
-      "syntax": [
-        "UTF8STRING"
-      ],
-      "uuid": [
-        "00000000-0000-0000-0000-ffff00000041"
-      ]
+        "class": [
+            "object",
+            "system",
+            "access_control_profile",
+            "access_control_search",
+            "access_control_modify"
+        ],
+        "name": ["idm_acp_service_account_api_token_manage"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000025"],
+        "description": ["Builtin IDM Control for managing service account api tokens."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"memberof\",\"00000000-0000-0000-0000-000000000013\"]}"
+        ],
+        "acp_targetscope": [
+            "{\"And\": [{\"Eq\": [\"class\",\"service_account\"]}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}]}}]}"
+        ],
+        "acp_search_attr": ["name", "class", "uuid", "api_token"],
+        "acp_modify_removedattr": ["api_token"],
+        "acp_modify_presentattr": ["api_token"]
     }
-  }
-"#;
-pub static UUID_SCHEMA_ATTR_SSH_PUBLICKEY: &'static str = "00000000-0000-0000-0000-ffff00000042";
-pub static JSON_SCHEMA_ATTR_SSH_PUBLICKEY: &'static str = r#"
-  {
-    "valid": {
-      "uuid": "00000000-0000-0000-0000-ffff00000042"
-    },
-    "state": null,
+}"#;
+
+// 26 - accounts may be extended with posix attributes and a unix password.
+pub static _UUID_IDM_ACP_ACCOUNT_UNIX_EXTEND_PRIV_V1: &'static str =
+    "00000000-0000-0000-0000-ffffff000026";
+pub static JSON_IDM_ACP_ACCOUNT_UNIX_EXTEND_PRIV_V1: &'static str = r#"{
     "attrs": {
-      "class": [
-        "object",
-        "system",
-        "attributetype"
-      ],
-      "description": [
-        "SSH public keys of the object"
-      ],
-      "index": [],
-      "unique": [
-        "false"
-      ],
-      "multivalue": [
-        "true"
+        "class": [
+            "object",
+            "system",
+            "access_control_profile",
+            "access_control_search",
+            "access_control_modify"
+        ],
+        "name": ["idm_acp_account_unix_extend_priv"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000026"],
+        "description": ["Builtin IDM Control for managing and extending unix accounts"],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"memberof\",\"00000000-0000-0000-0000-000000000015\"]}"
+        ],
+        "acp_targetscope": [
+            "{\"And\": [{\"Eq\": [\"class\",\"account\"]}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"memberof\",\"00000000-0000-0000-0000-000000001000\"]}, {\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}]}}]}"
+        ],
+        "acp_search_attr": [
+            "class", "name", "uuid", "gidnumber", "loginshell", "unix_password"
+        ],
+        "acp_modify_removedattr": [
+            "loginshell", "gidnumber", "unix_password"
+        ],
+        "acp_modify_presentattr": [
+            "class", "loginshell", "gidnumber", "unix_password"
+        ],
+        "acp_modify_class": ["posixaccount"]
+    }
+}"#;
+
+// 27 - groups may be extended with a gidnumber.
+pub static _UUID_IDM_ACP_GROUP_UNIX_EXTEND_PRIV_V1: &'static str =
+    "00000000-0000-0000-0000-ffffff000027";
+pub static JSON_IDM_ACP_GROUP_UNIX_EXTEND_PRIV_V1: &'static str = r#"{
+    "attrs": {
+        "class": [
+            "object",
+            "system",
+            "access_control_profile",
+            "access_control_search",
+            "access_control_modify"
+        ],
+        "name": ["idm_acp_group_unix_extend_priv"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000027"],
+        "description": ["Builtin IDM Control for managing and extending unix groups"],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"memberof\",\"00000000-0000-0000-0000-000000000016\"]}"
+        ],
+        "acp_targetscope": [
+            "{\"And\": [{\"Eq\": [\"class\",\"group\"]}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"memberof\",\"00000000-0000-0000-0000-000000001000\"]}, {\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}]}}]}"
+        ],
+        "acp_search_attr": [
+            "class", "name", "uuid", "gidnumber"
+        ],
+        "acp_modify_removedattr": [
+            "gidnumber"
+        ],
+        "acp_modify_presentattr": [
+            "class", "gidnumber"
+        ],
+        "acp_modify_class": ["posixgroup"]
+    }
+}"#;
+
+// 28 - unix machines resolve posix accounts and groups for nss, often
+// before anyone has logged in, so anonymous may read them.
+pub static _UUID_IDM_ACP_UNIX_READ_V1: &'static str = "00000000-0000-0000-0000-ffffff000028";
+pub static JSON_IDM_ACP_UNIX_READ_V1: &'static str = r#"{
+    "attrs": {
+        "class": ["object", "access_control_profile", "access_control_search", "system"],
+        "name": ["idm_acp_unix_read"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000028"],
+        "description": ["Builtin IDM Control for reading posix accounts and groups."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Pres\":\"class\"}"
+        ],
+        "acp_targetscope": [
+            "{\"And\": [{\"Or\": [{\"Eq\": [\"class\",\"posixaccount\"]}, {\"Eq\": [\"class\",\"posixgroup\"]}]}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}]}}]}"
+        ],
+        "acp_search_attr": [
+            "class",
+            "name",
+            "spn",
+            "uuid",
+            "displayname",
+            "gidnumber",
+            "loginshell",
+            "memberof",
+            "member"
+        ]
+    }
+}"#;
+
+// 29 - the domain info, describing this deployment. It's created at first
+// start with the domain name from the server configuration, as the domain
+// uuid must be unique to the deployment, see initialise_domain_info.
+pub static _UUID_DOMAIN_INFO: &'static str = "00000000-0000-0000-0000-ffffff000029";
+
+// 30 - idm_admins may manage the domain info.
+pub static _UUID_IDM_ACP_DOMAIN_ADMIN_PRIV_V1: &'static str =
+    "00000000-0000-0000-0000-ffffff000030";
+pub static JSON_IDM_ACP_DOMAIN_ADMIN_PRIV_V1: &'static str = r#"{
+    "attrs": {
+        "class": [
+            "object",
+            "system",
+            "access_control_profile",
+            "access_control_search",
+            "access_control_modify"
+        ],
+        "name": ["idm_acp_domain_admin_priv"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000030"],
+        "description": ["Builtin IDM Control for managing the domain info."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"memberof\",\"00000000-0000-0000-0000-000000000001\"]}"
+        ],
+        "acp_targetscope": [
+            "{\"Eq\":[\"class\",\"domain_info\"]}"
+        ],
+        "acp_search_attr": [
+            "class", "uuid", "description", "domain_name", "domain_display_name", "domain_uuid"
+        ],
+        "acp_modify_removedattr": ["domain_name", "domain_display_name"],
+        "acp_modify_presentattr": ["domain_name", "domain_display_name"]
+    }
+}"#;
+
+// 31 - idm_admins may manage the claims that sessions may request.
+pub static _UUID_IDM_ACP_CLAIM_MANAGE_PRIV_V1: &'static str =
+    "00000000-0000-0000-0000-ffffff000031";
+pub static JSON_IDM_ACP_CLAIM_MANAGE_PRIV_V1: &'static str = r#"{
+    "attrs": {
+        "class": [
+            "object",
+            "system",
+            "access_control_profile",
+            "access_control_search",
+            "access_control_modify",
+            "access_control_create",
+            "access_control_delete"
+        ],
+        "name": ["idm_acp_claim_manage_priv"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000031"],
+        "description": ["Builtin IDM Control for managing the claims sessions may request."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"memberof\",\"00000000-0000-0000-0000-000000000001\"]}"
+        ],
+        "acp_targetscope": [
+            "{\"And\": [{\"Eq\": [\"class\",\"claim\"]}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}]}}]}"
+        ],
+        "acp_search_attr": [
+            "class", "name", "uuid", "description", "claim_group", "claim_require_mfa", "claim_lifetime"
+        ],
+        "acp_modify_removedattr": [
+            "name", "description", "claim_group", "claim_require_mfa", "claim_lifetime"
+        ],
+        "acp_modify_presentattr": [
+            "name", "description", "claim_group", "claim_require_mfa", "claim_lifetime"
+        ],
+        "acp_create_attr": [
+            "class", "name", "description", "claim_group", "claim_require_mfa", "claim_lifetime"
+        ],
+        "acp_create_class": [
+            "object", "claim"
+        ]
+    }
+}"#;
+
+// 32 - idm_admins may register the oauth2 clients that accounts may sign in to.
+pub static _UUID_IDM_ACP_OAUTH2_MANAGE_PRIV_V1: &'static str =
+    "00000000-0000-0000-0000-ffffff000032";
+pub static JSON_IDM_ACP_OAUTH2_MANAGE_PRIV_V1: &'static str = r#"{
+    "attrs": {
+        "class": [
+            "object",
+            "system",
+            "access_control_profile",
+            "access_control_search",
+            "access_control_modify",
+            "access_control_create",
+            "access_control_delete"
+        ],
+        "name": ["idm_acp_oauth2_manage_priv"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000032"],
+        "description": ["Builtin IDM Control for managing oauth2 client registrations."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"memberof\",\"00000000-0000-0000-0000-000000000001\"]}"
+        ],
+        "acp_targetscope": [
+            "{\"And\": [{\"Eq\": [\"class\",\"oauth2_client\"]}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}]}}]}"
+        ],
+        "acp_search_attr": [
+            "class", "name", "uuid", "description", "oauth2_redirect_uri", "oauth2_scope_map"
+        ],
+        "acp_modify_removedattr": [
+            "name", "description", "oauth2_redirect_uri", "oauth2_scope_map"
+        ],
+        "acp_modify_presentattr": [
+            "name", "description", "oauth2_redirect_uri", "oauth2_scope_map"
+        ],
+        "acp_create_attr": [
+            "class", "name", "description", "oauth2_redirect_uri", "oauth2_scope_map"
+        ],
+        "acp_create_class": [
+            "object", "oauth2_client"
+        ]
+    }
+}"#;
+
+// 33 scim provisioning
+pub static _UUID_IDM_ACP_SCIM_PROVISION_PRIV_V1: &'static str =
+    "00000000-0000-0000-0000-ffffff000033";
+pub static JSON_IDM_ACP_SCIM_PROVISION_PRIV_V1: &'static str = r#"{
+    "attrs": {
+        "class": [
+            "object",
+            "system",
+            "access_control_profile",
+            "access_control_search",
+            "access_control_modify",
+            "access_control_create",
+            "access_control_delete"
+        ],
+        "name": ["idm_acp_scim_provision_priv"],
+        "uuid": ["00000000-0000-0000-0000-ffffff000033"],
+        "description": ["Builtin IDM Control for provisioning people and groups with scim."],
+        "acp_enable": ["true"],
+        "acp_receiver": [
+            "{\"Eq\":[\"memberof\",\"00000000-0000-0000-0000-000000000019\"]}"
+        ],
+        "acp_targetscope": [
+            "{\"And\": [{\"Or\": [{\"And\": [{\"Eq\": [\"class\",\"person\"]}, {\"Eq\": [\"class\",\"account\"]}]}, {\"Eq\": [\"class\",\"group\"]}]}, {\"AndNot\": {\"Or\": [{\"Eq\": [\"memberof\",\"00000000-0000-0000-0000-000000001000\"]}, {\"Eq\": [\"class\", \"tombstone\"]}, {\"Eq\": [\"class\", \"recycled\"]}]}}]}"
+        ],
+        "acp_search_attr": [
+            "class", "name", "uuid", "displayname", "mail", "scim_external_id", "account_expire", "member"
+        ],
+        "acp_modify_removedattr": [
+            "name", "displayname", "mail", "scim_external_id", "account_expire", "member"
+        ],
+        "acp_modify_presentattr": [
+            "name", "displayname", "mail", "scim_external_id", "account_expire", "member"
+        ],
+        "acp_create_attr": [
+            "class", "name", "displayname", "mail", "scim_external_id", "account_expire", "member"
+        ],
+        "acp_create_class": [
+            "object", "person", "account", "group"
+        ]
+    }
+}"#;
+
+// Anonymous should be the last opbject in the range here.
+pub static JSON_ANONYMOUS_V1: &'static str = r#"{
+    "attrs": {
+        "class": ["account", "object", "system_restricted"],
+        "name": ["anonymous"],
+        "uuid": ["00000000-0000-0000-0000-ffffffffffff"],
+        "description": ["Anonymous access account."],
+        "displayname": ["Anonymous"]
+    }
+}"#;
+
+// Core
+// Schema uuids start at 00000000-0000-0000-0000-ffff00000000
+pub static UUID_SCHEMA_ATTR_CLASS: &'static str = "00000000-0000-0000-0000-ffff00000000";
+pub static UUID_SCHEMA_ATTR_UUID: &'static str = "00000000-0000-0000-0000-ffff00000001";
+pub static UUID_SCHEMA_ATTR_NAME: &'static str = "00000000-0000-0000-0000-ffff00000002";
+pub static UUID_SCHEMA_ATTR_ATTRIBUTENAME: &'static str = "00000000-0000-0000-0000-ffff00000048";
+pub static UUID_SCHEMA_ATTR_CLASSNAME: &'static str = "00000000-0000-0000-0000-ffff00000049";
+pub static UUID_SCHEMA_ATTR_PRINCIPAL_NAME: &'static str = "00000000-0000-0000-0000-ffff00000003";
+pub static UUID_SCHEMA_ATTR_DESCRIPTION: &'static str = "00000000-0000-0000-0000-ffff00000004";
+pub static UUID_SCHEMA_ATTR_MULTIVALUE: &'static str = "00000000-0000-0000-0000-ffff00000005";
+pub static UUID_SCHEMA_ATTR_UNIQUE: &'static str = "00000000-0000-0000-0000-ffff00000047";
+pub static UUID_SCHEMA_ATTR_SECRET: &'static str = "00000000-0000-0000-0000-ffff00000087";
+pub static UUID_SCHEMA_ATTR_INDEX: &'static str = "00000000-0000-0000-0000-ffff00000006";
+pub static UUID_SCHEMA_ATTR_SYNTAX: &'static str = "00000000-0000-0000-0000-ffff00000007";
+pub static UUID_SCHEMA_ATTR_SYSTEMMAY: &'static str = "00000000-0000-0000-0000-ffff00000008";
+pub static UUID_SCHEMA_ATTR_MAY: &'static str = "00000000-0000-0000-0000-ffff00000009";
+pub static UUID_SCHEMA_ATTR_SYSTEMMUST: &'static str = "00000000-0000-0000-0000-ffff00000010";
+pub static UUID_SCHEMA_ATTR_MUST: &'static str = "00000000-0000-0000-0000-ffff00000011";
+pub static UUID_SCHEMA_ATTR_MEMBEROF: &'static str = "00000000-0000-0000-0000-ffff00000012";
+pub static UUID_SCHEMA_ATTR_MEMBER: &'static str = "00000000-0000-0000-0000-ffff00000013";
+pub static UUID_SCHEMA_ATTR_DIRECTMEMBEROF: &'static str = "00000000-0000-0000-0000-ffff00000014";
+pub static UUID_SCHEMA_ATTR_VERSION: &'static str = "00000000-0000-0000-0000-ffff00000015";
+pub static UUID_SCHEMA_ATTR_DOMAIN: &'static str = "00000000-0000-0000-0000-ffff00000016";
+pub static UUID_SCHEMA_ATTR_ACP_ENABLE: &'static str = "00000000-0000-0000-0000-ffff00000017";
+pub static UUID_SCHEMA_ATTR_ACP_RECEIVER: &'static str = "00000000-0000-0000-0000-ffff00000018";
+pub static UUID_SCHEMA_ATTR_ACP_TARGETSCOPE: &'static str = "00000000-0000-0000-0000-ffff00000019";
+pub static UUID_SCHEMA_ATTR_ACP_SEARCH_ATTR: &'static str = "00000000-0000-0000-0000-ffff00000020";
+pub static UUID_SCHEMA_ATTR_ACP_CREATE_CLASS: &'static str = "00000000-0000-0000-0000-ffff00000021";
+pub static UUID_SCHEMA_ATTR_ACP_CREATE_ATTR: &'static str = "00000000-0000-0000-0000-ffff00000022";
+pub static UUID_SCHEMA_ATTR_ACP_MODIFY_REMOVEDATTR: &'static str =
+    "00000000-0000-0000-0000-ffff00000023";
+pub static UUID_SCHEMA_ATTR_ACP_MODIFY_PRESENTATTR: &'static str =
+    "00000000-0000-0000-0000-ffff00000024";
+pub static UUID_SCHEMA_ATTR_ACP_MODIFY_CLASS: &'static str = "00000000-0000-0000-0000-ffff00000025";
+pub static UUID_SCHEMA_ATTR_ACP_COMPARE_ATTR: &'static str = "00000000-0000-0000-0000-ffff00000051";
+pub static UUID_SCHEMA_ATTR_RECYCLED_REFERENCES: &'static str =
+    "00000000-0000-0000-0000-ffff00000055";
+pub static UUID_SCHEMA_ATTR_DELETED_AT: &'static str = "00000000-0000-0000-0000-ffff00000056";
+pub static UUID_SCHEMA_ATTR_TOMBSTONED_AT: &'static str = "00000000-0000-0000-0000-ffff00000057";
+pub static UUID_SCHEMA_ATTR_TOKEN_SIGNING_KEY: &'static str =
+    "00000000-0000-0000-0000-ffff00000058";
+pub static UUID_SCHEMA_ATTR_TOKEN_SIGNING_KEY_PREVIOUS: &'static str =
+    "00000000-0000-0000-0000-ffff00000059";
+pub static UUID_SCHEMA_ATTR_TOKEN_SIGNING_KEY_ROTATED_AT: &'static str =
+    "00000000-0000-0000-0000-ffff00000060";
+pub static UUID_SCHEMA_ATTR_BADLIST_PASSWORD: &'static str = "00000000-0000-0000-0000-ffff00000061";
+pub static UUID_SCHEMA_ATTR_PASSWORD_MIN_SCORE: &'static str =
+    "00000000-0000-0000-0000-ffff00000062";
+pub static UUID_SCHEMA_ATTR_DISABLE_ANONYMOUS: &'static str =
+    "00000000-0000-0000-0000-ffff00000066";
+pub static UUID_SCHEMA_ATTR_API_TOKEN: &'static str = "00000000-0000-0000-0000-ffff00000067";
+pub static UUID_SCHEMA_ATTR_RADIUS_SECRET: &'static str = "00000000-0000-0000-0000-ffff00000069";
+pub static UUID_SCHEMA_ATTR_GIDNUMBER: &'static str = "00000000-0000-0000-0000-ffff00000070";
+pub static UUID_SCHEMA_ATTR_LOGINSHELL: &'static str = "00000000-0000-0000-0000-ffff00000071";
+pub static UUID_SCHEMA_ATTR_UNIX_PASSWORD: &'static str = "00000000-0000-0000-0000-ffff00000072";
+pub static UUID_SCHEMA_ATTR_DOMAIN_NAME: &'static str = "00000000-0000-0000-0000-ffff00000077";
+pub static UUID_SCHEMA_ATTR_DOMAIN_DISPLAY_NAME: &'static str =
+    "00000000-0000-0000-0000-ffff00000078";
+pub static UUID_SCHEMA_ATTR_DOMAIN_UUID: &'static str = "00000000-0000-0000-0000-ffff00000079";
+pub static UUID_SCHEMA_ATTR_SPN: &'static str = "00000000-0000-0000-0000-ffff00000080";
+pub static UUID_SCHEMA_ATTR_CLAIM: &'static str = "00000000-0000-0000-0000-ffff00000082";
+pub static UUID_SCHEMA_ATTR_CLAIM_GROUP: &'static str = "00000000-0000-0000-0000-ffff00000083";
+pub static UUID_SCHEMA_ATTR_CLAIM_REQUIRE_MFA: &'static str =
+    "00000000-0000-0000-0000-ffff00000084";
+pub static UUID_SCHEMA_ATTR_CLAIM_LIFETIME: &'static str = "00000000-0000-0000-0000-ffff00000085";
+pub static UUID_SCHEMA_ATTR_OAUTH2_REDIRECT_URI: &'static str =
+    "00000000-0000-0000-0000-ffff00000088";
+pub static UUID_SCHEMA_ATTR_OAUTH2_SCOPE_MAP: &'static str =
+    "00000000-0000-0000-0000-ffff00000089";
+pub static UUID_SCHEMA_ATTR_SCIM_EXTERNAL_ID: &'static str =
+    "00000000-0000-0000-0000-ffff00000091";
+
+pub static UUID_SCHEMA_CLASS_ATTRIBUTETYPE: &'static str = "00000000-0000-0000-0000-ffff00000026";
+pub static UUID_SCHEMA_CLASS_CLASSTYPE: &'static str = "00000000-0000-0000-0000-ffff00000027";
+pub static UUID_SCHEMA_CLASS_OBJECT: &'static str = "00000000-0000-0000-0000-ffff00000028";
+pub static UUID_SCHEMA_CLASS_EXTENSIBLEOBJECT: &'static str =
+    "00000000-0000-0000-0000-ffff00000029";
+pub static UUID_SCHEMA_CLASS_MEMBEROF: &'static str = "00000000-0000-0000-0000-ffff00000030";
+
+pub static UUID_SCHEMA_CLASS_RECYCLED: &'static str = "00000000-0000-0000-0000-ffff00000031";
+pub static UUID_SCHEMA_CLASS_TOMBSTONE: &'static str = "00000000-0000-0000-0000-ffff00000032";
+pub static UUID_SCHEMA_CLASS_SYSTEM_INFO: &'static str = "00000000-0000-0000-0000-ffff00000033";
+pub static UUID_SCHEMA_CLASS_SYSTEM_CONFIG: &'static str = "00000000-0000-0000-0000-ffff00000063";
+pub static UUID_SCHEMA_CLASS_DOMAIN_INFO: &'static str = "00000000-0000-0000-0000-ffff00000081";
+pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_PROFILE: &'static str =
+    "00000000-0000-0000-0000-ffff00000034";
+pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_SEARCH: &'static str =
+    "00000000-0000-0000-0000-ffff00000035";
+pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_DELETE: &'static str =
+    "00000000-0000-0000-0000-ffff00000036";
+pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_MODIFY: &'static str =
+    "00000000-0000-0000-0000-ffff00000037";
+pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_CREATE: &'static str =
+    "00000000-0000-0000-0000-ffff00000038";
+pub static UUID_SCHEMA_CLASS_ACCESS_CONTROL_COMPARE: &'static str =
+    "00000000-0000-0000-0000-ffff00000052";
+pub static UUID_SCHEMA_CLASS_SYSTEM: &'static str = "00000000-0000-0000-0000-ffff00000039";
+pub static UUID_SCHEMA_CLASS_SYSTEM_RESTRICTED: &'static str =
+    "00000000-0000-0000-0000-ffff00000075";
+pub static UUID_SCHEMA_CLASS_SYSTEM_UNDELETABLE: &'static str =
+    "00000000-0000-0000-0000-ffff00000076";
+
+// system supplementary
+pub static UUID_SCHEMA_ATTR_DISPLAYNAME: &'static str = "00000000-0000-0000-0000-ffff00000040";
+pub static JSON_SCHEMA_ATTR_DISPLAYNAME: &'static str = r#"{
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000040"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The publicly visible display name of this person"
+      ],
+      "index": [
+        "EQUALITY"
+      ],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "displayname"
+      ],
+      "syntax": [
+        "UTF8STRING"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000040"
+      ]
+    }
+}"#;
+pub static UUID_SCHEMA_ATTR_MAIL: &'static str = "00000000-0000-0000-0000-ffff00000041";
+pub static JSON_SCHEMA_ATTR_MAIL: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000041"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "mail addresses of the object"
+      ],
+      "index": [
+        "EQUALITY"
+      ],
+      "unique": [
+        "true"
+      ],
+      "multivalue": [
+        "true"
+      ],
+      "attributename": [
+        "mail"
+      ],
+      "syntax": [
+        "UTF8STRING"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000041"
+      ]
+    }
+  }
+"#;
+pub static UUID_SCHEMA_ATTR_SSH_PUBLICKEY: &'static str = "00000000-0000-0000-0000-ffff00000042";
+pub static JSON_SCHEMA_ATTR_SSH_PUBLICKEY: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000042"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "SSH public keys of the object, as \"tag: key\""
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "true"
+      ],
+      "attributename": [
+        "ssh_publickey"
+      ],
+      "syntax": [
+        "SSHKEY"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000042"
+      ]
+    }
+  }
+"#;
+pub static UUID_SCHEMA_ATTR_PRIMARY_CREDENTIAL: &'static str =
+    "00000000-0000-0000-0000-ffff00000043";
+pub static JSON_SCHEMA_ATTR_PRIMARY_CREDENTIAL: &'static str = r#"
+  {
+    "valid": {
+      "uuid": "00000000-0000-0000-0000-ffff00000043"
+    },
+    "state": null,
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "Primary credential material of the account for authentication interactively."
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "secret": [
+        "true"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "primary_credential"
+      ],
+      "syntax": [
+        "CREDENTIAL"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000043"
+      ]
+    }
+  }
+"#;
+pub static UUID_SCHEMA_ATTR_LEGALNAME: &'static str = "00000000-0000-0000-0000-ffff00000050";
+pub static JSON_SCHEMA_ATTR_LEGALNAME: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The private and sensitive legal name of this person"
+      ],
+      "index": [
+        "EQUALITY"
+      ],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "legalname"
+      ],
+      "syntax": [
+        "UTF8STRING"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000050"
+      ]
+    }
+}"#;
+
+pub static UUID_SCHEMA_ATTR_ACCOUNT_EXPIRE: &'static str = "00000000-0000-0000-0000-ffff00000053";
+pub static JSON_SCHEMA_ATTR_ACCOUNT_EXPIRE: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The datetime after which this account no longer may authenticate."
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "account_expire"
+      ],
+      "syntax": [
+        "DATETIME"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000053"
+      ]
+    }
+}"#;
+
+pub static UUID_SCHEMA_ATTR_ACCOUNT_VALID_FROM: &'static str =
+    "00000000-0000-0000-0000-ffff00000054";
+pub static JSON_SCHEMA_ATTR_ACCOUNT_VALID_FROM: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The datetime after which this account may commence authenticating."
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "account_valid_from"
+      ],
+      "syntax": [
+        "DATETIME"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000054"
+      ]
+    }
+}"#;
+
+pub static UUID_SCHEMA_ATTR_AUTH_FAILURES: &'static str = "00000000-0000-0000-0000-ffff00000064";
+pub static JSON_SCHEMA_ATTR_AUTH_FAILURES: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The number of consecutive failed authentications of this account."
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "auth_failures"
+      ],
+      "syntax": [
+        "UINT32"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000064"
+      ]
+    }
+}"#;
+
+pub static UUID_SCHEMA_ATTR_ACCOUNT_LOCKED_UNTIL: &'static str =
+    "00000000-0000-0000-0000-ffff00000065";
+pub static JSON_SCHEMA_ATTR_ACCOUNT_LOCKED_UNTIL: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The datetime until which this account may not authenticate, after repeated failures."
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "account_locked_until"
+      ],
+      "syntax": [
+        "DATETIME"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000065"
+      ]
+    }
+}"#;
+
+pub static JSON_SCHEMA_ATTR_API_TOKEN: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The api tokens a service account may authenticate with."
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "secret": [
+        "true"
+      ],
+      "multivalue": [
+        "true"
+      ],
+      "attributename": [
+        "api_token"
+      ],
+      "syntax": [
+        "API_TOKEN"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000067"
+      ]
+    }
+}"#;
+pub static JSON_SCHEMA_ATTR_RADIUS_SECRET: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The secret an account authenticates to radius with."
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "secret": [
+        "true"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "radius_secret"
+      ],
+      "syntax": [
+        "RADIUS_UTF8STRING"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000069"
+      ]
+    }
+}"#;
+pub static JSON_SCHEMA_ATTR_SPN: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The security principal name of an account or group, name@domain_name. It's generated from the name and the domain info, and can't be set."
+      ],
+      "index": [
+        "EQUALITY"
+      ],
+      "unique": [
+        "true"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "spn"
+      ],
+      "syntax": [
+        "UTF8STRING_INSENSITIVE"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000080"
+      ]
+    }
+}"#;
+pub static JSON_SCHEMA_ATTR_CLAIM: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The unexpired claims of a session. These are only ever present on the entry of an account as its session sees it, so that access controls may filter on them."
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "true"
+      ],
+      "attributename": [
+        "claim"
+      ],
+      "syntax": [
+        "UTF8STRING_INSENSITIVE"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000082"
+      ]
+    }
+}"#;
+pub static JSON_SCHEMA_ATTR_CLAIM_GROUP: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The groups whose members may request a claim."
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "true"
+      ],
+      "attributename": [
+        "claim_group"
+      ],
+      "syntax": [
+        "REFERENCE_UUID"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000083"
+      ]
+    }
+}"#;
+pub static JSON_SCHEMA_ATTR_CLAIM_REQUIRE_MFA: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "If a claim is only granted to sessions that gave multi-factor credentials."
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
       ],
       "attributename": [
-        "ssh_publickey"
+        "claim_require_mfa"
+      ],
+      "syntax": [
+        "BOOLEAN"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000084"
+      ]
+    }
+}"#;
+pub static JSON_SCHEMA_ATTR_CLAIM_LIFETIME: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "How long a claim lasts after it's granted, in seconds. Without this it lasts as long as the session."
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "claim_lifetime"
+      ],
+      "syntax": [
+        "UINT32"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000085"
+      ]
+    }
+}"#;
+pub static JSON_SCHEMA_ATTR_OAUTH2_REDIRECT_URI: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The uris an oauth2 client may be redirected to with a code. A redirect uri must match one of these exactly."
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "multivalue": [
+        "true"
+      ],
+      "attributename": [
+        "oauth2_redirect_uri"
       ],
       "syntax": [
         "UTF8STRING"
       ],
       "uuid": [
-        "00000000-0000-0000-0000-ffff00000042"
+        "00000000-0000-0000-0000-ffff00000088"
       ]
     }
-  }
-"#;
-pub static UUID_SCHEMA_ATTR_PRIMARY_CREDENTIAL: &'static str =
-    "00000000-0000-0000-0000-ffff00000043";
-pub static JSON_SCHEMA_ATTR_PRIMARY_CREDENTIAL: &'static str = r#"
-  {
-    "valid": {
-      "uuid": "00000000-0000-0000-0000-ffff00000043"
-    },
-    "state": null,
+}"#;
+pub static JSON_SCHEMA_ATTR_OAUTH2_SCOPE_MAP: &'static str = r#"{
     "attrs": {
       "class": [
         "object",
@@ -1134,29 +2150,57 @@ pub static JSON_SCHEMA_ATTR_PRIMARY_CREDENTIAL: &'static str = r#"
         "attributetype"
       ],
       "description": [
-        "Primary credential material of the account for authentication interactively."
+        "A scope an oauth2 client may be granted, and the group, by name or uuid, whose members it's granted to. The two are separated by a space."
       ],
       "index": [],
       "unique": [
         "false"
       ],
+      "multivalue": [
+        "true"
+      ],
+      "attributename": [
+        "oauth2_scope_map"
+      ],
+      "syntax": [
+        "UTF8STRING"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000089"
+      ]
+    }
+}"#;
+pub static JSON_SCHEMA_ATTR_SCIM_EXTERNAL_ID: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The id a scim provisioner knows this entry by, so it can find the entry again."
+      ],
+      "index": [
+        "EQUALITY"
+      ],
+      "unique": [
+        "true"
+      ],
       "multivalue": [
         "false"
       ],
       "attributename": [
-        "primary_credential"
+        "scim_external_id"
       ],
       "syntax": [
-        "CREDENTIAL"
+        "UTF8STRING"
       ],
       "uuid": [
-        "00000000-0000-0000-0000-ffff00000043"
+        "00000000-0000-0000-0000-ffff00000091"
       ]
     }
-  }
-"#;
-pub static UUID_SCHEMA_ATTR_LEGALNAME: &'static str = "00000000-0000-0000-0000-ffff00000050";
-pub static JSON_SCHEMA_ATTR_LEGALNAME: &'static str = r#"{
+}"#;
+pub static JSON_SCHEMA_ATTR_GIDNUMBER: &'static str = r#"{
     "attrs": {
       "class": [
         "object",
@@ -1164,11 +2208,39 @@ pub static JSON_SCHEMA_ATTR_LEGALNAME: &'static str = r#"{
         "attributetype"
       ],
       "description": [
-        "The private and sensitive legal name of this person"
+        "The gid number of a posix account or group. An account is its own primary group, so this is also its uid."
       ],
       "index": [
         "EQUALITY"
       ],
+      "unique": [
+        "true"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "gidnumber"
+      ],
+      "syntax": [
+        "UINT32"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000070"
+      ]
+    }
+}"#;
+pub static JSON_SCHEMA_ATTR_LOGINSHELL: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "The shell an account is given when it logs in to a unix machine."
+      ],
+      "index": [],
       "unique": [
         "false"
       ],
@@ -1176,13 +2248,44 @@ pub static JSON_SCHEMA_ATTR_LEGALNAME: &'static str = r#"{
         "false"
       ],
       "attributename": [
-        "legalname"
+        "loginshell"
       ],
       "syntax": [
         "UTF8STRING"
       ],
       "uuid": [
-        "00000000-0000-0000-0000-ffff00000050"
+        "00000000-0000-0000-0000-ffff00000071"
+      ]
+    }
+}"#;
+pub static JSON_SCHEMA_ATTR_UNIX_PASSWORD: &'static str = r#"{
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "attributetype"
+      ],
+      "description": [
+        "A password an account authenticates to unix machines with, kept apart from the primary credential."
+      ],
+      "index": [],
+      "unique": [
+        "false"
+      ],
+      "secret": [
+        "true"
+      ],
+      "multivalue": [
+        "false"
+      ],
+      "attributename": [
+        "unix_password"
+      ],
+      "syntax": [
+        "CREDENTIAL"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000072"
       ]
     }
 }"#;
@@ -1208,7 +2311,8 @@ pub static JSON_SCHEMA_CLASS_PERSON: &'static str = r#"
       ],
       "systemmay": [
         "mail",
-        "legalname"
+        "legalname",
+        "scim_external_id"
       ],
       "systemmust": [
         "displayname",
@@ -1241,7 +2345,9 @@ pub static JSON_SCHEMA_CLASS_GROUP: &'static str = r#"
         "group"
       ],
       "systemmay": [
-        "member"
+        "member",
+        "spn",
+        "scim_external_id"
       ],
       "systemmust": [
         "name"
@@ -1269,7 +2375,13 @@ pub static JSON_SCHEMA_CLASS_ACCOUNT: &'static str = r#"
       ],
       "systemmay": [
         "primary_credential",
-        "ssh_publickey"
+        "ssh_publickey",
+        "account_expire",
+        "account_valid_from",
+        "auth_failures",
+        "account_locked_until",
+        "radius_secret",
+        "spn"
       ],
       "systemmust": [
         "displayname",
@@ -1282,6 +2394,146 @@ pub static JSON_SCHEMA_CLASS_ACCOUNT: &'static str = r#"
   }
 "#;
 
+pub static UUID_SCHEMA_CLASS_SERVICE_ACCOUNT: &'static str = "00000000-0000-0000-0000-ffff00000068";
+pub static JSON_SCHEMA_CLASS_SERVICE_ACCOUNT: &'static str = r#"
+  {
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "classtype"
+      ],
+      "description": [
+        "Object representation of a service account, that authenticates with api tokens"
+      ],
+      "classname": [
+        "service_account"
+      ],
+      "systemmay": [
+        "api_token"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000068"
+      ]
+    }
+  }
+"#;
+
+pub static UUID_SCHEMA_CLASS_POSIXACCOUNT: &'static str = "00000000-0000-0000-0000-ffff00000073";
+pub static JSON_SCHEMA_CLASS_POSIXACCOUNT: &'static str = r#"
+  {
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "classtype"
+      ],
+      "description": [
+        "Object representation of an account that may log in to unix machines"
+      ],
+      "classname": [
+        "posixaccount"
+      ],
+      "systemmay": [
+        "loginshell",
+        "unix_password"
+      ],
+      "systemmust": [
+        "gidnumber"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000073"
+      ]
+    }
+  }
+"#;
+
+pub static UUID_SCHEMA_CLASS_POSIXGROUP: &'static str = "00000000-0000-0000-0000-ffff00000074";
+pub static JSON_SCHEMA_CLASS_POSIXGROUP: &'static str = r#"
+  {
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "classtype"
+      ],
+      "description": [
+        "Object representation of a group on unix machines"
+      ],
+      "classname": [
+        "posixgroup"
+      ],
+      "systemmust": [
+        "gidnumber"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000074"
+      ]
+    }
+  }
+"#;
+
+pub static UUID_SCHEMA_CLASS_CLAIM: &'static str = "00000000-0000-0000-0000-ffff00000086";
+pub static JSON_SCHEMA_CLASS_CLAIM: &'static str = r#"
+  {
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "classtype"
+      ],
+      "description": [
+        "Object representation of a claim, that sessions may request as they authenticate"
+      ],
+      "classname": [
+        "claim"
+      ],
+      "systemmay": [
+        "description",
+        "claim_group",
+        "claim_require_mfa",
+        "claim_lifetime"
+      ],
+      "systemmust": [
+        "name"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000086"
+      ]
+    }
+  }
+"#;
+
+pub static UUID_SCHEMA_CLASS_OAUTH2_CLIENT: &'static str = "00000000-0000-0000-0000-ffff00000090";
+pub static JSON_SCHEMA_CLASS_OAUTH2_CLIENT: &'static str = r#"
+  {
+    "attrs": {
+      "class": [
+        "object",
+        "system",
+        "classtype"
+      ],
+      "description": [
+        "Object representation of an oauth2 client, named by its client id, that accounts may sign in to"
+      ],
+      "classname": [
+        "oauth2_client"
+      ],
+      "systemmay": [
+        "description",
+        "oauth2_redirect_uri",
+        "oauth2_scope_map"
+      ],
+      "systemmust": [
+        "name"
+      ],
+      "uuid": [
+        "00000000-0000-0000-0000-ffff00000090"
+      ]
+    }
+  }
+"#;
+
 // ============ TEST DATA ============
 #[cfg(test)]
 pub static JSON_TESTPERSON1: &'static str = r#"{
@@ -1,12 +1,26 @@
 // use actix::SystemRunner;
+use actix::dev::ToEnvelope;
 use actix::Actor;
+use actix_web::dev::HttpResponseBuilder;
 use actix_web::middleware::session::{self, RequestSession};
+use actix_web::middleware::{Middleware, Response as MiddlewareResponse, Started};
 use actix_web::{
-    error, http, middleware, App, Error, HttpMessage, HttpRequest, HttpResponse, Result, State,
+    error, http, App, Error, FromRequest, HttpMessage, HttpRequest, HttpResponse, Query, Result,
+    State,
 };
 
-use bytes::BytesMut;
-use futures::{future, Future, Stream};
+use bytes::{Bytes, BytesMut};
+use futures::sync::mpsc;
+use futures::{future, stream, Future, Stream};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
 use time::Duration;
 
 use crate::config::Configuration;
@@ -14,33 +28,166 @@ use crate::config::Configuration;
 // SearchResult
 use crate::actors::v1::QueryServerV1;
 use crate::actors::v1::{
-    AuthMessage, CreateMessage, DeleteMessage, ModifyMessage, SearchMessage, WhoamiMessage,
+    AccessCheckMessage, ApiTokenAuthMessage, ApiTokenDestroyMessage, ApiTokenGenerateMessage,
+    ApiTokenListMessage, AuditListMessage, AuthMessage, BackupCodesGenerateMessage, BackupMessage,
+    ChangesMessage, CompareMessage, CreateMessage, CredentialChangeMessage,
+    CredentialPolicyMessage, CredentialStatusMessage, DeleteMessage, EffectiveAccessMessage,
+    EntryCountMessage, ExportMessage, IndexStatusMessage, LogoutMessage, ModifyBatchMessage,
+    ModifyMessage, Oauth2AuthorizeMessage, Oauth2TokenMessage, RadiusAuthTokenMessage,
+    RadiusSecretGenerateMessage, ReadinessMessage, ReauthMessage, ReindexMessage,
+    ReviveRecycledMessage, SchemaMessage, SearchCountMessage, SearchMessage, SearchRecycledMessage,
+    SearchStreamMessage, SessionListMessage, SessionRevokeMessage, SshPublicKeysMessage,
+    TOTPGenerateMessage, TOTPVerifyMessage, UnixAuthMessage, UnixGroupTokenMessage,
+    UnixUserTokenMessage, VacuumMessage, WebauthnGenerateMessage, WebauthnListMessage,
+    WebauthnRegisterMessage, WebauthnRemoveMessage, WhoamiMessage,
 };
+use crate::admin::{read_domain_info, start_admin_socket, DomainInfo};
 use crate::async_log;
 use crate::audit::AuditScope;
-use crate::be::{Backend, BackendTransaction};
-use crate::crypto::setup_tls;
+use crate::be::dbbackup::read_backup;
+use crate::be::{read_db_versions, Backend, BackendTransaction};
+use crate::credential::webauthn::WebauthnConfig;
+use crate::idm::oauth2::Oauth2AccessToken;
+use crate::idm::reauth::ReauthPolicy;
 use crate::idm::server::IdmServer;
+use crate::idm::tokenkeys::TokenKeys;
 use crate::interval::IntervalActor;
+use crate::ldap::gateway::LdapGateway;
+use crate::ldap::server::{start_ldap_server, LdapServer};
+use crate::logging::{log_request, RequestLog};
+use crate::metrics::{Metrics, Operation};
+use crate::ratelimit::{RateLimitKind, RateLimiter};
+use crate::requestlimit::RequestLimits;
 use crate::schema::Schema;
+use crate::scim::gateway::{self as scim_gateway, ScimKind};
+use crate::scim::proto::{ScimError, ScimListResponse, CONTENT_TYPE_SCIM};
 use crate::server::QueryServer;
+use crate::shutdown::ShutdownActor;
+use crate::tls::{TlsCertificates, TlsReloadActor};
 use crate::utils::SID;
-use kanidm_proto::v1::OperationError;
+use kanidm_proto::v1::Entry as ProtoEntry;
+use kanidm_proto::v1::Filter as ProtoFilter;
+use kanidm_proto::v1::ModifyList as ProtoModifyList;
 use kanidm_proto::v1::{
-    AuthRequest, AuthState, CreateRequest, DeleteRequest, ModifyRequest, SearchRequest,
-    UserAuthToken,
+    AccessCheckRequest, ApiTokenDestroyRequest, ApiTokenGenerateRequest, ApiTokenListRequest,
+    AuditListRequest, AuthRequest, AuthResponse, AuthState, AuthStep, BackupRequest,
+    ChangesRequest, CompareRequest, CreateRequest, CredentialChangeRequest,
+    CredentialPolicyRequest, DeleteRequest, EffectiveAccessRequest, ExportRequest,
+    IndexStatusRequest, ModifyBatchRequest, ModifyRequest, Oauth2AuthorizeRequest,
+    Oauth2ErrorResponse, Oauth2TokenRequest, ReauthRequest, ReindexRequest, ReviveRecycledRequest,
+    SchemaRequest, SearchCountRequest, SearchRecycledRequest, SearchRequest, SessionListRequest,
+    SessionRevokeRequest, TOTPVerifyRequest, UnixAuthRequest, UserAuthToken, VacuumRequest,
+    WebauthnRegisterRequest, WebauthnRemoveRequest,
+};
+use kanidm_proto::v1::{
+    ErrorResponse, ExportStreamItem, HealthCheck, HealthResponse, OperationError, RepairResponse,
+    SearchStreamItem, VerifyCheck, KOPID, SEARCH_STREAM_CBOR, SEARCH_STREAM_JSON,
 };
 
 use uuid::Uuid;
 
+// Operations that only read are handled by a pool of workers, each in its
+// own read transaction. Those that may write are queued to a single worker,
+// so they are applied one at a time and never hold up a read.
 struct AppState {
-    qe: actix::Addr<QueryServerV1>,
-    max_size: usize,
+    qe_r: actix::Addr<QueryServerV1>,
+    qe_w: actix::Addr<QueryServerV1>,
+    request_limits: RequestLimits,
+    token_keys: Arc<TokenKeys>,
+    idms: Arc<IdmServer>,
+    metrics: Arc<Metrics>,
+    rate_limiter: Arc<RateLimiter>,
+    // The issuer named in the oauth2 tokens we sign.
+    issuer: String,
 }
 
-fn get_current_user(req: &HttpRequest<AppState>) -> Option<UserAuthToken> {
-    match req.session().get::<UserAuthToken>("uat") {
-        Ok(maybe_uat) => maybe_uat,
+// When a request began, so its latency can be counted once it's answered.
+struct RequestStart(Instant);
+
+// The uuid of the account a request was authenticated as, once its token has
+// been checked.
+struct RequestIdentity(String);
+
+// Each request is counted, and logged with the operation it was, once it's
+// answered.
+struct RequestMetrics;
+
+impl Middleware<AppState> for RequestMetrics {
+    fn start(&self, req: &HttpRequest<AppState>) -> Result<Started> {
+        req.extensions_mut().insert(RequestStart(Instant::now()));
+        Ok(Started::Done)
+    }
+
+    fn response(
+        &self,
+        req: &HttpRequest<AppState>,
+        resp: HttpResponse,
+    ) -> Result<MiddlewareResponse> {
+        if let Some(RequestStart(start)) = req.extensions().get::<RequestStart>() {
+            let operation = Operation::from_path(req.path());
+            let elapsed = start.elapsed();
+            req.state()
+                .metrics
+                .record_request(operation, resp.status().as_u16(), elapsed);
+            log_request(&RequestLog {
+                eventid: resp
+                    .headers()
+                    .get(KOPID)
+                    .and_then(|v| v.to_str().ok())
+                    .map(|v| v.to_string()),
+                operation: operation.as_str(),
+                method: req.method().to_string(),
+                path: req.path().to_string(),
+                source: client_address(req).map(|a| a.to_string()),
+                identity: req
+                    .extensions()
+                    .get::<RequestIdentity>()
+                    .map(|i| i.0.clone()),
+                status: resp.status().as_u16(),
+                duration: elapsed.as_secs() as f64 * 1000.0
+                    + f64::from(elapsed.subsec_micros()) / 1000.0,
+            });
+        }
+        Ok(MiddlewareResponse::Done(resp))
+    }
+}
+
+fn current_time() -> std::time::Duration {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Clock failure!")
+}
+
+// As get_current_user, but the token is given even when its password must be
+// changed, for the few requests such an account may still make.
+fn get_current_user_unrestricted(req: &HttpRequest<AppState>) -> Option<UserAuthToken> {
+    // The session only carries the signed token. If the signature does not
+    // check out, the token has expired, or its session has been ended, it is
+    // treated as though there is none, so the request is not authenticated.
+    let ct = current_time();
+    let verify = |token: &str| match req.state().token_keys.verify_uat(token, ct) {
+        Some(ref uat) if req.state().idms.is_session_active(&uat.sessionid, ct) => {
+            req.extensions_mut()
+                .insert(RequestIdentity(uat.uuid.clone()));
+            Some(uat.clone())
+        }
+        _ => None,
+    };
+    match req.session().get::<String>("uat") {
+        Ok(Some(token)) => {
+            let uat = verify(token.as_str());
+            if uat.is_none() {
+                req.session().remove("uat");
+            }
+            uat
+        }
+        // A client that keeps the token itself gives it as a bearer token.
+        Ok(None) => req
+            .headers()
+            .get(http::header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| v.starts_with("Bearer "))
+            .and_then(|v| verify(v[7..].trim())),
         Err(_) => {
             // return Box::new(future::err(e));
             None
@@ -48,18 +195,229 @@ fn get_current_user(req: &HttpRequest<AppState>) -> Option<UserAuthToken> {
     }
 }
 
-macro_rules! json_event_post {
-    ($req:expr, $state:expr, $message_type:ty, $request_type:ty) => {{
-        // This is copied every request. Is there a better way?
-        // The issue is the fold move takes ownership of state if
-        // we don't copy this here
-        let max_size = $state.max_size;
+fn get_current_user(req: &HttpRequest<AppState>) -> Option<UserAuthToken> {
+    // Until a reset password is changed, the token may only be used to
+    // change it, so anything else is treated as not authenticated.
+    get_current_user_unrestricted(req).filter(|uat| !uat.must_change_password)
+}
 
-        // Get auth if any?
-        let uat = get_current_user(&$req);
+// The address a request is from. This is the peer, unless the peer is a proxy
+// we trust to say who it forwarded the request for.
+fn client_address(req: &HttpRequest<AppState>) -> Option<IpAddr> {
+    let peer = req.peer_addr()?.ip();
+    let forwarded_for: Vec<&str> = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect();
+    Some(
+        req.state()
+            .rate_limiter
+            .client_address(peer, forwarded_for.as_slice()),
+    )
+}
+
+// The response to a request over a rate limit, or None if it may go ahead.
+// This is checked before anything is done for the request.
+fn throttle(
+    req: &HttpRequest<AppState>,
+    kind: RateLimitKind,
+    eventid: Uuid,
+) -> Option<HttpResponse> {
+    let source = client_address(req);
+    match req.state().rate_limiter.check(kind, source, current_time()) {
+        Ok(()) => None,
+        Err(t) => {
+            req.state().metrics.record_rate_limited(kind, t.global);
+            info!(
+                "Rate limited {} from {:?} for {}s, global {}",
+                kind.as_str(),
+                source,
+                t.retry_after,
+                t.global
+            );
+            Some(error_response(
+                BodyFormat::accepted(req),
+                eventid,
+                OperationError::RateLimited(t.retry_after),
+            ))
+        }
+    }
+}
+
+// Searches without an account are made as anonymous, so they're limited
+// alike.
+fn throttle_anonymous(req: &HttpRequest<AppState>) -> Option<HttpResponse> {
+    let anonymous = get_current_user(req)
+        .map(|uat| uat.anonymous)
+        .unwrap_or(true);
+    if anonymous {
+        throttle(req, RateLimitKind::AnonymousSearch, Uuid::new_v4())
+    } else {
+        None
+    }
+}
+
+// The status each error is given, so that clients can tell what went wrong
+// without looking at the body.
+fn error_status(e: &OperationError) -> http::StatusCode {
+    match e {
+        OperationError::NotAuthenticated => http::StatusCode::UNAUTHORIZED,
+        OperationError::AccessDenied
+        | OperationError::SystemProtectedObject
+        | OperationError::SystemProtectedAttribute(_)
+        | OperationError::ReauthRequired => http::StatusCode::FORBIDDEN,
+        OperationError::NoMatchingEntries => http::StatusCode::NOT_FOUND,
+        OperationError::DuplicateValue(_) | OperationError::ModifyAssertionFailed => {
+            http::StatusCode::CONFLICT
+        }
+        OperationError::RateLimited(_) => http::StatusCode::TOO_MANY_REQUESTS,
+        OperationError::RequestTooLarge(_) => http::StatusCode::PAYLOAD_TOO_LARGE,
+        OperationError::ChangelogTrimmed => http::StatusCode::GONE,
+        // The database was busy, so the request may be retried.
+        OperationError::SQLiteError(kind) if kind.is_retryable() => {
+            http::StatusCode::SERVICE_UNAVAILABLE
+        }
+        OperationError::EmptyRequest
+        | OperationError::SchemaViolation(_)
+        | OperationError::FilterUUIDResolution
+        | OperationError::InvalidAttributeName(_)
+        | OperationError::InvalidAttribute(_)
+        | OperationError::InvalidRequestState
+        | OperationError::InvalidUuid
+        | OperationError::InvalidAccountState(_)
+        | OperationError::InvalidAuthState(_)
+        | OperationError::InvalidSessionState
+        | OperationError::ResourceLimit
+        | OperationError::ResultLimit(_)
+        | OperationError::TooManyEntries(_)
+        | OperationError::TooManyModifications(_)
+        | OperationError::ValueTooLong(_, _)
+        | OperationError::ReviveTombstone
+        | OperationError::ReviveFailed(_)
+        | OperationError::InvalidTOTP
+        | OperationError::InvalidWebauthn(_)
+        | OperationError::IncorrectPassword
+        | OperationError::PasswordQuality(_)
+        | OperationError::Oauth2(_) => http::StatusCode::BAD_REQUEST,
+        // A batch fails as the item that failed it would have.
+        OperationError::BatchItemFailed(_, inner) => error_status(inner),
+        _ => http::StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+// The body formats a client may use. Json is the default, and cbor is only
+// used when the client says so, so that older clients are unaffected.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BodyFormat {
+    Json,
+    Cbor,
+}
+
+const CONTENT_TYPE_CBOR: &str = "application/cbor";
+
+impl BodyFormat {
+    // The format of the request body. Clients have never had to set a
+    // content type, so anything that isn't cbor is taken to be json.
+    fn of_request(req: &HttpRequest<AppState>) -> Self {
+        if req.content_type() == CONTENT_TYPE_CBOR {
+            BodyFormat::Cbor
+        } else {
+            BodyFormat::Json
+        }
+    }
+
+    // The format to respond in. Accept is only checked for cbor, and
+    // anything else is given json.
+    fn accepted(req: &HttpRequest<AppState>) -> Self {
+        let accepts_cbor = req
+            .headers()
+            .get(http::header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| {
+                v.split(',')
+                    .any(|t| t.split(';').next().map(|t| t.trim()) == Some(CONTENT_TYPE_CBOR))
+            })
+            .unwrap_or(false);
+        if accepts_cbor {
+            BodyFormat::Cbor
+        } else {
+            BodyFormat::Json
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(self, body: &[u8]) -> Result<T, Error> {
+        match self {
+            BodyFormat::Json => serde_json::from_slice(body)
+                .map_err(|e| error::ErrorBadRequest(format!("Json Decode Failed: {:?}", e))),
+            BodyFormat::Cbor => serde_cbor::from_slice(body)
+                .map_err(|e| error::ErrorBadRequest(format!("Cbor Decode Failed: {:?}", e))),
+        }
+    }
+
+    fn respond<T: Serialize>(self, mut resp: HttpResponseBuilder, r: T) -> HttpResponse {
+        match self {
+            BodyFormat::Json => resp.json(r),
+            BodyFormat::Cbor => match serde_cbor::to_vec(&r) {
+                Ok(body) => resp.content_type(CONTENT_TYPE_CBOR).body(body),
+                Err(e) => {
+                    error!("Failed to encode cbor response: {:?}", e);
+                    HttpResponse::InternalServerError().finish()
+                }
+            },
+        }
+    }
+}
+
+// Every response for an operation carries its event id, which is written
+// with everything the server logs for it.
+fn ok_response<T: Serialize>(fmt: BodyFormat, eventid: Uuid, r: T) -> HttpResponse {
+    let mut resp = HttpResponse::Ok();
+    resp.header(KOPID, eventid.to_hyphenated_ref().to_string());
+    fmt.respond(resp, r)
+}
+
+fn error_response(fmt: BodyFormat, eventid: Uuid, e: OperationError) -> HttpResponse {
+    let mut resp = HttpResponse::build(error_status(&e));
+    resp.header(KOPID, eventid.to_hyphenated_ref().to_string());
+    if let OperationError::RateLimited(secs) = e {
+        resp.header(http::header::RETRY_AFTER, secs.to_string());
+    }
+    let mut er = ErrorResponse::from(&e);
+    er.eventid = Some(eventid.to_hyphenated_ref().to_string());
+    fmt.respond(resp, er)
+}
 
-        // HttpRequest::payload() is stream of Bytes objects
-        $req.payload()
+// The body of a request, read into memory. One over the limit of its kind of
+// request is refused with 413, and if its length is given, before any of it
+// is read.
+fn read_body(
+    req: &HttpRequest<AppState>,
+    fmt: BodyFormat,
+    eventid: Uuid,
+) -> Box<dyn Future<Item = BytesMut, Error = Error>> {
+    let limit = req.state().request_limits.body_limit(req.path());
+    let too_large = move || -> Error {
+        error::InternalError::from_response(
+            "request too large",
+            error_response(fmt, eventid, OperationError::RequestTooLarge(limit as u64)),
+        )
+        .into()
+    };
+
+    let length = req
+        .headers()
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if length.map(|l| l > limit).unwrap_or(false) {
+        return Box::new(future::err(too_large()));
+    }
+
+    // HttpRequest::payload() is stream of Bytes objects
+    Box::new(
+        req.payload()
             // `Future::from_err` acts like `?` in that it coerces the error type from
             // the future into the final error type
             .from_err()
@@ -67,41 +425,82 @@ macro_rules! json_event_post {
             // call supplied closure, then it resolves to result of closure
             .fold(BytesMut::new(), move |mut body, chunk| {
                 // limit max size of in-memory payload
-                if (body.len() + chunk.len()) > max_size {
-                    Err(error::ErrorBadRequest("overflow"))
+                if (body.len() + chunk.len()) > limit {
+                    Err(too_large())
                 } else {
                     body.extend_from_slice(&chunk);
                     Ok(body)
                 }
-            })
+            }),
+    )
+}
+
+macro_rules! json_event_post {
+    ($req:expr, $state:expr, $qe:ident, $message_type:ty, $request_type:ty) => {{
+        json_event_post!(
+            $req,
+            $state,
+            $qe,
+            $message_type,
+            $request_type,
+            get_current_user
+        )
+    }};
+    ($req:expr, $state:expr, $qe:ident, $message_type:ty, $request_type:ty, $get_user:ident) => {{
+        json_event_post!(
+            $req,
+            $state,
+            $qe,
+            $message_type,
+            $request_type,
+            $get_user,
+            |_, _| Ok(())
+        )
+    }};
+    // check is given the request limits and the decoded request, and the
+    // request is refused with its error before it's sent to the db.
+    ($req:expr, $state:expr, $qe:ident, $message_type:ty, $request_type:ty, $get_user:ident, $check:expr) => {{
+        // This is copied every request. Is there a better way?
+        // The issue is the and_then move takes ownership of state if
+        // we don't copy this here
+        let request_limits = $state.request_limits.clone();
+
+        // Get auth if any?
+        let uat = $get_user(&$req);
+        let eventid = Uuid::new_v4();
+        let content = BodyFormat::of_request(&$req);
+        let fmt = BodyFormat::accepted(&$req);
+
+        read_body(&$req, fmt, eventid)
             // `Future::and_then` can be used to merge an asynchronous workflow with a
             // synchronous workflow
             .and_then(
                 move |body| -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
-                    // body is loaded, now we can deserialize serde-json
-                    let r_obj = serde_json::from_slice::<$request_type>(&body);
+                    // body is loaded, now we can deserialize it as the client
+                    // sent it.
+                    let r_obj = content.decode::<$request_type>(&body);
 
                     // Send to the db for handling
                     match r_obj {
                         Ok(obj) => {
+                            if let Err(e) = ($check)(&request_limits, &obj) {
+                                return Box::new(future::ok(error_response(fmt, eventid, e)));
+                            }
                             // combine request + uat -> message.
-                            let m_obj = <($message_type)>::new(uat, obj);
+                            let m_obj = <($message_type)>::new(eventid, uat, obj);
                             let res = $state
-                                .qe
+                                .$qe
                                 .send(m_obj)
                                 // What is from_err?
                                 .from_err()
-                                .and_then(|res| match res {
-                                    Ok(event_result) => Ok(HttpResponse::Ok().json(event_result)),
-                                    Err(e) => Ok(HttpResponse::InternalServerError().json(e)),
+                                .and_then(move |res| match res {
+                                    Ok(event_result) => Ok(ok_response(fmt, eventid, event_result)),
+                                    Err(e) => Ok(error_response(fmt, eventid, e)),
                                 });
 
                             Box::new(res)
                         }
-                        Err(e) => Box::new(future::err(error::ErrorBadRequest(format!(
-                            "Json Decode Failed: {:?}",
-                            e
-                        )))),
+                        Err(e) => Box::new(future::err(e)),
                     }
                 },
             )
@@ -109,22 +508,28 @@ macro_rules! json_event_post {
 }
 
 macro_rules! json_event_get {
-    ($req:expr, $state:expr, $message_type:ty) => {{
+    ($req:expr, $state:expr, $qe:ident, $message_type:ty) => {{
+        json_event_get!($req, $state, $qe, $message_type, get_current_user)
+    }};
+    ($req:expr, $state:expr, $qe:ident, $message_type:ty, $get_user:ident) => {{
         // Get current auth data - remember, the QS checks if the
         // none/some is okay, because it's too hard to make it work here
         // with all the async parts.
-        let uat = get_current_user(&$req);
+        let uat = $get_user(&$req);
+        let eventid = Uuid::new_v4();
+        let fmt = BodyFormat::accepted(&$req);
 
         // New event, feed current auth data from the token to it.
-        let obj = <($message_type)>::new(uat);
+        let obj = <($message_type)>::new(eventid, uat);
 
-        let res = $state.qe.send(obj).from_err().and_then(|res| match res {
-            Ok(event_result) => Ok(HttpResponse::Ok().json(event_result)),
-            Err(e) => match e {
-                OperationError::NotAuthenticated => Ok(HttpResponse::Unauthorized().json(e)),
-                _ => Ok(HttpResponse::InternalServerError().json(e)),
-            },
-        });
+        let res = $state
+            .$qe
+            .send(obj)
+            .from_err()
+            .and_then(move |res| match res {
+                Ok(event_result) => Ok(ok_response(fmt, eventid, event_result)),
+                Err(e) => Ok(error_response(fmt, eventid, e)),
+            });
 
         Box::new(res)
     }};
@@ -135,136 +540,1448 @@ macro_rules! json_event_get {
 fn create(
     (req, state): (HttpRequest<AppState>, State<AppState>),
 ) -> impl Future<Item = HttpResponse, Error = Error> {
-    json_event_post!(req, state, CreateMessage, CreateRequest)
+    json_event_post!(
+        req,
+        state,
+        qe_w,
+        CreateMessage,
+        CreateRequest,
+        get_current_user,
+        RequestLimits::check_create
+    )
 }
 
 fn modify(
     (req, state): (HttpRequest<AppState>, State<AppState>),
 ) -> impl Future<Item = HttpResponse, Error = Error> {
-    json_event_post!(req, state, ModifyMessage, ModifyRequest)
+    json_event_post!(
+        req,
+        state,
+        qe_w,
+        ModifyMessage,
+        ModifyRequest,
+        get_current_user,
+        RequestLimits::check_modify
+    )
+}
+
+fn modify_batch(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(
+        req,
+        state,
+        qe_w,
+        ModifyBatchMessage,
+        ModifyBatchRequest,
+        get_current_user,
+        RequestLimits::check_modify_batch
+    )
 }
 
 fn delete(
     (req, state): (HttpRequest<AppState>, State<AppState>),
 ) -> impl Future<Item = HttpResponse, Error = Error> {
-    json_event_post!(req, state, DeleteMessage, DeleteRequest)
+    json_event_post!(req, state, qe_w, DeleteMessage, DeleteRequest)
 }
 
 fn search(
     (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    if let Some(resp) = throttle_anonymous(&req) {
+        return Box::new(future::ok(resp));
+    }
+    Box::new(json_event_post!(
+        req,
+        state,
+        qe_r,
+        SearchMessage,
+        SearchRequest
+    ))
+}
+
+// How many records of a streamed search or export may be waiting to be sent
+// before the server waits for the client to read them.
+const SEARCH_STREAM_BUFFER: usize = 64;
+
+// Encode one record of a stream, framed as the proto describes.
+fn stream_record<T: Serialize>(fmt: BodyFormat, item: &T) -> Result<Bytes, Error> {
+    match fmt {
+        BodyFormat::Json => {
+            let mut record = serde_json::to_vec(item).map_err(error::ErrorInternalServerError)?;
+            record.push(b'\n');
+            Ok(Bytes::from(record))
+        }
+        BodyFormat::Cbor => {
+            let body = serde_cbor::to_vec(item).map_err(error::ErrorInternalServerError)?;
+            let mut record = Vec::with_capacity(body.len() + 4);
+            record.extend_from_slice(&(body.len() as u32).to_be_bytes());
+            record.extend_from_slice(&body);
+            Ok(Bytes::from(record))
+        }
+    }
+}
+
+// The response is begun once the first record is ready. An error found
+// before then, such as the request not being authenticated, is given its
+// usual status. After that the status has been sent, so an error is given
+// as the last record instead, made by on_error.
+fn stream_response<T: Serialize + 'static>(
+    fmt: BodyFormat,
+    eventid: Uuid,
+    rx: mpsc::Receiver<Result<T, OperationError>>,
+    on_error: fn(ErrorResponse) -> T,
 ) -> impl Future<Item = HttpResponse, Error = Error> {
-    json_event_post!(req, state, SearchMessage, SearchRequest)
+    rx.into_future()
+        .map_err(|_| error::ErrorInternalServerError("stream failed"))
+        .map(move |(first, rest)| {
+            if let Some(Err(e)) = first {
+                return error_response(fmt, eventid, e);
+            }
+            let records = stream::iter_ok(first)
+                .chain(rest)
+                .map_err(|_| error::ErrorInternalServerError("stream failed"))
+                .and_then(move |r| {
+                    let item = r.unwrap_or_else(|e| {
+                        let mut er = ErrorResponse::from(&e);
+                        er.eventid = Some(eventid.to_hyphenated_ref().to_string());
+                        on_error(er)
+                    });
+                    stream_record(fmt, &item)
+                });
+            HttpResponse::Ok()
+                .header(KOPID, eventid.to_hyphenated_ref().to_string())
+                .content_type(match fmt {
+                    BodyFormat::Json => SEARCH_STREAM_JSON,
+                    BodyFormat::Cbor => SEARCH_STREAM_CBOR,
+                })
+                .streaming(records)
+        })
 }
 
-fn whoami(
+fn search_stream(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    if let Some(resp) = throttle_anonymous(&req) {
+        return Box::new(future::ok(resp));
+    }
+    let uat = get_current_user(&req);
+    let eventid = Uuid::new_v4();
+    let content = BodyFormat::of_request(&req);
+    let fmt = BodyFormat::accepted(&req);
+
+    Box::new(read_body(&req, fmt, eventid).and_then(
+        move |body| -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+            let obj = match content.decode::<SearchRequest>(&body) {
+                Ok(obj) => obj,
+                Err(e) => return Box::new(future::err(e)),
+            };
+            let (tx, rx) = mpsc::channel(SEARCH_STREAM_BUFFER);
+            state
+                .qe_r
+                .do_send(SearchStreamMessage::new(eventid, uat, obj, tx));
+
+            Box::new(stream_response(fmt, eventid, rx, SearchStreamItem::Error))
+        },
+    ))
+}
+
+fn search_count(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    if let Some(resp) = throttle_anonymous(&req) {
+        return Box::new(future::ok(resp));
+    }
+    Box::new(json_event_post!(
+        req,
+        state,
+        qe_r,
+        SearchCountMessage,
+        SearchCountRequest
+    ))
+}
+
+fn compare(
     (req, state): (HttpRequest<AppState>, State<AppState>),
 ) -> impl Future<Item = HttpResponse, Error = Error> {
-    json_event_get!(req, state, WhoamiMessage)
+    json_event_post!(req, state, qe_r, CompareMessage, CompareRequest)
 }
 
-// We probably need an extract auth or similar to handle the different
-// types (cookie, bearer), and to generic this over get/post.
+fn access_check(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, qe_w, AccessCheckMessage, AccessCheckRequest)
+}
 
-fn auth(
+fn effective_access(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(
+        req,
+        state,
+        qe_r,
+        EffectiveAccessMessage,
+        EffectiveAccessRequest
+    )
+}
+
+fn audit_list(
     (req, state): (HttpRequest<AppState>, State<AppState>),
 ) -> impl Future<Item = HttpResponse, Error = Error> {
-    let max_size = state.max_size;
+    json_event_post!(req, state, qe_r, AuditListMessage, AuditListRequest)
+}
 
-    req.payload()
+// The change id to list changes since is given in the query, as in
+// /v1/changes?since=<cid>, so there is no body to decode.
+fn changes(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let eventid = Uuid::new_v4();
+    let fmt = BodyFormat::accepted(&req);
+    let uat = get_current_user(&req);
+    // An empty since is the same as none.
+    let since = req.query().get("since").filter(|s| !s.is_empty()).cloned();
+
+    let m_obj = ChangesMessage::new(eventid, uat, ChangesRequest { since: since });
+
+    state
+        .qe_r
+        .send(m_obj)
         .from_err()
-        .fold(BytesMut::new(), move |mut body, chunk| {
-            // limit max size of in-memory payload
-            if (body.len() + chunk.len()) > max_size {
-                Err(error::ErrorBadRequest("overflow"))
-            } else {
-                body.extend_from_slice(&chunk);
-                Ok(body)
+        .and_then(move |res| match res {
+            Ok(event_result) => Ok(ok_response(fmt, eventid, event_result)),
+            Err(e) => Ok(error_response(fmt, eventid, e)),
+        })
+}
+
+fn export(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let eventid = Uuid::new_v4();
+    let fmt = BodyFormat::accepted(&req);
+    let uat = get_current_user(&req);
+    // An empty since is the same as none, which is a full export.
+    let since = req.query().get("since").filter(|s| !s.is_empty()).cloned();
+
+    let (tx, rx) = mpsc::channel(SEARCH_STREAM_BUFFER);
+    state.qe_r.do_send(ExportMessage::new(
+        eventid,
+        uat,
+        ExportRequest { since: since },
+        tx,
+    ));
+
+    stream_response(fmt, eventid, rx, ExportStreamItem::Error)
+}
+
+fn backup(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, qe_r, BackupMessage, BackupRequest)
+}
+
+fn reindex(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, qe_w, ReindexMessage, ReindexRequest)
+}
+
+fn vacuum(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, qe_w, VacuumMessage, VacuumRequest)
+}
+
+fn index_status(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, qe_r, IndexStatusMessage, IndexStatusRequest)
+}
+
+fn schema(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, qe_r, SchemaMessage, SchemaRequest)
+}
+
+fn recycle_bin(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(
+        req,
+        state,
+        qe_r,
+        SearchRecycledMessage,
+        SearchRecycledRequest
+    )
+}
+
+// The entry to revive is named by the path, so there is no body to decode.
+fn recycle_bin_revive(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let eventid = Uuid::new_v4();
+    let fmt = BodyFormat::accepted(&req);
+    let uat = get_current_user(&req);
+    let uuid = req.match_info().get("uuid").unwrap_or("").to_string();
+
+    let obj = ReviveRecycledRequest::new(ProtoFilter::Eq("uuid".to_string(), uuid));
+    let m_obj = ReviveRecycledMessage::new(eventid, uat, obj);
+
+    state
+        .qe_w
+        .send(m_obj)
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(event_result) => Ok(ok_response(fmt, eventid, event_result)),
+            Err(e) => Ok(error_response(fmt, eventid, e)),
+        })
+}
+
+fn whoami(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_get!(
+        req,
+        state,
+        qe_r,
+        WhoamiMessage,
+        get_current_user_unrestricted
+    )
+}
+
+// End the current session. The token is removed from the cookie, but even
+// if a copy was kept it is no longer accepted.
+fn logout(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let eventid = Uuid::new_v4();
+    let fmt = BodyFormat::accepted(&req);
+    let uat = get_current_user_unrestricted(&req);
+
+    state
+        .qe_w
+        .send(LogoutMessage::new(eventid, uat))
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(event_result) => {
+                req.session().remove("uat");
+                Ok(ok_response(fmt, eventid, event_result))
             }
+            Err(e) => Ok(error_response(fmt, eventid, e)),
         })
-        .and_then(
-            move |body| -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
-                let r_obj = serde_json::from_slice::<AuthRequest>(&body);
-
-                // Send to the db for action
-                match r_obj {
-                    Ok(obj) => {
-                        // First, deal with some state management.
-                        // Do anything here first that's needed like getting the session details
-                        // out of the req cookie.
-
-                        // From the actix source errors here
-                        // seems to be related to the serde_json deserialise of the cookie
-                        // content, and because we control it's get/set it SHOULD be fine
-                        // provided we use secure cookies. But we can't always trust that ...
-                        let maybe_sessionid = match req.session().get::<Uuid>("auth-session-id") {
-                            Ok(c) => c,
-                            Err(e) => {
-                                return Box::new(future::err(e));
-                            }
-                        };
+}
 
-                        let auth_msg = AuthMessage::new(obj, maybe_sessionid);
+fn session_list(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, qe_r, SessionListMessage, SessionListRequest)
+}
 
-                        // We probably need to know if we allocate the cookie, that this is a
-                        // new session, and in that case, anything *except* authrequest init is
-                        // invalid.
-                        let res =
-                            state
-                                .qe
-                                .send(auth_msg)
-                                .from_err()
-                                .and_then(move |res| match res {
-                                    Ok(ar) => {
-                                        match &ar.state {
-                                            AuthState::Success(uat) => {
-                                                // Remove the auth-session-id
-                                                req.session().remove("auth-session-id");
-                                                // Set the uat into the cookie
-                                                match req.session().set("uat", uat) {
-                                                    Ok(_) => Ok(HttpResponse::Ok().json(ar)),
-                                                    Err(_) => {
-                                                        Ok(HttpResponse::InternalServerError()
-                                                            .json(()))
-                                                    }
-                                                }
-                                            }
-                                            AuthState::Denied(_) => {
-                                                // Remove the auth-session-id
-                                                req.session().remove("auth-session-id");
-                                                Ok(HttpResponse::Ok().json(ar))
-                                            }
-                                            AuthState::Continue(_) => {
-                                                // Ensure the auth-session-id is set
-                                                match req
-                                                    .session()
-                                                    .set("auth-session-id", ar.sessionid)
-                                                {
-                                                    Ok(_) => Ok(HttpResponse::Ok().json(ar)),
-                                                    Err(_) => {
-                                                        Ok(HttpResponse::InternalServerError()
-                                                            .json(()))
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-                                    Err(e) => Ok(HttpResponse::InternalServerError().json(e)),
-                                });
-                        Box::new(res)
+// The session to revoke is named by the path, so there is no body to decode.
+fn session_revoke(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let eventid = Uuid::new_v4();
+    let fmt = BodyFormat::accepted(&req);
+    let uat = get_current_user(&req);
+    let sessionid = match Uuid::parse_str(req.match_info().get("sessionid").unwrap_or("")) {
+        Ok(u) => u,
+        Err(_) => {
+            return Box::new(future::ok(error_response(
+                fmt,
+                eventid,
+                OperationError::InvalidUuid,
+            )))
+        }
+    };
+
+    let m_obj = SessionRevokeMessage::new(eventid, uat, SessionRevokeRequest::new(sessionid));
+
+    Box::new(
+        state
+            .qe_w
+            .send(m_obj)
+            .from_err()
+            .and_then(move |res| match res {
+                Ok(event_result) => Ok(ok_response(fmt, eventid, event_result)),
+                Err(e) => Ok(error_response(fmt, eventid, e)),
+            }),
+    )
+}
+
+// Generate a totp secret for the authenticated account. There is no body to
+// decode, as the account is that of the session.
+fn totp_generate(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let eventid = Uuid::new_v4();
+    let fmt = BodyFormat::accepted(&req);
+    let uat = get_current_user(&req);
+
+    state
+        .qe_w
+        .send(TOTPGenerateMessage::new(eventid, uat))
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(event_result) => Ok(ok_response(fmt, eventid, event_result)),
+            Err(e) => Ok(error_response(fmt, eventid, e)),
+        })
+}
+
+fn totp_verify(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, qe_w, TOTPVerifyMessage, TOTPVerifyRequest)
+}
+
+// Begin registering a webauthn token to the authenticated account. As with
+// totp, the account is that of the session.
+fn webauthn_generate(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let eventid = Uuid::new_v4();
+    let fmt = BodyFormat::accepted(&req);
+    let uat = get_current_user(&req);
+
+    state
+        .qe_w
+        .send(WebauthnGenerateMessage::new(eventid, uat))
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(event_result) => Ok(ok_response(fmt, eventid, event_result)),
+            Err(e) => Ok(error_response(fmt, eventid, e)),
+        })
+}
+
+fn webauthn_register(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(
+        req,
+        state,
+        qe_w,
+        WebauthnRegisterMessage,
+        WebauthnRegisterRequest
+    )
+}
+
+fn webauthn_list(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_get!(req, state, qe_r, WebauthnListMessage)
+}
+
+fn webauthn_remove(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(
+        req,
+        state,
+        qe_w,
+        WebauthnRemoveMessage,
+        WebauthnRemoveRequest
+    )
+}
+
+// Replace the backup codes of the authenticated account. The codes are only
+// ever shown in this response.
+fn backup_codes_generate(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let eventid = Uuid::new_v4();
+    let fmt = BodyFormat::accepted(&req);
+    let uat = get_current_user(&req);
+
+    state
+        .qe_w
+        .send(BackupCodesGenerateMessage::new(eventid, uat))
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(event_result) => Ok(ok_response(fmt, eventid, event_result)),
+            Err(e) => Ok(error_response(fmt, eventid, e)),
+        })
+}
+
+fn credential_status(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_get!(req, state, qe_r, CredentialStatusMessage)
+}
+
+fn credential_policy(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(
+        req,
+        state,
+        qe_w,
+        CredentialPolicyMessage,
+        CredentialPolicyRequest
+    )
+}
+
+fn credential_change(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(
+        req,
+        state,
+        qe_w,
+        CredentialChangeMessage,
+        CredentialChangeRequest,
+        get_current_user_unrestricted
+    )
+}
+
+// The token is only ever shown in this response.
+fn api_token_generate(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(
+        req,
+        state,
+        qe_w,
+        ApiTokenGenerateMessage,
+        ApiTokenGenerateRequest
+    )
+}
+
+fn api_token_list(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, qe_w, ApiTokenListMessage, ApiTokenListRequest)
+}
+
+// As with sessions, the token to destroy is named by the path.
+fn api_token_destroy(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let eventid = Uuid::new_v4();
+    let fmt = BodyFormat::accepted(&req);
+    let uat = get_current_user(&req);
+    let id = match Uuid::parse_str(req.match_info().get("id").unwrap_or("")) {
+        Ok(u) => u,
+        Err(_) => {
+            return Box::new(future::ok(error_response(
+                fmt,
+                eventid,
+                OperationError::InvalidUuid,
+            )))
+        }
+    };
+    let account = req.match_info().get("account").unwrap_or("");
+
+    let m_obj = ApiTokenDestroyMessage::new(eventid, uat, ApiTokenDestroyRequest::new(account, id));
+
+    Box::new(
+        state
+            .qe_w
+            .send(m_obj)
+            .from_err()
+            .and_then(move |res| match res {
+                Ok(event_result) => Ok(ok_response(fmt, eventid, event_result)),
+                Err(e) => Ok(error_response(fmt, eventid, e)),
+            }),
+    )
+}
+
+// Replace the radius secret of the authenticated account. As with backup
+// codes, the account is that of the session.
+fn radius_secret_generate(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let eventid = Uuid::new_v4();
+    let fmt = BodyFormat::accepted(&req);
+    let uat = get_current_user(&req);
+
+    state
+        .qe_w
+        .send(RadiusSecretGenerateMessage::new(eventid, uat))
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(event_result) => Ok(ok_response(fmt, eventid, event_result)),
+            Err(e) => Ok(error_response(fmt, eventid, e)),
+        })
+}
+
+// The account is named by the path, by name or uuid.
+fn radius_auth_token(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let eventid = Uuid::new_v4();
+    let fmt = BodyFormat::accepted(&req);
+    let uat = get_current_user(&req);
+    let account = req.match_info().get("account").unwrap_or("").to_string();
+
+    state
+        .qe_r
+        .send(RadiusAuthTokenMessage::new(eventid, uat, account))
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(event_result) => Ok(ok_response(fmt, eventid, event_result)),
+            Err(e) => Ok(error_response(fmt, eventid, e)),
+        })
+}
+
+// The ssh public keys of the account named by the path, for sshd to check a
+// login against. Anonymous may ask for these.
+fn ssh_publickeys(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let eventid = Uuid::new_v4();
+    let fmt = BodyFormat::accepted(&req);
+    let uat = get_current_user(&req);
+    let account = req.match_info().get("account").unwrap_or("").to_string();
+
+    state
+        .qe_r
+        .send(SshPublicKeysMessage::new(eventid, uat, account))
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(event_result) => Ok(ok_response(fmt, eventid, event_result)),
+            Err(e) => Ok(error_response(fmt, eventid, e)),
+        })
+}
+
+// The account or group is named by the path, by name or uuid.
+fn account_unix_token(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let eventid = Uuid::new_v4();
+    let fmt = BodyFormat::accepted(&req);
+    let uat = get_current_user(&req);
+    let id = req.match_info().get("id").unwrap_or("").to_string();
+
+    state
+        .qe_r
+        .send(UnixUserTokenMessage::new(eventid, uat, id))
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(event_result) => Ok(ok_response(fmt, eventid, event_result)),
+            Err(e) => Ok(error_response(fmt, eventid, e)),
+        })
+}
+
+fn group_unix_token(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let eventid = Uuid::new_v4();
+    let fmt = BodyFormat::accepted(&req);
+    let uat = get_current_user(&req);
+    let id = req.match_info().get("id").unwrap_or("").to_string();
+
+    state
+        .qe_r
+        .send(UnixGroupTokenMessage::new(eventid, uat, id))
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(event_result) => Ok(ok_response(fmt, eventid, event_result)),
+            Err(e) => Ok(error_response(fmt, eventid, e)),
+        })
+}
+
+// A unix machine checking a unix password for pam. This never begins a
+// session of the account, so the machine's own session is unaffected.
+fn unix_auth(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    json_event_post!(req, state, qe_w, UnixAuthMessage, UnixAuthRequest)
+}
+
+// The public keys that tokens are signed with, so that other services can
+// verify them without asking us.
+fn jwk((req, state): (HttpRequest<AppState>, State<AppState>)) -> HttpResponse {
+    let eventid = Uuid::new_v4();
+    let fmt = BodyFormat::accepted(&req);
+    match state.token_keys.to_jwkset(current_time()) {
+        Ok(jwks) => ok_response(fmt, eventid, jwks),
+        Err(e) => error_response(fmt, eventid, e),
+    }
+}
+
+// Oauth2 clients expect errors in the form the rfc gives, not our own. Nothing
+// about the grant may be cached.
+fn oauth2_error_response(status: http::StatusCode, eventid: Uuid, error: &str) -> HttpResponse {
+    HttpResponse::build(status)
+        .header(KOPID, eventid.to_hyphenated_ref().to_string())
+        .header(http::header::CACHE_CONTROL, "no-store")
+        .json(Oauth2ErrorResponse {
+            error: error.to_string(),
+            error_description: None,
+        })
+}
+
+// The start of an authorization code grant. The browser must already have
+// authenticated to us, and is sent back to the client with a code, or with
+// the reason it was refused once the redirect uri is known to be the
+// client's.
+fn oauth2_authorize(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let eventid = Uuid::new_v4();
+    let fmt = BodyFormat::accepted(&req);
+    let uat = get_current_user(&req);
+    let ar = match Query::<Oauth2AuthorizeRequest>::extract(&req) {
+        Ok(q) => q.into_inner(),
+        Err(_) => {
+            return Box::new(future::ok(oauth2_error_response(
+                http::StatusCode::BAD_REQUEST,
+                eventid,
+                "invalid_request",
+            )))
+        }
+    };
+
+    Box::new(
+        state
+            .qe_w
+            .send(Oauth2AuthorizeMessage::new(eventid, uat, ar))
+            .from_err()
+            .and_then(move |res| match res {
+                Ok(location) => Ok(HttpResponse::Found()
+                    .header(KOPID, eventid.to_hyphenated_ref().to_string())
+                    .header(http::header::LOCATION, location)
+                    .finish()),
+                Err(OperationError::Oauth2(error)) => Ok(oauth2_error_response(
+                    http::StatusCode::BAD_REQUEST,
+                    eventid,
+                    error,
+                )),
+                Err(e) => Ok(error_response(fmt, eventid, e)),
+            }),
+    )
+}
+
+// The client exchanging its code for tokens. The request is form encoded, as
+// the rfc requires, and the tokens are signed with the same keys as our own.
+fn oauth2_token(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let eventid = Uuid::new_v4();
+    let qe_w = state.qe_w.clone();
+    let token_keys = state.token_keys.clone();
+    let issuer = state.issuer.clone();
+
+    req.urlencoded::<Oauth2TokenRequest>()
+        .limit(state.request_limits.body_limit(req.path()))
+        .then(
+            move |r| -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+                let tr = match r {
+                    Ok(tr) => tr,
+                    Err(_) => {
+                        return Box::new(future::ok(oauth2_error_response(
+                            http::StatusCode::BAD_REQUEST,
+                            eventid,
+                            "invalid_request",
+                        )))
                     }
-                    Err(e) => Box::new(future::err(error::ErrorBadRequest(format!(
-                        "Json Decode Failed: {:?}",
-                        e
-                    )))),
-                }
+                };
+                Box::new(
+                    qe_w.send(Oauth2TokenMessage::new(eventid, tr))
+                        .from_err()
+                        .map(move |res| {
+                            match res.and_then(|grant| {
+                                grant.issue(&token_keys, issuer.as_str(), current_time())
+                            }) {
+                                Ok(tokens) => HttpResponse::Ok()
+                                    .header(KOPID, eventid.to_hyphenated_ref().to_string())
+                                    .header(http::header::CACHE_CONTROL, "no-store")
+                                    .json(tokens),
+                                Err(OperationError::Oauth2(error)) => oauth2_error_response(
+                                    http::StatusCode::BAD_REQUEST,
+                                    eventid,
+                                    error,
+                                ),
+                                Err(e) => error_response(BodyFormat::Json, eventid, e),
+                            }
+                        }),
+                )
             },
         )
 }
 
+// The claims of the bearer's access token. The token is only good while the
+// session it was issued from is, so a logout ends it too.
+fn oauth2_userinfo(req: &HttpRequest<AppState>) -> HttpResponse {
+    let eventid = Uuid::new_v4();
+    let state = req.state();
+    let ct = current_time();
+    let at = req
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| v.starts_with("Bearer "))
+        .and_then(|v| Oauth2AccessToken::verify(&state.token_keys, v[7..].trim(), ct))
+        .filter(|at| state.idms.is_session_active(at.sessionid(), ct));
+
+    match at {
+        Some(at) => ok_response(BodyFormat::Json, eventid, at.userinfo()),
+        None => {
+            let mut resp =
+                oauth2_error_response(http::StatusCode::UNAUTHORIZED, eventid, "invalid_token");
+            resp.headers_mut().insert(
+                http::header::WWW_AUTHENTICATE,
+                http::header::HeaderValue::from_static("Bearer error=\"invalid_token\""),
+            );
+            resp
+        }
+    }
+}
+
+// Scim responses are json, with a content type of their own.
+fn scim_response<T: Serialize>(status: http::StatusCode, eventid: Uuid, r: &T) -> HttpResponse {
+    match serde_json::to_vec(r) {
+        Ok(body) => HttpResponse::build(status)
+            .header(KOPID, eventid.to_hyphenated_ref().to_string())
+            .content_type(CONTENT_TYPE_SCIM)
+            .body(body),
+        Err(e) => {
+            error!("Failed to encode scim response: {:?}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+fn scim_error_response(eventid: Uuid, e: ScimError) -> HttpResponse {
+    let status = http::StatusCode::from_u16(e.status_code())
+        .unwrap_or(http::StatusCode::INTERNAL_SERVER_ERROR);
+    let mut resp = scim_response(status, eventid, &e);
+    if status == http::StatusCode::UNAUTHORIZED {
+        resp.headers_mut().insert(
+            http::header::WWW_AUTHENTICATE,
+            http::header::HeaderValue::from_static("Bearer"),
+        );
+    }
+    resp
+}
+
+fn scim_operation_error(eventid: Uuid, e: OperationError) -> HttpResponse {
+    let status = error_status(&e).as_u16();
+    scim_error_response(eventid, scim_gateway::operation_error(&e, status))
+}
+
+// Each step of a scim request fails with the response to give, so that the
+// steps can be chained and the first failure answered.
+type ScimStep<T> = Box<dyn Future<Item = T, Error = HttpResponse>>;
+
+fn scim_send<M, R>(qe: &actix::Addr<QueryServerV1>, eventid: Uuid, m: M) -> ScimStep<R>
+where
+    M: actix::Message<Result = Result<R, OperationError>> + Send + 'static,
+    R: Send + 'static,
+    QueryServerV1: actix::Handler<M>,
+    <QueryServerV1 as Actor>::Context: ToEnvelope<QueryServerV1, M>,
+{
+    Box::new(qe.send(m).then(move |r| match r {
+        Ok(Ok(r)) => Ok(r),
+        Ok(Err(e)) => Err(scim_operation_error(eventid, e)),
+        Err(_) => Err(scim_operation_error(eventid, OperationError::InvalidState)),
+    }))
+}
+
+fn scim_finish(
+    step: ScimStep<HttpResponse>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    Box::new(step.then(|r| -> Result<HttpResponse, Error> {
+        match r {
+            Ok(resp) | Err(resp) => Ok(resp),
+        }
+    }))
+}
+
+// What each step of a scim request needs from it. There is no session, as
+// the client gives its api token as a bearer token with every request.
+#[derive(Clone)]
+struct ScimRequest {
+    eventid: Uuid,
+    kind: ScimKind,
+    qe_r: actix::Addr<QueryServerV1>,
+    qe_w: actix::Addr<QueryServerV1>,
+    // Where the resources are, for their locations.
+    base: String,
+    token: Option<String>,
+}
+
+impl ScimRequest {
+    fn new(kind: ScimKind, req: &HttpRequest<AppState>) -> Self {
+        let ci = req.connection_info();
+        let token = req
+            .headers()
+            .get(http::header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| v.len() > 7 && v[..7].eq_ignore_ascii_case("bearer "))
+            .map(|v| v[7..].trim().to_string());
+        ScimRequest {
+            eventid: Uuid::new_v4(),
+            kind: kind,
+            qe_r: req.state().qe_r.clone(),
+            qe_w: req.state().qe_w.clone(),
+            base: format!("{}://{}/scim/v2", ci.scheme(), ci.host()),
+            token: token,
+        }
+    }
+
+    fn fail<T: 'static>(&self, e: ScimError) -> ScimStep<T> {
+        Box::new(future::err(scim_error_response(self.eventid, e)))
+    }
+
+    fn auth(&self) -> ScimStep<UserAuthToken> {
+        match &self.token {
+            Some(token) => scim_send(
+                &self.qe_r,
+                self.eventid,
+                ApiTokenAuthMessage::new(self.eventid, token.clone()),
+            ),
+            None => self.fail(ScimError::new(401, None, "A bearer token is required")),
+        }
+    }
+
+    fn search(&self, uat: &UserAuthToken, sr: SearchRequest) -> ScimStep<Vec<ProtoEntry>> {
+        Box::new(
+            scim_send(
+                &self.qe_r,
+                self.eventid,
+                SearchMessage::new(self.eventid, Some(uat.clone()), sr),
+            )
+            .map(|r| r.entries),
+        )
+    }
+
+    // The resources of entries. The members of a group are given by name,
+    // so their uuids are searched for as well.
+    fn resources(&self, uat: &UserAuthToken, entries: Vec<ProtoEntry>) -> ScimStep<Vec<JsonValue>> {
+        let base = self.base.clone();
+        let names: BTreeSet<String> = match self.kind {
+            ScimKind::User => {
+                let ct = current_time();
+                return Box::new(future::ok(
+                    entries
+                        .iter()
+                        .filter_map(|e| scim_gateway::entry_to_user(e, base.as_str(), ct))
+                        .filter_map(|u| serde_json::to_value(u).ok())
+                        .collect(),
+                ));
+            }
+            ScimKind::Group => entries
+                .iter()
+                .flat_map(scim_gateway::member_names)
+                .collect(),
+        };
+        let members: ScimStep<BTreeMap<String, String>> = if names.is_empty() {
+            Box::new(future::ok(BTreeMap::new()))
+        } else {
+            let f = ProtoFilter::Or(
+                names
+                    .into_iter()
+                    .map(|n| ProtoFilter::Eq("name".to_string(), n))
+                    .collect(),
+            );
+            let sr = SearchRequest::new_with_attrs(f, vec!["name".to_string(), "uuid".to_string()]);
+            Box::new(self.search(uat, sr).map(|found| {
+                found
+                    .into_iter()
+                    .filter_map(|e| {
+                        let name = e.attrs.get("name")?.first()?.clone();
+                        let uuid = e.attrs.get("uuid")?.first()?.clone();
+                        Some((name, uuid))
+                    })
+                    .collect()
+            }))
+        };
+        Box::new(members.map(move |uuids| {
+            entries
+                .iter()
+                .filter_map(|e| scim_gateway::entry_to_group(e, &uuids, base.as_str()))
+                .filter_map(|g| serde_json::to_value(g).ok())
+                .collect()
+        }))
+    }
+
+    // The resource with this id, as the client now sees it.
+    fn get(&self, uat: &UserAuthToken, f: ProtoFilter) -> ScimStep<JsonValue> {
+        let sr = self.clone();
+        let eventid = self.eventid;
+        let uat = uat.clone();
+        Box::new(
+            self.search(&uat, SearchRequest::new(f))
+                .and_then(move |entries| sr.resources(&uat, entries))
+                .and_then(move |mut rs| {
+                    if rs.is_empty() {
+                        Err(scim_error_response(
+                            eventid,
+                            ScimError::new(404, None, "Resource not found"),
+                        ))
+                    } else {
+                        Ok(rs.remove(0))
+                    }
+                }),
+        )
+    }
+}
+
+// The body of a request, decoded as the kind of resource it's for.
+fn scim_body<T: 'static>(
+    req: &HttpRequest<AppState>,
+    sr: &ScimRequest,
+    decode: fn(ScimKind, &[u8]) -> Result<T, ScimError>,
+) -> ScimStep<T> {
+    let eventid = sr.eventid;
+    let kind = sr.kind;
+    Box::new(
+        req.body()
+            .limit(req.state().request_limits.body_limit(req.path()))
+            .then(move |r| match r {
+                Ok(body) => decode(kind, &body).map_err(|e| scim_error_response(eventid, e)),
+                Err(e) => Err(scim_error_response(
+                    eventid,
+                    ScimError::invalid_syntax(format!("{:?}", e).as_str()),
+                )),
+            }),
+    )
+}
+
+// A list of resources, with the filters clients use to find a resource, and
+// pages counted from one as scim does.
+fn scim_list(
+    kind: ScimKind,
+    req: &HttpRequest<AppState>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let sr = ScimRequest::new(kind, req);
+    let query = req.query();
+    let f = match scim_gateway::search_filter(kind, query.get("filter").map(|f| f.as_str())) {
+        Ok(f) => f,
+        Err(e) => return scim_finish(sr.fail(e)),
+    };
+    let start = query
+        .get("startIndex")
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(1)
+        .max(1);
+    let count = query.get("count").and_then(|s| s.parse::<usize>().ok());
+
+    scim_finish(Box::new(sr.auth().and_then(move |uat| {
+        let eventid = sr.eventid;
+        sr.search(&uat, SearchRequest::new(f))
+            .and_then(move |entries| {
+                let total = entries.len();
+                let page = entries
+                    .into_iter()
+                    .skip(start - 1)
+                    .take(count.unwrap_or(total))
+                    .collect();
+                sr.resources(&uat, page).map(move |rs| (rs, total))
+            })
+            .map(move |(rs, total)| {
+                scim_response(
+                    http::StatusCode::OK,
+                    eventid,
+                    &ScimListResponse::new(rs, total, start),
+                )
+            })
+    })))
+}
+
+fn scim_get(
+    kind: ScimKind,
+    req: &HttpRequest<AppState>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let sr = ScimRequest::new(kind, req);
+    let f = match scim_gateway::id_filter(kind, req.match_info().get("id").unwrap_or("")) {
+        Ok(f) => f,
+        Err(e) => return scim_finish(sr.fail(e)),
+    };
+
+    let eventid = sr.eventid;
+    scim_finish(Box::new(
+        sr.auth()
+            .and_then(move |uat| sr.get(&uat, f))
+            .map(move |r| scim_response(http::StatusCode::OK, eventid, &r)),
+    ))
+}
+
+// The resource is created, and given back with where it can be found.
+fn scim_create(
+    kind: ScimKind,
+    req: &HttpRequest<AppState>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let sr = ScimRequest::new(kind, req);
+    let eventid = sr.eventid;
+    let request_limits = req.state().request_limits.clone();
+    let body = scim_body(req, &sr, scim_gateway::decode_create).and_then(move |entry| {
+        let cr = CreateRequest::new(vec![entry]);
+        request_limits
+            .check_create(&cr)
+            .map(|_| cr)
+            .map_err(|e| scim_operation_error(eventid, e))
+    });
+
+    scim_finish(Box::new(body.and_then(move |cr| {
+        sr.auth().and_then(move |uat| {
+            scim_send(
+                &sr.qe_w,
+                eventid,
+                CreateMessage::new(eventid, Some(uat.clone()), cr),
+            )
+            .and_then(move |cr| {
+                let id = cr.uuids.first().cloned().unwrap_or_default();
+                let created = match scim_gateway::id_filter(kind, id.as_str()) {
+                    Ok(f) => sr.get(&uat, f),
+                    Err(e) => sr.fail(e),
+                };
+                created.map(move |r| {
+                    let location = format!("{}/{}/{}", sr.base, kind.endpoint(), id);
+                    let mut resp = scim_response(http::StatusCode::CREATED, eventid, &r);
+                    if let Ok(v) = http::header::HeaderValue::from_str(location.as_str()) {
+                        resp.headers_mut().insert(http::header::LOCATION, v);
+                    }
+                    resp
+                })
+            })
+        })
+    })))
+}
+
+// Both put and patch are modifications of the resource, which is then given
+// back. A patch of only what we don't keep changes nothing.
+fn scim_modify(
+    kind: ScimKind,
+    req: &HttpRequest<AppState>,
+    decode: fn(ScimKind, &[u8]) -> Result<ProtoModifyList, ScimError>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let sr = ScimRequest::new(kind, req);
+    let eventid = sr.eventid;
+    let f = match scim_gateway::id_filter(kind, req.match_info().get("id").unwrap_or("")) {
+        Ok(f) => f,
+        Err(e) => return scim_finish(sr.fail(e)),
+    };
+    let request_limits = req.state().request_limits.clone();
+    let mf = f.clone();
+    let body = scim_body(req, &sr, decode).and_then(move |modlist| {
+        let mr = ModifyRequest::new(mf, modlist);
+        request_limits
+            .check_modify(&mr)
+            .map(|_| mr)
+            .map_err(|e| scim_operation_error(eventid, e))
+    });
+
+    scim_finish(Box::new(body.and_then(move |mr| {
+        sr.auth().and_then(move |uat| {
+            let modified: ScimStep<()> = if mr.modlist.mods.is_empty() {
+                Box::new(future::ok(()))
+            } else {
+                Box::new(
+                    scim_send(
+                        &sr.qe_w,
+                        eventid,
+                        ModifyMessage::new(eventid, Some(uat.clone()), mr),
+                    )
+                    .map(|_| ()),
+                )
+            };
+            modified
+                .and_then(move |_| sr.get(&uat, f))
+                .map(move |r| scim_response(http::StatusCode::OK, eventid, &r))
+        })
+    })))
+}
+
+fn scim_replace(
+    kind: ScimKind,
+    req: &HttpRequest<AppState>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    scim_modify(kind, req, scim_gateway::decode_replace)
+}
+
+fn scim_patch(
+    kind: ScimKind,
+    req: &HttpRequest<AppState>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    scim_modify(kind, req, scim_gateway::decode_patch)
+}
+
+fn scim_delete(
+    kind: ScimKind,
+    req: &HttpRequest<AppState>,
+) -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+    let sr = ScimRequest::new(kind, req);
+    let eventid = sr.eventid;
+    let f = match scim_gateway::id_filter(kind, req.match_info().get("id").unwrap_or("")) {
+        Ok(f) => f,
+        Err(e) => return scim_finish(sr.fail(e)),
+    };
+
+    scim_finish(Box::new(sr.auth().and_then(move |uat| {
+        scim_send(
+            &sr.qe_w,
+            eventid,
+            DeleteMessage::new(eventid, Some(uat), DeleteRequest::new(f)),
+        )
+        .map(move |_| {
+            HttpResponse::NoContent()
+                .header(KOPID, eventid.to_hyphenated_ref().to_string())
+                .finish()
+        })
+    })))
+}
+
+// Anyone who can reach this may scrape it, so it can be served on its own
+// address with metrics_address.
+fn scrape_metrics(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let eventid = Uuid::new_v4();
+    let fmt = BodyFormat::accepted(&req);
+    let metrics = state.metrics.clone();
+    metrics.set_rate_limit_sources(state.rate_limiter.sources());
+
+    state
+        .qe_r
+        .send(EntryCountMessage::new(eventid))
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(entries) => Ok(HttpResponse::Ok()
+                .content_type("text/plain; version=0.0.4")
+                .body(metrics.render(entries))),
+            Err(e) => Ok(error_response(fmt, eventid, e)),
+        })
+}
+
+// The probes for orchestration. Neither needs authentication, as they only
+// say which checks passed.
+fn health_response(eventid: Uuid, hr: HealthResponse) -> HttpResponse {
+    let mut resp = if hr.healthy {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::ServiceUnavailable()
+    };
+    resp.header(KOPID, eventid.to_hyphenated_ref().to_string())
+        .json(hr)
+}
+
+// Being answered at all shows the runtime is working, so this checks
+// nothing else. In particular it doesn't wait on the database.
+fn status_live(_req: &HttpRequest<AppState>) -> HttpResponse {
+    let mut checks = BTreeMap::new();
+    checks.insert("runtime".to_string(), HealthCheck::new_healthy());
+    health_response(Uuid::new_v4(), HealthResponse::new(checks))
+}
+
+fn status_ready(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let eventid = Uuid::new_v4();
+    let fmt = BodyFormat::accepted(&req);
+
+    state
+        .qe_r
+        .send(ReadinessMessage::new(eventid))
+        .from_err()
+        .and_then(move |res| match res {
+            Ok(hr) => Ok(health_response(eventid, hr)),
+            Err(e) => Ok(error_response(fmt, eventid, e)),
+        })
+}
+
+// We probably need an extract auth or similar to handle the different
+// types (cookie, bearer), and to generic this over get/post.
+
+// Keep the cookie session in step with the auth session. On success the
+// signed token is set, and given in the response too, and the auth session
+// id is kept only while there are more steps to go.
+fn auth_response(req: &HttpRequest<AppState>, eventid: Uuid, mut ar: AuthResponse) -> HttpResponse {
+    let fmt = BodyFormat::accepted(req);
+    match &ar.state {
+        AuthState::Success(uat) => {
+            req.state().metrics.record_auth(true);
+            // Remove the auth-session-id
+            req.session().remove("auth-session-id");
+            // Set the signed uat into the cookie
+            let token = match req.state().token_keys.sign_uat(uat) {
+                Ok(token) => token,
+                Err(e) => return error_response(fmt, eventid, e),
+            };
+            match req.session().set("uat", token.clone()) {
+                Ok(_) => {
+                    ar.token = Some(token);
+                    ok_response(fmt, eventid, ar)
+                }
+                Err(_) => HttpResponse::InternalServerError().json(()),
+            }
+        }
+        AuthState::Denied(_, _) => {
+            req.state().metrics.record_auth(false);
+            // Remove the auth-session-id
+            req.session().remove("auth-session-id");
+            ok_response(fmt, eventid, ar)
+        }
+        AuthState::Continue(_) => {
+            // Ensure the auth-session-id is set
+            match req.session().set("auth-session-id", ar.sessionid) {
+                Ok(_) => ok_response(fmt, eventid, ar),
+                Err(_) => HttpResponse::InternalServerError().json(()),
+            }
+        }
+    }
+}
+
+fn auth(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let eventid = Uuid::new_v4();
+    let fmt = BodyFormat::accepted(&req);
+    let content = BodyFormat::of_request(&req);
+
+    read_body(&req, fmt, eventid).and_then(
+        move |body| -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+            let r_obj = content.decode::<AuthRequest>(&body);
+
+            // Send to the db for action
+            match r_obj {
+                Ok(obj) => {
+                    // Only the beginning of an authentication is limited,
+                    // so one that's underway can always be finished.
+                    if let AuthStep::Init(_, _) = &obj.step {
+                        if let Some(resp) = throttle(&req, RateLimitKind::Auth, eventid) {
+                            return Box::new(future::ok(resp));
+                        }
+                    }
+
+                    // First, deal with some state management.
+                    // Do anything here first that's needed like getting the session details
+                    // out of the req cookie.
+
+                    // From the actix source errors here
+                    // seems to be related to the serde_json deserialise of the cookie
+                    // content, and because we control it's get/set it SHOULD be fine
+                    // provided we use secure cookies. But we can't always trust that ...
+                    let maybe_sessionid = match req.session().get::<Uuid>("auth-session-id") {
+                        Ok(c) => c,
+                        Err(e) => {
+                            return Box::new(future::err(e));
+                        }
+                    };
+
+                    let source = client_address(&req).map(|a| a.to_string());
+                    let auth_msg = AuthMessage::new(eventid, obj, maybe_sessionid, source);
+
+                    // We probably need to know if we allocate the cookie, that this is a
+                    // new session, and in that case, anything *except* authrequest init is
+                    // invalid.
+                    let res = state
+                        .qe_w
+                        .send(auth_msg)
+                        .from_err()
+                        .and_then(move |res| match res {
+                            Ok(ar) => Ok(auth_response(&req, eventid, ar)),
+                            Err(e) => Ok(error_response(fmt, eventid, e)),
+                        });
+                    Box::new(res)
+                }
+                Err(e) => Box::new(future::err(e)),
+            }
+        },
+    )
+}
+
+// Begin giving the credentials of the current session again. The steps
+// that follow are made to /v1/auth as usual.
+fn reauth(
+    (req, state): (HttpRequest<AppState>, State<AppState>),
+) -> impl Future<Item = HttpResponse, Error = Error> {
+    let eventid = Uuid::new_v4();
+    let fmt = BodyFormat::accepted(&req);
+    let content = BodyFormat::of_request(&req);
+    let uat = get_current_user(&req);
+
+    read_body(&req, fmt, eventid).and_then(
+        move |body| -> Box<dyn Future<Item = HttpResponse, Error = Error>> {
+            match content.decode::<ReauthRequest>(&body) {
+                Ok(obj) => {
+                    let source = client_address(&req).map(|a| a.to_string());
+                    let reauth_msg = ReauthMessage::new(eventid, uat, obj, source);
+                    let res =
+                        state
+                            .qe_w
+                            .send(reauth_msg)
+                            .from_err()
+                            .and_then(move |res| match res {
+                                Ok(ar) => Ok(auth_response(&req, eventid, ar)),
+                                Err(e) => Ok(error_response(fmt, eventid, e)),
+                            });
+                    Box::new(res)
+                }
+                Err(e) => Box::new(future::err(e)),
+            }
+        },
+    )
+}
+
+// While the server runs it keeps this file beside the database, so the tools
+// that replace the database can refuse to run beneath it. It's removed when
+// the server stops, but is left behind if it crashes, and must then be
+// removed by hand.
+fn server_lock_path(config: &Configuration) -> Option<String> {
+    if config.db_path.is_empty() {
+        // The database is in memory, so nothing else can reach it.
+        None
+    } else {
+        Some(format!("{}.lock", config.db_path))
+    }
+}
+
+// For the commands that change the database, which must not run beneath the
+// server.
+fn refuse_if_running(config: &Configuration, action: &str) {
+    if let Some(p) = server_lock_path(config) {
+        if Path::new(p.as_str()).exists() {
+            error!(
+                "Refusing to {} while the server is running. If it is not, remove {}",
+                action, p
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+pub struct ServerLock {
+    path: Option<String>,
+}
+
+impl ServerLock {
+    pub fn acquire(config: &Configuration) -> Result<Self, OperationError> {
+        let path = server_lock_path(config);
+        if let Some(p) = &path {
+            if Path::new(p).exists() {
+                warn!(
+                    "{} exists, so a server may already be running on this database, or did not stop cleanly",
+                    p
+                );
+            }
+            fs::write(p, format!("{}\n", std::process::id())).map_err(|e| {
+                error!("Failed to write {}: {:?}", p, e);
+                OperationError::FsError
+            })?;
+        }
+        Ok(ServerLock { path: path })
+    }
+}
+
+impl Drop for ServerLock {
+    fn drop(&mut self) {
+        if let Some(p) = &self.path {
+            let _ = fs::remove_file(p);
+        }
+    }
+}
+
 fn setup_backend(config: &Configuration) -> Result<Backend, OperationError> {
     let mut audit_be = AuditScope::new("backend_setup");
-    let pool_size: u32 = config.threads as u32;
-    let be = Backend::new(&mut audit_be, config.db_path.as_str(), pool_size);
+    // Each reader has a connection, and so does the writer.
+    let pool_size: u32 = config.threads as u32 + 1;
+    let be = Backend::new(&mut audit_be, config.db_path.as_str(), pool_size).map(|mut be| {
+        be.set_cache_size(config.cache_entries, config.cache_idls);
+        be
+    });
     // debug!
     debug!("{}", audit_be);
     be
@@ -278,6 +1995,7 @@ fn setup_qs_idms(
     audit: &mut AuditScope,
     be: Backend,
     sid: SID,
+    config: &Configuration,
 ) -> Result<(QueryServer, IdmServer), OperationError> {
     // Create "just enough" schema for us to be able to load from
     // disk ... Schema loading is one time where we validate the
@@ -291,7 +2009,16 @@ fn setup_qs_idms(
     };
 
     // Create a query_server implementation
-    let query_server = QueryServer::new(be, schema);
+    let mut query_server = QueryServer::new(be, schema);
+    query_server.set_filter_limits(
+        config.filter_limits.clone(),
+        config.filter_limits_anonymous.clone(),
+    );
+    query_server.set_reauth_policy(ReauthPolicy::new_within(match config.reauth_within {
+        0 => None,
+        w => Some(std::time::Duration::from_secs(w)),
+    }));
+    query_server.set_domain_name(config.domain.as_str());
 
     // TODO #62: Should the IDM parts be broken out to the IdmServer?
     // What's important about this initial setup here is that it also triggers
@@ -303,9 +2030,19 @@ fn setup_qs_idms(
     // Write it out if changes are needed.
     query_server.initialise_helper(audit)?;
 
+    // Derived work that was deferred by a write is lost if the server stopped
+    // before it was applied. Find and generate again whatever it was.
+    query_server.repair(audit)?;
+
     // We generate a SINGLE idms only!
 
-    let idms = IdmServer::new(query_server.clone(), sid);
+    let mut idms = IdmServer::new(query_server.clone(), sid);
+    idms.set_session_lifetime(config.session_lifetime);
+    idms.set_lockout_policy(config.auth_lockout_threshold, config.auth_lockout_window);
+    idms.set_webauthn_config(WebauthnConfig::new(
+        config.domain.as_str(),
+        config.webauthn_origin().as_str(),
+    ));
 
     Ok((query_server, idms))
 }
@@ -333,7 +2070,35 @@ pub fn backup_server_core(config: Configuration, dst_path: &str) {
     // Let the txn abort, even on success.
 }
 
-pub fn restore_server_core(config: Configuration, dst_path: &str) {
+pub fn restore_server_core(config: Configuration, src_path: &str, dry_run: bool) {
+    let mut audit = AuditScope::new("server_restore");
+
+    if dry_run {
+        // Only the backup is read, so this can run beside a live server.
+        let r = read_backup(&mut audit, src_path);
+        debug!("{}", audit);
+        match r {
+            Ok(backup) => {
+                info!(
+                    "Would restore {} entries and {} audit records from a version {} backup",
+                    backup.entries.len(),
+                    backup.auditlog.len(),
+                    backup.version
+                );
+                for (class, count) in backup.class_counts() {
+                    info!("  {}: {}", class, count);
+                }
+            }
+            Err(e) => {
+                error!("Restore would fail: {:?}", e);
+                std::process::exit(1);
+            }
+        };
+        return;
+    }
+
+    refuse_if_running(&config, "restore");
+
     let be = match setup_backend(&config) {
         Ok(be) => be,
         Err(e) => {
@@ -341,16 +2106,28 @@ pub fn restore_server_core(config: Configuration, dst_path: &str) {
             return;
         }
     };
-    let mut audit = AuditScope::new("backend_restore");
+    // setup the qs - without initialise, as restoring does that itself.
+    let schema_mem = match Schema::new(&mut audit) {
+        Ok(sc) => sc,
+        Err(e) => {
+            error!("Failed to setup in memory schema: {:?}", e);
+            return;
+        }
+    };
+    let server = QueryServer::new(be, schema_mem);
 
-    let be_wr_txn = be.write();
-    let r = be_wr_txn
-        .restore(&mut audit, dst_path)
-        .and_then(|_| be_wr_txn.commit());
+    let r = server.restore(&mut audit, src_path);
     debug!("{}", audit);
 
     match r {
         Ok(_) => info!("Restore success!"),
+        Err(OperationError::ConsistencyError(errs)) => {
+            for er in errs {
+                error!("{:?}", er);
+            }
+            error!("Restore failed: the restored database is inconsistent");
+            std::process::exit(1);
+        }
         Err(e) => {
             error!("Restore failed: {:?}", e);
             std::process::exit(1);
@@ -358,6 +2135,95 @@ pub fn restore_server_core(config: Configuration, dst_path: &str) {
     };
 }
 
+pub fn reindex_server_core(config: Configuration) {
+    let mut audit = AuditScope::new("server_reindex");
+    let be = match setup_backend(&config) {
+        Ok(be) => be,
+        Err(e) => {
+            error!("Failed to setup BE: {:?}", e);
+            return;
+        }
+    };
+    // setup the qs - without initialise, as the schema to index by is read
+    // from the database.
+    let schema_mem = match Schema::new(&mut audit) {
+        Ok(sc) => sc,
+        Err(e) => {
+            error!("Failed to setup in memory schema: {:?}", e);
+            return;
+        }
+    };
+    let server = QueryServer::new(be, schema_mem);
+
+    info!("Reindexing ...");
+    // The indexes are only replaced when this commits, so a failure or a
+    // crash part way through leaves the old ones.
+    let mut qs_write = server.write();
+    let r = qs_write
+        .reindex(&mut audit)
+        .and_then(|counts| qs_write.commit(&mut audit).map(|_| counts));
+    debug!("{}", audit);
+
+    match r {
+        Ok(counts) => {
+            for (attr, count) in counts.iter() {
+                info!("  {}: {} keys", attr, count);
+            }
+            info!(
+                "Reindex success! {} indexes, {} keys",
+                counts.len(),
+                counts.values().sum::<usize>()
+            );
+        }
+        Err(e) => {
+            error!("Reindex failed: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+}
+
+pub fn vacuum_server_core(config: Configuration) {
+    let mut audit = AuditScope::new("server_vacuum");
+
+    // The file is rebuilt outside of any transaction, so nothing else may
+    // be using it.
+    if let Some(p) = server_lock_path(&config) {
+        if Path::new(p.as_str()).exists() {
+            error!(
+                "Refusing to vacuum while the server is running. If it is not, remove {}",
+                p
+            );
+            std::process::exit(1);
+        }
+    }
+
+    let be = match setup_backend(&config) {
+        Ok(be) => be,
+        Err(e) => {
+            error!("Failed to setup BE: {:?}", e);
+            return;
+        }
+    };
+
+    let r = be.vacuum(&mut audit);
+    debug!("{}", audit);
+
+    match r {
+        Ok(_) => info!("Vacuum success!"),
+        Err(OperationError::ConsistencyError(errs)) => {
+            for er in errs {
+                error!("{:?}", er);
+            }
+            error!("Vacuum failed: the database file is damaged");
+            std::process::exit(1);
+        }
+        Err(e) => {
+            error!("Vacuum failed: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+}
+
 pub fn reset_sid_core(config: Configuration) {
     // Setup the be
     let be = match setup_backend(&config) {
@@ -371,7 +2237,7 @@ pub fn reset_sid_core(config: Configuration) {
     info!("New Server ID: {:?}", nsid);
 }
 
-pub fn verify_server_core(config: Configuration) {
+pub fn verify_server_core(config: Configuration, repair: bool, checks: &[VerifyCheck]) {
     let mut audit = AuditScope::new("server_verify");
     // Setup the be
     let be = match setup_backend(&config) {
@@ -392,7 +2258,20 @@ pub fn verify_server_core(config: Configuration) {
     let server = QueryServer::new(be, schema_mem);
 
     // Run verifications.
-    let r = server.verify(&mut audit);
+    let mut r = server.verify_checks(&mut audit, checks);
+
+    // Only what the plugins derive can be repaired, and the full schema must
+    // be loaded to write it.
+    if r.len() != 0 && repair {
+        info!("Repairing derived state ...");
+        match server
+            .initialise_helper(&mut audit)
+            .and_then(|_| server.repair(&mut audit))
+        {
+            Ok(_) => r = server.verify_checks(&mut audit, checks),
+            Err(e) => error!("Repair failed -> {:?}", e),
+        }
+    }
 
     debug!("{}", audit);
 
@@ -400,7 +2279,7 @@ pub fn verify_server_core(config: Configuration) {
         std::process::exit(0);
     } else {
         for er in r {
-            error!("{:?}", er);
+            error!("{}", er);
         }
         std::process::exit(1);
     }
@@ -408,37 +2287,41 @@ pub fn verify_server_core(config: Configuration) {
     // Now add IDM server verifications?
 }
 
-pub fn recover_account_core(config: Configuration, name: String, password: String) {
+// Set a generated password on the account, which is returned to be shown
+// to the operator.
+pub fn recover_account_core(config: Configuration, name: String) -> String {
     let mut audit = AuditScope::new("recover_account");
+    refuse_if_running(&config, "recover an account");
 
     // Start the backend.
     let be = match setup_backend(&config) {
         Ok(be) => be,
         Err(e) => {
             error!("Failed to setup BE: {:?}", e);
-            return;
+            std::process::exit(1);
         }
     };
     let server_id = be.get_db_sid();
     // setup the qs - *with* init of the migrations and schema.
-    let (_qs, idms) = match setup_qs_idms(&mut audit, be, server_id) {
+    let (_qs, idms) = match setup_qs_idms(&mut audit, be, server_id, &config) {
         Ok(t) => t,
         Err(e) => {
             debug!("{}", audit);
             error!("Unable to setup query server or idm server -> {:?}", e);
-            return;
+            std::process::exit(1);
         }
     };
 
     // Run the password change.
     let mut idms_prox_write = idms.proxy_write();
-    match idms_prox_write.recover_account(&mut audit, name, password) {
-        Ok(_) => {
+    match idms_prox_write.recover_account_local(&mut audit, name) {
+        Ok(password) => {
             idms_prox_write
                 .commit(&mut audit)
                 .expect("A critical error during commit occured.");
             debug!("{}", audit);
             info!("Password reset!");
+            password
         }
         Err(e) => {
             error!("Error during password reset -> {:?}", e);
@@ -447,6 +2330,134 @@ pub fn recover_account_core(config: Configuration, name: String, password: Strin
             std::mem::drop(idms_prox_write);
             std::process::exit(1);
         }
+    }
+}
+
+// The domain info, as the admin socket gives it. Opening the database
+// applies any migrations, so this can't run beneath the server either.
+pub fn domain_info_core(config: Configuration) -> DomainInfo {
+    refuse_if_running(&config, "open the database");
+    let be = match setup_backend(&config) {
+        Ok(be) => be,
+        Err(e) => {
+            error!("Failed to setup BE: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+    let mut audit = AuditScope::new("domain_info");
+    let server_id = be.get_db_sid();
+    let (qs, _idms) = match setup_qs_idms(&mut audit, be, server_id, &config) {
+        Ok(t) => t,
+        Err(e) => {
+            debug!("{}", audit);
+            error!("Unable to setup query server or idm server -> {:?}", e);
+            std::process::exit(1);
+        }
+    };
+    let r = read_domain_info(&mut audit, &qs.read());
+    debug!("{}", audit);
+    match r {
+        Ok(di) => di,
+        Err(e) => {
+            error!("Failed to read the domain info -> {:?}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Repair the database while the server is stopped. The schema must be loaded
+// to write, so this starts the server as far as loading it.
+pub fn repair_server_core(config: Configuration, dry_run: bool) -> RepairResponse {
+    refuse_if_running(&config, "repair the database");
+    let mut audit = AuditScope::new("server_repair");
+    let be = match setup_backend(&config) {
+        Ok(be) => be,
+        Err(e) => {
+            error!("Failed to setup BE: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+    let schema_mem = match Schema::new(&mut audit) {
+        Ok(sc) => sc,
+        Err(e) => {
+            error!("Failed to setup in memory schema: {:?}", e);
+            std::process::exit(1);
+        }
+    };
+    let server = QueryServer::new(be, schema_mem);
+    let r = server
+        .initialise_helper(&mut audit)
+        .and_then(|_| server.repair_consistency(&mut audit, dry_run));
+    debug!("{}", audit);
+    match r {
+        Ok(rr) => rr,
+        Err(e) => {
+            error!("Repair failed -> {:?}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// The version each part of the database is at, and the version this server
+// supports. The database is only read, so this is safe beneath the server,
+// and shows whether it would be upgraded before it's opened.
+pub fn db_version_core(config: Configuration) -> Vec<(&'static str, i64, i64)> {
+    let mut audit = AuditScope::new("db_version");
+    let r = read_db_versions(&mut audit, config.db_path.as_str());
+    debug!("{}", audit);
+    match r {
+        Ok(versions) => versions,
+        Err(e) => {
+            error!("Failed to read the database versions -> {:?}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+pub fn rotate_token_key_core(config: Configuration) {
+    let mut audit = AuditScope::new("rotate_token_key");
+
+    // Start the backend.
+    let be = match setup_backend(&config) {
+        Ok(be) => be,
+        Err(e) => {
+            error!("Failed to setup BE: {:?}", e);
+            return;
+        }
+    };
+    let server_id = be.get_db_sid();
+    let (_qs, idms) = match setup_qs_idms(&mut audit, be, server_id, &config) {
+        Ok(t) => t,
+        Err(e) => {
+            debug!("{}", audit);
+            error!("Unable to setup query server or idm server -> {:?}", e);
+            return;
+        }
+    };
+
+    // The new key takes effect when the server is next started. Tokens
+    // signed by the old key remain valid for one session lifetime after
+    // this point.
+    let mut idms_prox_write = idms.proxy_write();
+    match idms_prox_write.rotate_token_keys(
+        &mut audit,
+        current_time(),
+        std::time::Duration::from_secs(config.session_lifetime),
+    ) {
+        Ok(_) => {
+            idms_prox_write
+                .commit(&mut audit)
+                .expect("A critical error during commit occured.");
+            debug!("{}", audit);
+            info!("Token signing key rotated!");
+        }
+        Err(e) => {
+            error!("Error during token key rotation -> {:?}", e);
+            debug!("{}", audit);
+            // abort the txn
+            std::mem::drop(idms_prox_write);
+            std::process::exit(1);
+        }
     };
 }
 
@@ -464,7 +2475,17 @@ pub fn create_server_core(config: Configuration) {
     let log_addr = async_log::start();
 
     // Setup TLS (if any)
-    let opt_tls_params = match setup_tls(&config) {
+    let tls_certs = match &config.tls_config {
+        Some(tls_config) => match TlsCertificates::load(tls_config) {
+            Ok(tls_certs) => Some(tls_certs),
+            Err(e) => {
+                error!("Failed to load TLS certificates -> {}", e);
+                return;
+            }
+        },
+        None => None,
+    };
+    let opt_tls_params = match tls_certs.as_ref().map(|c| c.acceptor()).transpose() {
         Ok(opt_tls_params) => opt_tls_params,
         Err(e) => {
             error!("Failed to configure TLS parameters -> {:?}", e);
@@ -476,20 +2497,22 @@ pub fn create_server_core(config: Configuration) {
     // server as they come in.
 
     // Setup the be for the qs.
-    let be = match setup_backend(&config) {
+    let metrics = Arc::new(Metrics::new());
+    let mut be = match setup_backend(&config) {
         Ok(be) => be,
         Err(e) => {
             error!("Failed to setup BE -> {:?}", e);
             return;
         }
     };
+    be.set_metrics(metrics.clone());
 
     let server_id = be.get_db_sid();
     info!("Server ID -> {:?}", server_id);
 
     let mut audit = AuditScope::new("setup_qs_idms");
     // Start the IDM server.
-    let (qs, idms) = match setup_qs_idms(&mut audit, be, server_id) {
+    let (qs, idms) = match setup_qs_idms(&mut audit, be, server_id, &config) {
         Ok(t) => t,
         Err(e) => {
             debug!("{}", audit);
@@ -527,29 +2550,180 @@ pub fn create_server_core(config: Configuration) {
         }
         None => {}
     }
+    // Tokens signed by a rotated out key are accepted for as long as a
+    // session could last.
+    let token_grace = std::time::Duration::from_secs(config.session_lifetime);
+    let token_keys = {
+        let mut idms_prox_write = idms.proxy_write();
+        let token_keys = match idms_prox_write.get_or_create_token_keys(&mut audit, token_grace) {
+            Ok(token_keys) => token_keys,
+            Err(e) => {
+                debug!("{}", audit);
+                error!("Unable to load token signing keys -> {:?}", e);
+                return;
+            }
+        };
+        match idms_prox_write.commit(&mut audit) {
+            Ok(_) => {}
+            Err(e) => {
+                debug!("{}", audit);
+                error!("Unable to commit token signing keys -> {:?}", e);
+                return;
+            }
+        }
+        Arc::new(token_keys)
+    };
     log_addr.do_send(audit);
 
     // Pass it to the actor for threading.
     // Start the query server with the given be path: future config
-    let server_addr = QueryServerV1::start(log_addr.clone(), qs, idms, config.threads);
+    let idms = Arc::new(idms);
+    let server_read_addr = QueryServerV1::start(
+        log_addr.clone(),
+        qs.clone(),
+        idms.clone(),
+        config.online_backup.clone(),
+        config.threads,
+    );
+    let server_write_addr = QueryServerV1::start(
+        log_addr.clone(),
+        qs,
+        idms.clone(),
+        config.online_backup.clone(),
+        1,
+    );
+
+    // The admin socket is served before anything else that listens, so the
+    // server can be recovered through it whatever else is wrong.
+    if let Some(admin_socket) = &config.admin_socket {
+        if start_admin_socket(
+            admin_socket.as_str(),
+            server_read_addr.clone(),
+            server_write_addr.clone(),
+        )
+        .is_err()
+        {
+            return;
+        }
+    }
 
     // Setup timed events
-    let _int_addr = IntervalActor::new(server_addr.clone()).start();
+    let _int_addr = IntervalActor::new(
+        server_read_addr.clone(),
+        server_write_addr.clone(),
+        config.recycle_bin_max_age,
+        config.tombstone_max_age,
+        config.changelog_max_age,
+        config.online_backup.clone(),
+    )
+    .start();
+
+    // Every listener shares the rate limits, so that a source can't go around
+    // them by using another.
+    let rate_limiter = Arc::new(RateLimiter::new(config.rate_limits.clone(), current_time()));
+
+    // The ldap gateway has a listener of its own, so it needs its own tls
+    // acceptor. Binds send the password as given, so only the integration
+    // tests may go without.
+    if let Some(ldap_address) = &config.ldapaddress {
+        let ldap_tls = match tls_certs.as_ref().map(|c| c.acceptor()).transpose() {
+            Ok(Some(tls_params)) => Some(tls_params.build()),
+            Ok(None) if config.integration_test_config.is_some() => None,
+            Ok(None) => {
+                error!("The LDAP gateway requires TLS parameters");
+                return;
+            }
+            Err(e) => {
+                error!("Failed to configure LDAP TLS parameters -> {:?}", e);
+                return;
+            }
+        };
+        let ldap = LdapServer {
+            gateway: LdapGateway::new(config.domain.as_str(), &config.ldap_attr_map),
+            qe_r: server_read_addr.clone(),
+            qe_w: server_write_addr.clone(),
+            idms: idms.clone(),
+            metrics: metrics.clone(),
+            rate_limiter: rate_limiter.clone(),
+        };
+        if start_ldap_server(ldap_address.as_str(), ldap_tls, ldap).is_err() {
+            return;
+        }
+    }
 
-    // Copy the max size
-    let max_size = config.maximum_request;
+    let request_limits = config.request_limits.clone();
     let secure_cookies = config.secure_cookies;
+    let session_lifetime = config.session_lifetime;
     // let domain = config.domain.clone();
     let cookie_key: [u8; 32] = config.cookie_key.clone();
+    let issuer = config.webauthn_origin();
+
+    // The servers are stopped by the shutdown actor, not by actix-web's own
+    // signal handling, so that the writer can be flushed after them.
+    let mut http_servers = Vec::new();
+    let shutdown_timeout =
+        std::cmp::min(config.shutdown_grace_period, u64::from(u16::max_value())) as u16;
+    let shutdown_write_addr = server_write_addr.clone();
+
+    // Metrics are served apart from everything else if they have their own
+    // address.
+    let serve_metrics = match &config.metrics_address {
+        Some(metrics_address) => {
+            let server_read_addr = server_read_addr.clone();
+            let server_write_addr = server_write_addr.clone();
+            let token_keys = token_keys.clone();
+            let idms = idms.clone();
+            let metrics = metrics.clone();
+            let rate_limiter = rate_limiter.clone();
+            let request_limits = request_limits.clone();
+            let issuer = issuer.clone();
+            let metrics_builder = actix_web::server::new(move || {
+                App::with_state(AppState {
+                    qe_r: server_read_addr.clone(),
+                    qe_w: server_write_addr.clone(),
+                    request_limits: request_limits.clone(),
+                    token_keys: token_keys.clone(),
+                    idms: idms.clone(),
+                    metrics: metrics.clone(),
+                    rate_limiter: rate_limiter.clone(),
+                    issuer: issuer.clone(),
+                })
+                .resource("/metrics", |r| {
+                    r.method(http::Method::GET).with_async(scrape_metrics)
+                })
+            });
+            match metrics_builder.bind(metrics_address) {
+                Ok(mb) => {
+                    http_servers.push(
+                        mb.disable_signals()
+                            .shutdown_timeout(shutdown_timeout)
+                            .start(),
+                    );
+                }
+                Err(e) => {
+                    error!("Failed to bind metrics address -> {:?}", e);
+                    return;
+                }
+            }
+            false
+        }
+        None => true,
+    };
 
     // start the web server
     let aws_builder = actix_web::server::new(move || {
         App::with_state(AppState {
-            qe: server_addr.clone(),
-            max_size: max_size,
+            qe_r: server_read_addr.clone(),
+            qe_w: server_write_addr.clone(),
+            request_limits: request_limits.clone(),
+            token_keys: token_keys.clone(),
+            idms: idms.clone(),
+            metrics: metrics.clone(),
+            rate_limiter: rate_limiter.clone(),
+            issuer: issuer.clone(),
         })
         // Connect all our end points here.
-        .middleware(middleware::Logger::default())
+        .middleware(RequestMetrics)
         .middleware(session::SessionStorage::new(
             // Signed prevents tampering. this 32 byte key MUST
             // be generated (probably a cli option, and it's up to the
@@ -561,8 +2735,8 @@ pub fn create_server_core(config: Configuration) {
             session::CookieSessionBackend::signed(&cookie_key)
                 // Limit to path?
                 // .path("/")
-                // TODO #63: make this configurable!
-                .max_age(Duration::hours(1))
+                // The cookie lives as long as the token it carries.
+                .max_age(Duration::seconds(session_lifetime as i64))
                 // .domain(domain.as_str())
                 // .same_site(cookie::SameSite::Strict) // constrain to the domain
                 // Disallow from js and ...?
@@ -575,6 +2749,121 @@ pub fn create_server_core(config: Configuration) {
         .resource("/v1/whoami", |r| {
             r.method(http::Method::GET).with_async(whoami)
         })
+        .resource("/v1/jwk", |r| r.method(http::Method::GET).with(jwk))
+        .resource("/oauth2/authorize", |r| {
+            r.method(http::Method::GET).with_async(oauth2_authorize)
+        })
+        .resource("/oauth2/token", |r| {
+            r.method(http::Method::POST).with_async(oauth2_token)
+        })
+        .resource("/oauth2/userinfo", |r| {
+            r.method(http::Method::GET).f(oauth2_userinfo)
+        })
+        .resource("/scim/v2/Users", |r| {
+            r.method(http::Method::GET)
+                .f(|req| scim_list(ScimKind::User, req));
+            r.method(http::Method::POST)
+                .f(|req| scim_create(ScimKind::User, req));
+        })
+        .resource("/scim/v2/Users/{id}", |r| {
+            r.method(http::Method::GET)
+                .f(|req| scim_get(ScimKind::User, req));
+            r.method(http::Method::PUT)
+                .f(|req| scim_replace(ScimKind::User, req));
+            r.method(http::Method::PATCH)
+                .f(|req| scim_patch(ScimKind::User, req));
+            r.method(http::Method::DELETE)
+                .f(|req| scim_delete(ScimKind::User, req));
+        })
+        .resource("/scim/v2/Groups", |r| {
+            r.method(http::Method::GET)
+                .f(|req| scim_list(ScimKind::Group, req));
+            r.method(http::Method::POST)
+                .f(|req| scim_create(ScimKind::Group, req));
+        })
+        .resource("/scim/v2/Groups/{id}", |r| {
+            r.method(http::Method::GET)
+                .f(|req| scim_get(ScimKind::Group, req));
+            r.method(http::Method::PUT)
+                .f(|req| scim_replace(ScimKind::Group, req));
+            r.method(http::Method::PATCH)
+                .f(|req| scim_patch(ScimKind::Group, req));
+            r.method(http::Method::DELETE)
+                .f(|req| scim_delete(ScimKind::Group, req));
+        })
+        .resource("/status", |r| r.method(http::Method::GET).f(status_live))
+        .resource("/status/ready", |r| {
+            r.method(http::Method::GET).with_async(status_ready)
+        })
+        .resource("/v1/logout", |r| {
+            r.method(http::Method::POST).with_async(logout)
+        })
+        .resource("/v1/sessions", |r| {
+            r.method(http::Method::POST).with_async(session_list)
+        })
+        .resource("/v1/sessions/{sessionid}/_revoke", |r| {
+            r.method(http::Method::POST).with_async(session_revoke)
+        })
+        .resource("/v1/self/_credential/totp/_generate", |r| {
+            r.method(http::Method::POST).with_async(totp_generate)
+        })
+        .resource("/v1/self/_credential/totp/_verify", |r| {
+            r.method(http::Method::POST).with_async(totp_verify)
+        })
+        .resource("/v1/self/_credential/webauthn", |r| {
+            r.method(http::Method::GET).with_async(webauthn_list)
+        })
+        .resource("/v1/self/_credential/webauthn/_generate", |r| {
+            r.method(http::Method::POST).with_async(webauthn_generate)
+        })
+        .resource("/v1/self/_credential/webauthn/_register", |r| {
+            r.method(http::Method::POST).with_async(webauthn_register)
+        })
+        .resource("/v1/self/_credential/webauthn/_remove", |r| {
+            r.method(http::Method::POST).with_async(webauthn_remove)
+        })
+        .resource("/v1/self/_credential/backup_codes/_generate", |r| {
+            r.method(http::Method::POST)
+                .with_async(backup_codes_generate)
+        })
+        .resource("/v1/self/_credential/_status", |r| {
+            r.method(http::Method::GET).with_async(credential_status)
+        })
+        .resource("/v1/self/_credential/_policy", |r| {
+            r.method(http::Method::POST).with_async(credential_policy)
+        })
+        .resource("/v1/self/_credential/radius/_generate", |r| {
+            r.method(http::Method::POST)
+                .with_async(radius_secret_generate)
+        })
+        .resource("/v1/account/{account}/_radius/_token", |r| {
+            r.method(http::Method::GET).with_async(radius_auth_token)
+        })
+        .resource("/v1/account/{account}/_ssh_pubkeys", |r| {
+            r.method(http::Method::GET).with_async(ssh_publickeys)
+        })
+        .resource("/v1/account/{id}/_unix/_token", |r| {
+            r.method(http::Method::GET).with_async(account_unix_token)
+        })
+        .resource("/v1/group/{id}/_unix/_token", |r| {
+            r.method(http::Method::GET).with_async(group_unix_token)
+        })
+        .resource("/v1/unix/_auth", |r| {
+            r.method(http::Method::POST).with_async(unix_auth)
+        })
+        .resource("/v1/credential/_change", |r| {
+            r.method(http::Method::POST).with_async(credential_change)
+        })
+        .resource("/v1/service_account/_api_token/_generate", |r| {
+            r.method(http::Method::POST).with_async(api_token_generate)
+        })
+        .resource("/v1/service_account/_api_token/_list", |r| {
+            r.method(http::Method::POST).with_async(api_token_list)
+        })
+        .resource(
+            "/v1/service_account/{account}/_api_token/{id}/_destroy",
+            |r| r.method(http::Method::POST).with_async(api_token_destroy),
+        )
         // .resource("/v1/login", ...)
         // .resource("/v1/logout", ...)
         // .resource("/v1/token", ...) generate a token for id servers to use
@@ -588,15 +2877,75 @@ pub fn create_server_core(config: Configuration) {
         .resource("/v1/modify", |r| {
             r.method(http::Method::POST).with_async(modify)
         })
+        .resource("/v1/modify/batch", |r| {
+            r.method(http::Method::POST).with_async(modify_batch)
+        })
         .resource("/v1/delete", |r| {
             r.method(http::Method::POST).with_async(delete)
         })
         .resource("/v1/search", |r| {
             r.method(http::Method::POST).with_async(search)
         })
+        .resource("/v1/search/_stream", |r| {
+            r.method(http::Method::POST).with_async(search_stream)
+        })
+        .resource("/v1/search/count", |r| {
+            r.method(http::Method::POST).with_async(search_count)
+        })
+        .resource("/v1/compare", |r| {
+            r.method(http::Method::POST).with_async(compare)
+        })
+        .resource("/v1/access/_check", |r| {
+            r.method(http::Method::POST).with_async(access_check)
+        })
+        .resource("/v1/access/_effective", |r| {
+            r.method(http::Method::POST).with_async(effective_access)
+        })
+        .resource("/v1/audit/_list", |r| {
+            r.method(http::Method::POST).with_async(audit_list)
+        })
+        .resource("/v1/changes", |r| {
+            r.method(http::Method::GET).with_async(changes)
+        })
+        .resource("/v1/export", |r| {
+            r.method(http::Method::GET).with_async(export)
+        })
+        .resource("/v1/backup", |r| {
+            r.method(http::Method::POST).with_async(backup)
+        })
+        .resource("/v1/reindex", |r| {
+            r.method(http::Method::POST).with_async(reindex)
+        })
+        .resource("/v1/vacuum", |r| {
+            r.method(http::Method::POST).with_async(vacuum)
+        })
+        .resource("/v1/index/_status", |r| {
+            r.method(http::Method::POST).with_async(index_status)
+        })
+        .resource("/v1/schema", |r| {
+            r.method(http::Method::POST).with_async(schema)
+        })
+        .resource("/v1/recycle_bin", |r| {
+            r.method(http::Method::POST).with_async(recycle_bin)
+        })
+        .resource("/v1/recycle_bin/{uuid}/_revive", |r| {
+            r.method(http::Method::POST).with_async(recycle_bin_revive)
+        })
         .resource("/v1/auth", |r| {
             r.method(http::Method::POST).with_async(auth)
         })
+        .resource("/v1/auth/reauth", |r| {
+            r.method(http::Method::POST).with_async(reauth)
+        })
+        .configure(|app| {
+            if serve_metrics {
+                app.resource("/metrics", |r| {
+                    r.method(http::Method::GET).with_async(scrape_metrics)
+                })
+            } else {
+                app
+            }
+        })
         // Add an ldap compat search function type?
         /*
         .resource("/v1/list/{class_list}", |r| {
@@ -613,7 +2962,22 @@ pub fn create_server_core(config: Configuration) {
         }
     };
 
-    tls_aws_builder
-        .expect("Failed to initialise server!")
-        .start();
+    http_servers.push(
+        tls_aws_builder
+            .expect("Failed to initialise server!")
+            .disable_signals()
+            .shutdown_timeout(shutdown_timeout)
+            .start(),
+    );
+    ShutdownActor::new(
+        http_servers,
+        shutdown_write_addr,
+        config.shutdown_grace_period,
+    )
+    .start();
+
+    // The certificates are loaded again on SIGHUP, such as after a renewal.
+    if let Some(tls_certs) = tls_certs {
+        TlsReloadActor::new(tls_certs).start();
+    }
 }
@@ -0,0 +1,146 @@
+use crate::be::dbvalue::DbApiTokenV1;
+use crate::credential::Password;
+use kanidm_proto::v1::ApiTokenInfo;
+
+use rand::prelude::*;
+use std::convert::TryFrom;
+use std::time::Duration;
+use uuid::Uuid;
+
+// The secret is 256 bits, so the token can't be guessed.
+const API_TOKEN_SECRET_LEN: usize = 32;
+
+// A long lived token that a service account authenticates with, in place of
+// a password. The token is given to the client as "<id>.<secret>", so that
+// only the token named by the id needs to be checked. Only a hash of the
+// secret is kept.
+#[derive(Clone, Debug)]
+pub struct ApiToken {
+    pub(crate) id: Uuid,
+    pub(crate) label: String,
+    secret: Password,
+    // Times are seconds since the unix epoch.
+    pub(crate) created: u64,
+    pub(crate) expiry: Option<u64>,
+    pub(crate) last_used: Option<u64>,
+    // Without this, sessions of the token may only read.
+    pub(crate) read_write: bool,
+}
+
+impl TryFrom<DbApiTokenV1> for ApiToken {
+    type Error = ();
+
+    fn try_from(value: DbApiTokenV1) -> Result<Self, Self::Error> {
+        Ok(ApiToken {
+            id: value.u,
+            label: value.l,
+            secret: Password::try_from(value.s)?,
+            created: value.c,
+            expiry: value.e,
+            last_used: value.lu,
+            read_write: value.rw,
+        })
+    }
+}
+
+impl ApiToken {
+    // Generate a new token at ct, returning it with the token to give to
+    // the client. This is the only time the secret is available.
+    pub fn generate(
+        label: &str,
+        expiry: Option<u64>,
+        read_write: bool,
+        ct: Duration,
+    ) -> (Self, String) {
+        let mut rng = rand::thread_rng();
+        let raw: Vec<u8> = (0..API_TOKEN_SECRET_LEN).map(|_| rng.gen()).collect();
+        let secret = base64::encode_config(&raw, base64::URL_SAFE_NO_PAD);
+        let id = Uuid::new_v4();
+        let token = format!("{}.{}", id.to_hyphenated_ref(), secret);
+        (
+            ApiToken {
+                id: id,
+                label: label.to_string(),
+                secret: Password::new_token_secret(secret.as_str()),
+                created: ct.as_secs(),
+                expiry: expiry,
+                last_used: None,
+                read_write: read_write,
+            },
+            token,
+        )
+    }
+
+    // Split a token given by a client into the id and the secret.
+    pub fn parse(token: &str) -> Option<(Uuid, &str)> {
+        let mut parts = token.trim().splitn(2, '.');
+        let id = parts.next().and_then(|i| Uuid::parse_str(i).ok())?;
+        let secret = parts.next().filter(|s| !s.is_empty())?;
+        Some((id, secret))
+    }
+
+    pub fn is_expired(&self, ct: Duration) -> bool {
+        match self.expiry {
+            Some(e) => ct.as_secs() >= e,
+            None => false,
+        }
+    }
+
+    pub fn verify(&self, secret: &str) -> bool {
+        self.secret.verify(secret)
+    }
+
+    pub fn set_last_used(&self, ct: Duration) -> Self {
+        ApiToken {
+            last_used: Some(ct.as_secs()),
+            ..self.clone()
+        }
+    }
+
+    pub fn to_proto(&self) -> ApiTokenInfo {
+        ApiTokenInfo {
+            id: self.id,
+            label: self.label.clone(),
+            created: self.created,
+            expiry: self.expiry,
+            last_used: self.last_used,
+            read_write: self.read_write,
+        }
+    }
+
+    pub(crate) fn to_dbapitokenv1(&self) -> DbApiTokenV1 {
+        DbApiTokenV1 {
+            u: self.id,
+            l: self.label.clone(),
+            s: self.secret.to_dbpasswordv1(),
+            c: self.created,
+            e: self.expiry,
+            lu: self.last_used,
+            rw: self.read_write,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::credential::apitoken::ApiToken;
+    use std::time::Duration;
+
+    #[test]
+    fn test_apitoken_generate_verify() {
+        let ct = Duration::from_secs(6000);
+        let (at, token) = ApiToken::generate("backup", Some(7000), false, ct);
+        assert!(at.created == 6000);
+
+        let (id, secret) = ApiToken::parse(token.as_str()).expect("Failed to parse token");
+        assert!(id == at.id);
+        assert!(at.verify(secret));
+        assert!(!at.verify("not the secret"));
+
+        assert!(!at.is_expired(ct));
+        assert!(at.is_expired(Duration::from_secs(7000)));
+
+        assert!(ApiToken::parse("not a token").is_none());
+        assert!(ApiToken::parse(format!("{}.", id).as_str()).is_none());
+    }
+}
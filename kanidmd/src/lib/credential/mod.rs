@@ -0,0 +1,674 @@
+use crate::be::dbvalue::{DbCredPolicyV1, DbCredV1, DbPasswordV1};
+use crate::credential::totp::TOTP;
+use crate::credential::webauthn::{counter_valid, WebauthnToken};
+use kanidm_proto::v1::{CredentialPolicy, OperationError, REDACTED};
+use openssl::hash::MessageDigest;
+use openssl::pkcs5::{pbkdf2_hmac, scrypt};
+use rand::prelude::*;
+use std::convert::TryFrom;
+use std::fmt;
+use uuid::Uuid;
+
+pub mod apitoken;
+pub mod strength;
+pub mod totp;
+pub mod webauthn;
+
+// Which combination of factors a credential requires to authenticate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Policy {
+    PasswordOnly,
+    // The password, and then any one of the second factors.
+    PasswordMFA,
+    WebauthnOnly,
+}
+
+impl Policy {
+    pub fn to_proto(&self) -> CredentialPolicy {
+        match self {
+            Policy::PasswordOnly => CredentialPolicy::PasswordOnly,
+            Policy::PasswordMFA => CredentialPolicy::PasswordMFA,
+            Policy::WebauthnOnly => CredentialPolicy::WebauthnOnly,
+        }
+    }
+
+    fn to_dbcredpolicyv1(&self) -> DbCredPolicyV1 {
+        match self {
+            Policy::PasswordOnly => DbCredPolicyV1::PasswordOnly,
+            Policy::PasswordMFA => DbCredPolicyV1::PasswordMFA,
+            Policy::WebauthnOnly => DbCredPolicyV1::WebauthnOnly,
+        }
+    }
+}
+
+impl From<CredentialPolicy> for Policy {
+    fn from(p: CredentialPolicy) -> Self {
+        match p {
+            CredentialPolicy::PasswordOnly => Policy::PasswordOnly,
+            CredentialPolicy::PasswordMFA => Policy::PasswordMFA,
+            CredentialPolicy::WebauthnOnly => Policy::WebauthnOnly,
+        }
+    }
+}
+
+impl From<DbCredPolicyV1> for Policy {
+    fn from(p: DbCredPolicyV1) -> Self {
+        match p {
+            DbCredPolicyV1::PasswordOnly => Policy::PasswordOnly,
+            DbCredPolicyV1::PasswordMFA => Policy::PasswordMFA,
+            DbCredPolicyV1::WebauthnOnly => Policy::WebauthnOnly,
+        }
+    }
+}
+
+// TODO: Determine this at startup based on a time factor
+const PBKDF2_COST: usize = 10000;
+// NIST 800-63.b salt should be 112 bits -> 14  8u8.
+// I choose tinfoil hat though ...
+const PBKDF2_SALT_LEN: usize = 24;
+// 64 * u8 -> 512 bits of out.
+const PBKDF2_KEY_LEN: usize = 64;
+// scrypt parameters for new passwords. N = 2^14 and r = 8 needs 16MiB of
+// memory per hash, which makes offline guessing far more costly than pbkdf2.
+const SCRYPT_N: u64 = 16384;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+const SCRYPT_MAXMEM: u64 = 32 * 1024 * 1024;
+const SCRYPT_SALT_LEN: usize = 24;
+const SCRYPT_KEY_LEN: usize = 64;
+// How many backup codes are generated, and their length. The alphabet leaves
+// out characters that are easily confused when written down.
+pub const BACKUP_CODE_COUNT: usize = 8;
+const BACKUP_CODE_LEN: usize = 10;
+const BACKUP_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+// Radius secrets may be typed into devices, so share the alphabet, and are
+// long enough to be as strong as a random key.
+const RADIUS_SECRET_LEN: usize = 48;
+const RECOVERY_PASSWORD_LEN: usize = 24;
+
+// Why PBKDF2? Rust's bcrypt has a number of hardcodings like max pw len of 72
+// I don't really feel like adding in so many restrictions, so I'll use
+// pbkdf2 in openssl because it doesn't have the same limits. New passwords
+// are now hashed with scrypt, which is memory hard, but pbkdf2 hashes are
+// still verified so that existing passwords keep working.
+#[derive(Clone, Debug)]
+enum KDF {
+    //     cost, salt,   hash
+    PBKDF2(usize, Vec<u8>, Vec<u8>),
+    //     n,   r,   p,   salt,    hash
+    SCRYPT(u64, u32, u32, Vec<u8>, Vec<u8>),
+}
+
+#[derive(Clone)]
+pub struct Password {
+    material: KDF,
+}
+
+// Only the kdf, as the salt and hash are enough to try guesses offline.
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.material {
+            KDF::PBKDF2(..) => write!(f, "Password(PBKDF2 {})", REDACTED),
+            KDF::SCRYPT(..) => write!(f, "Password(SCRYPT {})", REDACTED),
+        }
+    }
+}
+
+impl TryFrom<DbPasswordV1> for Password {
+    type Error = ();
+
+    fn try_from(value: DbPasswordV1) -> Result<Self, Self::Error> {
+        match value {
+            DbPasswordV1::PBKDF2(c, s, h) => Ok(Password {
+                material: KDF::PBKDF2(c, s, h),
+            }),
+            DbPasswordV1::SCRYPT(n, r, p, s, h) => Ok(Password {
+                material: KDF::SCRYPT(n, r, p, s, h),
+            }),
+        }
+    }
+}
+
+impl Password {
+    fn new_pbkdf2(cleartext: &str) -> KDF {
+        let mut rng = rand::thread_rng();
+        let salt: Vec<u8> = (0..PBKDF2_SALT_LEN).map(|_| rng.gen()).collect();
+        // This is 512 bits of output
+        let mut key: Vec<u8> = (0..PBKDF2_KEY_LEN).map(|_| 0).collect();
+
+        pbkdf2_hmac(
+            cleartext.as_bytes(),
+            salt.as_slice(),
+            PBKDF2_COST,
+            MessageDigest::sha256(),
+            key.as_mut_slice(),
+        )
+        .expect("PBKDF2 failure");
+        // Turn key to a vec.
+        KDF::PBKDF2(PBKDF2_COST, salt, key)
+    }
+
+    fn new_scrypt(cleartext: &str) -> KDF {
+        let mut rng = rand::thread_rng();
+        let salt: Vec<u8> = (0..SCRYPT_SALT_LEN).map(|_| rng.gen()).collect();
+        let mut key: Vec<u8> = (0..SCRYPT_KEY_LEN).map(|_| 0).collect();
+
+        scrypt(
+            cleartext.as_bytes(),
+            salt.as_slice(),
+            SCRYPT_N,
+            SCRYPT_R as u64,
+            SCRYPT_P as u64,
+            SCRYPT_MAXMEM,
+            key.as_mut_slice(),
+        )
+        .expect("scrypt failure");
+        KDF::SCRYPT(SCRYPT_N, SCRYPT_R, SCRYPT_P, salt, key)
+    }
+
+    pub fn new(cleartext: &str) -> Self {
+        Password {
+            material: Self::new_scrypt(cleartext),
+        }
+    }
+
+    // Backup codes are long and random, and every stored code is checked on
+    // each attempt, so they don't need (or want) a memory hard kdf.
+    fn new_backup_code(cleartext: &str) -> Self {
+        Password {
+            material: Self::new_pbkdf2(cleartext),
+        }
+    }
+
+    // As are the secrets of api tokens, which are checked on every auth of
+    // a service account.
+    pub(crate) fn new_token_secret(cleartext: &str) -> Self {
+        Password {
+            material: Self::new_pbkdf2(cleartext),
+        }
+    }
+
+    pub fn verify(&self, cleartext: &str) -> bool {
+        match &self.material {
+            KDF::PBKDF2(cost, salt, key) => {
+                let mut chal_key: Vec<u8> = (0..PBKDF2_KEY_LEN).map(|_| 0).collect();
+                pbkdf2_hmac(
+                    cleartext.as_bytes(),
+                    salt.as_slice(),
+                    *cost,
+                    MessageDigest::sha256(),
+                    chal_key.as_mut_slice(),
+                )
+                .expect("PBKDF2 failure");
+                // Actually compare the outputs.
+                &chal_key == key
+            }
+            KDF::SCRYPT(n, r, p, salt, key) => {
+                let mut chal_key: Vec<u8> = (0..key.len()).map(|_| 0).collect();
+                // A stored hash we can't recompute, such as one with
+                // parameters past our memory limit, never verifies.
+                scrypt(
+                    cleartext.as_bytes(),
+                    salt.as_slice(),
+                    *n,
+                    *r as u64,
+                    *p as u64,
+                    SCRYPT_MAXMEM,
+                    chal_key.as_mut_slice(),
+                )
+                .map(|_| &chal_key == key)
+                .unwrap_or(false)
+            }
+        }
+    }
+
+    pub(crate) fn to_dbpasswordv1(&self) -> DbPasswordV1 {
+        match &self.material {
+            KDF::PBKDF2(cost, salt, hash) => {
+                DbPasswordV1::PBKDF2(*cost, salt.clone(), hash.clone())
+            }
+            KDF::SCRYPT(n, r, p, salt, hash) => {
+                DbPasswordV1::SCRYPT(*n, *r, *p, salt.clone(), hash.clone())
+            }
+        }
+    }
+}
+
+// Codes are shown as two groups of five, but may be given back with any
+// case or separators.
+pub(crate) fn normalise_backup_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+fn generate_backup_code() -> String {
+    let mut rng = rand::thread_rng();
+    let code: String = (0..BACKUP_CODE_LEN)
+        .map(|_| BACKUP_CODE_ALPHABET[rng.gen_range(0, BACKUP_CODE_ALPHABET.len())] as char)
+        .collect();
+    format!(
+        "{}-{}",
+        &code[..BACKUP_CODE_LEN / 2],
+        &code[BACKUP_CODE_LEN / 2..]
+    )
+}
+
+// Radius needs the cleartext, so unlike the other secrets here this is kept
+// as is, rather than hashed.
+pub(crate) fn generate_radius_secret() -> String {
+    let mut rng = rand::thread_rng();
+    (0..RADIUS_SECRET_LEN)
+        .map(|_| BACKUP_CODE_ALPHABET[rng.gen_range(0, BACKUP_CODE_ALPHABET.len())] as char)
+        .collect()
+}
+
+// Given to an operator who recovers an account. It's long enough to be
+// strong without any other kind of character.
+pub(crate) fn generate_recovery_password() -> String {
+    let mut rng = rand::thread_rng();
+    (0..RECOVERY_PASSWORD_LEN)
+        .map(|_| BACKUP_CODE_ALPHABET[rng.gen_range(0, BACKUP_CODE_ALPHABET.len())] as char)
+        .collect()
+}
+
+#[derive(Clone, Debug)]
+/// This is how we store credentials in the server. An account can have many credentials, and
+/// a credential can have many factors. Only successful auth to a credential as a whole unit
+/// will succeed. For example:
+/// A: Credential { password: aaa }
+/// B: Credential { password: bbb, otp: ... }
+/// In this case, if we selected credential B, and then provided password "aaa" we would deny
+/// the auth as the password of B was incorrect. Additionally, while A only needs the "password",
+/// B requires both the password and otp to be valid.
+///
+/// In this way, each Credential provides it's own password requirements and policy, and requires
+/// some metadata to support this such as it's source and strength etc. Some of these details are
+/// to be resolved ...
+pub struct Credential {
+    // Source (machine, user, ....). Strength?
+    pub(crate) password: Option<Password>,
+    pub(crate) totp: Option<TOTP>,
+    pub(crate) webauthn: Vec<WebauthnToken>,
+    // Each backup code is hashed as a password is, and removed once used.
+    pub(crate) backup_codes: Vec<Password>,
+    // If None, the policy follows from the factors the credential has.
+    pub(crate) policy: Option<Policy>,
+    // Set by an administrative reset, the password must be changed before
+    // the account can be used for anything else.
+    pub(crate) must_change: bool,
+    pub(crate) claims: Vec<String>,
+    // Uuid of Credential, used by auth session to lock this specific credential
+    // if required.
+    pub(crate) uuid: Uuid,
+    // TODO: Add auth policy IE validUntil, lock state ...
+    // locked: bool
+}
+
+impl TryFrom<DbCredV1> for Credential {
+    type Error = ();
+
+    fn try_from(value: DbCredV1) -> Result<Self, Self::Error> {
+        // Work out what the policy is?
+        let DbCredV1 {
+            password,
+            totp,
+            webauthn,
+            backup_codes,
+            policy,
+            must_change,
+            claims,
+            uuid,
+        } = value;
+
+        let v_password = match password {
+            Some(dbp) => Some(Password::try_from(dbp)?),
+            None => None,
+        };
+
+        Ok(Credential {
+            password: v_password,
+            totp: totp.map(TOTP::from),
+            webauthn: webauthn.into_iter().map(WebauthnToken::from).collect(),
+            backup_codes: backup_codes
+                .into_iter()
+                .map(Password::try_from)
+                .collect::<Result<Vec<_>, _>>()?,
+            policy: policy.map(Policy::from),
+            must_change: must_change,
+            claims: claims,
+            uuid: uuid,
+        })
+    }
+}
+
+impl Credential {
+    pub fn new_password_only(cleartext: &str) -> Self {
+        Credential {
+            password: Some(Password::new(cleartext)),
+            totp: None,
+            webauthn: Vec::new(),
+            backup_codes: Vec::new(),
+            policy: None,
+            must_change: false,
+            claims: Vec::new(),
+            uuid: Uuid::new_v4(),
+        }
+    }
+
+    pub fn set_password(&self, cleartext: &str) -> Self {
+        Credential {
+            password: Some(Password::new(cleartext)),
+            totp: self.totp.clone(),
+            webauthn: self.webauthn.clone(),
+            backup_codes: self.backup_codes.clone(),
+            policy: self.policy,
+            must_change: false,
+            claims: self.claims.clone(),
+            uuid: self.uuid.clone(),
+        }
+    }
+
+    pub fn update_totp(&self, totp: TOTP) -> Self {
+        Credential {
+            password: self.password.clone(),
+            totp: Some(totp),
+            webauthn: self.webauthn.clone(),
+            backup_codes: self.backup_codes.clone(),
+            policy: self.policy,
+            must_change: self.must_change,
+            claims: self.claims.clone(),
+            uuid: self.uuid.clone(),
+        }
+    }
+
+    pub fn add_webauthn(&self, token: WebauthnToken) -> Result<Self, OperationError> {
+        if self.webauthn.iter().any(|t| t.name == token.name) {
+            return Err(OperationError::InvalidWebauthn("token name already in use"));
+        }
+        if self.webauthn.iter().any(|t| t.cred_id == token.cred_id) {
+            return Err(OperationError::InvalidWebauthn("token already registered"));
+        }
+        let mut webauthn = self.webauthn.clone();
+        webauthn.push(token);
+        Ok(Credential {
+            password: self.password.clone(),
+            totp: self.totp.clone(),
+            webauthn: webauthn,
+            backup_codes: self.backup_codes.clone(),
+            policy: self.policy,
+            must_change: self.must_change,
+            claims: self.claims.clone(),
+            uuid: self.uuid.clone(),
+        })
+    }
+
+    // Record the counter a token signed with, or None if it isn't one of ours
+    // or the counter hasn't advanced, as when a clone of the token is used.
+    pub fn update_webauthn_counter(&self, cred_id: &[u8], counter: u32) -> Option<Self> {
+        let idx = self
+            .webauthn
+            .iter()
+            .position(|t| t.cred_id.as_slice() == cred_id)?;
+        if !counter_valid(self.webauthn[idx].counter, counter) {
+            return None;
+        }
+        let mut webauthn = self.webauthn.clone();
+        webauthn[idx].counter = counter;
+        Some(Credential {
+            password: self.password.clone(),
+            totp: self.totp.clone(),
+            webauthn: webauthn,
+            backup_codes: self.backup_codes.clone(),
+            policy: self.policy,
+            must_change: self.must_change,
+            claims: self.claims.clone(),
+            uuid: self.uuid.clone(),
+        })
+    }
+
+    pub fn remove_webauthn(&self, name: &str) -> Result<Self, OperationError> {
+        if !self.webauthn.iter().any(|t| t.name == name) {
+            return Err(OperationError::NoMatchingEntries);
+        }
+        let ncred = Credential {
+            password: self.password.clone(),
+            totp: self.totp.clone(),
+            webauthn: self
+                .webauthn
+                .iter()
+                .filter(|t| t.name != name)
+                .cloned()
+                .collect(),
+            backup_codes: self.backup_codes.clone(),
+            policy: self.policy,
+            must_change: self.must_change,
+            claims: self.claims.clone(),
+            uuid: self.uuid.clone(),
+        };
+        // Don't allow the last token to be removed from under a policy that
+        // needs it, as no one could then authenticate with the credential.
+        if ncred.satisfies(ncred.policy()) {
+            Ok(ncred)
+        } else {
+            Err(OperationError::InvalidAccountState(
+                "The credential policy requires this token",
+            ))
+        }
+    }
+
+    // The policy in force, either as set, or from the factors the credential
+    // has.
+    pub fn policy(&self) -> Policy {
+        match self.policy {
+            Some(p) => p,
+            None if self.totp.is_some() || !self.webauthn.is_empty() => Policy::PasswordMFA,
+            None => Policy::PasswordOnly,
+        }
+    }
+
+    // Does the credential have the factors that policy requires?
+    pub fn satisfies(&self, policy: Policy) -> bool {
+        match policy {
+            Policy::PasswordOnly => self.password.is_some(),
+            Policy::PasswordMFA => {
+                self.password.is_some() && (self.totp.is_some() || !self.webauthn.is_empty())
+            }
+            Policy::WebauthnOnly => !self.webauthn.is_empty(),
+        }
+    }
+
+    // Set the policy, or with None return to the policy following the
+    // factors of the credential.
+    pub fn set_policy(&self, policy: Option<Policy>) -> Result<Self, OperationError> {
+        let ncred = Credential {
+            password: self.password.clone(),
+            totp: self.totp.clone(),
+            webauthn: self.webauthn.clone(),
+            backup_codes: self.backup_codes.clone(),
+            policy: policy,
+            must_change: self.must_change,
+            claims: self.claims.clone(),
+            uuid: self.uuid.clone(),
+        };
+        if ncred.satisfies(ncred.policy()) {
+            Ok(ncred)
+        } else {
+            Err(OperationError::InvalidAccountState(
+                "The credential lacks the factors this policy requires",
+            ))
+        }
+    }
+
+    // Replace any backup codes with new ones, returning the new codes in the
+    // clear. This is the only time they can be seen.
+    pub fn generate_backup_codes(&self) -> (Self, Vec<String>) {
+        let codes: Vec<String> = (0..BACKUP_CODE_COUNT)
+            .map(|_| generate_backup_code())
+            .collect();
+        let cred = Credential {
+            password: self.password.clone(),
+            totp: self.totp.clone(),
+            webauthn: self.webauthn.clone(),
+            backup_codes: codes
+                .iter()
+                .map(|c| Password::new_backup_code(normalise_backup_code(c).as_str()))
+                .collect(),
+            policy: self.policy,
+            must_change: self.must_change,
+            claims: self.claims.clone(),
+            uuid: self.uuid.clone(),
+        };
+        (cred, codes)
+    }
+
+    pub fn verify_backup_code(&self, code: &str) -> bool {
+        let code = normalise_backup_code(code);
+        self.backup_codes.iter().any(|c| c.verify(code.as_str()))
+    }
+
+    // Remove the backup code, or None if it isn't one of ours, such as when
+    // it has already been used.
+    pub fn consume_backup_code(&self, code: &str) -> Option<Self> {
+        let code = normalise_backup_code(code);
+        let idx = self
+            .backup_codes
+            .iter()
+            .position(|c| c.verify(code.as_str()))?;
+        let mut backup_codes = self.backup_codes.clone();
+        backup_codes.remove(idx);
+        Some(Credential {
+            password: self.password.clone(),
+            totp: self.totp.clone(),
+            webauthn: self.webauthn.clone(),
+            backup_codes: backup_codes,
+            policy: self.policy,
+            must_change: self.must_change,
+            claims: self.claims.clone(),
+            uuid: self.uuid.clone(),
+        })
+    }
+
+    pub fn set_must_change(&self, must_change: bool) -> Self {
+        Credential {
+            password: self.password.clone(),
+            totp: self.totp.clone(),
+            webauthn: self.webauthn.clone(),
+            backup_codes: self.backup_codes.clone(),
+            policy: self.policy,
+            must_change: must_change,
+            claims: self.claims.clone(),
+            uuid: self.uuid.clone(),
+        }
+    }
+
+    pub fn verify_password(&self, cleartext: &str) -> bool {
+        match &self.password {
+            Some(pw) => pw.verify(cleartext),
+            None => false,
+        }
+    }
+
+    pub fn to_db_valuev1(&self) -> DbCredV1 {
+        DbCredV1 {
+            password: self.password.as_ref().map(|pw| pw.to_dbpasswordv1()),
+            totp: self.totp.as_ref().map(|t| t.to_dbtotpv1()),
+            webauthn: self.webauthn.iter().map(|t| t.to_dbwebauthnv1()).collect(),
+            backup_codes: self
+                .backup_codes
+                .iter()
+                .map(|c| c.to_dbpasswordv1())
+                .collect(),
+            policy: self.policy.map(|p| p.to_dbcredpolicyv1()),
+            must_change: self.must_change,
+            claims: self.claims.clone(),
+            uuid: self.uuid.clone(),
+        }
+    }
+
+    /*
+    pub fn add_claim(&mut self) {
+    }
+
+    pub fn remove_claim(&mut self) {
+    }
+    */
+
+    /*
+    pub fn modify_password(&mut self) {
+        // Change the password
+    }
+
+    pub fn add_webauthn_token() {
+    }
+
+    pub fn remove_webauthn_token() {
+    }
+    */
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::credential::*;
+
+    #[test]
+    fn test_credential_simple() {
+        let c = Credential::new_password_only("password");
+        assert!(c.verify_password("password"));
+        assert!(!c.verify_password("password1"));
+        assert!(!c.verify_password("Password1"));
+        assert!(!c.verify_password("It Works!"));
+        assert!(!c.verify_password("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa"));
+    }
+
+    #[test]
+    fn test_credential_backup_codes() {
+        let c = Credential::new_password_only("password");
+        let (c, codes) = c.generate_backup_codes();
+        assert!(codes.len() == BACKUP_CODE_COUNT);
+        assert!(c.verify_backup_code(codes[0].as_str()));
+        // Case and separators don't matter.
+        assert!(c.verify_backup_code(codes[0].to_uppercase().replace("-", " ").as_str()));
+        assert!(!c.verify_backup_code("aaaaa-aaaaa"));
+
+        // A code is gone once consumed, and can't be consumed again.
+        let c = c
+            .consume_backup_code(codes[0].as_str())
+            .expect("Failed to consume");
+        assert!(c.backup_codes.len() == BACKUP_CODE_COUNT - 1);
+        assert!(!c.verify_backup_code(codes[0].as_str()));
+        assert!(c.consume_backup_code(codes[0].as_str()).is_none());
+        assert!(c.verify_backup_code(codes[1].as_str()));
+    }
+
+    #[test]
+    fn test_credential_password_kdf() {
+        // New passwords use scrypt, and survive a round trip to the db.
+        let c = Credential::new_password_only("password");
+        match c.password.as_ref().map(|pw| &pw.material) {
+            Some(KDF::SCRYPT(_, _, _, _, _)) => {}
+            _ => panic!("new password is not scrypt"),
+        }
+        let c = Credential::try_from(c.to_db_valuev1()).expect("Failed to load");
+        assert!(c.verify_password("password"));
+        assert!(!c.verify_password("password1"));
+
+        // Older pbkdf2 passwords still verify.
+        let c = Credential {
+            password: Some(Password {
+                material: Password::new_pbkdf2("password"),
+            }),
+            ..c
+        };
+        assert!(c.verify_password("password"));
+        assert!(!c.verify_password("password1"));
+
+        // Changing the password clears the must change flag.
+        let c = c.set_must_change(true);
+        assert!(c.must_change);
+        let c = c.set_password("password2");
+        assert!(!c.must_change);
+        assert!(c.verify_password("password2"));
+    }
+}
@@ -0,0 +1,433 @@
+// Estimate how easily a password could be guessed, in the manner of zxcvbn.
+// The password is covered by the cheapest run of known patterns (common
+// words, personal details, repeats, sequences and rows of keys) and brute
+// forced characters. The cost in bits of guessing that run is then given as
+// a score from 0 (trivial) to 4 (very strong).
+use kanidm_proto::v1::PasswordFeedback;
+
+// The most common passwords, and words found in them, most common first. A
+// match costs log2 of its rank in bits, so order matters.
+const COMMON_WORDS: &[&str] = &[
+    "password",
+    "123456",
+    "12345678",
+    "qwerty",
+    "letmein",
+    "welcome",
+    "admin",
+    "login",
+    "iloveyou",
+    "abc123",
+    "monkey",
+    "dragon",
+    "master",
+    "sunshine",
+    "princess",
+    "football",
+    "baseball",
+    "shadow",
+    "superman",
+    "trustno1",
+    "secret",
+    "hello",
+    "freedom",
+    "whatever",
+    "starwars",
+    "computer",
+    "access",
+    "charlie",
+    "michael",
+    "jesus",
+    "ninja",
+    "mustang",
+    "batman",
+    "love",
+    "god",
+    "root",
+    "user",
+    "guest",
+    "test",
+    "changeme",
+    "default",
+    "summer",
+    "winter",
+    "spring",
+    "autumn",
+    "january",
+    "february",
+    "march",
+    "april",
+    "june",
+    "july",
+    "august",
+    "september",
+    "october",
+    "november",
+    "december",
+    "monday",
+    "friday",
+    "hunter",
+    "killer",
+    "soccer",
+    "hockey",
+    "jordan",
+    "harley",
+    "ranger",
+    "buster",
+    "thomas",
+    "tigger",
+    "robert",
+    "pepper",
+    "cheese",
+    "ginger",
+    "maggie",
+    "flower",
+    "london",
+    "orange",
+    "purple",
+    "yellow",
+    "silver",
+    "golden",
+    "matrix",
+    "pokemon",
+    "kanidm",
+];
+
+const KEYBOARD_ROWS: &[&str] = &["1234567890", "qwertyuiop", "asdfghjkl", "zxcvbnm"];
+
+// Patterns shorter than this are not worth matching.
+const MIN_MATCH_LEN: usize = 3;
+
+// Score thresholds, as log2 of the guesses zxcvbn uses: 10^3, 10^6, 10^8 and
+// 10^10.
+const SCORE_BITS: [f64; 4] = [9.97, 19.93, 26.58, 33.22];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Pattern {
+    Common,
+    Personal,
+    Repeat,
+    Sequence,
+    Keyboard,
+}
+
+#[derive(Debug)]
+struct Match {
+    start: usize,
+    end: usize,
+    bits: f64,
+    pattern: Pattern,
+}
+
+#[derive(Debug)]
+pub struct Estimate {
+    // From 0 to 4, as zxcvbn.
+    pub score: u8,
+    // The patterns that made the password weaker, if any.
+    pub feedback: Vec<PasswordFeedback>,
+}
+
+// Undo the common substitutions, such as p@ssw0rd.
+fn unleet(c: char) -> char {
+    match c {
+        '4' | '@' => 'a',
+        '3' => 'e',
+        '1' | '!' => 'i',
+        '0' => 'o',
+        '$' | '5' => 's',
+        '7' | '+' => 't',
+        _ => c,
+    }
+}
+
+// How many characters a brute force guess would have to try from, given the
+// kinds of character in the password.
+fn cardinality(chars: &[char]) -> u32 {
+    let mut lower = false;
+    let mut upper = false;
+    let mut digit = false;
+    let mut symbol = false;
+    let mut other = false;
+    for c in chars {
+        if c.is_ascii_lowercase() {
+            lower = true
+        } else if c.is_ascii_uppercase() {
+            upper = true
+        } else if c.is_ascii_digit() {
+            digit = true
+        } else if c.is_ascii() {
+            symbol = true
+        } else {
+            other = true
+        }
+    }
+    [
+        (lower, 26),
+        (upper, 26),
+        (digit, 10),
+        (symbol, 33),
+        (other, 100),
+    ]
+    .iter()
+    .filter(|(present, _)| *present)
+    .map(|(_, n)| n)
+    .sum()
+}
+
+fn dictionary_matches(
+    orig: &[char],
+    lower: &[char],
+    words: &[(String, Pattern)],
+    matches: &mut Vec<Match>,
+) {
+    for (rank, (word, pattern)) in words.iter().enumerate() {
+        let word: Vec<char> = word.chars().collect();
+        if word.len() < MIN_MATCH_LEN || word.len() > lower.len() {
+            continue;
+        }
+        for start in 0..=(lower.len() - word.len()) {
+            let end = start + word.len();
+            let found = lower[start..end]
+                .iter()
+                .zip(word.iter())
+                .all(|(c, w)| c == w || unleet(*c) == *w);
+            if !found {
+                continue;
+            }
+            let leet = lower[start..end]
+                .iter()
+                .zip(word.iter())
+                .any(|(c, w)| c != w);
+            // Capitalising the first letter, or everything, is the usual
+            // variation. Anything else is a bit harder to guess.
+            let segment = &orig[start..end];
+            let uppers = segment.iter().filter(|c| c.is_uppercase()).count();
+            let case_bits = if uppers == 0 {
+                0.0
+            } else if uppers == segment.len() || (uppers == 1 && segment[0].is_uppercase()) {
+                1.0
+            } else {
+                (segment.len() as f64).log2()
+            };
+            // Personal details are the first thing an attacker tries.
+            let rank_bits = match pattern {
+                Pattern::Personal => 0.0,
+                _ => ((rank + 1) as f64).log2(),
+            };
+            matches.push(Match {
+                start: start,
+                end: end,
+                bits: rank_bits + case_bits + if leet { 1.0 } else { 0.0 },
+                pattern: *pattern,
+            });
+        }
+    }
+}
+
+// The longest run from each position of a repeated character, or a step of
+// plus or minus one, such as aaaa, abcd or 9876.
+fn run_matches(lower: &[char], brute_bits: f64, matches: &mut Vec<Match>) {
+    for start in 0..lower.len() {
+        let mut end = start + 1;
+        while end < lower.len() && lower[end] == lower[start] {
+            end += 1;
+        }
+        if end - start >= MIN_MATCH_LEN {
+            matches.push(Match {
+                start: start,
+                end: end,
+                bits: brute_bits + ((end - start) as f64).log2(),
+                pattern: Pattern::Repeat,
+            });
+        }
+
+        for step in &[1i64, -1] {
+            let mut end = start + 1;
+            while end < lower.len()
+                && lower[end].is_ascii_alphanumeric()
+                && lower[end - 1].is_ascii_alphanumeric()
+                && lower[end] as i64 - lower[end - 1] as i64 == *step
+            {
+                end += 1;
+            }
+            if end - start >= MIN_MATCH_LEN {
+                let base: f64 = if lower[start].is_ascii_digit() {
+                    10.0
+                } else {
+                    26.0
+                };
+                let descending = if *step < 0 { 1.0 } else { 0.0 };
+                matches.push(Match {
+                    start: start,
+                    end: end,
+                    bits: base.log2() + ((end - start) as f64).log2() + descending,
+                    pattern: Pattern::Sequence,
+                });
+            }
+        }
+    }
+}
+
+// The longest run from each position along a row of the keyboard, in either
+// direction, such as qwerty or lkjh.
+fn keyboard_matches(lower: &[char], matches: &mut Vec<Match>) {
+    let rows: Vec<Vec<char>> = KEYBOARD_ROWS
+        .iter()
+        .flat_map(|r| vec![r.chars().collect(), r.chars().rev().collect()])
+        .collect();
+    // Which row, which direction and where in it to start.
+    let start_bits = ((rows.len() * 10) as f64).log2();
+    for start in 0..lower.len() {
+        for row in &rows {
+            let pos = match row.iter().position(|c| *c == lower[start]) {
+                Some(p) => p,
+                None => continue,
+            };
+            let len = lower[start..]
+                .iter()
+                .zip(row[pos..].iter())
+                .take_while(|(a, b)| a == b)
+                .count();
+            if len >= MIN_MATCH_LEN {
+                matches.push(Match {
+                    start: start,
+                    end: start + len,
+                    bits: start_bits + (len as f64).log2(),
+                    pattern: Pattern::Keyboard,
+                });
+            }
+        }
+    }
+}
+
+fn score(bits: f64) -> u8 {
+    SCORE_BITS.iter().take_while(|t| bits >= **t).count() as u8
+}
+
+// Estimate the strength of password. The user inputs are details such as the
+// account and display names, that should not be relied on.
+pub fn estimate(password: &str, user_inputs: &[&str]) -> Estimate {
+    let orig: Vec<char> = password.chars().collect();
+    let lower: Vec<char> = password.to_lowercase().chars().collect();
+    // Lowercasing some characters changes their length, in which case only
+    // brute force is considered.
+    if orig.len() != lower.len() || orig.is_empty() {
+        return Estimate {
+            score: score((cardinality(&orig) as f64).log2() * orig.len() as f64),
+            feedback: Vec::new(),
+        };
+    }
+    let brute_bits = (cardinality(&orig) as f64).log2();
+
+    let words: Vec<(String, Pattern)> = user_inputs
+        .iter()
+        .flat_map(|i| {
+            let i = i.to_lowercase();
+            let mut parts: Vec<String> = i.split_whitespace().map(|p| p.to_string()).collect();
+            parts.push(i);
+            parts
+        })
+        .map(|i| (i, Pattern::Personal))
+        .chain(
+            COMMON_WORDS
+                .iter()
+                .map(|w| (w.to_string(), Pattern::Common)),
+        )
+        .collect();
+
+    let mut matches = Vec::new();
+    dictionary_matches(&orig, &lower, &words, &mut matches);
+    run_matches(&lower, brute_bits, &mut matches);
+    keyboard_matches(&lower, &mut matches);
+
+    // The cheapest way to guess each prefix of the password, and the match
+    // that ends it, if it isn't a brute forced character.
+    let mut best: Vec<(f64, Option<usize>)> = vec![(0.0, None)];
+    for end in 1..=lower.len() {
+        let mut b = (best[end - 1].0 + brute_bits, None);
+        for (idx, m) in matches.iter().enumerate().filter(|(_, m)| m.end == end) {
+            let bits = best[m.start].0 + m.bits;
+            if bits < b.0 {
+                b = (bits, Some(idx));
+            }
+        }
+        best.push(b);
+    }
+
+    // Walk back to find which patterns were used.
+    let mut patterns = Vec::new();
+    let mut pos = lower.len();
+    while pos > 0 {
+        match best[pos].1 {
+            Some(idx) => {
+                patterns.push(matches[idx].pattern);
+                pos = matches[idx].start;
+            }
+            None => pos -= 1,
+        }
+    }
+
+    let feedback = [
+        (Pattern::Personal, PasswordFeedback::AvoidPersonalInfo),
+        (Pattern::Common, PasswordFeedback::AvoidCommonWords),
+        (Pattern::Repeat, PasswordFeedback::AvoidRepeats),
+        (Pattern::Sequence, PasswordFeedback::AvoidSequences),
+        (Pattern::Keyboard, PasswordFeedback::AvoidSequences),
+    ]
+    .iter()
+    .filter(|(p, _)| patterns.contains(p))
+    .map(|(_, f)| f.clone())
+    .fold(Vec::new(), |mut acc, f| {
+        if !acc.contains(&f) {
+            acc.push(f);
+        }
+        acc
+    });
+
+    Estimate {
+        score: score(best[lower.len()].0),
+        feedback: feedback,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::credential::strength::estimate;
+    use kanidm_proto::v1::PasswordFeedback;
+
+    #[test]
+    fn test_strength_weak_patterns() {
+        let e = estimate("password", &[]);
+        assert!(e.score == 0);
+        assert!(e.feedback == vec![PasswordFeedback::AvoidCommonWords]);
+        // Substitutions and capitals don't help much.
+        assert!(estimate("P@ssw0rd", &[]).score == 0);
+
+        let e = estimate("aaaaaaaaaaaa", &[]);
+        assert!(e.score == 0);
+        assert!(e.feedback == vec![PasswordFeedback::AvoidRepeats]);
+
+        let e = estimate("abcdefghijkl", &[]);
+        assert!(e.score == 0);
+        assert!(e.feedback == vec![PasswordFeedback::AvoidSequences]);
+        assert!(estimate("qwertyuiop", &[]).score == 0);
+        assert!(estimate("9876543210", &[]).score == 0);
+
+        let e = estimate("william1234", &["william", "William Brown"]);
+        assert!(e.score < 3);
+        assert!(e.feedback.contains(&PasswordFeedback::AvoidPersonalInfo));
+    }
+
+    #[test]
+    fn test_strength_strong() {
+        for pw in &[
+            "correct horse battery staple",
+            "Tr0ub4dor&3xq",
+            "ntaoeuntnaoeuhraohuercahu😍",
+        ] {
+            let e = estimate(pw, &["admin"]);
+            assert!(e.score == 4);
+            assert!(e.feedback.is_empty());
+        }
+    }
+}
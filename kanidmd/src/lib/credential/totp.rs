@@ -0,0 +1,201 @@
+use crate::be::dbvalue::{DbTotpAlgoV1, DbTotpV1};
+use kanidm_proto::v1::{OperationError, REDACTED};
+use kanidm_proto::v1::{TOTPAlgo as ProtoTOTPAlgo, TOTPSecret as ProtoTOTPSecret};
+
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use rand::prelude::*;
+use std::fmt;
+use std::time::Duration;
+
+// The step and digest that authenticator apps assume when none is given.
+pub const TOTP_DEFAULT_STEP: u64 = 30;
+// RFC 4226 recommends at least 160 bits of secret.
+const TOTP_SECRET_LEN: usize = 20;
+// Six digit codes.
+const TOTP_MODULUS: u32 = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TOTPAlgo {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl TOTPAlgo {
+    fn digest(&self) -> MessageDigest {
+        match self {
+            TOTPAlgo::Sha1 => MessageDigest::sha1(),
+            TOTPAlgo::Sha256 => MessageDigest::sha256(),
+            TOTPAlgo::Sha512 => MessageDigest::sha512(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct TOTP {
+    secret: Vec<u8>,
+    step: u64,
+    algo: TOTPAlgo,
+}
+
+impl fmt::Debug for TOTP {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TOTP")
+            .field("secret", &REDACTED)
+            .field("step", &self.step)
+            .field("algo", &self.algo)
+            .finish()
+    }
+}
+
+impl From<DbTotpV1> for TOTP {
+    fn from(value: DbTotpV1) -> Self {
+        TOTP {
+            secret: value.k,
+            step: value.s,
+            algo: match value.a {
+                DbTotpAlgoV1::S1 => TOTPAlgo::Sha1,
+                DbTotpAlgoV1::S256 => TOTPAlgo::Sha256,
+                DbTotpAlgoV1::S512 => TOTPAlgo::Sha512,
+            },
+        }
+    }
+}
+
+impl TOTP {
+    pub fn new(secret: Vec<u8>, step: u64, algo: TOTPAlgo) -> Self {
+        TOTP {
+            secret: secret,
+            step: step,
+            algo: algo,
+        }
+    }
+
+    pub fn generate_secure(step: u64) -> Self {
+        let mut rng = rand::thread_rng();
+        let secret: Vec<u8> = (0..TOTP_SECRET_LEN).map(|_| rng.gen()).collect();
+        TOTP::new(secret, step, TOTPAlgo::Sha1)
+    }
+
+    // RFC 4226 HOTP of the counter, with dynamic truncation to six digits.
+    fn digest(&self, counter: u64) -> Result<u32, OperationError> {
+        let key = PKey::hmac(&self.secret).map_err(|_| OperationError::CryptographyError)?;
+        let mut signer =
+            Signer::new(self.algo.digest(), &key).map_err(|_| OperationError::CryptographyError)?;
+        signer
+            .update(&counter.to_be_bytes())
+            .map_err(|_| OperationError::CryptographyError)?;
+        let hmac = signer
+            .sign_to_vec()
+            .map_err(|_| OperationError::CryptographyError)?;
+
+        let offset = (hmac[hmac.len() - 1] & 0xf) as usize;
+        let code = ((hmac[offset] as u32 & 0x7f) << 24)
+            | ((hmac[offset + 1] as u32) << 16)
+            | ((hmac[offset + 2] as u32) << 8)
+            | (hmac[offset + 3] as u32);
+        Ok(code % TOTP_MODULUS)
+    }
+
+    pub fn do_totp_duration_from_epoch(&self, ct: &Duration) -> Result<u32, OperationError> {
+        self.digest(ct.as_secs() / self.step)
+    }
+
+    // Check chal against the code for ct, and the steps either side of it to
+    // allow for clock skew. On a match, the step that matched is returned so
+    // that the caller can refuse the same code being used again.
+    pub fn verify(&self, chal: u32, ct: &Duration) -> Option<u64> {
+        let current = ct.as_secs() / self.step;
+        let first = if current > 0 { current - 1 } else { current };
+        (first..=current + 1).find(|counter| match self.digest(*counter) {
+            Ok(code) => code == chal,
+            Err(_) => false,
+        })
+    }
+
+    pub fn to_proto(&self, accountname: &str, issuer: &str) -> ProtoTOTPSecret {
+        ProtoTOTPSecret {
+            accountname: accountname.to_string(),
+            issuer: issuer.to_string(),
+            secret: self.secret.clone(),
+            algo: match self.algo {
+                TOTPAlgo::Sha1 => ProtoTOTPAlgo::Sha1,
+                TOTPAlgo::Sha256 => ProtoTOTPAlgo::Sha256,
+                TOTPAlgo::Sha512 => ProtoTOTPAlgo::Sha512,
+            },
+            step: self.step,
+        }
+    }
+
+    pub fn to_dbtotpv1(&self) -> DbTotpV1 {
+        DbTotpV1 {
+            k: self.secret.clone(),
+            s: self.step,
+            a: match self.algo {
+                TOTPAlgo::Sha1 => DbTotpAlgoV1::S1,
+                TOTPAlgo::Sha256 => DbTotpAlgoV1::S256,
+                TOTPAlgo::Sha512 => DbTotpAlgoV1::S512,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::credential::totp::{TOTPAlgo, TOTP, TOTP_DEFAULT_STEP};
+    use std::time::Duration;
+
+    // The test vectors of RFC 6238, truncated to six digits.
+    #[test]
+    fn test_totp_rfc6238_vectors() {
+        let sha1 = TOTP::new(
+            b"12345678901234567890".to_vec(),
+            TOTP_DEFAULT_STEP,
+            TOTPAlgo::Sha1,
+        );
+        let sha256 = TOTP::new(
+            b"12345678901234567890123456789012".to_vec(),
+            TOTP_DEFAULT_STEP,
+            TOTPAlgo::Sha256,
+        );
+        let sha512 = TOTP::new(
+            b"1234567890123456789012345678901234567890123456789012345678901234".to_vec(),
+            TOTP_DEFAULT_STEP,
+            TOTPAlgo::Sha512,
+        );
+
+        let check = |totp: &TOTP, t: u64, code: u32| {
+            assert!(totp.do_totp_duration_from_epoch(&Duration::from_secs(t)) == Ok(code));
+        };
+        check(&sha1, 59, 287082);
+        check(&sha1, 1111111109, 81804);
+        check(&sha1, 2000000000, 279037);
+        check(&sha256, 59, 119246);
+        check(&sha256, 1111111109, 84774);
+        check(&sha512, 59, 693936);
+        check(&sha512, 1111111109, 91201);
+    }
+
+    #[test]
+    fn test_totp_verify_skew() {
+        let totp = TOTP::generate_secure(TOTP_DEFAULT_STEP);
+        let ct = Duration::from_secs(6000);
+        let code = totp
+            .do_totp_duration_from_epoch(&ct)
+            .expect("Failed to generate code");
+
+        // Accepted one step either side, but no further.
+        assert!(totp.verify(code, &ct) == Some(200));
+        assert!(totp.verify(code, &(ct - Duration::from_secs(TOTP_DEFAULT_STEP))) == Some(200));
+        assert!(totp.verify(code, &(ct + Duration::from_secs(TOTP_DEFAULT_STEP))) == Some(200));
+        assert!(totp
+            .verify(code, &(ct + Duration::from_secs(TOTP_DEFAULT_STEP * 2)))
+            .is_none());
+        assert!(totp
+            .verify(code, &(ct - Duration::from_secs(TOTP_DEFAULT_STEP * 2)))
+            .is_none());
+        assert!(totp.verify((code + 1) % 1_000_000, &ct).is_none());
+    }
+}
@@ -0,0 +1,606 @@
+use crate::be::dbvalue::DbWebauthnV1;
+use kanidm_proto::v1::OperationError;
+use kanidm_proto::v1::{
+    WebauthnAssertion, WebauthnCreationChallenge, WebauthnCredentialDescriptor,
+    WebauthnPubKeyCredParams, WebauthnRegisterCredential, WebauthnRelyingParty,
+    WebauthnRequestChallenge, WebauthnTokenInfo, WebauthnUser,
+};
+
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Public};
+use openssl::sha::sha256;
+use openssl::sign::Verifier;
+use rand::prelude::*;
+use serde_cbor::Value as CBORValue;
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+// How long, in milliseconds, the browser should wait for the token.
+pub const WEBAUTHN_TIMEOUT: u32 = 60000;
+const CHALLENGE_LEN: usize = 32;
+// The only key type we accept is ECDSA P-256 with SHA-256, which every
+// token supports.
+const COSE_ALG_ES256: i64 = -7;
+const COSE_KTY_EC2: i128 = 2;
+const COSE_CRV_P256: i128 = 1;
+// Authenticator data flags, user present and attested credential data.
+const FLAG_UP: u8 = 0x01;
+const FLAG_AT: u8 = 0x40;
+
+fn b64_encode(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+fn b64_decode(data: &str) -> Result<Vec<u8>, OperationError> {
+    base64::decode_config(data.trim_end_matches('='), base64::URL_SAFE_NO_PAD)
+        .map_err(|_| OperationError::InvalidWebauthn("invalid base64"))
+}
+
+// The relying party that tokens are registered and asked to sign for. The
+// rp id is the domain, and the origin the url that the browser reports the
+// request came from.
+#[derive(Debug, Clone)]
+pub struct WebauthnConfig {
+    rp_id: String,
+    origin: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Challenge(Vec<u8>);
+
+impl Challenge {
+    fn new() -> Self {
+        let mut rng = rand::thread_rng();
+        Challenge((0..CHALLENGE_LEN).map(|_| rng.gen()).collect())
+    }
+
+    fn to_b64(&self) -> String {
+        b64_encode(&self.0)
+    }
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    type_: String,
+    challenge: String,
+    origin: String,
+}
+
+struct AuthenticatorData {
+    rp_id_hash: Vec<u8>,
+    flags: u8,
+    counter: u32,
+    // The credential id and public key, only present on registration.
+    attested: Option<(Vec<u8>, CBORValue)>,
+}
+
+impl AuthenticatorData {
+    fn parse(data: &[u8]) -> Result<Self, OperationError> {
+        if data.len() < 37 {
+            return Err(OperationError::InvalidWebauthn(
+                "authenticator data too short",
+            ));
+        }
+        let flags = data[32];
+        let counter = u32::from_be_bytes([data[33], data[34], data[35], data[36]]);
+
+        let attested = if flags & FLAG_AT != 0 {
+            // aaguid(16), credential id length(2), credential id, cose key.
+            let rest = &data[37..];
+            if rest.len() < 18 {
+                return Err(OperationError::InvalidWebauthn("attested data too short"));
+            }
+            let id_len = u16::from_be_bytes([rest[16], rest[17]]) as usize;
+            let rest = &rest[18..];
+            if rest.len() < id_len {
+                return Err(OperationError::InvalidWebauthn("credential id too short"));
+            }
+            let cred_id = rest[..id_len].to_vec();
+            // The key may be followed by extensions, so only the first cbor
+            // value is read.
+            let key = serde_cbor::Deserializer::from_slice(&rest[id_len..])
+                .into_iter::<CBORValue>()
+                .next()
+                .ok_or(OperationError::InvalidWebauthn("missing public key"))?
+                .map_err(|_| OperationError::InvalidWebauthn("invalid public key"))?;
+            Some((cred_id, key))
+        } else {
+            None
+        };
+
+        Ok(AuthenticatorData {
+            rp_id_hash: data[..32].to_vec(),
+            flags: flags,
+            counter: counter,
+            attested: attested,
+        })
+    }
+}
+
+// Get the x and y coordinates of an ES256 COSE key.
+fn cose_es256_coordinates(key: &CBORValue) -> Result<(Vec<u8>, Vec<u8>), OperationError> {
+    let map = match key {
+        CBORValue::Map(m) => m,
+        _ => return Err(OperationError::InvalidWebauthn("invalid public key")),
+    };
+    let get = |k: i128| map.get(&CBORValue::Integer(k));
+
+    match (get(1), get(3), get(-1)) {
+        (
+            Some(CBORValue::Integer(COSE_KTY_EC2)),
+            Some(CBORValue::Integer(alg)),
+            Some(CBORValue::Integer(COSE_CRV_P256)),
+        ) if *alg == COSE_ALG_ES256 as i128 => {}
+        _ => return Err(OperationError::InvalidWebauthn("unsupported public key")),
+    };
+    match (get(-2), get(-3)) {
+        (Some(CBORValue::Bytes(x)), Some(CBORValue::Bytes(y))) => Ok((x.clone(), y.clone())),
+        _ => Err(OperationError::InvalidWebauthn("invalid public key")),
+    }
+}
+
+fn es256_pkey(x: &[u8], y: &[u8]) -> Result<PKey<Public>, OperationError> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)
+        .map_err(|_| OperationError::CryptographyError)?;
+    let x = BigNum::from_slice(x).map_err(|_| OperationError::CryptographyError)?;
+    let y = BigNum::from_slice(y).map_err(|_| OperationError::CryptographyError)?;
+    // This checks the point is on the curve.
+    let key = EcKey::from_public_key_affine_coordinates(&group, &x, &y)
+        .map_err(|_| OperationError::InvalidWebauthn("invalid public key"))?;
+    PKey::from_ec_key(key).map_err(|_| OperationError::CryptographyError)
+}
+
+#[derive(Debug, Clone)]
+pub struct WebauthnToken {
+    pub(crate) name: String,
+    pub(crate) cred_id: Vec<u8>,
+    x: Vec<u8>,
+    y: Vec<u8>,
+    // The highest signature counter the token has authenticated with.
+    pub(crate) counter: u32,
+}
+
+impl From<DbWebauthnV1> for WebauthnToken {
+    fn from(value: DbWebauthnV1) -> Self {
+        WebauthnToken {
+            name: value.n,
+            cred_id: value.i,
+            x: value.x,
+            y: value.y,
+            counter: value.c,
+        }
+    }
+}
+
+impl WebauthnToken {
+    pub fn to_dbwebauthnv1(&self) -> DbWebauthnV1 {
+        DbWebauthnV1 {
+            n: self.name.clone(),
+            i: self.cred_id.clone(),
+            x: self.x.clone(),
+            y: self.y.clone(),
+            c: self.counter,
+        }
+    }
+
+    pub fn to_proto(&self) -> WebauthnTokenInfo {
+        WebauthnTokenInfo {
+            name: self.name.clone(),
+            id: b64_encode(&self.cred_id),
+            counter: self.counter,
+        }
+    }
+
+    fn to_descriptor(&self) -> WebauthnCredentialDescriptor {
+        WebauthnCredentialDescriptor {
+            type_: "public-key".to_string(),
+            id: b64_encode(&self.cred_id),
+        }
+    }
+}
+
+impl WebauthnConfig {
+    pub fn new(rp_id: &str, origin: &str) -> Self {
+        WebauthnConfig {
+            rp_id: rp_id.to_string(),
+            origin: origin.to_string(),
+        }
+    }
+
+    fn check_client_data(
+        &self,
+        data: &[u8],
+        type_: &str,
+        chal: &Challenge,
+    ) -> Result<(), OperationError> {
+        let cd: ClientData = serde_json::from_slice(data)
+            .map_err(|_| OperationError::InvalidWebauthn("invalid client data"))?;
+        if cd.type_ != type_ {
+            return Err(OperationError::InvalidWebauthn(
+                "incorrect client data type",
+            ));
+        }
+        if b64_decode(cd.challenge.as_str())? != chal.0 {
+            return Err(OperationError::InvalidWebauthn("incorrect challenge"));
+        }
+        if cd.origin != self.origin {
+            return Err(OperationError::InvalidWebauthn("incorrect origin"));
+        }
+        Ok(())
+    }
+
+    fn check_authenticator_data(&self, ad: &AuthenticatorData) -> Result<(), OperationError> {
+        if ad.rp_id_hash != sha256(self.rp_id.as_bytes()) {
+            return Err(OperationError::InvalidWebauthn("incorrect rp id"));
+        }
+        if ad.flags & FLAG_UP == 0 {
+            return Err(OperationError::InvalidWebauthn("user not present"));
+        }
+        Ok(())
+    }
+
+    pub fn generate_challenge_register(
+        &self,
+        name: &str,
+        displayname: &str,
+        uuid: &Uuid,
+        existing: &[WebauthnToken],
+    ) -> (WebauthnCreationChallenge, Challenge) {
+        let chal = Challenge::new();
+        let proto = WebauthnCreationChallenge {
+            challenge: chal.to_b64(),
+            rp: WebauthnRelyingParty {
+                id: self.rp_id.clone(),
+                name: self.rp_id.clone(),
+            },
+            user: WebauthnUser {
+                id: b64_encode(uuid.as_bytes()),
+                name: name.to_string(),
+                display_name: displayname.to_string(),
+            },
+            pub_key_cred_params: vec![WebauthnPubKeyCredParams {
+                type_: "public-key".to_string(),
+                alg: COSE_ALG_ES256,
+            }],
+            timeout: WEBAUTHN_TIMEOUT,
+            exclude_credentials: existing.iter().map(|t| t.to_descriptor()).collect(),
+            // We don't check which make of token is used, so there is no
+            // reason to ask for it.
+            attestation: "none".to_string(),
+        };
+        (proto, chal)
+    }
+
+    pub fn register_credential(
+        &self,
+        reg: &WebauthnRegisterCredential,
+        chal: &Challenge,
+        name: &str,
+    ) -> Result<WebauthnToken, OperationError> {
+        if reg.type_ != "public-key" {
+            return Err(OperationError::InvalidWebauthn("incorrect credential type"));
+        }
+        let client_data = b64_decode(reg.response.client_data_json.as_str())?;
+        self.check_client_data(&client_data, "webauthn.create", chal)?;
+
+        let att_obj = b64_decode(reg.response.attestation_object.as_str())?;
+        let att_obj: BTreeMap<String, CBORValue> = serde_cbor::from_slice(&att_obj)
+            .map_err(|_| OperationError::InvalidWebauthn("invalid attestation object"))?;
+        match att_obj.get("fmt") {
+            Some(CBORValue::Text(fmt)) if fmt == "none" => {}
+            _ => {
+                return Err(OperationError::InvalidWebauthn(
+                    "unsupported attestation format",
+                ))
+            }
+        };
+        let auth_data = match att_obj.get("authData") {
+            Some(CBORValue::Bytes(d)) => AuthenticatorData::parse(d)?,
+            _ => {
+                return Err(OperationError::InvalidWebauthn(
+                    "missing authenticator data",
+                ))
+            }
+        };
+        self.check_authenticator_data(&auth_data)?;
+
+        let (cred_id, key) = auth_data.attested.ok_or(OperationError::InvalidWebauthn(
+            "missing attested credential",
+        ))?;
+        if cred_id != b64_decode(reg.raw_id.as_str())? {
+            return Err(OperationError::InvalidWebauthn("incorrect credential id"));
+        }
+        let (x, y) = cose_es256_coordinates(&key)?;
+        es256_pkey(&x, &y)?;
+
+        Ok(WebauthnToken {
+            name: name.to_string(),
+            cred_id: cred_id,
+            x: x,
+            y: y,
+            counter: auth_data.counter,
+        })
+    }
+
+    pub fn generate_challenge_authenticate(
+        &self,
+        tokens: &[WebauthnToken],
+    ) -> (WebauthnRequestChallenge, Challenge) {
+        let chal = Challenge::new();
+        let proto = WebauthnRequestChallenge {
+            challenge: chal.to_b64(),
+            timeout: WEBAUTHN_TIMEOUT,
+            rp_id: self.rp_id.clone(),
+            allow_credentials: tokens.iter().map(|t| t.to_descriptor()).collect(),
+            // The token is a second factor to the password.
+            user_verification: "discouraged".to_string(),
+        };
+        (proto, chal)
+    }
+
+    // Verify the assertion was signed by one of tokens in response to chal.
+    // On success the credential id of the token and its new signature
+    // counter are returned, which the caller must check has advanced.
+    pub fn authenticate_credential(
+        &self,
+        tokens: &[WebauthnToken],
+        chal: &Challenge,
+        asrt: &WebauthnAssertion,
+    ) -> Result<(Vec<u8>, u32), OperationError> {
+        if asrt.type_ != "public-key" {
+            return Err(OperationError::InvalidWebauthn("incorrect credential type"));
+        }
+        let cred_id = b64_decode(asrt.raw_id.as_str())?;
+        let token = tokens
+            .iter()
+            .find(|t| t.cred_id == cred_id)
+            .ok_or(OperationError::InvalidWebauthn("unknown credential"))?;
+
+        let client_data = b64_decode(asrt.response.client_data_json.as_str())?;
+        self.check_client_data(&client_data, "webauthn.get", chal)?;
+
+        let raw_auth_data = b64_decode(asrt.response.authenticator_data.as_str())?;
+        let auth_data = AuthenticatorData::parse(&raw_auth_data)?;
+        self.check_authenticator_data(&auth_data)?;
+
+        // The signature is over the authenticator data and the hash of the
+        // client data.
+        let signature = b64_decode(asrt.response.signature.as_str())?;
+        let pkey = es256_pkey(&token.x, &token.y)?;
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &pkey)
+            .map_err(|_| OperationError::CryptographyError)?;
+        verifier
+            .update(&raw_auth_data)
+            .and_then(|_| verifier.update(&sha256(&client_data)))
+            .map_err(|_| OperationError::CryptographyError)?;
+        match verifier.verify(&signature) {
+            Ok(true) => Ok((cred_id, auth_data.counter)),
+            _ => Err(OperationError::InvalidWebauthn("incorrect signature")),
+        }
+    }
+}
+
+// Tokens that don't keep a counter always report zero, otherwise a counter
+// that doesn't advance means the token may have been cloned.
+pub fn counter_valid(last: u32, counter: u32) -> bool {
+    (last == 0 && counter == 0) || counter > last
+}
+
+// A token in software, to stand in for a browser and a hardware token.
+#[cfg(test)]
+pub(crate) mod softtoken {
+    use super::b64_encode;
+    use kanidm_proto::v1::{
+        WebauthnAssertion, WebauthnAssertionResponse, WebauthnAttestationResponse,
+        WebauthnCreationChallenge, WebauthnRegisterCredential, WebauthnRequestChallenge,
+    };
+    use openssl::bn::{BigNum, BigNumContext};
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::{PKey, Private};
+    use openssl::sha::sha256;
+    use openssl::sign::Signer;
+    use rand::prelude::*;
+    use serde_cbor::Value as CBORValue;
+    use std::collections::BTreeMap;
+
+    #[derive(Clone)]
+    pub struct SoftToken {
+        key: EcKey<Private>,
+        cred_id: Vec<u8>,
+        pub counter: u32,
+    }
+
+    fn client_data(type_: &str, challenge: &str, origin: &str) -> Vec<u8> {
+        format!(
+            r#"{{"type":"{}","challenge":"{}","origin":"{}"}}"#,
+            type_, challenge, origin
+        )
+        .into_bytes()
+    }
+
+    impl SoftToken {
+        pub fn new() -> Self {
+            let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+            let mut rng = rand::thread_rng();
+            SoftToken {
+                key: EcKey::generate(&group).unwrap(),
+                cred_id: (0..16).map(|_| rng.gen()).collect(),
+                counter: 0,
+            }
+        }
+
+        fn auth_data(&self, rp_id: &str, attested: bool) -> Vec<u8> {
+            let mut data = sha256(rp_id.as_bytes()).to_vec();
+            data.push(if attested { 0x41 } else { 0x01 });
+            data.extend_from_slice(&self.counter.to_be_bytes());
+            if attested {
+                let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+                let mut ctx = BigNumContext::new().unwrap();
+                let mut x = BigNum::new().unwrap();
+                let mut y = BigNum::new().unwrap();
+                self.key
+                    .public_key()
+                    .affine_coordinates_gfp(&group, &mut x, &mut y, &mut ctx)
+                    .unwrap();
+                let mut cose = BTreeMap::new();
+                cose.insert(CBORValue::Integer(1), CBORValue::Integer(2));
+                cose.insert(CBORValue::Integer(3), CBORValue::Integer(-7));
+                cose.insert(CBORValue::Integer(-1), CBORValue::Integer(1));
+                cose.insert(CBORValue::Integer(-2), CBORValue::Bytes(x.to_vec()));
+                cose.insert(CBORValue::Integer(-3), CBORValue::Bytes(y.to_vec()));
+
+                data.extend_from_slice(&[0; 16]);
+                data.extend_from_slice(&(self.cred_id.len() as u16).to_be_bytes());
+                data.extend_from_slice(&self.cred_id);
+                data.extend(serde_cbor::to_vec(&CBORValue::Map(cose)).unwrap());
+            }
+            data
+        }
+
+        pub fn register(
+            &self,
+            chal: &WebauthnCreationChallenge,
+            origin: &str,
+        ) -> WebauthnRegisterCredential {
+            let mut att_obj = BTreeMap::new();
+            att_obj.insert(
+                CBORValue::Text("fmt".to_string()),
+                CBORValue::Text("none".to_string()),
+            );
+            att_obj.insert(
+                CBORValue::Text("attStmt".to_string()),
+                CBORValue::Map(BTreeMap::new()),
+            );
+            att_obj.insert(
+                CBORValue::Text("authData".to_string()),
+                CBORValue::Bytes(self.auth_data(chal.rp.id.as_str(), true)),
+            );
+            WebauthnRegisterCredential {
+                id: b64_encode(&self.cred_id),
+                raw_id: b64_encode(&self.cred_id),
+                response: WebauthnAttestationResponse {
+                    attestation_object: b64_encode(
+                        &serde_cbor::to_vec(&CBORValue::Map(att_obj)).unwrap(),
+                    ),
+                    client_data_json: b64_encode(&client_data(
+                        "webauthn.create",
+                        chal.challenge.as_str(),
+                        origin,
+                    )),
+                },
+                type_: "public-key".to_string(),
+            }
+        }
+
+        pub fn sign(&mut self, chal: &WebauthnRequestChallenge, origin: &str) -> WebauthnAssertion {
+            self.counter += 1;
+            let auth_data = self.auth_data(chal.rp_id.as_str(), false);
+            let cd = client_data("webauthn.get", chal.challenge.as_str(), origin);
+
+            let pkey = PKey::from_ec_key(self.key.clone()).unwrap();
+            let mut signer = Signer::new(MessageDigest::sha256(), &pkey).unwrap();
+            signer.update(&auth_data).unwrap();
+            signer.update(&sha256(&cd)).unwrap();
+            let signature = signer.sign_to_vec().unwrap();
+
+            WebauthnAssertion {
+                id: b64_encode(&self.cred_id),
+                raw_id: b64_encode(&self.cred_id),
+                response: WebauthnAssertionResponse {
+                    authenticator_data: b64_encode(&auth_data),
+                    client_data_json: b64_encode(&cd),
+                    signature: b64_encode(&signature),
+                    user_handle: None,
+                },
+                type_: "public-key".to_string(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::credential::webauthn::softtoken::SoftToken;
+    use crate::credential::webauthn::{counter_valid, WebauthnConfig};
+    use kanidm_proto::v1::OperationError;
+    use uuid::Uuid;
+
+    static ORIGIN: &'static str = "https://idm.example.com";
+
+    #[test]
+    fn test_webauthn_register_authenticate() {
+        let config = WebauthnConfig::new("idm.example.com", ORIGIN);
+        let mut token = SoftToken::new();
+
+        let (proto, chal) =
+            config.generate_challenge_register("admin", "Admin", &Uuid::new_v4(), &[]);
+        let reg = token.register(&proto, ORIGIN);
+        let wan = config
+            .register_credential(&reg, &chal, "yubikey")
+            .expect("Failed to register");
+        let tokens = vec![wan];
+
+        let (proto, chal) = config.generate_challenge_authenticate(&tokens);
+        assert!(proto.allow_credentials.len() == 1);
+        let asrt = token.sign(&proto, ORIGIN);
+        let (cred_id, counter) = config
+            .authenticate_credential(&tokens, &chal, &asrt)
+            .expect("Failed to authenticate");
+        assert!(cred_id == tokens[0].cred_id);
+        assert!(counter == 1);
+        assert!(counter_valid(tokens[0].counter, counter));
+
+        // A response to some other challenge is refused.
+        let (_, other_chal) = config.generate_challenge_authenticate(&tokens);
+        assert!(
+            config.authenticate_credential(&tokens, &other_chal, &asrt)
+                == Err(OperationError::InvalidWebauthn("incorrect challenge"))
+        );
+
+        // As is one from a token that isn't registered.
+        let mut other = SoftToken::new();
+        let asrt = other.sign(&proto, ORIGIN);
+        assert!(
+            config.authenticate_credential(&tokens, &chal, &asrt)
+                == Err(OperationError::InvalidWebauthn("unknown credential"))
+        );
+    }
+
+    #[test]
+    fn test_webauthn_reject_origin_and_rp() {
+        let config = WebauthnConfig::new("idm.example.com", ORIGIN);
+        let token = SoftToken::new();
+
+        let (proto, chal) =
+            config.generate_challenge_register("admin", "Admin", &Uuid::new_v4(), &[]);
+        let reg = token.register(&proto, "https://evil.example.com");
+        assert!(
+            config.register_credential(&reg, &chal, "yubikey").err()
+                == Some(OperationError::InvalidWebauthn("incorrect origin"))
+        );
+
+        let mut proto = proto;
+        proto.rp.id = "evil.example.com".to_string();
+        let reg = token.register(&proto, ORIGIN);
+        assert!(
+            config.register_credential(&reg, &chal, "yubikey").err()
+                == Some(OperationError::InvalidWebauthn("incorrect rp id"))
+        );
+    }
+
+    #[test]
+    fn test_webauthn_counter() {
+        assert!(counter_valid(0, 0));
+        assert!(counter_valid(0, 1));
+        assert!(counter_valid(4, 5));
+        assert!(!counter_valid(5, 5));
+        assert!(!counter_valid(5, 4));
+        assert!(!counter_valid(5, 0));
+    }
+}
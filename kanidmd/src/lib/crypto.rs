@@ -1,17 +1,65 @@
-use crate::config::Configuration;
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcKey, EcKeyRef};
+use openssl::ecdsa::EcdsaSig;
 use openssl::error::ErrorStack;
-use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{HasPublic, PKey, Private};
+use openssl::sha::sha256;
+use openssl::sign::Signer;
 
-pub fn setup_tls(config: &Configuration) -> Result<Option<SslAcceptorBuilder>, ErrorStack> {
-    match &config.tls_config {
-        Some(tls_config) => {
-            let mut ssl_builder = SslAcceptor::mozilla_modern(SslMethod::tls())?;
-            ssl_builder.set_ca_file(&tls_config.ca)?;
-            ssl_builder.set_private_key_file(&tls_config.key, SslFiletype::PEM)?;
-            ssl_builder.set_certificate_file(&tls_config.cert, SslFiletype::PEM)?;
-            ssl_builder.check_private_key()?;
-            Ok(Some(ssl_builder))
-        }
-        None => Ok(None),
+pub const HMAC_SHA256_LEN: usize = 32;
+// An ES256 signature is the r and s values, each padded to 32 bytes.
+const ES256_COMPONENT_LEN: i32 = 32;
+
+// Authenticate data that we hand to a client and expect back unchanged, such
+// as search page cookies.
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+    let pkey = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &pkey)?;
+    signer.update(data)?;
+    signer.sign_to_vec()
+}
+
+// Keys and signatures for ES256 (ECDSA over P-256 with SHA-256), as used in
+// JWS. The signature is in the fixed length r || s form that JWS requires,
+// not DER.
+pub fn es256_generate() -> Result<EcKey<Private>, ErrorStack> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    EcKey::generate(&group)
+}
+
+pub fn es256_sign(key: &EcKeyRef<Private>, data: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+    let sig = EcdsaSig::sign(&sha256(data), key)?;
+    let mut out = sig.r().to_vec_padded(ES256_COMPONENT_LEN)?;
+    out.extend(sig.s().to_vec_padded(ES256_COMPONENT_LEN)?);
+    Ok(out)
+}
+
+pub fn es256_verify<T: HasPublic>(
+    key: &EcKeyRef<T>,
+    data: &[u8],
+    sig: &[u8],
+) -> Result<bool, ErrorStack> {
+    if sig.len() != (ES256_COMPONENT_LEN * 2) as usize {
+        return Ok(false);
     }
+    let (r, s) = sig.split_at(ES256_COMPONENT_LEN as usize);
+    let sig = EcdsaSig::from_private_components(BigNum::from_slice(r)?, BigNum::from_slice(s)?)?;
+    sig.verify(&sha256(data), key)
+}
+
+// The x and y coordinates of the public key, as a JWK presents them.
+pub fn es256_public_coordinates<T: HasPublic>(
+    key: &EcKeyRef<T>,
+) -> Result<(Vec<u8>, Vec<u8>), ErrorStack> {
+    let mut ctx = BigNumContext::new()?;
+    let mut x = BigNum::new()?;
+    let mut y = BigNum::new()?;
+    key.public_key()
+        .affine_coordinates(key.group(), &mut x, &mut y, &mut ctx)?;
+    Ok((
+        x.to_vec_padded(ES256_COMPONENT_LEN)?,
+        y.to_vec_padded(ES256_COMPONENT_LEN)?,
+    ))
 }
@@ -0,0 +1,63 @@
+// Work derived from a change that touches too many entries to be done in the
+// write that made the change. The write records what is needed and commits,
+// then the write worker applies it in transactions of its own.
+//
+// The queue is only held in memory. If the server stops before the work is
+// applied, the consistency pass at the next start finds what was missed and
+// generates it again, so every action must be safe to apply more than once.
+use crate::metrics::Metrics;
+use std::collections::{BTreeSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DelayedAction {
+    // Recalculate the memberof of these entries, as the groups they are a
+    // member of have changed.
+    MemberOf(BTreeSet<Uuid>),
+    // Regenerate every spn, as the domain was renamed.
+    Spn,
+}
+
+pub struct DelayedActionQueue {
+    actions: Mutex<VecDeque<DelayedAction>>,
+    metrics: Arc<Metrics>,
+}
+
+impl DelayedActionQueue {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        DelayedActionQueue {
+            actions: Mutex::new(VecDeque::new()),
+            metrics: metrics,
+        }
+    }
+
+    pub fn push(&self, mut actions: Vec<DelayedAction>) {
+        if actions.is_empty() {
+            return;
+        }
+        let mut q = self.actions.lock().expect("delayed action lock poisoned");
+        for a in actions.drain(..) {
+            // Nothing is gained by regenerating the spns twice in a row.
+            if a == DelayedAction::Spn && q.back() == Some(&DelayedAction::Spn) {
+                continue;
+            }
+            q.push_back(a);
+        }
+        self.metrics.set_delayed_actions(q.len());
+    }
+
+    pub fn pop(&self) -> Option<DelayedAction> {
+        let mut q = self.actions.lock().expect("delayed action lock poisoned");
+        let a = q.pop_front();
+        self.metrics.set_delayed_actions(q.len());
+        a
+    }
+
+    pub fn len(&self) -> usize {
+        self.actions
+            .lock()
+            .expect("delayed action lock poisoned")
+            .len()
+    }
+}
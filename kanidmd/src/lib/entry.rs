@@ -15,6 +15,9 @@ use kanidm_proto::v1::{OperationError, SchemaError};
 
 use crate::be::dbentry::{DbEntry, DbEntryV1, DbEntryVers};
 
+use chrono::{DateTime, Utc};
+
+use std::cmp::Ordering;
 use std::collections::btree_map::{Iter as BTreeIter, IterMut as BTreeIterMut};
 use std::collections::btree_set::Iter as BTreeSetIter;
 use std::collections::BTreeMap;
@@ -205,20 +208,21 @@ impl Entry<EntryInvalid, EntryNew> {
 
         // Somehow we need to take the tree of e attrs, and convert
         // all ref types to our types ...
-        let map2: Result<BTreeMap<String, BTreeSet<Value>>, OperationError> = e
-            .attrs
-            .iter()
-            .map(|(k, v)| {
-                let nv: Result<BTreeSet<Value>, _> =
-                    v.iter().map(|vr| qs.clone_value(audit, &k, vr)).collect();
-                match nv {
-                    Ok(nvi) => Ok((k.clone(), nvi)),
-                    Err(e) => Err(e),
-                }
-            })
-            .collect();
-
-        let x = map2?;
+        let mut x: BTreeMap<String, BTreeSet<Value>> = BTreeMap::new();
+        for (k, v) in e.attrs.iter() {
+            let an = qs.clone_attr_name(k)?;
+            // Two names that fold to the same attribute, such as Name and name, are
+            // ambiguous, so rather than pick one we reject the entry.
+            if x.contains_key(&an) {
+                audit_log!(audit, "Duplicate attribute after normalisation -> {:?}", k);
+                return Err(OperationError::InvalidAttributeName(k.clone()));
+            }
+            let nv: BTreeSet<Value> = v
+                .iter()
+                .map(|vr| qs.clone_value(audit, &an, vr))
+                .collect::<Result<_, _>>()?;
+            x.insert(an, nv);
+        }
 
         Ok(Entry {
             // For now, we do a straight move, and we sort the incoming data
@@ -268,6 +272,7 @@ impl Entry<EntryInvalid, EntryNew> {
                         vs.into_iter().map(|v| Value::new_class(v.as_str())).collect()
                     }
                     "acp_create_attr" | "acp_search_attr" | "acp_modify_removedattr" | "acp_modify_presentattr" |
+                    "acp_compare_attr" |
                     "systemmay" | "may" | "systemmust" | "must" 
                     => {
                         vs.into_iter().map(|v| Value::new_attr(v.as_str())).collect()
@@ -283,7 +288,7 @@ impl Entry<EntryInvalid, EntryNew> {
                     "member" | "memberof" | "directmemberof" => {
                         vs.into_iter().map(|v| Value::new_refer_s(v.as_str()).unwrap() ).collect()
                     }
-                    "acp_enable" | "multivalue" | "unique" => {
+                    "acp_enable" | "multivalue" | "unique" | "secret" => {
                         vs.into_iter().map(|v| Value::new_bools(v.as_str())
                             .unwrap_or_else(|| {
                                 warn!("WARNING: Allowing syntax incorrect attribute to be presented UTF8 string");
@@ -318,6 +323,14 @@ impl Entry<EntryInvalid, EntryNew> {
                     "displayname" | "description" => {
                         vs.into_iter().map(|v| Value::new_utf8(v)).collect()
                     }
+                    "gidnumber" => {
+                        vs.into_iter().map(|v| Value::new_uint32s(v.as_str())
+                            .unwrap_or_else(|| {
+                                warn!("WARNING: Allowing syntax incorrect attribute to be presented UTF8 string");
+                                Value::new_utf8(v)
+                            })
+                        ).collect()
+                    }
                     ia => {
                         warn!("WARNING: Allowing invalid attribute {} to be interpretted as UTF8 string. YOU MAY ENCOUNTER ODD BEHAVIOUR!!!", ia);
                         vs.into_iter().map(|v| Value::new_utf8(v)).collect()
@@ -706,8 +719,20 @@ impl Entry<EntryValid, EntryCommitted> {
         self.attrs == rhs.attrs
     }
 
-    pub fn to_tombstone(&self) -> Self {
-        // Duplicate this to a tombstone entry
+    // The claims of a session are added to the entry of its account as the
+    // event is made, so that access controls can filter on them. This is
+    // only done to the event's copy of the entry, which is never written.
+    pub fn set_claims(&mut self, claims: Vec<&str>) {
+        let _ = self.attrs.remove("claim");
+        if !claims.is_empty() {
+            let vs: BTreeSet<_> = claims.into_iter().map(Value::new_iutf8s).collect();
+            let _ = self.attrs.insert("claim".to_string(), vs);
+        }
+    }
+
+    pub fn to_tombstone(&self, tombstoned_at: DateTime<Utc>) -> Self {
+        // Duplicate this to a tombstone entry, keeping when this happened so
+        // the tombstone can be purged once it has aged out.
         let class_ava = btreeset![Value::new_class("object"), Value::new_class("tombstone")];
 
         let mut attrs_new: BTreeMap<String, BTreeSet<Value>> = BTreeMap::new();
@@ -717,6 +742,10 @@ impl Entry<EntryValid, EntryCommitted> {
             btreeset![Value::new_uuidr(&self.valid.uuid)],
         );
         attrs_new.insert("class".to_string(), class_ava);
+        attrs_new.insert(
+            "tombstoned_at".to_string(),
+            btreeset![Value::new_datetime(tombstoned_at)],
+        );
 
         Entry {
             valid: self.valid.clone(),
@@ -1049,9 +1078,43 @@ impl<STATE> Entry<EntryValid, STATE> {
 
         Ok(mods)
     }
+
+    // As gen_modlist_assert, but only for what existing is missing. Any
+    // attribute existing already has is left as it is, so that changes made
+    // to it are kept, except for class, which is always asserted.
+    pub fn gen_modlist_repair<ESTATE>(
+        &self,
+        existing: &Entry<EntryValid, ESTATE>,
+    ) -> ModifyList<ModifyInvalid> {
+        let mut mods = ModifyList::new();
+
+        for (k, vs) in self.attrs.iter() {
+            if k == "uuid" || (k != "class" && existing.attrs.contains_key(k)) {
+                continue;
+            }
+            for v in vs {
+                mods.push_mod(Modify::Present(k.clone(), v.clone()));
+            }
+        }
+
+        mods
+    }
 }
 
 impl Entry<EntryReduced, EntryCommitted> {
+    pub fn get_id(&self) -> u64 {
+        self.state.id
+    }
+
+    // The value to sort this entry by. Values are kept in order, so this is
+    // the smallest value of the attribute, compared by its syntax.
+    pub fn get_sort_key(&self, attr: &str) -> Option<PartialValue> {
+        self.attrs
+            .get(attr)
+            .and_then(|vs| vs.iter().next())
+            .map(|v| v.to_partialvalue())
+    }
+
     pub fn into_pe(
         &self,
         audit: &mut AuditScope,
@@ -1070,6 +1133,28 @@ impl Entry<EntryReduced, EntryCommitted> {
             .collect();
         Ok(ProtoEntry { attrs: attrs? })
     }
+
+    // Reduce the entry to only the requested attributes. The uuid is always
+    // retained so the client can identify the entry. Anything the access
+    // controls already removed stays removed.
+    pub fn project_attributes(self, attrs: &BTreeSet<String>) -> Self {
+        let Entry {
+            valid,
+            state,
+            attrs: e_attrs,
+        } = self;
+
+        let p_attrs = e_attrs
+            .into_iter()
+            .filter(|(k, _)| k == "uuid" || attrs.contains(k))
+            .collect();
+
+        Entry {
+            valid: valid,
+            state: state,
+            attrs: p_attrs,
+        }
+    }
 }
 
 // impl<STATE> Entry<EntryValid, STATE> {
@@ -1138,6 +1223,16 @@ impl<VALID, STATE> Entry<VALID, STATE> {
         self.attribute_equality(attr, value)
     }
 
+    // Whether every assertion in the modlist holds for this entry as it is
+    // now. The other modifications are not considered.
+    pub fn modlist_assertions_hold(&self, modlist: &ModifyList<ModifyValid>) -> bool {
+        modlist.iter().all(|modify| match modify {
+            Modify::Assert(a, v) => self.attribute_value_pres(a.as_str(), v),
+            Modify::AssertMissing(a) => !self.attribute_pres(a.as_str()),
+            _ => true,
+        })
+    }
+
     pub fn attribute_equality(&self, attr: &str, value: &PartialValue) -> bool {
         // we assume based on schema normalisation on the way in
         // that the equality here of the raw values MUST be correct.
@@ -1149,6 +1244,35 @@ impl<VALID, STATE> Entry<VALID, STATE> {
         }
     }
 
+    pub fn attribute_inclusion(&self, attr: &str, values: &[PartialValue]) -> bool {
+        match self.attrs.get(attr) {
+            Some(v_list) => values.iter().any(|pv| v_list.contains(pv)),
+            None => false,
+        }
+    }
+
+    // These compare with the ordering of the values syntax, so integers are
+    // numeric, and strings are lexical. Values of differing types never match.
+    pub fn attribute_greater_or_equal(&self, attr: &str, value: &PartialValue) -> bool {
+        match self.attrs.get(attr) {
+            Some(v_list) => v_list.iter().any(|v| match v.ordering(value) {
+                Some(Ordering::Greater) | Some(Ordering::Equal) => true,
+                _ => false,
+            }),
+            None => false,
+        }
+    }
+
+    pub fn attribute_less_or_equal(&self, attr: &str, value: &PartialValue) -> bool {
+        match self.attrs.get(attr) {
+            Some(v_list) => v_list.iter().any(|v| match v.ordering(value) {
+                Some(Ordering::Less) | Some(Ordering::Equal) => true,
+                _ => false,
+            }),
+            None => false,
+        }
+    }
+
     pub fn attribute_substring(&self, attr: &str, subvalue: &PartialValue) -> bool {
         match self.attrs.get(attr) {
             Some(v_list) => v_list
@@ -1195,6 +1319,13 @@ impl<VALID, STATE> Entry<VALID, STATE> {
                 // Given attr, is is present in the entry?
                 self.attribute_pres(attr.as_str())
             }
+            FilterResolved::Gte(attr, value) => {
+                self.attribute_greater_or_equal(attr.as_str(), value)
+            }
+            FilterResolved::Lte(attr, value) => self.attribute_less_or_equal(attr.as_str(), value),
+            FilterResolved::Inclusion(attr, values) => {
+                self.attribute_inclusion(attr.as_str(), values.as_slice())
+            }
             FilterResolved::Or(l) => l.iter().fold(false, |acc, f| {
                 // Check with ftweedal about or filter zero len correctness.
                 if acc {
@@ -1212,6 +1343,8 @@ impl<VALID, STATE> Entry<VALID, STATE> {
                 }
             }),
             FilterResolved::AndNot(f) => !self.entry_match_no_index_inner(f),
+            FilterResolved::True => true,
+            FilterResolved::False => false,
         }
     }
 }
@@ -1278,6 +1411,12 @@ where
                 Modify::Present(a, v) => self.add_ava(a.as_str(), v),
                 Modify::Removed(a, v) => self.remove_ava(a.as_str(), v),
                 Modify::Purged(a) => self.purge_ava(a.as_str()),
+                Modify::Set(a, vs) => {
+                    self.purge_ava(a.as_str());
+                    vs.iter().for_each(|v| self.add_ava(a.as_str(), v));
+                }
+                // Assertions are checked before the modlist is applied.
+                Modify::Assert(_, _) | Modify::AssertMissing(_) => {}
             }
         }
     }
@@ -1322,6 +1461,9 @@ impl From<&SchemaAttribute> for Entry<EntryValid, EntryNew> {
         attrs.insert("uuid".to_string(), uuid_v);
         attrs.insert("multivalue".to_string(), multivalue_v);
         attrs.insert("unique".to_string(), unique_v);
+        if s.secret {
+            attrs.insert("secret".to_string(), btreeset![Value::from(true)]);
+        }
         attrs.insert("index".to_string(), index_v);
         attrs.insert("syntax".to_string(), syntax_v);
         attrs.insert(
@@ -1,10 +1,16 @@
 use crate::audit::AuditScope;
+use crate::cid::Cid;
+use crate::constants::{_UUID_IDM_ACP_MANAGER_PRIV, _UUID_IDM_ADMINS};
 use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntryReduced, EntryValid};
 use crate::filter::{Filter, FilterValid};
+use crate::value::PartialValue;
 use kanidm_proto::v1::Entry as ProtoEntry;
+use kanidm_proto::v1::Filter as ProtoFilter;
+use kanidm_proto::v1::ModifyList as ProtoModifyList;
 use kanidm_proto::v1::{
-    AuthCredential, AuthResponse, AuthState, AuthStep, SearchResponse, UserAuthToken,
-    WhoamiResponse,
+    AccessCheckOperation, AuthCredential, AuthResponse, AuthState, AuthStep, ExportRequest,
+    SchemaAttribute, SchemaClass, SchemaResponse, SearchRequest, SearchResponse, SortOrder,
+    UserAuthToken, WhoamiResponse,
 };
 // use error::OperationError;
 use crate::modify::{ModifyList, ModifyValid};
@@ -12,8 +18,15 @@ use crate::server::{
     QueryServerReadTransaction, QueryServerTransaction, QueryServerWriteTransaction,
 };
 use kanidm_proto::v1::OperationError;
+#[cfg(test)]
+use kanidm_proto::v1::WebauthnAssertion;
 
-use crate::actors::v1::{AuthMessage, CreateMessage, DeleteMessage, ModifyMessage, SearchMessage};
+use crate::actors::v1::{
+    AccessCheckMessage, AuditListMessage, AuthMessage, BackupMessage, ChangesMessage,
+    CompareMessage, CreateMessage, DeleteMessage, EffectiveAccessMessage, IndexStatusMessage,
+    ModifyBatchMessage, ModifyMessage, ReauthMessage, ReindexMessage, ReviveRecycledMessage,
+    SchemaMessage, SearchCountMessage, SearchMessage, SearchRecycledMessage, VacuumMessage,
+};
 // Bring in schematransaction trait for validate
 // use crate::schema::SchemaTransaction;
 
@@ -24,11 +37,16 @@ use crate::filter::FilterInvalid;
 use crate::modify::ModifyInvalid;
 
 use actix::prelude::*;
+use chrono::DateTime;
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::time::{Duration, SystemTime};
 use uuid::Uuid;
 
 #[derive(Debug)]
 pub struct SearchResult {
     entries: Vec<ProtoEntry>,
+    next_cookie: Option<String>,
 }
 
 impl SearchResult {
@@ -36,6 +54,7 @@ impl SearchResult {
         audit: &mut AuditScope,
         qs: &QueryServerReadTransaction,
         entries: Vec<Entry<EntryReduced, EntryCommitted>>,
+        next_cookie: Option<String>,
     ) -> Result<Self, OperationError> {
         let entries: Result<_, _> = entries
             .iter()
@@ -46,20 +65,119 @@ impl SearchResult {
                 e.into_pe(audit, qs)
             })
             .collect();
-        Ok(SearchResult { entries: entries? })
+        Ok(SearchResult {
+            entries: entries?,
+            next_cookie: next_cookie,
+        })
     }
 
     // Consume self into a search response
     pub fn response(self) -> SearchResponse {
         SearchResponse {
             entries: self.entries,
+            next_cookie: self.next_cookie,
+            plan: None,
         }
     }
 }
 
+#[derive(Debug)]
+pub struct SchemaResult {
+    attributes: Vec<SchemaAttribute>,
+    classes: Vec<SchemaClass>,
+}
+
+impl SchemaResult {
+    // Project the schema entries we were allowed to read into their typed
+    // forms. Anything we can't read is left empty rather than failing.
+    pub fn new(entries: Vec<Entry<EntryReduced, EntryCommitted>>) -> Self {
+        let single_str = |e: &Entry<EntryReduced, EntryCommitted>, attr: &str| {
+            e.get_ava_single(attr)
+                .and_then(|v| v.to_str())
+                .map(|s| s.to_string())
+                .unwrap_or_else(|| String::new())
+        };
+        let merged_set = |e: &Entry<EntryReduced, EntryCommitted>, sys: &str, attr: &str| {
+            let mut r: BTreeSet<&str> = e.get_ava_set_str(sys).unwrap_or_else(|| BTreeSet::new());
+            if let Some(s) = e.get_ava_set_str(attr) {
+                r.extend(s);
+            }
+            r.into_iter().map(|s| s.to_string()).collect()
+        };
+
+        let mut attributes = Vec::new();
+        let mut classes = Vec::new();
+        entries.iter().for_each(|e| {
+            if e.attribute_value_pres("class", &PartialValue::new_class("attributetype")) {
+                attributes.push(SchemaAttribute {
+                    name: single_str(e, "attributename"),
+                    description: single_str(e, "description"),
+                    multivalue: e
+                        .get_ava_single("multivalue")
+                        .and_then(|v| v.to_bool())
+                        .unwrap_or(false),
+                    unique: e
+                        .get_ava_single("unique")
+                        .and_then(|v| v.to_bool())
+                        .unwrap_or(false),
+                    secret: e
+                        .get_ava_single("secret")
+                        .and_then(|v| v.to_bool())
+                        .unwrap_or(false),
+                    syntax: e
+                        .get_ava_single("syntax")
+                        .and_then(|v| v.to_syntaxtype())
+                        .map(|s| s.to_string())
+                        .unwrap_or_else(|| String::new()),
+                    index: e
+                        .get_ava("index")
+                        .map(|vs| vs.iter().map(|v| v.to_proto_string_clone()).collect())
+                        .unwrap_or_else(|| Vec::new()),
+                });
+            } else if e.attribute_value_pres("class", &PartialValue::new_class("classtype")) {
+                classes.push(SchemaClass {
+                    name: single_str(e, "classname"),
+                    must: merged_set(e, "systemmust", "must"),
+                    may: merged_set(e, "systemmay", "may"),
+                });
+            }
+        });
+        attributes.sort_by(|a, b| a.name.cmp(&b.name));
+        classes.sort_by(|a, b| a.name.cmp(&b.name));
+
+        SchemaResult {
+            attributes: attributes,
+            classes: classes,
+        }
+    }
+
+    pub fn response(self) -> SchemaResponse {
+        SchemaResponse::new(self.attributes, self.classes)
+    }
+}
+
 // At the top we get "event types" and they contain the needed
 // actions, and a generic event component.
 
+fn now() -> Duration {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Clock failure!")
+}
+
+// Add the claims of the token that haven't expired at ct to the entry of its
+// account, so access controls can filter on them. Once a claim expires, the
+// session continues without it.
+fn apply_uat_claims(e: &mut Entry<EntryValid, EntryCommitted>, uat: &UserAuthToken, ct: Duration) {
+    let claims: Vec<&str> = uat
+        .claims
+        .iter()
+        .filter(|c| !c.is_expired(ct))
+        .map(|c| c.name.as_str())
+        .collect();
+    e.set_claims(claims);
+}
+
 #[derive(Debug, Clone)]
 pub enum EventOrigin {
     // External event, needs a UUID associated! Perhaps even an Entry/User to improve ACP checks?
@@ -76,6 +194,10 @@ pub struct Event {
     // The event's initiator aka origin source.
     // This importantly, is used for access control!
     pub origin: EventOrigin,
+    // When the session the event was made with last gave its credentials,
+    // for operations that require this to be recent. Internal events, and
+    // those not made with a token, have none.
+    pub auth_time: Option<u64>,
 }
 
 impl Event {
@@ -97,6 +219,7 @@ impl Event {
 
         Ok(Event {
             origin: EventOrigin::User(e),
+            auth_time: None,
         })
     }
 
@@ -112,12 +235,12 @@ impl Event {
             Uuid::parse_str(uat.uuid.as_str()).map_err(|_| OperationError::InvalidUuid)
         );
 
-        let e = try_audit!(audit, qs.internal_search_uuid(audit, &u));
-        // TODO #64: Now apply claims from the uat into the Entry
-        // to allow filtering.
+        let mut e = try_audit!(audit, qs.internal_search_uuid(audit, &u));
+        apply_uat_claims(&mut e, &uat, now());
 
         Ok(Event {
             origin: EventOrigin::User(e),
+            auth_time: Some(uat.auth_time),
         })
     }
 
@@ -128,17 +251,22 @@ impl Event {
     ) -> Result<Self, OperationError> {
         audit_log!(audit, "from_rw_uat -> {:?}", uat);
         let uat = uat.ok_or(OperationError::NotAuthenticated)?;
+        // A read only service session may search, but never write.
+        if uat.read_only {
+            audit_log!(audit, "read only session may not write");
+            return Err(OperationError::AccessDenied);
+        }
         let u = try_audit!(
             audit,
             Uuid::parse_str(uat.uuid.as_str()).map_err(|_| OperationError::InvalidUuid)
         );
 
-        let e = try_audit!(audit, qs.internal_search_uuid(audit, &u));
-        // TODO #64: Now apply claims from the uat into the Entry
-        // to allow filtering.
+        let mut e = try_audit!(audit, qs.internal_search_uuid(audit, &u));
+        apply_uat_claims(&mut e, &uat, now());
 
         Ok(Event {
             origin: EventOrigin::User(e),
+            auth_time: Some(uat.auth_time),
         })
     }
 
@@ -159,12 +287,14 @@ impl Event {
 
         Ok(Event {
             origin: EventOrigin::User(e),
+            auth_time: None,
         })
     }
 
     pub fn from_internal() -> Self {
         Event {
             origin: EventOrigin::Internal,
+            auth_time: None,
         }
     }
 
@@ -172,6 +302,7 @@ impl Event {
     pub fn from_impersonate_entry(e: Entry<EntryValid, EntryCommitted>) -> Self {
         Event {
             origin: EventOrigin::User(e),
+            auth_time: None,
         }
     }
 
@@ -204,7 +335,22 @@ pub struct SearchEvent {
     pub filter: Filter<FilterValid>,
     // This is the original filter, for the purpose of ACI checking.
     pub filter_orig: Filter<FilterValid>,
-    // TODO #83: Add list of attributes to request
+    // The attributes the client asked to be returned, or None for all.
+    pub attrs: Option<BTreeSet<String>>,
+    // The most entries to return in one page, if paging.
+    pub page_size: Option<usize>,
+    // Resume a paged search after this position.
+    pub page_after: Option<PagePosition>,
+    // The attribute and direction to order results by.
+    pub sort: Option<(String, SortOrder)>,
+}
+
+// Where an entry falls in the order of a sorted or paged search. Ties on the
+// sort key, and entries without it, are broken by entry id.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PagePosition {
+    key: Option<PartialValue>,
+    id: u64,
 }
 
 impl SearchEvent {
@@ -213,9 +359,36 @@ impl SearchEvent {
         msg: SearchMessage,
         qs: &QueryServerReadTransaction,
     ) -> Result<Self, OperationError> {
-        match Filter::from_ro(audit, &msg.req.filter, qs) {
+        let event = Event::from_ro_uat(audit, qs, msg.uat)?;
+        let attrs = match &msg.req.attrs {
+            Some(attrs) => Some(
+                attrs
+                    .iter()
+                    .map(|a| qs.clone_attr_name(a))
+                    .collect::<Result<BTreeSet<_>, _>>()?,
+            ),
+            None => None,
+        };
+        let page_size = match msg.req.page_size {
+            Some(0) => return Err(OperationError::InvalidRequestState),
+            Some(s) => Some(s as usize),
+            None => None,
+        };
+        let sort = match &msg.req.sort {
+            Some((attr, order)) => Some((qs.clone_attr_name(attr)?, *order)),
+            None => None,
+        };
+        let page_after = match &msg.req.page_cookie {
+            Some(cookie) => Some(SearchEvent::resolve_page_after(qs, cookie, &sort)?),
+            None => None,
+        };
+        match Filter::from_ro(audit, &event, &msg.req.filter, qs) {
             Ok(f) => Ok(SearchEvent {
-                event: Event::from_ro_uat(audit, qs, msg.uat)?,
+                event: event,
+                attrs: attrs,
+                page_size: page_size,
+                page_after: page_after,
+                sort: sort,
                 // We do need to do this twice to account for the ignore_hidden
                 // changes.
                 filter: f
@@ -231,6 +404,41 @@ impl SearchEvent {
         }
     }
 
+    pub fn from_count_message(
+        audit: &mut AuditScope,
+        msg: SearchCountMessage,
+        qs: &QueryServerReadTransaction,
+    ) -> Result<Self, OperationError> {
+        let req = SearchRequest::new(msg.req.filter);
+        SearchEvent::from_message(audit, SearchMessage::new(msg.eventid, msg.uat, req), qs)
+    }
+
+    // Search every attribute and class definition in the schema.
+    pub fn from_schema_message(
+        audit: &mut AuditScope,
+        msg: SchemaMessage,
+        qs: &QueryServerReadTransaction,
+    ) -> Result<Self, OperationError> {
+        let f = filter!(f_or(vec![
+            f_eq("class", PartialValue::new_class("attributetype")),
+            f_eq("class", PartialValue::new_class("classtype"))
+        ]));
+        Ok(SearchEvent {
+            event: Event::from_ro_uat(audit, qs, msg.uat)?,
+            filter: f
+                .clone()
+                .validate(qs.get_schema())
+                .map_err(|e| OperationError::SchemaViolation(e))?,
+            filter_orig: f
+                .validate(qs.get_schema())
+                .map_err(|e| OperationError::SchemaViolation(e))?,
+            attrs: None,
+            page_size: None,
+            page_after: None,
+            sort: None,
+        })
+    }
+
     pub fn from_whoami_request(
         audit: &mut AuditScope,
         uat: Option<UserAuthToken>,
@@ -244,6 +452,10 @@ impl SearchEvent {
             filter_orig: filter_all!(f_self())
                 .validate(qs.get_schema())
                 .map_err(|e| OperationError::SchemaViolation(e))?,
+            attrs: None,
+            page_size: None,
+            page_after: None,
+            sort: None,
         })
     }
 
@@ -254,6 +466,10 @@ impl SearchEvent {
             event: Event::from_impersonate_entry_ser(e),
             filter: filter.clone().to_valid(),
             filter_orig: filter.to_valid(),
+            attrs: None,
+            page_size: None,
+            page_after: None,
+            sort: None,
         }
     }
 
@@ -266,6 +482,10 @@ impl SearchEvent {
             event: Event::from_impersonate_entry(e),
             filter: filter.clone().to_valid(),
             filter_orig: filter.to_valid(),
+            attrs: None,
+            page_size: None,
+            page_after: None,
+            sort: None,
         }
     }
 
@@ -278,20 +498,22 @@ impl SearchEvent {
             event: Event::from_impersonate(event),
             filter: filter,
             filter_orig: filter_orig,
+            attrs: None,
+            page_size: None,
+            page_after: None,
+            sort: None,
         }
     }
 
-    /*
-    #[cfg(test)]
-    #[allow(dead_code)]
-    pub fn from_rec_request(
+    pub fn from_rec_message(
         audit: &mut AuditScope,
-        request: SearchRecycledRequest,
+        msg: SearchRecycledMessage,
         qs: &QueryServerReadTransaction,
     ) -> Result<Self, OperationError> {
-        match Filter::from_ro(audit, &request.filter, qs) {
+        let event = Event::from_ro_uat(audit, qs, msg.uat)?;
+        match Filter::from_ro(audit, &event, &msg.req.filter, qs) {
             Ok(f) => Ok(SearchEvent {
-                event: Event::from_ro_uat(audit, qs, msg.uat)?,
+                event: event,
                 filter: f
                     .clone()
                     .to_recycled()
@@ -300,11 +522,14 @@ impl SearchEvent {
                 filter_orig: f
                     .validate(qs.get_schema())
                     .map_err(|e| OperationError::SchemaViolation(e))?,
+                attrs: None,
+                page_size: None,
+                page_after: None,
+                sort: None,
             }),
             Err(e) => Err(e),
         }
     }
-    */
 
     #[cfg(test)]
     /* Impersonate a request for recycled objects */
@@ -316,6 +541,10 @@ impl SearchEvent {
             event: Event::from_impersonate_entry(e),
             filter: filter.clone().to_recycled().to_valid(),
             filter_orig: filter.to_valid(),
+            attrs: None,
+            page_size: None,
+            page_after: None,
+            sort: None,
         }
     }
 
@@ -329,6 +558,10 @@ impl SearchEvent {
             event: Event::from_impersonate_entry(e),
             filter: filter.clone().to_ignore_hidden().to_valid(),
             filter_orig: filter.to_valid(),
+            attrs: None,
+            page_size: None,
+            page_after: None,
+            sort: None,
         }
     }
 
@@ -338,6 +571,10 @@ impl SearchEvent {
             event: Event::from_internal(),
             filter: filter.clone().to_valid(),
             filter_orig: filter.to_valid(),
+            attrs: None,
+            page_size: None,
+            page_after: None,
+            sort: None,
         }
     }
 
@@ -346,8 +583,64 @@ impl SearchEvent {
             event: Event::from_internal(),
             filter: filter.clone(),
             filter_orig: filter,
+            attrs: None,
+            page_size: None,
+            page_after: None,
+            sort: None,
         }
     }
+
+    // Does this search need its results put in order?
+    pub fn is_ordered(&self) -> bool {
+        self.sort.is_some() || self.page_size.is_some() || self.page_after.is_some()
+    }
+
+    pub fn page_position(&self, e: &Entry<EntryReduced, EntryCommitted>) -> PagePosition {
+        PagePosition {
+            key: self
+                .sort
+                .as_ref()
+                .and_then(|(attr, _)| e.get_sort_key(attr.as_str())),
+            id: e.get_id(),
+        }
+    }
+
+    pub fn cmp_page_position(&self, a: &PagePosition, b: &PagePosition) -> Ordering {
+        let key_ord = match (&a.key, &b.key) {
+            (Some(ka), Some(kb)) => match &self.sort {
+                Some((_, SortOrder::Descending)) => kb.cmp(ka),
+                _ => ka.cmp(kb),
+            },
+            // Entries without the attribute go last in either direction.
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        };
+        key_ord.then(a.id.cmp(&b.id))
+    }
+
+    // Decode a page cookie back to the position to resume the search after.
+    pub fn resolve_page_after<T: QueryServerTransaction>(
+        qs: &T,
+        cookie: &str,
+        sort: &Option<(String, SortOrder)>,
+    ) -> Result<PagePosition, OperationError> {
+        let data = qs.resolve_page_cookie(cookie)?;
+        let (c_sort, position): (Option<(String, SortOrder)>, PagePosition) =
+            serde_cbor::from_slice(data.as_slice())
+                .map_err(|_| OperationError::InvalidRequestState)?;
+        // A cookie only continues a search in the order it came from.
+        if c_sort == *sort {
+            Ok(position)
+        } else {
+            Err(OperationError::InvalidRequestState)
+        }
+    }
+
+    // The cookie data to continue this search after the given position.
+    pub fn page_cookie_data(&self, p: &PagePosition) -> Result<Vec<u8>, OperationError> {
+        serde_cbor::to_vec(&(&self.sort, p)).map_err(|_| OperationError::SerdeCborError)
+    }
 }
 
 // Represents the decoded entries from the protocol -> internal entry representation
@@ -399,6 +692,17 @@ impl CreateEvent {
         }
     }
 
+    #[cfg(test)]
+    pub unsafe fn new_impersonate_entry(
+        e: Entry<EntryValid, EntryCommitted>,
+        entries: Vec<Entry<EntryInvalid, EntryNew>>,
+    ) -> Self {
+        CreateEvent {
+            event: Event::from_impersonate_entry(e),
+            entries: entries,
+        }
+    }
+
     pub fn new_internal(entries: Vec<Entry<EntryInvalid, EntryNew>>) -> Self {
         CreateEvent {
             event: Event::from_internal(),
@@ -407,6 +711,63 @@ impl CreateEvent {
     }
 }
 
+#[derive(Debug)]
+pub struct CompareEvent {
+    pub event: Event,
+    // This is the filter, as it will be processed.
+    pub filter: Filter<FilterValid>,
+    // This is the original filter, for the purpose of ACI checking.
+    pub filter_orig: Filter<FilterValid>,
+    pub attr: String,
+    // Normalised to the syntax of attr, so this is directly comparable to the
+    // values on the entry.
+    pub value: PartialValue,
+}
+
+impl CompareEvent {
+    pub fn from_message(
+        audit: &mut AuditScope,
+        msg: CompareMessage,
+        qs: &QueryServerReadTransaction,
+    ) -> Result<Self, OperationError> {
+        let event = Event::from_ro_uat(audit, qs, msg.uat)?;
+        let attr = qs.clone_attr_name(&msg.req.attr)?;
+        let value = qs.clone_partialvalue(audit, &attr, &msg.req.value)?;
+        match Filter::from_ro(audit, &event, &msg.req.filter, qs) {
+            Ok(f) => Ok(CompareEvent {
+                event: event,
+                attr: attr,
+                value: value,
+                filter: f
+                    .clone()
+                    .to_ignore_hidden()
+                    .validate(qs.get_schema())
+                    .map_err(|e| OperationError::SchemaViolation(e))?,
+                filter_orig: f
+                    .validate(qs.get_schema())
+                    .map_err(|e| OperationError::SchemaViolation(e))?,
+            }),
+            Err(e) => Err(e),
+        }
+    }
+
+    #[cfg(test)]
+    pub unsafe fn new_impersonate_entry_ser(
+        e: &str,
+        filter: Filter<FilterInvalid>,
+        attr: &str,
+        value: PartialValue,
+    ) -> Self {
+        CompareEvent {
+            event: Event::from_impersonate_entry_ser(e),
+            filter: filter.clone().to_valid(),
+            filter_orig: filter.to_valid(),
+            attr: attr.to_string(),
+            value: value,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ExistsEvent {
     pub event: Event,
@@ -443,6 +804,9 @@ pub struct DeleteEvent {
     pub filter: Filter<FilterValid>,
     // This is the original filter, for the purpose of ACI checking.
     pub filter_orig: Filter<FilterValid>,
+    // If true, a filter that matches nothing deletes nothing rather than
+    // being an error.
+    pub allow_empty: bool,
 }
 
 impl DeleteEvent {
@@ -451,9 +815,11 @@ impl DeleteEvent {
         msg: DeleteMessage,
         qs: &QueryServerWriteTransaction,
     ) -> Result<Self, OperationError> {
-        match Filter::from_rw(audit, &msg.req.filter, qs) {
+        let event = Event::from_rw_uat(audit, qs, msg.uat)?;
+        match Filter::from_rw(audit, &event, &msg.req.filter, qs) {
             Ok(f) => Ok(DeleteEvent {
-                event: Event::from_rw_uat(audit, qs, msg.uat)?,
+                event: event,
+                allow_empty: msg.req.allow_empty,
                 filter: f
                     .clone()
                     .to_ignore_hidden()
@@ -476,6 +842,7 @@ impl DeleteEvent {
             event: Event::from_impersonate_entry(e),
             filter: filter.clone().to_valid(),
             filter_orig: filter.to_valid(),
+            allow_empty: false,
         }
     }
 
@@ -485,6 +852,7 @@ impl DeleteEvent {
             event: Event::from_impersonate_entry_ser(e),
             filter: filter.clone().to_valid(),
             filter_orig: filter.to_valid(),
+            allow_empty: false,
         }
     }
 
@@ -494,6 +862,7 @@ impl DeleteEvent {
             event: Event::from_internal(),
             filter: filter.clone().to_valid(),
             filter_orig: filter.to_valid(),
+            allow_empty: false,
         }
     }
 
@@ -502,6 +871,20 @@ impl DeleteEvent {
             event: Event::from_internal(),
             filter: filter.clone(),
             filter_orig: filter,
+            allow_empty: false,
+        }
+    }
+
+    pub fn new_impersonate(
+        event: &Event,
+        filter: Filter<FilterValid>,
+        filter_orig: Filter<FilterValid>,
+    ) -> Self {
+        DeleteEvent {
+            event: Event::from_impersonate(event),
+            filter: filter,
+            filter_orig: filter_orig,
+            allow_empty: false,
         }
     }
 }
@@ -514,6 +897,9 @@ pub struct ModifyEvent {
     // This is the original filter, for the purpose of ACI checking.
     pub filter_orig: Filter<FilterValid>,
     pub modlist: ModifyList<ModifyValid>,
+    // If true, a filter that matches nothing modifies nothing rather than
+    // being an error. Internal modifications always allow this.
+    pub allow_empty: bool,
 }
 
 impl ModifyEvent {
@@ -522,10 +908,30 @@ impl ModifyEvent {
         msg: ModifyMessage,
         qs: &QueryServerWriteTransaction,
     ) -> Result<Self, OperationError> {
-        match Filter::from_rw(audit, &msg.req.filter, qs) {
-            Ok(f) => match ModifyList::from(audit, &msg.req.modlist, qs) {
+        let event = Event::from_rw_uat(audit, qs, msg.uat)?;
+        Self::from_parts(
+            audit,
+            event,
+            &msg.req.filter,
+            &msg.req.modlist,
+            msg.req.allow_empty,
+            qs,
+        )
+    }
+
+    fn from_parts(
+        audit: &mut AuditScope,
+        event: Event,
+        filter: &ProtoFilter,
+        modlist: &ProtoModifyList,
+        allow_empty: bool,
+        qs: &QueryServerWriteTransaction,
+    ) -> Result<Self, OperationError> {
+        match Filter::from_rw(audit, &event, filter, qs) {
+            Ok(f) => match ModifyList::from(audit, modlist, qs) {
                 Ok(m) => Ok(ModifyEvent {
-                    event: Event::from_rw_uat(audit, qs, msg.uat)?,
+                    event: event,
+                    allow_empty: allow_empty,
                     filter: f
                         .clone()
                         .to_ignore_hidden()
@@ -551,6 +957,7 @@ impl ModifyEvent {
             filter: filter.clone(),
             filter_orig: filter,
             modlist: modlist,
+            allow_empty: false,
         }
     }
 
@@ -564,6 +971,7 @@ impl ModifyEvent {
             filter: filter.clone().to_valid(),
             filter_orig: filter.to_valid(),
             modlist: modlist.to_valid(),
+            allow_empty: false,
         }
     }
 
@@ -578,6 +986,7 @@ impl ModifyEvent {
             filter: filter.clone().to_valid(),
             filter_orig: filter.to_valid(),
             modlist: modlist.to_valid(),
+            allow_empty: false,
         }
     }
 
@@ -592,6 +1001,7 @@ impl ModifyEvent {
             filter: filter.clone().to_valid(),
             filter_orig: filter.to_valid(),
             modlist: modlist.to_valid(),
+            allow_empty: false,
         }
     }
 
@@ -606,10 +1016,44 @@ impl ModifyEvent {
             filter: filter,
             filter_orig: filter_orig,
             modlist: modlist,
+            allow_empty: false,
         }
     }
 }
 
+// A set of modifications that must all succeed or all fail together.
+#[derive(Debug)]
+pub struct ModifyBatchEvent {
+    pub modifies: Vec<ModifyEvent>,
+}
+
+impl ModifyBatchEvent {
+    pub fn from_message(
+        audit: &mut AuditScope,
+        msg: ModifyBatchMessage,
+        qs: &QueryServerWriteTransaction,
+    ) -> Result<Self, OperationError> {
+        let event = Event::from_rw_uat(audit, qs, msg.uat)?;
+        let modifies: Result<Vec<_>, _> = msg
+            .req
+            .changes
+            .iter()
+            .enumerate()
+            .map(|(i, (filter, modlist))| {
+                ModifyEvent::from_parts(audit, event.clone(), filter, modlist, false, qs)
+                    .map_err(|e| OperationError::BatchItemFailed(i as u64, Box::new(e)))
+            })
+            .collect();
+        Ok(ModifyBatchEvent {
+            modifies: modifies?,
+        })
+    }
+
+    pub fn new_internal(modifies: Vec<ModifyEvent>) -> Self {
+        ModifyBatchEvent { modifies: modifies }
+    }
+}
+
 #[derive(Debug)]
 pub struct AuthEventStepInit {
     pub name: String,
@@ -622,10 +1066,25 @@ pub struct AuthEventStepCreds {
     pub creds: Vec<AuthCredential>,
 }
 
+#[derive(Debug)]
+pub struct AuthEventStepRequestClaims {
+    pub sessionid: Uuid,
+    pub claims: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct AuthEventStepReauth {
+    // The token of the session to reauthenticate.
+    pub uat: UserAuthToken,
+    pub appid: Option<String>,
+}
+
 #[derive(Debug)]
 pub enum AuthEventStep {
     Init(AuthEventStepInit),
     Creds(AuthEventStepCreds),
+    RequestClaims(AuthEventStepRequestClaims),
+    Reauth(AuthEventStepReauth),
 }
 
 impl AuthEventStep {
@@ -652,9 +1111,26 @@ impl AuthEventStep {
                     "session id not present in cred",
                 )),
             },
+            AuthStep::RequestClaims(claims) => match sid {
+                Some(ssid) => Ok(AuthEventStep::RequestClaims(AuthEventStepRequestClaims {
+                    sessionid: ssid,
+                    claims: claims,
+                })),
+                None => Err(OperationError::InvalidAuthState(
+                    "session id not present in claim request",
+                )),
+            },
         }
     }
 
+    #[cfg(test)]
+    pub fn request_claims(sid: Uuid, claims: Vec<&str>) -> Self {
+        AuthEventStep::RequestClaims(AuthEventStepRequestClaims {
+            sessionid: sid,
+            claims: claims.into_iter().map(|c| c.to_string()).collect(),
+        })
+    }
+
     #[cfg(test)]
     pub fn anonymous_init() -> Self {
         AuthEventStep::Init(AuthEventStepInit {
@@ -686,6 +1162,38 @@ impl AuthEventStep {
             creds: vec![AuthCredential::Password(pw.to_string())],
         })
     }
+
+    #[cfg(test)]
+    pub fn cred_step_totp(sid: Uuid, totp: u32) -> Self {
+        AuthEventStep::Creds(AuthEventStepCreds {
+            sessionid: sid,
+            creds: vec![AuthCredential::TOTP(format!("{:06}", totp))],
+        })
+    }
+
+    #[cfg(test)]
+    pub fn cred_step_webauthn(sid: Uuid, asrt: WebauthnAssertion) -> Self {
+        AuthEventStep::Creds(AuthEventStepCreds {
+            sessionid: sid,
+            creds: vec![AuthCredential::Webauthn(asrt)],
+        })
+    }
+
+    #[cfg(test)]
+    pub fn cred_step_backup_code(sid: Uuid, code: &str) -> Self {
+        AuthEventStep::Creds(AuthEventStepCreds {
+            sessionid: sid,
+            creds: vec![AuthCredential::BackupCode(code.to_string())],
+        })
+    }
+
+    #[cfg(test)]
+    pub fn cred_step_api_token(sid: Uuid, token: &str) -> Self {
+        AuthEventStep::Creds(AuthEventStepCreds {
+            sessionid: sid,
+            creds: vec![AuthCredential::ApiToken(token.to_string())],
+        })
+    }
 }
 
 #[derive(Debug)]
@@ -693,6 +1201,8 @@ pub struct AuthEvent {
     pub event: Option<Event>,
     pub step: AuthEventStep,
     // pub sessionid: Option<Uuid>,
+    // Where the request came from, recorded against the session on success.
+    pub source: Option<String>,
 }
 
 impl AuthEvent {
@@ -700,14 +1210,40 @@ impl AuthEvent {
         Ok(AuthEvent {
             event: None,
             step: AuthEventStep::from_authstep(msg.req.step, msg.sessionid)?,
+            source: msg.source,
         })
     }
 
+    pub fn from_reauth_message(msg: ReauthMessage) -> Result<Self, OperationError> {
+        let uat = msg.uat.ok_or(OperationError::NotAuthenticated)?;
+        Ok(AuthEvent {
+            event: None,
+            step: AuthEventStep::Reauth(AuthEventStepReauth {
+                uat: uat,
+                appid: msg.req.appid,
+            }),
+            source: msg.source,
+        })
+    }
+
+    #[cfg(test)]
+    pub fn reauth(uat: &UserAuthToken) -> Self {
+        AuthEvent {
+            event: None,
+            step: AuthEventStep::Reauth(AuthEventStepReauth {
+                uat: uat.clone(),
+                appid: None,
+            }),
+            source: None,
+        }
+    }
+
     #[cfg(test)]
     pub fn anonymous_init() -> Self {
         AuthEvent {
             event: None,
             step: AuthEventStep::anonymous_init(),
+            source: None,
         }
     }
 
@@ -716,6 +1252,16 @@ impl AuthEvent {
         AuthEvent {
             event: None,
             step: AuthEventStep::named_init(name),
+            source: None,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn request_claims(sid: Uuid, claims: Vec<&str>) -> Self {
+        AuthEvent {
+            event: None,
+            step: AuthEventStep::request_claims(sid, claims),
+            source: None,
         }
     }
 
@@ -724,6 +1270,7 @@ impl AuthEvent {
         AuthEvent {
             event: None,
             step: AuthEventStep::cred_step_anonymous(sid),
+            source: None,
         }
     }
 
@@ -732,6 +1279,43 @@ impl AuthEvent {
         AuthEvent {
             event: None,
             step: AuthEventStep::cred_step_password(sid, pw),
+            source: None,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn cred_step_totp(sid: Uuid, totp: u32) -> Self {
+        AuthEvent {
+            event: None,
+            step: AuthEventStep::cred_step_totp(sid, totp),
+            source: None,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn cred_step_webauthn(sid: Uuid, asrt: WebauthnAssertion) -> Self {
+        AuthEvent {
+            event: None,
+            step: AuthEventStep::cred_step_webauthn(sid, asrt),
+            source: None,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn cred_step_backup_code(sid: Uuid, code: &str) -> Self {
+        AuthEvent {
+            event: None,
+            step: AuthEventStep::cred_step_backup_code(sid, code),
+            source: None,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn cred_step_api_token(sid: Uuid, token: &str) -> Self {
+        AuthEvent {
+            event: None,
+            step: AuthEventStep::cred_step_api_token(sid, token),
+            source: None,
         }
     }
 }
@@ -748,6 +1332,7 @@ impl AuthResult {
         AuthResponse {
             sessionid: self.sessionid,
             state: self.state,
+            token: None,
         }
     }
 }
@@ -781,6 +1366,8 @@ impl WhoamiResult {
 #[derive(Debug)]
 pub struct PurgeTombstoneEvent {
     pub event: Event,
+    // How long a tombstone is kept before it is removed.
+    pub max_age: Duration,
 }
 
 impl Message for PurgeTombstoneEvent {
@@ -788,9 +1375,10 @@ impl Message for PurgeTombstoneEvent {
 }
 
 impl PurgeTombstoneEvent {
-    pub fn new() -> Self {
+    pub fn new(max_age: Duration) -> Self {
         PurgeTombstoneEvent {
             event: Event::from_internal(),
+            max_age: max_age,
         }
     }
 }
@@ -798,6 +1386,8 @@ impl PurgeTombstoneEvent {
 #[derive(Debug)]
 pub struct PurgeRecycledEvent {
     pub event: Event,
+    // How long an entry stays in the recycle bin before it is tombstoned.
+    pub max_age: Duration,
 }
 
 impl Message for PurgeRecycledEvent {
@@ -805,9 +1395,101 @@ impl Message for PurgeRecycledEvent {
 }
 
 impl PurgeRecycledEvent {
-    pub fn new() -> Self {
+    pub fn new(max_age: Duration) -> Self {
         PurgeRecycledEvent {
             event: Event::from_internal(),
+            max_age: max_age,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PurgeChangelogEvent {
+    pub event: Event,
+    // How long a change is kept in the changelog.
+    pub max_age: Duration,
+}
+
+impl Message for PurgeChangelogEvent {
+    type Result = ();
+}
+
+impl PurgeChangelogEvent {
+    pub fn new(max_age: Duration) -> Self {
+        PurgeChangelogEvent {
+            event: Event::from_internal(),
+            max_age: max_age,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct VerifyEvent {
+    pub event: Event,
+}
+
+impl Message for VerifyEvent {
+    type Result = ();
+}
+
+impl VerifyEvent {
+    pub fn new() -> Self {
+        VerifyEvent {
+            event: Event::from_internal(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DelayedActionEvent {
+    pub event: Event,
+}
+
+impl Message for DelayedActionEvent {
+    type Result = ();
+}
+
+impl DelayedActionEvent {
+    pub fn new() -> Self {
+        DelayedActionEvent {
+            event: Event::from_internal(),
+        }
+    }
+}
+
+// Sent to the writer when the server stops. As there's one writer, every
+// write queued before it has been committed or rolled back by the time it's
+// handled.
+#[derive(Debug)]
+pub struct ShutdownEvent {
+    pub event: Event,
+}
+
+impl Message for ShutdownEvent {
+    type Result = Result<(), OperationError>;
+}
+
+impl ShutdownEvent {
+    pub fn new() -> Self {
+        ShutdownEvent {
+            event: Event::from_internal(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct OnlineBackupEvent {
+    pub event: Event,
+}
+
+impl Message for OnlineBackupEvent {
+    type Result = ();
+}
+
+impl OnlineBackupEvent {
+    pub fn new() -> Self {
+        OnlineBackupEvent {
+            event: Event::from_internal(),
         }
     }
 }
@@ -821,6 +1503,9 @@ pub struct ReviveRecycledEvent {
     // to be retained, because the filter is the orig filter for this check.
     //
     // It will be duplicated into the modify event as it exists.
+    // The same filter limited to tombstones, so that a request for an
+    // entry that has already been purged from the recycle bin can say so.
+    pub filter_tombstone: Filter<FilterValid>,
 }
 
 impl Message for ReviveRecycledEvent {
@@ -828,24 +1513,28 @@ impl Message for ReviveRecycledEvent {
 }
 
 impl ReviveRecycledEvent {
-    /*
     pub fn from_message(
         audit: &mut AuditScope,
         msg: ReviveRecycledMessage,
         qs: &QueryServerWriteTransaction,
     ) -> Result<Self, OperationError> {
-        match Filter::from_rw(audit, &msg.req.filter, qs) {
+        let event = Event::from_rw_uat(audit, qs, msg.uat)?;
+        match Filter::from_rw(audit, &event, &msg.req.filter, qs) {
             Ok(f) => Ok(ReviveRecycledEvent {
-                event: Event::from_rw_uat(audit, qs, msg.uat)?,
+                event: event,
                 filter: f
+                    .clone()
                     .to_recycled()
                     .validate(qs.get_schema())
                     .map_err(|e| OperationError::SchemaViolation(e))?,
+                filter_tombstone: f
+                    .to_tombstone()
+                    .validate(qs.get_schema())
+                    .map_err(|e| OperationError::SchemaViolation(e))?,
             }),
             Err(e) => Err(e),
         }
     }
-    */
 
     #[cfg(test)]
     pub unsafe fn new_impersonate_entry(
@@ -854,7 +1543,316 @@ impl ReviveRecycledEvent {
     ) -> Self {
         ReviveRecycledEvent {
             event: Event::from_impersonate_entry(e),
-            filter: filter.to_valid(),
+            filter_tombstone: filter.clone().to_tombstone().to_valid(),
+            filter: filter.to_recycled().to_valid(),
+        }
+    }
+}
+
+// Whether the receiver would be allowed an operation on the entries matching
+// the filter. The filter is built as the receiver would have sent it, so
+// that the check is the same as if they had made the request. Nothing is
+// changed.
+#[derive(Debug)]
+pub struct AccessCheckEvent {
+    pub receiver: Event,
+    pub filter: Filter<FilterValid>,
+    pub filter_orig: Filter<FilterValid>,
+    pub operation: AccessCheckOperation,
+    // The modifications to check, when the operation is a modify.
+    pub modlist: Option<ModifyList<ModifyValid>>,
+}
+
+impl AccessCheckEvent {
+    pub fn from_message(
+        audit: &mut AuditScope,
+        msg: AccessCheckMessage,
+        qs: &QueryServerWriteTransaction,
+    ) -> Result<Self, OperationError> {
+        let uat = msg.uat.ok_or(OperationError::NotAuthenticated)?;
+        // This reveals what any account may do, so is for admins only.
+        if !uat
+            .groups
+            .iter()
+            .any(|g| g.uuid == _UUID_IDM_ADMINS || g.uuid == _UUID_IDM_ACP_MANAGER_PRIV)
+        {
+            audit_log!(audit, "{} may not check access", uat.name);
+            return Err(OperationError::AccessDenied);
         }
+
+        let u = match Uuid::parse_str(msg.req.receiver.as_str()) {
+            Ok(u) => u,
+            Err(_) => try_audit!(audit, qs.name_to_uuid(audit, msg.req.receiver.as_str())),
+        };
+        let e = try_audit!(audit, qs.internal_search_uuid(audit, &u));
+        let receiver = Event {
+            origin: EventOrigin::User(e),
+            auth_time: None,
+        };
+
+        let f = Filter::from_rw(audit, &receiver, &msg.req.filter, qs)?;
+        let modlist = match &msg.req.operation {
+            AccessCheckOperation::Modify(ml) => Some(
+                ModifyList::from(audit, ml, qs)?
+                    .validate(qs.get_schema())
+                    .map_err(|e| OperationError::SchemaViolation(e))?,
+            ),
+            _ => None,
+        };
+
+        Ok(AccessCheckEvent {
+            receiver: receiver,
+            filter: f
+                .clone()
+                .to_ignore_hidden()
+                .validate(qs.get_schema())
+                .map_err(|e| OperationError::SchemaViolation(e))?,
+            filter_orig: f
+                .validate(qs.get_schema())
+                .map_err(|e| OperationError::SchemaViolation(e))?,
+            operation: msg.req.operation,
+            modlist: modlist,
+        })
+    }
+}
+
+// The rights the requesting identity has over the entries it can see with
+// the target filter. Only the requester's own rights can be asked for, so
+// this reveals nothing a search and a trial operation wouldn't.
+#[derive(Debug)]
+pub struct EffectiveAccessEvent {
+    pub event: Event,
+    pub filter: Filter<FilterValid>,
+    pub filter_orig: Filter<FilterValid>,
+}
+
+impl EffectiveAccessEvent {
+    pub fn from_message(
+        audit: &mut AuditScope,
+        msg: EffectiveAccessMessage,
+        qs: &QueryServerReadTransaction,
+    ) -> Result<Self, OperationError> {
+        let event = Event::from_ro_uat(audit, qs, msg.uat)?;
+        let f = Filter::from_ro(audit, &event, &msg.req.target, qs)?;
+        Ok(EffectiveAccessEvent {
+            event: event,
+            filter: f
+                .clone()
+                .to_ignore_hidden()
+                .validate(qs.get_schema())
+                .map_err(|e| OperationError::SchemaViolation(e))?,
+            filter_orig: f
+                .validate(qs.get_schema())
+                .map_err(|e| OperationError::SchemaViolation(e))?,
+        })
+    }
+}
+
+// A read of the audit log. The bounds are seconds since the epoch.
+#[derive(Debug)]
+pub struct AuditListEvent {
+    pub since: i64,
+    pub until: i64,
+    pub target: Option<Uuid>,
+}
+
+impl AuditListEvent {
+    pub fn from_message(
+        audit: &mut AuditScope,
+        msg: AuditListMessage,
+        qs: &QueryServerReadTransaction,
+    ) -> Result<Self, OperationError> {
+        let uat = msg.uat.ok_or(OperationError::NotAuthenticated)?;
+        // This reveals every change to every entry, so is for admins only.
+        if !uat.groups.iter().any(|g| g.uuid == _UUID_IDM_ADMINS) {
+            audit_log!(audit, "{} may not read the audit log", uat.name);
+            return Err(OperationError::AccessDenied);
+        }
+
+        let time = |t: &Option<String>, default: i64| match t {
+            Some(s) => DateTime::parse_from_rfc3339(s.as_str())
+                .map(|dt| dt.timestamp())
+                .map_err(|_| OperationError::InvalidRequestState),
+            None => Ok(default),
+        };
+        let since = try_audit!(audit, time(&msg.req.since, 0));
+        let until = try_audit!(audit, time(&msg.req.until, std::i64::MAX));
+
+        // The target may have been deleted, so a name may no longer find it,
+        // but its uuid always will.
+        let target = match &msg.req.target {
+            Some(t) => Some(match Uuid::parse_str(t.as_str()) {
+                Ok(u) => u,
+                Err(_) => try_audit!(audit, qs.name_to_uuid(audit, t.as_str())),
+            }),
+            None => None,
+        };
+
+        Ok(AuditListEvent {
+            since: since,
+            until: until,
+            target: target,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ChangesEvent {
+    pub since: Option<Cid>,
+}
+
+impl ChangesEvent {
+    pub fn from_message(
+        audit: &mut AuditScope,
+        msg: ChangesMessage,
+    ) -> Result<Self, OperationError> {
+        let uat = msg.uat.ok_or(OperationError::NotAuthenticated)?;
+        // Only the names of attributes are given, but this still shows every
+        // change to every entry, as the audit log does.
+        if !uat.groups.iter().any(|g| g.uuid == _UUID_IDM_ADMINS) {
+            audit_log!(audit, "{} may not read the changelog", uat.name);
+            return Err(OperationError::AccessDenied);
+        }
+
+        let since = match &msg.req.since {
+            Some(s) => Some(try_audit!(
+                audit,
+                s.parse::<Cid>()
+                    .map_err(|_| OperationError::InvalidRequestState)
+            )),
+            None => None,
+        };
+
+        Ok(ChangesEvent { since: since })
+    }
+}
+
+// What changed since a change id, as the event may read it. This reads the
+// changelog, but only gives entries through the access controls, so anyone
+// may ask.
+#[derive(Debug)]
+pub struct ExportEvent {
+    pub event: Event,
+    pub since: Option<Cid>,
+}
+
+impl ExportEvent {
+    pub fn from_request(
+        audit: &mut AuditScope,
+        uat: Option<UserAuthToken>,
+        req: &ExportRequest,
+        qs: &QueryServerReadTransaction,
+    ) -> Result<Self, OperationError> {
+        let event = Event::from_ro_uat(audit, qs, uat)?;
+        let since = match &req.since {
+            Some(s) => Some(try_audit!(
+                audit,
+                s.parse::<Cid>()
+                    .map_err(|_| OperationError::InvalidRequestState)
+            )),
+            None => None,
+        };
+
+        Ok(ExportEvent {
+            event: event,
+            since: since,
+        })
+    }
+}
+
+// One record of an export. The entry is reduced to what the event may read.
+#[derive(Debug)]
+pub enum ExportRecord {
+    Entry(Entry<EntryReduced, EntryCommitted>),
+    Deleted(Uuid),
+}
+
+// A backup requested by an admin, rather than on the schedule.
+#[derive(Debug)]
+pub struct BackupEvent {
+    pub event: Event,
+}
+
+impl BackupEvent {
+    pub fn from_message(
+        audit: &mut AuditScope,
+        msg: BackupMessage,
+        qs: &QueryServerReadTransaction,
+    ) -> Result<Self, OperationError> {
+        let uat = msg.uat.ok_or(OperationError::NotAuthenticated)?;
+        // A backup holds every credential, so is for admins only.
+        if !uat.groups.iter().any(|g| g.uuid == _UUID_IDM_ADMINS) {
+            audit_log!(audit, "{} may not take a backup", uat.name);
+            return Err(OperationError::AccessDenied);
+        }
+        Ok(BackupEvent {
+            event: Event::from_ro_uat(audit, qs, Some(uat))?,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ReindexEvent {
+    pub event: Event,
+}
+
+impl ReindexEvent {
+    pub fn from_message(
+        audit: &mut AuditScope,
+        msg: ReindexMessage,
+        qs: &QueryServerReadTransaction,
+    ) -> Result<Self, OperationError> {
+        let uat = msg.uat.ok_or(OperationError::NotAuthenticated)?;
+        if !uat.groups.iter().any(|g| g.uuid == _UUID_IDM_ADMINS) {
+            audit_log!(audit, "{} may not reindex the database", uat.name);
+            return Err(OperationError::AccessDenied);
+        }
+        Ok(ReindexEvent {
+            event: Event::from_ro_uat(audit, qs, Some(uat))?,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct IndexStatusEvent {
+    pub event: Event,
+}
+
+impl IndexStatusEvent {
+    pub fn from_message(
+        audit: &mut AuditScope,
+        msg: IndexStatusMessage,
+        qs: &QueryServerReadTransaction,
+    ) -> Result<Self, OperationError> {
+        let uat = msg.uat.ok_or(OperationError::NotAuthenticated)?;
+        if !uat.groups.iter().any(|g| g.uuid == _UUID_IDM_ADMINS) {
+            audit_log!(audit, "{} may not read the index status", uat.name);
+            return Err(OperationError::AccessDenied);
+        }
+        Ok(IndexStatusEvent {
+            event: Event::from_ro_uat(audit, qs, Some(uat))?,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct VacuumEvent {
+    pub event: Event,
+}
+
+impl VacuumEvent {
+    pub fn from_message(
+        audit: &mut AuditScope,
+        msg: VacuumMessage,
+        qs: &QueryServerReadTransaction,
+    ) -> Result<Self, OperationError> {
+        let uat = msg.uat.ok_or(OperationError::NotAuthenticated)?;
+        if !uat.groups.iter().any(|g| g.uuid == _UUID_IDM_ADMINS) {
+            audit_log!(audit, "{} may not vacuum the database", uat.name);
+            return Err(OperationError::AccessDenied);
+        }
+        Ok(VacuumEvent {
+            event: Event::from_ro_uat(audit, qs, Some(uat))?,
+        })
     }
 }
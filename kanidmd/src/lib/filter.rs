@@ -8,7 +8,7 @@ use crate::schema::SchemaTransaction;
 use crate::server::{
     QueryServerReadTransaction, QueryServerTransaction, QueryServerWriteTransaction,
 };
-use crate::value::PartialValue;
+use crate::value::{IndexType, PartialValue};
 use kanidm_proto::v1::Filter as ProtoFilter;
 use kanidm_proto::v1::{OperationError, SchemaError};
 use std::cmp::{Ordering, PartialOrd};
@@ -33,6 +33,21 @@ pub fn f_pres<'a>(a: &'a str) -> FC<'a> {
     FC::Pres(a)
 }
 
+#[allow(dead_code)]
+pub fn f_gte<'a>(a: &'a str, v: PartialValue) -> FC<'a> {
+    FC::Gte(a, v)
+}
+
+#[allow(dead_code)]
+pub fn f_lte<'a>(a: &'a str, v: PartialValue) -> FC<'a> {
+    FC::Lte(a, v)
+}
+
+#[allow(dead_code)]
+pub fn f_inc<'a>(a: &'a str, vs: Vec<PartialValue>) -> FC<'a> {
+    FC::Inclusion(a, vs)
+}
+
 #[allow(dead_code)]
 pub fn f_or<'a>(vs: Vec<FC<'a>>) -> FC<'a> {
     FC::Or(vs)
@@ -53,6 +68,16 @@ pub fn f_self<'a>() -> FC<'a> {
     FC::SelfUUID
 }
 
+#[allow(dead_code)]
+pub fn f_true<'a>() -> FC<'a> {
+    FC::True
+}
+
+#[allow(dead_code)]
+pub fn f_false<'a>() -> FC<'a> {
+    FC::False
+}
+
 // This is the short-form for tests and internal filters that can then
 // be transformed into a filter for the server to use.
 #[derive(Debug, Deserialize)]
@@ -60,10 +85,15 @@ pub enum FC<'a> {
     Eq(&'a str, PartialValue),
     Sub(&'a str, PartialValue),
     Pres(&'a str),
+    Gte(&'a str, PartialValue),
+    Lte(&'a str, PartialValue),
+    Inclusion(&'a str, Vec<PartialValue>),
     Or(Vec<FC<'a>>),
     And(Vec<FC<'a>>),
     AndNot(Box<FC<'a>>),
     SelfUUID,
+    True,
+    False,
     // Not(Box<FC>),
 }
 
@@ -74,10 +104,15 @@ enum FilterComp {
     Eq(String, PartialValue),
     Sub(String, PartialValue),
     Pres(String),
+    Gte(String, PartialValue),
+    Lte(String, PartialValue),
+    Inclusion(String, Vec<PartialValue>),
     Or(Vec<FilterComp>),
     And(Vec<FilterComp>),
     AndNot(Box<FilterComp>),
     SelfUUID,
+    True,
+    False,
     // Does this mean we can add a true not to the type now?
     // Not(Box<FilterComp>),
 }
@@ -92,9 +127,87 @@ pub enum FilterResolved {
     Eq(String, PartialValue),
     Sub(String, PartialValue),
     Pres(String),
+    Gte(String, PartialValue),
+    Lte(String, PartialValue),
+    Inclusion(String, Vec<PartialValue>),
     Or(Vec<FilterResolved>),
     And(Vec<FilterResolved>),
     AndNot(Box<FilterResolved>),
+    True,
+    False,
+}
+
+// These limits bound the size of filters that a client may submit, so that a
+// deep or wide filter can't exhaust the stack or cpu during conversion and
+// resolution. Internal filters are not subject to these.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FilterLimits {
+    pub max_depth: usize,
+    pub max_elements: usize,
+    pub max_inclusion: usize,
+    // Whether a filter that no index can narrow may be searched with. When
+    // it may, it is logged as a slow search.
+    pub allow_unindexed: bool,
+    // The most entries a search may load once the indexes have given the
+    // candidates, or the most in a page of a paged search. Members of
+    // idm_unlimited_search_priv aren't limited.
+    pub max_results: usize,
+}
+
+impl FilterLimits {
+    pub fn new() -> Self {
+        FilterLimits {
+            max_depth: 32,
+            max_elements: 1024,
+            max_inclusion: 512,
+            allow_unindexed: true,
+            max_results: 4096,
+        }
+    }
+
+    pub fn new_anonymous() -> Self {
+        FilterLimits {
+            max_depth: 8,
+            max_elements: 64,
+            max_inclusion: 32,
+            allow_unindexed: true,
+            max_results: 512,
+        }
+    }
+
+    pub fn check(&self, f: &ProtoFilter) -> Result<(), OperationError> {
+        let mut elements: usize = 0;
+        self.check_inner(f, 1, &mut elements)
+    }
+
+    // We bail as soon as the depth is exceeded, so the recursion here is bounded
+    // by max_depth, not the depth of the filter we were sent.
+    fn check_inner(
+        &self,
+        f: &ProtoFilter,
+        depth: usize,
+        elements: &mut usize,
+    ) -> Result<(), OperationError> {
+        *elements += 1;
+        if depth > self.max_depth || *elements > self.max_elements {
+            return Err(OperationError::ResourceLimit);
+        }
+        match f {
+            ProtoFilter::Or(l) | ProtoFilter::And(l) => l
+                .iter()
+                .map(|f| self.check_inner(f, depth + 1, elements))
+                .collect(),
+            ProtoFilter::AndNot(f) => self.check_inner(f, depth + 1, elements),
+            ProtoFilter::Inclusion(_, vs) => {
+                if vs.len() > self.max_inclusion {
+                    Err(OperationError::ResourceLimit)
+                } else {
+                    Ok(())
+                }
+            }
+            _ => Ok(()),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -130,7 +243,9 @@ impl Filter<FilterValidResolved> {
         // The other big one is folding redundant
         // terms down.
         //
-        // If an or/not/and condition has no items, remove it
+        // If an or/and condition has no items, remove it
+        //
+        // A double andnot is the term itself, so we can remove both.
         //
         // If its the root item?
 
@@ -153,6 +268,13 @@ impl Filter<FilterValidResolved> {
     pub fn to_inner(&self) -> &FilterResolved {
         &self.state.inner
     }
+
+    // Whether the backend can find the candidates for this filter with these
+    // indexes, rather than by testing every entry. It searches with the
+    // optimised filter, so that is what's judged.
+    pub fn is_indexed(&self, idx: &BTreeSet<(String, IndexType)>) -> bool {
+        self.state.inner.optimise().is_indexed(idx)
+    }
 }
 
 impl Filter<FilterValid> {
@@ -166,11 +288,14 @@ impl Filter<FilterValid> {
     }
 
     pub fn resolve(&self, ev: &Event) -> Result<Filter<FilterValidResolved>, OperationError> {
-        // Given a filter, resolve Not and SelfUUID to real terms.
+        // Given a filter, resolve Not and SelfUUID to real terms. We also
+        // normalise here, so that logically identical queries are always
+        // presented in the same form to the backend.
         Ok(Filter {
             state: FilterValidResolved {
                 inner: FilterResolved::resolve(self.state.inner.clone(), ev)
-                    .ok_or(OperationError::FilterUUIDResolution)?,
+                    .ok_or(OperationError::FilterUUIDResolution)?
+                    .optimise(),
             },
         })
     }
@@ -228,6 +353,15 @@ impl Filter<FilterInvalid> {
         }
     }
 
+    pub fn to_tombstone(self) -> Self {
+        // Destructure the former filter and surround it with a tombstone only query
+        Filter {
+            state: FilterInvalid {
+                inner: FilterComp::new_tombstone(self.state.inner),
+            },
+        }
+    }
+
     #[cfg(test)]
     pub unsafe fn to_valid_resolved(self) -> Filter<FilterValidResolved> {
         // There is a good reason this function only exists in tests ...
@@ -284,9 +418,11 @@ impl Filter<FilterInvalid> {
     // takes "clone_value(t, a, v) instead, but that may have a similar issue.
     pub fn from_ro(
         audit: &mut AuditScope,
+        ev: &Event,
         f: &ProtoFilter,
         qs: &QueryServerReadTransaction,
     ) -> Result<Self, OperationError> {
+        qs.check_filter_limits(audit, ev, f)?;
         Ok(Filter {
             state: FilterInvalid {
                 inner: FilterComp::from_ro(audit, f, qs)?,
@@ -296,9 +432,11 @@ impl Filter<FilterInvalid> {
 
     pub fn from_rw(
         audit: &mut AuditScope,
+        ev: &Event,
         f: &ProtoFilter,
         qs: &QueryServerWriteTransaction,
     ) -> Result<Self, OperationError> {
+        qs.check_filter_limits(audit, ev, f)?;
         Ok(Filter {
             state: FilterInvalid {
                 inner: FilterComp::from_rw(audit, f, qs)?,
@@ -313,10 +451,15 @@ impl FilterComp {
             FC::Eq(a, v) => FilterComp::Eq(a.to_string(), v),
             FC::Sub(a, v) => FilterComp::Sub(a.to_string(), v),
             FC::Pres(a) => FilterComp::Pres(a.to_string()),
+            FC::Gte(a, v) => FilterComp::Gte(a.to_string(), v),
+            FC::Lte(a, v) => FilterComp::Lte(a.to_string(), v),
+            FC::Inclusion(a, vs) => FilterComp::Inclusion(a.to_string(), vs),
             FC::Or(v) => FilterComp::Or(v.into_iter().map(|c| FilterComp::new(c)).collect()),
             FC::And(v) => FilterComp::And(v.into_iter().map(|c| FilterComp::new(c)).collect()),
             FC::AndNot(b) => FilterComp::AndNot(Box::new(FilterComp::new(*b))),
             FC::SelfUUID => FilterComp::SelfUUID,
+            FC::True => FilterComp::True,
+            FC::False => FilterComp::False,
         }
     }
 
@@ -337,6 +480,13 @@ impl FilterComp {
         ])
     }
 
+    fn new_tombstone(fc: FilterComp) -> Self {
+        FilterComp::And(vec![
+            FilterComp::Eq("class".to_string(), PartialValue::new_iutf8s("tombstone")),
+            fc,
+        ])
+    }
+
     fn get_attr_set<'a>(&'a self, r_set: &mut BTreeSet<&'a str>) {
         match self {
             FilterComp::Eq(attr, _) => {
@@ -348,12 +498,22 @@ impl FilterComp {
             FilterComp::Pres(attr) => {
                 r_set.insert(attr.as_str());
             }
+            FilterComp::Gte(attr, _) => {
+                r_set.insert(attr.as_str());
+            }
+            FilterComp::Lte(attr, _) => {
+                r_set.insert(attr.as_str());
+            }
+            FilterComp::Inclusion(attr, _) => {
+                r_set.insert(attr.as_str());
+            }
             FilterComp::Or(vs) => vs.iter().for_each(|f| f.get_attr_set(r_set)),
             FilterComp::And(vs) => vs.iter().for_each(|f| f.get_attr_set(r_set)),
             FilterComp::AndNot(f) => f.get_attr_set(r_set),
             FilterComp::SelfUUID => {
                 r_set.insert("uuid");
             }
+            FilterComp::True | FilterComp::False => {}
         }
     }
 
@@ -376,10 +536,12 @@ impl FilterComp {
                 // Now check it exists
                 match schema_attributes.get(&attr_norm) {
                     Some(schema_a) => {
+                        // Fold the value to the case sensitivity of the syntax.
+                        let value_norm = schema_a.normalise_partialvalue(value);
                         schema_a
-                            .validate_partialvalue(&value)
+                            .validate_partialvalue(&value_norm)
                             // Okay, it worked, transform to a filter component
-                            .map(|_| FilterComp::Eq(attr_norm, value.clone()))
+                            .map(|_| FilterComp::Eq(attr_norm, value_norm))
                         // On error, pass the error back out.
                     }
                     None => Err(SchemaError::InvalidAttribute),
@@ -391,10 +553,13 @@ impl FilterComp {
                 // Now check it exists
                 match schema_attributes.get(&attr_norm) {
                     Some(schema_a) => {
+                        // Fold the value to the case sensitivity of the syntax.
+                        let value_norm = schema_a.normalise_partialvalue(value);
                         schema_a
-                            .validate_partialvalue(&value)
+                            .validate_partialvalue(&value_norm)
+                            .and_then(|_| Self::validate_not_binary(&value_norm))
                             // Okay, it worked, transform to a filter component
-                            .map(|_| FilterComp::Sub(attr_norm, value.clone()))
+                            .map(|_| FilterComp::Sub(attr_norm, value_norm))
                         // On error, pass the error back out.
                     }
                     None => Err(SchemaError::InvalidAttribute),
@@ -411,6 +576,56 @@ impl FilterComp {
                     None => Err(SchemaError::InvalidAttribute),
                 }
             }
+            FilterComp::Gte(attr, value) => {
+                let attr_norm = schema.normalise_attr_name(attr);
+                match schema_attributes.get(&attr_norm) {
+                    // Only some syntaxes can be ordered, so reject the others.
+                    Some(schema_a) => {
+                        let value_norm = schema_a.normalise_partialvalue(value);
+                        schema_a
+                            .validate_partialvalue(&value_norm)
+                            .and_then(|_| Self::validate_orderable(&value_norm))
+                            .map(|_| FilterComp::Gte(attr_norm, value_norm))
+                    }
+                    None => Err(SchemaError::InvalidAttribute),
+                }
+            }
+            FilterComp::Lte(attr, value) => {
+                let attr_norm = schema.normalise_attr_name(attr);
+                match schema_attributes.get(&attr_norm) {
+                    Some(schema_a) => {
+                        let value_norm = schema_a.normalise_partialvalue(value);
+                        schema_a
+                            .validate_partialvalue(&value_norm)
+                            .and_then(|_| Self::validate_orderable(&value_norm))
+                            .map(|_| FilterComp::Lte(attr_norm, value_norm))
+                    }
+                    None => Err(SchemaError::InvalidAttribute),
+                }
+            }
+            FilterComp::Inclusion(attr, values) => {
+                // Like an or, an empty set can never match anything.
+                if values.len() == 0 {
+                    return Err(SchemaError::EmptyFilter);
+                };
+                let attr_norm = schema.normalise_attr_name(attr);
+                match schema_attributes.get(&attr_norm) {
+                    Some(schema_a) => {
+                        let x: Result<Vec<_>, _> = values
+                            .iter()
+                            .map(|value| {
+                                let value_norm = schema_a.normalise_partialvalue(value);
+                                schema_a
+                                    .validate_partialvalue(&value_norm)
+                                    .and_then(|_| Self::validate_not_binary(&value_norm))
+                                    .map(|_| value_norm)
+                            })
+                            .collect();
+                        x.map(|values_norm| FilterComp::Inclusion(attr_norm, values_norm))
+                    }
+                    None => Err(SchemaError::InvalidAttribute),
+                }
+            }
             FilterComp::Or(filters) => {
                 // If all filters are okay, return Ok(Filter::Or())
                 // If any is invalid, return the error.
@@ -449,6 +664,52 @@ impl FilterComp {
                 // Pretty hard to mess this one up ;)
                 Ok(FilterComp::SelfUUID)
             }
+            FilterComp::True => Ok(FilterComp::True),
+            FilterComp::False => Ok(FilterComp::False),
+        }
+    }
+
+    fn validate_orderable(value: &PartialValue) -> Result<(), SchemaError> {
+        if value.is_orderable() {
+            Ok(())
+        } else {
+            Err(SchemaError::InvalidAttributeSyntax)
+        }
+    }
+
+    // Binary values can only be matched by equality or presence. Ordering
+    // terms are already refused by validate_orderable.
+    fn validate_not_binary(value: &PartialValue) -> Result<(), SchemaError> {
+        if value.is_binary() {
+            Err(SchemaError::InvalidAttributeSyntax)
+        } else {
+            Ok(())
+        }
+    }
+
+    // When the value of an ordering term can't be parsed to the attributes syntax
+    // we can't meaningfully compare it to anything, so rather than the generic
+    // InvalidAttribute we report this as a schema violation of the syntax.
+    fn ordered_partialvalue(
+        r: Result<PartialValue, OperationError>,
+    ) -> Result<PartialValue, OperationError> {
+        r.map_err(|e| match e {
+            OperationError::InvalidAttribute(_) => {
+                OperationError::SchemaViolation(SchemaError::InvalidAttributeSyntax)
+            }
+            e => e,
+        })
+    }
+
+    // An account or group may be named by its spn, name@domain_name, where a
+    // name is expected. A name may itself contain an @, so both are matched.
+    fn new_eq(a: String, v: PartialValue) -> Self {
+        match v.to_str() {
+            Some(s) if a == "name" && s.contains('@') => FilterComp::Or(vec![
+                FilterComp::Eq(a, v.clone()),
+                FilterComp::Eq("spn".to_string(), v),
+            ]),
+            _ => FilterComp::Eq(a, v),
         }
     }
 
@@ -458,11 +719,35 @@ impl FilterComp {
         qs: &QueryServerReadTransaction,
     ) -> Result<Self, OperationError> {
         Ok(match f {
-            ProtoFilter::Eq(a, v) => FilterComp::Eq(a.clone(), qs.clone_partialvalue(audit, a, v)?),
+            ProtoFilter::Eq(a, v) => {
+                let a = qs.clone_attr_name(a)?;
+                let v = qs.clone_partialvalue(audit, &a, v)?;
+                FilterComp::new_eq(a, v)
+            }
             ProtoFilter::Sub(a, v) => {
-                FilterComp::Sub(a.clone(), qs.clone_partialvalue(audit, a, v)?)
+                let a = qs.clone_attr_name(a)?;
+                let v = qs.clone_partialvalue(audit, &a, v)?;
+                FilterComp::Sub(a, v)
+            }
+            ProtoFilter::Pres(a) => FilterComp::Pres(qs.clone_attr_name(a)?),
+            ProtoFilter::Gte(a, v) => {
+                let a = qs.clone_attr_name(a)?;
+                let v = Self::ordered_partialvalue(qs.clone_partialvalue(audit, &a, v))?;
+                FilterComp::Gte(a, v)
+            }
+            ProtoFilter::Lte(a, v) => {
+                let a = qs.clone_attr_name(a)?;
+                let v = Self::ordered_partialvalue(qs.clone_partialvalue(audit, &a, v))?;
+                FilterComp::Lte(a, v)
+            }
+            ProtoFilter::Inclusion(a, vs) => {
+                let a = qs.clone_attr_name(a)?;
+                let vs = vs
+                    .iter()
+                    .map(|v| qs.clone_partialvalue(audit, &a, v))
+                    .collect::<Result<Vec<_>, _>>()?;
+                FilterComp::Inclusion(a, vs)
             }
-            ProtoFilter::Pres(a) => FilterComp::Pres(a.clone()),
             ProtoFilter::Or(l) => FilterComp::Or(
                 l.iter()
                     .map(|f| Self::from_ro(audit, f, qs))
@@ -475,6 +760,8 @@ impl FilterComp {
             ),
             ProtoFilter::AndNot(l) => FilterComp::AndNot(Box::new(Self::from_ro(audit, l, qs)?)),
             ProtoFilter::SelfUUID => FilterComp::SelfUUID,
+            ProtoFilter::True => FilterComp::True,
+            ProtoFilter::False => FilterComp::False,
         })
     }
 
@@ -484,11 +771,35 @@ impl FilterComp {
         qs: &QueryServerWriteTransaction,
     ) -> Result<Self, OperationError> {
         Ok(match f {
-            ProtoFilter::Eq(a, v) => FilterComp::Eq(a.clone(), qs.clone_partialvalue(audit, a, v)?),
+            ProtoFilter::Eq(a, v) => {
+                let a = qs.clone_attr_name(a)?;
+                let v = qs.clone_partialvalue(audit, &a, v)?;
+                FilterComp::new_eq(a, v)
+            }
             ProtoFilter::Sub(a, v) => {
-                FilterComp::Sub(a.clone(), qs.clone_partialvalue(audit, a, v)?)
+                let a = qs.clone_attr_name(a)?;
+                let v = qs.clone_partialvalue(audit, &a, v)?;
+                FilterComp::Sub(a, v)
+            }
+            ProtoFilter::Pres(a) => FilterComp::Pres(qs.clone_attr_name(a)?),
+            ProtoFilter::Gte(a, v) => {
+                let a = qs.clone_attr_name(a)?;
+                let v = Self::ordered_partialvalue(qs.clone_partialvalue(audit, &a, v))?;
+                FilterComp::Gte(a, v)
+            }
+            ProtoFilter::Lte(a, v) => {
+                let a = qs.clone_attr_name(a)?;
+                let v = Self::ordered_partialvalue(qs.clone_partialvalue(audit, &a, v))?;
+                FilterComp::Lte(a, v)
+            }
+            ProtoFilter::Inclusion(a, vs) => {
+                let a = qs.clone_attr_name(a)?;
+                let vs = vs
+                    .iter()
+                    .map(|v| qs.clone_partialvalue(audit, &a, v))
+                    .collect::<Result<Vec<_>, _>>()?;
+                FilterComp::Inclusion(a, vs)
             }
-            ProtoFilter::Pres(a) => FilterComp::Pres(a.clone()),
             ProtoFilter::Or(l) => FilterComp::Or(
                 l.iter()
                     .map(|f| Self::from_rw(audit, f, qs))
@@ -501,6 +812,8 @@ impl FilterComp {
             ),
             ProtoFilter::AndNot(l) => FilterComp::AndNot(Box::new(Self::from_rw(audit, l, qs)?)),
             ProtoFilter::SelfUUID => FilterComp::SelfUUID,
+            ProtoFilter::True => FilterComp::True,
+            ProtoFilter::False => FilterComp::False,
         })
     }
 }
@@ -532,9 +845,16 @@ impl PartialEq for FilterResolved {
             (FilterResolved::Eq(a1, v1), FilterResolved::Eq(a2, v2)) => a1 == a2 && v1 == v2,
             (FilterResolved::Sub(a1, v1), FilterResolved::Sub(a2, v2)) => a1 == a2 && v1 == v2,
             (FilterResolved::Pres(a1), FilterResolved::Pres(a2)) => a1 == a2,
+            (FilterResolved::Gte(a1, v1), FilterResolved::Gte(a2, v2)) => a1 == a2 && v1 == v2,
+            (FilterResolved::Lte(a1, v1), FilterResolved::Lte(a2, v2)) => a1 == a2 && v1 == v2,
+            (FilterResolved::Inclusion(a1, vs1), FilterResolved::Inclusion(a2, vs2)) => {
+                a1 == a2 && vs1 == vs2
+            }
             (FilterResolved::And(vs1), FilterResolved::And(vs2)) => vs1 == vs2,
             (FilterResolved::Or(vs1), FilterResolved::Or(vs2)) => vs1 == vs2,
             (FilterResolved::AndNot(f1), FilterResolved::AndNot(f2)) => f1 == f2,
+            (FilterResolved::True, FilterResolved::True) => true,
+            (FilterResolved::False, FilterResolved::False) => true,
             (_, _) => false,
         }
     }
@@ -577,16 +897,44 @@ impl Ord for FilterResolved {
                 o => o,
             },
             (FilterResolved::Pres(a1), FilterResolved::Pres(a2)) => a1.cmp(a2),
+            (FilterResolved::Gte(a1, v1), FilterResolved::Gte(a2, v2)) => match a1.cmp(a2) {
+                Ordering::Equal => v1.cmp(v2),
+                o => o,
+            },
+            (FilterResolved::Lte(a1, v1), FilterResolved::Lte(a2, v2)) => match a1.cmp(a2) {
+                Ordering::Equal => v1.cmp(v2),
+                o => o,
+            },
+            (FilterResolved::Inclusion(a1, vs1), FilterResolved::Inclusion(a2, vs2)) => {
+                match a1.cmp(a2) {
+                    Ordering::Equal => vs1.cmp(vs2),
+                    o => o,
+                }
+            }
+            // Constants cost nothing to evaluate, so always go first.
+            (FilterResolved::True, FilterResolved::True) => Ordering::Equal,
+            (FilterResolved::False, FilterResolved::False) => Ordering::Equal,
+            (FilterResolved::False, _) => Ordering::Less,
+            (_, FilterResolved::False) => Ordering::Greater,
+            (FilterResolved::True, _) => Ordering::Less,
+            (_, FilterResolved::True) => Ordering::Greater,
             (FilterResolved::Eq(_, _), _) => {
                 // Always higher prefer Eq over all else, as these will have
                 // the best indexes and return smallest candidates.
                 Ordering::Less
             }
             (_, FilterResolved::Eq(_, _)) => Ordering::Greater,
+            // An inclusion is a set of eq's, so it's nearly as good.
+            (FilterResolved::Inclusion(_, _), _) => Ordering::Less,
+            (_, FilterResolved::Inclusion(_, _)) => Ordering::Greater,
             (FilterResolved::Pres(_), _) => Ordering::Less,
             (_, FilterResolved::Pres(_)) => Ordering::Greater,
             (FilterResolved::Sub(_, _), _) => Ordering::Greater,
             (_, FilterResolved::Sub(_, _)) => Ordering::Less,
+            // Ranges sit between presence and substring, as they will
+            // generally select more candidates than an eq.
+            (FilterResolved::Gte(_, _), FilterResolved::Lte(_, _)) => Ordering::Less,
+            (FilterResolved::Lte(_, _), FilterResolved::Gte(_, _)) => Ordering::Greater,
             (_, _) => Ordering::Equal,
         }
     }
@@ -599,6 +947,9 @@ impl FilterResolved {
             FilterComp::Eq(a, v) => FilterResolved::Eq(a, v),
             FilterComp::Sub(a, v) => FilterResolved::Sub(a, v),
             FilterComp::Pres(a) => FilterResolved::Pres(a),
+            FilterComp::Gte(a, v) => FilterResolved::Gte(a, v),
+            FilterComp::Lte(a, v) => FilterResolved::Lte(a, v),
+            FilterComp::Inclusion(a, vs) => FilterResolved::Inclusion(a, vs),
             FilterComp::Or(vs) => FilterResolved::Or(
                 vs.into_iter()
                     .map(|v| FilterResolved::from_invalid(v))
@@ -618,6 +969,8 @@ impl FilterResolved {
                 FilterResolved::AndNot(Box::new(FilterResolved::from_invalid((*f).clone())))
             }
             FilterComp::SelfUUID => panic!("Not possible to resolve SelfUUID in from_invalid!"),
+            FilterComp::True => FilterResolved::True,
+            FilterComp::False => FilterResolved::False,
         }
     }
 
@@ -626,6 +979,9 @@ impl FilterResolved {
             FilterComp::Eq(a, v) => Some(FilterResolved::Eq(a, v)),
             FilterComp::Sub(a, v) => Some(FilterResolved::Sub(a, v)),
             FilterComp::Pres(a) => Some(FilterResolved::Pres(a)),
+            FilterComp::Gte(a, v) => Some(FilterResolved::Gte(a, v)),
+            FilterComp::Lte(a, v) => Some(FilterResolved::Lte(a, v)),
+            FilterComp::Inclusion(a, vs) => Some(FilterResolved::Inclusion(a, vs)),
             FilterComp::Or(vs) => {
                 let fi: Option<Vec<_>> = vs
                     .into_iter()
@@ -656,6 +1012,8 @@ impl FilterResolved {
                 )),
                 _ => None,
             },
+            FilterComp::True => Some(FilterResolved::True),
+            FilterComp::False => Some(FilterResolved::False),
         }
     }
 
@@ -688,6 +1046,19 @@ impl FilterResolved {
                     _ => {}
                 });
 
+                // Any false term means nothing can match.
+                if f_list_new.contains(&FilterResolved::False) {
+                    return FilterResolved::False;
+                }
+
+                // True terms don't change the result, and an and of nothing,
+                // as entries are matched, is true. An empty or within was
+                // optimised to false above, so decided the term already.
+                f_list_new.retain(|f| *f != FilterResolved::True);
+                if f_list_new.is_empty() {
+                    return FilterResolved::True;
+                }
+
                 // finally, optimise this list by sorting.
                 f_list_new.sort_unstable();
                 f_list_new.dedup();
@@ -709,22 +1080,76 @@ impl FilterResolved {
                     _ => {}
                 });
 
+                // Likewise, any true term means everything matches.
+                if f_list_new.contains(&FilterResolved::True) {
+                    return FilterResolved::True;
+                }
+
+                // And an or of nothing matches nothing.
+                f_list_new.retain(|f| *f != FilterResolved::False);
+                if f_list_new.is_empty() {
+                    return FilterResolved::False;
+                }
+
                 // sort, but reverse so that sub-optimal elements are later!
                 f_list_new.sort_unstable_by(|a, b| b.cmp(a));
                 f_list_new.dedup();
 
                 FilterResolved::Or(f_list_new)
             }
+            FilterResolved::AndNot(f) => match f.optimise() {
+                // !!x is x
+                FilterResolved::AndNot(f_inner) => *f_inner,
+                FilterResolved::True => FilterResolved::False,
+                FilterResolved::False => FilterResolved::True,
+                f_opt => FilterResolved::AndNot(Box::new(f_opt)),
+            },
+            FilterResolved::Inclusion(a, vs) => {
+                // Sort the set so that equivalent inclusions compare equal.
+                let mut vs = vs.clone();
+                vs.sort_unstable();
+                vs.dedup();
+                // A set of one is just an eq, which is better understood by
+                // the rest of the optimiser.
+                if vs.len() == 1 {
+                    FilterResolved::Eq(a.clone(), vs.remove(0))
+                } else {
+                    FilterResolved::Inclusion(a.clone(), vs)
+                }
+            }
             f => f.clone(),
         }
     }
+
+    // This must agree with how the backend turns a filter into the ids of
+    // its candidates.
+    fn is_indexed(&self, idx: &BTreeSet<(String, IndexType)>) -> bool {
+        match self {
+            FilterResolved::Eq(a, _) | FilterResolved::Inclusion(a, _) => {
+                idx.contains(&(a.clone(), IndexType::EQUALITY))
+            }
+            FilterResolved::Pres(a) => idx.contains(&(a.clone(), IndexType::PRESENCE)),
+            FilterResolved::Sub(a, v) => {
+                idx.contains(&(a.clone(), IndexType::SUBSTRING)) && !v.get_idx_sub_keys().is_empty()
+            }
+            FilterResolved::And(l) => l.iter().any(|f| f.is_indexed(idx)),
+            FilterResolved::Or(l) => l.iter().all(|f| f.is_indexed(idx)),
+            FilterResolved::False => true,
+            _ => false,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::entry::{Entry, EntryNew, EntryValid};
-    use crate::filter::{Filter, FilterInvalid};
-    use crate::value::PartialValue;
+    use crate::constants::{UUID_ADMIN, UUID_ANONYMOUS};
+    use crate::entry::{Entry, EntryInvalid, EntryNew, EntryValid};
+    use crate::event::Event;
+    use crate::filter::{Filter, FilterInvalid, FilterLimits};
+    use crate::server::QueryServerTransaction;
+    use crate::value::{IndexType, PartialValue, Value};
+    use kanidm_proto::v1::Filter as ProtoFilter;
+    use kanidm_proto::v1::OperationError;
     use std::cmp::{Ordering, PartialOrd};
     use std::collections::BTreeSet;
 
@@ -749,7 +1174,9 @@ mod tests {
             $expect:expr
         ) => {{
             #[allow(unused_imports)]
-            use crate::filter::{f_and, f_andnot, f_eq, f_or, f_pres, f_sub};
+            use crate::filter::{
+                f_and, f_andnot, f_eq, f_false, f_gte, f_lte, f_or, f_pres, f_sub, f_true,
+            };
             use crate::filter::{Filter, FilterInvalid};
             let f_init: Filter<FilterInvalid> = Filter::new($init);
             let f_expect: Filter<FilterInvalid> = Filter::new($expect);
@@ -866,6 +1293,106 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_filter_normalise() {
+        // Nested and/or of the same type flatten, with duplicates removed.
+        filter_optimise_assert!(
+            f_and!([
+                f_and!([
+                    f_pres("class"),
+                    f_eq("name", PartialValue::new_iutf8s("claire"))
+                ]),
+                f_and!([f_eq("name", PartialValue::new_iutf8s("claire"))]),
+                f_pres("class")
+            ]),
+            f_and!([
+                f_eq("name", PartialValue::new_iutf8s("claire")),
+                f_pres("class")
+            ])
+        );
+
+        // An empty or matches nothing, so decides an and it's within, and an
+        // empty and matches everything, so decides an or.
+        filter_optimise_assert!(
+            f_and!([
+                f_and!([f_eq("name", PartialValue::new_iutf8s("claire"))]),
+                f_or!([])
+            ]),
+            f_false()
+        );
+
+        filter_optimise_assert!(
+            f_or!([
+                f_and!([]),
+                f_or!([f_pres("class")]),
+                f_eq("name", PartialValue::new_iutf8s("claire"))
+            ]),
+            f_true()
+        );
+
+        // Within one of their own kind they are flattened away, which is the
+        // same result.
+        filter_optimise_assert!(
+            f_and!([f_and!([]), f_pres("class")]),
+            f_and!([f_pres("class")])
+        );
+        filter_optimise_assert!(
+            f_or!([f_or!([]), f_pres("class")]),
+            f_or!([f_pres("class")])
+        );
+        filter_optimise_assert!(f_or!([]), f_false());
+        filter_optimise_assert!(f_and!([]), f_true());
+
+        // Double andnot is removed.
+        filter_optimise_assert!(
+            f_andnot(f_andnot(f_eq("name", PartialValue::new_iutf8s("claire")))),
+            f_eq("name", PartialValue::new_iutf8s("claire"))
+        );
+
+        // Even when nested inside other terms, and the result is then flattened.
+        filter_optimise_assert!(
+            f_and!([
+                f_andnot(f_andnot(f_and!([f_pres("class")]))),
+                f_eq("name", PartialValue::new_iutf8s("claire"))
+            ]),
+            f_and!([
+                f_eq("name", PartialValue::new_iutf8s("claire")),
+                f_pres("class")
+            ])
+        );
+
+        // But a single andnot remains.
+        filter_optimise_assert!(
+            f_andnot(f_andnot(f_andnot(f_pres("class")))),
+            f_andnot(f_pres("class"))
+        );
+    }
+
+    #[test]
+    fn test_filter_optimise_bool() {
+        // False in an and, or true in an or, decides the whole term.
+        filter_optimise_assert!(
+            f_and!([f_pres("class"), f_or!([f_pres("name"), f_false()])]),
+            f_and!([f_pres("class"), f_or!([f_pres("name")])])
+        );
+        filter_optimise_assert!(f_and!([f_pres("class"), f_false()]), f_false());
+        filter_optimise_assert!(f_or!([f_pres("class"), f_true()]), f_true());
+        filter_optimise_assert!(
+            f_and!([f_pres("class"), f_or!([f_pres("name"), f_true()])]),
+            f_and!([f_pres("class")])
+        );
+        // Otherwise they are dropped as they don't change the result.
+        filter_optimise_assert!(
+            f_and!([f_true(), f_pres("class")]),
+            f_and!([f_pres("class")])
+        );
+        filter_optimise_assert!(f_and!([f_true(), f_true()]), f_true());
+        filter_optimise_assert!(f_or!([f_false(), f_false()]), f_false());
+        // Negation flips them.
+        filter_optimise_assert!(f_andnot(f_true()), f_false());
+        filter_optimise_assert!(f_or!([f_andnot(f_false()), f_pres("class")]), f_true());
+    }
+
     #[test]
     fn test_filter_eq() {
         let f_t1a = filter!(f_pres("userid"));
@@ -1159,6 +1686,258 @@ mod tests {
         assert!(!e4.entry_match_no_index(&f_t1a));
     }
 
+    #[test]
+    fn test_range_entry_filter() {
+        let mut e1: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+            r#"{
+            "valid": null,
+            "state": null,
+            "attrs": {
+                "class": ["person"],
+                "uuid": ["db237e8a-0079-4b8c-8a56-593b22aa44d1"],
+                "displayname": ["9"]
+            }
+        }"#,
+        );
+        e1.add_ava("testnumber", &Value::new_uint32(9));
+        let e1 = unsafe { e1.to_valid_new() };
+
+        let mut e2: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+            r#"{
+            "valid": null,
+            "state": null,
+            "attrs": {
+                "class": ["person"],
+                "uuid": ["4b6228ab-1dbe-42a4-a9f5-f6368222438e"],
+                "displayname": ["10"]
+            }
+        }"#,
+        );
+        e2.add_ava("testnumber", &Value::new_uint32(10));
+        let e2 = unsafe { e2.to_valid_new() };
+
+        // Numeric ordering - 9 < 10
+        let f_t1a = unsafe { filter_resolved!(f_gte("testnumber", PartialValue::new_uint32(10))) };
+        assert!(!e1.entry_match_no_index(&f_t1a));
+        assert!(e2.entry_match_no_index(&f_t1a));
+
+        let f_t2a = unsafe { filter_resolved!(f_lte("testnumber", PartialValue::new_uint32(9))) };
+        assert!(e1.entry_match_no_index(&f_t2a));
+        assert!(!e2.entry_match_no_index(&f_t2a));
+
+        // Bounds are inclusive.
+        let f_t3a = unsafe {
+            filter_resolved!(f_and!([
+                f_gte("testnumber", PartialValue::new_uint32(9)),
+                f_lte("testnumber", PartialValue::new_uint32(10)),
+            ]))
+        };
+        assert!(e1.entry_match_no_index(&f_t3a));
+        assert!(e2.entry_match_no_index(&f_t3a));
+
+        // Lexical ordering - "10" < "9"
+        let f_t4a = unsafe { filter_resolved!(f_gte("displayname", PartialValue::new_utf8s("9"))) };
+        assert!(e1.entry_match_no_index(&f_t4a));
+        assert!(!e2.entry_match_no_index(&f_t4a));
+
+        let f_t5a =
+            unsafe { filter_resolved!(f_lte("displayname", PartialValue::new_utf8s("10"))) };
+        assert!(!e1.entry_match_no_index(&f_t5a));
+        assert!(e2.entry_match_no_index(&f_t5a));
+
+        // Mismatched types never match
+        let f_t6a = unsafe { filter_resolved!(f_gte("testnumber", PartialValue::new_utf8s("1"))) };
+        assert!(!e1.entry_match_no_index(&f_t6a));
+        assert!(!e2.entry_match_no_index(&f_t6a));
+    }
+
+    #[test]
+    fn test_inclusion_entry_filter() {
+        let e1: Entry<EntryValid, EntryNew> = unsafe {
+            Entry::unsafe_from_entry_str(
+                r#"{
+            "valid": null,
+            "state": null,
+            "attrs": {
+                "userid": ["william"],
+                "uuid": ["db237e8a-0079-4b8c-8a56-593b22aa44d1"]
+            }
+        }"#,
+            )
+            .to_valid_new()
+        };
+
+        let e2: Entry<EntryValid, EntryNew> = unsafe {
+            Entry::unsafe_from_entry_str(
+                r#"{
+            "valid": null,
+            "state": null,
+            "attrs": {
+                "userid": ["claire"],
+                "uuid": ["4b6228ab-1dbe-42a4-a9f5-f6368222438e"]
+            }
+        }"#,
+            )
+            .to_valid_new()
+        };
+
+        // An inclusion must match exactly what the equivalent or does.
+        let values = vec![
+            PartialValue::new_iutf8s("alice"),
+            PartialValue::new_iutf8s("william"),
+            PartialValue::new_iutf8s("bob"),
+        ];
+        let f_inc_a = unsafe { filter_resolved!(f_inc("userid", values.clone())) };
+        let f_or_a = unsafe {
+            filter_resolved!(f_or(
+                values.iter().map(|v| f_eq("userid", v.clone())).collect()
+            ))
+        };
+        assert!(e1.entry_match_no_index(&f_inc_a));
+        assert!(e1.entry_match_no_index(&f_or_a));
+        assert!(!e2.entry_match_no_index(&f_inc_a));
+        assert!(!e2.entry_match_no_index(&f_or_a));
+
+        // Not present at all
+        let f_t2a =
+            unsafe { filter_resolved!(f_inc("name", vec![PartialValue::new_iutf8s("william")])) };
+        assert!(!e1.entry_match_no_index(&f_t2a));
+
+        // The order and duplication of the set doesn't matter
+        let f_t3a = unsafe {
+            filter_resolved!(f_inc(
+                "userid",
+                vec![
+                    PartialValue::new_iutf8s("bob"),
+                    PartialValue::new_iutf8s("william"),
+                    PartialValue::new_iutf8s("alice"),
+                    PartialValue::new_iutf8s("bob"),
+                ]
+            ))
+        };
+        assert!(f_t3a.optimise() == f_inc_a.optimise());
+
+        // A set of one is an eq.
+        let f_t4a =
+            unsafe { filter_resolved!(f_inc("userid", vec![PartialValue::new_iutf8s("william")])) };
+        let f_t4b =
+            unsafe { filter_resolved!(f_eq("userid", PartialValue::new_iutf8s("william"))) };
+        assert!(f_t4a.optimise() == f_t4b);
+    }
+
+    fn nested_filter(depth: usize) -> ProtoFilter {
+        (1..depth).fold(ProtoFilter::Pres("class".to_string()), |acc, _| {
+            ProtoFilter::AndNot(Box::new(acc))
+        })
+    }
+
+    fn wide_filter(elements: usize) -> ProtoFilter {
+        // The or term itself counts as an element.
+        ProtoFilter::Or(
+            (1..elements)
+                .map(|_| ProtoFilter::Pres("class".to_string()))
+                .collect(),
+        )
+    }
+
+    fn inclusion_filter(size: usize) -> ProtoFilter {
+        ProtoFilter::Inclusion(
+            "name".to_string(),
+            (0..size).map(|i| format!("name{}", i)).collect(),
+        )
+    }
+
+    // A name given as an spn matches the account of that spn.
+    #[test]
+    fn test_filter_name_spn() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let qs_read = server.read();
+            let ev_int = Event::from_internal();
+
+            let search = |audit: &mut AuditScope, name: &str| {
+                let f = ProtoFilter::Eq("name".to_string(), name.to_string());
+                let f = Filter::from_ro(audit, &ev_int, &f, &qs_read).expect("invalid filter");
+                qs_read.internal_search(audit, f).expect("search failed")
+            };
+
+            let r = search(audit, "Admin@localhost");
+            assert!(r.len() == 1);
+            assert!(r[0].get_uuid() == &*UUID_ADMIN);
+            assert!(search(audit, "admin").len() == 1);
+            assert!(search(audit, "admin@example.com").len() == 0);
+        })
+    }
+
+    #[test]
+    fn test_filter_resource_limits() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let qs_read = server.read();
+            let anon = qs_read
+                .internal_search_uuid(audit, &UUID_ANONYMOUS)
+                .expect("failed");
+            let admin = qs_read
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("failed");
+            let ev_anon = Event::from_impersonate_entry(anon);
+            let ev_admin = Event::from_impersonate_entry(admin);
+            let ev_int = Event::from_internal();
+
+            let limits_anon = FilterLimits::new_anonymous();
+            let limits = FilterLimits::new();
+
+            // Just at the limit is fine.
+            let f_ok = nested_filter(limits_anon.max_depth);
+            assert!(Filter::from_ro(audit, &ev_anon, &f_ok, &qs_read).is_ok());
+
+            // One more is too deep for anonymous, but fine for a real user.
+            let f_deep = nested_filter(limits_anon.max_depth + 1);
+            assert!(
+                Filter::from_ro(audit, &ev_anon, &f_deep, &qs_read)
+                    == Err(OperationError::ResourceLimit)
+            );
+            assert!(Filter::from_ro(audit, &ev_admin, &f_deep, &qs_read).is_ok());
+
+            // A filter far beyond any limit fails cleanly.
+            let f_very_deep = nested_filter(limits.max_depth * 128);
+            assert!(
+                Filter::from_ro(audit, &ev_admin, &f_very_deep, &qs_read)
+                    == Err(OperationError::ResourceLimit)
+            );
+
+            // Element counts are limited too.
+            let f_wide_ok = wide_filter(limits.max_elements);
+            assert!(Filter::from_ro(audit, &ev_admin, &f_wide_ok, &qs_read).is_ok());
+            assert!(
+                Filter::from_ro(audit, &ev_anon, &f_wide_ok, &qs_read)
+                    == Err(OperationError::ResourceLimit)
+            );
+
+            let f_wide = wide_filter(limits.max_elements + 1);
+            assert!(
+                Filter::from_ro(audit, &ev_admin, &f_wide, &qs_read)
+                    == Err(OperationError::ResourceLimit)
+            );
+
+            // Internal events are not limited.
+            assert!(Filter::from_ro(audit, &ev_int, &f_wide, &qs_read).is_ok());
+
+            // Inclusion sets are limited by their size.
+            let f_inc_ok = inclusion_filter(limits.max_inclusion);
+            assert!(Filter::from_ro(audit, &ev_admin, &f_inc_ok, &qs_read).is_ok());
+            assert!(
+                Filter::from_ro(audit, &ev_anon, &f_inc_ok, &qs_read)
+                    == Err(OperationError::ResourceLimit)
+            );
+
+            let f_inc = inclusion_filter(limits.max_inclusion + 1);
+            assert!(
+                Filter::from_ro(audit, &ev_admin, &f_inc, &qs_read)
+                    == Err(OperationError::ResourceLimit)
+            );
+            assert!(Filter::from_ro(audit, &ev_int, &f_inc, &qs_read).is_ok());
+        })
+    }
+
     #[test]
     fn test_attr_set_filter() {
         let mut f_expect = BTreeSet::new();
@@ -1185,4 +1964,39 @@ mod tests {
 
         assert!(f_t2a.get_attr_set() == f_expect);
     }
+
+    #[test]
+    fn test_filter_is_indexed() {
+        let mut idx = BTreeSet::new();
+        idx.insert(("name".to_string(), IndexType::EQUALITY));
+        idx.insert(("name".to_string(), IndexType::SUBSTRING));
+
+        let f = unsafe { filter_resolved!(f_eq("name", PartialValue::new_iutf8s("alice"))) };
+        assert!(f.is_indexed(&idx));
+        let f = unsafe { filter_resolved!(f_pres("name")) };
+        assert!(!f.is_indexed(&idx));
+        // A substring needs at least one key to be looked up by.
+        let f = unsafe { filter_resolved!(f_sub("name", PartialValue::new_iutf8s("lic"))) };
+        assert!(f.is_indexed(&idx));
+        let f = unsafe { filter_resolved!(f_sub("name", PartialValue::new_iutf8s("li"))) };
+        assert!(!f.is_indexed(&idx));
+
+        // One indexed term narrows an and, but an or needs them all.
+        let f = unsafe {
+            filter_resolved!(f_and!([
+                f_eq("name", PartialValue::new_iutf8s("alice")),
+                f_pres("description"),
+            ]))
+        };
+        assert!(f.is_indexed(&idx));
+        let f = unsafe {
+            filter_resolved!(f_or!([
+                f_eq("name", PartialValue::new_iutf8s("alice")),
+                f_pres("description"),
+            ]))
+        };
+        assert!(!f.is_indexed(&idx));
+        let f = unsafe { filter_resolved!(f_andnot(f_pres("description"))) };
+        assert!(!f.is_indexed(&idx));
+    }
 }
@@ -1,18 +1,27 @@
 use crate::entry::{Entry, EntryCommitted, EntryValid};
 use kanidm_proto::v1::OperationError;
 
-use kanidm_proto::v1::UserAuthToken;
+use kanidm_proto::v1::{AuthDenyReason, UserAuthToken};
 
-use crate::credential::Credential;
+use crate::audit::AuditScope;
+use crate::constants::{AUTH_LOCKOUT_MAX, AUTH_LOCKOUT_SOURCE_FACTOR, UUID_ANONYMOUS};
+use crate::credential::apitoken::ApiToken;
+use crate::credential::totp::TOTP;
+use crate::credential::webauthn::WebauthnToken;
+use crate::credential::{generate_radius_secret, Credential, Policy};
 use crate::idm::claim::Claim;
 use crate::idm::group::Group;
-use crate::modify::{ModifyInvalid, ModifyList};
+use crate::modify::{m_purge, Modify, ModifyInvalid, ModifyList};
+use crate::server::QueryServerTransaction;
 use crate::value::{PartialValue, Value};
 
+use chrono::{DateTime, Utc};
+use std::time::{Duration, SystemTime};
 use uuid::Uuid;
 
 lazy_static! {
     static ref PVCLASS_ACCOUNT: PartialValue = PartialValue::new_class("account");
+    static ref PVCLASS_SERVICE_ACCOUNT: PartialValue = PartialValue::new_class("service_account");
 }
 
 #[derive(Debug, Clone)]
@@ -24,79 +33,303 @@ pub(crate) struct Account {
     // what the ops should be based on the values we cache here ... That's a future
     // william problem I think :)
     pub name: String,
+    pub spn: String,
     pub displayname: String,
     pub uuid: Uuid,
     pub groups: Vec<Group>,
     pub primary: Option<Credential>,
+    // Consecutive failed authentications, and when the lock they caused
+    // ends, in seconds since the epoch.
+    pub auth_failures: u32,
+    pub locked_until: Option<u64>,
+    // Service accounts authenticate with their api tokens, rather than
+    // the primary credential.
+    pub service_account: bool,
+    pub api_tokens: Vec<ApiToken>,
+    // The window the account may authenticate in, in seconds since the
+    // epoch. Either end may be open.
+    pub valid_from: Option<u64>,
+    pub expire: Option<u64>,
     // primary: Credential
     // app_creds: Vec<Credential>
-    // account expiry? (as opposed to cred expiry)
+}
+
+fn try_from_entry_common(
+    value: Entry<EntryValid, EntryCommitted>,
+    groups: Vec<Group>,
+) -> Result<Account, OperationError> {
+    // Check the classes
+    if !value.attribute_value_pres("class", &PVCLASS_ACCOUNT) {
+        return Err(OperationError::InvalidAccountState(
+            "Missing class: account",
+        ));
+    }
+
+    // Now extract our needed attributes
+    let name = value
+        .get_ava_single_string("name")
+        .ok_or(OperationError::InvalidAccountState(
+            "Missing attribute: name",
+        ))?;
+
+    // The spn is only missing before the domain info is created at first
+    // start.
+    let spn = value
+        .get_ava_single_string("spn")
+        .unwrap_or_else(|| name.clone());
+
+    let displayname =
+        value
+            .get_ava_single_string("displayname")
+            .ok_or(OperationError::InvalidAccountState(
+                "Missing attribute: displayname",
+            ))?;
+
+    let primary = value
+        .get_ava_single_credential("primary_credential")
+        .map(|v| v.clone());
+
+    let uuid = value.get_uuid().clone();
+
+    let auth_failures = value
+        .get_ava_single("auth_failures")
+        .and_then(|v| v.to_uint32())
+        .unwrap_or(0);
+
+    let locked_until = value
+        .get_ava_single("account_locked_until")
+        .and_then(|v| v.to_datetime())
+        .map(|dt| dt.timestamp().max(0) as u64);
+
+    let valid_from = value
+        .get_ava_single("account_valid_from")
+        .and_then(|v| v.to_datetime())
+        .map(|dt| dt.timestamp().max(0) as u64);
+
+    let expire = value
+        .get_ava_single("account_expire")
+        .and_then(|v| v.to_datetime())
+        .map(|dt| dt.timestamp().max(0) as u64);
+
+    let service_account = value.attribute_value_pres("class", &PVCLASS_SERVICE_ACCOUNT);
+
+    let api_tokens = value
+        .get_ava("api_token")
+        .map(|vs| {
+            vs.into_iter()
+                .filter_map(|v| v.to_apitoken())
+                .cloned()
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok(Account {
+        uuid: uuid,
+        name: name,
+        spn: spn,
+        displayname: displayname,
+        groups: groups,
+        primary: primary,
+        auth_failures: auth_failures,
+        locked_until: locked_until,
+        service_account: service_account,
+        api_tokens: api_tokens,
+        valid_from: valid_from,
+        expire: expire,
+    })
+}
+
+// How many failed authentications in a row lock an account, and for how
+// long at first, in seconds. A threshold of 0 never locks.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct LockoutPolicy {
+    pub threshold: u32,
+    pub window: u64,
+}
+
+impl LockoutPolicy {
+    // How long the given number of consecutive failures locks for. The
+    // window doubles with each failure past the threshold.
+    pub(crate) fn lock_for(&self, failures: u32) -> Option<u64> {
+        if self.threshold == 0 || failures < self.threshold {
+            return None;
+        }
+        let factor = 1u64
+            .checked_shl(failures - self.threshold)
+            .unwrap_or(u64::max_value());
+        Some(self.window.saturating_mul(factor).min(AUTH_LOCKOUT_MAX))
+    }
+
+    pub(crate) fn for_source(&self) -> LockoutPolicy {
+        LockoutPolicy {
+            threshold: self.threshold.saturating_mul(AUTH_LOCKOUT_SOURCE_FACTOR),
+            window: self.window,
+        }
+    }
 }
 
 impl Account {
-    // TODO #71: We need a second try_from that doesn't do group resolve for test cases I think.
-    pub(crate) fn try_from_entry(
+    pub(crate) fn try_from_entry<T: QueryServerTransaction>(
+        au: &mut AuditScope,
         value: Entry<EntryValid, EntryCommitted>,
+        qs: &T,
     ) -> Result<Self, OperationError> {
-        // Check the classes
-        if !value.attribute_value_pres("class", &PVCLASS_ACCOUNT) {
-            return Err(OperationError::InvalidAccountState(
-                "Missing class: account",
-            ));
-        }
+        let groups = try_audit!(au, Group::try_from_account_entry(au, &value, qs));
+        try_from_entry_common(value, groups)
+    }
 
-        // Now extract our needed attributes
-        let name =
-            value
-                .get_ava_single_string("name")
-                .ok_or(OperationError::InvalidAccountState(
-                    "Missing attribute: name",
-                ))?;
-
-        let displayname = value.get_ava_single_string("displayname").ok_or(
-            OperationError::InvalidAccountState("Missing attribute: displayname"),
-        )?;
-
-        let primary = value
-            .get_ava_single_credential("primary_credential")
-            .map(|v| v.clone());
-
-        // TODO #71: Resolve groups!!!!
-        let groups = Vec::new();
-
-        let uuid = value.get_uuid().clone();
-
-        Ok(Account {
-            uuid: uuid,
-            name: name,
-            displayname: displayname,
-            groups: groups,
-            primary: primary,
-        })
+    // Test cases don't always have a server to resolve groups from.
+    #[cfg(test)]
+    pub(crate) fn try_from_entry_no_groups(
+        value: Entry<EntryValid, EntryCommitted>,
+    ) -> Result<Self, OperationError> {
+        try_from_entry_common(value, Vec::new())
     }
 
     // Could this actually take a claims list and application instead?
-    pub(crate) fn to_userauthtoken(&self, claims: Vec<Claim>) -> Option<UserAuthToken> {
+    pub(crate) fn to_userauthtoken(
+        &self,
+        sessionid: &Uuid,
+        claims: Vec<Claim>,
+        ct: Duration,
+        lifetime: Duration,
+    ) -> Option<UserAuthToken> {
         // This could consume self?
         // The cred handler provided is what authenticated this user, so we can use it to
         // process what the proper claims should be.
 
         // Get the claims from the cred_h
 
+        // The token can't outlive the account.
+        let expiry = match self.expire {
+            Some(expire) => (ct + lifetime).as_secs().min(expire),
+            None => (ct + lifetime).as_secs(),
+        };
+
         Some(UserAuthToken {
+            issued_at: ct.as_secs(),
+            expiry: expiry,
+            auth_time: ct.as_secs(),
+            sessionid: sessionid.clone(),
             name: self.name.clone(),
+            spn: self.spn.clone(),
             displayname: self.name.clone(),
             uuid: self.uuid.to_hyphenated_ref().to_string(),
             application: None,
             groups: self.groups.iter().map(|g| g.into_proto()).collect(),
             claims: claims.iter().map(|c| c.into_proto()).collect(),
+            must_change_password: self
+                .primary
+                .as_ref()
+                .map(|c| c.must_change)
+                .unwrap_or(false),
+            anonymous: self.uuid == *UUID_ANONYMOUS,
+            // Set by the auth session, if it was an api token.
+            api_token: None,
+            read_only: false,
         })
     }
 
+    // Why the account may not authenticate at ct, if ct is outside of its
+    // valid from and expiry times.
+    pub(crate) fn validity_denied(&self, ct: Duration) -> Option<AuthDenyReason> {
+        let secs = ct.as_secs();
+        match (self.valid_from, self.expire) {
+            (Some(from), _) if secs < from => Some(AuthDenyReason::NotYetValid(from)),
+            (_, Some(expire)) if secs >= expire => Some(AuthDenyReason::Expired(expire)),
+            _ => None,
+        }
+    }
+
+    // When the account unlocks, if it is locked at ct.
+    pub(crate) fn is_locked(&self, ct: Duration) -> Option<u64> {
+        self.locked_until.filter(|until| *until > ct.as_secs())
+    }
+
+    // Record a failed authentication at ct, and the lock it begins if
+    // there have now been too many. The time the lock ends is returned.
+    pub(crate) fn gen_auth_failure_mod(
+        &self,
+        ct: Duration,
+        policy: &LockoutPolicy,
+    ) -> (ModifyList<ModifyInvalid>, Option<u64>) {
+        // Reaching the threshold always sets a lock, so being past it with
+        // no lock means an admin cleared it, and the count starts again.
+        let failures = if policy.threshold > 0
+            && self.auth_failures >= policy.threshold
+            && self.locked_until.is_none()
+        {
+            1
+        } else {
+            self.auth_failures.saturating_add(1)
+        };
+        let mut modlist =
+            ModifyList::new_purge_and_set("auth_failures", Value::new_uint32(failures));
+
+        let until = policy
+            .lock_for(failures)
+            .map(|lock| ct.as_secs().saturating_add(lock));
+        if let Some(until) = until {
+            let dt: DateTime<Utc> =
+                DateTime::from(SystemTime::UNIX_EPOCH + Duration::from_secs(until));
+            modlist.push_mod(m_purge("account_locked_until"));
+            modlist.push_mod(Modify::Present(
+                "account_locked_until".to_string(),
+                Value::new_datetime(dt),
+            ));
+        }
+        (modlist, until)
+    }
+
+    // A successful authentication ends the run of failures, and any lock
+    // that has expired. Nothing needs to change if there were none.
+    pub(crate) fn gen_auth_success_mod(&self) -> Option<ModifyList<ModifyInvalid>> {
+        if self.auth_failures == 0 && self.locked_until.is_none() {
+            None
+        } else {
+            Some(ModifyList::new_list(vec![
+                m_purge("auth_failures"),
+                m_purge("account_locked_until"),
+            ]))
+        }
+    }
+
+    // Record that the api token was used at ct. The token may have been
+    // destroyed while the session was authenticating, and that must not
+    // bring it back.
+    pub(crate) fn gen_api_token_used_mod(
+        &self,
+        id: &Uuid,
+        ct: Duration,
+    ) -> Result<ModifyList<ModifyInvalid>, OperationError> {
+        let at = self
+            .api_tokens
+            .iter()
+            .find(|at| at.id == *id)
+            .ok_or(OperationError::NoMatchingEntries)?;
+        Ok(ModifyList::new_list(vec![
+            Modify::Removed("api_token".to_string(), PartialValue::new_apitoken_id(*id)),
+            Modify::Present(
+                "api_token".to_string(),
+                Value::new_apitoken(at.set_last_used(ct)),
+            ),
+        ]))
+    }
+
+    // Replace the radius secret, returning the new one.
+    pub(crate) fn gen_radius_secret_mod(&self) -> (ModifyList<ModifyInvalid>, String) {
+        let secret = generate_radius_secret();
+        (
+            ModifyList::new_purge_and_set("radius_secret", Value::new_radius_str(secret.as_str())),
+            secret,
+        )
+    }
+
     pub(crate) fn gen_password_mod(
         &self,
         cleartext: &str,
         appid: &Option<String>,
+        must_change: bool,
     ) -> Result<ModifyList<ModifyInvalid>, OperationError> {
         // What should this look like? Probablf an appid + stuff -> modify?
         // then the caller has to apply the modify under the requests event
@@ -104,17 +337,17 @@ impl Account {
         match appid {
             Some(_) => Err(OperationError::InvalidState),
             None => {
-                // TODO: Enforce PW policy. Can we allow this change?
                 match &self.primary {
                     // Change the cred
                     Some(primary) => {
-                        let ncred = primary.set_password(cleartext);
+                        let ncred = primary.set_password(cleartext).set_must_change(must_change);
                         let vcred = Value::new_credential("primary", ncred);
                         Ok(ModifyList::new_purge_and_set("primary_credential", vcred))
                     }
                     // Make a new credential instead
                     None => {
-                        let ncred = Credential::new_password_only(cleartext);
+                        let ncred =
+                            Credential::new_password_only(cleartext).set_must_change(must_change);
                         let vcred = Value::new_credential("primary", ncred);
                         Ok(ModifyList::new_purge_and_set("primary_credential", vcred))
                     }
@@ -122,6 +355,116 @@ impl Account {
             } // no appid
         }
     }
+
+    // Add the totp to the primary credential, so that it is required as a
+    // second factor alongside the password.
+    pub(crate) fn gen_totp_mod(
+        &self,
+        totp: TOTP,
+    ) -> Result<ModifyList<ModifyInvalid>, OperationError> {
+        match &self.primary {
+            Some(primary) => {
+                let ncred = primary.update_totp(totp);
+                let vcred = Value::new_credential("primary", ncred);
+                Ok(ModifyList::new_purge_and_set("primary_credential", vcred))
+            }
+            None => Err(OperationError::InvalidAccountState(
+                "A password must be set before totp",
+            )),
+        }
+    }
+
+    pub(crate) fn gen_webauthn_add_mod(
+        &self,
+        token: WebauthnToken,
+    ) -> Result<ModifyList<ModifyInvalid>, OperationError> {
+        match &self.primary {
+            Some(primary) => {
+                let ncred = primary.add_webauthn(token)?;
+                let vcred = Value::new_credential("primary", ncred);
+                Ok(ModifyList::new_purge_and_set("primary_credential", vcred))
+            }
+            None => Err(OperationError::InvalidAccountState(
+                "A password must be set before webauthn",
+            )),
+        }
+    }
+
+    pub(crate) fn gen_webauthn_remove_mod(
+        &self,
+        name: &str,
+    ) -> Result<ModifyList<ModifyInvalid>, OperationError> {
+        match &self.primary {
+            Some(primary) => {
+                let ncred = primary.remove_webauthn(name)?;
+                let vcred = Value::new_credential("primary", ncred);
+                Ok(ModifyList::new_purge_and_set("primary_credential", vcred))
+            }
+            None => Err(OperationError::NoMatchingEntries),
+        }
+    }
+
+    pub(crate) fn gen_policy_mod(
+        &self,
+        policy: Option<Policy>,
+    ) -> Result<ModifyList<ModifyInvalid>, OperationError> {
+        match &self.primary {
+            Some(primary) => {
+                let ncred = primary.set_policy(policy)?;
+                let vcred = Value::new_credential("primary", ncred);
+                Ok(ModifyList::new_purge_and_set("primary_credential", vcred))
+            }
+            None => Err(OperationError::InvalidAccountState(
+                "A password must be set before a credential policy",
+            )),
+        }
+    }
+
+    // Backup codes stand in for the second factor, so one must be set up.
+    pub(crate) fn gen_backup_codes_mod(
+        &self,
+    ) -> Result<(ModifyList<ModifyInvalid>, Vec<String>), OperationError> {
+        match &self.primary {
+            Some(primary) if primary.totp.is_some() || !primary.webauthn.is_empty() => {
+                let (ncred, codes) = primary.generate_backup_codes();
+                let vcred = Value::new_credential("primary", ncred);
+                Ok((
+                    ModifyList::new_purge_and_set("primary_credential", vcred),
+                    codes,
+                ))
+            }
+            _ => Err(OperationError::InvalidAccountState(
+                "Totp or webauthn must be set before backup codes",
+            )),
+        }
+    }
+
+    pub(crate) fn gen_backup_code_consume_mod(
+        &self,
+        code: &str,
+    ) -> Result<ModifyList<ModifyInvalid>, OperationError> {
+        let ncred = self
+            .primary
+            .as_ref()
+            .and_then(|primary| primary.consume_backup_code(code))
+            .ok_or(OperationError::InvalidRequestState)?;
+        let vcred = Value::new_credential("primary", ncred);
+        Ok(ModifyList::new_purge_and_set("primary_credential", vcred))
+    }
+
+    pub(crate) fn gen_webauthn_counter_mod(
+        &self,
+        cred_id: &[u8],
+        counter: u32,
+    ) -> Result<ModifyList<ModifyInvalid>, OperationError> {
+        let ncred = self
+            .primary
+            .as_ref()
+            .and_then(|primary| primary.update_webauthn_counter(cred_id, counter))
+            .ok_or(OperationError::InvalidRequestState)?;
+        let vcred = Value::new_credential("primary", ncred);
+        Ok(ModifyList::new_purge_and_set("primary_credential", vcred))
+    }
 }
 
 // Need to also add a "to UserAuthToken" ...
@@ -140,7 +483,7 @@ mod tests {
             unsafe { Entry::unsafe_from_entry_str(JSON_ANONYMOUS_V1).to_valid_new() };
         let anon_e = unsafe { anon_e.to_valid_committed() };
 
-        let anon_account = Account::try_from_entry(anon_e).expect("Must not fail");
+        let anon_account = Account::try_from_entry_no_groups(anon_e).expect("Must not fail");
         println!("{:?}", anon_account);
         // I think that's it? we may want to check anonymous mech ...
     }
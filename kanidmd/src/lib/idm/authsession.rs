@@ -1,60 +1,313 @@
 use crate::audit::AuditScope;
 use crate::constants::UUID_ANONYMOUS;
 use crate::idm::account::Account;
-use crate::idm::claim::Claim;
+use crate::idm::claim::{Claim, ClaimPolicy};
 use kanidm_proto::v1::OperationError;
-use kanidm_proto::v1::{AuthAllowed, AuthCredential, AuthState};
+use kanidm_proto::v1::{
+    AuthAllowed, AuthCredential, AuthDenyReason, AuthState, WebauthnAssertion,
+    WebauthnRequestChallenge,
+};
 
-use crate::credential::{Credential, Password};
+use crate::credential::apitoken::ApiToken;
+use crate::credential::totp::TOTP;
+use crate::credential::webauthn::{counter_valid, Challenge, WebauthnConfig, WebauthnToken};
+use crate::credential::{normalise_backup_code, Credential, Password, Policy};
 
-use std::convert::TryFrom;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use uuid::Uuid;
 
 // Each CredHandler takes one or more credentials and determines if the
 // handlers requirements can be 100% fufilled. This is where MFA or other
 // auth policies would exist, but each credHandler has to be a whole
 // encapsulated unit of function.
 
+// Why a credential was refused, when it isn't because it was wrong.
+const DENY_NOT_PERMITTED: &'static str = "credential not permitted by policy";
+const DENY_UNSATISFIABLE: &'static str = "credential policy cannot be satisfied";
+const DENY_NOT_ANONYMOUS: &'static str = "non-anonymous credential provided";
+const DENY_NO_CREDENTIAL: &'static str = "authentication denied";
+const DENY_TOKEN_EXPIRED: &'static str = "api token expired";
+
+// Only a wrong credential is a failure, that counts towards locking the
+// account. Every other reason is the credential not being permitted.
+fn deny_reason(reason: &str) -> AuthDenyReason {
+    if reason == DENY_NOT_PERMITTED
+        || reason == DENY_UNSATISFIABLE
+        || reason == DENY_NOT_ANONYMOUS
+        || reason == DENY_NO_CREDENTIAL
+        || reason == DENY_TOKEN_EXPIRED
+    {
+        AuthDenyReason::NotPermitted
+    } else {
+        AuthDenyReason::Failed
+    }
+}
+
 enum CredState {
     Success(Vec<Claim>),
     Continue(Vec<AuthAllowed>),
     Denied(&'static str),
 }
 
+#[derive(Clone, Debug)]
+struct CredWebauthn {
+    config: WebauthnConfig,
+    tokens: Vec<WebauthnToken>,
+    chal: Challenge,
+    proto: WebauthnRequestChallenge,
+    // The token and counter that were accepted, which the server records on
+    // the account before the session is allowed.
+    used: Option<(Vec<u8>, u32)>,
+}
+
+impl CredWebauthn {
+    fn new(config: &WebauthnConfig, tokens: &[WebauthnToken]) -> Self {
+        // Each session has its own challenge for the tokens to sign.
+        let (proto, chal) = config.generate_challenge_authenticate(tokens);
+        CredWebauthn {
+            config: config.clone(),
+            tokens: tokens.to_vec(),
+            chal: chal,
+            proto: proto,
+            used: None,
+        }
+    }
+
+    fn validate(&mut self, asrt: &WebauthnAssertion) -> Option<&'static str> {
+        match self
+            .config
+            .authenticate_credential(&self.tokens, &self.chal, asrt)
+        {
+            Ok((cred_id, counter)) => {
+                // The counter is checked against the highest the account had
+                // recorded when the session began.
+                let last = self
+                    .tokens
+                    .iter()
+                    .find(|t| t.cred_id == cred_id)
+                    .map(|t| t.counter)
+                    .unwrap_or(0);
+                if counter_valid(last, counter) {
+                    self.used = Some((cred_id, counter));
+                    None
+                } else {
+                    Some("webauthn counter did not advance, the token may be cloned")
+                }
+            }
+            Err(OperationError::InvalidWebauthn(reason)) => Some(reason),
+            Err(_) => Some("webauthn authentication denied"),
+        }
+    }
+}
+
+// A password, and then any one of the second factors of the credential.
+#[derive(Clone, Debug)]
+struct CredMFA {
+    pw: Password,
+    pw_ok: bool,
+    totp: Option<TOTP>,
+    wan: Option<CredWebauthn>,
+    // The backup codes as they were when the session began. A code accepted
+    // here is only spent once the server removes it from the account.
+    backup_codes: Vec<Password>,
+    backup_code_used: Option<String>,
+    mfa_ok: bool,
+    // The credential uuid, which the last used totp step is recorded against.
+    cred_uuid: Uuid,
+}
+
+impl CredMFA {
+    fn mfa_mechs(&self) -> Vec<AuthAllowed> {
+        let mut mechs = Vec::new();
+        if self.totp.is_some() {
+            mechs.push(AuthAllowed::TOTP);
+        }
+        if let Some(wan) = &self.wan {
+            mechs.push(AuthAllowed::Webauthn(wan.proto.clone()));
+        }
+        if !self.backup_codes.is_empty() {
+            mechs.push(AuthAllowed::BackupCode);
+        }
+        mechs
+    }
+
+    // The mechanisms that satisfy the next outstanding factor.
+    fn next_mechs(&self) -> Vec<AuthAllowed> {
+        if self.pw_ok {
+            self.mfa_mechs()
+        } else {
+            vec![AuthAllowed::Password]
+        }
+    }
+
+    fn validate_password(&mut self, cleartext: &str) -> Option<&'static str> {
+        if self.pw_ok {
+            return Some(DENY_NOT_PERMITTED);
+        }
+        if self.pw.verify(cleartext) {
+            self.pw_ok = true;
+            None
+        } else {
+            Some("incorrect password")
+        }
+    }
+
+    fn validate_totp(
+        &mut self,
+        chal: &str,
+        ct: &Duration,
+        totp_last_step: &mut BTreeMap<Uuid, u64>,
+    ) -> Option<&'static str> {
+        let totp = match &self.totp {
+            Some(t) => t,
+            None => return Some(DENY_NOT_PERMITTED),
+        };
+        let chal = match chal.trim().parse::<u32>() {
+            Ok(c) => c,
+            Err(_) => return Some("invalid totp"),
+        };
+        match totp.verify(chal, ct) {
+            Some(step) => {
+                // A code may only be used once, so refuse any step at or
+                // before the last one accepted.
+                match totp_last_step.get(&self.cred_uuid) {
+                    Some(last) if step <= *last => Some("totp replayed"),
+                    _ => {
+                        totp_last_step.insert(self.cred_uuid, step);
+                        self.mfa_ok = true;
+                        None
+                    }
+                }
+            }
+            None => Some("incorrect totp"),
+        }
+    }
+
+    fn validate_webauthn(&mut self, asrt: &WebauthnAssertion) -> Option<&'static str> {
+        let res = match &mut self.wan {
+            Some(wan) => wan.validate(asrt),
+            None => return Some(DENY_NOT_PERMITTED),
+        };
+        if res.is_none() {
+            self.mfa_ok = true;
+        }
+        res
+    }
+
+    fn validate_backup_code(&mut self, code: &str) -> Option<&'static str> {
+        if self.backup_codes.is_empty() {
+            return Some(DENY_NOT_PERMITTED);
+        }
+        let code = normalise_backup_code(code);
+        if self.backup_codes.iter().any(|c| c.verify(code.as_str())) {
+            self.backup_code_used = Some(code);
+            self.mfa_ok = true;
+            None
+        } else {
+            Some("incorrect backup code")
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct CredApiToken {
+    tokens: Vec<ApiToken>,
+    // The token that was accepted, which shapes the session it begins.
+    used: Option<ApiToken>,
+}
+
+impl CredApiToken {
+    fn validate(&mut self, token: &str, ct: &Duration) -> Option<&'static str> {
+        let (id, secret) = match ApiToken::parse(token) {
+            Some(v) => v,
+            None => return Some("incorrect api token"),
+        };
+        let at = match self.tokens.iter().find(|at| at.id == id) {
+            Some(at) => at,
+            None => return Some("incorrect api token"),
+        };
+        if !at.verify(secret) {
+            Some("incorrect api token")
+        } else if at.is_expired(*ct) {
+            // Only say it has expired to someone who holds the token.
+            Some(DENY_TOKEN_EXPIRED)
+        } else {
+            self.used = Some(at.clone());
+            None
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 enum CredHandler {
-    Denied,
+    Denied(&'static str),
     // The bool is a flag if the cred has been authed against.
     Anonymous,
     // AppPassword
     // {
     // Password
-    Password(Password), // Webauthn
-                        // Webauthn + Password
-                        // TOTP
-                        // TOTP + Password
-                        // } <<-- could all these be "AccountPrimary" and pass to Account?
-                        // Selection at this level could be premature ...
-                        // Verification Link?
+    Password(Password),
+    // Password + (TOTP | Webauthn | BackupCode)
+    PasswordMFA(CredMFA),
+    // Webauthn
+    Webauthn(CredWebauthn),
+    // } <<-- could all these be "AccountPrimary" and pass to Account?
+    // Selection at this level could be premature ...
+    // Verification Link?
+    // The api tokens of a service account.
+    ApiToken(CredApiToken),
 }
 
-impl TryFrom<&Credential> for CredHandler {
-    type Error = ();
+impl CredHandler {
     // Is there a nicer implementation of this?
-    fn try_from(c: &Credential) -> Result<Self, Self::Error> {
-        if c.password.is_some() {
-            Ok(CredHandler::Password(c.password.clone().unwrap()))
-        } else {
-            Err(())
+    fn try_from(c: &Credential, webauthn: &WebauthnConfig) -> Result<Self, ()> {
+        let policy = c.policy();
+        if !c.satisfies(policy) {
+            return Ok(CredHandler::Denied(DENY_UNSATISFIABLE));
+        }
+
+        match policy {
+            Policy::PasswordOnly => {
+                let pw = c.password.as_ref().ok_or(())?;
+                Ok(CredHandler::Password(pw.clone()))
+            }
+            Policy::PasswordMFA => {
+                let pw = c.password.as_ref().ok_or(())?;
+                let wan = if c.webauthn.is_empty() {
+                    None
+                } else {
+                    Some(CredWebauthn::new(webauthn, &c.webauthn))
+                };
+
+                Ok(CredHandler::PasswordMFA(CredMFA {
+                    pw: pw.clone(),
+                    pw_ok: false,
+                    totp: c.totp.clone(),
+                    wan: wan,
+                    backup_codes: c.backup_codes.clone(),
+                    backup_code_used: None,
+                    mfa_ok: false,
+                    cred_uuid: c.uuid.clone(),
+                }))
+            }
+            Policy::WebauthnOnly => Ok(CredHandler::Webauthn(CredWebauthn::new(
+                webauthn,
+                &c.webauthn,
+            ))),
         }
     }
-}
 
-impl CredHandler {
-    pub fn validate(&mut self, creds: &Vec<AuthCredential>) -> CredState {
+    pub fn validate(
+        &mut self,
+        creds: &Vec<AuthCredential>,
+        ct: &Duration,
+        totp_last_step: &mut BTreeMap<Uuid, u64>,
+    ) -> CredState {
         match self {
-            CredHandler::Denied => {
+            CredHandler::Denied(reason) => {
                 // Sad trombone.
-                CredState::Denied("authentication denied")
+                CredState::Denied(*reason)
             }
             CredHandler::Anonymous => {
                 creds.iter().fold(
@@ -77,7 +330,7 @@ impl CredHandler {
                                         // For anonymous, no claims will ever be issued.
                                         CredState::Success(Vec::new())
                                     }
-                                    _ => CredState::Denied("non-anonymous credential provided"),
+                                    _ => CredState::Denied(DENY_NOT_ANONYMOUS),
                                 }
                             }
                         } // end match acc
@@ -92,7 +345,9 @@ impl CredHandler {
                         match acc {
                             // If failed, continue to fail.
                             CredState::Denied(_) => acc,
-                            _ => {
+                            // Nothing more is needed once the password is given.
+                            CredState::Success(_) => CredState::Denied(DENY_NOT_PERMITTED),
+                            CredState::Continue(_) => {
                                 match cred {
                                     AuthCredential::Password(cleartext) => {
                                         if pw.verify(cleartext.as_str()) {
@@ -102,32 +357,133 @@ impl CredHandler {
                                         }
                                     }
                                     // All other cases fail.
-                                    _ => CredState::Denied("pw authentication denied"),
+                                    _ => CredState::Denied(DENY_NOT_PERMITTED),
                                 }
                             }
                         } // end match acc
                     },
                 )
             } // end credhandler::password
+            CredHandler::PasswordMFA(pw_mfa) => {
+                // The factors may be given together or across several steps,
+                // but only in order, and any failure denies the whole credential.
+                let acc = creds.iter().fold(None, |acc, cred| match acc {
+                    Some(_) => acc,
+                    // Nothing more is needed once both factors are given.
+                    None if pw_mfa.mfa_ok => Some(DENY_NOT_PERMITTED),
+                    None => match cred {
+                        AuthCredential::Password(cleartext) => {
+                            pw_mfa.validate_password(cleartext.as_str())
+                        }
+                        // A second factor can't stand in for the password.
+                        _ if !pw_mfa.pw_ok => Some(DENY_NOT_PERMITTED),
+                        AuthCredential::TOTP(chal) => {
+                            pw_mfa.validate_totp(chal.as_str(), ct, totp_last_step)
+                        }
+                        AuthCredential::Webauthn(asrt) => pw_mfa.validate_webauthn(asrt),
+                        AuthCredential::BackupCode(code) => {
+                            pw_mfa.validate_backup_code(code.as_str())
+                        }
+                        AuthCredential::Anonymous | AuthCredential::ApiToken(_) => {
+                            Some(DENY_NOT_PERMITTED)
+                        }
+                    },
+                });
+
+                match acc {
+                    Some(reason) => CredState::Denied(reason),
+                    None if pw_mfa.mfa_ok => CredState::Success(Vec::new()),
+                    None => CredState::Continue(pw_mfa.next_mechs()),
+                }
+            } // end credhandler::passwordmfa
+            CredHandler::Webauthn(wan) => {
+                creds.iter().fold(
+                    CredState::Continue(vec![AuthAllowed::Webauthn(wan.proto.clone())]),
+                    |acc, cred| match acc {
+                        CredState::Denied(_) => acc,
+                        CredState::Success(_) => CredState::Denied(DENY_NOT_PERMITTED),
+                        CredState::Continue(_) => match cred {
+                            AuthCredential::Webauthn(asrt) => match wan.validate(asrt) {
+                                None => CredState::Success(Vec::new()),
+                                Some(reason) => CredState::Denied(reason),
+                            },
+                            // Even the right password doesn't help here.
+                            _ => CredState::Denied(DENY_NOT_PERMITTED),
+                        },
+                    },
+                )
+            } // end credhandler::webauthn
+            CredHandler::ApiToken(cred_at) => creds.iter().fold(
+                CredState::Continue(vec![AuthAllowed::ApiToken]),
+                |acc, cred| match acc {
+                    CredState::Denied(_) => acc,
+                    CredState::Success(_) => CredState::Denied(DENY_NOT_PERMITTED),
+                    CredState::Continue(_) => match cred {
+                        AuthCredential::ApiToken(token) => {
+                            match cred_at.validate(token.as_str(), ct) {
+                                None => CredState::Success(Vec::new()),
+                                Some(reason) => CredState::Denied(reason),
+                            }
+                        }
+                        _ => CredState::Denied(DENY_NOT_PERMITTED),
+                    },
+                },
+            ), // end credhandler::apitoken
         }
     }
 
     pub fn valid_auth_mechs(&self) -> Vec<AuthAllowed> {
         match &self {
-            CredHandler::Denied => Vec::new(),
+            CredHandler::Denied(_) => Vec::new(),
             CredHandler::Anonymous => vec![AuthAllowed::Anonymous],
             CredHandler::Password(_) => vec![AuthAllowed::Password],
-            // webauth
-            // mfa
+            CredHandler::PasswordMFA(pw_mfa) => pw_mfa.next_mechs(),
+            CredHandler::Webauthn(wan) => vec![AuthAllowed::Webauthn(wan.proto.clone())],
+            CredHandler::ApiToken(_) => vec![AuthAllowed::ApiToken],
         }
     }
 
-    pub(crate) fn is_denied(&self) -> bool {
+    // Claims that require multi-factor credentials are only granted when
+    // both factors were given.
+    fn is_mfa(&self) -> bool {
         match &self {
-            CredHandler::Denied => true,
+            CredHandler::PasswordMFA(_) => true,
             _ => false,
         }
     }
+
+    fn api_token_used(&self) -> Option<&ApiToken> {
+        match &self {
+            CredHandler::ApiToken(cred_at) => cred_at.used.as_ref(),
+            _ => None,
+        }
+    }
+
+    fn webauthn_used(&self) -> Option<&(Vec<u8>, u32)> {
+        match &self {
+            CredHandler::PasswordMFA(pw_mfa) => {
+                pw_mfa.wan.as_ref().and_then(|wan| wan.used.as_ref())
+            }
+            CredHandler::Webauthn(wan) => wan.used.as_ref(),
+            _ => None,
+        }
+    }
+
+    fn backup_code_used(&self) -> Option<&str> {
+        match &self {
+            CredHandler::PasswordMFA(pw_mfa) => {
+                pw_mfa.backup_code_used.as_ref().map(|c| c.as_str())
+            }
+            _ => None,
+        }
+    }
+
+    pub(crate) fn denied_reason(&self) -> Option<&'static str> {
+        match &self {
+            CredHandler::Denied(reason) => Some(*reason),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -143,33 +499,48 @@ pub(crate) struct AuthSession {
     handler: CredHandler,
     // Store any related appid we are processing for.
     appid: Option<String>,
-    // Store claims related to the handler
+    // The claims requested for this session that the account may have. They
+    // are granted on success if the credentials given were strong enough.
+    claims: Vec<ClaimPolicy>,
     // need to store state somehow?
     finished: bool,
+    // The active session this is reauthenticating, if it is a reauth.
+    reauth: Option<Uuid>,
 }
 
 impl AuthSession {
-    pub fn new(account: Account, appid: Option<String>) -> Self {
+    pub fn new(account: Account, appid: Option<String>, webauthn: &WebauthnConfig) -> Self {
         // During this setup, determine the credential handler that we'll be using
         // for this session. This is currently based on presentation of an application
         // id.
         let handler = match appid {
-            Some(_) => CredHandler::Denied,
+            Some(_) => CredHandler::Denied(DENY_NO_CREDENTIAL),
             None => {
                 // We want the primary handler - this is where we make a decision
                 // based on the anonymous ... in theory this could be cleaner
                 // and interact with the account more?
                 if account.uuid == UUID_ANONYMOUS.clone() {
                     CredHandler::Anonymous
+                } else if account.service_account {
+                    // Service accounts may only use their api tokens.
+                    if account.api_tokens.is_empty() {
+                        CredHandler::Denied(DENY_NO_CREDENTIAL)
+                    } else {
+                        CredHandler::ApiToken(CredApiToken {
+                            tokens: account.api_tokens.clone(),
+                            used: None,
+                        })
+                    }
                 } else {
                     // Now we see if they have one ...
                     match &account.primary {
                         Some(cred) => {
                             // TODO: Log this corruption better ... :(
                             // Probably means new authsession has to be failable
-                            CredHandler::try_from(cred).unwrap_or_else(|_| CredHandler::Denied)
+                            CredHandler::try_from(cred, webauthn)
+                                .unwrap_or_else(|_| CredHandler::Denied(DENY_NO_CREDENTIAL))
                         }
-                        None => CredHandler::Denied,
+                        None => CredHandler::Denied(DENY_NO_CREDENTIAL),
                     }
                 }
             }
@@ -182,21 +553,51 @@ impl AuthSession {
         // TODO #59: Implement handler locking!
 
         // if credhandler == deny, finish = true.
-        let finished: bool = handler.is_denied();
+        let finished: bool = handler.denied_reason().is_some();
 
         AuthSession {
             account: account,
             handler: handler,
             appid: appid,
+            claims: Vec::new(),
             finished: finished,
+            reauth: None,
         }
     }
 
+    // Request claims for the session, replacing any requested before.
+    pub fn request_claims(
+        &mut self,
+        claims: Vec<ClaimPolicy>,
+    ) -> Result<AuthState, OperationError> {
+        if self.finished {
+            return Err(OperationError::InvalidAuthState(
+                "session already finalised!",
+            ));
+        }
+        self.claims = claims;
+        Ok(AuthState::Continue(self.handler.valid_auth_mechs()))
+    }
+
+    // Make this session a reauth of the active session, which is refreshed
+    // rather than a new session begun when it succeeds.
+    pub fn set_reauth(&mut self, sessionid: Uuid) {
+        self.reauth = Some(sessionid);
+    }
+
+    pub fn reauth(&self) -> Option<&Uuid> {
+        self.reauth.as_ref()
+    }
+
     // This should return a AuthResult or similar state of checking?
     pub fn validate_creds(
         &mut self,
         au: &mut AuditScope,
+        sessionid: &Uuid,
         creds: &Vec<AuthCredential>,
+        ct: Duration,
+        lifetime: Duration,
+        totp_last_step: &mut BTreeMap<Uuid, u64>,
     ) -> Result<AuthState, OperationError> {
         if self.finished {
             return Err(OperationError::InvalidAuthState(
@@ -204,14 +605,34 @@ impl AuthSession {
             ));
         }
 
-        match self.handler.validate(creds) {
-            CredState::Success(claims) => {
+        match self.handler.validate(creds, &ct, totp_last_step) {
+            CredState::Success(mut claims) => {
                 audit_log!(au, "Successful cred handling");
                 self.finished = true;
-                let uat = self
+                let mfa = self.handler.is_mfa();
+                for policy in self.claims.iter() {
+                    match policy.grant(mfa, ct) {
+                        Some(c) => claims.push(c),
+                        None => audit_log!(
+                            au,
+                            "claim {} requires multi-factor credentials",
+                            policy.name()
+                        ),
+                    }
+                }
+                let mut uat = self
                     .account
-                    .to_userauthtoken(claims)
+                    .to_userauthtoken(sessionid, claims, ct, lifetime)
                     .ok_or(OperationError::InvalidState)?;
+                // A service session can't outlive its token, and is read only
+                // unless the token allows writes.
+                if let Some(at) = self.handler.api_token_used() {
+                    uat.api_token = Some(at.id);
+                    uat.read_only = !at.read_write;
+                    if let Some(e) = at.expiry {
+                        uat.expiry = uat.expiry.min(e);
+                    }
+                }
                 Ok(AuthState::Success(uat))
             }
             CredState::Continue(allowed) => {
@@ -221,7 +642,7 @@ impl AuthSession {
             CredState::Denied(reason) => {
                 self.finished = true;
                 audit_log!(au, "Credentials denied: {}", reason);
-                Ok(AuthState::Denied(reason.to_string()))
+                Ok(AuthState::Denied(deny_reason(reason), reason.to_string()))
             }
         }
         // Also send an async message to self to log the auth as provided.
@@ -237,6 +658,34 @@ impl AuthSession {
         //  If success, to authtoken?
     }
 
+    pub fn account_uuid(&self) -> &Uuid {
+        &self.account.uuid
+    }
+
+    // The backup code this session was authenticated with, which must be
+    // removed from the account before the session is allowed.
+    pub fn backup_code_used(&self) -> Option<&str> {
+        self.handler.backup_code_used()
+    }
+
+    // The webauthn token and counter this session was authenticated with,
+    // which must be recorded on the account before the session is allowed.
+    pub fn webauthn_used(&self) -> Option<&(Vec<u8>, u32)> {
+        self.handler.webauthn_used()
+    }
+
+    // Why the session was denied as it began, such as when the credential
+    // can't meet its policy.
+    pub fn denied_reason(&self) -> Option<&'static str> {
+        self.handler.denied_reason()
+    }
+
+    // The api token this session was authenticated with, whose last use
+    // is recorded on the account.
+    pub fn api_token_used(&self) -> Option<&Uuid> {
+        self.handler.api_token_used().map(|at| &at.id)
+    }
+
     pub fn valid_auth_mechs(&self) -> Vec<AuthAllowed> {
         if self.finished {
             Vec::new()
@@ -248,16 +697,50 @@ impl AuthSession {
 
 #[cfg(test)]
 mod tests {
+    use crate::audit::AuditScope;
     use crate::constants::{JSON_ADMIN_V1, JSON_ANONYMOUS_V1};
-    use crate::credential::Credential;
-    use crate::idm::authsession::AuthSession;
-    use kanidm_proto::v1::AuthAllowed;
+    use crate::credential::totp::{TOTP, TOTP_DEFAULT_STEP};
+    use crate::credential::webauthn::softtoken::SoftToken;
+    use crate::credential::webauthn::WebauthnConfig;
+    use crate::credential::{Credential, Policy};
+    use crate::idm::authsession::{AuthSession, DENY_NOT_PERMITTED, DENY_UNSATISFIABLE};
+    use kanidm_proto::v1::{
+        AuthAllowed, AuthCredential, AuthDenyReason, AuthState, OperationError,
+    };
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    static ORIGIN: &'static str = "https://idm.example.com";
+
+    // Give one step of creds to the session, at a fixed time.
+    fn auth_step(
+        au: &mut AuditScope,
+        session: &mut AuthSession,
+        creds: Vec<AuthCredential>,
+    ) -> AuthState {
+        session
+            .validate_creds(
+                au,
+                &Uuid::new_v4(),
+                &creds,
+                Duration::from_secs(6000),
+                Duration::from_secs(3600),
+                &mut BTreeMap::new(),
+            )
+            .expect("Failed to validate creds")
+    }
+
+    fn password(pw: &str) -> AuthCredential {
+        AuthCredential::Password(pw.to_string())
+    }
 
     #[test]
     fn test_idm_authsession_anonymous_auth_mech() {
+        let webauthn = WebauthnConfig::new("idm.example.com", ORIGIN);
         let anon_account = entry_str_to_account!(JSON_ANONYMOUS_V1);
 
-        let session = AuthSession::new(anon_account, None);
+        let session = AuthSession::new(anon_account, None, &webauthn);
 
         let auth_mechs = session.valid_auth_mechs();
 
@@ -271,9 +754,14 @@ mod tests {
 
     #[test]
     fn test_idm_authsession_missing_appid() {
+        let webauthn = WebauthnConfig::new("idm.example.com", ORIGIN);
         let anon_account = entry_str_to_account!(JSON_ANONYMOUS_V1);
 
-        let session = AuthSession::new(anon_account, Some("NonExistantAppID".to_string()));
+        let session = AuthSession::new(
+            anon_account,
+            Some("NonExistantAppID".to_string()),
+            &webauthn,
+        );
 
         let auth_mechs = session.valid_auth_mechs();
 
@@ -283,6 +771,7 @@ mod tests {
 
     #[test]
     fn test_idm_authsession_simple_password_mech() {
+        let webauthn = WebauthnConfig::new("idm.example.com", ORIGIN);
         // create the ent
         let mut account = entry_str_to_account!(JSON_ADMIN_V1);
         // manually load in a cred
@@ -290,7 +779,7 @@ mod tests {
         account.primary = Some(cred);
 
         // now check
-        let session = AuthSession::new(account, None);
+        let session = AuthSession::new(account, None, &webauthn);
         let auth_mechs = session.valid_auth_mechs();
 
         assert!(
@@ -300,4 +789,434 @@ mod tests {
             })
         );
     }
+
+    #[test]
+    fn test_idm_authsession_password_totp_mech() {
+        let webauthn = WebauthnConfig::new("idm.example.com", ORIGIN);
+        let mut au = AuditScope::new("test_idm_authsession_password_totp_mech");
+        let totp = TOTP::generate_secure(TOTP_DEFAULT_STEP);
+        let ct = Duration::from_secs(6000);
+        let lifetime = Duration::from_secs(3600);
+        let sid = Uuid::new_v4();
+        let mut last_step = BTreeMap::new();
+
+        let code = totp
+            .do_totp_duration_from_epoch(&ct)
+            .expect("Failed to generate code");
+        let bad_code = (code + 1) % 1_000_000;
+
+        let mut account = entry_str_to_account!(JSON_ADMIN_V1);
+        account.primary =
+            Some(Credential::new_password_only("test_password").update_totp(totp.clone()));
+
+        // Only the password is asked for first.
+        let session = AuthSession::new(account.clone(), None, &webauthn);
+        assert!(session.valid_auth_mechs() == vec![AuthAllowed::Password]);
+
+        // Password alone asks for the totp.
+        let mut session = AuthSession::new(account.clone(), None, &webauthn);
+        match session.validate_creds(
+            &mut au,
+            &sid,
+            &vec![AuthCredential::Password("test_password".to_string())],
+            ct,
+            lifetime,
+            &mut last_step,
+        ) {
+            Ok(AuthState::Continue(allowed)) => assert!(allowed == vec![AuthAllowed::TOTP]),
+            _ => panic!(),
+        };
+        // A wrong code is denied.
+        match session.validate_creds(
+            &mut au,
+            &sid,
+            &vec![AuthCredential::TOTP(bad_code.to_string())],
+            ct,
+            lifetime,
+            &mut last_step,
+        ) {
+            Ok(AuthState::Denied(_, _)) => {}
+            _ => panic!(),
+        };
+
+        // Both in turn succeed.
+        let mut session = AuthSession::new(account.clone(), None, &webauthn);
+        match session.validate_creds(
+            &mut au,
+            &sid,
+            &vec![AuthCredential::Password("test_password".to_string())],
+            ct,
+            lifetime,
+            &mut last_step,
+        ) {
+            Ok(AuthState::Continue(_)) => {}
+            _ => panic!(),
+        };
+        match session.validate_creds(
+            &mut au,
+            &sid,
+            &vec![AuthCredential::TOTP(format!("{:06}", code))],
+            ct,
+            lifetime,
+            &mut last_step,
+        ) {
+            Ok(AuthState::Success(_)) => {}
+            _ => panic!(),
+        };
+
+        // The same code can not be used again, even with the right password.
+        let mut session = AuthSession::new(account.clone(), None, &webauthn);
+        match session.validate_creds(
+            &mut au,
+            &sid,
+            &vec![
+                AuthCredential::Password("test_password".to_string()),
+                AuthCredential::TOTP(format!("{:06}", code)),
+            ],
+            ct,
+            lifetime,
+            &mut last_step,
+        ) {
+            Ok(AuthState::Denied(_, _)) => {}
+            _ => panic!(),
+        };
+
+        // The code of the next step can be, but not before the password.
+        let ct_next = ct + Duration::from_secs(TOTP_DEFAULT_STEP);
+        let code_next = totp
+            .do_totp_duration_from_epoch(&ct_next)
+            .expect("Failed to generate code");
+        let mut session = AuthSession::new(account.clone(), None, &webauthn);
+        match session.validate_creds(
+            &mut au,
+            &sid,
+            &vec![
+                AuthCredential::TOTP(format!("{:06}", code_next)),
+                AuthCredential::Password("test_password".to_string()),
+            ],
+            ct_next,
+            lifetime,
+            &mut last_step,
+        ) {
+            Ok(AuthState::Denied(AuthDenyReason::NotPermitted, reason)) => {
+                assert!(reason == DENY_NOT_PERMITTED)
+            }
+            _ => panic!(),
+        };
+        let mut session = AuthSession::new(account, None, &webauthn);
+        match session.validate_creds(
+            &mut au,
+            &sid,
+            &vec![
+                AuthCredential::Password("test_password".to_string()),
+                AuthCredential::TOTP(format!("{:06}", code_next)),
+            ],
+            ct_next,
+            lifetime,
+            &mut last_step,
+        ) {
+            Ok(AuthState::Success(_)) => {}
+            _ => panic!(),
+        };
+        println!("{}", au);
+    }
+
+    #[test]
+    fn test_idm_authsession_password_webauthn_mech() {
+        let webauthn = WebauthnConfig::new("idm.example.com", ORIGIN);
+        let mut au = AuditScope::new("test_idm_authsession_password_webauthn_mech");
+        let ct = Duration::from_secs(6000);
+        let lifetime = Duration::from_secs(3600);
+        let sid = Uuid::new_v4();
+        let mut last_step = BTreeMap::new();
+
+        let mut token = SoftToken::new();
+        let (proto, chal) =
+            webauthn.generate_challenge_register("admin", "Admin", &Uuid::new_v4(), &[]);
+        let wan = webauthn
+            .register_credential(&token.register(&proto, ORIGIN), &chal, "yubikey")
+            .expect("Failed to register");
+
+        let mut account = entry_str_to_account!(JSON_ADMIN_V1);
+        account.primary = Some(
+            Credential::new_password_only("test_password")
+                .add_webauthn(wan)
+                .expect("Failed to add token"),
+        );
+
+        // The challenge is advertised once the password is given.
+        let mut session = AuthSession::new(account.clone(), None, &webauthn);
+        assert!(session.valid_auth_mechs() == vec![AuthAllowed::Password]);
+        // A clone of the token would be behind on its counter.
+        let mut clone = token.clone();
+
+        let wan_chal = match session.validate_creds(
+            &mut au,
+            &sid,
+            &vec![AuthCredential::Password("test_password".to_string())],
+            ct,
+            lifetime,
+            &mut last_step,
+        ) {
+            Ok(AuthState::Continue(allowed)) => match allowed.as_slice() {
+                [AuthAllowed::Webauthn(c)] => c.clone(),
+                _ => panic!(),
+            },
+            _ => panic!(),
+        };
+        match session.validate_creds(
+            &mut au,
+            &sid,
+            &vec![AuthCredential::Webauthn(token.sign(&wan_chal, ORIGIN))],
+            ct,
+            lifetime,
+            &mut last_step,
+        ) {
+            Ok(AuthState::Success(_)) => {}
+            _ => panic!(),
+        };
+        // The server records the counter on the account, as it would on success.
+        let (cred_id, counter) = session.webauthn_used().cloned().expect("No token used");
+        account.primary = account
+            .primary
+            .as_ref()
+            .and_then(|c| c.update_webauthn_counter(&cred_id, counter));
+        assert!(account.primary.is_some());
+
+        // The clone signs with the counter the token had already used.
+        let mut session = AuthSession::new(account.clone(), None, &webauthn);
+        let wan_chal = match session.validate_creds(
+            &mut au,
+            &sid,
+            &vec![AuthCredential::Password("test_password".to_string())],
+            ct,
+            lifetime,
+            &mut last_step,
+        ) {
+            Ok(AuthState::Continue(allowed)) => match allowed.as_slice() {
+                [AuthAllowed::Webauthn(c)] => c.clone(),
+                _ => panic!(),
+            },
+            _ => panic!(),
+        };
+        match session.validate_creds(
+            &mut au,
+            &sid,
+            &vec![AuthCredential::Webauthn(clone.sign(&wan_chal, ORIGIN))],
+            ct,
+            lifetime,
+            &mut last_step,
+        ) {
+            Ok(AuthState::Denied(_, _)) => {}
+            _ => panic!(),
+        };
+
+        // And a response to the challenge of another session is refused.
+        let mut session = AuthSession::new(account, None, &webauthn);
+        match session.validate_creds(
+            &mut au,
+            &sid,
+            &vec![
+                AuthCredential::Password("test_password".to_string()),
+                AuthCredential::Webauthn(token.sign(&wan_chal, ORIGIN)),
+            ],
+            ct,
+            lifetime,
+            &mut last_step,
+        ) {
+            Ok(AuthState::Denied(_, _)) => {}
+            _ => panic!(),
+        };
+        println!("{}", au);
+    }
+
+    #[test]
+    fn test_idm_authsession_policy_password_only() {
+        let webauthn = WebauthnConfig::new("idm.example.com", ORIGIN);
+        let mut au = AuditScope::new("test_idm_authsession_policy_password_only");
+
+        // The totp is enrolled, but the policy doesn't ask for it.
+        let mut account = entry_str_to_account!(JSON_ADMIN_V1);
+        account.primary = Some(
+            Credential::new_password_only("test_password")
+                .update_totp(TOTP::generate_secure(TOTP_DEFAULT_STEP))
+                .set_policy(Some(Policy::PasswordOnly))
+                .expect("Failed to set policy"),
+        );
+
+        let mut session = AuthSession::new(account.clone(), None, &webauthn);
+        assert!(session.valid_auth_mechs() == vec![AuthAllowed::Password]);
+        match auth_step(&mut au, &mut session, vec![password("test_password")]) {
+            AuthState::Success(_) => {}
+            _ => panic!(),
+        };
+
+        // A totp is superfluous, and so refused, as is a second password.
+        let mut session = AuthSession::new(account.clone(), None, &webauthn);
+        match auth_step(
+            &mut au,
+            &mut session,
+            vec![AuthCredential::TOTP("000000".to_string())],
+        ) {
+            AuthState::Denied(AuthDenyReason::NotPermitted, reason) => {
+                assert!(reason == DENY_NOT_PERMITTED)
+            }
+            _ => panic!(),
+        };
+        let mut session = AuthSession::new(account.clone(), None, &webauthn);
+        match auth_step(
+            &mut au,
+            &mut session,
+            vec![password("test_password"), password("test_password")],
+        ) {
+            AuthState::Denied(AuthDenyReason::NotPermitted, reason) => {
+                assert!(reason == DENY_NOT_PERMITTED)
+            }
+            _ => panic!(),
+        };
+
+        // Which is told apart from a wrong password.
+        let mut session = AuthSession::new(account, None, &webauthn);
+        match auth_step(&mut au, &mut session, vec![password("wrong")]) {
+            AuthState::Denied(AuthDenyReason::Failed, reason) => {
+                assert!(reason == "incorrect password")
+            }
+            _ => panic!(),
+        };
+    }
+
+    #[test]
+    fn test_idm_authsession_policy_password_mfa_progress() {
+        let webauthn = WebauthnConfig::new("idm.example.com", ORIGIN);
+        let mut au = AuditScope::new("test_idm_authsession_policy_password_mfa_progress");
+
+        let mut account = entry_str_to_account!(JSON_ADMIN_V1);
+        account.primary = Some(
+            Credential::new_password_only("test_password")
+                .update_totp(TOTP::generate_secure(TOTP_DEFAULT_STEP)),
+        );
+        // With no policy set, one follows from the factors.
+        assert!(account.primary.as_ref().map(|c| c.policy()) == Some(Policy::PasswordMFA));
+
+        // Each step leaves only what is outstanding.
+        let mut session = AuthSession::new(account.clone(), None, &webauthn);
+        assert!(session.valid_auth_mechs() == vec![AuthAllowed::Password]);
+        match auth_step(&mut au, &mut session, vec![]) {
+            AuthState::Continue(allowed) => assert!(allowed == vec![AuthAllowed::Password]),
+            _ => panic!(),
+        };
+        match auth_step(&mut au, &mut session, vec![password("test_password")]) {
+            AuthState::Continue(allowed) => assert!(allowed == vec![AuthAllowed::TOTP]),
+            _ => panic!(),
+        };
+        assert!(session.valid_auth_mechs() == vec![AuthAllowed::TOTP]);
+        // The password has been given, so giving it again doesn't help.
+        match auth_step(&mut au, &mut session, vec![password("test_password")]) {
+            AuthState::Denied(AuthDenyReason::NotPermitted, reason) => {
+                assert!(reason == DENY_NOT_PERMITTED)
+            }
+            _ => panic!(),
+        };
+
+        // Nor does a backup code, when there are none.
+        let mut session = AuthSession::new(account, None, &webauthn);
+        match auth_step(
+            &mut au,
+            &mut session,
+            vec![
+                password("test_password"),
+                AuthCredential::BackupCode("aaaaa-aaaaa".to_string()),
+            ],
+        ) {
+            AuthState::Denied(AuthDenyReason::NotPermitted, reason) => {
+                assert!(reason == DENY_NOT_PERMITTED)
+            }
+            _ => panic!(),
+        };
+    }
+
+    #[test]
+    fn test_idm_authsession_policy_webauthn_only() {
+        let webauthn = WebauthnConfig::new("idm.example.com", ORIGIN);
+        let mut au = AuditScope::new("test_idm_authsession_policy_webauthn_only");
+
+        let mut token = SoftToken::new();
+        let (proto, chal) =
+            webauthn.generate_challenge_register("admin", "Admin", &Uuid::new_v4(), &[]);
+        let wan = webauthn
+            .register_credential(&token.register(&proto, ORIGIN), &chal, "yubikey")
+            .expect("Failed to register");
+
+        // The policy can't be set until there is a token.
+        let cred = Credential::new_password_only("test_password");
+        match cred.set_policy(Some(Policy::WebauthnOnly)) {
+            Err(OperationError::InvalidAccountState(_)) => {}
+            _ => panic!(),
+        };
+        let cred = cred
+            .add_webauthn(wan)
+            .expect("Failed to add token")
+            .set_policy(Some(Policy::WebauthnOnly))
+            .expect("Failed to set policy");
+        // And then the last token can't be removed.
+        match cred.remove_webauthn("yubikey") {
+            Err(OperationError::InvalidAccountState(_)) => {}
+            _ => panic!(),
+        };
+
+        let mut account = entry_str_to_account!(JSON_ADMIN_V1);
+        account.primary = Some(cred);
+
+        // The right password doesn't help.
+        let mut session = AuthSession::new(account.clone(), None, &webauthn);
+        match auth_step(&mut au, &mut session, vec![password("test_password")]) {
+            AuthState::Denied(AuthDenyReason::NotPermitted, reason) => {
+                assert!(reason == DENY_NOT_PERMITTED)
+            }
+            _ => panic!(),
+        };
+
+        // The token alone is enough.
+        let mut session = AuthSession::new(account, None, &webauthn);
+        let wan_chal = match session.valid_auth_mechs().as_slice() {
+            [AuthAllowed::Webauthn(c)] => c.clone(),
+            _ => panic!(),
+        };
+        match auth_step(
+            &mut au,
+            &mut session,
+            vec![AuthCredential::Webauthn(token.sign(&wan_chal, ORIGIN))],
+        ) {
+            AuthState::Success(_) => {}
+            _ => panic!(),
+        };
+    }
+
+    #[test]
+    fn test_idm_authsession_policy_unsatisfiable() {
+        let webauthn = WebauthnConfig::new("idm.example.com", ORIGIN);
+        let mut au = AuditScope::new("test_idm_authsession_policy_unsatisfiable");
+
+        // As if the credential was changed without regard to its policy.
+        let mut cred = Credential::new_password_only("test_password");
+        cred.policy = Some(Policy::PasswordMFA);
+        let mut account = entry_str_to_account!(JSON_ADMIN_V1);
+        account.primary = Some(cred);
+
+        let mut session = AuthSession::new(account, None, &webauthn);
+        assert!(session.denied_reason() == Some(DENY_UNSATISFIABLE));
+        assert!(session.valid_auth_mechs() == Vec::new());
+        match session.validate_creds(
+            &mut au,
+            &Uuid::new_v4(),
+            &vec![password("test_password")],
+            Duration::from_secs(6000),
+            Duration::from_secs(3600),
+            &mut BTreeMap::new(),
+            &mut BTreeMap::new(),
+        ) {
+            Err(OperationError::InvalidAuthState(_)) => {}
+            _ => panic!(),
+        };
+    }
 }
@@ -1,20 +1,124 @@
+use crate::audit::AuditScope;
+use crate::entry::{Entry, EntryCommitted, EntryValid};
+use crate::idm::group::Group;
+use crate::server::QueryServerTransaction;
+use crate::value::PartialValue;
 use kanidm_proto::v1::Claim as ProtoClaim;
+use kanidm_proto::v1::OperationError;
 
-#[derive(Debug)]
+use std::time::Duration;
+use uuid::Uuid;
+
+lazy_static! {
+    static ref PVCLASS_CLAIM: PartialValue = PartialValue::new_class("claim");
+}
+
+#[derive(Debug, Clone)]
 pub struct Claim {
-    // For now, empty. Later we'll flesh this out to uuid + name?
+    name: String,
+    uuid: Uuid,
+    expiry: Option<u64>,
 }
 
 impl Claim {
-    /*
-    pub fn new() -> Self {
-        Claim {
-            // Fill this in!
+    pub fn into_proto(&self) -> ProtoClaim {
+        ProtoClaim {
+            name: self.name.clone(),
+            uuid: self.uuid.to_hyphenated_ref().to_string(),
+            expiry: self.expiry,
         }
     }
-    */
+}
 
-    pub fn into_proto(&self) -> ProtoClaim {
-        unimplemented!();
+// A claim as it's defined by its entry. Sessions of the members of its groups
+// may request it, but if it requires multi-factor credentials, it's only
+// granted when they were given.
+#[derive(Debug, Clone)]
+pub(crate) struct ClaimPolicy {
+    name: String,
+    uuid: Uuid,
+    groups: Vec<Uuid>,
+    require_mfa: bool,
+    // How long the claim lasts after it's granted, in seconds. Without this
+    // it lasts as long as the session.
+    lifetime: Option<u64>,
+}
+
+impl ClaimPolicy {
+    // Resolve the claims requested by name. Names that aren't claims are
+    // ignored, as the session is simply not granted them.
+    pub fn try_from_names<T: QueryServerTransaction>(
+        au: &mut AuditScope,
+        names: &[String],
+        qs: &T,
+    ) -> Result<Vec<Self>, OperationError> {
+        if names.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let filt = filter!(f_and!([
+            f_eq("class", PVCLASS_CLAIM.clone()),
+            f_or(
+                names
+                    .iter()
+                    .map(|n| f_eq("name", PartialValue::new_iutf8s(n.as_str())))
+                    .collect()
+            )
+        ]));
+        let entries = try_audit!(au, qs.internal_search(au, filt));
+
+        entries.iter().map(ClaimPolicy::try_from_entry).collect()
+    }
+
+    pub fn try_from_entry(
+        value: &Entry<EntryValid, EntryCommitted>,
+    ) -> Result<Self, OperationError> {
+        if !value.attribute_value_pres("class", &PVCLASS_CLAIM) {
+            return Err(OperationError::InvalidEntryState);
+        }
+
+        let name = value
+            .get_ava_single_string("name")
+            .ok_or(OperationError::InvalidEntryState)?;
+
+        let groups = value
+            .get_ava_reference_uuid("claim_group")
+            .map(|uuids| uuids.into_iter().cloned().collect())
+            .unwrap_or_else(|| Vec::new());
+
+        Ok(ClaimPolicy {
+            name: name,
+            uuid: value.get_uuid().clone(),
+            groups: groups,
+            require_mfa: value
+                .get_ava_single_bool("claim_require_mfa")
+                .unwrap_or(false),
+            lifetime: value
+                .get_ava_single("claim_lifetime")
+                .and_then(|v| v.to_uint32())
+                .map(|l| l as u64),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    // Only the members of the claim's groups may request it.
+    pub fn may_request(&self, groups: &[Group]) -> bool {
+        groups.iter().any(|g| self.groups.contains(g.uuid()))
+    }
+
+    // Grant the claim to a session authenticated at ct, if its credentials
+    // were strong enough.
+    pub fn grant(&self, mfa: bool, ct: Duration) -> Option<Claim> {
+        if self.require_mfa && !mfa {
+            return None;
+        }
+        Some(Claim {
+            name: self.name.clone(),
+            uuid: self.uuid.clone(),
+            expiry: self.lifetime.map(|l| ct.as_secs() + l),
+        })
     }
 }
@@ -1,12 +1,29 @@
+use crate::audit::AuditScope;
 use crate::event::Event;
+use crate::server::QueryServerWriteTransaction;
+use kanidm_proto::v1::{OperationError, UserAuthToken, REDACTED};
+use std::fmt;
 use uuid::Uuid;
 
-#[derive(Debug)]
 pub struct PasswordChangeEvent {
     pub event: Event,
     pub target: Uuid,
     pub cleartext: String,
     pub appid: Option<String>,
+    // The account must change the password again before it can be used.
+    pub must_change: bool,
+}
+
+impl fmt::Debug for PasswordChangeEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PasswordChangeEvent")
+            .field("event", &self.event)
+            .field("target", &self.target)
+            .field("cleartext", &REDACTED)
+            .field("appid", &self.appid)
+            .field("must_change", &self.must_change)
+            .finish()
+    }
 }
 
 impl PasswordChangeEvent {
@@ -16,6 +33,28 @@ impl PasswordChangeEvent {
             target: target.clone(),
             cleartext: cleartext.to_string(),
             appid: appid.map(|v| v.to_string()),
+            must_change: false,
         }
     }
+
+    // A change made on behalf of the holder of uat, so subject to their
+    // access controls.
+    pub fn from_parts(
+        audit: &mut AuditScope,
+        qs: &QueryServerWriteTransaction,
+        uat: &UserAuthToken,
+        target: &Uuid,
+        cleartext: &str,
+        must_change: bool,
+    ) -> Result<Self, OperationError> {
+        let e = Event::from_rw_uat(audit, qs, Some(uat.clone()))?;
+
+        Ok(PasswordChangeEvent {
+            event: e,
+            target: target.clone(),
+            cleartext: cleartext.to_string(),
+            appid: None,
+            must_change: must_change,
+        })
+    }
 }
@@ -1,19 +1,85 @@
+use crate::audit::AuditScope;
+use crate::entry::{Entry, EntryCommitted, EntryValid};
+use crate::server::QueryServerTransaction;
+use crate::value::PartialValue;
 use kanidm_proto::v1::Group as ProtoGroup;
+use kanidm_proto::v1::OperationError;
+
+use uuid::Uuid;
+
+lazy_static! {
+    static ref PVCLASS_GROUP: PartialValue = PartialValue::new_class("group");
+}
 
 #[derive(Debug, Clone)]
 pub struct Group {
-    // name
-// uuid
+    name: String,
+    uuid: Uuid,
 }
 
 impl Group {
-    /*
-    pub fn new() -> Self {
-        Group {}
+    // Resolve the groups an account is a member of. The memberof plugin
+    // maintains this attribute with nested memberships already flattened,
+    // so we only need to look up the names.
+    pub fn try_from_account_entry<T: QueryServerTransaction>(
+        au: &mut AuditScope,
+        value: &Entry<EntryValid, EntryCommitted>,
+        qs: &T,
+    ) -> Result<Vec<Self>, OperationError> {
+        match value.get_ava_reference_uuid("memberof") {
+            Some(uuids) => Self::try_from_uuids(au, uuids, qs),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub fn try_from_uuids<T: QueryServerTransaction>(
+        au: &mut AuditScope,
+        uuids: Vec<&Uuid>,
+        qs: &T,
+    ) -> Result<Vec<Self>, OperationError> {
+        if uuids.len() == 0 {
+            return Ok(Vec::new());
+        }
+
+        let filt = filter!(f_or(
+            uuids
+                .into_iter()
+                .map(|u| f_eq("uuid", PartialValue::new_uuidr(u)))
+                .collect()
+        ));
+        let entries = try_audit!(au, qs.internal_search(au, filt));
+
+        entries.iter().map(Group::try_from_entry).collect()
+    }
+
+    pub fn try_from_entry(
+        value: &Entry<EntryValid, EntryCommitted>,
+    ) -> Result<Self, OperationError> {
+        if !value.attribute_value_pres("class", &PVCLASS_GROUP) {
+            return Err(OperationError::InvalidAccountState("Missing class: group"));
+        }
+
+        let name =
+            value
+                .get_ava_single_string("name")
+                .ok_or(OperationError::InvalidAccountState(
+                    "Missing attribute: name",
+                ))?;
+
+        Ok(Group {
+            name: name,
+            uuid: value.get_uuid().clone(),
+        })
+    }
+
+    pub fn uuid(&self) -> &Uuid {
+        &self.uuid
     }
-    */
 
     pub fn into_proto(&self) -> ProtoGroup {
-        unimplemented!();
+        ProtoGroup {
+            name: self.name.clone(),
+            uuid: self.uuid.to_hyphenated_ref().to_string(),
+        }
     }
 }
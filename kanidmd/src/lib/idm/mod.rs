@@ -6,5 +6,10 @@ pub(crate) mod account;
 pub(crate) mod authsession;
 pub(crate) mod claim;
 pub(crate) mod group;
+pub(crate) mod oauth2;
+pub(crate) mod radius;
+pub(crate) mod reauth;
 pub(crate) mod server;
+pub(crate) mod tokenkeys;
+pub(crate) mod unix;
 // mod identity;
@@ -0,0 +1,611 @@
+// A minimal oauth2 and openid connect provider, so that web applications can
+// sign accounts in with their kanidm sessions. Only the authorization code
+// flow with PKCE is supported. The account authenticates to kanidm as it
+// always does, and its session then authorizes the client, which exchanges
+// the code for tokens signed with the same keys as UserAuthTokens.
+use crate::audit::AuditScope;
+use crate::constants::{OAUTH2_ACCESS_TOKEN_LIFETIME, OAUTH2_CODE_LIFETIME};
+use crate::entry::{Entry, EntryCommitted, EntryValid};
+use crate::idm::tokenkeys::TokenKeys;
+use crate::server::QueryServerTransaction;
+use crate::value::PartialValue;
+use kanidm_proto::v1::OperationError;
+use kanidm_proto::v1::{
+    Oauth2AuthorizeRequest, Oauth2TokenRequest, Oauth2TokenResponse, Oauth2UserInfo, OidcToken,
+    UserAuthToken,
+};
+
+use openssl::sha::sha256;
+use rand::Rng;
+use std::time::Duration;
+use uuid::Uuid;
+
+lazy_static! {
+    static ref PVCLASS_OAUTH2_CLIENT: PartialValue = PartialValue::new_class("oauth2_client");
+}
+
+// The scopes that mean something to us. A client may map any others, but
+// they only appear in the scope of its tokens.
+const SCOPE_OPENID: &'static str = "openid";
+const SCOPE_PROFILE: &'static str = "profile";
+const SCOPE_GROUPS: &'static str = "groups";
+
+// The JWS types of the tokens issued to clients, which are never accepted
+// as UserAuthTokens. Access tokens are typed as RFC 9068 gives.
+const TYP_ACCESS_TOKEN: &'static str = "at+jwt";
+const TYP_ID_TOKEN: &'static str = "JWT";
+
+const OAUTH2_CODE_LEN: usize = 32;
+const PKCE_VERIFIER_MIN: usize = 43;
+const PKCE_VERIFIER_MAX: usize = 128;
+
+// Leave only the unreserved characters of RFC 3986 as they are.
+fn query_encode(v: &str) -> String {
+    v.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+// The redirect uri with params added to its query. Those without a value
+// are left out.
+pub(crate) fn redirect_location(redirect_uri: &str, params: &[(&str, Option<&str>)]) -> String {
+    let query: Vec<String> = params
+        .iter()
+        .filter_map(|(k, v)| v.map(|v| format!("{}={}", k, query_encode(v))))
+        .collect();
+    let sep = if redirect_uri.contains('?') { '&' } else { '?' };
+    format!("{}{}{}", redirect_uri, sep, query.join("&"))
+}
+
+pub(crate) fn generate_code() -> String {
+    let mut rng = rand::thread_rng();
+    let raw: Vec<u8> = (0..OAUTH2_CODE_LEN).map(|_| rng.gen()).collect();
+    base64::encode_config(&raw, base64::URL_SAFE_NO_PAD)
+}
+
+// Does the verifier hash to the challenge, as S256 of RFC 7636 describes?
+fn pkce_verify(verifier: &str, challenge: &str) -> bool {
+    let valid = verifier.len() >= PKCE_VERIFIER_MIN
+        && verifier.len() <= PKCE_VERIFIER_MAX
+        && verifier
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-._~".contains(&b));
+    valid
+        && base64::encode_config(&sha256(verifier.as_bytes()), base64::URL_SAFE_NO_PAD) == challenge
+}
+
+fn has_scope(scopes: &[String], scope: &str) -> bool {
+    scopes.iter().any(|s| s == scope)
+}
+
+// The claims of uat that the scopes cover, as name, preferred_username and
+// groups.
+fn scoped_claims(
+    scopes: &[String],
+    uat: &UserAuthToken,
+) -> (Option<String>, Option<String>, Option<Vec<String>>) {
+    let profile = has_scope(scopes, SCOPE_PROFILE);
+    (
+        Some(uat.displayname.clone()).filter(|_| profile),
+        Some(uat.name.clone()).filter(|_| profile),
+        Some(uat.groups.iter().map(|g| g.name.clone()).collect())
+            .filter(|_| has_scope(scopes, SCOPE_GROUPS)),
+    )
+}
+
+// A client as it's registered by its entry, which is named by the client id.
+#[derive(Debug, Clone)]
+pub(crate) struct Oauth2Client {
+    name: String,
+    redirect_uris: Vec<String>,
+    // Each scope the client may be granted, and the uuid of the group whose
+    // members it's granted to. A scope may be granted to many groups.
+    scope_map: Vec<(String, String)>,
+}
+
+impl Oauth2Client {
+    pub fn try_from_name<T: QueryServerTransaction>(
+        au: &mut AuditScope,
+        name: &str,
+        qs: &T,
+    ) -> Result<Self, OperationError> {
+        let filt = filter!(f_and!([
+            f_eq("class", PVCLASS_OAUTH2_CLIENT.clone()),
+            f_eq("name", PartialValue::new_iutf8s(name))
+        ]));
+        let entries = try_audit!(au, qs.internal_search(au, filt));
+        match entries.first() {
+            Some(e) => Oauth2Client::try_from_entry(au, e, qs),
+            None => {
+                audit_log!(au, "no oauth2 client is registered as {}", name);
+                Err(OperationError::Oauth2("invalid_client"))
+            }
+        }
+    }
+
+    pub fn try_from_entry<T: QueryServerTransaction>(
+        au: &mut AuditScope,
+        value: &Entry<EntryValid, EntryCommitted>,
+        qs: &T,
+    ) -> Result<Self, OperationError> {
+        if !value.attribute_value_pres("class", &PVCLASS_OAUTH2_CLIENT) {
+            return Err(OperationError::InvalidEntryState);
+        }
+
+        let name = value
+            .get_ava_single_string("name")
+            .ok_or(OperationError::InvalidEntryState)?;
+
+        let redirect_uris = value
+            .get_ava_set_str("oauth2_redirect_uri")
+            .map(|uris| uris.into_iter().map(|u| u.to_string()).collect())
+            .unwrap_or_else(|| Vec::new());
+
+        // A mapping that is malformed, or whose group no longer exists,
+        // grants nothing rather than failing the client.
+        let mut scope_map = Vec::new();
+        for m in value
+            .get_ava_set_str("oauth2_scope_map")
+            .unwrap_or_else(|| Default::default())
+        {
+            let parts: Vec<&str> = m.split_whitespace().collect();
+            if parts.len() != 2 {
+                audit_log!(au, "ignoring malformed scope map {:?} of {}", m, name);
+                continue;
+            }
+            let group = match Uuid::parse_str(parts[1]) {
+                Ok(u) => Ok(u),
+                Err(_) => qs.name_to_uuid(au, parts[1]),
+            };
+            match group {
+                Ok(u) => scope_map.push((parts[0].to_string(), u.to_hyphenated_ref().to_string())),
+                Err(_) => audit_log!(au, "ignoring scope map {:?} of {}", m, name),
+            }
+        }
+
+        Ok(Oauth2Client {
+            name: name,
+            redirect_uris: redirect_uris,
+            scope_map: scope_map,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    // Redirect uris are compared exactly, so that a code can never be sent
+    // anywhere the client didn't register.
+    pub fn is_redirect_uri(&self, uri: &str) -> bool {
+        self.redirect_uris.iter().any(|u| u == uri)
+    }
+
+    // The scopes asked for that the client maps to a group of the session,
+    // in the order they were asked for.
+    fn granted_scopes(&self, requested: &str, uat: &UserAuthToken) -> Vec<String> {
+        let mut granted: Vec<String> = Vec::new();
+        for scope in requested.split_whitespace() {
+            let allowed = self
+                .scope_map
+                .iter()
+                .any(|(s, g)| s == scope && uat.groups.iter().any(|ug| &ug.uuid == g));
+            if allowed && !has_scope(&granted, scope) {
+                granted.push(scope.to_string());
+            }
+        }
+        granted
+    }
+
+    // Check an authorization request from the session of uat, which the
+    // redirect uri has already been checked for. A refusal gives the error
+    // code to redirect with.
+    pub fn begin_grant(
+        &self,
+        au: &mut AuditScope,
+        uat: &UserAuthToken,
+        req: &Oauth2AuthorizeRequest,
+        ct: Duration,
+    ) -> Result<Oauth2CodeGrant, &'static str> {
+        if req.response_type != "code" {
+            audit_log!(au, "unsupported response type {}", req.response_type);
+            return Err("unsupported_response_type");
+        }
+        let code_challenge = match (&req.code_challenge, &req.code_challenge_method) {
+            (Some(c), Some(m)) if m == "S256" && !c.is_empty() => c.clone(),
+            _ => {
+                audit_log!(au, "authorization request without an S256 code challenge");
+                return Err("invalid_request");
+            }
+        };
+        if uat.anonymous {
+            audit_log!(au, "anonymous may not authorize oauth2 clients");
+            return Err("access_denied");
+        }
+        if req.scope.split_whitespace().next().is_none() {
+            return Err("invalid_scope");
+        }
+        let scopes = self.granted_scopes(req.scope.as_str(), uat);
+        if scopes.is_empty() {
+            audit_log!(
+                au,
+                "{} may not grant {} any of {:?}",
+                uat.name,
+                self.name,
+                req.scope
+            );
+            return Err("access_denied");
+        }
+
+        Ok(Oauth2CodeGrant {
+            client_id: self.name.clone(),
+            redirect_uri: req.redirect_uri.clone(),
+            code_challenge: code_challenge,
+            scopes: scopes,
+            nonce: req.nonce.clone(),
+            uat: uat.clone(),
+            expiry: ct.as_secs() + OAUTH2_CODE_LIFETIME,
+        })
+    }
+}
+
+// An authorization code that has been issued and not yet exchanged, with what
+// the exchange must match and the session it was issued from.
+#[derive(Debug, Clone)]
+pub struct Oauth2CodeGrant {
+    client_id: String,
+    redirect_uri: String,
+    code_challenge: String,
+    scopes: Vec<String>,
+    nonce: Option<String>,
+    uat: UserAuthToken,
+    expiry: u64,
+}
+
+impl Oauth2CodeGrant {
+    pub fn sessionid(&self) -> &Uuid {
+        &self.uat.sessionid
+    }
+
+    pub fn is_expired(&self, ct: Duration) -> bool {
+        ct.as_secs() >= self.expiry
+    }
+
+    // Check an exchange of the code against what it was issued with. Every
+    // failure is invalid_grant, so the client isn't told which part was wrong.
+    pub fn check_exchange(
+        &self,
+        au: &mut AuditScope,
+        req: &Oauth2TokenRequest,
+        ct: Duration,
+    ) -> Result<(), OperationError> {
+        let failure = if self.is_expired(ct) {
+            Some("the code has expired")
+        } else if req.client_id != self.client_id {
+            Some("the code was issued to another client")
+        } else if req.redirect_uri != self.redirect_uri {
+            Some("the redirect uri does not match")
+        } else if !pkce_verify(req.code_verifier.as_str(), self.code_challenge.as_str()) {
+            Some("the code verifier does not match the challenge")
+        } else {
+            None
+        };
+        match failure {
+            Some(reason) => {
+                audit_log!(au, "oauth2 code exchange refused: {}", reason);
+                Err(OperationError::Oauth2("invalid_grant"))
+            }
+            None => Ok(()),
+        }
+    }
+
+    // The tokens the client is given for the code. They last no longer than
+    // the session they came from.
+    pub fn issue(
+        &self,
+        keys: &TokenKeys,
+        issuer: &str,
+        ct: Duration,
+    ) -> Result<Oauth2TokenResponse, OperationError> {
+        let iat = ct.as_secs();
+        let exp = std::cmp::min(iat + OAUTH2_ACCESS_TOKEN_LIFETIME, self.uat.expiry);
+        let scope = self.scopes.join(" ");
+        let (name, preferred_username, groups) = scoped_claims(&self.scopes, &self.uat);
+
+        let access_token = Oauth2AccessToken {
+            iss: issuer.to_string(),
+            aud: self.client_id.clone(),
+            sub: self.uat.uuid.clone(),
+            iat: iat,
+            exp: exp,
+            sid: self.uat.sessionid,
+            scope: scope.clone(),
+            name: name.clone(),
+            preferred_username: preferred_username.clone(),
+            groups: groups.clone(),
+        };
+
+        let id_token = if has_scope(&self.scopes, SCOPE_OPENID) {
+            let oidc = OidcToken {
+                iss: issuer.to_string(),
+                sub: self.uat.uuid.clone(),
+                aud: self.client_id.clone(),
+                iat: iat,
+                exp: exp,
+                auth_time: self.uat.auth_time,
+                nonce: self.nonce.clone(),
+                name: name,
+                preferred_username: preferred_username,
+                groups: groups,
+            };
+            Some(keys.sign(Some(TYP_ID_TOKEN), &oidc)?)
+        } else {
+            None
+        };
+
+        Ok(Oauth2TokenResponse {
+            access_token: keys.sign(Some(TYP_ACCESS_TOKEN), &access_token)?,
+            token_type: "Bearer".to_string(),
+            expires_in: exp.saturating_sub(iat),
+            scope: scope,
+            id_token: id_token,
+        })
+    }
+}
+
+// The claims of an access token. They are only for us to read back at the
+// userinfo endpoint, so aren't part of the proto.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Oauth2AccessToken {
+    iss: String,
+    aud: String,
+    sub: String,
+    iat: u64,
+    exp: u64,
+    // The kanidm session the token was issued from, which it ends with.
+    sid: Uuid,
+    scope: String,
+    // Only the claims the scopes cover, as in the id token, as a client can
+    // read these as well as we can.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    preferred_username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    groups: Option<Vec<String>>,
+}
+
+impl Oauth2AccessToken {
+    // Check the signature and expiry of an access token at ct. Whether its
+    // session is still active is for the caller to check.
+    pub fn verify(keys: &TokenKeys, token: &str, ct: Duration) -> Option<Self> {
+        keys.verify::<Oauth2AccessToken>(Some(TYP_ACCESS_TOKEN), token, ct)
+            .filter(|at| ct.as_secs() < at.exp)
+    }
+
+    pub fn sessionid(&self) -> &Uuid {
+        &self.sid
+    }
+
+    pub fn userinfo(&self) -> Oauth2UserInfo {
+        Oauth2UserInfo {
+            sub: self.sub.clone(),
+            name: self.name.clone(),
+            preferred_username: self.preferred_username.clone(),
+            groups: self.groups.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::AuditScope;
+    use crate::idm::oauth2::{pkce_verify, redirect_location, Oauth2Client, Oauth2CodeGrant};
+    use crate::idm::oauth2::{Oauth2AccessToken, PKCE_VERIFIER_MIN};
+    use crate::idm::tokenkeys::TokenKeys;
+    use kanidm_proto::v1::{
+        Group, Oauth2AuthorizeRequest, Oauth2TokenRequest, OidcToken, UserAuthToken,
+    };
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    static TEST_CURRENT_TIME: u64 = 6000;
+    static TEST_GROUP_UUID: &'static str = "a7b3c9d2-4e5f-4a6b-8c7d-9e0f1a2b3c4d";
+    static TEST_REDIRECT: &'static str = "https://app.example.com/callback";
+    // The example verifier and challenge of RFC 7636 appendix B.
+    static TEST_VERIFIER: &'static str = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+    static TEST_CHALLENGE: &'static str = "E9Melhoa2OwvFrEMTJguCHaoeK1t6URwbuz47vCqDmm";
+
+    fn test_uat() -> UserAuthToken {
+        UserAuthToken {
+            issued_at: TEST_CURRENT_TIME,
+            expiry: TEST_CURRENT_TIME + 600,
+            auth_time: TEST_CURRENT_TIME,
+            sessionid: Uuid::new_v4(),
+            name: "testperson".to_string(),
+            spn: "testperson@localhost".to_string(),
+            displayname: "Test Person".to_string(),
+            uuid: "cc8e95b4-c24f-4d68-ba54-8bed76f63930".to_string(),
+            application: None,
+            groups: vec![Group {
+                name: "testgroup".to_string(),
+                uuid: TEST_GROUP_UUID.to_string(),
+            }],
+            claims: Vec::new(),
+            must_change_password: false,
+            anonymous: false,
+            api_token: None,
+            read_only: false,
+        }
+    }
+
+    fn test_client() -> Oauth2Client {
+        Oauth2Client {
+            name: "testapp".to_string(),
+            redirect_uris: vec![TEST_REDIRECT.to_string()],
+            scope_map: vec![
+                ("openid".to_string(), TEST_GROUP_UUID.to_string()),
+                ("profile".to_string(), TEST_GROUP_UUID.to_string()),
+                (
+                    "admin".to_string(),
+                    Uuid::new_v4().to_hyphenated_ref().to_string(),
+                ),
+            ],
+        }
+    }
+
+    fn test_grant(client: &Oauth2Client, scope: &str) -> Result<Oauth2CodeGrant, &'static str> {
+        let mut au = AuditScope::new("test_oauth2_grant");
+        let req = Oauth2AuthorizeRequest::new("testapp", TEST_REDIRECT, scope, TEST_CHALLENGE);
+        client.begin_grant(
+            &mut au,
+            &test_uat(),
+            &req,
+            Duration::from_secs(TEST_CURRENT_TIME),
+        )
+    }
+
+    #[test]
+    fn test_idm_oauth2_pkce() {
+        assert!(pkce_verify(TEST_VERIFIER, TEST_CHALLENGE));
+        assert!(!pkce_verify(
+            TEST_VERIFIER,
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t6URwbuz47vCqDmM"
+        ));
+        // A verifier that is too short is refused whatever it hashes to.
+        let short = &TEST_VERIFIER[..PKCE_VERIFIER_MIN - 1];
+        assert!(!pkce_verify(short, TEST_CHALLENGE));
+        assert!(!pkce_verify("", TEST_CHALLENGE));
+    }
+
+    #[test]
+    fn test_idm_oauth2_redirect_location() {
+        assert!(
+            redirect_location(TEST_REDIRECT, &[("code", Some("abc")), ("state", None)])
+                == "https://app.example.com/callback?code=abc"
+        );
+        assert!(
+            redirect_location(
+                "https://app.example.com/cb?a=b",
+                &[("error", Some("access_denied")), ("state", Some("x y&z"))]
+            ) == "https://app.example.com/cb?a=b&error=access_denied&state=x%20y%26z"
+        );
+    }
+
+    #[test]
+    fn test_idm_oauth2_scopes() {
+        let client = test_client();
+        assert!(client.is_redirect_uri(TEST_REDIRECT));
+        assert!(!client.is_redirect_uri("https://app.example.com/callback/"));
+        assert!(!client.is_redirect_uri("https://APP.example.com/callback"));
+
+        // Only the scopes mapped to a group of the session are granted.
+        let grant = test_grant(&client, "openid email admin profile openid").expect("grant failed");
+        assert!(grant.scopes == vec!["openid".to_string(), "profile".to_string()]);
+
+        assert!(test_grant(&client, "admin").err() == Some("access_denied"));
+        assert!(test_grant(&client, "").err() == Some("invalid_scope"));
+    }
+
+    #[test]
+    fn test_idm_oauth2_exchange() {
+        let ct = Duration::from_secs(TEST_CURRENT_TIME);
+        let mut au = AuditScope::new("test_idm_oauth2_exchange");
+        let client = test_client();
+        let mut grant = test_grant(&client, "openid profile").expect("grant failed");
+        grant.nonce = Some("a nonce".to_string());
+
+        let req = Oauth2TokenRequest::new("code", TEST_REDIRECT, "testapp", TEST_VERIFIER);
+        assert!(grant.check_exchange(&mut au, &req, ct).is_ok());
+
+        let mut bad = req.clone();
+        bad.code_verifier = TEST_VERIFIER.replace("d", "e");
+        assert!(grant.check_exchange(&mut au, &bad, ct).is_err());
+        let mut bad = req.clone();
+        bad.redirect_uri = "https://app.example.com/other".to_string();
+        assert!(grant.check_exchange(&mut au, &bad, ct).is_err());
+        let mut bad = req.clone();
+        bad.client_id = "otherapp".to_string();
+        assert!(grant.check_exchange(&mut au, &bad, ct).is_err());
+        assert!(grant
+            .check_exchange(&mut au, &req, ct + Duration::from_secs(60))
+            .is_err());
+
+        let keys = TokenKeys::generate(Duration::from_secs(3600)).expect("keygen failed");
+        let tr = grant
+            .issue(&keys, "https://localhost", ct)
+            .expect("issue failed");
+        // The tokens end with the session, rather than lasting the hour.
+        assert!(tr.expires_in == 600);
+        assert!(tr.scope == "openid profile");
+
+        let id_token: OidcToken = keys
+            .verify(Some("JWT"), tr.id_token.expect("no id token").as_str(), ct)
+            .expect("id token verify failed");
+        assert!(id_token.aud == "testapp");
+        assert!(id_token.sub == grant.uat.uuid);
+        assert!(id_token.nonce == Some("a nonce".to_string()));
+        assert!(id_token.preferred_username == Some("testperson".to_string()));
+        assert!(id_token.groups.is_none());
+
+        // The access token is neither an id token nor a UserAuthToken.
+        assert!(keys
+            .verify::<OidcToken>(Some("JWT"), tr.access_token.as_str(), ct)
+            .is_none());
+        assert!(keys.verify_uat(tr.access_token.as_str(), ct).is_none());
+
+        let at = Oauth2AccessToken::verify(&keys, tr.access_token.as_str(), ct)
+            .expect("access token verify failed");
+        assert!(at.sessionid() == grant.sessionid());
+        let ui = at.userinfo();
+        assert!(ui.sub == grant.uat.uuid);
+        assert!(ui.name == Some("Test Person".to_string()));
+        assert!(ui.groups.is_none());
+        assert!(Oauth2AccessToken::verify(
+            &keys,
+            tr.access_token.as_str(),
+            ct + Duration::from_secs(600)
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_idm_oauth2_access_token_scoped() {
+        let ct = Duration::from_secs(TEST_CURRENT_TIME);
+        let client = test_client();
+        let grant = test_grant(&client, "openid").expect("grant failed");
+        let keys = TokenKeys::generate(Duration::from_secs(3600)).expect("keygen failed");
+        let tr = grant
+            .issue(&keys, "https://localhost", ct)
+            .expect("issue failed");
+
+        // Without profile or groups, the access token has none of their
+        // claims, nor does the id token, nor does userinfo give them.
+        let claims: serde_json::Value = keys
+            .verify(Some("at+jwt"), tr.access_token.as_str(), ct)
+            .expect("access token verify failed");
+        assert!(claims.get("sub").is_some());
+        assert!(claims.get("name").is_none());
+        assert!(claims.get("preferred_username").is_none());
+        assert!(claims.get("groups").is_none());
+
+        let id_token: OidcToken = keys
+            .verify(Some("JWT"), tr.id_token.expect("no id token").as_str(), ct)
+            .expect("id token verify failed");
+        assert!(id_token.name.is_none());
+        assert!(id_token.preferred_username.is_none());
+        assert!(id_token.groups.is_none());
+
+        let ui = Oauth2AccessToken::verify(&keys, tr.access_token.as_str(), ct)
+            .expect("access token verify failed")
+            .userinfo();
+        assert!(ui.sub == grant.uat.uuid);
+        assert!(ui.name.is_none());
+        assert!(ui.preferred_username.is_none());
+        assert!(ui.groups.is_none());
+    }
+}
@@ -0,0 +1,97 @@
+use crate::audit::AuditScope;
+use crate::entry::{Entry, EntryCommitted, EntryReduced};
+use crate::idm::group::Group;
+use crate::server::QueryServerTransaction;
+use kanidm_proto::v1::OperationError;
+use kanidm_proto::v1::{RadiusAuthToken, REDACTED};
+use std::fmt;
+
+use uuid::Uuid;
+
+// The view of an account a radius server is given. This is built from the
+// entry as reduced by access controls, so holds only what the reader may see.
+#[derive(Clone)]
+pub(crate) struct RadiusAccount {
+    pub name: String,
+    pub displayname: String,
+    pub uuid: Uuid,
+    pub groups: Vec<Group>,
+    pub radius_secret: String,
+}
+
+impl fmt::Debug for RadiusAccount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RadiusAccount")
+            .field("name", &self.name)
+            .field("displayname", &self.displayname)
+            .field("uuid", &self.uuid)
+            .field("groups", &self.groups)
+            .field("radius_secret", &REDACTED)
+            .finish()
+    }
+}
+
+impl RadiusAccount {
+    pub(crate) fn try_from_entry_reduced<T: QueryServerTransaction>(
+        au: &mut AuditScope,
+        value: Entry<EntryReduced, EntryCommitted>,
+        qs: &T,
+    ) -> Result<Self, OperationError> {
+        let radius_secret = value
+            .get_ava_single("radius_secret")
+            .and_then(|v| v.get_radius_secret())
+            .map(|s| s.to_string())
+            .ok_or(OperationError::InvalidAccountState(
+                "Missing attribute: radius_secret",
+            ))?;
+
+        let name = value
+            .get_ava_single("name")
+            .and_then(|v| v.as_string())
+            .cloned()
+            .ok_or(OperationError::InvalidAccountState(
+                "Missing attribute: name",
+            ))?;
+
+        let displayname = value
+            .get_ava_single("displayname")
+            .and_then(|v| v.as_string())
+            .cloned()
+            .ok_or(OperationError::InvalidAccountState(
+                "Missing attribute: displayname",
+            ))?;
+
+        let uuid = value
+            .get_ava_single("uuid")
+            .and_then(|v| v.to_uuid())
+            .cloned()
+            .ok_or(OperationError::InvalidAccountState(
+                "Missing attribute: uuid",
+            ))?;
+
+        // Memberof may not be readable, in which case there are no groups.
+        let memberof: Vec<&Uuid> = value
+            .get_ava("memberof")
+            .map(|vs| vs.into_iter().filter_map(|v| v.to_ref_uuid()).collect())
+            .unwrap_or_default();
+        let groups = try_audit!(au, Group::try_from_uuids(au, memberof, qs));
+
+        Ok(RadiusAccount {
+            name: name,
+            displayname: displayname,
+            uuid: uuid,
+            groups: groups,
+            radius_secret: radius_secret,
+        })
+    }
+
+    pub(crate) fn to_radiusauthtoken(&self) -> RadiusAuthToken {
+        RadiusAuthToken {
+            name: self.name.clone(),
+            displayname: self.displayname.clone(),
+            uuid: self.uuid.to_hyphenated_ref().to_string(),
+            secret: self.radius_secret.clone(),
+            groups: self.groups.iter().map(|g| g.into_proto()).collect(),
+        }
+    }
+}
@@ -0,0 +1,80 @@
+// Some operations are sensitive enough that holding a valid session isn't
+// sufficient - the credentials must have been given recently as well, so
+// that a session left open, or a stolen token, can't be used to take over
+// the account or the server.
+use crate::audit::AuditScope;
+use crate::constants::REAUTH_WINDOW;
+use kanidm_proto::v1::OperationError;
+
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProtectedOperation {
+    // Setting a password, totp, webauthn token, backup codes or policy.
+    CredentialChange,
+    // Creating, changing or removing access control profiles.
+    AccessControl,
+    // Changing the system or domain configuration.
+    SystemConfig,
+}
+
+// How recently the session must have authenticated for each protected
+// operation. None means that operation only needs a valid session.
+#[derive(Debug, Clone)]
+pub struct ReauthPolicy {
+    pub credential_change: Option<Duration>,
+    pub access_control: Option<Duration>,
+    pub system_config: Option<Duration>,
+}
+
+impl ReauthPolicy {
+    pub fn new() -> Self {
+        let within = Some(Duration::from_secs(REAUTH_WINDOW));
+        ReauthPolicy {
+            credential_change: within,
+            access_control: within,
+            system_config: within,
+        }
+    }
+
+    // The same window for every protected operation.
+    pub fn new_within(within: Option<Duration>) -> Self {
+        ReauthPolicy {
+            credential_change: within,
+            access_control: within,
+            system_config: within,
+        }
+    }
+
+    pub fn requires_reauth_within(&self, op: ProtectedOperation) -> Option<Duration> {
+        match op {
+            ProtectedOperation::CredentialChange => self.credential_change,
+            ProtectedOperation::AccessControl => self.access_control,
+            ProtectedOperation::SystemConfig => self.system_config,
+        }
+    }
+
+    // Check that a session which last gave its credentials at auth_time may
+    // perform op at the current time ct.
+    pub fn check(
+        &self,
+        au: &mut AuditScope,
+        op: ProtectedOperation,
+        auth_time: u64,
+        ct: Duration,
+    ) -> Result<(), OperationError> {
+        match self.requires_reauth_within(op) {
+            Some(within) if ct.as_secs() >= auth_time.saturating_add(within.as_secs()) => {
+                audit_log!(
+                    au,
+                    "{:?} requires auth within {}s, but the session authenticated at {}",
+                    op,
+                    within.as_secs(),
+                    auth_time
+                );
+                Err(OperationError::ReauthRequired)
+            }
+            _ => Ok(()),
+        }
+    }
+}
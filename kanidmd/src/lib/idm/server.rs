@@ -1,21 +1,49 @@
 use crate::audit::AuditScope;
-use crate::constants::AUTH_SESSION_TIMEOUT;
-use crate::event::{AuthEvent, AuthEventStep, AuthResult};
-use crate::idm::account::Account;
+use crate::be::dbaudit::{DbAuditChangeV1, DbAuditOperationV1};
+use crate::constants::{
+    _UUID_IDM_ADMINS, _UUID_IDM_UNIX_AUTH_SERVERS, AUTH_LOCKOUT_THRESHOLD, AUTH_LOCKOUT_WINDOW,
+    AUTH_SESSION_TIMEOUT, AUTH_TOKEN_LIFETIME, PW_MIN_LENGTH, PW_MIN_SCORE, UUID_ANONYMOUS,
+    UUID_DOMAIN_INFO, UUID_SYSTEM_CONFIG,
+};
+use crate::credential::apitoken::ApiToken;
+use crate::credential::strength;
+use crate::credential::totp::{TOTP, TOTP_DEFAULT_STEP};
+use crate::credential::webauthn::{Challenge, WebauthnConfig, WebauthnToken};
+use crate::credential::{generate_recovery_password, Policy};
+use crate::event::{AuthEvent, AuthEventStep, AuthResult, Event};
+use crate::idm::account::{Account, LockoutPolicy};
 use crate::idm::authsession::AuthSession;
+use crate::idm::claim::ClaimPolicy;
 use crate::idm::event::PasswordChangeEvent;
+use crate::idm::oauth2::{generate_code, redirect_location, Oauth2Client, Oauth2CodeGrant};
+use crate::idm::radius::RadiusAccount;
+use crate::idm::reauth::ProtectedOperation;
+use crate::idm::tokenkeys::TokenKeys;
+use crate::idm::unix::{verify_dummy_unix_credential, UnixGroup, UnixUserAccount};
+use crate::modify::{Modify, ModifyInvalid, ModifyList};
 use crate::server::{QueryServer, QueryServerTransaction, QueryServerWriteTransaction};
 use crate::utils::{uuid_from_duration, SID};
-use crate::value::PartialValue;
+use crate::value::{PartialValue, Value};
 
-use kanidm_proto::v1::AuthState;
-use kanidm_proto::v1::OperationError;
+use kanidm_proto::v1::{
+    ApiTokenInfo, AuthDenyReason, AuthState, CredentialStatusResponse, Oauth2AuthorizeRequest,
+    Oauth2TokenRequest, PasswordFeedback, RadiusAuthToken, SessionInfo, TOTPSecret, UnixGroupToken,
+    UnixUserToken, UserAuthToken, WebauthnCreationChallenge, WebauthnRegisterCredential,
+    WebauthnTokenInfo,
+};
+use kanidm_proto::v1::{OperationError, AUDIT_REDACTED};
 
 use concread::cowcell::{CowCell, CowCellWriteTxn};
 use std::collections::BTreeMap;
+use std::net::SocketAddr;
 use std::time::Duration;
 use uuid::Uuid;
 
+const DENY_LOCKED: &'static str = "too many failed authentications, try again later";
+const DENY_ANONYMOUS_DISABLED: &'static str = "anonymous disabled";
+const DENY_NOT_YET_VALID: &'static str = "account is not yet valid";
+const DENY_EXPIRED: &'static str = "account has expired";
+
 pub struct IdmServer {
     // There is a good reason to keep this single thread - it
     // means that limits to sessions can be easily applied and checked to
@@ -25,10 +53,33 @@ pub struct IdmServer {
     // TODO #60: This needs a mark-and-sweep gc to be added.
     // use split_off()
     sessions: CowCell<BTreeMap<Uuid, AuthSession>>,
+    // The sessions that have completed auth, and that tokens may be used
+    // with. These are only held in memory, so a restart ends them all.
+    active_sessions: CowCell<BTreeMap<Uuid, ActiveSession>>,
+    // Totp secrets that have been generated, but not yet confirmed with a
+    // code, by the session that requested them.
+    totp_pending: CowCell<BTreeMap<Uuid, TOTP>>,
+    // The last totp step accepted for each credential, so that a code
+    // can't be used twice.
+    totp_last_step: CowCell<BTreeMap<Uuid, u64>>,
+    // Webauthn registration challenges, by the session that requested them.
+    webauthn_pending: CowCell<BTreeMap<Uuid, Challenge>>,
+    // Consecutive failed authentications from each source address, and when
+    // the lock they caused ends. The failures of each account are kept on
+    // its entry, but sources come and go, so these are only held in memory.
+    source_failures: CowCell<BTreeMap<String, (u32, Option<u64>)>>,
+    // The oauth2 authorization codes that have been issued and not yet
+    // exchanged. A code is only good for a minute, so these are only held
+    // in memory.
+    oauth2_codes: CowCell<BTreeMap<String, Oauth2CodeGrant>>,
+    webauthn: WebauthnConfig,
     // Need a reference to the query server.
     qs: QueryServer,
     // thread/server id
     sid: SID,
+    // How long the tokens issued on a successful auth are valid for.
+    session_lifetime: Duration,
+    lockout: LockoutPolicy,
 }
 
 pub struct IdmServerWriteTransaction<'a> {
@@ -36,8 +87,17 @@ pub struct IdmServerWriteTransaction<'a> {
     // the idm in memory structures (maybe the query server too). This is
     // things like authentication
     sessions: CowCellWriteTxn<'a, BTreeMap<Uuid, AuthSession>>,
+    active_sessions: CowCellWriteTxn<'a, BTreeMap<Uuid, ActiveSession>>,
+    totp_pending: CowCellWriteTxn<'a, BTreeMap<Uuid, TOTP>>,
+    totp_last_step: CowCellWriteTxn<'a, BTreeMap<Uuid, u64>>,
+    webauthn_pending: CowCellWriteTxn<'a, BTreeMap<Uuid, Challenge>>,
+    source_failures: CowCellWriteTxn<'a, BTreeMap<String, (u32, Option<u64>)>>,
+    oauth2_codes: CowCellWriteTxn<'a, BTreeMap<String, Oauth2CodeGrant>>,
+    webauthn: &'a WebauthnConfig,
     qs: &'a QueryServer,
     sid: &'a SID,
+    session_lifetime: &'a Duration,
+    lockout: &'a LockoutPolicy,
 }
 
 /*
@@ -48,6 +108,58 @@ pub struct IdmServerReadTransaction<'a> {
 }
 */
 
+#[derive(Debug, Clone)]
+struct ActiveSession {
+    account: String,
+    source: Option<String>,
+    issued_at: u64,
+    expiry: u64,
+    // When the credentials were last given, which a reauth moves on.
+    auth_time: u64,
+    // The api token a service session was begun with.
+    api_token: Option<Uuid>,
+}
+
+impl ActiveSession {
+    fn to_proto(&self, sessionid: &Uuid) -> SessionInfo {
+        SessionInfo {
+            sessionid: sessionid.clone(),
+            account: self.account.clone(),
+            source: self.source.clone(),
+            issued_at: self.issued_at,
+            expiry: self.expiry,
+        }
+    }
+}
+
+// An account may always manage its own sessions, but only idm_admins may
+// manage the sessions of others.
+fn may_manage_sessions(uat: &UserAuthToken, account: &str) -> bool {
+    uat.uuid == account || uat.groups.iter().any(|g| g.uuid == _UUID_IDM_ADMINS)
+}
+
+// Failures are counted by address, as the port changes with each connection.
+fn source_address(source: &Option<String>) -> Option<String> {
+    source.as_ref().map(|s| {
+        s.parse::<SocketAddr>()
+            .map(|a| a.ip().to_string())
+            .unwrap_or_else(|_| s.clone())
+    })
+}
+
+fn locked_state(until: u64) -> AuthState {
+    AuthState::Denied(AuthDenyReason::Locked(until), DENY_LOCKED.to_string())
+}
+
+// The state for an account outside of its valid from and expiry times.
+fn validity_state(reason: AuthDenyReason) -> AuthState {
+    let msg = match reason {
+        AuthDenyReason::NotYetValid(_) => DENY_NOT_YET_VALID,
+        _ => DENY_EXPIRED,
+    };
+    AuthState::Denied(reason, msg.to_string())
+}
+
 pub struct IdmServerProxyWriteTransaction<'a> {
     // This does NOT take any read to the memory content, allowing safe
     // qs operations to occur through this interface.
@@ -59,16 +171,52 @@ impl IdmServer {
     pub fn new(qs: QueryServer, sid: SID) -> IdmServer {
         IdmServer {
             sessions: CowCell::new(BTreeMap::new()),
+            active_sessions: CowCell::new(BTreeMap::new()),
+            totp_pending: CowCell::new(BTreeMap::new()),
+            totp_last_step: CowCell::new(BTreeMap::new()),
+            webauthn_pending: CowCell::new(BTreeMap::new()),
+            source_failures: CowCell::new(BTreeMap::new()),
+            oauth2_codes: CowCell::new(BTreeMap::new()),
+            webauthn: WebauthnConfig::new("localhost", "https://localhost"),
             qs: qs,
             sid: sid,
+            session_lifetime: Duration::from_secs(AUTH_TOKEN_LIFETIME),
+            lockout: LockoutPolicy {
+                threshold: AUTH_LOCKOUT_THRESHOLD,
+                window: AUTH_LOCKOUT_WINDOW,
+            },
         }
     }
 
+    pub fn set_session_lifetime(&mut self, lifetime: u64) {
+        self.session_lifetime = Duration::from_secs(lifetime);
+    }
+
+    pub fn set_lockout_policy(&mut self, threshold: u32, window: u64) {
+        self.lockout = LockoutPolicy {
+            threshold: threshold,
+            window: window,
+        };
+    }
+
+    pub fn set_webauthn_config(&mut self, webauthn: WebauthnConfig) {
+        self.webauthn = webauthn;
+    }
+
     pub fn write(&self) -> IdmServerWriteTransaction {
         IdmServerWriteTransaction {
             sessions: self.sessions.write(),
+            active_sessions: self.active_sessions.write(),
+            totp_pending: self.totp_pending.write(),
+            totp_last_step: self.totp_last_step.write(),
+            webauthn_pending: self.webauthn_pending.write(),
+            source_failures: self.source_failures.write(),
+            oauth2_codes: self.oauth2_codes.write(),
+            webauthn: &self.webauthn,
             qs: &self.qs,
             sid: &self.sid,
+            session_lifetime: &self.session_lifetime,
+            lockout: &self.lockout,
         }
     }
 
@@ -83,6 +231,284 @@ impl IdmServer {
             qs_write: self.qs.write(),
         }
     }
+
+    // Check that the session of uat authenticated recently enough for op
+    // at ct, else it must reauth first.
+    pub fn check_reauth(
+        &self,
+        au: &mut AuditScope,
+        uat: &UserAuthToken,
+        op: ProtectedOperation,
+        ct: Duration,
+    ) -> Result<(), OperationError> {
+        // Read only sessions can't change anything, however recent.
+        if uat.read_only {
+            audit_log!(au, "read only session {} may not {:?}", uat.sessionid, op);
+            return Err(OperationError::AccessDenied);
+        }
+        self.qs.get_reauth_policy().check(au, op, uat.auth_time, ct)
+    }
+
+    // Is this session still valid at ct, or has it been ended or expired?
+    pub fn is_session_active(&self, sessionid: &Uuid, ct: Duration) -> bool {
+        match self.active_sessions.read().get(sessionid) {
+            Some(s) => ct.as_secs() < s.expiry,
+            None => false,
+        }
+    }
+
+    // Authenticate an api token given on its own as a bearer token, for
+    // clients that can't make the auth exchange, such as scim provisioners.
+    // No session is begun, so the token is checked on every request and the
+    // uat is only good for that request. The token's id stands in for the
+    // session id.
+    pub fn auth_api_token(
+        &self,
+        au: &mut AuditScope,
+        token: &str,
+        ct: Duration,
+    ) -> Result<UserAuthToken, OperationError> {
+        let (id, secret) = ApiToken::parse(token).ok_or(OperationError::NotAuthenticated)?;
+        let qs_read = self.qs.read();
+        let f = filter!(f_eq("api_token", PartialValue::new_apitoken_id(id)));
+        let entry = match qs_read.internal_search(au, f) {
+            Ok(mut entries) if entries.len() == 1 => entries.remove(0),
+            _ => {
+                audit_log!(au, "no account holds api token {}", id);
+                return Err(OperationError::NotAuthenticated);
+            }
+        };
+        let account = try_audit!(au, Account::try_from_entry(au, entry, &qs_read));
+        let at = match account.api_tokens.iter().find(|at| at.id == id) {
+            Some(at) if at.verify(secret) && !at.is_expired(ct) => at,
+            _ => {
+                audit_log!(au, "api token {} is incorrect or expired", id);
+                return Err(OperationError::NotAuthenticated);
+            }
+        };
+        if account.validity_denied(ct).is_some() || account.is_locked(ct).is_some() {
+            audit_log!(au, "{} may not authenticate at this time", account.name);
+            return Err(OperationError::NotAuthenticated);
+        }
+
+        let mut uat = account
+            .to_userauthtoken(&id, Vec::new(), ct, self.session_lifetime)
+            .ok_or(OperationError::InvalidState)?;
+        uat.api_token = Some(id);
+        uat.read_only = !at.read_write;
+        if let Some(e) = at.expiry {
+            uat.expiry = uat.expiry.min(e);
+        }
+        Ok(uat)
+    }
+
+    pub fn list_sessions(
+        &self,
+        au: &mut AuditScope,
+        uat: &UserAuthToken,
+        account: &Uuid,
+        ct: Duration,
+    ) -> Result<Vec<SessionInfo>, OperationError> {
+        let account = account.to_hyphenated_ref().to_string();
+        if !may_manage_sessions(uat, account.as_str()) {
+            audit_log!(au, "{} may not list the sessions of {}", uat.uuid, account);
+            return Err(OperationError::AccessDenied);
+        }
+
+        Ok(self
+            .active_sessions
+            .read()
+            .iter()
+            .filter(|(_, s)| s.account == account && ct.as_secs() < s.expiry)
+            .map(|(sessionid, s)| s.to_proto(sessionid))
+            .collect())
+    }
+
+    pub fn list_account_webauthn(
+        &self,
+        au: &mut AuditScope,
+        uat: &UserAuthToken,
+    ) -> Result<Vec<WebauthnTokenInfo>, OperationError> {
+        let target = Uuid::parse_str(uat.uuid.as_str()).map_err(|_| OperationError::InvalidUuid)?;
+        let qs_read = self.qs.read();
+        let account_entry = try_audit!(au, qs_read.internal_search_uuid(au, &target));
+        let account = try_audit!(au, Account::try_from_entry(au, account_entry, &qs_read));
+
+        Ok(account
+            .primary
+            .map(|c| c.webauthn.iter().map(|t| t.to_proto()).collect())
+            .unwrap_or_else(Vec::new))
+    }
+
+    // What a radius server needs to authenticate the target, by name or
+    // uuid, as the holder of uat. Access controls decide if they may read
+    // the secret - an account may read its own, and radius servers may read
+    // any.
+    pub fn get_radius_auth_token(
+        &self,
+        au: &mut AuditScope,
+        uat: &UserAuthToken,
+        target: &str,
+    ) -> Result<RadiusAuthToken, OperationError> {
+        let qs_read = self.qs.read();
+        let target = match Uuid::parse_str(target) {
+            Ok(u) => u,
+            Err(_) => try_audit!(au, qs_read.name_to_uuid(au, target)),
+        };
+        let event = try_audit!(au, Event::from_ro_uat(au, &qs_read, Some(uat.clone())));
+        // Searching for the secret means that if it can't be read, the
+        // entry isn't found, so this doesn't reveal if one is set.
+        let mut entries = try_audit!(
+            au,
+            qs_read.impersonate_search_ext(
+                au,
+                filter!(f_eq("uuid", PartialValue::new_uuidr(&target))),
+                filter_all!(f_and!([
+                    f_eq("uuid", PartialValue::new_uuidr(&target)),
+                    f_pres("radius_secret")
+                ])),
+                &event,
+            )
+        );
+        let entry = match entries.pop() {
+            Some(e) => e,
+            None => {
+                audit_log!(
+                    au,
+                    "no radius secret of {} readable by {}",
+                    target,
+                    uat.uuid
+                );
+                return Err(OperationError::NoMatchingEntries);
+            }
+        };
+        let account = try_audit!(
+            au,
+            RadiusAccount::try_from_entry_reduced(au, entry, &qs_read)
+        );
+        Ok(account.to_radiusauthtoken())
+    }
+
+    // What a unix machine needs to resolve the posix account with this name
+    // or uuid for nss, as the holder of uat. Anyone may read these.
+    pub fn get_unix_user_token(
+        &self,
+        au: &mut AuditScope,
+        uat: &UserAuthToken,
+        target: &str,
+    ) -> Result<UnixUserToken, OperationError> {
+        let qs_read = self.qs.read();
+        let target = match Uuid::parse_str(target) {
+            Ok(u) => u,
+            Err(_) => try_audit!(au, qs_read.name_to_uuid(au, target)),
+        };
+        let event = try_audit!(au, Event::from_ro_uat(au, &qs_read, Some(uat.clone())));
+        let f = filter!(f_and!([
+            f_eq("class", PartialValue::new_class("posixaccount")),
+            f_eq("uuid", PartialValue::new_uuidr(&target))
+        ]));
+        let mut entries = try_audit!(au, qs_read.impersonate_search_ext(au, f.clone(), f, &event));
+        let entry = match entries.pop() {
+            Some(e) => e,
+            None => {
+                audit_log!(au, "no posix account {} readable by {}", target, uat.uuid);
+                return Err(OperationError::NoMatchingEntries);
+            }
+        };
+        let account = try_audit!(au, UnixUserAccount::try_from_entry(au, &entry, &qs_read));
+        Ok(account.to_unixusertoken())
+    }
+
+    // As get_unix_user_token, for the posix group with this name or uuid.
+    pub fn get_unix_group_token(
+        &self,
+        au: &mut AuditScope,
+        uat: &UserAuthToken,
+        target: &str,
+    ) -> Result<UnixGroupToken, OperationError> {
+        let qs_read = self.qs.read();
+        let target = match Uuid::parse_str(target) {
+            Ok(u) => u,
+            Err(_) => try_audit!(au, qs_read.name_to_uuid(au, target)),
+        };
+        let event = try_audit!(au, Event::from_ro_uat(au, &qs_read, Some(uat.clone())));
+        let f = filter!(f_and!([
+            f_eq("class", PartialValue::new_class("posixgroup")),
+            f_eq("uuid", PartialValue::new_uuidr(&target))
+        ]));
+        let mut entries = try_audit!(au, qs_read.impersonate_search_ext(au, f.clone(), f, &event));
+        let entry = match entries.pop() {
+            Some(e) => e,
+            None => {
+                audit_log!(au, "no posix group {} readable by {}", target, uat.uuid);
+                return Err(OperationError::NoMatchingEntries);
+            }
+        };
+        let group = try_audit!(au, UnixGroup::try_from_entry(&entry));
+        Ok(group.to_unixgrouptoken())
+    }
+
+    // The public keys of the account with this name, in the form sshd reads
+    // from an authorized keys file. Only keys the reader may see are given,
+    // so anonymous is enough to ask.
+    pub fn get_ssh_publickeys(
+        &self,
+        au: &mut AuditScope,
+        uat: &UserAuthToken,
+        target: &str,
+    ) -> Result<Vec<String>, OperationError> {
+        let qs_read = self.qs.read();
+        let event = try_audit!(au, Event::from_ro_uat(au, &qs_read, Some(uat.clone())));
+        let f = filter!(f_and!([
+            f_eq("class", PartialValue::new_class("account")),
+            f_eq("name", PartialValue::new_iutf8s(target))
+        ]));
+        let mut entries = try_audit!(au, qs_read.impersonate_search_ext(au, f.clone(), f, &event));
+        let entry = match entries.pop() {
+            Some(e) => e,
+            None => {
+                audit_log!(au, "no account {} readable by {}", target, uat.uuid);
+                return Err(OperationError::NoMatchingEntries);
+            }
+        };
+        Ok(entry
+            .get_ava("ssh_publickey")
+            .map(|vs| {
+                vs.into_iter()
+                    .filter_map(|v| v.get_sshkey())
+                    .map(|k| k.to_string())
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    pub fn credential_status(
+        &self,
+        au: &mut AuditScope,
+        uat: &UserAuthToken,
+    ) -> Result<CredentialStatusResponse, OperationError> {
+        let target = Uuid::parse_str(uat.uuid.as_str()).map_err(|_| OperationError::InvalidUuid)?;
+        let qs_read = self.qs.read();
+        let account_entry = try_audit!(au, qs_read.internal_search_uuid(au, &target));
+        let account = try_audit!(au, Account::try_from_entry(au, account_entry, &qs_read));
+
+        Ok(match account.primary {
+            Some(c) => CredentialStatusResponse {
+                policy: Some(c.policy().to_proto()),
+                password: c.password.is_some(),
+                totp: c.totp.is_some(),
+                webauthn: c.webauthn.iter().map(|t| t.name.clone()).collect(),
+                backup_codes_remaining: c.backup_codes.len() as u32,
+            },
+            None => CredentialStatusResponse {
+                policy: None,
+                password: false,
+                totp: false,
+                webauthn: Vec::new(),
+                backup_codes_remaining: 0,
+            },
+        })
+    }
 }
 
 impl<'a> IdmServerWriteTransaction<'a> {
@@ -99,6 +525,17 @@ impl<'a> IdmServerWriteTransaction<'a> {
         // swap them?
         *self.sessions = valid;
         // expired will now be dropped, and can't be used by future sessions.
+
+        // Active sessions each have their own lifetime, so are checked one
+        // by one.
+        self.active_sessions.retain(|_, s| ct.as_secs() < s.expiry);
+        // A pending totp can only be confirmed by the session that asked for it.
+        let active_sessions = &self.active_sessions;
+        self.totp_pending
+            .retain(|sessionid, _| active_sessions.contains_key(sessionid));
+        self.webauthn_pending
+            .retain(|sessionid, _| active_sessions.contains_key(sessionid));
+        self.oauth2_codes.retain(|_, grant| !grant.is_expired(ct));
     }
 
     pub fn auth(
@@ -113,10 +550,6 @@ impl<'a> IdmServerWriteTransaction<'a> {
 
         match &ae.step {
             AuthEventStep::Init(init) => {
-                // Allocate a session id.
-                // TODO: #60 - make this new_v1 and use the tstamp.
-                let sessionid = uuid_from_duration(ct, self.sid);
-
                 // Begin the auth procedure!
                 // Start a read
                 //
@@ -127,8 +560,8 @@ impl<'a> IdmServerWriteTransaction<'a> {
                 // We do need a txn so that we can process/search and claims
                 // or related based on the quality of the provided auth steps
                 //
-                // We *DO NOT* need a write though, because I think that lock outs
-                // and rate limits are *per server* and *in memory* only.
+                // We *DO NOT* need a write though, as lock outs are only
+                // written when credentials fail.
                 let qs_read = self.qs.read();
                 // Check anything needed? Get the current auth-session-id from request
                 // because it associates to the nonce's etc which were all cached.
@@ -161,30 +594,117 @@ impl<'a> IdmServerWriteTransaction<'a> {
                 // typing and functionality so we can assess what auth types can
                 // continue, and helps to keep non-needed entry specific data
                 // out of the LRU.
-                let account = Account::try_from_entry(entry)?;
-                let auth_session = AuthSession::new(account, init.appid.clone());
-
-                // Get the set of mechanisms that can proceed. This is tied
-                // to the session so that it can mutate state and have progression
-                // of what's next, or ordering.
-                let next_mech = auth_session.valid_auth_mechs();
-
-                // If we have a session of the same id, return an error (despite how
-                // unlikely this is ...
-                if self.sessions.contains_key(&sessionid) {
-                    return Err(OperationError::InvalidSessionState);
+                let account = Account::try_from_entry(au, entry, &qs_read)?;
+                // The session checks the system config with a read of its own.
+                drop(qs_read);
+                self.begin_auth_session(au, account, init.appid.clone(), &ae.source, None, ct)
+            }
+            AuthEventStep::Reauth(reauth) => {
+                // Only a session that is still active can be reauthenticated,
+                // and then only by the account it belongs to.
+                let active = reauth.uat.sessionid;
+                match self.active_sessions.get(&active) {
+                    Some(s) if s.account == reauth.uat.uuid && ct.as_secs() < s.expiry => {}
+                    _ => {
+                        audit_log!(au, "session {} is not active to reauth", active);
+                        return Err(OperationError::NotAuthenticated);
+                    }
                 }
-                self.sessions.insert(sessionid, auth_session);
 
-                // Debugging: ensure we really inserted ...
-                assert!(self.sessions.get(&sessionid).is_some());
+                let qs_read = self.qs.read();
+                let target = try_audit!(
+                    au,
+                    Uuid::parse_str(reauth.uat.uuid.as_str())
+                        .map_err(|_| OperationError::InvalidUuid)
+                );
+                let entry = try_audit!(au, qs_read.internal_search_uuid(au, &target));
+                let account = try_audit!(au, Account::try_from_entry(au, entry, &qs_read));
+                drop(qs_read);
+
+                audit_log!(au, "Initiating reauthentication of session {}", active);
+                self.begin_auth_session(
+                    au,
+                    account,
+                    reauth.appid.clone(),
+                    &ae.source,
+                    Some(active),
+                    ct,
+                )
+            }
+            AuthEventStep::RequestClaims(req) => {
+                let account_uuid = try_audit!(
+                    au,
+                    self.sessions
+                        .get(&req.sessionid)
+                        .map(|s| s.account_uuid().clone())
+                        .ok_or(OperationError::InvalidSessionState)
+                );
+                // Only the claims of the account's groups may be requested.
+                let claims = {
+                    let qs_read = self.qs.read();
+                    let entry = try_audit!(au, qs_read.internal_search_uuid(au, &account_uuid));
+                    let account = try_audit!(au, Account::try_from_entry(au, entry, &qs_read));
+                    let policies = try_audit!(
+                        au,
+                        ClaimPolicy::try_from_names(au, req.claims.as_slice(), &qs_read)
+                    );
+                    policies
+                        .into_iter()
+                        .filter(|p| {
+                            let allowed = p.may_request(account.groups.as_slice());
+                            if !allowed {
+                                audit_log!(au, "claim {} may not be requested", p.name());
+                            }
+                            allowed
+                        })
+                        .collect()
+                };
 
+                let auth_session = try_audit!(
+                    au,
+                    (*self.sessions)
+                        .get_mut(&req.sessionid)
+                        .ok_or(OperationError::InvalidSessionState)
+                );
+                let state = try_audit!(au, auth_session.request_claims(claims));
                 Ok(AuthResult {
-                    sessionid: sessionid,
-                    state: AuthState::Continue(next_mech),
+                    sessionid: req.sessionid,
+                    state: state,
                 })
             }
             AuthEventStep::Creds(creds) => {
+                let account_uuid = try_audit!(
+                    au,
+                    self.sessions
+                        .get(&creds.sessionid)
+                        .map(|s| s.account_uuid().clone())
+                        .ok_or(OperationError::InvalidSessionState)
+                );
+                // Other sessions may have locked the account since this one
+                // began, so the lock is checked again before every step.
+                let account = {
+                    let qs_read = self.qs.read();
+                    let entry = try_audit!(au, qs_read.internal_search_uuid(au, &account_uuid));
+                    try_audit!(au, Account::try_from_entry(au, entry, &qs_read))
+                };
+                if let Some(until) = self.locked_until(&account, &ae.source, ct) {
+                    audit_log!(au, "Authentication denied as locked until {}", until);
+                    self.sessions.remove(&creds.sessionid);
+                    return Ok(AuthResult {
+                        sessionid: creds.sessionid,
+                        state: locked_state(until),
+                    });
+                }
+                // Nor may the session outlast the account.
+                if let Some(reason) = account.validity_denied(ct) {
+                    audit_log!(au, "Authentication denied -> {:?}", reason);
+                    self.sessions.remove(&creds.sessionid);
+                    return Ok(AuthResult {
+                        sessionid: creds.sessionid,
+                        state: validity_state(reason),
+                    });
+                }
+
                 // Do we have a session?
                 let auth_session = try_audit!(
                     au,
@@ -196,377 +716,3788 @@ impl<'a> IdmServerWriteTransaction<'a> {
                 // Process the credentials here as required.
                 // Basically throw them at the auth_session and see what
                 // falls out.
-                auth_session.validate_creds(au, &creds.creds).map(|aus| {
-                    AuthResult {
-                        // Is this right?
-                        sessionid: creds.sessionid,
-                        state: aus,
+                let aus = auth_session.validate_creds(
+                    au,
+                    &creds.sessionid,
+                    &creds.creds,
+                    ct,
+                    *self.session_lifetime,
+                    &mut *self.totp_last_step,
+                )?;
+                let backup_code = auth_session.backup_code_used().map(|c| c.to_string());
+                let webauthn = auth_session.webauthn_used().cloned();
+                let api_token = auth_session.api_token_used().cloned();
+                let reauth = auth_session.reauth().cloned();
+
+                // A backup code can only be used once. Sessions hold a copy of
+                // the codes from when they began, so the code is removed from
+                // the account here, and if another session has already spent
+                // it this one is denied.
+                let aus = match (aus, backup_code) {
+                    (AuthState::Success(uat), Some(code)) => {
+                        match self.consume_backup_code(au, &uat.uuid, code.as_str()) {
+                            Ok(()) => AuthState::Success(uat),
+                            Err(e) => {
+                                audit_log!(au, "failed to consume backup code -> {:?}", e);
+                                AuthState::Denied(
+                                    AuthDenyReason::Failed,
+                                    "backup code already used".to_string(),
+                                )
+                            }
+                        }
+                    }
+                    (aus, _) => aus,
+                };
+
+                // Sessions hold a copy of the webauthn counters from when they
+                // began, so the counter is checked again as it's recorded on
+                // the account, and a token that another session has already
+                // seen sign with it is refused as a likely clone.
+                let aus = match (aus, webauthn) {
+                    (AuthState::Success(uat), Some((cred_id, counter))) => {
+                        match self.record_webauthn_counter(au, &uat.uuid, &cred_id, counter) {
+                            Ok(()) => AuthState::Success(uat),
+                            Err(e) => {
+                                audit_log!(au, "failed to record webauthn counter -> {:?}", e);
+                                AuthState::Denied(
+                                    AuthDenyReason::Failed,
+                                    "webauthn counter did not advance, the token may be cloned"
+                                        .to_string(),
+                                )
+                            }
+                        }
+                    }
+                    (aus, _) => aus,
+                };
+
+                // The token may have been destroyed while this session was
+                // authenticating, in which case it's refused.
+                let aus = match (aus, api_token) {
+                    (AuthState::Success(uat), Some(id)) => {
+                        match self.record_api_token_used(au, &uat.uuid, &id, ct) {
+                            Ok(()) => AuthState::Success(uat),
+                            Err(e) => {
+                                audit_log!(au, "failed to record api token use -> {:?}", e);
+                                AuthState::Denied(
+                                    AuthDenyReason::NotPermitted,
+                                    "api token destroyed".to_string(),
+                                )
+                            }
+                        }
+                    }
+                    (aus, _) => aus,
+                };
+
+                // Count the failure towards a lock, which the client is told
+                // of if this failure began it. Success ends the count.
+                let aus = match aus {
+                    AuthState::Denied(AuthDenyReason::Failed, reason) => {
+                        match self.record_auth_failure(au, &account, &ae.source, ct)? {
+                            Some(until) => {
+                                audit_log!(au, "Authentication failures locked until {}", until);
+                                locked_state(until)
+                            }
+                            None => AuthState::Denied(AuthDenyReason::Failed, reason),
+                        }
+                    }
+                    AuthState::Success(uat) => {
+                        self.record_auth_success(au, &account, &ae.source)?;
+                        AuthState::Success(uat)
+                    }
+                    aus => aus,
+                };
+
+                // A successful auth begins the session that the token is
+                // valid for. A reauth instead refreshes the session it was
+                // begun from, which keeps its id and lifetime.
+                let aus = match (aus, reauth) {
+                    (AuthState::Success(mut uat), Some(active)) => {
+                        match self.active_sessions.get_mut().get_mut(&active) {
+                            Some(s) if s.account == uat.uuid => {
+                                s.auth_time = uat.auth_time;
+                                // The account may have been given an expiry
+                                // since the session began.
+                                s.expiry = s.expiry.min(uat.expiry);
+                                uat.sessionid = active;
+                                uat.issued_at = s.issued_at;
+                                uat.expiry = s.expiry;
+                            }
+                            _ => {
+                                audit_log!(au, "session {} ended during reauth", active);
+                                return Err(OperationError::NotAuthenticated);
+                            }
+                        }
+                        audit_log!(au, "reauthenticated session {}", active);
+                        AuthState::Success(uat)
                     }
+                    (AuthState::Success(uat), None) => {
+                        self.active_sessions.insert(
+                            creds.sessionid,
+                            ActiveSession {
+                                account: uat.uuid.clone(),
+                                source: ae.source.clone(),
+                                issued_at: uat.issued_at,
+                                expiry: uat.expiry,
+                                auth_time: uat.auth_time,
+                                api_token: uat.api_token,
+                            },
+                        );
+                        AuthState::Success(uat)
+                    }
+                    (aus, _) => aus,
+                };
+
+                Ok(AuthResult {
+                    // Is this right?
+                    sessionid: creds.sessionid,
+                    state: aus,
                 })
             }
         }
     }
 
-    pub fn commit(self) -> Result<(), OperationError> {
-        self.sessions.commit();
-        Ok(())
-    }
-}
+    // Check the unix password of a posix account for a unix machine, without
+    // beginning a session. Only the api token sessions of service accounts in
+    // idm_unix_auth_servers may ask. Every failure gives None, so that the
+    // caller learns nothing about whether the account exists or is posix.
+    // Wrong passwords count towards the lock of the account as in auth, but
+    // not of the source, as the machine asks on behalf of all its users.
+    pub fn auth_unix(
+        &mut self,
+        au: &mut AuditScope,
+        uat: &UserAuthToken,
+        account: &str,
+        cred: &str,
+        ct: Duration,
+    ) -> Result<Option<UnixUserToken>, OperationError> {
+        if uat.api_token.is_none()
+            || !uat
+                .groups
+                .iter()
+                .any(|g| g.uuid == _UUID_IDM_UNIX_AUTH_SERVERS)
+        {
+            audit_log!(au, "{} may not check unix credentials", uat.uuid);
+            return Err(OperationError::AccessDenied);
+        }
 
-/*
-impl<'a> IdmServerReadTransaction<'a> {
-    pub fn whoami() -> () {}
-}
-*/
+        // Every refusal checks a password all the same, so that the time it
+        // takes doesn't tell the caller whether the account exists or why.
+        let qs_read = self.qs.read();
+        let target = match Uuid::parse_str(account) {
+            Ok(u) => Some(u),
+            Err(_) => qs_read.name_to_uuid(au, account).ok(),
+        };
+        let entry = match target.and_then(|u| qs_read.internal_search_uuid(au, &u).ok()) {
+            Some(e) => e,
+            None => {
+                audit_log!(au, "unix auth of {} denied as it doesn't exist", account);
+                verify_dummy_unix_credential(cred);
+                return Ok(None);
+            }
+        };
+        let unix_account = match UnixUserAccount::try_from_entry(au, &entry, &qs_read) {
+            Ok(u) => u,
+            Err(e) => {
+                audit_log!(au, "unix auth of {} denied -> {:?}", account, e);
+                verify_dummy_unix_credential(cred);
+                return Ok(None);
+            }
+        };
+        let account = try_audit!(au, Account::try_from_entry(au, entry, &qs_read));
+        drop(qs_read);
 
-impl<'a> IdmServerProxyWriteTransaction<'a> {
-    pub fn set_account_password(
+        if let Some(until) = account.is_locked(ct) {
+            audit_log!(au, "unix auth denied as locked until {}", until);
+            verify_dummy_unix_credential(cred);
+            return Ok(None);
+        }
+        if let Some(reason) = account.validity_denied(ct) {
+            audit_log!(au, "unix auth denied -> {:?}", reason);
+            verify_dummy_unix_credential(cred);
+            return Ok(None);
+        }
+
+        if unix_account.verify_unix_credential(cred) {
+            self.record_auth_success(au, &account, &None)?;
+            Ok(Some(unix_account.to_unixusertoken()))
+        } else {
+            audit_log!(au, "unix auth of {} failed", account.uuid);
+            self.record_auth_failure(au, &account, &None, ct)?;
+            Ok(None)
+        }
+    }
+
+    // Begin an auth session for the account, which is a reauth of the active
+    // session if given.
+    fn begin_auth_session(
         &mut self,
         au: &mut AuditScope,
-        pce: &PasswordChangeEvent,
-    ) -> Result<(), OperationError> {
-        // TODO: Is it a security issue to reveal pw policy checks BEFORE permission is
-        // determined over the credential modification?
-        //
-        // I don't think so - because we should only be showing how STRONG the pw is ...
+        account: Account,
+        appid: Option<String>,
+        source: &Option<String>,
+        reauth: Option<Uuid>,
+        ct: Duration,
+    ) -> Result<AuthResult, OperationError> {
+        // Allocate a session id.
+        // TODO: #60 - make this new_v1 and use the tstamp.
+        let sessionid = uuid_from_duration(ct, self.sid);
 
-        // Get the account
-        let account_entry = try_audit!(au, self.qs_write.internal_search_uuid(au, &pce.target));
-        let account = try_audit!(au, Account::try_from_entry(account_entry));
-        // Ask if tis all good - this step checks pwpolicy and such
-        // it returns a modify
-        let modlist = try_audit!(
-            au,
-            account.gen_password_mod(pce.cleartext.as_str(), &pce.appid)
-        );
-        audit_log!(au, "processing change {:?}", modlist);
-        // given the new credential generate a modify
-        // We use impersonate here to get the event from ae
+        if account.uuid == *UUID_ANONYMOUS {
+            let qs_read = self.qs.read();
+            let config = try_audit!(au, qs_read.internal_search_uuid(au, &UUID_SYSTEM_CONFIG));
+            if config.get_ava_single_bool("disable_anonymous") == Some(true) {
+                audit_log!(au, "Authentication denied as anonymous is disabled");
+                return Ok(AuthResult {
+                    sessionid: sessionid,
+                    state: AuthState::Denied(
+                        AuthDenyReason::NotPermitted,
+                        DENY_ANONYMOUS_DISABLED.to_string(),
+                    ),
+                });
+            }
+        }
+
+        // A locked account or source gets no session to try
+        // credentials with.
+        if let Some(until) = self.locked_until(&account, source, ct) {
+            audit_log!(au, "Authentication denied as locked until {}", until);
+            return Ok(AuthResult {
+                sessionid: sessionid,
+                state: locked_state(until),
+            });
+        }
+
+        if let Some(reason) = account.validity_denied(ct) {
+            audit_log!(au, "Authentication denied -> {:?}", reason);
+            return Ok(AuthResult {
+                sessionid: sessionid,
+                state: validity_state(reason),
+            });
+        }
+
+        let mut auth_session = AuthSession::new(account, appid, self.webauthn);
+        if let Some(active) = reauth {
+            auth_session.set_reauth(active);
+        }
+
+        // Get the set of mechanisms that can proceed. This is tied
+        // to the session so that it can mutate state and have progression
+        // of what's next, or ordering.
+        let state = match auth_session.denied_reason() {
+            Some(reason) => {
+                audit_log!(au, "Authentication denied as it began: {}", reason);
+                AuthState::Denied(AuthDenyReason::NotPermitted, reason.to_string())
+            }
+            None => AuthState::Continue(auth_session.valid_auth_mechs()),
+        };
+
+        // If we have a session of the same id, return an error (despite how
+        // unlikely this is ...
+        if self.sessions.contains_key(&sessionid) {
+            return Err(OperationError::InvalidSessionState);
+        }
+        self.sessions.insert(sessionid, auth_session);
+
+        // Debugging: ensure we really inserted ...
+        assert!(self.sessions.get(&sessionid).is_some());
+
+        Ok(AuthResult {
+            sessionid: sessionid,
+            state: state,
+        })
+    }
+
+    // The time the account, or the source it is being authenticated from,
+    // is locked until, if either is locked at ct.
+    fn locked_until(
+        &self,
+        account: &Account,
+        source: &Option<String>,
+        ct: Duration,
+    ) -> Option<u64> {
+        let source_lock = source_address(source)
+            .and_then(|s| self.source_failures.get(&s).and_then(|(_, until)| *until))
+            .filter(|until| *until > ct.as_secs());
+        match (account.is_locked(ct), source_lock) {
+            (Some(a), Some(s)) => Some(a.max(s)),
+            (a, s) => a.or(s),
+        }
+    }
+
+    fn record_auth_failure(
+        &mut self,
+        au: &mut AuditScope,
+        account: &Account,
+        source: &Option<String>,
+        ct: Duration,
+    ) -> Result<Option<u64>, OperationError> {
+        let (modlist, account_lock) = account.gen_auth_failure_mod(ct, self.lockout);
+        let mut qs_write = self.qs.write();
         try_audit!(
             au,
-            self.qs_write.impersonate_modify(
+            qs_write.internal_modify(
                 au,
-                // Filter as executed
-                filter!(f_eq("uuid", PartialValue::new_uuidr(&pce.target))),
-                // Filter as intended (acp)
-                filter_all!(f_eq("uuid", PartialValue::new_uuidr(&pce.target))),
+                filter!(f_eq("uuid", PartialValue::new_uuidr(&account.uuid))),
                 modlist,
-                &pce.event,
             )
         );
+        try_audit!(au, qs_write.commit(au));
 
-        Ok(())
+        let source_lock = match source_address(source) {
+            Some(s) => {
+                let lockout = self.lockout.for_source();
+                let failures = self.source_failures.entry(s).or_insert((0, None));
+                failures.0 = failures.0.saturating_add(1);
+                if let Some(lock) = lockout.lock_for(failures.0) {
+                    failures.1 = Some(ct.as_secs().saturating_add(lock));
+                }
+                failures.1.filter(|until| *until > ct.as_secs())
+            }
+            None => None,
+        };
+        Ok(match (account_lock, source_lock) {
+            (Some(a), Some(s)) => Some(a.max(s)),
+            (a, s) => a.or(s),
+        })
     }
 
-    pub fn recover_account(
+    fn record_auth_success(
         &mut self,
         au: &mut AuditScope,
-        name: String,
-        cleartext: String,
+        account: &Account,
+        source: &Option<String>,
     ) -> Result<(), OperationError> {
-        // name to uuid
-        let target = try_audit!(au, self.qs_write.name_to_uuid(au, name.as_str()));
-        // internal pce.
-        let pce = PasswordChangeEvent::new_internal(&target, cleartext.as_str(), None);
-        // now set_account_password.
-        self.set_account_password(au, &pce)
-    }
-
-    pub fn commit(self, au: &mut AuditScope) -> Result<(), OperationError> {
-        self.qs_write.commit(au)
+        if let Some(s) = source_address(source) {
+            self.source_failures.remove(&s);
+        }
+        match account.gen_auth_success_mod() {
+            Some(modlist) => {
+                let mut qs_write = self.qs.write();
+                try_audit!(
+                    au,
+                    qs_write.internal_modify(
+                        au,
+                        filter!(f_eq("uuid", PartialValue::new_uuidr(&account.uuid))),
+                        modlist,
+                    )
+                );
+                qs_write.commit(au)
+            }
+            None => Ok(()),
+        }
     }
-}
-
-// Need tests of the sessions and the auth ...
 
-#[cfg(test)]
+    fn consume_backup_code(
+        &mut self,
+        au: &mut AuditScope,
+        account: &str,
+        code: &str,
+    ) -> Result<(), OperationError> {
+        let target = Uuid::parse_str(account).map_err(|_| OperationError::InvalidUuid)?;
+        // The idm write lock is held, so no other auth can be consuming a code
+        // between this search and the commit.
+        let mut qs_write = self.qs.write();
+        let account_entry = try_audit!(au, qs_write.internal_search_uuid(au, &target));
+        let account = try_audit!(au, Account::try_from_entry(au, account_entry, &qs_write));
+        let modlist = try_audit!(au, account.gen_backup_code_consume_mod(code));
+        try_audit!(
+            au,
+            qs_write.internal_modify(
+                au,
+                filter!(f_eq("uuid", PartialValue::new_uuidr(&target))),
+                modlist,
+            )
+        );
+        qs_write.commit(au)
+    }
+
+    fn record_webauthn_counter(
+        &mut self,
+        au: &mut AuditScope,
+        account: &str,
+        cred_id: &[u8],
+        counter: u32,
+    ) -> Result<(), OperationError> {
+        let target = Uuid::parse_str(account).map_err(|_| OperationError::InvalidUuid)?;
+        let mut qs_write = self.qs.write();
+        let account_entry = try_audit!(au, qs_write.internal_search_uuid(au, &target));
+        let account = try_audit!(au, Account::try_from_entry(au, account_entry, &qs_write));
+        let modlist = try_audit!(au, account.gen_webauthn_counter_mod(cred_id, counter));
+        try_audit!(
+            au,
+            qs_write.internal_modify(
+                au,
+                filter!(f_eq("uuid", PartialValue::new_uuidr(&target))),
+                modlist,
+            )
+        );
+        qs_write.commit(au)
+    }
+
+    fn record_api_token_used(
+        &mut self,
+        au: &mut AuditScope,
+        account: &str,
+        id: &Uuid,
+        ct: Duration,
+    ) -> Result<(), OperationError> {
+        let target = Uuid::parse_str(account).map_err(|_| OperationError::InvalidUuid)?;
+        let mut qs_write = self.qs.write();
+        let account_entry = try_audit!(au, qs_write.internal_search_uuid(au, &target));
+        let account = try_audit!(au, Account::try_from_entry(au, account_entry, &qs_write));
+        let modlist = try_audit!(au, account.gen_api_token_used_mod(id, ct));
+        try_audit!(
+            au,
+            qs_write.internal_modify(
+                au,
+                filter!(f_eq("uuid", PartialValue::new_uuidr(&target))),
+                modlist,
+            )
+        );
+        qs_write.commit(au)
+    }
+
+    // End the session the token belongs to.
+    pub fn logout(
+        &mut self,
+        au: &mut AuditScope,
+        uat: &UserAuthToken,
+    ) -> Result<(), OperationError> {
+        match self.active_sessions.remove(&uat.sessionid) {
+            Some(_) => {
+                audit_log!(au, "ended session {}", uat.sessionid);
+                Ok(())
+            }
+            None => Err(OperationError::NotAuthenticated),
+        }
+    }
+
+    pub fn revoke_session(
+        &mut self,
+        au: &mut AuditScope,
+        uat: &UserAuthToken,
+        sessionid: &Uuid,
+    ) -> Result<(), OperationError> {
+        let account = match self.active_sessions.get(sessionid) {
+            Some(s) => s.account.clone(),
+            None => return Err(OperationError::NoMatchingEntries),
+        };
+        if !may_manage_sessions(uat, account.as_str()) {
+            audit_log!(
+                au,
+                "{} may not revoke the sessions of {}",
+                uat.uuid,
+                account
+            );
+            return Err(OperationError::AccessDenied);
+        }
+        self.active_sessions.remove(sessionid);
+        audit_log!(au, "revoked session {} of {}", sessionid, account);
+        Ok(())
+    }
+
+    // End every session of the account, other than keep if given, such as
+    // after the password is changed. Returns how many were ended.
+    pub fn end_account_sessions(
+        &mut self,
+        au: &mut AuditScope,
+        target: &Uuid,
+        keep: Option<&Uuid>,
+    ) -> usize {
+        let account = target.to_hyphenated_ref().to_string();
+        let before = self.active_sessions.len();
+        self.active_sessions
+            .retain(|sessionid, s| s.account != account || Some(sessionid) == keep);
+        let ended = before - self.active_sessions.len();
+        audit_log!(au, "ended {} sessions of {}", ended, account);
+        ended
+    }
+
+    // End every session that was begun with the api token, once it is
+    // destroyed. Returns how many were ended.
+    pub fn end_api_token_sessions(&mut self, au: &mut AuditScope, id: &Uuid) -> usize {
+        let before = self.active_sessions.len();
+        self.active_sessions
+            .retain(|_, s| s.api_token.as_ref() != Some(id));
+        let ended = before - self.active_sessions.len();
+        audit_log!(au, "ended {} sessions of api token {}", ended, id);
+        ended
+    }
+
+    // Generate a totp secret for the account of this session. It isn't added
+    // to the account until a code from it is verified.
+    pub fn generate_account_totp(
+        &mut self,
+        au: &mut AuditScope,
+        uat: &UserAuthToken,
+    ) -> Result<TOTPSecret, OperationError> {
+        let totp = TOTP::generate_secure(TOTP_DEFAULT_STEP);
+        let secret = totp.to_proto(uat.name.as_str(), "kanidm");
+        self.totp_pending.insert(uat.sessionid, totp);
+        audit_log!(au, "generated pending totp for session {}", uat.sessionid);
+        Ok(secret)
+    }
+
+    // Check chal against the pending totp of this session. On success the
+    // totp is returned to be added to the account, along with the step that
+    // was used.
+    pub fn verify_account_totp(
+        &mut self,
+        au: &mut AuditScope,
+        uat: &UserAuthToken,
+        chal: u32,
+        ct: Duration,
+    ) -> Result<(TOTP, u64), OperationError> {
+        let step = match self.totp_pending.get(&uat.sessionid) {
+            Some(totp) => totp.verify(chal, &ct),
+            None => {
+                audit_log!(au, "no pending totp for session {}", uat.sessionid);
+                return Err(OperationError::InvalidRequestState);
+            }
+        };
+        match step {
+            Some(step) => {
+                let totp = self
+                    .totp_pending
+                    .remove(&uat.sessionid)
+                    .ok_or(OperationError::InvalidState)?;
+                Ok((totp, step))
+            }
+            None => {
+                audit_log!(au, "totp code did not match the pending secret");
+                Err(OperationError::InvalidTOTP)
+            }
+        }
+    }
+
+    // Record that the code of step was used with the credential, so it can't
+    // then be used to authenticate.
+    pub fn set_totp_last_step(&mut self, cred_uuid: Uuid, step: u64) {
+        self.totp_last_step.insert(cred_uuid, step);
+    }
+
+    // Begin registering a webauthn token to the account of this session.
+    pub fn generate_account_webauthn(
+        &mut self,
+        au: &mut AuditScope,
+        uat: &UserAuthToken,
+    ) -> Result<WebauthnCreationChallenge, OperationError> {
+        let target = Uuid::parse_str(uat.uuid.as_str()).map_err(|_| OperationError::InvalidUuid)?;
+        let qs_read = self.qs.read();
+        let account_entry = try_audit!(au, qs_read.internal_search_uuid(au, &target));
+        let account = try_audit!(au, Account::try_from_entry(au, account_entry, &qs_read));
+
+        // Tokens are a second factor, so a password must be set first.
+        let existing = match &account.primary {
+            Some(c) => c.webauthn.as_slice(),
+            None => {
+                return Err(OperationError::InvalidAccountState(
+                    "A password must be set before webauthn",
+                ))
+            }
+        };
+        let (proto, chal) = self.webauthn.generate_challenge_register(
+            account.name.as_str(),
+            account.displayname.as_str(),
+            &account.uuid,
+            existing,
+        );
+        self.webauthn_pending.insert(uat.sessionid, chal);
+        audit_log!(
+            au,
+            "generated webauthn registration for session {}",
+            uat.sessionid
+        );
+        Ok(proto)
+    }
+
+    // Check the response of the token to the pending challenge of this
+    // session. On success the token is returned to be added to the account.
+    pub fn verify_account_webauthn(
+        &mut self,
+        au: &mut AuditScope,
+        uat: &UserAuthToken,
+        name: &str,
+        reg: &WebauthnRegisterCredential,
+    ) -> Result<WebauthnToken, OperationError> {
+        let chal = match self.webauthn_pending.remove(&uat.sessionid) {
+            Some(c) => c,
+            None => {
+                audit_log!(au, "no pending webauthn for session {}", uat.sessionid);
+                return Err(OperationError::InvalidRequestState);
+            }
+        };
+        // A challenge is only good for one attempt.
+        let token = try_audit!(au, self.webauthn.register_credential(reg, &chal, name));
+        Ok(token)
+    }
+
+    // Answer an authorization request from the session of uat with where its
+    // browser is to be redirected. Until the client and redirect uri are
+    // known to be good there is nowhere to send an error, so those fail the
+    // request itself. Any later refusal is given to the client by redirect.
+    pub fn oauth2_authorize(
+        &mut self,
+        au: &mut AuditScope,
+        uat: &UserAuthToken,
+        req: &Oauth2AuthorizeRequest,
+        ct: Duration,
+    ) -> Result<String, OperationError> {
+        let qs_read = self.qs.read();
+        let client = Oauth2Client::try_from_name(au, req.client_id.as_str(), &qs_read)?;
+        let redirect_uri = req.redirect_uri.as_str();
+        if !client.is_redirect_uri(redirect_uri) {
+            audit_log!(
+                au,
+                "{} is not a redirect uri of {}",
+                redirect_uri,
+                client.name()
+            );
+            return Err(OperationError::Oauth2("invalid_request"));
+        }
+
+        let state = req.state.as_ref().map(|s| s.as_str());
+        let location = match client.begin_grant(au, uat, req, ct) {
+            Ok(grant) => {
+                let code = generate_code();
+                let location = redirect_location(
+                    redirect_uri,
+                    &[("code", Some(code.as_str())), ("state", state)],
+                );
+                self.oauth2_codes.insert(code, grant);
+                audit_log!(
+                    au,
+                    "issued an oauth2 code for {} to session {}",
+                    client.name(),
+                    uat.sessionid
+                );
+                location
+            }
+            Err(error) => {
+                redirect_location(redirect_uri, &[("error", Some(error)), ("state", state)])
+            }
+        };
+        Ok(location)
+    }
+
+    // Exchange a code for the grant it was issued with. A code may only be
+    // tried once, so it's spent whether or not the exchange succeeds.
+    pub fn oauth2_token_exchange(
+        &mut self,
+        au: &mut AuditScope,
+        req: &Oauth2TokenRequest,
+        ct: Duration,
+    ) -> Result<Oauth2CodeGrant, OperationError> {
+        if req.grant_type != "authorization_code" {
+            audit_log!(au, "unsupported grant type {}", req.grant_type);
+            return Err(OperationError::Oauth2("unsupported_grant_type"));
+        }
+        let grant = match self.oauth2_codes.remove(&req.code) {
+            Some(g) => g,
+            None => {
+                audit_log!(au, "the oauth2 code is unknown or already spent");
+                return Err(OperationError::Oauth2("invalid_grant"));
+            }
+        };
+        grant.check_exchange(au, req, ct)?;
+
+        // The code is only as good as the session it was issued to.
+        match self.active_sessions.get(grant.sessionid()) {
+            Some(s) if ct.as_secs() < s.expiry => Ok(grant),
+            _ => {
+                audit_log!(au, "session {} has ended", grant.sessionid());
+                Err(OperationError::Oauth2("invalid_grant"))
+            }
+        }
+    }
+
+    pub fn commit(self) -> Result<(), OperationError> {
+        self.sessions.commit();
+        self.active_sessions.commit();
+        self.totp_pending.commit();
+        self.totp_last_step.commit();
+        self.webauthn_pending.commit();
+        self.source_failures.commit();
+        self.oauth2_codes.commit();
+        Ok(())
+    }
+}
+
+/*
+impl<'a> IdmServerReadTransaction<'a> {
+    pub fn whoami() -> () {}
+}
+*/
+
+impl<'a> IdmServerProxyWriteTransaction<'a> {
+    // Run a new password through the quality checks, giving every reason it
+    // fails. The user inputs are details of the account, such as its name,
+    // that don't belong in its password.
+    fn check_password_quality(
+        &self,
+        au: &mut AuditScope,
+        cleartext: &str,
+        user_inputs: &[&str],
+    ) -> Result<(), OperationError> {
+        let mut feedback = Vec::new();
+        if cleartext.chars().count() < PW_MIN_LENGTH {
+            feedback.push(PasswordFeedback::TooShort(PW_MIN_LENGTH as u32));
+        }
+
+        // The badlist and threshold can be changed by modifying the config.
+        let config = try_audit!(
+            au,
+            self.qs_write.internal_search_uuid(au, &UUID_SYSTEM_CONFIG)
+        );
+        let lower = cleartext.to_lowercase();
+        if config.attribute_value_pres(
+            "badlist_password",
+            &PartialValue::new_iutf8s(lower.as_str()),
+        ) {
+            feedback.push(PasswordFeedback::BadListed);
+        }
+
+        let min_score = config
+            .get_ava_single("password_min_score")
+            .and_then(|v| v.to_uint32())
+            .map(|s| s as u8)
+            .unwrap_or(PW_MIN_SCORE);
+        let estimate = strength::estimate(cleartext, user_inputs);
+        if estimate.score < min_score {
+            feedback.push(PasswordFeedback::TooGuessable);
+            feedback.extend(estimate.feedback);
+        }
+
+        if feedback.is_empty() {
+            Ok(())
+        } else {
+            audit_log!(au, "password rejected -> {:?}", feedback);
+            Err(OperationError::PasswordQuality(feedback))
+        }
+    }
+
+    pub fn set_account_password(
+        &mut self,
+        au: &mut AuditScope,
+        pce: &PasswordChangeEvent,
+    ) -> Result<(), OperationError> {
+        // TODO: Is it a security issue to reveal pw policy checks BEFORE permission is
+        // determined over the credential modification?
+        //
+        // I don't think so - because we should only be showing how STRONG the pw is ...
+
+        // Get the account
+        let account_entry = try_audit!(au, self.qs_write.internal_search_uuid(au, &pce.target));
+        let account = try_audit!(
+            au,
+            Account::try_from_entry(au, account_entry, &self.qs_write)
+        );
+        try_audit!(
+            au,
+            self.check_password_quality(
+                au,
+                pce.cleartext.as_str(),
+                &[account.name.as_str(), account.displayname.as_str()],
+            )
+        );
+        // Ask if tis all good - this step checks pwpolicy and such
+        // it returns a modify
+        let modlist = try_audit!(
+            au,
+            account.gen_password_mod(pce.cleartext.as_str(), &pce.appid, pce.must_change)
+        );
+        audit_log!(au, "processing change {:?}", modlist);
+        // given the new credential generate a modify
+        // We use impersonate here to get the event from ae
+        try_audit!(
+            au,
+            self.qs_write.impersonate_modify(
+                au,
+                // Filter as executed
+                filter!(f_eq("uuid", PartialValue::new_uuidr(&pce.target))),
+                // Filter as intended (acp)
+                filter_all!(f_eq("uuid", PartialValue::new_uuidr(&pce.target))),
+                modlist,
+                &pce.event,
+            )
+        );
+
+        Ok(())
+    }
+
+    // Change the password of the account the token belongs to. Unless the
+    // session authenticated recently enough to change credentials, the
+    // current password must be given. Returns the uuid of the account.
+    pub fn self_set_account_password(
+        &mut self,
+        au: &mut AuditScope,
+        uat: &UserAuthToken,
+        current: Option<&str>,
+        cleartext: &str,
+        ct: Duration,
+    ) -> Result<Uuid, OperationError> {
+        if uat.read_only {
+            audit_log!(
+                au,
+                "read only session {} may not change password",
+                uat.sessionid
+            );
+            return Err(OperationError::AccessDenied);
+        }
+        let target = try_audit!(
+            au,
+            Uuid::parse_str(uat.uuid.as_str()).map_err(|_| OperationError::InvalidUuid)
+        );
+        let account_entry = try_audit!(au, self.qs_write.internal_search_uuid(au, &target));
+        let account = try_audit!(
+            au,
+            Account::try_from_entry(au, account_entry, &self.qs_write)
+        );
+        match current {
+            Some(current) => {
+                let valid = account
+                    .primary
+                    .as_ref()
+                    .map(|c| c.verify_password(current))
+                    .unwrap_or(false);
+                if !valid {
+                    audit_log!(au, "incorrect current password for {}", target);
+                    return Err(OperationError::IncorrectPassword);
+                }
+            }
+            None => {
+                try_audit!(
+                    au,
+                    self.qs_write.get_reauth_policy().check(
+                        au,
+                        ProtectedOperation::CredentialChange,
+                        uat.auth_time,
+                        ct
+                    )
+                );
+            }
+        }
+        // Having proven themself, the account may always change its own
+        // password, so this isn't subject to access controls.
+        let pce = PasswordChangeEvent::new_internal(&target, cleartext, None);
+        self.set_account_password(au, &pce)?;
+        Ok(target)
+    }
+
+    // Reset the password of the target, by name or uuid, as the holder of
+    // uat. Access controls decide if they may. Returns the uuid of the
+    // account.
+    pub fn admin_set_account_password(
+        &mut self,
+        au: &mut AuditScope,
+        uat: &UserAuthToken,
+        target: &str,
+        cleartext: &str,
+        must_change: bool,
+    ) -> Result<Uuid, OperationError> {
+        let target = match Uuid::parse_str(target) {
+            Ok(u) => u,
+            Err(_) => try_audit!(au, self.qs_write.name_to_uuid(au, target)),
+        };
+        let pce = try_audit!(
+            au,
+            PasswordChangeEvent::from_parts(
+                au,
+                &self.qs_write,
+                uat,
+                &target,
+                cleartext,
+                must_change
+            )
+        );
+        self.set_account_password(au, &pce)?;
+        Ok(target)
+    }
+
+    // Set the unix password of the target, by name or uuid, as the holder of
+    // uat. An account may always set its own, otherwise access controls
+    // decide. Sessions aren't ended, as they don't use this password.
+    pub fn set_unix_account_password(
+        &mut self,
+        au: &mut AuditScope,
+        uat: &UserAuthToken,
+        target: &str,
+        cleartext: &str,
+    ) -> Result<(), OperationError> {
+        let target = match Uuid::parse_str(target) {
+            Ok(u) => u,
+            Err(_) => try_audit!(au, self.qs_write.name_to_uuid(au, target)),
+        };
+        // This also refuses read only sessions.
+        let event = try_audit!(
+            au,
+            Event::from_rw_uat(au, &self.qs_write, Some(uat.clone()))
+        );
+        let event = if uat.uuid == target.to_hyphenated_ref().to_string() {
+            Event::from_internal()
+        } else {
+            event
+        };
+
+        let account_entry = try_audit!(au, self.qs_write.internal_search_uuid(au, &target));
+        let account = try_audit!(
+            au,
+            UnixUserAccount::try_from_entry(au, &account_entry, &self.qs_write)
+        );
+        try_audit!(
+            au,
+            self.check_password_quality(
+                au,
+                cleartext,
+                &[account.name.as_str(), account.displayname.as_str()],
+            )
+        );
+        let modlist = account.gen_password_mod(cleartext);
+        try_audit!(
+            au,
+            self.qs_write.impersonate_modify(
+                au,
+                filter!(f_eq("uuid", PartialValue::new_uuidr(&target))),
+                filter_all!(f_eq("uuid", PartialValue::new_uuidr(&target))),
+                modlist,
+                &event,
+            )
+        );
+        Ok(())
+    }
+
+    pub fn recover_account(
+        &mut self,
+        au: &mut AuditScope,
+        name: String,
+        cleartext: String,
+    ) -> Result<(), OperationError> {
+        // name to uuid
+        let target = try_audit!(au, self.qs_write.name_to_uuid(au, name.as_str()));
+        // internal pce.
+        let pce = PasswordChangeEvent::new_internal(&target, cleartext.as_str(), None);
+        // now set_account_password.
+        self.set_account_password(au, &pce)
+    }
+
+    // Set a generated password on the account, for an operator on the
+    // server's host who has lost theirs. Nothing authenticated this, so it's
+    // recorded in the audit log as made by the local admin.
+    pub fn recover_account_local(
+        &mut self,
+        au: &mut AuditScope,
+        name: String,
+    ) -> Result<String, OperationError> {
+        let target = try_audit!(au, self.qs_write.name_to_uuid(au, name.as_str()));
+        let cleartext = generate_recovery_password();
+        let pce = PasswordChangeEvent::new_internal(&target, cleartext.as_str(), None);
+        self.set_account_password(au, &pce)?;
+        self.qs_write.audit_local_admin(
+            au,
+            DbAuditOperationV1::Modify,
+            vec![target],
+            vec![
+                DbAuditChangeV1::Purged("primary_credential".to_string()),
+                DbAuditChangeV1::Present(
+                    "primary_credential".to_string(),
+                    AUDIT_REDACTED.to_string(),
+                ),
+            ],
+        )?;
+        Ok(cleartext)
+    }
+
+    // Add a verified totp to the primary credential of the account. Returns
+    // the uuid of the credential it was added to.
+    pub fn set_account_totp(
+        &mut self,
+        au: &mut AuditScope,
+        target: &Uuid,
+        totp: TOTP,
+    ) -> Result<Uuid, OperationError> {
+        let account_entry = try_audit!(au, self.qs_write.internal_search_uuid(au, target));
+        let account = try_audit!(
+            au,
+            Account::try_from_entry(au, account_entry, &self.qs_write)
+        );
+        let modlist = try_audit!(au, account.gen_totp_mod(totp));
+        let cred_uuid = account
+            .primary
+            .as_ref()
+            .map(|c| c.uuid.clone())
+            .ok_or(OperationError::InvalidState)?;
+        audit_log!(au, "processing change {:?}", modlist);
+        try_audit!(
+            au,
+            self.qs_write.internal_modify(
+                au,
+                filter!(f_eq("uuid", PartialValue::new_uuidr(target))),
+                modlist,
+            )
+        );
+        Ok(cred_uuid)
+    }
+
+    pub fn add_account_webauthn(
+        &mut self,
+        au: &mut AuditScope,
+        target: &Uuid,
+        token: WebauthnToken,
+    ) -> Result<(), OperationError> {
+        let account_entry = try_audit!(au, self.qs_write.internal_search_uuid(au, target));
+        let account = try_audit!(
+            au,
+            Account::try_from_entry(au, account_entry, &self.qs_write)
+        );
+        let modlist = try_audit!(au, account.gen_webauthn_add_mod(token));
+        audit_log!(au, "processing change {:?}", modlist);
+        self.qs_write.internal_modify(
+            au,
+            filter!(f_eq("uuid", PartialValue::new_uuidr(target))),
+            modlist,
+        )
+    }
+
+    pub fn remove_account_webauthn(
+        &mut self,
+        au: &mut AuditScope,
+        target: &Uuid,
+        name: &str,
+    ) -> Result<(), OperationError> {
+        let account_entry = try_audit!(au, self.qs_write.internal_search_uuid(au, target));
+        let account = try_audit!(
+            au,
+            Account::try_from_entry(au, account_entry, &self.qs_write)
+        );
+        let modlist = try_audit!(au, account.gen_webauthn_remove_mod(name));
+        audit_log!(au, "processing change {:?}", modlist);
+        self.qs_write.internal_modify(
+            au,
+            filter!(f_eq("uuid", PartialValue::new_uuidr(target))),
+            modlist,
+        )
+    }
+
+    pub fn set_account_credential_policy(
+        &mut self,
+        au: &mut AuditScope,
+        target: &Uuid,
+        policy: Option<Policy>,
+    ) -> Result<(), OperationError> {
+        let account_entry = try_audit!(au, self.qs_write.internal_search_uuid(au, target));
+        let account = try_audit!(
+            au,
+            Account::try_from_entry(au, account_entry, &self.qs_write)
+        );
+        let modlist = try_audit!(au, account.gen_policy_mod(policy));
+        audit_log!(au, "processing change {:?}", modlist);
+        self.qs_write.internal_modify(
+            au,
+            filter!(f_eq("uuid", PartialValue::new_uuidr(target))),
+            modlist,
+        )
+    }
+
+    // Replace the backup codes of the primary credential, returning the new
+    // codes.
+    pub fn generate_backup_codes(
+        &mut self,
+        au: &mut AuditScope,
+        target: &Uuid,
+    ) -> Result<Vec<String>, OperationError> {
+        let account_entry = try_audit!(au, self.qs_write.internal_search_uuid(au, target));
+        let account = try_audit!(
+            au,
+            Account::try_from_entry(au, account_entry, &self.qs_write)
+        );
+        let (modlist, codes) = try_audit!(au, account.gen_backup_codes_mod());
+        audit_log!(au, "generated {} backup codes for {}", codes.len(), target);
+        try_audit!(
+            au,
+            self.qs_write.internal_modify(
+                au,
+                filter!(f_eq("uuid", PartialValue::new_uuidr(target))),
+                modlist,
+            )
+        );
+        Ok(codes)
+    }
+
+    // Replace the radius secret of the account, returning the new secret.
+    pub fn generate_radius_secret(
+        &mut self,
+        au: &mut AuditScope,
+        target: &Uuid,
+    ) -> Result<String, OperationError> {
+        let account_entry = try_audit!(au, self.qs_write.internal_search_uuid(au, target));
+        let account = try_audit!(
+            au,
+            Account::try_from_entry(au, account_entry, &self.qs_write)
+        );
+        let (modlist, secret) = account.gen_radius_secret_mod();
+        audit_log!(au, "generated radius secret for {}", target);
+        try_audit!(
+            au,
+            self.qs_write.internal_modify(
+                au,
+                filter!(f_eq("uuid", PartialValue::new_uuidr(target))),
+                modlist,
+            )
+        );
+        Ok(secret)
+    }
+
+    fn resolve_target(&self, au: &mut AuditScope, target: &str) -> Result<Uuid, OperationError> {
+        match Uuid::parse_str(target) {
+            Ok(u) => Ok(u),
+            Err(_) => self.qs_write.name_to_uuid(au, target),
+        }
+    }
+
+    // Generate an api token for the target service account, by name or
+    // uuid, as the holder of uat. Returns the id of the token and the token
+    // itself, which is not stored and can't be shown again.
+    pub fn generate_api_token(
+        &mut self,
+        au: &mut AuditScope,
+        uat: &UserAuthToken,
+        target: &str,
+        label: &str,
+        expiry: Option<u64>,
+        read_write: bool,
+        ct: Duration,
+    ) -> Result<(Uuid, String), OperationError> {
+        let target = try_audit!(au, self.resolve_target(au, target));
+        if expiry.map(|e| e <= ct.as_secs()).unwrap_or(false) {
+            audit_log!(au, "api token would already have expired");
+            return Err(OperationError::InvalidRequestState);
+        }
+        let (at, token) = ApiToken::generate(label, expiry, read_write, ct);
+        let id = at.id;
+        let modlist = ModifyList::new_list(vec![Modify::Present(
+            "api_token".to_string(),
+            Value::new_apitoken(at),
+        )]);
+        self.modify_api_tokens(au, uat, &target, modlist)?;
+        audit_log!(au, "generated api token {} for {}", id, target);
+        Ok((id, token))
+    }
+
+    pub fn destroy_api_token(
+        &mut self,
+        au: &mut AuditScope,
+        uat: &UserAuthToken,
+        target: &str,
+        id: &Uuid,
+    ) -> Result<(), OperationError> {
+        let target = try_audit!(au, self.resolve_target(au, target));
+        let modlist = ModifyList::new_list(vec![Modify::Removed(
+            "api_token".to_string(),
+            PartialValue::new_apitoken_id(*id),
+        )]);
+        self.modify_api_tokens(au, uat, &target, modlist)?;
+        audit_log!(au, "destroyed api token {} of {}", id, target);
+        Ok(())
+    }
+
+    // Tokens may only be given to service accounts, and access controls
+    // decide who may manage them.
+    fn modify_api_tokens(
+        &mut self,
+        au: &mut AuditScope,
+        uat: &UserAuthToken,
+        target: &Uuid,
+        modlist: ModifyList<ModifyInvalid>,
+    ) -> Result<(), OperationError> {
+        let event = try_audit!(
+            au,
+            Event::from_rw_uat(au, &self.qs_write, Some(uat.clone()))
+        );
+        self.qs_write.impersonate_modify(
+            au,
+            filter!(f_and!([
+                f_eq("uuid", PartialValue::new_uuidr(target)),
+                f_eq("class", PartialValue::new_class("service_account"))
+            ])),
+            filter_all!(f_eq("uuid", PartialValue::new_uuidr(target))),
+            modlist,
+            &event,
+        )
+    }
+
+    // List the api tokens of the target service account, as the holder of
+    // uat. Only what is needed to tell the tokens apart is returned.
+    pub fn list_api_tokens(
+        &mut self,
+        au: &mut AuditScope,
+        uat: &UserAuthToken,
+        target: &str,
+    ) -> Result<Vec<ApiTokenInfo>, OperationError> {
+        let target = try_audit!(au, self.resolve_target(au, target));
+        let event = try_audit!(
+            au,
+            Event::from_rw_uat(au, &self.qs_write, Some(uat.clone()))
+        );
+        let entries = try_audit!(
+            au,
+            self.qs_write.impersonate_search(
+                au,
+                filter!(f_eq("uuid", PartialValue::new_uuidr(&target))),
+                filter_all!(f_and!([
+                    f_eq("uuid", PartialValue::new_uuidr(&target)),
+                    f_pres("api_token")
+                ])),
+                &event,
+            )
+        );
+        Ok(entries
+            .iter()
+            .filter_map(|e| e.get_ava("api_token"))
+            .flat_map(|vs| vs.into_iter().filter_map(|v| v.to_apitoken()))
+            .map(|at| at.to_proto())
+            .collect())
+    }
+
+    fn save_token_keys(
+        &mut self,
+        au: &mut AuditScope,
+        keys: &TokenKeys,
+    ) -> Result<(), OperationError> {
+        let modlist = try_audit!(au, keys.to_modlist());
+        self.qs_write.internal_modify(
+            au,
+            filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_DOMAIN_INFO))),
+            modlist,
+        )
+    }
+
+    // Load the token signing keys, generating them if this is the first
+    // start of the server.
+    pub fn get_or_create_token_keys(
+        &mut self,
+        au: &mut AuditScope,
+        grace: Duration,
+    ) -> Result<TokenKeys, OperationError> {
+        let domain_info = try_audit!(
+            au,
+            self.qs_write.internal_search_uuid(au, &UUID_DOMAIN_INFO)
+        );
+        match try_audit!(au, TokenKeys::try_from_entry(&domain_info, grace)) {
+            Some(keys) => Ok(keys),
+            None => {
+                audit_log!(au, "generating new token signing key");
+                let keys = try_audit!(au, TokenKeys::generate(grace));
+                self.save_token_keys(au, &keys)?;
+                Ok(keys)
+            }
+        }
+    }
+
+    pub fn rotate_token_keys(
+        &mut self,
+        au: &mut AuditScope,
+        ct: Duration,
+        grace: Duration,
+    ) -> Result<TokenKeys, OperationError> {
+        let keys = self.get_or_create_token_keys(au, grace)?;
+        let keys = try_audit!(au, keys.rotate(ct));
+        self.save_token_keys(au, &keys)?;
+        Ok(keys)
+    }
+
+    pub fn commit(self, au: &mut AuditScope) -> Result<(), OperationError> {
+        self.qs_write.commit(au)
+    }
+}
+
+// Need tests of the sessions and the auth ...
+
+#[cfg(test)]
 mod tests {
-    use crate::constants::{AUTH_SESSION_TIMEOUT, UUID_ADMIN};
-    use crate::credential::Credential;
-    use crate::event::{AuthEvent, AuthResult, ModifyEvent};
+    use crate::constants::{
+        _UUID_IDM_RADIUS_SERVERS, _UUID_IDM_UNIX_AUTH_SERVERS, AUTH_LOCKOUT_SOURCE_FACTOR,
+        AUTH_LOCKOUT_THRESHOLD, AUTH_LOCKOUT_WINDOW, AUTH_SESSION_TIMEOUT, AUTH_TOKEN_LIFETIME,
+        PW_MIN_LENGTH, REAUTH_WINDOW, STR_UUID_LOCAL_ADMIN, UUID_ADMIN, UUID_SYSTEM_CONFIG,
+    };
+    use crate::credential::totp::{TOTPAlgo, TOTP, TOTP_DEFAULT_STEP};
+    use crate::credential::webauthn::softtoken::SoftToken;
+    use crate::credential::{Credential, Policy, BACKUP_CODE_COUNT};
+    use crate::entry::{Entry, EntryInvalid, EntryNew};
+    use crate::event::{AuditListEvent, AuthEvent, AuthResult, CreateEvent, ModifyEvent};
     use crate::idm::event::PasswordChangeEvent;
+    use crate::idm::reauth::ProtectedOperation;
     use crate::modify::{Modify, ModifyList};
     use crate::value::{PartialValue, Value};
+    use kanidm_proto::v1::Modify as ProtoModify;
     use kanidm_proto::v1::OperationError;
-    use kanidm_proto::v1::{AuthAllowed, AuthState};
+    use kanidm_proto::v1::{
+        AuthAllowed, AuthDenyReason, AuthState, CredentialPolicy, PasswordFeedback, UserAuthToken,
+        AUDIT_REDACTED,
+    };
+
+    use crate::audit::AuditScope;
+    use crate::idm::account::Account;
+    use crate::idm::server::IdmServer;
+    use crate::server::{QueryServer, QueryServerTransaction, QueryServerWriteTransaction};
+    use chrono::{DateTime, Utc};
+    use std::time::{Duration, SystemTime};
+    use uuid::Uuid;
+
+    static TEST_PASSWORD: &'static str = "ntaoeuntnaoeuhraohuercahu😍";
+    static TEST_PASSWORD_INC: &'static str = "ntaoentu nkrcgaeunhibwmwmqj;k wqjbkx ";
+    static TEST_CURRENT_TIME: u64 = 6000;
+    static TEST_CURRENT_EXPIRE: u64 = TEST_CURRENT_TIME + AUTH_SESSION_TIMEOUT + 1;
+
+    #[test]
+    fn test_idm_anonymous_auth() {
+        run_idm_test!(|_qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            let sid = {
+                // Start and test anonymous auth.
+                let mut idms_write = idms.write();
+                // Send the initial auth event for initialising the session
+                let anon_init = AuthEvent::anonymous_init();
+                // Expect success
+                let r1 = idms_write.auth(au, &anon_init, Duration::from_secs(TEST_CURRENT_TIME));
+                /* Some weird lifetime shit happens here ... */
+                // audit_log!(au, "r1 ==> {:?}", r1);
+
+                let sid = match r1 {
+                    Ok(ar) => {
+                        let AuthResult { sessionid, state } = ar;
+                        match state {
+                            AuthState::Continue(mut conts) => {
+                                // Should only be one auth mech
+                                assert!(conts.len() == 1);
+                                // And it should be anonymous
+                                let m = conts.pop().expect("Should not fail");
+                                assert!(m == AuthAllowed::Anonymous);
+                            }
+                            _ => {
+                                error!(
+                                    "A critical error has occured! We have a non-continue result!"
+                                );
+                                panic!();
+                            }
+                        };
+                        // Now pass back the sessionid, we are good to continue.
+                        sessionid
+                    }
+                    Err(e) => {
+                        // Should not occur!
+                        error!("A critical error has occured! {:?}", e);
+                        panic!();
+                    }
+                };
+
+                println!("sessionid is ==> {:?}", sid);
+
+                idms_write.commit().expect("Must not fail");
+
+                sid
+            };
+            {
+                let mut idms_write = idms.write();
+                // Now send the anonymous request, given the session id.
+                let anon_step = AuthEvent::cred_step_anonymous(sid);
+
+                // Expect success
+                let r2 = idms_write.auth(au, &anon_step, Duration::from_secs(TEST_CURRENT_TIME));
+                println!("r2 ==> {:?}", r2);
+
+                match r2 {
+                    Ok(ar) => {
+                        let AuthResult {
+                            sessionid: _,
+                            state,
+                        } = ar;
+                        match state {
+                            AuthState::Success(uat) => {
+                                // Check the uat.
+                                assert!(uat.anonymous);
+                            }
+                            _ => {
+                                error!(
+                                    "A critical error has occured! We have a non-succcess result!"
+                                );
+                                panic!();
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        error!("A critical error has occured! {:?}", e);
+                        // Should not occur!
+                        panic!();
+                    }
+                };
+
+                idms_write.commit().expect("Must not fail");
+            }
+        });
+    }
+
+    #[test]
+    fn test_idm_anonymous_disabled() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            let mut qs_write = qs.write();
+            assert!(qs_write
+                .internal_modify(
+                    au,
+                    filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_SYSTEM_CONFIG))),
+                    ModifyList::new_purge_and_set("disable_anonymous", Value::new_bool(true)),
+                )
+                .is_ok());
+            qs_write.commit(au).expect("Must not fail");
+
+            let mut idms_write = idms.write();
+            let ct = Duration::from_secs(TEST_CURRENT_TIME);
+            let sid = match idms_write.auth(au, &AuthEvent::anonymous_init(), ct) {
+                Ok(AuthResult {
+                    sessionid,
+                    state: AuthState::Denied(AuthDenyReason::NotPermitted, reason),
+                }) => {
+                    assert!(reason == "anonymous disabled");
+                    sessionid
+                }
+                _ => panic!(),
+            };
+            // No session was begun to continue.
+            assert!(
+                idms_write
+                    .auth(au, &AuthEvent::cred_step_anonymous(sid), ct)
+                    .err()
+                    == Some(OperationError::InvalidSessionState)
+            );
+            idms_write.commit().expect("Must not fail");
+        })
+    }
+
+    // Test sending anonymous but with no session init.
+    #[test]
+    fn test_idm_anonymous_auth_invalid_states() {
+        run_idm_test!(|_qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            {
+                let mut idms_write = idms.write();
+                let sid = Uuid::new_v4();
+                let anon_step = AuthEvent::cred_step_anonymous(sid);
+
+                // Expect failure
+                let r2 = idms_write.auth(au, &anon_step, Duration::from_secs(TEST_CURRENT_TIME));
+                println!("r2 ==> {:?}", r2);
+
+                match r2 {
+                    Ok(_) => {
+                        error!("Auth state machine not correctly enforced!");
+                        panic!();
+                    }
+                    Err(e) => match e {
+                        OperationError::InvalidSessionState => {}
+                        _ => panic!(),
+                    },
+                };
+            }
+        })
+    }
+
+    fn init_admin_w_password(
+        au: &mut AuditScope,
+        qs: &QueryServer,
+        pw: &str,
+    ) -> Result<(), OperationError> {
+        let cred = Credential::new_password_only(pw);
+        let v_cred = Value::new_credential("primary", cred);
+        let mut qs_write = qs.write();
+
+        // now modify and provide a primary credential.
+        let me_inv_m = unsafe {
+            ModifyEvent::new_internal_invalid(
+                filter!(f_eq("name", PartialValue::new_iutf8s("admin"))),
+                ModifyList::new_list(vec![Modify::Present(
+                    "primary_credential".to_string(),
+                    v_cred,
+                )]),
+            )
+        };
+        // go!
+        assert!(qs_write.modify(au, &me_inv_m).is_ok());
+
+        qs_write.commit(au)
+    }
+
+    fn init_admin_authsession_sid(idms: &IdmServer, au: &mut AuditScope) -> Uuid {
+        let mut idms_write = idms.write();
+        let admin_init = AuthEvent::named_init("admin");
+
+        let r1 = idms_write.auth(au, &admin_init, Duration::from_secs(TEST_CURRENT_TIME));
+        let ar = r1.unwrap();
+        let AuthResult { sessionid, state } = ar;
+
+        match state {
+            AuthState::Continue(_) => {}
+            _ => {
+                error!("Sessions was not initialised");
+                panic!();
+            }
+        };
+
+        idms_write.commit().expect("Must not fail");
+
+        sessionid
+    }
+
+    fn init_admin_uat(idms: &IdmServer, au: &mut AuditScope, ct: Duration) -> UserAuthToken {
+        // Session ids are derived from the time, so each uat needs a
+        // distinct ct.
+        let mut idms_write = idms.write();
+        let sid = match idms_write.auth(au, &AuthEvent::named_init("admin"), ct) {
+            Ok(ar) => ar.sessionid,
+            Err(e) => {
+                error!("A critical error has occured! {:?}", e);
+                panic!();
+            }
+        };
+        let pw_step = AuthEvent::cred_step_password(sid, TEST_PASSWORD);
+        let uat = match idms_write.auth(au, &pw_step, ct).map(|ar| ar.state) {
+            Ok(AuthState::Success(uat)) => uat,
+            _ => {
+                error!("A critical error has occured! We have a non-succcess result!");
+                panic!();
+            }
+        };
+
+        idms_write.commit().expect("Must not fail");
+        assert!(uat.sessionid == sid);
+        uat
+    }
+
+    #[test]
+    fn test_idm_simple_password_auth() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
+            let sid = init_admin_authsession_sid(idms, au);
+
+            let mut idms_write = idms.write();
+            let anon_step = AuthEvent::cred_step_password(sid, TEST_PASSWORD);
+
+            // Expect success
+            let r2 = idms_write.auth(au, &anon_step, Duration::from_secs(TEST_CURRENT_TIME));
+            println!("r2 ==> {:?}", r2);
+
+            match r2 {
+                Ok(ar) => {
+                    let AuthResult {
+                        sessionid: _,
+                        state,
+                    } = ar;
+                    match state {
+                        AuthState::Success(uat) => {
+                            // Check the uat.
+                            assert!(uat.issued_at == TEST_CURRENT_TIME);
+                            assert!(uat.expiry == TEST_CURRENT_TIME + AUTH_TOKEN_LIFETIME);
+                            assert!(!uat.is_expired(Duration::from_secs(TEST_CURRENT_TIME)));
+                            assert!(uat.is_expired(Duration::from_secs(
+                                TEST_CURRENT_TIME + AUTH_TOKEN_LIFETIME
+                            )));
+                        }
+                        _ => {
+                            error!("A critical error has occured! We have a non-succcess result!");
+                            panic!();
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("A critical error has occured! {:?}", e);
+                    // Should not occur!
+                    panic!();
+                }
+            };
+
+            idms_write.commit().expect("Must not fail");
+        })
+    }
+
+    #[test]
+    fn test_idm_uat_nested_groups() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
+
+            // admin -> idm_admins -> idm_group_write_priv -> testgroup
+            let e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "group"],
+                    "name": ["testgroup"],
+                    "member": ["00000000-0000-0000-0000-000000000004"]
+                }
+            }"#,
+            );
+            let mut qs_write = qs.write();
+            let ce = CreateEvent::new_internal(vec![e]);
+            assert!(qs_write.create(au, &ce).is_ok());
+            qs_write.commit(au).expect("Must not fail");
+
+            let sid = init_admin_authsession_sid(idms, au);
+
+            let mut idms_write = idms.write();
+            let anon_step = AuthEvent::cred_step_password(sid, TEST_PASSWORD);
+            let r2 = idms_write.auth(au, &anon_step, Duration::from_secs(TEST_CURRENT_TIME));
+
+            match r2 {
+                Ok(AuthResult {
+                    sessionid: _,
+                    state: AuthState::Success(uat),
+                }) => {
+                    let names: Vec<&str> = uat.groups.iter().map(|g| g.name.as_str()).collect();
+                    assert!(names.contains(&"idm_admins"));
+                    assert!(names.contains(&"idm_group_write_priv"));
+                    assert!(names.contains(&"testgroup"));
+                }
+                _ => {
+                    error!("A critical error has occured! {:?}", r2);
+                    panic!();
+                }
+            };
+
+            idms_write.commit().expect("Must not fail");
+        })
+    }
+
+    #[test]
+    fn test_idm_simple_password_invalid() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
+            let sid = init_admin_authsession_sid(idms, au);
+            let mut idms_write = idms.write();
+            let anon_step = AuthEvent::cred_step_password(sid, TEST_PASSWORD_INC);
+
+            // Expect success
+            let r2 = idms_write.auth(au, &anon_step, Duration::from_secs(TEST_CURRENT_TIME));
+            println!("r2 ==> {:?}", r2);
+
+            match r2 {
+                Ok(ar) => {
+                    let AuthResult {
+                        sessionid: _,
+                        state,
+                    } = ar;
+                    match state {
+                        AuthState::Denied(_, _reason) => {
+                            // Check the uat.
+                        }
+                        _ => {
+                            error!("A critical error has occured! We have a non-denied result!");
+                            panic!();
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("A critical error has occured! {:?}", e);
+                    // Should not occur!
+                    panic!();
+                }
+            };
+
+            idms_write.commit().expect("Must not fail");
+        })
+    }
+
+    #[test]
+    fn test_idm_simple_password_reset() {
+        run_idm_test!(|_qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            let pce = PasswordChangeEvent::new_internal(&UUID_ADMIN, TEST_PASSWORD, None);
+
+            let mut idms_prox_write = idms.proxy_write();
+            assert!(idms_prox_write.set_account_password(au, &pce).is_ok());
+            assert!(idms_prox_write.set_account_password(au, &pce).is_ok());
+            assert!(idms_prox_write.commit(au).is_ok());
+        })
+    }
+
+    // The generated password works, and the change is recorded as the local
+    // admin's, without the credential.
+    #[test]
+    fn test_idm_recover_account_local() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            let mut idms_prox_write = idms.proxy_write();
+            let pw = idms_prox_write
+                .recover_account_local(au, "admin".to_string())
+                .expect("recover failed");
+            assert!(idms_prox_write
+                .recover_account_local(au, "nobody".to_string())
+                .is_err());
+            assert!(idms_prox_write.commit(au).is_ok());
+
+            let qs_read = qs.read();
+            let admin = qs_read
+                .internal_search_uuid(au, &UUID_ADMIN)
+                .expect("Missing admin");
+            assert!(admin
+                .get_ava_single_credential("primary_credential")
+                .expect("Missing credential")
+                .verify_password(pw.as_str()));
+
+            let ale = AuditListEvent {
+                since: 0,
+                until: std::i64::MAX,
+                target: Some(UUID_ADMIN.clone()),
+            };
+            let records = qs_read.audit_list(au, &ale).expect("audit list failed");
+            assert!(records.len() == 1);
+            assert!(records[0].identity == STR_UUID_LOCAL_ADMIN);
+            assert!(records[0].changes.iter().all(|c| match c {
+                ProtoModify::Present(_, v) => v == AUDIT_REDACTED,
+                _ => true,
+            }));
+        })
+    }
+
+    #[test]
+    fn test_idm_session_expire() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
+            let sid = init_admin_authsession_sid(idms, au);
+            let mut idms_write = idms.write();
+            assert!(idms_write.is_sessionid_present(&sid));
+            // Expire like we are currently "now". Should not affect our session.
+            idms_write.expire_auth_sessions(Duration::from_secs(TEST_CURRENT_TIME));
+            assert!(idms_write.is_sessionid_present(&sid));
+            // Expire as though we are in the future.
+            idms_write.expire_auth_sessions(Duration::from_secs(TEST_CURRENT_EXPIRE));
+            assert!(!idms_write.is_sessionid_present(&sid));
+            assert!(idms_write.commit().is_ok());
+            let idms_write = idms.write();
+            assert!(!idms_write.is_sessionid_present(&sid));
+        })
+    }
+
+    #[test]
+    fn test_idm_token_keys_persist() {
+        run_idm_test!(|_qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            let ct = Duration::from_secs(TEST_CURRENT_TIME);
+            let grace = Duration::from_secs(AUTH_TOKEN_LIFETIME);
+            let uat = UserAuthToken {
+                issued_at: TEST_CURRENT_TIME,
+                expiry: TEST_CURRENT_TIME + AUTH_TOKEN_LIFETIME * 4,
+                auth_time: TEST_CURRENT_TIME,
+                sessionid: Uuid::new_v4(),
+                name: "admin".to_string(),
+                spn: "admin@localhost".to_string(),
+                displayname: "admin".to_string(),
+                uuid: "00000000-0000-0000-0000-000000000000".to_string(),
+                application: None,
+                groups: Vec::new(),
+                claims: Vec::new(),
+                must_change_password: false,
+                anonymous: false,
+                api_token: None,
+                read_only: false,
+            };
+
+            // The first load generates and stores a key.
+            let mut idms_prox_write = idms.proxy_write();
+            let keys = idms_prox_write
+                .get_or_create_token_keys(au, grace)
+                .expect("Failed to create keys");
+            assert!(idms_prox_write.commit(au).is_ok());
+            let token = keys.sign_uat(&uat).expect("Failed to sign");
+
+            // Later loads find the same key.
+            let mut idms_prox_write = idms.proxy_write();
+            let keys = idms_prox_write
+                .get_or_create_token_keys(au, grace)
+                .expect("Failed to load keys");
+            assert!(keys.verify_uat(token.as_str(), ct).is_some());
+
+            // A rotation keeps the old key for the grace period only.
+            let keys = idms_prox_write
+                .rotate_token_keys(au, ct, grace)
+                .expect("Failed to rotate keys");
+            assert!(idms_prox_write.commit(au).is_ok());
+            assert!(keys.verify_uat(token.as_str(), ct).is_some());
+
+            let mut idms_prox_write = idms.proxy_write();
+            let keys = idms_prox_write
+                .get_or_create_token_keys(au, grace)
+                .expect("Failed to load keys");
+            assert!(keys.verify_uat(token.as_str(), ct).is_some());
+            assert!(keys.verify_uat(token.as_str(), ct + grace).is_none());
+        })
+    }
+
+    #[test]
+    fn test_idm_session_logout_revoke() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
+            let ct = Duration::from_secs(TEST_CURRENT_TIME);
+            let uat_a = init_admin_uat(idms, au, ct);
+            let uat_b = init_admin_uat(idms, au, ct + Duration::from_secs(1));
+
+            assert!(idms.is_session_active(&uat_a.sessionid, ct));
+            assert!(idms.is_session_active(&uat_b.sessionid, ct));
+            assert!(!idms.is_session_active(&uat_a.sessionid, Duration::from_secs(uat_a.expiry)));
+            let sessions = idms
+                .list_sessions(au, &uat_a, &UUID_ADMIN, ct)
+                .expect("Failed to list sessions");
+            assert!(sessions.len() == 2);
+
+            // Someone outside idm_admins can't see or end admin's sessions.
+            let mut uat_other = uat_a.clone();
+            uat_other.uuid = "00000000-0000-0000-0000-ffffffffffff".to_string();
+            uat_other.groups = Vec::new();
+            assert!(
+                idms.list_sessions(au, &uat_other, &UUID_ADMIN, ct)
+                    == Err(OperationError::AccessDenied)
+            );
+            let mut idms_write = idms.write();
+            assert!(
+                idms_write.revoke_session(au, &uat_other, &uat_b.sessionid)
+                    == Err(OperationError::AccessDenied)
+            );
+
+            // Logout only ends the session it is made with.
+            assert!(idms_write.logout(au, &uat_a).is_ok());
+            assert!(idms_write.logout(au, &uat_a) == Err(OperationError::NotAuthenticated));
+            idms_write.commit().expect("Must not fail");
+            assert!(!idms.is_session_active(&uat_a.sessionid, ct));
+            assert!(idms.is_session_active(&uat_b.sessionid, ct));
+
+            // And the other can be revoked.
+            let mut idms_write = idms.write();
+            assert!(idms_write
+                .revoke_session(au, &uat_a, &uat_b.sessionid)
+                .is_ok());
+            assert!(
+                idms_write.revoke_session(au, &uat_a, &uat_b.sessionid)
+                    == Err(OperationError::NoMatchingEntries)
+            );
+            idms_write.commit().expect("Must not fail");
+            assert!(!idms.is_session_active(&uat_b.sessionid, ct));
+            assert!(idms
+                .list_sessions(au, &uat_a, &UUID_ADMIN, ct)
+                .expect("Failed to list sessions")
+                .is_empty());
+        })
+    }
+
+    #[test]
+    fn test_idm_session_active_expire() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
+            let uat = init_admin_uat(idms, au, Duration::from_secs(TEST_CURRENT_TIME));
+
+            let before = Duration::from_secs(uat.expiry - 1);
+
+            let mut idms_write = idms.write();
+            idms_write.expire_auth_sessions(before);
+            idms_write.commit().expect("Must not fail");
+            assert!(idms.is_session_active(&uat.sessionid, before));
+
+            // Once expired, the session is cleaned up with the auth sessions.
+            let mut idms_write = idms.write();
+            idms_write.expire_auth_sessions(Duration::from_secs(uat.expiry));
+            idms_write.commit().expect("Must not fail");
+            assert!(!idms.is_session_active(&uat.sessionid, before));
+        })
+    }
+
+    #[test]
+    fn test_idm_totp_enroll_and_auth() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
+            let ct = Duration::from_secs(TEST_CURRENT_TIME);
+            let uat = init_admin_uat(idms, au, ct);
+            let target = Uuid::parse_str(uat.uuid.as_str()).expect("Invalid uuid");
+
+            // Generate, and confirm the secret with a code from it.
+            let mut idms_write = idms.write();
+            let secret = idms_write
+                .generate_account_totp(au, &uat)
+                .expect("Failed to generate totp");
+            let totp = TOTP::new(secret.secret.clone(), secret.step, TOTPAlgo::Sha1);
+            let code = totp
+                .do_totp_duration_from_epoch(&ct)
+                .expect("Failed to generate code");
+
+            // A wrong code leaves the secret pending.
+            match idms_write.verify_account_totp(au, &uat, (code + 1) % 1_000_000, ct) {
+                Err(OperationError::InvalidTOTP) => {}
+                _ => panic!(),
+            };
+            let (totp_v, step) = idms_write
+                .verify_account_totp(au, &uat, code, ct)
+                .expect("Failed to verify totp");
+
+            let mut idms_prox_write = idms.proxy_write();
+            let cred_uuid = idms_prox_write
+                .set_account_totp(au, &target, totp_v)
+                .expect("Failed to set totp");
+            idms_prox_write.commit(au).expect("Must not fail");
+            idms_write.set_totp_last_step(cred_uuid, step);
+            idms_write.commit().expect("Must not fail");
+
+            // The password alone now only continues to the totp.
+            let ct_next = ct + Duration::from_secs(TOTP_DEFAULT_STEP);
+            let mut idms_write = idms.write();
+            let sid = match idms_write.auth(au, &AuthEvent::named_init("admin"), ct_next) {
+                Ok(AuthResult {
+                    sessionid,
+                    state: AuthState::Continue(allowed),
+                }) => {
+                    assert!(allowed == vec![AuthAllowed::Password]);
+                    sessionid
+                }
+                _ => panic!(),
+            };
+            let pw_step = AuthEvent::cred_step_password(sid, TEST_PASSWORD);
+            match idms_write.auth(au, &pw_step, ct_next).map(|ar| ar.state) {
+                Ok(AuthState::Continue(allowed)) => assert!(allowed == vec![AuthAllowed::TOTP]),
+                _ => panic!(),
+            };
+            // The code used to enroll can't be used to authenticate.
+            let totp_step = AuthEvent::cred_step_totp(sid, code);
+            match idms_write.auth(au, &totp_step, ct_next).map(|ar| ar.state) {
+                Ok(AuthState::Denied(_, _)) => {}
+                _ => panic!(),
+            };
+
+            // But a later code can.
+            let ct_later = ct_next + Duration::from_secs(TOTP_DEFAULT_STEP);
+            let sid = match idms_write.auth(au, &AuthEvent::named_init("admin"), ct_later) {
+                Ok(ar) => ar.sessionid,
+                Err(_) => panic!(),
+            };
+            let pw_step = AuthEvent::cred_step_password(sid, TEST_PASSWORD);
+            match idms_write.auth(au, &pw_step, ct_later).map(|ar| ar.state) {
+                Ok(AuthState::Continue(_)) => {}
+                _ => panic!(),
+            };
+            let code_later = totp
+                .do_totp_duration_from_epoch(&ct_later)
+                .expect("Failed to generate code");
+            let totp_step = AuthEvent::cred_step_totp(sid, code_later);
+            match idms_write.auth(au, &totp_step, ct_later).map(|ar| ar.state) {
+                Ok(AuthState::Success(_)) => {}
+                _ => panic!(),
+            };
+            idms_write.commit().expect("Must not fail");
+        })
+    }
+
+    #[test]
+    fn test_idm_webauthn_register_and_auth() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
+            let ct = Duration::from_secs(TEST_CURRENT_TIME);
+            let uat = init_admin_uat(idms, au, ct);
+            let target = Uuid::parse_str(uat.uuid.as_str()).expect("Invalid uuid");
+            let mut token = SoftToken::new();
+
+            let mut register = |name: &str, token: &SoftToken| {
+                let mut idms_write = idms.write();
+                let chal = idms_write
+                    .generate_account_webauthn(au, &uat)
+                    .expect("Failed to generate");
+                let wan = idms_write.verify_account_webauthn(
+                    au,
+                    &uat,
+                    name,
+                    &token.register(&chal, "https://localhost"),
+                );
+                idms_write.commit().expect("Must not fail");
+                let mut idms_prox_write = idms.proxy_write();
+                let r = wan.and_then(|wan| idms_prox_write.add_account_webauthn(au, &target, wan));
+                idms_prox_write.commit(au).expect("Must not fail");
+                r
+            };
+            assert!(register("yubikey", &token).is_ok());
+            // Names are unique, and a token can't be registered twice.
+            assert!(
+                register("yubikey", &SoftToken::new())
+                    == Err(OperationError::InvalidWebauthn("token name already in use"))
+            );
+            assert!(
+                register("again", &token)
+                    == Err(OperationError::InvalidWebauthn("token already registered"))
+            );
+            let tokens = idms
+                .list_account_webauthn(au, &uat)
+                .expect("Failed to list");
+            assert!(tokens.len() == 1);
+            assert!(tokens[0].name == "yubikey");
+
+            // Now the password is followed by the token.
+            let ct_next = ct + Duration::from_secs(1);
+            let mut idms_write = idms.write();
+            let sid = match idms_write.auth(au, &AuthEvent::named_init("admin"), ct_next) {
+                Ok(ar) => ar.sessionid,
+                Err(_) => panic!(),
+            };
+            let pw_step = AuthEvent::cred_step_password(sid, TEST_PASSWORD);
+            let chal = match idms_write.auth(au, &pw_step, ct_next).map(|ar| ar.state) {
+                Ok(AuthState::Continue(allowed)) => match allowed.as_slice() {
+                    [AuthAllowed::Webauthn(chal)] => chal.clone(),
+                    _ => panic!(),
+                },
+                _ => panic!(),
+            };
+            let wan_step =
+                AuthEvent::cred_step_webauthn(sid, token.sign(&chal, "https://localhost"));
+            match idms_write.auth(au, &wan_step, ct_next).map(|ar| ar.state) {
+                Ok(AuthState::Success(_)) => {}
+                _ => panic!(),
+            };
+            idms_write.commit().expect("Must not fail");
+            assert!(
+                idms.list_account_webauthn(au, &uat)
+                    .expect("Failed to list")[0]
+                    .counter
+                    == 1
+            );
+
+            // Once removed, the password alone is enough again.
+            let mut idms_prox_write = idms.proxy_write();
+            assert!(
+                idms_prox_write.remove_account_webauthn(au, &target, "missing")
+                    == Err(OperationError::NoMatchingEntries)
+            );
+            assert!(idms_prox_write
+                .remove_account_webauthn(au, &target, "yubikey")
+                .is_ok());
+            idms_prox_write.commit(au).expect("Must not fail");
+            assert!(idms
+                .list_account_webauthn(au, &uat)
+                .expect("Failed to list")
+                .is_empty());
+            init_admin_uat(idms, au, ct_next + Duration::from_secs(1));
+        })
+    }
+
+    // Authenticate as admin with the password and then the token.
+    fn admin_webauthn_auth(
+        idms: &IdmServer,
+        au: &mut AuditScope,
+        token: &mut SoftToken,
+        ct: Duration,
+    ) -> AuthState {
+        let mut idms_write = idms.write();
+        let sid = match idms_write.auth(au, &AuthEvent::named_init("admin"), ct) {
+            Ok(ar) => ar.sessionid,
+            Err(_) => panic!(),
+        };
+        let pw_step = AuthEvent::cred_step_password(sid, TEST_PASSWORD);
+        let chal = match idms_write.auth(au, &pw_step, ct).map(|ar| ar.state) {
+            Ok(AuthState::Continue(allowed)) => match allowed.as_slice() {
+                [AuthAllowed::Webauthn(chal)] => chal.clone(),
+                _ => panic!(),
+            },
+            _ => panic!(),
+        };
+        let wan_step = AuthEvent::cred_step_webauthn(sid, token.sign(&chal, "https://localhost"));
+        let state = idms_write
+            .auth(au, &wan_step, ct)
+            .map(|ar| ar.state)
+            .expect("Failed to auth");
+        idms_write.commit().expect("Must not fail");
+        state
+    }
+
+    #[test]
+    fn test_idm_webauthn_counter_persisted() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
+            let ct = Duration::from_secs(TEST_CURRENT_TIME);
+            let uat = init_admin_uat(idms, au, ct);
+            let target = Uuid::parse_str(uat.uuid.as_str()).expect("Invalid uuid");
+            let mut token = SoftToken::new();
+
+            let mut idms_write = idms.write();
+            let chal = idms_write
+                .generate_account_webauthn(au, &uat)
+                .expect("Failed to generate");
+            let wan = idms_write
+                .verify_account_webauthn(
+                    au,
+                    &uat,
+                    "yubikey",
+                    &token.register(&chal, "https://localhost"),
+                )
+                .expect("Failed to verify");
+            idms_write.commit().expect("Must not fail");
+            let mut idms_prox_write = idms.proxy_write();
+            idms_prox_write
+                .add_account_webauthn(au, &target, wan)
+                .expect("Failed to add token");
+            idms_prox_write.commit(au).expect("Must not fail");
+
+            // A clone taken now is behind once the token has been used.
+            let mut clone = token.clone();
+            let ct_next = ct + Duration::from_secs(1);
+            match admin_webauthn_auth(idms, au, &mut token, ct_next) {
+                AuthState::Success(_) => {}
+                _ => panic!(),
+            };
+
+            // Nothing of the counter is kept in memory, so a server started
+            // over the same database still refuses the clone.
+            let idms_reloaded = IdmServer::new(qs.clone(), [0; 4]);
+            assert!(
+                idms_reloaded
+                    .list_account_webauthn(au, &uat)
+                    .expect("Failed to list")[0]
+                    .counter
+                    == 1
+            );
+            match admin_webauthn_auth(&idms_reloaded, au, &mut clone, ct_next) {
+                AuthState::Denied(AuthDenyReason::Failed, _) => {}
+                _ => panic!(),
+            };
+            // While the token itself goes on.
+            match admin_webauthn_auth(&idms_reloaded, au, &mut token, ct_next) {
+                AuthState::Success(_) => {}
+                _ => panic!(),
+            };
+        })
+    }
+
+    // Enroll a totp with admin, and then generate backup codes for it.
+    fn init_admin_backup_codes(
+        idms: &IdmServer,
+        au: &mut AuditScope,
+        ct: Duration,
+    ) -> (UserAuthToken, Vec<String>) {
+        let uat = init_admin_uat(idms, au, ct);
+        let target = Uuid::parse_str(uat.uuid.as_str()).expect("Invalid uuid");
+
+        let mut idms_prox_write = idms.proxy_write();
+        // Without a second factor there is nothing for the codes to replace.
+        match idms_prox_write.generate_backup_codes(au, &target) {
+            Err(OperationError::InvalidAccountState(_)) => {}
+            _ => panic!(),
+        };
+        idms_prox_write
+            .set_account_totp(au, &target, TOTP::generate_secure(TOTP_DEFAULT_STEP))
+            .expect("Failed to set totp");
+        let codes = idms_prox_write
+            .generate_backup_codes(au, &target)
+            .expect("Failed to generate backup codes");
+        idms_prox_write.commit(au).expect("Must not fail");
+        (uat, codes)
+    }
+
+    // Begin an auth as admin, and provide the password.
+    fn init_admin_mfa_sid(idms: &IdmServer, au: &mut AuditScope, ct: Duration) -> Uuid {
+        let mut idms_write = idms.write();
+        let sid = match idms_write.auth(au, &AuthEvent::named_init("admin"), ct) {
+            Ok(ar) => ar.sessionid,
+            Err(_) => panic!(),
+        };
+        let pw_step = AuthEvent::cred_step_password(sid, TEST_PASSWORD);
+        match idms_write.auth(au, &pw_step, ct).map(|ar| ar.state) {
+            Ok(AuthState::Continue(allowed)) => assert!(allowed.contains(&AuthAllowed::BackupCode)),
+            _ => panic!(),
+        };
+        idms_write.commit().expect("Must not fail");
+        sid
+    }
+
+    #[test]
+    fn test_idm_backup_code_double_spend() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
+            let ct = Duration::from_secs(TEST_CURRENT_TIME);
+            let (uat, codes) = init_admin_backup_codes(idms, au, ct);
+            assert!(codes.len() == BACKUP_CODE_COUNT);
+
+            // Both sessions begin while the code is unspent.
+            let ct_a = ct + Duration::from_secs(1);
+            let ct_b = ct + Duration::from_secs(2);
+            let sid_a = init_admin_mfa_sid(idms, au, ct_a);
+            let sid_b = init_admin_mfa_sid(idms, au, ct_b);
+
+            let mut idms_write = idms.write();
+            let code_step = AuthEvent::cred_step_backup_code(sid_a, codes[0].as_str());
+            match idms_write.auth(au, &code_step, ct_a).map(|ar| ar.state) {
+                Ok(AuthState::Success(_)) => {}
+                _ => panic!(),
+            };
+            // The second session is denied, even though the code was valid
+            // when it began.
+            let code_step = AuthEvent::cred_step_backup_code(sid_b, codes[0].as_str());
+            match idms_write.auth(au, &code_step, ct_b).map(|ar| ar.state) {
+                Ok(AuthState::Denied(_, _)) => {}
+                _ => panic!(),
+            };
+            idms_write.commit().expect("Must not fail");
+
+            let status = idms
+                .credential_status(au, &uat)
+                .expect("Failed to get status");
+            assert!(status.totp);
+            assert!(status.backup_codes_remaining == (BACKUP_CODE_COUNT - 1) as u32);
+        })
+    }
+
+    #[test]
+    fn test_idm_backup_code_exhaustion() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
+            let ct = Duration::from_secs(TEST_CURRENT_TIME);
+            let (uat, codes) = init_admin_backup_codes(idms, au, ct);
+
+            for (i, code) in codes.iter().enumerate() {
+                let ct_i = ct + Duration::from_secs(1 + i as u64);
+                let sid = init_admin_mfa_sid(idms, au, ct_i);
+                let mut idms_write = idms.write();
+                let code_step = AuthEvent::cred_step_backup_code(sid, code.as_str());
+                match idms_write.auth(au, &code_step, ct_i).map(|ar| ar.state) {
+                    Ok(AuthState::Success(_)) => {}
+                    _ => panic!(),
+                };
+                idms_write.commit().expect("Must not fail");
+            }
+
+            let status = idms
+                .credential_status(au, &uat)
+                .expect("Failed to get status");
+            assert!(status.backup_codes_remaining == 0);
+
+            // With none left they are no longer offered, and are refused.
+            let ct_end = ct + Duration::from_secs(100);
+            let mut idms_write = idms.write();
+            let sid = match idms_write.auth(au, &AuthEvent::named_init("admin"), ct_end) {
+                Ok(AuthResult {
+                    sessionid,
+                    state: AuthState::Continue(allowed),
+                }) => {
+                    assert!(allowed == vec![AuthAllowed::Password]);
+                    sessionid
+                }
+                _ => panic!(),
+            };
+            let pw_step = AuthEvent::cred_step_password(sid, TEST_PASSWORD);
+            match idms_write.auth(au, &pw_step, ct_end).map(|ar| ar.state) {
+                Ok(AuthState::Continue(allowed)) => assert!(allowed == vec![AuthAllowed::TOTP]),
+                _ => panic!(),
+            };
+            let code_step = AuthEvent::cred_step_backup_code(sid, codes[0].as_str());
+            match idms_write.auth(au, &code_step, ct_end).map(|ar| ar.state) {
+                Ok(AuthState::Denied(_, _)) => {}
+                _ => panic!(),
+            };
+            idms_write.commit().expect("Must not fail");
+        })
+    }
+
+    #[test]
+    fn test_idm_credential_policy() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
+            let ct = Duration::from_secs(TEST_CURRENT_TIME);
+            let uat = init_admin_uat(idms, au, ct);
+            let target = Uuid::parse_str(uat.uuid.as_str()).expect("Invalid uuid");
+
+            // Without a second factor, the policy can't require one.
+            let mut idms_prox_write = idms.proxy_write();
+            match idms_prox_write.set_account_credential_policy(
+                au,
+                &target,
+                Some(Policy::PasswordMFA),
+            ) {
+                Err(OperationError::InvalidAccountState(_)) => {}
+                _ => panic!(),
+            };
+            idms_prox_write
+                .set_account_totp(au, &target, TOTP::generate_secure(TOTP_DEFAULT_STEP))
+                .expect("Failed to set totp");
+            // The totp can be enrolled but not yet required.
+            idms_prox_write
+                .set_account_credential_policy(au, &target, Some(Policy::PasswordOnly))
+                .expect("Failed to set policy");
+            idms_prox_write.commit(au).expect("Must not fail");
+
+            let status = idms
+                .credential_status(au, &uat)
+                .expect("Failed to get status");
+            assert!(status.totp);
+            assert!(status.policy == Some(CredentialPolicy::PasswordOnly));
+            // So the password alone is enough.
+            init_admin_uat(idms, au, ct + Duration::from_secs(1));
+
+            // Once the policy follows the factors again, the totp is needed.
+            let mut idms_prox_write = idms.proxy_write();
+            idms_prox_write
+                .set_account_credential_policy(au, &target, None)
+                .expect("Failed to set policy");
+            idms_prox_write.commit(au).expect("Must not fail");
+            let status = idms
+                .credential_status(au, &uat)
+                .expect("Failed to get status");
+            assert!(status.policy == Some(CredentialPolicy::PasswordMFA));
+            let ct_next = ct + Duration::from_secs(2);
+            let mut idms_write = idms.write();
+            let sid = match idms_write.auth(au, &AuthEvent::named_init("admin"), ct_next) {
+                Ok(ar) => ar.sessionid,
+                Err(_) => panic!(),
+            };
+            let pw_step = AuthEvent::cred_step_password(sid, TEST_PASSWORD);
+            match idms_write.auth(au, &pw_step, ct_next).map(|ar| ar.state) {
+                Ok(AuthState::Continue(allowed)) => assert!(allowed == vec![AuthAllowed::TOTP]),
+                _ => panic!(),
+            };
+            idms_write.commit().expect("Must not fail");
+        })
+    }
+
+    // Create the claims admin may request through idm_admins: sudo, which
+    // requires multi-factor credentials and lasts 300 seconds, and audit,
+    // which doesn't. Nobody may request other.
+    fn init_claims(au: &mut AuditScope, qs: &QueryServer) {
+        let e_sudo: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+            r#"{
+            "valid": null,
+            "state": null,
+            "attrs": {
+                "class": ["object", "claim"],
+                "name": ["sudo"],
+                "claim_group": ["00000000-0000-0000-0000-000000000001"],
+                "claim_require_mfa": ["true"],
+                "claim_lifetime": ["300"]
+            }
+        }"#,
+        );
+        let e_audit: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+            r#"{
+            "valid": null,
+            "state": null,
+            "attrs": {
+                "class": ["object", "claim"],
+                "name": ["audit"],
+                "claim_group": ["00000000-0000-0000-0000-000000000001"]
+            }
+        }"#,
+        );
+        let e_group: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+            r#"{
+            "valid": null,
+            "state": null,
+            "attrs": {
+                "class": ["object", "group"],
+                "name": ["claim_testgroup"],
+                "uuid": ["a7b3c9d2-4e5f-4a6b-8c7d-9e0f1a2b3c4d"]
+            }
+        }"#,
+        );
+        let e_other: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+            r#"{
+            "valid": null,
+            "state": null,
+            "attrs": {
+                "class": ["object", "claim"],
+                "name": ["other"],
+                "claim_group": ["a7b3c9d2-4e5f-4a6b-8c7d-9e0f1a2b3c4d"]
+            }
+        }"#,
+        );
+        let mut qs_write = qs.write();
+        let ce = CreateEvent::new_internal(vec![e_sudo, e_audit, e_group, e_other]);
+        assert!(qs_write.create(au, &ce).is_ok());
+        qs_write.commit(au).expect("Must not fail");
+    }
+
+    #[test]
+    fn test_idm_claims_mfa() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
+            init_claims(au, qs);
+            let ct = Duration::from_secs(TEST_CURRENT_TIME);
+            let uat = init_admin_uat(idms, au, ct);
+            let target = Uuid::parse_str(uat.uuid.as_str()).expect("Invalid uuid");
+
+            let totp = TOTP::generate_secure(TOTP_DEFAULT_STEP);
+            let mut idms_prox_write = idms.proxy_write();
+            idms_prox_write
+                .set_account_totp(au, &target, totp.clone())
+                .expect("Failed to set totp");
+            idms_prox_write.commit(au).expect("Must not fail");
+
+            let ct_next = ct + Duration::from_secs(TOTP_DEFAULT_STEP);
+            let mut idms_write = idms.write();
+            let sid = match idms_write.auth(au, &AuthEvent::named_init("admin"), ct_next) {
+                Ok(ar) => ar.sessionid,
+                Err(_) => panic!(),
+            };
+            let claim_step = AuthEvent::request_claims(sid, vec!["sudo", "audit", "other"]);
+            match idms_write.auth(au, &claim_step, ct_next).map(|ar| ar.state) {
+                Ok(AuthState::Continue(allowed)) => assert!(allowed == vec![AuthAllowed::Password]),
+                _ => panic!(),
+            };
+            let pw_step = AuthEvent::cred_step_password(sid, TEST_PASSWORD);
+            match idms_write.auth(au, &pw_step, ct_next).map(|ar| ar.state) {
+                Ok(AuthState::Continue(allowed)) => assert!(allowed == vec![AuthAllowed::TOTP]),
+                _ => panic!(),
+            };
+            let code = totp
+                .do_totp_duration_from_epoch(&ct_next)
+                .expect("Failed to generate code");
+            let totp_step = AuthEvent::cred_step_totp(sid, code);
+            let uat = match idms_write.auth(au, &totp_step, ct_next).map(|ar| ar.state) {
+                Ok(AuthState::Success(uat)) => uat,
+                _ => panic!(),
+            };
+            idms_write.commit().expect("Must not fail");
+
+            // The claim of another group isn't granted, and sudo expires
+            // before the session does.
+            let mut claims: Vec<(&str, Option<u64>)> = uat
+                .claims
+                .iter()
+                .map(|c| (c.name.as_str(), c.expiry))
+                .collect();
+            claims.sort();
+            assert!(claims == vec![("audit", None), ("sudo", Some(ct_next.as_secs() + 300))]);
+            assert!(uat.expiry > ct_next.as_secs() + 300);
+        })
+    }
+
+    #[test]
+    fn test_idm_claims_password_only() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
+            init_claims(au, qs);
+            let ct = Duration::from_secs(TEST_CURRENT_TIME);
+
+            let mut idms_write = idms.write();
+            let sid = match idms_write.auth(au, &AuthEvent::named_init("admin"), ct) {
+                Ok(ar) => ar.sessionid,
+                Err(_) => panic!(),
+            };
+            let claim_step = AuthEvent::request_claims(sid, vec!["sudo", "audit"]);
+            match idms_write.auth(au, &claim_step, ct).map(|ar| ar.state) {
+                Ok(AuthState::Continue(_)) => {}
+                _ => panic!(),
+            };
+            let pw_step = AuthEvent::cred_step_password(sid, TEST_PASSWORD);
+            let uat = match idms_write.auth(au, &pw_step, ct).map(|ar| ar.state) {
+                Ok(AuthState::Success(uat)) => uat,
+                _ => panic!(),
+            };
+            // The session succeeds, but without sudo.
+            let names: Vec<&str> = uat.claims.iter().map(|c| c.name.as_str()).collect();
+            assert!(names == vec!["audit"]);
+
+            // Once the session is finished, claims can't be requested.
+            let claim_step = AuthEvent::request_claims(sid, vec!["sudo"]);
+            assert!(idms_write.auth(au, &claim_step, ct).is_err());
+            idms_write.commit().expect("Must not fail");
+        })
+    }
+
+    // Authenticate admin with only a password, returning the final state.
+    fn admin_password_auth(
+        idms: &IdmServer,
+        au: &mut AuditScope,
+        pw: &str,
+        ct: Duration,
+    ) -> AuthState {
+        let mut idms_write = idms.write();
+        let sid = match idms_write.auth(au, &AuthEvent::named_init("admin"), ct) {
+            Ok(ar) => ar.sessionid,
+            Err(_) => panic!(),
+        };
+        let state = match idms_write.auth(au, &AuthEvent::cred_step_password(sid, pw), ct) {
+            Ok(ar) => ar.state,
+            Err(_) => panic!(),
+        };
+        idms_write.commit().expect("Must not fail");
+        state
+    }
 
-    use crate::audit::AuditScope;
-    use crate::idm::server::IdmServer;
-    use crate::server::QueryServer;
-    use std::time::Duration;
-    use uuid::Uuid;
+    #[test]
+    fn test_idm_self_password_change() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
+            let ct = Duration::from_secs(TEST_CURRENT_TIME);
+            let uat_a = init_admin_uat(idms, au, ct);
+            let uat_b = init_admin_uat(idms, au, ct + Duration::from_secs(1));
+            let new_pw = "a new and much longer password";
+            // Long after auth, so the current password is needed.
+            let ct_late = ct + Duration::from_secs(REAUTH_WINDOW + 10);
 
-    static TEST_PASSWORD: &'static str = "ntaoeuntnaoeuhraohuercahu😍";
-    static TEST_PASSWORD_INC: &'static str = "ntaoentu nkrcgaeunhibwmwmqj;k wqjbkx ";
-    static TEST_CURRENT_TIME: u64 = 6000;
-    static TEST_CURRENT_EXPIRE: u64 = TEST_CURRENT_TIME + AUTH_SESSION_TIMEOUT + 1;
+            let mut idms_prox_write = idms.proxy_write();
+            assert!(
+                idms_prox_write.self_set_account_password(
+                    au,
+                    &uat_a,
+                    Some(TEST_PASSWORD_INC),
+                    new_pw,
+                    ct_late
+                ) == Err(OperationError::IncorrectPassword)
+            );
+            assert!(
+                idms_prox_write.self_set_account_password(au, &uat_a, None, new_pw, ct_late)
+                    == Err(OperationError::ReauthRequired)
+            );
+            // A weak password is refused even with the right current one.
+            match idms_prox_write.self_set_account_password(
+                au,
+                &uat_a,
+                Some(TEST_PASSWORD),
+                "short",
+                ct_late,
+            ) {
+                Err(OperationError::PasswordQuality(feedback)) => {
+                    assert!(feedback.contains(&PasswordFeedback::TooShort(PW_MIN_LENGTH as u32)))
+                }
+                _ => panic!(),
+            };
+            let target = idms_prox_write
+                .self_set_account_password(au, &uat_a, Some(TEST_PASSWORD), new_pw, ct_late)
+                .expect("Failed to change password");
+            assert!(target == *UUID_ADMIN);
+            idms_prox_write.commit(au).expect("Must not fail");
+
+            // Every other session is ended.
+            let mut idms_write = idms.write();
+            assert!(idms_write.end_account_sessions(au, &target, Some(&uat_a.sessionid)) == 1);
+            idms_write.commit().expect("Must not fail");
+            assert!(idms.is_session_active(&uat_a.sessionid, ct));
+            assert!(!idms.is_session_active(&uat_b.sessionid, ct));
+
+            // Only the new password works now.
+            let ct_next = ct + Duration::from_secs(2);
+            match admin_password_auth(idms, au, TEST_PASSWORD, ct_next) {
+                AuthState::Denied(_, _) => {}
+                _ => panic!(),
+            };
+            let ct_next = ct + Duration::from_secs(3);
+            let uat = match admin_password_auth(idms, au, new_pw, ct_next) {
+                AuthState::Success(uat) => uat,
+                _ => panic!(),
+            };
+
+            // Having just authenticated, the current password isn't needed.
+            let mut idms_prox_write = idms.proxy_write();
+            assert!(idms_prox_write
+                .self_set_account_password(au, &uat, None, TEST_PASSWORD, ct_next)
+                .is_ok());
+            idms_prox_write.commit(au).expect("Must not fail");
+        })
+    }
 
     #[test]
-    fn test_idm_anonymous_auth() {
-        run_idm_test!(|_qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
-            let sid = {
-                // Start and test anonymous auth.
-                let mut idms_write = idms.write();
-                // Send the initial auth event for initialising the session
-                let anon_init = AuthEvent::anonymous_init();
-                // Expect success
-                let r1 = idms_write.auth(au, &anon_init, Duration::from_secs(TEST_CURRENT_TIME));
-                /* Some weird lifetime shit happens here ... */
-                // audit_log!(au, "r1 ==> {:?}", r1);
+    fn test_idm_reauth() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
+            let ct = Duration::from_secs(TEST_CURRENT_TIME);
+            let uat = init_admin_uat(idms, au, ct);
+            assert!(uat.auth_time == TEST_CURRENT_TIME);
+            let ct_late = ct + Duration::from_secs(REAUTH_WINDOW + 10);
 
-                let sid = match r1 {
-                    Ok(ar) => {
-                        let AuthResult { sessionid, state } = ar;
-                        match state {
-                            AuthState::Continue(mut conts) => {
-                                // Should only be one auth mech
-                                assert!(conts.len() == 1);
-                                // And it should be anonymous
-                                let m = conts.pop().expect("Should not fail");
-                                assert!(m == AuthAllowed::Anonymous);
-                            }
-                            _ => {
-                                error!(
-                                    "A critical error has occured! We have a non-continue result!"
-                                );
-                                panic!();
-                            }
-                        };
-                        // Now pass back the sessionid, we are good to continue.
-                        sessionid
-                    }
-                    Err(e) => {
-                        // Should not occur!
-                        error!("A critical error has occured! {:?}", e);
-                        panic!();
+            assert!(idms
+                .check_reauth(au, &uat, ProtectedOperation::CredentialChange, ct)
+                .is_ok());
+            assert!(
+                idms.check_reauth(au, &uat, ProtectedOperation::CredentialChange, ct_late)
+                    == Err(OperationError::ReauthRequired)
+            );
+
+            // Reauth the same session, with the same password.
+            let mut idms_write = idms.write();
+            let sid = match idms_write.auth(au, &AuthEvent::reauth(&uat), ct_late) {
+                Ok(AuthResult {
+                    sessionid,
+                    state: AuthState::Continue(_),
+                }) => sessionid,
+                _ => panic!(),
+            };
+            let uat_re = match idms_write
+                .auth(
+                    au,
+                    &AuthEvent::cred_step_password(sid, TEST_PASSWORD),
+                    ct_late,
+                )
+                .map(|ar| ar.state)
+            {
+                Ok(AuthState::Success(uat_re)) => uat_re,
+                _ => panic!(),
+            };
+            idms_write.commit().expect("Must not fail");
+
+            // The session keeps its id and lifetime, but is now recent.
+            assert!(uat_re.sessionid == uat.sessionid);
+            assert!(uat_re.issued_at == uat.issued_at);
+            assert!(uat_re.expiry == uat.expiry);
+            assert!(uat_re.auth_time == ct_late.as_secs());
+            assert!(idms
+                .check_reauth(au, &uat_re, ProtectedOperation::CredentialChange, ct_late)
+                .is_ok());
+            assert!(idms.is_session_active(&uat.sessionid, ct_late));
+
+            // A session that has ended can't be reauthed.
+            let mut idms_write = idms.write();
+            let mut uat_gone = uat.clone();
+            uat_gone.sessionid = Uuid::new_v4();
+            assert!(
+                idms_write
+                    .auth(au, &AuthEvent::reauth(&uat_gone), ct_late)
+                    .err()
+                    == Some(OperationError::NotAuthenticated)
+            );
+            idms_write.commit().expect("Must not fail");
+        })
+    }
+
+    #[test]
+    fn test_idm_admin_password_reset() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
+            let ct = Duration::from_secs(TEST_CURRENT_TIME);
+            let uat = init_admin_uat(idms, au, ct);
+            let reset_pw = "a temporary password";
+
+            // Someone without the access to do so can't reset a password.
+            let mut uat_other = uat.clone();
+            uat_other.uuid = "00000000-0000-0000-0000-ffffffffffff".to_string();
+            uat_other.groups = Vec::new();
+            let mut idms_prox_write = idms.proxy_write();
+            assert!(idms_prox_write
+                .admin_set_account_password(au, &uat_other, "admin", reset_pw, true)
+                .is_err());
+            let target = idms_prox_write
+                .admin_set_account_password(au, &uat, "admin", reset_pw, true)
+                .expect("Failed to reset password");
+            assert!(target == *UUID_ADMIN);
+            idms_prox_write.commit(au).expect("Must not fail");
+
+            // The token now says the password must be changed.
+            let ct_next = ct + Duration::from_secs(1);
+            let uat = match admin_password_auth(idms, au, reset_pw, ct_next) {
+                AuthState::Success(uat) => uat,
+                _ => panic!(),
+            };
+            assert!(uat.must_change_password);
+
+            // Until it is.
+            let mut idms_prox_write = idms.proxy_write();
+            assert!(idms_prox_write
+                .self_set_account_password(au, &uat, Some(reset_pw), TEST_PASSWORD, ct_next)
+                .is_ok());
+            idms_prox_write.commit(au).expect("Must not fail");
+            let uat = init_admin_uat(idms, au, ct + Duration::from_secs(2));
+            assert!(!uat.must_change_password);
+        })
+    }
+
+    fn password_feedback(
+        idms: &IdmServer,
+        au: &mut AuditScope,
+        pw: &str,
+    ) -> Option<Vec<PasswordFeedback>> {
+        let pce = PasswordChangeEvent::new_internal(&UUID_ADMIN, pw, None);
+        let mut idms_prox_write = idms.proxy_write();
+        let r = idms_prox_write.set_account_password(au, &pce);
+        idms_prox_write.commit(au).expect("Must not fail");
+        match r {
+            Ok(_) => None,
+            Err(OperationError::PasswordQuality(feedback)) => Some(feedback),
+            Err(e) => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_idm_password_quality() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            // Too short, and guessable with it.
+            assert!(
+                password_feedback(idms, au, "password1")
+                    == Some(vec![
+                        PasswordFeedback::TooShort(PW_MIN_LENGTH as u32),
+                        PasswordFeedback::TooGuessable,
+                        PasswordFeedback::AvoidCommonWords,
+                    ])
+            );
+            // Long enough, but easily guessed.
+            assert!(
+                password_feedback(idms, au, "aaaaaaaaaaaaaaaa")
+                    == Some(vec![
+                        PasswordFeedback::TooGuessable,
+                        PasswordFeedback::AvoidRepeats,
+                    ])
+            );
+            // The name of the account is no secret.
+            match password_feedback(idms, au, "administrator2020") {
+                Some(feedback) => {
+                    assert!(feedback.contains(&PasswordFeedback::AvoidPersonalInfo))
+                }
+                None => panic!(),
+            };
+
+            // A strong password is accepted, until it's added to the badlist.
+            let strong = "correct horse battery staple";
+            assert!(password_feedback(idms, au, strong).is_none());
+            let mut qs_write = qs.write();
+            assert!(qs_write
+                .internal_modify(
+                    au,
+                    filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_SYSTEM_CONFIG))),
+                    ModifyList::new_list(vec![Modify::Present(
+                        "badlist_password".to_string(),
+                        Value::new_iutf8s("Correct Horse Battery Staple"),
+                    )]),
+                )
+                .is_ok());
+            qs_write.commit(au).expect("Must not fail");
+            assert!(password_feedback(idms, au, strong) == Some(vec![PasswordFeedback::BadListed]));
+
+            // The score required can be changed too.
+            assert!(password_feedback(idms, au, "password1234").is_some());
+            let mut qs_write = qs.write();
+            assert!(qs_write
+                .internal_modify(
+                    au,
+                    filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_SYSTEM_CONFIG))),
+                    ModifyList::new_list(vec![Modify::Present(
+                        "password_min_score".to_string(),
+                        Value::new_uint32(0),
+                    )]),
+                )
+                .is_ok());
+            qs_write.commit(au).expect("Must not fail");
+            assert!(password_feedback(idms, au, "password1234").is_none());
+        })
+    }
+
+    // Authenticate as admin from source, which may be denied before the
+    // password is given.
+    fn admin_password_auth_from(
+        idms: &IdmServer,
+        au: &mut AuditScope,
+        pw: &str,
+        source: Option<&str>,
+        ct: Duration,
+    ) -> AuthState {
+        let mut idms_write = idms.write();
+        let mut init = AuthEvent::named_init("admin");
+        init.source = source.map(|s| s.to_string());
+        let sid = match idms_write.auth(au, &init, ct) {
+            Ok(AuthResult {
+                state: AuthState::Continue(_),
+                sessionid,
+            }) => sessionid,
+            Ok(ar) => return ar.state,
+            Err(_) => panic!(),
+        };
+        let mut step = AuthEvent::cred_step_password(sid, pw);
+        step.source = source.map(|s| s.to_string());
+        let state = match idms_write.auth(au, &step, ct) {
+            Ok(ar) => ar.state,
+            Err(_) => panic!(),
+        };
+        idms_write.commit().expect("Must not fail");
+        state
+    }
+
+    fn admin_auth_failures(qs: &QueryServer, au: &mut AuditScope) -> Option<u32> {
+        let qs_read = qs.read();
+        let entry = qs_read
+            .internal_search_uuid(au, &UUID_ADMIN)
+            .expect("Must not fail");
+        entry
+            .get_ava_single("auth_failures")
+            .and_then(|v| v.to_uint32())
+    }
+
+    #[test]
+    fn test_idm_account_lockout() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
+            // A session begun before the lock can't be used after it.
+            let early_sid = init_admin_authsession_sid(idms, au);
+
+            // Each auth needs its own time for a distinct session id.
+            let sec = Duration::from_secs(1);
+            let mut ct = Duration::from_secs(TEST_CURRENT_TIME);
+            for i in 1..AUTH_LOCKOUT_THRESHOLD {
+                ct += sec;
+                match admin_password_auth_from(idms, au, TEST_PASSWORD_INC, None, ct) {
+                    AuthState::Denied(AuthDenyReason::Failed, _) => {}
+                    _ => panic!(),
+                }
+                assert!(admin_auth_failures(qs, au) == Some(i));
+            }
+            ct += sec;
+            let locked_until = ct.as_secs() + AUTH_LOCKOUT_WINDOW;
+            match admin_password_auth_from(idms, au, TEST_PASSWORD_INC, None, ct) {
+                AuthState::Denied(AuthDenyReason::Locked(until), _) => {
+                    assert!(until == locked_until)
+                }
+                _ => panic!(),
+            }
+            // Now even the right password is refused.
+            ct += sec;
+            match admin_password_auth_from(idms, au, TEST_PASSWORD, None, ct) {
+                AuthState::Denied(AuthDenyReason::Locked(until), _) => {
+                    assert!(until == locked_until)
+                }
+                _ => panic!(),
+            }
+            let mut idms_write = idms.write();
+            match idms_write.auth(
+                au,
+                &AuthEvent::cred_step_password(early_sid, TEST_PASSWORD),
+                ct,
+            ) {
+                Ok(AuthResult {
+                    state: AuthState::Denied(AuthDenyReason::Locked(_), _),
+                    ..
+                }) => {}
+                _ => panic!(),
+            }
+            idms_write.commit().expect("Must not fail");
+
+            // The lock is kept on the entry, so a restart doesn't end it.
+            let idms_restarted = IdmServer::new(qs.clone(), [0; 4]);
+            ct += sec;
+            match admin_password_auth_from(&idms_restarted, au, TEST_PASSWORD, None, ct) {
+                AuthState::Denied(AuthDenyReason::Locked(_), _) => {}
+                _ => panic!(),
+            }
+
+            // Once the lock ends, each further failure locks for twice as
+            // long as the last.
+            let mut ct = Duration::from_secs(locked_until);
+            match admin_password_auth_from(idms, au, TEST_PASSWORD_INC, None, ct) {
+                AuthState::Denied(AuthDenyReason::Locked(until), _) => {
+                    assert!(until == locked_until + AUTH_LOCKOUT_WINDOW * 2)
+                }
+                _ => panic!(),
+            }
+
+            // Success after a lock ends the count.
+            ct += Duration::from_secs(AUTH_LOCKOUT_WINDOW * 2);
+            match admin_password_auth_from(idms, au, TEST_PASSWORD, None, ct) {
+                AuthState::Success(_) => {}
+                _ => panic!(),
+            }
+            assert!(admin_auth_failures(qs, au).is_none());
+        })
+    }
+
+    #[test]
+    fn test_idm_account_lockout_admin_clear() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
+            let sec = Duration::from_secs(1);
+            let mut ct = Duration::from_secs(TEST_CURRENT_TIME);
+
+            for _ in 0..AUTH_LOCKOUT_THRESHOLD {
+                ct += sec;
+                admin_password_auth_from(idms, au, TEST_PASSWORD_INC, None, ct);
+            }
+            ct += sec;
+            match admin_password_auth_from(idms, au, TEST_PASSWORD, None, ct) {
+                AuthState::Denied(AuthDenyReason::Locked(_), _) => {}
+                _ => panic!(),
+            }
+
+            // Removing the lock ends it at once, and the count begins again.
+            let mut qs_write = qs.write();
+            assert!(qs_write
+                .internal_modify(
+                    au,
+                    filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_ADMIN))),
+                    ModifyList::new_list(vec![Modify::Purged("account_locked_until".to_string())]),
+                )
+                .is_ok());
+            qs_write.commit(au).expect("Must not fail");
+
+            ct += sec;
+            match admin_password_auth_from(idms, au, TEST_PASSWORD_INC, None, ct) {
+                AuthState::Denied(AuthDenyReason::Failed, _) => {}
+                _ => panic!(),
+            }
+            assert!(admin_auth_failures(qs, au) == Some(1));
+            ct += sec;
+            match admin_password_auth_from(idms, au, TEST_PASSWORD, None, ct) {
+                AuthState::Success(_) => {}
+                _ => panic!(),
+            }
+        })
+    }
+
+    #[test]
+    fn test_idm_account_lockout_source() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
+            let sec = Duration::from_secs(1);
+            let mut ct = Duration::from_secs(TEST_CURRENT_TIME);
+
+            // A threshold of 0 never locks.
+            let mut idms_nolock = IdmServer::new(qs.clone(), [0; 4]);
+            idms_nolock.set_lockout_policy(0, AUTH_LOCKOUT_WINDOW);
+            for _ in 0..(AUTH_LOCKOUT_THRESHOLD * 2) {
+                ct += sec;
+                match admin_password_auth_from(&idms_nolock, au, TEST_PASSWORD_INC, None, ct) {
+                    AuthState::Denied(AuthDenyReason::Failed, _) => {}
+                    _ => panic!(),
+                }
+            }
+            ct += sec;
+            match admin_password_auth_from(&idms_nolock, au, TEST_PASSWORD, None, ct) {
+                AuthState::Success(_) => {}
+                _ => panic!(),
+            }
+
+            // The user succeeding in between keeps the account unlocked,
+            // but the failures of the attacker's address still add up, to
+            // a higher threshold. The port differs between connections.
+            let attacker = |i: u32| format!("192.0.2.1:{}", 40000 + i);
+            let user = Some("198.51.100.1:40000");
+            for i in 1..(AUTH_LOCKOUT_THRESHOLD * AUTH_LOCKOUT_SOURCE_FACTOR) {
+                ct += sec;
+                let source = attacker(i);
+                match admin_password_auth_from(idms, au, TEST_PASSWORD_INC, Some(&source), ct) {
+                    AuthState::Denied(AuthDenyReason::Failed, _) => {}
+                    _ => panic!(),
+                }
+                if i % (AUTH_LOCKOUT_THRESHOLD - 1) == 0 {
+                    ct += sec;
+                    match admin_password_auth_from(idms, au, TEST_PASSWORD, user, ct) {
+                        AuthState::Success(_) => {}
+                        _ => panic!(),
                     }
-                };
+                }
+            }
+            ct += sec;
+            let source = attacker(0);
+            match admin_password_auth_from(idms, au, TEST_PASSWORD_INC, Some(&source), ct) {
+                AuthState::Denied(AuthDenyReason::Locked(until), _) => {
+                    assert!(until == ct.as_secs() + AUTH_LOCKOUT_WINDOW)
+                }
+                _ => panic!(),
+            }
 
-                println!("sessionid is ==> {:?}", sid);
+            ct += sec;
+            match admin_password_auth_from(idms, au, TEST_PASSWORD, Some(&source), ct) {
+                AuthState::Denied(AuthDenyReason::Locked(_), _) => {}
+                _ => panic!(),
+            }
+            ct += sec;
+            match admin_password_auth_from(idms, au, TEST_PASSWORD, user, ct) {
+                AuthState::Success(_) => {}
+                _ => panic!(),
+            }
+        })
+    }
+
+    // Set the window admin may authenticate in, in seconds since the epoch.
+    fn set_admin_validity(
+        qs: &QueryServer,
+        au: &mut AuditScope,
+        valid_from: Option<u64>,
+        expire: Option<u64>,
+    ) {
+        let to_dt = |secs: u64| -> DateTime<Utc> {
+            DateTime::from(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+        };
+        let mut mods = vec![
+            Modify::Purged("account_valid_from".to_string()),
+            Modify::Purged("account_expire".to_string()),
+        ];
+        if let Some(from) = valid_from {
+            mods.push(Modify::Present(
+                "account_valid_from".to_string(),
+                Value::new_datetime(to_dt(from)),
+            ));
+        }
+        if let Some(expire) = expire {
+            mods.push(Modify::Present(
+                "account_expire".to_string(),
+                Value::new_datetime(to_dt(expire)),
+            ));
+        }
+        let mut qs_write = qs.write();
+        assert!(qs_write
+            .internal_modify(
+                au,
+                filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_ADMIN))),
+                ModifyList::new_list(mods),
+            )
+            .is_ok());
+        qs_write.commit(au).expect("Must not fail");
+    }
+
+    #[test]
+    fn test_idm_account_validity() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
+            let valid_from = TEST_CURRENT_TIME + 100;
+            let expire = TEST_CURRENT_TIME + 200;
+            set_admin_validity(qs, au, Some(valid_from), Some(expire));
+
+            // Before the window, even the right password is refused.
+            let ct = Duration::from_secs(TEST_CURRENT_TIME);
+            match admin_password_auth_from(idms, au, TEST_PASSWORD, None, ct) {
+                AuthState::Denied(AuthDenyReason::NotYetValid(from), _) => {
+                    assert!(from == valid_from)
+                }
+                _ => panic!(),
+            }
+
+            // Within it the token is issued, but can't outlive the account.
+            let ct = Duration::from_secs(TEST_CURRENT_TIME + 150);
+            let uat = match admin_password_auth_from(idms, au, TEST_PASSWORD, None, ct) {
+                AuthState::Success(uat) => uat,
+                _ => panic!(),
+            };
+            assert!(uat.expiry == expire);
+            assert!(idms.is_session_active(&uat.sessionid, Duration::from_secs(expire - 1)));
+            assert!(!idms.is_session_active(&uat.sessionid, Duration::from_secs(expire)));
+
+            // A session begun within the window can't be completed after it.
+            let mut idms_write = idms.write();
+            let sid = match idms_write.auth(
+                au,
+                &AuthEvent::named_init("admin"),
+                Duration::from_secs(expire - 1),
+            ) {
+                Ok(AuthResult {
+                    state: AuthState::Continue(_),
+                    sessionid,
+                }) => sessionid,
+                _ => panic!(),
+            };
+            match idms_write.auth(
+                au,
+                &AuthEvent::cred_step_password(sid, TEST_PASSWORD),
+                Duration::from_secs(expire + 1),
+            ) {
+                Ok(AuthResult {
+                    state: AuthState::Denied(AuthDenyReason::Expired(at), _),
+                    ..
+                }) => assert!(at == expire),
+                _ => panic!(),
+            }
+            idms_write.commit().expect("Must not fail");
+
+            // After the window, and until the expiry is cleared.
+            let ct = Duration::from_secs(expire + 2);
+            match admin_password_auth_from(idms, au, TEST_PASSWORD, None, ct) {
+                AuthState::Denied(AuthDenyReason::Expired(at), _) => assert!(at == expire),
+                _ => panic!(),
+            }
+            set_admin_validity(qs, au, Some(valid_from), None);
+            let ct = Duration::from_secs(expire + 3);
+            match admin_password_auth_from(idms, au, TEST_PASSWORD, None, ct) {
+                AuthState::Success(uat) => {
+                    assert!(uat.expiry == ct.as_secs() + AUTH_TOKEN_LIFETIME)
+                }
+                _ => panic!(),
+            }
+        })
+    }
+
+    static JSON_TESTSERVICE: &'static str = r#"{
+        "valid": null,
+        "state": null,
+        "attrs": {
+            "class": ["object", "account", "service_account"],
+            "name": ["testservice"],
+            "displayname": ["Test Service"],
+            "uuid": ["4a9d8d15-6c4c-4b38-9f5c-5c1d2b0a3f3e"]
+        }
+    }"#;
+
+    fn init_testservice(qs: &QueryServer, au: &mut AuditScope) {
+        let e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_TESTSERVICE);
+        let mut qs_write = qs.write();
+        let ce = CreateEvent::new_internal(vec![e]);
+        assert!(qs_write.create(au, &ce).is_ok());
+        qs_write.commit(au).expect("Must not fail");
+    }
+
+    fn testservice_token_auth(
+        idms: &IdmServer,
+        au: &mut AuditScope,
+        token: &str,
+        ct: Duration,
+    ) -> AuthState {
+        let mut idms_write = idms.write();
+        let sid = match idms_write.auth(au, &AuthEvent::named_init("testservice"), ct) {
+            Ok(AuthResult {
+                sessionid,
+                state: AuthState::Continue(allowed),
+            }) => {
+                assert!(allowed == vec![AuthAllowed::ApiToken]);
+                sessionid
+            }
+            Ok(AuthResult { state, .. }) => {
+                idms_write.commit().expect("Must not fail");
+                return state;
+            }
+            Err(_) => panic!(),
+        };
+        let state = match idms_write.auth(au, &AuthEvent::cred_step_api_token(sid, token), ct) {
+            Ok(ar) => ar.state,
+            Err(_) => panic!(),
+        };
+        idms_write.commit().expect("Must not fail");
+        state
+    }
+
+    #[test]
+    fn test_idm_api_token_auth() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
+            init_testservice(qs, au);
+            let ct = Duration::from_secs(TEST_CURRENT_TIME);
+            let uat = init_admin_uat(idms, au, ct);
+
+            // Tokens can only be given to service accounts.
+            let mut idms_prox_write = idms.proxy_write();
+            assert!(idms_prox_write
+                .generate_api_token(au, &uat, "admin", "backup", None, false, ct)
+                .is_err());
+            let (id, token) = idms_prox_write
+                .generate_api_token(au, &uat, "testservice", "backup", None, false, ct)
+                .expect("Failed to generate api token");
+            idms_prox_write.commit(au).expect("Must not fail");
+
+            // The wrong token is a failure, as with a wrong password.
+            let ct = ct + Duration::from_secs(1);
+            let wrong = format!("{}.notthesecret", id);
+            match testservice_token_auth(idms, au, wrong.as_str(), ct) {
+                AuthState::Denied(AuthDenyReason::Failed, _) => {}
+                _ => panic!(),
+            }
+
+            let ct = ct + Duration::from_secs(1);
+            let suat = match testservice_token_auth(idms, au, token.as_str(), ct) {
+                AuthState::Success(suat) => suat,
+                _ => panic!(),
+            };
+            assert!(suat.api_token == Some(id));
+            assert!(suat.read_only);
+            assert!(idms.is_session_active(&suat.sessionid, ct));
+
+            // The token is listed with when it was last used, but never
+            // with its secret.
+            let mut idms_prox_write = idms.proxy_write();
+            let tokens = idms_prox_write
+                .list_api_tokens(au, &uat, "testservice")
+                .expect("Failed to list api tokens");
+            assert!(tokens.len() == 1);
+            assert!(tokens[0].id == id);
+            assert!(tokens[0].label == "backup");
+            assert!(tokens[0].created == TEST_CURRENT_TIME);
+            assert!(tokens[0].last_used == Some(ct.as_secs()));
+            assert!(!tokens[0].read_write);
+
+            // A read only session can't write, or change credentials.
+            assert!(
+                idms_prox_write
+                    .generate_api_token(au, &suat, "testservice", "more", None, true, ct)
+                    .err()
+                    == Some(OperationError::AccessDenied)
+            );
+            idms_prox_write.commit(au).expect("Must not fail");
+            assert!(
+                idms.check_reauth(au, &suat, ProtectedOperation::CredentialChange, ct)
+                    == Err(OperationError::AccessDenied)
+            );
+        })
+    }
+
+    #[test]
+    fn test_idm_api_token_expiry() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
+            init_testservice(qs, au);
+            let ct = Duration::from_secs(TEST_CURRENT_TIME);
+            let uat = init_admin_uat(idms, au, ct);
+            let expiry = TEST_CURRENT_TIME + 60;
+
+            let mut idms_prox_write = idms.proxy_write();
+            assert!(idms_prox_write
+                .generate_api_token(au, &uat, "testservice", "past", Some(1), true, ct)
+                .is_err());
+            let (_, token) = idms_prox_write
+                .generate_api_token(au, &uat, "testservice", "job", Some(expiry), true, ct)
+                .expect("Failed to generate api token");
+            idms_prox_write.commit(au).expect("Must not fail");
+
+            // The session can't outlive the token.
+            let ct = ct + Duration::from_secs(1);
+            match testservice_token_auth(idms, au, token.as_str(), ct) {
+                AuthState::Success(suat) => {
+                    assert!(suat.expiry == expiry);
+                    assert!(!suat.read_only);
+                }
+                _ => panic!(),
+            }
 
-                idms_write.commit().expect("Must not fail");
+            let ct = Duration::from_secs(expiry);
+            match testservice_token_auth(idms, au, token.as_str(), ct) {
+                AuthState::Denied(AuthDenyReason::NotPermitted, reason) => {
+                    assert!(reason == "api token expired")
+                }
+                _ => panic!(),
+            }
+        })
+    }
 
-                sid
-            };
-            {
-                let mut idms_write = idms.write();
-                // Now send the anonymous request, given the session id.
-                let anon_step = AuthEvent::cred_step_anonymous(sid);
+    #[test]
+    fn test_idm_api_token_bearer() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
+            init_testservice(qs, au);
+            let ct = Duration::from_secs(TEST_CURRENT_TIME);
+            let uat = init_admin_uat(idms, au, ct);
+            let expiry = TEST_CURRENT_TIME + 60;
 
-                // Expect success
-                let r2 = idms_write.auth(au, &anon_step, Duration::from_secs(TEST_CURRENT_TIME));
-                println!("r2 ==> {:?}", r2);
+            let mut idms_prox_write = idms.proxy_write();
+            let (id, token) = idms_prox_write
+                .generate_api_token(au, &uat, "testservice", "scim", Some(expiry), true, ct)
+                .expect("Failed to generate api token");
+            idms_prox_write.commit(au).expect("Must not fail");
 
-                match r2 {
-                    Ok(ar) => {
-                        let AuthResult {
-                            sessionid: _,
-                            state,
-                        } = ar;
-                        match state {
-                            AuthState::Success(_uat) => {
-                                // Check the uat.
-                            }
-                            _ => {
-                                error!(
-                                    "A critical error has occured! We have a non-succcess result!"
-                                );
-                                panic!();
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        error!("A critical error has occured! {:?}", e);
-                        // Should not occur!
-                        panic!();
-                    }
-                };
+            let suat = idms
+                .auth_api_token(au, token.as_str(), ct)
+                .expect("Failed to auth api token");
+            assert!(suat.name == "testservice");
+            assert!(suat.api_token == Some(id));
+            assert!(suat.sessionid == id);
+            assert!(suat.expiry == expiry);
+            assert!(!suat.read_only);
+            // No session was begun for it.
+            assert!(!idms.is_session_active(&suat.sessionid, ct));
 
-                idms_write.commit().expect("Must not fail");
-            }
-        });
+            let wrong = format!("{}.notthesecret", id);
+            assert!(
+                idms.auth_api_token(au, wrong.as_str(), ct).err()
+                    == Some(OperationError::NotAuthenticated)
+            );
+            assert!(idms.auth_api_token(au, "not a token", ct).is_err());
+            assert!(idms
+                .auth_api_token(au, token.as_str(), Duration::from_secs(expiry))
+                .is_err());
+        })
     }
 
-    // Test sending anonymous but with no session init.
     #[test]
-    fn test_idm_anonymous_auth_invalid_states() {
-        run_idm_test!(|_qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
-            {
-                let mut idms_write = idms.write();
-                let sid = Uuid::new_v4();
-                let anon_step = AuthEvent::cred_step_anonymous(sid);
+    fn test_idm_api_token_destroy() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
+            init_testservice(qs, au);
+            let ct = Duration::from_secs(TEST_CURRENT_TIME);
+            let uat = init_admin_uat(idms, au, ct);
 
-                // Expect failure
-                let r2 = idms_write.auth(au, &anon_step, Duration::from_secs(TEST_CURRENT_TIME));
-                println!("r2 ==> {:?}", r2);
+            let mut idms_prox_write = idms.proxy_write();
+            let (id_a, token_a) = idms_prox_write
+                .generate_api_token(au, &uat, "testservice", "a", None, false, ct)
+                .expect("Failed to generate api token");
+            let (_, token_b) = idms_prox_write
+                .generate_api_token(au, &uat, "testservice", "b", None, false, ct)
+                .expect("Failed to generate api token");
+            idms_prox_write.commit(au).expect("Must not fail");
 
-                match r2 {
-                    Ok(_) => {
-                        error!("Auth state machine not correctly enforced!");
-                        panic!();
-                    }
-                    Err(e) => match e {
-                        OperationError::InvalidSessionState => {}
-                        _ => panic!(),
-                    },
-                };
+            let ct_a = ct + Duration::from_secs(1);
+            let suat_a = match testservice_token_auth(idms, au, token_a.as_str(), ct_a) {
+                AuthState::Success(suat) => suat,
+                _ => panic!(),
+            };
+            let ct_b = ct + Duration::from_secs(2);
+            let suat_b = match testservice_token_auth(idms, au, token_b.as_str(), ct_b) {
+                AuthState::Success(suat) => suat,
+                _ => panic!(),
+            };
+
+            // Destroying one token ends its sessions, and leaves the other.
+            let mut idm_write = idms.write();
+            let mut idms_prox_write = idms.proxy_write();
+            idms_prox_write
+                .destroy_api_token(au, &uat, "testservice", &id_a)
+                .expect("Failed to destroy api token");
+            idms_prox_write.commit(au).expect("Must not fail");
+            assert!(idm_write.end_api_token_sessions(au, &id_a) == 1);
+            idm_write.commit().expect("Must not fail");
+            assert!(!idms.is_session_active(&suat_a.sessionid, ct_b));
+            assert!(idms.is_session_active(&suat_b.sessionid, ct_b));
+
+            let ct = ct + Duration::from_secs(3);
+            match testservice_token_auth(idms, au, token_a.as_str(), ct) {
+                AuthState::Denied(AuthDenyReason::Failed, _) => {}
+                _ => panic!(),
             }
+            let mut idms_prox_write = idms.proxy_write();
+            let tokens = idms_prox_write
+                .list_api_tokens(au, &uat, "testservice")
+                .expect("Failed to list api tokens");
+            assert!(tokens.len() == 1);
+            assert!(tokens[0].label == "b");
         })
     }
 
-    fn init_admin_w_password(
-        au: &mut AuditScope,
-        qs: &QueryServer,
-        pw: &str,
-    ) -> Result<(), OperationError> {
-        let cred = Credential::new_password_only(pw);
-        let v_cred = Value::new_credential("primary", cred);
-        let mut qs_write = qs.write();
+    static JSON_TESTPERSON_RADIUS: &'static str = r#"{
+        "valid": null,
+        "state": null,
+        "attrs": {
+            "class": ["object", "person", "account"],
+            "name": ["testperson"],
+            "displayname": ["Test Person"],
+            "uuid": ["6c3ec7a2-4c46-4c3a-9cf0-6b2b8c1f9d01"]
+        }
+    }"#;
 
-        // now modify and provide a primary credential.
-        let me_inv_m = unsafe {
-            ModifyEvent::new_internal_invalid(
-                filter!(f_eq("name", PartialValue::new_iutf8s("admin"))),
+    static JSON_TESTOTHER_RADIUS: &'static str = r#"{
+        "valid": null,
+        "state": null,
+        "attrs": {
+            "class": ["object", "person", "account"],
+            "name": ["testother"],
+            "displayname": ["Test Other"],
+            "uuid": ["6c3ec7a2-4c46-4c3a-9cf0-6b2b8c1f9d02"]
+        }
+    }"#;
+
+    static JSON_TESTRADIUS: &'static str = r#"{
+        "valid": null,
+        "state": null,
+        "attrs": {
+            "class": ["object", "account"],
+            "name": ["testradius"],
+            "displayname": ["Test Radius"],
+            "uuid": ["6c3ec7a2-4c46-4c3a-9cf0-6b2b8c1f9d03"]
+        }
+    }"#;
+
+    static JSON_TESTVLAN: &'static str = r#"{
+        "valid": null,
+        "state": null,
+        "attrs": {
+            "class": ["object", "group"],
+            "name": ["testvlan"],
+            "uuid": ["6c3ec7a2-4c46-4c3a-9cf0-6b2b8c1f9d04"]
+        }
+    }"#;
+
+    fn add_member(
+        qs_write: &mut QueryServerWriteTransaction,
+        au: &mut AuditScope,
+        group: &str,
+        member: &str,
+    ) {
+        assert!(qs_write
+            .internal_modify(
+                au,
+                filter!(f_eq("uuid", PartialValue::new_uuids(group).unwrap())),
                 ModifyList::new_list(vec![Modify::Present(
-                    "primary_credential".to_string(),
-                    v_cred,
+                    "member".to_string(),
+                    Value::new_refer_s(member).unwrap()
                 )]),
             )
-        };
-        // go!
-        assert!(qs_write.modify(au, &me_inv_m).is_ok());
+            .is_ok());
+    }
 
-        qs_write.commit(au)
+    // testperson is in testvlan, and testradius is a radius server.
+    fn init_radius_entries(qs: &QueryServer, au: &mut AuditScope) {
+        let es: Vec<Entry<EntryInvalid, EntryNew>> = vec![
+            JSON_TESTPERSON_RADIUS,
+            JSON_TESTOTHER_RADIUS,
+            JSON_TESTRADIUS,
+            JSON_TESTVLAN,
+        ]
+        .into_iter()
+        .map(Entry::unsafe_from_entry_str)
+        .collect();
+        let mut qs_write = qs.write();
+        let ce = CreateEvent::new_internal(es);
+        assert!(qs_write.create(au, &ce).is_ok());
+        add_member(
+            &mut qs_write,
+            au,
+            "6c3ec7a2-4c46-4c3a-9cf0-6b2b8c1f9d04",
+            "6c3ec7a2-4c46-4c3a-9cf0-6b2b8c1f9d01",
+        );
+        add_member(
+            &mut qs_write,
+            au,
+            _UUID_IDM_RADIUS_SERVERS,
+            "6c3ec7a2-4c46-4c3a-9cf0-6b2b8c1f9d03",
+        );
+        qs_write.commit(au).expect("Must not fail");
     }
 
-    fn init_admin_authsession_sid(idms: &IdmServer, au: &mut AuditScope) -> Uuid {
-        let mut idms_write = idms.write();
-        let admin_init = AuthEvent::named_init("admin");
+    // Reading a radius token only needs the account and when it
+    // authenticated, so the uat is made from the entry directly.
+    fn init_uat_for(
+        qs: &QueryServer,
+        au: &mut AuditScope,
+        name: &str,
+        ct: Duration,
+    ) -> UserAuthToken {
+        let qs_read = qs.read();
+        let u = qs_read.name_to_uuid(au, name).expect("Missing account");
+        let e = qs_read
+            .internal_search_uuid(au, &u)
+            .expect("Missing account");
+        let account = Account::try_from_entry(au, e, &qs_read).expect("Invalid account");
+        account
+            .to_userauthtoken(&Uuid::new_v4(), Vec::new(), ct, Duration::from_secs(300))
+            .expect("Failed to create uat")
+    }
 
-        let r1 = idms_write.auth(au, &admin_init, Duration::from_secs(TEST_CURRENT_TIME));
-        let ar = r1.unwrap();
-        let AuthResult { sessionid, state } = ar;
+    #[test]
+    fn test_idm_radius_secret() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            init_radius_entries(qs, au);
+            let ct = Duration::from_secs(TEST_CURRENT_TIME);
+            let uat_person = init_uat_for(qs, au, "testperson", ct);
+            let uat_other = init_uat_for(qs, au, "testother", ct);
+            let uat_radius = init_uat_for(qs, au, "testradius", ct);
 
-        match state {
-            AuthState::Continue(_) => {}
-            _ => {
-                error!("Sessions was not initialised");
-                panic!();
-            }
-        };
+            // There is no secret until one is generated.
+            assert!(idms
+                .get_radius_auth_token(au, &uat_person, "testperson")
+                .is_err());
 
-        idms_write.commit().expect("Must not fail");
+            let target = Uuid::parse_str(uat_person.uuid.as_str()).unwrap();
+            let mut idms_prox_write = idms.proxy_write();
+            let secret = idms_prox_write
+                .generate_radius_secret(au, &target)
+                .expect("Failed to generate radius secret");
+            idms_prox_write.commit(au).expect("Must not fail");
 
-        sessionid
+            // The owner may read their own.
+            let rat = idms
+                .get_radius_auth_token(au, &uat_person, "testperson")
+                .expect("Failed to read radius token");
+            assert!(rat.secret == secret);
+            assert!(rat.name == "testperson");
+            assert!(rat.displayname == "Test Person");
+            assert!(rat.uuid == uat_person.uuid);
+
+            // A radius server may read anyone's, with the groups to map
+            // them by.
+            let rat = idms
+                .get_radius_auth_token(au, &uat_radius, uat_person.uuid.as_str())
+                .expect("Failed to read radius token");
+            assert!(rat.secret == secret);
+            assert!(rat.groups.iter().any(|g| g.name == "testvlan"));
+
+            // Anyone else may not.
+            assert!(idms
+                .get_radius_auth_token(au, &uat_other, "testperson")
+                .is_err());
+
+            // Generating again replaces the secret.
+            let mut idms_prox_write = idms.proxy_write();
+            let secret_b = idms_prox_write
+                .generate_radius_secret(au, &target)
+                .expect("Failed to generate radius secret");
+            idms_prox_write.commit(au).expect("Must not fail");
+            assert!(secret_b != secret);
+            let rat = idms
+                .get_radius_auth_token(au, &uat_radius, "testperson")
+                .expect("Failed to read radius token");
+            assert!(rat.secret == secret_b);
+        })
     }
 
     #[test]
-    fn test_idm_simple_password_auth() {
+    fn test_idm_ssh_publickeys() {
         run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
-            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
-            let sid = init_admin_authsession_sid(idms, au);
-
-            let mut idms_write = idms.write();
-            let anon_step = AuthEvent::cred_step_password(sid, TEST_PASSWORD);
+            init_radius_entries(qs, au);
+            let ct = Duration::from_secs(TEST_CURRENT_TIME);
+            let uat_anon = init_uat_for(qs, au, "anonymous", ct);
+            let uat_other = init_uat_for(qs, au, "testother", ct);
 
-            // Expect success
-            let r2 = idms_write.auth(au, &anon_step, Duration::from_secs(TEST_CURRENT_TIME));
-            println!("r2 ==> {:?}", r2);
+            let key_a = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIC6QCYmikCVyX0g7E6MRDYEVh2OmvffZOnDQtxeCRDdx user@laptop";
+            let key_b = "ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBNNUsyUJMMMPBXS0M9Obg5n6+A2X+y5RW4UwThCEezTwf1AlhsnbsEFuaf14WWvDeiVMuIbyruElPLOKo237IJI= user@desktop";
+            let mut qs_write = qs.write();
+            assert!(qs_write
+                .internal_modify(
+                    au,
+                    filter!(f_eq("name", PartialValue::new_iutf8s("testperson"))),
+                    ModifyList::new_list(vec![
+                        Modify::Present(
+                            "ssh_publickey".to_string(),
+                            Value::new_sshkey("laptop", key_a).unwrap()
+                        ),
+                        Modify::Present(
+                            "ssh_publickey".to_string(),
+                            Value::new_sshkey("desktop", key_b).unwrap()
+                        ),
+                    ]),
+                )
+                .is_ok());
+            qs_write.commit(au).expect("Must not fail");
 
-            match r2 {
-                Ok(ar) => {
-                    let AuthResult {
-                        sessionid: _,
-                        state,
-                    } = ar;
-                    match state {
-                        AuthState::Success(_uat) => {
-                            // Check the uat.
-                        }
-                        _ => {
-                            error!("A critical error has occured! We have a non-succcess result!");
-                            panic!();
-                        }
-                    }
-                }
-                Err(e) => {
-                    error!("A critical error has occured! {:?}", e);
-                    // Should not occur!
-                    panic!();
-                }
-            };
+            // Anonymous and other accounts may read the keys, without tags.
+            for uat in &[&uat_anon, &uat_other] {
+                let mut keys = idms
+                    .get_ssh_publickeys(au, uat, "testperson")
+                    .expect("Failed to read keys");
+                keys.sort();
+                assert!(keys == vec![key_b.to_string(), key_a.to_string()]);
+            }
+            // An account without keys has none, and one that doesn't exist
+            // is an error.
+            assert!(idms
+                .get_ssh_publickeys(au, &uat_anon, "testother")
+                .expect("Failed to read keys")
+                .is_empty());
+            assert!(idms
+                .get_ssh_publickeys(au, &uat_anon, "nosuchaccount")
+                .is_err());
 
-            idms_write.commit().expect("Must not fail");
+            // A key is removed by its tag.
+            let mut qs_write = qs.write();
+            assert!(qs_write
+                .internal_modify(
+                    au,
+                    filter!(f_eq("name", PartialValue::new_iutf8s("testperson"))),
+                    ModifyList::new_list(vec![Modify::Removed(
+                        "ssh_publickey".to_string(),
+                        PartialValue::new_sshkey_tagr("laptop")
+                    )]),
+                )
+                .is_ok());
+            qs_write.commit(au).expect("Must not fail");
+            let keys = idms
+                .get_ssh_publickeys(au, &uat_anon, "testperson")
+                .expect("Failed to read keys");
+            assert!(keys == vec![key_b.to_string()]);
         })
     }
 
+    static JSON_TESTUNIXGROUP: &'static str = r#"{
+        "valid": null,
+        "state": null,
+        "attrs": {
+            "class": ["object", "group", "posixgroup"],
+            "name": ["testunixgroup"],
+            "uuid": ["6c3ec7a2-4c46-4c3a-9cf0-6b2b8c1f9d05"],
+            "gidnumber": ["20001"]
+        }
+    }"#;
+
+    // testperson is made a posix account in testunixgroup, and still in
+    // testvlan, which isn't a posix group.
+    fn init_unix_entries(qs: &QueryServer, au: &mut AuditScope) {
+        init_radius_entries(qs, au);
+        let e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_TESTUNIXGROUP);
+        let mut qs_write = qs.write();
+        let ce = CreateEvent::new_internal(vec![e]);
+        assert!(qs_write.create(au, &ce).is_ok());
+        assert!(qs_write
+            .internal_modify(
+                au,
+                filter!(f_eq("name", PartialValue::new_iutf8s("testperson"))),
+                ModifyList::new_list(vec![
+                    Modify::Present("class".to_string(), Value::new_class("posixaccount")),
+                    Modify::Present("loginshell".to_string(), Value::new_utf8s("/bin/zsh")),
+                ]),
+            )
+            .is_ok());
+        add_member(
+            &mut qs_write,
+            au,
+            "6c3ec7a2-4c46-4c3a-9cf0-6b2b8c1f9d05",
+            "6c3ec7a2-4c46-4c3a-9cf0-6b2b8c1f9d01",
+        );
+        qs_write.commit(au).expect("Must not fail");
+    }
+
     #[test]
-    fn test_idm_simple_password_invalid() {
+    fn test_idm_unix_token() {
         run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
-            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
-            let sid = init_admin_authsession_sid(idms, au);
-            let mut idms_write = idms.write();
-            let anon_step = AuthEvent::cred_step_password(sid, TEST_PASSWORD_INC);
+            init_unix_entries(qs, au);
+            let ct = Duration::from_secs(TEST_CURRENT_TIME);
+            let uat_anon = init_uat_for(qs, au, "anonymous", ct);
 
-            // Expect success
-            let r2 = idms_write.auth(au, &anon_step, Duration::from_secs(TEST_CURRENT_TIME));
-            println!("r2 ==> {:?}", r2);
+            // Anyone may resolve a posix account, by name or uuid. The
+            // gidnumber was generated from the uuid, and only posix groups
+            // are given, after the account's own.
+            for id in &["testperson", "6c3ec7a2-4c46-4c3a-9cf0-6b2b8c1f9d01"] {
+                let ut = idms
+                    .get_unix_user_token(au, &uat_anon, id)
+                    .expect("Failed to read unix token");
+                assert!(ut.name == "testperson");
+                assert!(ut.displayname == "Test Person");
+                assert!(ut.uuid == "6c3ec7a2-4c46-4c3a-9cf0-6b2b8c1f9d01");
+                assert!(ut.gidnumber == 203529474);
+                assert!(ut.shell == Some("/bin/zsh".to_string()));
+                let groups: Vec<(&str, u32)> = ut
+                    .groups
+                    .iter()
+                    .map(|g| (g.name.as_str(), g.gidnumber))
+                    .collect();
+                assert!(groups == vec![("testperson", 203529474), ("testunixgroup", 20001)]);
+            }
 
-            match r2 {
-                Ok(ar) => {
-                    let AuthResult {
-                        sessionid: _,
-                        state,
-                    } = ar;
-                    match state {
-                        AuthState::Denied(_reason) => {
-                            // Check the uat.
-                        }
-                        _ => {
-                            error!("A critical error has occured! We have a non-denied result!");
-                            panic!();
-                        }
-                    }
-                }
-                Err(e) => {
-                    error!("A critical error has occured! {:?}", e);
-                    // Should not occur!
-                    panic!();
-                }
-            };
+            let gt = idms
+                .get_unix_group_token(au, &uat_anon, "testunixgroup")
+                .expect("Failed to read unix group token");
+            assert!(gt.uuid == "6c3ec7a2-4c46-4c3a-9cf0-6b2b8c1f9d05");
+            assert!(gt.gidnumber == 20001);
 
-            idms_write.commit().expect("Must not fail");
+            // Accounts and groups that aren't extended have no token.
+            assert!(idms
+                .get_unix_user_token(au, &uat_anon, "testother")
+                .is_err());
+            assert!(idms
+                .get_unix_group_token(au, &uat_anon, "testvlan")
+                .is_err());
         })
     }
 
     #[test]
-    fn test_idm_simple_password_reset() {
-        run_idm_test!(|_qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
-            let pce = PasswordChangeEvent::new_internal(&UUID_ADMIN, TEST_PASSWORD, None);
+    fn test_idm_unix_password() {
+        run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
+            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
+            init_unix_entries(qs, au);
+            let ct = Duration::from_secs(TEST_CURRENT_TIME);
+            let uat_admin = init_admin_uat(idms, au, ct);
+            let uat_person = init_uat_for(qs, au, "testperson", ct);
+            let uat_other = init_uat_for(qs, au, "testother", ct);
+
+            let unix_password = |au: &mut AuditScope| {
+                let qs_read = qs.read();
+                let e = qs_read
+                    .internal_search_uuid(au, &Uuid::parse_str(uat_person.uuid.as_str()).unwrap())
+                    .expect("Missing account");
+                (
+                    e.get_ava_single("unix_password")
+                        .and_then(|v| v.to_credential())
+                        .cloned(),
+                    e.attribute_pres("primary_credential"),
+                )
+            };
 
+            // An account may set its own, which leaves the primary credential
+            // alone.
             let mut idms_prox_write = idms.proxy_write();
-            assert!(idms_prox_write.set_account_password(au, &pce).is_ok());
-            assert!(idms_prox_write.set_account_password(au, &pce).is_ok());
-            assert!(idms_prox_write.commit(au).is_ok());
+            assert!(idms_prox_write
+                .set_unix_account_password(au, &uat_person, "testperson", TEST_PASSWORD)
+                .is_ok());
+            idms_prox_write.commit(au).expect("Must not fail");
+            let (cred, primary) = unix_password(au);
+            assert!(cred
+                .expect("Missing unix password")
+                .verify_password(TEST_PASSWORD));
+            assert!(!primary);
+
+            // Others need access controls to allow it, and weak passwords
+            // are refused.
+            let mut idms_prox_write = idms.proxy_write();
+            assert!(idms_prox_write
+                .set_unix_account_password(au, &uat_other, "testperson", TEST_PASSWORD_INC)
+                .is_err());
+            match idms_prox_write.set_unix_account_password(
+                au,
+                &uat_admin,
+                "testperson",
+                "password",
+            ) {
+                Err(OperationError::PasswordQuality(_)) => {}
+                _ => panic!(),
+            }
+            assert!(idms_prox_write
+                .set_unix_account_password(au, &uat_admin, "testperson", TEST_PASSWORD_INC)
+                .is_ok());
+            idms_prox_write.commit(au).expect("Must not fail");
+            let (cred, _) = unix_password(au);
+            assert!(cred
+                .expect("Missing unix password")
+                .verify_password(TEST_PASSWORD_INC));
+
+            // An account that isn't a posix account has no unix password.
+            let mut idms_prox_write = idms.proxy_write();
+            assert!(idms_prox_write
+                .set_unix_account_password(au, &uat_other, "testother", TEST_PASSWORD)
+                .is_err());
         })
     }
 
     #[test]
-    fn test_idm_session_expire() {
+    fn test_idm_unix_auth() {
         run_idm_test!(|qs: &QueryServer, idms: &IdmServer, au: &mut AuditScope| {
-            init_admin_w_password(au, qs, TEST_PASSWORD).expect("Failed to setup admin account");
-            let sid = init_admin_authsession_sid(idms, au);
-            let mut idms_write = idms.write();
-            assert!(idms_write.is_sessionid_present(&sid));
-            // Expire like we are currently "now". Should not affect our session.
-            idms_write.expire_auth_sessions(Duration::from_secs(TEST_CURRENT_TIME));
-            assert!(idms_write.is_sessionid_present(&sid));
-            // Expire as though we are in the future.
-            idms_write.expire_auth_sessions(Duration::from_secs(TEST_CURRENT_EXPIRE));
-            assert!(!idms_write.is_sessionid_present(&sid));
-            assert!(idms_write.commit().is_ok());
-            let idms_write = idms.write();
-            assert!(!idms_write.is_sessionid_present(&sid));
+            init_unix_entries(qs, au);
+            let mut qs_write = qs.write();
+            add_member(
+                &mut qs_write,
+                au,
+                _UUID_IDM_UNIX_AUTH_SERVERS,
+                "6c3ec7a2-4c46-4c3a-9cf0-6b2b8c1f9d03",
+            );
+            qs_write.commit(au).expect("Must not fail");
+            let ct = Duration::from_secs(TEST_CURRENT_TIME);
+            let uat_person = init_uat_for(qs, au, "testperson", ct);
+            let mut idms_prox_write = idms.proxy_write();
+            assert!(idms_prox_write
+                .set_unix_account_password(au, &uat_person, "testperson", TEST_PASSWORD)
+                .is_ok());
+            idms_prox_write.commit(au).expect("Must not fail");
+
+            // Only service sessions in idm_unix_auth_servers may check.
+            let mut uat_unix = init_uat_for(qs, au, "testradius", ct);
+            let mut uat_other = init_uat_for(qs, au, "testother", ct);
+            uat_other.api_token = Some(Uuid::new_v4());
+
+            let auth_unix = |au: &mut AuditScope, uat: &UserAuthToken, id: &str, cred: &str, ct| {
+                let mut idms_write = idms.write();
+                let r = idms_write.auth_unix(au, uat, id, cred, ct);
+                idms_write.commit().expect("Must not fail");
+                r
+            };
+
+            match auth_unix(au, &uat_unix, "testperson", TEST_PASSWORD, ct) {
+                Err(OperationError::AccessDenied) => {}
+                _ => panic!(),
+            }
+            match auth_unix(au, &uat_other, "testperson", TEST_PASSWORD, ct) {
+                Err(OperationError::AccessDenied) => {}
+                _ => panic!(),
+            }
+            uat_unix.api_token = Some(Uuid::new_v4());
+
+            for id in &["testperson", "6c3ec7a2-4c46-4c3a-9cf0-6b2b8c1f9d01"] {
+                let ut = auth_unix(au, &uat_unix, id, TEST_PASSWORD, ct)
+                    .expect("Failed to check unix password")
+                    .expect("Unix password not accepted");
+                assert!(ut.name == "testperson");
+                assert!(ut.gidnumber == 203529474);
+            }
+
+            // A wrong password, an account that isn't posix, and one that
+            // doesn't exist all look the same.
+            for (id, cred) in &[
+                ("testperson", TEST_PASSWORD_INC),
+                ("testother", TEST_PASSWORD),
+                ("nosuchaccount", TEST_PASSWORD),
+            ] {
+                assert!(auth_unix(au, &uat_unix, id, cred, ct)
+                    .expect("Failed to check unix password")
+                    .is_none());
+            }
+
+            // Failures lock the account as they would in auth.
+            for _ in 1..AUTH_LOCKOUT_THRESHOLD {
+                assert!(
+                    auth_unix(au, &uat_unix, "testperson", TEST_PASSWORD_INC, ct)
+                        .expect("Failed to check unix password")
+                        .is_none()
+                );
+            }
+            assert!(auth_unix(au, &uat_unix, "testperson", TEST_PASSWORD, ct)
+                .expect("Failed to check unix password")
+                .is_none());
+            let ct = ct + Duration::from_secs(AUTH_LOCKOUT_WINDOW + 1);
+            assert!(auth_unix(au, &uat_unix, "testperson", TEST_PASSWORD, ct)
+                .expect("Failed to check unix password")
+                .is_some());
         })
     }
 }
@@ -0,0 +1,388 @@
+use crate::crypto::{es256_generate, es256_public_coordinates, es256_sign, es256_verify};
+use crate::entry::{Entry, EntryCommitted, EntryValid};
+use crate::modify::{Modify, ModifyInvalid, ModifyList};
+use crate::value::Value;
+use kanidm_proto::v1::OperationError;
+use kanidm_proto::v1::{Jwk, JwkSet, UserAuthToken};
+
+use chrono::{DateTime, Utc};
+use openssl::ec::EcKey;
+use openssl::pkey::Private;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::time::{Duration, SystemTime};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JwsHeader {
+    alg: String,
+    kid: String,
+    // UserAuthTokens have no type, and the tokens issued to oauth2 clients
+    // do, so that neither can be passed off as the other.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    typ: Option<String>,
+}
+
+#[derive(Clone)]
+struct TokenKey {
+    key: EcKey<Private>,
+    kid: String,
+}
+
+impl TokenKey {
+    fn new(key: EcKey<Private>) -> Result<Self, OperationError> {
+        // The kid is derived from the public key, so it's stable for as long
+        // as the key is.
+        let der = key
+            .public_key_to_der()
+            .map_err(|_| OperationError::CryptographyError)?;
+        let kid = openssl::sha::sha256(&der)[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        Ok(TokenKey { key: key, kid: kid })
+    }
+
+    fn from_der(der: &[u8]) -> Result<Self, OperationError> {
+        EcKey::private_key_from_der(der)
+            .map_err(|_| OperationError::CryptographyError)
+            .and_then(TokenKey::new)
+    }
+
+    fn to_der(&self) -> Result<Vec<u8>, OperationError> {
+        self.key
+            .private_key_to_der()
+            .map_err(|_| OperationError::CryptographyError)
+    }
+
+    fn to_jwk(&self) -> Result<Jwk, OperationError> {
+        let (x, y) =
+            es256_public_coordinates(&self.key).map_err(|_| OperationError::CryptographyError)?;
+        Ok(Jwk {
+            kty: "EC".to_string(),
+            crv: "P-256".to_string(),
+            x: base64::encode_config(&x, base64::URL_SAFE_NO_PAD),
+            y: base64::encode_config(&y, base64::URL_SAFE_NO_PAD),
+            kid: self.kid.clone(),
+            alg: "ES256".to_string(),
+            use_: "sig".to_string(),
+        })
+    }
+}
+
+// The keys that issued UserAuthTokens are signed with, as compact ES256 JWS.
+// They are generated on first start and kept on the domain_info entry. After
+// a rotation the previous key is still accepted for a grace period, so that
+// the sessions it signed are not all ended at once.
+#[derive(Clone)]
+pub struct TokenKeys {
+    current: TokenKey,
+    // The previous key, and when it was rotated out.
+    previous: Option<(TokenKey, Duration)>,
+    grace: Duration,
+}
+
+fn datetime_to_duration(dt: &DateTime<Utc>) -> Duration {
+    SystemTime::from(*dt)
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0))
+}
+
+impl TokenKeys {
+    pub fn generate(grace: Duration) -> Result<Self, OperationError> {
+        let key = es256_generate().map_err(|_| OperationError::CryptographyError)?;
+        Ok(TokenKeys {
+            current: TokenKey::new(key)?,
+            previous: None,
+            grace: grace,
+        })
+    }
+
+    // Load the keys from the domain_info entry, if they have been generated.
+    pub fn try_from_entry(
+        value: &Entry<EntryValid, EntryCommitted>,
+        grace: Duration,
+    ) -> Result<Option<Self>, OperationError> {
+        let current = match value
+            .get_ava_single("token_signing_key")
+            .and_then(|v| v.to_binary())
+        {
+            Some(der) => TokenKey::from_der(der)?,
+            None => return Ok(None),
+        };
+
+        let previous = match (
+            value
+                .get_ava_single("token_signing_key_previous")
+                .and_then(|v| v.to_binary()),
+            value
+                .get_ava_single("token_signing_key_rotated_at")
+                .and_then(|v| v.to_datetime()),
+        ) {
+            (Some(der), Some(dt)) => Some((TokenKey::from_der(der)?, datetime_to_duration(dt))),
+            _ => None,
+        };
+
+        Ok(Some(TokenKeys {
+            current: current,
+            previous: previous,
+            grace: grace,
+        }))
+    }
+
+    // Replace the current key with a new one, keeping the current key as
+    // the previous. Any key before that is no longer accepted at all.
+    pub fn rotate(self, ct: Duration) -> Result<Self, OperationError> {
+        let key = es256_generate().map_err(|_| OperationError::CryptographyError)?;
+        Ok(TokenKeys {
+            current: TokenKey::new(key)?,
+            previous: Some((self.current, ct)),
+            grace: self.grace,
+        })
+    }
+
+    pub fn to_modlist(&self) -> Result<ModifyList<ModifyInvalid>, OperationError> {
+        let mut mods = vec![
+            Modify::Purged("token_signing_key".to_string()),
+            Modify::Present(
+                "token_signing_key".to_string(),
+                Value::new_binary(self.current.to_der()?),
+            ),
+            Modify::Purged("token_signing_key_previous".to_string()),
+            Modify::Purged("token_signing_key_rotated_at".to_string()),
+        ];
+        if let Some((prev, rotated_at)) = &self.previous {
+            mods.push(Modify::Present(
+                "token_signing_key_previous".to_string(),
+                Value::new_binary(prev.to_der()?),
+            ));
+            mods.push(Modify::Present(
+                "token_signing_key_rotated_at".to_string(),
+                Value::new_datetime(DateTime::from(SystemTime::UNIX_EPOCH + *rotated_at)),
+            ));
+        }
+        Ok(ModifyList::new_list(mods))
+    }
+
+    // The public keys that a token presented at ct may be signed with.
+    pub fn to_jwkset(&self, ct: Duration) -> Result<JwkSet, OperationError> {
+        let mut keys = vec![self.current.to_jwk()?];
+        if let Some(prev) = self.previous_valid(ct) {
+            keys.push(prev.to_jwk()?);
+        }
+        Ok(JwkSet { keys: keys })
+    }
+
+    fn previous_valid(&self, ct: Duration) -> Option<&TokenKey> {
+        match &self.previous {
+            Some((prev, rotated_at)) if ct < *rotated_at + self.grace => Some(prev),
+            _ => None,
+        }
+    }
+
+    pub fn sign_uat(&self, uat: &UserAuthToken) -> Result<String, OperationError> {
+        self.sign(None, uat)
+    }
+
+    // Check the signature and expiry of a token at ct, returning the token
+    // only if both are good.
+    pub fn verify_uat(&self, token: &str, ct: Duration) -> Option<UserAuthToken> {
+        let uat: UserAuthToken = self.verify(None, token, ct)?;
+        if uat.is_expired(ct) {
+            None
+        } else {
+            Some(uat)
+        }
+    }
+
+    // Sign any payload as a compact JWS of type typ with the current key.
+    pub fn sign<T: Serialize>(
+        &self,
+        typ: Option<&str>,
+        payload: &T,
+    ) -> Result<String, OperationError> {
+        let header = JwsHeader {
+            alg: "ES256".to_string(),
+            kid: self.current.kid.clone(),
+            typ: typ.map(|t| t.to_string()),
+        };
+        let header = serde_json::to_vec(&header).map_err(|_| OperationError::SerdeJsonError)?;
+        let payload = serde_json::to_vec(payload).map_err(|_| OperationError::SerdeJsonError)?;
+
+        let signing_input = format!(
+            "{}.{}",
+            base64::encode_config(&header, base64::URL_SAFE_NO_PAD),
+            base64::encode_config(&payload, base64::URL_SAFE_NO_PAD)
+        );
+        let sig = es256_sign(&self.current.key, signing_input.as_bytes())
+            .map_err(|_| OperationError::CryptographyError)?;
+
+        Ok(format!(
+            "{}.{}",
+            signing_input,
+            base64::encode_config(&sig, base64::URL_SAFE_NO_PAD)
+        ))
+    }
+
+    // Check the signature and type of a token at ct, returning its payload.
+    // Whether the payload has expired is for the caller to check.
+    pub fn verify<T: DeserializeOwned>(
+        &self,
+        typ: Option<&str>,
+        token: &str,
+        ct: Duration,
+    ) -> Option<T> {
+        let parts: Vec<&str> = token.split('.').collect();
+        if parts.len() != 3 {
+            return None;
+        }
+
+        let header: JwsHeader = base64::decode_config(parts[0], base64::URL_SAFE_NO_PAD)
+            .ok()
+            .and_then(|h| serde_json::from_slice(&h).ok())?;
+        if header.alg != "ES256" || header.typ.as_ref().map(|t| t.as_str()) != typ {
+            return None;
+        }
+
+        let key = if header.kid == self.current.kid {
+            &self.current
+        } else {
+            match self.previous_valid(ct) {
+                Some(prev) if header.kid == prev.kid => prev,
+                _ => return None,
+            }
+        };
+
+        let sig = base64::decode_config(parts[2], base64::URL_SAFE_NO_PAD).ok()?;
+        let signing_input = &token[..parts[0].len() + 1 + parts[1].len()];
+        match es256_verify(&key.key, signing_input.as_bytes(), &sig) {
+            Ok(true) => {}
+            _ => return None,
+        }
+
+        base64::decode_config(parts[1], base64::URL_SAFE_NO_PAD)
+            .ok()
+            .and_then(|p| serde_json::from_slice(&p).ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::idm::tokenkeys::TokenKeys;
+    use kanidm_proto::v1::UserAuthToken;
+    use std::time::Duration;
+    use uuid::Uuid;
+
+    static TEST_CURRENT_TIME: u64 = 6000;
+    static TEST_GRACE: u64 = 3600;
+
+    fn test_uat() -> UserAuthToken {
+        UserAuthToken {
+            issued_at: TEST_CURRENT_TIME,
+            expiry: TEST_CURRENT_TIME + 86400,
+            auth_time: TEST_CURRENT_TIME,
+            sessionid: Uuid::new_v4(),
+            name: "admin".to_string(),
+            spn: "admin@localhost".to_string(),
+            displayname: "admin".to_string(),
+            uuid: "00000000-0000-0000-0000-000000000000".to_string(),
+            application: None,
+            groups: Vec::new(),
+            claims: Vec::new(),
+            must_change_password: false,
+            anonymous: false,
+            api_token: None,
+            read_only: false,
+        }
+    }
+
+    #[test]
+    fn test_idm_tokenkeys_sign_verify() {
+        let ct = Duration::from_secs(TEST_CURRENT_TIME);
+        let keys = TokenKeys::generate(Duration::from_secs(TEST_GRACE)).expect("keygen failed");
+        let token = keys.sign_uat(&test_uat()).expect("sign failed");
+
+        let uat = keys.verify_uat(token.as_str(), ct).expect("verify failed");
+        assert!(uat.name == "admin");
+
+        // Not after it has expired.
+        assert!(keys
+            .verify_uat(token.as_str(), Duration::from_secs(uat.expiry))
+            .is_none());
+
+        // Nor by a different key.
+        let other = TokenKeys::generate(Duration::from_secs(TEST_GRACE)).expect("keygen failed");
+        assert!(other.verify_uat(token.as_str(), ct).is_none());
+    }
+
+    #[test]
+    fn test_idm_tokenkeys_tampered() {
+        let ct = Duration::from_secs(TEST_CURRENT_TIME);
+        let keys = TokenKeys::generate(Duration::from_secs(TEST_GRACE)).expect("keygen failed");
+        let token = keys.sign_uat(&test_uat()).expect("sign failed");
+        let parts: Vec<&str> = token.split('.').collect();
+
+        // Swap in a payload claiming to be someone else.
+        let mut uat = test_uat();
+        uat.name = "hacker".to_string();
+        let payload = base64::encode_config(
+            &serde_json::to_vec(&uat).expect("json failed"),
+            base64::URL_SAFE_NO_PAD,
+        );
+        let tampered = format!("{}.{}.{}", parts[0], payload, parts[2]);
+        assert!(keys.verify_uat(tampered.as_str(), ct).is_none());
+
+        // Or strip the signature.
+        let unsigned = format!("{}.{}.", parts[0], parts[1]);
+        assert!(keys.verify_uat(unsigned.as_str(), ct).is_none());
+        assert!(keys.verify_uat("garbage", ct).is_none());
+    }
+
+    #[test]
+    fn test_idm_tokenkeys_typ() {
+        let ct = Duration::from_secs(TEST_CURRENT_TIME);
+        let keys = TokenKeys::generate(Duration::from_secs(TEST_GRACE)).expect("keygen failed");
+        let uat_token = keys.sign_uat(&test_uat()).expect("sign failed");
+        let typed_token = keys.sign(Some("at+jwt"), &test_uat()).expect("sign failed");
+
+        // A token is only accepted as the type it was signed as.
+        assert!(keys.verify_uat(typed_token.as_str(), ct).is_none());
+        assert!(keys
+            .verify::<UserAuthToken>(Some("at+jwt"), uat_token.as_str(), ct)
+            .is_none());
+        assert!(keys
+            .verify::<UserAuthToken>(Some("JWT"), typed_token.as_str(), ct)
+            .is_none());
+        assert!(keys
+            .verify::<UserAuthToken>(Some("at+jwt"), typed_token.as_str(), ct)
+            .is_some());
+    }
+
+    #[test]
+    fn test_idm_tokenkeys_rotate() {
+        let ct = Duration::from_secs(TEST_CURRENT_TIME);
+        let grace = Duration::from_secs(TEST_GRACE);
+        let keys = TokenKeys::generate(grace).expect("keygen failed");
+        let old_token = keys.sign_uat(&test_uat()).expect("sign failed");
+
+        let keys = keys.rotate(ct).expect("rotate failed");
+        let new_token = keys.sign_uat(&test_uat()).expect("sign failed");
+        assert!(keys.to_jwkset(ct).expect("jwk failed").keys.len() == 2);
+
+        // Both are accepted in the grace period.
+        let ct_grace = ct + grace - Duration::from_secs(1);
+        assert!(keys.verify_uat(old_token.as_str(), ct_grace).is_some());
+        assert!(keys.verify_uat(new_token.as_str(), ct_grace).is_some());
+
+        // After it, only the new key is, even though the old token has not
+        // itself expired.
+        let ct_after = ct + grace;
+        assert!(keys.verify_uat(old_token.as_str(), ct_after).is_none());
+        assert!(keys.verify_uat(new_token.as_str(), ct_after).is_some());
+        assert!(keys.to_jwkset(ct_after).expect("jwk failed").keys.len() == 1);
+
+        // A second rotation drops the original key entirely.
+        let keys = keys.rotate(ct).expect("rotate failed");
+        assert!(keys.verify_uat(old_token.as_str(), ct).is_none());
+        assert!(keys.verify_uat(new_token.as_str(), ct).is_some());
+    }
+}
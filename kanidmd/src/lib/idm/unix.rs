@@ -0,0 +1,226 @@
+use crate::audit::AuditScope;
+use crate::credential::{Credential, Password};
+use crate::entry::Entry;
+use crate::modify::{ModifyInvalid, ModifyList};
+use crate::server::QueryServerTransaction;
+use crate::value::{PartialValue, Value};
+use kanidm_proto::v1::OperationError;
+use kanidm_proto::v1::{UnixGroupToken, UnixUserToken};
+
+use uuid::Uuid;
+
+lazy_static! {
+    static ref PVCLASS_POSIXACCOUNT: PartialValue = PartialValue::new_class("posixaccount");
+    static ref PVCLASS_POSIXGROUP: PartialValue = PartialValue::new_class("posixgroup");
+    // Checked in place of a unix password when there is none to check, with
+    // the same kdf parameters as a real one.
+    static ref DUMMY_UNIX_PASSWORD: Password = Password::new("dummy unix password");
+}
+
+// Take as long as checking a unix password would, so that a refusal without
+// one doesn't tell the caller why the account was refused.
+pub(crate) fn verify_dummy_unix_credential(cleartext: &str) {
+    let _ = DUMMY_UNIX_PASSWORD.verify(cleartext);
+}
+
+// An account as a unix machine sees it. When built from an entry reduced by
+// access controls, this holds only what the reader may see.
+#[derive(Debug, Clone)]
+pub(crate) struct UnixUserAccount {
+    pub name: String,
+    pub displayname: String,
+    pub uuid: Uuid,
+    pub gidnumber: u32,
+    pub shell: Option<String>,
+    pub groups: Vec<UnixGroup>,
+    pub cred: Option<Credential>,
+}
+
+impl UnixUserAccount {
+    pub(crate) fn try_from_entry<VALID, STATE, T: QueryServerTransaction>(
+        au: &mut AuditScope,
+        value: &Entry<VALID, STATE>,
+        qs: &T,
+    ) -> Result<Self, OperationError> {
+        if !value.attribute_value_pres("class", &PVCLASS_POSIXACCOUNT) {
+            return Err(OperationError::InvalidAccountState(
+                "Missing class: posixaccount",
+            ));
+        }
+
+        let name = value
+            .get_ava_single("name")
+            .and_then(|v| v.as_string())
+            .cloned()
+            .ok_or(OperationError::InvalidAccountState(
+                "Missing attribute: name",
+            ))?;
+
+        let displayname = value
+            .get_ava_single("displayname")
+            .and_then(|v| v.as_string())
+            .cloned()
+            .ok_or(OperationError::InvalidAccountState(
+                "Missing attribute: displayname",
+            ))?;
+
+        let uuid = value
+            .get_ava_single("uuid")
+            .and_then(|v| v.to_uuid())
+            .cloned()
+            .ok_or(OperationError::InvalidAccountState(
+                "Missing attribute: uuid",
+            ))?;
+
+        let gidnumber = value
+            .get_ava_single("gidnumber")
+            .and_then(|v| v.to_uint32())
+            .ok_or(OperationError::InvalidAccountState(
+                "Missing attribute: gidnumber",
+            ))?;
+
+        let shell = value
+            .get_ava_single("loginshell")
+            .and_then(|v| v.as_string())
+            .cloned();
+
+        let cred = value
+            .get_ava_single("unix_password")
+            .and_then(|v| v.to_credential())
+            .cloned();
+
+        // The account is its own primary group, so it comes first. Groups
+        // that aren't posix groups have no meaning to a unix machine, so are
+        // left out.
+        let memberof: Vec<&Uuid> = value
+            .get_ava("memberof")
+            .map(|vs| vs.into_iter().filter_map(|v| v.to_ref_uuid()).collect())
+            .unwrap_or_default();
+        let mut groups = vec![UnixGroup {
+            name: name.clone(),
+            uuid: uuid,
+            gidnumber: gidnumber,
+        }];
+        groups.extend(try_audit!(au, UnixGroup::try_from_uuids(au, memberof, qs)));
+
+        Ok(UnixUserAccount {
+            name: name,
+            displayname: displayname,
+            uuid: uuid,
+            gidnumber: gidnumber,
+            shell: shell,
+            groups: groups,
+            cred: cred,
+        })
+    }
+
+    pub(crate) fn to_unixusertoken(&self) -> UnixUserToken {
+        UnixUserToken {
+            name: self.name.clone(),
+            displayname: self.displayname.clone(),
+            uuid: self.uuid.to_hyphenated_ref().to_string(),
+            gidnumber: self.gidnumber,
+            shell: self.shell.clone(),
+            groups: self.groups.iter().map(|g| g.to_unixgrouptoken()).collect(),
+        }
+    }
+
+    // An account without a unix password can't be authenticated as.
+    pub(crate) fn verify_unix_credential(&self, cleartext: &str) -> bool {
+        match &self.cred {
+            Some(c) => c.verify_password(cleartext),
+            None => {
+                verify_dummy_unix_credential(cleartext);
+                false
+            }
+        }
+    }
+
+    // The unix password has no other factors to keep, so it's always
+    // replaced by a new credential.
+    pub(crate) fn gen_password_mod(&self, cleartext: &str) -> ModifyList<ModifyInvalid> {
+        let ncred = Credential::new_password_only(cleartext);
+        let vcred = Value::new_credential("unix", ncred);
+        ModifyList::new_purge_and_set("unix_password", vcred)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct UnixGroup {
+    pub name: String,
+    pub uuid: Uuid,
+    pub gidnumber: u32,
+}
+
+impl UnixGroup {
+    // Resolve the posix groups among these uuids, ignoring any others.
+    pub(crate) fn try_from_uuids<T: QueryServerTransaction>(
+        au: &mut AuditScope,
+        uuids: Vec<&Uuid>,
+        qs: &T,
+    ) -> Result<Vec<Self>, OperationError> {
+        if uuids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let filt = filter!(f_and!([
+            f_eq("class", PVCLASS_POSIXGROUP.clone()),
+            f_or(
+                uuids
+                    .into_iter()
+                    .map(|u| f_eq("uuid", PartialValue::new_uuidr(u)))
+                    .collect()
+            )
+        ]));
+        let entries = try_audit!(au, qs.internal_search(au, filt));
+
+        entries.iter().map(UnixGroup::try_from_entry).collect()
+    }
+
+    pub(crate) fn try_from_entry<VALID, STATE>(
+        value: &Entry<VALID, STATE>,
+    ) -> Result<Self, OperationError> {
+        if !value.attribute_value_pres("class", &PVCLASS_POSIXGROUP) {
+            return Err(OperationError::InvalidAccountState(
+                "Missing class: posixgroup",
+            ));
+        }
+
+        let name = value
+            .get_ava_single("name")
+            .and_then(|v| v.as_string())
+            .cloned()
+            .ok_or(OperationError::InvalidAccountState(
+                "Missing attribute: name",
+            ))?;
+
+        let uuid = value
+            .get_ava_single("uuid")
+            .and_then(|v| v.to_uuid())
+            .cloned()
+            .ok_or(OperationError::InvalidAccountState(
+                "Missing attribute: uuid",
+            ))?;
+
+        let gidnumber = value
+            .get_ava_single("gidnumber")
+            .and_then(|v| v.to_uint32())
+            .ok_or(OperationError::InvalidAccountState(
+                "Missing attribute: gidnumber",
+            ))?;
+
+        Ok(UnixGroup {
+            name: name,
+            uuid: uuid,
+            gidnumber: gidnumber,
+        })
+    }
+
+    pub(crate) fn to_unixgrouptoken(&self) -> UnixGroupToken {
+        UnixGroupToken {
+            name: self.name.clone(),
+            uuid: self.uuid.to_hyphenated_ref().to_string(),
+            gidnumber: self.gidnumber,
+        }
+    }
+}
@@ -1,31 +1,83 @@
 use actix::prelude::*;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 use crate::actors::v1::QueryServerV1;
-use crate::constants::PURGE_TIMEOUT;
-use crate::event::{PurgeRecycledEvent, PurgeTombstoneEvent};
+use crate::config::OnlineBackup;
+use crate::constants::{DELAYED_ACTION_TIMEOUT, PURGE_TIMEOUT, VERIFY_TIMEOUT};
+use crate::event::{
+    DelayedActionEvent, OnlineBackupEvent, PurgeChangelogEvent, PurgeRecycledEvent,
+    PurgeTombstoneEvent, VerifyEvent,
+};
 
 pub struct IntervalActor {
-    // Store any addresses we require
-    server: actix::Addr<QueryServerV1>,
+    // Store any addresses we require. Purges write, so they are queued
+    // with the other writes.
+    server_read: actix::Addr<QueryServerV1>,
+    server_write: actix::Addr<QueryServerV1>,
+    // How long entries are retained in each state before being purged.
+    recycle_bin_max_age: Duration,
+    tombstone_max_age: Duration,
+    changelog_max_age: Duration,
+    backup: Option<OnlineBackup>,
 }
 
 impl IntervalActor {
-    pub fn new(server: actix::Addr<QueryServerV1>) -> Self {
-        IntervalActor { server: server }
+    pub fn new(
+        server_read: actix::Addr<QueryServerV1>,
+        server_write: actix::Addr<QueryServerV1>,
+        recycle_bin_max_age: u64,
+        tombstone_max_age: u64,
+        changelog_max_age: u64,
+        backup: Option<OnlineBackup>,
+    ) -> Self {
+        IntervalActor {
+            server_read: server_read,
+            server_write: server_write,
+            recycle_bin_max_age: Duration::from_secs(recycle_bin_max_age),
+            tombstone_max_age: Duration::from_secs(tombstone_max_age),
+            changelog_max_age: Duration::from_secs(changelog_max_age),
+            backup: backup,
+        }
     }
 
     // Define new events here
     fn purge_tombstones(&mut self) {
         // Make a purge request ...
-        let pe = PurgeTombstoneEvent::new();
-        self.server.do_send(pe)
+        let pe = PurgeTombstoneEvent::new(self.tombstone_max_age);
+        self.server_write.do_send(pe)
     }
 
     fn purge_recycled(&mut self) {
-        let pe = PurgeRecycledEvent::new();
-        self.server.do_send(pe)
+        let pe = PurgeRecycledEvent::new(self.recycle_bin_max_age);
+        self.server_write.do_send(pe)
     }
+
+    fn purge_changelog(&mut self) {
+        let pe = PurgeChangelogEvent::new(self.changelog_max_age);
+        self.server_write.do_send(pe)
+    }
+
+    fn delayed_actions(&mut self) {
+        self.server_write.do_send(DelayedActionEvent::new())
+    }
+
+    fn verify(&mut self) {
+        self.server_read.do_send(VerifyEvent::new())
+    }
+
+    fn online_backup(&mut self) {
+        self.server_read.do_send(OnlineBackupEvent::new())
+    }
+}
+
+// How long until the next multiple of interval since the epoch, so that
+// backups happen at the same times however long the server has been up.
+fn until_next_interval(interval: u64) -> Duration {
+    let ct = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Clock failure!");
+    let into = ct.as_secs() % interval;
+    Duration::from_secs(interval - into)
 }
 
 impl Actor for IntervalActor {
@@ -39,5 +91,26 @@ impl Actor for IntervalActor {
         ctx.run_interval(Duration::from_secs(PURGE_TIMEOUT), move |act, _ctx| {
             act.purge_tombstones();
         });
+        ctx.run_interval(Duration::from_secs(PURGE_TIMEOUT), move |act, _ctx| {
+            act.purge_changelog();
+        });
+        ctx.run_interval(
+            Duration::from_secs(DELAYED_ACTION_TIMEOUT),
+            move |act, _ctx| {
+                act.delayed_actions();
+            },
+        );
+        ctx.run_interval(Duration::from_secs(VERIFY_TIMEOUT), move |act, _ctx| {
+            act.verify();
+        });
+        if let Some(ob) = &self.backup {
+            let interval = Duration::from_secs(ob.interval);
+            ctx.run_later(until_next_interval(ob.interval), move |act, ctx| {
+                act.online_backup();
+                ctx.run_interval(interval, move |act, _ctx| {
+                    act.online_backup();
+                });
+            });
+        }
     }
 }
@@ -0,0 +1,646 @@
+// How ldap names, dns and filters are given as ours, and our entries as
+// ldap entries. Everything lives directly below the base dn made from the
+// domain, dc=example,dc=com for example.com, and each entry is named by its
+// name, as cn=alice,dc=example,dc=com with the default attribute map. The
+// spn, name and uuid of an entry are all accepted in a dn as well.
+use crate::ldap::proto::{
+    LdapFilter, LdapPartialAttribute, LdapResult, LdapResultCode, LdapSearchResultEntry,
+    LdapSubstring,
+};
+use kanidm_proto::v1::Entry as ProtoEntry;
+use kanidm_proto::v1::Filter as ProtoFilter;
+use kanidm_proto::v1::OperationError;
+use std::collections::{BTreeMap, BTreeSet};
+use uuid::Uuid;
+
+// The attributes holding references, which are given as the dns of the
+// entries they refer to.
+const LDAP_DN_ATTRS: [&'static str; 3] = ["member", "memberof", "directmemberof"];
+
+fn is_dn_attr(a: &str) -> bool {
+    LDAP_DN_ATTRS.iter().any(|d| *d == a)
+}
+
+const LDAP_SCHEMA_DN: &'static str = "cn=schema";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LdapTarget {
+    RootDse,
+    Schema,
+    Base,
+    // An entry below the base, and the filter that finds it.
+    Entry(ProtoFilter),
+}
+
+pub struct LdapGateway {
+    domain: String,
+    basedn: String,
+    base_rdns: Vec<(String, String)>,
+    // From the lower case ldap name to ours, and back.
+    to_kanidm: BTreeMap<String, String>,
+    to_ldap: BTreeMap<String, String>,
+}
+
+fn ldap_result(code: LdapResultCode, message: &str) -> LdapResult {
+    LdapResult::new(code, message)
+}
+
+pub fn basedn_from_domain(domain: &str) -> String {
+    domain
+        .split('.')
+        .map(|dc| format!("dc={}", escape_dn_value(dc)))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+// Escape the characters that are special in a dn value, as RFC 4514 gives.
+pub fn escape_dn_value(v: &str) -> String {
+    let mut r = String::with_capacity(v.len());
+    let last = v.chars().count().saturating_sub(1);
+    for (i, c) in v.chars().enumerate() {
+        match c {
+            ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=' => {
+                r.push('\\');
+                r.push(c);
+            }
+            '#' if i == 0 => r.push_str("\\#"),
+            ' ' if i == 0 || i == last => r.push_str("\\ "),
+            '\0' => r.push_str("\\00"),
+            c => r.push(c),
+        }
+    }
+    r
+}
+
+// Split a dn into its attribute and value pairs, with the attribute in lower
+// case and the value unescaped. Multi valued rdns are never used by us, so
+// they're refused with the rest of what can't be parsed.
+pub fn split_dn(dn: &str) -> Result<Vec<(String, String)>, ()> {
+    let mut rdns = Vec::new();
+    if dn.trim().is_empty() {
+        return Ok(rdns);
+    }
+    let bytes = dn.as_bytes();
+    let mut i = 0;
+    loop {
+        let eq = dn[i..].find('=').map(|p| p + i).ok_or(())?;
+        let attr = dn[i..eq].trim().to_lowercase();
+        if attr.is_empty() {
+            return Err(());
+        }
+        i = eq + 1;
+        let mut value: Vec<u8> = Vec::new();
+        // Unescaped trailing spaces are not part of the value.
+        let mut keep = 0;
+        while i < bytes.len() && bytes[i] == b' ' {
+            i += 1;
+        }
+        while i < bytes.len() {
+            match bytes[i] {
+                b',' => break,
+                b'+' => return Err(()),
+                b'\\' => {
+                    let hex = dn
+                        .get(i + 1..i + 3)
+                        .filter(|h| h.bytes().all(|b| b.is_ascii_hexdigit()))
+                        .and_then(|h| u8::from_str_radix(h, 16).ok());
+                    match (hex, bytes.get(i + 1)) {
+                        (Some(b), _) => {
+                            value.push(b);
+                            i += 3;
+                        }
+                        (None, Some(c)) => {
+                            value.push(*c);
+                            i += 2;
+                        }
+                        (None, None) => return Err(()),
+                    }
+                    keep = value.len();
+                }
+                c => {
+                    value.push(c);
+                    if c != b' ' {
+                        keep = value.len();
+                    }
+                    i += 1;
+                }
+            }
+        }
+        value.truncate(keep);
+        let value = String::from_utf8(value).map_err(|_| ())?;
+        if value.is_empty() {
+            return Err(());
+        }
+        rdns.push((attr, value));
+        if i >= bytes.len() {
+            break;
+        }
+        // Skip the comma.
+        i += 1;
+    }
+    Ok(rdns)
+}
+
+fn lower_rdns(rdns: &[(String, String)]) -> Vec<(String, String)> {
+    rdns.iter()
+        .map(|(a, v)| (a.clone(), v.to_lowercase()))
+        .collect()
+}
+
+impl LdapGateway {
+    // The attribute map is from ldap names to ours, and replaces the default
+    // map for any name it has.
+    pub fn new(domain: &str, attr_map: &BTreeMap<String, String>) -> Self {
+        let mut to_kanidm: BTreeMap<String, String> = crate::constants::LDAP_ATTR_MAP
+            .iter()
+            .map(|(l, k)| (l.to_string(), k.to_string()))
+            .collect();
+        attr_map.iter().for_each(|(l, k)| {
+            to_kanidm.insert(l.to_lowercase(), k.to_lowercase());
+        });
+        let to_ldap = to_kanidm
+            .iter()
+            .map(|(l, k)| (k.clone(), l.clone()))
+            .collect();
+        let basedn = basedn_from_domain(domain);
+        let base_rdns = split_dn(basedn.as_str()).unwrap_or_else(|_| Vec::new());
+        LdapGateway {
+            domain: domain.to_lowercase(),
+            basedn: basedn,
+            base_rdns: lower_rdns(&base_rdns),
+            to_kanidm: to_kanidm,
+            to_ldap: to_ldap,
+        }
+    }
+
+    pub fn basedn(&self) -> &str {
+        self.basedn.as_str()
+    }
+
+    pub fn attr_to_kanidm(&self, a: &str) -> String {
+        let a = a.to_lowercase();
+        self.to_kanidm.get(&a).cloned().unwrap_or(a)
+    }
+
+    pub fn attr_to_ldap(&self, a: &str) -> String {
+        self.to_ldap
+            .get(a)
+            .cloned()
+            .unwrap_or_else(|| a.to_string())
+    }
+
+    // The rdns of a dn below the base, or None if it's not below the base.
+    fn below_base(&self, rdns: &[(String, String)]) -> Option<usize> {
+        let n = rdns.len().checked_sub(self.base_rdns.len())?;
+        if lower_rdns(&rdns[n..]) == self.base_rdns {
+            Some(n)
+        } else {
+            None
+        }
+    }
+
+    // The name of an account from an spn of this domain, or from its name.
+    fn account_name(&self, v: &str) -> String {
+        match v.rfind('@') {
+            Some(p) if v[p + 1..].eq_ignore_ascii_case(self.domain.as_str()) => v[..p].to_string(),
+            _ => v.to_string(),
+        }
+    }
+
+    // What an rdn of an entry refers to, as a filter on our attributes.
+    fn rdn_filter(&self, attr: &str, value: &str) -> Option<ProtoFilter> {
+        match self.attr_to_kanidm(attr).as_str() {
+            "name" | "uid" => Some(ProtoFilter::Eq("name".to_string(), value.to_string())),
+            "spn" => Some(ProtoFilter::Eq(
+                "name".to_string(),
+                self.account_name(value),
+            )),
+            "uuid" => Some(ProtoFilter::Eq("uuid".to_string(), value.to_string())),
+            _ => None,
+        }
+    }
+
+    // What the base of a search refers to.
+    pub fn target(&self, dn: &str) -> Result<LdapTarget, LdapResult> {
+        let rdns =
+            split_dn(dn).map_err(|_| ldap_result(LdapResultCode::InvalidDNSyntax, "invalid dn"))?;
+        if rdns.is_empty() {
+            return Ok(LdapTarget::RootDse);
+        }
+        if lower_rdns(&rdns) == lower_rdns(&split_dn(LDAP_SCHEMA_DN).unwrap_or_default()) {
+            return Ok(LdapTarget::Schema);
+        }
+        let not_found = || {
+            let mut r = ldap_result(LdapResultCode::NoSuchObject, "no such object");
+            r.matcheddn = self.basedn.clone();
+            r
+        };
+        match self.below_base(&rdns) {
+            Some(0) => Ok(LdapTarget::Base),
+            Some(1) => self
+                .rdn_filter(rdns[0].0.as_str(), rdns[0].1.as_str())
+                .map(LdapTarget::Entry)
+                .ok_or_else(not_found),
+            Some(_) => Err(not_found()),
+            None => Err(ldap_result(LdapResultCode::NoSuchObject, "no such object")),
+        }
+    }
+
+    // The account a bind dn names. This may be a dn below the base or a
+    // single rdn, and a bare name or spn is accepted too.
+    pub fn bind_name(&self, dn: &str) -> Result<String, LdapResult> {
+        let invalid = || ldap_result(LdapResultCode::InvalidDNSyntax, "invalid bind dn");
+        if !dn.contains('=') {
+            return Ok(self.account_name(dn.trim()));
+        }
+        let rdns = split_dn(dn).map_err(|_| invalid())?;
+        let rdn = match (rdns.len(), self.below_base(&rdns)) {
+            (1, _) | (_, Some(1)) => &rdns[0],
+            _ => return Err(invalid()),
+        };
+        match self.rdn_filter(rdn.0.as_str(), rdn.1.as_str()) {
+            Some(ProtoFilter::Eq(ref a, ref v)) if a == "name" => Ok(v.clone()),
+            _ => Err(invalid()),
+        }
+    }
+
+    fn value_to_kanidm(&self, attr: &str, v: &str) -> String {
+        if !is_dn_attr(attr) {
+            return v.to_string();
+        }
+        // A reference is given as a dn, and we want the name or uuid in it.
+        match split_dn(v) {
+            Ok(ref rdns) if rdns.len() == 1 || self.below_base(rdns) == Some(1) => {
+                match self.rdn_filter(rdns[0].0.as_str(), rdns[0].1.as_str()) {
+                    Some(ProtoFilter::Eq(_, v)) => v,
+                    _ => v.to_string(),
+                }
+            }
+            _ => v.to_string(),
+        }
+    }
+
+    pub fn filter(&self, f: &LdapFilter) -> Result<ProtoFilter, LdapResult> {
+        let unwilling = |m: &str| ldap_result(LdapResultCode::UnwillingToPerform, m);
+        Ok(match f {
+            LdapFilter::And(fs) if fs.is_empty() => ProtoFilter::True,
+            LdapFilter::Or(fs) if fs.is_empty() => ProtoFilter::False,
+            LdapFilter::And(fs) => ProtoFilter::And(
+                fs.iter()
+                    .map(|f| self.filter(f))
+                    .collect::<Result<_, _>>()?,
+            ),
+            LdapFilter::Or(fs) => ProtoFilter::Or(
+                fs.iter()
+                    .map(|f| self.filter(f))
+                    .collect::<Result<_, _>>()?,
+            ),
+            LdapFilter::Not(f) => ProtoFilter::AndNot(Box::new(self.filter(f)?)),
+            LdapFilter::Equality(a, v) => {
+                let a = self.attr_to_kanidm(a);
+                let v = self.value_to_kanidm(a.as_str(), v);
+                ProtoFilter::Eq(a, v)
+            }
+            LdapFilter::Substring(
+                a,
+                LdapSubstring {
+                    initial: None,
+                    any,
+                    final_: None,
+                },
+            ) if any.len() == 1 => ProtoFilter::Sub(self.attr_to_kanidm(a), any[0].clone()),
+            LdapFilter::Substring(_, _) => {
+                return Err(unwilling(
+                    "only substring filters of the form (attr=*value*) are supported",
+                ))
+            }
+            LdapFilter::GreaterOrEqual(a, v) => ProtoFilter::Gte(self.attr_to_kanidm(a), v.clone()),
+            LdapFilter::LessOrEqual(a, v) => ProtoFilter::Lte(self.attr_to_kanidm(a), v.clone()),
+            LdapFilter::Present(a) => ProtoFilter::Pres(self.attr_to_kanidm(a)),
+            LdapFilter::Approx(_, _) | LdapFilter::Extensible => {
+                return Err(unwilling(
+                    "approximate and extensible matches are not supported",
+                ))
+            }
+        })
+    }
+
+    // The attributes of ours that a search asked for, or None for all of
+    // them. "*" asks for all, and "1.1" for none.
+    pub fn search_attrs(&self, attrs: &[String]) -> Option<BTreeSet<String>> {
+        if attrs.is_empty() || attrs.iter().any(|a| a == "*") {
+            return None;
+        }
+        Some(
+            attrs
+                .iter()
+                .filter(|a| a.as_str() != "1.1" && a.as_str() != "+")
+                .map(|a| self.attr_to_kanidm(a))
+                .collect(),
+        )
+    }
+
+    fn name_dn(&self, name: &str) -> String {
+        format!(
+            "{}={},{}",
+            self.attr_to_ldap("name"),
+            escape_dn_value(name),
+            self.basedn
+        )
+    }
+
+    fn uuid_dn(&self, uuid: &str) -> String {
+        format!("{}={},{}", self.attr_to_ldap("uuid"), uuid, self.basedn)
+    }
+
+    pub fn entry_dn(&self, e: &ProtoEntry) -> String {
+        match (e.get_ava_single("name"), e.get_ava_single("uuid")) {
+            (Some(name), _) => self.name_dn(name),
+            (None, Some(uuid)) => self.uuid_dn(uuid),
+            (None, None) => self.basedn.clone(),
+        }
+    }
+
+    // A reference is a name, or a uuid if what it refers to has no name.
+    fn reference_dn(&self, v: &str) -> String {
+        if Uuid::parse_str(v).is_ok() {
+            self.uuid_dn(v)
+        } else {
+            self.name_dn(v)
+        }
+    }
+
+    pub fn entry(
+        &self,
+        e: ProtoEntry,
+        attrs: &Option<BTreeSet<String>>,
+        typesonly: bool,
+    ) -> LdapSearchResultEntry {
+        let dn = self.entry_dn(&e);
+        let attributes = e
+            .attrs
+            .into_iter()
+            .filter(|(k, _)| attrs.as_ref().map(|s| s.contains(k)).unwrap_or(true))
+            .map(|(k, vs)| {
+                let vals = if typesonly {
+                    Vec::new()
+                } else if is_dn_attr(k.as_str()) {
+                    vs.iter().map(|v| self.reference_dn(v)).collect()
+                } else {
+                    vs
+                };
+                LdapPartialAttribute {
+                    atype: self.attr_to_ldap(k.as_str()),
+                    vals: vals,
+                }
+            })
+            .collect();
+        LdapSearchResultEntry {
+            dn: dn,
+            attributes: attributes,
+        }
+    }
+
+    fn fixed_entry(dn: &str, attrs: Vec<(&str, Vec<&str>)>) -> LdapSearchResultEntry {
+        LdapSearchResultEntry {
+            dn: dn.to_string(),
+            attributes: attrs
+                .into_iter()
+                .map(|(a, vs)| LdapPartialAttribute {
+                    atype: a.to_string(),
+                    vals: vs.into_iter().map(|v| v.to_string()).collect(),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn root_dse(&self) -> LdapSearchResultEntry {
+        Self::fixed_entry(
+            "",
+            vec![
+                ("objectClass", vec!["top"]),
+                ("namingContexts", vec![self.basedn.as_str()]),
+                ("supportedLDAPVersion", vec!["3"]),
+                ("subschemaSubentry", vec![LDAP_SCHEMA_DN]),
+                ("vendorName", vec!["Kanidm Project"]),
+            ],
+        )
+    }
+
+    // Enough schema for clients to read, not a description of ours.
+    pub fn schema(&self) -> LdapSearchResultEntry {
+        Self::fixed_entry(
+            LDAP_SCHEMA_DN,
+            vec![
+                ("objectClass", vec!["top", "subschema"]),
+                ("cn", vec!["schema"]),
+                (
+                    "attributeTypes",
+                    vec![
+                        "( 2.5.4.0 NAME 'objectClass' EQUALITY objectIdentifierMatch SYNTAX 1.3.6.1.4.1.1466.115.121.1.38 )",
+                        "( 2.5.4.3 NAME 'cn' EQUALITY caseIgnoreMatch SYNTAX 1.3.6.1.4.1.1466.115.121.1.15 )",
+                        "( 0.9.2342.19200300.100.1.25 NAME 'dc' EQUALITY caseIgnoreIA5Match SYNTAX 1.3.6.1.4.1.1466.115.121.1.26 SINGLE-VALUE )",
+                    ],
+                ),
+                (
+                    "objectClasses",
+                    vec![
+                        "( 2.5.6.0 NAME 'top' ABSTRACT MUST objectClass )",
+                        "( 0.9.2342.19200300.100.4.13 NAME 'domain' SUP top STRUCTURAL MUST dc )",
+                    ],
+                ),
+            ],
+        )
+    }
+
+    pub fn base(&self) -> LdapSearchResultEntry {
+        let dc = self.domain.split('.').next().unwrap_or("");
+        Self::fixed_entry(
+            self.basedn.as_str(),
+            vec![("objectClass", vec!["top", "domain"]), ("dc", vec![dc])],
+        )
+    }
+}
+
+pub fn operation_error(e: &OperationError) -> LdapResult {
+    let code = match e {
+        OperationError::NotAuthenticated | OperationError::AccessDenied => {
+            LdapResultCode::InsufficentAccessRights
+        }
+        OperationError::ResourceLimit
+        | OperationError::ResultLimit(_)
+        | OperationError::RateLimited(_) => LdapResultCode::AdminLimitExceeded,
+        OperationError::EmptyRequest
+        | OperationError::SchemaViolation(_)
+        | OperationError::FilterUUIDResolution
+        | OperationError::InvalidAttributeName(_)
+        | OperationError::InvalidAttribute(_)
+        | OperationError::InvalidRequestState => LdapResultCode::UnwillingToPerform,
+        OperationError::InvalidState | OperationError::InvalidDBState => {
+            LdapResultCode::OperationsError
+        }
+        _ => LdapResultCode::Other,
+    };
+    ldap_result(code, format!("{:?}", e).as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape_dn_value, split_dn, LdapGateway, LdapTarget};
+    use crate::ldap::proto::{LdapFilter, LdapResultCode, LdapSubstring};
+    use kanidm_proto::v1::Entry as ProtoEntry;
+    use kanidm_proto::v1::Filter as ProtoFilter;
+    use std::collections::{BTreeMap, BTreeSet};
+
+    fn s(v: &str) -> String {
+        v.to_string()
+    }
+
+    fn gateway() -> LdapGateway {
+        let mut map = BTreeMap::new();
+        map.insert(s("mail"), s("email"));
+        LdapGateway::new("example.com", &map)
+    }
+
+    #[test]
+    fn test_ldap_gateway_dn() {
+        assert!(split_dn("").unwrap().is_empty());
+        assert!(
+            split_dn("CN=a\\,b , dc=example,dc=com").unwrap()
+                == vec![
+                    (s("cn"), s("a,b")),
+                    (s("dc"), s("example")),
+                    (s("dc"), s("com"))
+                ]
+        );
+        assert!(split_dn("cn=\\41\\ ").unwrap() == vec![(s("cn"), s("A "))]);
+        assert!(split_dn("cn=a+uid=b").is_err());
+        assert!(split_dn("cn=").is_err());
+        assert!(split_dn("nothing").is_err());
+        assert!(escape_dn_value(" a,b=c ") == "\\ a\\,b\\=c\\ ");
+
+        let ldap = gateway();
+        assert!(ldap.basedn() == "dc=example,dc=com");
+        assert!(ldap.target("").unwrap() == LdapTarget::RootDse);
+        assert!(ldap.target("CN=Schema").unwrap() == LdapTarget::Schema);
+        assert!(ldap.target("DC=Example, DC=Com").unwrap() == LdapTarget::Base);
+        assert!(
+            ldap.target("cn=alice,dc=example,dc=com").unwrap()
+                == LdapTarget::Entry(ProtoFilter::Eq(s("name"), s("alice")))
+        );
+        assert!(
+            ldap.target("spn=alice@example.com,dc=example,dc=com")
+                .unwrap()
+                == LdapTarget::Entry(ProtoFilter::Eq(s("name"), s("alice")))
+        );
+        let r = ldap.target("cn=a,ou=b,dc=example,dc=com").unwrap_err();
+        assert!(r.code == LdapResultCode::NoSuchObject && r.matcheddn == "dc=example,dc=com");
+        assert!(ldap.target("dc=other").unwrap_err().code == LdapResultCode::NoSuchObject);
+        assert!(ldap.target("dc=,").unwrap_err().code == LdapResultCode::InvalidDNSyntax);
+
+        assert!(ldap.bind_name("alice").unwrap() == "alice");
+        assert!(ldap.bind_name("alice@example.com").unwrap() == "alice");
+        assert!(ldap.bind_name("alice@other.com").unwrap() == "alice@other.com");
+        assert!(ldap.bind_name("uid=alice").unwrap() == "alice");
+        assert!(ldap.bind_name("cn=alice,dc=example,dc=com").unwrap() == "alice");
+        assert!(ldap.bind_name("cn=alice,dc=other,dc=com").is_err());
+        assert!(ldap.bind_name("uuid=x,dc=example,dc=com").is_err());
+    }
+
+    #[test]
+    fn test_ldap_gateway_filter() {
+        let ldap = gateway();
+        let f = LdapFilter::And(vec![
+            LdapFilter::Equality(s("objectClass"), s("person")),
+            LdapFilter::Present(s("CN")),
+            LdapFilter::Not(Box::new(LdapFilter::Equality(s("mail"), s("a@b")))),
+            LdapFilter::Or(vec![
+                LdapFilter::Substring(
+                    s("cn"),
+                    LdapSubstring {
+                        initial: None,
+                        any: vec![s("li")],
+                        final_: None,
+                    },
+                ),
+                LdapFilter::Equality(s("memberOf"), s("cn=admins,dc=example,dc=com")),
+                LdapFilter::GreaterOrEqual(s("gidnumber"), s("10")),
+            ]),
+            LdapFilter::Or(vec![]),
+        ]);
+        assert!(
+            ldap.filter(&f).unwrap()
+                == ProtoFilter::And(vec![
+                    ProtoFilter::Eq(s("class"), s("person")),
+                    ProtoFilter::Pres(s("name")),
+                    ProtoFilter::AndNot(Box::new(ProtoFilter::Eq(s("email"), s("a@b")))),
+                    ProtoFilter::Or(vec![
+                        ProtoFilter::Sub(s("name"), s("li")),
+                        ProtoFilter::Eq(s("memberof"), s("admins")),
+                        ProtoFilter::Gte(s("gidnumber"), s("10")),
+                    ]),
+                    ProtoFilter::False,
+                ])
+        );
+
+        let prefix = LdapFilter::Substring(
+            s("cn"),
+            LdapSubstring {
+                initial: Some(s("a")),
+                any: vec![],
+                final_: None,
+            },
+        );
+        assert!(ldap.filter(&prefix).unwrap_err().code == LdapResultCode::UnwillingToPerform);
+        assert!(
+            ldap.filter(&LdapFilter::Extensible).unwrap_err().code
+                == LdapResultCode::UnwillingToPerform
+        );
+    }
+
+    #[test]
+    fn test_ldap_gateway_entry() {
+        let ldap = gateway();
+        let mut attrs = BTreeMap::new();
+        attrs.insert(s("name"), vec![s("alice")]);
+        attrs.insert(s("class"), vec![s("account"), s("person")]);
+        attrs.insert(
+            s("memberof"),
+            vec![s("admins"), s("d0a4ed4a-0b27-4d4e-b5b4-cc1e27e4c4f6")],
+        );
+        attrs.insert(s("email"), vec![s("alice@example.com")]);
+        attrs.insert(s("uuid"), vec![s("2a7bce5a-0c1b-4b5a-9e2a-5c0b3e4c1d2f")]);
+        let e = ProtoEntry { attrs: attrs };
+
+        let le = ldap.entry(e.clone(), &None, false);
+        assert!(le.dn == "cn=alice,dc=example,dc=com");
+        let get = |le: &super::LdapSearchResultEntry, a: &str| {
+            le.attributes
+                .iter()
+                .find(|pa| pa.atype == a)
+                .map(|pa| pa.vals.clone())
+        };
+        assert!(get(&le, "cn") == Some(vec![s("alice")]));
+        assert!(get(&le, "objectclass") == Some(vec![s("account"), s("person")]));
+        assert!(get(&le, "mail") == Some(vec![s("alice@example.com")]));
+        assert!(
+            get(&le, "memberof")
+                == Some(vec![
+                    s("cn=admins,dc=example,dc=com"),
+                    s("uuid=d0a4ed4a-0b27-4d4e-b5b4-cc1e27e4c4f6,dc=example,dc=com")
+                ])
+        );
+
+        // Only what was asked for is given, though the dn is always made.
+        let wanted = ldap.search_attrs(&[s("MAIL"), s("1.1")]);
+        let mut expect = BTreeSet::new();
+        expect.insert(s("email"));
+        assert!(wanted == Some(expect));
+        let le = ldap.entry(e.clone(), &wanted, true);
+        assert!(le.dn == "cn=alice,dc=example,dc=com");
+        assert!(le.attributes.len() == 1 && get(&le, "mail") == Some(vec![]));
+        assert!(ldap.search_attrs(&[s("cn"), s("*")]) == None);
+        assert!(ldap.search_attrs(&[]) == None);
+        assert!(ldap.search_attrs(&[s("1.1")]) == Some(BTreeSet::new()));
+    }
+}
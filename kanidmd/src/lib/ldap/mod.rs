@@ -0,0 +1,7 @@
+// A read only ldap gateway, for applications that can only speak ldap. A
+// bind authenticates with a password as any other auth does, and searches
+// are made as the session that gives, so the access controls apply just as
+// they do to the rest of the api. Nothing can be changed through ldap.
+pub(crate) mod gateway;
+pub(crate) mod proto;
+pub(crate) mod server;
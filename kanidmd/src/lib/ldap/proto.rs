@@ -0,0 +1,755 @@
+// The parts of LDAPv3 (RFC 4511) that the gateway speaks, and their BER
+// encoding. Messages are first read into a tree of tagged values, and the
+// operations are then picked out of that, so a malformed message can never
+// be half understood.
+//
+// Every tag LDAP uses is below 31, so tags are always a single byte, and
+// lengths are always definite.
+use bytes::{BufMut, BytesMut};
+use std::io;
+use tokio::codec::{Decoder, Encoder};
+
+// The largest message a client may send. Requests are small, and this stops
+// a client holding a large buffer open.
+const LDAP_MAX_MESSAGE: usize = 1024 * 1024;
+// How deeply values may nest. Filters are the only part that nests, and the
+// server limits their depth far below this.
+const LDAP_MAX_DEPTH: usize = 64;
+
+const TAG_BOOLEAN: u8 = 0x01;
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTETSTRING: u8 = 0x04;
+const TAG_ENUMERATED: u8 = 0x0a;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+
+const CLASS_APPLICATION: u8 = 0x40;
+const CLASS_CONTEXT: u8 = 0x80;
+const CONSTRUCTED: u8 = 0x20;
+
+const OP_BIND_REQUEST: u8 = 0;
+const OP_BIND_RESPONSE: u8 = 1;
+const OP_UNBIND_REQUEST: u8 = 2;
+const OP_SEARCH_REQUEST: u8 = 3;
+const OP_SEARCH_RESULT_ENTRY: u8 = 4;
+const OP_SEARCH_RESULT_DONE: u8 = 5;
+const OP_ABANDON_REQUEST: u8 = 16;
+const OP_EXTENDED_REQUEST: u8 = 23;
+const OP_EXTENDED_RESPONSE: u8 = 24;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LdapResultCode {
+    Success = 0,
+    OperationsError = 1,
+    ProtocolError = 2,
+    SizeLimitExceeded = 4,
+    AuthMethodNotSupported = 7,
+    AdminLimitExceeded = 11,
+    UnavailableCriticalExtension = 12,
+    NoSuchObject = 32,
+    InvalidDNSyntax = 34,
+    InvalidCredentials = 49,
+    InsufficentAccessRights = 50,
+    UnwillingToPerform = 53,
+    Other = 80,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapResult {
+    pub code: LdapResultCode,
+    pub matcheddn: String,
+    pub message: String,
+}
+
+impl LdapResult {
+    pub fn new(code: LdapResultCode, message: &str) -> Self {
+        LdapResult {
+            code: code,
+            matcheddn: String::new(),
+            message: message.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LdapBindCred {
+    Simple(String),
+    // The mechanism of a SASL bind. These are never accepted.
+    Sasl(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapBindRequest {
+    pub version: i64,
+    pub dn: String,
+    pub cred: LdapBindCred,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LdapSearchScope {
+    Base = 0,
+    OneLevel = 1,
+    Subtree = 2,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapSubstring {
+    pub initial: Option<String>,
+    pub any: Vec<String>,
+    pub final_: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LdapFilter {
+    And(Vec<LdapFilter>),
+    Or(Vec<LdapFilter>),
+    Not(Box<LdapFilter>),
+    Equality(String, String),
+    Substring(String, LdapSubstring),
+    GreaterOrEqual(String, String),
+    LessOrEqual(String, String),
+    Present(String),
+    Approx(String, String),
+    Extensible,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapSearchRequest {
+    pub base: String,
+    pub scope: LdapSearchScope,
+    pub sizelimit: i64,
+    pub typesonly: bool,
+    pub filter: LdapFilter,
+    pub attrs: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapPartialAttribute {
+    pub atype: String,
+    pub vals: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapSearchResultEntry {
+    pub dn: String,
+    pub attributes: Vec<LdapPartialAttribute>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LdapOp {
+    BindRequest(LdapBindRequest),
+    BindResponse(LdapResult),
+    UnbindRequest,
+    SearchRequest(LdapSearchRequest),
+    SearchResultEntry(LdapSearchResultEntry),
+    SearchResultDone(LdapResult),
+    AbandonRequest(i64),
+    ExtendedResponse(LdapResult),
+    // Any other request. This is the application tag of the request, and
+    // the response to it is always the tag after.
+    Unsupported(u8),
+    // The response to an unsupported request, by its application tag.
+    UnsupportedResponse(u8, LdapResult),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapMsg {
+    pub msgid: i64,
+    pub op: LdapOp,
+    // The oids of the critical controls sent with the request. None are
+    // supported, so a request with any is refused.
+    pub critical_controls: Vec<String>,
+}
+
+impl LdapMsg {
+    pub fn new(msgid: i64, op: LdapOp) -> Self {
+        LdapMsg {
+            msgid: msgid,
+            op: op,
+            critical_controls: Vec::new(),
+        }
+    }
+}
+
+impl LdapOp {
+    // The response to a request refused with this result, or None for the
+    // requests that are never answered.
+    pub fn response(&self, result: LdapResult) -> Option<LdapOp> {
+        match self {
+            LdapOp::BindRequest(_) => Some(LdapOp::BindResponse(result)),
+            LdapOp::SearchRequest(_) => Some(LdapOp::SearchResultDone(result)),
+            LdapOp::Unsupported(OP_EXTENDED_REQUEST) => Some(LdapOp::ExtendedResponse(result)),
+            LdapOp::Unsupported(tag) => Some(LdapOp::UnsupportedResponse(tag + 1, result)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum BerData {
+    Primitive(Vec<u8>),
+    Constructed(Vec<BerValue>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct BerValue {
+    tag: u8,
+    data: BerData,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Read the tag and length at the start of buf. This gives None until the
+// whole header is there.
+fn read_header(buf: &[u8]) -> Result<Option<(u8, usize, usize)>, io::Error> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let tag = buf[0];
+    if tag & 0x1f == 0x1f {
+        return Err(invalid("multi byte tags are not used by ldap"));
+    }
+    let first = buf[1];
+    if first & 0x80 == 0 {
+        return Ok(Some((tag, 2, first as usize)));
+    }
+    let n = (first & 0x7f) as usize;
+    if n == 0 {
+        return Err(invalid("indefinite lengths are not allowed"));
+    }
+    if n > 4 {
+        return Err(invalid("length is too large"));
+    }
+    if buf.len() < 2 + n {
+        return Ok(None);
+    }
+    let len = buf[2..2 + n]
+        .iter()
+        .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+    Ok(Some((tag, 2 + n, len)))
+}
+
+fn parse_values(mut buf: &[u8], depth: usize) -> Result<Vec<BerValue>, io::Error> {
+    if depth > LDAP_MAX_DEPTH {
+        return Err(invalid("values are nested too deeply"));
+    }
+    let mut values = Vec::new();
+    while !buf.is_empty() {
+        let (tag, hlen, len) = read_header(buf)?.ok_or_else(|| invalid("truncated value"))?;
+        let end = hlen
+            .checked_add(len)
+            .filter(|end| *end <= buf.len())
+            .ok_or_else(|| invalid("truncated value"))?;
+        let content = &buf[hlen..end];
+        let data = if tag & CONSTRUCTED != 0 {
+            BerData::Constructed(parse_values(content, depth + 1)?)
+        } else {
+            BerData::Primitive(content.to_vec())
+        };
+        values.push(BerValue {
+            tag: tag,
+            data: data,
+        });
+        buf = &buf[end..];
+    }
+    Ok(values)
+}
+
+fn write_length(out: &mut Vec<u8>, len: usize) {
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len
+            .to_be_bytes()
+            .iter()
+            .skip_while(|b| **b == 0)
+            .cloned()
+            .collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend(bytes);
+    }
+}
+
+impl BerValue {
+    fn primitive(tag: u8, data: Vec<u8>) -> Self {
+        BerValue {
+            tag: tag,
+            data: BerData::Primitive(data),
+        }
+    }
+
+    fn constructed(tag: u8, values: Vec<BerValue>) -> Self {
+        BerValue {
+            tag: tag | CONSTRUCTED,
+            data: BerData::Constructed(values),
+        }
+    }
+
+    fn string(s: &str) -> Self {
+        BerValue::primitive(TAG_OCTETSTRING, s.as_bytes().to_vec())
+    }
+
+    // Integers are two's complement, in as few bytes as keep the sign.
+    fn integer(tag: u8, i: i64) -> Self {
+        let bytes = i.to_be_bytes();
+        let mut start = 0;
+        while start < 7 {
+            let redundant = (bytes[start] == 0x00 && bytes[start + 1] & 0x80 == 0)
+                || (bytes[start] == 0xff && bytes[start + 1] & 0x80 != 0);
+            if !redundant {
+                break;
+            }
+            start += 1;
+        }
+        BerValue::primitive(tag, bytes[start..].to_vec())
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.push(self.tag);
+        match &self.data {
+            BerData::Primitive(d) => {
+                write_length(out, d.len());
+                out.extend(d);
+            }
+            BerData::Constructed(vs) => {
+                let mut inner = Vec::new();
+                vs.iter().for_each(|v| v.write(&mut inner));
+                write_length(out, inner.len());
+                out.extend(inner);
+            }
+        }
+    }
+
+    fn expect_tag(&self, tag: u8) -> Result<&Self, io::Error> {
+        if self.tag == tag {
+            Ok(self)
+        } else {
+            Err(invalid("unexpected tag"))
+        }
+    }
+
+    fn as_bytes(&self) -> Result<&[u8], io::Error> {
+        match &self.data {
+            BerData::Primitive(d) => Ok(d.as_slice()),
+            BerData::Constructed(_) => Err(invalid("expected a primitive value")),
+        }
+    }
+
+    fn as_values(&self) -> Result<&[BerValue], io::Error> {
+        match &self.data {
+            BerData::Constructed(vs) => Ok(vs.as_slice()),
+            BerData::Primitive(_) => Err(invalid("expected a constructed value")),
+        }
+    }
+
+    fn as_string(&self) -> Result<String, io::Error> {
+        String::from_utf8(self.as_bytes()?.to_vec()).map_err(|_| invalid("invalid utf8"))
+    }
+
+    fn as_integer(&self) -> Result<i64, io::Error> {
+        let d = self.as_bytes()?;
+        if d.is_empty() || d.len() > 8 {
+            return Err(invalid("invalid integer"));
+        }
+        let init: i64 = if d[0] & 0x80 != 0 { -1 } else { 0 };
+        Ok(d.iter().fold(init, |acc, b| (acc << 8) | *b as i64))
+    }
+
+    fn as_bool(&self) -> Result<bool, io::Error> {
+        match self.as_bytes()? {
+            [b] => Ok(*b != 0),
+            _ => Err(invalid("invalid boolean")),
+        }
+    }
+}
+
+fn value_at(vs: &[BerValue], i: usize) -> Result<&BerValue, io::Error> {
+    vs.get(i).ok_or_else(|| invalid("missing value"))
+}
+
+fn parse_filter(v: &BerValue) -> Result<LdapFilter, io::Error> {
+    let ava = |v: &BerValue| -> Result<(String, String), io::Error> {
+        let vs = v.as_values()?;
+        Ok((value_at(vs, 0)?.as_string()?, value_at(vs, 1)?.as_string()?))
+    };
+    let context = CLASS_CONTEXT | CONSTRUCTED;
+    match v.tag {
+        t if t == context => v
+            .as_values()?
+            .iter()
+            .map(parse_filter)
+            .collect::<Result<_, _>>()
+            .map(LdapFilter::And),
+        t if t == context | 1 => v
+            .as_values()?
+            .iter()
+            .map(parse_filter)
+            .collect::<Result<_, _>>()
+            .map(LdapFilter::Or),
+        t if t == context | 2 => match v.as_values()? {
+            [f] => Ok(LdapFilter::Not(Box::new(parse_filter(f)?))),
+            _ => Err(invalid("not must hold one filter")),
+        },
+        t if t == context | 3 => ava(v).map(|(a, v)| LdapFilter::Equality(a, v)),
+        t if t == context | 4 => {
+            let vs = v.as_values()?;
+            let attr = value_at(vs, 0)?.as_string()?;
+            let mut sub = LdapSubstring {
+                initial: None,
+                any: Vec::new(),
+                final_: None,
+            };
+            for s in value_at(vs, 1)?.expect_tag(TAG_SEQUENCE)?.as_values()? {
+                match s.tag {
+                    t if t == CLASS_CONTEXT => sub.initial = Some(s.as_string()?),
+                    t if t == CLASS_CONTEXT | 1 => sub.any.push(s.as_string()?),
+                    t if t == CLASS_CONTEXT | 2 => sub.final_ = Some(s.as_string()?),
+                    _ => return Err(invalid("unknown substring")),
+                }
+            }
+            Ok(LdapFilter::Substring(attr, sub))
+        }
+        t if t == context | 5 => ava(v).map(|(a, v)| LdapFilter::GreaterOrEqual(a, v)),
+        t if t == context | 6 => ava(v).map(|(a, v)| LdapFilter::LessOrEqual(a, v)),
+        t if t == CLASS_CONTEXT | 7 => v.as_string().map(LdapFilter::Present),
+        t if t == context | 8 => ava(v).map(|(a, v)| LdapFilter::Approx(a, v)),
+        t if t == context | 9 => Ok(LdapFilter::Extensible),
+        _ => Err(invalid("unknown filter")),
+    }
+}
+
+fn parse_op(v: &BerValue) -> Result<LdapOp, io::Error> {
+    if v.tag & 0xc0 != CLASS_APPLICATION {
+        return Err(invalid("expected a protocol op"));
+    }
+    let op = v.tag & 0x1f;
+    match op {
+        OP_BIND_REQUEST => {
+            let vs = v.as_values()?;
+            let cred = value_at(vs, 2)?;
+            let cred = match cred.tag {
+                t if t == CLASS_CONTEXT => LdapBindCred::Simple(cred.as_string()?),
+                t if t == CLASS_CONTEXT | CONSTRUCTED | 3 => {
+                    LdapBindCred::Sasl(value_at(cred.as_values()?, 0)?.as_string()?)
+                }
+                _ => return Err(invalid("unknown authentication choice")),
+            };
+            Ok(LdapOp::BindRequest(LdapBindRequest {
+                version: value_at(vs, 0)?.as_integer()?,
+                dn: value_at(vs, 1)?.as_string()?,
+                cred: cred,
+            }))
+        }
+        OP_UNBIND_REQUEST => Ok(LdapOp::UnbindRequest),
+        OP_SEARCH_REQUEST => {
+            let vs = v.as_values()?;
+            let scope = match value_at(vs, 1)?.as_integer()? {
+                0 => LdapSearchScope::Base,
+                1 => LdapSearchScope::OneLevel,
+                2 => LdapSearchScope::Subtree,
+                _ => return Err(invalid("unknown search scope")),
+            };
+            let attrs = value_at(vs, 7)?
+                .expect_tag(TAG_SEQUENCE)?
+                .as_values()?
+                .iter()
+                .map(|a| a.as_string())
+                .collect::<Result<_, _>>()?;
+            Ok(LdapOp::SearchRequest(LdapSearchRequest {
+                base: value_at(vs, 0)?.as_string()?,
+                scope: scope,
+                sizelimit: value_at(vs, 3)?.as_integer()?,
+                typesonly: value_at(vs, 5)?.as_bool()?,
+                filter: parse_filter(value_at(vs, 6)?)?,
+                attrs: attrs,
+            }))
+        }
+        OP_ABANDON_REQUEST => v.as_integer().map(LdapOp::AbandonRequest),
+        // Modify, add, delete, modify dn, compare and extended requests.
+        6 | 8 | 10 | 12 | 14 | OP_EXTENDED_REQUEST => Ok(LdapOp::Unsupported(op)),
+        _ => Err(invalid("unexpected protocol op")),
+    }
+}
+
+fn parse_msg(v: &BerValue) -> Result<LdapMsg, io::Error> {
+    let vs = v.expect_tag(TAG_SEQUENCE)?.as_values()?;
+    let msgid = value_at(vs, 0)?.expect_tag(TAG_INTEGER)?.as_integer()?;
+    let op = parse_op(value_at(vs, 1)?)?;
+    let mut critical_controls = Vec::new();
+    if let Some(controls) = vs.get(2) {
+        for c in controls
+            .expect_tag(CLASS_CONTEXT | CONSTRUCTED)?
+            .as_values()?
+        {
+            let cvs = c.as_values()?;
+            let critical = match cvs.get(1) {
+                Some(b) if b.tag == TAG_BOOLEAN => b.as_bool()?,
+                _ => false,
+            };
+            if critical {
+                critical_controls.push(value_at(cvs, 0)?.as_string()?);
+            }
+        }
+    }
+    Ok(LdapMsg {
+        msgid: msgid,
+        op: op,
+        critical_controls: critical_controls,
+    })
+}
+
+fn result_values(r: &LdapResult) -> Vec<BerValue> {
+    vec![
+        BerValue::integer(TAG_ENUMERATED, r.code as i64),
+        BerValue::string(r.matcheddn.as_str()),
+        BerValue::string(r.message.as_str()),
+    ]
+}
+
+fn op_value(op: &LdapOp) -> Result<BerValue, io::Error> {
+    let app =
+        |tag: u8, values: Vec<BerValue>| BerValue::constructed(CLASS_APPLICATION | tag, values);
+    Ok(match op {
+        LdapOp::BindResponse(r) => app(OP_BIND_RESPONSE, result_values(r)),
+        LdapOp::SearchResultEntry(e) => {
+            let attrs = e
+                .attributes
+                .iter()
+                .map(|a| {
+                    BerValue::constructed(
+                        TAG_SEQUENCE,
+                        vec![
+                            BerValue::string(a.atype.as_str()),
+                            BerValue::constructed(
+                                TAG_SET,
+                                a.vals.iter().map(|v| BerValue::string(v)).collect(),
+                            ),
+                        ],
+                    )
+                })
+                .collect();
+            app(
+                OP_SEARCH_RESULT_ENTRY,
+                vec![
+                    BerValue::string(e.dn.as_str()),
+                    BerValue::constructed(TAG_SEQUENCE, attrs),
+                ],
+            )
+        }
+        LdapOp::SearchResultDone(r) => app(OP_SEARCH_RESULT_DONE, result_values(r)),
+        LdapOp::ExtendedResponse(r) => app(OP_EXTENDED_RESPONSE, result_values(r)),
+        LdapOp::UnsupportedResponse(tag, r) => app(*tag, result_values(r)),
+        _ => return Err(invalid("only responses are sent")),
+    })
+}
+
+pub struct LdapCodec;
+
+impl Decoder for LdapCodec {
+    type Item = LdapMsg;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<LdapMsg>, io::Error> {
+        let (hlen, len) = match read_header(&buf)? {
+            Some((_, hlen, len)) => (hlen, len),
+            None => return Ok(None),
+        };
+        if len > LDAP_MAX_MESSAGE {
+            return Err(invalid("message is too large"));
+        }
+        if buf.len() < hlen + len {
+            buf.reserve(hlen + len - buf.len());
+            return Ok(None);
+        }
+        let msg = buf.split_to(hlen + len);
+        let values = parse_values(&msg, 0)?;
+        match values.as_slice() {
+            [v] => parse_msg(v).map(Some),
+            _ => Err(invalid("expected one message")),
+        }
+    }
+}
+
+impl Encoder for LdapCodec {
+    type Item = LdapMsg;
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: LdapMsg, buf: &mut BytesMut) -> Result<(), io::Error> {
+        let v = BerValue::constructed(
+            TAG_SEQUENCE,
+            vec![
+                BerValue::integer(TAG_INTEGER, msg.msgid),
+                op_value(&msg.op)?,
+            ],
+        );
+        let mut out = Vec::new();
+        v.write(&mut out);
+        buf.reserve(out.len());
+        buf.put_slice(out.as_slice());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> Result<Option<LdapMsg>, io::Error> {
+        let mut buf = BytesMut::from(bytes.to_vec());
+        LdapCodec.decode(&mut buf)
+    }
+
+    #[test]
+    fn test_ldap_proto_bind() {
+        // A simple bind of cn=admin with the password "secret", as sent by
+        // ldapsearch.
+        let bytes = [
+            0x30, 0x1a, 0x02, 0x01, 0x01, 0x60, 0x15, 0x02, 0x01, 0x03, 0x04, 0x08, b'c', b'n',
+            b'=', b'a', b'd', b'm', b'i', b'n', 0x80, 0x06, b's', b'e', b'c', b'r', b'e', b't',
+        ];
+        // A partial message waits for the rest.
+        assert!(decode(&bytes[..10]).unwrap() == None);
+        let msg = decode(&bytes).unwrap().unwrap();
+        assert!(msg.msgid == 1);
+        assert!(
+            msg.op
+                == LdapOp::BindRequest(LdapBindRequest {
+                    version: 3,
+                    dn: "cn=admin".to_string(),
+                    cred: LdapBindCred::Simple("secret".to_string()),
+                })
+        );
+
+        let mut buf = BytesMut::new();
+        LdapCodec
+            .encode(
+                LdapMsg::new(
+                    1,
+                    LdapOp::BindResponse(LdapResult::new(LdapResultCode::Success, "")),
+                ),
+                &mut buf,
+            )
+            .unwrap();
+        assert!(
+            buf.as_ref()
+                == [
+                    0x30, 0x0c, 0x02, 0x01, 0x01, 0x61, 0x07, 0x0a, 0x01, 0x00, 0x04, 0x00, 0x04,
+                    0x00
+                ]
+        );
+    }
+
+    #[test]
+    fn test_ldap_proto_search() {
+        // base "dc=example", subtree, (&(objectClass=person)(cn=*)(!(uid=a*))),
+        // asking for cn.
+        let filter = BerValue::constructed(
+            CLASS_CONTEXT,
+            vec![
+                BerValue::constructed(
+                    CLASS_CONTEXT | 3,
+                    vec![BerValue::string("objectClass"), BerValue::string("person")],
+                ),
+                BerValue::primitive(CLASS_CONTEXT | 7, b"cn".to_vec()),
+                BerValue::constructed(
+                    CLASS_CONTEXT | 2,
+                    vec![BerValue::constructed(
+                        CLASS_CONTEXT | 4,
+                        vec![
+                            BerValue::string("uid"),
+                            BerValue::constructed(
+                                TAG_SEQUENCE,
+                                vec![BerValue::primitive(CLASS_CONTEXT, b"a".to_vec())],
+                            ),
+                        ],
+                    )],
+                ),
+            ],
+        );
+        let req = BerValue::constructed(
+            TAG_SEQUENCE,
+            vec![
+                BerValue::integer(TAG_INTEGER, 300),
+                BerValue::constructed(
+                    CLASS_APPLICATION | OP_SEARCH_REQUEST,
+                    vec![
+                        BerValue::string("dc=example"),
+                        BerValue::integer(TAG_ENUMERATED, 2),
+                        BerValue::integer(TAG_ENUMERATED, 0),
+                        BerValue::integer(TAG_INTEGER, 10),
+                        BerValue::integer(TAG_INTEGER, 0),
+                        BerValue::primitive(TAG_BOOLEAN, vec![0]),
+                        filter,
+                        BerValue::constructed(TAG_SEQUENCE, vec![BerValue::string("cn")]),
+                    ],
+                ),
+            ],
+        );
+        let mut bytes = Vec::new();
+        req.write(&mut bytes);
+
+        let msg = decode(&bytes).unwrap().unwrap();
+        assert!(msg.msgid == 300);
+        assert!(
+            msg.op
+                == LdapOp::SearchRequest(LdapSearchRequest {
+                    base: "dc=example".to_string(),
+                    scope: LdapSearchScope::Subtree,
+                    sizelimit: 10,
+                    typesonly: false,
+                    filter: LdapFilter::And(vec![
+                        LdapFilter::Equality("objectClass".to_string(), "person".to_string()),
+                        LdapFilter::Present("cn".to_string()),
+                        LdapFilter::Not(Box::new(LdapFilter::Substring(
+                            "uid".to_string(),
+                            LdapSubstring {
+                                initial: Some("a".to_string()),
+                                any: Vec::new(),
+                                final_: None,
+                            }
+                        ))),
+                    ]),
+                    attrs: vec!["cn".to_string()],
+                })
+        );
+    }
+
+    #[test]
+    fn test_ldap_proto_unsupported_and_malformed() {
+        // A delete of "cn=a" is recognised, so it can be refused.
+        let msg = decode(&[
+            0x30, 0x09, 0x02, 0x01, 0x02, 0x4a, 0x04, b'c', b'n', b'=', b'a',
+        ])
+        .unwrap()
+        .unwrap();
+        assert!(msg.op == LdapOp::Unsupported(10));
+        assert!(
+            msg.op
+                .response(LdapResult::new(LdapResultCode::UnwillingToPerform, ""))
+                == Some(LdapOp::UnsupportedResponse(
+                    11,
+                    LdapResult::new(LdapResultCode::UnwillingToPerform, "")
+                ))
+        );
+
+        // Inner lengths that run past the message, indefinite lengths and
+        // responses are all rejected.
+        assert!(decode(&[0x30, 0x05, 0x02, 0x09, 0x01, 0x42, 0x00]).is_err());
+        assert!(decode(&[0x30, 0x80, 0x00, 0x00]).is_err());
+        assert!(decode(&[0x30, 0x05, 0x02, 0x01, 0x01, 0x41, 0x00]).is_err());
+        // As are messages larger than we accept.
+        assert!(decode(&[0x30, 0x84, 0x7f, 0xff, 0xff, 0xff]).is_err());
+    }
+
+    #[test]
+    fn test_ldap_proto_integer() {
+        for i in [0, 1, 127, 128, 255, 256, -1, -128, -129, 2147483647].iter() {
+            let v = BerValue::integer(TAG_INTEGER, *i);
+            assert!(v.as_integer().unwrap() == *i);
+        }
+        assert!(BerValue::integer(TAG_INTEGER, 128).as_bytes().unwrap() == [0x00, 0x80]);
+        assert!(BerValue::integer(TAG_INTEGER, -128).as_bytes().unwrap() == [0x80]);
+    }
+}
@@ -0,0 +1,439 @@
+// The ldap listener. Each connection reads its requests in turn, and answers
+// each before reading the next. A bind is two steps of a normal auth, made
+// through the write worker, and searches are made through the read workers
+// with the session the connection is bound as.
+use crate::actors::v1::{AuthMessage, QueryServerV1, SearchMessage};
+use crate::idm::server::IdmServer;
+use crate::ldap::gateway::{operation_error, LdapGateway, LdapTarget};
+use crate::ldap::proto::{
+    LdapBindCred, LdapBindRequest, LdapCodec, LdapMsg, LdapOp, LdapResult, LdapResultCode,
+    LdapSearchRequest, LdapSearchResultEntry, LdapSearchScope,
+};
+use crate::metrics::Metrics;
+use crate::ratelimit::{RateLimitKind, RateLimiter};
+
+use actix::{Addr, Arbiter};
+use futures::future::Either;
+use futures::{future, stream, Future, Sink, Stream};
+use kanidm_proto::v1::Filter as ProtoFilter;
+use kanidm_proto::v1::{
+    AuthAllowed, AuthCredential, AuthRequest, AuthResponse, AuthState, AuthStep, OperationError,
+    SearchRequest, UserAuthToken,
+};
+use openssl::ssl::SslAcceptor;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::codec::Framed;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio_openssl::SslAcceptorExt;
+use uuid::Uuid;
+
+pub struct LdapServer {
+    pub gateway: LdapGateway,
+    pub qe_r: Addr<QueryServerV1>,
+    pub qe_w: Addr<QueryServerV1>,
+    pub idms: Arc<IdmServer>,
+    pub metrics: Arc<Metrics>,
+    // Shared with http, so binds are limited as its authentications are.
+    pub rate_limiter: Arc<RateLimiter>,
+}
+
+// What a connection is bound as. Until it binds it searches as anonymous,
+// and the anonymous session is begun at its first search.
+struct LdapSession {
+    uat: Option<UserAuthToken>,
+    source: Option<String>,
+}
+
+type LdapFuture = Box<dyn Future<Item = (LdapSession, Vec<LdapMsg>), Error = ()>>;
+type AuthFuture = Box<dyn Future<Item = Result<UserAuthToken, LdapResult>, Error = ()>>;
+
+fn current_time() -> Duration {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Clock failure!")
+}
+
+fn invalid_credentials() -> LdapResult {
+    LdapResult::new(LdapResultCode::InvalidCredentials, "invalid credentials")
+}
+
+// Begin an auth session for the account, and give the credential as its only
+// step. An account whose policy needs more than a password can't bind.
+fn authenticate(
+    ldap: &Arc<LdapServer>,
+    source: Option<String>,
+    name: String,
+    cred: AuthCredential,
+) -> AuthFuture {
+    let qe_w = ldap.qe_w.clone();
+    let anonymous = match cred {
+        AuthCredential::Anonymous => true,
+        _ => false,
+    };
+    let init = AuthMessage::new(
+        Uuid::new_v4(),
+        AuthRequest {
+            step: AuthStep::Init(name, None),
+        },
+        None,
+        source.clone(),
+    );
+    let auth_error = |e: OperationError| match e {
+        OperationError::RateLimited(_) => operation_error(&e),
+        _ => invalid_credentials(),
+    };
+    Box::new(
+        ldap.qe_w
+            .send(init)
+            .map_err(|e| error!("LDAP auth failed -> {:?}", e))
+            .and_then(move |r| match r {
+                Ok(AuthResponse {
+                    sessionid,
+                    state: AuthState::Continue(allowed),
+                    ..
+                }) if allowed.iter().any(|a| match (a, anonymous) {
+                    (AuthAllowed::Anonymous, true) | (AuthAllowed::Password, false) => true,
+                    _ => false,
+                }) =>
+                {
+                    let step = AuthMessage::new(
+                        Uuid::new_v4(),
+                        AuthRequest {
+                            step: AuthStep::Creds(vec![cred]),
+                        },
+                        Some(sessionid),
+                        source,
+                    );
+                    Either::A(
+                        qe_w.send(step)
+                            .map_err(|e| error!("LDAP auth failed -> {:?}", e))
+                            .map(move |r| match r {
+                                Ok(AuthResponse {
+                                    state: AuthState::Success(uat),
+                                    ..
+                                }) => {
+                                    if uat.must_change_password {
+                                        Err(LdapResult::new(
+                                            LdapResultCode::InvalidCredentials,
+                                            "the password must be changed before binding",
+                                        ))
+                                    } else {
+                                        Ok(uat)
+                                    }
+                                }
+                                Ok(_) => Err(invalid_credentials()),
+                                Err(e) => Err(auth_error(e)),
+                            }),
+                    )
+                }
+                Ok(_) => Either::B(future::ok(Err(invalid_credentials()))),
+                Err(e) => Either::B(future::ok(Err(auth_error(e)))),
+            }),
+    )
+}
+
+// The session to search with. It must still be active, as a session
+// ended by logout or revocation is no longer accepted by the rest of the
+// api either.
+fn session_uat(
+    ldap: &Arc<LdapServer>,
+    session: LdapSession,
+) -> Box<dyn Future<Item = (LdapSession, Result<UserAuthToken, LdapResult>), Error = ()>> {
+    let ct = current_time();
+    let current = match &session.uat {
+        Some(uat)
+            if ct.as_secs() < uat.expiry && ldap.idms.is_session_active(&uat.sessionid, ct) =>
+        {
+            Some(Ok(uat.clone()))
+        }
+        Some(uat) if !uat.anonymous => Some(Err(LdapResult::new(
+            LdapResultCode::InsufficentAccessRights,
+            "the session has ended, bind again",
+        ))),
+        _ => None,
+    };
+    match current {
+        Some(r) => Box::new(future::ok((session, r))),
+        None => Box::new(
+            authenticate(
+                ldap,
+                session.source.clone(),
+                "anonymous".to_string(),
+                AuthCredential::Anonymous,
+            )
+            .map(move |r| {
+                let mut session = session;
+                session.uat = r.as_ref().ok().cloned();
+                (session, r)
+            }),
+        ),
+    }
+}
+
+// There is no proxy before ldap, so the source is always the peer.
+fn throttle(ldap: &Arc<LdapServer>, source: Option<IpAddr>) -> Result<(), LdapResult> {
+    ldap.rate_limiter
+        .check(RateLimitKind::Auth, source, current_time())
+        .map_err(|t| {
+            ldap.metrics
+                .record_rate_limited(RateLimitKind::Auth, t.global);
+            operation_error(&OperationError::RateLimited(t.retry_after))
+        })
+}
+
+fn bind(
+    ldap: &Arc<LdapServer>,
+    mut session: LdapSession,
+    msgid: i64,
+    req: LdapBindRequest,
+) -> LdapFuture {
+    let respond = move |session, r| (session, vec![LdapMsg::new(msgid, LdapOp::BindResponse(r))]);
+    // Whatever the result, the connection is no longer bound as it was.
+    session.uat = None;
+    if req.version != 3 {
+        let r = LdapResult::new(LdapResultCode::ProtocolError, "only ldapv3 is supported");
+        return Box::new(future::ok(respond(session, r)));
+    }
+    let auth = match req.cred {
+        LdapBindCred::Simple(ref pw) if req.dn.is_empty() && pw.is_empty() => {
+            Ok(("anonymous".to_string(), AuthCredential::Anonymous))
+        }
+        // A dn with no password is an unauthenticated bind, which would
+        // otherwise look like a success to a careless client.
+        LdapBindCred::Simple(ref pw) if req.dn.is_empty() || pw.is_empty() => Err(LdapResult::new(
+            LdapResultCode::UnwillingToPerform,
+            "unauthenticated binds are not allowed",
+        )),
+        LdapBindCred::Simple(pw) => ldap
+            .gateway
+            .bind_name(req.dn.as_str())
+            .map(|name| (name, AuthCredential::Password(pw))),
+        LdapBindCred::Sasl(mech) => Err(LdapResult::new(
+            LdapResultCode::AuthMethodNotSupported,
+            format!("sasl {} is not supported, only simple binds are", mech).as_str(),
+        )),
+    };
+    let source = session
+        .source
+        .as_ref()
+        .and_then(|s| s.parse::<SocketAddr>().ok())
+        .map(|a| a.ip());
+    let auth = auth.and_then(|a| throttle(ldap, source).map(|_| a));
+    match auth {
+        Ok((name, cred)) => Box::new(authenticate(ldap, session.source.clone(), name, cred).map(
+            move |r| match r {
+                Ok(uat) => {
+                    session.uat = Some(uat);
+                    respond(session, LdapResult::new(LdapResultCode::Success, ""))
+                }
+                Err(r) => respond(session, r),
+            },
+        )),
+        Err(r) => Box::new(future::ok(respond(session, r))),
+    }
+}
+
+// Only the attributes asked for of an entry we make up ourselves. "+" asks
+// for the operational attributes, and all of ours are given for it.
+fn select_attrs(mut e: LdapSearchResultEntry, attrs: &[String]) -> LdapSearchResultEntry {
+    if !attrs.is_empty() && !attrs.iter().any(|a| a == "*" || a == "+") {
+        e.attributes.retain(|pa| {
+            attrs
+                .iter()
+                .any(|a| a.eq_ignore_ascii_case(pa.atype.as_str()))
+        });
+    }
+    e
+}
+
+fn search(
+    ldap: &Arc<LdapServer>,
+    session: LdapSession,
+    msgid: i64,
+    req: LdapSearchRequest,
+) -> LdapFuture {
+    let done = move |r| LdapMsg::new(msgid, LdapOp::SearchResultDone(r));
+    let entry = move |e| LdapMsg::new(msgid, LdapOp::SearchResultEntry(e));
+    let success = || LdapResult::new(LdapResultCode::Success, "");
+
+    let target = match ldap.gateway.target(req.base.as_str()) {
+        Ok(t) => t,
+        Err(r) => return Box::new(future::ok((session, vec![done(r)]))),
+    };
+    let fixed = match (&target, req.scope) {
+        (LdapTarget::RootDse, LdapSearchScope::Base) => Some(Some(ldap.gateway.root_dse())),
+        (LdapTarget::Schema, LdapSearchScope::Base) => Some(Some(ldap.gateway.schema())),
+        (LdapTarget::Base, LdapSearchScope::Base) => Some(Some(ldap.gateway.base())),
+        // Nothing is below the root dse, the schema, or an entry.
+        (LdapTarget::RootDse, _)
+        | (LdapTarget::Schema, _)
+        | (LdapTarget::Entry(_), LdapSearchScope::OneLevel) => Some(None),
+        _ => None,
+    };
+    if let Some(e) = fixed {
+        let mut msgs: Vec<_> = e
+            .into_iter()
+            .map(|e| entry(select_attrs(e, req.attrs.as_slice())))
+            .collect();
+        msgs.push(done(success()));
+        return Box::new(future::ok((session, msgs)));
+    }
+
+    let filter = match ldap.gateway.filter(&req.filter) {
+        Ok(f) => match target {
+            LdapTarget::Entry(ef) => ProtoFilter::And(vec![ef, f]),
+            _ => f,
+        },
+        Err(r) => return Box::new(future::ok((session, vec![done(r)]))),
+    };
+    let attrs = ldap.gateway.search_attrs(req.attrs.as_slice());
+    let mut sreq = SearchRequest::new(filter);
+    // The name is always needed to give the dn.
+    sreq.attrs = attrs.as_ref().map(|a| {
+        let mut a: Vec<String> = a.iter().cloned().collect();
+        a.push("name".to_string());
+        a
+    });
+
+    let ldap = ldap.clone();
+    Box::new(session_uat(&ldap, session).and_then(move |(session, r)| {
+        let uat = match r {
+            Ok(uat) => uat,
+            Err(r) => return Either::B(future::ok((session, vec![done(r)]))),
+        };
+        Either::A(
+            ldap.qe_r
+                .send(SearchMessage::new(Uuid::new_v4(), Some(uat), sreq))
+                .map_err(|e| error!("LDAP search failed -> {:?}", e))
+                .map(move |r| {
+                    let msgs = match r {
+                        Ok(sr) => {
+                            let limit = if req.sizelimit > 0 {
+                                req.sizelimit as usize
+                            } else {
+                                usize::max_value()
+                            };
+                            let result = if sr.entries.len() > limit {
+                                LdapResult::new(LdapResultCode::SizeLimitExceeded, "")
+                            } else {
+                                success()
+                            };
+                            let mut msgs: Vec<_> = sr
+                                .entries
+                                .into_iter()
+                                .take(limit)
+                                .map(|e| entry(ldap.gateway.entry(e, &attrs, req.typesonly)))
+                                .collect();
+                            msgs.push(done(result));
+                            msgs
+                        }
+                        Err(OperationError::NoMatchingEntries) => vec![done(success())],
+                        Err(e) => vec![done(operation_error(&e))],
+                    };
+                    (session, msgs)
+                }),
+        )
+    }))
+}
+
+fn handle(ldap: &Arc<LdapServer>, session: LdapSession, msg: LdapMsg) -> LdapFuture {
+    let msgid = msg.msgid;
+    let refuse = move |op: LdapOp, code, message| {
+        op.response(LdapResult::new(code, message))
+            .map(|op| LdapMsg::new(msgid, op))
+            .into_iter()
+            .collect::<Vec<_>>()
+    };
+    if !msg.critical_controls.is_empty() {
+        let msgs = refuse(
+            msg.op,
+            LdapResultCode::UnavailableCriticalExtension,
+            "controls are not supported",
+        );
+        return Box::new(future::ok((session, msgs)));
+    }
+    match msg.op {
+        LdapOp::BindRequest(req) => bind(ldap, session, msgid, req),
+        LdapOp::SearchRequest(req) => search(ldap, session, msgid, req),
+        // Each request is answered before the next is read, so there is
+        // never one to abandon.
+        LdapOp::AbandonRequest(id) => {
+            debug!("LDAP abandon of {} ignored", id);
+            Box::new(future::ok((session, Vec::new())))
+        }
+        op => {
+            let msgs = refuse(
+                op,
+                LdapResultCode::UnwillingToPerform,
+                "this server is read only",
+            );
+            Box::new(future::ok((session, msgs)))
+        }
+    }
+}
+
+fn serve<S>(
+    io: S,
+    ldap: Arc<LdapServer>,
+    source: Option<String>,
+) -> impl Future<Item = (), Error = ()>
+where
+    S: AsyncRead + AsyncWrite + 'static,
+{
+    let (sink, requests) = Framed::new(io, LdapCodec).split();
+    let session = LdapSession {
+        uat: None,
+        source: source,
+    };
+    requests
+        .map_err(|e| debug!("LDAP connection closed -> {:?}", e))
+        .take_while(|msg| Ok(msg.op != LdapOp::UnbindRequest))
+        .fold((sink, session), move |(sink, session), msg| {
+            handle(&ldap, session, msg).and_then(|(session, msgs)| {
+                sink.send_all(stream::iter_ok::<_, io::Error>(msgs))
+                    .map(|(sink, _)| (sink, session))
+                    .map_err(|e| debug!("LDAP connection closed -> {:?}", e))
+            })
+        })
+        .map(|_| ())
+}
+
+// Listen for ldap on address. Without a tls acceptor connections are in
+// plain text, which is only allowed for the integration tests.
+pub fn start_ldap_server(
+    address: &str,
+    tls: Option<SslAcceptor>,
+    ldap: LdapServer,
+) -> Result<(), ()> {
+    let addr = address
+        .parse::<SocketAddr>()
+        .map_err(|e| error!("Invalid LDAP address {} -> {:?}", address, e))?;
+    let listener = TcpListener::bind(&addr)
+        .map_err(|e| error!("Failed to bind LDAP address {} -> {:?}", address, e))?;
+    info!("Serving LDAP for {} at {}", ldap.gateway.basedn(), address);
+
+    let ldap = Arc::new(ldap);
+    let server = listener
+        .incoming()
+        .map_err(|e| error!("LDAP listener failed -> {:?}", e))
+        .for_each(move |tcpstream| {
+            let source = tcpstream.peer_addr().ok().map(|a| a.to_string());
+            let ldap = ldap.clone();
+            match &tls {
+                Some(acceptor) => Arbiter::spawn(
+                    acceptor
+                        .accept_async(tcpstream)
+                        .map_err(|e| debug!("LDAP TLS handshake failed -> {:?}", e))
+                        .and_then(move |s| serve(s, ldap, source)),
+                ),
+                None => Arbiter::spawn(serve(tcpstream, ldap, source)),
+            };
+            Ok(())
+        });
+    Arbiter::spawn(server);
+    Ok(())
+}
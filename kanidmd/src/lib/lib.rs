@@ -19,12 +19,18 @@ mod async_log;
 #[macro_use]
 mod audit;
 mod be;
+mod cid;
 pub mod constants;
 mod credential;
+mod delayed;
 mod entry;
 mod event;
 mod filter;
 mod interval;
+mod ldap;
+mod metrics;
+mod ratelimit;
+mod requestlimit;
 mod modify;
 mod value;
 #[macro_use]
@@ -33,7 +39,12 @@ mod access;
 mod actors;
 mod idm;
 mod schema;
+mod scim;
 mod server;
+mod shutdown;
+mod tls;
 
+pub mod admin;
 pub mod config;
 pub mod core;
+pub mod logging;
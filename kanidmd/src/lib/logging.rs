@@ -0,0 +1,450 @@
+// The server's logger. Each line is given the target of the part of the
+// server it came from, one of TARGETS, so that a target can be logged at its
+// own level, and lines can be filtered on it once shipped elsewhere. Lines
+// are written to stderr, as text, or as one json object each.
+//
+// Nothing here redacts secrets. Those we hold, such as the credentials in an
+// auth request, are written as kanidm_proto::v1::REDACTED whenever they're
+// debug formatted, so they can't be logged however they're reached.
+use chrono::{SecondsFormat, Utc};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde_json::{Map, Value as JsonValue};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+
+// be is the backend, qs the query server and what it's made of, such as
+// schema and access controls, and server anything not in another.
+pub const TARGETS: [&str; 7] = ["be", "qs", "idm", "plugins", "http", "ldap", "server"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+pub fn parse_log_format(f: &str) -> Option<LogFormat> {
+    match f {
+        "text" => Some(LogFormat::Text),
+        "json" => Some(LogFormat::Json),
+        _ => None,
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+pub fn parse_log_level(level: &str) -> Result<LevelFilter, String> {
+    match LevelFilter::from_str(level) {
+        Ok(LevelFilter::Off) | Err(_) => Err(format!(
+            "invalid level {} - must be one of error, warn, info, debug or trace",
+            level
+        )),
+        Ok(l) => Ok(l),
+    }
+}
+
+fn find_target(target: &str) -> Option<&'static str> {
+    TARGETS.iter().find(|t| **t == target).cloned()
+}
+
+fn parse_target(target: &str) -> Result<&'static str, String> {
+    find_target(target).ok_or_else(|| {
+        format!(
+            "invalid target {} - must be one of {}",
+            target,
+            TARGETS.join(", ")
+        )
+    })
+}
+
+// The target of what's logged in a module of ours, or None for a library we
+// use. A target of ours can also be given by name, as with info!(target:
+// "http", ...).
+fn target_of(target: &str) -> Option<&'static str> {
+    if let Some(t) = find_target(target) {
+        return Some(t);
+    }
+    let mut path = target.split("::");
+    match (path.next(), path.next()) {
+        (Some("kanidm"), Some(m)) => Some(match m {
+            "be" => "be",
+            "server" | "schema" | "access" | "filter" | "entry" | "event" | "modify" | "value"
+            | "actors" | "cid" | "delayed" => "qs",
+            "idm" | "credential" => "idm",
+            "plugins" => "plugins",
+            "core" | "scim" | "tls" | "metrics" | "ratelimit" => "http",
+            "ldap" => "ldap",
+            _ => "server",
+        }),
+        (Some("kanidm"), None) | (Some("kanidmd"), _) => Some("server"),
+        (Some("actix_web"), _) => Some("http"),
+        _ => None,
+    }
+}
+
+// What a library logs is only shown when it's a warning or worse, except
+// for actix_web, which tells us about the http server at info.
+fn dependency_level(target: &str) -> LevelFilter {
+    if target.starts_with("actix_web") {
+        LevelFilter::Info
+    } else {
+        LevelFilter::Warn
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Levels {
+    level: LevelFilter,
+    // Those targets logged at a level other than the one above.
+    targets: BTreeMap<&'static str, LevelFilter>,
+}
+
+impl Levels {
+    fn new(level: LevelFilter) -> Self {
+        Levels {
+            level: level,
+            targets: BTreeMap::new(),
+        }
+    }
+
+    fn of(&self, target: &str) -> LevelFilter {
+        match target_of(target) {
+            Some(t) => self.targets.get(t).cloned().unwrap_or(self.level),
+            None => dependency_level(target).min(self.level),
+        }
+    }
+
+    // The most verbose level of any target, which the log macros check
+    // before anything reaches the logger.
+    fn max(&self) -> LevelFilter {
+        self.targets.values().fold(self.level, |a, b| a.max(*b))
+    }
+}
+
+struct Logger {
+    json: AtomicBool,
+    levels: RwLock<Levels>,
+}
+
+lazy_static! {
+    static ref LOGGER: Logger = Logger {
+        json: AtomicBool::new(false),
+        levels: RwLock::new(Levels::new(LevelFilter::Info)),
+    };
+}
+
+impl Logger {
+    fn format(&self) -> LogFormat {
+        if self.json.load(Ordering::Relaxed) {
+            LogFormat::Json
+        } else {
+            LogFormat::Text
+        }
+    }
+
+    fn level_of(&self, target: &str) -> LevelFilter {
+        self.levels
+            .read()
+            .map(|l| l.of(target))
+            .unwrap_or(LevelFilter::Info)
+    }
+
+    fn update_levels<F: FnOnce(&mut Levels)>(&self, f: F) {
+        if let Ok(mut levels) = self.levels.write() {
+            f(&mut levels);
+            log::set_max_level(levels.max());
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_of(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            write_line(format_record(self.format(), now().as_str(), record).as_str());
+        }
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn write_line(line: &str) {
+    let stderr = io::stderr();
+    let mut handle = stderr.lock();
+    let _ = writeln!(handle, "{}", line);
+}
+
+fn json_line(time: &str, level: Level, target: &str, message: String) -> Map<String, JsonValue> {
+    let mut line = Map::new();
+    line.insert("time".to_string(), JsonValue::from(time));
+    line.insert(
+        "level".to_string(),
+        JsonValue::from(level.to_string().to_lowercase()),
+    );
+    line.insert("target".to_string(), JsonValue::from(target));
+    line.insert("message".to_string(), JsonValue::from(message));
+    line
+}
+
+fn format_record(format: LogFormat, time: &str, record: &Record) -> String {
+    let target = match target_of(record.target()) {
+        Some(t) => t,
+        None => record.target(),
+    };
+    match format {
+        LogFormat::Text => format!(
+            "[{} {:<5} {}] {}",
+            time,
+            record.level(),
+            target,
+            record.args()
+        ),
+        LogFormat::Json => JsonValue::Object(json_line(
+            time,
+            record.level(),
+            target,
+            record.args().to_string(),
+        ))
+        .to_string(),
+    }
+}
+
+// One http request, logged by the http target at info once it's answered.
+#[derive(Debug, Clone, Serialize)]
+pub struct RequestLog {
+    // As given back to the client in the kopid header.
+    pub eventid: Option<String>,
+    pub operation: &'static str,
+    pub method: String,
+    pub path: String,
+    pub source: Option<String>,
+    // The uuid of the account the request was authenticated as.
+    pub identity: Option<String>,
+    pub status: u16,
+    // In milliseconds.
+    pub duration: f64,
+}
+
+fn format_request(format: LogFormat, time: &str, r: &RequestLog) -> String {
+    match format {
+        LogFormat::Text => format!(
+            "[{} {:<5} http] {} {} {} {} {:.3}ms eventid={} identity={} source={}",
+            time,
+            Level::Info,
+            r.method,
+            r.path,
+            r.status,
+            r.operation,
+            r.duration,
+            r.eventid.as_ref().map(|s| s.as_str()).unwrap_or("-"),
+            r.identity.as_ref().map(|s| s.as_str()).unwrap_or("-"),
+            r.source.as_ref().map(|s| s.as_str()).unwrap_or("-"),
+        ),
+        LogFormat::Json => {
+            let mut line = json_line(time, Level::Info, "http", "request".to_string());
+            if let Ok(JsonValue::Object(fields)) = serde_json::to_value(r) {
+                line.extend(fields);
+            }
+            JsonValue::Object(line).to_string()
+        }
+    }
+}
+
+pub fn log_request(r: &RequestLog) {
+    if Level::Info <= LOGGER.level_of("http") {
+        write_line(format_request(LOGGER.format(), now().as_str(), r).as_str());
+    }
+}
+
+// Install the logger for the process. Each target in targets is given its
+// own level, and the rest are logged at level.
+pub fn init(
+    format: LogFormat,
+    level: &str,
+    targets: &BTreeMap<String, String>,
+) -> Result<(), String> {
+    let mut levels = Levels::new(parse_log_level(level)?);
+    for (t, l) in targets.iter() {
+        levels
+            .targets
+            .insert(parse_target(t.as_str())?, parse_log_level(l.as_str())?);
+    }
+    LOGGER
+        .json
+        .store(format == LogFormat::Json, Ordering::Relaxed);
+    LOGGER.update_levels(|l| *l = levels);
+    log::set_logger(&*LOGGER).map_err(|e| format!("the logger could not be set - {}", e))
+}
+
+// The levels can be changed while the server runs, for the whole process.
+pub fn log_level() -> String {
+    LOGGER
+        .levels
+        .read()
+        .map(|l| l.level.to_string().to_lowercase())
+        .unwrap_or_default()
+}
+
+pub fn set_log_level(level: &str) -> Result<(), String> {
+    let l = parse_log_level(level)?;
+    LOGGER.update_levels(|levels| levels.level = l);
+    Ok(())
+}
+
+// The level the target is logged at, whether its own or not.
+pub fn target_log_level(target: &str) -> Result<String, String> {
+    let t = parse_target(target)?;
+    Ok(LOGGER.level_of(t).to_string().to_lowercase())
+}
+
+// A level of default logs the target at the level of the rest again.
+pub fn set_target_log_level(target: &str, level: &str) -> Result<(), String> {
+    let t = parse_target(target)?;
+    if level == "default" {
+        LOGGER.update_levels(|levels| {
+            levels.targets.remove(t);
+        });
+    } else {
+        let l = parse_log_level(level)?;
+        LOGGER.update_levels(|levels| {
+            levels.targets.insert(t, l);
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        format_record, format_request, parse_log_level, target_of, Levels, LogFormat, RequestLog,
+    };
+    use crate::event::AuthEvent;
+    use kanidm_proto::v1::{AuthCredential, AuthRequest, AuthStep, REDACTED};
+    use log::{Level, LevelFilter, Record};
+    use serde_json::Value as JsonValue;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_logging_targets() {
+        assert_eq!(target_of("kanidm::be::idl_sqlite"), Some("be"));
+        assert_eq!(target_of("kanidm::server"), Some("qs"));
+        assert_eq!(target_of("kanidm::schema"), Some("qs"));
+        assert_eq!(target_of("kanidm::idm::server"), Some("idm"));
+        assert_eq!(target_of("kanidm::credential::totp"), Some("idm"));
+        assert_eq!(target_of("kanidm::plugins::memberof"), Some("plugins"));
+        assert_eq!(target_of("kanidm::core"), Some("http"));
+        assert_eq!(target_of("actix_web::server"), Some("http"));
+        assert_eq!(target_of("kanidm::ldap::server"), Some("ldap"));
+        assert_eq!(target_of("kanidm::admin"), Some("server"));
+        assert_eq!(target_of("kanidmd"), Some("server"));
+        assert_eq!(target_of("http"), Some("http"));
+        assert_eq!(target_of("tokio_reactor"), None);
+    }
+
+    #[test]
+    fn test_logging_levels() {
+        let mut levels = Levels::new(LevelFilter::Info);
+        levels.targets.insert("be", LevelFilter::Trace);
+        levels.targets.insert("http", LevelFilter::Warn);
+
+        assert_eq!(levels.of("kanidm::be::idl_arc_sqlite"), LevelFilter::Trace);
+        assert_eq!(levels.of("kanidm::core"), LevelFilter::Warn);
+        assert_eq!(levels.of("kanidm::server"), LevelFilter::Info);
+        assert_eq!(levels.of("actix_web::server"), LevelFilter::Warn);
+        // A library is never logged more than its own limit, nor more than
+        // the rest.
+        assert_eq!(levels.of("tokio_reactor"), LevelFilter::Warn);
+        levels.level = LevelFilter::Error;
+        assert_eq!(levels.of("tokio_reactor"), LevelFilter::Error);
+        assert_eq!(levels.max(), LevelFilter::Trace);
+
+        assert_eq!(parse_log_level("Debug"), Ok(LevelFilter::Debug));
+        assert!(parse_log_level("off").is_err());
+        assert!(parse_log_level("loud").is_err());
+    }
+
+    // An auth event is logged as the auth actor and idm server do, with the
+    // whole of it debug formatted.
+    #[test]
+    fn test_logging_auth_password_redacted() {
+        let password = "a very secret password of mine";
+        let ae = AuthEvent::cred_step_password(Uuid::new_v4(), password);
+        let req = AuthRequest {
+            step: AuthStep::Creds(vec![AuthCredential::Password(password.to_string())]),
+        };
+        for format in vec![LogFormat::Text, LogFormat::Json] {
+            let line = format_record(
+                format,
+                "time",
+                &Record::builder()
+                    .level(Level::Info)
+                    .target("kanidm::idm::server")
+                    .args(format_args!("Received AuthEvent -> {:?} {:?}", ae, req))
+                    .build(),
+            );
+            assert!(line.contains("Received AuthEvent"));
+            assert!(line.contains(REDACTED));
+            assert!(!line.contains(password));
+        }
+    }
+
+    #[test]
+    fn test_logging_json_lines() {
+        let line = format_record(
+            LogFormat::Json,
+            "time",
+            &Record::builder()
+                .level(Level::Warn)
+                .target("kanidm::be::idl_sqlite")
+                .args(format_args!("a \"quoted\"\nmessage"))
+                .build(),
+        );
+        assert!(!line.contains('\n'));
+        let v: JsonValue = serde_json::from_str(line.as_str()).expect("invalid json");
+        assert_eq!(v["level"], "warn");
+        assert_eq!(v["target"], "be");
+        assert_eq!(v["message"], "a \"quoted\"\nmessage");
+
+        let r = RequestLog {
+            eventid: Some("eventid".to_string()),
+            operation: "search",
+            method: "POST".to_string(),
+            path: "/v1/search".to_string(),
+            source: Some("127.0.0.1".to_string()),
+            identity: None,
+            status: 200,
+            duration: 1.5,
+        };
+        let v: JsonValue =
+            serde_json::from_str(format_request(LogFormat::Json, "time", &r).as_str())
+                .expect("invalid json");
+        assert_eq!(v["target"], "http");
+        assert_eq!(v["eventid"], "eventid");
+        assert_eq!(v["operation"], "search");
+        assert_eq!(v["identity"], JsonValue::Null);
+        assert_eq!(v["status"], 200);
+        assert_eq!(v["duration"], 1.5);
+
+        let line = format_request(LogFormat::Text, "time", &r);
+        assert!(line.contains("POST /v1/search 200 search 1.500ms eventid=eventid"));
+    }
+}
@@ -43,7 +43,7 @@ macro_rules! modlist {
         $vs:expr
     ) => {{
         #[allow(unused_imports)]
-        use crate::modify::{m_pres, m_purge, m_remove};
+        use crate::modify::{m_assert, m_assert_missing, m_pres, m_purge, m_remove, m_set};
         use crate::modify::{Modify, ModifyList};
         let s: Box<[Modify]> = Box::new($vs);
         ModifyList::new_list(s.into_vec())
@@ -84,7 +84,10 @@ macro_rules! filter {
         #[allow(unused_imports)]
         use crate::filter::FC;
         #[allow(unused_imports)]
-        use crate::filter::{f_and, f_andnot, f_eq, f_or, f_pres, f_self, f_sub};
+        use crate::filter::{
+            f_and, f_andnot, f_eq, f_false, f_gte, f_inc, f_lte, f_or, f_pres, f_self, f_sub,
+            f_true,
+        };
         Filter::new_ignore_hidden($fc)
     }};
 }
@@ -99,7 +102,10 @@ macro_rules! filter_rec {
         #[allow(unused_imports)]
         use crate::filter::FC;
         #[allow(unused_imports)]
-        use crate::filter::{f_and, f_andnot, f_eq, f_or, f_pres, f_self, f_sub};
+        use crate::filter::{
+            f_and, f_andnot, f_eq, f_false, f_gte, f_inc, f_lte, f_or, f_pres, f_self, f_sub,
+            f_true,
+        };
         Filter::new_recycled($fc)
     }};
 }
@@ -114,7 +120,10 @@ macro_rules! filter_all {
         #[allow(unused_imports)]
         use crate::filter::FC;
         #[allow(unused_imports)]
-        use crate::filter::{f_and, f_andnot, f_eq, f_or, f_pres, f_self, f_sub};
+        use crate::filter::{
+            f_and, f_andnot, f_eq, f_false, f_gte, f_inc, f_lte, f_or, f_pres, f_self, f_sub,
+            f_true,
+        };
         Filter::new($fc)
     }};
 }
@@ -127,7 +136,9 @@ macro_rules! filter_valid {
         $fc:expr
     ) => {{
         #[allow(unused_imports)]
-        use crate::filter::{f_and, f_andnot, f_eq, f_or, f_pres, f_sub};
+        use crate::filter::{
+            f_and, f_andnot, f_eq, f_false, f_gte, f_inc, f_lte, f_or, f_pres, f_sub, f_true,
+        };
         use crate::filter::{Filter, FilterInvalid};
         let f: Filter<FilterInvalid> = Filter::new($fc);
         // Create a resolved filter, via the most unsafe means possible!
@@ -143,7 +154,9 @@ macro_rules! filter_resolved {
         $fc:expr
     ) => {{
         #[allow(unused_imports)]
-        use crate::filter::{f_and, f_andnot, f_eq, f_or, f_pres, f_sub};
+        use crate::filter::{
+            f_and, f_andnot, f_eq, f_false, f_gte, f_inc, f_lte, f_or, f_pres, f_sub, f_true,
+        };
         use crate::filter::{Filter, FilterInvalid};
         let f: Filter<FilterInvalid> = Filter::new($fc);
         // Create a resolved filter, via the most unsafe means possible!
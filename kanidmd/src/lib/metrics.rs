@@ -0,0 +1,484 @@
+// Counters of what the server has done, given to prometheus in its text
+// format. These are updated on every request, so they are all atomics, and
+// nothing here takes a lock.
+use crate::ratelimit::RateLimitKind;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// The upper bounds of the latency buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+// The classes of http status that requests are counted by.
+const STATUS_CLASSES: [&'static str; 4] = ["2xx", "3xx", "4xx", "5xx"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operation {
+    Search,
+    Create,
+    Modify,
+    Delete,
+    Auth,
+    Other,
+}
+
+impl Operation {
+    const ALL: [Operation; 6] = [
+        Operation::Search,
+        Operation::Create,
+        Operation::Modify,
+        Operation::Delete,
+        Operation::Auth,
+        Operation::Other,
+    ];
+
+    pub fn from_path(path: &str) -> Self {
+        match path {
+            "/v1/search" | "/v1/search/_stream" | "/v1/search/count" | "/v1/compare" => {
+                Operation::Search
+            }
+            "/v1/create" => Operation::Create,
+            "/v1/modify" | "/v1/modify/batch" => Operation::Modify,
+            "/v1/delete" => Operation::Delete,
+            "/v1/auth" | "/v1/auth/reauth" => Operation::Auth,
+            _ => Operation::Other,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Operation::Search => "search",
+            Operation::Create => "create",
+            Operation::Modify => "modify",
+            Operation::Delete => "delete",
+            Operation::Auth => "auth",
+            Operation::Other => "other",
+        }
+    }
+}
+
+fn duration_micros(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000 + u64::from(d.subsec_micros())
+}
+
+struct Histogram {
+    // The count of observations in each bucket alone. They are summed into
+    // the cumulative counts prometheus expects when they are rendered.
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Histogram {
+            buckets: LATENCY_BUCKETS.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, d: Duration) {
+        let micros = duration_micros(d);
+        let secs = micros as f64 / 1_000_000.0;
+        // Anything slower than the last bucket is only in +Inf, which is
+        // the count.
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|b| secs <= *b) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, label: &str) {
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(self.buckets.iter()) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, label, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{},le=\"+Inf\"}} {}",
+            name,
+            label,
+            self.count.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "{}_sum{{{}}} {}",
+            name,
+            label,
+            self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(
+            out,
+            "{}_count{{{}}} {}",
+            name,
+            label,
+            self.count.load(Ordering::Relaxed)
+        );
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+pub struct Metrics {
+    // Indexed by operation, then by status class.
+    requests: Vec<AtomicU64>,
+    request_duration: Vec<Histogram>,
+    auth_success: AtomicU64,
+    auth_denied: AtomicU64,
+    be_read_duration: Histogram,
+    be_write_duration: Histogram,
+    be_full_scans: AtomicU64,
+    entry_cache_hits: AtomicU64,
+    entry_cache_misses: AtomicU64,
+    idl_cache_hits: AtomicU64,
+    idl_cache_misses: AtomicU64,
+    delayed_actions: AtomicU64,
+    // Indexed by kind, then by whether it was the global limit.
+    rate_limited: Vec<AtomicU64>,
+    rate_limit_sources: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            requests: (0..Operation::ALL.len() * STATUS_CLASSES.len())
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            request_duration: Operation::ALL.iter().map(|_| Histogram::new()).collect(),
+            auth_success: AtomicU64::new(0),
+            auth_denied: AtomicU64::new(0),
+            be_read_duration: Histogram::new(),
+            be_write_duration: Histogram::new(),
+            be_full_scans: AtomicU64::new(0),
+            entry_cache_hits: AtomicU64::new(0),
+            entry_cache_misses: AtomicU64::new(0),
+            idl_cache_hits: AtomicU64::new(0),
+            idl_cache_misses: AtomicU64::new(0),
+            delayed_actions: AtomicU64::new(0),
+            rate_limited: (0..RateLimitKind::ALL.len() * 2)
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+            rate_limit_sources: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record_request(&self, op: Operation, status: u16, d: Duration) {
+        let class = match status {
+            0..=299 => 0,
+            300..=399 => 1,
+            400..=499 => 2,
+            _ => 3,
+        };
+        let i = op as usize;
+        self.requests[i * STATUS_CLASSES.len() + class].fetch_add(1, Ordering::Relaxed);
+        self.request_duration[i].observe(d);
+    }
+
+    // Only the final step of an authentication is counted, as that's where
+    // it succeeds or is denied.
+    pub fn record_auth(&self, success: bool) {
+        if success {
+            self.auth_success.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.auth_denied.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_be_txn(&self, write: bool, d: Duration) {
+        if write {
+            self.be_write_duration.observe(d);
+        } else {
+            self.be_read_duration.observe(d);
+        }
+    }
+
+    // A search that no index could narrow, so every entry was tested.
+    pub fn record_full_scan(&self) {
+        self.be_full_scans.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn full_scans(&self) -> u64 {
+        self.be_full_scans.load(Ordering::Relaxed)
+    }
+
+    pub fn record_entry_cache(&self, hit: bool) {
+        if hit {
+            self.entry_cache_hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.entry_cache_misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_idl_cache(&self, hit: bool) {
+        if hit {
+            self.idl_cache_hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.idl_cache_misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // The hits and misses of the entry cache.
+    pub fn entry_cache(&self) -> (u64, u64) {
+        (
+            self.entry_cache_hits.load(Ordering::Relaxed),
+            self.entry_cache_misses.load(Ordering::Relaxed),
+        )
+    }
+
+    // The hits and misses of the cache of index lookups.
+    pub fn idl_cache(&self) -> (u64, u64) {
+        (
+            self.idl_cache_hits.load(Ordering::Relaxed),
+            self.idl_cache_misses.load(Ordering::Relaxed),
+        )
+    }
+
+    // How much derived work is waiting to be applied after the writes that
+    // caused it.
+    pub fn set_delayed_actions(&self, n: usize) {
+        self.delayed_actions.store(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn delayed_actions(&self) -> u64 {
+        self.delayed_actions.load(Ordering::Relaxed)
+    }
+
+    // A request refused for being over a rate limit, of its source or of all
+    // sources.
+    pub fn record_rate_limited(&self, kind: RateLimitKind, global: bool) {
+        self.rate_limited[kind as usize * 2 + global as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    // How many sources the rate limits are tracking.
+    pub fn set_rate_limit_sources(&self, n: usize) {
+        self.rate_limit_sources.store(n as u64, Ordering::Relaxed);
+    }
+
+    // Render everything in the prometheus text format. The entry count is
+    // read from the database for each scrape, so it's given here.
+    pub fn render(&self, entries: usize) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "kanidm_http_requests_total",
+            "counter",
+            "Requests handled, by operation and status class.",
+        );
+        for op in Operation::ALL.iter() {
+            for (c, class) in STATUS_CLASSES.iter().enumerate() {
+                let _ = writeln!(
+                    out,
+                    "kanidm_http_requests_total{{operation=\"{}\",status=\"{}\"}} {}",
+                    op.as_str(),
+                    class,
+                    self.requests[*op as usize * STATUS_CLASSES.len() + c].load(Ordering::Relaxed)
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "kanidm_http_request_duration_seconds",
+            "histogram",
+            "Time taken to respond to requests, by operation.",
+        );
+        for op in Operation::ALL.iter() {
+            self.request_duration[*op as usize].render(
+                &mut out,
+                "kanidm_http_request_duration_seconds",
+                format!("operation=\"{}\"", op.as_str()).as_str(),
+            );
+        }
+
+        header(
+            &mut out,
+            "kanidm_auth_total",
+            "counter",
+            "Authentications that succeeded or were denied.",
+        );
+        let _ = writeln!(
+            out,
+            "kanidm_auth_total{{result=\"success\"}} {}",
+            self.auth_success.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "kanidm_auth_total{{result=\"denied\"}} {}",
+            self.auth_denied.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "kanidm_rate_limited_total",
+            "counter",
+            "Requests refused for being over a rate limit, by kind and limit.",
+        );
+        for kind in RateLimitKind::ALL.iter() {
+            for (g, limit) in ["source", "global"].iter().enumerate() {
+                let _ = writeln!(
+                    out,
+                    "kanidm_rate_limited_total{{kind=\"{}\",limit=\"{}\"}} {}",
+                    kind.as_str(),
+                    limit,
+                    self.rate_limited[*kind as usize * 2 + g].load(Ordering::Relaxed)
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "kanidm_rate_limit_sources",
+            "gauge",
+            "Sources the rate limits are tracking.",
+        );
+        let _ = writeln!(
+            out,
+            "kanidm_rate_limit_sources {}",
+            self.rate_limit_sources.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "kanidm_backend_transaction_duration_seconds",
+            "histogram",
+            "Time that backend transactions were open for.",
+        );
+        self.be_read_duration.render(
+            &mut out,
+            "kanidm_backend_transaction_duration_seconds",
+            "kind=\"read\"",
+        );
+        self.be_write_duration.render(
+            &mut out,
+            "kanidm_backend_transaction_duration_seconds",
+            "kind=\"write\"",
+        );
+
+        header(
+            &mut out,
+            "kanidm_backend_full_scans_total",
+            "counter",
+            "Searches that no index could narrow, so tested every entry.",
+        );
+        let _ = writeln!(out, "kanidm_backend_full_scans_total {}", self.full_scans());
+
+        header(
+            &mut out,
+            "kanidm_backend_cache_lookups_total",
+            "counter",
+            "Lookups in the backend caches, by cache and whether they hit.",
+        );
+        for (cache, (hits, misses)) in
+            [("entry", self.entry_cache()), ("idl", self.idl_cache())].iter()
+        {
+            let _ = writeln!(
+                out,
+                "kanidm_backend_cache_lookups_total{{cache=\"{}\",result=\"hit\"}} {}",
+                cache, hits
+            );
+            let _ = writeln!(
+                out,
+                "kanidm_backend_cache_lookups_total{{cache=\"{}\",result=\"miss\"}} {}",
+                cache, misses
+            );
+        }
+
+        header(
+            &mut out,
+            "kanidm_delayed_actions",
+            "gauge",
+            "Derived work queued to be applied after the writes that caused it.",
+        );
+        let _ = writeln!(out, "kanidm_delayed_actions {}", self.delayed_actions());
+
+        header(
+            &mut out,
+            "kanidm_db_entries",
+            "gauge",
+            "Entries in the database, including recycled entries and tombstones.",
+        );
+        let _ = writeln!(out, "kanidm_db_entries {}", entries);
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Metrics, Operation};
+    use crate::ratelimit::RateLimitKind;
+    use std::time::Duration;
+
+    #[test]
+    fn test_metrics_render() {
+        let m = Metrics::new();
+        m.record_request(Operation::Search, 200, Duration::from_millis(3));
+        m.record_request(Operation::Search, 404, Duration::from_secs(10));
+        m.record_auth(false);
+        m.record_full_scan();
+        m.record_entry_cache(true);
+        m.record_entry_cache(false);
+        m.record_entry_cache(true);
+        m.set_delayed_actions(3);
+        m.record_rate_limited(RateLimitKind::Auth, false);
+        m.record_rate_limited(RateLimitKind::Auth, false);
+        m.set_rate_limit_sources(5);
+        let out = m.render(7);
+
+        assert!(out.contains("kanidm_http_requests_total{operation=\"search\",status=\"2xx\"} 1\n"));
+        assert!(out.contains("kanidm_http_requests_total{operation=\"search\",status=\"4xx\"} 1\n"));
+        assert!(out.contains("kanidm_http_requests_total{operation=\"create\",status=\"2xx\"} 0\n"));
+        // The buckets are cumulative, and the slow request is only in +Inf.
+        assert!(out.contains(
+            "kanidm_http_request_duration_seconds_bucket{operation=\"search\",le=\"0.0025\"} 0\n"
+        ));
+        assert!(out.contains(
+            "kanidm_http_request_duration_seconds_bucket{operation=\"search\",le=\"0.005\"} 1\n"
+        ));
+        assert!(out.contains(
+            "kanidm_http_request_duration_seconds_bucket{operation=\"search\",le=\"5\"} 1\n"
+        ));
+        assert!(out.contains(
+            "kanidm_http_request_duration_seconds_bucket{operation=\"search\",le=\"+Inf\"} 2\n"
+        ));
+        assert!(
+            out.contains("kanidm_http_request_duration_seconds_sum{operation=\"search\"} 10.003\n")
+        );
+        assert!(out.contains("kanidm_auth_total{result=\"success\"} 0\n"));
+        assert!(out.contains("kanidm_auth_total{result=\"denied\"} 1\n"));
+        assert!(out.contains("kanidm_backend_full_scans_total 1\n"));
+        assert!(
+            out.contains("kanidm_backend_cache_lookups_total{cache=\"entry\",result=\"hit\"} 2\n")
+        );
+        assert!(
+            out.contains("kanidm_backend_cache_lookups_total{cache=\"idl\",result=\"miss\"} 0\n")
+        );
+        assert!(out.contains("kanidm_rate_limited_total{kind=\"auth\",limit=\"source\"} 2\n"));
+        assert!(out
+            .contains("kanidm_rate_limited_total{kind=\"anonymous_search\",limit=\"global\"} 0\n"));
+        assert!(out.contains("kanidm_rate_limit_sources 5\n"));
+        assert!(out.contains("kanidm_delayed_actions 3\n"));
+        assert!(out.contains("kanidm_db_entries 7\n"));
+    }
+
+    #[test]
+    fn test_metrics_operation_from_path() {
+        assert_eq!(Operation::from_path("/v1/search"), Operation::Search);
+        assert_eq!(Operation::from_path("/v1/modify/batch"), Operation::Modify);
+        assert_eq!(Operation::from_path("/v1/auth/reauth"), Operation::Auth);
+        assert_eq!(Operation::from_path("/v1/whoami"), Operation::Other);
+    }
+}
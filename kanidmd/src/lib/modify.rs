@@ -23,6 +23,12 @@ pub enum Modify {
     Removed(String, PartialValue),
     // This attr *should not* exist.
     Purged(String),
+    // This value *must* exist before the modification, or it fails.
+    Assert(String, PartialValue),
+    // This attr *must not* exist before the modification, or it fails.
+    AssertMissing(String),
+    // This attr *should* have exactly these values.
+    Set(String, Vec<Value>),
 }
 
 #[allow(dead_code)]
@@ -40,6 +46,21 @@ pub fn m_purge(a: &str) -> Modify {
     Modify::Purged(a.to_string())
 }
 
+#[allow(dead_code)]
+pub fn m_assert(a: &str, v: &PartialValue) -> Modify {
+    Modify::Assert(a.to_string(), v.clone())
+}
+
+#[allow(dead_code)]
+pub fn m_assert_missing(a: &str) -> Modify {
+    Modify::AssertMissing(a.to_string())
+}
+
+#[allow(dead_code)]
+pub fn m_set(a: &str, vs: &[Value]) -> Modify {
+    Modify::Set(a.to_string(), vs.to_vec())
+}
+
 impl Modify {
     pub fn from(
         audit: &mut AuditScope,
@@ -47,11 +68,29 @@ impl Modify {
         qs: &QueryServerWriteTransaction,
     ) -> Result<Self, OperationError> {
         Ok(match m {
-            ProtoModify::Present(a, v) => Modify::Present(a.clone(), qs.clone_value(audit, a, v)?),
+            ProtoModify::Present(a, v) => {
+                let a = qs.clone_attr_name(a)?;
+                let v = qs.clone_value(audit, &a, v)?;
+                Modify::Present(a, v)
+            }
             ProtoModify::Removed(a, v) => {
-                Modify::Removed(a.clone(), qs.clone_partialvalue(audit, a, v)?)
+                let a = qs.clone_attr_name(a)?;
+                let v = qs.clone_partialvalue(audit, &a, v)?;
+                Modify::Removed(a, v)
+            }
+            ProtoModify::Purged(a) => Modify::Purged(qs.clone_attr_name(a)?),
+            ProtoModify::Assert(a, v) => {
+                let a = qs.clone_attr_name(a)?;
+                let v = qs.clone_partialvalue(audit, &a, v)?;
+                Modify::Assert(a, v)
+            }
+            ProtoModify::AssertMissing(a) => Modify::AssertMissing(qs.clone_attr_name(a)?),
+            ProtoModify::Set(a, vs) => {
+                let a = qs.clone_attr_name(a)?;
+                let vs: Result<Vec<_>, _> =
+                    vs.iter().map(|v| qs.clone_value(audit, &a, v)).collect();
+                Modify::Set(a, vs?)
             }
-            ProtoModify::Purged(a) => Modify::Purged(a.clone()),
         })
     }
 }
@@ -154,6 +193,33 @@ impl ModifyList<ModifyInvalid> {
                         None => Err(SchemaError::InvalidAttribute),
                     }
                 }
+                Modify::Assert(attr, value) => {
+                    let attr_norm = schema.normalise_attr_name(attr);
+                    match schema_attributes.get(&attr_norm) {
+                        Some(schema_a) => schema_a
+                            .validate_partialvalue(&value)
+                            .map(|_| Modify::Assert(attr_norm, value.clone())),
+                        None => Err(SchemaError::InvalidAttribute),
+                    }
+                }
+                Modify::AssertMissing(attr) => {
+                    let attr_norm = schema.normalise_attr_name(attr);
+                    match schema_attributes.get(&attr_norm) {
+                        Some(_attr_name) => Ok(Modify::AssertMissing(attr_norm)),
+                        None => Err(SchemaError::InvalidAttribute),
+                    }
+                }
+                Modify::Set(attr, values) => {
+                    let attr_norm = schema.normalise_attr_name(attr);
+                    match schema_attributes.get(&attr_norm) {
+                        Some(schema_a) => values
+                            .iter()
+                            .map(|v| schema_a.validate_value(v))
+                            .collect::<Result<Vec<_>, _>>()
+                            .map(|_| Modify::Set(attr_norm, values.clone())),
+                        None => Err(SchemaError::InvalidAttribute),
+                    }
+                }
             })
             .collect();
 
@@ -0,0 +1,221 @@
+// Access control profile validation. A profile is only parsed when the
+// access controls are reloaded at commit, where a filter that doesn't
+// validate fails the whole commit, and an attribute or class that isn't in
+// the schema is silently never granted. Instead, check profiles as they are
+// created or modified, so the change is rejected with the reason.
+use crate::plugins::Plugin;
+
+use crate::audit::AuditScope;
+use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntryValid};
+use crate::event::{CreateEvent, Event, ModifyEvent};
+use crate::filter::Filter;
+use crate::schema::SchemaTransaction;
+use crate::server::{QueryServerTransaction, QueryServerWriteTransaction};
+use crate::value::PartialValue;
+use kanidm_proto::v1::{OperationError, SchemaError};
+
+pub struct AccessControl {}
+
+lazy_static! {
+    static ref CLASS_ACP: PartialValue = PartialValue::new_class("access_control_profile");
+}
+
+static ACP_FILTER_ATTRS: [&'static str; 2] = ["acp_receiver", "acp_targetscope"];
+static ACP_ATTR_ATTRS: [&'static str; 5] = [
+    "acp_search_attr",
+    "acp_modify_presentattr",
+    "acp_modify_removedattr",
+    "acp_create_attr",
+    "acp_compare_attr",
+];
+static ACP_CLASS_ATTRS: [&'static str; 2] = ["acp_modify_class", "acp_create_class"];
+
+fn validate_acp<VALID, STATE>(
+    au: &mut AuditScope,
+    qs: &QueryServerWriteTransaction,
+    e: &Entry<VALID, STATE>,
+) -> Result<(), OperationError> {
+    if !e.attribute_value_pres("class", &CLASS_ACP) {
+        return Ok(());
+    }
+
+    // As in AccessControlProfile::try_from, these are internal filters.
+    let ev = Event::from_internal();
+    for attr in ACP_FILTER_ATTRS.iter() {
+        let pfs = e.get_ava(attr).unwrap_or_else(|| Vec::new());
+        for pf in pfs.iter().filter_map(|v| v.as_json_filter()) {
+            let f = try_audit!(au, Filter::from_rw(au, &ev, pf, qs));
+            try_audit!(
+                au,
+                f.validate(qs.get_schema())
+                    .map(|_| ())
+                    .map_err(|se| OperationError::SchemaViolation(se))
+            );
+        }
+    }
+
+    let schema = qs.get_schema();
+    for attr in ACP_ATTR_ATTRS.iter() {
+        let names = e.get_ava(attr).unwrap_or_else(|| Vec::new());
+        for name in names.iter().filter_map(|v| v.to_str()) {
+            if !schema.get_attributes().contains_key(name) {
+                audit_log!(au, "{} names an unknown attribute {}", attr, name);
+                return Err(OperationError::InvalidAttributeName(name.to_string()));
+            }
+        }
+    }
+
+    for attr in ACP_CLASS_ATTRS.iter() {
+        let names = e.get_ava(attr).unwrap_or_else(|| Vec::new());
+        for name in names.iter().filter_map(|v| v.to_str()) {
+            if !schema.get_classes().contains_key(name) {
+                audit_log!(au, "{} names an unknown class {}", attr, name);
+                return Err(OperationError::SchemaViolation(SchemaError::InvalidClass));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+impl Plugin for AccessControl {
+    fn id() -> &'static str {
+        "plugin_accesscontrol"
+    }
+
+    fn pre_create(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        cand: &Vec<Entry<EntryValid, EntryNew>>,
+        _ce: &CreateEvent,
+    ) -> Result<(), OperationError> {
+        cand.iter().try_for_each(|e| validate_acp(au, qs, e))
+    }
+
+    fn pre_modify(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        cand: &mut Vec<Entry<EntryInvalid, EntryCommitted>>,
+        _me: &ModifyEvent,
+    ) -> Result<(), OperationError> {
+        cand.iter().try_for_each(|e| validate_acp(au, qs, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::entry::{Entry, EntryInvalid, EntryNew};
+    use crate::modify::{Modify, ModifyList};
+    use crate::value::{PartialValue, Value};
+    use kanidm_proto::v1::{OperationError, SchemaError};
+
+    static JSON_TEST_ACP: &'static str = r#"{
+        "valid": null,
+        "state": null,
+        "attrs": {
+            "class": [
+                "object",
+                "access_control_profile",
+                "access_control_search",
+                "access_control_modify"
+            ],
+            "name": ["test_acp"],
+            "uuid": ["7cbd4c6a-5bc0-4b2e-86b8-7a16c0a49b21"],
+            "acp_enable": ["true"],
+            "acp_receiver": ["{\"Eq\":[\"name\",\"admin\"]}"],
+            "acp_targetscope": ["{\"Eq\":[\"class\",\"person\"]}"],
+            "acp_search_attr": ["name", "displayname"],
+            "acp_modify_presentattr": ["displayname"],
+            "acp_modify_removedattr": ["displayname"]
+        }
+    }"#;
+
+    #[test]
+    fn test_accesscontrol_create_valid() {
+        let e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_TEST_ACP);
+        let create = vec![e];
+        let preload = Vec::new();
+
+        run_create_test!(Ok(()), preload, create, None, |_, _| {});
+    }
+
+    // The attributes a profile grants must exist in the schema.
+    #[test]
+    fn test_accesscontrol_create_unknown_attr() {
+        let mut e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_TEST_ACP);
+        e.add_ava("acp_search_attr", &Value::new_iutf8s("nonexistant"));
+        let create = vec![e];
+        let preload = Vec::new();
+
+        run_create_test!(
+            Err(OperationError::InvalidAttributeName(
+                "nonexistant".to_string()
+            )),
+            preload,
+            create,
+            None,
+            |_, _| {}
+        );
+    }
+
+    #[test]
+    fn test_accesscontrol_create_unknown_class() {
+        let mut e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_TEST_ACP);
+        e.add_ava("acp_modify_class", &Value::new_iutf8s("nonexistant"));
+        let create = vec![e];
+        let preload = Vec::new();
+
+        run_create_test!(
+            Err(OperationError::SchemaViolation(SchemaError::InvalidClass)),
+            preload,
+            create,
+            None,
+            |_, _| {}
+        );
+    }
+
+    // The filters must only name attributes in the schema, not only parse.
+    #[test]
+    fn test_accesscontrol_create_invalid_filter() {
+        let mut e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_TEST_ACP);
+        e.set_avas(
+            "acp_receiver",
+            vec![
+                Value::new_json_filter("{\"Eq\":[\"nonexistant\",\"admin\"]}")
+                    .expect("invalid filter"),
+            ],
+        );
+        let create = vec![e];
+        let preload = Vec::new();
+
+        run_create_test!(
+            Err(OperationError::InvalidAttributeName(
+                "nonexistant".to_string()
+            )),
+            preload,
+            create,
+            None,
+            |_, _| {}
+        );
+    }
+
+    #[test]
+    fn test_accesscontrol_modify_unknown_attr() {
+        let e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_TEST_ACP);
+        let preload = vec![e];
+
+        run_modify_test!(
+            Err(OperationError::InvalidAttributeName(
+                "nonexistant".to_string()
+            )),
+            preload,
+            filter!(f_eq("name", PartialValue::new_iutf8s("test_acp"))),
+            ModifyList::new_list(vec![Modify::Present(
+                "acp_modify_presentattr".to_string(),
+                Value::new_iutf8s("nonexistant")
+            )]),
+            None,
+            |_, _| {}
+        );
+    }
+}
@@ -59,7 +59,7 @@ fn get_cand_attr_set<VALID, STATE>(
                         vr,
                         uuid
                     );
-                    return Err(OperationError::Plugin);
+                    return Err(OperationError::DuplicateValue(attr.to_string()));
                 }
             }
         }
@@ -87,7 +87,9 @@ fn enforce_unique<STATE>(
         return Ok(());
     }
 
-    // Now do an internal search on name and !uuid for each
+    // Now do an internal search on name and !uuid for each. Schema requires
+    // unique attributes to carry an equality index, so each term can be
+    // resolved from it.
 
     // Or
     let filt_in = filter!(f_or(
@@ -109,7 +111,8 @@ fn enforce_unique<STATE>(
 
     // If all okay, okay!
     if conflict_cand.len() > 0 {
-        return Err(OperationError::Plugin);
+        audit_log!(au, "ava already exists in db -> {:?}", attr);
+        return Err(OperationError::DuplicateValue(attr.to_string()));
     }
 
     Ok(())
@@ -221,7 +224,7 @@ mod tests {
         let preload = vec![e];
 
         run_create_test!(
-            Err(OperationError::Plugin),
+            Err(OperationError::DuplicateValue("name".to_string())),
             preload,
             create,
             None,
@@ -249,7 +252,7 @@ mod tests {
         let preload = Vec::new();
 
         run_create_test!(
-            Err(OperationError::Plugin),
+            Err(OperationError::DuplicateValue("name".to_string())),
             preload,
             create,
             None,
@@ -290,7 +293,7 @@ mod tests {
         let preload = vec![ea, eb];
 
         run_modify_test!(
-            Err(OperationError::Plugin),
+            Err(OperationError::DuplicateValue("name".to_string())),
             preload,
             filter!(f_or!([f_eq(
                 "name",
@@ -335,7 +338,7 @@ mod tests {
         let preload = vec![ea, eb];
 
         run_modify_test!(
-            Err(OperationError::Plugin),
+            Err(OperationError::DuplicateValue("name".to_string())),
             preload,
             filter!(f_or!([
                 f_eq("name", PartialValue::new_iutf8s("testgroup_a")),
@@ -167,7 +167,7 @@ impl Plugin for Base {
         // internal exists is actually a wrapper around a search for uuid internally
         //
         // But does it add value? How many people will try to custom define/add uuid?
-        let mut au_qs = AuditScope::new("qs_exist");
+        let mut au_qs = au.child("qs_exist");
         let r = qs.internal_exists(&mut au_qs, filt_in);
         au.append_scope(au_qs);
 
@@ -198,6 +198,9 @@ impl Plugin for Base {
                 Modify::Present(a, _) => a,
                 Modify::Removed(a, _) => a,
                 Modify::Purged(a) => a,
+                Modify::Set(a, _) => a,
+                // Asserting the uuid doesn't change it.
+                Modify::Assert(_, _) | Modify::AssertMissing(_) => continue,
             };
             if attr == "uuid" {
                 audit_log!(au, "Modifications to UUID's are NOT ALLOWED");
@@ -466,8 +469,7 @@ mod tests {
                 "uuid": ["79724141-3603-4060-b6bb-35c72772611d", "79724141-3603-4060-b6bb-35c72772611e"]
             }
         }"#,
-        )
-        ;
+        );
 
         let create = vec![e.clone()];
 
@@ -0,0 +1,242 @@
+// Posix accounts and groups need a gidnumber. When one isn't given, it's
+// generated from the uuid, so that it's the same on every server without
+// any coordination between them. A gidnumber given by an admin is kept,
+// and attrunique rejects it if another entry already has it.
+use crate::plugins::Plugin;
+
+use crate::audit::AuditScope;
+use crate::constants::{GID_GENERATED_MAX, GID_GENERATED_MIN};
+use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew};
+use crate::event::{CreateEvent, ModifyEvent};
+use crate::server::QueryServerWriteTransaction;
+use crate::value::{PartialValue, Value};
+use kanidm_proto::v1::OperationError;
+use uuid::Uuid;
+
+pub struct GidNumber {}
+
+lazy_static! {
+    static ref CLASS_POSIXGROUP: PartialValue = PartialValue::new_class("posixgroup");
+    static ref CLASS_POSIXACCOUNT: PartialValue = PartialValue::new_class("posixaccount");
+}
+
+// The last four bytes of a uuid are random for the v4 uuids we generate.
+pub(crate) fn uuid_to_gid_u32(u: &Uuid) -> u32 {
+    let b = u.as_bytes();
+    let x = u32::from_be_bytes([b[12], b[13], b[14], b[15]]);
+    GID_GENERATED_MIN + (x % (GID_GENERATED_MAX - GID_GENERATED_MIN))
+}
+
+fn apply_gidnumber<T: Copy>(
+    au: &mut AuditScope,
+    e: &mut Entry<EntryInvalid, T>,
+) -> Result<(), OperationError> {
+    if (e.attribute_value_pres("class", &CLASS_POSIXGROUP)
+        || e.attribute_value_pres("class", &CLASS_POSIXACCOUNT))
+        && !e.attribute_pres("gidnumber")
+    {
+        let u = match e.get_ava_single("uuid").and_then(|v| v.to_uuid()) {
+            Some(u) => *u,
+            None => return Err(OperationError::InvalidEntryState),
+        };
+        let gid = uuid_to_gid_u32(&u);
+        audit_log!(au, "generated gidnumber {} for {}", gid, u);
+        e.set_avas("gidnumber", vec![Value::new_uint32(gid)]);
+    }
+    Ok(())
+}
+
+impl Plugin for GidNumber {
+    fn id() -> &'static str {
+        "plugin_gidnumber"
+    }
+
+    fn pre_create_transform(
+        au: &mut AuditScope,
+        _qs: &mut QueryServerWriteTransaction,
+        cand: &mut Vec<Entry<EntryInvalid, EntryNew>>,
+        _ce: &CreateEvent,
+    ) -> Result<(), OperationError> {
+        cand.iter_mut().try_for_each(|e| apply_gidnumber(au, e))
+    }
+
+    fn pre_modify(
+        au: &mut AuditScope,
+        _qs: &mut QueryServerWriteTransaction,
+        cand: &mut Vec<Entry<EntryInvalid, EntryCommitted>>,
+        _me: &ModifyEvent,
+    ) -> Result<(), OperationError> {
+        cand.iter_mut().try_for_each(|e| apply_gidnumber(au, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::AuditScope;
+    use crate::entry::{Entry, EntryInvalid, EntryNew};
+    use crate::modify::{Modify, ModifyList};
+    use crate::server::{QueryServerTransaction, QueryServerWriteTransaction};
+    use crate::value::{PartialValue, Value};
+    use kanidm_proto::v1::OperationError;
+    use uuid::Uuid;
+
+    static UUID_TESTGROUP: &'static str = "83a0927f-3de1-45ec-bea0-2f7b997ef244";
+
+    fn check_gid(au: &mut AuditScope, qs: &QueryServerWriteTransaction, uuid: &str, gid: u32) {
+        let u = Uuid::parse_str(uuid).expect("Invalid uuid");
+        let e = qs
+            .internal_search_uuid(au, &u)
+            .expect("Failed to get entry");
+        let found = e.get_ava_single("gidnumber").and_then(|v| v.to_uint32());
+        assert!(found == Some(gid));
+    }
+
+    // The generated gidnumber only depends on the uuid.
+    #[test]
+    fn test_gidnumber_create_generate() {
+        let e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+            r#"{
+            "valid": null,
+            "state": null,
+            "attrs": {
+                "class": ["object", "group", "posixgroup"],
+                "name": ["testgroup"],
+                "uuid": ["83a0927f-3de1-45ec-bea0-2f7b997ef244"]
+            }
+        }"#,
+        );
+
+        let create = vec![e];
+        let preload = Vec::new();
+
+        run_create_test!(Ok(()), preload, create, None, |au, qs| check_gid(
+            au,
+            qs,
+            UUID_TESTGROUP,
+            427881029
+        ));
+    }
+
+    // A gidnumber that is given is kept.
+    #[test]
+    fn test_gidnumber_create_noaction() {
+        let e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+            r#"{
+            "valid": null,
+            "state": null,
+            "attrs": {
+                "class": ["object", "group", "posixgroup"],
+                "name": ["testgroup"],
+                "uuid": ["83a0927f-3de1-45ec-bea0-2f7b997ef244"],
+                "gidnumber": ["10001"]
+            }
+        }"#,
+        );
+
+        let create = vec![e];
+        let preload = Vec::new();
+
+        run_create_test!(Ok(()), preload, create, None, |au, qs| check_gid(
+            au,
+            qs,
+            UUID_TESTGROUP,
+            10001
+        ));
+    }
+
+    // Extending an existing group gives it the same gidnumber it would have
+    // been created with, as does removing a gidnumber that was given.
+    #[test]
+    fn test_gidnumber_modify_generate() {
+        let e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+            r#"{
+            "valid": null,
+            "state": null,
+            "attrs": {
+                "class": ["object", "group"],
+                "name": ["testgroup"],
+                "uuid": ["83a0927f-3de1-45ec-bea0-2f7b997ef244"]
+            }
+        }"#,
+        );
+
+        let preload = vec![e];
+
+        run_modify_test!(
+            Ok(()),
+            preload,
+            filter!(f_eq("name", PartialValue::new_iutf8s("testgroup"))),
+            ModifyList::new_list(vec![Modify::Present(
+                "class".to_string(),
+                Value::new_class("posixgroup")
+            )]),
+            None,
+            |au, qs| check_gid(au, qs, UUID_TESTGROUP, 427881029)
+        );
+
+        let e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+            r#"{
+            "valid": null,
+            "state": null,
+            "attrs": {
+                "class": ["object", "group", "posixgroup"],
+                "name": ["testgroup"],
+                "uuid": ["83a0927f-3de1-45ec-bea0-2f7b997ef244"],
+                "gidnumber": ["10001"]
+            }
+        }"#,
+        );
+
+        let preload = vec![e];
+
+        run_modify_test!(
+            Ok(()),
+            preload,
+            filter!(f_eq("name", PartialValue::new_iutf8s("testgroup"))),
+            ModifyList::new_list(vec![Modify::Purged("gidnumber".to_string())]),
+            None,
+            |au, qs| check_gid(au, qs, UUID_TESTGROUP, 427881029)
+        );
+    }
+
+    // Two entries may not share a gidnumber.
+    #[test]
+    fn test_gidnumber_duplicate() {
+        let ea: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+            r#"{
+            "valid": null,
+            "state": null,
+            "attrs": {
+                "class": ["object", "group", "posixgroup"],
+                "name": ["testgroup_a"],
+                "gidnumber": ["10001"]
+            }
+        }"#,
+        );
+
+        let eb: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+            r#"{
+            "valid": null,
+            "state": null,
+            "attrs": {
+                "class": ["object", "group"],
+                "name": ["testgroup_b"]
+            }
+        }"#,
+        );
+
+        let preload = vec![ea, eb];
+
+        run_modify_test!(
+            Err(OperationError::DuplicateValue("gidnumber".to_string())),
+            preload,
+            filter!(f_eq("name", PartialValue::new_iutf8s("testgroup_b"))),
+            ModifyList::new_list(vec![
+                Modify::Present("class".to_string(), Value::new_class("posixgroup")),
+                Modify::Present("gidnumber".to_string(), Value::new_uint32(10001))
+            ]),
+            None,
+            |_, _| {}
+        );
+    }
+}
@@ -165,6 +165,7 @@ macro_rules! run_delete_test {
             {
                 let mut qs_write = qs.write();
                 let r = qs_write.delete(&mut au_test, &de);
+                debug!("r: {:?}", r);
                 $check(&mut au_test, &qs_write);
                 assert!(r == $expect);
                 match r {
@@ -9,8 +9,13 @@
 //
 // As a result, we first need to run refint to clean up all dangling references, then memberof
 // fixes the graph of memberships
+//
+// When a change affects the memberships of many entries, recalculating them is deferred until
+// after the write commits, and done by the write worker in a transaction of its own. Until then,
+// those entries have the memberships they had before.
 
 use crate::audit::AuditScope;
+use crate::delayed::DelayedAction;
 use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntryValid};
 use crate::event::{CreateEvent, DeleteEvent, ModifyEvent};
 use crate::modify::{Modify, ModifyList};
@@ -187,6 +192,41 @@ fn apply_memberof(
     Ok(())
 }
 
+// The write decides if there are too many affected entries to update them in it.
+fn apply_or_delay_memberof(
+    au: &mut AuditScope,
+    qs: &mut QueryServerWriteTransaction,
+    affected_uuids: Vec<&Uuid>,
+) -> Result<(), OperationError> {
+    if qs.should_delay(affected_uuids.len()) {
+        let uuids = affected_uuids.into_iter().cloned().collect();
+        qs.delay_action(au, DelayedAction::MemberOf(uuids));
+        Ok(())
+    } else {
+        apply_memberof(au, qs, affected_uuids)
+    }
+}
+
+// Some of the entries may have been deleted since this was deferred, so only
+// those that remain are updated.
+pub fn apply_delayed(
+    au: &mut AuditScope,
+    qs: &mut QueryServerWriteTransaction,
+    uuids: &BTreeSet<Uuid>,
+) -> Result<(), OperationError> {
+    if uuids.len() == 0 {
+        return Ok(());
+    }
+    let filt = filter!(f_or(
+        uuids
+            .iter()
+            .map(|u| f_eq("uuid", PartialValue::new_uuidr(u)))
+            .collect()
+    ));
+    let remaining = try_audit!(au, qs.internal_search(au, filt));
+    apply_memberof(au, qs, remaining.iter().map(|e| e.get_uuid()).collect())
+}
+
 impl Plugin for MemberOf {
     fn id() -> &'static str {
         "memberof"
@@ -205,7 +245,7 @@ impl Plugin for MemberOf {
         // Trigger apply_memberof on all because they changed.
         let cand_refs: Vec<&Entry<_, _>> = cand.iter().map(|e| e).collect();
         let uuids = affected_uuids(au, cand_refs);
-        apply_memberof(au, qs, uuids)
+        apply_or_delay_memberof(au, qs, uuids)
     }
 
     fn post_modify(
@@ -248,7 +288,7 @@ impl Plugin for MemberOf {
         changed.sort();
         changed.dedup();
 
-        apply_memberof(au, qs, changed)
+        apply_or_delay_memberof(au, qs, changed)
     }
 
     fn pre_delete(
@@ -270,11 +310,14 @@ impl Plugin for MemberOf {
         // due to the nature of MO, we do not know the difference between
         // direct and indirect membership, meaning we would be safer
         // to not do this.
-
-        // NOTE: DO NOT purge directmemberof - we use that to restore memberships
-        // in recycle revive!
-
-        cand.iter_mut().for_each(|e| e.purge_ava("memberof"));
+        //
+        // directmemberof is purged too, else a revived entry would claim
+        // memberships the groups no longer hold. Refint records the removed
+        // member references on the recycled entry instead.
+        cand.iter_mut().for_each(|e| {
+            e.purge_ava("memberof");
+            e.purge_ava("directmemberof");
+        });
         Ok(())
     }
 
@@ -288,6 +331,42 @@ impl Plugin for MemberOf {
         // Trigger apply_memberof on all - because they all changed.
         let cand_refs: Vec<&Entry<_, _>> = cand.iter().map(|e| e).collect();
         let uuids = affected_uuids(au, cand_refs);
+        apply_or_delay_memberof(au, qs, uuids)
+    }
+
+    fn regenerate(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+    ) -> Result<(), OperationError> {
+        // Remove every membership first, so none that the groups no longer
+        // hold can remain. Groups that change here update their members as
+        // they would for any modify.
+        try_audit!(
+            au,
+            qs.internal_modify(
+                au,
+                filter!(f_or!([f_pres("memberof"), f_pres("directmemberof")])),
+                ModifyList::new_list(vec![
+                    Modify::Purged("memberof".to_string()),
+                    Modify::Purged("directmemberof".to_string()),
+                ]),
+            )
+        );
+
+        // Then apply them again to every member of every group, as if each
+        // group had just been created.
+        let groups = try_audit!(
+            au,
+            qs.internal_search(
+                au,
+                filter!(f_and!([
+                    f_eq("class", CLASS_GROUP.clone()),
+                    f_pres("member")
+                ]))
+            )
+        );
+        let group_refs: Vec<&Entry<_, _>> = groups.iter().collect();
+        let uuids = affected_uuids(au, group_refs);
         apply_memberof(au, qs, uuids)
     }
 
@@ -1,19 +1,25 @@
 use crate::audit::AuditScope;
+use crate::delayed::DelayedAction;
 use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntryValid};
 use crate::event::{CreateEvent, DeleteEvent, ModifyEvent};
 use crate::server::{QueryServerReadTransaction, QueryServerWriteTransaction};
-use kanidm_proto::v1::{ConsistencyError, OperationError};
+use kanidm_proto::v1::{ConsistencyError, OperationError, RepairChange};
 
 #[macro_use]
 mod macros;
 
+mod accesscontrol;
 mod attrunique;
 mod base;
 mod failure;
+mod gidnumber;
 mod memberof;
 mod protected;
+mod reauth;
 mod recycle;
 mod refint;
+mod schemainuse;
+mod spn;
 
 trait Plugin {
     fn id() -> &'static str;
@@ -103,6 +109,17 @@ trait Plugin {
         debug!("plugin {} has an unimplemented verify!", Self::id());
         vec![Err(ConsistencyError::Unknown)]
     }
+
+    // Generate everything the plugin derives again, ignoring what the
+    // entries hold now. This is run after a restore, as a backup may be
+    // from a version that derived it differently.
+    fn regenerate(
+        _au: &mut AuditScope,
+        _qs: &mut QueryServerWriteTransaction,
+    ) -> Result<(), OperationError> {
+        debug!("plugin {} has an unimplemented regenerate!", Self::id());
+        Err(OperationError::Plugin)
+    }
 }
 
 pub struct Plugins {}
@@ -119,7 +136,7 @@ macro_rules! run_pre_create_transform_plugin {
         $ce:ident,
         $target_plugin:ty
     ) => {{
-        let mut audit_scope = AuditScope::new(<($target_plugin)>::id());
+        let mut audit_scope = $au.child(<($target_plugin)>::id());
         let r = audit_segment!(audit_scope, || <($target_plugin)>::pre_create_transform(
             &mut audit_scope,
             $qs,
@@ -139,7 +156,7 @@ macro_rules! run_pre_create_plugin {
         $ce:ident,
         $target_plugin:ty
     ) => {{
-        let mut audit_scope = AuditScope::new(<($target_plugin)>::id());
+        let mut audit_scope = $au.child(<($target_plugin)>::id());
         let r = audit_segment!(audit_scope, || <($target_plugin)>::pre_create(
             &mut audit_scope,
             $qs,
@@ -159,7 +176,7 @@ macro_rules! run_post_create_plugin {
         $ce:ident,
         $target_plugin:ty
     ) => {{
-        let mut audit_scope = AuditScope::new(<($target_plugin)>::id());
+        let mut audit_scope = $au.child(<($target_plugin)>::id());
         let r = audit_segment!(audit_scope, || <($target_plugin)>::post_create(
             &mut audit_scope,
             $qs,
@@ -179,7 +196,7 @@ macro_rules! run_pre_modify_plugin {
         $ce:ident,
         $target_plugin:ty
     ) => {{
-        let mut audit_scope = AuditScope::new(<($target_plugin)>::id());
+        let mut audit_scope = $au.child(<($target_plugin)>::id());
         let r = audit_segment!(audit_scope, || <($target_plugin)>::pre_modify(
             &mut audit_scope,
             $qs,
@@ -200,7 +217,7 @@ macro_rules! run_post_modify_plugin {
         $ce:ident,
         $target_plugin:ty
     ) => {{
-        let mut audit_scope = AuditScope::new(<($target_plugin)>::id());
+        let mut audit_scope = $au.child(<($target_plugin)>::id());
         let r = audit_segment!(audit_scope, || <($target_plugin)>::post_modify(
             &mut audit_scope,
             $qs,
@@ -221,7 +238,7 @@ macro_rules! run_pre_delete_plugin {
         $ce:ident,
         $target_plugin:ty
     ) => {{
-        let mut audit_scope = AuditScope::new(<($target_plugin)>::id());
+        let mut audit_scope = $au.child(<($target_plugin)>::id());
         let r = audit_segment!(audit_scope, || <($target_plugin)>::pre_delete(
             &mut audit_scope,
             $qs,
@@ -241,7 +258,7 @@ macro_rules! run_post_delete_plugin {
         $ce:ident,
         $target_plugin:ty
     ) => {{
-        let mut audit_scope = AuditScope::new(<($target_plugin)>::id());
+        let mut audit_scope = $au.child(<($target_plugin)>::id());
         let r = audit_segment!(audit_scope, || <($target_plugin)>::post_delete(
             &mut audit_scope,
             $qs,
@@ -260,7 +277,7 @@ macro_rules! run_verify_plugin {
         $results:expr,
         $target_plugin:ty
     ) => {{
-        let mut audit_scope = AuditScope::new(<($target_plugin)>::id());
+        let mut audit_scope = $au.child(<($target_plugin)>::id());
         let mut r = audit_segment!(audit_scope, || <($target_plugin)>::verify(
             &mut audit_scope,
             $qs,
@@ -270,6 +287,22 @@ macro_rules! run_verify_plugin {
     }};
 }
 
+macro_rules! run_regenerate_plugin {
+    (
+        $au:ident,
+        $qs:ident,
+        $target_plugin:ty
+    ) => {{
+        let mut audit_scope = $au.child(<($target_plugin)>::id());
+        let r = audit_segment!(audit_scope, || <($target_plugin)>::regenerate(
+            &mut audit_scope,
+            $qs,
+        ));
+        $au.append_scope(audit_scope);
+        r
+    }};
+}
+
 impl Plugins {
     pub fn run_pre_create_transform(
         au: &mut AuditScope,
@@ -278,8 +311,15 @@ impl Plugins {
         ce: &CreateEvent,
     ) -> Result<(), OperationError> {
         audit_segment!(au, || {
-            let res =
-                run_pre_create_transform_plugin!(au, qs, cand, ce, base::Base).and_then(|_| {
+            let res = run_pre_create_transform_plugin!(au, qs, cand, ce, base::Base)
+                .and_then(|_| {
+                    run_pre_create_transform_plugin!(au, qs, cand, ce, gidnumber::GidNumber)
+                })
+                .and_then(|_| run_pre_create_transform_plugin!(au, qs, cand, ce, spn::Spn))
+                .and_then(|_| {
+                    run_pre_create_transform_plugin!(au, qs, cand, ce, protected::Protected)
+                })
+                .and_then(|_| {
                     run_pre_create_transform_plugin!(au, qs, cand, ce, attrunique::AttrUnique)
                 });
 
@@ -294,7 +334,11 @@ impl Plugins {
         ce: &CreateEvent,
     ) -> Result<(), OperationError> {
         audit_segment!(au, || {
-            let res = run_pre_create_plugin!(au, qs, cand, ce, protected::Protected);
+            let res = run_pre_create_plugin!(au, qs, cand, ce, protected::Protected)
+                .and_then(|_| run_pre_create_plugin!(au, qs, cand, ce, reauth::Reauth))
+                .and_then(|_| {
+                    run_pre_create_plugin!(au, qs, cand, ce, accesscontrol::AccessControl)
+                });
 
             res
         })
@@ -322,8 +366,14 @@ impl Plugins {
     ) -> Result<(), OperationError> {
         audit_segment!(au, || {
             let res = run_pre_modify_plugin!(au, qs, cand, me, protected::Protected)
+                .and_then(|_| run_pre_modify_plugin!(au, qs, cand, me, reauth::Reauth))
                 .and_then(|_| run_pre_modify_plugin!(au, qs, cand, me, base::Base))
-                .and_then(|_| run_pre_modify_plugin!(au, qs, cand, me, attrunique::AttrUnique));
+                .and_then(|_| run_pre_modify_plugin!(au, qs, cand, me, gidnumber::GidNumber))
+                .and_then(|_| run_pre_modify_plugin!(au, qs, cand, me, spn::Spn))
+                .and_then(|_| run_pre_modify_plugin!(au, qs, cand, me, attrunique::AttrUnique))
+                .and_then(|_| {
+                    run_pre_modify_plugin!(au, qs, cand, me, accesscontrol::AccessControl)
+                });
 
             res
         })
@@ -341,7 +391,8 @@ impl Plugins {
                 run_post_modify_plugin!(au, qs, pre_cand, cand, me, refint::ReferentialIntegrity)
                     .and_then(|_| {
                         run_post_modify_plugin!(au, qs, pre_cand, cand, me, memberof::MemberOf)
-                    });
+                    })
+                    .and_then(|_| run_post_modify_plugin!(au, qs, pre_cand, cand, me, spn::Spn));
 
             res
         })
@@ -354,7 +405,10 @@ impl Plugins {
         de: &DeleteEvent,
     ) -> Result<(), OperationError> {
         audit_segment!(au, || {
-            let res = run_pre_delete_plugin!(au, qs, cand, de, protected::Protected);
+            let res = run_pre_delete_plugin!(au, qs, cand, de, protected::Protected)
+                .and_then(|_| run_pre_delete_plugin!(au, qs, cand, de, reauth::Reauth))
+                .and_then(|_| run_pre_delete_plugin!(au, qs, cand, de, schemainuse::SchemaInUse))
+                .and_then(|_| run_pre_delete_plugin!(au, qs, cand, de, memberof::MemberOf));
             res
         })
     }
@@ -382,6 +436,69 @@ impl Plugins {
         run_verify_plugin!(au, qs, &mut results, attrunique::AttrUnique);
         run_verify_plugin!(au, qs, &mut results, refint::ReferentialIntegrity);
         run_verify_plugin!(au, qs, &mut results, memberof::MemberOf);
+        run_verify_plugin!(au, qs, &mut results, spn::Spn);
+        results
+    }
+
+    // Only what the plugins derive from other entries, which run_regenerate
+    // can generate again.
+    pub fn run_verify_derived(
+        au: &mut AuditScope,
+        qs: &QueryServerReadTransaction,
+    ) -> Vec<Result<(), ConsistencyError>> {
+        let mut results = Vec::new();
+        run_verify_plugin!(au, qs, &mut results, memberof::MemberOf);
+        run_verify_plugin!(au, qs, &mut results, spn::Spn);
         results
     }
+
+    pub fn run_verify_refint(
+        au: &mut AuditScope,
+        qs: &QueryServerReadTransaction,
+    ) -> Vec<Result<(), ConsistencyError>> {
+        let mut results = Vec::new();
+        run_verify_plugin!(au, qs, &mut results, refint::ReferentialIntegrity);
+        results
+    }
+
+    pub fn run_verify_memberof(
+        au: &mut AuditScope,
+        qs: &QueryServerReadTransaction,
+    ) -> Vec<Result<(), ConsistencyError>> {
+        let mut results = Vec::new();
+        run_verify_plugin!(au, qs, &mut results, memberof::MemberOf);
+        results
+    }
+
+    pub fn run_delayed_action(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        action: &DelayedAction,
+    ) -> Result<(), OperationError> {
+        audit_segment!(au, || match action {
+            DelayedAction::MemberOf(uuids) => memberof::apply_delayed(au, qs, uuids),
+            DelayedAction::Spn => spn::regenerate_spns(au, qs),
+        })
+    }
+
+    // The references that refint finds dangling are removed, and what was
+    // changed is given back.
+    pub fn run_remove_dangling(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+    ) -> Result<Vec<RepairChange>, OperationError> {
+        audit_segment!(au, || refint::remove_dangling(au, qs))
+    }
+
+    pub fn run_regenerate(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+    ) -> Result<(), OperationError> {
+        audit_segment!(au, || {
+            let res = run_regenerate_plugin!(au, qs, spn::Spn)
+                .and_then(|_| run_regenerate_plugin!(au, qs, memberof::MemberOf));
+
+            res
+        })
+    }
 }
@@ -1,12 +1,22 @@
 // System protected objects. Items matching specific requirements
 // may only have certain modifications performed.
+//
+// There are three levels of protection, each marked by a class:
+// * system - fully immutable, such as schema and the builtin acps. Only
+//   must and may can be altered, so that system classes can be extended.
+// * system_restricted - builtin accounts and groups, where names, classes
+//   and uuids are fixed, but display names, members and credentials may
+//   be changed.
+// * system_undeletable - may be modified freely, but not deleted.
+// None of these can be deleted, or added to an entry by a client.
 use crate::plugins::Plugin;
 
 use crate::audit::AuditScope;
 use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntryValid};
 use crate::event::{CreateEvent, DeleteEvent, ModifyEvent};
+use crate::filter::f_eq;
 use crate::modify::Modify;
-use crate::server::QueryServerWriteTransaction;
+use crate::server::{QueryServerTransaction, QueryServerWriteTransaction};
 use crate::value::{PartialValue, Value};
 use kanidm_proto::v1::OperationError;
 use std::collections::HashSet;
@@ -24,19 +34,122 @@ lazy_static! {
         m.insert("may");
         m
     };
+    static ref RESTRICTED_ALLOWED_ATTRS: HashSet<&'static str> = {
+        let mut m = HashSet::new();
+        m.insert("displayname");
+        m.insert("description");
+        m.insert("legalname");
+        m.insert("mail");
+        m.insert("member");
+        m.insert("ssh_publickey");
+        m.insert("primary_credential");
+        m.insert("unix_password");
+        m.insert("radius_secret");
+        m.insert("account_expire");
+        m.insert("account_valid_from");
+        m.insert("account_locked_until");
+        m
+    };
     static ref PVCLASS_SYSTEM: PartialValue = PartialValue::new_class("system");
+    static ref PVCLASS_SYSTEM_RESTRICTED: PartialValue =
+        PartialValue::new_class("system_restricted");
+    static ref PVCLASS_SYSTEM_UNDELETABLE: PartialValue =
+        PartialValue::new_class("system_undeletable");
     static ref PVCLASS_TOMBSTONE: PartialValue = PartialValue::new_class("tombstone");
     static ref PVCLASS_RECYCLED: PartialValue = PartialValue::new_class("recycled");
+    static ref PVCLASS_ATTRIBUTETYPE: PartialValue = PartialValue::new_class("attributetype");
+    static ref PVCLASS_CLASSTYPE: PartialValue = PartialValue::new_class("classtype");
     static ref VCLASS_SYSTEM: Value = Value::new_class("system");
+    static ref VCLASS_SYSTEM_RESTRICTED: Value = Value::new_class("system_restricted");
+    static ref VCLASS_SYSTEM_UNDELETABLE: Value = Value::new_class("system_undeletable");
     static ref VCLASS_TOMBSTONE: Value = Value::new_class("tombstone");
     static ref VCLASS_RECYCLED: Value = Value::new_class("recycled");
 }
 
+// The classes a client may never create an entry with, add, or delete an
+// entry that has.
+fn is_protected_class(v: &Value) -> bool {
+    v == &(*VCLASS_SYSTEM)
+        || v == &(*VCLASS_SYSTEM_RESTRICTED)
+        || v == &(*VCLASS_SYSTEM_UNDELETABLE)
+        || v == &(*VCLASS_TOMBSTONE)
+        || v == &(*VCLASS_RECYCLED)
+}
+
+fn has_protected_class<VALID, STATE>(e: &Entry<VALID, STATE>) -> bool {
+    e.attribute_value_pres("class", &PVCLASS_SYSTEM)
+        || e.attribute_value_pres("class", &PVCLASS_SYSTEM_RESTRICTED)
+        || e.attribute_value_pres("class", &PVCLASS_SYSTEM_UNDELETABLE)
+        || e.attribute_value_pres("class", &PVCLASS_TOMBSTONE)
+        || e.attribute_value_pres("class", &PVCLASS_RECYCLED)
+}
+
+// The attributes that may be modified on the entry given its protection
+// level, or None if any may be.
+fn allowed_attrs<VALID, STATE>(e: &Entry<VALID, STATE>) -> Option<&'static HashSet<&'static str>> {
+    if e.attribute_value_pres("class", &PVCLASS_SYSTEM) {
+        Some(&*ALLOWED_ATTRS)
+    } else if e.attribute_value_pres("class", &PVCLASS_SYSTEM_RESTRICTED) {
+        Some(&*RESTRICTED_ALLOWED_ATTRS)
+    } else {
+        None
+    }
+}
+
 impl Plugin for Protected {
     fn id() -> &'static str {
         "plugin_protected"
     }
 
+    fn pre_create_transform(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        cand: &mut Vec<Entry<EntryInvalid, EntryNew>>,
+        ce: &CreateEvent,
+    ) -> Result<(), OperationError> {
+        if ce.event.is_internal() {
+            audit_log!(
+                au,
+                "Internal operation, not enforcing system object protection"
+            );
+            return Ok(());
+        }
+
+        // New schema definitions may not shadow system ones. This must be
+        // checked before attrunique, which would otherwise just report the
+        // name as a duplicate.
+        let names: Vec<_> = cand
+            .iter()
+            .flat_map(|e| {
+                let an = e
+                    .get_ava_single("attributename")
+                    .filter(|_| e.attribute_value_pres("class", &PVCLASS_ATTRIBUTETYPE))
+                    .map(|v| f_eq("attributename", v.to_partialvalue()));
+                let cn = e
+                    .get_ava_single("classname")
+                    .filter(|_| e.attribute_value_pres("class", &PVCLASS_CLASSTYPE))
+                    .map(|v| f_eq("classname", v.to_partialvalue()));
+                an.into_iter().chain(cn.into_iter())
+            })
+            .collect();
+
+        if names.len() == 0 {
+            return Ok(());
+        }
+
+        let filt = filter!(f_and(vec![
+            f_eq("class", PVCLASS_SYSTEM.clone()),
+            f_or(names)
+        ]));
+        let shadowed = try_audit!(au, qs.internal_search(au, filt));
+        if shadowed.len() > 0 {
+            audit_log!(au, "Refusing to shadow system schema -> {:?}", shadowed);
+            Err(OperationError::SystemProtectedObject)
+        } else {
+            Ok(())
+        }
+    }
+
     fn pre_create(
         au: &mut AuditScope,
         _qs: &mut QueryServerWriteTransaction,
@@ -55,10 +168,7 @@ impl Plugin for Protected {
         cand.iter().fold(Ok(()), |acc, cand| match acc {
             Err(_) => acc,
             Ok(_) => {
-                if cand.attribute_value_pres("class", &PVCLASS_SYSTEM)
-                    || cand.attribute_value_pres("class", &PVCLASS_TOMBSTONE)
-                    || cand.attribute_value_pres("class", &PVCLASS_RECYCLED)
-                {
+                if has_protected_class(cand) {
                     Err(OperationError::SystemProtectedObject)
                 } else {
                     acc
@@ -81,19 +191,22 @@ impl Plugin for Protected {
             );
             return Ok(());
         }
-        // Prevent adding class: system, tombstone, or recycled.
+        // Prevent adding class: system, tombstone, or recycled, or any other
+        // protection level.
         me.modlist.iter().fold(Ok(()), |acc, m| {
             if acc.is_err() {
                 acc
             } else {
                 match m {
                     Modify::Present(a, v) => {
-                        // TODO: Can we avoid this clone?
-                        if a == "class"
-                            && (v == &(VCLASS_SYSTEM.clone())
-                                || v == &(VCLASS_TOMBSTONE.clone())
-                                || v == &(VCLASS_RECYCLED.clone()))
-                        {
+                        if a == "class" && is_protected_class(v) {
+                            Err(OperationError::SystemProtectedObject)
+                        } else {
+                            Ok(())
+                        }
+                    }
+                    Modify::Set(a, vs) => {
+                        if a == "class" && vs.iter().any(|v| is_protected_class(v)) {
                             Err(OperationError::SystemProtectedObject)
                         } else {
                             Ok(())
@@ -118,37 +231,34 @@ impl Plugin for Protected {
             }
         })?;
 
-        // if class: system, check the mods are "allowed"
-        let system_pres = cand.iter().fold(false, |acc, c| {
-            if acc {
-                acc
-            } else {
-                c.attribute_value_pres("class", &PVCLASS_SYSTEM)
-            }
-        });
-
-        audit_log!(au, "class: system -> {}", system_pres);
-        // No system types being altered, return.
-        if system_pres == false {
-            return Ok(());
-        }
-
-        // Something altered is system, check if it's allowed.
-        me.modlist.iter().fold(Ok(()), |acc, m| {
-            // Already hit an error, move on.
-            if acc.is_err() {
-                acc
-            } else {
-                let a = match m {
-                    Modify::Present(a, _) => a,
-                    Modify::Removed(a, _) => a,
-                    Modify::Purged(a) => a,
-                };
-                match ALLOWED_ATTRS.get(a.as_str()) {
-                    Some(_) => Ok(()),
-                    None => Err(OperationError::SystemProtectedObject),
+        // Check the mods are allowed by the protection level of each entry.
+        // Assertions change nothing, so are always allowed.
+        let attrs: Vec<&str> = me
+            .modlist
+            .iter()
+            .filter_map(|m| match m {
+                Modify::Present(a, _)
+                | Modify::Removed(a, _)
+                | Modify::Purged(a)
+                | Modify::Set(a, _) => Some(a.as_str()),
+                Modify::Assert(_, _) | Modify::AssertMissing(_) => None,
+            })
+            .collect();
+
+        cand.iter().try_for_each(|c| match allowed_attrs(c) {
+            Some(allowed) => match attrs.iter().find(|a| !allowed.contains(**a)) {
+                Some(a) => {
+                    audit_log!(
+                        au,
+                        "Refusing to modify {} of system protected {:?}",
+                        a,
+                        c.get_uuid()
+                    );
+                    Err(OperationError::SystemProtectedAttribute(a.to_string()))
                 }
-            }
+                None => Ok(()),
+            },
+            None => Ok(()),
         })
     }
 
@@ -170,10 +280,7 @@ impl Plugin for Protected {
         cand.iter().fold(Ok(()), |acc, cand| match acc {
             Err(_) => acc,
             Ok(_) => {
-                if cand.attribute_value_pres("class", &PVCLASS_SYSTEM)
-                    || cand.attribute_value_pres("class", &PVCLASS_TOMBSTONE)
-                    || cand.attribute_value_pres("class", &PVCLASS_RECYCLED)
-                {
+                if has_protected_class(cand) {
                     Err(OperationError::SystemProtectedObject)
                 } else {
                     acc
@@ -213,11 +320,18 @@ mod tests {
                 "{\"Pres\":\"class\"}"
             ],
             "acp_search_attr": ["name", "class", "uuid", "classname", "attributename"],
-            "acp_modify_class": ["system"],
-            "acp_modify_removedattr": ["class", "displayname", "may", "must"],
-            "acp_modify_presentattr": ["class", "displayname", "may", "must"],
-            "acp_create_class": ["object", "person", "system"],
-            "acp_create_attr": ["name", "class", "description", "displayname"]
+            "acp_modify_class": ["system", "system_restricted", "group"],
+            "acp_modify_removedattr": [
+                "class", "name", "displayname", "description", "member", "may", "must"
+            ],
+            "acp_modify_presentattr": [
+                "class", "name", "displayname", "description", "member", "may", "must"
+            ],
+            "acp_create_class": ["object", "person", "system", "attributetype", "classtype"],
+            "acp_create_attr": [
+                "name", "class", "description", "displayname", "attributename", "classname",
+                "multivalue", "unique", "syntax", "may"
+            ]
         }
     }"#;
 
@@ -252,6 +366,64 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_pre_create_schema_shadow_deny() {
+        // Test a new definition can't take the name of a system one.
+        let acp: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_ADMIN_ALLOW_ALL);
+
+        let preload = vec![acp];
+
+        let ea: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+            r#"{
+            "valid": null,
+            "state": null,
+            "attrs": {
+                "class": ["object", "attributetype"],
+                "attributename": ["displayname"],
+                "description": ["shadow"],
+                "multivalue": ["true"],
+                "unique": ["false"],
+                "syntax": ["UTF8STRING"]
+            }
+        }"#,
+        );
+        let create = vec![ea];
+
+        run_create_test!(
+            Err(OperationError::SystemProtectedObject),
+            preload,
+            create,
+            Some(JSON_ADMIN_V1),
+            |_, _| {}
+        );
+
+        let acp: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_ADMIN_ALLOW_ALL);
+
+        let preload = vec![acp];
+
+        let ec: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+            r#"{
+            "valid": null,
+            "state": null,
+            "attrs": {
+                "class": ["object", "classtype"],
+                "classname": ["person"],
+                "description": ["shadow"],
+                "may": ["displayname"]
+            }
+        }"#,
+        );
+        let create = vec![ec];
+
+        run_create_test!(
+            Err(OperationError::SystemProtectedObject),
+            preload,
+            create,
+            Some(JSON_ADMIN_V1),
+            |_, _| {}
+        );
+    }
+
     #[test]
     fn test_pre_modify_system_deny() {
         let acp: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_ADMIN_ALLOW_ALL);
@@ -272,7 +444,9 @@ mod tests {
         let preload = vec![acp, e.clone()];
 
         run_modify_test!(
-            Err(OperationError::SystemProtectedObject),
+            Err(OperationError::SystemProtectedAttribute(
+                "displayname".to_string()
+            )),
             preload,
             filter!(f_eq("name", PartialValue::new_iutf8s("testperson"))),
             modlist!([
@@ -372,4 +546,192 @@ mod tests {
             |_, _| {}
         );
     }
+
+    static JSON_RESTRICTED_GROUP: &'static str = r#"{
+        "valid": null,
+        "state": null,
+        "attrs": {
+            "class": ["group", "system_restricted"],
+            "name": ["testgroup"],
+            "description": ["testgroup"]
+        }
+    }"#;
+
+    #[test]
+    fn test_pre_modify_restricted_allow() {
+        let acp: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_ADMIN_ALLOW_ALL);
+        // Builtin groups may have their description and members changed.
+        let e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_RESTRICTED_GROUP);
+
+        let preload = vec![acp, e];
+
+        run_modify_test!(
+            Ok(()),
+            preload,
+            filter!(f_eq("name", PartialValue::new_iutf8s("testgroup"))),
+            modlist!([
+                m_purge("description"),
+                m_pres("description", &Value::new_utf8s("changed")),
+                m_pres(
+                    "member",
+                    &Value::new_refer_s("bb18f746-a409-497d-928c-5455d4aef4f7").unwrap()
+                ),
+            ]),
+            Some(JSON_ADMIN_V1),
+            |_, _| {}
+        );
+    }
+
+    #[test]
+    fn test_pre_modify_restricted_deny() {
+        let acp: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_ADMIN_ALLOW_ALL);
+        // But not be renamed.
+        let e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_RESTRICTED_GROUP);
+
+        let preload = vec![acp, e];
+
+        run_modify_test!(
+            Err(OperationError::SystemProtectedAttribute("name".to_string())),
+            preload,
+            filter!(f_eq("name", PartialValue::new_iutf8s("testgroup"))),
+            modlist!([
+                m_pres("description", &Value::new_utf8s("changed")),
+                m_purge("name"),
+                m_pres("name", &Value::new_iutf8s("renamed")),
+            ]),
+            Some(JSON_ADMIN_V1),
+            |_, _| {}
+        );
+    }
+
+    #[test]
+    fn test_pre_modify_restricted_class_deny() {
+        let acp: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_ADMIN_ALLOW_ALL);
+        let e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_RESTRICTED_GROUP);
+
+        let preload = vec![acp, e];
+
+        run_modify_test!(
+            Err(OperationError::SystemProtectedAttribute(
+                "class".to_string()
+            )),
+            preload,
+            filter!(f_eq("name", PartialValue::new_iutf8s("testgroup"))),
+            modlist!([m_remove("class", &PartialValue::new_class("group"))]),
+            Some(JSON_ADMIN_V1),
+            |_, _| {}
+        );
+
+        // Purging class is refused too, by access controls before it gets
+        // this far.
+        let acp: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_ADMIN_ALLOW_ALL);
+        let e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_RESTRICTED_GROUP);
+
+        let preload = vec![acp, e];
+
+        run_modify_test!(
+            Err(OperationError::AccessDenied),
+            preload,
+            filter!(f_eq("name", PartialValue::new_iutf8s("testgroup"))),
+            modlist!([m_purge("class")]),
+            Some(JSON_ADMIN_V1),
+            |_, _| {}
+        );
+    }
+
+    #[test]
+    fn test_pre_modify_restricted_class_add_deny() {
+        let acp: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_ADMIN_ALLOW_ALL);
+        // A protection level can't be given to an entry.
+        let e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+            r#"{
+            "valid": null,
+            "state": null,
+            "attrs": {
+                "class": ["group"],
+                "name": ["testgroup"],
+                "description": ["testgroup"]
+            }
+        }"#,
+        );
+
+        let preload = vec![acp, e];
+
+        run_modify_test!(
+            Err(OperationError::SystemProtectedObject),
+            preload,
+            filter!(f_eq("name", PartialValue::new_iutf8s("testgroup"))),
+            modlist!([m_pres("class", &Value::new_class("system_restricted"))]),
+            Some(JSON_ADMIN_V1),
+            |_, _| {}
+        );
+    }
+
+    #[test]
+    fn test_pre_delete_restricted_deny() {
+        let acp: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_ADMIN_ALLOW_ALL);
+        let e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_RESTRICTED_GROUP);
+
+        let preload = vec![acp, e];
+
+        run_delete_test!(
+            Err(OperationError::SystemProtectedObject),
+            preload,
+            filter!(f_eq("name", PartialValue::new_iutf8s("testgroup"))),
+            Some(JSON_ADMIN_V1),
+            |_, _| {}
+        );
+    }
+
+    static JSON_UNDELETABLE_PERSON: &'static str = r#"{
+        "valid": null,
+        "state": null,
+        "attrs": {
+            "class": ["person", "system_undeletable"],
+            "name": ["testperson"],
+            "description": ["testperson"],
+            "displayname": ["testperson"]
+        }
+    }"#;
+
+    #[test]
+    fn test_pre_modify_undeletable_allow() {
+        let acp: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_ADMIN_ALLOW_ALL);
+        // Any attribute may be changed, even the name.
+        let e: Entry<EntryInvalid, EntryNew> =
+            Entry::unsafe_from_entry_str(JSON_UNDELETABLE_PERSON);
+
+        let preload = vec![acp, e];
+
+        run_modify_test!(
+            Ok(()),
+            preload,
+            filter!(f_eq("name", PartialValue::new_iutf8s("testperson"))),
+            modlist!([
+                m_purge("name"),
+                m_pres("name", &Value::new_iutf8s("renamed")),
+                m_purge("displayname"),
+                m_pres("displayname", &Value::new_utf8s("renamed")),
+            ]),
+            Some(JSON_ADMIN_V1),
+            |_, _| {}
+        );
+    }
+
+    #[test]
+    fn test_pre_delete_undeletable_deny() {
+        let acp: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_ADMIN_ALLOW_ALL);
+        let e: Entry<EntryInvalid, EntryNew> =
+            Entry::unsafe_from_entry_str(JSON_UNDELETABLE_PERSON);
+
+        let preload = vec![acp, e];
+
+        run_delete_test!(
+            Err(OperationError::SystemProtectedObject),
+            preload,
+            filter!(f_eq("name", PartialValue::new_iutf8s("testperson"))),
+            Some(JSON_ADMIN_V1),
+            |_, _| {}
+        );
+    }
 }
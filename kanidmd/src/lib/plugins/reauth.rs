@@ -0,0 +1,257 @@
+// Changes to access controls, the system configuration and the domain info
+// can give away the whole server, so these need the session they are made
+// with to have authenticated recently, rather than at any time in its life.
+use crate::plugins::Plugin;
+
+use crate::audit::AuditScope;
+use crate::constants::{UUID_DOMAIN_INFO, UUID_SYSTEM_CONFIG, UUID_SYSTEM_INFO};
+use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntryValid};
+use crate::event::{CreateEvent, DeleteEvent, Event, ModifyEvent};
+use crate::idm::reauth::ProtectedOperation;
+use crate::server::QueryServerWriteTransaction;
+use crate::value::PartialValue;
+use kanidm_proto::v1::OperationError;
+use std::time::SystemTime;
+
+pub struct Reauth {}
+
+lazy_static! {
+    static ref PVCLASS_ACP: PartialValue = PartialValue::new_class("access_control_profile");
+    static ref PVUUID_SYSTEM_CONFIG: PartialValue = PartialValue::new_uuidr(&UUID_SYSTEM_CONFIG);
+    static ref PVUUID_SYSTEM_INFO: PartialValue = PartialValue::new_uuidr(&UUID_SYSTEM_INFO);
+    static ref PVUUID_DOMAIN_INFO: PartialValue = PartialValue::new_uuidr(&UUID_DOMAIN_INFO);
+}
+
+fn protected_operation<VALID, STATE>(e: &Entry<VALID, STATE>) -> Option<ProtectedOperation> {
+    if e.attribute_value_pres("class", &PVCLASS_ACP) {
+        Some(ProtectedOperation::AccessControl)
+    } else if e.attribute_value_pres("uuid", &PVUUID_SYSTEM_CONFIG)
+        || e.attribute_value_pres("uuid", &PVUUID_SYSTEM_INFO)
+        || e.attribute_value_pres("uuid", &PVUUID_DOMAIN_INFO)
+    {
+        Some(ProtectedOperation::SystemConfig)
+    } else {
+        None
+    }
+}
+
+fn check_candidates<'a, VALID: 'a, STATE: 'a, I>(
+    au: &mut AuditScope,
+    qs: &QueryServerWriteTransaction,
+    cand: I,
+    event: &Event,
+) -> Result<(), OperationError>
+where
+    I: Iterator<Item = &'a Entry<VALID, STATE>>,
+{
+    // Internal events, and those not made with a session, have no auth
+    // time to check.
+    let auth_time = match event.auth_time {
+        Some(t) => t,
+        None => return Ok(()),
+    };
+    let ct = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Clock failure!");
+    let policy = qs.get_reauth_policy();
+    cand.filter_map(|e| protected_operation(e))
+        .try_for_each(|op| policy.check(au, op, auth_time, ct))
+}
+
+impl Plugin for Reauth {
+    fn id() -> &'static str {
+        "plugin_reauth"
+    }
+
+    fn pre_create(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        cand: &Vec<Entry<EntryValid, EntryNew>>,
+        ce: &CreateEvent,
+    ) -> Result<(), OperationError> {
+        check_candidates(au, qs, cand.iter(), &ce.event)
+    }
+
+    fn pre_modify(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        cand: &mut Vec<Entry<EntryInvalid, EntryCommitted>>,
+        me: &ModifyEvent,
+    ) -> Result<(), OperationError> {
+        check_candidates(au, qs, cand.iter(), &me.event)
+    }
+
+    fn pre_delete(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        cand: &mut Vec<Entry<EntryInvalid, EntryCommitted>>,
+        de: &DeleteEvent,
+    ) -> Result<(), OperationError> {
+        check_candidates(au, qs, cand.iter(), &de.event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::AuditScope;
+    use crate::be::Backend;
+    use crate::constants::{JSON_ADMIN_V1, REAUTH_WINDOW};
+    use crate::entry::{Entry, EntryInvalid, EntryNew};
+    use crate::event::{CreateEvent, DeleteEvent, ModifyEvent};
+    use crate::modify::{Modify, ModifyList};
+    use crate::schema::Schema;
+    use crate::server::QueryServer;
+    use crate::value::{PartialValue, Value};
+    use kanidm_proto::v1::OperationError;
+    use std::time::SystemTime;
+
+    static JSON_ADMIN_ALLOW_ALL: &'static str = r#"{
+        "valid": null,
+        "state": null,
+        "attrs": {
+            "class": [
+                "object",
+                "access_control_profile",
+                "access_control_modify",
+                "access_control_create",
+                "access_control_delete",
+                "access_control_search"
+            ],
+            "name": ["idm_admins_acp_allow_all_test"],
+            "uuid": ["bb18f746-a409-497d-928c-5455d4aef4f7"],
+            "description": ["Builtin IDM Administrators Access Controls."],
+            "acp_enable": ["true"],
+            "acp_receiver": [
+                "{\"Eq\":[\"uuid\",\"00000000-0000-0000-0000-000000000000\"]}"
+            ],
+            "acp_targetscope": [
+                "{\"Pres\":\"class\"}"
+            ],
+            "acp_search_attr": ["name", "class", "uuid"],
+            "acp_modify_class": ["object"],
+            "acp_modify_presentattr": ["acp_search_attr", "description", "badlist_password"],
+            "acp_create_class": ["object", "access_control_profile", "access_control_search"],
+            "acp_create_attr": [
+                "name", "class", "uuid", "acp_enable", "acp_receiver", "acp_targetscope",
+                "acp_search_attr"
+            ]
+        }
+    }"#;
+
+    static JSON_TEST_ACP: &'static str = r#"{
+        "valid": null,
+        "state": null,
+        "attrs": {
+            "class": ["object", "access_control_profile", "access_control_search"],
+            "name": ["acp_reauth_test"],
+            "uuid": ["a0b3ce1c-6fa6-4a62-a6c8-a9d8e0e7f4a1"],
+            "acp_enable": ["true"],
+            "acp_receiver": ["{\"Eq\":[\"name\",\"admin\"]}"],
+            "acp_targetscope": ["{\"Eq\":[\"name\",\"admin\"]}"],
+            "acp_search_attr": ["name"]
+        }
+    }"#;
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("Clock failure!")
+            .as_secs()
+    }
+
+    #[test]
+    fn test_reauth_access_control() {
+        let acp: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_ADMIN_ALLOW_ALL);
+        let preload = vec![acp];
+        let mut au = AuditScope::new("test_reauth_access_control");
+        let qs = setup_test!(&mut au, preload);
+
+        let stale = Some(now() - REAUTH_WINDOW - 1);
+        let fresh = Some(now());
+
+        let e_acp: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_TEST_ACP);
+        let mut ce = unsafe { CreateEvent::new_impersonate_entry_ser(JSON_ADMIN_V1, vec![e_acp]) };
+
+        // A session that authenticated too long ago is told to reauth ...
+        ce.event.auth_time = stale;
+        let mut qs_write = qs.write();
+        assert!(qs_write.create(&mut au, &ce) == Err(OperationError::ReauthRequired));
+        drop(qs_write);
+
+        // ... after which the same request succeeds.
+        ce.event.auth_time = fresh;
+        let mut qs_write = qs.write();
+        assert!(qs_write.create(&mut au, &ce).is_ok());
+        assert!(qs_write.commit(&mut au).is_ok());
+
+        let f_acp = filter!(f_eq("name", PartialValue::new_iutf8s("acp_reauth_test")));
+        let mut me = unsafe {
+            ModifyEvent::new_impersonate_entry_ser(
+                JSON_ADMIN_V1,
+                f_acp.clone(),
+                ModifyList::new_list(vec![Modify::Present(
+                    "acp_search_attr".to_string(),
+                    Value::new_iutf8s("displayname"),
+                )]),
+            )
+        };
+        me.event.auth_time = stale;
+        let mut qs_write = qs.write();
+        assert!(qs_write.modify(&mut au, &me) == Err(OperationError::ReauthRequired));
+        drop(qs_write);
+
+        let mut de = unsafe { DeleteEvent::new_impersonate_entry_ser(JSON_ADMIN_V1, f_acp) };
+        de.event.auth_time = stale;
+        let mut qs_write = qs.write();
+        assert!(qs_write.delete(&mut au, &de) == Err(OperationError::ReauthRequired));
+        de.event.auth_time = fresh;
+        assert!(qs_write.delete(&mut au, &de).is_ok());
+        assert!(qs_write.commit(&mut au).is_ok());
+
+        // Entries that aren't protected only need a valid session.
+        let mut me = unsafe {
+            ModifyEvent::new_impersonate_entry_ser(
+                JSON_ADMIN_V1,
+                filter!(f_eq("name", PartialValue::new_iutf8s("admin"))),
+                ModifyList::new_list(vec![Modify::Present(
+                    "description".to_string(),
+                    Value::new_utf8s("stale but allowed"),
+                )]),
+            )
+        };
+        me.event.auth_time = Some(0);
+        let mut qs_write = qs.write();
+        assert!(qs_write.modify(&mut au, &me).is_ok());
+        assert!(qs_write.commit(&mut au).is_ok());
+        println!("{}", au);
+    }
+
+    #[test]
+    fn test_reauth_system_config() {
+        let acp: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_ADMIN_ALLOW_ALL);
+        let preload = vec![acp];
+        let mut au = AuditScope::new("test_reauth_system_config");
+        let qs = setup_test!(&mut au, preload);
+
+        let mut me = unsafe {
+            ModifyEvent::new_impersonate_entry_ser(
+                JSON_ADMIN_V1,
+                filter!(f_eq("class", PartialValue::new_class("system_config"))),
+                ModifyList::new_list(vec![Modify::Present(
+                    "badlist_password".to_string(),
+                    Value::new_iutf8s("reauthtestpassword"),
+                )]),
+            )
+        };
+        me.event.auth_time = Some(now() - REAUTH_WINDOW - 1);
+        let mut qs_write = qs.write();
+        assert!(qs_write.modify(&mut au, &me) == Err(OperationError::ReauthRequired));
+        drop(qs_write);
+
+        me.event.auth_time = Some(now());
+        let mut qs_write = qs.write();
+        assert!(qs_write.modify(&mut au, &me).is_ok());
+        assert!(qs_write.commit(&mut au).is_ok());
+        println!("{}", au);
+    }
+}
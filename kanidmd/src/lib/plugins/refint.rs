@@ -9,7 +9,7 @@
 // when that is written, as they *both* manipulate and alter entry reference
 // data, so we should be careful not to step on each other.
 
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 
 use crate::audit::AuditScope;
 use crate::entry::{Entry, EntryCommitted, EntryNew, EntryValid};
@@ -20,7 +20,7 @@ use crate::schema::SchemaTransaction;
 use crate::server::QueryServerTransaction;
 use crate::server::{QueryServerReadTransaction, QueryServerWriteTransaction};
 use crate::value::{PartialValue, Value};
-use kanidm_proto::v1::{ConsistencyError, OperationError};
+use kanidm_proto::v1::{ConsistencyError, OperationError, RepairChange};
 use uuid::Uuid;
 
 // NOTE: This *must* be after base.rs!!!
@@ -36,7 +36,7 @@ impl ReferentialIntegrity {
     ) -> Result<(), OperationError> {
         debug!("{:?}", uuid_value);
         let uuid = try_audit!(au, uuid_value.to_ref_uuid().ok_or(OperationError::Plugin));
-        let mut au_qs = AuditScope::new("qs_exist");
+        let mut au_qs = au.child("qs_exist");
         // NOTE: This only checks LIVE entries (not using filter_all)
         let filt_in = filter!(f_eq("uuid", PartialValue::new_uuid(uuid.clone())));
         let r = qs.internal_exists(&mut au_qs, filt_in);
@@ -128,6 +128,14 @@ impl Plugin for ReferentialIntegrity {
                         None => {}
                     }
                 }
+                Modify::Set(a, vs) => match ref_types.get(a) {
+                    Some(a_type) => {
+                        for v in vs {
+                            Self::check_uuid_exists(au, qs, &a_type.name, v)?
+                        }
+                    }
+                    None => {}
+                },
                 _ => {}
             }
         }
@@ -166,6 +174,32 @@ impl Plugin for ReferentialIntegrity {
 
         audit_log!(au, "refint post_delete filter {:?}", filt);
 
+        // Find what references what before we strip them, so that the
+        // recycled entries can record which references they lost. A revive
+        // does not restore these, but they allow a restore to be informed.
+        let referrers = try_audit!(au, qs.internal_search(au, filt.clone()));
+        let mut stripped: BTreeMap<&Uuid, Vec<Value>> = BTreeMap::new();
+        for r in referrers.iter() {
+            for r_type in ref_types.values() {
+                let rvs = match r.get_ava_reference_uuid(r_type.name.as_str()) {
+                    Some(rvs) => rvs,
+                    None => continue,
+                };
+                for u in uuids.iter() {
+                    if rvs.contains(u) {
+                        stripped
+                            .entry(*u)
+                            .or_insert_with(Vec::new)
+                            .push(Value::new_utf8(format!(
+                                "{}:{}",
+                                r_type.name,
+                                r.get_uuid().to_hyphenated_ref()
+                            )));
+                    }
+                }
+            }
+        }
+
         // Create a modlist:
         //    In each, create a "removed" for each attr:uuid pair
         let modlist: ModifyList<ModifyInvalid> = ModifyList::new_list(
@@ -183,8 +217,26 @@ impl Plugin for ReferentialIntegrity {
         audit_log!(au, "refint post_delete modlist {:?}", modlist);
 
         // Do an internal modify to apply the modlist and filter.
+        try_audit!(au, qs.internal_modify(au, filt, modlist));
+
+        // Now record the stripped references on the recycled entries.
+        for (u, vs) in stripped.into_iter() {
+            let modlist: ModifyList<ModifyInvalid> = ModifyList::new_list(
+                vs.into_iter()
+                    .map(|v| Modify::Present("recycled_references".to_string(), v))
+                    .collect(),
+            );
+            try_audit!(
+                au,
+                qs.internal_modify(
+                    au,
+                    filter_all!(f_eq("uuid", PartialValue::new_uuidr(u))),
+                    modlist
+                )
+            );
+        }
 
-        qs.internal_modify(au, filt, modlist)
+        Ok(())
     }
 
     fn verify(
@@ -225,7 +277,7 @@ impl Plugin for ReferentialIntegrity {
                                     }
                                 }
                                 None => res.push(Err(ConsistencyError::InvalidAttributeType(
-                                    "A non-value-ref type was found.",
+                                    "A non-value-ref type was found.".to_string(),
                                 ))),
                             }
                         }
@@ -239,6 +291,62 @@ impl Plugin for ReferentialIntegrity {
     }
 }
 
+// Remove each reference to an entry that doesn't exist, as the refint check
+// finds them. What memberof holds is left to be generated again from the
+// groups. This gives each attribute changed, as it was and as it is now.
+pub(crate) fn remove_dangling(
+    au: &mut AuditScope,
+    qs: &mut QueryServerWriteTransaction,
+) -> Result<Vec<RepairChange>, OperationError> {
+    let all_cand = qs.internal_search(au, filter_all!(f_pres("class")))?;
+    let acu_map: BTreeSet<&Uuid> = all_cand.iter().map(|e| e.get_uuid()).collect();
+    let ref_types: Vec<String> = qs
+        .get_schema()
+        .get_reference_types()
+        .values()
+        .map(|rtype| rtype.name.clone())
+        .filter(|name| name != "memberof" && name != "directmemberof")
+        .collect();
+
+    let mut changes = Vec::new();
+    for c in all_cand.iter() {
+        for attr in ref_types.iter() {
+            let vs = match c.get_ava(attr) {
+                Some(vs) => vs,
+                None => continue,
+            };
+            let before: Vec<String> = vs.iter().map(|v| v.to_proto_string_clone()).collect();
+            let (dangling, kept): (Vec<&Value>, Vec<&Value>) =
+                vs.into_iter().partition(|v| match v.to_ref_uuid() {
+                    Some(vu) => acu_map.get(vu).is_none(),
+                    None => false,
+                });
+            if dangling.is_empty() {
+                continue;
+            }
+
+            let modlist = ModifyList::new_list(
+                dangling
+                    .iter()
+                    .map(|v| Modify::Removed(attr.clone(), v.to_partialvalue()))
+                    .collect(),
+            );
+            qs.internal_modify(
+                au,
+                filter_all!(f_eq("uuid", PartialValue::new_uuidr(c.get_uuid()))),
+                modlist,
+            )?;
+            changes.push(RepairChange::new(
+                c.get_uuid().to_hyphenated_ref().to_string(),
+                attr.clone(),
+                before,
+                kept.iter().map(|v| v.to_proto_string_clone()).collect(),
+            ));
+        }
+    }
+    Ok(changes)
+}
+
 #[cfg(test)]
 mod tests {
     // #[macro_use]
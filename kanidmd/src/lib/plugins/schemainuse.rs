@@ -0,0 +1,182 @@
+// Schema in use plugin. A user defined attribute or class may only be
+// deleted once no entry relies on it any longer, else those entries would
+// no longer be valid against the reloaded schema. Recycled entries count
+// too, as they could be revived.
+use crate::plugins::Plugin;
+
+use crate::audit::AuditScope;
+use crate::entry::{Entry, EntryCommitted, EntryInvalid};
+use crate::event::DeleteEvent;
+use crate::server::{QueryServerTransaction, QueryServerWriteTransaction};
+use crate::value::PartialValue;
+use kanidm_proto::v1::{ConsistencyError, OperationError};
+
+pub struct SchemaInUse {}
+
+lazy_static! {
+    static ref PVCLASS_ATTRIBUTETYPE: PartialValue = PartialValue::new_class("attributetype");
+    static ref PVCLASS_CLASSTYPE: PartialValue = PartialValue::new_class("classtype");
+}
+
+impl Plugin for SchemaInUse {
+    fn id() -> &'static str {
+        "plugin_schema_inuse"
+    }
+
+    fn pre_delete(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        cand: &mut Vec<Entry<EntryInvalid, EntryCommitted>>,
+        _de: &DeleteEvent,
+    ) -> Result<(), OperationError> {
+        let mut res: Vec<Result<(), ConsistencyError>> = Vec::new();
+
+        for e in cand.iter() {
+            if e.attribute_value_pres("class", &PVCLASS_ATTRIBUTETYPE) {
+                if let Some(name) = e.get_ava_single("attributename").and_then(|v| v.to_str()) {
+                    let filt = filter_all!(f_pres(name));
+                    let users = try_audit!(au, qs.internal_search(au, filt));
+                    if users.len() > 0 {
+                        audit_log!(
+                            au,
+                            "attribute {} is in use by {} entries",
+                            name,
+                            users.len()
+                        );
+                        res.push(Err(ConsistencyError::SchemaAttributeInUse(
+                            name.to_string(),
+                        )));
+                    }
+                }
+            }
+            if e.attribute_value_pres("class", &PVCLASS_CLASSTYPE) {
+                if let Some(name) = e.get_ava_single("classname").and_then(|v| v.to_str()) {
+                    let filt = filter_all!(f_eq("class", PartialValue::new_class(name)));
+                    let users = try_audit!(au, qs.internal_search(au, filt));
+                    if users.len() > 0 {
+                        audit_log!(au, "class {} is in use by {} entries", name, users.len());
+                        res.push(Err(ConsistencyError::SchemaClassInUse(name.to_string())));
+                    }
+                }
+            }
+        }
+
+        if res.len() == 0 {
+            Ok(())
+        } else {
+            Err(OperationError::ConsistencyError(res))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::constants::JSON_ADMIN_V1;
+    use crate::entry::{Entry, EntryInvalid, EntryNew};
+    use crate::value::PartialValue;
+    use kanidm_proto::v1::{ConsistencyError, OperationError};
+
+    static JSON_ADMIN_ALLOW_ALL: &'static str = r#"{
+        "valid": null,
+        "state": null,
+        "attrs": {
+            "class": [
+                "object",
+                "access_control_profile",
+                "access_control_search",
+                "access_control_delete"
+            ],
+            "name": ["idm_admins_acp_allow_all_test"],
+            "uuid": ["bb18f746-a409-497d-928c-5455d4aef4f7"],
+            "description": ["Builtin IDM Administrators Access Controls."],
+            "acp_enable": ["true"],
+            "acp_receiver": [
+                "{\"Eq\":[\"uuid\",\"00000000-0000-0000-0000-000000000000\"]}"
+            ],
+            "acp_targetscope": [
+                "{\"Pres\":\"class\"}"
+            ],
+            "acp_search_attr": ["class", "uuid", "attributename"]
+        }
+    }"#;
+
+    static JSON_TESTATTR: &'static str = r#"{
+        "valid": null,
+        "state": null,
+        "attrs": {
+            "class": ["object", "attributetype"],
+            "attributename": ["testattr"],
+            "uuid": ["f5b3c1f2-5a3e-4b6c-9d55-06f7f5e1b1a4"],
+            "description": ["Test Attribute"],
+            "multivalue": ["false"],
+            "unique": ["false"],
+            "syntax": ["UTF8STRING"]
+        }
+    }"#;
+
+    static JSON_TESTPERSON: &'static str = r#"{
+        "valid": null,
+        "state": null,
+        "attrs": {
+            "class": ["object", "person"],
+            "name": ["testperson"],
+            "description": ["testperson"],
+            "displayname": ["testperson"]
+        }
+    }"#;
+
+    // The check applies even to internal deletes, as the schema would be
+    // left inconsistent with the entries either way.
+    #[test]
+    fn test_pre_delete_attr_in_use_deny() {
+        let ep: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_TESTPERSON);
+
+        let preload = vec![ep];
+
+        run_delete_test!(
+            Err(OperationError::ConsistencyError(vec![Err(
+                ConsistencyError::SchemaAttributeInUse("displayname".to_string())
+            )])),
+            preload,
+            filter!(f_eq(
+                "attributename",
+                PartialValue::new_iutf8s("displayname")
+            )),
+            None,
+            |_, _| {}
+        );
+    }
+
+    #[test]
+    fn test_pre_delete_class_in_use_deny() {
+        let ep: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_TESTPERSON);
+
+        let preload = vec![ep];
+
+        run_delete_test!(
+            Err(OperationError::ConsistencyError(vec![Err(
+                ConsistencyError::SchemaClassInUse("person".to_string())
+            )])),
+            preload,
+            filter!(f_eq("classname", PartialValue::new_iutf8s("person"))),
+            None,
+            |_, _| {}
+        );
+    }
+
+    #[test]
+    fn test_pre_delete_unused_allow() {
+        let acp: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_ADMIN_ALLOW_ALL);
+        let ea: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_TESTATTR);
+
+        let preload = vec![acp, ea];
+
+        run_delete_test!(
+            Ok(()),
+            preload,
+            filter!(f_eq("attributename", PartialValue::new_iutf8s("testattr"))),
+            Some(JSON_ADMIN_V1),
+            |_, _| {}
+        );
+    }
+}
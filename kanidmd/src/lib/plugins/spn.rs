@@ -0,0 +1,410 @@
+// Accounts and groups have a security principal name, name@domain_name, so
+// they can be named without ambiguity outside of this deployment. It's
+// generated from the name and the domain info, and can't be given or changed
+// by anyone else. When the domain is renamed every spn is regenerated. If
+// there are too many to do that in the same transaction, it's deferred until
+// after the rename commits, and until then the spns name the old domain.
+use crate::plugins::Plugin;
+
+use crate::audit::AuditScope;
+use crate::constants::UUID_DOMAIN_INFO;
+use crate::delayed::DelayedAction;
+use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntryValid};
+use crate::event::{CreateEvent, ModifyEvent};
+use crate::filter::{Filter, FilterInvalid};
+use crate::modify::{Modify, ModifyList};
+use crate::server::{
+    QueryServerReadTransaction, QueryServerTransaction, QueryServerWriteTransaction,
+};
+use crate::value::{PartialValue, Value};
+use kanidm_proto::v1::{ConsistencyError, OperationError};
+
+pub struct Spn {}
+
+lazy_static! {
+    static ref CLASS_GROUP: PartialValue = PartialValue::new_class("group");
+    static ref CLASS_ACCOUNT: PartialValue = PartialValue::new_class("account");
+    static ref PVUUID_DOMAIN_INFO: PartialValue = PartialValue::new_uuidr(&UUID_DOMAIN_INFO);
+}
+
+fn get_domain_name<T: QueryServerTransaction>(
+    au: &mut AuditScope,
+    qs: &T,
+) -> Result<Option<String>, OperationError> {
+    match qs.internal_search_uuid(au, &UUID_DOMAIN_INFO) {
+        Ok(e) => Ok(e
+            .get_ava_single("domain_name")
+            .and_then(|v| v.to_str())
+            .map(|s| s.to_string())),
+        // The builtin accounts are created before the domain info at first
+        // start. They are given their spn once it exists.
+        Err(OperationError::NoMatchingEntries) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn apply_spn<T: Copy>(
+    au: &mut AuditScope,
+    domain_name: &str,
+    e: &mut Entry<EntryInvalid, T>,
+) -> Result<(), OperationError> {
+    if e.attribute_value_pres("class", &CLASS_GROUP)
+        || e.attribute_value_pres("class", &CLASS_ACCOUNT)
+    {
+        let spn = match e.get_ava_single("name").and_then(|v| v.to_str()) {
+            Some(n) => format!("{}@{}", n, domain_name),
+            None => return Err(OperationError::InvalidEntryState),
+        };
+        audit_log!(au, "generated spn {}", spn);
+        e.set_avas("spn", vec![Value::new_iutf8s(spn.as_str())]);
+    }
+    Ok(())
+}
+
+fn modifies_spn(me: &ModifyEvent) -> bool {
+    me.modlist.iter().any(|m| match m {
+        Modify::Present(a, _) | Modify::Removed(a, _) | Modify::Purged(a) | Modify::Set(a, _) => {
+            a == "spn"
+        }
+        Modify::Assert(_, _) | Modify::AssertMissing(_) => false,
+    })
+}
+
+fn filter_spn_holders() -> Filter<FilterInvalid> {
+    filter!(f_or!([
+        f_eq("class", CLASS_ACCOUNT.clone()),
+        f_eq("class", CLASS_GROUP.clone())
+    ]))
+}
+
+// Purging the spn is enough, as pre_modify generates it again.
+pub fn regenerate_spns(
+    au: &mut AuditScope,
+    qs: &mut QueryServerWriteTransaction,
+) -> Result<(), OperationError> {
+    qs.internal_modify(
+        au,
+        filter_spn_holders(),
+        ModifyList::new_list(vec![Modify::Purged("spn".to_string())]),
+    )
+}
+
+fn get_cand_domain_name<STATE>(cand: &Vec<Entry<EntryValid, STATE>>) -> Option<&str> {
+    cand.iter()
+        .find(|e| e.attribute_value_pres("uuid", &PVUUID_DOMAIN_INFO))
+        .and_then(|e| e.get_ava_single("domain_name"))
+        .and_then(|v| v.to_str())
+}
+
+impl Plugin for Spn {
+    fn id() -> &'static str {
+        "plugin_spn"
+    }
+
+    fn pre_create_transform(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        cand: &mut Vec<Entry<EntryInvalid, EntryNew>>,
+        ce: &CreateEvent,
+    ) -> Result<(), OperationError> {
+        if !ce.event.is_internal() && cand.iter().any(|e| e.attribute_pres("spn")) {
+            audit_log!(au, "Refusing to create an entry with an spn");
+            return Err(OperationError::InvalidAttribute("spn"));
+        }
+        match get_domain_name(au, qs)? {
+            Some(dn) => cand
+                .iter_mut()
+                .try_for_each(|e| apply_spn(au, dn.as_str(), e)),
+            None => Ok(()),
+        }
+    }
+
+    fn pre_modify(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        cand: &mut Vec<Entry<EntryInvalid, EntryCommitted>>,
+        me: &ModifyEvent,
+    ) -> Result<(), OperationError> {
+        // The regeneration after a domain rename is internal, and purges the
+        // spn of every account and group.
+        if !me.event.is_internal() && modifies_spn(me) {
+            audit_log!(au, "Refusing to modify an spn");
+            return Err(OperationError::InvalidAttribute("spn"));
+        }
+        match get_domain_name(au, qs)? {
+            Some(dn) => cand
+                .iter_mut()
+                .try_for_each(|e| apply_spn(au, dn.as_str(), e)),
+            None => Ok(()),
+        }
+    }
+
+    fn post_modify(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+        pre_cand: &Vec<Entry<EntryValid, EntryCommitted>>,
+        cand: &Vec<Entry<EntryValid, EntryCommitted>>,
+        _me: &ModifyEvent,
+    ) -> Result<(), OperationError> {
+        match (get_cand_domain_name(pre_cand), get_cand_domain_name(cand)) {
+            (Some(pre), Some(post)) if pre != post => {
+                audit_log!(au, "domain renamed {} -> {}, regenerating spns", pre, post);
+                let holders = try_audit!(au, qs.internal_search(au, filter_spn_holders()));
+                if qs.should_delay(holders.len()) {
+                    qs.delay_action(au, DelayedAction::Spn);
+                    Ok(())
+                } else {
+                    regenerate_spns(au, qs)
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn regenerate(
+        au: &mut AuditScope,
+        qs: &mut QueryServerWriteTransaction,
+    ) -> Result<(), OperationError> {
+        regenerate_spns(au, qs)
+    }
+
+    // Every account and group must have the spn that would be generated for
+    // it now, so this finds a rename whose regeneration never happened.
+    fn verify(
+        au: &mut AuditScope,
+        qs: &QueryServerReadTransaction,
+    ) -> Vec<Result<(), ConsistencyError>> {
+        let domain_name = match get_domain_name(au, qs) {
+            Ok(Some(dn)) => dn,
+            Ok(None) => return Vec::new(),
+            Err(_) => return vec![Err(ConsistencyError::QueryServerSearchFailure)],
+        };
+        let holders = match qs.internal_search(au, filter_spn_holders()) {
+            Ok(h) => h,
+            Err(_) => return vec![Err(ConsistencyError::QueryServerSearchFailure)],
+        };
+
+        holders
+            .iter()
+            .filter_map(|e| {
+                let expected = e
+                    .get_ava_single("name")
+                    .and_then(|v| v.to_str())
+                    .map(|n| format!("{}@{}", n, domain_name));
+                let found = e.get_ava_single("spn").and_then(|v| v.to_str());
+                match (expected, found) {
+                    (Some(x), Some(f)) if x == f => None,
+                    (x, f) => {
+                        audit_log!(au, "{:?} has spn {:?}, expected {:?}", e.get_uuid(), f, x);
+                        Some(Err(ConsistencyError::SpnInvalid(e.get_id())))
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::AuditScope;
+    use crate::constants::{JSON_ADMIN_V1, UUID_DOMAIN_INFO};
+    use crate::entry::{Entry, EntryInvalid, EntryNew};
+    use crate::server::{QueryServerTransaction, QueryServerWriteTransaction};
+    use crate::value::{PartialValue, Value};
+    use kanidm_proto::v1::OperationError;
+    use uuid::Uuid;
+
+    static UUID_TESTPERSON: &'static str = "cc8e95b4-c24f-4d68-ba54-8bed76f63930";
+
+    fn check_spn(au: &mut AuditScope, qs: &QueryServerWriteTransaction, uuid: &str, spn: &str) {
+        let u = Uuid::parse_str(uuid).expect("Invalid uuid");
+        let e = qs
+            .internal_search_uuid(au, &u)
+            .expect("Failed to get entry");
+        let found = e.get_ava_single("spn").and_then(|v| v.to_str());
+        assert!(found == Some(spn));
+    }
+
+    static JSON_TESTPERSON: &'static str = r#"{
+        "valid": null,
+        "state": null,
+        "attrs": {
+            "class": ["object", "account"],
+            "name": ["testperson"],
+            "displayname": ["Test Person"],
+            "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"]
+        }
+    }"#;
+
+    #[test]
+    fn test_spn_create_generate() {
+        let e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_TESTPERSON);
+        let create = vec![e];
+        let preload = Vec::new();
+
+        run_create_test!(Ok(()), preload, create, None, |au, qs| check_spn(
+            au,
+            qs,
+            UUID_TESTPERSON,
+            "testperson@localhost"
+        ));
+    }
+
+    // A given spn is replaced, even by an internal create.
+    #[test]
+    fn test_spn_create_replace() {
+        let mut e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_TESTPERSON);
+        e.add_ava("spn", &Value::new_iutf8s("admin@localhost"));
+        let create = vec![e];
+        let preload = Vec::new();
+
+        run_create_test!(Ok(()), preload, create, None, |au, qs| check_spn(
+            au,
+            qs,
+            UUID_TESTPERSON,
+            "testperson@localhost"
+        ));
+    }
+
+    #[test]
+    fn test_spn_modify_rename() {
+        let e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_TESTPERSON);
+        let preload = vec![e];
+
+        run_modify_test!(
+            Ok(()),
+            preload,
+            filter!(f_eq("name", PartialValue::new_iutf8s("testperson"))),
+            modlist!([
+                m_purge("name"),
+                m_pres("name", &Value::new_iutf8s("renamed"))
+            ]),
+            None,
+            |au, qs| check_spn(au, qs, UUID_TESTPERSON, "renamed@localhost")
+        );
+    }
+
+    // Renaming the domain regenerates every spn in the same transaction, and
+    // the old spns no longer match.
+    #[test]
+    fn test_spn_domain_rename() {
+        let e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_TESTPERSON);
+        let preload = vec![e];
+
+        run_modify_test!(
+            Ok(()),
+            preload,
+            filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_DOMAIN_INFO))),
+            modlist!([
+                m_purge("domain_name"),
+                m_pres("domain_name", &Value::new_iutf8s("example.com"))
+            ]),
+            None,
+            |au: &mut AuditScope, qs: &QueryServerWriteTransaction| {
+                check_spn(au, qs, UUID_TESTPERSON, "testperson@example.com");
+                let old = qs
+                    .internal_search(
+                        au,
+                        filter!(f_eq(
+                            "spn",
+                            PartialValue::new_iutf8s("testperson@localhost")
+                        )),
+                    )
+                    .expect("Failed to search");
+                assert!(old.len() == 0);
+                let new = qs
+                    .internal_search(
+                        au,
+                        filter!(f_eq("spn", PartialValue::new_iutf8s("admin@example.com"))),
+                    )
+                    .expect("Failed to search");
+                assert!(new.len() == 1);
+            }
+        );
+    }
+
+    // Even granted by access controls, an spn can't be given or changed.
+    static JSON_ADMIN_ALLOW_ALL: &'static str = r#"{
+        "valid": null,
+        "state": null,
+        "attrs": {
+            "class": [
+                "object",
+                "access_control_profile",
+                "access_control_modify",
+                "access_control_create",
+                "access_control_search"
+            ],
+            "name": ["idm_admins_acp_allow_all_test"],
+            "uuid": ["bb18f746-a409-497d-928c-5455d4aef4f7"],
+            "acp_enable": ["true"],
+            "acp_receiver": [
+                "{\"Eq\":[\"uuid\",\"00000000-0000-0000-0000-000000000000\"]}"
+            ],
+            "acp_targetscope": [
+                "{\"Pres\":\"class\"}"
+            ],
+            "acp_search_attr": ["name", "class", "uuid", "spn"],
+            "acp_modify_removedattr": ["name", "spn"],
+            "acp_modify_presentattr": ["name", "spn"],
+            "acp_create_class": ["object", "account"],
+            "acp_create_attr": ["name", "class", "displayname", "uuid", "spn"]
+        }
+    }"#;
+
+    #[test]
+    fn test_spn_create_deny() {
+        let acp: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_ADMIN_ALLOW_ALL);
+        let mut e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_TESTPERSON);
+        e.add_ava("spn", &Value::new_iutf8s("testperson@localhost"));
+        let create = vec![e];
+        let preload = vec![acp];
+
+        run_create_test!(
+            Err(OperationError::InvalidAttribute("spn")),
+            preload,
+            create,
+            Some(JSON_ADMIN_V1),
+            |_, _| {}
+        );
+    }
+
+    #[test]
+    fn test_spn_modify_deny() {
+        let acp: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_ADMIN_ALLOW_ALL);
+        let e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_TESTPERSON);
+        let preload = vec![acp, e];
+
+        run_modify_test!(
+            Err(OperationError::InvalidAttribute("spn")),
+            preload,
+            filter!(f_eq("name", PartialValue::new_iutf8s("testperson"))),
+            modlist!([
+                m_purge("spn"),
+                m_pres("spn", &Value::new_iutf8s("admin@localhost"))
+            ]),
+            Some(JSON_ADMIN_V1),
+            |_, _| {}
+        );
+    }
+
+    // Renaming is allowed, and the spn follows.
+    #[test]
+    fn test_spn_modify_rename_impersonate() {
+        let acp: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_ADMIN_ALLOW_ALL);
+        let e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(JSON_TESTPERSON);
+        let preload = vec![acp, e];
+
+        run_modify_test!(
+            Ok(()),
+            preload,
+            filter!(f_eq("name", PartialValue::new_iutf8s("testperson"))),
+            modlist!([
+                m_purge("name"),
+                m_pres("name", &Value::new_iutf8s("renamed"))
+            ]),
+            Some(JSON_ADMIN_V1),
+            |au, qs| check_spn(au, qs, UUID_TESTPERSON, "renamed@localhost")
+        );
+    }
+}
@@ -0,0 +1,444 @@
+// Throttling of what anyone may ask of the server without an account, so that
+// no source can guess passwords or read the directory faster than the limits
+// allow. This is before, and apart from, the lockout of accounts, as it limits
+// the requests themselves rather than their failures.
+//
+// Each source has a token bucket for each kind of request, which holds as
+// many tokens as the limit, and is refilled by the limit over each window.
+// There is a bucket for each kind shared by all sources too, so that many
+// sources together are held to a limit as well.
+use lru::LruCache;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RateLimitKind {
+    // The beginning of an authentication, which is where guessing begins.
+    Auth,
+    // Searches by the anonymous account, or by no account at all.
+    AnonymousSearch,
+}
+
+impl RateLimitKind {
+    pub const ALL: [RateLimitKind; 2] = [RateLimitKind::Auth, RateLimitKind::AnonymousSearch];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RateLimitKind::Auth => "auth",
+            RateLimitKind::AnonymousSearch => "anonymous_search",
+        }
+    }
+}
+
+// An address, or a network of them given as address/prefix, such as
+// 10.0.0.0/8.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AddrRange {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+pub fn parse_addr_range(s: &str) -> Option<AddrRange> {
+    let mut parts = s.trim().splitn(2, '/');
+    let addr = parts.next()?.parse::<IpAddr>().ok()?;
+    let max = match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    };
+    let prefix = match parts.next() {
+        Some(p) => p.parse::<u8>().ok().filter(|p| *p <= max)?,
+        None => max,
+    };
+    Some(AddrRange {
+        addr: addr,
+        prefix: prefix,
+    })
+}
+
+impl AddrRange {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(a), IpAddr::V4(b)) => {
+                let mask = u32::max_value()
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(a) & mask == u32::from(*b) & mask
+            }
+            (IpAddr::V6(a), IpAddr::V6(b)) => {
+                let mask = u128::max_value()
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(a) & mask == u128::from(*b) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// The requests allowed in each window, from each source and from all of them
+// together, with 0 for no limit.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RateLimits {
+    pub auth: u32,
+    pub auth_global: u32,
+    pub anonymous_search: u32,
+    pub anonymous_search_global: u32,
+    // In seconds.
+    pub window: u64,
+    // How many buckets of sources are kept. When there are more, those used
+    // least recently are forgotten, and begin again full if they return.
+    pub max_sources: usize,
+    // Proxies whose X-Forwarded-For is believed. Otherwise the source is the
+    // address that connected, whatever the header says.
+    pub trusted_proxies: Vec<AddrRange>,
+    // Sources that are never limited, such as health checks.
+    pub exempt: Vec<AddrRange>,
+}
+
+impl RateLimits {
+    pub fn new() -> Self {
+        RateLimits {
+            auth: 60,
+            auth_global: 3000,
+            anonymous_search: 300,
+            anonymous_search_global: 6000,
+            window: 60,
+            max_sources: 65536,
+            trusted_proxies: Vec::new(),
+            exempt: Vec::new(),
+        }
+    }
+
+    // The limit of a source, and of all sources.
+    fn limits(&self, kind: RateLimitKind) -> (u32, u32) {
+        match kind {
+            RateLimitKind::Auth => (self.auth, self.auth_global),
+            RateLimitKind::AnonymousSearch => (self.anonymous_search, self.anonymous_search_global),
+        }
+    }
+}
+
+// A request over a limit, and how many seconds until it may be made again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Throttled {
+    // Whether it's the limit of all sources that was reached.
+    pub global: bool,
+    pub retry_after: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Duration,
+}
+
+impl Bucket {
+    fn full(limit: u32, ct: Duration) -> Self {
+        Bucket {
+            tokens: f64::from(limit),
+            updated: ct,
+        }
+    }
+
+    // Add what has refilled since the bucket was last updated. A clock that
+    // went backwards refills nothing.
+    fn refill(&mut self, limit: u32, window: u64, ct: Duration) {
+        if let Some(elapsed) = ct.checked_sub(self.updated) {
+            let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_micros()) / 1e6;
+            self.tokens =
+                (self.tokens + elapsed * f64::from(limit) / window as f64).min(f64::from(limit));
+            self.updated = ct;
+        }
+    }
+
+    // Seconds until there is a token to take, 0 if there is one now.
+    fn wait(&self, limit: u32, window: u64) -> u64 {
+        if self.tokens >= 1.0 {
+            0
+        } else {
+            let secs = (1.0 - self.tokens) * window as f64 / f64::from(limit);
+            std::cmp::max(secs.ceil() as u64, 1)
+        }
+    }
+}
+
+struct Buckets {
+    global: Vec<Bucket>,
+    sources: LruCache<(RateLimitKind, IpAddr), Bucket>,
+}
+
+pub struct RateLimiter {
+    limits: RateLimits,
+    buckets: Mutex<Buckets>,
+}
+
+// A hop of X-Forwarded-For, which some proxies give with the port.
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim();
+    hop.parse::<IpAddr>()
+        .or_else(|_| hop.parse::<SocketAddr>().map(|a| a.ip()))
+        .ok()
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits, ct: Duration) -> Self {
+        let global = RateLimitKind::ALL
+            .iter()
+            .map(|k| Bucket::full(limits.limits(*k).1, ct))
+            .collect();
+        let sources = LruCache::new(std::cmp::max(limits.max_sources, 1));
+        RateLimiter {
+            limits: limits,
+            buckets: Mutex::new(Buckets {
+                global: global,
+                sources: sources,
+            }),
+        }
+    }
+
+    // Who a request is from. While the peer is a trusted proxy, the hop it
+    // says it forwarded for is taken, from the last of X-Forwarded-For, as
+    // each proxy adds to the end. The first hop that isn't a trusted proxy
+    // is the client.
+    pub fn client_address(&self, peer: IpAddr, forwarded_for: &[&str]) -> IpAddr {
+        let mut hops = forwarded_for
+            .iter()
+            .flat_map(|h| h.split(','))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev();
+        let mut client = peer;
+        while self
+            .limits
+            .trusted_proxies
+            .iter()
+            .any(|r| r.contains(&client))
+        {
+            match hops.next().and_then(parse_hop) {
+                Some(ip) => client = ip,
+                None => break,
+            }
+        }
+        client
+    }
+
+    // Take a token for a request from source, from its bucket and the
+    // bucket of all sources, or neither if either is empty. A request with
+    // no source is only held to the limit of all sources.
+    pub fn check(
+        &self,
+        kind: RateLimitKind,
+        source: Option<IpAddr>,
+        ct: Duration,
+    ) -> Result<(), Throttled> {
+        if let Some(ip) = &source {
+            if self.limits.exempt.iter().any(|r| r.contains(ip)) {
+                return Ok(());
+            }
+        }
+        let (limit, global_limit) = self.limits.limits(kind);
+        let window = std::cmp::max(self.limits.window, 1);
+        let mut buckets = self.buckets.lock().expect("rate limit lock poisoned");
+
+        let mut bucket = match source {
+            Some(ip) if limit > 0 => {
+                let mut b = buckets
+                    .sources
+                    .get(&(kind, ip))
+                    .cloned()
+                    .unwrap_or_else(|| Bucket::full(limit, ct));
+                b.refill(limit, window, ct);
+                let wait = b.wait(limit, window);
+                if wait > 0 {
+                    buckets.sources.put((kind, ip), b);
+                    return Err(Throttled {
+                        global: false,
+                        retry_after: wait,
+                    });
+                }
+                Some(b)
+            }
+            _ => None,
+        };
+
+        if global_limit > 0 {
+            let global = &mut buckets.global[kind as usize];
+            global.refill(global_limit, window, ct);
+            let wait = global.wait(global_limit, window);
+            if wait > 0 {
+                return Err(Throttled {
+                    global: true,
+                    retry_after: wait,
+                });
+            }
+            global.tokens -= 1.0;
+        }
+        if let (Some(ip), Some(b)) = (source, bucket.as_mut()) {
+            b.tokens -= 1.0;
+            buckets.sources.put((kind, ip), *b);
+        }
+        Ok(())
+    }
+
+    // The number of sources that have buckets.
+    pub fn sources(&self) -> usize {
+        self.buckets
+            .lock()
+            .expect("rate limit lock poisoned")
+            .sources
+            .len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_addr_range, RateLimitKind, RateLimiter, RateLimits, Throttled};
+    use std::net::IpAddr;
+    use std::time::Duration;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn limiter(auth: u32, auth_global: u32) -> RateLimiter {
+        let mut limits = RateLimits::new();
+        limits.auth = auth;
+        limits.auth_global = auth_global;
+        limits.window = 10;
+        limits.max_sources = 4;
+        limits.trusted_proxies = vec![parse_addr_range("10.0.0.0/8").unwrap()];
+        limits.exempt = vec![parse_addr_range("192.168.1.1").unwrap()];
+        RateLimiter::new(limits, Duration::from_secs(0))
+    }
+
+    #[test]
+    fn test_parse_addr_range() {
+        let r = parse_addr_range("10.0.0.0/8").expect("Failed to parse");
+        assert!(r.contains(&ip("10.200.1.1")));
+        assert!(!r.contains(&ip("11.0.0.1")));
+        assert!(!r.contains(&ip("::ffff:10.0.0.1")));
+        let r = parse_addr_range("127.0.0.1").expect("Failed to parse");
+        assert!(r.contains(&ip("127.0.0.1")));
+        assert!(!r.contains(&ip("127.0.0.2")));
+        let r = parse_addr_range("fd00::/16").expect("Failed to parse");
+        assert!(r.contains(&ip("fd00:1::1")));
+        assert!(!r.contains(&ip("fe80::1")));
+        assert!(parse_addr_range("0.0.0.0/0")
+            .expect("Failed to parse")
+            .contains(&ip("8.8.8.8")));
+        assert!(parse_addr_range("10.0.0.0/33").is_none());
+        assert!(parse_addr_range("10.0.0/8").is_none());
+        assert!(parse_addr_range("localhost").is_none());
+    }
+
+    #[test]
+    fn test_ratelimit_source() {
+        let rl = limiter(3, 0);
+        let src = Some(ip("203.0.113.1"));
+        let t0 = Duration::from_secs(100);
+        for _ in 0..3 {
+            assert!(rl.check(RateLimitKind::Auth, src, t0).is_ok());
+        }
+        // A token is refilled every 10/3 seconds.
+        assert!(
+            rl.check(RateLimitKind::Auth, src, t0)
+                == Err(Throttled {
+                    global: false,
+                    retry_after: 4,
+                })
+        );
+        // Other sources, and other kinds, have buckets of their own.
+        assert!(rl
+            .check(RateLimitKind::Auth, Some(ip("203.0.113.2")), t0)
+            .is_ok());
+        assert!(rl.check(RateLimitKind::AnonymousSearch, src, t0).is_ok());
+        // Exempt sources, and those without a source, aren't limited.
+        for _ in 0..10 {
+            assert!(rl
+                .check(RateLimitKind::Auth, Some(ip("192.168.1.1")), t0)
+                .is_ok());
+            assert!(rl.check(RateLimitKind::Auth, None, t0).is_ok());
+        }
+
+        assert!(rl
+            .check(RateLimitKind::Auth, src, t0 + Duration::from_secs(3))
+            .is_err());
+        assert!(rl
+            .check(RateLimitKind::Auth, src, t0 + Duration::from_secs(4))
+            .is_ok());
+        // After a whole window the bucket is full again, but no fuller.
+        let t1 = t0 + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(rl.check(RateLimitKind::Auth, src, t1).is_ok());
+        }
+        assert!(rl.check(RateLimitKind::Auth, src, t1).is_err());
+    }
+
+    #[test]
+    fn test_ratelimit_global() {
+        let rl = limiter(2, 3);
+        let t0 = Duration::from_secs(100);
+        assert!(rl
+            .check(RateLimitKind::Auth, Some(ip("203.0.113.1")), t0)
+            .is_ok());
+        assert!(rl
+            .check(RateLimitKind::Auth, Some(ip("203.0.113.2")), t0)
+            .is_ok());
+        assert!(rl.check(RateLimitKind::Auth, None, t0).is_ok());
+        match rl.check(RateLimitKind::Auth, Some(ip("203.0.113.3")), t0) {
+            Err(Throttled { global: true, .. }) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        // A source refused by the global limit keeps its own tokens.
+        let t1 = t0 + Duration::from_secs(4);
+        assert!(rl
+            .check(RateLimitKind::Auth, Some(ip("203.0.113.3")), t1)
+            .is_ok());
+        let t2 = t0 + Duration::from_secs(20);
+        assert!(rl
+            .check(RateLimitKind::Auth, Some(ip("203.0.113.3")), t2)
+            .is_ok());
+        assert!(rl
+            .check(RateLimitKind::Auth, Some(ip("203.0.113.3")), t2)
+            .is_ok());
+        match rl.check(RateLimitKind::Auth, Some(ip("203.0.113.3")), t2) {
+            Err(Throttled { global: false, .. }) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    // An attacker with many addresses only ever holds max_sources buckets,
+    // and each new address begins with a full bucket.
+    #[test]
+    fn test_ratelimit_bounded() {
+        let rl = limiter(1, 0);
+        let t0 = Duration::from_secs(100);
+        for i in 0..1000 {
+            let src = Some(ip(format!("198.51.{}.{}", i / 256, i % 256).as_str()));
+            assert!(rl.check(RateLimitKind::Auth, src, t0).is_ok());
+            assert!(rl.check(RateLimitKind::Auth, src, t0).is_err());
+        }
+        assert!(rl.sources() == 4);
+    }
+
+    #[test]
+    fn test_ratelimit_client_address() {
+        let rl = limiter(1, 0);
+        // Only a trusted proxy is believed.
+        assert!(rl.client_address(ip("203.0.113.1"), &["198.51.100.1"]) == ip("203.0.113.1"));
+        assert!(rl.client_address(ip("10.0.0.1"), &["198.51.100.1"]) == ip("198.51.100.1"));
+        assert!(rl.client_address(ip("10.0.0.1"), &[]) == ip("10.0.0.1"));
+        // What the client sent itself is ignored, as is anything past the
+        // first hop that isn't a trusted proxy.
+        assert!(
+            rl.client_address(ip("10.0.0.1"), &["127.0.0.1, 198.51.100.1, 10.0.0.2"])
+                == ip("198.51.100.1")
+        );
+        assert!(
+            rl.client_address(ip("10.0.0.1"), &["127.0.0.1", "198.51.100.1:4433"])
+                == ip("198.51.100.1")
+        );
+        assert!(rl.client_address(ip("10.0.0.1"), &["unknown"]) == ip("10.0.0.1"));
+    }
+}
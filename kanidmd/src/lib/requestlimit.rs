@@ -0,0 +1,191 @@
+// Bounds on what a client may send in one request, so that no request can make
+// the server buffer or decode more than it means to. A body over the limit of
+// its kind of request is refused as it's read, before any of it is decoded,
+// and what a create or modify asks for is checked once it is decoded, before
+// it's given to the query server. Internal operations are not subject to these.
+use crate::metrics::Operation;
+use kanidm_proto::v1::{
+    CreateRequest, Entry as ProtoEntry, Modify as ProtoModify, ModifyBatchRequest,
+    ModifyList as ProtoModifyList, ModifyRequest, OperationError,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RequestLimits {
+    // The most bytes the body of an authentication, and of a search, may be.
+    pub maximum_auth: usize,
+    pub maximum_search: usize,
+    // The most bytes the body of any other request may be, which is mostly
+    // creates and modifies.
+    pub maximum: usize,
+    pub max_create_entries: usize,
+    // Of a modify, or of all the changes of a batch together.
+    pub max_modifications: usize,
+    // The most bytes any value given to a create or modify may be.
+    pub max_value_length: usize,
+}
+
+impl RequestLimits {
+    pub fn new() -> Self {
+        RequestLimits {
+            maximum_auth: 16384,
+            maximum_search: 65536,
+            maximum: 262144,
+            max_create_entries: 1000,
+            max_modifications: 10000,
+            max_value_length: 65536,
+        }
+    }
+
+    // The limit of the body of a request to this path.
+    pub fn body_limit(&self, path: &str) -> usize {
+        match Operation::from_path(path) {
+            Operation::Auth => self.maximum_auth,
+            Operation::Search => self.maximum_search,
+            _ => self.maximum,
+        }
+    }
+
+    pub fn check_create(&self, cr: &CreateRequest) -> Result<(), OperationError> {
+        if cr.entries.len() > self.max_create_entries {
+            return Err(OperationError::TooManyEntries(
+                self.max_create_entries as u64,
+            ));
+        }
+        cr.entries.iter().try_for_each(|e| self.check_entry(e))
+    }
+
+    pub fn check_modify(&self, mr: &ModifyRequest) -> Result<(), OperationError> {
+        self.check_modifications(mr.modlist.mods.len())?;
+        self.check_modlist(&mr.modlist)
+    }
+
+    pub fn check_modify_batch(&self, mbr: &ModifyBatchRequest) -> Result<(), OperationError> {
+        self.check_modifications(mbr.changes.iter().map(|(_, ml)| ml.mods.len()).sum())?;
+        mbr.changes
+            .iter()
+            .try_for_each(|(_, ml)| self.check_modlist(ml))
+    }
+
+    fn check_modifications(&self, count: usize) -> Result<(), OperationError> {
+        if count > self.max_modifications {
+            Err(OperationError::TooManyModifications(
+                self.max_modifications as u64,
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn check_entry(&self, e: &ProtoEntry) -> Result<(), OperationError> {
+        e.attrs
+            .iter()
+            .try_for_each(|(a, vs)| vs.iter().try_for_each(|v| self.check_value(a, v)))
+    }
+
+    fn check_modlist(&self, ml: &ProtoModifyList) -> Result<(), OperationError> {
+        ml.mods.iter().try_for_each(|m| match m {
+            ProtoModify::Present(a, v) | ProtoModify::Removed(a, v) | ProtoModify::Assert(a, v) => {
+                self.check_value(a, v)
+            }
+            ProtoModify::Set(a, vs) => vs.iter().try_for_each(|v| self.check_value(a, v)),
+            ProtoModify::Purged(_) | ProtoModify::AssertMissing(_) => Ok(()),
+        })
+    }
+
+    fn check_value(&self, attr: &str, v: &str) -> Result<(), OperationError> {
+        if v.len() > self.max_value_length {
+            Err(OperationError::ValueTooLong(
+                attr.to_string(),
+                self.max_value_length as u64,
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RequestLimits;
+    use kanidm_proto::v1::{
+        CreateRequest, Entry as ProtoEntry, Filter as ProtoFilter, Modify as ProtoModify,
+        ModifyBatchRequest, ModifyList as ProtoModifyList, ModifyRequest, OperationError,
+    };
+    use std::collections::BTreeMap;
+
+    fn entry(desc: &str) -> ProtoEntry {
+        let mut attrs = BTreeMap::new();
+        attrs.insert("class".to_string(), vec!["object".to_string()]);
+        attrs.insert("description".to_string(), vec![desc.to_string()]);
+        ProtoEntry { attrs: attrs }
+    }
+
+    #[test]
+    fn test_request_limits_body() {
+        let limits = RequestLimits::new();
+        assert!(limits.body_limit("/v1/auth") == limits.maximum_auth);
+        assert!(limits.body_limit("/v1/search/_stream") == limits.maximum_search);
+        assert!(limits.body_limit("/v1/create") == limits.maximum);
+        assert!(limits.body_limit("/v1/self") == limits.maximum);
+    }
+
+    #[test]
+    fn test_request_limits_create() {
+        let mut limits = RequestLimits::new();
+        limits.max_create_entries = 2;
+        limits.max_value_length = 8;
+
+        let cr = CreateRequest::new(vec![entry("a"), entry("b")]);
+        assert!(limits.check_create(&cr).is_ok());
+
+        let cr = CreateRequest::new(vec![entry("a"), entry("b"), entry("c")]);
+        assert!(limits.check_create(&cr) == Err(OperationError::TooManyEntries(2)));
+
+        // The value of the limit itself is allowed.
+        let cr = CreateRequest::new(vec![entry("12345678")]);
+        assert!(limits.check_create(&cr).is_ok());
+        let cr = CreateRequest::new(vec![entry("123456789")]);
+        assert!(
+            limits.check_create(&cr)
+                == Err(OperationError::ValueTooLong("description".to_string(), 8))
+        );
+    }
+
+    #[test]
+    fn test_request_limits_modify() {
+        let mut limits = RequestLimits::new();
+        limits.max_modifications = 2;
+        limits.max_value_length = 4;
+
+        let filter = ProtoFilter::Eq("name".to_string(), "testperson".to_string());
+        let ml = ProtoModifyList::new_list;
+        let present = |v: &str| ProtoModify::Present("description".to_string(), v.to_string());
+
+        let mr = ModifyRequest::new(filter.clone(), ml(vec![present("a"), present("b")]));
+        assert!(limits.check_modify(&mr).is_ok());
+        let mr = ModifyRequest::new(
+            filter.clone(),
+            ml(vec![present("a"), present("b"), present("c")]),
+        );
+        assert!(limits.check_modify(&mr) == Err(OperationError::TooManyModifications(2)));
+
+        let mr = ModifyRequest::new(
+            filter.clone(),
+            ml(vec![ProtoModify::Set(
+                "description".to_string(),
+                vec!["a".to_string(), "abcde".to_string()],
+            )]),
+        );
+        assert!(
+            limits.check_modify(&mr)
+                == Err(OperationError::ValueTooLong("description".to_string(), 4))
+        );
+
+        // A batch is limited by all of its changes together.
+        let mbr = ModifyBatchRequest::new(vec![
+            (filter.clone(), ml(vec![present("a")])),
+            (filter.clone(), ml(vec![present("b"), present("c")])),
+        ]);
+        assert!(limits.check_modify_batch(&mbr) == Err(OperationError::TooManyModifications(2)));
+    }
+}
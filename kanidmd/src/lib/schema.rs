@@ -33,6 +33,8 @@ pub struct SchemaAttribute {
     pub description: String,
     pub multivalue: bool,
     pub unique: bool,
+    // The values are never shown in the audit log of changes.
+    pub secret: bool,
     pub index: Vec<IndexType>,
     pub syntax: SyntaxType,
 }
@@ -81,6 +83,8 @@ impl SchemaAttribute {
                 .get_ava_single_bool("unique")
                 .ok_or(OperationError::InvalidSchemaState("missing unique"))
         );
+        // secret is optional, as most attributes aren't.
+        let secret = value.get_ava_single_bool("secret").unwrap_or(false);
         // index vec
         // even if empty, it SHOULD be present ... (is that value to put an empty set?)
         // The get_ava_opt_index handles the optional case for us :)
@@ -109,6 +113,7 @@ impl SchemaAttribute {
             description: description,
             multivalue: multivalue,
             unique: unique,
+            secret: secret,
             index: index,
             syntax: syntax,
         })
@@ -188,6 +193,54 @@ impl SchemaAttribute {
         }
     }
 
+    fn validate_uint32(&self, v: &Value) -> Result<(), SchemaError> {
+        if v.is_uint32() {
+            Ok(())
+        } else {
+            Err(SchemaError::InvalidAttributeSyntax)
+        }
+    }
+
+    fn validate_binary(&self, v: &Value) -> Result<(), SchemaError> {
+        if v.is_binary() {
+            Ok(())
+        } else {
+            Err(SchemaError::InvalidAttributeSyntax)
+        }
+    }
+
+    fn validate_datetime(&self, v: &Value) -> Result<(), SchemaError> {
+        if v.is_datetime() {
+            Ok(())
+        } else {
+            Err(SchemaError::InvalidAttributeSyntax)
+        }
+    }
+
+    fn validate_apitoken(&self, v: &Value) -> Result<(), SchemaError> {
+        if v.is_apitoken() {
+            Ok(())
+        } else {
+            Err(SchemaError::InvalidAttributeSyntax)
+        }
+    }
+
+    fn validate_radius_string(&self, v: &Value) -> Result<(), SchemaError> {
+        if v.is_radius_string() {
+            Ok(())
+        } else {
+            Err(SchemaError::InvalidAttributeSyntax)
+        }
+    }
+
+    fn validate_sshkey(&self, v: &Value) -> Result<(), SchemaError> {
+        if v.is_sshkey() {
+            Ok(())
+        } else {
+            Err(SchemaError::InvalidAttributeSyntax)
+        }
+    }
+
     fn validate_utf8string_insensitive(&self, v: &Value) -> Result<(), SchemaError> {
         if v.is_insensitive_utf8() {
             Ok(())
@@ -204,6 +257,29 @@ impl SchemaAttribute {
         }
     }
 
+    // Fold the case of a value from the protocol, if this attributes syntax is
+    // case insensitive. This must be applied before the value is parsed.
+    pub fn normalise_value(&self, v: &str) -> String {
+        if self.syntax.is_case_insensitive() {
+            v.to_lowercase()
+        } else {
+            v.to_string()
+        }
+    }
+
+    // Internal filters may be constructed with a string type that doesn't match
+    // the attributes case sensitivity, so we normalise these to the type that
+    // will be present in entries.
+    pub fn normalise_partialvalue(&self, v: &PartialValue) -> PartialValue {
+        match (&self.syntax, v) {
+            (SyntaxType::UTF8STRING_INSENSITIVE, PartialValue::Utf8(s))
+            | (SyntaxType::UTF8STRING_INSENSITIVE, PartialValue::Iutf8(s)) => {
+                PartialValue::new_iutf8s(s.as_str())
+            }
+            (_, v) => v.clone(),
+        }
+    }
+
     // TODO: There may be a difference between a value and a filter value on complex
     // types - IE a complex type may have multiple parts that are secret, but a filter
     // on that may only use a single tagged attribute for example.
@@ -218,6 +294,12 @@ impl SchemaAttribute {
             SyntaxType::UTF8STRING => v.is_utf8(),
             SyntaxType::JSON_FILTER => v.is_json_filter(),
             SyntaxType::CREDENTIAL => v.is_credential(),
+            SyntaxType::UINT32 => v.is_uint32(),
+            SyntaxType::BINARY => v.is_binary(),
+            SyntaxType::DATETIME => v.is_datetime(),
+            SyntaxType::API_TOKEN => v.is_apitoken(),
+            SyntaxType::RADIUS_UTF8STRING => v.is_radius_string(),
+            SyntaxType::SSHKEY => v.is_sshkey(),
         };
         if r {
             Ok(())
@@ -312,6 +394,48 @@ impl SchemaAttribute {
                     acc
                 }
             }),
+            SyntaxType::UINT32 => ava.iter().fold(Ok(()), |acc, v| {
+                if acc.is_ok() {
+                    self.validate_uint32(v)
+                } else {
+                    acc
+                }
+            }),
+            SyntaxType::BINARY => ava.iter().fold(Ok(()), |acc, v| {
+                if acc.is_ok() {
+                    self.validate_binary(v)
+                } else {
+                    acc
+                }
+            }),
+            SyntaxType::DATETIME => ava.iter().fold(Ok(()), |acc, v| {
+                if acc.is_ok() {
+                    self.validate_datetime(v)
+                } else {
+                    acc
+                }
+            }),
+            SyntaxType::API_TOKEN => ava.iter().fold(Ok(()), |acc, v| {
+                if acc.is_ok() {
+                    self.validate_apitoken(v)
+                } else {
+                    acc
+                }
+            }),
+            SyntaxType::RADIUS_UTF8STRING => ava.iter().fold(Ok(()), |acc, v| {
+                if acc.is_ok() {
+                    self.validate_radius_string(v)
+                } else {
+                    acc
+                }
+            }),
+            SyntaxType::SSHKEY => ava.iter().fold(Ok(()), |acc, v| {
+                if acc.is_ok() {
+                    self.validate_sshkey(v)
+                } else {
+                    acc
+                }
+            }),
         }
     }
 }
@@ -412,6 +536,14 @@ pub trait SchemaTransaction {
         self.get_inner().is_multivalue(attr)
     }
 
+    // An attribute that isn't in the schema has nothing to redact.
+    fn is_secret(&self, attr: &str) -> bool {
+        self.get_attributes()
+            .get(attr)
+            .map(|sa| sa.secret)
+            .unwrap_or(false)
+    }
+
     fn normalise_attr_name(&self, an: &str) -> String {
         // Will duplicate.
         an.to_lowercase()
@@ -425,6 +557,14 @@ pub trait SchemaTransaction {
             .collect()
     }
 
+    // Every index that the attribute definitions ask for.
+    fn get_indexes(&self) -> BTreeSet<(String, IndexType)> {
+        self.get_attributes()
+            .iter()
+            .flat_map(|(k, v)| v.index.iter().map(move |i| (k.clone(), i.clone())))
+            .collect()
+    }
+
     // Probably need something like get_classes or similar
     // so that externals can call and use this data.
 
@@ -449,7 +589,7 @@ pub trait SchemaTransaction {
 
 impl SchemaInner {
     pub fn new(audit: &mut AuditScope) -> Result<Self, OperationError> {
-        let mut au = AuditScope::new("schema_new");
+        let mut au = audit.child("schema_new");
         let r = audit_segment!(au, || {
             //
             let mut s = SchemaInner {
@@ -467,6 +607,7 @@ impl SchemaInner {
                     description: String::from("The set of classes defining an object"),
                     multivalue: true,
                     unique: false,
+                    secret: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
@@ -482,6 +623,7 @@ impl SchemaInner {
                     // Uniqueness is handled by base.rs, not attrunique here due to
                     // needing to check recycled objects too.
                     unique: false,
+                    secret: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UUID,
                 },
@@ -495,6 +637,7 @@ impl SchemaInner {
                     description: String::from("The shortform name of an object"),
                     multivalue: false,
                     unique: true,
+                    secret: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
@@ -508,6 +651,7 @@ impl SchemaInner {
                     description: String::from("The name of a schema attribute"),
                     multivalue: false,
                     unique: true,
+                    secret: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
@@ -521,6 +665,7 @@ impl SchemaInner {
                     description: String::from("The name of a schema class"),
                     multivalue: false,
                     unique: true,
+                    secret: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
@@ -534,6 +679,7 @@ impl SchemaInner {
                     description: String::from("A description of an attribute, object or class"),
                     multivalue: true,
                     unique: false,
+                    secret: false,
                     index: vec![],
                     syntax: SyntaxType::UTF8STRING,
                 },
@@ -544,6 +690,7 @@ impl SchemaInner {
                 description: String::from("If true, this attribute is able to store multiple values rather than just a single value."),
                 multivalue: false,
                 unique: false,
+                secret: false,
                 index: vec![],
                 syntax: SyntaxType::BOOLEAN,
             });
@@ -553,9 +700,26 @@ impl SchemaInner {
                 description: String::from("If true, this attribute must store a unique value through out the database."),
                 multivalue: false,
                 unique: false,
+                secret: false,
                 index: vec![],
                 syntax: SyntaxType::BOOLEAN,
             });
+            s.attributes.insert(
+                String::from("secret"),
+                SchemaAttribute {
+                    name: String::from("secret"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_SECRET)
+                        .expect("unable to parse static uuid"),
+                    description: String::from(
+                        "If true, the values of this attribute are redacted in the audit log.",
+                    ),
+                    multivalue: false,
+                    unique: false,
+                    secret: false,
+                    index: vec![],
+                    syntax: SyntaxType::BOOLEAN,
+                },
+            );
             s.attributes.insert(
                 String::from("index"),
                 SchemaAttribute {
@@ -567,6 +731,7 @@ impl SchemaInner {
                     ),
                     multivalue: true,
                     unique: false,
+                    secret: false,
                     index: vec![],
                     syntax: SyntaxType::INDEX_ID,
                 },
@@ -582,6 +747,7 @@ impl SchemaInner {
                     ),
                     multivalue: false,
                     unique: false,
+                    secret: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::SYNTAX_ID,
                 },
@@ -597,6 +763,7 @@ impl SchemaInner {
                     ),
                     multivalue: true,
                     unique: false,
+                    secret: false,
                     index: vec![],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
@@ -612,6 +779,7 @@ impl SchemaInner {
                     ),
                     multivalue: true,
                     unique: false,
+                    secret: false,
                     index: vec![],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
@@ -627,6 +795,7 @@ impl SchemaInner {
                     ),
                     multivalue: true,
                     unique: false,
+                    secret: false,
                     index: vec![],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
@@ -642,6 +811,7 @@ impl SchemaInner {
                     ),
                     multivalue: true,
                     unique: false,
+                    secret: false,
                     index: vec![],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
@@ -657,6 +827,7 @@ impl SchemaInner {
                     description: String::from("A flag to determine if this ACP is active for application. True is enabled, and enforce. False is checked but not enforced."),
                     multivalue: false,
                 unique: false,
+                secret: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::BOOLEAN,
                 },
@@ -673,6 +844,7 @@ impl SchemaInner {
                     ),
                     multivalue: false,
                     unique: false,
+                    secret: false,
                     index: vec![IndexType::EQUALITY, IndexType::SUBSTRING],
                     syntax: SyntaxType::JSON_FILTER,
                 },
@@ -688,6 +860,7 @@ impl SchemaInner {
                     ),
                     multivalue: false,
                     unique: false,
+                    secret: false,
                     index: vec![IndexType::EQUALITY, IndexType::SUBSTRING],
                     syntax: SyntaxType::JSON_FILTER,
                 },
@@ -701,6 +874,7 @@ impl SchemaInner {
                     description: String::from("The attributes that may be viewed or searched by the reciever on targetscope."),
                     multivalue: true,
                 unique: false,
+                secret: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
@@ -716,6 +890,7 @@ impl SchemaInner {
                     ),
                     multivalue: true,
                     unique: false,
+                    secret: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
@@ -731,6 +906,7 @@ impl SchemaInner {
                     ),
                     multivalue: true,
                     unique: false,
+                    secret: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
@@ -745,6 +921,7 @@ impl SchemaInner {
                     description: String::from("The set of attribute types that could be removed or purged in a modification."),
                     multivalue: true,
                 unique: false,
+                secret: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
@@ -758,6 +935,7 @@ impl SchemaInner {
                     description: String::from("The set of attribute types that could be added or asserted in a modification."),
                     multivalue: true,
                 unique: false,
+                secret: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
@@ -771,6 +949,21 @@ impl SchemaInner {
                     description: String::from("The set of class values that could be asserted or added to an entry. Only applies to modify::present operations on class."),
                     multivalue: true,
                 unique: false,
+                secret: false,
+                    index: vec![IndexType::EQUALITY],
+                    syntax: SyntaxType::UTF8STRING_INSENSITIVE,
+                },
+            );
+            s.attributes.insert(
+                String::from("acp_compare_attr"),
+                SchemaAttribute {
+                    name: String::from("acp_compare_attr"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_ACP_COMPARE_ATTR)
+                        .expect("unable to parse static uuid"),
+                    description: String::from("The attributes whose values may be compared by the reciever on targetscope, without being able to read them."),
+                    multivalue: true,
+                    unique: false,
+                    secret: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
@@ -785,6 +978,7 @@ impl SchemaInner {
                     description: String::from("reverse group membership of the object"),
                     multivalue: true,
                     unique: false,
+                    secret: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::REFERENCE_UUID,
                 },
@@ -798,10 +992,53 @@ impl SchemaInner {
                     description: String::from("reverse direct group membership of the object"),
                     multivalue: true,
                     unique: false,
+                    secret: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::REFERENCE_UUID,
                 },
             );
+            s.attributes.insert(
+                String::from("recycled_references"),
+                SchemaAttribute {
+                    name: String::from("recycled_references"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_RECYCLED_REFERENCES)
+                        .expect("unable to parse static uuid"),
+                    description: String::from("References to a recycled object that were removed when it was deleted, as attr:uuid of the referring object"),
+                    multivalue: true,
+                    unique: false,
+                    secret: false,
+                    index: vec![],
+                    syntax: SyntaxType::UTF8STRING,
+                },
+            );
+            s.attributes.insert(
+                String::from("deleted_at"),
+                SchemaAttribute {
+                    name: String::from("deleted_at"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_DELETED_AT)
+                        .expect("unable to parse static uuid"),
+                    description: String::from("The time an object was moved to the recycle bin"),
+                    multivalue: false,
+                    unique: false,
+                    secret: false,
+                    index: vec![],
+                    syntax: SyntaxType::DATETIME,
+                },
+            );
+            s.attributes.insert(
+                String::from("tombstoned_at"),
+                SchemaAttribute {
+                    name: String::from("tombstoned_at"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_TOMBSTONED_AT)
+                        .expect("unable to parse static uuid"),
+                    description: String::from("The time a recycled object became a tombstone"),
+                    multivalue: false,
+                    unique: false,
+                    secret: false,
+                    index: vec![],
+                    syntax: SyntaxType::DATETIME,
+                },
+            );
             s.attributes.insert(
                 String::from("member"),
                 SchemaAttribute {
@@ -811,6 +1048,7 @@ impl SchemaInner {
                     description: String::from("List of members of the group"),
                     multivalue: true,
                     unique: false,
+                    secret: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::REFERENCE_UUID,
                 },
@@ -827,6 +1065,7 @@ impl SchemaInner {
                     ),
                     multivalue: false,
                     unique: false,
+                    secret: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
@@ -841,11 +1080,150 @@ impl SchemaInner {
                     description: String::from("A DNS Domain name entry."),
                     multivalue: true,
                     unique: false,
+                    secret: false,
                     index: vec![IndexType::EQUALITY],
                     syntax: SyntaxType::UTF8STRING_INSENSITIVE,
                 },
             );
 
+            // Token signing keys for sysinfo
+            s.attributes.insert(
+                String::from("token_signing_key"),
+                SchemaAttribute {
+                    name: String::from("token_signing_key"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_TOKEN_SIGNING_KEY)
+                        .expect("unable to parse static uuid"),
+                    description: String::from(
+                        "The private key that issued user auth tokens are signed with",
+                    ),
+                    multivalue: false,
+                    unique: false,
+                    secret: true,
+                    index: vec![],
+                    syntax: SyntaxType::BINARY,
+                },
+            );
+            s.attributes.insert(
+                String::from("token_signing_key_previous"),
+                SchemaAttribute {
+                    name: String::from("token_signing_key_previous"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_TOKEN_SIGNING_KEY_PREVIOUS)
+                        .expect("unable to parse static uuid"),
+                    description: String::from(
+                        "The token signing key in use before the last rotation",
+                    ),
+                    multivalue: false,
+                    unique: false,
+                    secret: true,
+                    index: vec![],
+                    syntax: SyntaxType::BINARY,
+                },
+            );
+            s.attributes.insert(
+                String::from("token_signing_key_rotated_at"),
+                SchemaAttribute {
+                    name: String::from("token_signing_key_rotated_at"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_TOKEN_SIGNING_KEY_ROTATED_AT)
+                        .expect("unable to parse static uuid"),
+                    description: String::from("The time the token signing key was last rotated"),
+                    multivalue: false,
+                    unique: false,
+                    secret: false,
+                    index: vec![],
+                    syntax: SyntaxType::DATETIME,
+                },
+            );
+            // Domain info
+            s.attributes.insert(
+                String::from("domain_name"),
+                SchemaAttribute {
+                    name: String::from("domain_name"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_DOMAIN_NAME)
+                        .expect("unable to parse static uuid"),
+                    description: String::from(
+                        "The DNS domain name of the deployment, that spns are made with",
+                    ),
+                    multivalue: false,
+                    unique: true,
+                    secret: false,
+                    index: vec![IndexType::EQUALITY],
+                    syntax: SyntaxType::UTF8STRING_INSENSITIVE,
+                },
+            );
+            s.attributes.insert(
+                String::from("domain_display_name"),
+                SchemaAttribute {
+                    name: String::from("domain_display_name"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_DOMAIN_DISPLAY_NAME)
+                        .expect("unable to parse static uuid"),
+                    description: String::from("The name of the deployment shown to people"),
+                    multivalue: false,
+                    unique: false,
+                    secret: false,
+                    index: vec![],
+                    syntax: SyntaxType::UTF8STRING,
+                },
+            );
+            s.attributes.insert(
+                String::from("domain_uuid"),
+                SchemaAttribute {
+                    name: String::from("domain_uuid"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_DOMAIN_UUID)
+                        .expect("unable to parse static uuid"),
+                    description: String::from("The uuid of the deployment, made at first start"),
+                    multivalue: false,
+                    unique: true,
+                    secret: false,
+                    index: vec![],
+                    syntax: SyntaxType::UUID,
+                },
+            );
+            // Password quality for system config
+            s.attributes.insert(
+                String::from("badlist_password"),
+                SchemaAttribute {
+                    name: String::from("badlist_password"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_BADLIST_PASSWORD)
+                        .expect("unable to parse static uuid"),
+                    description: String::from("A known weak password that may not be set"),
+                    multivalue: true,
+                    unique: false,
+                    secret: false,
+                    index: vec![],
+                    syntax: SyntaxType::UTF8STRING_INSENSITIVE,
+                },
+            );
+            s.attributes.insert(
+                String::from("password_min_score"),
+                SchemaAttribute {
+                    name: String::from("password_min_score"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_PASSWORD_MIN_SCORE)
+                        .expect("unable to parse static uuid"),
+                    description: String::from(
+                        "The lowest strength score, from 0 to 4, a new password may have",
+                    ),
+                    multivalue: false,
+                    unique: false,
+                    secret: false,
+                    index: vec![],
+                    syntax: SyntaxType::UINT32,
+                },
+            );
+            s.attributes.insert(
+                String::from("disable_anonymous"),
+                SchemaAttribute {
+                    name: String::from("disable_anonymous"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_ATTR_DISABLE_ANONYMOUS)
+                        .expect("unable to parse static uuid"),
+                    description: String::from("If true, anonymous may not authenticate"),
+                    multivalue: false,
+                    unique: false,
+                    secret: false,
+                    index: vec![],
+                    syntax: SyntaxType::BOOLEAN,
+                },
+            );
+
             s.classes.insert(
                 String::from("attributetype"),
                 SchemaClass {
@@ -853,7 +1231,7 @@ impl SchemaInner {
                     uuid: Uuid::parse_str(UUID_SCHEMA_CLASS_ATTRIBUTETYPE)
                         .expect("unable to parse static uuid"),
                     description: String::from("Definition of a schema attribute"),
-                    systemmay: vec![String::from("index")],
+                    systemmay: vec![String::from("index"), String::from("secret")],
                     may: vec![],
                     systemmust: vec![
                         String::from("class"),
@@ -941,7 +1319,10 @@ impl SchemaInner {
                     name: String::from("recycled"),
                     uuid: Uuid::parse_str(UUID_SCHEMA_CLASS_RECYCLED).expect("unable to parse static uuid"),
                     description: String::from("An object that has been deleted, but still recoverable via the revive operation. Recycled objects are not modifiable, only revivable."),
-                    systemmay: vec![],
+                    systemmay: vec![
+                        String::from("recycled_references"),
+                        String::from("deleted_at"),
+                    ],
                     may: vec![],
                     systemmust: vec![],
                     must: vec![],
@@ -952,8 +1333,10 @@ impl SchemaInner {
                 SchemaClass {
                     name: String::from("tombstone"),
                     uuid: Uuid::parse_str(UUID_SCHEMA_CLASS_TOMBSTONE).expect("unable to parse static uuid"),
-                    description: String::from("An object that is purged from the recycle bin. This is a system internal state. Tombstones have no attributes beside UUID."),
-                    systemmay: vec![],
+                    description: String::from("An object that is purged from the recycle bin. This is a system internal state. Tombstones have no attributes beside UUID and the time they were tombstoned."),
+                    systemmay: vec![
+                        String::from("tombstoned_at"),
+                    ],
                     may: vec![],
                     systemmust: vec![
                         String::from("class"),
@@ -970,7 +1353,11 @@ impl SchemaInner {
                     uuid: Uuid::parse_str(UUID_SCHEMA_CLASS_SYSTEM_INFO)
                         .expect("unable to parse static uuid"),
                     description: String::from("System metadata object class"),
-                    systemmay: vec![],
+                    systemmay: vec![
+                        String::from("token_signing_key"),
+                        String::from("token_signing_key_previous"),
+                        String::from("token_signing_key_rotated_at"),
+                    ],
                     may: vec![],
                     systemmust: vec![
                         String::from("version"),
@@ -981,6 +1368,43 @@ impl SchemaInner {
                     must: vec![],
                 },
             );
+            s.classes.insert(
+                String::from("system_config"),
+                SchemaClass {
+                    name: String::from("system_config"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_CLASS_SYSTEM_CONFIG)
+                        .expect("unable to parse static uuid"),
+                    description: String::from("System configuration object class"),
+                    systemmay: vec![
+                        String::from("description"),
+                        String::from("badlist_password"),
+                        String::from("password_min_score"),
+                        String::from("disable_anonymous"),
+                    ],
+                    may: vec![],
+                    systemmust: vec![],
+                    must: vec![],
+                },
+            );
+            s.classes.insert(
+                String::from("domain_info"),
+                SchemaClass {
+                    name: String::from("domain_info"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_CLASS_DOMAIN_INFO)
+                        .expect("unable to parse static uuid"),
+                    description: String::from("The deployment's domain info object class"),
+                    systemmay: vec![
+                        String::from("description"),
+                        String::from("domain_display_name"),
+                        String::from("token_signing_key"),
+                        String::from("token_signing_key_previous"),
+                        String::from("token_signing_key_rotated_at"),
+                    ],
+                    may: vec![],
+                    systemmust: vec![String::from("domain_name"), String::from("domain_uuid")],
+                    must: vec![],
+                },
+            );
             // ACP
             s.classes.insert(
                 String::from("access_control_profile"),
@@ -1058,6 +1482,19 @@ impl SchemaInner {
                     must: vec![],
                 },
             );
+            s.classes.insert(
+                String::from("access_control_compare"),
+                SchemaClass {
+                    name: String::from("access_control_compare"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_CLASS_ACCESS_CONTROL_COMPARE)
+                        .expect("unable to parse static uuid"),
+                    description: String::from("System Access Control Compare Class"),
+                    systemmay: vec![],
+                    may: vec![],
+                    systemmust: vec!["acp_compare_attr".to_string()],
+                    must: vec![],
+                },
+            );
             s.classes.insert(
                 String::from("system"),
                 SchemaClass {
@@ -1071,6 +1508,32 @@ impl SchemaInner {
                     must: vec![],
                 },
             );
+            s.classes.insert(
+                String::from("system_restricted"),
+                SchemaClass {
+                    name: String::from("system_restricted"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_CLASS_SYSTEM_RESTRICTED)
+                        .expect("unable to parse static uuid"),
+                    description: String::from("A class denoting a builtin entry that can't be deleted, and where only some attributes, such as the display name and credentials, may be modified."),
+                    systemmay: vec![],
+                    may: vec![],
+                    systemmust: vec![],
+                    must: vec![],
+                },
+            );
+            s.classes.insert(
+                String::from("system_undeletable"),
+                SchemaClass {
+                    name: String::from("system_undeletable"),
+                    uuid: Uuid::parse_str(UUID_SCHEMA_CLASS_SYSTEM_UNDELETABLE)
+                        .expect("unable to parse static uuid"),
+                    description: String::from("A class denoting a builtin entry that may be modified, but can't be deleted."),
+                    systemmay: vec![],
+                    may: vec![],
+                    systemmust: vec![],
+                    must: vec![],
+                },
+            );
 
             let r = s.validate(&mut au);
             if r.len() == 0 {
@@ -1150,6 +1613,15 @@ impl SchemaInner {
                 }
             }
         }
+        // Uniqueness is checked with an equality search on every write, so
+        // it must be able to use an index.
+        for attr in self.attributes.values() {
+            if attr.unique && !attr.index.contains(&IndexType::EQUALITY) {
+                res.push(Err(ConsistencyError::SchemaUniqueAttributeNotIndexed(
+                    attr.name.clone(),
+                )))
+            }
+        }
 
         res
     }
@@ -1584,6 +2056,7 @@ mod tests {
             description: String::from(""),
             multivalue: false,
             unique: false,
+            secret: false,
             index: vec![IndexType::EQUALITY],
             syntax: SyntaxType::UTF8STRING_INSENSITIVE,
         };
@@ -1606,6 +2079,7 @@ mod tests {
             description: String::from(""),
             multivalue: true,
             unique: false,
+            secret: false,
             index: vec![IndexType::EQUALITY],
             syntax: SyntaxType::UTF8STRING,
         };
@@ -1623,6 +2097,7 @@ mod tests {
             description: String::from(""),
             multivalue: true,
             unique: false,
+            secret: false,
             index: vec![IndexType::EQUALITY],
             syntax: SyntaxType::BOOLEAN,
         };
@@ -1646,6 +2121,7 @@ mod tests {
             description: String::from(""),
             multivalue: false,
             unique: false,
+            secret: false,
             index: vec![IndexType::EQUALITY],
             syntax: SyntaxType::SYNTAX_ID,
         };
@@ -1664,6 +2140,7 @@ mod tests {
             description: String::from(""),
             multivalue: false,
             unique: false,
+            secret: false,
             index: vec![IndexType::EQUALITY],
             syntax: SyntaxType::INDEX_ID,
         };
@@ -1978,6 +2455,16 @@ mod tests {
         // Test the recursive structures validate
         let f_or_empty = filter_all!(f_or!([]));
         assert_eq!(f_or_empty.validate(&schema), Err(SchemaError::EmptyFilter));
+        // An empty or nested in an and is still rejected, rather than being
+        // silently removed by the optimiser.
+        let f_and_or_empty = filter_all!(f_and!([
+            f_eq("class", PartialValue::new_class("attributetype")),
+            f_or!([])
+        ]));
+        assert_eq!(
+            f_and_or_empty.validate(&schema),
+            Err(SchemaError::EmptyFilter)
+        );
         let f_or = filter_all!(f_or!([f_eq(
             "multivalue",
             PartialValue::new_iutf8s("zzzz")
@@ -2013,6 +2500,38 @@ mod tests {
         println!("{}", audit);
     }
 
+    #[test]
+    fn test_schema_attribute_case_normalisation() {
+        let mut audit = AuditScope::new("test_schema_attribute_case_normalisation");
+        let schema_outer = Schema::new(&mut audit).expect("failed to create schema");
+        let schema = schema_outer.read();
+        let attrs = schema.get_attributes();
+
+        // Insensitive syntaxes fold case.
+        let sa_name = attrs.get("name").expect("name missing");
+        assert_eq!(sa_name.normalise_value("AdMiN"), "admin");
+        assert_eq!(
+            sa_name.normalise_partialvalue(&PartialValue::new_utf8s("AdMiN")),
+            PartialValue::new_iutf8s("admin")
+        );
+
+        // Exact syntaxes do not.
+        let sa_dn = attrs.get("description").expect("description missing");
+        assert_eq!(sa_dn.normalise_value("AdMiN"), "AdMiN");
+        assert_eq!(
+            sa_dn.normalise_partialvalue(&PartialValue::new_utf8s("AdMiN")),
+            PartialValue::new_utf8s("AdMiN")
+        );
+
+        // A folded filter value matches the normalised form in the entry.
+        let f_insense = filter_all!(f_eq("name", PartialValue::new_utf8s("AdMiN")));
+        assert_eq!(
+            f_insense.validate(&schema),
+            Ok(unsafe { filter_valid!(f_eq("name", PartialValue::new_iutf8s("admin"))) })
+        );
+        println!("{}", audit);
+    }
+
     #[test]
     fn test_schema_filter_normalisation() {
         // Test mixed case attr name
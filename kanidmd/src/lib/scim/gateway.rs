@@ -0,0 +1,969 @@
+// How scim resources, filters and patches are given as our entries, filters
+// and modifications. A user is a person account and a group is a group, and
+// the id of either is its uuid. The externalId a provisioning system keeps
+// for a resource is stored as scim_external_id, so it can find its own
+// resources again.
+use crate::scim::proto::{
+    ScimEmail, ScimError, ScimGroup, ScimMember, ScimMeta, ScimPatchRequest, ScimUser,
+    SCIM_SCHEMA_GROUP, SCIM_SCHEMA_USER,
+};
+use chrono::DateTime;
+use kanidm_proto::v1::Entry as ProtoEntry;
+use kanidm_proto::v1::Filter as ProtoFilter;
+use kanidm_proto::v1::{Modify as ProtoModify, ModifyList as ProtoModifyList, OperationError};
+use serde::de::DeserializeOwned;
+use serde_json::Value as JsonValue;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use uuid::Uuid;
+
+// An account is disabled by expiring it, at a time that has always passed.
+const SCIM_INACTIVE_EXPIRE: &'static str = "1970-01-01T00:00:00Z";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScimKind {
+    User,
+    Group,
+}
+
+impl ScimKind {
+    pub fn resource_type(self) -> &'static str {
+        match self {
+            ScimKind::User => "User",
+            ScimKind::Group => "Group",
+        }
+    }
+
+    pub fn endpoint(self) -> &'static str {
+        match self {
+            ScimKind::User => "Users",
+            ScimKind::Group => "Groups",
+        }
+    }
+
+    fn schema(self) -> &'static str {
+        match self {
+            ScimKind::User => SCIM_SCHEMA_USER,
+            ScimKind::Group => SCIM_SCHEMA_GROUP,
+        }
+    }
+
+    // The entries that are resources of this kind.
+    pub fn class_filter(self) -> ProtoFilter {
+        match self {
+            ScimKind::User => ProtoFilter::And(vec![
+                ProtoFilter::Eq("class".to_string(), "person".to_string()),
+                ProtoFilter::Eq("class".to_string(), "account".to_string()),
+            ]),
+            ScimKind::Group => ProtoFilter::Eq("class".to_string(), "group".to_string()),
+        }
+    }
+
+    // Our attribute for a scim attribute, given in lower case, as scim
+    // attribute names are case insensitive.
+    fn attr(self, a: &str) -> Option<&'static str> {
+        match (self, a) {
+            (_, "id") => Some("uuid"),
+            (_, "externalid") => Some("scim_external_id"),
+            (ScimKind::User, "username") => Some("name"),
+            (ScimKind::User, "displayname") => Some("displayname"),
+            (ScimKind::User, "emails") | (ScimKind::User, "emails.value") => Some("mail"),
+            (ScimKind::Group, "displayname") => Some("name"),
+            (ScimKind::Group, "members") | (ScimKind::Group, "members.value") => Some("member"),
+            _ => None,
+        }
+    }
+
+    // Attributes may be given with the urn of their schema before them.
+    fn strip_schema<'a>(self, a: &'a str) -> &'a str {
+        let schema = self.schema();
+        match a.get(..schema.len()) {
+            Some(p) if p.eq_ignore_ascii_case(schema) && a[schema.len()..].starts_with(':') => {
+                &a[schema.len() + 1..]
+            }
+            _ => a,
+        }
+    }
+}
+
+// The entry with this id, which must be of the kind. Anything that isn't a
+// uuid can't name a resource, so it's not found.
+pub fn id_filter(kind: ScimKind, id: &str) -> Result<ProtoFilter, ScimError> {
+    match Uuid::parse_str(id) {
+        Ok(u) => Ok(ProtoFilter::And(vec![
+            kind.class_filter(),
+            ProtoFilter::Eq("uuid".to_string(), u.to_hyphenated_ref().to_string()),
+        ])),
+        Err(_) => Err(ScimError::new(404, None, "Resource not found")),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Open,
+    Close,
+}
+
+fn tokenize(f: &str) -> Result<Vec<Token>, ScimError> {
+    let mut tokens = Vec::new();
+    let mut chars = f.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            '"' => {
+                let mut v = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(e) => v.push(e),
+                            None => return Err(ScimError::invalid_filter("Unterminated string")),
+                        },
+                        Some(c) => v.push(c),
+                        None => return Err(ScimError::invalid_filter("Unterminated string")),
+                    }
+                }
+                tokens.push(Token::Quoted(v));
+            }
+            c => {
+                let mut w = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '(' || c == ')' || c == '"' {
+                        break;
+                    }
+                    w.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Word(w));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+// A filter of the subset of RFC 7644 section 3.4.2.2 that clients use to
+// find a resource before they create it: eq, co and pr, joined by and and
+// or, with and binding tighter.
+struct FilterParser {
+    kind: ScimKind,
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl FilterParser {
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    fn next_is_word(&self, w: &str) -> bool {
+        match self.tokens.get(self.pos) {
+            Some(Token::Word(t)) => t.eq_ignore_ascii_case(w),
+            _ => false,
+        }
+    }
+
+    fn or(&mut self) -> Result<ProtoFilter, ScimError> {
+        let mut terms = vec![self.and()?];
+        while self.next_is_word("or") {
+            self.pos += 1;
+            terms.push(self.and()?);
+        }
+        Ok(if terms.len() == 1 {
+            terms.remove(0)
+        } else {
+            ProtoFilter::Or(terms)
+        })
+    }
+
+    fn and(&mut self) -> Result<ProtoFilter, ScimError> {
+        let mut terms = vec![self.term()?];
+        while self.next_is_word("and") {
+            self.pos += 1;
+            terms.push(self.term()?);
+        }
+        Ok(if terms.len() == 1 {
+            terms.remove(0)
+        } else {
+            ProtoFilter::And(terms)
+        })
+    }
+
+    fn term(&mut self) -> Result<ProtoFilter, ScimError> {
+        let a = match self.next() {
+            Some(Token::Open) => {
+                let f = self.or()?;
+                return match self.next() {
+                    Some(Token::Close) => Ok(f),
+                    _ => Err(ScimError::invalid_filter("Unbalanced parentheses")),
+                };
+            }
+            Some(Token::Word(a)) => a,
+            _ => return Err(ScimError::invalid_filter("Expected an attribute")),
+        };
+        let attr = self
+            .kind
+            .attr(self.kind.strip_schema(a.as_str()).to_lowercase().as_str())
+            .ok_or_else(|| {
+                ScimError::invalid_filter(format!("Unsupported attribute {}", a).as_str())
+            })?
+            .to_string();
+        let op = match self.next() {
+            Some(Token::Word(op)) => op.to_lowercase(),
+            _ => return Err(ScimError::invalid_filter("Expected an operator")),
+        };
+        if op == "pr" {
+            return Ok(ProtoFilter::Pres(attr));
+        }
+        let value = match self.next() {
+            Some(Token::Quoted(v)) | Some(Token::Word(v)) => v,
+            _ => return Err(ScimError::invalid_filter("Expected a value")),
+        };
+        match op.as_str() {
+            "eq" => Ok(ProtoFilter::Eq(attr, value)),
+            "co" => Ok(ProtoFilter::Sub(attr, value)),
+            _ => Err(ScimError::invalid_filter(
+                format!("Unsupported operator {}", op).as_str(),
+            )),
+        }
+    }
+}
+
+pub fn parse_filter(kind: ScimKind, f: &str) -> Result<ProtoFilter, ScimError> {
+    let mut p = FilterParser {
+        kind: kind,
+        tokens: tokenize(f)?,
+        pos: 0,
+    };
+    let filter = p.or()?;
+    if p.pos != p.tokens.len() {
+        return Err(ScimError::invalid_filter("Unexpected trailing input"));
+    }
+    Ok(filter)
+}
+
+// The filter of a list, limited to resources of the kind.
+pub fn search_filter(kind: ScimKind, f: Option<&str>) -> Result<ProtoFilter, ScimError> {
+    match f {
+        Some(f) if !f.trim().is_empty() => Ok(ProtoFilter::And(vec![
+            kind.class_filter(),
+            parse_filter(kind, f)?,
+        ])),
+        _ => Ok(kind.class_filter()),
+    }
+}
+
+// The strings of a value, which clients send bare, in arrays, or as objects
+// with a value, depending on the client and the attribute.
+fn json_strings(v: &JsonValue) -> Result<Vec<String>, ScimError> {
+    match v {
+        JsonValue::Null => Ok(Vec::new()),
+        JsonValue::String(s) => Ok(vec![s.clone()]),
+        JsonValue::Bool(b) => Ok(vec![b.to_string()]),
+        JsonValue::Number(n) => Ok(vec![n.to_string()]),
+        JsonValue::Array(a) => {
+            let mut r = Vec::new();
+            for v in a {
+                r.extend(json_strings(v)?);
+            }
+            Ok(r)
+        }
+        JsonValue::Object(o) => match o.get("value") {
+            Some(v) => json_strings(v),
+            None => Err(ScimError::invalid_value("Expected an object with a value")),
+        },
+    }
+}
+
+// Some clients give active as the strings "True" and "False".
+fn json_bool(v: &JsonValue) -> Result<bool, ScimError> {
+    match v {
+        JsonValue::Bool(b) => Ok(*b),
+        JsonValue::String(s) if s.eq_ignore_ascii_case("true") => Ok(true),
+        JsonValue::String(s) if s.eq_ignore_ascii_case("false") => Ok(false),
+        JsonValue::Array(a) if a.len() == 1 => json_bool(&a[0]),
+        _ => Err(ScimError::invalid_value("Expected a boolean")),
+    }
+}
+
+fn active_modify(active: bool) -> ProtoModify {
+    if active {
+        ProtoModify::Purged("account_expire".to_string())
+    } else {
+        ProtoModify::Set(
+            "account_expire".to_string(),
+            vec![SCIM_INACTIVE_EXPIRE.to_string()],
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PatchOp {
+    Add,
+    Replace,
+    Remove,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct PatchPath {
+    attr: String,
+    // The value a path such as members[value eq "x"] names.
+    value: Option<String>,
+    // If the path was narrowed by a filter on anything other than the value,
+    // as with emails[type eq "work"].value.
+    narrowed: bool,
+}
+
+fn parse_path(kind: ScimKind, path: &str) -> Result<PatchPath, ScimError> {
+    let path = kind.strip_schema(path.trim());
+    let open = match path.find('[') {
+        Some(open) => open,
+        None => {
+            // Only the first part of a sub attribute matters to us, so
+            // emails.value is emails, and name.givenName is ignored as name.
+            let attr = path.splitn(2, '.').next().unwrap_or("");
+            return Ok(PatchPath {
+                attr: attr.to_lowercase(),
+                value: None,
+                narrowed: false,
+            });
+        }
+    };
+    let close = path
+        .rfind(']')
+        .filter(|c| *c > open)
+        .ok_or_else(|| ScimError::new(400, Some("invalidPath"), "Unbalanced brackets"))?;
+    let value = match tokenize(&path[open + 1..close])?.as_slice() {
+        [Token::Word(a), Token::Word(op), Token::Quoted(v)]
+            if a.eq_ignore_ascii_case("value") && op.eq_ignore_ascii_case("eq") =>
+        {
+            Some(v.clone())
+        }
+        _ => None,
+    };
+    Ok(PatchPath {
+        attr: path[..open].to_lowercase(),
+        narrowed: value.is_none(),
+        value: value,
+    })
+}
+
+// The modifications of a multi valued attribute. Replace gives the whole of
+// the attribute as a single change, as Okta does for the members of a group
+// on each push.
+fn multi_modify(
+    op: PatchOp,
+    attr: &str,
+    path: &PatchPath,
+    value: Option<&JsonValue>,
+    mods: &mut Vec<ProtoModify>,
+) -> Result<(), ScimError> {
+    let values = match value {
+        Some(v) => json_strings(v)?,
+        None => Vec::new(),
+    };
+    match (op, &path.value) {
+        (PatchOp::Add, None) => mods.extend(
+            values
+                .into_iter()
+                .map(|v| ProtoModify::Present(attr.to_string(), v)),
+        ),
+        (PatchOp::Replace, None) => mods.push(ProtoModify::Set(attr.to_string(), values)),
+        (PatchOp::Remove, None) if value.is_none() => {
+            mods.push(ProtoModify::Purged(attr.to_string()))
+        }
+        (PatchOp::Remove, None) => mods.extend(
+            values
+                .into_iter()
+                .map(|v| ProtoModify::Removed(attr.to_string(), v)),
+        ),
+        (PatchOp::Remove, Some(old)) => {
+            mods.push(ProtoModify::Removed(attr.to_string(), old.clone()))
+        }
+        (_, Some(old)) => {
+            mods.push(ProtoModify::Removed(attr.to_string(), old.clone()));
+            mods.extend(
+                values
+                    .into_iter()
+                    .map(|v| ProtoModify::Present(attr.to_string(), v)),
+            );
+        }
+    }
+    Ok(())
+}
+
+fn single_modify(
+    op: PatchOp,
+    attr: &str,
+    value: Option<&JsonValue>,
+    mods: &mut Vec<ProtoModify>,
+) -> Result<(), ScimError> {
+    match (op, value) {
+        (PatchOp::Remove, _) => mods.push(ProtoModify::Purged(attr.to_string())),
+        (_, Some(v)) => mods.push(ProtoModify::Set(attr.to_string(), json_strings(v)?)),
+        (_, None) => return Err(ScimError::invalid_value("A value is required")),
+    }
+    Ok(())
+}
+
+fn patch_attr(
+    kind: ScimKind,
+    op: PatchOp,
+    path: &PatchPath,
+    value: Option<&JsonValue>,
+    mods: &mut Vec<ProtoModify>,
+) -> Result<(), ScimError> {
+    match (kind, path.attr.as_str()) {
+        (ScimKind::User, "active") => match (op, value) {
+            (PatchOp::Remove, _) => mods.push(active_modify(true)),
+            (_, Some(v)) => mods.push(active_modify(json_bool(v)?)),
+            (_, None) => return Err(ScimError::invalid_value("A value is required")),
+        },
+        // We keep no type for an email, so a path narrowed to one type is
+        // taken to be all of them, and adding to it replaces them.
+        (ScimKind::User, "emails") if path.narrowed => {
+            let op = if op == PatchOp::Add {
+                PatchOp::Replace
+            } else {
+                op
+            };
+            let unnarrowed = PatchPath {
+                attr: path.attr.clone(),
+                value: None,
+                narrowed: false,
+            };
+            multi_modify(op, "mail", &unnarrowed, value, mods)?
+        }
+        (_, a) => match kind.attr(a) {
+            // The id can't be changed, but clients include it with the rest
+            // of the resource.
+            Some("uuid") => {}
+            Some(attr @ "mail") | Some(attr @ "member") => {
+                multi_modify(op, attr, path, value, mods)?
+            }
+            Some(attr) => single_modify(op, attr, value, mods)?,
+            // Anything we don't keep, such as name.givenName, is ignored so
+            // that the rest of the patch can be applied.
+            None => {}
+        },
+    }
+    Ok(())
+}
+
+// The modifications of a patch, as RFC 7644 section 3.5.2 gives. An
+// operation without a path has an object as its value, each member of which
+// is applied as though it were the path.
+pub fn patch_modlist(kind: ScimKind, pr: &ScimPatchRequest) -> Result<ProtoModifyList, ScimError> {
+    let mut mods = Vec::new();
+    for o in pr.operations.iter() {
+        let op = match o.op.to_lowercase().as_str() {
+            "add" => PatchOp::Add,
+            "replace" => PatchOp::Replace,
+            "remove" => PatchOp::Remove,
+            _ => {
+                return Err(ScimError::invalid_syntax(
+                    format!("Unsupported operation {}", o.op).as_str(),
+                ))
+            }
+        };
+        match (&o.path, &o.value) {
+            (Some(p), _) => {
+                patch_attr(kind, op, &parse_path(kind, p)?, o.value.as_ref(), &mut mods)?
+            }
+            (None, Some(JsonValue::Object(obj))) if op != PatchOp::Remove => {
+                for (k, v) in obj.iter() {
+                    patch_attr(kind, op, &parse_path(kind, k)?, Some(v), &mut mods)?;
+                }
+            }
+            (None, _) => {
+                return Err(ScimError::new(
+                    400,
+                    Some("noTarget"),
+                    "An operation without a path needs an object as its value",
+                ))
+            }
+        }
+    }
+    Ok(ProtoModifyList::new_list(mods))
+}
+
+fn entry_attr(attrs: &mut BTreeMap<String, Vec<String>>, a: &str, vs: Vec<String>) {
+    if !vs.is_empty() {
+        attrs.insert(a.to_string(), vs);
+    }
+}
+
+// Azure AD gives no displayName unless it's mapped, so the name is used.
+fn user_displayname(u: &ScimUser) -> String {
+    u.display_name
+        .clone()
+        .unwrap_or_else(|| u.user_name.clone())
+}
+
+fn user_emails(u: &ScimUser) -> Vec<String> {
+    u.emails.iter().map(|e| e.value.clone()).collect()
+}
+
+pub fn user_to_entry(u: &ScimUser) -> ProtoEntry {
+    let mut attrs = BTreeMap::new();
+    attrs.insert(
+        "class".to_string(),
+        vec!["person".to_string(), "account".to_string()],
+    );
+    attrs.insert("name".to_string(), vec![u.user_name.clone()]);
+    attrs.insert("displayname".to_string(), vec![user_displayname(u)]);
+    entry_attr(&mut attrs, "mail", user_emails(u));
+    entry_attr(
+        &mut attrs,
+        "scim_external_id",
+        u.external_id.iter().cloned().collect(),
+    );
+    if u.active == Some(false) {
+        attrs.insert(
+            "account_expire".to_string(),
+            vec![SCIM_INACTIVE_EXPIRE.to_string()],
+        );
+    }
+    ProtoEntry { attrs: attrs }
+}
+
+pub fn group_to_entry(g: &ScimGroup) -> ProtoEntry {
+    let mut attrs = BTreeMap::new();
+    attrs.insert("class".to_string(), vec!["group".to_string()]);
+    attrs.insert("name".to_string(), vec![g.display_name.clone()]);
+    entry_attr(
+        &mut attrs,
+        "scim_external_id",
+        g.external_id.iter().cloned().collect(),
+    );
+    entry_attr(
+        &mut attrs,
+        "member",
+        g.members.iter().map(|m| m.value.clone()).collect(),
+    );
+    ProtoEntry { attrs: attrs }
+}
+
+// A put replaces the resource, so each attribute is given as a whole. Active
+// is only changed if it was given, so that a client which doesn't manage it
+// can't enable an account that was disabled here.
+pub fn user_modlist(u: &ScimUser) -> ProtoModifyList {
+    let mut mods = vec![
+        ProtoModify::Set("name".to_string(), vec![u.user_name.clone()]),
+        ProtoModify::Set("displayname".to_string(), vec![user_displayname(u)]),
+        ProtoModify::Set("mail".to_string(), user_emails(u)),
+        ProtoModify::Set(
+            "scim_external_id".to_string(),
+            u.external_id.iter().cloned().collect(),
+        ),
+    ];
+    if let Some(active) = u.active {
+        mods.push(active_modify(active));
+    }
+    ProtoModifyList::new_list(mods)
+}
+
+pub fn group_modlist(g: &ScimGroup) -> ProtoModifyList {
+    ProtoModifyList::new_list(vec![
+        ProtoModify::Set("name".to_string(), vec![g.display_name.clone()]),
+        ProtoModify::Set(
+            "scim_external_id".to_string(),
+            g.external_id.iter().cloned().collect(),
+        ),
+        ProtoModify::Set(
+            "member".to_string(),
+            g.members.iter().map(|m| m.value.clone()).collect(),
+        ),
+    ])
+}
+
+fn decode<T: DeserializeOwned>(body: &[u8]) -> Result<T, ScimError> {
+    serde_json::from_slice(body)
+        .map_err(|e| ScimError::invalid_syntax(format!("Json Decode Failed: {}", e).as_str()))
+}
+
+// The entry a post creates.
+pub fn decode_create(kind: ScimKind, body: &[u8]) -> Result<ProtoEntry, ScimError> {
+    match kind {
+        ScimKind::User => decode::<ScimUser>(body).map(|u| user_to_entry(&u)),
+        ScimKind::Group => decode::<ScimGroup>(body).map(|g| group_to_entry(&g)),
+    }
+}
+
+// The modifications of a put, which replaces the resource.
+pub fn decode_replace(kind: ScimKind, body: &[u8]) -> Result<ProtoModifyList, ScimError> {
+    match kind {
+        ScimKind::User => decode::<ScimUser>(body).map(|u| user_modlist(&u)),
+        ScimKind::Group => decode::<ScimGroup>(body).map(|g| group_modlist(&g)),
+    }
+}
+
+pub fn decode_patch(kind: ScimKind, body: &[u8]) -> Result<ProtoModifyList, ScimError> {
+    decode::<ScimPatchRequest>(body).and_then(|pr| patch_modlist(kind, &pr))
+}
+
+fn first(e: &ProtoEntry, a: &str) -> Option<String> {
+    e.attrs.get(a).and_then(|vs| vs.first()).cloned()
+}
+
+fn meta(kind: ScimKind, base: &str, id: &str) -> Option<ScimMeta> {
+    Some(ScimMeta {
+        resource_type: kind.resource_type().to_string(),
+        location: Some(format!("{}/{}/{}", base, kind.endpoint(), id)),
+    })
+}
+
+// The user of an entry, with its location below base. An account is active
+// unless it has expired, and an expiry that can't be read is taken to have
+// passed.
+pub fn entry_to_user(e: &ProtoEntry, base: &str, ct: Duration) -> Option<ScimUser> {
+    let id = first(e, "uuid")?;
+    let active = match first(e, "account_expire") {
+        Some(exp) => DateTime::parse_from_rfc3339(exp.as_str())
+            .map(|exp| exp.timestamp() > ct.as_secs() as i64)
+            .unwrap_or(false),
+        None => true,
+    };
+    let emails = e
+        .attrs
+        .get("mail")
+        .map(|vs| {
+            vs.iter()
+                .enumerate()
+                .map(|(i, v)| ScimEmail {
+                    value: v.clone(),
+                    kind: Some("work".to_string()),
+                    primary: i == 0,
+                })
+                .collect()
+        })
+        .unwrap_or_else(Vec::new);
+    Some(ScimUser {
+        schemas: vec![SCIM_SCHEMA_USER.to_string()],
+        meta: meta(ScimKind::User, base, id.as_str()),
+        id: Some(id),
+        external_id: first(e, "scim_external_id"),
+        user_name: first(e, "name")?,
+        display_name: first(e, "displayname"),
+        emails: emails,
+        active: Some(active),
+    })
+}
+
+// The names of the members of a group, which must be given as their uuids.
+// Members without a name are already given by uuid.
+pub fn member_names(e: &ProtoEntry) -> Vec<String> {
+    e.attrs
+        .get("member")
+        .map(|vs| {
+            vs.iter()
+                .filter(|v| Uuid::parse_str(v.as_str()).is_err())
+                .cloned()
+                .collect()
+        })
+        .unwrap_or_else(Vec::new)
+}
+
+pub fn entry_to_group(
+    e: &ProtoEntry,
+    member_uuids: &BTreeMap<String, String>,
+    base: &str,
+) -> Option<ScimGroup> {
+    let id = first(e, "uuid")?;
+    let members = e
+        .attrs
+        .get("member")
+        .map(|vs| {
+            vs.iter()
+                .filter_map(|v| match member_uuids.get(v) {
+                    Some(u) => Some(ScimMember {
+                        value: u.clone(),
+                        display: Some(v.clone()),
+                    }),
+                    None if Uuid::parse_str(v.as_str()).is_ok() => Some(ScimMember {
+                        value: v.clone(),
+                        display: None,
+                    }),
+                    None => None,
+                })
+                .collect()
+        })
+        .unwrap_or_else(Vec::new);
+    Some(ScimGroup {
+        schemas: vec![SCIM_SCHEMA_GROUP.to_string()],
+        meta: meta(ScimKind::Group, base, id.as_str()),
+        id: Some(id),
+        external_id: first(e, "scim_external_id"),
+        display_name: first(e, "name")?,
+        members: members,
+    })
+}
+
+// An error in the form scim clients expect, with the status the rest of the
+// api would have given it. Clients look for uniqueness in particular, to
+// tell that the resource they were creating already exists.
+pub fn operation_error(e: &OperationError, status: u16) -> ScimError {
+    let scim_type = match status {
+        409 => Some("uniqueness"),
+        400 => Some("invalidValue"),
+        _ => None,
+    };
+    ScimError::new(status, scim_type, format!("{:?}", e).as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        entry_to_group, entry_to_user, member_names, parse_filter, patch_modlist, search_filter,
+        user_modlist, user_to_entry, ScimKind,
+    };
+    use crate::scim::proto::{ScimPatchRequest, ScimUser};
+    use kanidm_proto::v1::Entry as ProtoEntry;
+    use kanidm_proto::v1::Filter as ProtoFilter;
+    use kanidm_proto::v1::Modify as ProtoModify;
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    fn s(v: &str) -> String {
+        v.to_string()
+    }
+
+    fn patch(kind: ScimKind, body: &str) -> Vec<ProtoModify> {
+        let pr: ScimPatchRequest = serde_json::from_str(body).expect("Invalid patch");
+        patch_modlist(kind, &pr).expect("Failed to patch").mods
+    }
+
+    #[test]
+    fn test_scim_gateway_filter() {
+        assert!(
+            parse_filter(ScimKind::User, "userName eq \"alice@example.com\"").unwrap()
+                == ProtoFilter::Eq(s("name"), s("alice@example.com"))
+        );
+        assert!(
+            parse_filter(
+                ScimKind::User,
+                "externalId EQ \"a \\\"b\\\"\" or (emails.value co \"@example.com\" and id pr)"
+            )
+            .unwrap()
+                == ProtoFilter::Or(vec![
+                    ProtoFilter::Eq(s("scim_external_id"), s("a \"b\"")),
+                    ProtoFilter::And(vec![
+                        ProtoFilter::Sub(s("mail"), s("@example.com")),
+                        ProtoFilter::Pres(s("uuid")),
+                    ]),
+                ])
+        );
+        assert!(
+            parse_filter(
+                ScimKind::Group,
+                "urn:ietf:params:scim:schemas:core:2.0:Group:displayName eq \"admins\""
+            )
+            .unwrap()
+                == ProtoFilter::Eq(s("name"), s("admins"))
+        );
+        assert!(
+            search_filter(ScimKind::Group, Some(" ")).unwrap() == ScimKind::Group.class_filter()
+        );
+
+        let invalid = |f: &str| {
+            parse_filter(ScimKind::User, f).unwrap_err().scim_type == Some(s("invalidFilter"))
+        };
+        assert!(invalid("title eq \"x\""));
+        assert!(invalid("userName sw \"a\""));
+        assert!(invalid("userName eq"));
+        assert!(invalid("userName eq \"a"));
+        assert!(invalid("(userName eq \"a\""));
+        assert!(invalid("userName eq \"a\" userName"));
+        assert!(invalid("emails[type eq \"work\"] pr"));
+    }
+
+    // Patches as Azure AD sends them when provisioning.
+    #[test]
+    fn test_scim_gateway_patch_azure() {
+        let mods = patch(
+            ScimKind::User,
+            r#"{"schemas":["urn:ietf:params:scim:api:messages:2.0:PatchOp"],"Operations":[{"op":"Replace","path":"emails[type eq \"work\"].value","value":"updatedEmail@microsoft.com"},{"op":"Replace","path":"name.familyName","value":"updatedFamilyName"},{"op":"Add","path":"displayName","value":"Updated Name"}]}"#,
+        );
+        assert!(
+            mods == vec![
+                ProtoModify::Set(s("mail"), vec![s("updatedEmail@microsoft.com")]),
+                ProtoModify::Set(s("displayname"), vec![s("Updated Name")]),
+            ]
+        );
+
+        // Older versions give active as a string.
+        let mods = patch(
+            ScimKind::User,
+            r#"{"schemas":["urn:ietf:params:scim:api:messages:2.0:PatchOp"],"Operations":[{"op":"Replace","path":"active","value":"False"}]}"#,
+        );
+        assert!(
+            mods == vec![ProtoModify::Set(
+                s("account_expire"),
+                vec![s("1970-01-01T00:00:00Z")]
+            )]
+        );
+
+        let mods = patch(
+            ScimKind::Group,
+            r#"{"schemas":["urn:ietf:params:scim:api:messages:2.0:PatchOp"],"Operations":[{"op":"Add","path":"members","value":[{"$ref":null,"value":"9c1c4b5e-0f6a-4e0c-8f0c-7a3c3f2d1e01"},{"$ref":null,"value":"9c1c4b5e-0f6a-4e0c-8f0c-7a3c3f2d1e02"}]}]}"#,
+        );
+        assert!(
+            mods == vec![
+                ProtoModify::Present(s("member"), s("9c1c4b5e-0f6a-4e0c-8f0c-7a3c3f2d1e01")),
+                ProtoModify::Present(s("member"), s("9c1c4b5e-0f6a-4e0c-8f0c-7a3c3f2d1e02")),
+            ]
+        );
+
+        let mods = patch(
+            ScimKind::Group,
+            r#"{"schemas":["urn:ietf:params:scim:api:messages:2.0:PatchOp"],"Operations":[{"op":"Remove","path":"members","value":[{"$ref":null,"value":"9c1c4b5e-0f6a-4e0c-8f0c-7a3c3f2d1e01"}]}]}"#,
+        );
+        assert!(
+            mods == vec![ProtoModify::Removed(
+                s("member"),
+                s("9c1c4b5e-0f6a-4e0c-8f0c-7a3c3f2d1e01")
+            )]
+        );
+    }
+
+    // Patches as Okta sends them, which replace the whole of a group on each
+    // push, with its id included.
+    #[test]
+    fn test_scim_gateway_patch_okta() {
+        let mods = patch(
+            ScimKind::Group,
+            r#"{"schemas":["urn:ietf:params:scim:api:messages:2.0:PatchOp"],"Operations":[{"op":"replace","value":{"id":"abf4dd94-a4c0-4f67-89c9-76b03340cb9b","displayName":"Test SCIMv2"}}]}"#,
+        );
+        assert!(mods == vec![ProtoModify::Set(s("name"), vec![s("Test SCIMv2")])]);
+
+        let mods = patch(
+            ScimKind::Group,
+            r#"{"schemas":["urn:ietf:params:scim:api:messages:2.0:PatchOp"],"Operations":[{"op":"replace","path":"members","value":[{"value":"23a35c27-23d3-4c03-b4c5-6443c09e7173","display":"test.user@okta.local"},{"value":"89bb1940-b905-4575-9e7f-6f887cfb368e","display":"test.user2@okta.local"}]}]}"#,
+        );
+        assert!(
+            mods == vec![ProtoModify::Set(
+                s("member"),
+                vec![
+                    s("23a35c27-23d3-4c03-b4c5-6443c09e7173"),
+                    s("89bb1940-b905-4575-9e7f-6f887cfb368e")
+                ]
+            )]
+        );
+
+        // An empty replace removes every member.
+        let mods = patch(
+            ScimKind::Group,
+            r#"{"schemas":["urn:ietf:params:scim:api:messages:2.0:PatchOp"],"Operations":[{"op":"replace","path":"members","value":[]}]}"#,
+        );
+        assert!(mods == vec![ProtoModify::Set(s("member"), vec![])]);
+
+        let mods = patch(
+            ScimKind::Group,
+            r#"{"schemas":["urn:ietf:params:scim:api:messages:2.0:PatchOp"],"Operations":[{"op":"remove","path":"members[value eq \"89bb1940-b905-4575-9e7f-6f887cfb368e\"]"},{"op":"add","path":"members","value":[{"value":"23a35c27-23d3-4c03-b4c5-6443c09e7173","display":"test.user@okta.local"}]}]}"#,
+        );
+        assert!(
+            mods == vec![
+                ProtoModify::Removed(s("member"), s("89bb1940-b905-4575-9e7f-6f887cfb368e")),
+                ProtoModify::Present(s("member"), s("23a35c27-23d3-4c03-b4c5-6443c09e7173")),
+            ]
+        );
+
+        let mods = patch(
+            ScimKind::User,
+            r#"{"schemas":["urn:ietf:params:scim:api:messages:2.0:PatchOp"],"Operations":[{"op":"replace","value":{"active":true}}]}"#,
+        );
+        assert!(mods == vec![ProtoModify::Purged(s("account_expire"))]);
+
+        let pr: ScimPatchRequest = serde_json::from_str(
+            r#"{"schemas":["urn:ietf:params:scim:api:messages:2.0:PatchOp"],"Operations":[{"op":"move","path":"members"}]}"#,
+        )
+        .unwrap();
+        assert!(
+            patch_modlist(ScimKind::Group, &pr).unwrap_err().scim_type == Some(s("invalidSyntax"))
+        );
+    }
+
+    #[test]
+    fn test_scim_gateway_user() {
+        // A user as Azure AD creates it.
+        let u: ScimUser = serde_json::from_str(
+            r#"{"schemas":["urn:ietf:params:scim:schemas:core:2.0:User","urn:ietf:params:scim:schemas:extension:enterprise:2.0:User"],"externalId":"0a21f0f2-8d2a-4f8e-bf98-7363c4aed4ef","userName":"Test_User@testuser.com","active":false,"emails":[{"primary":true,"type":"work","value":"Test_User@testuser.com"}],"meta":{"resourceType":"User"},"name":{"formatted":"givenName familyName","familyName":"familyName","givenName":"givenName"},"roles":[]}"#,
+        )
+        .unwrap();
+        let e = user_to_entry(&u);
+        assert!(e.attrs["name"] == vec![s("Test_User@testuser.com")]);
+        assert!(e.attrs["displayname"] == vec![s("Test_User@testuser.com")]);
+        assert!(e.attrs["mail"] == vec![s("Test_User@testuser.com")]);
+        assert!(e.attrs["scim_external_id"] == vec![s("0a21f0f2-8d2a-4f8e-bf98-7363c4aed4ef")]);
+        assert!(e.attrs["account_expire"] == vec![s("1970-01-01T00:00:00Z")]);
+
+        let mut u = u;
+        u.active = None;
+        u.external_id = None;
+        let mods = user_modlist(&u).mods;
+        assert!(mods.contains(&ProtoModify::Set(s("scim_external_id"), vec![])));
+        assert!(!mods.iter().any(|m| match m {
+            ProtoModify::Set(a, _) => a == "account_expire",
+            _ => false,
+        }));
+
+        let mut attrs = BTreeMap::new();
+        attrs.insert(s("uuid"), vec![s("2a7bce5a-0c1b-4b5a-9e2a-5c0b3e4c1d2f")]);
+        attrs.insert(s("name"), vec![s("alice")]);
+        attrs.insert(s("mail"), vec![s("a@example.com"), s("b@example.com")]);
+        attrs.insert(s("account_expire"), vec![s("2020-01-01T00:00:00Z")]);
+        let e = ProtoEntry { attrs: attrs };
+        let before = Duration::from_secs(1_500_000_000);
+        let after = Duration::from_secs(1_600_000_000);
+
+        let u = entry_to_user(&e, "https://idm.example.com/scim/v2", before).unwrap();
+        assert!(u.id == Some(s("2a7bce5a-0c1b-4b5a-9e2a-5c0b3e4c1d2f")));
+        assert!(u.active == Some(true));
+        assert!(u.emails.len() == 2 && u.emails[0].primary && !u.emails[1].primary);
+        assert!(
+            u.meta.unwrap().location
+                == Some(s(
+                    "https://idm.example.com/scim/v2/Users/2a7bce5a-0c1b-4b5a-9e2a-5c0b3e4c1d2f"
+                ))
+        );
+        let u = entry_to_user(&e, "", after).unwrap();
+        assert!(u.active == Some(false));
+    }
+
+    #[test]
+    fn test_scim_gateway_group() {
+        let mut attrs = BTreeMap::new();
+        attrs.insert(s("uuid"), vec![s("d0a4ed4a-0b27-4d4e-b5b4-cc1e27e4c4f6")]);
+        attrs.insert(s("name"), vec![s("staff")]);
+        attrs.insert(
+            s("member"),
+            vec![
+                s("alice"),
+                s("2a7bce5a-0c1b-4b5a-9e2a-5c0b3e4c1d2f"),
+                s("gone"),
+            ],
+        );
+        let e = ProtoEntry { attrs: attrs };
+        assert!(member_names(&e) == vec![s("alice"), s("gone")]);
+
+        let mut uuids = BTreeMap::new();
+        uuids.insert(s("alice"), s("9c1c4b5e-0f6a-4e0c-8f0c-7a3c3f2d1e01"));
+        let g = entry_to_group(&e, &uuids, "").unwrap();
+        assert!(g.display_name == "staff");
+        assert!(
+            g.members
+                .iter()
+                .map(|m| m.value.as_str())
+                .collect::<Vec<_>>()
+                == vec![
+                    "9c1c4b5e-0f6a-4e0c-8f0c-7a3c3f2d1e01",
+                    "2a7bce5a-0c1b-4b5a-9e2a-5c0b3e4c1d2f"
+                ]
+        );
+    }
+}
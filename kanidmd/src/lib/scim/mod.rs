@@ -0,0 +1,7 @@
+// Scim 2.0 provisioning, so that an identity provider such as Azure AD or
+// Okta can push its users and groups to us. A provisioning client
+// authenticates each request with the api token of a service account, and
+// each request is made as that account, so the access controls decide what
+// it may provision just as they do for the rest of the api.
+pub(crate) mod gateway;
+pub(crate) mod proto;
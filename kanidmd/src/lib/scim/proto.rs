@@ -0,0 +1,154 @@
+// The scim 2.0 resources and messages we use, as RFC 7643 and RFC 7644 give
+// them. Only the attributes we keep are given names, and anything else a
+// client sends is ignored.
+use serde_json::Value as JsonValue;
+
+pub const SCIM_SCHEMA_USER: &'static str = "urn:ietf:params:scim:schemas:core:2.0:User";
+pub const SCIM_SCHEMA_GROUP: &'static str = "urn:ietf:params:scim:schemas:core:2.0:Group";
+pub const SCIM_SCHEMA_LIST_RESPONSE: &'static str =
+    "urn:ietf:params:scim:api:messages:2.0:ListResponse";
+pub const SCIM_SCHEMA_ERROR: &'static str = "urn:ietf:params:scim:api:messages:2.0:Error";
+
+pub const CONTENT_TYPE_SCIM: &'static str = "application/scim+json";
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimMeta {
+    pub resource_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ScimEmail {
+    pub value: String,
+    #[serde(default, rename = "type", skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    #[serde(default)]
+    pub primary: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimUser {
+    #[serde(default)]
+    pub schemas: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external_id: Option<String>,
+    pub user_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub emails: Vec<ScimEmail>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<ScimMeta>,
+}
+
+// A member is given by its id, which is the uuid of the entry.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ScimMember {
+    pub value: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimGroup {
+    #[serde(default)]
+    pub schemas: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub external_id: Option<String>,
+    pub display_name: String,
+    #[serde(default)]
+    pub members: Vec<ScimMember>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<ScimMeta>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimListResponse<T> {
+    pub schemas: Vec<String>,
+    pub total_results: usize,
+    pub start_index: usize,
+    pub items_per_page: usize,
+    #[serde(rename = "Resources")]
+    pub resources: Vec<T>,
+}
+
+impl<T> ScimListResponse<T> {
+    pub fn new(resources: Vec<T>, total_results: usize, start_index: usize) -> Self {
+        ScimListResponse {
+            schemas: vec![SCIM_SCHEMA_LIST_RESPONSE.to_string()],
+            total_results: total_results,
+            start_index: start_index,
+            items_per_page: resources.len(),
+            resources: resources,
+        }
+    }
+}
+
+// The value of an operation is left as json, as its shape depends on the
+// path, and clients differ in what they send for the same change.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ScimPatchOperation {
+    pub op: String,
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub value: Option<JsonValue>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ScimPatchRequest {
+    #[serde(default)]
+    pub schemas: Vec<String>,
+    #[serde(rename = "Operations")]
+    pub operations: Vec<ScimPatchOperation>,
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimError {
+    pub schemas: Vec<String>,
+    // The http status, which scim gives as a string.
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scim_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl ScimError {
+    pub fn new(status: u16, scim_type: Option<&str>, detail: &str) -> Self {
+        ScimError {
+            schemas: vec![SCIM_SCHEMA_ERROR.to_string()],
+            status: status.to_string(),
+            scim_type: scim_type.map(|s| s.to_string()),
+            detail: Some(detail.to_string()),
+        }
+    }
+
+    pub fn invalid_filter(detail: &str) -> Self {
+        ScimError::new(400, Some("invalidFilter"), detail)
+    }
+
+    pub fn invalid_syntax(detail: &str) -> Self {
+        ScimError::new(400, Some("invalidSyntax"), detail)
+    }
+
+    pub fn invalid_value(detail: &str) -> Self {
+        ScimError::new(400, Some("invalidValue"), detail)
+    }
+
+    pub fn status_code(&self) -> u16 {
+        self.status.parse().unwrap_or(500)
+    }
+}
@@ -1,25 +1,40 @@
 // This is really only used for long lived, high level types that need clone
 // that otherwise can't be cloned. Think Mutex.
 // use actix::prelude::*;
-use std::sync::Arc;
+use chrono::{DateTime, Utc};
+use openssl::memcmp;
+use rand::prelude::*;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
 use uuid::Uuid;
 
 use crate::audit::AuditScope;
-use crate::be::{Backend, BackendReadTransaction, BackendTransaction, BackendWriteTransaction};
+use crate::be::dbaudit::{DbAuditChangeV1, DbAuditOperationV1, DbAuditRecord, DbAuditRecordV1};
+use crate::be::dbchange::DbChangeRecord;
+use crate::be::{
+    idx_name, Backend, BackendReadTransaction, BackendTransaction, BackendWriteTransaction,
+};
 
 use crate::access::{
-    AccessControlCreate, AccessControlDelete, AccessControlModify, AccessControlSearch,
-    AccessControls, AccessControlsReadTransaction, AccessControlsTransaction,
+    AccessControlCompare, AccessControlCreate, AccessControlDelete, AccessControlModify,
+    AccessControlSearch, AccessControls, AccessControlsReadTransaction, AccessControlsTransaction,
     AccessControlsWriteTransaction,
 };
 // We use so many, we just import them all ...
+use crate::cid::Cid;
 use crate::constants::*;
+use crate::crypto::{hmac_sha256, HMAC_SHA256_LEN};
+use crate::delayed::{DelayedAction, DelayedActionQueue};
 use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntryReduced, EntryValid};
 use crate::event::{
-    CreateEvent, DeleteEvent, Event, EventOrigin, ExistsEvent, ModifyEvent, ReviveRecycledEvent,
-    SearchEvent,
+    AccessCheckEvent, AuditListEvent, ChangesEvent, CompareEvent, CreateEvent, DeleteEvent,
+    EffectiveAccessEvent, Event, EventOrigin, ExistsEvent, ExportEvent, ExportRecord,
+    ModifyBatchEvent, ModifyEvent, ReviveRecycledEvent, SearchEvent,
 };
-use crate::filter::{Filter, FilterInvalid, FilterValid};
+use crate::filter::{Filter, FilterInvalid, FilterLimits, FilterValid, FilterValidResolved};
+use crate::idm::reauth::ReauthPolicy;
 use crate::modify::{Modify, ModifyInvalid, ModifyList, ModifyValid};
 use crate::plugins::Plugins;
 use crate::schema::{
@@ -27,21 +42,50 @@ use crate::schema::{
     SchemaWriteTransaction,
 };
 use crate::value::{PartialValue, SyntaxType, Value};
-use kanidm_proto::v1::{ConsistencyError, OperationError, SchemaError};
+use kanidm_proto::v1::Entry as ProtoEntry;
+use kanidm_proto::v1::Filter as ProtoFilter;
+use kanidm_proto::v1::{
+    AccessCheckEntry, AccessCheckOperation, AuditRecord, ChangedEntry, ChangesResponse,
+    ConsistencyError, EffectiveAccess, HealthCheck, HealthResponse, IndexStatus, OperationError,
+    RepairChange, RepairResponse, SchemaError, SearchPlan, VerifyCheck, AUDIT_REDACTED,
+};
 
 lazy_static! {
     static ref PVCLASS_ATTRIBUTETYPE: PartialValue = PartialValue::new_class("attributetype");
     static ref PVCLASS_CLASSTYPE: PartialValue = PartialValue::new_class("classtype");
     static ref PVCLASS_TOMBSTONE: PartialValue = PartialValue::new_class("tombstone");
     static ref PVCLASS_RECYCLED: PartialValue = PartialValue::new_class("recycled");
+    static ref PVCLASS_SYSTEM: PartialValue = PartialValue::new_class("system");
+    static ref PVMEMBEROF_UNLIMITED_SEARCH: PartialValue =
+        PartialValue::new_refer_s(_UUID_IDM_UNLIMITED_SEARCH_PRIV).unwrap();
     static ref PVCLASS_ACS: PartialValue = PartialValue::new_class("access_control_search");
     static ref PVCLASS_ACD: PartialValue = PartialValue::new_class("access_control_delete");
     static ref PVCLASS_ACM: PartialValue = PartialValue::new_class("access_control_modify");
     static ref PVCLASS_ACC: PartialValue = PartialValue::new_class("access_control_create");
     static ref PVCLASS_ACP: PartialValue = PartialValue::new_class("access_control_profile");
+    static ref PVCLASS_ACCMP: PartialValue = PartialValue::new_class("access_control_compare");
     static ref PVACP_ENABLE_TRUE: PartialValue = PartialValue::new_bool(true);
 }
 
+fn duration_to_datetime(ct: Duration) -> DateTime<Utc> {
+    DateTime::from(SystemTime::UNIX_EPOCH + ct)
+}
+
+// Anything that changed state before this point has been retained for
+// max_age and can move on.
+fn purge_cutoff(ct: Duration, max_age: Duration) -> DateTime<Utc> {
+    duration_to_datetime(ct.checked_sub(max_age).unwrap_or(Duration::from_secs(0)))
+}
+
+// Entries from before the state change was timestamped have no record of
+// when it happened, so they are treated as having aged out.
+fn has_aged_out(e: &Entry<EntryValid, EntryCommitted>, attr: &str, cutoff: &DateTime<Utc>) -> bool {
+    match e.get_ava_single(attr).and_then(|v| v.to_datetime()) {
+        Some(dt) => dt <= cutoff,
+        None => true,
+    }
+}
+
 // This is the core of the server. It implements all
 // the search and modify actions, applies access controls
 // and get's everything ready to push back to the fe code
@@ -55,27 +99,279 @@ pub trait QueryServerTransaction {
     type AccessControlsTransactionType: AccessControlsTransaction;
     fn get_accesscontrols(&self) -> &Self::AccessControlsTransactionType;
 
+    fn get_filter_limits(&self) -> &FilterLimits;
+
+    fn get_filter_limits_anonymous(&self) -> &FilterLimits;
+
+    fn get_page_key(&self) -> &[u8];
+
+    // Check a client supplied filter is within the resource limits that apply
+    // to the event origin. Anonymous gets stricter limits than authenticated users,
+    // and internal events aren't limited at all.
+    fn check_filter_limits(
+        &self,
+        au: &mut AuditScope,
+        ev: &Event,
+        f: &ProtoFilter,
+    ) -> Result<(), OperationError> {
+        let limits = match self.get_filter_limits_for(ev) {
+            Some(l) => l,
+            None => return Ok(()),
+        };
+        limits.check(f).map_err(|e| {
+            audit_log!(au, "Filter exceeds resource limits {:?}", limits);
+            e
+        })
+    }
+
+    fn get_filter_limits_for(&self, ev: &Event) -> Option<&FilterLimits> {
+        match &ev.origin {
+            EventOrigin::Internal => None,
+            EventOrigin::User(e) => {
+                if *e.get_uuid() == *UUID_ANONYMOUS {
+                    Some(self.get_filter_limits_anonymous())
+                } else {
+                    Some(self.get_filter_limits())
+                }
+            }
+        }
+    }
+
+    // The most entries the search may load, or return in a page if it's
+    // paged. Internal searches, and those by members of
+    // idm_unlimited_search_priv, have no limit.
+    fn get_result_limit(&self, ev: &Event) -> Option<usize> {
+        match &ev.origin {
+            EventOrigin::User(e)
+                if e.attribute_value_pres("memberof", &PVMEMBEROF_UNLIMITED_SEARCH) =>
+            {
+                None
+            }
+            _ => self.get_filter_limits_for(ev).map(|l| l.max_results),
+        }
+    }
+
+    // A filter that no index can narrow has the backend test every entry.
+    // These are logged as slow, or refused if the limits of the event origin
+    // don't allow them. Like the other limits, this doesn't apply to
+    // internal searches.
+    fn check_filter_indexed(
+        &self,
+        au: &mut AuditScope,
+        ev: &Event,
+        f: &Filter<FilterValidResolved>,
+    ) -> Result<(), OperationError> {
+        let limits = match self.get_filter_limits_for(ev) {
+            Some(l) => l,
+            None => return Ok(()),
+        };
+        if f.is_indexed(&self.get_schema().get_indexes()) {
+            Ok(())
+        } else if limits.allow_unindexed {
+            audit_log!(au, "Unindexed filter, this search may be slow {:?}", f);
+            warn!("Unindexed search, this may be slow -> {:?}", f);
+            Ok(())
+        } else {
+            audit_log!(au, "Unindexed filter refused by resource limits {:?}", f);
+            Err(OperationError::ResourceLimit)
+        }
+    }
+
+    // Which indexes the schema asks for and which exist, with the size of
+    // those that do.
+    fn index_status(&self, au: &mut AuditScope) -> Result<Vec<IndexStatus>, OperationError> {
+        let declared = self.get_schema().get_indexes();
+        let existing = self.get_be_txn().get_idx_set(au)?;
+        declared
+            .union(&existing)
+            .map(|(attr, itype)| {
+                let exists = existing.contains(&(attr.clone(), itype.clone()));
+                let (keys, distinct) = if exists {
+                    self.get_be_txn().idx_cardinality(au, attr, itype)?
+                } else {
+                    (0, 0)
+                };
+                Ok(IndexStatus {
+                    name: idx_name(attr, itype),
+                    attr: attr.clone(),
+                    index: itype.to_string(),
+                    declared: declared.contains(&(attr.clone(), itype.clone())),
+                    exists: exists,
+                    keys: keys,
+                    distinct: distinct,
+                })
+            })
+            .collect()
+    }
+
+    // A page cookie is an opaque position in a paged search, followed by a
+    // HMAC of that position so that clients can't forge one. The key is
+    // generated at server start, so outstanding cookies expire on restart.
+    fn new_page_cookie(&self, position: &[u8]) -> Result<String, OperationError> {
+        let mac = hmac_sha256(self.get_page_key(), position)
+            .map_err(|_| OperationError::CryptographyError)?;
+        Ok(position
+            .iter()
+            .chain(mac.iter())
+            .map(|b| format!("{:02x}", b))
+            .collect())
+    }
+
+    fn resolve_page_cookie(&self, cookie: &str) -> Result<Vec<u8>, OperationError> {
+        if !cookie.is_ascii() || cookie.len() % 2 != 0 {
+            return Err(OperationError::InvalidRequestState);
+        }
+        let raw: Vec<u8> = (0..cookie.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&cookie[i..i + 2], 16))
+            .collect::<Result<_, _>>()
+            .map_err(|_| OperationError::InvalidRequestState)?;
+        if raw.len() < HMAC_SHA256_LEN {
+            return Err(OperationError::InvalidRequestState);
+        }
+
+        let (position, mac) = raw.split_at(raw.len() - HMAC_SHA256_LEN);
+        let expect = hmac_sha256(self.get_page_key(), position)
+            .map_err(|_| OperationError::CryptographyError)?;
+        if memcmp::eq(expect.as_slice(), mac) {
+            Ok(position.to_vec())
+        } else {
+            Err(OperationError::InvalidRequestState)
+        }
+    }
+
     fn search_ext(
         &self,
         au: &mut AuditScope,
         se: &SearchEvent,
     ) -> Result<Vec<Entry<EntryReduced, EntryCommitted>>, OperationError> {
+        self.search_ext_paged(au, se).map(|(entries, _)| entries)
+    }
+
+    // As search_ext, but if the event asked for a page of entries this also
+    // returns the cookie to request the next page with, if any remain.
+    fn search_ext_paged(
+        &self,
+        au: &mut AuditScope,
+        se: &SearchEvent,
+    ) -> Result<(Vec<Entry<EntryReduced, EntryCommitted>>, Option<String>), OperationError> {
+        self.search_ext_plan(au, se)
+            .map(|(entries, next_cookie, _)| (entries, next_cookie))
+    }
+
+    // As search_ext_paged, but also return how the search was answered.
+    fn search_ext_plan(
+        &self,
+        au: &mut AuditScope,
+        se: &SearchEvent,
+    ) -> Result<
+        (
+            Vec<Entry<EntryReduced, EntryCommitted>>,
+            Option<String>,
+            SearchPlan,
+        ),
+        OperationError,
+    > {
         /*
          * This just wraps search, but it's for the external interface
          * so as a result it also reduces the entry set's attributes at
          * the end.
          */
-        let entries = self.search(au, se)?;
+        let (entries, plan) = self.search_plan(au, se)?;
 
-        let mut audit_acp = AuditScope::new("access_control_profiles");
+        let mut audit_acp = au.child("access_control_profiles");
         let access = self.get_accesscontrols();
         let acp_res = access.search_filter_entry_attributes(&mut audit_acp, se, entries);
         au.append_scope(audit_acp);
         // Log and fail if something went wrong.
         let entries_filtered = try_audit!(au, acp_res);
 
+        // Sorting happens after access controls so that the order can't
+        // disclose values the client isn't allowed to read. Paged searches
+        // resume after the position of the last entry returned, and ties are
+        // broken by entry id. Ids are only ever allocated upwards, so writes
+        // that happen while paging can't move entries between earlier pages.
+        let (entries_ordered, next_cookie) = if se.is_ordered() {
+            let mut positioned: Vec<_> = entries_filtered
+                .into_iter()
+                .map(|e| (se.page_position(&e), e))
+                .collect();
+            positioned.sort_by(|(a, _), (b, _)| se.cmp_page_position(a, b));
+            if let Some(after) = &se.page_after {
+                positioned.retain(|(p, _)| se.cmp_page_position(p, after) == Ordering::Greater);
+            }
+
+            let next_cookie = match se.page_size {
+                Some(page_size) if positioned.len() > page_size => {
+                    positioned.truncate(page_size);
+                    match positioned.last() {
+                        Some((p, _)) => Some(self.new_page_cookie(&se.page_cookie_data(p)?)?),
+                        None => None,
+                    }
+                }
+                _ => None,
+            };
+            audit_log!(au, "search: next cookie -> {:?}", next_cookie);
+
+            (
+                positioned.into_iter().map(|(_, e)| e).collect(),
+                next_cookie,
+            )
+        } else {
+            (entries_filtered, None)
+        };
+
+        // If the client asked for a subset of attributes, reduce to that.
+        // Attributes they can't read were already removed above, so they
+        // are silently absent rather than an error.
+        let entries_projected = match &se.attrs {
+            Some(attrs) => entries_ordered
+                .into_iter()
+                .map(|e| e.project_attributes(attrs))
+                .collect(),
+            None => entries_ordered,
+        };
+
         // This is the final entry set that was reduced.
-        Ok(entries_filtered)
+        Ok((entries_projected, next_cookie, plan))
+    }
+
+    // As search_ext, but each entry is given to f as soon as access controls
+    // have reduced it, so the reduced set is never held at once. The order
+    // must be known before the first entry can be given, so sorted and paged
+    // searches can't be answered this way. If f fails, the search stops
+    // there.
+    fn search_ext_each(
+        &self,
+        au: &mut AuditScope,
+        se: &SearchEvent,
+        f: &mut dyn FnMut(
+            &mut AuditScope,
+            Entry<EntryReduced, EntryCommitted>,
+        ) -> Result<(), OperationError>,
+    ) -> Result<SearchPlan, OperationError> {
+        if se.is_ordered() {
+            audit_log!(au, "search: sorted and paged searches can't be streamed");
+            return Err(OperationError::InvalidRequestState);
+        }
+
+        let (entries, plan) = self.search_plan(au, se)?;
+
+        let mut audit_acp = au.child("access_control_profiles");
+        let access = self.get_accesscontrols();
+        let acp_res = match &se.attrs {
+            Some(attrs) => access.search_filter_entry_attributes_each(
+                &mut audit_acp,
+                se,
+                entries,
+                &mut |audit, e| f(audit, e.project_attributes(attrs)),
+            ),
+            None => access.search_filter_entry_attributes_each(&mut audit_acp, se, entries, f),
+        };
+        au.append_scope(audit_acp);
+        try_audit!(au, acp_res);
+
+        Ok(plan)
     }
 
     fn search(
@@ -83,6 +379,16 @@ pub trait QueryServerTransaction {
         au: &mut AuditScope,
         se: &SearchEvent,
     ) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError> {
+        self.search_plan(au, se).map(|(entries, _)| entries)
+    }
+
+    // As search, but also return how the search was answered, down to the
+    // entries that access controls allowed.
+    fn search_plan(
+        &self,
+        au: &mut AuditScope,
+        se: &SearchEvent,
+    ) -> Result<(Vec<Entry<EntryValid, EntryCommitted>>, SearchPlan), OperationError> {
         audit_log!(au, "search: filter -> {:?}", se.filter);
 
         // This is an important security step because it prevents us from
@@ -96,38 +402,122 @@ pub trait QueryServerTransaction {
 
         // Now resolve all references.
         let vfr = try_audit!(au, se.filter.resolve(&se.event));
+        self.check_filter_indexed(au, &se.event, &vfr)?;
+
+        // A paged search may load more entries than the limit, as long as
+        // no page is larger than it. Otherwise the backend refuses to load
+        // more candidates than the limit.
+        let limit = match (self.get_result_limit(&se.event), se.page_size) {
+            (Some(limit), Some(page_size)) if page_size > limit => {
+                audit_log!(
+                    au,
+                    "search: page size {} exceeds limit {}",
+                    page_size,
+                    limit
+                );
+                return Err(OperationError::ResultLimit(limit as u64));
+            }
+            (_, Some(_)) => None,
+            (limit, None) => limit,
+        };
 
         // NOTE: We currently can't build search plugins due to the inability to hand
         // the QS wr/ro to the plugin trait. However, there shouldn't be a need for search
         // plugis, because all data transforms should be in the write path.
 
-        let mut audit_be = AuditScope::new("backend_search");
+        let mut audit_be = au.child("backend_search");
         let res = self
             .get_be_txn()
-            .search(&mut audit_be, &vfr)
-            .map(|r| r)
-            .map_err(|_| OperationError::Backend);
+            .search_plan(&mut audit_be, &vfr, limit)
+            .map_err(|e| match e {
+                OperationError::ResultLimit(_) => e,
+                _ => OperationError::Backend,
+            });
         au.append_scope(audit_be);
 
-        let res = try_audit!(au, res);
+        let (res, mut plan) = try_audit!(au, res);
 
         // Apply ACP before we let the plugins "have at it".
         // WARNING; for external searches this is NOT the only
         // ACP application. There is a second application to reduce the
         // attribute set on the entries!
         //
-        let mut audit_acp = AuditScope::new("access_control_profiles");
+        let mut audit_acp = au.child("access_control_profiles");
         let access = self.get_accesscontrols();
         let acp_res = access.search_filter_entries(&mut audit_acp, se, res);
 
         au.append_scope(audit_acp);
         let acp_res = try_audit!(au, acp_res);
 
-        Ok(acp_res)
+        plan.allowed = acp_res.len();
+        Ok((acp_res, plan))
+    }
+
+    // The number of entries the search matches that the client is allowed to
+    // see. This stops after access controls are applied, so the entries are
+    // never reduced or converted for sending.
+    fn search_count(&self, au: &mut AuditScope, se: &SearchEvent) -> Result<u64, OperationError> {
+        let count = self.search(au, se).map(|r| r.len() as u64)?;
+        audit_log!(au, "search_count: {}", count);
+        Ok(count)
+    }
+
+    fn search_exists(&self, au: &mut AuditScope, se: &SearchEvent) -> Result<bool, OperationError> {
+        self.search_count(au, se).map(|c| c > 0)
+    }
+
+    // Check if the single entry the filter selects has the value in attr. The
+    // caller must be able to find the entry, but only needs compare (or read)
+    // access to the attribute itself.
+    fn compare(&self, au: &mut AuditScope, ce: &CompareEvent) -> Result<bool, OperationError> {
+        let se = SearchEvent::new_impersonate(&ce.event, ce.filter.clone(), ce.filter_orig.clone());
+        let mut candidates = self.search(au, &se)?;
+
+        let entry = match candidates.len() {
+            0 => return Err(OperationError::NoMatchingEntries),
+            1 => candidates.remove(0),
+            n => {
+                audit_log!(au, "compare: filter matched {} entries, expected one", n);
+                return Err(OperationError::InvalidRequestState);
+            }
+        };
+
+        let access = self.get_accesscontrols();
+        match access.compare_allow_operation(au, ce, &entry) {
+            Ok(true) => {}
+            Ok(false) => return Err(OperationError::AccessDenied),
+            Err(e) => {
+                audit_log!(au, "compare: error in access control {:?}", e);
+                return Err(e);
+            }
+        }
+
+        let matched = entry.attribute_value_pres(ce.attr.as_str(), &ce.value);
+        audit_log!(au, "compare: matched -> {}", matched);
+        Ok(matched)
+    }
+
+    // What the requester may do to each entry it can find with the filter.
+    // The entries come from a normal search, so those it can't see aren't
+    // reported on.
+    fn effective_access(
+        &self,
+        au: &mut AuditScope,
+        eae: &EffectiveAccessEvent,
+    ) -> Result<Vec<EffectiveAccess>, OperationError> {
+        let se =
+            SearchEvent::new_impersonate(&eae.event, eae.filter.clone(), eae.filter_orig.clone());
+        let candidates = self.search(au, &se)?;
+
+        let mut audit_acp = au.child("access_control_profiles");
+        let access = self.get_accesscontrols();
+        let res = access.effective_access(&mut audit_acp, &eae.event, &candidates);
+        au.append_scope(audit_acp);
+        Ok(res)
     }
 
     fn exists(&self, au: &mut AuditScope, ee: &ExistsEvent) -> Result<bool, OperationError> {
-        let mut audit_be = AuditScope::new("backend_exists");
+        let mut audit_be = au.child("backend_exists");
 
         let vfr = try_audit!(au, ee.filter.resolve(&ee.event));
 
@@ -140,6 +530,199 @@ pub trait QueryServerTransaction {
         res
     }
 
+    // The records of the audit log made in the time range of the event, and
+    // if it has a target, only those of changes to it.
+    fn audit_list(
+        &self,
+        au: &mut AuditScope,
+        ale: &AuditListEvent,
+    ) -> Result<Vec<AuditRecord>, OperationError> {
+        let mut audit_be = au.child("backend_search_audit");
+        let res = self
+            .get_be_txn()
+            .search_audit(&mut audit_be, ale.since, ale.until);
+        au.append_scope(audit_be);
+
+        Ok(try_audit!(au, res)
+            .into_iter()
+            .filter(|r| match &ale.target {
+                Some(u) => r.has_target(u),
+                None => true,
+            })
+            .map(|r| r.into_proto())
+            .collect())
+    }
+
+    // The entries changed since the change id of the event, each with every
+    // attribute that changed and the id of its latest change, in the order
+    // of those ids.
+    fn changes_since(
+        &self,
+        au: &mut AuditScope,
+        che: &ChangesEvent,
+    ) -> Result<ChangesResponse, OperationError> {
+        let mut audit_be = au.child("backend_search_changes");
+        let res = self
+            .get_be_txn()
+            .get_changelog_meta(&mut audit_be)
+            .and_then(|meta| {
+                self.get_be_txn()
+                    .search_changes(&mut audit_be, che.since.as_ref())
+                    .map(|records| (meta, records))
+            });
+        au.append_scope(audit_be);
+        let (meta, records) = try_audit!(au, res);
+
+        // The changes since an id that is older than the changelog may have
+        // been trimmed, so aren't all there.
+        if let (Some(since), Some(trimmed)) = (&che.since, &meta.trimmed) {
+            if since < trimmed {
+                audit_log!(au, "changes since {} are trimmed up to {}", since, trimmed);
+                return Err(OperationError::ChangelogTrimmed);
+            }
+        }
+
+        let mut changed: BTreeMap<Uuid, (BTreeSet<String>, Cid)> = BTreeMap::new();
+        for record in records.into_iter() {
+            match record {
+                DbChangeRecord::V1(r) => {
+                    let e = changed
+                        .entry(r.uuid)
+                        .or_insert_with(|| (BTreeSet::new(), r.cid.clone()));
+                    e.0.extend(r.attrs.into_iter());
+                    e.1 = r.cid;
+                }
+            }
+        }
+        let mut entries: Vec<_> = changed.into_iter().collect();
+        entries.sort_by(|a, b| (a.1).1.cmp(&(b.1).1));
+
+        Ok(ChangesResponse::new(
+            entries
+                .into_iter()
+                .map(|(u, (attrs, cid))| ChangedEntry {
+                    uuid: u.to_hyphenated_ref().to_string(),
+                    attrs: attrs.into_iter().collect(),
+                    cid: cid.to_string(),
+                })
+                .collect(),
+            meta.last.map(|c| c.to_string()),
+        ))
+    }
+
+    // Give f each entry that changed since the event's change id, as the
+    // event may read it, then the uuid of each that was deleted since. With
+    // no change id, every entry the event may read is given instead. This
+    // returns the change id to export since next time.
+    fn export_each(
+        &self,
+        au: &mut AuditScope,
+        ee: &ExportEvent,
+        f: &mut dyn FnMut(&mut AuditScope, ExportRecord) -> Result<(), OperationError>,
+    ) -> Result<Option<Cid>, OperationError> {
+        let mut audit_be = au.child("backend_search_changes");
+        let res = self
+            .get_be_txn()
+            .get_changelog_meta(&mut audit_be)
+            .and_then(|meta| match &ee.since {
+                Some(since) => self
+                    .get_be_txn()
+                    .search_changes(&mut audit_be, Some(since))
+                    .map(|records| (meta, Some(records))),
+                None => Ok((meta, None)),
+            });
+        au.append_scope(audit_be);
+        let (meta, records) = try_audit!(au, res);
+
+        let changed: Option<BTreeSet<Uuid>> = match (&ee.since, records) {
+            (Some(since), Some(records)) => {
+                // As in changes_since, a partial export would look complete,
+                // so the caller must start again from a full one.
+                if let Some(trimmed) = &meta.trimmed {
+                    if since < trimmed {
+                        audit_log!(au, "export since {} is trimmed up to {}", since, trimmed);
+                        return Err(OperationError::ChangelogTrimmed);
+                    }
+                }
+                Some(
+                    records
+                        .into_iter()
+                        .map(|record| match record {
+                            DbChangeRecord::V1(r) => r.uuid,
+                        })
+                        .collect(),
+                )
+            }
+            _ => None,
+        };
+
+        let filter = match &changed {
+            Some(uuids) if uuids.is_empty() => return Ok(meta.last),
+            Some(uuids) => filter!(f_or(
+                uuids
+                    .iter()
+                    .map(|u| f_eq("uuid", PartialValue::new_uuid(u.clone())))
+                    .collect()
+            )),
+            None => filter!(f_pres("class")),
+        };
+        let f_valid = filter
+            .validate(self.get_schema())
+            .map_err(OperationError::SchemaViolation)?;
+        // Entries are named by their uuid in an export, so one is only given
+        // if its uuid may be read.
+        let f_intent_valid = filter_all!(f_pres("uuid"))
+            .validate(self.get_schema())
+            .map_err(OperationError::SchemaViolation)?;
+        let se = SearchEvent::new_impersonate(&ee.event, f_valid, f_intent_valid);
+
+        // The reduced entries may not have their uuid, so which were given
+        // is taken from the entries before they are reduced.
+        let (entries, _) = self.search_plan(au, &se)?;
+        let given: BTreeSet<Uuid> = entries.iter().map(|e| e.get_uuid().clone()).collect();
+
+        let mut audit_acp = au.child("access_control_profiles");
+        let access = self.get_accesscontrols();
+        let acp_res = access.search_filter_entry_attributes_each(
+            &mut audit_acp,
+            &se,
+            entries,
+            &mut |audit, e| f(audit, ExportRecord::Entry(e)),
+        );
+        au.append_scope(audit_acp);
+        try_audit!(au, acp_res);
+
+        let missing: Vec<Uuid> = match changed {
+            Some(uuids) => uuids.difference(&given).cloned().collect(),
+            None => return Ok(meta.last),
+        };
+        if missing.is_empty() {
+            return Ok(meta.last);
+        }
+
+        // Of the changed entries that weren't given, those that are still
+        // live can't be read by the event, so are left out. The rest were
+        // recycled, made tombstones or purged.
+        let live: BTreeSet<Uuid> = self
+            .internal_search(
+                au,
+                filter!(f_or(
+                    missing
+                        .iter()
+                        .map(|u| f_eq("uuid", PartialValue::new_uuid(u.clone())))
+                        .collect()
+                )),
+            )?
+            .iter()
+            .map(|e| e.get_uuid().clone())
+            .collect();
+        for u in missing.into_iter().filter(|u| !live.contains(u)) {
+            f(au, ExportRecord::Deleted(u))?;
+        }
+
+        Ok(meta.last)
+    }
+
     // Should this actually be names_to_uuids and we do batches?
     //  In the initial design "no", we can always write a batched
     //  interface later.
@@ -255,7 +838,7 @@ pub trait QueryServerTransaction {
         // Build an exists event
         let ee = ExistsEvent::new_internal(f_valid);
         // Submit it
-        let mut audit_int = AuditScope::new("internal_exists");
+        let mut audit_int = au.child("internal_exists");
         let res = self.exists(&mut audit_int, &ee);
         au.append_scope(audit_int);
         // return result
@@ -271,7 +854,7 @@ pub trait QueryServerTransaction {
             .validate(self.get_schema())
             .map_err(|e| OperationError::SchemaViolation(e))?;
         let se = SearchEvent::new_internal(f_valid);
-        let mut audit_int = AuditScope::new("internal_search");
+        let mut audit_int = audit.child("internal_search");
         let res = self.search(&mut audit_int, &se);
         audit.append_scope(audit_int);
         res
@@ -285,7 +868,7 @@ pub trait QueryServerTransaction {
         event: &Event,
     ) -> Result<Vec<Entry<EntryValid, EntryCommitted>>, OperationError> {
         let se = SearchEvent::new_impersonate(event, f_valid, f_intent_valid);
-        let mut audit_int = AuditScope::new("impersonate_search");
+        let mut audit_int = audit.child("impersonate_search");
         let res = self.search(&mut audit_int, &se);
         audit.append_scope(audit_int);
         res
@@ -308,6 +891,28 @@ pub trait QueryServerTransaction {
         self.impersonate_search_valid(audit, f_valid, f_intent_valid, event)
     }
 
+    // As impersonate_search, but the entries are reduced to the attributes
+    // the event may read, as they would be for an external search.
+    fn impersonate_search_ext(
+        &self,
+        audit: &mut AuditScope,
+        filter: Filter<FilterInvalid>,
+        filter_intent: Filter<FilterInvalid>,
+        event: &Event,
+    ) -> Result<Vec<Entry<EntryReduced, EntryCommitted>>, OperationError> {
+        let f_valid = filter
+            .validate(self.get_schema())
+            .map_err(OperationError::SchemaViolation)?;
+        let f_intent_valid = filter_intent
+            .validate(self.get_schema())
+            .map_err(OperationError::SchemaViolation)?;
+        let se = SearchEvent::new_impersonate(event, f_valid, f_intent_valid);
+        let mut audit_int = audit.child("impersonate_search_ext");
+        let res = self.search_ext(&mut audit_int, &se);
+        audit.append_scope(audit_int);
+        res
+    }
+
     // Get a single entry by it's UUID. This is heavily relied on for internal
     // server operations, especially in login and acp checks for acp.
     fn internal_search_uuid(
@@ -320,7 +925,7 @@ pub trait QueryServerTransaction {
             .validate(self.get_schema())
             .map_err(|e| OperationError::SchemaViolation(e))?;
         let se = SearchEvent::new_internal(f_valid);
-        let mut audit_int = AuditScope::new("internal_search_uuid");
+        let mut audit_int = audit.child("internal_search_uuid");
         let res = self.search(&mut audit_int, &se);
         audit.append_scope(audit_int);
         match res {
@@ -336,6 +941,21 @@ pub trait QueryServerTransaction {
         }
     }
 
+    /// Normalise an attribute name supplied by a client. Names are folded to
+    /// lowercase, and may only contain ascii alphanumerics, - and _.
+    fn clone_attr_name(&self, attr: &str) -> Result<String, OperationError> {
+        let an = self.get_schema().normalise_attr_name(attr);
+        if an.len() > 0
+            && an
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            Ok(an)
+        } else {
+            Err(OperationError::InvalidAttributeName(attr.to_string()))
+        }
+    }
+
     /// Do a schema aware conversion from a String:String to String:Value for modification
     /// present.
     fn clone_value(
@@ -356,6 +976,8 @@ pub trait QueryServerTransaction {
         // Lookup the attr
         match schema.get_attributes().get(&temp_a) {
             Some(schema_a) => {
+                // Fold the case of the value if the syntax requires it.
+                let value = &schema_a.normalise_value(value.as_str());
                 match schema_a.syntax {
                     SyntaxType::UTF8STRING => Ok(Value::new_utf8(value.clone())),
                     SyntaxType::UTF8STRING_INSENSITIVE => Ok(Value::new_iutf8s(value.as_str())),
@@ -399,6 +1021,16 @@ pub trait QueryServerTransaction {
                     SyntaxType::JSON_FILTER => Value::new_json_filter(value)
                         .ok_or(OperationError::InvalidAttribute("Invalid Filter syntax")),
                     SyntaxType::CREDENTIAL => Err(OperationError::InvalidAttribute("Credentials can not be supplied through modification - please use the IDM api")),
+                    SyntaxType::UINT32 => Value::new_uint32s(value.as_str())
+                        .ok_or(OperationError::InvalidAttribute("Invalid uint32 syntax")),
+                    SyntaxType::BINARY => Value::new_binarys(value.as_str())
+                        .ok_or(OperationError::InvalidAttribute("Invalid base64 binary syntax")),
+                    SyntaxType::DATETIME => Value::new_datetimes(value.as_str())
+                        .ok_or(OperationError::InvalidAttribute("Invalid rfc3339 datetime syntax")),
+                    SyntaxType::API_TOKEN => Err(OperationError::InvalidAttribute("Api tokens can not be supplied through modification - please use the IDM api")),
+                    SyntaxType::RADIUS_UTF8STRING => Err(OperationError::InvalidAttribute("Radius secrets can not be supplied through modification - please use the IDM api")),
+                    SyntaxType::SSHKEY => Value::new_sshkey_str(value.as_str())
+                        .ok_or(OperationError::InvalidAttribute("Invalid ssh public key syntax - expected \"tag: key\"")),
                 }
             }
             None => {
@@ -422,6 +1054,8 @@ pub trait QueryServerTransaction {
         // Lookup the attr
         match schema.get_attributes().get(&temp_a) {
             Some(schema_a) => {
+                // Fold the case of the value if the syntax requires it.
+                let value = &schema_a.normalise_value(value.as_str());
                 match schema_a.syntax {
                     SyntaxType::UTF8STRING => Ok(PartialValue::new_utf8(value.clone())),
                     SyntaxType::UTF8STRING_INSENSITIVE => {
@@ -463,6 +1097,22 @@ pub trait QueryServerTransaction {
                     SyntaxType::JSON_FILTER => PartialValue::new_json_filter(value)
                         .ok_or(OperationError::InvalidAttribute("Invalid Filter syntax")),
                     SyntaxType::CREDENTIAL => Ok(PartialValue::new_credential_tag(value.as_str())),
+                    SyntaxType::UINT32 => PartialValue::new_uint32s(value.as_str())
+                        .ok_or(OperationError::InvalidAttribute("Invalid uint32 syntax")),
+                    SyntaxType::BINARY => PartialValue::new_binarys(value.as_str()).ok_or(
+                        OperationError::InvalidAttribute("Invalid base64 binary syntax"),
+                    ),
+                    SyntaxType::DATETIME => PartialValue::new_datetimes(value.as_str()).ok_or(
+                        OperationError::InvalidAttribute("Invalid rfc3339 datetime syntax"),
+                    ),
+                    SyntaxType::API_TOKEN => PartialValue::new_apitoken_ids(value.as_str()).ok_or(
+                        OperationError::InvalidAttribute("Invalid api token id syntax"),
+                    ),
+                    SyntaxType::RADIUS_UTF8STRING => Ok(PartialValue::new_radius_string()),
+                    // Either the tag, or the whole value as it's shown, may be given.
+                    SyntaxType::SSHKEY => Ok(PartialValue::new_sshkey_tagr(
+                        value.split(':').next().unwrap_or(""),
+                    )),
                 }
             }
             None => {
@@ -503,6 +1153,9 @@ pub struct QueryServerReadTransaction {
     // type, maybe others?
     schema: SchemaReadTransaction,
     accesscontrols: AccessControlsReadTransaction,
+    filter_limits: FilterLimits,
+    filter_limits_anonymous: FilterLimits,
+    page_key: Arc<Vec<u8>>,
 }
 
 // Actually conduct a search request
@@ -526,6 +1179,18 @@ impl QueryServerTransaction for QueryServerReadTransaction {
     fn get_accesscontrols(&self) -> &AccessControlsReadTransaction {
         &self.accesscontrols
     }
+
+    fn get_filter_limits(&self) -> &FilterLimits {
+        &self.filter_limits
+    }
+
+    fn get_filter_limits_anonymous(&self) -> &FilterLimits {
+        &self.filter_limits_anonymous
+    }
+
+    fn get_page_key(&self) -> &[u8] {
+        self.page_key.as_slice()
+    }
 }
 
 impl QueryServerReadTransaction {
@@ -533,7 +1198,7 @@ impl QueryServerReadTransaction {
     // call various functions for validation, including possibly plugin
     // verifications.
     fn verify(&self, au: &mut AuditScope) -> Vec<Result<(), ConsistencyError>> {
-        let mut audit = AuditScope::new("verify");
+        let mut audit = au.child("verify");
 
         // If we fail after backend, we need to return NOW because we can't
         // assert any other faith in the DB states.
@@ -554,27 +1219,67 @@ impl QueryServerReadTransaction {
         }
 
         //  * Indexing (req be + sch )
-        /*
-        idx_errs = self.get_be_txn()
-            .verify_indexes();
+        let idx_errs = self.get_be_txn().verify_indexes(&mut audit);
 
         if idx_errs.len() != 0 {
             au.append_scope(audit);
             return idx_errs;
         }
-         */
 
         // Ok BE passed, lets move on to the content.
         // Most of our checks are in the plugins, so we let them
         // do their job.
 
         // Now, call the plugins verification system.
-        let pl_errs = Plugins::run_verify(&mut audit, self);
+        let mut pl_errs = Plugins::run_verify(&mut audit, self);
+        pl_errs.append(&mut self.verify_entry_schema(&mut audit));
 
         // Finish up ...
         au.append_scope(audit);
         pl_errs
     }
+
+    // Every live entry must still be one the schema would accept, as an entry
+    // is only checked when it's written.
+    fn verify_entry_schema(&self, au: &mut AuditScope) -> Vec<Result<(), ConsistencyError>> {
+        let entries = match self.internal_search(au, filter!(f_pres("class"))) {
+            Ok(entries) => entries,
+            Err(_) => return vec![Err(ConsistencyError::QueryServerSearchFailure)],
+        };
+        let schema = self.get_schema();
+        entries
+            .into_iter()
+            .filter_map(|e| {
+                let id = e.get_id();
+                match e.invalidate().validate(schema) {
+                    Ok(_) => None,
+                    Err(err) => {
+                        audit_log!(au, "entry {} does not conform -> {:?}", id, err);
+                        Some(Err(ConsistencyError::EntrySchemaInvalid(id)))
+                    }
+                }
+            })
+            .collect()
+    }
+
+    // One of the checks of verify, alone. Unlike verify, nothing before it
+    // must pass for it to run.
+    pub fn verify_check(
+        &self,
+        au: &mut AuditScope,
+        check: &VerifyCheck,
+    ) -> Vec<Result<(), ConsistencyError>> {
+        match check {
+            VerifyCheck::Schema => {
+                let mut r = self.get_schema().validate(au);
+                r.append(&mut self.verify_entry_schema(au));
+                r
+            }
+            VerifyCheck::Index => self.get_be_txn().verify_indexes(au),
+            VerifyCheck::Refint => Plugins::run_verify_refint(au, self),
+            VerifyCheck::MemberOf => Plugins::run_verify_memberof(au, self),
+        }
+    }
 }
 
 pub struct QueryServerWriteTransaction<'a> {
@@ -587,6 +1292,16 @@ pub struct QueryServerWriteTransaction<'a> {
     // changing content.
     changed_schema: bool,
     changed_acp: bool,
+    filter_limits: FilterLimits,
+    filter_limits_anonymous: FilterLimits,
+    page_key: Arc<Vec<u8>>,
+    reauth: ReauthPolicy,
+    commit_lock: &'a RwLock<()>,
+    // What derived work this write has deferred, and where it's queued once
+    // the write commits. With no threshold, everything is applied inline.
+    delayed: Vec<DelayedAction>,
+    delayed_queue: &'a DelayedActionQueue,
+    delayed_threshold: Option<usize>,
 }
 
 impl<'a> QueryServerTransaction for QueryServerWriteTransaction<'a> {
@@ -607,6 +1322,18 @@ impl<'a> QueryServerTransaction for QueryServerWriteTransaction<'a> {
     fn get_accesscontrols(&self) -> &AccessControlsWriteTransaction<'a> {
         &self.accesscontrols
     }
+
+    fn get_filter_limits(&self) -> &FilterLimits {
+        &self.filter_limits
+    }
+
+    fn get_filter_limits_anonymous(&self) -> &FilterLimits {
+        &self.filter_limits_anonymous
+    }
+
+    fn get_page_key(&self) -> &[u8] {
+        self.page_key.as_slice()
+    }
 }
 
 #[derive(Clone)]
@@ -615,27 +1342,94 @@ pub struct QueryServer {
     be: Backend,
     schema: Arc<Schema>,
     accesscontrols: Arc<AccessControls>,
+    filter_limits: FilterLimits,
+    filter_limits_anonymous: FilterLimits,
+    page_key: Arc<Vec<u8>>,
+    reauth: ReauthPolicy,
+    // Only used to create the domain info at first start.
+    domain_name: String,
+    // A write holds this to commit, and a read to begin, so a read never
+    // sees part of a commit.
+    commit_lock: Arc<RwLock<()>>,
+    delayed_queue: Arc<DelayedActionQueue>,
+    delayed_threshold: usize,
 }
 
 impl QueryServer {
     pub fn new(be: Backend, schema: Schema) -> Self {
         // log_event!(log, "Starting query worker ...");
+        let delayed_queue = Arc::new(DelayedActionQueue::new(be.get_metrics()));
         QueryServer {
             be: be,
             schema: Arc::new(schema),
             accesscontrols: Arc::new(AccessControls::new()),
+            filter_limits: FilterLimits::new(),
+            filter_limits_anonymous: FilterLimits::new_anonymous(),
+            page_key: Arc::new(QueryServer::new_page_key()),
+            reauth: ReauthPolicy::new(),
+            domain_name: String::from("localhost"),
+            commit_lock: Arc::new(RwLock::new(())),
+            delayed_queue: delayed_queue,
+            delayed_threshold: DELAYED_ACTION_THRESHOLD,
         }
     }
 
+    fn new_page_key() -> Vec<u8> {
+        let mut rng = rand::thread_rng();
+        (0..HMAC_SHA256_LEN).map(|_| rng.gen()).collect()
+    }
+
+    pub fn set_filter_limits(&mut self, limits: FilterLimits, limits_anonymous: FilterLimits) {
+        self.filter_limits = limits;
+        self.filter_limits_anonymous = limits_anonymous;
+    }
+
+    pub fn set_reauth_policy(&mut self, reauth: ReauthPolicy) {
+        self.reauth = reauth;
+    }
+
+    pub fn get_reauth_policy(&self) -> &ReauthPolicy {
+        &self.reauth
+    }
+
+    pub fn set_domain_name(&mut self, domain_name: &str) {
+        self.domain_name = domain_name.to_string();
+    }
+
+    #[cfg(test)]
+    pub(crate) fn set_delayed_threshold(&mut self, threshold: usize) {
+        self.delayed_threshold = threshold;
+    }
+
+    // Any number of reads may run at once, and alongside a write. Each sees
+    // the server as it was when the read began.
     pub fn read(&self) -> QueryServerReadTransaction {
+        let _commit_guard = self.commit_lock.read().expect("commit lock poisoned");
         QueryServerReadTransaction {
             be_txn: self.be.read(),
             schema: self.schema.read(),
             accesscontrols: self.accesscontrols.read(),
+            filter_limits: self.filter_limits.clone(),
+            filter_limits_anonymous: self.filter_limits_anonymous.clone(),
+            page_key: self.page_key.clone(),
         }
     }
 
+    // Only one write may run at a time, as only one may hold the schema. The
+    // schema is taken first, so the backend transaction begins after the
+    // last write committed.
     pub fn write(&self) -> QueryServerWriteTransaction {
+        self.write_threshold(Some(self.delayed_threshold))
+    }
+
+    // A write that applies all the derived work it causes itself, for
+    // applying what was deferred and for repairs.
+    fn write_inline(&self) -> QueryServerWriteTransaction {
+        self.write_threshold(None)
+    }
+
+    fn write_threshold(&self, delayed_threshold: Option<usize>) -> QueryServerWriteTransaction {
+        let schema = self.schema.write();
         QueryServerWriteTransaction {
             // I think this is *not* needed, because commit is mut self which should
             // take ownership of the value, and cause the commit to "only be run
@@ -645,14 +1439,39 @@ impl QueryServer {
             // which today I don't think we have ... yet.
             committed: false,
             be_txn: self.be.write(),
-            schema: self.schema.write(),
+            schema: schema,
             accesscontrols: self.accesscontrols.write(),
             changed_schema: false,
             changed_acp: false,
+            filter_limits: self.filter_limits.clone(),
+            filter_limits_anonymous: self.filter_limits_anonymous.clone(),
+            page_key: self.page_key.clone(),
+            reauth: self.reauth.clone(),
+            commit_lock: &self.commit_lock,
+            delayed: Vec::new(),
+            delayed_queue: &self.delayed_queue,
+            delayed_threshold: delayed_threshold,
         }
     }
 
     pub(crate) fn initialise_helper(&self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        // A newer server may have changed the builtins in ways this one
+        // doesn't know about, so its database is left alone.
+        let level = self.be.write().get_migration_level();
+        if level > SYSTEM_MIGRATION_LEVEL {
+            audit_log!(
+                audit,
+                "database migration level {} is newer than {}",
+                level,
+                SYSTEM_MIGRATION_LEVEL
+            );
+            error!(
+                "The database was migrated by a newer server, to level {}",
+                level
+            );
+            return Err(OperationError::InvalidDBState);
+        }
+
         let mut ts_write_1 = self.write();
         ts_write_1
             .initialise_schema_core(audit)
@@ -666,22 +1485,282 @@ impl QueryServer {
         let mut ts_write_3 = self.write();
         ts_write_3
             .initialise_idm(audit)
-            .and_then(|_| ts_write_3.commit(audit))
+            .and_then(|_| ts_write_3.commit(audit))?;
+
+        let mut ts_write_4 = self.write();
+        ts_write_4
+            .initialise_domain_info(audit, self.domain_name.as_str())
+            .and_then(|_| ts_write_4.commit(audit))?;
+
+        // A database from before there were indexes has none, so they are
+        // built when it's first started with them.
+        let mut ts_write_5 = self.write();
+        ts_write_5
+            .update_indexes(audit)
+            .and_then(|_| ts_write_5.commit(audit))
     }
 
+    // The result is kept with the backend, so a failure is reported by the
+    // readiness check until a later verify passes.
     pub fn verify(&self, au: &mut AuditScope) -> Vec<Result<(), ConsistencyError>> {
-        let r_txn = self.read();
-        r_txn.verify(au)
+        let mut r_txn = self.read();
+        r_txn.be_txn.bypass_cache();
+        let r = r_txn.verify(au);
+        self.be.set_consistent(r.len() == 0);
+        r
     }
-}
-
-impl<'a> QueryServerWriteTransaction<'a> {
-    pub fn create(&mut self, au: &mut AuditScope, ce: &CreateEvent) -> Result<(), OperationError> {
-        // The create event is a raw, read only representation of the request
-        // that was made to us, including information about the identity
-        // performing the request.
-
-        // Log the request
+
+    // Run these checks, or all of verify if none are given. Only a read is
+    // taken, so writes continue while it runs.
+    pub fn verify_checks(
+        &self,
+        au: &mut AuditScope,
+        checks: &[VerifyCheck],
+    ) -> Vec<ConsistencyError> {
+        let r = if checks.is_empty() {
+            self.verify(au)
+        } else {
+            let mut r_txn = self.read();
+            r_txn.be_txn.bypass_cache();
+            checks
+                .iter()
+                .flat_map(|check| r_txn.verify_check(au, check))
+                .collect()
+        };
+        r.into_iter().filter_map(|r| r.err()).collect()
+    }
+
+    // Fix what verify finds that's derived from other state, by deriving it
+    // again, and log each change as it was and as it's made. If anything is
+    // found that can't be repaired, such as a uuid held by two entries, then
+    // nothing is, as what the rest is derived from can't be trusted. With
+    // dry_run the changes are made in a write that is then dropped.
+    pub fn repair_consistency(
+        &self,
+        au: &mut AuditScope,
+        dry_run: bool,
+    ) -> Result<RepairResponse, OperationError> {
+        let found: Vec<ConsistencyError> = {
+            let mut r_txn = self.read();
+            r_txn.be_txn.bypass_cache();
+            let mut r = r_txn.get_be_txn().verify();
+            r.append(&mut r_txn.get_be_txn().verify_indexes(au));
+            r.append(&mut Plugins::run_verify(au, &r_txn));
+            r.into_iter().filter_map(|r| r.err()).collect()
+        };
+        let unsafe_found: Vec<ConsistencyError> =
+            found.iter().filter(|e| !e.repairable()).cloned().collect();
+        if found.is_empty() || !unsafe_found.is_empty() {
+            for e in unsafe_found.iter() {
+                error!("Not repairing, found {} - {}", e, e.guidance());
+            }
+            return Ok(RepairResponse::new(dry_run, Vec::new(), unsafe_found));
+        }
+
+        let mut qs_write = self.write_inline();
+        let mut changes = Vec::new();
+
+        // The searches of what follows need the indexes to be right.
+        let diffs = qs_write.be_txn.index_diffs(au)?;
+        if !diffs.is_empty() {
+            changes.extend(diffs.into_iter().map(|d| {
+                RepairChange::new(
+                    format!("id {}", d.id),
+                    format!("index {}", d.name),
+                    d.held.into_iter().collect(),
+                    d.expect.into_iter().collect(),
+                )
+            }));
+            qs_write.reindex(au)?;
+        }
+
+        changes.append(&mut Plugins::run_remove_dangling(au, &mut qs_write)?);
+
+        let before = qs_write.derived_values(au)?;
+        Plugins::run_regenerate(au, &mut qs_write)?;
+        let after = qs_write.derived_values(au)?;
+        let keys: BTreeSet<&(Uuid, String)> = before.keys().chain(after.keys()).collect();
+        for key in keys.into_iter() {
+            let b = before.get(key).cloned().unwrap_or_else(Vec::new);
+            let a = after.get(key).cloned().unwrap_or_else(Vec::new);
+            if b != a {
+                changes.push(RepairChange::new(
+                    key.0.to_hyphenated_ref().to_string(),
+                    key.1.clone(),
+                    b,
+                    a,
+                ));
+            }
+        }
+
+        for c in changes.iter() {
+            audit_log!(au, "repair -> {}", c);
+            if dry_run {
+                info!("Repair would change {}", c);
+            } else {
+                info!("Repair changed {}", c);
+            }
+        }
+        if dry_run {
+            return Ok(RepairResponse::new(dry_run, changes, Vec::new()));
+        }
+        qs_write.commit(au)?;
+
+        let remaining = self.verify_checks(au, &[]);
+        for e in remaining.iter() {
+            error!("Repair left {} - {}", e, e.guidance());
+        }
+        Ok(RepairResponse::new(dry_run, changes, remaining))
+    }
+
+    // Check what the plugins derive, and if any of it is wrong, generate all
+    // of it again. A crash between a write and the derived work it deferred
+    // leaves this behind, so it's run at each start.
+    pub fn repair(&self, au: &mut AuditScope) -> Result<(), OperationError> {
+        let r = {
+            let mut r_txn = self.read();
+            r_txn.be_txn.bypass_cache();
+            Plugins::run_verify_derived(au, &r_txn)
+        };
+        if r.len() == 0 {
+            return Ok(());
+        }
+        audit_log!(au, "repairing derived state: {:?}", r);
+
+        let mut qs_write = self.write_inline();
+        Plugins::run_regenerate(au, &mut qs_write).and_then(|_| qs_write.commit(au))?;
+
+        let r = Plugins::run_verify_derived(au, &self.read());
+        if r.len() == 0 {
+            Ok(())
+        } else {
+            Err(OperationError::ConsistencyError(r))
+        }
+    }
+
+    pub fn delayed_actions(&self) -> usize {
+        self.delayed_queue.len()
+    }
+
+    // Apply the derived work that writes have deferred, each action in a
+    // transaction of its own. The number of actions applied is returned.
+    pub fn process_delayed_actions(&self, au: &mut AuditScope) -> Result<usize, OperationError> {
+        self.process_delayed_actions_with(au, Plugins::run_delayed_action)
+    }
+
+    // An action that fails is not queued again, as it would only fail again.
+    // What it should have done is left for the consistency pass to find.
+    fn process_delayed_actions_with<F>(
+        &self,
+        au: &mut AuditScope,
+        mut handler: F,
+    ) -> Result<usize, OperationError>
+    where
+        F: FnMut(
+            &mut AuditScope,
+            &mut QueryServerWriteTransaction,
+            &DelayedAction,
+        ) -> Result<(), OperationError>,
+    {
+        let mut applied = 0;
+        while let Some(action) = self.delayed_queue.pop() {
+            audit_log!(au, "applying delayed action {:?}", action);
+            let mut qs_write = self.write_inline();
+            let r = handler(au, &mut qs_write, &action).and_then(|_| qs_write.commit(au));
+            if let Err(e) = r {
+                audit_log!(au, "delayed action {:?} failed -> {:?}", action, e);
+                return Err(e);
+            }
+            applied += 1;
+        }
+        Ok(applied)
+    }
+
+    // Replace the whole database with the content of a backup. What is
+    // derived from other entries is generated again rather than trusted, and
+    // this only succeeds if the restored database is then consistent.
+    pub fn restore(&self, au: &mut AuditScope, src_path: &str) -> Result<(), OperationError> {
+        let be_txn = self.be.write();
+        be_txn.restore(au, src_path).and_then(|_| be_txn.commit())?;
+
+        // An older backup may lack schema and system entries that are now
+        // expected, and loading them also reloads the schema that was
+        // restored.
+        self.initialise_helper(au)?;
+
+        // The restored schema may index other attributes than the indexes
+        // that were rebuilt with the entries.
+        let mut qs_write = self.write_inline();
+        qs_write
+            .reindex(au)
+            .and_then(|_| Plugins::run_regenerate(au, &mut qs_write))
+            .and_then(|_| qs_write.commit(au))?;
+
+        let r = self.verify(au);
+        if r.len() == 0 {
+            Ok(())
+        } else {
+            Err(OperationError::ConsistencyError(r))
+        }
+    }
+
+    // Rebuild the database file. Every writer takes the schema first, so
+    // holding it here keeps them out until this is done.
+    pub fn vacuum(&self, au: &mut AuditScope) -> Result<(), OperationError> {
+        let _schema_write = self.schema.write();
+        self.be.vacuum(au)
+    }
+
+    pub fn checkpoint(&self, au: &mut AuditScope) -> Result<bool, OperationError> {
+        self.be.checkpoint(au)
+    }
+
+    // Whether the server is ready for requests. The domain info is read to
+    // show the database works, but nothing of it is given out.
+    pub fn health(&self, au: &mut AuditScope) -> HealthResponse {
+        let mut checks = BTreeMap::new();
+
+        let database = match self.read().internal_search_uuid(au, &UUID_DOMAIN_INFO) {
+            Ok(_) => HealthCheck::new_healthy(),
+            Err(OperationError::SQLiteError(kind)) => {
+                HealthCheck::new_failed(format!("SQLiteError: {:?}", kind))
+            }
+            Err(e) => HealthCheck::new_failed(e.code().to_string()),
+        };
+        checks.insert("database".to_string(), database);
+
+        let consistency = if self.be.is_consistent() {
+            HealthCheck::new_healthy()
+        } else {
+            HealthCheck::new_failed("the last verification failed".to_string())
+        };
+        checks.insert("consistency".to_string(), consistency);
+
+        HealthResponse::new(checks)
+    }
+}
+
+impl<'a> QueryServerWriteTransaction<'a> {
+    pub fn get_reauth_policy(&self) -> &ReauthPolicy {
+        &self.reauth
+    }
+
+    pub fn create(&mut self, au: &mut AuditScope, ce: &CreateEvent) -> Result<(), OperationError> {
+        self.create_uuids(au, ce).map(|_| ())
+    }
+
+    // As create, but returns the uuids of the new entries in the same order
+    // as the entries of the event.
+    pub fn create_uuids(
+        &mut self,
+        au: &mut AuditScope,
+        ce: &CreateEvent,
+    ) -> Result<Vec<Uuid>, OperationError> {
+        // The create event is a raw, read only representation of the request
+        // that was made to us, including information about the identity
+        // performing the request.
+
+        // Log the request
 
         // TODO #67: Do we need limits on number of creates, or do we constraint
         // based on request size in the frontend?
@@ -709,7 +1788,7 @@ impl<'a> QueryServerWriteTransaction<'a> {
 
         // Do we have rights to perform these creates?
         // create_allow_operation
-        let mut audit_acp = AuditScope::new("access_control_profiles");
+        let mut audit_acp = au.child("access_control_profiles");
         let access = self.get_accesscontrols();
         let acp_res = access.create_allow_operation(&mut audit_acp, ce, &candidates);
         au.append_scope(audit_acp);
@@ -727,7 +1806,7 @@ impl<'a> QueryServerWriteTransaction<'a> {
         // pre-plugins are defined here in their correct order of calling!
         // I have no intent to make these dynamic or configurable.
 
-        let mut audit_plugin_pre_transform = AuditScope::new("plugin_pre_create_transform");
+        let mut audit_plugin_pre_transform = au.child("plugin_pre_create_transform");
         let plug_pre_transform_res = Plugins::run_pre_create_transform(
             &mut audit_plugin_pre_transform,
             self,
@@ -760,13 +1839,13 @@ impl<'a> QueryServerWriteTransaction<'a> {
         // Run any pre-create plugins now with schema validated entries.
         // This is important for normalisation of certain types IE class
         // or attributes for these checks.
-        let mut audit_plugin_pre = AuditScope::new("plugin_pre_create");
+        let mut audit_plugin_pre = au.child("plugin_pre_create");
         let plug_pre_res = Plugins::run_pre_create(&mut audit_plugin_pre, self, &norm_cand, ce);
         au.append_scope(audit_plugin_pre);
 
         let _ = try_audit!(au, plug_pre_res, "Create operation failed (plugin), {:?}");
 
-        let mut audit_be = AuditScope::new("backend_create");
+        let mut audit_be = au.child("backend_create");
         // We may change from ce.entries later to something else?
         let res = self
             .be_txn
@@ -776,29 +1855,36 @@ impl<'a> QueryServerWriteTransaction<'a> {
 
         au.append_scope(audit_be);
 
-        if res.is_err() {
+        if let Err(e) = res {
             // be_txn is dropped, ie aborted here.
-            audit_log!(au, "Create operation failed (backend), {:?}", res);
-            return res;
+            audit_log!(au, "Create operation failed (backend), {:?}", e);
+            return Err(e);
         }
         // Run any post plugins
 
-        let mut audit_plugin_post = AuditScope::new("plugin_post_create");
+        let mut audit_plugin_post = au.child("plugin_post_create");
         let plug_post_res = Plugins::run_post_create(&mut audit_plugin_post, self, &norm_cand, ce);
         au.append_scope(audit_plugin_post);
 
-        if plug_post_res.is_err() {
-            audit_log!(
+        if let Err(e) = plug_post_res {
+            audit_log!(au, "Create operation failed (post plugin), {:?}", e);
+            return Err(e);
+        }
+
+        for e in norm_cand.iter() {
+            let changes = self.audit_entry_changes(e);
+            self.audit_write(
                 au,
-                "Create operation failed (post plugin), {:?}",
-                plug_post_res
-            );
-            return plug_post_res;
+                &ce.event,
+                DbAuditOperationV1::Create,
+                vec![e.get_uuid().clone()],
+                changes,
+            )?;
         }
 
         // We have finished all plugs and now have a successful operation - flag if
         // schema or acp requires reload.
-        self.changed_schema = norm_cand.iter().fold(false, |acc, e| {
+        self.changed_schema = norm_cand.iter().fold(self.changed_schema, |acc, e| {
             if acc {
                 acc
             } else {
@@ -806,7 +1892,7 @@ impl<'a> QueryServerWriteTransaction<'a> {
                     || e.attribute_value_pres("class", &PVCLASS_ATTRIBUTETYPE)
             }
         });
-        self.changed_acp = norm_cand.iter().fold(false, |acc, e| {
+        self.changed_acp = norm_cand.iter().fold(self.changed_acp, |acc, e| {
             if acc {
                 acc
             } else {
@@ -823,10 +1909,19 @@ impl<'a> QueryServerWriteTransaction<'a> {
         // We are complete, finalise logging and return
 
         audit_log!(au, "Create operation success");
-        res
+        Ok(norm_cand.iter().map(|e| e.get_uuid().clone()).collect())
     }
 
     pub fn delete(&mut self, au: &mut AuditScope, de: &DeleteEvent) -> Result<(), OperationError> {
+        self.delete_count(au, de).map(|_| ())
+    }
+
+    // As delete, but returns the number of entries that were deleted.
+    pub fn delete_count(
+        &mut self,
+        au: &mut AuditScope,
+        de: &DeleteEvent,
+    ) -> Result<u64, OperationError> {
         // Do you have access to view all the set members? Reduce based on your
         // read permissions and attrs
         // THIS IS PRETTY COMPLEX SEE THE DESIGN DOC
@@ -850,7 +1945,7 @@ impl<'a> QueryServerWriteTransaction<'a> {
 
         // Apply access controls to reduce the set if required.
         // delete_allow_operation
-        let mut audit_acp = AuditScope::new("access_control_profiles");
+        let mut audit_acp = au.child("access_control_profiles");
         let access = self.get_accesscontrols();
         let acp_res = access.delete_allow_operation(&mut audit_acp, de, &pre_candidates);
         au.append_scope(audit_acp);
@@ -861,13 +1956,16 @@ impl<'a> QueryServerWriteTransaction<'a> {
         // Is the candidate set empty?
         if pre_candidates.len() == 0 {
             audit_log!(au, "delete: no candidates match filter {:?}", de.filter);
+            if de.allow_empty {
+                return Ok(0);
+            }
             return Err(OperationError::NoMatchingEntries);
         };
 
-        let modlist_inv = ModifyList::new_list(vec![Modify::Present(
-            String::from("class"),
-            Value::new_class("recycled"),
-        )]);
+        let modlist_inv = ModifyList::new_list(vec![
+            Modify::Present(String::from("class"), Value::new_class("recycled")),
+            Modify::Present(String::from("deleted_at"), Value::new_datetime(Utc::now())),
+        ]);
 
         let modlist = match modlist_inv.validate(&self.schema) {
             Ok(ml) => ml,
@@ -879,23 +1977,24 @@ impl<'a> QueryServerWriteTransaction<'a> {
             .map(|er| er.clone().invalidate())
             .collect();
 
-        candidates
-            .iter_mut()
-            .for_each(|er| er.apply_modlist(&modlist));
-
         audit_log!(au, "delete: candidates -> {:?}", candidates);
 
-        // Pre delete plugs
-        let mut audit_plugin_pre = AuditScope::new("plugin_pre_delete");
+        // Pre delete plugs. These see the candidates as they are now, before
+        // they are marked as recycled.
+        let mut audit_plugin_pre = au.child("plugin_pre_delete");
         let plug_pre_res =
             Plugins::run_pre_delete(&mut audit_plugin_pre, self, &mut candidates, de);
         au.append_scope(audit_plugin_pre);
 
-        if plug_pre_res.is_err() {
-            audit_log!(au, "Delete operation failed (plugin), {:?}", plug_pre_res);
-            return plug_pre_res;
+        if let Err(e) = plug_pre_res {
+            audit_log!(au, "Delete operation failed (plugin), {:?}", e);
+            return Err(e);
         }
 
+        candidates
+            .iter_mut()
+            .for_each(|er| er.apply_modlist(&modlist));
+
         let res: Result<Vec<Entry<EntryValid, EntryCommitted>>, SchemaError> = candidates
             .into_iter()
             .map(|e| e.validate(&self.schema))
@@ -906,30 +2005,38 @@ impl<'a> QueryServerWriteTransaction<'a> {
             Err(e) => return Err(OperationError::SchemaViolation(e)),
         };
 
-        let mut audit_be = AuditScope::new("backend_modify");
+        let mut audit_be = au.child("backend_modify");
 
         let res = self.be_txn.modify(&mut audit_be, &del_cand);
         au.append_scope(audit_be);
 
-        if res.is_err() {
+        if let Err(e) = res {
             // be_txn is dropped, ie aborted here.
-            audit_log!(au, "Delete operation failed (backend), {:?}", res);
-            return res;
+            audit_log!(au, "Delete operation failed (backend), {:?}", e);
+            return Err(e);
         }
 
         // Post delete plugs
-        let mut audit_plugin_post = AuditScope::new("plugin_post_delete");
+        let mut audit_plugin_post = au.child("plugin_post_delete");
         let plug_post_res = Plugins::run_post_delete(&mut audit_plugin_post, self, &del_cand, de);
         au.append_scope(audit_plugin_post);
 
-        if plug_post_res.is_err() {
-            audit_log!(au, "Delete operation failed (plugin), {:?}", plug_post_res);
-            return plug_post_res;
+        if let Err(e) = plug_post_res {
+            audit_log!(au, "Delete operation failed (plugin), {:?}", e);
+            return Err(e);
         }
 
+        self.audit_write(
+            au,
+            &de.event,
+            DbAuditOperationV1::Delete,
+            del_cand.iter().map(|e| e.get_uuid().clone()).collect(),
+            Vec::new(),
+        )?;
+
         // We have finished all plugs and now have a successful operation - flag if
         // schema or acp requires reload.
-        self.changed_schema = del_cand.iter().fold(false, |acc, e| {
+        self.changed_schema = del_cand.iter().fold(self.changed_schema, |acc, e| {
             if acc {
                 acc
             } else {
@@ -937,7 +2044,7 @@ impl<'a> QueryServerWriteTransaction<'a> {
                     || e.attribute_value_pres("class", &PVCLASS_ATTRIBUTETYPE)
             }
         });
-        self.changed_acp = del_cand.iter().fold(false, |acc, e| {
+        self.changed_acp = del_cand.iter().fold(self.changed_acp, |acc, e| {
             if acc {
                 acc
             } else {
@@ -953,11 +2060,16 @@ impl<'a> QueryServerWriteTransaction<'a> {
 
         // Send result
         audit_log!(au, "Delete operation success");
-        res
+        Ok(del_cand.len() as u64)
     }
 
-    pub fn purge_tombstones(&self, au: &mut AuditScope) -> Result<(), OperationError> {
-        // delete everything that is a tombstone.
+    pub fn purge_tombstones(
+        &self,
+        au: &mut AuditScope,
+        ct: Duration,
+        max_age: Duration,
+    ) -> Result<(), OperationError> {
+        // delete every tombstone that has been kept for longer than max_age.
 
         // Search for tombstones
         let ts =
@@ -966,15 +2078,20 @@ impl<'a> QueryServerWriteTransaction<'a> {
                 Err(e) => return Err(e),
             };
 
+        // TODO #68: Has an appropriate amount of time/condition past (ie replication events?)
+        let cutoff = purge_cutoff(ct, max_age);
+        let ts: Vec<_> = ts
+            .into_iter()
+            .filter(|e| has_aged_out(e, "tombstoned_at", &cutoff))
+            .collect();
+
         if ts.len() == 0 {
-            audit_log!(au, "No Tombstones present - purge operation success");
+            audit_log!(au, "No Tombstones to purge - purge operation success");
             return Ok(());
         }
 
-        // TODO #68: Has an appropriate amount of time/condition past (ie replication events?)
-
         // Delete them
-        let mut audit_be = AuditScope::new("backend_delete");
+        let mut audit_be = au.child("backend_delete");
 
         let res = self
             .be_txn
@@ -989,12 +2106,18 @@ impl<'a> QueryServerWriteTransaction<'a> {
         }
 
         // Send result
-        audit_log!(au, "Tombstone purge operation success");
+        audit_log!(au, "Tombstone purge operation success, {} purged", ts.len());
         res
     }
 
-    pub fn purge_recycled(&self, au: &mut AuditScope) -> Result<(), OperationError> {
-        // Send everything that is recycled to tombstone
+    pub fn purge_recycled(
+        &self,
+        au: &mut AuditScope,
+        ct: Duration,
+        max_age: Duration,
+    ) -> Result<(), OperationError> {
+        // Send everything that has been recycled for longer than max_age
+        // to tombstone
         // Search all recycled
         let rc =
             match self.internal_search(au, filter_all!(f_eq("class", PVCLASS_RECYCLED.clone()))) {
@@ -1002,16 +2125,23 @@ impl<'a> QueryServerWriteTransaction<'a> {
                 Err(e) => return Err(e),
             };
 
+        let cutoff = purge_cutoff(ct, max_age);
+        let rc: Vec<_> = rc
+            .into_iter()
+            .filter(|e| has_aged_out(e, "deleted_at", &cutoff))
+            .collect();
+
         if rc.len() == 0 {
-            audit_log!(au, "No recycled present - purge operation success");
+            audit_log!(au, "No recycled to purge - purge operation success");
             return Ok(());
         }
 
         // Modify them to strip all avas except uuid
-        let tombstone_cand = rc.iter().map(|e| e.to_tombstone()).collect();
+        let tombstoned_at = duration_to_datetime(ct);
+        let tombstone_cand = rc.iter().map(|e| e.to_tombstone(tombstoned_at)).collect();
 
         // Backend Modify
-        let mut audit_be = AuditScope::new("backend_modify");
+        let mut audit_be = au.child("backend_modify");
 
         let res = self.be_txn.modify(&mut audit_be, &tombstone_cand);
         au.append_scope(audit_be);
@@ -1023,41 +2153,213 @@ impl<'a> QueryServerWriteTransaction<'a> {
         }
 
         // return
-        audit_log!(au, "Purge recycled operation success");
+        audit_log!(
+            au,
+            "Purge recycled operation success, {} tombstoned",
+            rc.len()
+        );
         res
     }
 
+    // Trim the changes made longer than max_age ago from the changelog.
+    pub fn purge_changelog(
+        &self,
+        au: &mut AuditScope,
+        ct: Duration,
+        max_age: Duration,
+    ) -> Result<(), OperationError> {
+        let cutoff = ct.checked_sub(max_age).unwrap_or(Duration::from_secs(0));
+        let cutoff = cutoff.as_secs() * 1_000_000_000 + cutoff.subsec_nanos() as u64;
+
+        let mut audit_be = au.child("backend_trim_changelog");
+        let res = self.be_txn.trim_changelog(&mut audit_be, cutoff);
+        au.append_scope(audit_be);
+
+        let trimmed = try_audit!(au, res);
+        audit_log!(au, "Purge changelog operation success, {} trimmed", trimmed);
+        Ok(())
+    }
+
     // Should this take a revive event?
     pub fn revive_recycled(
         &mut self,
         au: &mut AuditScope,
         re: &ReviveRecycledEvent,
-    ) -> Result<(), OperationError> {
+    ) -> Result<Vec<Uuid>, OperationError> {
         // Revive an entry to live. This is a specialised (limited)
         // modify proxy.
         //
         // impersonate modify will require ability to search the class=recycled
         // and the ability to remove that from the object.
 
-        // create the modify
-        // tl;dr, remove the class=recycled
-        let modlist = ModifyList::new_list(vec![Modify::Removed(
-            "class".to_string(),
-            PVCLASS_RECYCLED.clone(),
-        )]);
-
-        let m_valid = try_audit!(
+        // The modify searches the same way, so these are the entries it
+        // will bring back.
+        let revived: Vec<Uuid> = try_audit!(
             au,
-            modlist
+            self.impersonate_search_valid(au, re.filter.clone(), re.filter.clone(), &re.event)
+        )
+        .iter()
+        .map(|e| e.get_uuid().clone())
+        .collect();
+
+        // If nothing matched, it may be that the entry has already aged out
+        // to a tombstone, which can never come back.
+        if revived.len() == 0 {
+            let se = SearchEvent::new_internal(re.filter_tombstone.clone());
+            let mut audit_int = au.child("internal_search");
+            let ts = self.search(&mut audit_int, &se);
+            au.append_scope(audit_int);
+            if try_audit!(au, ts).len() > 0 {
+                audit_log!(au, "revive_recycled: refusing to revive a tombstone");
+                return Err(OperationError::ReviveTombstone);
+            }
+        }
+
+        if revived.len() == 0 {
+            audit_log!(au, "revive_recycled: no recycled entries match");
+            return Err(OperationError::NoMatchingEntries);
+        }
+
+        // Each entry is revived on its own so that a failure can be pinned
+        // to the entry that caused it, such as a name now held by a live
+        // entry. The request is still all or nothing - if any entry fails,
+        // the error lists every failure and the caller must not commit.
+        let mut failed: Vec<(String, OperationError)> = Vec::new();
+        for u in revived.iter() {
+            // create the modify
+            // tl;dr, remove the class=recycled. The references recorded at
+            // delete are only valid while recycled, so they go too - a revive
+            // does not restore them.
+            let modlist = ModifyList::new_list(vec![
+                Modify::Removed("class".to_string(), PVCLASS_RECYCLED.clone()),
+                Modify::Purged("recycled_references".to_string()),
+                Modify::Purged("deleted_at".to_string()),
+            ]);
+
+            let m_valid = try_audit!(
+                au,
+                modlist
+                    .validate(self.get_schema())
+                    .map_err(|e| OperationError::SchemaViolation(e))
+            );
+
+            let f_valid = try_audit!(
+                au,
+                filter_all!(f_and!([
+                    f_eq("class", PVCLASS_RECYCLED.clone()),
+                    f_eq("uuid", PartialValue::new_uuid(u.clone()))
+                ]))
                 .validate(self.get_schema())
                 .map_err(|e| OperationError::SchemaViolation(e))
-        );
+            );
+
+            // Now impersonate the modify
+            let me = ModifyEvent::new_impersonate(&re.event, f_valid.clone(), f_valid, m_valid);
+            let mut audit_int = au.child("impersonate_modify");
+            let res = self.modify_operation(&mut audit_int, &me, DbAuditOperationV1::Revive);
+            au.append_scope(audit_int);
+            if let Err(e) = res {
+                audit_log!(au, "revive_recycled: {} failed -> {:?}", u, e);
+                failed.push((u.to_hyphenated_ref().to_string(), e));
+            }
+        }
 
-        // Now impersonate the modify
-        self.impersonate_modify_valid(au, re.filter.clone(), re.filter.clone(), m_valid, &re.event)
+        if failed.len() == 0 {
+            Ok(revived)
+        } else {
+            Err(OperationError::ReviveFailed(failed))
+        }
+    }
+
+    // Report whether the receiver of the check would be allowed the operation
+    // on each entry matching the filter, and which profiles applied. As with
+    // the real operation, a modify or delete is only allowed on an entry the
+    // receiver could also find with the filter. Nothing is changed.
+    pub fn access_check(
+        &self,
+        au: &mut AuditScope,
+        ace: &AccessCheckEvent,
+    ) -> Result<Vec<AccessCheckEntry>, OperationError> {
+        let se = SearchEvent::new_internal(ace.filter.clone());
+        let mut audit_int = au.child("internal_search");
+        let res = self.search(&mut audit_int, &se);
+        au.append_scope(audit_int);
+        let candidates = try_audit!(au, res);
+
+        let access = self.get_accesscontrols();
+        candidates
+            .into_iter()
+            .map(|e| {
+                let profiles = access.applicable_acp_names(au, &ace.operation, &ace.receiver, &e);
+
+                let se = SearchEvent::new_impersonate(
+                    &ace.receiver,
+                    ace.filter.clone(),
+                    ace.filter_orig.clone(),
+                );
+                let visible = access
+                    .search_filter_entries(au, &se, vec![e.clone()])?
+                    .len()
+                    == 1;
+
+                let allowed = visible
+                    && match (&ace.operation, &ace.modlist) {
+                        (AccessCheckOperation::Modify(_), Some(modlist)) => {
+                            let me = ModifyEvent::new_impersonate(
+                                &ace.receiver,
+                                ace.filter.clone(),
+                                ace.filter_orig.clone(),
+                                modlist.clone(),
+                            );
+                            access.modify_allow_operation(au, &me, &vec![e.clone()])?
+                        }
+                        (AccessCheckOperation::Delete, _) => {
+                            let de = DeleteEvent::new_impersonate(
+                                &ace.receiver,
+                                ace.filter.clone(),
+                                ace.filter_orig.clone(),
+                            );
+                            access.delete_allow_operation(au, &de, &vec![e.clone()])?
+                        }
+                        _ => true,
+                    };
+
+                audit_log!(
+                    au,
+                    "access_check: {:?} allowed {} by {:?}",
+                    e.get_uuid(),
+                    allowed,
+                    profiles
+                );
+                Ok(AccessCheckEntry {
+                    uuid: e.get_uuid().to_hyphenated_ref().to_string(),
+                    allowed: allowed,
+                    profiles: profiles,
+                })
+            })
+            .collect()
     }
 
     pub fn modify(&mut self, au: &mut AuditScope, me: &ModifyEvent) -> Result<(), OperationError> {
+        self.modify_count(au, me).map(|_| ())
+    }
+
+    // As modify, but returns the number of entries that were modified.
+    pub fn modify_count(
+        &mut self,
+        au: &mut AuditScope,
+        me: &ModifyEvent,
+    ) -> Result<u64, OperationError> {
+        self.modify_operation(au, me, DbAuditOperationV1::Modify)
+    }
+
+    // A revive is a modify, but it's recorded in the audit log as what it is.
+    fn modify_operation(
+        &mut self,
+        au: &mut AuditScope,
+        me: &ModifyEvent,
+        operation: DbAuditOperationV1,
+    ) -> Result<u64, OperationError> {
         // Get the candidates.
         // Modify applies a modlist to a filter, so we need to internal search
         // then apply.
@@ -1098,7 +2400,15 @@ impl<'a> QueryServerWriteTransaction<'a> {
                         "modify: no candidates match filter ... continuing {:?}",
                         me.filter
                     );
-                    return Ok(());
+                    return Ok(0);
+                }
+                _ if me.allow_empty => {
+                    audit_log!(
+                        au,
+                        "modify: no candidates match filter, empty allowed {:?}",
+                        me.filter
+                    );
+                    return Ok(0);
                 }
                 _ => {
                     audit_log!(
@@ -1113,7 +2423,7 @@ impl<'a> QueryServerWriteTransaction<'a> {
 
         // Are we allowed to make the changes we want to?
         // modify_allow_operation
-        let mut audit_acp = AuditScope::new("access_control_profiles");
+        let mut audit_acp = au.child("access_control_profiles");
         let access = self.get_accesscontrols();
         let acp_res = access.modify_allow_operation(&mut audit_acp, me, &pre_candidates);
         au.append_scope(audit_acp);
@@ -1121,6 +2431,16 @@ impl<'a> QueryServerWriteTransaction<'a> {
             return Err(OperationError::AccessDenied);
         }
 
+        // Any assertions are checked against the entries as they are now,
+        // before anything in the modlist is applied.
+        if !pre_candidates
+            .iter()
+            .all(|e| e.modlist_assertions_hold(&me.modlist))
+        {
+            audit_log!(au, "modify: modlist assertion failed");
+            return Err(OperationError::ModifyAssertionFailed);
+        }
+
         // Clone a set of writeables.
         // Apply the modlist -> Remember, we have a set of origs
         // and the new modified ents.
@@ -1136,15 +2456,15 @@ impl<'a> QueryServerWriteTransaction<'a> {
         audit_log!(au, "modify: candidates -> {:?}", candidates);
 
         // Pre mod plugins
-        let mut audit_plugin_pre = AuditScope::new("plugin_pre_modify");
+        let mut audit_plugin_pre = au.child("plugin_pre_modify");
         // We should probably supply the pre-post cands here.
         let plug_pre_res =
             Plugins::run_pre_modify(&mut audit_plugin_pre, self, &mut candidates, me);
         au.append_scope(audit_plugin_pre);
 
-        if plug_pre_res.is_err() {
-            audit_log!(au, "Modify operation failed (plugin), {:?}", plug_pre_res);
-            return plug_pre_res;
+        if let Err(e) = plug_pre_res {
+            audit_log!(au, "Modify operation failed (plugin), {:?}", e);
+            return Err(e);
         }
 
         // NOTE: There is a potential optimisation here, where if
@@ -1165,22 +2485,22 @@ impl<'a> QueryServerWriteTransaction<'a> {
         };
 
         // Backend Modify
-        let mut audit_be = AuditScope::new("backend_modify");
+        let mut audit_be = au.child("backend_modify");
 
         let res = self.be_txn.modify(&mut audit_be, &norm_cand);
         au.append_scope(audit_be);
 
-        if res.is_err() {
+        if let Err(e) = res {
             // be_txn is dropped, ie aborted here.
-            audit_log!(au, "Modify operation failed (backend), {:?}", res);
-            return res;
+            audit_log!(au, "Modify operation failed (backend), {:?}", e);
+            return Err(e);
         }
 
         // Post Plugins
         //
         // memberOf actually wants the pre cand list and the norm_cand list to see what
         // changed. Could be optimised, but this is correct still ...
-        let mut audit_plugin_post = AuditScope::new("plugin_post_modify");
+        let mut audit_plugin_post = au.child("plugin_post_modify");
         let plug_post_res = Plugins::run_post_modify(
             &mut audit_plugin_post,
             self,
@@ -1190,11 +2510,20 @@ impl<'a> QueryServerWriteTransaction<'a> {
         );
         au.append_scope(audit_plugin_post);
 
-        if plug_post_res.is_err() {
-            audit_log!(au, "Modify operation failed (plugin), {:?}", plug_post_res);
-            return plug_post_res;
+        if let Err(e) = plug_post_res {
+            audit_log!(au, "Modify operation failed (plugin), {:?}", e);
+            return Err(e);
         }
 
+        let changes = self.audit_changes(&me.modlist);
+        self.audit_write(
+            au,
+            &me.event,
+            operation,
+            norm_cand.iter().map(|e| e.get_uuid().clone()).collect(),
+            changes,
+        )?;
+
         // We have finished all plugs and now have a successful operation - flag if
         // schema or acp requires reload. Remember, this is a modify, so we need to check
         // pre and post cands.
@@ -1202,7 +2531,7 @@ impl<'a> QueryServerWriteTransaction<'a> {
             norm_cand
                 .iter()
                 .chain(pre_candidates.iter())
-                .fold(false, |acc, e| {
+                .fold(self.changed_schema, |acc, e| {
                     if acc {
                         acc
                     } else {
@@ -1210,16 +2539,17 @@ impl<'a> QueryServerWriteTransaction<'a> {
                             || e.attribute_value_pres("class", &PVCLASS_ATTRIBUTETYPE)
                     }
                 });
-        self.changed_acp = norm_cand
-            .iter()
-            .chain(pre_candidates.iter())
-            .fold(false, |acc, e| {
-                if acc {
-                    acc
-                } else {
-                    e.attribute_value_pres("class", &PVCLASS_ACP)
-                }
-            });
+        self.changed_acp =
+            norm_cand
+                .iter()
+                .chain(pre_candidates.iter())
+                .fold(self.changed_acp, |acc, e| {
+                    if acc {
+                        acc
+                    } else {
+                        e.attribute_value_pres("class", &PVCLASS_ACP)
+                    }
+                });
         audit_log!(
             au,
             "Schema reload: {:?}, ACP reload: {:?}",
@@ -1229,9 +2559,142 @@ impl<'a> QueryServerWriteTransaction<'a> {
 
         // return
         audit_log!(au, "Modify operation success");
+        Ok(norm_cand.len() as u64)
+    }
+
+    // Record a write made by an account in the audit log. Internal writes are
+    // either the consequence of one that's recorded, such as the changes
+    // plugins make to keep references consistent, or made by the server
+    // itself, so they aren't recorded.
+    fn audit_write(
+        &self,
+        au: &mut AuditScope,
+        event: &Event,
+        operation: DbAuditOperationV1,
+        targets: Vec<Uuid>,
+        changes: Vec<DbAuditChangeV1>,
+    ) -> Result<(), OperationError> {
+        let identity = match &event.origin {
+            EventOrigin::User(e) => e.get_uuid().clone(),
+            EventOrigin::Internal => return Ok(()),
+        };
+        self.append_audit_record(au, identity, operation, targets, changes)
+    }
+
+    // Changes made through the admin socket are internal, as nothing
+    // authenticates to it, but unlike the server's own they're recorded, as
+    // made by the local admin.
+    pub fn audit_local_admin(
+        &self,
+        au: &mut AuditScope,
+        operation: DbAuditOperationV1,
+        targets: Vec<Uuid>,
+        changes: Vec<DbAuditChangeV1>,
+    ) -> Result<(), OperationError> {
+        self.append_audit_record(au, UUID_LOCAL_ADMIN.clone(), operation, targets, changes)
+    }
+
+    fn append_audit_record(
+        &self,
+        au: &mut AuditScope,
+        identity: Uuid,
+        operation: DbAuditOperationV1,
+        targets: Vec<Uuid>,
+        changes: Vec<DbAuditChangeV1>,
+    ) -> Result<(), OperationError> {
+        let record = DbAuditRecord::V1(DbAuditRecordV1 {
+            time: Utc::now().timestamp(),
+            eventid: au.eventid().clone(),
+            identity: identity,
+            operation: operation,
+            targets: targets,
+            changes: changes,
+        });
+
+        let mut audit_be = au.child("backend_append_audit");
+        let res = self.be_txn.append_audit(&mut audit_be, &record);
+        au.append_scope(audit_be);
         res
     }
 
+    // The value as it's written to the audit log, which is never the value of
+    // an attribute the schema marks as secret.
+    fn audit_value(&self, attr: &str, v: String) -> String {
+        if self.schema.is_secret(attr) {
+            AUDIT_REDACTED.to_string()
+        } else {
+            v
+        }
+    }
+
+    fn audit_changes(&self, modlist: &ModifyList<ModifyValid>) -> Vec<DbAuditChangeV1> {
+        modlist
+            .iter()
+            .map(|m| match m {
+                Modify::Present(a, v) => DbAuditChangeV1::Present(
+                    a.clone(),
+                    self.audit_value(a, v.to_proto_string_clone()),
+                ),
+                Modify::Removed(a, pv) => DbAuditChangeV1::Removed(
+                    a.clone(),
+                    self.audit_value(a, pv.to_proto_string_clone()),
+                ),
+                Modify::Purged(a) => DbAuditChangeV1::Purged(a.clone()),
+                Modify::Assert(a, pv) => DbAuditChangeV1::Assert(
+                    a.clone(),
+                    self.audit_value(a, pv.to_proto_string_clone()),
+                ),
+                Modify::AssertMissing(a) => DbAuditChangeV1::AssertMissing(a.clone()),
+                Modify::Set(a, vs) => DbAuditChangeV1::Set(
+                    a.clone(),
+                    vs.iter()
+                        .map(|v| self.audit_value(a, v.to_proto_string_clone()))
+                        .collect(),
+                ),
+            })
+            .collect()
+    }
+
+    // A new entry is recorded as the values it was created with.
+    fn audit_entry_changes(&self, e: &Entry<EntryValid, EntryNew>) -> Vec<DbAuditChangeV1> {
+        e.avas()
+            .flat_map(|(a, vs)| {
+                vs.iter().map(move |v| {
+                    DbAuditChangeV1::Present(
+                        a.clone(),
+                        self.audit_value(a, v.to_proto_string_clone()),
+                    )
+                })
+            })
+            .collect()
+    }
+
+    // Apply each modification of the batch in order, returning the number of
+    // entries each one changed. Nothing is applied unless the caller commits,
+    // so a failure at any point leaves the transaction to be aborted, and the
+    // error records which item of the batch was at fault.
+    pub fn modify_batch(
+        &mut self,
+        au: &mut AuditScope,
+        mbe: &ModifyBatchEvent,
+    ) -> Result<Vec<u64>, OperationError> {
+        if mbe.modifies.len() == 0 {
+            audit_log!(au, "modify_batch: empty batch request");
+            return Err(OperationError::EmptyRequest);
+        }
+
+        mbe.modifies
+            .iter()
+            .enumerate()
+            .map(|(i, me)| {
+                self.modify_count(au, me).map_err(|e| {
+                    audit_log!(au, "modify_batch: item {} failed, {:?}", i, e);
+                    OperationError::BatchItemFailed(i as u64, Box::new(e))
+                })
+            })
+            .collect()
+    }
+
     // These are where searches and other actions are actually implemented. This
     // is the "internal" version, where we define the event as being internal
     // only, allowing certain plugin by passes etc.
@@ -1242,7 +2705,7 @@ impl<'a> QueryServerWriteTransaction<'a> {
         entries: Vec<Entry<EntryInvalid, EntryNew>>,
     ) -> Result<(), OperationError> {
         // Start the audit scope
-        let mut audit_int = AuditScope::new("internal_create");
+        let mut audit_int = audit.child("internal_create");
         // Create the CreateEvent
         let ce = CreateEvent::new_internal(entries);
         let res = self.create(&mut audit_int, &ce);
@@ -1258,7 +2721,7 @@ impl<'a> QueryServerWriteTransaction<'a> {
         let f_valid = filter
             .validate(self.get_schema())
             .map_err(|e| OperationError::SchemaViolation(e))?;
-        let mut audit_int = AuditScope::new("internal_delete");
+        let mut audit_int = audit.child("internal_delete");
         let de = DeleteEvent::new_internal(f_valid);
         let res = self.delete(&mut audit_int, &de);
         audit.append_scope(audit_int);
@@ -1277,7 +2740,7 @@ impl<'a> QueryServerWriteTransaction<'a> {
         let m_valid = modlist
             .validate(self.get_schema())
             .map_err(|e| OperationError::SchemaViolation(e))?;
-        let mut audit_int = AuditScope::new("internal_modify");
+        let mut audit_int = audit.child("internal_modify");
         let me = ModifyEvent::new_internal(f_valid, m_valid);
         let res = self.modify(&mut audit_int, &me);
         audit.append_scope(audit_int);
@@ -1292,7 +2755,7 @@ impl<'a> QueryServerWriteTransaction<'a> {
         m_valid: ModifyList<ModifyValid>,
         event: &Event,
     ) -> Result<(), OperationError> {
-        let mut audit_int = AuditScope::new("impersonate_modify");
+        let mut audit_int = audit.child("impersonate_modify");
         let me = ModifyEvent::new_impersonate(event, f_valid, f_intent_valid, m_valid);
         let res = self.modify(&mut audit_int, &me);
         audit.append_scope(audit_int);
@@ -1343,25 +2806,46 @@ impl<'a> QueryServerWriteTransaction<'a> {
         unimplemented!()
     }
 
-    pub fn internal_migrate_or_create_str(
-        &mut self,
+    fn builtin_from_str(
+        &self,
         audit: &mut AuditScope,
         e_str: &str,
-    ) -> Result<(), OperationError> {
-        let res = audit_segment!(audit, || Entry::from_proto_entry_str(audit, e_str, self)
-            .and_then(|e: Entry<EntryInvalid, EntryNew>| {
+    ) -> Result<Entry<EntryValid, EntryNew>, OperationError> {
+        Entry::from_proto_entry_str(audit, e_str, self).and_then(
+            |e: Entry<EntryInvalid, EntryNew>| {
                 let schema = self.get_schema();
                 e.validate(schema)
                     .map_err(|e| OperationError::SchemaViolation(e))
-            })
-            .and_then(
-                |e: Entry<EntryValid, EntryNew>| self.internal_migrate_or_create(audit, e)
-            ));
+            },
+        )
+    }
+
+    pub fn internal_migrate_or_create_str(
+        &mut self,
+        audit: &mut AuditScope,
+        e_str: &str,
+    ) -> Result<(), OperationError> {
+        let res = audit_segment!(audit, || self.builtin_from_str(audit, e_str).and_then(
+            |e: Entry<EntryValid, EntryNew>| self.internal_migrate_or_create(audit, e)
+        ));
         audit_log!(audit, "internal_migrate_or_create_str -> result {:?}", res);
         assert!(res.is_ok());
         res
     }
 
+    pub fn internal_repair_or_create_str(
+        &mut self,
+        audit: &mut AuditScope,
+        e_str: &str,
+    ) -> Result<(), OperationError> {
+        let res = audit_segment!(audit, || self.builtin_from_str(audit, e_str).and_then(
+            |e: Entry<EntryValid, EntryNew>| self.internal_repair_or_create(audit, e)
+        ));
+        audit_log!(audit, "internal_repair_or_create_str -> result {:?}", res);
+        assert!(res.is_ok());
+        res
+    }
+
     pub fn internal_migrate_or_create(
         &mut self,
         audit: &mut AuditScope,
@@ -1376,37 +2860,88 @@ impl<'a> QueryServerWriteTransaction<'a> {
         //
         // NOTE: gen modlist IS schema aware and will handle multivalue
         // correctly!
+        self.internal_builtin_or_create(audit, e, true)
+    }
+
+    // As internal_migrate_or_create, except that an attribute the entry
+    // already has is kept as it is, unless the entry is one of the system
+    // entries that nothing but the server may change.
+    pub fn internal_repair_or_create(
+        &mut self,
+        audit: &mut AuditScope,
+        e: Entry<EntryValid, EntryNew>,
+    ) -> Result<(), OperationError> {
+        self.internal_builtin_or_create(audit, e, false)
+    }
+
+    fn internal_builtin_or_create(
+        &mut self,
+        audit: &mut AuditScope,
+        e: Entry<EntryValid, EntryNew>,
+        update: bool,
+    ) -> Result<(), OperationError> {
         let filt = match e.filter_from_attrs(&vec![String::from("uuid")]) {
             Some(f) => f,
             None => return Err(OperationError::FilterGeneration),
         };
 
-        match self.internal_search(audit, filt.clone()) {
-            Ok(results) => {
-                if results.len() == 0 {
-                    // It does not exist. Create it.
-                    self.internal_create(audit, vec![e.invalidate()])
-                } else if results.len() == 1 {
-                    // If the thing is subset, pass
-                    match e.gen_modlist_assert(&self.schema) {
-                        Ok(modlist) => {
-                            // Apply to &results[0]
-                            audit_log!(audit, "Generated modlist -> {:?}", modlist);
-                            self.internal_modify(audit, filt, modlist)
-                        }
-                        Err(e) => Err(OperationError::SchemaViolation(e)),
-                    }
+        let mut results = self.internal_search(audit, filt.clone())?;
+        let existing = if results.len() == 0 {
+            None
+        } else if results.len() == 1 {
+            self.internal_restore_builtin(audit, &filt, results.remove(0))?
+        } else {
+            return Err(OperationError::InvalidDBState);
+        };
+
+        match existing {
+            // It does not exist. Create it.
+            None => self.internal_create(audit, vec![e.invalidate()]),
+            Some(existing) => {
+                let modlist = if update || existing.attribute_value_pres("class", &PVCLASS_SYSTEM) {
+                    e.gen_modlist_assert(&self.schema)
+                        .map_err(|e| OperationError::SchemaViolation(e))?
                 } else {
-                    Err(OperationError::InvalidDBState)
-                }
-            }
-            Err(e) => {
-                // An error occured. pass it back up.
-                Err(e)
+                    e.gen_modlist_repair(&existing)
+                };
+                audit_log!(audit, "Generated modlist -> {:?}", modlist);
+                self.internal_modify(audit, filt, modlist)
             }
         }
     }
 
+    // A builtin that was deleted is brought back. One that is recycled is
+    // revived with what it had, but a tombstone has nothing left, so it's
+    // removed for the builtin to be created again. This gives the live entry
+    // if there is one.
+    fn internal_restore_builtin(
+        &mut self,
+        audit: &mut AuditScope,
+        filt: &Filter<FilterInvalid>,
+        e: Entry<EntryValid, EntryCommitted>,
+    ) -> Result<Option<Entry<EntryValid, EntryCommitted>>, OperationError> {
+        if e.attribute_value_pres("class", &PVCLASS_TOMBSTONE) {
+            audit_log!(audit, "Removing the tombstone of builtin {}", e.get_uuid());
+            let mut audit_be = audit.child("backend_delete");
+            let res = self.be_txn.delete(&mut audit_be, &vec![e]);
+            audit.append_scope(audit_be);
+            return res.map(|_| None);
+        }
+        if !e.attribute_value_pres("class", &PVCLASS_RECYCLED) {
+            return Ok(Some(e));
+        }
+
+        audit_log!(audit, "Reviving builtin {}", e.get_uuid());
+        let modlist = ModifyList::new_list(vec![
+            Modify::Removed("class".to_string(), PVCLASS_RECYCLED.clone()),
+            Modify::Purged("recycled_references".to_string()),
+            Modify::Purged("deleted_at".to_string()),
+        ]);
+        self.internal_modify(audit, filt.clone(), modlist)?;
+        let mut results = self.internal_search(audit, filt.clone())?;
+        Ok(results.pop())
+    }
+
     pub fn internal_assert_or_create_str(
         &mut self,
         audit: &mut AuditScope,
@@ -1494,12 +3029,34 @@ impl<'a> QueryServerWriteTransaction<'a> {
             JSON_SCHEMA_ATTR_MAIL,
             JSON_SCHEMA_ATTR_SSH_PUBLICKEY,
             JSON_SCHEMA_ATTR_PRIMARY_CREDENTIAL,
+            JSON_SCHEMA_ATTR_ACCOUNT_EXPIRE,
+            JSON_SCHEMA_ATTR_ACCOUNT_VALID_FROM,
+            JSON_SCHEMA_ATTR_AUTH_FAILURES,
+            JSON_SCHEMA_ATTR_ACCOUNT_LOCKED_UNTIL,
+            JSON_SCHEMA_ATTR_API_TOKEN,
+            JSON_SCHEMA_ATTR_RADIUS_SECRET,
+            JSON_SCHEMA_ATTR_GIDNUMBER,
+            JSON_SCHEMA_ATTR_LOGINSHELL,
+            JSON_SCHEMA_ATTR_UNIX_PASSWORD,
+            JSON_SCHEMA_ATTR_SPN,
+            JSON_SCHEMA_ATTR_CLAIM,
+            JSON_SCHEMA_ATTR_CLAIM_GROUP,
+            JSON_SCHEMA_ATTR_CLAIM_REQUIRE_MFA,
+            JSON_SCHEMA_ATTR_CLAIM_LIFETIME,
+            JSON_SCHEMA_ATTR_OAUTH2_REDIRECT_URI,
+            JSON_SCHEMA_ATTR_OAUTH2_SCOPE_MAP,
+            JSON_SCHEMA_ATTR_SCIM_EXTERNAL_ID,
             JSON_SCHEMA_CLASS_PERSON,
             JSON_SCHEMA_CLASS_GROUP,
             JSON_SCHEMA_CLASS_ACCOUNT,
+            JSON_SCHEMA_CLASS_SERVICE_ACCOUNT,
+            JSON_SCHEMA_CLASS_POSIXACCOUNT,
+            JSON_SCHEMA_CLASS_POSIXGROUP,
+            JSON_SCHEMA_CLASS_CLAIM,
+            JSON_SCHEMA_CLASS_OAUTH2_CLIENT,
         ];
 
-        let mut audit_si = AuditScope::new("start_initialise_schema_idm");
+        let mut audit_si = audit.child("start_initialise_schema_idm");
         let r: Result<Vec<()>, _> = idm_schema
             .iter()
             // Each item individually logs it's result
@@ -1511,15 +3068,41 @@ impl<'a> QueryServerWriteTransaction<'a> {
         r.map(|_| ())
     }
 
-    // This function is idempotent
+    fn internal_builtin_or_create_str(
+        &mut self,
+        audit: &mut AuditScope,
+        e_str: &str,
+        update: bool,
+    ) -> Result<(), OperationError> {
+        if update {
+            self.internal_migrate_or_create_str(audit, e_str)
+        } else {
+            self.internal_repair_or_create_str(audit, e_str)
+        }
+    }
+
+    // This function is idempotent. A builtin that is missing is created again,
+    // but one that exists is only brought up to its definition if the database
+    // is below SYSTEM_MIGRATION_LEVEL, which it's then raised to.
     pub fn initialise_idm(&mut self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        let level = self.be_txn.get_migration_level();
+        audit_log!(audit, "migration level {}", level);
+        let update = level < SYSTEM_MIGRATION_LEVEL;
+
         // First, check the system_info object. This stores some server information
         // and details. It's a pretty static thing. Also check anonymous, important to many
         // concepts.
-        let mut audit_an = AuditScope::new("start_system_core_items");
+        let mut audit_an = audit.child("start_system_core_items");
         let res = self
             .internal_assert_or_create_str(&mut audit_an, JSON_SYSTEM_INFO_V1)
-            .and_then(|_| self.internal_migrate_or_create_str(&mut audit_an, JSON_ANONYMOUS_V1));
+            // The config is changed at runtime, so must be migrated rather
+            // than asserted.
+            .and_then(|_| {
+                self.internal_builtin_or_create_str(&mut audit_an, JSON_SYSTEM_CONFIG_V1, update)
+            })
+            .and_then(|_| {
+                self.internal_builtin_or_create_str(&mut audit_an, JSON_ANONYMOUS_V1, update)
+            });
         audit.append_scope(audit_an);
         assert!(res.is_ok());
         if res.is_err() {
@@ -1528,10 +3111,12 @@ impl<'a> QueryServerWriteTransaction<'a> {
 
         // Check the admin object exists (migrations).
         // Create the default idm_admin group.
-        let mut audit_an = AuditScope::new("start_idm_admin_migrations");
+        let mut audit_an = audit.child("start_idm_admin_migrations");
         let res = self
-            .internal_migrate_or_create_str(&mut audit_an, JSON_ADMIN_V1)
-            .and_then(|_| self.internal_migrate_or_create_str(&mut audit_an, JSON_IDM_ADMINS_V1));
+            .internal_builtin_or_create_str(&mut audit_an, JSON_ADMIN_V1, update)
+            .and_then(|_| {
+                self.internal_builtin_or_create_str(&mut audit_an, JSON_IDM_ADMINS_V1, update)
+            });
         audit.append_scope(audit_an);
         assert!(res.is_ok());
         if res.is_err() {
@@ -1541,7 +3126,7 @@ impl<'a> QueryServerWriteTransaction<'a> {
         // Create any system default schema entries.
 
         // Create any system default access profile entries.
-        let mut audit_an = AuditScope::new("start_idm_migrations_internal");
+        let mut audit_an = audit.child("start_idm_migrations_internal");
         let idm_entries = [
             // Builtin groups
             JSON_IDM_PEOPLE_WRITE_PRIV_V1,
@@ -1558,12 +3143,18 @@ impl<'a> QueryServerWriteTransaction<'a> {
             JSON_IDM_ACP_MANAGER_PRIV_V1,
             JSON_IDM_SERVICE_ACCOUNT_CREATE_PRIV_V1,
             JSON_IDM_PERSON_ACCOUNT_CREATE_PRIV_V1,
+            JSON_IDM_ACCOUNT_UNIX_EXTEND_PRIV_V1,
+            JSON_IDM_GROUP_UNIX_EXTEND_PRIV_V1,
+            JSON_IDM_UNIX_AUTH_SERVERS_V1,
+            JSON_IDM_UNLIMITED_SEARCH_PRIV_V1,
+            JSON_IDM_SCIM_PROVISIONERS_V1,
             JSON_IDM_HIGH_PRIVILEGE_V1,
             // Built in access controls.
             JSON_IDM_ADMINS_ACP_RECYCLE_SEARCH_V1,
             JSON_IDM_ADMINS_ACP_REVIVE_V1,
             // JSON_IDM_ADMINS_ACP_MANAGE_V1,
             JSON_IDM_ALL_ACP_READ_V1,
+            JSON_IDM_ALL_ACP_SCHEMA_READ_V1,
             JSON_IDM_SELF_ACP_READ_V1,
             JSON_IDM_ACP_PEOPLE_READ_PRIV_V1,
             JSON_IDM_ACP_PEOPLE_WRITE_PRIV_V1,
@@ -1579,12 +3170,22 @@ impl<'a> QueryServerWriteTransaction<'a> {
             JSON_IDM_ACP_SCHEMA_WRITE_ATTRS_PRIV_V1,
             JSON_IDM_ACP_SCHEMA_WRITE_CLASSES_PRIV_V1,
             JSON_IDM_ACP_ACP_MANAGER_PRIV_V1,
+            JSON_IDM_ACP_SYSTEM_CONFIG_PRIV_V1,
+            JSON_IDM_ACP_ANONYMOUS_READ_V1,
+            JSON_IDM_ACP_SERVICE_ACCOUNT_API_TOKEN_MANAGE_V1,
+            JSON_IDM_ACP_ACCOUNT_UNIX_EXTEND_PRIV_V1,
+            JSON_IDM_ACP_GROUP_UNIX_EXTEND_PRIV_V1,
+            JSON_IDM_ACP_UNIX_READ_V1,
+            JSON_IDM_ACP_DOMAIN_ADMIN_PRIV_V1,
+            JSON_IDM_ACP_CLAIM_MANAGE_PRIV_V1,
+            JSON_IDM_ACP_OAUTH2_MANAGE_PRIV_V1,
+            JSON_IDM_ACP_SCIM_PROVISION_PRIV_V1,
         ];
 
         let res: Result<(), _> = idm_entries
             .iter()
             // Each item individually logs it's result
-            .map(|e_str| self.internal_migrate_or_create_str(&mut audit_an, e_str))
+            .map(|e_str| self.internal_builtin_or_create_str(&mut audit_an, e_str, update))
             .collect();
         audit.append_scope(audit_an);
         assert!(res.is_ok());
@@ -1592,9 +3193,99 @@ impl<'a> QueryServerWriteTransaction<'a> {
             return res;
         }
 
+        if update {
+            self.be_txn
+                .set_migration_level(audit, SYSTEM_MIGRATION_LEVEL)?;
+            audit_log!(audit, "migrated to level {}", SYSTEM_MIGRATION_LEVEL);
+        }
         Ok(())
     }
 
+    // The domain info is created once, at first start, with the domain name
+    // from the configuration and a new domain uuid. After that it's only
+    // changed by admins, so unlike the other builtins it isn't migrated, but
+    // it's still brought back if deleted.
+    pub fn initialise_domain_info(
+        &mut self,
+        audit: &mut AuditScope,
+        domain_name: &str,
+    ) -> Result<(), OperationError> {
+        let filt = filter_all!(f_eq("uuid", PartialValue::new_uuid(*UUID_DOMAIN_INFO)));
+        let mut results = self.internal_search(audit, filt.clone())?;
+        if let Some(e) = results.pop() {
+            if self.internal_restore_builtin(audit, &filt, e)?.is_some() {
+                return Ok(());
+            }
+        }
+
+        let mut pe = ProtoEntry {
+            attrs: BTreeMap::new(),
+        };
+        pe.attrs.insert(
+            "class".to_string(),
+            vec![
+                "object".to_string(),
+                "domain_info".to_string(),
+                "system_undeletable".to_string(),
+            ],
+        );
+        pe.attrs
+            .insert("uuid".to_string(), vec![_UUID_DOMAIN_INFO.to_string()]);
+        pe.attrs.insert(
+            "description".to_string(),
+            vec!["This deployment's domain info.".to_string()],
+        );
+        pe.attrs
+            .insert("domain_name".to_string(), vec![domain_name.to_string()]);
+        pe.attrs.insert(
+            "domain_display_name".to_string(),
+            vec![domain_name.to_string()],
+        );
+        pe.attrs.insert(
+            "domain_uuid".to_string(),
+            vec![Uuid::new_v4().to_hyphenated_ref().to_string()],
+        );
+
+        let mut audit_di = audit.child("start_domain_info");
+        let res = Entry::from_proto_entry(&mut audit_di, &pe, self)
+            .and_then(|e| self.internal_create(&mut audit_di, vec![e]))
+            // Anything created before the domain info, such as the builtin
+            // accounts or everything on a server being upgraded, has no spn.
+            .and_then(|_| {
+                self.internal_modify(
+                    &mut audit_di,
+                    filter!(f_or!([
+                        f_eq("class", PartialValue::new_class("account")),
+                        f_eq("class", PartialValue::new_class("group"))
+                    ])),
+                    ModifyList::new_list(vec![Modify::Purged("spn".to_string())]),
+                )
+            });
+        audit.append_scope(audit_di);
+        res
+    }
+
+    // Rebuild every index that the schema asks for. This gives the number
+    // of keys in each index.
+    pub fn reindex(
+        &mut self,
+        audit: &mut AuditScope,
+    ) -> Result<BTreeMap<String, usize>, OperationError> {
+        // Changes to the schema in this transaction are only seen once it's
+        // reloaded.
+        self.reload_schema(audit)?;
+        let idx = self.schema.get_indexes();
+        self.be_txn.reindex(audit, &idx)
+    }
+
+    // Rebuild the indexes if the schema asks for others than exist. Those
+    // that exist are kept up to date by each write, so this is only needed
+    // when the schema changes.
+    fn update_indexes(&mut self, audit: &mut AuditScope) -> Result<(), OperationError> {
+        let idx = self.schema.get_indexes();
+        self.be_txn.update_indexes(audit, &idx)
+    }
+
     fn reload_schema(&mut self, audit: &mut AuditScope) -> Result<(), OperationError> {
         // supply entries to the writable schema to reload from.
         // find all attributes.
@@ -1636,7 +3327,7 @@ impl<'a> QueryServerWriteTransaction<'a> {
 
     fn reload_accesscontrols(&mut self, audit: &mut AuditScope) -> Result<(), OperationError> {
         // supply entries to the writable access controls to reload from.
-        // This has to be done in FOUR passes - one for each type!
+        // This has to be done in FIVE passes - one for each type!
         //
         // Note, we have to do the search, parse, then submit here, because of the
         // requirement to have the write query server reference in the parse stage - this
@@ -1707,10 +3398,82 @@ impl<'a> QueryServerWriteTransaction<'a> {
         let delete_acps = try_audit!(audit, delete_acps);
 
         try_audit!(audit, self.accesscontrols.update_delete(delete_acps));
+        // Update compare
+        let filt = filter!(f_and!([
+            f_eq("class", PVCLASS_ACP.clone()),
+            f_eq("class", PVCLASS_ACCMP.clone()),
+            f_eq("acp_enable", PVACP_ENABLE_TRUE.clone()),
+        ]));
+
+        let res = try_audit!(audit, self.internal_search(audit, filt));
+        let compare_acps: Result<Vec<_>, _> = res
+            .iter()
+            .map(|e| AccessControlCompare::try_from(audit, self, e))
+            .collect();
+
+        let compare_acps = try_audit!(audit, compare_acps);
+
+        try_audit!(audit, self.accesscontrols.update_compare(compare_acps));
         // Alternately, we just get ACP class, and just let acctrl work it out ...
         Ok(())
     }
 
+    // Whether derived work that changes this many entries should be deferred
+    // until after this write commits.
+    pub(crate) fn should_delay(&self, affected: usize) -> bool {
+        match self.delayed_threshold {
+            Some(t) => affected > t,
+            None => false,
+        }
+    }
+
+    // The values of what the plugins derive, for each entry that has any.
+    fn derived_values(
+        &self,
+        au: &mut AuditScope,
+    ) -> Result<BTreeMap<(Uuid, String), Vec<String>>, OperationError> {
+        let entries = self.internal_search(
+            au,
+            filter!(f_or!([
+                f_pres("memberof"),
+                f_pres("directmemberof"),
+                f_pres("spn")
+            ])),
+        )?;
+        let mut values = BTreeMap::new();
+        for e in entries.iter() {
+            for attr in ["memberof", "directmemberof", "spn"].iter() {
+                if let Some(vs) = e.get_ava(attr) {
+                    values.insert(
+                        (e.get_uuid().clone(), attr.to_string()),
+                        vs.iter().map(|v| v.to_proto_string_clone()).collect(),
+                    );
+                }
+            }
+        }
+        Ok(values)
+    }
+
+    // Write the entries as they are, past the schema and the plugins, to
+    // leave the server in a state that verify must find.
+    #[cfg(test)]
+    pub(crate) unsafe fn backdoor_modify(
+        &self,
+        au: &mut AuditScope,
+        entries: Vec<Entry<EntryInvalid, EntryCommitted>>,
+    ) -> Result<(), OperationError> {
+        let entries = entries
+            .into_iter()
+            .map(|e| e.to_valid_committed())
+            .collect();
+        self.be_txn.modify(au, &entries).map(|_| ())
+    }
+
+    pub(crate) fn delay_action(&mut self, au: &mut AuditScope, action: DelayedAction) {
+        audit_log!(au, "deferring {:?}", action);
+        self.delayed.push(action);
+    }
+
     pub fn commit(mut self, audit: &mut AuditScope) -> Result<(), OperationError> {
         // This could be faster if we cache the set of classes changed
         // in an operation so we can check if we need to do the reload or not
@@ -1718,6 +3481,7 @@ impl<'a> QueryServerWriteTransaction<'a> {
         // Reload the schema from qs.
         if self.changed_schema {
             self.reload_schema(audit)?;
+            self.update_indexes(audit)?;
         }
         // Determine if we need to update access control profiles
         // based on any modifications that have occured.
@@ -1735,6 +3499,14 @@ impl<'a> QueryServerWriteTransaction<'a> {
             accesscontrols,
             changed_schema: _,
             changed_acp: _,
+            filter_limits: _,
+            filter_limits_anonymous: _,
+            page_key: _,
+            reauth: _,
+            commit_lock,
+            delayed,
+            delayed_queue,
+            delayed_threshold: _,
         } = self;
         assert!(!committed);
         // Begin an audit.
@@ -1744,9 +3516,11 @@ impl<'a> QueryServerWriteTransaction<'a> {
         if r.len() == 0 {
             // Schema has been validated, so we can go ahead and commit it with the be
             // because both are consistent.
+            let _commit_guard = commit_lock.write().expect("commit lock poisoned");
             schema
                 .commit()
                 .and_then(|_| accesscontrols.commit().and_then(|_| be_txn.commit()))
+                .map(|_| delayed_queue.push(delayed))
         } else {
             Err(OperationError::ConsistencyError(r))
         }
@@ -1758,14 +3532,39 @@ impl<'a> QueryServerWriteTransaction<'a> {
 
 #[cfg(test)]
 mod tests {
-    use crate::constants::{JSON_ADMIN_V1, UUID_ADMIN};
+    use crate::actors::v1::{CompareMessage, SchemaMessage, SearchMessage};
+    use crate::audit::AuditScope;
+    use crate::be::{Backend, BackendTransaction};
+    use crate::cid::Cid;
+    use crate::constants::{
+        _UUID_IDM_ADMINS, JSON_ADMIN_V1, RECYCLEBIN_MAX_AGE, SYSTEM_MIGRATION_LEVEL, UUID_ADMIN,
+        UUID_ANONYMOUS, UUID_DOMAIN_INFO,
+    };
     use crate::credential::Credential;
-    use crate::entry::{Entry, EntryInvalid, EntryNew};
-    use crate::event::{CreateEvent, DeleteEvent, ModifyEvent, ReviveRecycledEvent, SearchEvent};
+    use crate::delayed::DelayedAction;
+    use crate::entry::{Entry, EntryCommitted, EntryInvalid, EntryNew, EntryValid};
+    use crate::event::{
+        AuditListEvent, ChangesEvent, CompareEvent, CreateEvent, DeleteEvent, Event, ExportEvent,
+        ExportRecord, ModifyBatchEvent, ModifyEvent, ReviveRecycledEvent, SchemaResult,
+        SearchEvent,
+    };
+    use crate::filter::{Filter, FilterInvalid, FilterLimits};
     use crate::modify::{Modify, ModifyList};
-    use crate::server::QueryServerTransaction;
+    use crate::schema::Schema;
+    use crate::server::{QueryServer, QueryServerTransaction, QueryServerWriteTransaction};
     use crate::value::{PartialValue, Value};
-    use kanidm_proto::v1::{OperationError, SchemaError};
+    use kanidm_proto::v1::Entry as ProtoEntry;
+    use kanidm_proto::v1::Filter as ProtoFilter;
+    use kanidm_proto::v1::Modify as ProtoModify;
+    use kanidm_proto::v1::ModifyList as ProtoModifyList;
+    use kanidm_proto::v1::{
+        AuditOperation, ChangesResponse, Claim, CompareRequest, ConsistencyError, HealthCheck,
+        OperationError, SchemaError, SchemaRequest, SearchRequest, SortOrder, UserAuthToken,
+        VerifyCheck, AUDIT_REDACTED,
+    };
+    use rusqlite::NO_PARAMS;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::time::{Duration, SystemTime};
     use uuid::Uuid;
 
     #[test]
@@ -1814,6 +3613,70 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_qs_create_uuids() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+
+            // One entry has its uuid given, the others are generated.
+            let e1: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["testperson1"],
+                    "description": ["testperson1"],
+                    "displayname": ["testperson1"]
+                }
+            }"#,
+            );
+            let e2: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["testperson2"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                    "description": ["testperson2"],
+                    "displayname": ["testperson2"]
+                }
+            }"#,
+            );
+            let e3: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["testperson3"],
+                    "description": ["testperson3"],
+                    "displayname": ["testperson3"]
+                }
+            }"#,
+            );
+
+            let ce = CreateEvent::new_internal(vec![e1, e2, e3]);
+            let uuids = server_txn.create_uuids(audit, &ce).expect("create failure");
+            assert!(uuids.len() == 3);
+            assert!(uuids[1] == Uuid::parse_str("cc8e95b4-c24f-4d68-ba54-8bed76f63930").unwrap());
+
+            for (name, u) in ["testperson1", "testperson2", "testperson3"]
+                .iter()
+                .zip(uuids.iter())
+            {
+                let r = server_txn
+                    .internal_search(audit, filter!(f_eq("name", PartialValue::new_iutf8s(name))))
+                    .expect("search failure");
+                assert!(r.len() == 1);
+                assert!(r[0].get_uuid() == u);
+            }
+
+            assert!(server_txn.commit(audit).is_ok());
+        });
+    }
+
     #[test]
     fn test_qs_init_idempotent_schema_core() {
         run_test!(|server: &QueryServer, audit: &mut AuditScope| {
@@ -2047,6 +3910,101 @@ mod tests {
         })
     }
 
+    #[test]
+    fn test_qs_self_uuid_resolution() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            // Allow everyone to see and change the description of themself.
+            let e_acp_search: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "access_control_profile", "access_control_search"],
+                    "name": ["test_acp_self_search"],
+                    "uuid": ["3bb4d8c6-f5d0-4ab1-ad0e-0e1d5ed8c4ab"],
+                    "acp_enable": ["true"],
+                    "acp_receiver": ["{\"Pres\":\"class\"}"],
+                    "acp_targetscope": ["\"Self\""],
+                    "acp_search_attr": ["uuid", "description"]
+                }
+            }"#,
+            );
+            let e_acp_modify: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "access_control_profile", "access_control_modify"],
+                    "name": ["test_acp_self_modify"],
+                    "uuid": ["0b4dd1f5-a7e1-4bb4-9d86-1b8d2b5f0c36"],
+                    "acp_enable": ["true"],
+                    "acp_receiver": ["{\"Pres\":\"class\"}"],
+                    "acp_targetscope": ["\"Self\""],
+                    "acp_modify_presentattr": ["description"],
+                    "acp_modify_removedattr": ["description"]
+                }
+            }"#,
+            );
+            let mut server_txn = server.write();
+            let ce = CreateEvent::new_internal(vec![e_acp_search, e_acp_modify]);
+            assert!(server_txn.create(audit, &ce).is_ok());
+            assert!(server_txn.commit(audit).is_ok());
+
+            let mut server_txn = server.write();
+            let admin = server_txn
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("failed");
+            let anon = server_txn
+                .internal_search_uuid(audit, &UUID_ANONYMOUS)
+                .expect("failed");
+
+            // A modify of self only affects the authenticated identity.
+            let me_self = unsafe {
+                ModifyEvent::new_impersonate_entry(
+                    admin.clone(),
+                    filter!(f_self()),
+                    ModifyList::new_list(vec![Modify::Present(
+                        "description".to_string(),
+                        Value::new_utf8s("self modified"),
+                    )]),
+                )
+            };
+            assert!(server_txn.modify(audit, &me_self).is_ok());
+
+            let r = server_txn
+                .internal_search(
+                    audit,
+                    filter!(f_eq(
+                        "description",
+                        PartialValue::new_utf8s("self modified")
+                    )),
+                )
+                .expect("search failed");
+            assert!(r.len() == 1);
+            assert!(r[0].get_uuid() == &*UUID_ADMIN);
+
+            // Searching the recycle bin for self resolves, but admin isn't there.
+            let sre_self =
+                unsafe { SearchEvent::new_rec_impersonate_entry(admin, filter!(f_self())) };
+            assert!(server_txn.search(audit, &sre_self).map(|r| r.len()) == Ok(0));
+
+            // Anonymous resolves to itself, which it may see but not delete.
+            let de_anon = unsafe { DeleteEvent::new_impersonate_entry(anon, filter!(f_self())) };
+            assert!(server_txn.delete(audit, &de_anon) == Err(OperationError::AccessDenied));
+
+            // Internal operations have no identity to substitute.
+            let de_int = unsafe { DeleteEvent::new_internal_invalid(filter!(f_self())) };
+            assert!(server_txn.delete(audit, &de_int) == Err(OperationError::FilterUUIDResolution));
+            let me_int = unsafe {
+                ModifyEvent::new_internal_invalid(
+                    filter!(f_self()),
+                    ModifyList::new_list(vec![Modify::Purged("description".to_string())]),
+                )
+            };
+            assert!(server_txn.modify(audit, &me_int) == Err(OperationError::FilterUUIDResolution));
+        })
+    }
+
     #[test]
     fn test_qs_delete() {
         run_test!(|server: &QueryServer, audit: &mut AuditScope| {
@@ -2137,51 +4095,438 @@ mod tests {
     }
 
     #[test]
-    fn test_qs_tombstone() {
+    fn test_qs_modify_delete_count() {
         run_test!(|server: &QueryServer, audit: &mut AuditScope| {
             let mut server_txn = server.write();
-            let admin = server_txn
-                .internal_search_uuid(audit, &UUID_ADMIN)
-                .expect("failed");
-
-            let filt_i_ts = filter_all!(f_eq("class", PartialValue::new_class("tombstone")));
-
-            // Create fake external requests. Probably from admin later
-            // Should we do this with impersonate instead of using the external
-            let me_ts = unsafe {
-                ModifyEvent::new_impersonate_entry(
-                    admin.clone(),
-                    filt_i_ts.clone(),
-                    ModifyList::new_list(vec![Modify::Present(
-                        "class".to_string(),
-                        Value::new_class("tombstone"),
-                    )]),
-                )
-            };
-
-            let de_ts =
-                unsafe { DeleteEvent::new_impersonate_entry(admin.clone(), filt_i_ts.clone()) };
-            let se_ts = unsafe { SearchEvent::new_ext_impersonate_entry(admin, filt_i_ts.clone()) };
 
-            // First, create a tombstone
-            let e_ts: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+            let e1: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
                 r#"{
                 "valid": null,
                 "state": null,
                 "attrs": {
-                    "class": ["tombstone", "object"],
-                    "uuid": ["9557f49c-97a5-4277-a9a5-097d17eb8317"]
+                    "class": ["object", "person"],
+                    "name": ["testperson1"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                    "description": ["testperson"],
+                    "displayname": ["testperson1"]
                 }
             }"#,
             );
 
-            let ce = CreateEvent::new_internal(vec![e_ts]);
-            let cr = server_txn.create(audit, &ce);
-            assert!(cr.is_ok());
-
-            // Can it be seen (external search)
-            let r1 = server_txn.search(audit, &se_ts).expect("search failed");
-            assert!(r1.len() == 0);
+            let e2: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["testperson2"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63932"],
+                    "description": ["testperson"],
+                    "displayname": ["testperson2"]
+                }
+            }"#,
+            );
+
+            let ce = CreateEvent::new_internal(vec![e1, e2]);
+            assert!(server_txn.create(audit, &ce).is_ok());
+
+            let anon = server_txn
+                .internal_search_uuid(audit, &UUID_ANONYMOUS)
+                .expect("failed");
+
+            let f_none = filter!(f_eq("name", PartialValue::new_iutf8s("notaperson")));
+            let f_many = filter!(f_eq("description", PartialValue::new_utf8s("testperson")));
+
+            // Modifying nothing is an error, unless the request allows it.
+            let mut me_empty = unsafe {
+                ModifyEvent::new_impersonate_entry(
+                    anon.clone(),
+                    f_none.clone(),
+                    ModifyList::new_list(vec![Modify::Present(
+                        "description".to_string(),
+                        Value::new_utf8s("anusaosu"),
+                    )]),
+                )
+            };
+            assert!(
+                server_txn.modify_count(audit, &me_empty) == Err(OperationError::NoMatchingEntries)
+            );
+            me_empty.allow_empty = true;
+            assert!(server_txn.modify_count(audit, &me_empty) == Ok(0));
+
+            // Each matching entry is counted.
+            let me_mult = unsafe {
+                ModifyEvent::new_internal_invalid(
+                    f_many.clone(),
+                    ModifyList::new_list(vec![Modify::Present(
+                        "description".to_string(),
+                        Value::new_utf8s("anusaosu"),
+                    )]),
+                )
+            };
+            assert!(server_txn.modify_count(audit, &me_mult) == Ok(2));
+
+            // The same for delete.
+            let mut de_empty = unsafe { DeleteEvent::new_internal_invalid(f_none) };
+            assert!(
+                server_txn.delete_count(audit, &de_empty) == Err(OperationError::NoMatchingEntries)
+            );
+            de_empty.allow_empty = true;
+            assert!(server_txn.delete_count(audit, &de_empty) == Ok(0));
+
+            let de_mult = unsafe { DeleteEvent::new_internal_invalid(f_many) };
+            assert!(server_txn.delete_count(audit, &de_mult) == Ok(2));
+
+            assert!(server_txn.commit(audit).is_ok());
+        })
+    }
+
+    #[test]
+    fn test_qs_modify_batch() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+
+            let names = vec![
+                "testperson1",
+                "testperson2",
+                "testperson3",
+                "testperson4",
+                "testperson5",
+            ];
+            let entries: Vec<Entry<EntryInvalid, EntryNew>> = names
+                .iter()
+                .map(|n| {
+                    let mut e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                        r#"{
+                        "valid": null,
+                        "state": null,
+                        "attrs": {
+                            "class": ["object", "person"],
+                            "description": ["testperson"]
+                        }
+                    }"#,
+                    );
+                    e.add_ava("name", &Value::new_iutf8s(n));
+                    e.add_ava("displayname", &Value::new_utf8s(n));
+                    e
+                })
+                .collect();
+            let ce = CreateEvent::new_internal(entries);
+            assert!(server_txn.create(audit, &ce).is_ok());
+            assert!(server_txn.commit(audit).is_ok());
+
+            // Each change sets a different displayname on a different entry.
+            let change = |name: &str, modify: Modify| unsafe {
+                ModifyEvent::new_internal_invalid(
+                    filter!(f_eq("name", PartialValue::new_iutf8s(name))),
+                    ModifyList::new_list(vec![Modify::Purged("displayname".to_string()), modify]),
+                )
+            };
+            let renamed = |name: &str| {
+                change(
+                    name,
+                    Modify::Present("displayname".to_string(), Value::new_utf8s("renamed")),
+                )
+            };
+
+            // The third change adds a second value to the single value
+            // displayname, so the whole batch must fail on that item.
+            let mbe_inv = ModifyBatchEvent::new_internal(vec![
+                renamed("testperson1"),
+                renamed("testperson2"),
+                unsafe {
+                    ModifyEvent::new_internal_invalid(
+                        filter!(f_eq("name", PartialValue::new_iutf8s("testperson3"))),
+                        ModifyList::new_list(vec![Modify::Present(
+                            "displayname".to_string(),
+                            Value::new_utf8s("renamed"),
+                        )]),
+                    )
+                },
+                renamed("testperson4"),
+                renamed("testperson5"),
+            ]);
+
+            let mut server_txn = server.write();
+            match server_txn.modify_batch(audit, &mbe_inv) {
+                Err(OperationError::BatchItemFailed(2, e)) => match *e {
+                    OperationError::SchemaViolation(_) => {}
+                    e => panic!("unexpected error {:?}", e),
+                },
+                r => panic!("unexpected result {:?}", r),
+            }
+            // The caller never commits a failed batch.
+            drop(server_txn);
+
+            // None of the changes, including those before the failure, were kept.
+            let filt_renamed = filter!(f_eq("displayname", PartialValue::new_utf8s("renamed")));
+            let server_txn = server.read();
+            let r = server_txn
+                .internal_search(audit, filt_renamed.clone())
+                .expect("failed");
+            assert!(r.len() == 0);
+            drop(server_txn);
+
+            // A valid batch applies every change, reporting each count.
+            let mbe = ModifyBatchEvent::new_internal(vec![
+                renamed("testperson1"),
+                renamed("testperson2"),
+                renamed("testperson3"),
+            ]);
+            let mut server_txn = server.write();
+            assert!(server_txn.modify_batch(audit, &mbe) == Ok(vec![1, 1, 1]));
+            assert!(server_txn.commit(audit).is_ok());
+
+            let server_txn = server.read();
+            let r = server_txn
+                .internal_search(audit, filt_renamed)
+                .expect("failed");
+            assert!(r.len() == 3);
+            drop(server_txn);
+
+            // An empty batch is refused.
+            let mut server_txn = server.write();
+            assert!(
+                server_txn.modify_batch(audit, &ModifyBatchEvent::new_internal(vec![]))
+                    == Err(OperationError::EmptyRequest)
+            );
+        })
+    }
+
+    #[test]
+    fn test_qs_modify_assert() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+
+            let e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["testperson1"],
+                    "description": ["old"],
+                    "displayname": ["testperson1"]
+                }
+            }"#,
+            );
+            let ce = CreateEvent::new_internal(vec![e]);
+            assert!(server_txn.create(audit, &ce).is_ok());
+            assert!(server_txn.commit(audit).is_ok());
+
+            let swap = |expect: &str, replace: &str| unsafe {
+                ModifyEvent::new_internal_invalid(
+                    filter!(f_eq("name", PartialValue::new_iutf8s("testperson1"))),
+                    ModifyList::new_list(vec![
+                        Modify::Assert("description".to_string(), PartialValue::new_utf8s(expect)),
+                        Modify::AssertMissing("legalname".to_string()),
+                        Modify::Purged("description".to_string()),
+                        Modify::Present("description".to_string(), Value::new_utf8s(replace)),
+                    ]),
+                )
+            };
+            let filt_desc = |d: &str| filter!(f_eq("description", PartialValue::new_utf8s(d)));
+
+            // The assertion holds, so the whole modlist is applied.
+            let mut server_txn = server.write();
+            assert!(server_txn.modify_count(audit, &swap("old", "new")) == Ok(1));
+            assert!(server_txn.commit(audit).is_ok());
+
+            let server_txn = server.read();
+            let r = server_txn
+                .internal_search(audit, filt_desc("new"))
+                .expect("failed");
+            assert!(r.len() == 1);
+            drop(server_txn);
+
+            // The value is no longer "old", so nothing is changed.
+            let mut server_txn = server.write();
+            assert!(
+                server_txn.modify_count(audit, &swap("old", "newer"))
+                    == Err(OperationError::ModifyAssertionFailed)
+            );
+            drop(server_txn);
+
+            let server_txn = server.read();
+            let r = server_txn
+                .internal_search(audit, filt_desc("new"))
+                .expect("failed");
+            assert!(r.len() == 1);
+            let r = server_txn
+                .internal_search(audit, filt_desc("newer"))
+                .expect("failed");
+            assert!(r.len() == 0);
+            drop(server_txn);
+
+            // An attribute that is present fails an assert missing.
+            let mut server_txn = server.write();
+            let me_present = unsafe {
+                ModifyEvent::new_internal_invalid(
+                    filter!(f_eq("name", PartialValue::new_iutf8s("testperson1"))),
+                    ModifyList::new_list(vec![
+                        Modify::AssertMissing("description".to_string()),
+                        Modify::Purged("description".to_string()),
+                    ]),
+                )
+            };
+            assert!(
+                server_txn.modify_count(audit, &me_present)
+                    == Err(OperationError::ModifyAssertionFailed)
+            );
+        })
+    }
+
+    #[test]
+    fn test_qs_modify_set() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+
+            let person = |name: &str, uuid: &str| {
+                let mut e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                    r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "person"]
+                    }
+                }"#,
+                );
+                e.add_ava("name", &Value::new_iutf8s(name));
+                e.add_ava("displayname", &Value::new_utf8s(name));
+                e.add_ava("uuid", &Value::new_uuids(uuid).expect("invalid uuid"));
+                e
+            };
+            let u1 = "cc8e95b4-c24f-4d68-ba54-8bed76f63931";
+            let u2 = "cc8e95b4-c24f-4d68-ba54-8bed76f63932";
+            let u3 = "cc8e95b4-c24f-4d68-ba54-8bed76f63933";
+
+            let e_group: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "group"],
+                    "name": ["testgroup1"],
+                    "member": [
+                        "cc8e95b4-c24f-4d68-ba54-8bed76f63931",
+                        "cc8e95b4-c24f-4d68-ba54-8bed76f63932"
+                    ]
+                }
+            }"#,
+            );
+            let ce = CreateEvent::new_internal(vec![
+                person("testperson1", u1),
+                person("testperson2", u2),
+                person("testperson3", u3),
+                e_group,
+            ]);
+            assert!(server_txn.create(audit, &ce).is_ok());
+            assert!(server_txn.commit(audit).is_ok());
+
+            // Replace the whole member list in one step.
+            let me_set = unsafe {
+                ModifyEvent::new_internal_invalid(
+                    filter!(f_eq("name", PartialValue::new_iutf8s("testgroup1"))),
+                    modlist!([m_set(
+                        "member",
+                        &[
+                            Value::new_refer_s(u2).expect("invalid uuid"),
+                            Value::new_refer_s(u3).expect("invalid uuid"),
+                        ]
+                    )]),
+                )
+            };
+            let mut server_txn = server.write();
+            assert!(server_txn.modify_count(audit, &me_set) == Ok(1));
+            assert!(server_txn.commit(audit).is_ok());
+
+            let server_txn = server.read();
+            let r = server_txn
+                .internal_search(
+                    audit,
+                    filter!(f_eq("name", PartialValue::new_iutf8s("testgroup1"))),
+                )
+                .expect("failed");
+            assert!(r.len() == 1);
+            let members = r[0].get_ava_reference_uuid("member").expect("no members");
+            assert!(members.len() == 2);
+            assert!(r[0].attribute_value_pres(
+                "member",
+                &PartialValue::new_refer_s(u2).expect("invalid uuid")
+            ));
+            assert!(r[0].attribute_value_pres(
+                "member",
+                &PartialValue::new_refer_s(u3).expect("invalid uuid")
+            ));
+            drop(server_txn);
+
+            // The final value set is checked against the schema, so two values
+            // of a single value attribute are rejected.
+            let me_multi = unsafe {
+                ModifyEvent::new_internal_invalid(
+                    filter!(f_eq("name", PartialValue::new_iutf8s("testperson1"))),
+                    modlist!([m_set(
+                        "displayname",
+                        &[Value::new_utf8s("one"), Value::new_utf8s("two")]
+                    )]),
+                )
+            };
+            let mut server_txn = server.write();
+            assert!(
+                server_txn.modify_count(audit, &me_multi)
+                    == Err(OperationError::SchemaViolation(
+                        SchemaError::InvalidAttributeSyntax
+                    ))
+            );
+        })
+    }
+
+    #[test]
+    fn test_qs_tombstone() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+            let admin = server_txn
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("failed");
+
+            let filt_i_ts = filter_all!(f_eq("class", PartialValue::new_class("tombstone")));
+
+            // Create fake external requests. Probably from admin later
+            // Should we do this with impersonate instead of using the external
+            let me_ts = unsafe {
+                ModifyEvent::new_impersonate_entry(
+                    admin.clone(),
+                    filt_i_ts.clone(),
+                    ModifyList::new_list(vec![Modify::Present(
+                        "class".to_string(),
+                        Value::new_class("tombstone"),
+                    )]),
+                )
+            };
+
+            let de_ts =
+                unsafe { DeleteEvent::new_impersonate_entry(admin.clone(), filt_i_ts.clone()) };
+            let se_ts = unsafe { SearchEvent::new_ext_impersonate_entry(admin, filt_i_ts.clone()) };
+
+            // First, create a tombstone
+            let e_ts: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["tombstone", "object"],
+                    "uuid": ["9557f49c-97a5-4277-a9a5-097d17eb8317"]
+                }
+            }"#,
+            );
+
+            let ce = CreateEvent::new_internal(vec![e_ts]);
+            let cr = server_txn.create(audit, &ce);
+            assert!(cr.is_ok());
+
+            // Can it be seen (external search)
+            let r1 = server_txn.search(audit, &se_ts).expect("search failed");
+            assert!(r1.len() == 0);
 
             // Can it be deleted (external delete)
             // Should be err-no candidates.
@@ -2198,8 +4543,11 @@ mod tests {
                 .expect("internal search failed");
             assert!(r2.len() == 1);
 
-            // Now purge
-            assert!(server_txn.purge_tombstones(audit).is_ok());
+            // Now purge. It was created without a tombstoned_at, so has
+            // aged out regardless of the time.
+            assert!(server_txn
+                .purge_tombstones(audit, Duration::from_secs(0), Duration::from_secs(0))
+                .is_ok());
 
             // Assert it's gone
             // Internal search should not see it.
@@ -2315,7 +4663,9 @@ mod tests {
             assert!(server_txn.revive_recycled(audit, &rre_rc).is_ok());
 
             //  purge to tombstone
-            assert!(server_txn.purge_recycled(audit).is_ok());
+            assert!(server_txn
+                .purge_recycled(audit, Duration::from_secs(0), Duration::from_secs(0))
+                .is_ok());
 
             // Should be no recycled objects.
             let r3 = server_txn
@@ -2339,17 +4689,15 @@ mod tests {
         })
     }
 
-    // The delete test above should be unaffected by recycle anyway
     #[test]
-    fn test_qs_recycle_advanced() {
+    fn test_qs_recycle_lifecycle() {
         run_test!(|server: &QueryServer, audit: &mut AuditScope| {
-            // Create items
             let mut server_txn = server.write();
             let admin = server_txn
                 .internal_search_uuid(audit, &UUID_ADMIN)
                 .expect("failed");
 
-            let e1: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+            let e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
                 r#"{
                 "valid": null,
                 "state": null,
@@ -2362,47 +4710,334 @@ mod tests {
                 }
             }"#,
             );
-            let ce = CreateEvent::new_internal(vec![e1]);
-
-            let cr = server_txn.create(audit, &ce);
-            assert!(cr.is_ok());
-            // Delete and ensure they became recycled.
-            let de_sin = unsafe {
-                DeleteEvent::new_internal_invalid(filter!(f_eq(
-                    "name",
-                    PartialValue::new_iutf8s("testperson1")
-                )))
-            };
-            assert!(server_txn.delete(audit, &de_sin).is_ok());
-            // Can in be seen by special search? (external recycle search)
-            let filt_rc = filter_all!(f_eq("class", PartialValue::new_class("recycled")));
-            let sre_rc = unsafe { SearchEvent::new_rec_impersonate_entry(admin, filt_rc.clone()) };
-            let r2 = server_txn.search(audit, &sre_rc).expect("search failed");
-            assert!(r2.len() == 1);
+            assert!(server_txn.internal_create(audit, vec![e]).is_ok());
+            let filt_uuid = filter_all!(f_eq(
+                "uuid",
+                PartialValue::new_uuids("cc8e95b4-c24f-4d68-ba54-8bed76f63930").unwrap()
+            ));
+            assert!(server_txn.internal_delete(audit, filt_uuid.clone()).is_ok());
 
-            // Create dup uuid (rej)
-            // After a delete -> recycle, create duplicate name etc.
-            let cr = server_txn.create(audit, &ce);
-            assert!(cr.is_err());
+            // The delete is stamped with the real time, so the purges are
+            // driven from there.
+            let ct = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .expect("Clock failure!");
+            let max_age = Duration::from_secs(RECYCLEBIN_MAX_AGE);
+            let ct_aged = ct + max_age + Duration::from_secs(1);
+            let ct_ts_aged = ct_aged + max_age + Duration::from_secs(1);
 
-            assert!(server_txn.commit(audit).is_ok());
-        })
-    }
+            let sre_rc =
+                unsafe { SearchEvent::new_rec_impersonate_entry(admin.clone(), filt_uuid.clone()) };
+            let filt_i_ts = filter_all!(f_eq("class", PartialValue::new_class("tombstone")));
 
-    #[test]
-    fn test_qs_name_to_uuid() {
-        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
-            let mut server_txn = server.write();
+            // Still inside the window, so it stays in the recycle bin.
+            assert!(server_txn.purge_recycled(audit, ct, max_age).is_ok());
+            let r1 = server_txn.search(audit, &sre_rc).expect("search failed");
+            assert!(r1.len() == 1);
 
-            let e1: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
-                r#"{
-                "valid": null,
-                "state": null,
-                "attrs": {
-                    "class": ["object", "person"],
-                    "name": ["testperson1"],
-                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
-                    "description": ["testperson"],
+            // Once it has aged out, it becomes a tombstone, which neither
+            // normal nor recycle bin searches show.
+            assert!(server_txn.purge_recycled(audit, ct_aged, max_age).is_ok());
+            let r2 = server_txn.search(audit, &sre_rc).expect("search failed");
+            assert!(r2.len() == 0);
+            let r3 = server_txn
+                .internal_search(
+                    audit,
+                    filter!(f_eq("name", PartialValue::new_iutf8s("testperson1"))),
+                )
+                .expect("internal search failed");
+            assert!(r3.len() == 0);
+            let r4 = server_txn
+                .internal_search(audit, filt_i_ts.clone())
+                .expect("internal search failed");
+            assert!(r4.len() == 1);
+            assert!(r4[0].get_ava_single("tombstoned_at").is_some());
+
+            // A tombstone can not be revived.
+            let rre = unsafe { ReviveRecycledEvent::new_impersonate_entry(admin, filt_uuid) };
+            assert!(
+                server_txn.revive_recycled(audit, &rre) == Err(OperationError::ReviveTombstone)
+            );
+
+            // The tombstone is kept for its own window from when it was
+            // tombstoned, then removed.
+            assert!(server_txn.purge_tombstones(audit, ct_aged, max_age).is_ok());
+            let r5 = server_txn
+                .internal_search(audit, filt_i_ts.clone())
+                .expect("internal search failed");
+            assert!(r5.len() == 1);
+            assert!(server_txn
+                .purge_tombstones(audit, ct_ts_aged, max_age)
+                .is_ok());
+            let r6 = server_txn
+                .internal_search(audit, filt_i_ts)
+                .expect("internal search failed");
+            assert!(r6.len() == 0);
+
+            assert!(server_txn.commit(audit).is_ok());
+        })
+    }
+
+    #[test]
+    fn test_qs_recycle_revive_partial() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let e_rc = |name: &str, uuid: &str| {
+                let mut e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                    r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "person", "recycled"],
+                        "description": ["testperson"]
+                    }
+                }"#,
+                );
+                e.add_ava("name", &Value::new_iutf8s(name));
+                e.add_ava("displayname", &Value::new_utf8s(name));
+                e.add_ava("uuid", &Value::new_uuids(uuid).expect("invalid uuid"));
+                e
+            };
+            let u2 = "cc8e95b4-c24f-4d68-ba54-8bed76f63932";
+
+            // Three recycled entries, and a live entry that has since taken
+            // the name of one of them.
+            let mut server_txn = server.write();
+            assert!(server_txn
+                .internal_create(
+                    audit,
+                    vec![
+                        e_rc("testperson1", "cc8e95b4-c24f-4d68-ba54-8bed76f63931"),
+                        e_rc("testperson2", u2),
+                        e_rc("testperson3", "cc8e95b4-c24f-4d68-ba54-8bed76f63933"),
+                    ]
+                )
+                .is_ok());
+            let e_live: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["testperson2"],
+                    "description": ["testperson2 mark two"],
+                    "displayname": ["testperson2"]
+                }
+            }"#,
+            );
+            assert!(server_txn.internal_create(audit, vec![e_live]).is_ok());
+            assert!(server_txn.commit(audit).is_ok());
+
+            // Reviving all three fails, naming only the entry that collides.
+            let mut server_txn = server.write();
+            let admin = server_txn
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("failed");
+            let rre = unsafe {
+                ReviveRecycledEvent::new_impersonate_entry(
+                    admin,
+                    filter_all!(f_or!([
+                        f_eq("name", PartialValue::new_iutf8s("testperson1")),
+                        f_eq("name", PartialValue::new_iutf8s("testperson2")),
+                        f_eq("name", PartialValue::new_iutf8s("testperson3"))
+                    ])),
+                )
+            };
+            assert!(
+                server_txn.revive_recycled(audit, &rre)
+                    == Err(OperationError::ReviveFailed(vec![(
+                        u2.to_string(),
+                        OperationError::DuplicateValue("name".to_string())
+                    )]))
+            );
+            // The failed request is not committed, so none were revived.
+            drop(server_txn);
+
+            let server_txn = server.read();
+            let r = server_txn
+                .internal_search(
+                    audit,
+                    filter_all!(f_and!([
+                        f_eq("class", PartialValue::new_class("recycled")),
+                        f_eq("description", PartialValue::new_utf8s("testperson"))
+                    ])),
+                )
+                .expect("internal search failed");
+            assert!(r.len() == 3);
+        })
+    }
+
+    // The delete test above should be unaffected by recycle anyway
+    #[test]
+    fn test_qs_recycle_advanced() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            // Create items
+            let mut server_txn = server.write();
+            let admin = server_txn
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("failed");
+
+            let e1: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["testperson1"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                    "description": ["testperson"],
+                    "displayname": ["testperson1"]
+                }
+            }"#,
+            );
+            let ce = CreateEvent::new_internal(vec![e1]);
+
+            let cr = server_txn.create(audit, &ce);
+            assert!(cr.is_ok());
+            // Delete and ensure they became recycled.
+            let de_sin = unsafe {
+                DeleteEvent::new_internal_invalid(filter!(f_eq(
+                    "name",
+                    PartialValue::new_iutf8s("testperson1")
+                )))
+            };
+            assert!(server_txn.delete(audit, &de_sin).is_ok());
+            // Can in be seen by special search? (external recycle search)
+            let filt_rc = filter_all!(f_eq("class", PartialValue::new_class("recycled")));
+            let sre_rc = unsafe { SearchEvent::new_rec_impersonate_entry(admin, filt_rc.clone()) };
+            let r2 = server_txn.search(audit, &sre_rc).expect("search failed");
+            assert!(r2.len() == 1);
+
+            // Create dup uuid (rej)
+            // After a delete -> recycle, create duplicate name etc.
+            let cr = server_txn.create(audit, &ce);
+            assert!(cr.is_err());
+
+            assert!(server_txn.commit(audit).is_ok());
+        })
+    }
+
+    #[test]
+    fn test_qs_recycle_refint() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+            let admin = server_txn
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("failed");
+
+            let e_person: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["testperson1"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                    "description": ["testperson"],
+                    "displayname": ["testperson1"]
+                }
+            }"#,
+            );
+            let e_group = |name: &str, uuid: &str| {
+                let mut e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                    r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "group"],
+                        "member": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"]
+                    }
+                }"#,
+                );
+                e.add_ava("name", &Value::new_iutf8s(name));
+                e.add_ava("uuid", &Value::new_uuids(uuid).expect("invalid uuid"));
+                e
+            };
+            let u_group_a = "d2b496bd-8493-47b7-8142-f568b5cf47ee";
+            let u_group_b = "a3e4ad48-1b35-4c47-9b20-6d9fdcbe5d61";
+            let ce = CreateEvent::new_internal(vec![
+                e_person,
+                e_group("testgroup_a", u_group_a),
+                e_group("testgroup_b", u_group_b),
+            ]);
+            assert!(server_txn.create(audit, &ce).is_ok());
+
+            // Deleting the person removes them from both groups.
+            let de = unsafe {
+                DeleteEvent::new_internal_invalid(filter!(f_eq(
+                    "name",
+                    PartialValue::new_iutf8s("testperson1")
+                )))
+            };
+            assert!(server_txn.delete(audit, &de).is_ok());
+            let r = server_txn
+                .internal_search(
+                    audit,
+                    filter!(f_eq(
+                        "member",
+                        PartialValue::new_refer_s("cc8e95b4-c24f-4d68-ba54-8bed76f63930")
+                            .expect("invalid uuid")
+                    )),
+                )
+                .expect("search failed");
+            assert!(r.len() == 0);
+
+            // The recycled entry records what was removed, and no longer
+            // claims to be a member of anything.
+            let filt_p = filter_all!(f_eq("name", PartialValue::new_iutf8s("testperson1")));
+            let rc = server_txn
+                .internal_search(audit, filt_p.clone())
+                .expect("search failed");
+            assert!(rc.len() == 1);
+            let recorded: BTreeSet<String> = rc[0]
+                .get_ava_set_str("recycled_references")
+                .expect("no recorded references")
+                .into_iter()
+                .map(|s| s.to_string())
+                .collect();
+            assert!(
+                recorded
+                    == btreeset![
+                        format!("member:{}", u_group_a),
+                        format!("member:{}", u_group_b)
+                    ]
+            );
+            assert!(!rc[0].attribute_pres("directmemberof"));
+            assert!(!rc[0].attribute_pres("memberof"));
+            assert!(server_txn.commit(audit).is_ok());
+
+            // Revive does not restore the memberships, and leaves nothing
+            // dangling behind.
+            let mut server_txn = server.write();
+            let rre = unsafe { ReviveRecycledEvent::new_impersonate_entry(admin, filt_p.clone()) };
+            assert!(server_txn.revive_recycled(audit, &rre).is_ok());
+            let r = server_txn
+                .internal_search(
+                    audit,
+                    filter!(f_eq("name", PartialValue::new_iutf8s("testperson1"))),
+                )
+                .expect("search failed");
+            assert!(r.len() == 1);
+            assert!(!r[0].attribute_pres("recycled_references"));
+            assert!(!r[0].attribute_pres("directmemberof"));
+            assert!(!r[0].attribute_pres("memberof"));
+            assert!(server_txn.commit(audit).is_ok());
+        })
+    }
+
+    #[test]
+    fn test_qs_name_to_uuid() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+
+            let e1: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["testperson1"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                    "description": ["testperson"],
                     "displayname": ["testperson1"]
                 }
                 }"#,
@@ -2629,13 +5264,30 @@ mod tests {
 
             // Start a new write
             let mut server_txn = server.write();
-            // delete the class
+            // delete the class - it's still in use, so this is refused
             let de_class = unsafe {
                 DeleteEvent::new_internal_invalid(filter!(f_eq(
                     "classname",
                     PartialValue::new_iutf8s("testclass")
                 )))
             };
+            assert!(
+                server_txn.delete(audit, &de_class)
+                    == Err(OperationError::ConsistencyError(vec![Err(
+                        ConsistencyError::SchemaClassInUse("testclass".to_string())
+                    )]))
+            );
+            // Remove the class from our entry, and then it can go.
+            let me = unsafe {
+                ModifyEvent::new_internal_invalid(
+                    filter!(f_eq("name", PartialValue::new_iutf8s("testobj1"))),
+                    ModifyList::new_list(vec![Modify::Removed(
+                        "class".to_string(),
+                        PartialValue::new_class("testclass"),
+                    )]),
+                )
+            };
+            assert!(server_txn.modify(audit, &me).is_ok());
             assert!(server_txn.delete(audit, &de_class).is_ok());
             // Commit
             server_txn.commit(audit).expect("should not fail");
@@ -2645,14 +5297,6 @@ mod tests {
             // Trying to add now should fail
             let ce_fail = CreateEvent::new_internal(vec![e1.clone()]);
             assert!(server_txn.create(audit, &ce_fail).is_err());
-            // Search our entry
-            let testobj1 = server_txn
-                .internal_search_uuid(
-                    audit,
-                    &Uuid::parse_str("cc8e95b4-c24f-4d68-ba54-8bed76f63930").unwrap(),
-                )
-                .expect("failed");
-            assert!(testobj1.attribute_value_pres("class", &PartialValue::new_iutf8s("testclass")));
 
             // Should still be good
             server_txn.commit(audit).expect("should not fail");
@@ -2716,13 +5360,27 @@ mod tests {
 
             // Start a new write
             let mut server_txn = server.write();
-            // delete the attr
+            // delete the attr - it's still in use, so this is refused
             let de_attr = unsafe {
                 DeleteEvent::new_internal_invalid(filter!(f_eq(
                     "attributename",
                     PartialValue::new_iutf8s("testattr")
                 )))
             };
+            assert!(
+                server_txn.delete(audit, &de_attr)
+                    == Err(OperationError::ConsistencyError(vec![Err(
+                        ConsistencyError::SchemaAttributeInUse("testattr".to_string())
+                    )]))
+            );
+            // Remove the attr from our entry, and then it can go.
+            let me = unsafe {
+                ModifyEvent::new_internal_invalid(
+                    filter!(f_eq("name", PartialValue::new_iutf8s("testobj1"))),
+                    ModifyList::new_list(vec![Modify::Purged("testattr".to_string())]),
+                )
+            };
+            assert!(server_txn.modify(audit, &me).is_ok());
             assert!(server_txn.delete(audit, &de_attr).is_ok());
             // Commit
             server_txn.commit(audit).expect("should not fail");
@@ -2735,15 +5393,6 @@ mod tests {
             // Search our attribute - should FAIL
             let filt = filter!(f_eq("testattr", PartialValue::new_utf8s("test")));
             assert!(server_txn.internal_search(audit, filt).is_err());
-            // Search the entry - the attribute will still be present
-            // even if we can't search on it.
-            let testobj1 = server_txn
-                .internal_search_uuid(
-                    audit,
-                    &Uuid::parse_str("cc8e95b4-c24f-4d68-ba54-8bed76f63930").unwrap(),
-                )
-                .expect("failed");
-            assert!(testobj1.attribute_value_pres("testattr", &PartialValue::new_utf8s("test")));
 
             server_txn.commit(audit).expect("should not fail");
             // Commit.
@@ -2751,60 +5400,2924 @@ mod tests {
     }
 
     #[test]
-    fn test_qs_modify_password_only() {
+    fn test_qs_range_filter() {
         run_test!(|server: &QueryServer, audit: &mut AuditScope| {
-            let e1: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+            // Attribute definition
+            let e_ad: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
                 r#"{
                 "valid": null,
                 "state": null,
                 "attrs": {
-                    "class": ["object", "person", "account"],
-                    "name": ["testperson1"],
-                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
-                    "description": ["testperson"],
-                    "displayname": ["testperson1"]
+                    "class": ["object", "attributetype"],
+                    "attributename": ["testnumber"],
+                    "uuid": ["cfcae205-31c3-484b-8ced-667d1709c5e3"],
+                    "description": ["Test Attribute"],
+                    "multivalue": ["false"],
+                    "unique": ["false"],
+                    "syntax": ["UINT32"]
                 }
             }"#,
             );
+
             let mut server_txn = server.write();
-            // Add the entry. Today we have no syntax to take simple str to a credential
-            // but honestly, that's probably okay :)
-            let ce = CreateEvent::new_internal(vec![e1]);
-            let cr = server_txn.create(audit, &ce);
-            assert!(cr.is_ok());
+            let ce_attr = CreateEvent::new_internal(vec![e_ad]);
+            assert!(server_txn.create(audit, &ce_attr).is_ok());
+            server_txn.commit(audit).expect("should not fail");
 
-            // Build the credential.
-            let cred = Credential::new_password_only("test_password");
-            let v_cred = Value::new_credential("primary", cred);
-            assert!(v_cred.validate());
+            let entries: Vec<Entry<EntryInvalid, EntryNew>> = vec![
+                ("testobj1", "cc8e95b4-c24f-4d68-ba54-8bed76f63930", 1),
+                ("testobj2", "cc8e95b4-c24f-4d68-ba54-8bed76f63931", 2),
+                ("testobj10", "cc8e95b4-c24f-4d68-ba54-8bed76f63932", 10),
+            ]
+            .into_iter()
+            .map(|(name, uuid, n)| {
+                let mut e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                    r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "extensibleobject"]
+                    }
+                }"#,
+                );
+                e.add_ava("name", &Value::new_iutf8s(name));
+                e.add_ava("uuid", &Value::new_uuids(uuid).unwrap());
+                e.add_ava("testnumber", &Value::new_uint32(n));
+                e
+            })
+            .collect();
 
-            // now modify and provide a primary credential.
-            let me_inv_m = unsafe {
-                ModifyEvent::new_internal_invalid(
-                    filter!(f_eq("name", PartialValue::new_iutf8s("testperson1"))),
-                    ModifyList::new_list(vec![Modify::Present(
-                        "primary_credential".to_string(),
-                        v_cred,
-                    )]),
-                )
-            };
-            // go!
-            assert!(server_txn.modify(audit, &me_inv_m).is_ok());
+            let mut server_txn = server.write();
+            let ce = CreateEvent::new_internal(entries);
+            assert!(server_txn.create(audit, &ce).is_ok());
+
+            let ev = Event::from_internal();
+
+            // Numeric ordering - only 10 is >= 10
+            let pf = ProtoFilter::Gte("testnumber".to_string(), "10".to_string());
+            let filt = Filter::from_rw(audit, &ev, &pf, &server_txn).expect("invalid filter");
+            let r = server_txn
+                .internal_search(audit, filt)
+                .expect("search failure");
+            assert!(r.len() == 1);
+
+            let pf = ProtoFilter::Lte("testnumber".to_string(), "2".to_string());
+            let filt = Filter::from_rw(audit, &ev, &pf, &server_txn).expect("invalid filter");
+            let r = server_txn
+                .internal_search(audit, filt)
+                .expect("search failure");
+            assert!(r.len() == 2);
+
+            // Lexical ordering - "testobj10" sorts before "testobj2".
+            let pf = ProtoFilter::And(vec![
+                ProtoFilter::Pres("testnumber".to_string()),
+                ProtoFilter::Gte("name".to_string(), "testobj10".to_string()),
+            ]);
+            let filt = Filter::from_rw(audit, &ev, &pf, &server_txn).expect("invalid filter");
+            let r = server_txn
+                .internal_search(audit, filt)
+                .expect("search failure");
+            assert!(r.len() == 2);
+
+            // A value that can't be parsed for the syntax fails.
+            let pf = ProtoFilter::Gte("testnumber".to_string(), "ten".to_string());
+            let r = Filter::from_rw(audit, &ev, &pf, &server_txn);
+            assert!(
+                r == Err(OperationError::SchemaViolation(
+                    SchemaError::InvalidAttributeSyntax
+                ))
+            );
 
-            // assert it exists and the password checks out
-            let test_ent = server_txn
-                .internal_search_uuid(
-                    audit,
-                    &Uuid::parse_str("cc8e95b4-c24f-4d68-ba54-8bed76f63930").unwrap(),
-                )
-                .expect("failed");
-            // get the primary ava
-            let cred_ref = test_ent
-                .get_ava_single_credential("primary_credential")
-                .expect("Failed");
-            // do a pw check.
-            assert!(cred_ref.verify_password("test_password"));
-        })
+            // As does a syntax that can't be ordered.
+            let pf = ProtoFilter::Gte(
+                "uuid".to_string(),
+                "cc8e95b4-c24f-4d68-ba54-8bed76f63930".to_string(),
+            );
+            let filt = Filter::from_rw(audit, &ev, &pf, &server_txn).expect("invalid filter");
+            assert!(
+                server_txn.internal_search(audit, filt)
+                    == Err(OperationError::SchemaViolation(
+                        SchemaError::InvalidAttributeSyntax
+                    ))
+            );
+
+            server_txn.commit(audit).expect("should not fail");
+        })
+    }
+
+    #[test]
+    fn test_qs_binary_syntax() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let e_ad: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "attributetype"],
+                    "attributename": ["testbinary"],
+                    "uuid": ["cfcae205-31c3-484b-8ced-667d1709c5e4"],
+                    "description": ["Test Attribute"],
+                    "multivalue": ["true"],
+                    "unique": ["false"],
+                    "syntax": ["BINARY"]
+                }
+            }"#,
+            );
+            let mut server_txn = server.write();
+            let ce_attr = CreateEvent::new_internal(vec![e_ad]);
+            assert!(server_txn.create(audit, &ce_attr).is_ok());
+            server_txn.commit(audit).expect("should not fail");
+
+            // Neither of these is valid utf8, so they must survive as bytes.
+            let bin_a: Vec<u8> = vec![0xff, 0xfe, 0x00, 0x80, 0x41];
+            let bin_b: Vec<u8> = vec![0xc3, 0x28, 0xa0, 0xa1];
+            assert!(std::str::from_utf8(&bin_a).is_err());
+            assert!(std::str::from_utf8(&bin_b).is_err());
+            let b64_a = base64::encode(&bin_a);
+            let b64_b = base64::encode(&bin_b);
+
+            // Create through the proto, where the value is base64.
+            let mut pe = ProtoEntry {
+                attrs: BTreeMap::new(),
+            };
+            pe.attrs.insert(
+                "class".to_string(),
+                vec!["object".to_string(), "extensibleobject".to_string()],
+            );
+            pe.attrs
+                .insert("name".to_string(), vec!["testobj1".to_string()]);
+            pe.attrs
+                .insert("testbinary".to_string(), vec![b64_a.clone()]);
+
+            let mut server_txn = server.write();
+            let e = Entry::from_proto_entry(audit, &pe, &server_txn).expect("invalid entry");
+            let ce = CreateEvent::new_internal(vec![e]);
+            assert!(server_txn.create(audit, &ce).is_ok());
+            server_txn.commit(audit).expect("should not fail");
+
+            let ev = Event::from_internal();
+            let filt_a = ProtoFilter::Eq("testbinary".to_string(), b64_a.clone());
+
+            // Search by equality, and the proto form is the same base64 value.
+            let server_txn = server.read();
+            let filt = Filter::from_ro(audit, &ev, &filt_a, &server_txn).expect("invalid filter");
+            let r = server_txn.internal_search(audit, filt).expect("failed");
+            assert!(r.len() == 1);
+            assert!(
+                r[0].attribute_value_pres("testbinary", &PartialValue::new_binary(bin_a.clone()))
+            );
+            let pe_r = r[0]
+                .clone()
+                .reduce_attributes(btreeset!["testbinary"])
+                .into_pe(audit, &server_txn)
+                .expect("failed");
+            assert!(pe_r.attrs.get("testbinary") == Some(&vec![b64_a.clone()]));
+
+            // Only equality and presence may be used on binary values.
+            let filt = Filter::from_ro(
+                audit,
+                &ev,
+                &ProtoFilter::Sub("testbinary".to_string(), b64_a.clone()),
+                &server_txn,
+            )
+            .expect("invalid filter");
+            assert!(
+                server_txn.internal_search(audit, filt)
+                    == Err(OperationError::SchemaViolation(
+                        SchemaError::InvalidAttributeSyntax
+                    ))
+            );
+            let filt = Filter::from_ro(
+                audit,
+                &ev,
+                &ProtoFilter::Gte("testbinary".to_string(), b64_a.clone()),
+                &server_txn,
+            )
+            .expect("invalid filter");
+            assert!(
+                server_txn.internal_search(audit, filt)
+                    == Err(OperationError::SchemaViolation(
+                        SchemaError::InvalidAttributeSyntax
+                    ))
+            );
+            drop(server_txn);
+
+            // Modify through the proto, swapping one binary value for another.
+            let mut server_txn = server.write();
+            let pml = ProtoModifyList::new_list(vec![
+                ProtoModify::Removed("testbinary".to_string(), b64_a.clone()),
+                ProtoModify::Present("testbinary".to_string(), b64_b.clone()),
+            ]);
+            let ml = ModifyList::from(audit, &pml, &server_txn).expect("invalid modlist");
+            let me = unsafe {
+                ModifyEvent::new_internal_invalid(
+                    filter!(f_eq("name", PartialValue::new_iutf8s("testobj1"))),
+                    ml,
+                )
+            };
+            assert!(server_txn.modify(audit, &me).is_ok());
+
+            // A value that isn't base64 is rejected.
+            let pml = ProtoModifyList::new_list(vec![ProtoModify::Present(
+                "testbinary".to_string(),
+                "not base64!".to_string(),
+            )]);
+            assert!(ModifyList::from(audit, &pml, &server_txn).is_err());
+            server_txn.commit(audit).expect("should not fail");
+
+            let server_txn = server.read();
+            let filt = Filter::from_ro(audit, &ev, &filt_a, &server_txn).expect("invalid filter");
+            let r = server_txn.internal_search(audit, filt).expect("failed");
+            assert!(r.len() == 0);
+            let filt = Filter::from_ro(
+                audit,
+                &ev,
+                &ProtoFilter::Eq("testbinary".to_string(), b64_b.clone()),
+                &server_txn,
+            )
+            .expect("invalid filter");
+            let r = server_txn.internal_search(audit, filt).expect("failed");
+            assert!(r.len() == 1);
+            assert!(
+                r[0].get_ava_single("testbinary")
+                    .and_then(|v| v.to_binary())
+                    == Some(bin_b.as_slice())
+            );
+            let pe_r = r[0]
+                .clone()
+                .reduce_attributes(btreeset!["testbinary"])
+                .into_pe(audit, &server_txn)
+                .expect("failed");
+            assert!(pe_r.attrs.get("testbinary") == Some(&vec![b64_b]));
+        })
+    }
+
+    #[test]
+    fn test_qs_schema_extend_lifecycle() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let e_attr = || -> Entry<EntryInvalid, EntryNew> {
+                Entry::unsafe_from_entry_str(
+                    r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "attributetype"],
+                        "attributename": ["employeenumber"],
+                        "uuid": ["5b7e4f0c-6c3d-4d6e-9d2a-2d1c4f7f1a01"],
+                        "description": ["An employee number"],
+                        "multivalue": ["false"],
+                        "unique": ["true"],
+                        "index": ["EQUALITY"],
+                        "syntax": ["UTF8STRING_INSENSITIVE"]
+                    }
+                }"#,
+                )
+            };
+            let e_class: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "classtype"],
+                    "classname": ["employee"],
+                    "uuid": ["5b7e4f0c-6c3d-4d6e-9d2a-2d1c4f7f1a02"],
+                    "description": ["An employee"],
+                    "may": ["employeenumber"]
+                }
+            }"#,
+            );
+            let admin = server
+                .read()
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("failed");
+
+            // Schema admins may add definitions, which are live once committed.
+            let mut server_txn = server.write();
+            let ce = unsafe {
+                CreateEvent::new_impersonate_entry(admin.clone(), vec![e_attr(), e_class])
+            };
+            assert!(server_txn.create(audit, &ce).is_ok());
+            server_txn.commit(audit).expect("should not fail");
+
+            // A second definition of the same name is a duplicate.
+            let mut e_dup_attr = e_attr();
+            e_dup_attr.purge_ava("uuid");
+            let mut server_txn = server.write();
+            let ce = unsafe { CreateEvent::new_impersonate_entry(admin.clone(), vec![e_dup_attr]) };
+            assert!(
+                server_txn.create(audit, &ce)
+                    == Err(OperationError::DuplicateValue("attributename".to_string()))
+            );
+            drop(server_txn);
+
+            // Syntax names must be valid.
+            let e_badsyntax: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "attributetype"],
+                    "attributename": ["badsyntax"],
+                    "description": ["A bad syntax"],
+                    "multivalue": ["false"],
+                    "unique": ["false"],
+                    "syntax": ["NOT_A_SYNTAX"]
+                }
+            }"#,
+            );
+            let mut server_txn = server.write();
+            let ce =
+                unsafe { CreateEvent::new_impersonate_entry(admin.clone(), vec![e_badsyntax]) };
+            assert!(
+                server_txn.create(audit, &ce)
+                    == Err(OperationError::SchemaViolation(
+                        SchemaError::InvalidAttributeSyntax
+                    ))
+            );
+            drop(server_txn);
+
+            // Unique attributes must be indexed for equality, so the schema
+            // can't be reloaded with one that isn't.
+            let e_noindex: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "attributetype"],
+                    "attributename": ["noindex"],
+                    "description": ["A unique attribute without an index"],
+                    "multivalue": ["false"],
+                    "unique": ["true"],
+                    "syntax": ["UTF8STRING"]
+                }
+            }"#,
+            );
+            let mut server_txn = server.write();
+            let ce = unsafe { CreateEvent::new_impersonate_entry(admin.clone(), vec![e_noindex]) };
+            assert!(server_txn.create(audit, &ce).is_ok());
+            assert!(
+                server_txn.commit(audit)
+                    == Err(OperationError::ConsistencyError(vec![Err(
+                        ConsistencyError::SchemaUniqueAttributeNotIndexed("noindex".to_string())
+                    )]))
+            );
+
+            // Use the new attribute and class.
+            let mut e_person: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person", "employee"],
+                    "name": ["testperson"],
+                    "description": ["testperson"],
+                    "displayname": ["testperson"]
+                }
+            }"#,
+            );
+            e_person.add_ava("employeenumber", &Value::new_iutf8s("E1234"));
+            let mut server_txn = server.write();
+            let ce = CreateEvent::new_internal(vec![e_person]);
+            assert!(server_txn.create(audit, &ce).is_ok());
+            let r = server_txn
+                .internal_search(
+                    audit,
+                    filter!(f_eq("employeenumber", PartialValue::new_iutf8s("e1234"))),
+                )
+                .expect("search failure");
+            assert!(r.len() == 1);
+            server_txn.commit(audit).expect("should not fail");
+
+            // The new attribute is unique, so another entry can't take the
+            // same value.
+            let mut e_dup: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person", "employee"],
+                    "name": ["testperson2"],
+                    "description": ["testperson2"],
+                    "displayname": ["testperson2"]
+                }
+            }"#,
+            );
+            e_dup.add_ava("employeenumber", &Value::new_iutf8s("E1234"));
+            let mut server_txn = server.write();
+            let ce = CreateEvent::new_internal(vec![e_dup]);
+            assert!(
+                server_txn.create(audit, &ce)
+                    == Err(OperationError::DuplicateValue("employeenumber".to_string()))
+            );
+            drop(server_txn);
+
+            // Definitions still in use can't be deleted.
+            let de_attr = unsafe {
+                DeleteEvent::new_impersonate_entry(
+                    admin.clone(),
+                    filter!(f_eq(
+                        "attributename",
+                        PartialValue::new_iutf8s("employeenumber")
+                    )),
+                )
+            };
+            let de_class = unsafe {
+                DeleteEvent::new_impersonate_entry(
+                    admin.clone(),
+                    filter!(f_eq("classname", PartialValue::new_iutf8s("employee"))),
+                )
+            };
+            let mut server_txn = server.write();
+            assert!(
+                server_txn.delete(audit, &de_attr)
+                    == Err(OperationError::ConsistencyError(vec![Err(
+                        ConsistencyError::SchemaAttributeInUse("employeenumber".to_string())
+                    )]))
+            );
+            drop(server_txn);
+
+            // Once unused, the class and then the attribute can be removed
+            // in the same transaction.
+            let mut server_txn = server.write();
+            let me = unsafe {
+                ModifyEvent::new_internal_invalid(
+                    filter!(f_eq("name", PartialValue::new_iutf8s("testperson"))),
+                    ModifyList::new_list(vec![
+                        Modify::Purged("employeenumber".to_string()),
+                        Modify::Removed("class".to_string(), PartialValue::new_class("employee")),
+                    ]),
+                )
+            };
+            assert!(server_txn.modify(audit, &me).is_ok());
+            assert!(server_txn.delete(audit, &de_class).is_ok());
+            assert!(server_txn.delete(audit, &de_attr).is_ok());
+            server_txn.commit(audit).expect("should not fail");
+
+            let server_txn = server.read();
+            assert!(server_txn
+                .internal_search(
+                    audit,
+                    filter!(f_eq("employeenumber", PartialValue::new_iutf8s("e1234"))),
+                )
+                .is_err());
+        })
+    }
+
+    #[test]
+    fn test_qs_reference_syntax() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            // Referential integrity follows the syntax, so a new reference
+            // attribute is maintained just like member.
+            let e_ad: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "attributetype"],
+                    "attributename": ["testref"],
+                    "uuid": ["cfcae205-31c3-484b-8ced-667d1709c5e5"],
+                    "description": ["Test Attribute"],
+                    "multivalue": ["true"],
+                    "unique": ["false"],
+                    "syntax": ["REFERENCE_UUID"]
+                }
+            }"#,
+            );
+            let mut server_txn = server.write();
+            let ce_attr = CreateEvent::new_internal(vec![e_ad]);
+            assert!(server_txn.create(audit, &ce_attr).is_ok());
+            server_txn.commit(audit).expect("should not fail");
+
+            let referrer = |name: &str, attr: &str, target: &str| {
+                let mut e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                    r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "group", "extensibleobject"]
+                    }
+                }"#,
+                );
+                e.add_ava("name", &Value::new_iutf8s(name));
+                e.add_ava(attr, &Value::new_refer_s(target).expect("invalid uuid"));
+                e
+            };
+            let e_target: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "group"],
+                    "name": ["testgroup_a"],
+                    "uuid": ["d2b496bd-8493-47b7-8142-f568b5cf47ee"]
+                }
+            }"#,
+            );
+            let u_target = "d2b496bd-8493-47b7-8142-f568b5cf47ee";
+            let u_missing = "ca85168c-91b7-49a8-b7bb-a3d5bb40e97e";
+
+            // A reference to an entry that doesn't exist is refused.
+            for attr in &["member", "testref"] {
+                let mut server_txn = server.write();
+                let ce = CreateEvent::new_internal(vec![referrer("testgroup_b", attr, u_missing)]);
+                assert!(server_txn.create(audit, &ce) == Err(OperationError::Plugin));
+                drop(server_txn);
+            }
+
+            let mut server_txn = server.write();
+            let ce = CreateEvent::new_internal(vec![
+                e_target,
+                referrer("testgroup_b", "member", u_target),
+                referrer("testgroup_c", "testref", u_target),
+            ]);
+            assert!(server_txn.create(audit, &ce).is_ok());
+            server_txn.commit(audit).expect("should not fail");
+
+            // Equality on a reference may be given the name of the entry.
+            let ev = Event::from_internal();
+            let mut server_txn = server.write();
+            for (attr, name) in &[("member", "testgroup_b"), ("testref", "testgroup_c")] {
+                let pf = ProtoFilter::Eq(attr.to_string(), "testgroup_a".to_string());
+                let filt = Filter::from_rw(audit, &ev, &pf, &server_txn).expect("invalid filter");
+                let r = server_txn.internal_search(audit, filt).expect("failed");
+                assert!(r.len() == 1);
+                assert!(r[0].get_ava_single_string("name") == Some(name.to_string()));
+            }
+
+            // Deleting the target removes every reference to it.
+            let de = unsafe {
+                DeleteEvent::new_internal_invalid(filter!(f_eq(
+                    "name",
+                    PartialValue::new_iutf8s("testgroup_a")
+                )))
+            };
+            assert!(server_txn.delete(audit, &de).is_ok());
+            let pv_target = PartialValue::new_refer_s(u_target).expect("invalid uuid");
+            let r = server_txn
+                .internal_search(
+                    audit,
+                    filter!(f_or!([
+                        f_eq("member", pv_target.clone()),
+                        f_eq("testref", pv_target)
+                    ])),
+                )
+                .expect("failed");
+            assert!(r.len() == 0);
+            server_txn.commit(audit).expect("should not fail");
+        })
+    }
+
+    #[test]
+    fn test_qs_datetime_syntax() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let account = |name: &str, expire: &str| {
+                let mut pe = ProtoEntry {
+                    attrs: BTreeMap::new(),
+                };
+                pe.attrs.insert(
+                    "class".to_string(),
+                    vec!["object".to_string(), "account".to_string()],
+                );
+                pe.attrs.insert("name".to_string(), vec![name.to_string()]);
+                pe.attrs
+                    .insert("displayname".to_string(), vec![name.to_string()]);
+                pe.attrs
+                    .insert("account_expire".to_string(), vec![expire.to_string()]);
+                pe
+            };
+
+            // Lexically the first sorts last, but it is the earliest in utc.
+            let pes = vec![
+                account("testaccount1", "2020-01-01T09:00:00+10:00"),
+                account("testaccount2", "2020-01-01T00:00:00Z"),
+                account("testaccount3", "2020-01-01T01:00:00+00:00"),
+            ];
+
+            let mut server_txn = server.write();
+            let entries: Vec<_> = pes
+                .iter()
+                .map(|pe| Entry::from_proto_entry(audit, pe, &server_txn).expect("invalid entry"))
+                .collect();
+            let ce = CreateEvent::new_internal(entries);
+            assert!(server_txn.create(audit, &ce).is_ok());
+
+            // A value that isn't rfc3339 is rejected.
+            let pe = account("testaccount4", "2020-01-01 00:00");
+            assert!(Entry::from_proto_entry(audit, &pe, &server_txn).is_err());
+            // As is a value of the wrong syntax internally.
+            let mut e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "account"],
+                    "name": ["testaccount4"],
+                    "displayname": ["testaccount4"]
+                }
+            }"#,
+            );
+            e.add_ava("account_expire", &Value::new_utf8s("2020-01-01T00:00:00Z"));
+            let ce = CreateEvent::new_internal(vec![e]);
+            assert!(
+                server_txn.create(audit, &ce)
+                    == Err(OperationError::SchemaViolation(
+                        SchemaError::InvalidAttributeSyntax
+                    ))
+            );
+
+            let ev = Event::from_internal();
+            let search = |server_txn: &QueryServerWriteTransaction,
+                          audit: &mut AuditScope,
+                          pf: ProtoFilter| {
+                let filt = Filter::from_rw(audit, &ev, &pf, server_txn).expect("invalid filter");
+                let mut names: Vec<String> = server_txn
+                    .internal_search(audit, filt)
+                    .expect("search failure")
+                    .iter()
+                    .map(|e| e.get_ava_single_string("name").expect("no name"))
+                    .collect();
+                names.sort();
+                names
+            };
+
+            // The +10:00 value is stored as utc, so equality in utc matches it.
+            let names = search(
+                &server_txn,
+                audit,
+                ProtoFilter::Eq(
+                    "account_expire".to_string(),
+                    "2019-12-31T23:00:00Z".to_string(),
+                ),
+            );
+            assert!(names == vec!["testaccount1"]);
+
+            // And the same instant in another zone matches too.
+            let names = search(
+                &server_txn,
+                audit,
+                ProtoFilter::Eq(
+                    "account_expire".to_string(),
+                    "2020-01-01T10:00:00+10:00".to_string(),
+                ),
+            );
+            assert!(names == vec!["testaccount2"]);
+
+            // Ordering is chronological.
+            let names = search(
+                &server_txn,
+                audit,
+                ProtoFilter::Lte(
+                    "account_expire".to_string(),
+                    "2020-01-01T00:00:00Z".to_string(),
+                ),
+            );
+            assert!(names == vec!["testaccount1", "testaccount2"]);
+            let names = search(
+                &server_txn,
+                audit,
+                ProtoFilter::Gte(
+                    "account_expire".to_string(),
+                    "2020-01-01T10:30:00+10:00".to_string(),
+                ),
+            );
+            assert!(names == vec!["testaccount3"]);
+
+            // The value is presented in utc.
+            let r = server_txn
+                .internal_search(
+                    audit,
+                    filter!(f_eq("name", PartialValue::new_iutf8s("testaccount1"))),
+                )
+                .expect("search failure");
+            assert!(
+                r[0].get_ava_single("account_expire")
+                    .and_then(|v| v.to_datetime())
+                    .map(|dt| dt.to_rfc3339())
+                    == Some("2019-12-31T23:00:00+00:00".to_string())
+            );
+            assert!(server_txn.commit(audit).is_ok());
+
+            let server_txn = server.read();
+            let r = server_txn
+                .internal_search(
+                    audit,
+                    filter!(f_eq("name", PartialValue::new_iutf8s("testaccount1"))),
+                )
+                .expect("search failure");
+            let pe_r = r[0]
+                .clone()
+                .reduce_attributes(btreeset!["account_expire"])
+                .into_pe(audit, &server_txn)
+                .expect("failed");
+            assert!(
+                pe_r.attrs.get("account_expire") == Some(&vec!["2019-12-31T23:00:00Z".to_string()])
+            );
+        })
+    }
+
+    #[test]
+    fn test_qs_bool_filter() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let server_txn = server.read();
+            let all = server_txn
+                .internal_search(audit, filter!(f_pres("class")))
+                .expect("search failed");
+            assert!(all.len() > 0);
+
+            // True selects every entry, without relying on any attribute.
+            let r_true = server_txn
+                .internal_search(audit, filter!(f_true()))
+                .expect("search failed");
+            assert!(r_true.len() == all.len());
+
+            let r_false = server_txn
+                .internal_search(audit, filter!(f_false()))
+                .expect("search failed");
+            assert!(r_false.len() == 0);
+
+            // And can be supplied by a client.
+            let ev = Event::from_internal();
+            let pf = ProtoFilter::And(vec![
+                ProtoFilter::True,
+                ProtoFilter::Eq("name".to_string(), "admin".to_string()),
+            ]);
+            let filt = Filter::from_ro(audit, &ev, &pf, &server_txn).expect("invalid filter");
+            let r = server_txn
+                .internal_search(audit, filt)
+                .expect("search failure");
+            assert!(r.len() == 1);
+        })
+    }
+
+    #[test]
+    fn test_qs_attr_name_normalisation() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let server_txn = server.write();
+            let ev = Event::from_internal();
+
+            assert!(server_txn.clone_attr_name("Name") == Ok("name".to_string()));
+            assert!(server_txn.clone_attr_name("acp_Search_Attr").is_ok());
+            for an in &["name ", " name", "na me", "", "nåme", "name\0", "(name)"] {
+                assert!(
+                    server_txn.clone_attr_name(an)
+                        == Err(OperationError::InvalidAttributeName(an.to_string()))
+                );
+            }
+
+            // Filters fold the name, or reject it with the value given.
+            let pf = ProtoFilter::Eq("NAME".to_string(), "admin".to_string());
+            let filt = Filter::from_rw(audit, &ev, &pf, &server_txn).expect("invalid filter");
+            let r = server_txn
+                .internal_search(audit, filt)
+                .expect("search failure");
+            assert!(r.len() == 1);
+
+            let pf = ProtoFilter::Pres("name ".to_string());
+            assert!(
+                Filter::from_rw(audit, &ev, &pf, &server_txn).map(|_| ())
+                    == Err(OperationError::InvalidAttributeName("name ".to_string()))
+            );
+
+            // As do modifications.
+            let pml =
+                ProtoModifyList::new_list(vec![ProtoModify::Purged("Description".to_string())]);
+            let ml = ModifyList::from(audit, &pml, &server_txn).expect("invalid modlist");
+            assert!(ml.validate(server_txn.get_schema()).is_ok());
+
+            let pml =
+                ProtoModifyList::new_list(vec![ProtoModify::Purged("descr iption".to_string())]);
+            assert!(
+                ModifyList::from(audit, &pml, &server_txn).map(|_| ())
+                    == Err(OperationError::InvalidAttributeName(
+                        "descr iption".to_string()
+                    ))
+            );
+
+            // Entries may not have two attributes that fold to the same name.
+            let pe: ProtoEntry = serde_json::from_str(
+                r#"{
+                "attrs": {
+                    "class": ["object", "person"],
+                    "Name": ["testperson1"],
+                    "displayname": ["testperson1"]
+                }
+            }"#,
+            )
+            .expect("invalid proto entry");
+            let e = Entry::from_proto_entry(audit, &pe, &server_txn).expect("invalid entry");
+            assert!(e.attribute_pres("name"));
+            assert!(!e.attribute_pres("Name"));
+
+            let pe: ProtoEntry = serde_json::from_str(
+                r#"{
+                "attrs": {
+                    "class": ["object", "person"],
+                    "Name": ["testperson1"],
+                    "name": ["testperson2"],
+                    "displayname": ["testperson1"]
+                }
+            }"#,
+            )
+            .expect("invalid proto entry");
+            assert!(
+                Entry::from_proto_entry(audit, &pe, &server_txn).map(|_| ())
+                    == Err(OperationError::InvalidAttributeName("name".to_string()))
+            );
+        })
+    }
+
+    #[test]
+    fn test_qs_search_ext_attrs() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            // Allow everyone to read the name and class of themself, but
+            // not the description.
+            let e_acp_search: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "access_control_profile", "access_control_search"],
+                    "name": ["test_acp_self_search"],
+                    "uuid": ["e05ba3a6-ef1e-4a4b-8d1b-ccfe6b0f0e51"],
+                    "acp_enable": ["true"],
+                    "acp_receiver": ["{\"Pres\":\"class\"}"],
+                    "acp_targetscope": ["\"Self\""],
+                    "acp_search_attr": ["uuid", "name", "class"]
+                }
+            }"#,
+            );
+            let mut server_txn = server.write();
+            let ce = CreateEvent::new_internal(vec![e_acp_search]);
+            assert!(server_txn.create(audit, &ce).is_ok());
+            assert!(server_txn.commit(audit).is_ok());
+
+            let server_txn = server.write();
+            let anon = server_txn
+                .internal_search_uuid(audit, &UUID_ANONYMOUS)
+                .expect("failed");
+
+            let mut search_attrs = |attrs: Option<Vec<&str>>| {
+                let mut se =
+                    unsafe { SearchEvent::new_impersonate_entry(anon.clone(), filter!(f_self())) };
+                se.attrs = attrs.map(|v| v.into_iter().map(|a| a.to_string()).collect());
+                let r = server_txn.search_ext(audit, &se).expect("search failed");
+                assert!(r.len() == 1);
+                r[0].get_ava_names()
+                    .into_iter()
+                    .map(|a| a.to_string())
+                    .collect::<BTreeSet<_>>()
+            };
+
+            // No request returns all that can be read, including the builtin
+            // anonymous displayname access.
+            let names: BTreeSet<_> = vec!["uuid", "name", "class", "displayname"]
+                .into_iter()
+                .map(|a| a.to_string())
+                .collect();
+            assert!(search_attrs(None) == names);
+
+            // Unreadable and unknown attributes are silently omitted, and the
+            // uuid is always present.
+            let names: BTreeSet<_> = vec!["uuid", "name"]
+                .into_iter()
+                .map(|a| a.to_string())
+                .collect();
+            assert!(search_attrs(Some(vec!["name", "description", "notanattr"])) == names);
+
+            // Requesting nothing gives only the uuid.
+            let names: BTreeSet<_> = vec!["uuid"].into_iter().map(|a| a.to_string()).collect();
+            assert!(search_attrs(Some(vec![])) == names);
+        })
+    }
+
+    #[test]
+    fn test_qs_search_ext_each() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let server_txn = server.read();
+            let admin = server_txn
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("failed");
+            let f_all = filter!(f_pres("class"));
+            let se = unsafe { SearchEvent::new_impersonate_entry(admin.clone(), f_all.clone()) };
+
+            // Every entry is given, as search_ext would return them.
+            let expect: Vec<_> = server_txn
+                .search_ext(audit, &se)
+                .expect("search failed")
+                .iter()
+                .map(|e| e.get_uuid().clone())
+                .collect();
+            assert!(expect.len() > 3);
+            let mut given = Vec::new();
+            server_txn
+                .search_ext_each(audit, &se, &mut |_, e| {
+                    given.push(e.get_uuid().clone());
+                    Ok(())
+                })
+                .expect("search failed");
+            assert!(given == expect);
+
+            // When the receiver fails, nothing more is given and the search
+            // fails as it did.
+            let mut given = 0;
+            let r = server_txn.search_ext_each(audit, &se, &mut |_, _| {
+                given += 1;
+                if given == 2 {
+                    Err(OperationError::InvalidState)
+                } else {
+                    Ok(())
+                }
+            });
+            assert!(r == Err(OperationError::InvalidState));
+            assert!(given == 2);
+
+            // The order must be known before anything is given.
+            let mut se = unsafe { SearchEvent::new_impersonate_entry(admin, f_all) };
+            se.page_size = Some(3);
+            let r = server_txn.search_ext_each(audit, &se, &mut |_, _| Ok(()));
+            assert!(r == Err(OperationError::InvalidRequestState));
+        })
+    }
+
+    #[test]
+    fn test_qs_search_paged() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let server_txn = server.read();
+            let uat = UserAuthToken {
+                issued_at: 0,
+                expiry: u64::max_value(),
+                auth_time: 0,
+                sessionid: Uuid::new_v4(),
+                name: "admin".to_string(),
+                spn: "admin@localhost".to_string(),
+                displayname: "admin".to_string(),
+                uuid: UUID_ADMIN.to_string(),
+                application: None,
+                groups: Vec::new(),
+                claims: Vec::new(),
+                must_change_password: false,
+                anonymous: false,
+                api_token: None,
+                read_only: false,
+            };
+
+            let search =
+                |audit: &mut AuditScope, page_size: Option<u32>, page_cookie: Option<String>| {
+                    let req = SearchRequest {
+                        filter: ProtoFilter::Pres("class".to_string()),
+                        attrs: None,
+                        page_size: page_size,
+                        page_cookie: page_cookie,
+                        sort: None,
+                        trace: false,
+                    };
+                    let msg = SearchMessage::new(Uuid::new_v4(), Some(uat.clone()), req);
+                    let se = SearchEvent::from_message(audit, msg, &server_txn)?;
+                    server_txn.search_ext_paged(audit, &se)
+                };
+
+            let (all, next_cookie) = search(audit, None, None).expect("search failed");
+            assert!(next_cookie.is_none());
+            assert!(all.len() > 3);
+
+            // Following the cookies returns every entry once, in id order.
+            let mut paged = Vec::new();
+            let mut page_cookie = None;
+            loop {
+                let (page, next_cookie) =
+                    search(audit, Some(3), page_cookie).expect("search failed");
+                assert!(page.len() <= 3);
+                paged.extend(page);
+                match next_cookie {
+                    Some(c) => page_cookie = Some(c),
+                    None => break,
+                }
+            }
+            let ids: Vec<u64> = paged.iter().map(|e| e.get_id()).collect();
+            assert!(ids.windows(2).all(|w| w[0] < w[1]));
+            let mut all_ids: Vec<u64> = all.iter().map(|e| e.get_id()).collect();
+            all_ids.sort();
+            assert!(ids == all_ids);
+
+            // A tampered or malformed cookie is rejected.
+            let (_, next_cookie) = search(audit, Some(3), None).expect("search failed");
+            let cookie = next_cookie.expect("no cookie");
+            let mut forged: Vec<char> = cookie.chars().collect();
+            forged[0] = if forged[0] == '0' { '1' } else { '0' };
+            let forged: String = forged.into_iter().collect();
+            for bad in &[
+                forged,
+                "".to_string(),
+                "zz".to_string(),
+                cookie[2..].to_string(),
+            ] {
+                assert!(
+                    search(audit, Some(3), Some(bad.clone())).map(|_| ())
+                        == Err(OperationError::InvalidRequestState)
+                );
+            }
+
+            // As is a page size of zero.
+            assert!(
+                search(audit, Some(0), None).map(|_| ())
+                    == Err(OperationError::InvalidRequestState)
+            );
+        })
+    }
+
+    #[test]
+    fn test_qs_search_uat_claims() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            // Only a session with the sudo claim may read the description.
+            let e_acp: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "access_control_profile", "access_control_search"],
+                    "name": ["test_acp_claim"],
+                    "uuid": ["3b6b1c72-8a8e-4a8b-9d55-0f7a4f7d6c11"],
+                    "acp_enable": ["true"],
+                    "acp_receiver": ["{\"And\":[{\"Eq\":[\"name\",\"claimperson\"]},{\"Eq\":[\"claim\",\"sudo\"]}]}"],
+                    "acp_targetscope": ["{\"Eq\":[\"name\",\"testperson1\"]}"],
+                    "acp_search_attr": ["name", "description"]
+                }
+            }"#,
+            );
+            let e_account: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "account", "person"],
+                    "name": ["claimperson"],
+                    "uuid": ["5c9b2d0e-7a44-4c1f-8e8b-3a6f9e2d1b20"],
+                    "displayname": ["claimperson"]
+                }
+            }"#,
+            );
+            let e_target: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object"],
+                    "name": ["testperson1"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                    "description": ["secret"]
+                }
+            }"#,
+            );
+            let mut server_txn = server.write();
+            let ce = CreateEvent::new_internal(vec![e_acp, e_account, e_target]);
+            assert!(server_txn.create(audit, &ce).is_ok());
+            assert!(server_txn.commit(audit).is_ok());
+
+            let server_txn = server.read();
+            let search = |audit: &mut AuditScope, claims: Vec<Claim>| {
+                let uat = UserAuthToken {
+                    issued_at: 0,
+                    expiry: u64::max_value(),
+                    auth_time: 0,
+                    sessionid: Uuid::new_v4(),
+                    name: "claimperson".to_string(),
+                    spn: "claimperson@localhost".to_string(),
+                    displayname: "claimperson".to_string(),
+                    uuid: "5c9b2d0e-7a44-4c1f-8e8b-3a6f9e2d1b20".to_string(),
+                    application: None,
+                    groups: Vec::new(),
+                    claims: claims,
+                    must_change_password: false,
+                    anonymous: false,
+                    api_token: None,
+                    read_only: false,
+                };
+                let req = SearchRequest {
+                    filter: ProtoFilter::Eq("description".to_string(), "secret".to_string()),
+                    attrs: None,
+                    page_size: None,
+                    page_cookie: None,
+                    sort: None,
+                    trace: false,
+                };
+                let msg = SearchMessage::new(Uuid::new_v4(), Some(uat), req);
+                let se = SearchEvent::from_message(audit, msg, &server_txn).expect("invalid event");
+                server_txn
+                    .search_ext(audit, &se)
+                    .expect("search failed")
+                    .len()
+            };
+            let sudo = |expiry: Option<u64>| Claim {
+                name: "sudo".to_string(),
+                uuid: Uuid::new_v4().to_string(),
+                expiry: expiry,
+            };
+
+            assert!(search(audit, Vec::new()) == 0);
+            assert!(search(audit, vec![sudo(None)]) == 1);
+            assert!(search(audit, vec![sudo(Some(u64::max_value()))]) == 1);
+            // Once the claim expires, the session no longer satisfies the acp.
+            assert!(search(audit, vec![sudo(Some(1))]) == 0);
+        })
+    }
+
+    #[test]
+    fn test_qs_search_sorted() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let e_ad: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "attributetype"],
+                    "attributename": ["testnumber"],
+                    "uuid": ["5d9a3e3b-2c4e-4b8f-9f0e-8d0b1b2f7a10"],
+                    "description": ["Test Attribute"],
+                    "multivalue": ["true"],
+                    "unique": ["false"],
+                    "syntax": ["UINT32"]
+                }
+            }"#,
+            );
+
+            // Allow admin to read the names and numbers of the test entries.
+            let e_acp_search: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "access_control_profile", "access_control_search"],
+                    "name": ["test_acp_sort_search"],
+                    "uuid": ["5d9a3e3b-2c4e-4b8f-9f0e-8d0b1b2f7a20"],
+                    "acp_enable": ["true"],
+                    "acp_receiver": ["{\"Eq\":[\"name\",\"admin\"]}"],
+                    "acp_targetscope": ["{\"Eq\":[\"class\",\"extensibleobject\"]}"],
+                    "acp_search_attr": ["class", "name", "testnumber"]
+                }
+            }"#,
+            );
+
+            let mut server_txn = server.write();
+            let ce_attr = CreateEvent::new_internal(vec![e_ad]);
+            assert!(server_txn.create(audit, &ce_attr).is_ok());
+            server_txn.commit(audit).expect("should not fail");
+
+            let mut server_txn = server.write();
+            let ce_acp = CreateEvent::new_internal(vec![e_acp_search]);
+            assert!(server_txn.create(audit, &ce_acp).is_ok());
+            server_txn.commit(audit).expect("should not fail");
+
+            let new_entry = |name: &str, uuid: &str, ns: Vec<u32>| {
+                let mut e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                    r#"{
+                    "valid": null,
+                    "state": null,
+                    "attrs": {
+                        "class": ["object", "extensibleobject"]
+                    }
+                }"#,
+                );
+                e.add_ava("name", &Value::new_iutf8s(name));
+                e.add_ava("uuid", &Value::new_uuids(uuid).unwrap());
+                for n in ns {
+                    e.add_ava("testnumber", &Value::new_uint32(n));
+                }
+                e
+            };
+
+            let mut server_txn = server.write();
+            let ce = CreateEvent::new_internal(vec![
+                new_entry(
+                    "testobj1",
+                    "5d9a3e3b-2c4e-4b8f-9f0e-8d0b1b2f7a11",
+                    vec![30, 5],
+                ),
+                new_entry(
+                    "testobj10",
+                    "5d9a3e3b-2c4e-4b8f-9f0e-8d0b1b2f7a12",
+                    vec![2, 100],
+                ),
+                new_entry("testobj2", "5d9a3e3b-2c4e-4b8f-9f0e-8d0b1b2f7a13", vec![10]),
+                new_entry("TestObj3", "5d9a3e3b-2c4e-4b8f-9f0e-8d0b1b2f7a14", vec![]),
+            ]);
+            assert!(server_txn.create(audit, &ce).is_ok());
+            let admin = server_txn
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("failed");
+
+            let search = |audit: &mut AuditScope,
+                          server_txn: &QueryServerWriteTransaction,
+                          sort: Option<(&str, SortOrder)>,
+                          page_size: Option<usize>,
+                          page_cookie: Option<String>| {
+                let mut se = unsafe {
+                    SearchEvent::new_impersonate_entry(
+                        admin.clone(),
+                        filter!(f_eq("class", PartialValue::new_class("extensibleobject"))),
+                    )
+                };
+                se.sort = sort.map(|(a, o)| (a.to_string(), o));
+                se.page_size = page_size;
+                if let Some(cookie) = page_cookie {
+                    se.page_after = Some(SearchEvent::resolve_page_after(
+                        server_txn,
+                        cookie.as_str(),
+                        &se.sort,
+                    )?);
+                }
+                server_txn
+                    .search_ext_paged(audit, &se)
+                    .map(|(r, next_cookie)| {
+                        let names: Vec<String> = r
+                            .iter()
+                            .map(|e| e.get_ava("name").unwrap()[0].to_str().unwrap().to_string())
+                            .collect();
+                        (names, next_cookie)
+                    })
+            };
+
+            // Numbers sort numerically on their smallest value, and entries
+            // without the attribute are last in either direction.
+            let (names, _) = search(
+                audit,
+                &server_txn,
+                Some(("testnumber", SortOrder::Ascending)),
+                None,
+                None,
+            )
+            .expect("search failed");
+            assert!(names == vec!["testobj10", "testobj1", "testobj2", "testobj3"]);
+
+            let (names, _) = search(
+                audit,
+                &server_txn,
+                Some(("testnumber", SortOrder::Descending)),
+                None,
+                None,
+            )
+            .expect("search failed");
+            assert!(names == vec!["testobj2", "testobj1", "testobj10", "testobj3"]);
+
+            // Names are case folded, so TestObj3 sorts after testobj2.
+            let (names, _) = search(
+                audit,
+                &server_txn,
+                Some(("name", SortOrder::Ascending)),
+                None,
+                None,
+            )
+            .expect("search failed");
+            assert!(names == vec!["testobj1", "testobj10", "testobj2", "testobj3"]);
+
+            // Paging a sorted search keeps the order, even when an entry that
+            // sorts before the current position is added between pages.
+            let sort = Some(("testnumber", SortOrder::Ascending));
+            let (mut paged, mut next_cookie) =
+                search(audit, &server_txn, sort, Some(1), None).expect("search failed");
+            let ce = CreateEvent::new_internal(vec![new_entry(
+                "testobj0",
+                "5d9a3e3b-2c4e-4b8f-9f0e-8d0b1b2f7a15",
+                vec![1],
+            )]);
+            assert!(server_txn.create(audit, &ce).is_ok());
+            while let Some(cookie) = next_cookie {
+                let (page, nc) =
+                    search(audit, &server_txn, sort, Some(1), Some(cookie)).expect("search failed");
+                assert!(page.len() == 1);
+                paged.extend(page);
+                next_cookie = nc;
+            }
+            assert!(paged == vec!["testobj10", "testobj1", "testobj2", "testobj3"]);
+
+            // A cookie can't be used to continue a search in another order.
+            let (_, next_cookie) =
+                search(audit, &server_txn, sort, Some(1), None).expect("search failed");
+            assert!(
+                search(audit, &server_txn, None, Some(1), next_cookie).map(|_| ())
+                    == Err(OperationError::InvalidRequestState)
+            );
+        })
+    }
+
+    #[test]
+    fn test_qs_search_count() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let server_txn = server.read();
+            let anon = server_txn
+                .internal_search_uuid(audit, &UUID_ANONYMOUS)
+                .expect("failed");
+
+            // Anonymous can read names, so the count matches the entries a
+            // search would return.
+            let se_name = unsafe {
+                SearchEvent::new_impersonate_entry(anon.clone(), filter!(f_pres("name")))
+            };
+            let count = server_txn
+                .search_count(audit, &se_name)
+                .expect("count failed");
+            let r = server_txn
+                .search_ext(audit, &se_name)
+                .expect("search failed");
+            assert!(count > 0);
+            assert!(count == r.len() as u64);
+            assert!(server_txn.search_exists(audit, &se_name) == Ok(true));
+
+            // Group descriptions exist, but anonymous can't read them, so
+            // they can't be counted either.
+            let f_desc = filter!(f_and(vec![
+                f_eq("class", PartialValue::new_class("group")),
+                f_pres("description")
+            ]));
+            let r = server_txn
+                .internal_search(audit, f_desc.clone())
+                .expect("search failed");
+            assert!(r.len() > 0);
+            let se_desc = unsafe { SearchEvent::new_impersonate_entry(anon, f_desc) };
+            assert!(server_txn.search_count(audit, &se_desc) == Ok(0));
+            assert!(server_txn.search_exists(audit, &se_desc) == Ok(false));
+        })
+    }
+
+    #[test]
+    fn test_qs_compare() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+
+            let e_acp: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "access_control_profile", "access_control_compare"],
+                    "name": ["acp_anon_compare"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63950"],
+                    "acp_enable": ["true"],
+                    "acp_receiver": [
+                        "{\"Eq\":[\"name\",\"anonymous\"]}"
+                    ],
+                    "acp_targetscope": [
+                        "{\"Eq\":[\"class\",\"person\"]}"
+                    ],
+                    "acp_compare_attr": ["description"]
+                }
+            }"#,
+            );
+
+            // Anonymous no longer reads membership, so grant compare on it directly.
+            let e_acp_member: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "access_control_profile", "access_control_compare"],
+                    "name": ["acp_anon_compare_member"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63951"],
+                    "acp_enable": ["true"],
+                    "acp_receiver": [
+                        "{\"Eq\":[\"name\",\"anonymous\"]}"
+                    ],
+                    "acp_targetscope": [
+                        "{\"Eq\":[\"class\",\"group\"]}"
+                    ],
+                    "acp_compare_attr": ["member"]
+                }
+            }"#,
+            );
+
+            let e_person: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["testperson1"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                    "description": ["testperson"],
+                    "displayname": ["testperson1"]
+                }
+            }"#,
+            );
+
+            let e_group: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "group"],
+                    "name": ["testgroup1"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63940"],
+                    "description": ["testgroup"],
+                    "member": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"]
+                }
+            }"#,
+            );
+
+            let ce = CreateEvent::new_internal(vec![e_acp, e_acp_member, e_person, e_group]);
+            assert!(server_txn.create(audit, &ce).is_ok());
+            assert!(server_txn.commit(audit).is_ok());
+
+            let server_txn = server.read();
+            let uat = UserAuthToken {
+                issued_at: 0,
+                expiry: u64::max_value(),
+                auth_time: 0,
+                sessionid: Uuid::new_v4(),
+                name: "anonymous".to_string(),
+                spn: "anonymous@localhost".to_string(),
+                displayname: "anonymous".to_string(),
+                uuid: UUID_ANONYMOUS.to_string(),
+                application: None,
+                groups: Vec::new(),
+                claims: Vec::new(),
+                must_change_password: false,
+                anonymous: true,
+                api_token: None,
+                read_only: false,
+            };
+
+            let compare = |audit: &mut AuditScope, name: &str, attr: &str, value: &str| {
+                let msg = CompareMessage::new(
+                    Uuid::new_v4(),
+                    Some(uat.clone()),
+                    CompareRequest::new(
+                        ProtoFilter::Eq("name".to_string(), name.to_string()),
+                        attr.to_string(),
+                        value.to_string(),
+                    ),
+                );
+                CompareEvent::from_message(audit, msg, &server_txn)
+                    .and_then(|ce| server_txn.compare(audit, &ce))
+            };
+
+            // Anonymous can't read descriptions ...
+            let anon = server_txn
+                .internal_search_uuid(audit, &UUID_ANONYMOUS)
+                .expect("failed");
+            let se = unsafe {
+                SearchEvent::new_impersonate_entry(
+                    anon,
+                    filter!(f_eq("description", PartialValue::new_utf8s("testperson"))),
+                )
+            };
+            assert!(server_txn.search_ext(audit, &se).map(|r| r.len()) == Ok(0));
+
+            // ... but has been granted compare on them for persons.
+            assert!(compare(audit, "testperson1", "description", "testperson") == Ok(true));
+            assert!(compare(audit, "testperson1", "description", "nottestperson") == Ok(false));
+
+            // The grant doesn't extend to other targets or attributes.
+            assert!(
+                compare(audit, "testgroup1", "description", "testgroup")
+                    == Err(OperationError::AccessDenied)
+            );
+            assert!(
+                compare(audit, "testperson1", "legalname", "testperson")
+                    == Err(OperationError::AccessDenied)
+            );
+
+            // Values are normalised to the attribute syntax: names fold case,
+            // and references accept either the uuid or the name of the target.
+            assert!(compare(audit, "testperson1", "name", "TestPerson1") == Ok(true));
+            assert!(
+                compare(
+                    audit,
+                    "testgroup1",
+                    "member",
+                    "cc8e95b4-c24f-4d68-ba54-8bed76f63930"
+                ) == Ok(true)
+            );
+            assert!(compare(audit, "testgroup1", "member", "TESTPERSON1") == Ok(true));
+            assert!(compare(audit, "testgroup1", "member", "admin") == Ok(false));
+
+            // The filter must select exactly one entry.
+            assert!(
+                compare(audit, "nottestperson", "name", "nottestperson")
+                    == Err(OperationError::NoMatchingEntries)
+            );
+        })
+    }
+
+    #[test]
+    fn test_qs_schema_read() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let server_txn = server.read();
+            let uat = UserAuthToken {
+                issued_at: 0,
+                expiry: u64::max_value(),
+                auth_time: 0,
+                sessionid: Uuid::new_v4(),
+                name: "anonymous".to_string(),
+                spn: "anonymous@localhost".to_string(),
+                displayname: "anonymous".to_string(),
+                uuid: UUID_ANONYMOUS.to_string(),
+                application: None,
+                groups: Vec::new(),
+                claims: Vec::new(),
+                must_change_password: false,
+                anonymous: false,
+                api_token: None,
+                read_only: false,
+            };
+
+            // Anonymous can read the schema through the default acp.
+            let msg = SchemaMessage::new(Uuid::new_v4(), Some(uat), SchemaRequest::new());
+            let se = SearchEvent::from_schema_message(audit, msg, &server_txn)
+                .expect("Invalid schema message");
+            let entries = server_txn.search_ext(audit, &se).expect("search failure");
+            let sr = SchemaResult::new(entries).response();
+
+            let member = sr
+                .attributes
+                .iter()
+                .find(|a| a.name == "member")
+                .expect("member not found");
+            assert!(member.multivalue);
+            assert!(!member.unique);
+            assert!(member.syntax == "REFERENCE_UUID");
+            assert!(member.description != "");
+
+            let name = sr
+                .attributes
+                .iter()
+                .find(|a| a.name == "name")
+                .expect("name not found");
+            assert!(!name.multivalue);
+            assert!(name.unique);
+
+            // System and user must/may are merged for classes.
+            let group = sr
+                .classes
+                .iter()
+                .find(|c| c.name == "group")
+                .expect("group not found");
+            assert!(group.must.contains(&"name".to_string()));
+            assert!(group.may.contains(&"member".to_string()));
+
+            // Without authentication there is no access.
+            let msg = SchemaMessage::new(Uuid::new_v4(), None, SchemaRequest::new());
+            assert!(
+                SearchEvent::from_schema_message(audit, msg, &server_txn).map(|_| ())
+                    == Err(OperationError::NotAuthenticated)
+            );
+        })
+    }
+
+    #[test]
+    fn test_qs_eq_case_folding() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let server_txn = server.write();
+            let ev = Event::from_internal();
+
+            // name is case insensitive, so this is folded.
+            let pf = ProtoFilter::Eq("name".to_string(), "ADMIN".to_string());
+            let filt = Filter::from_rw(audit, &ev, &pf, &server_txn).expect("invalid filter");
+            let r = server_txn
+                .internal_search(audit, filt)
+                .expect("search failure");
+            assert!(r.len() == 1);
+            assert!(*r[0].get_uuid() == *UUID_ADMIN);
+
+            // As are internally built filters with the wrong string type.
+            let filt = filter!(f_eq("name", PartialValue::new_utf8s("AdMiN")));
+            let r = server_txn
+                .internal_search(audit, filt)
+                .expect("search failure");
+            assert!(r.len() == 1);
+
+            // displayname is case exact, so this must not match.
+            let pf = ProtoFilter::Eq("displayname".to_string(), "ADMINISTRATOR".to_string());
+            let filt = Filter::from_rw(audit, &ev, &pf, &server_txn).expect("invalid filter");
+            let r = server_txn
+                .internal_search(audit, filt)
+                .expect("search failure");
+            assert!(r.len() == 0);
+
+            let pf = ProtoFilter::Eq("displayname".to_string(), "Administrator".to_string());
+            let filt = Filter::from_rw(audit, &ev, &pf, &server_txn).expect("invalid filter");
+            let r = server_txn
+                .internal_search(audit, filt)
+                .expect("search failure");
+            assert!(r.len() == 1);
+        })
+    }
+
+    #[test]
+    fn test_qs_modify_password_only() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let e1: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person", "account"],
+                    "name": ["testperson1"],
+                    "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                    "description": ["testperson"],
+                    "displayname": ["testperson1"]
+                }
+            }"#,
+            );
+            let mut server_txn = server.write();
+            // Add the entry. Today we have no syntax to take simple str to a credential
+            // but honestly, that's probably okay :)
+            let ce = CreateEvent::new_internal(vec![e1]);
+            let cr = server_txn.create(audit, &ce);
+            assert!(cr.is_ok());
+
+            // Build the credential.
+            let cred = Credential::new_password_only("test_password");
+            let v_cred = Value::new_credential("primary", cred);
+            assert!(v_cred.validate());
+
+            // now modify and provide a primary credential.
+            let me_inv_m = unsafe {
+                ModifyEvent::new_internal_invalid(
+                    filter!(f_eq("name", PartialValue::new_iutf8s("testperson1"))),
+                    ModifyList::new_list(vec![Modify::Present(
+                        "primary_credential".to_string(),
+                        v_cred,
+                    )]),
+                )
+            };
+            // go!
+            assert!(server_txn.modify(audit, &me_inv_m).is_ok());
+
+            // assert it exists and the password checks out
+            let test_ent = server_txn
+                .internal_search_uuid(
+                    audit,
+                    &Uuid::parse_str("cc8e95b4-c24f-4d68-ba54-8bed76f63930").unwrap(),
+                )
+                .expect("failed");
+            // get the primary ava
+            let cred_ref = test_ent
+                .get_ava_single_credential("primary_credential")
+                .expect("Failed");
+            // do a pw check.
+            assert!(cred_ref.verify_password("test_password"));
+        })
+    }
+
+    // Every kind of write an account makes is recorded, with the values of
+    // secret attributes redacted.
+    #[test]
+    fn test_qs_audit_log() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+            let admin = server_txn
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("failed");
+            let filt = filter!(f_eq("name", PartialValue::new_iutf8s("testperson1")));
+
+            let mut e1: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person", "account"],
+                    "name": ["testperson1"],
+                    "displayname": ["testperson1"]
+                }
+            }"#,
+            );
+            e1.add_ava(
+                "primary_credential",
+                &Value::new_credential("primary", Credential::new_password_only("test_password")),
+            );
+            let ce = unsafe { CreateEvent::new_impersonate_entry(admin.clone(), vec![e1]) };
+            let u = server_txn
+                .create_uuids(audit, &ce)
+                .expect("create failed")
+                .remove(0);
+
+            let me = unsafe {
+                ModifyEvent::new_impersonate_entry(
+                    admin.clone(),
+                    filt.clone(),
+                    ModifyList::new_list(vec![
+                        Modify::Present("displayname".to_string(), Value::new_utf8s("renamed")),
+                        Modify::Present(
+                            "primary_credential".to_string(),
+                            Value::new_credential(
+                                "primary",
+                                Credential::new_password_only("other_password"),
+                            ),
+                        ),
+                    ]),
+                )
+            };
+            assert!(server_txn.modify(audit, &me).is_ok());
+
+            let de = unsafe { DeleteEvent::new_impersonate_entry(admin.clone(), filt.clone()) };
+            assert!(server_txn.delete(audit, &de).is_ok());
+
+            let rre = unsafe { ReviveRecycledEvent::new_impersonate_entry(admin.clone(), filt) };
+            assert!(server_txn.revive_recycled(audit, &rre).is_ok());
+
+            // Internal writes aren't recorded.
+            let ce = CreateEvent::new_internal(vec![Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["testperson2"],
+                    "description": ["testperson2"],
+                    "displayname": ["testperson2"]
+                }
+            }"#,
+            )]);
+            assert!(server_txn.create(audit, &ce).is_ok());
+
+            let ale = AuditListEvent {
+                since: 0,
+                until: std::i64::MAX,
+                target: None,
+            };
+            let records = server_txn
+                .audit_list(audit, &ale)
+                .expect("audit list failed");
+            let operations: Vec<_> = records.iter().map(|r| r.operation).collect();
+            assert!(
+                operations
+                    == vec![
+                        AuditOperation::Create,
+                        AuditOperation::Modify,
+                        AuditOperation::Delete,
+                        AuditOperation::Revive
+                    ]
+            );
+            let admin_uuid = UUID_ADMIN.to_hyphenated_ref().to_string();
+            let target = u.to_hyphenated_ref().to_string();
+            assert!(records
+                .iter()
+                .all(|r| r.identity == admin_uuid && r.targets == vec![target.clone()]));
+
+            // The credential is never shown, not even its tag.
+            let redacted =
+                ProtoModify::Present("primary_credential".to_string(), AUDIT_REDACTED.to_string());
+            assert!(records[0].changes.contains(&redacted));
+            assert!(records[0].changes.contains(&ProtoModify::Present(
+                "displayname".to_string(),
+                "testperson1".to_string()
+            )));
+            assert!(records[1].changes.contains(&redacted));
+            assert!(records[1].changes.contains(&ProtoModify::Present(
+                "displayname".to_string(),
+                "renamed".to_string()
+            )));
+            assert!(records[2].changes.is_empty());
+
+            // The records can be found by their target, and are kept apart
+            // from the entry itself.
+            let ale = AuditListEvent {
+                since: 0,
+                until: std::i64::MAX,
+                target: Some(Uuid::new_v4()),
+            };
+            assert!(server_txn
+                .audit_list(audit, &ale)
+                .expect("audit list failed")
+                .is_empty());
+
+            assert!(server_txn.commit(audit).is_ok());
+        })
+    }
+
+    fn changelog_person(name: &str) -> Entry<EntryInvalid, EntryNew> {
+        let mut e = Entry::new();
+        e.add_ava("class", &Value::new_class("object"));
+        e.add_ava("class", &Value::new_class("person"));
+        e.add_ava("name", &Value::new_iutf8s(name));
+        e.add_ava("description", &Value::new_utf8s(name));
+        e.add_ava("displayname", &Value::new_utf8s(name));
+        e
+    }
+
+    fn changelog_rename(audit: &mut AuditScope, server: &QueryServer, name: &str) {
+        let mut server_txn = server.write();
+        assert!(server_txn
+            .internal_modify(
+                audit,
+                filter!(f_eq("name", PartialValue::new_iutf8s(name))),
+                ModifyList::new_purge_and_set("displayname", Value::new_utf8s("renamed")),
+            )
+            .is_ok());
+        assert!(server_txn.commit(audit).is_ok());
+    }
+
+    fn read_changes(
+        audit: &mut AuditScope,
+        server: &QueryServer,
+        since: Option<Cid>,
+    ) -> Result<ChangesResponse, OperationError> {
+        server
+            .read()
+            .changes_since(audit, &ChangesEvent { since: since })
+    }
+
+    #[test]
+    fn test_qs_changelog_attribute_cids() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+            let ce = CreateEvent::new_internal(vec![changelog_person("testperson1")]);
+            let u = server_txn
+                .create_uuids(audit, &ce)
+                .expect("create failed")
+                .remove(0);
+            assert!(server_txn.commit(audit).is_ok());
+
+            let changestate = |audit: &mut AuditScope| {
+                server
+                    .read()
+                    .get_be_txn()
+                    .get_changestate(audit, &u)
+                    .expect("changestate failed")
+            };
+
+            // Every attribute was changed by the create.
+            let created = changestate(audit);
+            let create_cid = created.get("name").expect("no cid for name").clone();
+            for a in ["class", "name", "uuid", "description", "displayname"].iter() {
+                assert!(created.get(*a) == Some(&create_cid));
+            }
+
+            changelog_rename(audit, server, "testperson1");
+
+            let modified = changestate(audit);
+            let modify_cid = modified.get("displayname").expect("no cid").clone();
+            assert!(modify_cid > create_cid);
+            for (a, cid) in modified.iter() {
+                if a == "displayname" {
+                    continue;
+                }
+                assert!(created.get(a) == Some(cid));
+            }
+
+            // Writing the same value again changes nothing.
+            changelog_rename(audit, server, "testperson1");
+            assert!(changestate(audit) == modified);
+        })
+    }
+
+    #[test]
+    fn test_qs_changes_since() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+            let ce = CreateEvent::new_internal(vec![
+                changelog_person("testperson1"),
+                changelog_person("testperson2"),
+                changelog_person("testperson3"),
+            ]);
+            let uuids = server_txn.create_uuids(audit, &ce).expect("create failed");
+            assert!(server_txn.commit(audit).is_ok());
+
+            let before = read_changes(audit, server, None).expect("changes failed");
+            let since: Cid = before
+                .cid
+                .expect("no latest cid")
+                .parse()
+                .expect("invalid cid");
+            assert!(before
+                .entries
+                .iter()
+                .any(|c| c.uuid == uuids[0].to_hyphenated_ref().to_string()));
+
+            changelog_rename(audit, server, "testperson1");
+            changelog_rename(audit, server, "testperson3");
+
+            let after = read_changes(audit, server, Some(since.clone())).expect("changes failed");
+            let changed: Vec<_> = after
+                .entries
+                .iter()
+                .map(|c| (c.uuid.clone(), c.attrs.clone()))
+                .collect();
+            assert!(
+                changed
+                    == vec![
+                        (
+                            uuids[0].to_hyphenated_ref().to_string(),
+                            vec!["displayname".to_string()]
+                        ),
+                        (
+                            uuids[2].to_hyphenated_ref().to_string(),
+                            vec!["displayname".to_string()]
+                        ),
+                    ]
+            );
+            let latest: Cid = after
+                .cid
+                .expect("no latest cid")
+                .parse()
+                .expect("invalid cid");
+            assert!(after.entries[1].cid == latest.to_string());
+
+            // Nothing has changed since the latest change.
+            let none = read_changes(audit, server, Some(latest.clone())).expect("changes failed");
+            assert!(none.entries.is_empty());
+            assert!(none.cid == Some(latest.to_string()));
+
+            // Once the changes are trimmed, they can't be given from before.
+            let ct = Duration::from_nanos(latest.ts + 1);
+            let server_txn = server.write();
+            assert!(server_txn
+                .purge_changelog(audit, ct, Duration::from_secs(0))
+                .is_ok());
+            assert!(server_txn.commit(audit).is_ok());
+            assert!(
+                read_changes(audit, server, Some(since)).map(|_| ())
+                    == Err(OperationError::ChangelogTrimmed)
+            );
+            assert!(read_changes(audit, server, Some(latest.clone()))
+                .map(|r| r.entries.is_empty())
+                .unwrap_or(false));
+        })
+    }
+
+    // The names of the entries exported since, and the uuids of those deleted.
+    fn read_export(
+        audit: &mut AuditScope,
+        server: &QueryServer,
+        since: Option<Cid>,
+    ) -> Result<(Vec<String>, Vec<Uuid>, Option<Cid>), OperationError> {
+        let ee = ExportEvent {
+            event: Event::from_internal(),
+            since: since,
+        };
+        let mut names = Vec::new();
+        let mut deleted = Vec::new();
+        let cid = server.read().export_each(audit, &ee, &mut |_, r| {
+            match r {
+                ExportRecord::Entry(e) => names.push(
+                    e.get_ava_single("name")
+                        .and_then(|v| v.to_str())
+                        .unwrap_or("")
+                        .to_string(),
+                ),
+                ExportRecord::Deleted(u) => deleted.push(u),
+            }
+            Ok(())
+        })?;
+        names.sort();
+        Ok((names, deleted, cid))
+    }
+
+    #[test]
+    fn test_qs_export_since() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let mut server_txn = server.write();
+            let ce = CreateEvent::new_internal(vec![
+                changelog_person("testperson1"),
+                changelog_person("testperson2"),
+                changelog_person("testperson3"),
+            ]);
+            let uuids = server_txn.create_uuids(audit, &ce).expect("create failed");
+            assert!(server_txn.commit(audit).is_ok());
+
+            // A full export gives every entry, but no deletions.
+            let (names, deleted, cid) = read_export(audit, server, None).expect("export failed");
+            assert!(names.contains(&"testperson1".to_string()));
+            assert!(names.contains(&"testperson3".to_string()));
+            assert!(deleted.is_empty());
+            let since = cid.expect("no cursor");
+
+            changelog_rename(audit, server, "testperson1");
+            let mut server_txn = server.write();
+            assert!(server_txn
+                .internal_delete(
+                    audit,
+                    filter!(f_eq("name", PartialValue::new_iutf8s("testperson3")))
+                )
+                .is_ok());
+            assert!(server_txn.commit(audit).is_ok());
+
+            let (names, deleted, cid) =
+                read_export(audit, server, Some(since.clone())).expect("export failed");
+            assert!(names == vec!["testperson1".to_string()]);
+            assert!(deleted == vec![uuids[2]]);
+            let latest = cid.expect("no cursor");
+            assert!(latest > since);
+
+            let (names, deleted, cid) =
+                read_export(audit, server, Some(latest.clone())).expect("export failed");
+            assert!(names.is_empty() && deleted.is_empty());
+            assert!(cid == Some(latest.clone()));
+
+            // Once the changes are trimmed, only a full export is complete.
+            let ct = Duration::from_nanos(latest.ts + 1);
+            let server_txn = server.write();
+            assert!(server_txn
+                .purge_changelog(audit, ct, Duration::from_secs(0))
+                .is_ok());
+            assert!(server_txn.commit(audit).is_ok());
+            assert!(
+                read_export(audit, server, Some(since)).map(|_| ())
+                    == Err(OperationError::ChangelogTrimmed)
+            );
+        })
+    }
+
+    // The server isn't ready while its database can't be read, or after a
+    // verification has failed.
+    #[test]
+    fn test_qs_health() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let hr = server.health(audit);
+            assert!(hr.healthy);
+            assert!(hr.checks.values().all(|c| c.healthy && c.detail.is_none()));
+
+            // Poison the backend by hiding the entries from it.
+            let rename = |from: &str, to: &str| {
+                let be_txn = server.be.write();
+                be_txn
+                    .get_conn()
+                    .execute(
+                        format!("ALTER TABLE {} RENAME TO {}", from, to).as_str(),
+                        NO_PARAMS,
+                    )
+                    .expect("rename failed");
+                be_txn.commit().expect("commit failed");
+            };
+            rename("id2entry", "id2entry_poisoned");
+            let hr = server.health(audit);
+            assert!(!hr.healthy);
+            assert!(
+                hr.checks["database"] == HealthCheck::new_failed("SQLiteError: Other".to_string())
+            );
+            assert!(hr.checks["consistency"].healthy);
+            rename("id2entry_poisoned", "id2entry");
+            assert!(server.health(audit).healthy);
+
+            server.be.set_consistent(false);
+            let hr = server.health(audit);
+            assert!(!hr.healthy);
+            assert!(hr.checks["database"].healthy);
+            assert!(!hr.checks["consistency"].healthy);
+            // Passing a later verification makes it ready again.
+            assert!(server.verify(audit).len() == 0);
+            assert!(server.health(audit).healthy);
+        })
+    }
+
+    // A backup from before the format was versioned is still restored, and
+    // the memberof and spn it holds are generated again rather than trusted.
+    #[test]
+    fn test_qs_restore_legacy() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let path =
+                std::env::temp_dir().join(format!("kanidm_test_restore_{}.json", Uuid::new_v4()));
+            let path = path.to_str().expect("Invalid temp path");
+
+            // The old format was the list of entries alone. Their memberof is
+            // removed and their spn made stale, to show neither is kept.
+            server
+                .read()
+                .get_be_txn()
+                .backup(audit, path)
+                .expect("Backup failed!");
+            let backup = std::fs::read_to_string(path).expect("Failed to read backup");
+            let backup: serde_json::Value =
+                serde_json::from_str(backup.as_str()).expect("Failed to parse backup");
+            let mut entries = backup["entries"].clone();
+            for e in entries.as_array_mut().expect("Invalid backup") {
+                let attrs = e["ent"]["V1"]["attrs"]
+                    .as_object_mut()
+                    .expect("Invalid entry");
+                attrs.remove("memberof");
+                attrs.remove("directmemberof");
+                // Each spn is still unique, as it must be.
+                if let Some(spn) = attrs.get_mut("spn") {
+                    *spn = serde_json::from_str(
+                        spn.to_string()
+                            .replace("@localhost", "@example.com")
+                            .as_str(),
+                    )
+                    .expect("Invalid spn");
+                }
+            }
+            std::fs::write(path, entries.to_string()).expect("Failed to write backup");
+
+            assert!(server.restore(audit, path).is_ok());
+            let _ = std::fs::remove_file(path);
+
+            let admin = server
+                .read()
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("Failed to get admin");
+            let idm_admins = Uuid::parse_str(_UUID_IDM_ADMINS).expect("Invalid uuid");
+            assert!(
+                admin
+                    .get_ava_reference_uuid("memberof")
+                    .map(|mo| mo.contains(&&idm_admins))
+                    == Some(true)
+            );
+            assert!(
+                admin.get_ava_single("spn").and_then(|v| v.to_str()) == Some("admin@localhost")
+            );
+        })
+    }
+
+    #[test]
+    fn test_qs_search_unindexed() {
+        let mut audit = AuditScope::new("test_qs_search_unindexed");
+        let be = Backend::new(&mut audit, "", 1).expect("failed to init be");
+        let schema = Schema::new(&mut audit).expect("failed to init schema");
+        let mut server = QueryServer::new(be, schema);
+        let mut limits_anonymous = FilterLimits::new_anonymous();
+        limits_anonymous.allow_unindexed = false;
+        server.set_filter_limits(FilterLimits::new(), limits_anonymous);
+        server.initialise_helper(&mut audit).expect("init failed!");
+
+        let server_txn = server.read();
+        let anon = server_txn
+            .internal_search_uuid(&mut audit, &UUID_ANONYMOUS)
+            .expect("failed");
+        let admin = server_txn
+            .internal_search_uuid(&mut audit, &UUID_ADMIN)
+            .expect("failed");
+
+        // name is indexed for equality, description is not indexed at all.
+        let f_indexed = filter!(f_eq("name", PartialValue::new_iutf8s("admin")));
+        let f_unindexed = filter!(f_pres("description"));
+
+        let se = unsafe { SearchEvent::new_impersonate_entry(anon.clone(), f_indexed) };
+        assert!(server_txn.search(&mut audit, &se).is_ok());
+
+        let se = unsafe { SearchEvent::new_impersonate_entry(anon, f_unindexed.clone()) };
+        assert!(server_txn.search(&mut audit, &se) == Err(OperationError::ResourceLimit));
+
+        // Only the anonymous limits refuse them.
+        let se = unsafe { SearchEvent::new_impersonate_entry(admin, f_unindexed.clone()) };
+        assert!(server_txn.search(&mut audit, &se).is_ok());
+
+        // Nor are internal searches ever refused.
+        assert!(server_txn.internal_search(&mut audit, f_unindexed).is_ok());
+    }
+
+    #[test]
+    fn test_qs_search_result_limit() {
+        let mut audit = AuditScope::new("test_qs_search_result_limit");
+        let be = Backend::new(&mut audit, "", 1).expect("failed to init be");
+        let schema = Schema::new(&mut audit).expect("failed to init schema");
+        let mut server = QueryServer::new(be, schema);
+        let mut limits = FilterLimits::new();
+        limits.max_results = 8;
+        let mut limits_anonymous = FilterLimits::new_anonymous();
+        limits_anonymous.max_results = 4;
+        server.set_filter_limits(limits, limits_anonymous);
+        server.initialise_helper(&mut audit).expect("init failed!");
+
+        let mut server_txn = server.write();
+        let e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+            r#"{
+            "valid": null,
+            "state": null,
+            "attrs": {
+                "class": ["object", "person", "account"],
+                "name": ["testperson"],
+                "uuid": ["cc8e95b4-c24f-4d68-ba54-8bed76f63930"],
+                "description": ["testperson"],
+                "displayname": ["testperson"]
+            }
+        }"#,
+        );
+        server_txn
+            .internal_create(&mut audit, vec![e])
+            .expect("create failed");
+
+        let anon = server_txn
+            .internal_search_uuid(&mut audit, &UUID_ANONYMOUS)
+            .expect("failed");
+        let testperson = server_txn
+            .internal_search_uuid(
+                &mut audit,
+                &Uuid::parse_str("cc8e95b4-c24f-4d68-ba54-8bed76f63930").unwrap(),
+            )
+            .expect("failed");
+        // Admin is unlimited, as idm_admins is in idm_unlimited_search_priv.
+        let admin = server_txn
+            .internal_search_uuid(&mut audit, &UUID_ADMIN)
+            .expect("failed");
+
+        // Every attribute type is a candidate, which is more than either limit.
+        let f_wide = filter!(f_eq("class", PartialValue::new_class("attributetype")));
+        let f_narrow = filter!(f_eq("name", PartialValue::new_iutf8s("testperson")));
+
+        let search = |audit: &mut AuditScope,
+                      e: &Entry<EntryValid, EntryCommitted>,
+                      f: &Filter<FilterInvalid>,
+                      page_size: Option<usize>| {
+            let mut se = unsafe { SearchEvent::new_impersonate_entry(e.clone(), f.clone()) };
+            se.page_size = page_size;
+            server_txn.search(audit, &se).map(|r| r.len())
+        };
+
+        assert!(search(&mut audit, &anon, &f_wide, None) == Err(OperationError::ResultLimit(4)));
+        assert!(
+            search(&mut audit, &testperson, &f_wide, None) == Err(OperationError::ResultLimit(8))
+        );
+        assert!(search(&mut audit, &admin, &f_wide, None).is_ok());
+        assert!(server_txn
+            .internal_search(&mut audit, f_wide.clone())
+            .is_ok());
+
+        assert!(search(&mut audit, &anon, &f_narrow, None).is_ok());
+        assert!(search(&mut audit, &testperson, &f_narrow, None).is_ok());
+
+        // Pages may add up to more than the limit, but no page may exceed it.
+        assert!(search(&mut audit, &testperson, &f_wide, Some(8)).is_ok());
+        assert!(
+            search(&mut audit, &testperson, &f_wide, Some(9))
+                == Err(OperationError::ResultLimit(8))
+        );
+        assert!(search(&mut audit, &anon, &f_wide, Some(4)).is_ok());
+        assert!(search(&mut audit, &anon, &f_wide, Some(8)) == Err(OperationError::ResultLimit(4)));
+        assert!(search(&mut audit, &admin, &f_wide, Some(1024)).is_ok());
+    }
+
+    #[test]
+    fn test_qs_index_status() {
+        run_test!(|server: &QueryServer, audit: &mut AuditScope| {
+            let server_txn = server.read();
+            let status = server_txn.index_status(audit).expect("index status failed");
+
+            let name_eq = status
+                .iter()
+                .find(|s| s.name == "name.eq")
+                .expect("no name.eq index");
+            assert!(name_eq.declared && name_eq.exists);
+            assert!(name_eq.attr == "name" && name_eq.index == "EQUALITY");
+            assert!(name_eq.keys > 0 && name_eq.keys == name_eq.distinct);
+
+            // Every index the schema declares has been built.
+            assert!(status.iter().all(|s| s.declared && s.exists));
+            assert!(!status.iter().any(|s| s.attr == "description"));
+        })
+    }
+
+    #[test]
+    fn test_qs_reads_during_slow_write() {
+        // Reads must not queue behind a write that is slow to commit, and
+        // must keep the view they began with until they end.
+        let mut audit = AuditScope::new("test_qs_reads_during_slow_write");
+        let path =
+            std::env::temp_dir().join(format!("kanidm_test_slow_write_{}.db", Uuid::new_v4()));
+        let path_str = path.to_str().expect("invalid temp path").to_string();
+        let be = Backend::new(&mut audit, path_str.as_str(), 6).expect("Failed to init be");
+        let schema = Schema::new(&mut audit).expect("Failed to init schema");
+        let qs = QueryServer::new(be, schema);
+        qs.initialise_helper(&mut audit).expect("init failed!");
+
+        let filt_new = || filter!(f_eq("name", PartialValue::new_iutf8s("slowperson")));
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let qs_w = qs.clone();
+        let writer = std::thread::spawn(move || {
+            let mut audit = AuditScope::new("slow_writer");
+            let mut w_txn = qs_w.write();
+            let e: Entry<EntryInvalid, EntryNew> = Entry::unsafe_from_entry_str(
+                r#"{
+                "valid": null,
+                "state": null,
+                "attrs": {
+                    "class": ["object", "person"],
+                    "name": ["slowperson"],
+                    "uuid": ["4b8b43f2-8bd5-4d67-99d8-3b5ad2bd0bbf"],
+                    "description": ["slowperson"],
+                    "displayname": ["slowperson"]
+                }
+            }"#,
+            );
+            let ce = CreateEvent::new_internal(vec![e]);
+            assert!(w_txn.create(&mut audit, &ce).is_ok());
+            tx.send(()).expect("send failed");
+            std::thread::sleep(Duration::from_secs(1));
+            assert!(w_txn.commit(&mut audit).is_ok());
+        });
+
+        rx.recv().expect("recv failed");
+        let before = qs.read();
+
+        let readers: Vec<_> = (0..3)
+            .map(|_| {
+                let qs_r = qs.clone();
+                std::thread::spawn(move || {
+                    let mut audit = AuditScope::new("reader");
+                    let mut slowest = Duration::from_millis(0);
+                    for _ in 0..20 {
+                        let start = SystemTime::now();
+                        let r_txn = qs_r.read();
+                        let r = r_txn.internal_search(
+                            &mut audit,
+                            filter!(f_eq("name", PartialValue::new_iutf8s("admin"))),
+                        );
+                        assert!(r.map(|r| r.len() == 1).unwrap_or(false));
+                        let elapsed = start.elapsed().expect("clock went backwards");
+                        if elapsed > slowest {
+                            slowest = elapsed;
+                        }
+                        std::thread::sleep(Duration::from_millis(10));
+                    }
+                    slowest
+                })
+            })
+            .collect();
+
+        for r in readers {
+            let slowest = r.join().expect("reader panicked");
+            assert!(slowest < Duration::from_millis(500));
+        }
+        writer.join().expect("writer panicked");
+
+        let r = before.internal_search(&mut audit, filt_new());
+        assert!(r.map(|r| r.is_empty()).unwrap_or(false));
+        let after = qs.read();
+        let r = after.internal_search(&mut audit, filt_new());
+        assert!(r.map(|r| r.len() == 1).unwrap_or(false));
+
+        drop(before);
+        drop(after);
+        drop(qs);
+        for suffix in &["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path_str, suffix));
+        }
+    }
+
+    // A server that defers derived work changing more than two entries,
+    // started over the given backend.
+    fn setup_delayed(audit: &mut AuditScope, be: Backend) -> QueryServer {
+        let schema = Schema::new(audit).expect("Failed to init schema");
+        let mut qs = QueryServer::new(be, schema);
+        qs.initialise_helper(audit).expect("init failed!");
+        qs.set_delayed_threshold(2);
+        qs
+    }
+
+    fn delayed_group(name: &str, u: Uuid, members: &[Uuid]) -> Entry<EntryInvalid, EntryNew> {
+        let mut e = Entry::new();
+        e.add_ava("class", &Value::new_class("object"));
+        e.add_ava("class", &Value::new_class("group"));
+        e.add_ava("name", &Value::new_iutf8s(name));
+        e.add_ava("uuid", &Value::new_uuid(u));
+        for m in members {
+            e.add_ava("member", &Value::new_refer(m.clone()));
+        }
+        e
+    }
+
+    fn delayed_person(name: &str, u: Uuid) -> Entry<EntryInvalid, EntryNew> {
+        let mut e = Entry::new();
+        e.add_ava("class", &Value::new_class("object"));
+        e.add_ava("class", &Value::new_class("person"));
+        e.add_ava("name", &Value::new_iutf8s(name));
+        e.add_ava("uuid", &Value::new_uuid(u));
+        e.add_ava("description", &Value::new_utf8s(name));
+        e.add_ava("displayname", &Value::new_utf8s(name));
+        e
+    }
+
+    // Create a group of three people, which is too many for the memberof of
+    // each to be set in the same write.
+    fn create_delayed_group(audit: &mut AuditScope, qs: &QueryServer) -> (Uuid, Vec<Uuid>) {
+        let members: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        let group = Uuid::new_v4();
+        let mut entries: Vec<_> = members
+            .iter()
+            .enumerate()
+            .map(|(i, u)| delayed_person(format!("delayed_person_{}", i).as_str(), u.clone()))
+            .collect();
+        entries.push(delayed_group("delayed_group", group.clone(), &members));
+
+        let mut qs_write = qs.write();
+        assert!(qs_write.internal_create(audit, entries).is_ok());
+        assert!(qs_write.commit(audit).is_ok());
+        (group, members)
+    }
+
+    fn is_memberof(audit: &mut AuditScope, qs: &QueryServer, u: &Uuid, group: &Uuid) -> bool {
+        let r = qs
+            .read()
+            .internal_search(
+                audit,
+                filter!(f_and!([
+                    f_eq("uuid", PartialValue::new_uuidr(u)),
+                    f_eq("memberof", PartialValue::new_refer_r(group))
+                ])),
+            )
+            .expect("Failed to search");
+        r.len() == 1
+    }
+
+    #[test]
+    fn test_qs_delayed_memberof() {
+        let mut audit = AuditScope::new("test_qs_delayed_memberof");
+        let be = Backend::new(&mut audit, "", 1).expect("Failed to init be");
+        let qs = setup_delayed(&mut audit, be);
+
+        let (group, members) = create_delayed_group(&mut audit, &qs);
+
+        // The group was committed, but its members don't know of it yet.
+        assert!(qs.delayed_actions() == 1);
+        assert!(members
+            .iter()
+            .all(|m| !is_memberof(&mut audit, &qs, m, &group)));
+        assert!(qs.verify(&mut audit).iter().any(|r| match r {
+            Err(ConsistencyError::MemberOfInvalid(_)) => true,
+            _ => false,
+        }));
+
+        assert!(qs.process_delayed_actions(&mut audit) == Ok(1));
+        assert!(qs.delayed_actions() == 0);
+        assert!(members
+            .iter()
+            .all(|m| is_memberof(&mut audit, &qs, m, &group)));
+        assert!(qs.verify(&mut audit).len() == 0);
+
+        // Removing a member affects every member the group had.
+        let mut qs_write = qs.write();
+        assert!(qs_write
+            .internal_modify(
+                &mut audit,
+                filter!(f_eq("uuid", PartialValue::new_uuidr(&group))),
+                ModifyList::new_list(vec![Modify::Removed(
+                    "member".to_string(),
+                    PartialValue::new_refer_r(&members[0])
+                )]),
+            )
+            .is_ok());
+        assert!(qs_write.commit(&mut audit).is_ok());
+        assert!(is_memberof(&mut audit, &qs, &members[0], &group));
+        assert!(qs.process_delayed_actions(&mut audit) == Ok(1));
+        assert!(!is_memberof(&mut audit, &qs, &members[0], &group));
+        assert!(is_memberof(&mut audit, &qs, &members[1], &group));
+        assert!(qs.verify(&mut audit).len() == 0);
+    }
+
+    // The server stops after the writes commit, but before the work they
+    // deferred is applied. The next start finds and repairs what was missed.
+    #[test]
+    fn test_qs_delayed_repair_on_start() {
+        let mut audit = AuditScope::new("test_qs_delayed_repair_on_start");
+        let be = Backend::new(&mut audit, "", 1).expect("Failed to init be");
+        let qs = setup_delayed(&mut audit, be.clone());
+
+        let (group, members) = create_delayed_group(&mut audit, &qs);
+
+        // There are more accounts than the threshold, so renaming the domain
+        // defers regenerating their spns.
+        let mut qs_write = qs.write();
+        assert!(qs_write
+            .internal_modify(
+                &mut audit,
+                filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_DOMAIN_INFO))),
+                ModifyList::new_list(vec![
+                    Modify::Purged("domain_name".to_string()),
+                    Modify::Present("domain_name".to_string(), Value::new_iutf8s("example.com")),
+                ]),
+            )
+            .is_ok());
+        assert!(qs_write.commit(&mut audit).is_ok());
+        assert!(qs.delayed_actions() == 2);
+
+        // Each action is taken from the queue and its transaction begun, but
+        // the server stops before any of them commit.
+        let mut seen = Vec::new();
+        let r = qs.process_delayed_actions_with(&mut audit, |_, _, action| {
+            seen.push(action.clone());
+            Err(OperationError::InvalidState)
+        });
+        assert!(r == Err(OperationError::InvalidState));
+        let r = qs.process_delayed_actions_with(&mut audit, |_, _, action| {
+            seen.push(action.clone());
+            Err(OperationError::InvalidState)
+        });
+        assert!(r == Err(OperationError::InvalidState));
+        assert!(seen.len() == 2 && seen[1] == DelayedAction::Spn);
+        assert!(qs.delayed_actions() == 0);
+
+        let spn_admin = |audit: &mut AuditScope, qs: &QueryServer| {
+            let e = qs
+                .read()
+                .internal_search_uuid(audit, &UUID_ADMIN)
+                .expect("Failed to get admin");
+            e.get_ava_single("spn")
+                .and_then(|v| v.to_str())
+                .map(|s| s.to_string())
+        };
+        assert!(spn_admin(&mut audit, &qs) == Some("admin@localhost".to_string()));
+        let r = qs.verify(&mut audit);
+        assert!(r.iter().any(|r| match r {
+            Err(ConsistencyError::MemberOfInvalid(_)) => true,
+            _ => false,
+        }));
+        assert!(r.iter().any(|r| match r {
+            Err(ConsistencyError::SpnInvalid(_)) => true,
+            _ => false,
+        }));
+
+        // Start again over the same database. The queue was lost with the
+        // server.
+        drop(qs);
+        let schema = Schema::new(&mut audit).expect("Failed to init schema");
+        let qs = QueryServer::new(be, schema);
+        assert!(qs.initialise_helper(&mut audit).is_ok());
+        assert!(qs.delayed_actions() == 0);
+        assert!(qs.repair(&mut audit).is_ok());
+
+        assert!(members
+            .iter()
+            .all(|m| is_memberof(&mut audit, &qs, m, &group)));
+        assert!(spn_admin(&mut audit, &qs) == Some("admin@example.com".to_string()));
+        assert!(qs.verify(&mut audit).len() == 0);
+    }
+
+    // A consistent server with a person in a group, and the two entries.
+    fn setup_verify(
+        audit: &mut AuditScope,
+    ) -> (
+        QueryServer,
+        Entry<EntryValid, EntryCommitted>,
+        Entry<EntryValid, EntryCommitted>,
+    ) {
+        let be = Backend::new(audit, "", 1).expect("Failed to init be");
+        let qs = setup_delayed(audit, be);
+
+        let person = Uuid::new_v4();
+        let group = Uuid::new_v4();
+        let mut qs_write = qs.write();
+        assert!(qs_write
+            .internal_create(
+                audit,
+                vec![
+                    delayed_person("verify_person", person.clone()),
+                    delayed_group("verify_group", group.clone(), &[person.clone()]),
+                ],
+            )
+            .is_ok());
+        assert!(qs_write.commit(audit).is_ok());
+        assert!(qs.process_delayed_actions(audit).is_ok());
+        assert!(qs.verify_checks(audit, &[]).len() == 0);
+
+        let p = qs
+            .read()
+            .internal_search_uuid(audit, &person)
+            .expect("Failed to get entry");
+        let g = qs
+            .read()
+            .internal_search_uuid(audit, &group)
+            .expect("Failed to get entry");
+        (qs, p, g)
+    }
+
+    fn backdoor(audit: &mut AuditScope, qs: &QueryServer, e: Entry<EntryInvalid, EntryCommitted>) {
+        let qs_write = qs.write();
+        assert!(unsafe { qs_write.backdoor_modify(audit, vec![e]) }.is_ok());
+        assert!(qs_write.commit(audit).is_ok());
+    }
+
+    // Each check finds what was broken beneath it, and only that.
+    #[test]
+    fn test_qs_verify_checks() {
+        let mut audit = AuditScope::new("test_qs_verify_checks");
+        let (qs, p, g) = setup_verify(&mut audit);
+        let backdoor = |audit: &mut AuditScope, e| backdoor(audit, &qs, e);
+        let reported = |audit: &mut AuditScope, check: VerifyCheck, err: ConsistencyError| {
+            let r = qs.verify_checks(audit, &[check]);
+            r.len() > 0 && r.iter().all(|e| *e == err)
+        };
+
+        let mut e = p.clone().invalidate();
+        e.purge_ava("memberof");
+        backdoor(&mut audit, e);
+        assert!(reported(
+            &mut audit,
+            VerifyCheck::MemberOf,
+            ConsistencyError::MemberOfInvalid(p.get_id())
+        ));
+        assert!(qs
+            .verify_checks(
+                &mut audit,
+                &[VerifyCheck::Schema, VerifyCheck::Index, VerifyCheck::Refint]
+            )
+            .is_empty());
+        backdoor(&mut audit, p.clone().invalidate());
+
+        let mut e = g.clone().invalidate();
+        e.add_ava("member", &Value::new_refer(Uuid::new_v4()));
+        backdoor(&mut audit, e);
+        assert!(reported(
+            &mut audit,
+            VerifyCheck::Refint,
+            ConsistencyError::RefintNotUpheld(g.get_id())
+        ));
+        backdoor(&mut audit, g.clone().invalidate());
+
+        let mut e = p.clone().invalidate();
+        e.add_ava("not_an_attribute", &Value::new_utf8s("corrupt"));
+        backdoor(&mut audit, e);
+        assert!(reported(
+            &mut audit,
+            VerifyCheck::Schema,
+            ConsistencyError::EntrySchemaInvalid(p.get_id())
+        ));
+        backdoor(&mut audit, p.clone().invalidate());
+
+        let qs_write = qs.write();
+        qs_write
+            .get_be_txn()
+            .get_conn()
+            .execute(
+                format!("DELETE FROM idx_eq_name WHERE id = {}", p.get_id()).as_str(),
+                NO_PARAMS,
+            )
+            .expect("Failed to corrupt index");
+        assert!(qs_write.commit(&mut audit).is_ok());
+        assert!(reported(
+            &mut audit,
+            VerifyCheck::Index,
+            ConsistencyError::IndexInvalid("name.eq".to_string(), p.get_id())
+        ));
+        // A full verify stops at the index, as nothing after it can trust it.
+        assert!(
+            qs.verify_checks(&mut audit, &[])
+                == vec![ConsistencyError::IndexInvalid(
+                    "name.eq".to_string(),
+                    p.get_id()
+                )]
+        );
+
+        let mut qs_write = qs.write();
+        assert!(qs_write.reindex(&mut audit).is_ok());
+        assert!(qs_write.commit(&mut audit).is_ok());
+        assert!(qs.verify_checks(&mut audit, &[]).len() == 0);
+    }
+
+    #[test]
+    fn test_qs_repair_consistency() {
+        let mut audit = AuditScope::new("test_qs_repair_consistency");
+        let (qs, p, g) = setup_verify(&mut audit);
+
+        let mut e = p.clone().invalidate();
+        e.add_ava("memberof", &Value::new_refer(Uuid::new_v4()));
+        backdoor(&mut audit, &qs, e);
+        let mut e = g.clone().invalidate();
+        e.purge_ava("spn");
+        e.add_ava("member", &Value::new_refer(Uuid::new_v4()));
+        backdoor(&mut audit, &qs, e);
+        let qs_write = qs.write();
+        qs_write
+            .get_be_txn()
+            .get_conn()
+            .execute(
+                "INSERT INTO idx_eq_name (key, id) VALUES ('ghost', 99999)",
+                NO_PARAMS,
+            )
+            .expect("Failed to corrupt index");
+        assert!(qs_write.commit(&mut audit).is_ok());
+        let found = qs.verify_checks(&mut audit, &[]);
+        assert!(found.len() > 0);
+
+        // A dry run reports the same changes, but makes none of them.
+        let dry = qs
+            .repair_consistency(&mut audit, true)
+            .expect("Failed to repair");
+        assert!(dry.dry_run && dry.changes.len() > 0 && dry.unrepaired.is_empty());
+        assert!(qs.verify_checks(&mut audit, &[]) == found);
+
+        let r = qs
+            .repair_consistency(&mut audit, false)
+            .expect("Failed to repair");
+        assert!(r.changes == dry.changes && r.unrepaired.is_empty());
+        assert!(qs.verify_checks(&mut audit, &[]).len() == 0);
+        let changed = |target: &Uuid, attr: &str| {
+            let target = target.to_hyphenated_ref().to_string();
+            r.changes
+                .iter()
+                .any(|c| c.target == target && c.attr == attr)
+        };
+        assert!(r
+            .changes
+            .iter()
+            .any(|c| c.target == "id 99999" && c.attr == "index name.eq"));
+        assert!(changed(g.get_uuid(), "member"));
+        assert!(changed(g.get_uuid(), "spn"));
+        assert!(changed(p.get_uuid(), "memberof"));
+
+        // With two entries claiming one uuid, nothing derived can be trusted,
+        // so nothing is touched, even what could otherwise be repaired.
+        let mut e = p.clone().invalidate();
+        e.purge_ava("uuid");
+        e.add_ava("uuid", &Value::new_uuidr(g.get_uuid()));
+        e.add_ava("memberof", &Value::new_refer(Uuid::new_v4()));
+        backdoor(&mut audit, &qs, e);
+        let found = qs.verify_checks(&mut audit, &[]);
+        let r = qs
+            .repair_consistency(&mut audit, false)
+            .expect("Failed to repair");
+        assert!(r.changes.is_empty());
+        assert!(r
+            .unrepaired
+            .contains(&ConsistencyError::UuidNotUnique(g.get_uuid().to_string())));
+        assert!(qs.verify_checks(&mut audit, &[]) == found);
+    }
+
+    // Start a server over be, as a restart would.
+    fn start_builtins(audit: &mut AuditScope, be: &Backend) -> QueryServer {
+        let schema = Schema::new(audit).expect("Failed to init schema");
+        let qs = QueryServer::new(be.clone(), schema);
+        qs.initialise_helper(audit).expect("init failed!");
+        qs
+    }
+
+    fn migration_level(qs: &QueryServer) -> i64 {
+        qs.write().be_txn.get_migration_level()
+    }
+
+    fn admin_displayname(audit: &mut AuditScope, qs: &QueryServer) -> Option<String> {
+        qs.read()
+            .internal_search_uuid(audit, &UUID_ADMIN)
+            .ok()
+            .and_then(|e| e.get_ava_single_str("displayname").map(|s| s.to_string()))
+    }
+
+    fn set_admin_displayname(audit: &mut AuditScope, qs: &QueryServer, name: &str) {
+        let mut qs_write = qs.write();
+        assert!(qs_write
+            .internal_modify(
+                audit,
+                filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_ADMIN))),
+                ModifyList::new_list(vec![
+                    Modify::Purged("displayname".to_string()),
+                    Modify::Present("displayname".to_string(), Value::new_utf8s(name)),
+                ]),
+            )
+            .is_ok());
+        assert!(qs_write.commit(audit).is_ok());
+    }
+
+    #[test]
+    fn test_qs_migrate_fresh_start() {
+        let mut audit = AuditScope::new("test_qs_migrate_fresh_start");
+        let be = Backend::new(&mut audit, "", 1).expect("Failed to init be");
+        let qs = start_builtins(&mut audit, &be);
+        assert!(migration_level(&qs) == SYSTEM_MIGRATION_LEVEL);
+
+        let count = |audit: &mut AuditScope, qs: &QueryServer| {
+            qs.read()
+                .get_be_txn()
+                .count_entries(audit)
+                .expect("Failed to count")
+        };
+        let entries = count(&mut audit, &qs);
+        let idm_admins = Uuid::parse_str(_UUID_IDM_ADMINS).expect("Invalid uuid");
+        {
+            let qs_read = qs.read();
+            for u in [*UUID_ADMIN, *UUID_ANONYMOUS, idm_admins, *UUID_DOMAIN_INFO].iter() {
+                assert!(qs_read.internal_search_uuid(&mut audit, u).is_ok());
+            }
+        }
+        let domain_uuid = |audit: &mut AuditScope, qs: &QueryServer| {
+            qs.read()
+                .internal_search_uuid(audit, &UUID_DOMAIN_INFO)
+                .ok()
+                .and_then(|e| e.get_ava_single("domain_uuid").and_then(|v| v.to_uuid()))
+                .map(|u| u.clone())
+        };
+        let du = domain_uuid(&mut audit, &qs);
+        assert!(du.is_some());
+
+        // Starting again changes nothing.
+        drop(qs);
+        let qs = start_builtins(&mut audit, &be);
+        assert!(count(&mut audit, &qs) == entries);
+        assert!(domain_uuid(&mut audit, &qs) == du);
+        assert!(migration_level(&qs) == SYSTEM_MIGRATION_LEVEL);
+        assert!(qs.verify(&mut audit).len() == 0);
+    }
+
+    #[test]
+    fn test_qs_migrate_restart_after_tamper() {
+        let mut audit = AuditScope::new("test_qs_migrate_restart_after_tamper");
+        let be = Backend::new(&mut audit, "", 1).expect("Failed to init be");
+        let qs = start_builtins(&mut audit, &be);
+
+        set_admin_displayname(&mut audit, &qs, "Break Glass");
+
+        // The admin is deleted to the recycle bin, anonymous all the way to a
+        // tombstone, and the domain info is deleted too.
+        let ct = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("Clock failure!");
+        let max_age = Duration::from_secs(RECYCLEBIN_MAX_AGE);
+        let domain_uuid = {
+            let mut qs_write = qs.write();
+            let du = qs_write
+                .internal_search_uuid(&mut audit, &UUID_DOMAIN_INFO)
+                .ok()
+                .and_then(|e| e.get_ava_single("domain_uuid").and_then(|v| v.to_uuid()))
+                .map(|u| u.clone())
+                .expect("No domain uuid");
+            assert!(qs_write
+                .internal_delete(
+                    &mut audit,
+                    filter!(f_eq("uuid", PartialValue::new_uuidr(&UUID_ANONYMOUS)))
+                )
+                .is_ok());
+            assert!(qs_write
+                .purge_recycled(&mut audit, ct + max_age + Duration::from_secs(1), max_age)
+                .is_ok());
+            assert!(qs_write
+                .internal_delete(
+                    &mut audit,
+                    filter!(f_or!([
+                        f_eq("uuid", PartialValue::new_uuidr(&UUID_ADMIN)),
+                        f_eq("uuid", PartialValue::new_uuidr(&UUID_DOMAIN_INFO))
+                    ]))
+                )
+                .is_ok());
+            assert!(qs_write.commit(&mut audit).is_ok());
+            du
+        };
+        {
+            let qs_read = qs.read();
+            for u in [*UUID_ADMIN, *UUID_ANONYMOUS, *UUID_DOMAIN_INFO].iter() {
+                assert!(qs_read.internal_search_uuid(&mut audit, u).is_err());
+            }
+        }
+
+        // Each is back after a restart. The revived admin keeps its display
+        // name, and the domain info its uuid.
+        drop(qs);
+        let qs = start_builtins(&mut audit, &be);
+        assert!(admin_displayname(&mut audit, &qs) == Some("Break Glass".to_string()));
+        {
+            let qs_read = qs.read();
+            let anon = qs_read
+                .internal_search_uuid(&mut audit, &UUID_ANONYMOUS)
+                .expect("anonymous was not recreated");
+            assert!(anon.get_ava_single_str("name") == Some("anonymous"));
+            let di = qs_read
+                .internal_search_uuid(&mut audit, &UUID_DOMAIN_INFO)
+                .expect("domain info was not revived");
+            assert!(
+                di.get_ava_single("domain_uuid").and_then(|v| v.to_uuid()) == Some(&domain_uuid)
+            );
+            // As the admin lost its membership when deleted, idm_admins had no
+            // members, so the builtin's are put back.
+            let idm_admins = Uuid::parse_str(_UUID_IDM_ADMINS).expect("Invalid uuid");
+            let admin = qs_read
+                .internal_search_uuid(&mut audit, &UUID_ADMIN)
+                .expect("admin was not revived");
+            assert!(
+                admin
+                    .get_ava_reference_uuid("memberof")
+                    .map(|mo| mo.contains(&&idm_admins))
+                    == Some(true)
+            );
+        }
+        assert!(qs.verify(&mut audit).len() == 0);
+    }
+
+    #[test]
+    fn test_qs_migrate_level() {
+        let mut audit = AuditScope::new("test_qs_migrate_level");
+        let be = Backend::new(&mut audit, "", 1).expect("Failed to init be");
+        let qs = start_builtins(&mut audit, &be);
+
+        // At the level, changes made to the builtins survive a restart.
+        set_admin_displayname(&mut audit, &qs, "Break Glass");
+        drop(qs);
+        let qs = start_builtins(&mut audit, &be);
+        assert!(admin_displayname(&mut audit, &qs) == Some("Break Glass".to_string()));
+
+        // Below it, as after an upgrade, the builtins are brought up to their
+        // definitions, and the level raised.
+        {
+            let mut qs_write = qs.write();
+            assert!(qs_write
+                .be_txn
+                .set_migration_level(&mut audit, SYSTEM_MIGRATION_LEVEL - 1)
+                .is_ok());
+            assert!(qs_write.commit(&mut audit).is_ok());
+        }
+        drop(qs);
+        let qs = start_builtins(&mut audit, &be);
+        assert!(admin_displayname(&mut audit, &qs) == Some("Administrator".to_string()));
+        assert!(migration_level(&qs) == SYSTEM_MIGRATION_LEVEL);
+
+        // A database migrated by a newer server isn't started.
+        {
+            let mut qs_write = qs.write();
+            assert!(qs_write
+                .be_txn
+                .set_migration_level(&mut audit, SYSTEM_MIGRATION_LEVEL + 1)
+                .is_ok());
+            assert!(qs_write.commit(&mut audit).is_ok());
+        }
+        drop(qs);
+        let schema = Schema::new(&mut audit).expect("Failed to init schema");
+        let qs = QueryServer::new(be.clone(), schema);
+        assert!(qs.initialise_helper(&mut audit) == Err(OperationError::InvalidDBState));
     }
 
     /*
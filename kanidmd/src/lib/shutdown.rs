@@ -0,0 +1,119 @@
+// Stopping the server on SIGTERM or SIGINT. No new connections are accepted,
+// then the requests in progress are given until the grace period ends to
+// finish, then the writer is given what's left of it to apply what's queued
+// and to checkpoint the database. The system then stops, with the code
+// kanidmd exits with saying whether all of that finished in time. A second
+// signal stops the server at once.
+use actix::actors::signal;
+use actix::prelude::*;
+use actix_web::server::{Server, StopServer};
+use futures::future::{self, Future};
+use std::time::{Duration, Instant};
+
+use crate::actors::v1::QueryServerV1;
+use crate::constants::{EXIT_SHUTDOWN_CLEAN, EXIT_SHUTDOWN_FORCED};
+use crate::event::ShutdownEvent;
+
+pub struct ShutdownActor {
+    http_servers: Vec<Addr<Server>>,
+    server_write: Addr<QueryServerV1>,
+    grace_period: Duration,
+    stopping: bool,
+}
+
+impl ShutdownActor {
+    pub fn new(
+        http_servers: Vec<Addr<Server>>,
+        server_write: Addr<QueryServerV1>,
+        grace_period: u64,
+    ) -> Self {
+        ShutdownActor {
+            http_servers: http_servers,
+            server_write: server_write,
+            grace_period: Duration::from_secs(grace_period),
+            stopping: false,
+        }
+    }
+
+    fn shutdown(&mut self, ctx: &mut Context<Self>) {
+        info!(
+            "Stopping, waiting up to {}s for requests in progress",
+            self.grace_period.as_secs()
+        );
+        let begun = Instant::now();
+        let grace_period = self.grace_period;
+
+        // Whatever is still going at the end of the grace period is cut off.
+        ctx.run_later(grace_period, |_, _| {
+            warn!("Grace period ended, stopping now");
+            System::current().stop_with_code(EXIT_SHUTDOWN_FORCED);
+        });
+
+        // The listeners close at once, and the servers reply once their
+        // connections have, or the grace period ends, which they were also
+        // given.
+        let server_write = self.server_write.clone();
+        let stops = self
+            .http_servers
+            .iter()
+            .map(|s| {
+                s.send(StopServer { graceful: true })
+                    .then(|_| Ok::<(), ()>(()))
+            })
+            .collect::<Vec<_>>();
+        let stopped = future::join_all(stops)
+            .and_then(move |_: Vec<()>| {
+                info!("Requests finished, flushing queued writes");
+                server_write.send(ShutdownEvent::new()).then(Ok)
+            })
+            .map(move |r| {
+                let code = match r {
+                    Ok(Ok(())) if begun.elapsed() < grace_period => {
+                        info!("Stopped cleanly");
+                        EXIT_SHUTDOWN_CLEAN
+                    }
+                    Ok(Ok(())) => {
+                        warn!("Requests in progress were cut off by the grace period");
+                        EXIT_SHUTDOWN_FORCED
+                    }
+                    Ok(Err(e)) => {
+                        error!("Failed to flush the database -> {:?}", e);
+                        EXIT_SHUTDOWN_FORCED
+                    }
+                    Err(e) => {
+                        error!("Failed to reach the writer -> {:?}", e);
+                        EXIT_SHUTDOWN_FORCED
+                    }
+                };
+                System::current().stop_with_code(code);
+            });
+        ctx.spawn(stopped.into_actor(self));
+    }
+}
+
+impl Actor for ShutdownActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let signals = signal::ProcessSignals::from_registry();
+        signals.do_send(signal::Subscribe(ctx.address().recipient()));
+    }
+}
+
+impl Handler<signal::Signal> for ShutdownActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: signal::Signal, ctx: &mut Self::Context) {
+        match msg.0 {
+            signal::SignalType::Term | signal::SignalType::Int => {}
+            _ => return,
+        }
+        if self.stopping {
+            warn!("Signalled again while stopping, stopping now");
+            System::current().stop_with_code(EXIT_SHUTDOWN_FORCED);
+        } else {
+            self.stopping = true;
+            self.shutdown(ctx);
+        }
+    }
+}
@@ -0,0 +1,198 @@
+// The certificate and key the server presents, for https and ldaps. These
+// are loaded again on SIGHUP, so a renewed certificate is used without a
+// restart. Each handshake takes whichever were loaded last, so connections
+// that are already open are left as they are.
+use actix::actors::signal;
+use actix::prelude::*;
+use openssl::asn1::Asn1Time;
+use openssl::error::ErrorStack;
+use openssl::pkey::{PKey, Private};
+use openssl::ssl::{
+    select_next_proto, AlpnError, SniError, SslAcceptor, SslAcceptorBuilder, SslContext,
+    SslFiletype, SslMethod, SslVersion,
+};
+use openssl::x509::X509;
+use std::cmp::Ordering;
+use std::fs;
+use std::sync::{Arc, RwLock};
+
+use crate::config::{TlsConfiguration, TlsVersion};
+
+fn read_pem(path: &str) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|e| format!("can't read {} - {}", path, e))
+}
+
+// The first certificate is the one that's presented, so it's the one the
+// key must belong to.
+pub fn load_tls_chain(path: &str) -> Result<X509, String> {
+    let pem = read_pem(path)?;
+    X509::stack_from_pem(&pem)
+        .map_err(|e| format!("{} is not a pem certificate chain - {}", path, e))?
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("{} contains no certificates", path))
+}
+
+pub fn load_tls_key(path: &str) -> Result<PKey<Private>, String> {
+    let pem = read_pem(path)?;
+    PKey::private_key_from_pem(&pem)
+        .map_err(|e| format!("{} is not a pem private key - {}", path, e))
+}
+
+// Every problem with the files, with the option, as the config file names
+// it, of the file that has it.
+pub fn check_tls_files(tls_config: &TlsConfiguration) -> Vec<(&'static str, String)> {
+    let mut errs = Vec::new();
+    if let Err(e) = load_tls_chain(tls_config.ca.as_str()) {
+        errs.push(("tls.ca", e));
+    }
+    let cert = load_tls_chain(tls_config.cert.as_str()).map_err(|e| errs.push(("tls.chain", e)));
+    let pkey = load_tls_key(tls_config.key.as_str()).map_err(|e| errs.push(("tls.key", e)));
+
+    if let Ok(cert) = &cert {
+        let expired = Asn1Time::days_from_now(0)
+            .and_then(|now| cert.not_after().compare(&now))
+            .map(|o| o != Ordering::Greater)
+            .unwrap_or(true);
+        if expired {
+            errs.push((
+                "tls.chain",
+                format!(
+                    "the certificate in {} expired at {}",
+                    tls_config.cert,
+                    cert.not_after()
+                ),
+            ));
+        }
+    }
+    if let (Ok(cert), Ok(pkey)) = (cert, pkey) {
+        let matched = cert
+            .public_key()
+            .map(|pubkey| pubkey.public_eq(&pkey))
+            .unwrap_or(false);
+        if !matched {
+            errs.push((
+                "tls.key",
+                format!(
+                    "{} is not the key of the certificate in {}",
+                    tls_config.key, tls_config.cert
+                ),
+            ));
+        }
+    }
+    errs
+}
+
+fn acceptor_builder(tls_config: &TlsConfiguration) -> Result<SslAcceptorBuilder, ErrorStack> {
+    let mut ssl_builder = SslAcceptor::mozilla_modern(SslMethod::tls())?;
+    ssl_builder.set_ca_file(&tls_config.ca)?;
+    ssl_builder.set_private_key_file(&tls_config.key, SslFiletype::PEM)?;
+    ssl_builder.set_certificate_chain_file(&tls_config.cert)?;
+    ssl_builder.check_private_key()?;
+    if let Some(v) = &tls_config.min_version {
+        ssl_builder.set_min_proto_version(Some(match v {
+            TlsVersion::Tls12 => SslVersion::TLS1_2,
+            TlsVersion::Tls13 => SslVersion::TLS1_3,
+        }))?;
+    }
+    Ok(ssl_builder)
+}
+
+// The protocols actix-web offers with tls, in the wire format.
+const ALPN_PROTOCOLS: &[u8] = b"\x02h2\x08http/1.1";
+
+// The context a connection is switched to. Protocols are negotiated with the
+// callback of the context switched to, so it offers what actix-web would for
+// https. Ldap clients don't ask for any.
+fn load_context(tls_config: &TlsConfiguration) -> Result<SslContext, String> {
+    let errs = check_tls_files(tls_config);
+    if !errs.is_empty() {
+        let msgs: Vec<_> = errs.into_iter().map(|(_, e)| e).collect();
+        return Err(msgs.join(", "));
+    }
+    acceptor_builder(tls_config)
+        .map(|mut b| {
+            b.set_alpn_select_callback(|_, client| {
+                select_next_proto(ALPN_PROTOCOLS, client).ok_or(AlpnError::NOACK)
+            });
+            b.build().into_context()
+        })
+        .map_err(|e| format!("{:?}", e))
+}
+
+#[derive(Clone)]
+pub struct TlsCertificates {
+    tls_config: Arc<TlsConfiguration>,
+    current: Arc<RwLock<SslContext>>,
+}
+
+impl TlsCertificates {
+    // Refuses files that couldn't be used, such as a key that isn't the
+    // certificate's or a certificate that has expired.
+    pub fn load(tls_config: &TlsConfiguration) -> Result<Self, String> {
+        let ctx = load_context(tls_config)?;
+        Ok(TlsCertificates {
+            tls_config: Arc::new(tls_config.clone()),
+            current: Arc::new(RwLock::new(ctx)),
+        })
+    }
+
+    // If the files are no longer usable, what was loaded before is kept.
+    pub fn reload(&self) -> Result<(), String> {
+        let ctx = load_context(&self.tls_config)?;
+        let mut current = self
+            .current
+            .write()
+            .map_err(|_| "certificate lock poisoned".to_string())?;
+        *current = ctx;
+        Ok(())
+    }
+
+    // An acceptor for a listener, which switches each connection to the
+    // certificate loaded last. The switch is made when the client hello is
+    // read, which is before anything of the certificate has been sent.
+    pub fn acceptor(&self) -> Result<SslAcceptorBuilder, ErrorStack> {
+        let mut ssl_builder = acceptor_builder(&self.tls_config)?;
+        let current = self.current.clone();
+        ssl_builder.set_servername_callback(move |ssl, _alert| {
+            let ctx = current.read().map_err(|_| SniError::ALERT_FATAL)?;
+            ssl.set_ssl_context(&ctx).map_err(|_| SniError::ALERT_FATAL)
+        });
+        Ok(ssl_builder)
+    }
+}
+
+pub struct TlsReloadActor {
+    certs: TlsCertificates,
+}
+
+impl TlsReloadActor {
+    pub fn new(certs: TlsCertificates) -> Self {
+        TlsReloadActor { certs: certs }
+    }
+}
+
+impl Actor for TlsReloadActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let signals = signal::ProcessSignals::from_registry();
+        signals.do_send(signal::Subscribe(ctx.address().recipient()));
+    }
+}
+
+impl Handler<signal::Signal> for TlsReloadActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: signal::Signal, _: &mut Self::Context) {
+        if let signal::SignalType::Hup = msg.0 {
+            match self.certs.reload() {
+                Ok(_) => info!("Reloaded TLS certificates"),
+                Err(e) => error!(
+                    "Failed to reload TLS certificates, keeping the current ones -> {}",
+                    e
+                ),
+            }
+        }
+    }
+}
@@ -1,16 +1,21 @@
-use crate::be::dbvalue::{DbValueCredV1, DbValueV1};
+use crate::be::dbvalue::{DbValueCredV1, DbValueTaggedStringV1, DbValueV1};
+use crate::credential::apitoken::ApiToken;
 use crate::credential::Credential;
 use kanidm_proto::v1::Filter as ProtoFilter;
+use kanidm_proto::v1::REDACTED;
 
+use chrono::{DateTime, SecondsFormat, Utc};
 use std::borrow::Borrow;
+use std::collections::BTreeSet;
 use std::convert::TryFrom;
+use std::fmt;
 use std::str::FromStr;
 use uuid::Uuid;
 
 use std::cmp::Ordering;
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize, Serialize)]
 pub enum IndexType {
     EQUALITY,
     PRESENCE,
@@ -76,6 +81,21 @@ pub enum SyntaxType {
     REFERENCE_UUID,
     JSON_FILTER,
     CREDENTIAL,
+    UINT32,
+    // Arbitrary bytes, such as certificates or photos. These are base64 encoded
+    // in the proto, and can only be matched with equality or presence.
+    BINARY,
+    // An rfc3339 timestamp. Any offset is accepted, but it is normalised to utc.
+    DATETIME,
+    // A service account api token. Only the token id is exposed, the hashed
+    // secret is held in the data value.
+    API_TOKEN,
+    // A secret given to radius servers in cleartext, as radius needs it.
+    // It's only ever read through the IDM api, and is hidden otherwise.
+    RADIUS_UTF8STRING,
+    // An openssh public key, given as "tag: key". The tag is what it's
+    // matched and removed by.
+    SSHKEY,
 }
 
 impl TryFrom<&str> for SyntaxType {
@@ -93,6 +113,12 @@ impl TryFrom<&str> for SyntaxType {
             "REFERENCE_UUID" => Ok(SyntaxType::REFERENCE_UUID),
             "JSON_FILTER" => Ok(SyntaxType::JSON_FILTER),
             "CREDENTIAL" => Ok(SyntaxType::CREDENTIAL),
+            "UINT32" => Ok(SyntaxType::UINT32),
+            "BINARY" => Ok(SyntaxType::BINARY),
+            "DATETIME" => Ok(SyntaxType::DATETIME),
+            "API_TOKEN" => Ok(SyntaxType::API_TOKEN),
+            "RADIUS_UTF8STRING" => Ok(SyntaxType::RADIUS_UTF8STRING),
+            "SSHKEY" => Ok(SyntaxType::SSHKEY),
             _ => Err(()),
         }
     }
@@ -112,6 +138,12 @@ impl TryFrom<usize> for SyntaxType {
             6 => Ok(SyntaxType::REFERENCE_UUID),
             7 => Ok(SyntaxType::JSON_FILTER),
             8 => Ok(SyntaxType::CREDENTIAL),
+            9 => Ok(SyntaxType::UINT32),
+            10 => Ok(SyntaxType::BINARY),
+            11 => Ok(SyntaxType::DATETIME),
+            12 => Ok(SyntaxType::API_TOKEN),
+            13 => Ok(SyntaxType::RADIUS_UTF8STRING),
+            14 => Ok(SyntaxType::SSHKEY),
             _ => Err(()),
         }
     }
@@ -129,6 +161,12 @@ impl SyntaxType {
             SyntaxType::REFERENCE_UUID => "REFERENCE_UUID",
             SyntaxType::JSON_FILTER => "JSON_FILTER",
             SyntaxType::CREDENTIAL => "CREDENTIAL",
+            SyntaxType::UINT32 => "UINT32",
+            SyntaxType::BINARY => "BINARY",
+            SyntaxType::DATETIME => "DATETIME",
+            SyntaxType::API_TOKEN => "API_TOKEN",
+            SyntaxType::RADIUS_UTF8STRING => "RADIUS_UTF8STRING",
+            SyntaxType::SSHKEY => "SSHKEY",
         })
     }
 
@@ -143,15 +181,61 @@ impl SyntaxType {
             SyntaxType::REFERENCE_UUID => 6,
             SyntaxType::JSON_FILTER => 7,
             SyntaxType::CREDENTIAL => 8,
+            SyntaxType::UINT32 => 9,
+            SyntaxType::BINARY => 10,
+            SyntaxType::DATETIME => 11,
+            SyntaxType::API_TOKEN => 12,
+            SyntaxType::RADIUS_UTF8STRING => 13,
+            SyntaxType::SSHKEY => 14,
+        }
+    }
+
+    // Does equality on this syntax ignore case? If so, values are folded to
+    // lowercase before they are parsed, stored or compared. Anything that may
+    // contain secret or exact data must remain case sensitive.
+    pub fn is_case_insensitive(&self) -> bool {
+        match self {
+            SyntaxType::UTF8STRING_INSENSITIVE => true,
+            SyntaxType::UUID => true,
+            SyntaxType::BOOLEAN => true,
+            SyntaxType::SYNTAX_ID => true,
+            SyntaxType::INDEX_ID => true,
+            SyntaxType::REFERENCE_UUID => true,
+            // This is the tag, not the credential itself.
+            SyntaxType::CREDENTIAL => true,
+            // As above, this is the token id.
+            SyntaxType::API_TOKEN => true,
+            SyntaxType::UTF8STRING => false,
+            SyntaxType::JSON_FILTER => false,
+            SyntaxType::UINT32 => false,
+            SyntaxType::BINARY => false,
+            // The 'T' and 'Z' may be lowercase in rfc3339, and the parser
+            // accepts either.
+            SyntaxType::DATETIME => false,
+            SyntaxType::RADIUS_UTF8STRING => false,
+            // The key material is base64, so folding it would corrupt it.
+            SyntaxType::SSHKEY => false,
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub enum DataValue {
     Cred(Credential),
-    // SshKey(String),
-    // RadiusCred(String),
+    ApiToken(ApiToken),
+    SshKey(String),
+    RadiusCred(String),
+}
+
+impl fmt::Debug for DataValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DataValue::Cred(c) => f.debug_tuple("Cred").field(c).finish(),
+            DataValue::ApiToken(t) => f.debug_tuple("ApiToken").field(t).finish(),
+            DataValue::SshKey(k) => f.debug_tuple("SshKey").field(k).finish(),
+            DataValue::RadiusCred(_) => write!(f, "RadiusCred({})", REDACTED),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Eq, Ord, PartialOrd, PartialEq, Deserialize, Serialize)]
@@ -168,8 +252,15 @@ pub enum PartialValue {
     JsonFilt(ProtoFilter),
     // Tag, matches to a DataValue.
     Cred(String),
-    // SshKey(String),
-    // RadiusCred(String),
+    Uint32(u32),
+    Binary(Vec<u8>),
+    DateTime(DateTime<Utc>),
+    // Token id, matches to a DataValue.
+    ApiToken(Uuid),
+    // Tag, matches to a DataValue.
+    SshKey(String),
+    // The secret is in the DataValue, so that it's never in a filter.
+    RadiusCred,
 }
 
 impl PartialValue {
@@ -329,6 +420,131 @@ impl PartialValue {
         }
     }
 
+    pub fn new_uint32(u: u32) -> Self {
+        PartialValue::Uint32(u)
+    }
+
+    pub fn new_uint32s(s: &str) -> Option<Self> {
+        match u32::from_str(s) {
+            Ok(u) => Some(PartialValue::Uint32(u)),
+            Err(_) => None,
+        }
+    }
+
+    pub fn is_uint32(&self) -> bool {
+        match self {
+            PartialValue::Uint32(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn new_binary(b: Vec<u8>) -> Self {
+        PartialValue::Binary(b)
+    }
+
+    // Binary values are base64 encoded in the proto.
+    pub fn new_binarys(s: &str) -> Option<Self> {
+        match base64::decode(s) {
+            Ok(b) => Some(PartialValue::Binary(b)),
+            Err(_) => None,
+        }
+    }
+
+    pub fn is_binary(&self) -> bool {
+        match self {
+            PartialValue::Binary(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn new_datetime(dt: DateTime<Utc>) -> Self {
+        PartialValue::DateTime(dt)
+    }
+
+    pub fn new_datetimes(s: &str) -> Option<Self> {
+        match DateTime::parse_from_rfc3339(s) {
+            Ok(dt) => Some(PartialValue::DateTime(dt.with_timezone(&Utc))),
+            Err(_) => None,
+        }
+    }
+
+    pub fn is_datetime(&self) -> bool {
+        match self {
+            PartialValue::DateTime(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn new_apitoken_id(u: Uuid) -> Self {
+        PartialValue::ApiToken(u)
+    }
+
+    pub fn new_apitoken_ids(s: &str) -> Option<Self> {
+        Uuid::parse_str(s).map(PartialValue::ApiToken).ok()
+    }
+
+    pub fn is_apitoken(&self) -> bool {
+        match self {
+            PartialValue::ApiToken(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn new_radius_string() -> Self {
+        PartialValue::RadiusCred
+    }
+
+    pub fn new_sshkey_tag(s: String) -> Self {
+        PartialValue::SshKey(s)
+    }
+
+    pub fn new_sshkey_tagr(s: &str) -> Self {
+        PartialValue::SshKey(s.trim().to_string())
+    }
+
+    pub fn is_sshkey(&self) -> bool {
+        match self {
+            PartialValue::SshKey(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_radius_string(&self) -> bool {
+        match self {
+            PartialValue::RadiusCred => true,
+            _ => false,
+        }
+    }
+
+    pub(crate) fn to_proto_string_clone(&self) -> String {
+        match self {
+            PartialValue::Utf8(s) => s.clone(),
+            PartialValue::Iutf8(s) => s.clone(),
+            PartialValue::Uuid(u) => u.to_hyphenated_ref().to_string(),
+            PartialValue::Bool(b) => b.to_string(),
+            PartialValue::Syntax(syn) => syn.to_string(),
+            PartialValue::Index(it) => it.to_string(),
+            // In resolve value, we bypass this, but we keep it here for complete
+            // impl sake.
+            PartialValue::Refer(u) => u.to_hyphenated_ref().to_string(),
+            PartialValue::JsonFilt(s) => {
+                serde_json::to_string(s).expect("A json filter value was corrupted during run-time")
+            }
+            PartialValue::Cred(tag) => {
+                // You can't actually read the credential values because we only display the
+                // tag to the proto side. The credentials private data is stored seperately.
+                tag.to_string()
+            }
+            PartialValue::Uint32(u) => u.to_string(),
+            PartialValue::Binary(b) => base64::encode(b),
+            PartialValue::DateTime(dt) => dt.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+            PartialValue::ApiToken(u) => u.to_hyphenated_ref().to_string(),
+            // Radius servers read the secret through the IDM api instead.
+            PartialValue::RadiusCred => "radius".to_string(),
+            PartialValue::SshKey(tag) => tag.clone(),
+        }
+    }
+
     pub fn to_str(&self) -> Option<&str> {
         match self {
             PartialValue::Utf8(s) => Some(s.as_str()),
@@ -348,6 +564,53 @@ impl PartialValue {
             _ => false,
         }
     }
+
+    // Only some syntaxes have a meaningful order - strings are lexical, and
+    // integers are numeric. Everything else (uuids, bools, filters ...) can
+    // only be compared for equality.
+    pub fn is_orderable(&self) -> bool {
+        match self {
+            PartialValue::Utf8(_)
+            | PartialValue::Iutf8(_)
+            | PartialValue::Uint32(_)
+            | PartialValue::DateTime(_) => true,
+            _ => false,
+        }
+    }
+
+    // The key that an equality index keeps this value under. Equal values
+    // always have the same key, so a lookup finds just what an equality test
+    // would.
+    pub fn get_idx_eq_key(&self) -> String {
+        serde_json::to_string(self).expect("A partial value failed to serialise")
+    }
+
+    // The keys that a substring index keeps this value under, which are its
+    // runs of three characters. A value that contains a substring has every
+    // key of that substring, so strings shorter than three have none, and
+    // can't be looked up.
+    pub fn get_idx_sub_keys(&self) -> BTreeSet<String> {
+        match self.to_str() {
+            Some(s) => {
+                let chars: Vec<char> = s.chars().collect();
+                chars.windows(3).map(|w| w.iter().collect()).collect()
+            }
+            None => BTreeSet::new(),
+        }
+    }
+
+    // We can't rely on the derived Ord here, because that would happily order
+    // across differing types. This returns None if the two values aren't
+    // comparable.
+    pub fn ordering(&self, s: &PartialValue) -> Option<Ordering> {
+        match (self, s) {
+            (PartialValue::Utf8(s1), PartialValue::Utf8(s2)) => Some(s1.cmp(s2)),
+            (PartialValue::Iutf8(s1), PartialValue::Iutf8(s2)) => Some(s1.cmp(s2)),
+            (PartialValue::Uint32(u1), PartialValue::Uint32(u2)) => Some(u1.cmp(u2)),
+            (PartialValue::DateTime(d1), PartialValue::DateTime(d2)) => Some(d1.cmp(d2)),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -664,10 +927,162 @@ impl Value {
     pub fn to_credential(&self) -> Option<&Credential> {
         match &self.pv {
             PartialValue::Cred(_) => match &self.data {
-                Some(dv) => match dv {
-                    DataValue::Cred(c) => Some(&c),
-                },
-                None => None,
+                Some(DataValue::Cred(c)) => Some(c),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn new_uint32(u: u32) -> Self {
+        Value {
+            pv: PartialValue::new_uint32(u),
+            data: None,
+        }
+    }
+
+    pub fn new_uint32s(s: &str) -> Option<Self> {
+        Some(Value {
+            pv: PartialValue::new_uint32s(s)?,
+            data: None,
+        })
+    }
+
+    pub fn is_uint32(&self) -> bool {
+        match &self.pv {
+            PartialValue::Uint32(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn to_uint32(&self) -> Option<u32> {
+        match &self.pv {
+            PartialValue::Uint32(u) => Some(*u),
+            _ => None,
+        }
+    }
+
+    pub fn new_binary(b: Vec<u8>) -> Self {
+        Value {
+            pv: PartialValue::new_binary(b),
+            data: None,
+        }
+    }
+
+    pub fn new_binarys(s: &str) -> Option<Self> {
+        Some(Value {
+            pv: PartialValue::new_binarys(s)?,
+            data: None,
+        })
+    }
+
+    pub fn is_binary(&self) -> bool {
+        self.pv.is_binary()
+    }
+
+    pub fn to_binary(&self) -> Option<&[u8]> {
+        match &self.pv {
+            PartialValue::Binary(b) => Some(b.as_slice()),
+            _ => None,
+        }
+    }
+
+    pub fn new_datetime(dt: DateTime<Utc>) -> Self {
+        Value {
+            pv: PartialValue::new_datetime(dt),
+            data: None,
+        }
+    }
+
+    pub fn new_datetimes(s: &str) -> Option<Self> {
+        Some(Value {
+            pv: PartialValue::new_datetimes(s)?,
+            data: None,
+        })
+    }
+
+    pub fn is_datetime(&self) -> bool {
+        self.pv.is_datetime()
+    }
+
+    pub fn to_datetime(&self) -> Option<&DateTime<Utc>> {
+        match &self.pv {
+            PartialValue::DateTime(dt) => Some(dt),
+            _ => None,
+        }
+    }
+
+    pub fn new_apitoken(at: ApiToken) -> Self {
+        Value {
+            pv: PartialValue::new_apitoken_id(at.id),
+            data: Some(DataValue::ApiToken(at)),
+        }
+    }
+
+    pub fn is_apitoken(&self) -> bool {
+        self.pv.is_apitoken()
+    }
+
+    pub fn to_apitoken(&self) -> Option<&ApiToken> {
+        match &self.pv {
+            PartialValue::ApiToken(_) => match &self.data {
+                Some(DataValue::ApiToken(at)) => Some(at),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn new_radius_str(s: &str) -> Self {
+        Value {
+            pv: PartialValue::new_radius_string(),
+            data: Some(DataValue::RadiusCred(s.to_string())),
+        }
+    }
+
+    pub fn is_radius_string(&self) -> bool {
+        self.pv.is_radius_string()
+    }
+
+    pub fn get_radius_secret(&self) -> Option<&str> {
+        match &self.pv {
+            PartialValue::RadiusCred => match &self.data {
+                Some(DataValue::RadiusCred(s)) => Some(s.as_str()),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // The key must parse as an openssh public key, so that a bad key is
+    // found here rather than when someone tries to login with it.
+    pub fn new_sshkey(tag: &str, key: &str) -> Option<Self> {
+        let tag = tag.trim();
+        if tag.is_empty() {
+            return None;
+        }
+        let key = normalise_ssh_publickey(key)?;
+        Some(Value {
+            pv: PartialValue::new_sshkey_tagr(tag),
+            data: Some(DataValue::SshKey(key)),
+        })
+    }
+
+    // As given in the proto, "tag: key".
+    pub fn new_sshkey_str(s: &str) -> Option<Self> {
+        let (tag, key) = s.split_once(':')?;
+        Value::new_sshkey(tag, key)
+    }
+
+    pub fn is_sshkey(&self) -> bool {
+        self.pv.is_sshkey()
+    }
+
+    pub fn get_sshkey(&self) -> Option<&str> {
+        match &self.pv {
+            PartialValue::SshKey(_) => match &self.data {
+                Some(DataValue::SshKey(key)) => Some(key.as_str()),
+                _ => None,
             },
             _ => None,
         }
@@ -677,6 +1092,10 @@ impl Value {
         self.pv.contains(s)
     }
 
+    pub fn ordering(&self, s: &PartialValue) -> Option<Ordering> {
+        self.pv.ordering(s)
+    }
+
     // Converters between DBRepr -> MemRepr. It's likely many of these
     // will be just wrappers to our from str types.
 
@@ -730,6 +1149,28 @@ impl Value {
                     data: Some(DataValue::Cred(Credential::try_from(dvc.d)?)),
                 })
             }
+            DbValueV1::N32(u) => Ok(Value {
+                pv: PartialValue::Uint32(u),
+                data: None,
+            }),
+            DbValueV1::BI(b) => Ok(Value {
+                pv: PartialValue::Binary(b),
+                data: None,
+            }),
+            DbValueV1::DT(s) => Ok(Value {
+                pv: PartialValue::new_datetimes(s.as_str()).ok_or(())?,
+                data: None,
+            }),
+            DbValueV1::AT(dat) => {
+                let at = ApiToken::try_from(dat)?;
+                Ok(Value::new_apitoken(at))
+            }
+            DbValueV1::RU(s) => Ok(Value::new_radius_str(s.as_str())),
+            // This was checked when it was written, so it isn't parsed again.
+            DbValueV1::SK(ts) => Ok(Value {
+                pv: PartialValue::SshKey(ts.t),
+                data: Some(DataValue::SshKey(ts.d)),
+            }),
         }
     }
 
@@ -750,13 +1191,8 @@ impl Value {
             PartialValue::Cred(tag) => {
                 // Get the credential out and make sure it matches the type we expect.
                 let c = match &self.data {
-                    Some(v) => {
-                        match &v {
-                            DataValue::Cred(c) => c,
-                            // _ => panic!(),
-                        }
-                    }
-                    None => panic!(),
+                    Some(DataValue::Cred(c)) => c,
+                    _ => panic!(),
                 };
 
                 // Save the tag AND the dataValue here!
@@ -765,6 +1201,26 @@ impl Value {
                     d: c.to_db_valuev1(),
                 })
             }
+            PartialValue::Uint32(u) => DbValueV1::N32(u.clone()),
+            PartialValue::Binary(b) => DbValueV1::BI(b.clone()),
+            PartialValue::DateTime(dt) => {
+                DbValueV1::DT(dt.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+            }
+            PartialValue::ApiToken(_) => match &self.data {
+                Some(DataValue::ApiToken(at)) => DbValueV1::AT(at.to_dbapitokenv1()),
+                _ => panic!(),
+            },
+            PartialValue::RadiusCred => match &self.data {
+                Some(DataValue::RadiusCred(s)) => DbValueV1::RU(s.clone()),
+                _ => panic!(),
+            },
+            PartialValue::SshKey(tag) => match &self.data {
+                Some(DataValue::SshKey(key)) => DbValueV1::SK(DbValueTaggedStringV1 {
+                    t: tag.clone(),
+                    d: key.clone(),
+                }),
+                _ => panic!(),
+            },
         }
     }
 
@@ -832,24 +1288,18 @@ impl Value {
     }
 
     pub(crate) fn to_proto_string_clone(&self) -> String {
-        match &self.pv {
-            PartialValue::Utf8(s) => s.clone(),
-            PartialValue::Iutf8(s) => s.clone(),
-            PartialValue::Uuid(u) => u.to_hyphenated_ref().to_string(),
-            PartialValue::Bool(b) => b.to_string(),
-            PartialValue::Syntax(syn) => syn.to_string(),
-            PartialValue::Index(it) => it.to_string(),
-            // In resolve value, we bypass this, but we keep it here for complete
-            // impl sake.
-            PartialValue::Refer(u) => u.to_hyphenated_ref().to_string(),
-            PartialValue::JsonFilt(s) => {
-                serde_json::to_string(s).expect("A json filter value was corrupted during run-time")
+        match (&self.pv, &self.data) {
+            // As with credentials, the secret is never shown, only what
+            // identifies the token.
+            (PartialValue::ApiToken(u), Some(DataValue::ApiToken(at))) => {
+                format!("{}: {}", u.to_hyphenated_ref(), at.label)
             }
-            PartialValue::Cred(tag) => {
-                // You can't actually read the credential values because we only display the
-                // tag to the proto side. The credentials private data is stored seperately.
-                tag.to_string()
+            // Public keys aren't secret, so unlike credentials these are
+            // shown in full.
+            (PartialValue::SshKey(tag), Some(DataValue::SshKey(key))) => {
+                format!("{}: {}", tag, key)
             }
+            (pv, _) => pv.to_proto_string_clone(),
         }
     }
 
@@ -859,19 +1309,97 @@ impl Value {
         // data.
         match &self.pv {
             PartialValue::Cred(_) => match &self.data {
-                Some(v) => {
-                    match &v {
-                        DataValue::Cred(_) => true,
-                        // _ => false,
-                    }
-                }
-                None => false,
+                Some(DataValue::Cred(_)) => true,
+                _ => false,
+            },
+            PartialValue::ApiToken(_) => match &self.data {
+                Some(DataValue::ApiToken(_)) => true,
+                _ => false,
+            },
+            PartialValue::RadiusCred => match &self.data {
+                Some(DataValue::RadiusCred(_)) => true,
+                _ => false,
+            },
+            PartialValue::SshKey(_) => match &self.data {
+                Some(DataValue::SshKey(_)) => true,
+                _ => false,
             },
             _ => true,
         }
     }
 }
 
+// Read one length prefixed string of the ssh wire format.
+fn ssh_read_string<'a>(blob: &mut &'a [u8]) -> Option<&'a [u8]> {
+    if blob.len() < 4 {
+        return None;
+    }
+    let (len, rest) = blob.split_at(4);
+    let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+    if rest.len() < len {
+        return None;
+    }
+    let (s, rest) = rest.split_at(len);
+    *blob = rest;
+    Some(s)
+}
+
+fn ssh_read_ecdsa(blob: &mut &[u8], curve: &str) -> bool {
+    ssh_read_string(blob) == Some(curve.as_bytes())
+        // Only uncompressed points are used by openssh.
+        && ssh_read_string(blob).and_then(|p| p.first()) == Some(&0x04)
+}
+
+// Check the blob of a public key is what its type says it is, so that sshd
+// won't refuse it later.
+fn ssh_publickey_valid(keytype: &str, mut blob: &[u8]) -> bool {
+    if ssh_read_string(&mut blob) != Some(keytype.as_bytes()) {
+        return false;
+    }
+    let blob = &mut blob;
+    let valid = match keytype {
+        // e and n.
+        "ssh-rsa" => (0..2).all(|_| ssh_read_string(blob).is_some_and(|i| !i.is_empty())),
+        // p, q, g and y.
+        "ssh-dss" => (0..4).all(|_| ssh_read_string(blob).is_some_and(|i| !i.is_empty())),
+        "ssh-ed25519" => ssh_read_string(blob).is_some_and(|k| k.len() == 32),
+        "ecdsa-sha2-nistp256" => ssh_read_ecdsa(blob, "nistp256"),
+        "ecdsa-sha2-nistp384" => ssh_read_ecdsa(blob, "nistp384"),
+        "ecdsa-sha2-nistp521" => ssh_read_ecdsa(blob, "nistp521"),
+        // Security keys also carry the application they are for.
+        "sk-ssh-ed25519@openssh.com" => {
+            ssh_read_string(blob).is_some_and(|k| k.len() == 32) && ssh_read_string(blob).is_some()
+        }
+        "sk-ecdsa-sha2-nistp256@openssh.com" => {
+            ssh_read_ecdsa(blob, "nistp256") && ssh_read_string(blob).is_some()
+        }
+        _ => false,
+    };
+    valid && blob.is_empty()
+}
+
+// Parse an openssh public key, as "type base64 [comment]", returning it with
+// the whitespace tidied. Authorized keys options aren't accepted, and nor is
+// anything that could end the line early when the keys are given to sshd.
+fn normalise_ssh_publickey(key: &str) -> Option<String> {
+    if key.chars().any(|c| c.is_control() && c != '\t') {
+        return None;
+    }
+    let mut parts = key.split_whitespace();
+    let keytype = parts.next()?;
+    let b64 = parts.next()?;
+    let blob = base64::decode(b64).ok()?;
+    if !ssh_publickey_valid(keytype, blob.as_slice()) {
+        return None;
+    }
+    let comment: Vec<&str> = parts.collect();
+    if comment.is_empty() {
+        Some(format!("{} {}", keytype, b64))
+    } else {
+        Some(format!("{} {} {}", keytype, b64, comment.join(" ")))
+    }
+}
+
 impl Borrow<PartialValue> for Value {
     fn borrow(&self) -> &PartialValue {
         &self.pv
@@ -881,6 +1409,7 @@ impl Borrow<PartialValue> for Value {
 #[cfg(test)]
 mod tests {
     use crate::value::*;
+    use std::collections::BTreeSet;
 
     #[test]
     fn test_value_index_tryfrom() {
@@ -916,6 +1445,125 @@ mod tests {
 
         let r6 = SyntaxType::try_from("zzzzantheou");
         assert_eq!(r6, Err(()));
+
+        let r7 = SyntaxType::try_from("UINT32");
+        assert_eq!(r7, Ok(SyntaxType::UINT32));
+
+        let r8 = SyntaxType::try_from("BINARY");
+        assert_eq!(r8, Ok(SyntaxType::BINARY));
+        assert_eq!(
+            SyntaxType::try_from(SyntaxType::BINARY.to_usize()),
+            Ok(SyntaxType::BINARY)
+        );
+    }
+
+    #[test]
+    fn test_value_ordering() {
+        // Numeric values order numerically, not lexically.
+        let n9 = PartialValue::new_uint32s("9").expect("Invalid uint32");
+        let n10 = PartialValue::new_uint32s("10").expect("Invalid uint32");
+        assert_eq!(n9.ordering(&n10), Some(Ordering::Less));
+        assert_eq!(n10.ordering(&n9), Some(Ordering::Greater));
+        assert_eq!(n9.ordering(&n9), Some(Ordering::Equal));
+        assert!(PartialValue::new_uint32s("-1").is_none());
+        assert!(PartialValue::new_uint32s("abc").is_none());
+
+        // Strings are lexical
+        let s9 = PartialValue::new_utf8s("9");
+        let s10 = PartialValue::new_utf8s("10");
+        assert_eq!(s9.ordering(&s10), Some(Ordering::Greater));
+
+        // Differing types have no order.
+        assert_eq!(n9.ordering(&s9), None);
+        assert_eq!(
+            PartialValue::new_bool(true).ordering(&PartialValue::new_bool(false)),
+            None
+        );
+
+        // Datetimes are chronological, regardless of the offset they were given in.
+        let d1 =
+            PartialValue::new_datetimes("2020-01-01T09:00:00+10:00").expect("Invalid datetime");
+        let d2 = PartialValue::new_datetimes("2020-01-01T00:00:00Z").expect("Invalid datetime");
+        let d3 = PartialValue::new_datetimes("2019-12-31T23:00:00Z").expect("Invalid datetime");
+        assert_eq!(d1.ordering(&d2), Some(Ordering::Less));
+        assert_eq!(d1, d3);
+        assert!(PartialValue::new_datetimes("2020-01-01").is_none());
+    }
+
+    #[test]
+    fn test_value_radius_secret() {
+        let v = Value::new_radius_str("SecretValue");
+        assert!(v.is_radius_string());
+        assert!(v.validate());
+        // Case is kept, but the secret is never shown as a plain value.
+        assert_eq!(v.get_radius_secret(), Some("SecretValue"));
+        assert_eq!(v.to_proto_string_clone(), "radius");
+        assert!(v.to_partialvalue() == PartialValue::new_radius_string());
+
+        let dbv = Value::from_db_valuev1(v.to_db_valuev1()).expect("Invalid db value");
+        assert_eq!(dbv.get_radius_secret(), Some("SecretValue"));
+    }
+
+    #[test]
+    fn test_value_sshkey() {
+        let ed25519 = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIC6QCYmikCVyX0g7E6MRDYEVh2OmvffZOnDQtxeCRDdx user@host";
+        let rsa = "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAAAgQCaUwp7D3tWIWUSZnofETNhStg4Ooy0nYsWyEZNVpnWMkxIWZz8fXkMTvE3rkhuk3UX7MPRcH/N3AjzFs8C15k0lOWuWhftzLSm4x+tZL2DfzRchq5PXoh7hF3PB97ZSOoKDtgvtMZoO7Ikkvx//v9n+K/Z6yPt1hnucdh9LehySQ== user@host";
+        let ecdsa = "ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBNNUsyUJMMMPBXS0M9Obg5n6+A2X+y5RW4UwThCEezTwf1AlhsnbsEFuaf14WWvDeiVMuIbyruElPLOKo237IJI= user@host";
+        for key in &[ed25519, rsa, ecdsa] {
+            let v = Value::new_sshkey("laptop", key).expect("Valid key rejected");
+            assert!(v.is_sshkey());
+            assert!(v.validate());
+            assert_eq!(v.get_sshkey(), Some(*key));
+            assert!(v.to_partialvalue() == PartialValue::new_sshkey_tagr("laptop"));
+        }
+
+        // The proto form is split on the first colon only, and whitespace
+        // is tidied.
+        let v = Value::new_sshkey_str("laptop:   ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIC6QCYmikCVyX0g7E6MRDYEVh2OmvffZOnDQtxeCRDdx  user@host: home ")
+            .expect("Valid key rejected");
+        assert_eq!(
+            v.to_proto_string_clone(),
+            "laptop: ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIC6QCYmikCVyX0g7E6MRDYEVh2OmvffZOnDQtxeCRDdx user@host: home"
+        );
+        let dbv = Value::from_db_valuev1(v.to_db_valuev1()).expect("Invalid db value");
+        assert_eq!(dbv.get_sshkey(), v.get_sshkey());
+
+        // No tag, or no key.
+        assert!(Value::new_sshkey_str(ed25519).is_none());
+        assert!(Value::new_sshkey_str(
+            ": ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIC6QCYmikCVyX0g7E6MRDYEVh2OmvffZOnDQtxeCRDdx"
+        )
+        .is_none());
+        assert!(Value::new_sshkey_str("laptop:").is_none());
+        // Not base64, or truncated.
+        assert!(Value::new_sshkey("laptop", "ssh-ed25519 not-a-key").is_none());
+        assert!(Value::new_sshkey(
+            "laptop",
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIC6QCYmikCVyX0g7E6MRDYEVh2Om"
+        )
+        .is_none());
+        // The type doesn't match the blob.
+        assert!(Value::new_sshkey(
+            "laptop",
+            "ssh-rsa AAAAC3NzaC1lZDI1NTE5AAAAIC6QCYmikCVyX0g7E6MRDYEVh2OmvffZOnDQtxeCRDdx"
+        )
+        .is_none());
+        // Options, or a second line.
+        assert!(Value::new_sshkey("laptop", &format!("command=\"/bin/sh\" {}", ed25519)).is_none());
+        assert!(Value::new_sshkey("laptop", &format!("{}\n{}", ed25519, rsa)).is_none());
+    }
+
+    #[test]
+    fn test_value_idx_sub_keys() {
+        let keys = PartialValue::new_iutf8s("William").get_idx_sub_keys();
+        let expect: BTreeSet<String> = ["wil", "ill", "lli", "lia", "iam"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert!(keys == expect);
+        // Too short to have any, and not a string at all.
+        assert!(PartialValue::new_iutf8s("wi").get_idx_sub_keys().is_empty());
+        assert!(PartialValue::new_bool(true).get_idx_sub_keys().is_empty());
     }
 
     /*
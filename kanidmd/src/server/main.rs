@@ -1,20 +1,25 @@
 #![deny(warnings)]
 
 extern crate actix;
-extern crate env_logger;
-extern crate rpassword;
 
 extern crate kanidm;
 extern crate structopt;
 #[macro_use]
 extern crate log;
 
-use kanidm::config::Configuration;
+use kanidm::admin::{AdminClient, AdminRequest, AdminResponse};
+use kanidm::config::{
+    ConfigDuration, ConfigError, Configuration, ServerConfig, ServerConfigBackup,
+    ServerConfigLimits, ServerConfigTls,
+};
 use kanidm::core::{
-    backup_server_core, create_server_core, recover_account_core, reset_sid_core,
-    restore_server_core, verify_server_core,
+    backup_server_core, create_server_core, db_version_core, domain_info_core,
+    recover_account_core, reindex_server_core, repair_server_core, reset_sid_core,
+    restore_server_core, rotate_token_key_core, vacuum_server_core, verify_server_core, ServerLock,
 };
+use kanidm::logging;
 
+use kanidm_proto::v1::{RepairRequest, VerifyCheck, VerifyRequest};
 use std::path::PathBuf;
 use structopt::StructOpt;
 
@@ -23,7 +28,33 @@ struct CommonOpt {
     #[structopt(short = "d", long = "debug")]
     debug: bool,
     #[structopt(parse(from_os_str), short = "D", long = "db_path")]
-    db_path: PathBuf,
+    db_path: Option<PathBuf>,
+    // A server config file. The flags given are used over what it says.
+    #[structopt(parse(from_os_str), short = "f", long = "config")]
+    config_path: Option<PathBuf>,
+    // The admin socket the server serves, which the other commands use if
+    // the server is running.
+    #[structopt(parse(from_os_str), long = "admin_socket")]
+    admin_socket: Option<PathBuf>,
+}
+
+fn path_arg(p: &Option<PathBuf>) -> Option<String> {
+    p.as_ref().map(|p| p.to_string_lossy().into_owned())
+}
+
+impl CommonOpt {
+    fn server_config(&self) -> ServerConfig {
+        ServerConfig {
+            db_path: path_arg(&self.db_path),
+            admin_socket: path_arg(&self.admin_socket),
+            log_level: if self.debug {
+                Some("debug".to_string())
+            } else {
+                None
+            },
+            ..ServerConfig::default()
+        }
+    }
 }
 
 #[derive(Debug, StructOpt)]
@@ -34,14 +65,136 @@ struct ServerOpt {
     cert_path: Option<PathBuf>,
     #[structopt(parse(from_os_str), short = "k", long = "key")]
     key_path: Option<PathBuf>,
+    // 1.2 or 1.3.
+    #[structopt(long = "tls_min_version")]
+    tls_min_version: Option<String>,
     #[structopt(short = "r", long = "domain")]
-    domain: String,
+    domain: Option<String>,
+    // The url that users reach the server at, for webauthn.
+    #[structopt(long = "origin")]
+    origin: Option<String>,
     #[structopt(short = "b", long = "bindaddr")]
     bind: Option<String>,
+    // Serve /metrics here rather than on bindaddr, so it needn't be public.
+    #[structopt(long = "metrics_bindaddr")]
+    metrics_bind: Option<String>,
+    // Serve a read only ldap gateway here. This needs the tls options.
+    #[structopt(long = "ldapbindaddr")]
+    ldapbind: Option<String>,
+    // Give an ldap attribute as one of ours, as ldapname=name. This may be
+    // given more than once.
+    #[structopt(long = "ldap_attr_map")]
+    ldap_attr_map: Vec<String>,
+    #[structopt(long = "session_lifetime")]
+    session_lifetime: Option<u64>,
+    #[structopt(long = "auth_lockout_threshold")]
+    auth_lockout_threshold: Option<u32>,
+    #[structopt(long = "auth_lockout_window")]
+    auth_lockout_window: Option<u64>,
+    #[structopt(long = "reauth_within")]
+    reauth_within: Option<u64>,
+    #[structopt(long = "recycle_bin_max_age")]
+    recycle_bin_max_age: Option<u64>,
+    #[structopt(long = "tombstone_max_age")]
+    tombstone_max_age: Option<u64>,
+    // Seconds that changes are kept in the changelog.
+    #[structopt(long = "changelog_max_age")]
+    changelog_max_age: Option<u64>,
+    // Take backups into this directory while running.
+    #[structopt(parse(from_os_str), long = "backup_path")]
+    backup_path: Option<PathBuf>,
+    // @hourly, @daily, @weekly, or a number of seconds.
+    #[structopt(long = "backup_schedule")]
+    backup_schedule: Option<String>,
+    #[structopt(long = "backup_versions")]
+    backup_versions: Option<usize>,
+    // Refuse anonymous searches that no index can answer.
+    #[structopt(long = "deny_unindexed_anonymous")]
+    deny_unindexed_anonymous: bool,
+    // The most entries a search may load, or give in one page.
+    #[structopt(long = "max_results")]
+    max_results: Option<usize>,
+    #[structopt(long = "max_results_anonymous")]
+    max_results_anonymous: Option<usize>,
+    // How many entries, and index lookups, to keep in memory. 0 for none.
+    #[structopt(long = "cache_entries")]
+    cache_entries: Option<usize>,
+    #[structopt(long = "cache_idls")]
+    cache_idls: Option<usize>,
+    // Seconds to wait, once signalled to stop, for what's in progress.
+    #[structopt(long = "shutdown_grace_period")]
+    shutdown_grace_period: Option<u64>,
     #[structopt(flatten)]
     commonopts: CommonOpt,
 }
 
+impl ServerOpt {
+    fn server_config(&self) -> ServerConfig {
+        ServerConfig {
+            bindaddress: self.bind.clone(),
+            ldapbindaddress: self.ldapbind.clone(),
+            metrics_bindaddress: self.metrics_bind.clone(),
+            domain: self.domain.clone(),
+            origin: self.origin.clone(),
+            session_lifetime: self.session_lifetime.map(ConfigDuration::Seconds),
+            reauth_within: self.reauth_within.map(ConfigDuration::Seconds),
+            auth_lockout_threshold: self.auth_lockout_threshold,
+            auth_lockout_window: self.auth_lockout_window.map(ConfigDuration::Seconds),
+            recycle_bin_max_age: self.recycle_bin_max_age.map(ConfigDuration::Seconds),
+            tombstone_max_age: self.tombstone_max_age.map(ConfigDuration::Seconds),
+            changelog_max_age: self.changelog_max_age.map(ConfigDuration::Seconds),
+            shutdown_grace_period: self.shutdown_grace_period.map(ConfigDuration::Seconds),
+            // A map without a name is kept as it is, to be reported with
+            // everything else that's wrong.
+            ldap_attr_map: self
+                .ldap_attr_map
+                .iter()
+                .map(|m| {
+                    let mut parts = m.splitn(2, '=');
+                    let l = parts.next().unwrap_or("").to_string();
+                    let k = parts.next().unwrap_or("").to_string();
+                    (l, k)
+                })
+                .collect(),
+            tls: ServerConfigTls {
+                ca: path_arg(&self.ca_path),
+                chain: path_arg(&self.cert_path),
+                key: path_arg(&self.key_path),
+                min_version: self.tls_min_version.clone(),
+            },
+            limits: ServerConfigLimits {
+                max_results: self.max_results,
+                max_results_anonymous: self.max_results_anonymous,
+                allow_unindexed_anonymous: if self.deny_unindexed_anonymous {
+                    Some(false)
+                } else {
+                    None
+                },
+                max_filter_depth: None,
+                max_filter_depth_anonymous: None,
+                max_filter_elements: None,
+                max_filter_elements_anonymous: None,
+                max_filter_inclusion: None,
+                max_filter_inclusion_anonymous: None,
+                cache_entries: self.cache_entries,
+                cache_idls: self.cache_idls,
+                maximum_request: None,
+                maximum_request_auth: None,
+                maximum_request_search: None,
+                max_create_entries: None,
+                max_modifications: None,
+                max_value_length: None,
+            },
+            backup: ServerConfigBackup {
+                path: path_arg(&self.backup_path),
+                schedule: self.backup_schedule.clone(),
+                versions: self.backup_versions,
+            },
+            ..self.commonopts.server_config()
+        }
+    }
+}
+
 #[derive(Debug, StructOpt)]
 struct BackupOpt {
     #[structopt(parse(from_os_str))]
@@ -54,6 +207,35 @@ struct BackupOpt {
 struct RestoreOpt {
     #[structopt(parse(from_os_str))]
     path: PathBuf,
+    // Report what would be restored, without changing anything.
+    #[structopt(long = "dry_run")]
+    dry_run: bool,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct RepairOpt {
+    // Show what would be changed, without changing it.
+    #[structopt(long = "dry_run")]
+    dry_run: bool,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
+#[derive(Debug, StructOpt)]
+struct VerifyOpt {
+    // Generate again what the plugins derive, such as memberof, if it's
+    // found to be wrong.
+    #[structopt(long = "repair")]
+    repair: bool,
+    // Verify the running server through its admin socket.
+    #[structopt(long = "online")]
+    online: bool,
+    // Run only this check, which is one of schema, index, refint or
+    // memberof. It can be given more than once.
+    #[structopt(long = "check")]
+    checks: Vec<VerifyCheck>,
     #[structopt(flatten)]
     commonopts: CommonOpt,
 }
@@ -66,83 +248,204 @@ struct RecoverAccountOpt {
     commonopts: CommonOpt,
 }
 
+#[derive(Debug, StructOpt)]
+struct LogLevelOpt {
+    // The level to set. Without it, the level is shown.
+    level: Option<String>,
+    // Show or set the level of this target alone, such as be or http. Its
+    // level can be set to default, to log it as the rest are again.
+    #[structopt(long = "target")]
+    target: Option<String>,
+    #[structopt(flatten)]
+    commonopts: CommonOpt,
+}
+
 #[derive(Debug, StructOpt)]
 enum Opt {
     #[structopt(name = "server")]
     Server(ServerOpt),
+    // Check the config file and flags as the server would, and report every
+    // problem found, without starting it.
+    #[structopt(name = "configtest")]
+    ConfigTest(ServerOpt),
     #[structopt(name = "backup")]
     Backup(BackupOpt),
     #[structopt(name = "restore")]
     Restore(RestoreOpt),
     #[structopt(name = "verify")]
-    Verify(CommonOpt),
+    Verify(VerifyOpt),
+    // Fix what verify finds that can be derived again, through the running
+    // server if there is one.
+    #[structopt(name = "repair")]
+    Repair(RepairOpt),
+    // Set a generated password on the account, and show it.
     #[structopt(name = "recover_account")]
     RecoverAccount(RecoverAccountOpt),
+    #[structopt(name = "domain_info")]
+    DomainInfo(CommonOpt),
+    // Show the version of each part of the database, and the version this
+    // server supports.
+    #[structopt(name = "db_version")]
+    DbVersion(CommonOpt),
+    // Show or change the log level of the running server.
+    #[structopt(name = "log_level")]
+    LogLevel(LogLevelOpt),
     #[structopt(name = "reset_server_id")]
     ResetServerId(CommonOpt),
+    #[structopt(name = "rotate_token_key")]
+    RotateTokenKey(CommonOpt),
+    #[structopt(name = "reindex")]
+    Reindex(CommonOpt),
+    #[structopt(name = "vacuum")]
+    Vacuum(CommonOpt),
 }
 
 impl Opt {
-    fn debug(&self) -> bool {
+    fn commonopts(&self) -> &CommonOpt {
+        match self {
+            Opt::Server(sopt) | Opt::ConfigTest(sopt) => &sopt.commonopts,
+            Opt::ResetServerId(sopt)
+            | Opt::RotateTokenKey(sopt)
+            | Opt::Reindex(sopt)
+            | Opt::Vacuum(sopt)
+            | Opt::DomainInfo(sopt)
+            | Opt::DbVersion(sopt) => sopt,
+            Opt::Backup(bopt) => &bopt.commonopts,
+            Opt::Restore(ropt) => &ropt.commonopts,
+            Opt::Verify(vopt) => &vopt.commonopts,
+            Opt::Repair(ropt) => &ropt.commonopts,
+            Opt::RecoverAccount(ropt) => &ropt.commonopts,
+            Opt::LogLevel(lopt) => &lopt.commonopts,
+        }
+    }
+
+    // The options given as flags.
+    fn server_config(&self) -> ServerConfig {
         match self {
-            Opt::Server(sopt) => sopt.commonopts.debug,
-            Opt::Verify(sopt) | Opt::ResetServerId(sopt) => sopt.debug,
-            Opt::Backup(bopt) => bopt.commonopts.debug,
-            Opt::Restore(ropt) => ropt.commonopts.debug,
-            Opt::RecoverAccount(ropt) => ropt.commonopts.debug,
+            Opt::Server(sopt) | Opt::ConfigTest(sopt) => sopt.server_config(),
+            _ => self.commonopts().server_config(),
         }
     }
 }
 
+// The logger isn't set up yet, as the config says how it should be.
+fn config_failed(errs: &[ConfigError]) -> ! {
+    for e in errs {
+        eprintln!("{}", e);
+    }
+    eprintln!("Invalid configuration - {} problem(s) found", errs.len());
+    std::process::exit(1);
+}
+
+// The admin socket of the server running on the database, if there is one.
+// Without it, the commands that can work on the database instead.
+fn admin_client(config: &Configuration) -> Option<AdminClient> {
+    let path = config.admin_socket.as_ref()?;
+    match AdminClient::connect(path.as_str()) {
+        Ok(client) => {
+            info!("Using the admin socket of the running server at {}", path);
+            Some(client)
+        }
+        Err(e) => {
+            debug!("No server is listening at {} -> {:?}", path, e);
+            None
+        }
+    }
+}
+
+fn admin_request(client: &mut AdminClient, req: AdminRequest) -> AdminResponse {
+    match client.request(&req) {
+        Ok(AdminResponse::Error(e)) | Err(e) => {
+            error!("Admin socket request failed -> {}", e);
+            std::process::exit(1);
+        }
+        Ok(resp) => resp,
+    }
+}
+
+fn unexpected_response(resp: AdminResponse) -> ! {
+    error!("Unexpected response from the admin socket -> {:?}", resp);
+    std::process::exit(1);
+}
+
 fn main() {
     // Read cli args, determine if we should backup/restore
     let opt = Opt::from_args();
 
-    // Read our config (if any)
-    let mut config = Configuration::new();
-    // Apply any cli overrides?
+    // Read our config (if any), and apply the cli over it.
+    let file_config = match &opt.commonopts().config_path {
+        Some(p) => match ServerConfig::read(p) {
+            Ok(c) => c,
+            Err(e) => config_failed(&[e]),
+        },
+        None => ServerConfig::default(),
+    };
+    let sconfig = opt.server_config().or(file_config);
+    let config = match opt {
+        Opt::Server(_) | Opt::ConfigTest(_) => sconfig.validate(),
+        _ => sconfig.validate_offline(),
+    }
+    .unwrap_or_else(|errs| config_failed(&errs));
 
-    // Configure the server logger. This could be adjusted based on what config
-    // says.
-    if opt.debug() {
-        ::std::env::set_var("RUST_LOG", "actix_web=info,kanidm=debug");
-    } else {
-        ::std::env::set_var("RUST_LOG", "actix_web=info,kanidm=info");
+    // Configure the server logger. The admin socket can change its levels
+    // while the server runs.
+    if let Err(e) = logging::init(
+        config.log_format,
+        config.log_level.as_str(),
+        &config.log_targets,
+    ) {
+        eprintln!("{}", e);
+        std::process::exit(1);
     }
-    env_logger::init();
 
     match opt {
-        Opt::Server(sopt) => {
+        Opt::ConfigTest(_) => {
+            println!("Configuration is valid: {}", config);
+        }
+        Opt::Server(_) => {
             info!("Running in server mode ...");
 
-            config.update_db_path(&sopt.commonopts.db_path);
-            config.update_tls(&sopt.ca_path, &sopt.cert_path, &sopt.key_path);
-            config.update_bind(&sopt.bind);
-            config.domain = sopt.domain.clone();
+            // Held until the server stops.
+            let lock = match ServerLock::acquire(&config) {
+                Ok(l) => l,
+                Err(_) => std::process::exit(1),
+            };
 
             let sys = actix::System::new("kanidm-server");
             create_server_core(config);
-            let _ = sys.run();
+            // Whether the server stopped cleanly once signalled. Exiting
+            // doesn't drop anything, so the lock is released first.
+            let code = sys.run();
+            drop(lock);
+            std::process::exit(code);
         }
         Opt::Backup(bopt) => {
             info!("Running in backup mode ...");
 
-            config.update_db_path(&bopt.commonopts.db_path);
-
-            let p = match bopt.path.to_str() {
+            // The running server writes it, from wherever it was started.
+            let path = std::env::current_dir()
+                .map(|d| d.join(&bopt.path))
+                .unwrap_or(bopt.path);
+            let p = match path.to_str() {
                 Some(p) => p,
                 None => {
                     error!("Invalid backup path");
                     std::process::exit(1);
                 }
             };
-            backup_server_core(config, p);
+            match admin_client(&config) {
+                Some(mut client) => {
+                    match admin_request(&mut client, AdminRequest::Backup(p.to_string())) {
+                        AdminResponse::Success => info!("Backup success!"),
+                        resp => unexpected_response(resp),
+                    }
+                }
+                None => backup_server_core(config, p),
+            }
         }
         Opt::Restore(ropt) => {
             info!("Running in restore mode ...");
 
-            config.update_db_path(&ropt.commonopts.db_path);
-
             let p = match ropt.path.to_str() {
                 Some(p) => p,
                 None => {
@@ -150,27 +453,146 @@ fn main() {
                     std::process::exit(1);
                 }
             };
-            restore_server_core(config, p);
+            restore_server_core(config, p, ropt.dry_run);
         }
         Opt::Verify(vopt) => {
-            info!("Running in restore mode ...");
-
-            config.update_db_path(&vopt.db_path);
-            verify_server_core(config);
+            if !vopt.online {
+                info!("Running in verify mode ...");
+                verify_server_core(config, vopt.repair, vopt.checks.as_slice());
+                return;
+            }
+            if vopt.repair {
+                error!("A running server can't be repaired - stop it, and verify with --repair");
+                std::process::exit(1);
+            }
+            let mut client = match admin_client(&config) {
+                Some(client) => client,
+                None => {
+                    error!("No server is running to verify through the admin socket");
+                    std::process::exit(1);
+                }
+            };
+            let req = AdminRequest::Verify(VerifyRequest::new(vopt.checks));
+            let vr = match admin_request(&mut client, req) {
+                AdminResponse::Verify(vr) => vr,
+                resp => unexpected_response(resp),
+            };
+            for r in vr.results.iter() {
+                println!("{}", r);
+            }
+            if vr.results.len() != 0 {
+                std::process::exit(1);
+            }
+        }
+        Opt::Repair(ropt) => {
+            let rr = match admin_client(&config) {
+                Some(mut client) => {
+                    let req = AdminRequest::Repair(RepairRequest::new(ropt.dry_run));
+                    match admin_request(&mut client, req) {
+                        AdminResponse::Repair(rr) => rr,
+                        resp => unexpected_response(resp),
+                    }
+                }
+                None => repair_server_core(config, ropt.dry_run),
+            };
+            let verb = if rr.dry_run {
+                "would change"
+            } else {
+                "changed"
+            };
+            for c in rr.changes.iter() {
+                println!("{} {}", verb, c);
+            }
+            for e in rr.unrepaired.iter() {
+                println!("not repaired {} - {}", e, e.guidance());
+            }
+            if rr.unrepaired.len() != 0 {
+                std::process::exit(1);
+            }
         }
         Opt::RecoverAccount(raopt) => {
             info!("Running account recovery ...");
 
-            let password = rpassword::prompt_password_stderr("new password: ").unwrap();
-            config.update_db_path(&raopt.commonopts.db_path);
-
-            recover_account_core(config, raopt.name, password);
+            // Only the password is written to stdout, for scripts.
+            let password = match admin_client(&config) {
+                Some(mut client) => {
+                    match admin_request(&mut client, AdminRequest::RecoverAccount(raopt.name)) {
+                        AdminResponse::Password(p) => p,
+                        resp => unexpected_response(resp),
+                    }
+                }
+                None => recover_account_core(config, raopt.name),
+            };
+            println!("{}", password);
+        }
+        Opt::DomainInfo(_) => {
+            let di = match admin_client(&config) {
+                Some(mut client) => match admin_request(&mut client, AdminRequest::DomainInfo) {
+                    AdminResponse::DomainInfo(di) => di,
+                    resp => unexpected_response(resp),
+                },
+                None => domain_info_core(config),
+            };
+            println!("name: {}", di.name);
+            println!("display name: {}", di.display_name);
+            println!("uuid: {}", di.uuid);
+            println!("entries: {}", di.entries);
+        }
+        Opt::DbVersion(_) => {
+            let versions = db_version_core(config);
+            for (part, v, supported) in versions.iter() {
+                let state = if v < supported {
+                    "upgraded when opened"
+                } else if v > supported {
+                    "newer than this server"
+                } else {
+                    "current"
+                };
+                println!("{}: {} (supported {}) - {}", part, v, supported, state);
+            }
+            // Nothing this server can do opens a database newer than it.
+            if versions.iter().any(|(_, v, supported)| v > supported) {
+                std::process::exit(1);
+            }
         }
-        Opt::ResetServerId(vopt) => {
+        Opt::LogLevel(lopt) => {
+            let mut client = match admin_client(&config) {
+                Some(client) => client,
+                None => {
+                    error!("The log level can only be shown or changed through the admin socket of a running server");
+                    std::process::exit(1);
+                }
+            };
+            let req = match (lopt.target, lopt.level) {
+                (Some(target), Some(level)) => AdminRequest::SetTargetLogLevel(target, level),
+                (Some(target), None) => AdminRequest::GetTargetLogLevel(target),
+                (None, Some(level)) => AdminRequest::SetLogLevel(level),
+                (None, None) => AdminRequest::GetLogLevel,
+            };
+            match admin_request(&mut client, req) {
+                AdminResponse::LogLevel(level) => println!("{}", level),
+                resp => unexpected_response(resp),
+            }
+        }
+        Opt::ResetServerId(_) => {
             info!("Resetting server id. THIS MAY BREAK REPLICATION");
 
-            config.update_db_path(&vopt.db_path);
             reset_sid_core(config);
         }
+        Opt::RotateTokenKey(_) => {
+            info!("Rotating token signing key ...");
+
+            rotate_token_key_core(config);
+        }
+        Opt::Reindex(_) => {
+            info!("Running in reindex mode ...");
+
+            reindex_server_core(config);
+        }
+        Opt::Vacuum(_) => {
+            info!("Running in vacuum mode ...");
+
+            vacuum_server_core(config);
+        }
     }
 }
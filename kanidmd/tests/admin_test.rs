@@ -0,0 +1,246 @@
+#![deny(warnings)]
+
+extern crate assert_cmd;
+extern crate libc;
+
+use assert_cmd::prelude::*;
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+static PORT_ALLOC: AtomicUsize = AtomicUsize::new(26080);
+
+// A directory of its own for each test, for the db, the admin socket and
+// anything else written.
+struct TestDir {
+    path: PathBuf,
+    port: usize,
+}
+
+impl TestDir {
+    fn new() -> Self {
+        let port = PORT_ALLOC.fetch_add(1, Ordering::SeqCst);
+        let path = std::env::temp_dir().join(format!("kanidmd_admin_{}", port));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).expect("Failed to create test dir");
+        TestDir {
+            path: path,
+            port: port,
+        }
+    }
+
+    fn file(&self, name: &str) -> String {
+        self.path.join(name).to_str().unwrap().to_string()
+    }
+
+    // A command of kanidmd on the test db, which finds the server through
+    // the admin socket if it's running.
+    fn kanidmd(&self, args: &[&str]) -> Command {
+        let mut cmd = Command::cargo_bin("kanidmd").expect("Failed to find kanidmd binary");
+        cmd.args(args)
+            .arg("-D")
+            .arg(self.file("kanidm.db"))
+            .arg("--admin_socket")
+            .arg(self.file("kanidm.sock"));
+        cmd
+    }
+
+    fn start_server(&self, admin_socket: bool) -> Child {
+        let mut cmd = Command::cargo_bin("kanidmd").expect("Failed to find kanidmd binary");
+        cmd.arg("server")
+            .arg("-D")
+            .arg(self.file("kanidm.db"))
+            .arg("--domain")
+            .arg("localhost")
+            .arg("-b")
+            .arg(format!("127.0.0.1:{}", self.port));
+        if admin_socket {
+            cmd.arg("--admin_socket").arg(self.file("kanidm.sock"));
+        }
+        let child = cmd
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start kanidmd");
+
+        let begun = Instant::now();
+        while std::net::TcpStream::connect(format!("127.0.0.1:{}", self.port)).is_err() {
+            assert!(begun.elapsed() < Duration::from_secs(30));
+            thread::sleep(Duration::from_millis(100));
+        }
+        child
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+fn stop_server(mut child: Child) {
+    unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+    assert!(child.wait().expect("kanidmd didn't stop").success());
+}
+
+fn stdout_lines(output: &Output) -> Vec<String> {
+    String::from_utf8(output.stdout.clone())
+        .expect("Output was not utf8")
+        .lines()
+        .map(|l| l.to_string())
+        .collect()
+}
+
+fn domain_uuid(dir: &TestDir) -> String {
+    let output = dir
+        .kanidmd(&["domain_info"])
+        .assert()
+        .success()
+        .get_output()
+        .clone();
+    let lines = stdout_lines(&output);
+    assert!(lines.contains(&"name: localhost".to_string()));
+    lines
+        .iter()
+        .find(|l| l.starts_with("uuid: "))
+        .expect("No domain uuid")
+        .to_string()
+}
+
+#[test]
+fn test_admin_socket_live() {
+    let dir = TestDir::new();
+    let server = dir.start_server(true);
+
+    // The password is all that's written to stdout.
+    let output = dir
+        .kanidmd(&["recover_account", "-n", "admin"])
+        .assert()
+        .success()
+        .get_output()
+        .clone();
+    let lines = stdout_lines(&output);
+    assert!(lines.len() == 1);
+    assert!(lines[0].len() == 24);
+    dir.kanidmd(&["recover_account", "-n", "nobody"])
+        .assert()
+        .code(1);
+
+    let live_uuid = domain_uuid(&dir);
+
+    // The level is changed for as long as the server runs.
+    dir.kanidmd(&["log_level"])
+        .assert()
+        .success()
+        .stdout("info\n");
+    dir.kanidmd(&["log_level", "debug"])
+        .assert()
+        .success()
+        .stdout("debug\n");
+    dir.kanidmd(&["log_level"])
+        .assert()
+        .success()
+        .stdout("debug\n");
+    dir.kanidmd(&["log_level", "loud"]).assert().code(1);
+    // A target can be given a level of its own, until it's set back.
+    dir.kanidmd(&["log_level", "--target", "be", "trace"])
+        .assert()
+        .success()
+        .stdout("trace\n");
+    dir.kanidmd(&["log_level", "--target", "qs"])
+        .assert()
+        .success()
+        .stdout("debug\n");
+    dir.kanidmd(&["log_level", "--target", "be", "default"])
+        .assert()
+        .success()
+        .stdout("debug\n");
+    dir.kanidmd(&["log_level", "--target", "disk"])
+        .assert()
+        .code(1);
+
+    // A new server is consistent, checked all at once or alone.
+    dir.kanidmd(&["verify", "--online"])
+        .assert()
+        .success()
+        .stdout("");
+    dir.kanidmd(&[
+        "verify", "--online", "--check", "memberof", "--check", "index",
+    ])
+    .assert()
+    .success();
+    dir.kanidmd(&["verify", "--online", "--check", "acp"])
+        .assert()
+        .failure();
+    dir.kanidmd(&["verify", "--online", "--repair"])
+        .assert()
+        .code(1);
+    // Nor is there anything for a repair to change.
+    dir.kanidmd(&["repair", "--dry_run"])
+        .assert()
+        .success()
+        .stdout("");
+    dir.kanidmd(&["repair"]).assert().success().stdout("");
+
+    let backup = dir.file("backup.json");
+    dir.kanidmd(&["backup", backup.as_str()]).assert().success();
+    assert!(fs::metadata(&backup).map(|m| m.len() > 0).unwrap_or(false));
+
+    // Anything that isn't a request is answered with an error, and the
+    // connection can still be used.
+    let stream = UnixStream::connect(dir.file("kanidm.sock")).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    let mut resp = String::new();
+    writer.write_all(b"{\"Shutdown\":null}\n").unwrap();
+    reader.read_line(&mut resp).unwrap();
+    assert!(resp.starts_with("{\"Error\":"));
+    resp.clear();
+    writer.write_all(b"\"GetLogLevel\"\n").unwrap();
+    reader.read_line(&mut resp).unwrap();
+    assert!(resp == "{\"LogLevel\":\"debug\"}\n");
+
+    stop_server(server);
+
+    // Once the server has stopped, the same is read from the db.
+    assert!(domain_uuid(&dir) == live_uuid);
+}
+
+#[test]
+fn test_admin_offline() {
+    let dir = TestDir::new();
+
+    // Without a server the db is opened, and created as it's new.
+    let output = dir
+        .kanidmd(&["recover_account", "-n", "admin"])
+        .assert()
+        .success()
+        .get_output()
+        .clone();
+    assert!(stdout_lines(&output)[0].len() == 24);
+    let offline_uuid = domain_uuid(&dir);
+    dir.kanidmd(&["recover_account", "-n", "nobody"])
+        .assert()
+        .code(1);
+
+    // The log level belongs to a running server, as does an online verify.
+    dir.kanidmd(&["log_level"]).assert().code(1);
+    dir.kanidmd(&["verify", "--online"]).assert().code(1);
+    dir.kanidmd(&["repair"]).assert().success().stdout("");
+
+    // A server that can't be reached is not worked beneath.
+    let server = dir.start_server(false);
+    dir.kanidmd(&["domain_info"]).assert().code(1);
+    dir.kanidmd(&["recover_account", "-n", "admin"])
+        .assert()
+        .code(1);
+    stop_server(server);
+
+    assert!(domain_uuid(&dir) == offline_uuid);
+}
@@ -0,0 +1,167 @@
+#![deny(warnings)]
+
+extern crate assert_cmd;
+extern crate kanidm;
+extern crate libc;
+
+use kanidm::constants::{EXIT_SHUTDOWN_CLEAN, EXIT_SHUTDOWN_FORCED};
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+static PORT_ALLOC: AtomicUsize = AtomicUsize::new(25080);
+
+static AUTH_BODY: &'static str = r#"{"step":{"Init":["anonymous",null]}}"#;
+
+// The server is run as its own process, as it's the process that is
+// signalled, and its exit code that is checked.
+struct TestServer {
+    child: Child,
+    port: usize,
+    db_path: PathBuf,
+}
+
+impl TestServer {
+    fn start(grace_period: u64) -> Self {
+        let port = PORT_ALLOC.fetch_add(1, Ordering::SeqCst);
+        let db_path = std::env::temp_dir().join(format!("kanidmd_shutdown_{}.db", port));
+        let _ = fs::remove_file(&db_path);
+
+        let child = Command::new(assert_cmd::cargo::cargo_bin("kanidmd"))
+            .arg("server")
+            .arg("-D")
+            .arg(&db_path)
+            .arg("--domain")
+            .arg("localhost")
+            .arg("-b")
+            .arg(format!("127.0.0.1:{}", port))
+            .arg("--shutdown_grace_period")
+            .arg(grace_period.to_string())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start kanidmd");
+
+        let server = TestServer {
+            child: child,
+            port: port,
+            db_path: db_path,
+        };
+        let begun = Instant::now();
+        while server.connect().is_err() {
+            assert!(begun.elapsed() < Duration::from_secs(30));
+            thread::sleep(Duration::from_millis(100));
+        }
+        server
+    }
+
+    fn connect(&self) -> std::io::Result<TcpStream> {
+        TcpStream::connect(format!("127.0.0.1:{}", self.port))
+    }
+
+    fn signal(&self, sig: libc::c_int) {
+        assert!(unsafe { libc::kill(self.child.id() as libc::pid_t, sig) } == 0);
+    }
+
+    fn wait(&mut self) -> ExitStatus {
+        let begun = Instant::now();
+        loop {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                return status;
+            }
+            if begun.elapsed() > Duration::from_secs(30) {
+                let _ = self.child.kill();
+                panic!("kanidmd didn't stop");
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_file(&self.db_path);
+    }
+}
+
+// A request that is sent slowly: everything but the end of the body, which
+// the server waits for.
+fn begin_slow_request(stream: &mut TcpStream) -> &'static str {
+    let (first, rest) = AUTH_BODY.split_at(AUTH_BODY.len() / 2);
+    write!(
+        stream,
+        "POST /v1/auth HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        AUTH_BODY.len(),
+        first
+    )
+    .unwrap();
+    stream.flush().unwrap();
+    rest
+}
+
+#[test]
+fn test_shutdown_drains_requests() {
+    let mut server = TestServer::start(30);
+
+    let mut stream = server.connect().unwrap();
+    let rest = begin_slow_request(&mut stream);
+    // Let the server read what has been sent so far.
+    thread::sleep(Duration::from_millis(500));
+
+    server.signal(libc::SIGTERM);
+    thread::sleep(Duration::from_millis(500));
+    // No new connections are accepted while stopping.
+    assert!(server.connect().is_err());
+
+    // But the request in progress is still answered.
+    stream.write_all(rest.as_bytes()).unwrap();
+    stream.flush().unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"));
+
+    let status = server.wait();
+    assert!(status.code() == Some(EXIT_SHUTDOWN_CLEAN));
+}
+
+#[test]
+fn test_shutdown_grace_period_ends() {
+    let mut server = TestServer::start(1);
+
+    // This request never finishes, so the grace period ends.
+    let mut stream = server.connect().unwrap();
+    let _ = begin_slow_request(&mut stream);
+    thread::sleep(Duration::from_millis(500));
+
+    let begun = Instant::now();
+    server.signal(libc::SIGTERM);
+    let status = server.wait();
+    assert!(status.code() == Some(EXIT_SHUTDOWN_FORCED));
+    assert!(begun.elapsed() < Duration::from_secs(10));
+}
+
+#[test]
+fn test_shutdown_second_signal() {
+    let mut server = TestServer::start(30);
+
+    let mut stream = server.connect().unwrap();
+    let _ = begin_slow_request(&mut stream);
+    thread::sleep(Duration::from_millis(500));
+
+    // Signalled again, the server doesn't wait out the grace period.
+    let begun = Instant::now();
+    server.signal(libc::SIGINT);
+    thread::sleep(Duration::from_millis(500));
+    server.signal(libc::SIGINT);
+    let status = server.wait();
+    assert!(status.code() == Some(EXIT_SHUTDOWN_FORCED));
+    assert!(begun.elapsed() < Duration::from_secs(10));
+}